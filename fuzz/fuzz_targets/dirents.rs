@@ -0,0 +1,8 @@
+// A directory block.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = fs::fuzz::dirents(data);
+});
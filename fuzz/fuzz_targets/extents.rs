@@ -0,0 +1,8 @@
+// An extent tree, see fs::fuzz::extents for the input.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = fs::fuzz::extents(data);
+});
@@ -0,0 +1,8 @@
+// The superblock and group descriptors at the start of an image.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = fs::fuzz::mount(data);
+});
@@ -0,0 +1,32 @@
+// Write the seed corpora of the targets from a real image, into
+// corpus/<target>/ next to this file.
+//   cargo run --bin seed_corpus <image>...
+
+use std::fs::{create_dir_all, read, write};
+use std::path::Path;
+
+fn main() {
+    let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus");
+    let images: Vec<String> = std::env::args().skip(1).collect();
+    if images.is_empty() {
+        eprintln!("usage: seed_corpus <image>...");
+        std::process::exit(1);
+    }
+    for (index, image) in images.iter().enumerate() {
+        let data = read(image).expect("can't read the image");
+        let seeds = fs::fuzz::seeds(&data).expect("the image isn't a valid ext4");
+        let targets = [
+            ("mount", vec![seeds.mount]),
+            ("dirents", seeds.dirents),
+            ("extents", seeds.extents),
+        ];
+        for (target, inputs) in targets {
+            let dir = corpus.join(target);
+            create_dir_all(&dir).unwrap();
+            for (i, input) in inputs.iter().enumerate() {
+                write(dir.join(format!("{}-{}", index, i)), input).unwrap();
+            }
+            println!("{}: {} {} seeds", image, target, inputs.len());
+        }
+    }
+}
@@ -0,0 +1,155 @@
+#!/usr/bin/env python3
+# Extract the byte fixtures of disk_layout.rs from images of e2fsprogs, the
+# structures the tests of layout round trip. Two images of 1024 bytes
+# blocks are made, so the blocks stay small: one with metadata_csum, 64bit
+# and a journal transaction of csum_v3 written by debugfs, one without
+# them for the 32 bytes group descriptor and the plain journal tags. The
+# structures are cut from the raw images by the offsets of the kernel
+# headers, not by the code under test.
+#
+#   golden/layout.py
+#
+# The fixtures are written to golden/layout/, the ones checked in are
+# what the tests expect.
+
+import os
+import re
+import struct
+import subprocess
+import sys
+import tempfile
+
+FAKE_TIME = "1700000000"
+UUID = "4c61796f-7574-4669-7874-757265732121"
+HASH_SEED = "00112233-4455-6677-8899-aabbccddeeff"
+BLOCK = 1024
+IMAGE_SIZE = "8M"
+# the blocks of the transaction of debugfs, the last one is revoked.
+JOURNAL_BLOCKS = "300,301"
+JOURNAL_REVOKE = "302"
+
+
+def pattern(seed, offset, length):
+    # like golden::pattern.
+    return bytes(
+        ((((pos + seed * 0x51) & 0xFFFFFFFF) * 0x9E3779B1 & 0xFFFFFFFF) >> 24)
+        for pos in range(offset, offset + length)
+    )
+
+
+def run(*args, stdin=None):
+    env = dict(os.environ, E2FSPROGS_FAKE_TIME=FAKE_TIME)
+    out = subprocess.run(args, input=stdin, capture_output=True, check=True, env=env)
+    return out.stdout.decode()
+
+
+def debugfs(image, *commands, write=False):
+    flags = ["-w"] if write else []
+    return run("debugfs", *flags, "-f", "-", image, stdin=("\n".join(commands) + "\n").encode())
+
+
+def populate(root):
+    # frag has an extent per 4 blocks, more than i_block holds, so its
+    # tree has an index in the inode and a leaf block.
+    with open(os.path.join(root, "frag"), "wb") as f:
+        for i in range(12):
+            f.seek(i * 4 * BLOCK)
+            f.write(pattern(i, 0, BLOCK))
+    with open(os.path.join(root, "small"), "wb") as f:
+        f.write(b"small\n")
+    # dir grows past a block, e2fsck -D indexes it.
+    os.mkdir(os.path.join(root, "dir"))
+    for i in range(120):
+        with open(os.path.join(root, "dir", "file-name-%d" % i), "w") as f:
+            f.write("%d\n" % i)
+    for dirpath, dirnames, filenames in os.walk(root):
+        for name in dirnames + filenames:
+            os.utime(os.path.join(dirpath, name), (int(FAKE_TIME), int(FAKE_TIME)))
+
+
+def make_image(scratch, name, features):
+    root = os.path.join(scratch, name + "-root")
+    os.mkdir(root)
+    populate(root)
+    image = os.path.join(scratch, name + ".img")
+    run(
+        "mkfs.ext4", "-q", "-F", "-b", str(BLOCK), "-O", features, "-L", "layout",
+        "-U", UUID, "-E", "hash_seed=" + HASH_SEED, "-d", root, image, IMAGE_SIZE,
+    )
+    run("e2fsck", "-fyD", image)
+    data = os.path.join(scratch, "data")
+    with open(data, "wb") as f:
+        f.write(pattern(7, 0, 2 * BLOCK))
+    value = os.path.join(scratch, "value")
+    with open(value, "wb") as f:
+        f.write(pattern(8, 0, 700))
+    debugfs(
+        image,
+        "ea_set /small user.tag short",
+        "ea_set -f %s /frag user.big" % value,
+        "jo" + (" -c" if "metadata_csum" in features.split(",") else ""),
+        "jw -b %s -r %s %s" % (JOURNAL_BLOCKS, JOURNAL_REVOKE, data),
+        "jc",
+        write=True,
+    )
+    with open(image, "rb") as f:
+        return image, f.read()
+
+
+def block(raw, number, count=1):
+    return raw[number * BLOCK : (number + count) * BLOCK]
+
+
+def inode_number(image, path):
+    return int(re.search(r"Inode: (\d+)", debugfs(image, "stat " + path)).group(1))
+
+
+def inode(raw, image, path):
+    ino = inode_number(image, path)
+    # the inode table of group 0, all the inodes used are in it.
+    gd = raw[2 * BLOCK : 2 * BLOCK + 64]
+    table = struct.unpack_from("<I", gd, 0x8)[0]
+    size = struct.unpack_from("<H", raw, BLOCK + 0x58)[0]
+    offset = table * BLOCK + (ino - 1) * size
+    return raw[offset : offset + size]
+
+
+def bmap(image, path, lblock):
+    return int(debugfs(image, "bmap %s %d" % (path, lblock)).split()[-1])
+
+
+def journal(raw, image, lblock):
+    return block(raw, bmap(image, "<8>", lblock))
+
+
+def main():
+    out = os.path.join(os.path.dirname(os.path.abspath(__file__)), "layout")
+    os.makedirs(out, exist_ok=True)
+    fixtures = {}
+    with tempfile.TemporaryDirectory() as scratch:
+        image, raw = make_image(scratch, "csum", "metadata_csum,64bit")
+        fixtures["superblock.bin"] = raw[BLOCK : 2 * BLOCK]
+        fixtures["group_desc64.bin"] = raw[2 * BLOCK : 2 * BLOCK + 64]
+        frag = inode(raw, image, "/frag")
+        fixtures["inode.bin"] = frag
+        fixtures["inode_xattr.bin"] = inode(raw, image, "/small")
+        # the leaf of frag, from the index in its i_block.
+        leaf = struct.unpack_from("<I", frag, 0x28 + 12 + 4)[0]
+        fixtures["extent_block.bin"] = block(raw, leaf)
+        fixtures["xattr_block.bin"] = block(raw, struct.unpack_from("<I", frag, 0x68)[0])
+        fixtures["dir_block.bin"] = block(raw, bmap(image, "/", 0))
+        fixtures["dx_block.bin"] = block(raw, bmap(image, "/dir", 0))
+        for lblock, name in enumerate(["sb", "descriptor", "data0", "data1", "revoke", "commit"]):
+            if not name.startswith("data"):
+                fixtures["journal_%s.bin" % name] = journal(raw, image, lblock)
+
+        image, raw = make_image(scratch, "plain", "^metadata_csum,^64bit")
+        fixtures["group_desc32.bin"] = raw[2 * BLOCK : 2 * BLOCK + 32]
+        fixtures["journal_descriptor32.bin"] = journal(raw, image, 1)
+    for name, data in sorted(fixtures.items()):
+        with open(os.path.join(out, name), "wb") as f:
+            f.write(data)
+
+
+if __name__ == "__main__":
+    sys.exit(main())
@@ -0,0 +1,154 @@
+#!/usr/bin/env python3
+# Generate the golden manifests of the scripts with e2fsprogs, see
+# src/golden.rs for the formats. Every script is run through the host
+# filesystem in a scratch directory, mkfs.ext4 -d builds an image from it
+# and the manifest is read back from the image with debugfs, so it records
+# what e2fsprogs wrote rather than what the host has.
+#
+#   golden/reference.py [script...]
+#
+# Without arguments every golden/*.script is generated again.
+
+import glob
+import os
+import re
+import subprocess
+import sys
+import tempfile
+
+MKFS = ["mkfs.ext4", "-q", "-F", "-b", "4096", "-O", "metadata_csum"]
+IMAGE_SIZE = "64M"
+
+
+def crc32c(data, crc=0xFFFFFFFF):
+    # like crate::crc32c::crc32c, the crc isn't inverted.
+    table = crc32c.table
+    for byte in data:
+        crc = table[(crc ^ byte) & 0xFF] ^ (crc >> 8)
+    return crc
+
+
+def _table():
+    table = []
+    for i in range(256):
+        crc = i
+        for _ in range(8):
+            crc = (crc >> 1) ^ (0x82F63B78 if crc & 1 else 0)
+        table.append(crc)
+    return table
+
+
+crc32c.table = _table()
+
+
+def pattern(seed, offset, length):
+    # like golden::pattern, the byte only depends on the seed and position.
+    return bytes(
+        ((((pos + seed * 0x51) & 0xFFFFFFFF) * 0x9E3779B1 & 0xFFFFFFFF) >> 24)
+        for pos in range(offset, offset + length)
+    )
+
+
+def parse(path):
+    ops = []
+    with open(path) as f:
+        for line in f:
+            words = line.split("#")[0].split()
+            if words:
+                ops.append(words)
+    return ops
+
+
+def apply(root, ops):
+    for op in ops:
+        path = os.path.join(root, op[1])
+        if op[0] == "mkdir":
+            os.mkdir(path)
+        elif op[0] == "write":
+            offset, length, seed = int(op[2]), int(op[3]), int(op[4])
+            fd = os.open(path, os.O_WRONLY | os.O_CREAT, 0o644)
+            os.pwrite(fd, pattern(seed, offset, length), offset)
+            os.close(fd)
+        elif op[0] == "truncate":
+            os.truncate(path, int(op[2]))
+        elif op[0] == "symlink":
+            os.symlink(op[2], path)
+        elif op[0] == "link":
+            os.link(os.path.join(root, op[2]), path)
+        elif op[0] == "unlink":
+            os.unlink(path)
+        elif op[0] == "rmdir":
+            os.rmdir(path)
+        else:
+            sys.exit("unknown operation %s" % op[0])
+
+
+def debugfs(image, *commands):
+    cmds = "\n".join(commands) + "\n"
+    out = subprocess.run(
+        ["debugfs", "-f", "-", image],
+        input=cmds.encode(),
+        capture_output=True,
+        check=True,
+    )
+    return out.stdout
+
+
+def manifest(image, scratch):
+    # the names, types and data come from rdump, the link counts from stat.
+    dump = os.path.join(scratch, "dump")
+    os.mkdir(dump)
+    entries = []
+    for name in debugfs_names(image, "/"):
+        if name != "lost+found":
+            debugfs(image, 'rdump "/%s" %s' % (name, dump))
+    for dirpath, dirnames, filenames in os.walk(dump):
+        for name in dirnames + filenames:
+            full = os.path.join(dirpath, name)
+            entries.append(os.path.relpath(full, dump))
+    entries.sort(key=lambda x: x.encode())
+    stats = debugfs(image, *['stat "/%s"' % x for x in entries]).decode()
+    links = [int(x) for x in re.findall(r"Links: (\d+)", stats)]
+    lines = []
+    for path, nlink in zip(entries, links):
+        full = os.path.join(dump, path)
+        if os.path.islink(full):
+            target = os.readlink(full)
+            lines.append("l %d %d 00000000 %s -> %s" % (nlink, len(target), path, target))
+        elif os.path.isdir(full):
+            lines.append("d %d 0 00000000 %s" % (nlink, path))
+        else:
+            with open(full, "rb") as f:
+                data = f.read()
+            lines.append("f %d %d %08x %s" % (nlink, len(data), crc32c(data), path))
+    return "".join(x + "\n" for x in lines)
+
+
+def debugfs_names(image, path):
+    out = debugfs(image, 'ls -p "%s"' % path).decode()
+    names = []
+    for line in out.splitlines():
+        fields = line.split("/")
+        if len(fields) >= 7 and fields[5] not in (".", ".."):
+            names.append(fields[5])
+    return names
+
+
+def generate(script):
+    with tempfile.TemporaryDirectory() as scratch:
+        root = os.path.join(scratch, "root")
+        os.mkdir(root)
+        apply(root, parse(script))
+        image = os.path.join(scratch, "image")
+        subprocess.run(MKFS + ["-d", root, image, IMAGE_SIZE], check=True)
+        subprocess.run(["e2fsck", "-fn", image], check=True, capture_output=True)
+        text = manifest(image, scratch)
+    with open(script[: -len(".script")] + ".manifest", "w") as f:
+        f.write(text)
+
+
+if __name__ == "__main__":
+    here = os.path.dirname(os.path.abspath(__file__))
+    scripts = sys.argv[1:] or sorted(glob.glob(os.path.join(here, "*.script")))
+    for script in scripts:
+        generate(script)
@@ -0,0 +1,101 @@
+// The async file I/O of the async feature, for the coroutine scheduler: a
+// task awaiting a read yields to the executor instead of stalling the
+// hart. INodeInterface is of vfscore, so the async methods are in
+// AsyncINode, and the nodes implementing it hand it out by their FsNode,
+// see node.rs. readat, writeat and flush take any node, the others run
+// the sync operation.
+//
+// The futures never hold a lock across an await: a node takes what it
+// needs under its locks, drops them and awaits the device.
+
+use core::{future::Future, pin::Pin};
+
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc};
+use vfscore::{INodeInterface, VfsResult};
+
+use crate::node;
+use crate::sys::Mutex;
+
+/// The future of an async operation, boxed since the traits are used as
+/// dyn.
+pub type IoFuture<'a, T> = Pin<Box<dyn Future<Output = VfsResult<T>> + Send + 'a>>;
+
+/// The future of a block device request.
+pub type BlockFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+/// The async I/O of a node, the default methods run the sync ones.
+pub trait AsyncINode: INodeInterface + Sync {
+    fn readat_async<'a>(&'a self, offset: usize, buffer: &'a mut [u8]) -> IoFuture<'a, usize> {
+        Box::pin(async move { self.readat(offset, buffer) })
+    }
+
+    fn writeat_async<'a>(&'a self, offset: usize, buffer: &'a [u8]) -> IoFuture<'a, usize> {
+        Box::pin(async move { self.writeat(offset, buffer) })
+    }
+
+    fn flush_async(&self) -> IoFuture<'_, ()> {
+        Box::pin(async move { self.flush() })
+    }
+}
+
+/// A block device completing the requests asynchronously, the blocks are
+/// the sectors of the sync driver with the same id.
+pub trait AsyncBlockDevice: Send + Sync {
+    fn read_blocks_async<'a>(&'a self, block: usize, buf: &'a mut [u8]) -> BlockFuture<'a>;
+    fn write_blocks_async<'a>(&'a self, block: usize, buf: &'a [u8]) -> BlockFuture<'a>;
+}
+
+/// The async drivers of the block devices by the device id.
+static DEVICES: Mutex<BTreeMap<usize, Arc<dyn AsyncBlockDevice>>> = Mutex::new(BTreeMap::new());
+
+/// Set the async driver of the device, the filesystems mounted after it
+/// await their reads on it.
+pub fn set_async_device(device_id: usize, device: Arc<dyn AsyncBlockDevice>) {
+    DEVICES.lock().insert(device_id, device);
+}
+
+pub fn async_device(device_id: usize) -> Option<Arc<dyn AsyncBlockDevice>> {
+    DEVICES.lock().get(&device_id).cloned()
+}
+
+fn async_node(file: &Arc<dyn INodeInterface>) -> Option<&dyn AsyncINode> {
+    node::fs_node(file.as_ref())?.as_async()
+}
+
+pub fn readat<'a>(
+    file: &'a Arc<dyn INodeInterface>,
+    offset: usize,
+    buffer: &'a mut [u8],
+) -> IoFuture<'a, usize> {
+    let node = async_node(file);
+    Box::pin(async move {
+        match node {
+            Some(node) => node.readat_async(offset, buffer).await,
+            None => file.readat(offset, buffer),
+        }
+    })
+}
+
+pub fn writeat<'a>(
+    file: &'a Arc<dyn INodeInterface>,
+    offset: usize,
+    buffer: &'a [u8],
+) -> IoFuture<'a, usize> {
+    let node = async_node(file);
+    Box::pin(async move {
+        match node {
+            Some(node) => node.writeat_async(offset, buffer).await,
+            None => file.writeat(offset, buffer),
+        }
+    })
+}
+
+pub fn flush(file: &Arc<dyn INodeInterface>) -> IoFuture<'_, ()> {
+    let node = async_node(file);
+    Box::pin(async move {
+        match node {
+            Some(node) => node.flush_async().await,
+            None => file.flush(),
+        }
+    })
+}
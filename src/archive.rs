@@ -0,0 +1,687 @@
+// The tar archives of the trees, for the backups and the moves of the data
+// between the filesystems. write_tar walks a directory with walk.rs and
+// writes a ustar stream of POSIX: the regular files with their data, the
+// directories, the symbol links, the FIFOs and the device nodes, with
+// their modes, owners and mtimes. A file linked more than once is written
+// once, the next paths are hard links to the first one, the files are
+// identified like in walk. The names and the values which don't fit the
+// fields of ustar are in a pax header before their entry, like GNU tar
+// --format=posix. extract_tar makes the entries of a stream in any
+// writable directory, it reads the pax and the GNU long names too, so the
+// archives of GNU tar on a host extract here. A filesystem without the
+// links of capabilities.rs gets a copy of the file of a hard link or of a
+// symbol link to a file extracted before it, the other symbol links are
+// skipped with a warning. The streams are the ones of io.rs, read and
+// written by chunks, never whole.
+// TODO: keep the modes at the extraction when INodeInterface can set them.
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use vfscore::{FileType, INodeInterface, Stat, TimeSpec, VfsError, VfsResult};
+
+use crate::capabilities::{self, FsCapabilities};
+use crate::devnode::{make_dev, split_dev};
+use crate::io::{InodeReader, InodeWriter, Read, Write};
+use crate::mknod::{self, S_IFBLK, S_IFCHR, S_IFIFO, S_IFMT};
+use crate::ops::copy_file_data;
+use crate::owner;
+use crate::walk::{identity, WalkDir};
+
+/// The size of the blocks of a tar stream.
+pub const BLOCK_SIZE: usize = 512;
+
+/// The data of the files is copied by chunks of this size.
+const CHUNK: usize = 0x10000;
+
+// The type flags of the entries.
+const REGULAR: u8 = b'0';
+const HARD_LINK: u8 = b'1';
+const SYMLINK: u8 = b'2';
+const CHAR: u8 = b'3';
+const BLOCK: u8 = b'4';
+const DIRECTORY: u8 = b'5';
+const FIFO: u8 = b'6';
+const CONTIGUOUS: u8 = b'7';
+/// The pax header of the next entry.
+const PAX: u8 = b'x';
+/// The pax header of all the next entries.
+const PAX_GLOBAL: u8 = b'g';
+/// The long name and the long link target of the next entry, of GNU tar.
+const GNU_LONG_NAME: u8 = b'L';
+const GNU_LONG_LINK: u8 = b'K';
+
+/// A header block, with the fields of ustar by their range.
+struct Header([u8; BLOCK_SIZE]);
+
+impl Header {
+    const NAME: (usize, usize) = (0, 100);
+    const MODE: (usize, usize) = (100, 8);
+    const UID: (usize, usize) = (108, 8);
+    const GID: (usize, usize) = (116, 8);
+    const SIZE: (usize, usize) = (124, 12);
+    const MTIME: (usize, usize) = (136, 12);
+    const CHKSUM: (usize, usize) = (148, 8);
+    const TYPEFLAG: usize = 156;
+    const LINKNAME: (usize, usize) = (157, 100);
+    const MAGIC: (usize, usize) = (257, 8);
+    const DEVMAJOR: (usize, usize) = (329, 8);
+    const DEVMINOR: (usize, usize) = (337, 8);
+    const PREFIX: (usize, usize) = (345, 155);
+
+    fn new(typeflag: u8) -> Self {
+        let mut header = Self([0; BLOCK_SIZE]);
+        header.0[Self::TYPEFLAG] = typeflag;
+        // the magic "ustar\0" and the version "00".
+        header.0[Self::MAGIC.0..Self::MAGIC.0 + 8].copy_from_slice(b"ustar\x0000");
+        header
+    }
+
+    fn field(&self, (start, len): (usize, usize)) -> &[u8] {
+        &self.0[start..start + len]
+    }
+
+    /// Store the bytes in the field, false if they don't fit.
+    fn set_bytes(&mut self, (start, len): (usize, usize), bytes: &[u8]) -> bool {
+        if bytes.len() > len {
+            return false;
+        }
+        self.0[start..start + bytes.len()].copy_from_slice(bytes);
+        true
+    }
+
+    /// Store the value in octal with a NUL after it, false if it doesn't
+    /// fit.
+    fn set_octal(&mut self, (start, len): (usize, usize), value: u64) -> bool {
+        let digits = format!("{:0width$o}", value, width = len - 1);
+        if digits.len() > len - 1 {
+            return false;
+        }
+        self.0[start..start + len - 1].copy_from_slice(digits.as_bytes());
+        self.0[start + len - 1] = 0;
+        true
+    }
+
+    /// The number of the field, in octal or in the base-256 of GNU tar for
+    /// the large ones.
+    fn number(&self, field: (usize, usize)) -> VfsResult<u64> {
+        let bytes = self.field(field);
+        if bytes[0] & 0x80 != 0 {
+            let value = bytes[1..].iter().fold(0u64, |x, &b| x << 8 | b as u64);
+            return Ok(value | ((bytes[0] & 0x7f) as u64) << ((bytes.len() - 1) * 8));
+        }
+        let digits = bytes
+            .iter()
+            .skip_while(|&&b| b == b' ')
+            .take_while(|&&b| b.is_ascii_digit());
+        let mut value = 0u64;
+        let mut any = false;
+        for &b in digits {
+            if b > b'7' {
+                return Err(VfsError::InvalidData);
+            }
+            value = value.checked_mul(8).ok_or(VfsError::InvalidData)? + (b - b'0') as u64;
+            any = true;
+        }
+        // an empty field is 0, like GNU tar.
+        if !any && bytes.iter().any(|&b| b != 0 && b != b' ') {
+            return Err(VfsError::InvalidData);
+        }
+        Ok(value)
+    }
+
+    /// The bytes of the field up to its first NUL.
+    fn string(&self, field: (usize, usize)) -> String {
+        let bytes = self.field(field);
+        let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..len]).into_owned()
+    }
+
+    /// The sum of the bytes with the checksum as spaces.
+    fn checksum(&self) -> u64 {
+        let (start, len) = Self::CHKSUM;
+        let spaces = len as u64 * b' ' as u64;
+        let sum: u64 = self.0.iter().map(|&b| b as u64).sum();
+        sum - self.0[start..start + len]
+            .iter()
+            .map(|&b| b as u64)
+            .sum::<u64>()
+            + spaces
+    }
+
+    /// Store the checksum, 6 octal digits, a NUL and a space.
+    fn seal(&mut self) {
+        let digits = format!("{:06o}\0 ", self.checksum());
+        self.set_bytes(Self::CHKSUM, digits.as_bytes());
+    }
+
+    fn is_zero(&self) -> bool {
+        self.0.iter().all(|&b| b == 0)
+    }
+}
+
+/// A pax record "<len> <key>=<value>\n", the length counts itself.
+fn pax_record(key: &str, value: &str) -> String {
+    let base = key.len() + value.len() + 3;
+    let mut len = base + 1;
+    while len != base + len.to_string().len() {
+        len = base + len.to_string().len();
+    }
+    format!("{} {}={}\n", len, key, value)
+}
+
+/// Write the zeros padding len bytes to the next block.
+fn pad(out: &mut dyn Write, len: usize) -> VfsResult<()> {
+    let rest = len.next_multiple_of(BLOCK_SIZE) - len;
+    out.write_all(&[0; BLOCK_SIZE][..rest])
+}
+
+/// Split the path into the prefix and the name fields of ustar, None if it
+/// can't be split at a '/'.
+fn split_path(path: &str) -> Option<(&str, &str)> {
+    if path.len() <= Header::NAME.1 {
+        return Some(("", path));
+    }
+    let (prefix, name) = path
+        .match_indices('/')
+        .map(|(at, _)| (&path[..at], &path[at + 1..]))
+        .find(|(prefix, name)| prefix.len() <= Header::PREFIX.1 && name.len() <= Header::NAME.1)?;
+    (!name.is_empty()).then_some((prefix, name))
+}
+
+/// The entry of a stream being written.
+struct Entry<'a> {
+    path: &'a str,
+    typeflag: u8,
+    stat: &'a Stat,
+    size: u64,
+    link: Option<&'a str>,
+}
+
+/// Write the header of the entry, with a pax header before it for the
+/// fields which don't fit.
+fn write_header(out: &mut dyn Write, entry: &Entry) -> VfsResult<()> {
+    let stat = entry.stat;
+    let mut header = Header::new(entry.typeflag);
+    let mut pax = String::new();
+    match split_path(entry.path) {
+        Some((prefix, name)) => {
+            header.set_bytes(Header::PREFIX, prefix.as_bytes());
+            header.set_bytes(Header::NAME, name.as_bytes());
+        }
+        None => {
+            pax.push_str(&pax_record("path", entry.path));
+            let name = entry.path.as_bytes();
+            header.set_bytes(Header::NAME, &name[name.len() - Header::NAME.1..]);
+        }
+    }
+    if let Some(link) = entry.link
+        && !header.set_bytes(Header::LINKNAME, link.as_bytes())
+    {
+        pax.push_str(&pax_record("linkpath", link));
+    }
+    let perm = match stat.mode.bits() & 0o7777 {
+        // the filesystems which don't keep the permissions report none.
+        0 => match entry.typeflag {
+            DIRECTORY => 0o755,
+            SYMLINK => 0o777,
+            _ => 0o644,
+        },
+        perm => perm,
+    };
+    header.set_octal(Header::MODE, perm as u64);
+    let mtime = stat.mtime.sec.max(0) as u64;
+    let numbers = [
+        (Header::UID, "uid", stat.uid as u64),
+        (Header::GID, "gid", stat.gid as u64),
+        (Header::SIZE, "size", entry.size),
+        (Header::MTIME, "mtime", mtime),
+    ];
+    for (field, key, value) in numbers {
+        if !header.set_octal(field, value) {
+            pax.push_str(&pax_record(key, &value.to_string()));
+        }
+    }
+    if matches!(entry.typeflag, CHAR | BLOCK) {
+        let (major, minor) = split_dev(stat.rdev as u64);
+        if !header.set_octal(Header::DEVMAJOR, major as u64)
+            || !header.set_octal(Header::DEVMINOR, minor as u64)
+        {
+            return Err(VfsError::InvalidInput);
+        }
+    } else {
+        header.set_octal(Header::DEVMAJOR, 0);
+        header.set_octal(Header::DEVMINOR, 0);
+    }
+    if !pax.is_empty() {
+        let mut pax_header = Header::new(PAX);
+        let name = format!("PaxHeaders/{}", entry.path.rsplit('/').next().unwrap_or(""));
+        let name = name.as_bytes();
+        pax_header.set_bytes(Header::NAME, &name[..name.len().min(Header::NAME.1)]);
+        pax_header.set_octal(Header::MODE, 0o644);
+        pax_header.set_octal(Header::UID, 0);
+        pax_header.set_octal(Header::GID, 0);
+        pax_header.set_octal(Header::SIZE, pax.len() as u64);
+        pax_header.set_octal(Header::MTIME, mtime.min(0o77777777777));
+        pax_header.seal();
+        out.write_all(&pax_header.0)?;
+        out.write_all(pax.as_bytes())?;
+        pad(out, pax.len())?;
+    }
+    header.seal();
+    out.write_all(&header.0)
+}
+
+/// Write the size bytes of the file, the bytes of a file shrunk since its
+/// stat are zeros.
+fn write_data(out: &mut dyn Write, file: &Arc<dyn INodeInterface>, size: u64) -> VfsResult<()> {
+    let mut reader = InodeReader::with_capacity(CHUNK, file.clone());
+    let mut buf = vec![0; CHUNK];
+    let mut offset = 0;
+    while offset < size {
+        let len = (size - offset).min(CHUNK as u64) as usize;
+        let read = match reader.read(&mut buf[..len])? {
+            0 => {
+                buf[..len].fill(0);
+                len
+            }
+            read => read,
+        };
+        out.write_all(&buf[..read])?;
+        offset += read as u64;
+    }
+    pad(out, size as usize)
+}
+
+/// Write the tree below dir to out as a tar stream, with the paths
+/// relative to dir. The symbol links aren't followed, the sockets are
+/// skipped like GNU tar does. The first error stops the archive, the
+/// entries before it are written.
+pub fn write_tar(dir: Arc<dyn INodeInterface>, out: &mut dyn Write) -> VfsResult<()> {
+    // the first paths of the files linked more than once.
+    let mut linked: BTreeMap<(u64, usize), String> = BTreeMap::new();
+    for entry in WalkDir::new(dir) {
+        let entry = entry.map_err(|x| VfsError::from(x.error))?;
+        let mut stat = Stat::default();
+        entry.inode.stat(&mut stat)?;
+        let (typeflag, path) = match entry.file_type {
+            FileType::Directory => (DIRECTORY, format!("{}/", entry.path)),
+            FileType::File => (REGULAR, entry.path.clone()),
+            FileType::Link => (SYMLINK, entry.path.clone()),
+            FileType::Socket => continue,
+            FileType::Device => match stat.mode.bits() & S_IFMT {
+                S_IFCHR => (CHAR, entry.path.clone()),
+                S_IFBLK => (BLOCK, entry.path.clone()),
+                S_IFIFO => (FIFO, entry.path.clone()),
+                _ => continue,
+            },
+        };
+        let (first, target);
+        let mut header = Entry {
+            path: &path,
+            typeflag,
+            stat: &stat,
+            size: 0,
+            link: None,
+        };
+        if typeflag != DIRECTORY
+            && stat.nlink > 1
+            && let Some(id) = identity(entry.inode.as_ref())
+        {
+            match linked.get(&id) {
+                Some(path) => {
+                    first = path.clone();
+                    header.typeflag = HARD_LINK;
+                    header.link = Some(&first);
+                    write_header(out, &header)?;
+                    continue;
+                }
+                None => {
+                    linked.insert(id, entry.path.clone());
+                }
+            }
+        }
+        match typeflag {
+            REGULAR => {
+                header.size = stat.size as u64;
+                write_header(out, &header)?;
+                write_data(out, &entry.inode, stat.size as u64)?;
+            }
+            SYMLINK => {
+                target = entry.inode.resolve_link()?;
+                header.link = Some(&target);
+                write_header(out, &header)?;
+            }
+            _ => write_header(out, &header)?,
+        }
+    }
+    // the end of the archive, two blocks of zeros.
+    out.write_all(&[0; 2 * BLOCK_SIZE])
+}
+
+/// Fill buf from input, false at the end of the stream before any byte.
+fn read_block(input: &mut dyn Read, buf: &mut [u8]) -> VfsResult<bool> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        match input.read(&mut buf[pos..])? {
+            0 if pos == 0 => return Ok(false),
+            // the stream ends within a block.
+            0 => return Err(VfsError::InvalidData),
+            n => pos += n,
+        }
+    }
+    Ok(true)
+}
+
+/// Pass f the len bytes of the data of an entry by chunks, and skip its
+/// padding.
+fn read_data(
+    input: &mut dyn Read,
+    len: u64,
+    mut f: impl FnMut(&[u8]) -> VfsResult<()>,
+) -> VfsResult<()> {
+    let padded = len.next_multiple_of(BLOCK_SIZE as u64);
+    let mut buf = vec![0; CHUNK];
+    let mut pos = 0;
+    while pos < padded {
+        let chunk = (padded - pos).min(CHUNK as u64) as usize;
+        if !read_block(input, &mut buf[..chunk])? {
+            return Err(VfsError::InvalidData);
+        }
+        let data = (len.saturating_sub(pos) as usize).min(chunk);
+        f(&buf[..data])?;
+        pos += chunk as u64;
+    }
+    Ok(())
+}
+
+/// The data of an entry, for the pax headers and the GNU long names.
+fn read_small(input: &mut dyn Read, len: u64) -> VfsResult<Vec<u8>> {
+    // the names and the records of a header are small, a larger one is a
+    // corrupted stream.
+    if len > CHUNK as u64 {
+        return Err(VfsError::InvalidData);
+    }
+    let mut data = Vec::new();
+    read_data(input, len, |x| {
+        data.extend_from_slice(x);
+        Ok(())
+    })?;
+    Ok(data)
+}
+
+/// Parse the pax records into the map, the later ones replace the earlier.
+fn parse_pax(data: &[u8], pax: &mut BTreeMap<String, String>) -> VfsResult<()> {
+    let mut rest = data;
+    while !rest.is_empty() {
+        let space = rest
+            .iter()
+            .position(|&b| b == b' ')
+            .ok_or(VfsError::InvalidData)?;
+        let len: usize = core::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .ok_or(VfsError::InvalidData)?;
+        if len <= space + 1 || len > rest.len() || rest[len - 1] != b'\n' {
+            return Err(VfsError::InvalidData);
+        }
+        let record =
+            core::str::from_utf8(&rest[space + 1..len - 1]).map_err(|_| VfsError::InvalidData)?;
+        let (key, value) = record.split_once('=').ok_or(VfsError::InvalidData)?;
+        pax.insert(key.to_string(), value.to_string());
+        rest = &rest[len..];
+    }
+    Ok(())
+}
+
+/// The names of the path below the directory of the extraction, "." and
+/// the leading '/' are dropped. A ".." would escape the directory, it
+/// fails with InvalidInput.
+fn path_names(path: &str) -> VfsResult<Vec<&str>> {
+    let names: Vec<&str> = path
+        .split('/')
+        .filter(|x| !x.is_empty() && *x != ".")
+        .collect();
+    match names.contains(&"..") {
+        true => Err(VfsError::InvalidInput),
+        false => Ok(names),
+    }
+}
+
+/// Look up the names from dir, the missing directories are made with
+/// make.
+fn walk_names(
+    dir: &Arc<dyn INodeInterface>,
+    names: &[&str],
+    make: bool,
+) -> VfsResult<Arc<dyn INodeInterface>> {
+    let mut node = dir.clone();
+    for name in names {
+        node = match node.lookup(name) {
+            Err(VfsError::FileNotFound) if make => node.mkdir(name)?,
+            r => r?,
+        };
+    }
+    Ok(node)
+}
+
+/// The names from the extraction directory of the target of the symbol
+/// link in parents, None if it's absolute or out of the extraction.
+fn link_target_names<'a>(parents: &[&'a str], link: &'a str) -> Option<Vec<&'a str>> {
+    if link.starts_with('/') {
+        return None;
+    }
+    let mut names = parents.to_vec();
+    for name in link.split('/').filter(|x| !x.is_empty() && *x != ".") {
+        match name {
+            ".." => {
+                names.pop()?;
+            }
+            _ => names.push(name),
+        }
+    }
+    Some(names)
+}
+
+/// The file name of parent truncated, or made if it's missing.
+fn replace_file(
+    parent: &Arc<dyn INodeInterface>,
+    name: &str,
+) -> VfsResult<Arc<dyn INodeInterface>> {
+    match parent.lookup(name) {
+        Ok(file) => {
+            file.truncate(0)?;
+            Ok(file)
+        }
+        Err(VfsError::FileNotFound) => parent.touch(name),
+        Err(err) => Err(err),
+    }
+}
+
+/// Ignore NotSupported, the attributes a filesystem doesn't keep.
+fn ignore_unsupported(r: VfsResult<()>) -> VfsResult<()> {
+    match r {
+        Err(VfsError::NotSupported) => Ok(()),
+        r => r,
+    }
+}
+
+/// Make the entries of the tar stream of input in dir, the directories in
+/// the paths are made when missing and the existing files are replaced.
+/// The owners and the mtimes are set where the filesystem keeps them, the
+/// links are copied where it has none, see the module. The entries of an
+/// unknown type are skipped, a path with ".." or a hard link to a missing
+/// path fails with InvalidInput and a corrupted stream with InvalidData.
+/// The first error stops the extraction, the entries before it are made.
+pub fn extract_tar(input: &mut dyn Read, dir: Arc<dyn INodeInterface>) -> VfsResult<()> {
+    let mut header = Header([0; BLOCK_SIZE]);
+    let mut global: BTreeMap<String, String> = BTreeMap::new();
+    let mut local: BTreeMap<String, String> = BTreeMap::new();
+    let (mut long_name, mut long_link) = (None, None);
+    // the directories get their mtimes at the end, after their entries.
+    let mut dirs: Vec<(Arc<dyn INodeInterface>, TimeSpec)> = Vec::new();
+    let caps = capabilities::of_node(&dir);
+    let ownership = caps.contains(FsCapabilities::OWNERSHIP);
+    let chown = |node: &Arc<dyn INodeInterface>, uid, gid| match ownership {
+        true => ignore_unsupported(owner::chown(node, uid, gid).map_err(VfsError::from)),
+        false => Ok(()),
+    };
+    loop {
+        if !read_block(input, &mut header.0)? || header.is_zero() {
+            break;
+        }
+        if header.number(Header::CHKSUM)? != header.checksum() {
+            return Err(VfsError::InvalidData);
+        }
+        let typeflag = header.0[Header::TYPEFLAG];
+        let mut size = header.number(Header::SIZE)?;
+        match typeflag {
+            PAX => {
+                parse_pax(&read_small(input, size)?, &mut local)?;
+                continue;
+            }
+            PAX_GLOBAL => {
+                parse_pax(&read_small(input, size)?, &mut global)?;
+                continue;
+            }
+            GNU_LONG_NAME | GNU_LONG_LINK => {
+                let mut data = read_small(input, size)?;
+                if let Some(len) = data.iter().position(|&b| b == 0) {
+                    data.truncate(len);
+                }
+                let name = String::from_utf8_lossy(&data).into_owned();
+                match typeflag {
+                    GNU_LONG_NAME => long_name = Some(name),
+                    _ => long_link = Some(name),
+                }
+                continue;
+            }
+            _ => {}
+        }
+        let mut pax = global.clone();
+        pax.append(&mut local);
+        let number = |key: &str, field| match pax.get(key) {
+            // the times of pax may have a fraction.
+            Some(value) => value
+                .split('.')
+                .next()
+                .and_then(|x| x.parse().ok())
+                .ok_or(VfsError::InvalidData),
+            None => header.number(field),
+        };
+        size = number("size", Header::SIZE)?;
+        let uid = number("uid", Header::UID)? as u32;
+        let gid = number("gid", Header::GID)? as u32;
+        let mtime = TimeSpec {
+            sec: number("mtime", Header::MTIME)? as _,
+            nsec: 0,
+        };
+        let path = match (pax.get("path"), long_name.take()) {
+            (Some(path), _) => path.clone(),
+            (None, Some(path)) => path,
+            (None, None) => {
+                let (prefix, name) = (header.string(Header::PREFIX), header.string(Header::NAME));
+                match prefix.is_empty() {
+                    true => name,
+                    false => format!("{}/{}", prefix, name),
+                }
+            }
+        };
+        let link = match (pax.get("linkpath"), long_link.take()) {
+            (Some(link), _) => link.clone(),
+            (None, Some(link)) => link,
+            (None, None) => header.string(Header::LINKNAME),
+        };
+        let names = path_names(&path)?;
+        let Some((name, parents)) = names.split_last() else {
+            // the directory of the extraction itself, "./".
+            read_data(input, size, |_| Ok(()))?;
+            continue;
+        };
+        let parent = walk_names(&dir, parents, true)?;
+        let perm = header.number(Header::MODE)? as u32 & 0o7777;
+        let node = match typeflag {
+            REGULAR | CONTIGUOUS | 0 => {
+                let file = replace_file(&parent, name)?;
+                let mut writer = InodeWriter::with_capacity(CHUNK, file.clone());
+                read_data(input, size, |x| writer.write_all(x))?;
+                writer.flush()?;
+                file
+            }
+            DIRECTORY => {
+                read_data(input, size, |_| Ok(()))?;
+                let dir = match parent.mkdir(name) {
+                    Err(VfsError::AlreadyExists) => parent.lookup(name)?,
+                    r => r?,
+                };
+                dirs.push((dir.clone(), mtime));
+                chown(&dir, uid, gid)?;
+                continue;
+            }
+            HARD_LINK => {
+                read_data(input, size, |_| Ok(()))?;
+                let target = match walk_names(&dir, &path_names(&link)?, false) {
+                    Err(VfsError::FileNotFound) => return Err(VfsError::InvalidInput),
+                    r => r?,
+                };
+                if caps.contains(FsCapabilities::HARD_LINKS) {
+                    parent.link(name, target)?;
+                    continue;
+                }
+                let file = replace_file(&parent, name)?;
+                copy_file_data(&target, &file)?;
+                file
+            }
+            SYMLINK if caps.contains(FsCapabilities::SYMLINKS) => {
+                read_data(input, size, |_| Ok(()))?;
+                parent.sym_link(name, &link)?;
+                continue;
+            }
+            SYMLINK => {
+                read_data(input, size, |_| Ok(()))?;
+                let target = link_target_names(parents, &link)
+                    .and_then(|x| walk_names(&dir, &x, false).ok())
+                    .filter(|x| {
+                        x.metadata()
+                            .is_ok_and(|x| matches!(x.file_type, FileType::File))
+                    });
+                let Some(target) = target else {
+                    log::warn!("skip the symbol link {} to {}, no symbol links", path, link);
+                    continue;
+                };
+                let file = replace_file(&parent, name)?;
+                copy_file_data(&target, &file)?;
+                file
+            }
+            CHAR | BLOCK | FIFO => {
+                read_data(input, size, |_| Ok(()))?;
+                let major = header.number(Header::DEVMAJOR)? as u32;
+                let minor = header.number(Header::DEVMINOR)? as u32;
+                let kind = match typeflag {
+                    CHAR => S_IFCHR,
+                    BLOCK => S_IFBLK,
+                    _ => S_IFIFO,
+                };
+                mknod::mknod(&parent, name, kind | perm, make_dev(major, minor))?
+            }
+            _ => {
+                log::warn!(
+                    "skip the tar entry {} of the type {}",
+                    path,
+                    typeflag as char
+                );
+                read_data(input, size, |_| Ok(()))?;
+                continue;
+            }
+        };
+        chown(&node, uid, gid)?;
+        ignore_unsupported(node.utimes(&mut [mtime, mtime]))?;
+    }
+    for (dir, mtime) in dirs.into_iter().rev() {
+        ignore_unsupported(dir.utimes(&mut [mtime, mtime]))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,124 @@
+// The access times updated by the reads, with the policies of mount(2):
+// - relatime, the default, updates the access time only if it isn't after
+//   the modification time or the change time, or it's a day old, so ls
+//   after a write sees the read and the reads in a row don't write the
+//   inode each.
+// - strictatime updates it on every read.
+// - noatime never updates it.
+// The policy of a mount is in its MountFlags, see mounts.rs, the reads of
+// the FileHandles decide here if the access time moves. The filesystems
+// keep the time, a node which has one hands out an AccessTime by its
+// FsNode, like the nodes of statx.rs.
+// TODO: ramfs is another crate, its reads don't update its access times.
+
+use alloc::sync::Arc;
+use vfscore::{INodeInterface, Stat, TimeSpec, VfsResult};
+
+use crate::mounts::{self, MountFlags};
+use crate::node;
+
+/// The seconds after which relatime updates the access time anyway.
+pub const RELATIME_STALE: u64 = 24 * 60 * 60;
+
+/// When a read updates the access time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AtimePolicy {
+    Noatime,
+    #[default]
+    Relatime,
+    Strictatime,
+}
+
+fn key(time: TimeSpec) -> (u64, u64) {
+    (time.sec as u64, time.nsec as u64)
+}
+
+impl AtimePolicy {
+    /// The policy of the flags, noatime wins over strictatime and relatime
+    /// is the default, like Linux.
+    pub fn from_flags(flags: MountFlags) -> Self {
+        if flags.contains(MountFlags::NOATIME) {
+            Self::Noatime
+        } else if flags.contains(MountFlags::STRICTATIME) {
+            Self::Strictatime
+        } else {
+            Self::Relatime
+        }
+    }
+
+    pub fn flags(self) -> MountFlags {
+        match self {
+            Self::Noatime => MountFlags::NOATIME,
+            Self::Relatime => MountFlags::RELATIME,
+            Self::Strictatime => MountFlags::STRICTATIME,
+        }
+    }
+
+    /// The policy of an option of mount, None for the other options.
+    pub fn from_option(option: &str) -> Option<Self> {
+        match option {
+            "noatime" => Some(Self::Noatime),
+            "relatime" => Some(Self::Relatime),
+            "strictatime" => Some(Self::Strictatime),
+            _ => None,
+        }
+    }
+
+    /// Check if a read at now moves the access time of a node with the
+    /// times.
+    pub fn needs_update(
+        self,
+        atime: TimeSpec,
+        mtime: TimeSpec,
+        ctime: TimeSpec,
+        now: TimeSpec,
+    ) -> bool {
+        match self {
+            Self::Noatime => false,
+            Self::Strictatime => key(atime) != key(now),
+            Self::Relatime => {
+                key(atime) <= key(mtime)
+                    || key(atime) <= key(ctime)
+                    || key(now).0.saturating_sub(key(atime).0) >= RELATIME_STALE
+            }
+        }
+    }
+}
+
+/// The access time of a node, kept by its filesystem.
+pub trait AccessTime: Send + Sync {
+    /// The time of the clock of the filesystem.
+    fn now(&self) -> TimeSpec;
+
+    /// Set the access time alone, the change time stays. A filesystem
+    /// which can't write now, read-only or frozen, may skip it.
+    fn set_atime(&self, time: TimeSpec) -> VfsResult<()>;
+}
+
+/// The file was read, update its access time by the policy of its mount.
+/// The nodes without an AccessTime and the read-only mounts keep their
+/// times, a failed update is logged, the read succeeded anyway.
+pub fn accessed(file: &Arc<dyn INodeInterface>) {
+    let Some(node) = node::fs_node(file.as_ref()).and_then(|x| x.as_atime()) else {
+        return;
+    };
+    let mut stat = Stat::default();
+    if file.stat(&mut stat).is_err() {
+        return;
+    }
+    let flags = mounts::dev_flags(stat.dev as usize).unwrap_or_default();
+    if flags.contains(MountFlags::RDONLY) {
+        return;
+    }
+    let now = node.now();
+    let policy = AtimePolicy::from_flags(flags);
+    if policy.needs_update(stat.atime, stat.mtime, stat.ctime, now)
+        && let Err(err) = node.set_atime(now)
+    {
+        log::warn!(
+            "update the access time of dev {} failed: {:?}",
+            stat.dev,
+            err
+        );
+    }
+}
@@ -0,0 +1,47 @@
+// The batches of operations, the hint of the bulk creations of ops.rs
+// like the extraction of a rootfs: a filesystem may keep the metadata
+// written between begin_batch and end_batch in memory and write it back
+// once at the end, so the bitmaps, the group descriptors and the inode
+// tables touched by every file of the batch reach the device once. The
+// nodes which can batch implement BatchINode and hand it out by their
+// FsNode, like the nodes of owner.rs. The batches nest, the last
+// end_batch writes back. The operations of a batch are done when they
+// return, only their writeback waits: a crash in a batch loses the
+// operations not written back, never a part of one, and a sync writes
+// them back like outside a batch. The nodes without a BatchINode ignore
+// the hints.
+
+use alloc::sync::Arc;
+use vfscore::{INodeInterface, VfsResult};
+
+use crate::node;
+
+pub trait BatchINode: Send + Sync {
+    /// Start deferring the writeback of the filesystem of the node.
+    fn begin_batch(&self);
+
+    /// End the batch started by begin_batch, the last one writes back
+    /// what was deferred.
+    fn end_batch(&self) -> VfsResult<()>;
+}
+
+fn node_of(file: &Arc<dyn INodeInterface>) -> Option<&dyn BatchINode> {
+    node::fs_node(file.as_ref())?.as_batch()
+}
+
+/// Start a batch on the filesystem of the file, see the module. Every
+/// begin_batch needs its end_batch, on the same file.
+pub fn begin_batch(file: &Arc<dyn INodeInterface>) {
+    if let Some(node) = node_of(file) {
+        node.begin_batch();
+    }
+}
+
+/// End the batch started on the file, the errors are those of the
+/// writeback.
+pub fn end_batch(file: &Arc<dyn INodeInterface>) -> VfsResult<()> {
+    match node_of(file) {
+        Some(node) => node.end_batch(),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,659 @@
+// The microbenchmarks of the filesystems, for the work on the caches, the
+// locks and the allocations. A Scenario is a workload with its sizes: the
+// sequential and random reads and writes of a file, a storm of creates
+// and removes, the resolution of a deep path and the listing of a large
+// directory. A Config is a filesystem to run them on, any FileSystem, an
+// ext4 on a MockDisk with the options of its builder, so two configs
+// differing by an option compare it A/B. Every scenario works in its own
+// directory of the filesystem, its files are made before the measurement
+// and removed after it.
+//
+// A Measurement counts the operations of the scenario, the requests of
+// the MockDisk and the bytes they moved, and the time: the wall clock of
+// the host and the clock of the MockDisk, the delay of the config for
+// each request, so a slow device is measured without sleeping. The
+// results print as CSV for the scripts and as a summary for the people.
+// The cached scenarios have baselines of their device requests, see
+// check_baselines. The benches of tests/bench.rs run them, see there.
+
+use std::time::Instant;
+
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::fmt::Write;
+use vfscore::{FileSystem, INodeInterface, OpenFlags, VfsError, VfsResult};
+
+use crate::cache::{self, PAGE_SIZE};
+use crate::dentry::{dentry_open_at, DentryNode, ResolveContext};
+use crate::model::Rng;
+use crate::ops::remove_dir_all;
+use crate::testing::{MockDisk, MockOp};
+
+/// The size of the MockDisk of the ext4 configs.
+#[cfg(root_fs = "ext4_rs")]
+pub const DISK_SIZE: usize = 16 << 20;
+
+/// A workload, the sizes are in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scenario {
+    /// Read the file from its start to its end by io_size. A warm read
+    /// reads the file once before the measurement, from the page cache
+    /// then, a cold one drops the caches.
+    SeqRead {
+        file_size: usize,
+        io_size: usize,
+        warm: bool,
+    },
+    /// Read io_size at ops random offsets aligned to io_size, warm like
+    /// SeqRead.
+    RandRead {
+        file_size: usize,
+        io_size: usize,
+        ops: usize,
+        warm: bool,
+    },
+    /// Write a new file from its start to its end by io_size.
+    SeqWrite { file_size: usize, io_size: usize },
+    /// Write io_size at ops random offsets of a written file.
+    RandWrite {
+        file_size: usize,
+        io_size: usize,
+        ops: usize,
+    },
+    /// Create files empty files and remove them, rounds times.
+    CreateDelete { files: usize, rounds: usize },
+    /// Resolve the path of depth nested directories lookups times,
+    /// through the dentries of the path.
+    DeepPath { depth: usize, lookups: usize },
+    /// List the directory of entries files passes times.
+    Readdir { entries: usize, passes: usize },
+}
+
+/// The seed of the random offsets, the runs are repeatable.
+const SEED: u64 = 0xbe7c;
+
+fn pages(bytes: usize) -> usize {
+    bytes.div_ceil(PAGE_SIZE)
+}
+
+impl Scenario {
+    /// The name in the results, with the sizes which tell the runs of a
+    /// kind apart, like seq_read/65536/cold.
+    pub fn name(&self) -> String {
+        let warmth = |warm: bool| match warm {
+            true => "warm",
+            false => "cold",
+        };
+        match *self {
+            Self::SeqRead { io_size, warm, .. } => {
+                format!("seq_read/{}/{}", io_size, warmth(warm))
+            }
+            Self::RandRead { io_size, warm, .. } => {
+                format!("rand_read/{}/{}", io_size, warmth(warm))
+            }
+            Self::SeqWrite { io_size, .. } => format!("seq_write/{}", io_size),
+            Self::RandWrite { io_size, .. } => format!("rand_write/{}", io_size),
+            Self::CreateDelete { files, .. } => format!("create_delete/{}", files),
+            Self::DeepPath { depth, .. } => format!("deep_path/{}", depth),
+            Self::Readdir { entries, .. } => format!("readdir/{}", entries),
+        }
+    }
+
+    /// The operations measured, the reads, the writes, the creates and
+    /// removes, the resolutions and the listings.
+    pub fn ops(&self) -> usize {
+        match *self {
+            Self::SeqRead {
+                file_size, io_size, ..
+            }
+            | Self::SeqWrite { file_size, io_size } => file_size.div_ceil(io_size),
+            Self::RandRead { ops, .. } | Self::RandWrite { ops, .. } => ops,
+            Self::CreateDelete { files, rounds } => 2 * files * rounds,
+            Self::DeepPath { lookups, .. } => lookups,
+            Self::Readdir { passes, .. } => passes,
+        }
+    }
+
+    /// The most device requests of a run with the page cache, the
+    /// baselines of check_baselines. They're a few times what the shims
+    /// make, loose enough not to flake on a change of the metadata they
+    /// read and tight enough to catch a 10x regression. A page of a cold
+    /// read is read once, a warm read and the dentries of a resolved path
+    /// read nearly nothing. None for the scenarios whose requests aren't
+    /// cached.
+    pub fn max_cached_requests(&self) -> Option<usize> {
+        match *self {
+            Self::SeqRead {
+                file_size,
+                warm: false,
+                ..
+            }
+            | Self::RandRead {
+                file_size,
+                warm: false,
+                ..
+            } => Some(4 * pages(file_size) + 64),
+            Self::SeqRead { warm: true, .. } | Self::RandRead { warm: true, .. } => Some(64),
+            Self::DeepPath { depth, .. } => Some(8 * depth + 64),
+            _ => None,
+        }
+    }
+}
+
+/// The scenarios of the benches, sized to run in seconds on a MockDisk.
+pub fn standard() -> Vec<Scenario> {
+    const MIB: usize = 1 << 20;
+    vec![
+        Scenario::SeqRead {
+            file_size: MIB,
+            io_size: 4096,
+            warm: false,
+        },
+        Scenario::SeqRead {
+            file_size: MIB,
+            io_size: 65536,
+            warm: false,
+        },
+        Scenario::SeqRead {
+            file_size: MIB,
+            io_size: 4096,
+            warm: true,
+        },
+        Scenario::RandRead {
+            file_size: MIB,
+            io_size: 4096,
+            ops: 512,
+            warm: false,
+        },
+        Scenario::RandRead {
+            file_size: MIB,
+            io_size: 4096,
+            ops: 512,
+            warm: true,
+        },
+        Scenario::SeqWrite {
+            file_size: MIB,
+            io_size: 4096,
+        },
+        Scenario::SeqWrite {
+            file_size: MIB,
+            io_size: 65536,
+        },
+        Scenario::RandWrite {
+            file_size: MIB,
+            io_size: 4096,
+            ops: 256,
+        },
+        Scenario::CreateDelete {
+            files: 200,
+            rounds: 2,
+        },
+        Scenario::DeepPath {
+            depth: 16,
+            lookups: 1000,
+        },
+        Scenario::Readdir {
+            entries: 500,
+            passes: 20,
+        },
+    ]
+}
+
+/// A filesystem of a Config and the MockDisk under it, None for a
+/// filesystem in memory.
+pub struct Mounted {
+    pub fs: Arc<dyn FileSystem>,
+    pub disk: Option<Arc<MockDisk>>,
+}
+
+type Mount = Box<dyn Fn(u64) -> VfsResult<Mounted>>;
+
+/// A filesystem to run the scenarios on, see the module.
+pub struct Config {
+    /// The name in the results, like ext4/readahead=32.
+    pub name: String,
+    /// The nanoseconds of a request on the clock of the MockDisk.
+    pub delay: u64,
+    /// The budget of the page cache during the runs, 0 turns it off. None
+    /// keeps the one of the caller.
+    pub page_cache_bytes: Option<usize>,
+    /// Mount the filesystem on a MockDisk with the delay.
+    mount: Mount,
+}
+
+impl Config {
+    pub fn new(name: &str, mount: impl Fn(u64) -> VfsResult<Mounted> + 'static) -> Self {
+        Self {
+            name: name.to_string(),
+            delay: 0,
+            page_cache_bytes: None,
+            mount: Box::new(mount),
+        }
+    }
+
+    pub fn delay(mut self, nanos: u64) -> Self {
+        self.delay = nanos;
+        self
+    }
+
+    pub fn page_cache_bytes(mut self, bytes: usize) -> Self {
+        self.page_cache_bytes = Some(bytes);
+        self
+    }
+
+    /// The page cache is on during the runs, the baselines hold.
+    pub fn is_cached(&self) -> bool {
+        self.page_cache_bytes != Some(0)
+    }
+
+    /// A tmpfs, in memory.
+    pub fn tmpfs() -> Self {
+        Self::new("tmpfs", |_| {
+            Ok(Mounted {
+                fs: crate::tmpfs::TmpFs::new(),
+                disk: None,
+            })
+        })
+    }
+
+    /// An ext4 of DISK_SIZE made by ext4_mkfs, mounted with the options
+    /// set on the builder by options.
+    #[cfg(root_fs = "ext4_rs")]
+    pub fn ext4(
+        name: &str,
+        options: impl Fn(crate::Ext4Builder) -> crate::Ext4Builder + 'static,
+    ) -> Self {
+        Self::new(name, move |delay| {
+            let disk = Arc::new(MockDisk::from_image(ext4_image()?, 512).with_delay(delay));
+            let builder = crate::Ext4FileSystem::builder_from_device(disk.clone());
+            Ok(Mounted {
+                fs: options(builder).mount()?,
+                disk: Some(disk),
+            })
+        })
+    }
+}
+
+/// The formatted image of the ext4 configs, made once.
+#[cfg(root_fs = "ext4_rs")]
+fn ext4_image() -> VfsResult<Vec<u8>> {
+    use std::sync::OnceLock;
+
+    use crate::ext4_mkfs::{format, Options};
+
+    static IMAGE: OnceLock<Vec<u8>> = OnceLock::new();
+    if let Some(image) = IMAGE.get() {
+        return Ok(image.clone());
+    }
+    let options = Options::default();
+    let mut image = vec![0; DISK_SIZE];
+    format(DISK_SIZE as u64, &options, |block, data| {
+        let offset = block as usize * options.block_size;
+        image[offset..offset + data.len()].copy_from_slice(data);
+    })?;
+    Ok(IMAGE.get_or_init(|| image).clone())
+}
+
+/// A run of a scenario on a config.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    pub config: String,
+    pub scenario: Scenario,
+    /// The page cache was on, see Config::is_cached.
+    pub cached: bool,
+    pub ops: usize,
+    /// The nanoseconds of the host.
+    pub wall_nanos: u64,
+    /// The nanoseconds of the requests on the clock of the MockDisk.
+    pub device_nanos: u64,
+    pub reads: usize,
+    pub writes: usize,
+    pub flushes: usize,
+    pub bytes_read: usize,
+    pub bytes_written: usize,
+}
+
+impl Measurement {
+    pub fn requests(&self) -> usize {
+        self.reads + self.writes + self.flushes
+    }
+
+    /// The operations per second of the time of the host and the device.
+    pub fn ops_per_sec(&self) -> f64 {
+        let nanos = (self.wall_nanos + self.device_nanos).max(1);
+        self.ops as f64 * 1e9 / nanos as f64
+    }
+}
+
+/// The writes of the scenarios, a pattern so the blocks aren't zeros.
+fn fill(len: usize, seed: usize) -> Vec<u8> {
+    (0..len).map(|x| (x / 7 + seed) as u8).collect()
+}
+
+/// Write the file of size by chunks of a megabyte at most.
+fn write_file(file: &Arc<dyn INodeInterface>, size: usize) -> VfsResult<()> {
+    let chunk = fill(1 << 20, 1);
+    let mut pos = 0;
+    while pos < size {
+        let len = (size - pos).min(chunk.len());
+        pos += file.writeat(pos, &chunk[..len])?;
+    }
+    Ok(())
+}
+
+/// Read the whole file by io_size, return the reads.
+fn read_file(file: &Arc<dyn INodeInterface>, size: usize, io_size: usize) -> VfsResult<usize> {
+    let mut buf = vec![0; io_size];
+    let mut pos = 0;
+    let mut ops = 0;
+    while pos < size {
+        match file.readat(pos, &mut buf)? {
+            0 => break,
+            n => pos += n,
+        }
+        ops += 1;
+    }
+    Ok(ops)
+}
+
+/// The work of a scenario in dir, measured by measure.
+type Work<'a> = Box<dyn FnOnce() -> VfsResult<usize> + 'a>;
+
+/// Make the files of the scenario in dir, return its work.
+fn prepare<'a>(scenario: Scenario, dir: &'a Arc<dyn INodeInterface>) -> VfsResult<Work<'a>> {
+    let mut rng = Rng::new(SEED);
+    let work: Work<'a> = match scenario {
+        Scenario::SeqRead {
+            file_size,
+            io_size,
+            warm,
+        } => {
+            let file = dir.touch("file")?;
+            write_file(&file, file_size)?;
+            if warm {
+                read_file(&file, file_size, io_size)?;
+            }
+            Box::new(move || read_file(&file, file_size, io_size))
+        }
+        Scenario::RandRead {
+            file_size,
+            io_size,
+            ops,
+            warm,
+        } => {
+            let file = dir.touch("file")?;
+            write_file(&file, file_size)?;
+            if warm {
+                read_file(&file, file_size, PAGE_SIZE)?;
+            }
+            Box::new(move || {
+                let mut buf = vec![0; io_size];
+                for _ in 0..ops {
+                    let offset = rng.below(file_size / io_size) * io_size;
+                    file.readat(offset, &mut buf)?;
+                }
+                Ok(ops)
+            })
+        }
+        Scenario::SeqWrite { file_size, io_size } => {
+            let file = dir.touch("file")?;
+            Box::new(move || {
+                let data = fill(io_size, 2);
+                let mut pos = 0;
+                let mut ops = 0;
+                while pos < file_size {
+                    let len = (file_size - pos).min(io_size);
+                    pos += file.writeat(pos, &data[..len])?;
+                    ops += 1;
+                }
+                file.flush()?;
+                Ok(ops)
+            })
+        }
+        Scenario::RandWrite {
+            file_size,
+            io_size,
+            ops,
+        } => {
+            let file = dir.touch("file")?;
+            write_file(&file, file_size)?;
+            Box::new(move || {
+                let data = fill(io_size, 3);
+                for _ in 0..ops {
+                    let offset = rng.below(file_size / io_size) * io_size;
+                    file.writeat(offset, &data)?;
+                }
+                file.flush()?;
+                Ok(ops)
+            })
+        }
+        Scenario::CreateDelete { files, rounds } => Box::new(move || {
+            for _ in 0..rounds {
+                for i in 0..files {
+                    dir.touch(&format!("file-{}", i))?;
+                }
+                for i in 0..files {
+                    dir.remove(&format!("file-{}", i))?;
+                }
+            }
+            Ok(2 * files * rounds)
+        }),
+        Scenario::DeepPath { depth, lookups } => {
+            let mut path = String::new();
+            let mut node = dir.clone();
+            for i in 0..depth {
+                let name = format!("d{}", i);
+                node = node.mkdir(&name)?;
+                write!(path, "/{}", name).map_err(|_| VfsError::InvalidInput)?;
+            }
+            let root = Arc::new(DentryNode::new(String::from("/"), dir.clone(), Weak::new()));
+            let ctx = ResolveContext::with_root(root);
+            Box::new(move || {
+                for _ in 0..lookups {
+                    dentry_open_at(&ctx, &path, OpenFlags::NONE)?;
+                }
+                Ok(lookups)
+            })
+        }
+        Scenario::Readdir { entries, passes } => {
+            for i in 0..entries {
+                dir.touch(&format!("file-name-{}", i))?;
+            }
+            Box::new(move || {
+                for _ in 0..passes {
+                    if dir.read_dir()?.len() < entries {
+                        return Err(VfsError::InvalidData);
+                    }
+                }
+                Ok(passes)
+            })
+        }
+    };
+    Ok(work)
+}
+
+/// Run the scenario in a new directory of the root, it's removed after.
+/// The filesystem is flushed before the work and after it, the requests
+/// of the flushes count.
+fn run_one(
+    config: &Config,
+    mounted: &Mounted,
+    root: &Arc<dyn INodeInterface>,
+    index: usize,
+    scenario: Scenario,
+) -> VfsResult<Measurement> {
+    let name = format!("bench-{}", index);
+    let dir = root.mkdir(&name)?;
+    let work = prepare(scenario, &dir)?;
+    mounted.fs.flush()?;
+    let warm = matches!(
+        scenario,
+        Scenario::SeqRead { warm: true, .. } | Scenario::RandRead { warm: true, .. }
+    );
+    if !warm {
+        cache::drop_caches();
+    }
+    let disk = mounted.disk.as_deref();
+    if let Some(disk) = disk {
+        disk.clear_log();
+    }
+    let device = disk.map_or(0, |x| x.elapsed());
+    let start = Instant::now();
+    let ops = work()?;
+    mounted.fs.flush()?;
+    let wall_nanos = start.elapsed().as_nanos() as u64;
+    let mut measurement = Measurement {
+        config: config.name.clone(),
+        scenario,
+        cached: config.is_cached(),
+        ops,
+        wall_nanos,
+        device_nanos: disk.map_or(0, |x| x.elapsed() - device),
+        reads: 0,
+        writes: 0,
+        flushes: 0,
+        bytes_read: 0,
+        bytes_written: 0,
+    };
+    for request in disk.map(|x| x.log()).unwrap_or_default() {
+        match request.op {
+            MockOp::Read => {
+                measurement.reads += 1;
+                measurement.bytes_read += request.len;
+            }
+            MockOp::Write | MockOp::WriteFua => {
+                measurement.writes += 1;
+                measurement.bytes_written += request.len;
+            }
+            MockOp::Flush => measurement.flushes += 1,
+        }
+    }
+    drop(dir);
+    remove_dir_all(root.clone(), &name)?;
+    Ok(measurement)
+}
+
+/// Run the scenarios on the filesystem of the config, in their order. The
+/// page cache has the budget of the config during the runs. root_dir
+/// takes a static filesystem, so the filesystem is leaked like the one
+/// of testsuite::run.
+pub fn run(config: &Config, scenarios: &[Scenario]) -> VfsResult<Vec<Measurement>> {
+    let mounted: &'static Mounted = Box::leak(Box::new((config.mount)(config.delay)?));
+    let root = mounted.fs.root_dir();
+    let budget = cache::budget();
+    if let Some(bytes) = config.page_cache_bytes {
+        cache::set_budget(bytes);
+    }
+    let results = scenarios
+        .iter()
+        .enumerate()
+        .map(|(i, x)| run_one(config, mounted, &root, i, *x))
+        .collect();
+    cache::set_budget(budget);
+    results
+}
+
+/// Run the scenarios on every config, the results of a scenario follow
+/// one another in the order of the configs.
+pub fn compare(configs: &[Config], scenarios: &[Scenario]) -> VfsResult<Vec<Measurement>> {
+    let mut runs = Vec::new();
+    for config in configs {
+        info!("bench {}", config.name);
+        runs.push(run(config, scenarios)?);
+    }
+    let mut results = Vec::new();
+    for i in 0..scenarios.len() {
+        results.extend(runs.iter().map(|x| x[i].clone()));
+    }
+    Ok(results)
+}
+
+/// The header of csv.
+pub const CSV_HEADER: &str = "config,scenario,ops,wall_ns,device_ns,ops_per_sec,reads,writes,flushes,bytes_read,bytes_written";
+
+/// The results as CSV, a line per measurement after CSV_HEADER.
+pub fn csv(results: &[Measurement]) -> String {
+    let mut out = String::from(CSV_HEADER);
+    out.push('\n');
+    for x in results {
+        writeln!(
+            out,
+            "{},{},{},{},{},{:.1},{},{},{},{},{}",
+            x.config,
+            x.scenario.name(),
+            x.ops,
+            x.wall_nanos,
+            x.device_nanos,
+            x.ops_per_sec(),
+            x.reads,
+            x.writes,
+            x.flushes,
+            x.bytes_read,
+            x.bytes_written
+        )
+        .unwrap();
+    }
+    out
+}
+
+fn kib(bytes: usize) -> String {
+    match bytes {
+        0..=1023 => format!("{}B", bytes),
+        _ => format!("{}K", bytes / 1024),
+    }
+}
+
+/// The results as a table, a scenario by paragraph. The speed of each
+/// config is also the ratio to the first config of the scenario.
+pub fn summary(results: &[Measurement]) -> String {
+    let mut out = String::new();
+    let mut last: Option<&Measurement> = None;
+    for x in results {
+        let first = match last {
+            Some(first) if first.scenario == x.scenario => first,
+            _ => {
+                writeln!(out, "{}:", x.scenario.name()).unwrap();
+                x
+            }
+        };
+        writeln!(
+            out,
+            "  {:<24} {:>12.0} ops/s {:>6.2}x {:>7} requests {:>8} read {:>8} written",
+            x.config,
+            x.ops_per_sec(),
+            x.ops_per_sec() / first.ops_per_sec(),
+            x.requests(),
+            kib(x.bytes_read),
+            kib(x.bytes_written)
+        )
+        .unwrap();
+        last = Some(first);
+    }
+    out
+}
+
+/// Check the device requests of the cached runs against the baselines of
+/// their scenarios, see Scenario::max_cached_requests. return the runs
+/// over them.
+pub fn check_baselines(results: &[Measurement]) -> Vec<String> {
+    results
+        .iter()
+        .filter(|x| x.cached)
+        .filter_map(|x| {
+            let max = x.scenario.max_cached_requests()?;
+            (x.requests() > max).then(|| {
+                format!(
+                    "{} on {}: {} device requests, the baseline is {}",
+                    x.scenario.name(),
+                    x.config,
+                    x.requests(),
+                    max
+                )
+            })
+        })
+        .collect()
+}
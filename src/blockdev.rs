@@ -0,0 +1,556 @@
+// The block devices of the ext4 shim. Ext4FileSystem mounts any
+// BlockDevice of ext4_rs: SectorDevice reads the sectors of a device of
+// sys, RamDevice is in memory for the tests, and Partition, LoopDevice
+// and CachedDevice wrap another device. The filesystem shims added later
+// take a BlockDevice the same way, with new(device_id) as the sugar for
+// a SectorDevice.
+//
+// A read_offset returns READ_SIZE bytes from any byte offset, the bytes
+// beyond the end of the device read as zeros. A write_offset writes any
+// bytes at any offset within the device.
+
+use core::{
+    cmp::min,
+    fmt::{self, Debug},
+};
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+pub use ext4_rs::BlockDevice;
+use vfscore::{INodeInterface, VfsError, VfsResult};
+
+use crate::sys::{get_blk_device, Mutex};
+
+/// The bytes of a read_offset, the block size of ext4_rs.
+pub const READ_SIZE: usize = 4096;
+/// The size of the sectors of the devices of sys.
+pub const SECTOR_SIZE: usize = 512;
+
+/// Allocate a device number for a BlockDevice mounted without a device id.
+pub use crate::statfs::anon_dev;
+
+/// The sectors of the sys device device_id.
+#[derive(Debug)]
+pub struct SectorDevice {
+    device_id: usize,
+}
+
+impl SectorDevice {
+    /// None if there is no such device.
+    pub fn new(device_id: usize) -> Option<Self> {
+        get_blk_device(device_id)?;
+        Some(Self { device_id })
+    }
+
+    pub fn device_id(&self) -> usize {
+        self.device_id
+    }
+}
+
+impl BlockDevice for SectorDevice {
+    fn read_offset(&self, offset: usize) -> Vec<u8> {
+        let device = get_blk_device(self.device_id).unwrap();
+        let start = offset / SECTOR_SIZE;
+        let skip = offset % SECTOR_SIZE;
+        let mut data = vec![0u8; (skip + READ_SIZE).div_ceil(SECTOR_SIZE) * SECTOR_SIZE];
+        device.read_blocks(start, &mut data);
+        data.drain(..skip);
+        data.truncate(READ_SIZE);
+        data
+    }
+
+    fn write_offset(&self, offset: usize, buf: &[u8]) {
+        let device = get_blk_device(self.device_id).unwrap();
+
+        let mut block_id = offset / SECTOR_SIZE;
+        let mut offset_in_block = offset % SECTOR_SIZE;
+        let mut pos = 0;
+
+        while pos < buf.len() {
+            let remain = buf.len() - pos;
+            if offset_in_block == 0 && remain >= SECTOR_SIZE {
+                // write the aligned sectors from the buffer directly.
+                let len = remain / SECTOR_SIZE * SECTOR_SIZE;
+                device.write_blocks(block_id, &buf[pos..pos + len]);
+                block_id += len / SECTOR_SIZE;
+                pos += len;
+                continue;
+            }
+            // read-modify-write the partial sector.
+            let len = min(SECTOR_SIZE - offset_in_block, remain);
+            let mut data = [0u8; SECTOR_SIZE];
+            device.read_blocks(block_id, &mut data);
+            data[offset_in_block..offset_in_block + len].copy_from_slice(&buf[pos..pos + len]);
+            device.write_blocks(block_id, &data);
+
+            block_id += 1;
+            pos += len;
+            offset_in_block = 0;
+        }
+    }
+}
+
+/// A device in memory, for the tests off the target.
+pub struct RamDevice(Mutex<Vec<u8>>);
+
+impl RamDevice {
+    pub fn new(size: usize) -> Self {
+        Self::from_image(vec![0; size])
+    }
+
+    pub fn from_image(image: Vec<u8>) -> Self {
+        Self(Mutex::new(image))
+    }
+
+    /// A copy of the content, to save or compare it.
+    pub fn image(&self) -> Vec<u8> {
+        self.0.lock().clone()
+    }
+}
+
+impl Debug for RamDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RamDevice({} bytes)", self.0.lock().len())
+    }
+}
+
+impl BlockDevice for RamDevice {
+    fn read_offset(&self, offset: usize) -> Vec<u8> {
+        let data = self.0.lock();
+        let mut buf = vec![0; READ_SIZE];
+        if let Some(x) = data.get(offset..) {
+            let n = min(x.len(), READ_SIZE);
+            buf[..n].copy_from_slice(&x[..n]);
+        }
+        buf
+    }
+
+    fn write_offset(&self, offset: usize, buf: &[u8]) {
+        let mut data = self.0.lock();
+        assert!(
+            offset + buf.len() <= data.len(),
+            "write beyond the RamDevice"
+        );
+        data[offset..offset + buf.len()].copy_from_slice(buf);
+    }
+}
+
+/// The bytes [start, start + size) of a device, like a partition of a
+/// disk. The bytes beyond size read as zeros.
+pub struct Partition {
+    device: Arc<dyn BlockDevice + Send + Sync>,
+    start: usize,
+    size: usize,
+}
+
+impl Partition {
+    pub fn new(device: Arc<dyn BlockDevice + Send + Sync>, start: usize, size: usize) -> Self {
+        Self {
+            device,
+            start,
+            size,
+        }
+    }
+}
+
+impl Debug for Partition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Partition")
+            .field("start", &self.start)
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+impl BlockDevice for Partition {
+    fn read_offset(&self, offset: usize) -> Vec<u8> {
+        if offset >= self.size {
+            return vec![0; READ_SIZE];
+        }
+        let mut data = self.device.read_offset(self.start + offset);
+        // the bytes of the next partition aren't ours.
+        data[min(READ_SIZE, self.size - offset)..].fill(0);
+        data
+    }
+
+    fn write_offset(&self, offset: usize, buf: &[u8]) {
+        assert!(
+            offset + buf.len() <= self.size,
+            "write beyond the partition"
+        );
+        self.device.write_offset(self.start + offset, buf);
+    }
+}
+
+/// A device backed by a file of a mounted filesystem, like a loop device.
+/// The failed accesses are logged, BlockDevice can't return them: a failed
+/// read returns zeros and a failed write is lost.
+pub struct LoopDevice {
+    file: Arc<dyn INodeInterface>,
+}
+
+impl LoopDevice {
+    pub fn new(file: Arc<dyn INodeInterface>) -> Self {
+        Self { file }
+    }
+}
+
+impl Debug for LoopDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LoopDevice")
+    }
+}
+
+impl BlockDevice for LoopDevice {
+    fn read_offset(&self, offset: usize) -> Vec<u8> {
+        let mut buf = vec![0; READ_SIZE];
+        let mut pos = 0;
+        // the bytes beyond the end of the file read as zeros.
+        while pos < READ_SIZE {
+            match self.file.readat(offset + pos, &mut buf[pos..]) {
+                Ok(0) => break,
+                Ok(n) => pos += n,
+                Err(err) => {
+                    log::error!("loop device read at {} failed: {:?}", offset + pos, err);
+                    break;
+                }
+            }
+        }
+        buf
+    }
+
+    fn write_offset(&self, offset: usize, buf: &[u8]) {
+        let mut pos = 0;
+        while pos < buf.len() {
+            match self.file.writeat(offset + pos, &buf[pos..]) {
+                Ok(0) => {
+                    log::error!("loop device write at {} wrote nothing", offset + pos);
+                    return;
+                }
+                Ok(n) => pos += n,
+                Err(err) => {
+                    log::error!("loop device write at {} failed: {:?}", offset + pos, err);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// A read cache of a device, the writes go through to the device. The
+/// reads are cached by their offset, at most capacity of them, the least
+/// recently used one is evicted.
+pub struct CachedDevice {
+    device: Arc<dyn BlockDevice + Send + Sync>,
+    capacity: usize,
+    cache: Mutex<ReadCache>,
+}
+
+/// The cached reads by the offset with the stamp of their last use.
+struct ReadCache {
+    reads: BTreeMap<usize, (Vec<u8>, u64)>,
+    tick: u64,
+}
+
+impl CachedDevice {
+    pub fn new(device: Arc<dyn BlockDevice + Send + Sync>, capacity: usize) -> Self {
+        Self {
+            device,
+            capacity,
+            cache: Mutex::new(ReadCache {
+                reads: BTreeMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+
+    /// The number of the cached reads.
+    pub fn cached(&self) -> usize {
+        self.cache.lock().reads.len()
+    }
+}
+
+impl Debug for CachedDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CachedDevice")
+            .field("capacity", &self.capacity)
+            .field("cached", &self.cache.lock().reads.len())
+            .finish()
+    }
+}
+
+impl BlockDevice for CachedDevice {
+    fn read_offset(&self, offset: usize) -> Vec<u8> {
+        let mut cache = self.cache.lock();
+        cache.tick += 1;
+        let tick = cache.tick;
+        if let Some((data, stamp)) = cache.reads.get_mut(&offset) {
+            *stamp = tick;
+            return data.clone();
+        }
+        let data = self.device.read_offset(offset);
+        if self.capacity == 0 {
+            return data;
+        }
+        if cache.reads.len() >= self.capacity
+            && let Some(victim) = cache
+                .reads
+                .iter()
+                .min_by_key(|(_, (_, stamp))| *stamp)
+                .map(|(offset, _)| *offset)
+        {
+            cache.reads.remove(&victim);
+        }
+        cache.reads.insert(offset, (data.clone(), tick));
+        data
+    }
+
+    fn write_offset(&self, offset: usize, buf: &[u8]) {
+        let mut cache = self.cache.lock();
+        // the cached reads overlapping the write are stale.
+        let start = offset.saturating_sub(READ_SIZE - 1);
+        let stale: Vec<usize> = cache
+            .reads
+            .range(start..offset + buf.len())
+            .map(|(x, _)| *x)
+            .collect();
+        for x in stale {
+            cache.reads.remove(&x);
+        }
+        self.device.write_offset(offset, buf);
+    }
+}
+
+/// A device which reads without going through its cache, like the
+/// O_DIRECT reads of a disk, for the direct I/O of direct.rs. The offsets
+/// are those of the device the filesystem is mounted on. The devices
+/// without a DirectRead have no cache to bypass.
+pub trait DirectRead: Send + Sync {
+    fn read_direct(&self, offset: usize, buf: &mut [u8]);
+}
+
+/// The direct reads of the devices by the device number.
+static DIRECT_READS: Mutex<BTreeMap<usize, Arc<dyn DirectRead>>> = Mutex::new(BTreeMap::new());
+
+/// Set the direct read of the device, the direct I/O of the files reads
+/// the data blocks by it.
+pub fn set_direct_read(dev: usize, device: Arc<dyn DirectRead>) {
+    DIRECT_READS.lock().insert(dev, device);
+}
+
+pub fn direct_read_device(dev: usize) -> Option<Arc<dyn DirectRead>> {
+    DIRECT_READS.lock().get(&dev).cloned()
+}
+
+/// The cache writes through, so the device under it is never stale.
+impl DirectRead for CachedDevice {
+    fn read_direct(&self, offset: usize, buf: &mut [u8]) {
+        let mut pos = 0;
+        while pos < buf.len() {
+            let data = self.device.read_offset(offset + pos);
+            let len = min(data.len(), buf.len() - pos);
+            buf[pos..pos + len].copy_from_slice(&data[..len]);
+            pos += len;
+        }
+    }
+}
+
+/// A device which zeroes bytes without a buffer of zeros, like the write
+/// zeroes or the discard of a disk which reads the discarded sectors as
+/// zeros. The offsets are those of the device the filesystem is mounted
+/// on, which must not cache the zeroed bytes.
+pub trait WriteZeroes: Send + Sync {
+    fn write_zeroes(&self, offset: usize, len: usize);
+}
+
+/// The write zeroes of the devices by the device number.
+static WRITE_ZEROES: Mutex<BTreeMap<usize, Arc<dyn WriteZeroes>>> = Mutex::new(BTreeMap::new());
+
+/// Set the write zeroes of the device, the secure_delete of ext4 zeroes
+/// the freed blocks by it instead of writing zeros.
+pub fn set_write_zeroes(dev: usize, device: Arc<dyn WriteZeroes>) {
+    WRITE_ZEROES.lock().insert(dev, device);
+}
+
+pub fn write_zeroes_device(dev: usize) -> Option<Arc<dyn WriteZeroes>> {
+    WRITE_ZEROES.lock().get(&dev).cloned()
+}
+
+/// A device with a volatile write cache, the writes it took are durable
+/// only after its flush, like the FLUSH of a disk. The BlockDevice of
+/// ext4_rs has no flush, the devices without a DeviceFlush are written
+/// through. The sectors are those of the mounted device, the offsets of
+/// its BlockDevice by SECTOR_SIZE.
+pub trait DeviceFlush: Send + Sync {
+    fn flush(&self);
+
+    /// The device caches its writes, a device which doesn't is written
+    /// through like one without a DeviceFlush.
+    fn has_write_cache(&self) -> bool {
+        true
+    }
+
+    /// The device has write_fua, force unit access, like the REQ_FUA of
+    /// Linux.
+    fn supports_fua(&self) -> bool {
+        false
+    }
+
+    /// Write buf at the sector past the write cache, the write is durable
+    /// when it returns but the cached writes before it aren't. A device
+    /// without FUA returns NotSupported without writing, the callers check
+    /// supports_fua first.
+    fn write_fua(&self, sector: usize, buf: &[u8]) -> VfsResult<()> {
+        let _ = (sector, buf);
+        Err(VfsError::NotSupported)
+    }
+}
+
+/// The flushes of the devices by the device number.
+static DEVICE_FLUSHES: Mutex<BTreeMap<usize, Arc<dyn DeviceFlush>>> = Mutex::new(BTreeMap::new());
+
+/// Set the flush of the device, the synchronous writes and fsync flush
+/// it before they return, and the journal of ext4 orders its commits by
+/// it, see has_write_cache.
+pub fn set_device_flush(dev: usize, device: Arc<dyn DeviceFlush>) {
+    DEVICE_FLUSHES.lock().insert(dev, device);
+}
+
+/// Drop the flush of the device, when it's unmounted or written through.
+pub fn remove_device_flush(dev: usize) {
+    DEVICE_FLUSHES.lock().remove(&dev);
+}
+
+/// The flush of the device if it caches its writes.
+fn cached_device(dev: usize) -> Option<Arc<dyn DeviceFlush>> {
+    let device = DEVICE_FLUSHES.lock().get(&dev).cloned();
+    device.filter(|x| x.has_write_cache())
+}
+
+/// The device has a volatile write cache, the writes reach the media in
+/// any order until a flush.
+pub fn has_write_cache(dev: usize) -> bool {
+    cached_device(dev).is_some()
+}
+
+/// The device caches its writes and has write_fua.
+pub fn supports_fua(dev: usize) -> bool {
+    cached_device(dev).is_some_and(|x| x.supports_fua())
+}
+
+/// A device which counts its failed writes, like the errseq of the
+/// writeback errors of Linux. The BlockDevice of ext4_rs returns no
+/// error, a transaction of ext4 whose writes failed is found by the count
+/// changing across its commit, and is rolled back. The devices without a
+/// DeviceErrors lose their failed writes silently. The failed reads are
+/// counted the same way, for the retries of RetryPolicy.
+pub trait DeviceErrors: Send + Sync {
+    fn write_errors(&self) -> u64;
+
+    /// The failed reads so far, a device which doesn't count them reads
+    /// without a retry.
+    fn read_errors(&self) -> u64 {
+        0
+    }
+}
+
+/// The error counts of the devices by the device number.
+static DEVICE_ERRORS: Mutex<BTreeMap<usize, Arc<dyn DeviceErrors>>> = Mutex::new(BTreeMap::new());
+
+/// Set the error count of the device, the transactions of ext4 check it.
+pub fn set_device_errors(dev: usize, device: Arc<dyn DeviceErrors>) {
+    DEVICE_ERRORS.lock().insert(dev, device);
+}
+
+/// The failed writes of the device so far, None if it doesn't count them.
+pub fn device_errors(dev: usize) -> Option<u64> {
+    error_counts(dev).map(|x| x.write_errors())
+}
+
+/// The error counts of the device, for a caller comparing them around
+/// each of its requests.
+pub fn error_counts(dev: usize) -> Option<Arc<dyn DeviceErrors>> {
+    DEVICE_ERRORS.lock().get(&dev).cloned()
+}
+
+/// How a request failing with a device error is tried again, like the
+/// retries of the block layer for an SD card which fails a read now and
+/// then. The requests are retried only on a device counting its errors,
+/// see DeviceErrors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The tries of a request, the first one included, at least one.
+    pub attempts: u32,
+    /// The yields of the wait hook of pipe.rs before the second try, the
+    /// backoff doubles before each next one.
+    pub backoff_yields: u32,
+}
+
+impl Default for RetryPolicy {
+    /// No retry, a failed write fails its transaction as it did.
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+impl RetryPolicy {
+    /// Try the request once.
+    pub const NONE: Self = Self {
+        attempts: 1,
+        backoff_yields: 0,
+    };
+
+    /// The retries of a flaky device, like an SD card.
+    pub const FLAKY: Self = Self {
+        attempts: 3,
+        backoff_yields: 1,
+    };
+
+    /// Try the request until it returns true or the attempts are spent,
+    /// with the backoff between the tries. return whether it succeeded
+    /// and the failed tries.
+    pub fn run(&self, mut request: impl FnMut() -> bool) -> (bool, u32) {
+        let attempts = self.attempts.max(1);
+        for attempt in 0..attempts {
+            if attempt > 0 {
+                self.backoff(attempt);
+            }
+            if request() {
+                return (true, attempt);
+            }
+        }
+        (false, attempts)
+    }
+
+    /// Yield before the try after attempt failed ones, nothing without a
+    /// wait hook.
+    fn backoff(&self, attempt: u32) {
+        let Some(wait) = crate::pipe::wait_hook() else {
+            return;
+        };
+        let yields = self
+            .backoff_yields
+            .saturating_mul(1 << (attempt - 1).min(16));
+        (0..yields).for_each(|_| wait());
+    }
+}
+
+/// Flush the write cache of the device, nothing if it has none. The
+/// writes queued by the scheduler of the device are dispatched first.
+pub fn flush_device(dev: usize) {
+    crate::iosched::sync(dev);
+    if let Some(device) = cached_device(dev) {
+        device.flush();
+    }
+}
+
+/// Write buf at the sector of the device with FUA, after the writes
+/// queued by its scheduler. return false if the device has no FUA or
+/// refused the write, the caller writes and flushes instead, or nothing
+/// if it has no cache.
+pub fn write_fua(dev: usize, sector: usize, buf: &[u8]) -> bool {
+    let Some(device) = cached_device(dev).filter(|x| x.supports_fua()) else {
+        return false;
+    };
+    crate::iosched::sync(dev);
+    device.write_fua(sector, buf).is_ok()
+}
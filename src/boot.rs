@@ -0,0 +1,336 @@
+// The boot of the mount tree, the sequence every kernel bring-up ran by
+// hand. The root is mounted by the first RootSpec of the config which
+// mounts, through the types of fstype.rs, then the directories of the
+// standard tree, /dev, /proc, /tmp and /sys, are created on it and the
+// filesystems of the config are mounted on them in the dentry tree of
+// the root. A root which doesn't mount fails the boot, the other mounts
+// don't: a type which isn't built, like the proc of the kernel feature
+// on a host, or a mountpoint which can't be created on a read-only root
+// is reported and the boot goes on. The BootReport tells what was
+// mounted where, init_with of lib.rs installs its tree as the dentry tree and
+// its filesystems as FILESYSTEMS. The mounts keep the spec they were
+// found by as their source of /proc/mounts, see mounts::set_source.
+// The filesystems of the boot are leaked, root_dir needs them 'static
+// and they live as long as the kernel.
+
+use core::fmt::{self, Display, Formatter};
+
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use vfscore::{FileSystem, OpenFlags, VfsError, VfsResult};
+
+use crate::chardev;
+use crate::dentry::{dentry_open_at, invalidate_negative, DentryNode, ResolveContext};
+use crate::devnode::disk_name;
+use crate::fstype::{self, MountSource};
+use crate::mounts::{self, MountFlags};
+use crate::sys::{get_blk_device, get_blk_devices};
+use crate::volume;
+
+/// The directories of the standard tree, created on a writable root.
+pub const STANDARD_DIRS: [&str; 4] = ["/dev", "/proc", "/tmp", "/sys"];
+
+/// Where the root is looked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RootSpec {
+    /// The sys device, as the type detected on it.
+    Device(usize),
+    /// The sys device holding the filesystem of the uuid, like root=UUID=.
+    Uuid([u8; 16]),
+    /// The first sys device a type detects.
+    AnyDevice,
+    /// A type in memory, like a ramfs root on a machine without a disk.
+    Memory(&'static str),
+}
+
+impl RootSpec {
+    /// The source of the root in /proc/mounts, /dev/root for AnyDevice
+    /// like Linux.
+    pub fn source(&self) -> String {
+        match self {
+            Self::Device(device_id) => String::from("/dev/") + &disk_name(*device_id),
+            Self::Uuid(uuid) => String::from("UUID=") + &volume::format_uuid(uuid),
+            Self::AnyDevice => String::from("/dev/root"),
+            Self::Memory(name) => name.to_string(),
+        }
+    }
+}
+
+/// The mount tree of the boot, see the module.
+#[derive(Debug, Clone)]
+pub struct BootFsConfig {
+    /// The places of the root in the order of preference.
+    pub root: Vec<RootSpec>,
+    pub root_flags: MountFlags,
+    /// Mount the devfs of the kernel feature on /dev.
+    pub devfs: bool,
+    /// Mount the proc type on /proc.
+    pub procfs: bool,
+    /// Mount a tmpfs on /tmp.
+    pub tmpfs: bool,
+    /// The types in memory mounted after those by their path, like a
+    /// ramfs on /home.
+    pub extra: Vec<(&'static str, &'static str)>,
+    /// The directories created with the standard ones.
+    pub dirs: Vec<&'static str>,
+    /// The entropy of the platform for /dev/random and /dev/urandom, like
+    /// the rng-seed of the device tree or a read of a hardware RNG. They
+    /// fail with EAGAIN until it's given, see chardev.rs.
+    pub random_seed: Option<u64>,
+}
+
+impl Default for BootFsConfig {
+    /// The tree of Byte-OS, the root on the first device or a ramfs.
+    fn default() -> Self {
+        Self {
+            root: vec![RootSpec::Device(0), RootSpec::Memory("ramfs")],
+            root_flags: MountFlags::NONE,
+            devfs: true,
+            procfs: true,
+            tmpfs: true,
+            extra: vec![("tmpfs", "/dev/shm"), ("ramfs", "/home"), ("ramfs", "/var")],
+            dirs: vec!["/bin"],
+            random_seed: None,
+        }
+    }
+}
+
+/// What became of a mount of the boot.
+#[derive(Debug, Clone)]
+pub enum BootStatus {
+    Mounted,
+    /// Disabled by the config.
+    Skipped,
+    Failed(VfsError),
+}
+
+/// A mount of the boot, by its path.
+#[derive(Debug, Clone)]
+pub struct BootMount {
+    pub path: String,
+    pub fstype: &'static str,
+    pub status: BootStatus,
+}
+
+/// What the boot mounted where.
+pub struct BootReport {
+    /// The spec the root was found by.
+    pub root_spec: RootSpec,
+    pub root_fstype: &'static str,
+    /// The root of the tree, with the mounts.
+    pub root: Arc<DentryNode>,
+    /// The mounted filesystems by their path, the root first.
+    pub filesystems: Vec<(Arc<dyn FileSystem>, String)>,
+    /// Every mount of the config in its order, the root first.
+    pub mounts: Vec<BootMount>,
+    /// The directories created on the root.
+    pub created: Vec<String>,
+    /// The directories which are missing and can't be created.
+    pub missing: Vec<(String, VfsError)>,
+}
+
+impl BootReport {
+    pub fn mount(&self, path: &str) -> Option<&BootMount> {
+        self.mounts.iter().find(|x| x.path == path)
+    }
+}
+
+impl Display for BootReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "root {} by {:?}", self.root_fstype, self.root_spec)?;
+        for mount in self.mounts.iter() {
+            match &mount.status {
+                BootStatus::Mounted => writeln!(f, "{} {}", mount.path, mount.fstype)?,
+                BootStatus::Skipped => writeln!(f, "{} {} skipped", mount.path, mount.fstype)?,
+                BootStatus::Failed(err) => {
+                    writeln!(f, "{} {} failed: {:?}", mount.path, mount.fstype, err)?
+                }
+            }
+        }
+        for (path, err) in self.missing.iter() {
+            writeln!(f, "{} missing: {:?}", path, err)?;
+        }
+        Ok(())
+    }
+}
+
+fn leak(fs: Arc<dyn FileSystem>) -> &'static Arc<dyn FileSystem> {
+    Box::leak(Box::new(fs))
+}
+
+/// Mount the sys device as the type detected on it.
+fn mount_device(
+    device_id: usize,
+    flags: MountFlags,
+) -> VfsResult<(Arc<dyn FileSystem>, &'static str)> {
+    get_blk_device(device_id).ok_or(VfsError::FileNotFound)?;
+    let source = MountSource::Device(device_id);
+    let name = fstype::detect(&source).ok_or(VfsError::InvalidData)?;
+    Ok((fstype::mount_by_name(name, source, flags)?, name))
+}
+
+/// Mount the root of the spec, with its type.
+fn mount_spec(spec: RootSpec, flags: MountFlags) -> VfsResult<(Arc<dyn FileSystem>, &'static str)> {
+    let devices = 0..get_blk_devices().len();
+    match spec {
+        RootSpec::Device(device_id) => mount_device(device_id, flags),
+        RootSpec::AnyDevice => devices
+            .into_iter()
+            .find_map(|x| mount_device(x, flags).ok())
+            .ok_or(VfsError::FileNotFound),
+        RootSpec::Uuid(uuid) => {
+            // the devices are probed by read-only mounts, the one of the
+            // uuid is mounted again with the flags.
+            let device_id = devices
+                .into_iter()
+                .find(|x| {
+                    mount_device(*x, MountFlags::RDONLY)
+                        .is_ok_and(|(fs, _)| volume::uuid(&fs) == Some(uuid))
+                })
+                .ok_or(VfsError::FileNotFound)?;
+            mount_device(device_id, flags)
+        }
+        RootSpec::Memory(name) => {
+            Ok((fstype::mount_by_name(name, MountSource::None, flags)?, name))
+        }
+    }
+}
+
+/// Open the directory of the path in the tree of root, it's created if
+/// it's missing. return whether it was created.
+fn ensure_dir(root: &Arc<DentryNode>, path: &str) -> VfsResult<bool> {
+    let ctx = ResolveContext::with_root(root.clone());
+    if dentry_open_at(&ctx, path, OpenFlags::NONE).is_ok() {
+        return Ok(false);
+    }
+    let (parent, name) = path.rsplit_once('/').ok_or(VfsError::InvalidInput)?;
+    let parent = match parent {
+        "" => root.clone(),
+        parent => dentry_open_at(&ctx, parent, OpenFlags::NONE)?,
+    };
+    parent.node.mkdir(name)?;
+    invalidate_negative(&parent.node, name);
+    Ok(true)
+}
+
+/// The devfs of the kernel, with the disks of the filesystems.
+#[cfg(feature = "kernel")]
+fn new_devfs(filesystems: &[(Arc<dyn FileSystem>, String)]) -> VfsResult<Arc<dyn FileSystem>> {
+    use crate::capabilities::{self, FsCapabilities};
+
+    let filesystems: Vec<_> = filesystems
+        .iter()
+        .map(|(fs, path)| (fs.clone(), path.as_str()))
+        .collect();
+    let devfs: Arc<dyn FileSystem> = crate::build_devfs(&filesystems);
+    capabilities::register(&devfs, FsCapabilities::DEVFS);
+    Ok(devfs)
+}
+
+#[cfg(not(feature = "kernel"))]
+fn new_devfs(_: &[(Arc<dyn FileSystem>, String)]) -> VfsResult<Arc<dyn FileSystem>> {
+    Err(VfsError::NotSupported)
+}
+
+/// Mount the root and the tree of the config, see the module. Only a root
+/// which doesn't mount fails, with the error of the last spec.
+pub fn boot(config: &BootFsConfig) -> VfsResult<BootReport> {
+    if let Some(seed) = config.random_seed {
+        chardev::seed_random(seed);
+    }
+    let mut last = VfsError::InvalidInput;
+    let mut root = None;
+    for spec in config.root.iter().copied() {
+        match mount_spec(spec, config.root_flags) {
+            Ok((fs, name)) => {
+                root = Some((spec, fs, name));
+                break;
+            }
+            Err(err) => {
+                log::warn!("can't mount the root by {:?}: {:?}", spec, err);
+                last = err.into();
+            }
+        }
+    }
+    let Some((root_spec, fs, root_fstype)) = root else {
+        log::error!("no root to mount");
+        return Err(last);
+    };
+    let root = Arc::new(DentryNode::new(
+        String::from("/"),
+        leak(fs.clone()).root_dir(),
+        Weak::new(),
+    ));
+    mounts::set_source(
+        &root,
+        &fs,
+        &root_spec.source(),
+        root_fstype,
+        config.root_flags,
+    );
+    let mut report = BootReport {
+        root_spec,
+        root_fstype,
+        root: root.clone(),
+        filesystems: vec![(fs, String::from("/"))],
+        mounts: vec![BootMount {
+            path: String::from("/"),
+            fstype: root_fstype,
+            status: BootStatus::Mounted,
+        }],
+        created: Vec::new(),
+        missing: Vec::new(),
+    };
+    let dirs = STANDARD_DIRS.iter().chain(config.dirs.iter());
+    for path in dirs {
+        match ensure_dir(&root, path) {
+            Ok(true) => report.created.push(path.to_string()),
+            Ok(false) => {}
+            Err(err) => report.missing.push((path.to_string(), err)),
+        }
+    }
+
+    let builtin = [
+        ("devfs", "/dev", config.devfs),
+        ("proc", "/proc", config.procfs),
+        ("tmpfs", "/tmp", config.tmpfs),
+    ];
+    let extra = config.extra.iter().map(|(name, path)| (*name, *path, true));
+    for (fstype, path, enabled) in builtin.into_iter().chain(extra) {
+        let status = match enabled {
+            true => match mount_one(&mut report, fstype, path) {
+                Ok(()) => BootStatus::Mounted,
+                Err(err) => {
+                    log::warn!("can't mount {} on {}: {:?}", fstype, path, err);
+                    BootStatus::Failed(err)
+                }
+            },
+            false => BootStatus::Skipped,
+        };
+        report.mounts.push(BootMount {
+            path: path.to_string(),
+            fstype,
+            status,
+        });
+    }
+    Ok(report)
+}
+
+/// Mount the type in memory on the path of the tree, the mountpoint is
+/// created if it's missing.
+fn mount_one(report: &mut BootReport, fstype: &'static str, path: &str) -> VfsResult<()> {
+    if ensure_dir(&report.root, path)? {
+        report.created.push(path.to_string());
+    }
+    let fs = match fstype {
+        "devfs" => new_devfs(&report.filesystems)?,
+        name => fstype::mount_by_name(name, MountSource::None, MountFlags::NONE)?,
+    };
+    mounts::mount_with_source(&report.root, path, &fs, fstype, fstype, MountFlags::NONE)?;
+    report.filesystems.push((fs, path.to_string()));
+    Ok(())
+}
@@ -0,0 +1,315 @@
+// Page cache of the file data above the filesystems.
+// The pages are keyed by the identity of the inode and the page index, so
+// they are shared by all handles of the same inode. The cache writes
+// through, the pages are never dirty and can be evicted at any time.
+// Other caches register a Shrinker to share the memory budget with it.
+
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+
+use crate::sys::Mutex;
+
+pub const PAGE_SIZE: usize = 0x1000;
+
+/// The default memory budget shared by all the caches.
+const DEFAULT_BUDGET: usize = 16 * 1024 * 1024;
+
+/// The identity of an inode, dev distinguishes the filesystems.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct InodeId {
+    pub dev: usize,
+    pub ino: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub evictions: usize,
+    pub pages: usize,
+    pub bytes: usize,
+}
+
+struct Page {
+    data: Arc<Vec<u8>>,
+    stamp: u64,
+}
+
+struct PageCache {
+    pages: BTreeMap<(InodeId, usize), Page>,
+    /// The least recently used page has the smallest stamp.
+    lru: BTreeMap<u64, (InodeId, usize)>,
+    tick: u64,
+    budget: usize,
+    usage: usize,
+    stats: CacheStats,
+}
+
+impl PageCache {
+    const fn new() -> Self {
+        Self {
+            pages: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            budget: DEFAULT_BUDGET,
+            usage: 0,
+            stats: CacheStats {
+                hits: 0,
+                misses: 0,
+                evictions: 0,
+                pages: 0,
+                bytes: 0,
+            },
+        }
+    }
+
+    fn touch(&mut self, key: (InodeId, usize)) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let page = self.pages.get_mut(&key)?;
+        self.lru.remove(&page.stamp);
+        page.stamp = tick;
+        self.lru.insert(tick, key);
+        Some(page.data.clone())
+    }
+
+    fn remove(&mut self, key: &(InodeId, usize)) {
+        if let Some(page) = self.pages.remove(key) {
+            self.lru.remove(&page.stamp);
+            self.usage -= page.data.len();
+        }
+    }
+
+    /// Evict the least recently used pages until at least bytes are freed.
+    /// The pages held by readers are pinned and never evicted.
+    fn evict(&mut self, bytes: usize) -> usize {
+        let mut freed = 0;
+        let mut victims = Vec::new();
+        for (_, key) in self.lru.iter() {
+            if freed >= bytes {
+                break;
+            }
+            let page = &self.pages[key];
+            if Arc::strong_count(&page.data) == 1 {
+                freed += page.data.len();
+                victims.push(*key);
+            }
+        }
+        for key in victims.iter() {
+            self.remove(key);
+        }
+        self.stats.evictions += victims.len();
+        freed
+    }
+
+    /// The bytes of the pages held by readers.
+    fn pinned(&self) -> usize {
+        self.pages
+            .values()
+            .filter(|x| Arc::strong_count(&x.data) > 1)
+            .map(|x| x.data.len())
+            .sum()
+    }
+}
+
+/// A cache other than the page cache which can give back memory under
+/// the shared budget.
+pub trait Shrinker: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// The bytes used by the cache.
+    fn usage(&self) -> usize;
+    /// The bytes of the entries which can't be evicted now, like those
+    /// of the open files, they aren't in the share of the cache to free.
+    fn pinned(&self) -> usize {
+        0
+    }
+    /// Evict the unpinned clean entries until at least target bytes are
+    /// freed, the dirty ones are written back and evicted only if
+    /// writeback is set. return the freed bytes.
+    fn shrink(&self, target: usize, writeback: bool) -> usize;
+}
+
+/// The registered shrinkers, the dropped ones are removed lazily.
+static SHRINKERS: Mutex<Vec<Weak<dyn Shrinker>>> = Mutex::new(Vec::new());
+
+/// Register the cache to share the budget with the page cache.
+pub fn register_shrinker(shrinker: Weak<dyn Shrinker>) {
+    SHRINKERS.lock().push(shrinker);
+}
+
+fn shrinkers() -> Vec<Arc<dyn Shrinker>> {
+    let mut shrinkers = SHRINKERS.lock();
+    shrinkers.retain(|x| x.strong_count() > 0);
+    shrinkers.iter().filter_map(|x| x.upgrade()).collect()
+}
+
+static PAGE_CACHE: Mutex<PageCache> = Mutex::new(PageCache::new());
+
+/// Get the cached page, the page may be shorter than PAGE_SIZE at the end
+/// of the file.
+pub fn get(id: InodeId, index: usize) -> Option<Arc<Vec<u8>>> {
+    let mut cache = PAGE_CACHE.lock();
+    let page = cache.touch((id, index));
+    match page {
+        Some(_) => cache.stats.hits += 1,
+        None => cache.stats.misses += 1,
+    }
+    page
+}
+
+/// Insert the page read from the filesystem.
+pub fn insert(id: InodeId, index: usize, data: Vec<u8>) -> Arc<Vec<u8>> {
+    let data = Arc::new(data);
+    let mut cache = PAGE_CACHE.lock();
+    cache.remove(&(id, index));
+    cache.tick += 1;
+    let stamp = cache.tick;
+    cache.usage += data.len();
+    cache.pages.insert(
+        (id, index),
+        Page {
+            data: data.clone(),
+            stamp,
+        },
+    );
+    cache.lru.insert(stamp, (id, index));
+    drop(cache);
+    enforce_budget();
+    data
+}
+
+/// Drop the cached pages [start, end] of the inode, it must be called
+/// after the data of the inode was changed.
+pub fn invalidate_range(id: InodeId, start: usize, end: usize) {
+    let mut cache = PAGE_CACHE.lock();
+    let keys: Vec<_> = cache
+        .pages
+        .range((id, start)..=(id, end))
+        .map(|(key, _)| *key)
+        .collect();
+    keys.iter().for_each(|key| cache.remove(key));
+}
+
+/// Drop all cached pages of the inode.
+pub fn invalidate(id: InodeId) {
+    invalidate_range(id, 0, usize::MAX)
+}
+
+/// Drop all cached pages of the inodes of the device, after the device
+/// changed under its filesystem, see revalidate.rs.
+pub fn invalidate_dev(dev: usize) {
+    let start = InodeId { dev, ino: 0 };
+    let end = InodeId { dev, ino: u64::MAX };
+    let mut cache = PAGE_CACHE.lock();
+    let keys: Vec<_> = cache
+        .pages
+        .range((start, 0)..=(end, usize::MAX))
+        .map(|(key, _)| *key)
+        .collect();
+    keys.iter().for_each(|key| cache.remove(key));
+}
+
+/// Check if the page is cached, without touching it.
+pub fn contains(id: InodeId, index: usize) -> bool {
+    PAGE_CACHE.lock().pages.contains_key(&(id, index))
+}
+
+/// Drop all the cached pages which aren't pinned.
+pub fn drop_caches() {
+    let mut cache = PAGE_CACHE.lock();
+    cache.evict(usize::MAX);
+}
+
+/// Set the memory budget shared by all the caches, the caches are shrunk
+/// at once if they use more than it.
+pub fn set_budget(bytes: usize) {
+    PAGE_CACHE.lock().budget = bytes;
+    enforce_budget();
+}
+
+pub fn budget() -> usize {
+    PAGE_CACHE.lock().budget
+}
+
+/// Shrink the caches in proportion to their usage until the total usage
+/// fits the budget.
+fn enforce_budget() {
+    let budget = PAGE_CACHE.lock().budget;
+    let total: usize = usage().iter().map(|(_, x)| x).sum();
+    if total > budget {
+        shrink_caches(total - budget, false);
+    }
+}
+
+/// Evict the clean entries of all the caches until at least target_bytes
+/// are freed, the kernel calls it when the memory is low.
+/// return the freed bytes.
+pub fn shrink(target_bytes: usize) -> usize {
+    shrink_caches(target_bytes, false)
+}
+
+/// Like shrink, but also write back and evict the dirty entries.
+pub fn shrink_writeback(target_bytes: usize) -> usize {
+    shrink_caches(target_bytes, true)
+}
+
+/// Ask every cache to free its share of target, the share is in
+/// proportion to the usage which isn't pinned. The caches are asked again
+/// for the rest if some of them couldn't free their share.
+fn shrink_caches(target: usize, writeback: bool) -> usize {
+    let shrinkers = shrinkers();
+    let page_usage = {
+        let cache = PAGE_CACHE.lock();
+        cache.usage - cache.pinned()
+    };
+    let usages: Vec<usize> = shrinkers
+        .iter()
+        .map(|x| x.usage().saturating_sub(x.pinned()))
+        .collect();
+    let total = page_usage + usages.iter().sum::<usize>();
+    if total == 0 || target == 0 {
+        return 0;
+    }
+    let share = |usage: usize| (target as u128 * usage as u128).div_ceil(total as u128) as usize;
+
+    let mut freed = PAGE_CACHE.lock().evict(share(page_usage));
+    for (shrinker, usage) in shrinkers.iter().zip(usages) {
+        freed += shrinker.shrink(share(usage), writeback);
+    }
+    if freed < target {
+        freed += PAGE_CACHE.lock().evict(target - freed);
+    }
+    for shrinker in shrinkers.iter() {
+        if freed >= target {
+            break;
+        }
+        freed += shrinker.shrink(target - freed, writeback);
+    }
+    freed
+}
+
+/// Get the bytes used by every cache, for the /proc style reporting.
+pub fn usage() -> Vec<(&'static str, usize)> {
+    let mut usage = vec![("page", PAGE_CACHE.lock().usage)];
+    usage.extend(shrinkers().iter().map(|x| (x.name(), x.usage())));
+    usage
+}
+
+/// Get the pinned bytes of every cache, like usage.
+pub fn pinned_usage() -> Vec<(&'static str, usize)> {
+    let mut pinned = vec![("page", PAGE_CACHE.lock().pinned())];
+    pinned.extend(shrinkers().iter().map(|x| (x.name(), x.pinned())));
+    pinned
+}
+
+pub fn stats() -> CacheStats {
+    let cache = PAGE_CACHE.lock();
+    CacheStats {
+        pages: cache.pages.len(),
+        bytes: cache.usage,
+        ..cache.stats
+    }
+}
@@ -0,0 +1,122 @@
+// The partial transfers and their cancellation. readat and writeat follow
+// read(2) and write(2): a transfer which stops midway, cancelled or out
+// of space, returns the bytes done so far, and only a transfer which did
+// nothing fails. A long transfer checks its cancellation between its
+// chunks, like a pending signal of its task, so the kernel can interrupt
+// it and restart the rest. INodeInterface of vfscore takes no
+// cancellation, so the nodes which can stop midway implement CancelIo and
+// hand it out by their FsNode, like the nodes of statx.rs. The other
+// nodes run the transfer to its end. A transfer cancelled before its
+// first byte fails with Blocking, and EINTR at read_at and write_at.
+
+use alloc::sync::Arc;
+use vfscore::{INodeInterface, VfsError, VfsResult};
+
+use crate::error::{Errno, FsError, FsResult};
+use crate::node;
+
+/// The bytes of a transfer between the checks of its cancellation.
+pub const CANCEL_CHUNK: usize = 0x10000;
+
+/// The cancellation of a transfer which is never cancelled.
+pub fn never() -> bool {
+    false
+}
+
+/// The transfers of a node which can stop midway. cancelled is checked
+/// between the chunks, a transfer stops when it returns true.
+pub trait CancelIo: Send + Sync {
+    fn readat_cancel(
+        &self,
+        offset: usize,
+        buffer: &mut [u8],
+        cancelled: &dyn Fn() -> bool,
+    ) -> VfsResult<usize>;
+
+    fn writeat_cancel(
+        &self,
+        offset: usize,
+        buffer: &[u8],
+        cancelled: &dyn Fn() -> bool,
+    ) -> VfsResult<usize>;
+}
+
+fn node_of(file: &Arc<dyn INodeInterface>) -> Option<&dyn CancelIo> {
+    node::fs_node(file.as_ref())?.as_cancel()
+}
+
+/// The result of a transfer, the Blocking of a cancelled one is EINTR.
+pub fn interrupted(r: VfsResult<usize>, cancelled: &dyn Fn() -> bool) -> FsResult<usize> {
+    match r {
+        Err(VfsError::Blocking) if cancelled() => {
+            Err(FsError::new(VfsError::Blocking, Errno::EINTR))
+        }
+        r => Ok(r?),
+    }
+}
+
+/// Read at offset until cancelled, return the bytes read.
+pub fn read_at(
+    file: &Arc<dyn INodeInterface>,
+    offset: usize,
+    buffer: &mut [u8],
+    cancelled: &dyn Fn() -> bool,
+) -> FsResult<usize> {
+    let r = match node_of(file) {
+        Some(node) => node.readat_cancel(offset, buffer, cancelled),
+        None => file.readat(offset, buffer),
+    };
+    interrupted(r, cancelled)
+}
+
+/// Write at offset until cancelled, return the bytes written.
+pub fn write_at(
+    file: &Arc<dyn INodeInterface>,
+    offset: usize,
+    buffer: &[u8],
+    cancelled: &dyn Fn() -> bool,
+) -> FsResult<usize> {
+    let r = match node_of(file) {
+        Some(node) => node.writeat_cancel(offset, buffer, cancelled),
+        None => file.writeat(offset, buffer),
+    };
+    interrupted(r, cancelled)
+}
+
+/// Fill buffer from offset over the short reads, it fails with
+/// UnexpectedEof at the end of the file. The bytes before a failure are
+/// read, read_at returns their count.
+pub fn read_exact(
+    file: &Arc<dyn INodeInterface>,
+    offset: usize,
+    buffer: &mut [u8],
+    cancelled: &dyn Fn() -> bool,
+) -> FsResult<()> {
+    let mut pos = 0;
+    while pos < buffer.len() {
+        match read_at(file, offset + pos, &mut buffer[pos..], cancelled)? {
+            0 => return Err(VfsError::UnexpectedEof.into()),
+            n => pos += n,
+        }
+    }
+    Ok(())
+}
+
+/// Write all of buffer at offset over the short writes, it fails with
+/// WriteZero if nothing more can be written. The bytes before a failure
+/// are written, write_at returns their count.
+pub fn write_all(
+    file: &Arc<dyn INodeInterface>,
+    offset: usize,
+    buffer: &[u8],
+    cancelled: &dyn Fn() -> bool,
+) -> FsResult<()> {
+    let mut pos = 0;
+    while pos < buffer.len() {
+        match write_at(file, offset + pos, &buffer[pos..], cancelled)? {
+            0 => return Err(VfsError::WriteZero.into()),
+            n => pos += n,
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,191 @@
+// The capabilities of the filesystems, so the upper layers ask instead of
+// trying and getting NotSupported: copy_recursive and extract_tar copy a
+// file where they can't link it, create_with writes in place where it
+// can't rename, the conformance suite skips the cases a
+// filesystem can't run, a syscall can fail early. FileSystem of vfscore
+// has no capabilities, so they are of the filesystem of the node by the
+// f_type of its statfs, like pathconf.rs, and a mount registered
+// read-only can't write. The filesystems without a known f_type, like the
+// ramfs and the devfs of the kernel, register theirs by their address,
+// the unknown ones are assumed to have all of them, so their callers try
+// like before. The holes and the allocations of fallocate.rs are declared
+// by the types doing them natively, and added to those which can write
+// when their policy emulates them.
+// TODO: ask the filesystems themselves when FileSystem has capabilities.
+
+use core::ops::{BitAnd, BitOr};
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use vfscore::{FileSystem, INodeInterface, Stat, StatFS};
+
+use crate::fallocate::{self, Fallback, SpaceOp};
+use crate::mounts::{self, MountFlags};
+use crate::statfs::{EXT4_SUPER_MAGIC, MSDOS_SUPER_MAGIC, PROC_SUPER_MAGIC, TMPFS_MAGIC};
+use crate::sys::Mutex;
+
+/// The optional features of a filesystem.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FsCapabilities(u32);
+
+impl FsCapabilities {
+    pub const NONE: Self = Self(0);
+    /// The files and the directories can be created and written.
+    pub const WRITE: Self = Self(1 << 0);
+    pub const SYMLINKS: Self = Self(1 << 1);
+    pub const HARD_LINKS: Self = Self(1 << 2);
+    /// The holes of the files take no blocks.
+    pub const SPARSE_FILES: Self = Self(1 << 3);
+    /// The extended attributes of the files.
+    pub const XATTRS: Self = Self(1 << 4);
+    /// The lookups ignore the case of the names.
+    pub const CASEFOLD: Self = Self(1 << 5);
+    /// The owners of the files, chown of owner.rs.
+    pub const OWNERSHIP: Self = Self(1 << 6);
+    /// The device nodes and the FIFOs, mknod of mknod.rs.
+    pub const SPECIAL_FILES: Self = Self(1 << 7);
+    /// The holes of fallocate.rs, natively or emulated.
+    pub const PUNCH_HOLE: Self = Self(1 << 8);
+    /// The allocations of fallocate.rs, natively or emulated.
+    pub const PREALLOCATE: Self = Self(1 << 9);
+    /// The clones sharing the data of reflink.rs.
+    pub const REFLINK: Self = Self(1 << 10);
+    /// The renames of rename.rs.
+    pub const RENAME: Self = Self(1 << 11);
+    pub const ALL: Self = Self((1 << 12) - 1);
+
+    /// The ext4 shim, without the links.
+    /// TODO: add the links when the shim can make them.
+    pub const EXT4: Self = Self(
+        Self::WRITE.0
+            | Self::SPARSE_FILES.0
+            | Self::OWNERSHIP.0
+            | Self::SPECIAL_FILES.0
+            | Self::RENAME.0,
+    );
+    /// tmpfs.rs, its pages are allocated at their first write, punched
+    /// and allocated natively, and shared by the clones.
+    pub const TMPFS: Self = Self(
+        Self::WRITE.0
+            | Self::SPARSE_FILES.0
+            | Self::PUNCH_HOLE.0
+            | Self::PREALLOCATE.0
+            | Self::REFLINK.0
+            | Self::RENAME.0,
+    );
+    /// FAT, the files and directories only.
+    pub const FAT: Self = Self::WRITE;
+    /// /proc, its files are made by the kernel.
+    pub const PROCFS: Self = Self::NONE;
+    /// The ramfs of the kernel, the files and directories only.
+    pub const RAMFS: Self = Self::WRITE;
+    /// The devfs of the kernel, its nodes are made by the kernel.
+    pub const DEVFS: Self = Self::NONE;
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    /// The capabilities of the filesystem of the f_type, ALL if it isn't
+    /// known.
+    pub const fn of_type(ftype: u32) -> Self {
+        match ftype {
+            EXT4_SUPER_MAGIC => Self::EXT4,
+            TMPFS_MAGIC => Self::TMPFS,
+            MSDOS_SUPER_MAGIC => Self::FAT,
+            PROC_SUPER_MAGIC => Self::PROCFS,
+            _ => Self::ALL,
+        }
+    }
+}
+
+impl BitOr for FsCapabilities {
+    type Output = Self;
+
+    /// The union, what a stack of filesystems has in one layer at least.
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for FsCapabilities {
+    type Output = Self;
+
+    /// The intersection, what every layer of a stack has.
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+/// The registered filesystems, the dropped ones are removed lazily.
+static FILESYSTEMS: Mutex<Vec<(Weak<dyn FileSystem>, FsCapabilities)>> = Mutex::new(Vec::new());
+
+/// Register the capabilities of a filesystem whose f_type isn't known.
+pub fn register(fs: &Arc<dyn FileSystem>, capabilities: FsCapabilities) {
+    let mut filesystems = FILESYSTEMS.lock();
+    filesystems.retain(|x| x.0.strong_count() > 0);
+    filesystems.push((Arc::downgrade(fs), capabilities));
+}
+
+/// The capabilities of the filesystem, the registered ones or those of
+/// its root.
+pub fn capabilities(fs: &'static Arc<dyn FileSystem>) -> FsCapabilities {
+    let registered = FILESYSTEMS
+        .lock()
+        .iter()
+        .find(|x| core::ptr::addr_eq(x.0.as_ptr(), Arc::as_ptr(fs)))
+        .map(|x| x.1);
+    match registered {
+        Some(capabilities) => with_fallbacks(capabilities),
+        None => of_node(&fs.root_dir()),
+    }
+}
+
+/// The capabilities with the operations of fallocate.rs which the policy
+/// emulates if they can write, without them and the clones if not.
+pub fn with_fallbacks(capabilities: FsCapabilities) -> FsCapabilities {
+    let space = [
+        (SpaceOp::PunchHole, FsCapabilities::PUNCH_HOLE),
+        (SpaceOp::Allocate, FsCapabilities::PREALLOCATE),
+    ];
+    let mut capabilities = capabilities;
+    if !capabilities.contains(FsCapabilities::WRITE) {
+        capabilities = capabilities.without(FsCapabilities::REFLINK);
+    }
+    for (op, capability) in space {
+        if !capabilities.contains(FsCapabilities::WRITE) {
+            capabilities = capabilities.without(capability);
+        } else if fallocate::policy(op) == Fallback::Emulate {
+            capabilities = capabilities | capability;
+        }
+    }
+    capabilities
+}
+
+/// The capabilities of the filesystem of the node, by the f_type of its
+/// statfs, without WRITE if its mount is read-only, with_fallbacks. A
+/// node without a statfs has them all.
+pub fn of_node(node: &Arc<dyn INodeInterface>) -> FsCapabilities {
+    let mut statfs = StatFS::default();
+    let capabilities = match node.statfs(&mut statfs) {
+        Ok(()) => FsCapabilities::of_type(statfs.ftype as u32),
+        Err(_) => FsCapabilities::ALL,
+    };
+    let mut stat = Stat::default();
+    let read_only = node.stat(&mut stat).is_ok()
+        && mounts::dev_flags(stat.dev as usize).is_some_and(|x| x.contains(MountFlags::RDONLY));
+    with_fallbacks(match read_only {
+        true => capabilities.without(FsCapabilities::WRITE),
+        false => capabilities,
+    })
+}
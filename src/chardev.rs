@@ -0,0 +1,194 @@
+// The memory character devices of /dev: null, zero, full, random and
+// urandom, with the numbers of Linux, major 1. They have no offset, so an
+// lseek of them always succeeds at the fd, and they are always ready for
+// poll:
+// - null reads at the end of the file and discards the writes.
+// - zero reads zeros, the buffer of the caller is filled in place for any
+//   size, and discards the writes.
+// - full reads zeros and fails every write with StorageFull (ENOSPC).
+// - random and urandom read pseudo random bytes, a read is never short,
+//   and discard the writes.
+// build_devfs adds them to devfs.
+// random and urandom are a splitmix64 generator over the entropy of the
+// platform, they aren't for cryptography. Until seed_random gets it,
+// from the random_seed of the boot config, their reads fail with Blocking
+// (EAGAIN) and they aren't ready for poll, like getrandom with
+// GRND_NONBLOCK before the pool is initialized. On the host they're
+// seeded from the random keys of std at their first read.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use alloc::sync::Arc;
+use vfscore::{FileType, INodeInterface, Metadata, PollEvent, Stat, StatMode, VfsError, VfsResult};
+
+use crate::devnode::make_dev;
+
+/// The major of the memory devices.
+const MEM_MAJOR: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemDevice {
+    Null,
+    Zero,
+    Full,
+    Random,
+    Urandom,
+}
+
+impl MemDevice {
+    pub const ALL: [MemDevice; 5] = [
+        MemDevice::Null,
+        MemDevice::Zero,
+        MemDevice::Full,
+        MemDevice::Random,
+        MemDevice::Urandom,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MemDevice::Null => "null",
+            MemDevice::Zero => "zero",
+            MemDevice::Full => "full",
+            MemDevice::Random => "random",
+            MemDevice::Urandom => "urandom",
+        }
+    }
+
+    /// The minor of Linux.
+    pub fn minor(self) -> u32 {
+        match self {
+            MemDevice::Null => 3,
+            MemDevice::Zero => 5,
+            MemDevice::Full => 7,
+            MemDevice::Random => 8,
+            MemDevice::Urandom => 9,
+        }
+    }
+
+    pub fn rdev(self) -> u64 {
+        make_dev(MEM_MAJOR, self.minor())
+    }
+}
+
+/// The state of the generator of random and urandom.
+static RANDOM: AtomicU64 = AtomicU64::new(0x853c_49e6_748f_ea9b);
+/// The state has had the entropy of the platform.
+static SEEDED: AtomicBool = AtomicBool::new(false);
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Mix the seed in the state of random and urandom, like a write of
+/// entropy to the pool. The first seed makes them readable.
+pub fn seed_random(seed: u64) {
+    RANDOM.fetch_xor(seed.wrapping_mul(GOLDEN_GAMMA), Ordering::Relaxed);
+    SEEDED.store(true, Ordering::Release);
+}
+
+/// random and urandom have been seeded. On the host, the first call seeds
+/// them from the keys of RandomState, which std takes from the OS.
+pub fn random_seeded() -> bool {
+    #[cfg(feature = "std")]
+    if !SEEDED.load(Ordering::Acquire) {
+        use std::hash::{BuildHasher, Hasher};
+
+        seed_random(
+            std::collections::hash_map::RandomState::new()
+                .build_hasher()
+                .finish(),
+        );
+    }
+    SEEDED.load(Ordering::Acquire)
+}
+
+fn next_random() -> u64 {
+    let mut z = RANDOM
+        .fetch_add(GOLDEN_GAMMA, Ordering::Relaxed)
+        .wrapping_add(GOLDEN_GAMMA);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// The node of a memory device.
+pub struct MemNode(MemDevice);
+
+impl MemNode {
+    pub fn new(device: MemDevice) -> Arc<Self> {
+        Arc::new(Self(device))
+    }
+}
+
+impl INodeInterface for MemNode {
+    fn readat(&self, _offset: usize, buffer: &mut [u8]) -> VfsResult<usize> {
+        match self.0 {
+            MemDevice::Null => return Ok(0),
+            MemDevice::Zero | MemDevice::Full => buffer.fill(0),
+            MemDevice::Random | MemDevice::Urandom => {
+                if !random_seeded() {
+                    return Err(VfsError::Blocking);
+                }
+                for chunk in buffer.chunks_mut(8) {
+                    let len = chunk.len();
+                    chunk.copy_from_slice(&next_random().to_ne_bytes()[..len]);
+                }
+            }
+        }
+        Ok(buffer.len())
+    }
+
+    fn writeat(&self, _offset: usize, buffer: &[u8]) -> VfsResult<usize> {
+        match self.0 {
+            MemDevice::Full => Err(VfsError::StorageFull),
+            _ => Ok(buffer.len()),
+        }
+    }
+
+    /// The opens with O_TRUNC, like the redirections to /dev/null.
+    fn truncate(&self, _size: usize) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(Metadata {
+            filename: self.0.name(),
+            inode: usize::MAX,
+            file_type: FileType::Device,
+            size: 0,
+            childrens: 0,
+        })
+    }
+
+    fn stat(&self, stat: &mut Stat) -> VfsResult<()> {
+        stat.mode = StatMode::CHAR | StatMode::from_bits_truncate(0o666);
+        stat.nlink = 1;
+        stat.uid = 0;
+        stat.gid = 0;
+        stat.size = 0;
+        stat.blksize = 4096;
+        stat.blocks = 0;
+        stat.rdev = self.0.rdev() as _;
+        Ok(())
+    }
+
+    fn poll(&self, events: PollEvent) -> VfsResult<PollEvent> {
+        let mut res = PollEvent::NONE;
+        let random = matches!(self.0, MemDevice::Random | MemDevice::Urandom);
+        if events.contains(PollEvent::POLLIN) && (!random || random_seeded()) {
+            res |= PollEvent::POLLIN;
+        }
+        if events.contains(PollEvent::POLLOUT) {
+            res |= PollEvent::POLLOUT;
+        }
+        Ok(res)
+    }
+}
+
+/// The nodes of the memory devices by their name.
+pub fn mem_nodes() -> impl Iterator<Item = (&'static str, Arc<dyn INodeInterface>)> {
+    MemDevice::ALL
+        .into_iter()
+        .map(|x| (x.name(), MemNode::new(x) as Arc<dyn INodeInterface>))
+}
@@ -0,0 +1,304 @@
+// The crash consistency tests. A workload runs on a MockDisk recording
+// its writes, then the image of every prefix of the writes is rebuilt, as
+// if the power was cut after it, and mounted: the mount replays the
+// journal, the filesystem is checked, and every file must be as it was
+// before or after one of the steps of the workload, never torn between
+// them. The steps of an atomic workload, like a rename, keep the whole
+// tree in one of the states, a file can't be lost between two places.
+//
+// The filesystems without a journal run the same harness, their reports
+// document what a crash loses instead of failing: the images of the
+// prefixes which don't mount, don't pass the check or have torn files.
+//
+// run_reordering runs the steps on a MockDisk with a Reordering write
+// cache, the image of a prefix keeps the writes before the last flush in
+// the prefix and the FUA writes, and of the cached ones those picked by
+// each of the seeds, so only a filesystem ordering its writes by flushes
+// and FUA passes, it can't count on the order of the writes.
+
+use core::fmt::{self, Display, Formatter};
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use vfscore::{VfsError, VfsResult};
+
+use crate::golden::{Entry, Manifest};
+use crate::testing::{keeps_cached, MockDisk, RecordedWrite, WriteCache};
+use crate::File;
+
+/// A step of a workload, on the directory of the mount.
+pub type Step = fn(&File) -> VfsResult<()>;
+
+/// The operations under test.
+#[derive(Clone, Copy)]
+pub struct Workload {
+    pub name: &'static str,
+    /// Build the files of the steps, before the writes are recorded.
+    pub setup: Step,
+    pub steps: &'static [Step],
+    /// The whole tree is in the state before or after a step, not only
+    /// each file.
+    pub atomic: bool,
+}
+
+/// The filesystem of the images, mounted on a MockDisk.
+pub trait CrashTarget {
+    /// Mount the disk, the mount recovers an image cut by a crash.
+    fn mount(&mut self, disk: Arc<MockDisk>) -> Result<File, String>;
+    /// Write the state of the mounted filesystem to the disk, like syncfs.
+    fn sync(&mut self) -> Result<(), String>;
+    /// The problems of the mounted filesystem, like check of ext4.
+    fn check(&mut self) -> Vec<String>;
+    fn unmount(&mut self);
+}
+
+/// The prefixes of the writes to mount.
+#[derive(Debug, Clone, Copy)]
+pub enum Prefixes {
+    All,
+    /// count prefixes at random, with the empty and the whole ones.
+    Sample {
+        count: usize,
+        seed: u64,
+    },
+}
+
+/// A prefix of the writes whose image fails, the image after prefix
+/// writes.
+#[derive(Debug, Clone)]
+pub struct PrefixFailure {
+    pub prefix: usize,
+    pub reason: String,
+}
+
+/// What the crashes of a workload do.
+#[derive(Debug, Clone, Default)]
+pub struct CrashReport {
+    pub workload: &'static str,
+    /// The writes of the steps.
+    pub writes: usize,
+    /// The images of the prefixes mounted, one a prefix and a seed for
+    /// run_reordering.
+    pub prefixes: usize,
+    pub unmountable: Vec<PrefixFailure>,
+    /// The problems found by the check.
+    pub problems: Vec<PrefixFailure>,
+    /// The files in none of the states of the workload.
+    pub torn: Vec<PrefixFailure>,
+}
+
+impl CrashReport {
+    /// Every prefix mounts, passes the check and has no torn file.
+    pub fn is_consistent(&self) -> bool {
+        self.unmountable.is_empty() && self.problems.is_empty() && self.torn.is_empty()
+    }
+}
+
+impl Display for CrashReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {} writes, {} prefixes, {} unmountable, {} with problems, {} torn",
+            self.workload,
+            self.writes,
+            self.prefixes,
+            self.unmountable.len(),
+            self.problems.len(),
+            self.torn.len()
+        )?;
+        let failures = [
+            ("unmountable", &self.unmountable),
+            ("problems", &self.problems),
+            ("torn", &self.torn),
+        ];
+        for (kind, failures) in failures {
+            for x in failures.iter() {
+                writeln!(f, "  {} after {} writes: {}", kind, x.prefix, x.reason)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The prefixes to mount, sorted.
+fn prefixes(writes: usize, prefixes: Prefixes) -> Vec<usize> {
+    match prefixes {
+        Prefixes::All => (0..=writes).collect(),
+        Prefixes::Sample { count, mut seed } => {
+            let mut picked = vec![0, writes];
+            for _ in 0..count {
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                picked.push((seed >> 33) as usize % (writes + 1));
+            }
+            picked.sort_unstable();
+            picked.dedup();
+            picked
+        }
+    }
+}
+
+/// The entries of the manifest by their path.
+fn by_path(manifest: &Manifest) -> BTreeMap<&str, &Entry> {
+    manifest.0.iter().map(|x| (x.path.as_str(), x)).collect()
+}
+
+/// The first path of the manifest in none of the states, with what it
+/// holds.
+fn torn_path(states: &[Manifest], manifest: &Manifest, atomic: bool) -> Option<String> {
+    if atomic {
+        return match states.iter().any(|x| x == manifest) {
+            true => None,
+            false => Some(format!("the tree is in no state:\n{}", manifest)),
+        };
+    }
+    let states: Vec<_> = states.iter().map(by_path).collect();
+    let actual = by_path(manifest);
+    let mut paths: Vec<&str> = states.iter().flat_map(|x| x.keys().copied()).collect();
+    paths.extend(actual.keys().copied());
+    paths.sort_unstable();
+    paths.dedup();
+    paths.into_iter().find_map(|path| {
+        let entry = actual.get(path);
+        match states.iter().any(|x| x.get(path) == entry) {
+            true => None,
+            false => Some(match entry {
+                Some(entry) => format!("{} in no state", entry),
+                None => format!("{}: missing in no state", path),
+            }),
+        }
+    })
+}
+
+/// Run the workload on the image and mount the images of the prefixes of
+/// its writes, see the module.
+pub fn run(
+    target: &mut dyn CrashTarget,
+    image: &[u8],
+    sector_size: usize,
+    workload: &Workload,
+    which: Prefixes,
+) -> Result<CrashReport, String> {
+    let rebuild = |before: &[u8], writes: &[RecordedWrite], prefix: usize| {
+        let mut image = before.to_vec();
+        for x in writes[..prefix].iter() {
+            image[x.offset..x.offset + x.data.len()].copy_from_slice(&x.data);
+        }
+        vec![image]
+    };
+    run_images(
+        target,
+        image,
+        sector_size,
+        workload,
+        which,
+        WriteCache::default(),
+        rebuild,
+    )
+}
+
+/// Like run, the disk of the steps has a Reordering write cache and the
+/// image of a prefix is rebuilt for each of the seeds, see the module.
+pub fn run_reordering(
+    target: &mut dyn CrashTarget,
+    image: &[u8],
+    sector_size: usize,
+    workload: &Workload,
+    which: Prefixes,
+    seeds: &[u64],
+) -> Result<CrashReport, String> {
+    let rebuild = |before: &[u8], writes: &[RecordedWrite], prefix: usize| {
+        let prefix = &writes[..prefix];
+        // the flushes done before the power cut, those after the last
+        // write of the prefix may be lost with the writes after it.
+        let flushes = prefix.last().map_or(0, |x| x.flushes);
+        let seeds = seeds.iter().map(|seed| {
+            let mut image = before.to_vec();
+            let kept = prefix
+                .iter()
+                .enumerate()
+                .filter(|(i, x)| x.fua || x.flushes < flushes || keeps_cached(*seed, *i));
+            for (_, x) in kept {
+                image[x.offset..x.offset + x.data.len()].copy_from_slice(&x.data);
+            }
+            image
+        });
+        seeds.collect()
+    };
+    let cache = WriteCache::Reordering { seed: 0 };
+    run_images(target, image, sector_size, workload, which, cache, rebuild)
+}
+
+/// Run the workload on a disk with the cache, then mount the images
+/// rebuilt from the image before the steps and the writes of each prefix.
+fn run_images(
+    target: &mut dyn CrashTarget,
+    image: &[u8],
+    sector_size: usize,
+    workload: &Workload,
+    which: Prefixes,
+    cache: WriteCache,
+    rebuild: impl Fn(&[u8], &[RecordedWrite], usize) -> Vec<Vec<u8>>,
+) -> Result<CrashReport, String> {
+    let step_err = |what: &str, err: VfsError| format!("{} {}: {:?}", workload.name, what, err);
+    let disk = Arc::new(MockDisk::from_image(image.to_vec(), sector_size));
+    let root = target.mount(disk.clone())?;
+    (workload.setup)(&root).map_err(|x| step_err("setup", x))?;
+    target.sync()?;
+    let mut states = vec![Manifest::from_dir(&root)?];
+    drop(root);
+    target.unmount();
+    let before = disk.image();
+
+    let disk = Arc::new(MockDisk::from_image(before.clone(), sector_size));
+    disk.set_write_cache(cache);
+    disk.record_writes();
+    let root = target.mount(disk.clone())?;
+    for (i, step) in workload.steps.iter().enumerate() {
+        step(&root).map_err(|x| step_err(&format!("step {}", i), x))?;
+        target.sync()?;
+        states.push(Manifest::from_dir(&root)?);
+    }
+    drop(root);
+    target.unmount();
+    let writes = disk.recorded();
+
+    let mut report = CrashReport {
+        workload: workload.name,
+        writes: writes.len(),
+        ..Default::default()
+    };
+    let images = prefixes(writes.len(), which)
+        .into_iter()
+        .flat_map(|prefix| {
+            rebuild(&before, &writes, prefix)
+                .into_iter()
+                .map(move |x| (prefix, x))
+        });
+    for (prefix, image) in images {
+        report.prefixes += 1;
+        let fail = |reason| PrefixFailure { prefix, reason };
+        let root = match target.mount(Arc::new(MockDisk::from_image(image, sector_size))) {
+            Ok(root) => root,
+            Err(reason) => {
+                report.unmountable.push(fail(reason));
+                continue;
+            }
+        };
+        let problems = target.check();
+        if !problems.is_empty() {
+            report.problems.push(fail(problems.join(", ")));
+        }
+        match Manifest::from_dir(&root) {
+            Ok(manifest) => {
+                if let Some(reason) = torn_path(&states, &manifest, workload.atomic) {
+                    report.torn.push(fail(reason));
+                }
+            }
+            Err(reason) => report.torn.push(fail(reason)),
+        }
+        drop(root);
+        target.unmount();
+    }
+    Ok(report)
+}
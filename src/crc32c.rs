@@ -0,0 +1,33 @@
+// CRC32C (Castagnoli) used by the ext4 and jbd2 checksums.
+// Like crc32c_le of Linux, the crc isn't inverted before or after, the
+// callers pass the seed of their checksum scheme.
+
+const POLY: u32 = 0x82F63B78;
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                0 => crc >> 1,
+                _ => (crc >> 1) ^ POLY,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static TABLE: [u32; 256] = make_table();
+
+/// Update the crc with data.
+pub fn crc32c(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, x| {
+        TABLE[((crc ^ *x as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
@@ -1,18 +1,39 @@
-use core::fmt::Debug;
+use core::{
+    fmt::Debug,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
 
 use alloc::{
+    collections::BTreeMap,
     string::{String, ToString},
     sync::{Arc, Weak},
     vec::Vec,
 };
-use sync::{LazyInit, Mutex};
-use vfscore::{INodeInterface, OpenFlags, VfsError};
+use vfscore::{FileType, INodeInterface, OpenFlags, VfsError};
+
+use crate::error::{Errno, FsError, FsResult};
+use crate::handle::AccessMode;
+use crate::mounts;
+use crate::ops::{check_follow_link, check_name, check_path};
+use crate::readdir::{self, DirChange};
+use crate::sys::{LazyInit, Mutex};
+use crate::trace::{self, Target, TraceOp};
 
 pub struct DentryNode {
     pub filename: String,
     pub node: Arc<dyn INodeInterface>,
     pub parent: Weak<DentryNode>,
     pub children: Mutex<Vec<Arc<DentryNode>>>,
+    /// The dentry was unlinked from its parent.
+    removed: AtomicBool,
+    /// The entries of the directory change by themselves, see
+    /// set_volatile.
+    volatile: AtomicBool,
+    /// The dentry was cached by a lookup, it can be pruned and looked up
+    /// again, unlike the children added by hand.
+    cached: AtomicBool,
+    /// Held by a create_with in the directory, see ops::create_with.
+    pub(crate) create: Mutex<()>,
 }
 
 impl Debug for DentryNode {
@@ -32,78 +53,224 @@ impl DentryNode {
             node,
             parent,
             children: Mutex::new(Vec::new()),
+            removed: AtomicBool::new(false),
+            volatile: AtomicBool::new(false),
+            cached: AtomicBool::new(false),
+            create: Mutex::new(()),
+        }
+    }
+
+    /// Mark the entries of the directory as changing by themselves, like
+    /// the pids of /proc: its lookups and their misses aren't cached, nor
+    /// the ones under them. The children added by hand stay.
+    pub fn set_volatile(&self) {
+        self.volatile.store(true, Ordering::Release);
+    }
+
+    pub fn is_volatile(&self) -> bool {
+        self.volatile.load(Ordering::Acquire)
+    }
+
+    /// Check if the dentry was removed from the dentry tree.
+    pub fn is_removed(&self) -> bool {
+        self.removed.load(Ordering::Acquire)
+    }
+
+    /// Remove the child named name, both from the filesystem and the
+    /// dentry tree. The dentry of the child (if cached) is marked removed,
+    /// so contexts holding it fail the relative resolutions.
+    pub fn remove_child(self: &Arc<Self>, name: &str) -> Result<(), VfsError> {
+        check_name(name)?;
+        let child = self.clone().open(name, OpenFlags::NONE);
+        let is_dir = child
+            .as_ref()
+            .and_then(|x| x.node.metadata().ok())
+            .map(|x| matches!(x.file_type, FileType::Directory))
+            .unwrap_or(false);
+        if is_dir {
+            self.node.rmdir(name)?;
+        } else {
+            self.node.remove(name)?;
+        }
+        self.children.lock().retain(|x| x.filename != name);
+        if let Some(child) = child {
+            child.removed.store(true, Ordering::Release);
+        }
+        readdir::changed(&self.node, name, DirChange::Removed);
+        Ok(())
+    }
+
+    /// Drop the dentries of the entry old renamed to new of new_dir, the
+    /// dentry of a replaced target is marked removed. The moved entry is
+    /// looked up again under new_dir, the contexts holding its dentry
+    /// keep the old path.
+    pub fn renamed(self: &Arc<Self>, old: &str, new_dir: &Arc<DentryNode>, new: &str) {
+        self.children.lock().retain(|x| x.filename != old);
+        let mut children = new_dir.children.lock();
+        if let Some(i) = children.iter().position(|x| x.filename == new) {
+            children.remove(i).removed.store(true, Ordering::Release);
         }
+        drop(children);
+        forget_negative(new_dir, new);
+    }
+
+    /// Forget the cached entries old and new of new_dir after an exchange
+    /// of rename, each name holds the node of the other one now.
+    pub fn exchanged(self: &Arc<Self>, old: &str, new_dir: &Arc<DentryNode>, new: &str) {
+        self.children.lock().retain(|x| x.filename != old);
+        new_dir.children.lock().retain(|x| x.filename != new);
     }
 
     /// Mount a fs to DentryTree, return Some if successfully mounted.
     /// path: The mounted path.
     /// node: fs root directory node.
     pub fn mount(path: String, node: Arc<dyn INodeInterface>) -> Result<(), VfsError> {
-        let paths = path.split("/").into_iter();
-        let mut dentry = DENTRY_TREE.lock().clone();
-
-        for x in paths {
-            dentry = match x {
-                "." | "" => dentry,
-                ".." => dentry.parent.upgrade().unwrap_or(dentry),
-                filename => {
-                    let finded = dentry
-                        .children
-                        .lock()
-                        .iter()
-                        .find(|x| x.filename == *filename)
-                        .cloned();
-                    log::debug!("open: {}", filename);
-                    match finded {
-                        Some(new_dentry) => new_dentry,
-                        None => dentry.node.open(filename, OpenFlags::NONE).map(|x| {
-                            Arc::new(DentryNode::new(
-                                filename.to_string(),
-                                x,
-                                Arc::downgrade(&dentry),
-                            ))
-                        })?,
-                    }
-                }
-            }
-        }
-        dentry
-            .parent
-            .upgrade()
-            .unwrap()
-            .children
-            .lock()
-            .push(Arc::new(DentryNode::new(
-                dentry.filename.clone(),
-                node,
-                dentry.parent.clone(),
-            )));
-        Ok(())
+        Self::mount_at(&dentry_root(), &path, node)
+    }
+
+    /// Mount the root node of a fs on the path in the view of root. A
+    /// path already mounted is overmounted, the new mount hides the one
+    /// below until it's unmounted.
+    pub fn mount_at(
+        root: &Arc<DentryNode>,
+        path: &str,
+        node: Arc<dyn INodeInterface>,
+    ) -> Result<(), VfsError> {
+        let ctx = ResolveContext::with_root(root.clone());
+        let mountpoint = dentry_open_at(&ctx, path, OpenFlags::NONE)?;
+        attach(mountpoint, node)
     }
 
-    pub fn unmount(_path: String) -> Result<(), VfsError> {
-        todo!("unmount in dentry node");
+    /// Bind mount the dentry source on the path in the view of root, like
+    /// mount --bind: the path shows the entries of source. The mounts
+    /// under source aren't bound with it.
+    /// TODO: the dentries under the two paths are apart, a removal
+    /// through one leaves the other cached until it's looked up again.
+    pub fn bind_at(
+        root: &Arc<DentryNode>,
+        source: &Arc<DentryNode>,
+        path: &str,
+    ) -> Result<(), VfsError> {
+        let ctx = ResolveContext::with_root(root.clone());
+        let mountpoint = dentry_open_at(&ctx, path, OpenFlags::NONE)?;
+        attach(mountpoint, source.node.clone())
+    }
+
+    /// Unmount the filesystem mounted on the path, see mounts::umount.
+    pub fn unmount(path: String) -> Result<(), VfsError> {
+        crate::mounts::umount(&path).map_err(VfsError::from)
     }
 
     pub fn open(self: Arc<DentryNode>, name: &str, flags: OpenFlags) -> Option<Arc<DentryNode>> {
+        self.open_entry(name, flags).ok()
+    }
+
+    /// Open the child like open, with the error of the node. A creating
+    /// open with O_EXCL fails with AlreadyExists if the name exists. A name
+    /// cached as missing is created without a lookup before, by an open
+    /// with O_EXCL, which the filesystems creating by open check under
+    /// their directory lock: its AlreadyExists is a name another task
+    /// created since, a plain O_CREAT opens it.
+    pub fn open_entry(
+        self: Arc<DentryNode>,
+        name: &str,
+        flags: OpenFlags,
+    ) -> Result<Arc<DentryNode>, VfsError> {
         let mut children = self.children.lock();
+        let creating = flags.contains(OpenFlags::O_CREAT);
+        let exclusive = creating && flags.contains(OpenFlags::O_EXCL);
+        let volatile = self.is_volatile();
         if let Some(dnode) = children.iter().find(|x| x.filename == name) {
-            Some(dnode.clone())
+            return match exclusive {
+                true => Err(VfsError::AlreadyExists),
+                false => Ok(cross_mounts(dnode.clone())),
+            };
+        }
+        let missing = !volatile && is_negative(&self, name);
+        if missing && !creating {
+            return Err(VfsError::FileNotFound);
+        }
+        // the node is shared by all the opens of the path, open it
+        // read-write, FileHandle applies the access mode of each open.
+        let flags = (flags - OpenFlags::O_WRONLY - OpenFlags::O_PATH) | OpenFlags::O_RDWR;
+        let opened = if creating && missing {
+            match self.node.open(name, flags | OpenFlags::O_EXCL) {
+                Err(VfsError::AlreadyExists) if !exclusive => {
+                    self.node.open(name, flags - OpenFlags::O_EXCL)
+                }
+                r => r,
+            }
         } else {
-            match self.node.open(name, flags) {
-                Ok(node) => {
-                    // add node to dentry node children.
-                    let child_dentry = Arc::new(DentryNode::new(
-                        name.to_string(),
-                        node,
-                        Arc::downgrade(&self),
-                    ));
+            // a filesystem which opens by a lookup doesn't see O_EXCL.
+            if exclusive && self.node.lookup(name).is_ok() {
+                return Err(VfsError::AlreadyExists);
+            }
+            self.node.open(name, flags)
+        };
+        match opened {
+            Ok(node) => {
+                if creating {
+                    forget_negative(&self, name);
+                }
+                // add node to dentry node children.
+                let child_dentry = Arc::new(DentryNode::new(
+                    name.to_string(),
+                    node,
+                    Arc::downgrade(&self),
+                ));
+                if volatile {
+                    child_dentry.set_volatile();
+                } else {
+                    child_dentry.cached.store(true, Ordering::Release);
+                    CACHED_DENTRIES.fetch_add(1, Ordering::Relaxed);
                     children.push(child_dentry.clone());
-                    Some(child_dentry)
                 }
-                Err(_) => None,
+                Ok(child_dentry)
+            }
+            Err(VfsError::FileNotFound) if !creating && !volatile => {
+                add_negative(&self, name);
+                Err(VfsError::FileNotFound)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Add the child created by the node, the negative dentry of its name
+    /// becomes the child once. A child of the name another task added
+    /// since is kept instead.
+    fn add_created(self: &Arc<Self>, name: &str, node: Arc<dyn INodeInterface>) -> Arc<Self> {
+        let mut children = self.children.lock();
+        if let Some(dnode) = children.iter().find(|x| x.filename == name) {
+            return dnode.clone();
+        }
+        let child = Arc::new(DentryNode::new(
+            name.to_string(),
+            node,
+            Arc::downgrade(self),
+        ));
+        children.push(child.clone());
+        forget_negative(self, name);
+        child
+    }
+
+    /// Get the path of the dentry in the view of root.
+    /// The path is the same as path() if root isn't an ancestor.
+    pub fn path_from(self: &Arc<Self>, root: &Arc<DentryNode>) -> String {
+        let mut path = String::new();
+        let mut dentry = self.clone();
+        while !Arc::ptr_eq(&dentry, root) {
+            match dentry.parent.upgrade() {
+                Some(parent) => {
+                    path = String::from("/") + &dentry.filename + &path;
+                    dentry = parent;
+                }
+                None => break,
             }
         }
+        if path.is_empty() {
+            path = String::from("/");
+        }
+        path
     }
 
     pub fn path(&self) -> String {
@@ -125,42 +292,785 @@ impl DentryNode {
 
 pub static DENTRY_TREE: LazyInit<Mutex<Arc<DentryNode>>> = LazyInit::new();
 
+/// A mounted filesystem or bind mount. Its root covers the mountpoint,
+/// the dentry of the path in the mount below, which is the root of that
+/// mount on an overmount. The root has the name and the parent of the
+/// mountpoint, so the paths through the mount are the ones in the mount
+/// below.
+pub struct Mount {
+    pub root: Arc<DentryNode>,
+    pub mountpoint: Arc<DentryNode>,
+    /// The mount holding the mountpoint, None for the root filesystem.
+    pub parent: Option<Arc<Mount>>,
+}
+
+/// The mounts in the order they were mounted, an overmount follows the
+/// mount it covers.
+static MOUNTS: Mutex<Vec<Arc<Mount>>> = Mutex::new(Vec::new());
+
+/// Mount node on the mountpoint, the open mountpoint is the top of the
+/// mounts on the path.
+fn attach(mountpoint: Arc<DentryNode>, node: Arc<dyn INodeInterface>) -> Result<(), VfsError> {
+    // the root of the dentry tree is replaced by dentry_init instead.
+    let parent = mountpoint.parent.upgrade().ok_or(VfsError::InvalidInput)?;
+    let root = Arc::new(DentryNode::new(
+        mountpoint.filename.clone(),
+        node,
+        Arc::downgrade(&parent),
+    ));
+    let mount = Mount {
+        root,
+        parent: mount_of(&mountpoint),
+        mountpoint,
+    };
+    // the lookups cross from the cached dentry of the mountpoint, the open
+    // of a volatile directory doesn't cache it.
+    if mount
+        .parent
+        .as_ref()
+        .is_none_or(|x| !Arc::ptr_eq(&x.root, &mount.mountpoint))
+    {
+        let mut children = parent.children.lock();
+        if !children.iter().any(|x| Arc::ptr_eq(x, &mount.mountpoint)) {
+            children.push(mount.mountpoint.clone());
+        }
+    }
+    // the names missing in the covered directory aren't looked up again.
+    forget_negative_under(&mount.mountpoint);
+    MOUNTS.lock().push(Arc::new(mount));
+    Ok(())
+}
+
+/// Detach the mount whose root is the dentry, the path shows the
+/// mountpoint again and the names missing under it are looked up again.
+/// A mount under another mount or the root filesystem fails with
+/// InvalidInput, and a mount with mounts under it with EBUSY. The
+/// dentries held under the mount stay usable, like a lazy unmount.
+pub fn detach(root: &Arc<DentryNode>) -> FsResult<Arc<Mount>> {
+    let mut mounts = MOUNTS.lock();
+    let index = mounts
+        .iter()
+        .rposition(|x| Arc::ptr_eq(&x.root, root))
+        .ok_or(VfsError::InvalidInput)?;
+    let mount = mounts[index].clone();
+    if mounts
+        .iter()
+        .any(|x| x.parent.as_ref().is_some_and(|x| Arc::ptr_eq(x, &mount)))
+    {
+        return Err(FsError::new(VfsError::InvalidInput, Errno::EBUSY));
+    }
+    mounts.remove(index);
+    drop(mounts);
+    forget_negative_under(&mount.mountpoint);
+    Ok(mount)
+}
+
+/// The mounts in the order they were mounted.
+pub fn mounts() -> Vec<Arc<Mount>> {
+    MOUNTS.lock().clone()
+}
+
+/// The root of the topmost mount on the dentry, the dentry itself if
+/// nothing is mounted on it.
+fn cross_mounts(mut dentry: Arc<DentryNode>) -> Arc<DentryNode> {
+    let mounts = MOUNTS.lock();
+    while let Some(mount) = mounts.iter().find(|x| Arc::ptr_eq(&x.mountpoint, &dentry)) {
+        dentry = mount.root.clone();
+    }
+    dentry
+}
+
+/// The mount the dentry is in, None for the root filesystem.
+pub fn mount_of(dentry: &Arc<DentryNode>) -> Option<Arc<Mount>> {
+    let mounts = MOUNTS.lock();
+    let mut dentry = Some(dentry.clone());
+    while let Some(x) = dentry {
+        if let Some(mount) = mounts.iter().find(|m| Arc::ptr_eq(&m.root, &x)) {
+            return Some(mount.clone());
+        }
+        dentry = x.parent.upgrade();
+    }
+    None
+}
+
+/// Check if the entry name of the directory node is the mount point of a
+/// filesystem. The directory is matched by its node, so it must be the
+/// node of the dentry of the parent, the nodes the filesystem creates on
+/// each lookup aren't recognized.
+pub fn is_mount_point(dir: &Arc<dyn INodeInterface>, name: &str) -> bool {
+    mounted_at(dir, name).is_some()
+}
+
+/// Get the root of the filesystem mounted on the entry name of the
+/// directory node, matched like is_mount_point.
+pub fn mounted_at(dir: &Arc<dyn INodeInterface>, name: &str) -> Option<Arc<dyn INodeInterface>> {
+    // the last one is the top of an overmount.
+    MOUNTS
+        .lock()
+        .iter()
+        .rev()
+        .map(|x| &x.root)
+        .find(|x| {
+            x.filename == name
+                && x.parent.upgrade().is_some_and(|parent| {
+                    core::ptr::addr_eq(Arc::as_ptr(&parent.node), Arc::as_ptr(dir))
+                })
+        })
+        .map(|x| x.node.clone())
+}
+
+/// The default number of the negative dentries.
+pub const NEGATIVE_BUDGET: usize = 1024;
+
+/// The negative dentries, the names known to be missing in a directory by
+/// the address of its dentry. Beyond the budget they're evicted by a
+/// clock over their slots: a lookup marks its slot used, and the hand
+/// evicts the first unused slot it meets and clears the marks it passes.
+/// The dentry is held weakly, so its address isn't reused while the names
+/// are cached.
+struct NegativeCache {
+    /// The slot of each name by the address of its directory.
+    entries: BTreeMap<usize, BTreeMap<String, usize>>,
+    slots: Vec<Option<NegativeSlot>>,
+    /// The indexes of the empty slots.
+    free: Vec<usize>,
+    /// The slot the clock looks at next.
+    hand: usize,
+    len: usize,
+    budget: usize,
+}
+
+struct NegativeSlot {
+    dir: Weak<DentryNode>,
+    name: String,
+    /// Looked up since the hand passed.
+    used: bool,
+}
+
+impl NegativeCache {
+    fn contains(&mut self, dir: &Arc<DentryNode>, name: &str) -> bool {
+        let Some(&index) = self
+            .entries
+            .get(&(Arc::as_ptr(dir) as usize))
+            .and_then(|x| x.get(name))
+        else {
+            return false;
+        };
+        if let Some(slot) = self.slots[index].as_mut() {
+            slot.used = true;
+        }
+        true
+    }
+
+    fn insert(&mut self, dir: &Arc<DentryNode>, name: &str) {
+        if self.budget == 0 || self.contains(dir, name) {
+            return;
+        }
+        while self.len >= self.budget {
+            self.evict();
+        }
+        let slot = NegativeSlot {
+            dir: Arc::downgrade(dir),
+            name: name.to_string(),
+            used: false,
+        };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.slots[index] = Some(slot);
+                index
+            }
+            None => {
+                self.slots.push(Some(slot));
+                self.slots.len() - 1
+            }
+        };
+        self.entries
+            .entry(Arc::as_ptr(dir) as usize)
+            .or_default()
+            .insert(name.to_string(), index);
+        self.len += 1;
+    }
+
+    fn remove(&mut self, dir: &Arc<DentryNode>, name: &str) {
+        let index = self
+            .entries
+            .get(&(Arc::as_ptr(dir) as usize))
+            .and_then(|x| x.get(name));
+        if let Some(&index) = index {
+            self.remove_slot(index);
+        }
+    }
+
+    fn remove_slot(&mut self, index: usize) {
+        let Some(slot) = self.slots[index].take() else {
+            return;
+        };
+        let dir = Weak::as_ptr(&slot.dir) as usize;
+        if let Some(names) = self.entries.get_mut(&dir) {
+            names.remove(&slot.name);
+            if names.is_empty() {
+                self.entries.remove(&dir);
+            }
+        }
+        self.free.push(index);
+        self.len -= 1;
+    }
+
+    /// Move the hand to the first unused slot and evict it.
+    fn evict(&mut self) {
+        while self.len > 0 {
+            let index = self.hand;
+            self.hand = (self.hand + 1) % self.slots.len();
+            match self.slots[index].as_mut() {
+                Some(slot) if slot.used => slot.used = false,
+                Some(_) => return self.remove_slot(index),
+                None => {}
+            }
+        }
+    }
+
+    fn retain(&mut self, mut f: impl FnMut(&NegativeSlot) -> bool) {
+        for index in 0..self.slots.len() {
+            if self.slots[index].as_ref().is_some_and(|x| !f(x)) {
+                self.remove_slot(index);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.slots.clear();
+        self.free.clear();
+        self.hand = 0;
+        self.len = 0;
+    }
+}
+
+static NEGATIVE: Mutex<NegativeCache> = Mutex::new(NegativeCache {
+    entries: BTreeMap::new(),
+    slots: Vec::new(),
+    free: Vec::new(),
+    hand: 0,
+    len: 0,
+    budget: NEGATIVE_BUDGET,
+});
+
+fn is_negative(dir: &Arc<DentryNode>, name: &str) -> bool {
+    NEGATIVE.lock().contains(dir, name)
+}
+
+fn add_negative(dir: &Arc<DentryNode>, name: &str) {
+    NEGATIVE.lock().insert(dir, name);
+}
+
+fn forget_negative(dir: &Arc<DentryNode>, name: &str) {
+    NEGATIVE.lock().remove(dir, name);
+}
+
+/// Forget the names missing under the dentry, in it and in its cached
+/// descendants.
+fn forget_negative_under(dir: &Arc<DentryNode>) {
+    NEGATIVE.lock().retain(|slot| {
+        let mut dentry = slot.dir.upgrade();
+        while let Some(x) = dentry {
+            if Arc::ptr_eq(&x, dir) {
+                return false;
+            }
+            dentry = x.parent.upgrade();
+        }
+        true
+    });
+}
+
+/// Forget that the entry name is missing in the directory node, after it
+/// was created by the node instead of resolving a path with O_CREAT. The
+/// directory is matched by the node of its dentry like is_mount_point.
+pub fn invalidate_negative(dir: &Arc<dyn INodeInterface>, name: &str) {
+    NEGATIVE.lock().retain(|slot| {
+        slot.name != name
+            || !slot
+                .dir
+                .upgrade()
+                .is_some_and(|x| core::ptr::addr_eq(Arc::as_ptr(&x.node), Arc::as_ptr(dir)))
+    });
+}
+
+/// Set the number of the negative dentries, 0 disables them.
+pub fn set_negative_budget(budget: usize) {
+    let mut cache = NEGATIVE.lock();
+    cache.budget = budget;
+    while cache.len > budget {
+        cache.evict();
+    }
+}
+
+/// Drop all the negative dentries, like drop_caches.
+pub fn drop_negative_dentries() {
+    NEGATIVE.lock().clear();
+}
+
+/// The number of the negative dentries.
+pub fn negative_dentries() -> usize {
+    NEGATIVE.lock().len
+}
+
+/// The dentries cached by the lookups which are alive.
+static CACHED_DENTRIES: AtomicUsize = AtomicUsize::new(0);
+
+impl Drop for DentryNode {
+    fn drop(&mut self) {
+        if self.cached.load(Ordering::Relaxed) {
+            CACHED_DENTRIES.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl DentryNode {
+    /// The cached dentry isn't held by anything but its parent, nor its
+    /// node by anything but the dentry, and it has no children: nothing
+    /// is open through it and it can be pruned. The others are pinned.
+    fn is_unpinned(self: &Arc<Self>) -> bool {
+        self.cached.load(Ordering::Acquire)
+            && Arc::strong_count(self) == 1
+            && Arc::strong_count(&self.node) == 1
+            && self.children.lock().is_empty()
+    }
+}
+
+/// The counts of the positive dentries cached by the lookups.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DentryStats {
+    pub cached: usize,
+    /// The cached dentries which can't be pruned now, see prune_dentries.
+    pub pinned: usize,
+}
+
+/// The dentries the walks start from: the root and those of the mounts.
+fn tree_roots() -> Vec<Arc<DentryNode>> {
+    let mut roots = vec![dentry_root()];
+    roots.extend(mounts().into_iter().map(|x| x.root.clone()));
+    roots
+}
+
+/// The unpinned dentries under dir, counted without holding them.
+fn count_unpinned(dir: &Arc<DentryNode>) -> usize {
+    let children = dir.children.lock();
+    children
+        .iter()
+        .map(|x| count_unpinned(x) + x.is_unpinned() as usize)
+        .sum()
+}
+
+/// Prune at most budget of the unpinned dentries under dir, the leaves
+/// first, so a directory whose children were all pruned goes too.
+fn prune_under(dir: &Arc<DentryNode>, budget: &mut usize) -> usize {
+    let mut children = dir.children.lock();
+    let mut pruned: usize = children.iter().map(|x| prune_under(x, budget)).sum();
+    children.retain(|x| {
+        if *budget == 0 || !x.is_unpinned() {
+            return true;
+        }
+        *budget -= 1;
+        pruned += 1;
+        false
+    });
+    pruned
+}
+
+/// Drop the cached dentries under dir which aren't on the way to a
+/// mountpoint, those added by hand stay.
+fn invalidate_under(dir: &Arc<DentryNode>, mountpoints: &[Arc<DentryNode>]) -> usize {
+    let mut children = dir.children.lock();
+    let mut dropped: usize = children
+        .iter()
+        .map(|x| invalidate_under(x, mountpoints))
+        .sum();
+    children.retain(|x| {
+        if !x.cached.load(Ordering::Acquire)
+            || !x.children.lock().is_empty()
+            || mountpoints.iter().any(|m| Arc::ptr_eq(m, x))
+        {
+            return true;
+        }
+        dropped += 1;
+        false
+    });
+    dropped
+}
+
+/// Forget the dentries of the filesystem whose root directory is root,
+/// after its disk was changed by another writer, see revalidate.rs: the
+/// negative dentries and those of the lookups under the root are looked
+/// up again. The dentries held by an open file stay alive off the tree.
+/// return the number of the dropped dentries.
+pub fn invalidate_fs(root: &Arc<dyn INodeInterface>) -> usize {
+    let mountpoints: Vec<_> = mounts().into_iter().map(|x| x.mountpoint.clone()).collect();
+    tree_roots()
+        .iter()
+        .filter(|x| core::ptr::addr_eq(Arc::as_ptr(&x.node), Arc::as_ptr(root)))
+        .map(|x| {
+            forget_negative_under(x);
+            invalidate_under(x, &mountpoints)
+        })
+        .sum()
+}
+
+/// The counts of the cached dentries, the pinned ones are those held by
+/// an open file, a working directory, a mount or a cached child.
+pub fn dentry_stats() -> DentryStats {
+    let cached = CACHED_DENTRIES.load(Ordering::Relaxed);
+    let unpinned: usize = tree_roots().iter().map(count_unpinned).sum();
+    DentryStats {
+        cached,
+        pinned: cached.saturating_sub(unpinned),
+    }
+}
+
+/// Prune at most max of the unpinned dentries of the tree, like
+/// drop_negative_dentries does for the negative ones, the kernel calls it
+/// when the memory is low. The nodes of the pruned dentries are dropped,
+/// so their filesystems can free their inodes. The pinned dentries stay.
+/// return the number of the pruned dentries.
+/// TODO: a Shrinker of cache.rs can't prune them, an open holds the lock
+/// of its directory while the filesystem fills the caches.
+pub fn prune_dentries(max: usize) -> usize {
+    let mut budget = max;
+    tree_roots()
+        .iter()
+        .map(|x| prune_under(x, &mut budget))
+        .sum()
+}
+
+/// prune_dentries for the tree under dir, for the trees which aren't the
+/// dentry tree.
+pub fn prune_dentries_under(dir: &Arc<DentryNode>, max: usize) -> usize {
+    let mut budget = max;
+    prune_under(dir, &mut budget)
+}
+
+/// The max depth of nested symbol links while resolving a path.
+pub const MAX_SYMLINK_DEPTH: usize = 40;
+
+/// The max depth of a resolved dentry below the root of the context by
+/// default, a deeper path fails, so a directory loop of a corrupted
+/// filesystem can't make the dentries grow without end.
+pub const DEFAULT_MAX_PATH_DEPTH: usize = 64;
+
+/// Cwd is the current working directory of a task.
+/// path: the canonical path (symbol links resolved) in the view of the
+/// context root, it's returned by getcwd.
+#[derive(Clone)]
+pub struct Cwd {
+    pub dentry: Arc<DentryNode>,
+    path: String,
+}
+
+impl Cwd {
+    pub fn new(dentry: Arc<DentryNode>, root: &Arc<DentryNode>) -> Self {
+        Self {
+            path: dentry.path_from(root),
+            dentry,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+/// The credentials of a task, the ops layer checks the permissions with
+/// them. uid 0 is root.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cred {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Cred {
+    pub const ROOT: Cred = Cred { uid: 0, gid: 0 };
+
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+}
+
+/// ResolveContext describes how a task sees the dentry tree.
+/// root: the absolute paths and `..` are resolved from it, `..` at the
+/// root is clamped to the root, so the task can't escape from the root.
+/// cwd: the relative paths are resolved from it.
+/// cred: the credentials of the task, root by default.
+/// max_depth: the max depth of the components below the root, a path
+/// going deeper fails with InvalidInput, DEFAULT_MAX_PATH_DEPTH by
+/// default.
+/// protected: the links are protected like fs.protected_symlinks and
+/// fs.protected_hardlinks of Linux, see check_follow_link and
+/// check_hard_link of ops.rs. It's on with the credentials of with_cred.
+#[derive(Clone)]
+pub struct ResolveContext {
+    pub root: Arc<DentryNode>,
+    pub cwd: Cwd,
+    pub cred: Cred,
+    pub max_depth: usize,
+    pub protected: bool,
+}
+
+impl ResolveContext {
+    /// Create a context that sees the whole dentry tree.
+    pub fn new(cwd: Arc<DentryNode>) -> Self {
+        let root = dentry_root();
+        Self {
+            cwd: Cwd::new(cwd, &root),
+            root,
+            cred: Cred::ROOT,
+            max_depth: DEFAULT_MAX_PATH_DEPTH,
+            protected: false,
+        }
+    }
+
+    /// Create a context confined to the subtree of root, like chroot.
+    pub fn with_root(root: Arc<DentryNode>) -> Self {
+        Self {
+            cwd: Cwd::new(root.clone(), &root),
+            root,
+            cred: Cred::ROOT,
+            max_depth: DEFAULT_MAX_PATH_DEPTH,
+            protected: false,
+        }
+    }
+
+    /// The context acting with the credentials, with the links protected.
+    pub fn with_cred(mut self, cred: Cred) -> Self {
+        self.cred = cred;
+        self.protected = true;
+        self
+    }
+
+    /// Protect the links or not, see protected.
+    pub fn with_protected(mut self, protected: bool) -> Self {
+        self.protected = protected;
+        self
+    }
+
+    /// The context resolving the paths up to the depth below its root.
+    pub fn with_max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Change the current working directory, the target must be a directory.
+    pub fn chdir(&mut self, path: &str) -> Result<(), VfsError> {
+        let dentry = resolve(
+            self,
+            self.cwd.dentry.clone(),
+            path,
+            OpenFlags::NONE,
+            true,
+            0,
+        )?;
+        let metadata = dentry.node.metadata()?;
+        if !matches!(metadata.file_type, FileType::Directory) {
+            return Err(VfsError::NotDir);
+        }
+        self.cwd = Cwd::new(dentry, &self.root);
+        Ok(())
+    }
+
+    /// Get the canonical path of the current working directory.
+    pub fn getcwd(&self) -> &str {
+        self.cwd.path()
+    }
+
+    /// Get the parent of the dentry, the root of the context has no parent.
+    /// The parent of the root of a mount is the parent of its mountpoint,
+    /// through the mounts it covers.
+    fn parent_of(&self, dentry: &Arc<DentryNode>) -> Arc<DentryNode> {
+        let mut dentry = dentry.clone();
+        loop {
+            if Arc::ptr_eq(&dentry, &self.root) {
+                return dentry;
+            }
+            let mountpoint = MOUNTS
+                .lock()
+                .iter()
+                .find(|x| Arc::ptr_eq(&x.root, &dentry))
+                .map(|x| x.mountpoint.clone());
+            match mountpoint {
+                Some(mountpoint) => dentry = mountpoint,
+                None => break,
+            }
+        }
+        dentry.parent.upgrade().unwrap_or(dentry)
+    }
+
+    /// The depth of the dentry below the root, counted up to one over
+    /// max_depth. A dentry outside of the root counts to the top of the
+    /// tree.
+    fn depth_of(&self, dentry: &Arc<DentryNode>) -> usize {
+        let mut dentry = dentry.clone();
+        let mut depth = 0;
+        while depth <= self.max_depth {
+            let parent = self.parent_of(&dentry);
+            if Arc::ptr_eq(&parent, &dentry) {
+                break;
+            }
+            dentry = parent;
+            depth += 1;
+        }
+        depth
+    }
+}
+
+fn is_link(dentry: &Arc<DentryNode>) -> bool {
+    dentry
+        .node
+        .metadata()
+        .map(|x| matches!(x.file_type, FileType::Link))
+        .unwrap_or(false)
+}
+
 /// dentry_open function will open the dentry node by path and dentry.
 /// path should will be rebuild.
 /// flags not be used at now.
 pub fn dentry_open(
+    dentry: Arc<DentryNode>,
+    path: &str,
+    flags: OpenFlags,
+) -> FsResult<Arc<DentryNode>> {
+    dentry_open_at(&ResolveContext::new(dentry), path, flags)
+}
+
+/// dentry_open_at function will open the dentry node by path in the view
+/// of the ctx. The symbol links are followed, the targets start with '/'
+/// are resolved from the root of the ctx. With O_NOFOLLOW the last item
+/// isn't followed, a symbol link there fails the open unless O_PATH is
+/// set too, then the dentry of the link itself is opened, like lstat and
+/// readlink want.
+pub fn dentry_open_at(
+    ctx: &ResolveContext,
+    path: &str,
+    flags: OpenFlags,
+) -> FsResult<Arc<DentryNode>> {
+    // the trace has the VfsError, the errno is kept beside it.
+    let mut failed = None;
+    let r = trace::traced(
+        TraceOp::Open,
+        "dentry",
+        || Target::Path(path),
+        0,
+        0,
+        || {
+            open_at(ctx, path, flags).map_err(|err| {
+                failed = Some(err);
+                err.error
+            })
+        },
+    );
+    r.map_err(|error| failed.unwrap_or_else(|| error.into()))
+}
+
+fn open_at(ctx: &ResolveContext, path: &str, flags: OpenFlags) -> FsResult<Arc<DentryNode>> {
+    check_path(path)?;
+    let nofollow = flags.contains(OpenFlags::O_NOFOLLOW);
+    let dentry = resolve(ctx, ctx.cwd.dentry.clone(), path, flags, !nofollow, 0)?;
+    if nofollow && !flags.contains(OpenFlags::O_PATH) && is_link(&dentry) {
+        return Err(FsError::new(VfsError::InvalidInput, Errno::ELOOP));
+    }
+    // a read-only filesystem fails the writes with NotSupported only, the
+    // open fails first with EROFS like Linux.
+    if (AccessMode::from_flags(flags).can_write() || flags.contains(OpenFlags::O_TRUNC))
+        && dentry
+            .node
+            .metadata()
+            .is_ok_and(|x| matches!(x.file_type, FileType::File))
+    {
+        mounts::check_writable(dentry.node.as_ref())?;
+    }
+    Ok(dentry)
+}
+
+fn resolve(
+    ctx: &ResolveContext,
     mut dentry: Arc<DentryNode>,
     path: &str,
     flags: OpenFlags,
-) -> Result<Arc<DentryNode>, VfsError> {
+    follow_last: bool,
+    depth: usize,
+) -> FsResult<Arc<DentryNode>> {
+    if depth > MAX_SYMLINK_DEPTH {
+        return Err(FsError::new(VfsError::InvalidInput, Errno::ELOOP));
+    }
     if path.starts_with("/") {
-        dentry = DENTRY_TREE.lock().clone();
+        dentry = ctx.root.clone();
+    } else if dentry.is_removed() {
+        return Err(VfsError::FileNotFound.into());
     }
+    let mut level = ctx.depth_of(&dentry);
     let mut path_peeker = path.split("/").peekable();
     while let Some(filename) = path_peeker.next() {
+        // O_EXCL is of the last item.
+        let last = path_peeker.peek().is_none();
+        let item_flags = match last {
+            true => flags,
+            false => flags - OpenFlags::O_EXCL,
+        };
+        let exclusive = item_flags.contains(OpenFlags::O_CREAT | OpenFlags::O_EXCL);
         let new_dentry = match filename {
             "." | "" => Some(dentry.clone()),
-            ".." => Some(dentry.parent.upgrade().unwrap_or(dentry.clone())),
-            x => dentry.clone().open(x, flags.clone()),
+            ".." => {
+                level = level.saturating_sub(1);
+                Some(ctx.parent_of(&dentry))
+            }
+            _ if level >= ctx.max_depth => {
+                return Err(FsError::new(VfsError::InvalidInput, Errno::ENAMETOOLONG));
+            }
+            x => {
+                level += 1;
+                // the filesystems flatten the ENAMETOOLONG of a long name.
+                check_name(x)?;
+                match dentry.clone().open_entry(x, item_flags) {
+                    Ok(dentry) => Some(dentry),
+                    Err(VfsError::AlreadyExists) if exclusive => {
+                        return Err(VfsError::AlreadyExists.into());
+                    }
+                    // only a missing name is created, the other failures
+                    // of the lookup are the failure of the open.
+                    Err(VfsError::FileNotFound) => None,
+                    Err(err) => return Err(err.into()),
+                }
+            }
         };
         if let Some(new_dentry) = new_dentry {
-            dentry = new_dentry;
+            // follow the symbol link if it isn't the last item.
+            if (follow_last || path_peeker.peek().is_some()) && is_link(&new_dentry) {
+                if ctx.protected {
+                    check_follow_link(ctx.cred, dentry.node.as_ref(), new_dentry.node.as_ref())?;
+                }
+                let target = new_dentry.node.resolve_link()?;
+                dentry = resolve(ctx, dentry, &target, item_flags, true, depth + 1)?;
+                level = ctx.depth_of(&dentry);
+            } else {
+                dentry = new_dentry;
+            }
         } else if flags.contains(OpenFlags::O_CREAT) {
-            // is not the last item
-            let node = if path_peeker.peek().is_some() || flags.contains(OpenFlags::O_DIRECTORY) {
-                dentry.node.mkdir(filename)?
+            mounts::check_writable(dentry.node.as_ref())?;
+            let created = if !last || flags.contains(OpenFlags::O_DIRECTORY) {
+                dentry.node.mkdir(filename)
             } else {
-                dentry.node.touch(filename)?
+                dentry.node.touch(filename)
+            };
+            dentry = match created {
+                Ok(node) => {
+                    readdir::changed(&dentry.node, filename, DirChange::Added);
+                    dentry.add_created(filename, node)
+                }
+                // another task created it since the open missed it.
+                Err(VfsError::AlreadyExists) if !exclusive => dentry
+                    .clone()
+                    .open_entry(filename, item_flags - OpenFlags::O_CREAT)?,
+                Err(err) => return Err(err.into()),
             };
-            let new_dentry = Arc::new(DentryNode::new(
-                filename.to_string(),
-                node,
-                Arc::downgrade(&dentry),
-            ));
-            dentry.children.lock().push(new_dentry.clone());
-            dentry = new_dentry;
         } else {
-            return Err(VfsError::FileNotFound);
+            return Err(VfsError::FileNotFound.into());
         }
     }
     Ok(dentry)
@@ -0,0 +1,302 @@
+// The block device nodes of /dev, for the raw I/O of dd and mkfs. A
+// BlockNode is a sys device, /dev/vda, or one of the partitions in its
+// MBR, /dev/vda1, and reads and writes its bytes at any offset: the
+// sectors at the unaligned edges of an access are read, modified and
+// written back. build_devfs adds the nodes of block_nodes to devfs.
+// TODO: read the partitions of a GPT too, a protective MBR has none.
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use vfscore::{FileType, INodeInterface, Metadata, Stat, StatMode, VfsError, VfsResult};
+
+use crate::fstype::MountSource;
+use crate::ops::check_range;
+use crate::sys::{get_blk_device, get_blk_devices};
+
+/// The size of the sectors of the devices of sys.
+const SECTOR_SIZE: usize = 512;
+/// The major of the virtio disks of Linux, the minors of a disk are 16
+/// from disk * 16, its partitions follow it.
+const VIRTBLK_MAJOR: u32 = 254;
+const MINORS_PER_DISK: u32 = 16;
+
+/// The ioctl of the size in bytes, the u64 at the pointer arg.
+pub const BLKGETSIZE64: usize = 0x8008_1272;
+/// The ioctl of the size in sectors of 512 bytes, the unsigned long at
+/// the pointer arg.
+pub const BLKGETSIZE: usize = 0x1260;
+/// The ioctl of the logical sector size, the int at the pointer arg.
+pub const BLKSSZGET: usize = 0x1268;
+
+/// The device number of Linux of the major and the minor, the inverse of
+/// split_dev.
+pub fn make_dev(major: u32, minor: u32) -> u64 {
+    let (major, minor) = (major as u64, minor as u64);
+    (minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12) | ((major & !0xfff) << 32)
+}
+
+/// The major and minor numbers of a device number of Linux.
+pub fn split_dev(dev: u64) -> (u32, u32) {
+    let major = (dev >> 8) & 0xfff | (dev >> 32) & !0xfff;
+    let minor = dev & 0xff | (dev >> 12) & !0xff;
+    (major as u32, minor as u32)
+}
+
+/// The name of the device node of the sys device, vda, vdb and so on.
+pub fn disk_name(device_id: usize) -> String {
+    let mut name = String::from("vd");
+    // like Linux, vdz is followed by vdaa.
+    let mut letters = Vec::new();
+    let mut id = device_id + 1;
+    while id > 0 {
+        id -= 1;
+        letters.push((b'a' + (id % 26) as u8) as char);
+        id /= 26;
+    }
+    name.extend(letters.iter().rev());
+    name
+}
+
+/// A partition of an MBR, in bytes from the start of the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MbrPartition {
+    /// 1 to 4, the primary partitions.
+    pub index: u32,
+    pub start: u64,
+    pub size: u64,
+}
+
+const MBR_TABLE: usize = 0x1BE;
+const MBR_SIGNATURE: usize = 510;
+/// The type of the protective MBR of a GPT.
+const MBR_GPT: u8 = 0xEE;
+/// The types of the extended partitions, their logical partitions aren't
+/// read.
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+
+/// The primary partitions of the MBR in the first sector, those beyond
+/// the capacity of the disk are skipped.
+pub fn parse_mbr(sector: &[u8], capacity: u64) -> Vec<MbrPartition> {
+    if sector.len() < SECTOR_SIZE || sector[MBR_SIGNATURE..MBR_SIGNATURE + 2] != [0x55, 0xAA] {
+        return Vec::new();
+    }
+    (0..4)
+        .filter_map(|i| {
+            let entry = &sector[MBR_TABLE + i * 16..MBR_TABLE + (i + 1) * 16];
+            let kind = entry[4];
+            let lba = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+            let sectors = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64;
+            let part = MbrPartition {
+                index: i as u32 + 1,
+                start: lba * SECTOR_SIZE as u64,
+                size: sectors * SECTOR_SIZE as u64,
+            };
+            let valid = kind != 0
+                && kind != MBR_GPT
+                && !MBR_EXTENDED.contains(&kind)
+                && lba > 0
+                && sectors > 0
+                && part.start + part.size <= capacity;
+            valid.then_some(part)
+        })
+        .collect()
+}
+
+/// The bytes [start, start + size) of the sys device device_id.
+pub struct BlockNode {
+    name: String,
+    device_id: usize,
+    start: u64,
+    size: u64,
+    rdev: u64,
+}
+
+impl BlockNode {
+    /// The node of the whole disk, None if there is no such device.
+    pub fn disk(device_id: usize) -> Option<Arc<Self>> {
+        let capacity = get_blk_device(device_id)?.capacity() as u64;
+        Some(Arc::new(Self {
+            name: disk_name(device_id),
+            device_id,
+            start: 0,
+            size: capacity,
+            rdev: make_dev(VIRTBLK_MAJOR, device_id as u32 * MINORS_PER_DISK),
+        }))
+    }
+
+    /// The nodes of the partitions in the MBR of the disk.
+    pub fn partitions(device_id: usize) -> Vec<Arc<Self>> {
+        let Some(disk) = Self::disk(device_id) else {
+            return Vec::new();
+        };
+        let mut sector = [0; SECTOR_SIZE];
+        if !matches!(disk.readat(0, &mut sector), Ok(SECTOR_SIZE)) {
+            return Vec::new();
+        }
+        parse_mbr(&sector, disk.size)
+            .into_iter()
+            .map(|part| {
+                Arc::new(Self {
+                    name: disk.name.clone() + &part.index.to_string(),
+                    device_id,
+                    start: part.start,
+                    size: part.size,
+                    rdev: disk.rdev + part.index as u64,
+                })
+            })
+            .collect()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The size in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The source of a mount of the node, a partition starts after the
+    /// MBR.
+    pub fn source(&self) -> MountSource {
+        match self.start {
+            0 => MountSource::Device(self.device_id),
+            start => MountSource::Partition {
+                device_id: self.device_id,
+                start: start as usize,
+                size: self.size as usize,
+            },
+        }
+    }
+
+    /// The sectors covering len bytes at offset of the node, (the first
+    /// sector of the device, the offset in it, the bytes of the sectors).
+    fn span(&self, offset: usize, len: usize) -> (usize, usize, usize) {
+        let start = self.start as usize + offset;
+        let first = start / SECTOR_SIZE;
+        let skip = start % SECTOR_SIZE;
+        (
+            first,
+            skip,
+            (skip + len).div_ceil(SECTOR_SIZE) * SECTOR_SIZE,
+        )
+    }
+}
+
+impl INodeInterface for BlockNode {
+    fn readat(&self, offset: usize, buffer: &mut [u8]) -> VfsResult<usize> {
+        check_range(offset, buffer.len(), u64::MAX)?;
+        if offset as u64 >= self.size || buffer.is_empty() {
+            return Ok(0);
+        }
+        let device = get_blk_device(self.device_id).ok_or(VfsError::Io)?;
+        let len = buffer.len().min((self.size - offset as u64) as usize);
+        let (first, skip, bytes) = self.span(offset, len);
+        if skip == 0 && len == bytes {
+            device.read_blocks(first, &mut buffer[..len]);
+        } else {
+            let mut data = vec![0; bytes];
+            device.read_blocks(first, &mut data);
+            buffer[..len].copy_from_slice(&data[skip..skip + len]);
+        }
+        Ok(len)
+    }
+
+    /// Write the bytes within the device, a write crossing its end writes
+    /// the bytes before it, one at the end fails with StorageFull.
+    fn writeat(&self, offset: usize, buffer: &[u8]) -> VfsResult<usize> {
+        check_range(offset, buffer.len(), u64::MAX)?;
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        if offset as u64 >= self.size {
+            return Err(VfsError::StorageFull);
+        }
+        let device = get_blk_device(self.device_id).ok_or(VfsError::Io)?;
+        let len = buffer.len().min((self.size - offset as u64) as usize);
+        let (first, skip, bytes) = self.span(offset, len);
+        if skip == 0 && len == bytes {
+            device.write_blocks(first, &buffer[..len]);
+        } else {
+            // read-modify-write the sectors at the edges.
+            let mut data = vec![0; bytes];
+            device.read_blocks(first, &mut data[..SECTOR_SIZE]);
+            if bytes > SECTOR_SIZE {
+                device.read_blocks(
+                    first + bytes / SECTOR_SIZE - 1,
+                    &mut data[bytes - SECTOR_SIZE..],
+                );
+            }
+            data[skip..skip + len].copy_from_slice(&buffer[..len]);
+            device.write_blocks(first, &data);
+        }
+        Ok(len)
+    }
+
+    /// A block device has a fixed size.
+    fn truncate(&self, _size: usize) -> VfsResult<()> {
+        Err(VfsError::InvalidInput)
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(Metadata {
+            filename: &self.name,
+            inode: usize::MAX,
+            file_type: FileType::Device,
+            size: self.size as _,
+            childrens: 0,
+        })
+    }
+
+    fn stat(&self, stat: &mut Stat) -> VfsResult<()> {
+        stat.mode = StatMode::BLOCK | StatMode::from_bits_truncate(0o660);
+        stat.nlink = 1;
+        stat.uid = 0;
+        stat.gid = 0;
+        stat.size = self.size as _;
+        stat.blksize = 4096;
+        stat.blocks = (self.size / SECTOR_SIZE as u64) as _;
+        stat.rdev = self.rdev as _;
+        Ok(())
+    }
+
+    /// BLKGETSIZE64, BLKGETSIZE and BLKSSZGET, arg is the pointer of the
+    /// result in the address space of the caller.
+    fn ioctl(&self, command: usize, arg: usize) -> VfsResult<usize> {
+        if arg == 0 {
+            return Err(VfsError::InvalidInput);
+        }
+        // SAFETY: the kernel passes a pointer checked for the size of the
+        // result of the command, like the ioctls of the other devices.
+        unsafe {
+            match command {
+                BLKGETSIZE64 => (arg as *mut u64).write_unaligned(self.size),
+                BLKGETSIZE => {
+                    (arg as *mut usize).write_unaligned((self.size / SECTOR_SIZE as u64) as usize)
+                }
+                BLKSSZGET => (arg as *mut i32).write_unaligned(SECTOR_SIZE as i32),
+                _ => return Err(VfsError::NotSupported),
+            }
+        }
+        Ok(0)
+    }
+}
+
+/// The nodes of the sys devices and their partitions, by their name.
+pub fn block_nodes() -> Vec<(String, Arc<dyn INodeInterface>)> {
+    let mut nodes = Vec::new();
+    for device_id in 0..get_blk_devices().len() {
+        let disk = BlockNode::disk(device_id).into_iter();
+        for node in disk.chain(BlockNode::partitions(device_id)) {
+            nodes.push((node.name.clone(), node as Arc<dyn INodeInterface>));
+        }
+    }
+    nodes
+}
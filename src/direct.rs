@@ -0,0 +1,97 @@
+// Direct I/O, the transfers which bypass the caches, like O_DIRECT: a
+// file read once, like a stream or the input of an archive, would evict
+// the pages and the blocks worth keeping. The page cache and the block
+// cache are skipped, the data blocks are read from the device into the
+// buffer of the caller by the DirectRead of blockdev.rs. The nodes which
+// can transfer directly implement DirectINode and hand it out by their
+// FsNode, like the nodes of owner.rs. The dirty data which isn't on the
+// device yet, like the buffered writes of a file and the deferred
+// transactions, is written back or copied over the read, so a direct
+// read sees every write returned before it. The page cache writes
+// through, it's never fresher than the device. A transfer whose offset or
+// length isn't a multiple of DIRECT_ALIGN goes through the caches, or
+// fails with InvalidInput if the filesystem is strict about it. The nodes
+// without a DirectINode always go through their caches.
+
+use alloc::sync::Arc;
+use vfscore::{INodeInterface, VfsError, VfsResult};
+
+use crate::cancel;
+use crate::error::FsResult;
+use crate::node;
+
+/// The alignment of the offsets and the lengths of the direct transfers,
+/// the sectors of the devices, SECTOR_SIZE of blockdev.rs which is only
+/// built with ext4.
+pub const DIRECT_ALIGN: usize = 512;
+
+pub trait DirectINode: Send + Sync {
+    /// Read at offset into the buffer like readat_cancel of cancel.rs,
+    /// without filling the caches. offset and the length of the buffer
+    /// are aligned.
+    fn readat_direct(
+        &self,
+        offset: usize,
+        buffer: &mut [u8],
+        cancelled: &dyn Fn() -> bool,
+    ) -> VfsResult<usize>;
+
+    /// Write the buffer at offset like writeat_cancel of cancel.rs,
+    /// without buffering it or filling the caches. offset and the length
+    /// of the buffer are aligned.
+    fn writeat_direct(
+        &self,
+        offset: usize,
+        buffer: &[u8],
+        cancelled: &dyn Fn() -> bool,
+    ) -> VfsResult<usize>;
+
+    /// Fail the unaligned transfers instead of passing them to the caches.
+    fn strict_alignment(&self) -> bool;
+}
+
+fn node_of(file: &Arc<dyn INodeInterface>) -> Option<&dyn DirectINode> {
+    node::fs_node(file.as_ref())?.as_direct()
+}
+
+/// The unaligned transfers of the file fail, see DirectINode.
+pub fn strict_alignment(file: &Arc<dyn INodeInterface>) -> bool {
+    node_of(file).is_some_and(|x| x.strict_alignment())
+}
+
+/// The transfer of len bytes at offset can be direct.
+pub fn is_aligned(offset: usize, len: usize) -> bool {
+    offset % DIRECT_ALIGN == 0 && len % DIRECT_ALIGN == 0
+}
+
+/// Read at offset into the buffer bypassing the caches, see the module.
+pub fn read_at(
+    file: &Arc<dyn INodeInterface>,
+    offset: usize,
+    buffer: &mut [u8],
+    cancelled: &dyn Fn() -> bool,
+) -> FsResult<usize> {
+    match node_of(file) {
+        Some(node) if is_aligned(offset, buffer.len()) => {
+            cancel::interrupted(node.readat_direct(offset, buffer, cancelled), cancelled)
+        }
+        Some(node) if node.strict_alignment() => Err(VfsError::InvalidInput.into()),
+        _ => cancel::read_at(file, offset, buffer, cancelled),
+    }
+}
+
+/// Write the buffer at offset bypassing the caches, see the module.
+pub fn write_at(
+    file: &Arc<dyn INodeInterface>,
+    offset: usize,
+    buffer: &[u8],
+    cancelled: &dyn Fn() -> bool,
+) -> FsResult<usize> {
+    match node_of(file) {
+        Some(node) if is_aligned(offset, buffer.len()) => {
+            cancel::interrupted(node.writeat_direct(offset, buffer, cancelled), cancelled)
+        }
+        Some(node) if node.strict_alignment() => Err(VfsError::InvalidInput.into()),
+        _ => cancel::write_at(file, offset, buffer, cancelled),
+    }
+}
@@ -0,0 +1,608 @@
+// The on-disk structures of ext4 and jbd2 as types, the one place their
+// offsets are written down. The fields are byte arrays of their
+// endianness, Le16, Le32, Le64 and the Be ones of the journal, so the
+// types have no padding and an alignment of 1: ref_from and mut_from view
+// a structure in place in any byte slice without a copy, and the sizes
+// and the offsets are asserted at compile time. The accessors of the
+// fields convert them, get and set.
+// The versions of a structure are separate types: GroupDesc32 and the
+// GroupDesc64 of the 64bit feature, Inode and the InodeExtra following it
+// in the large inodes, JournalBlockTag and the JournalBlockTag3 of
+// csum_v3. The fields of the later revisions of the superblock are valid
+// with the features which define them, like Linux. ext4_layout keeps the
+// parsed views the shim works with, SuperBlockInfo and the others, they
+// are read from these types.
+
+use core::fmt::{self, Debug, Formatter};
+use core::mem::{align_of, offset_of, size_of};
+
+macro_rules! endian {
+    ($($(#[$doc:meta])* $name:ident($ty:ty, $len:literal, $from:ident, $to:ident);)*) => {$(
+        $(#[$doc])*
+        #[repr(transparent)]
+        #[derive(Clone, Copy, Default, PartialEq, Eq)]
+        pub struct $name([u8; $len]);
+
+        impl $name {
+            pub const fn new(value: $ty) -> Self {
+                Self(value.$to())
+            }
+
+            pub const fn get(self) -> $ty {
+                <$ty>::$from(self.0)
+            }
+
+            pub fn set(&mut self, value: $ty) {
+                self.0 = value.$to();
+            }
+        }
+
+        impl Debug for $name {
+            fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                write!(f, "{:#x}", self.get())
+            }
+        }
+    )*};
+}
+
+endian! {
+    /// A little-endian u16 of ext4.
+    Le16(u16, 2, from_le_bytes, to_le_bytes);
+    Le32(u32, 4, from_le_bytes, to_le_bytes);
+    Le64(u64, 8, from_le_bytes, to_le_bytes);
+    /// A big-endian u16 of the journal.
+    Be16(u16, 2, from_be_bytes, to_be_bytes);
+    Be32(u32, 4, from_be_bytes, to_be_bytes);
+    Be64(u64, 8, from_be_bytes, to_be_bytes);
+}
+
+/// Join the lo and hi halves of a field.
+pub fn lo_hi(lo: u32, hi: u32) -> u64 {
+    lo as u64 | (hi as u64) << 32
+}
+
+/// A structure of the disk, viewed in place in the bytes.
+///
+/// # Safety
+///
+/// The type is repr(C) of the fields of this module, u8 and arrays of
+/// them only: every bit pattern is valid, there's no padding and the
+/// alignment is 1. The layout! macro checks the last two.
+pub unsafe trait Layout: Sized {
+    /// The bytes of the structure at the start of bytes, None if they are
+    /// too short.
+    fn ref_from(bytes: &[u8]) -> Option<&Self> {
+        let bytes = bytes.get(..size_of::<Self>())?;
+        // SAFETY: the bytes are large enough and any bytes are a valid
+        // Self of alignment 1, see the trait.
+        Some(unsafe { &*(bytes.as_ptr() as *const Self) })
+    }
+
+    fn mut_from(bytes: &mut [u8]) -> Option<&mut Self> {
+        let bytes = bytes.get_mut(..size_of::<Self>())?;
+        // SAFETY: like ref_from, the borrow of bytes is exclusive.
+        Some(unsafe { &mut *(bytes.as_mut_ptr() as *mut Self) })
+    }
+
+    /// A copy of the structure, for the callers keeping it.
+    fn read_from(bytes: &[u8]) -> Option<Self>
+    where
+        Self: Copy,
+    {
+        Self::ref_from(bytes).copied()
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        // SAFETY: the structure has no padding, all its bytes are
+        // initialized.
+        unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, size_of::<Self>()) }
+    }
+
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        // SAFETY: like as_bytes, any bytes written are a valid Self.
+        unsafe { core::slice::from_raw_parts_mut(self as *mut Self as *mut u8, size_of::<Self>()) }
+    }
+
+    fn new_zeroed() -> Self {
+        // SAFETY: zeroes are a valid Self, see the trait.
+        unsafe { core::mem::zeroed() }
+    }
+}
+
+/// Implement Layout for the types, asserting their size, an alignment of 1
+/// and the offsets of their fields.
+macro_rules! layout {
+    ($($ty:ident = $size:literal { $($field:ident = $offset:literal),* $(,)? })*) => {$(
+        const _: () = {
+            assert!(size_of::<$ty>() == $size);
+            assert!(align_of::<$ty>() == 1);
+            $(assert!(offset_of!($ty, $field) == $offset);)*
+        };
+        // SAFETY: the fields are the types of this module, see Layout.
+        unsafe impl Layout for $ty {}
+    )*};
+}
+
+/// The ext4 superblock, 1024 bytes at SUPERBLOCK_OFFSET and at the start
+/// of the backup groups.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SuperBlock {
+    pub inodes_count: Le32,
+    pub blocks_count_lo: Le32,
+    pub r_blocks_count_lo: Le32,
+    pub free_blocks_count_lo: Le32,
+    pub free_inodes_count: Le32,
+    pub first_data_block: Le32,
+    pub log_block_size: Le32,
+    pub log_cluster_size: Le32,
+    pub blocks_per_group: Le32,
+    pub clusters_per_group: Le32,
+    pub inodes_per_group: Le32,
+    pub mtime: Le32,
+    pub wtime: Le32,
+    pub mnt_count: Le16,
+    pub max_mnt_count: Le16,
+    pub magic: Le16,
+    pub state: Le16,
+    pub errors: Le16,
+    pub minor_rev_level: Le16,
+    pub lastcheck: Le32,
+    pub checkinterval: Le32,
+    pub creator_os: Le32,
+    pub rev_level: Le32,
+    pub def_resuid: Le16,
+    pub def_resgid: Le16,
+    // the dynamic revision.
+    pub first_ino: Le32,
+    pub inode_size: Le16,
+    /// The group of a backup superblock, 0 in the primary.
+    pub block_group_nr: Le16,
+    pub feature_compat: Le32,
+    pub feature_incompat: Le32,
+    pub feature_ro_compat: Le32,
+    pub uuid: [u8; 16],
+    pub volume_name: [u8; 16],
+    pub last_mounted: [u8; 64],
+    pub algorithm_usage_bitmap: Le32,
+    pub prealloc_blocks: u8,
+    pub prealloc_dir_blocks: u8,
+    pub reserved_gdt_blocks: Le16,
+    // the journal.
+    pub journal_uuid: [u8; 16],
+    pub journal_inum: Le32,
+    pub journal_dev: Le32,
+    pub last_orphan: Le32,
+    pub hash_seed: [Le32; 4],
+    pub def_hash_version: u8,
+    pub jnl_backup_type: u8,
+    pub desc_size: Le16,
+    pub default_mount_opts: Le32,
+    pub first_meta_bg: Le32,
+    pub mkfs_time: Le32,
+    pub jnl_blocks: [Le32; 17],
+    // the 64bit feature.
+    pub blocks_count_hi: Le32,
+    pub r_blocks_count_hi: Le32,
+    pub free_blocks_count_hi: Le32,
+    pub min_extra_isize: Le16,
+    pub want_extra_isize: Le16,
+    pub flags: Le32,
+    pub raid_stride: Le16,
+    pub mmp_interval: Le16,
+    pub mmp_block: Le64,
+    pub raid_stripe_width: Le32,
+    pub log_groups_per_flex: u8,
+    pub checksum_type: u8,
+    pub reserved_pad: Le16,
+    pub kbytes_written: Le64,
+    pub snapshot_inum: Le32,
+    pub snapshot_id: Le32,
+    pub snapshot_r_blocks_count: Le64,
+    pub snapshot_list: Le32,
+    // the errors, see ErrorHistory.
+    pub error_count: Le32,
+    pub first_error_time: Le32,
+    pub first_error_ino: Le32,
+    pub first_error_block: Le64,
+    pub first_error_func: [u8; 32],
+    pub first_error_line: Le32,
+    pub last_error_time: Le32,
+    pub last_error_ino: Le32,
+    pub last_error_line: Le32,
+    pub last_error_block: Le64,
+    pub last_error_func: [u8; 32],
+    pub mount_opts: [u8; 64],
+    pub usr_quota_inum: Le32,
+    pub grp_quota_inum: Le32,
+    pub overhead_clusters: Le32,
+    pub backup_bgs: [Le32; 2],
+    pub encrypt_algos: [u8; 4],
+    pub encrypt_pw_salt: [u8; 16],
+    pub lpf_ino: Le32,
+    pub prj_quota_inum: Le32,
+    pub checksum_seed: Le32,
+    /// The high bytes of the times.
+    pub wtime_hi: u8,
+    pub mtime_hi: u8,
+    pub mkfs_time_hi: u8,
+    pub lastcheck_hi: u8,
+    pub first_error_time_hi: u8,
+    pub last_error_time_hi: u8,
+    pub first_error_errcode: u8,
+    pub last_error_errcode: u8,
+    pub encoding: Le16,
+    pub encoding_flags: Le16,
+    pub orphan_file_inum: Le32,
+    pub reserved: [Le32; 94],
+    /// The crc32c of the bytes before it, with metadata_csum.
+    pub checksum: Le32,
+}
+
+/// The group descriptor without the 64bit feature, and the first half of
+/// GroupDesc64.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct GroupDesc32 {
+    pub block_bitmap_lo: Le32,
+    pub inode_bitmap_lo: Le32,
+    pub inode_table_lo: Le32,
+    pub free_blocks_count_lo: Le16,
+    pub free_inodes_count_lo: Le16,
+    pub used_dirs_count_lo: Le16,
+    pub flags: Le16,
+    pub exclude_bitmap_lo: Le32,
+    pub block_bitmap_csum_lo: Le16,
+    pub inode_bitmap_csum_lo: Le16,
+    pub itable_unused_lo: Le16,
+    /// The crc16 of gdt_csum or the low half of the crc32c of
+    /// metadata_csum.
+    pub checksum: Le16,
+}
+
+/// The group descriptor of the 64bit feature, desc_size bytes of which
+/// the first 64 are known.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct GroupDesc64 {
+    pub lo: GroupDesc32,
+    pub block_bitmap_hi: Le32,
+    pub inode_bitmap_hi: Le32,
+    pub inode_table_hi: Le32,
+    pub free_blocks_count_hi: Le16,
+    pub free_inodes_count_hi: Le16,
+    pub used_dirs_count_hi: Le16,
+    pub itable_unused_hi: Le16,
+    pub exclude_bitmap_hi: Le32,
+    pub block_bitmap_csum_hi: Le16,
+    pub inode_bitmap_csum_hi: Le16,
+    pub reserved: Le32,
+}
+
+/// The size of the original inode, InodeExtra follows it in the large
+/// inodes.
+pub const GOOD_OLD_INODE_SIZE: usize = size_of::<Inode>();
+
+/// The original 128 bytes of the inode.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Inode {
+    pub mode: Le16,
+    pub uid_lo: Le16,
+    pub size_lo: Le32,
+    pub atime: Le32,
+    pub ctime: Le32,
+    pub mtime: Le32,
+    pub dtime: Le32,
+    pub gid_lo: Le16,
+    pub links_count: Le16,
+    pub blocks_lo: Le32,
+    pub flags: Le32,
+    pub version: Le32,
+    /// The extent tree, the block map, the target of a fast symlink or
+    /// the inline data.
+    pub block: [u8; 60],
+    pub generation: Le32,
+    pub file_acl_lo: Le32,
+    pub size_hi: Le32,
+    pub obso_faddr: Le32,
+    pub blocks_hi: Le16,
+    pub file_acl_hi: Le16,
+    pub uid_hi: Le16,
+    pub gid_hi: Le16,
+    pub checksum_lo: Le16,
+    pub reserved: Le16,
+}
+
+/// The fields after GOOD_OLD_INODE_SIZE, the ones beyond extra_isize bytes
+/// aren't there.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct InodeExtra {
+    /// The size of the extra fields from extra_isize.
+    pub extra_isize: Le16,
+    pub checksum_hi: Le16,
+    pub ctime_extra: Le32,
+    pub mtime_extra: Le32,
+    pub atime_extra: Le32,
+    pub crtime: Le32,
+    pub crtime_extra: Le32,
+    pub version_hi: Le32,
+    pub projid: Le32,
+}
+
+/// The offset of a field of InodeExtra in the inode.
+pub const fn extra_offset(offset: usize) -> usize {
+    GOOD_OLD_INODE_SIZE + offset
+}
+
+/// The header of a node of an extent tree, in i_block or at the start of
+/// an extent block.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ExtentHeader {
+    pub magic: Le16,
+    pub entries: Le16,
+    pub max: Le16,
+    /// 0 in the leaves, ExtentLeaf follows the header then, ExtentIdx
+    /// otherwise.
+    pub depth: Le16,
+    pub generation: Le32,
+}
+
+/// An entry of an index node, the block of its child.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ExtentIdx {
+    pub block: Le32,
+    pub leaf_lo: Le32,
+    pub leaf_hi: Le16,
+    pub unused: Le16,
+}
+
+/// An extent of a leaf.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ExtentLeaf {
+    pub block: Le32,
+    /// Beyond EXT_INIT_MAX_LEN the extent is unwritten.
+    pub len: Le16,
+    pub start_hi: Le16,
+    pub start_lo: Le32,
+}
+
+/// The checksum after the max entries of an extent block.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ExtentTail {
+    pub checksum: Le32,
+}
+
+impl ExtentIdx {
+    pub fn leaf(&self) -> u64 {
+        lo_hi(self.leaf_lo.get(), self.leaf_hi.get() as u32)
+    }
+}
+
+impl ExtentLeaf {
+    pub fn start(&self) -> u64 {
+        lo_hi(self.start_lo.get(), self.start_hi.get() as u32)
+    }
+}
+
+/// The fixed part of a directory entry, ext4_dir_entry_2, the name
+/// follows it.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct DirEntry {
+    pub inode: Le32,
+    pub rec_len: Le16,
+    pub name_len: u8,
+    pub file_type: u8,
+}
+
+/// The fake entry at the end of a leaf block holding its checksum.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct DirEntryTail {
+    pub reserved_zero1: Le32,
+    pub rec_len: Le16,
+    pub reserved_zero2: u8,
+    /// DIRENT_TAIL_FT.
+    pub reserved_ft: u8,
+    pub checksum: Le32,
+}
+
+/// The file type of DirEntryTail.
+pub const DIRENT_TAIL_FT: u8 = 0xDE;
+
+/// The limit and the count of the entries of an htree index block, in the
+/// place of the hash of its first DxEntry.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct DxCountLimit {
+    pub limit: Le16,
+    pub count: Le16,
+}
+
+/// An entry of an htree index block, the block of the hashes from hash.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct DxEntry {
+    pub hash: Le32,
+    pub block: Le32,
+}
+
+/// The checksum after the limit entries of an htree index block.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct DxTail {
+    pub reserved: Le32,
+    pub checksum: Le32,
+}
+
+/// The header of an xattr block.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct XattrHeader {
+    pub magic: Le32,
+    pub refcount: Le32,
+    pub blocks: Le32,
+    pub hash: Le32,
+    pub checksum: Le32,
+    pub reserved: [Le32; 3],
+}
+
+/// The magic before the xattrs in the inode, after the extra fields.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct XattrIbodyHeader {
+    pub magic: Le32,
+}
+
+/// The fixed part of an xattr entry, the name follows it.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct XattrEntry {
+    pub name_len: u8,
+    pub name_index: u8,
+    pub value_offs: Le16,
+    pub value_inum: Le32,
+    pub value_size: Le32,
+    pub hash: Le32,
+}
+
+/// The header of every block of the journal but the data blocks.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct JournalHeader {
+    pub magic: Be32,
+    pub blocktype: Be32,
+    pub sequence: Be32,
+}
+
+/// The superblock of the journal, its first block. Version 1 only has the
+/// fields up to errno, the others are version 2.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct JournalSuperBlock {
+    pub header: JournalHeader,
+    pub blocksize: Be32,
+    pub maxlen: Be32,
+    pub first: Be32,
+    pub sequence: Be32,
+    /// The first block of the log, 0 if it's clean.
+    pub start: Be32,
+    pub errno: Be32,
+    pub feature_compat: Be32,
+    pub feature_incompat: Be32,
+    pub feature_ro_compat: Be32,
+    pub uuid: [u8; 16],
+    pub nr_users: Be32,
+    pub dynsuper: Be32,
+    pub max_transaction: Be32,
+    pub max_trans_data: Be32,
+    pub checksum_type: u8,
+    pub padding2: [u8; 3],
+    pub num_fc_blocks: Be32,
+    pub head: Be32,
+    pub padding: [Be32; 40],
+    pub checksum: Be32,
+    pub users: [u8; 768],
+}
+
+/// The commit block of a transaction.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct CommitHeader {
+    pub header: JournalHeader,
+    pub checksum_type: u8,
+    pub checksum_size: u8,
+    pub padding: [u8; 2],
+    /// The checksum of the block with csum_v2 or v3 is the first.
+    pub checksum: [Be32; 8],
+    pub commit_sec: Be64,
+    pub commit_nsec: Be32,
+}
+
+/// A tag of a descriptor block without csum_v3, the 64bit feature
+/// follows it with the high half of the block.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct JournalBlockTag {
+    pub blocknr: Be32,
+    pub checksum: Be16,
+    pub flags: Be16,
+}
+
+/// A tag of a descriptor block with csum_v3.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct JournalBlockTag3 {
+    pub blocknr: Be32,
+    pub flags: Be32,
+    pub blocknr_high: Be32,
+    pub checksum: Be32,
+}
+
+/// The checksum at the end of a descriptor or revoke block.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct JournalBlockTail {
+    pub checksum: Be32,
+}
+
+layout! {
+    SuperBlock = 1024 {
+        inodes_count = 0x0, blocks_count_lo = 0x4, free_blocks_count_lo = 0xC,
+        log_block_size = 0x18, inodes_per_group = 0x28, wtime = 0x30,
+        magic = 0x38, state = 0x3A, def_resuid = 0x50, first_ino = 0x54,
+        inode_size = 0x58, block_group_nr = 0x5A, feature_compat = 0x5C,
+        feature_incompat = 0x60, feature_ro_compat = 0x64, uuid = 0x68,
+        volume_name = 0x78, reserved_gdt_blocks = 0xCE, journal_inum = 0xE0,
+        last_orphan = 0xE8, hash_seed = 0xEC, def_hash_version = 0xFC,
+        desc_size = 0xFE, jnl_blocks = 0x10C, blocks_count_hi = 0x150,
+        min_extra_isize = 0x15C, flags = 0x160, error_count = 0x194,
+        first_error_func = 0x1A8, last_error_line = 0x1D4,
+        last_error_func = 0x1E0, backup_bgs = 0x24C, checksum_seed = 0x270,
+        wtime_hi = 0x274, first_error_errcode = 0x27A, reserved = 0x284,
+        checksum = 0x3FC,
+    }
+    GroupDesc32 = 32 {
+        free_blocks_count_lo = 0xC, flags = 0x12, block_bitmap_csum_lo = 0x18,
+        itable_unused_lo = 0x1C, checksum = 0x1E,
+    }
+    GroupDesc64 = 64 {
+        block_bitmap_hi = 0x20, free_blocks_count_hi = 0x2C,
+        itable_unused_hi = 0x32, block_bitmap_csum_hi = 0x38,
+        inode_bitmap_csum_hi = 0x3A,
+    }
+    Inode = 128 {
+        links_count = 0x1A, flags = 0x20, block = 0x28, generation = 0x64,
+        size_hi = 0x6C, uid_hi = 0x78, checksum_lo = 0x7C,
+    }
+    InodeExtra = 32 {
+        checksum_hi = 0x2, ctime_extra = 0x4, crtime = 0x10, projid = 0x1C,
+    }
+    ExtentHeader = 12 { depth = 0x6 }
+    ExtentIdx = 12 { leaf_hi = 0x8 }
+    ExtentLeaf = 12 { start_lo = 0x8 }
+    ExtentTail = 4 {}
+    DirEntry = 8 { name_len = 0x6 }
+    DirEntryTail = 12 { reserved_ft = 0x7, checksum = 0x8 }
+    DxCountLimit = 4 { count = 0x2 }
+    DxEntry = 8 { block = 0x4 }
+    DxTail = 8 { checksum = 0x4 }
+    XattrHeader = 32 { checksum = 0x10 }
+    XattrIbodyHeader = 4 {}
+    XattrEntry = 16 { value_inum = 0x4, hash = 0xC }
+    JournalHeader = 12 { sequence = 0x8 }
+    JournalSuperBlock = 1024 {
+        blocksize = 0xC, start = 0x1C, feature_incompat = 0x28, uuid = 0x30,
+        checksum_type = 0x50, checksum = 0xFC, users = 0x100,
+    }
+    CommitHeader = 60 { checksum = 0x10, commit_sec = 0x30, commit_nsec = 0x38 }
+    JournalBlockTag = 8 { flags = 0x6 }
+    JournalBlockTag3 = 16 { blocknr_high = 0x8 }
+    JournalBlockTail = 4 {}
+}
@@ -0,0 +1,292 @@
+// The errors for the kernel side: the errno of a VfsError for the syscall
+// returns, its message for the logs, and VfsErrorContext carrying the
+// operation and the path of a failure for the diagnostics. VfsError is
+// of vfscore, so the conversions and the messages go through Errno, and
+// FsError carries the errno which VfsError has no variant for.
+
+use core::fmt::{self, Debug, Display};
+
+use alloc::string::{String, ToString};
+use vfscore::VfsError;
+
+/// A Linux errno, positive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Errno(pub i32);
+
+impl Errno {
+    pub const EPERM: Errno = Errno(1);
+    pub const ENOENT: Errno = Errno(2);
+    pub const EINTR: Errno = Errno(4);
+    pub const EIO: Errno = Errno(5);
+    pub const ENXIO: Errno = Errno(6);
+    pub const EBADF: Errno = Errno(9);
+    pub const EAGAIN: Errno = Errno(11);
+    pub const EACCES: Errno = Errno(13);
+    pub const EBUSY: Errno = Errno(16);
+    pub const EEXIST: Errno = Errno(17);
+    pub const EXDEV: Errno = Errno(18);
+    pub const ENODEV: Errno = Errno(19);
+    pub const ENOTDIR: Errno = Errno(20);
+    pub const EISDIR: Errno = Errno(21);
+    pub const EINVAL: Errno = Errno(22);
+    pub const ENFILE: Errno = Errno(23);
+    pub const EFBIG: Errno = Errno(27);
+    pub const ENOSPC: Errno = Errno(28);
+    pub const EROFS: Errno = Errno(30);
+    pub const EMLINK: Errno = Errno(31);
+    pub const EPIPE: Errno = Errno(32);
+    pub const ENAMETOOLONG: Errno = Errno(36);
+    pub const ENOTEMPTY: Errno = Errno(39);
+    pub const ELOOP: Errno = Errno(40);
+    pub const EOPNOTSUPP: Errno = Errno(95);
+    pub const ETIMEDOUT: Errno = Errno(110);
+    pub const ESTALE: Errno = Errno(116);
+    pub const EUCLEAN: Errno = Errno(117);
+    pub const EDQUOT: Errno = Errno(122);
+
+    /// The message of strerror.
+    pub fn message(self) -> &'static str {
+        match self {
+            Self::EPERM => "Operation not permitted",
+            Self::ENOENT => "No such file or directory",
+            Self::EINTR => "Interrupted system call",
+            Self::EIO => "Input/output error",
+            Self::ENXIO => "No such device or address",
+            Self::EBADF => "Bad file descriptor",
+            Self::EAGAIN => "Resource temporarily unavailable",
+            Self::EACCES => "Permission denied",
+            Self::EBUSY => "Device or resource busy",
+            Self::EEXIST => "File exists",
+            Self::EXDEV => "Invalid cross-device link",
+            Self::ENODEV => "No such device",
+            Self::ENOTDIR => "Not a directory",
+            Self::EISDIR => "Is a directory",
+            Self::EINVAL => "Invalid argument",
+            Self::ENFILE => "Too many open files in system",
+            Self::EFBIG => "File too large",
+            Self::ENOSPC => "No space left on device",
+            Self::EROFS => "Read-only file system",
+            Self::EMLINK => "Too many links",
+            Self::EPIPE => "Broken pipe",
+            Self::ENAMETOOLONG => "File name too long",
+            Self::ENOTEMPTY => "Directory not empty",
+            Self::ELOOP => "Too many levels of symbolic links",
+            Self::EOPNOTSUPP => "Operation not supported",
+            Self::ETIMEDOUT => "Connection timed out",
+            Self::ESTALE => "Stale file handle",
+            Self::EUCLEAN => "Structure needs cleaning",
+            Self::EDQUOT => "Disk quota exceeded",
+            _ => "Unknown error",
+        }
+    }
+}
+
+impl Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl From<VfsError> for Errno {
+    /// The corrupted metadata (InvalidData), the short reads and writes
+    /// and the other failures are EIO.
+    fn from(err: VfsError) -> Self {
+        match err {
+            VfsError::FileNotFound => Self::ENOENT,
+            VfsError::AlreadyExists => Self::EEXIST,
+            VfsError::NotDir => Self::ENOTDIR,
+            VfsError::DirectoryNotEmpty => Self::ENOTEMPTY,
+            VfsError::InvalidInput => Self::EINVAL,
+            VfsError::StorageFull => Self::ENOSPC,
+            VfsError::NotSupported => Self::EOPNOTSUPP,
+            VfsError::Blocking => Self::EAGAIN,
+            _ => Self::EIO,
+        }
+    }
+}
+
+impl From<Errno> for isize {
+    /// The negative errno returned by the syscalls.
+    fn from(errno: Errno) -> Self {
+        -(errno.0 as isize)
+    }
+}
+
+/// The return value of a syscall: the count, or the negative errno of a
+/// VfsError or an FsError.
+pub fn syscall_result<E: Into<Errno>>(r: Result<usize, E>) -> isize {
+    match r {
+        Ok(count) => count as isize,
+        Err(err) => err.into().into(),
+    }
+}
+
+/// A VfsError with the errno the syscall returns for it, for the
+/// failures VfsError has no variant for: ENAMETOOLONG is InvalidInput
+/// with its own errno. It converts back to the VfsError, so the callers
+/// returning VfsResult return it with `?`, and the syscall boundary
+/// keeps the errno.
+#[derive(Debug, Clone, Copy)]
+pub struct FsError {
+    pub error: VfsError,
+    pub errno: Errno,
+}
+
+pub type FsResult<T> = Result<T, FsError>;
+
+impl FsError {
+    pub const fn new(error: VfsError, errno: Errno) -> Self {
+        Self { error, errno }
+    }
+}
+
+impl From<VfsError> for FsError {
+    fn from(error: VfsError) -> Self {
+        Self::new(error, error.into())
+    }
+}
+
+impl From<FsError> for VfsError {
+    fn from(err: FsError) -> Self {
+        err.error
+    }
+}
+
+impl From<FsError> for Errno {
+    fn from(err: FsError) -> Self {
+        err.errno
+    }
+}
+
+impl From<VfsErrorContext> for FsError {
+    fn from(err: VfsErrorContext) -> Self {
+        err.error.into()
+    }
+}
+
+impl Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.errno)
+    }
+}
+
+/// A VfsError with where it happened. It converts back to the VfsError,
+/// so the functions returning VfsResult log it and return it with `?`.
+#[derive(Clone)]
+pub struct VfsErrorContext {
+    pub error: VfsError,
+    /// The operation which failed, like "open".
+    pub op: Option<&'static str>,
+    pub path: Option<String>,
+    pub ino: Option<u64>,
+}
+
+impl VfsErrorContext {
+    pub fn new(error: VfsError) -> Self {
+        Self {
+            error,
+            op: None,
+            path: None,
+            ino: None,
+        }
+    }
+
+    pub fn with_op(mut self, op: &'static str) -> Self {
+        self.op = Some(op);
+        self
+    }
+
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    pub fn with_ino(mut self, ino: u64) -> Self {
+        self.ino = Some(ino);
+        self
+    }
+}
+
+/// Attach the context to a VfsError, err.with_path("/etc/passwd").
+pub trait ErrorContext {
+    fn with_op(self, op: &'static str) -> VfsErrorContext;
+    fn with_path(self, path: &str) -> VfsErrorContext;
+    fn with_ino(self, ino: u64) -> VfsErrorContext;
+}
+
+impl ErrorContext for VfsError {
+    fn with_op(self, op: &'static str) -> VfsErrorContext {
+        VfsErrorContext::new(self).with_op(op)
+    }
+
+    fn with_path(self, path: &str) -> VfsErrorContext {
+        VfsErrorContext::new(self).with_path(path)
+    }
+
+    fn with_ino(self, ino: u64) -> VfsErrorContext {
+        VfsErrorContext::new(self).with_ino(ino)
+    }
+}
+
+impl From<VfsError> for VfsErrorContext {
+    fn from(error: VfsError) -> Self {
+        Self::new(error)
+    }
+}
+
+impl From<VfsErrorContext> for VfsError {
+    fn from(err: VfsErrorContext) -> Self {
+        err.error
+    }
+}
+
+impl From<VfsErrorContext> for Errno {
+    fn from(err: VfsErrorContext) -> Self {
+        err.error.into()
+    }
+}
+
+/// "open /etc/passwd: No such file or directory", the parts of the
+/// context which are set.
+impl Display for VfsErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = 0;
+        for part in [self.op, self.path.as_deref()].into_iter().flatten() {
+            if parts > 0 {
+                f.write_str(" ")?;
+            }
+            f.write_str(part)?;
+            parts += 1;
+        }
+        if let Some(ino) = self.ino {
+            if parts > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "ino {}", ino)?;
+            parts += 1;
+        }
+        if parts > 0 {
+            f.write_str(": ")?;
+        }
+        write!(f, "{}", Errno::from(self.error))
+    }
+}
+
+/// FileNotFound { op: "open", path: "/etc/passwd" }, the parts of the
+/// context which are set.
+impl Debug for VfsErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = format!("{:?}", self.error);
+        let mut s = f.debug_struct(&name);
+        if let Some(op) = self.op {
+            s.field("op", &op);
+        }
+        if let Some(path) = &self.path {
+            s.field("path", path);
+        }
+        if let Some(ino) = self.ino {
+            s.field("ino", &ino);
+        }
+        s.finish()
+    }
+}
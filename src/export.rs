@@ -0,0 +1,63 @@
+// The file handles of the exported filesystems, for open_by_handle_at and
+// an NFS export. A handle names a file by its inode number and the
+// generation of the inode, so the handle of a deleted file doesn't open
+// the file reusing its inode, and it stays valid across the mounts of the
+// image. FileSystem of vfscore can't open a file by a handle, so the
+// filesystems which can register an Export with their FileSystem, like
+// the Volume of volume.rs.
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use vfscore::{FileSystem, INodeInterface, VfsError, VfsResult};
+
+use crate::error::FsResult;
+use crate::sys::Mutex;
+
+/// The handle of a file, the inode number and its i_generation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FileHandleId {
+    pub ino: u64,
+    pub generation: u32,
+}
+
+pub trait Export: Send + Sync {
+    /// The handle of the node of this filesystem.
+    fn file_handle(&self, node: &dyn INodeInterface) -> VfsResult<FileHandleId>;
+
+    /// Open the file of the handle. A stale handle, of a file which was
+    /// deleted, fails with FileNotFound and ESTALE.
+    fn open_by_handle(&self, handle: FileHandleId) -> FsResult<Arc<dyn INodeInterface>>;
+}
+
+/// The registered exports with their filesystem, the dropped ones are
+/// removed lazily.
+static EXPORTS: Mutex<Vec<(Weak<dyn FileSystem>, Weak<dyn Export>)>> = Mutex::new(Vec::new());
+
+/// Register the export of the filesystem.
+pub fn register(fs: Weak<dyn FileSystem>, export: Weak<dyn Export>) {
+    EXPORTS.lock().push((fs, export));
+}
+
+fn export_of(fs: &Arc<dyn FileSystem>) -> VfsResult<Arc<dyn Export>> {
+    let mut exports = EXPORTS.lock();
+    exports.retain(|(fs, export)| fs.strong_count() > 0 && export.strong_count() > 0);
+    exports
+        .iter()
+        .find(|(x, _)| core::ptr::addr_eq(x.as_ptr(), Arc::as_ptr(fs)))
+        .and_then(|(_, export)| export.upgrade())
+        .ok_or(VfsError::NotSupported)
+}
+
+/// The handle of the node of fs, NotSupported if fs can't be exported.
+pub fn file_handle(fs: &Arc<dyn FileSystem>, node: &dyn INodeInterface) -> VfsResult<FileHandleId> {
+    export_of(fs)?.file_handle(node)
+}
+
+pub fn open_by_handle(
+    fs: &Arc<dyn FileSystem>,
+    handle: FileHandleId,
+) -> FsResult<Arc<dyn INodeInterface>> {
+    export_of(fs)?.open_by_handle(handle)
+}
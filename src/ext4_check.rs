@@ -0,0 +1,666 @@
+// A lightweight consistency checker of ext4, it finds the damages our
+// write paths may do without running e2fsck: the bitmaps against the
+// referenced blocks and linked inodes, the free counts of the groups
+// against their bitmaps and the ones of the filesystem against the
+// groups, the directory entries and the checksums of their blocks, the
+// htree indexes, the link counts and the sizes. Nothing is repaired.
+// The checker only reads through CheckDisk, so it works over any backend.
+// A long check can be cancelled between its groups and its directories,
+// like a mount, see check_cancel.
+
+use core::mem::size_of;
+
+use alloc::{collections::BTreeMap, string::String, vec, vec::Vec};
+use vfscore::{VfsError, VfsResult};
+
+use crate::cancel;
+use crate::disk_layout::{DxCountLimit, DxEntry, Layout};
+use crate::ext4_csum::{has_dirent_tail, inode_seed, verify_dir_block};
+use crate::ext4_htree::{dirhash, dx_entries_offset, dx_limit, DxRootInfo};
+use crate::ext4_layout::{
+    bitmap_test, le_u32, walk_extent_tree, DirentIter, Extent, GroupDesc, InodeInfo,
+    SuperBlockInfo, BG_BLOCK_UNINIT, BG_INODE_UNINIT, EXT4_HUGE_FILE_FL, EXT4_INDEX_FL,
+    INCOMPAT_FILETYPE, INCOMPAT_META_BG, RESIZE_INO, ROOT_INO, RO_COMPAT_HUGE_FILE,
+};
+use crate::ops::name_from_bytes;
+
+/// The reads used by the checker.
+pub trait CheckDisk {
+    fn superblock(&self) -> &SuperBlockInfo;
+    /// Read the group descriptor from the disk, the counters must be current.
+    fn group_desc(&self, group: usize) -> GroupDesc;
+    /// Read the block, at least a block size bytes are returned.
+    fn read_block(&self, block: u64) -> Vec<u8>;
+    fn read_inode(&self, ino: u32) -> InodeInfo;
+    /// The free blocks and inodes of the whole filesystem, those of the
+    /// superblock unless the mount keeps its own.
+    fn free_counts(&self) -> (u64, u64) {
+        let sb = self.superblock();
+        (sb.free_blocks_count, sb.free_inodes_count as u64)
+    }
+}
+
+/// A discrepancy found by the checker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The inode references a block beyond the filesystem.
+    BlockOutOfRange { ino: u32, block: u64 },
+    /// The inode references a block which is free in the block bitmap.
+    BlockNotAllocated { ino: u32, block: u64 },
+    /// The inode references a block which is already referenced.
+    BlockDuplicated { ino: u32, block: u64 },
+    /// The block is allocated in the bitmap but isn't referenced.
+    BlockLeaked { block: u64 },
+    /// The free blocks count of the descriptor doesn't match its bitmap.
+    FreeBlocks {
+        group: usize,
+        desc: u32,
+        bitmap: u32,
+    },
+    /// The free inodes count of the descriptor doesn't match its bitmap.
+    FreeInodes {
+        group: usize,
+        desc: u32,
+        bitmap: u32,
+    },
+    /// The free blocks count of the filesystem isn't the sum of the groups.
+    TotalFreeBlocks { total: u64, groups: u64 },
+    /// The free inodes count of the filesystem isn't the sum of the groups.
+    TotalFreeInodes { total: u64, groups: u64 },
+    /// The inode is allocated but has no valid mode.
+    BadMode { ino: u32, mode: u16 },
+    /// The extent tree or a directory block of the inode can't be parsed.
+    Unreadable { ino: u32, err: VfsError },
+    /// The directory entry points at an inode which isn't allocated.
+    InodeNotAllocated { dir: u32, ino: u32, name: String },
+    /// With metadata_csum, the directory leaf block has no checksum tail
+    /// or its checksum is wrong.
+    DirChecksum { ino: u32, block: u64 },
+    /// The file_type of the directory entry doesn't match the inode, or
+    /// isn't 0 without the filetype feature.
+    DirentType {
+        dir: u32,
+        ino: u32,
+        name: String,
+        file_type: u8,
+    },
+    /// The inode is allocated but no directory entry links it.
+    InodeLeaked { ino: u32 },
+    /// i_links_count isn't the number of the entries linking the inode.
+    LinkCount {
+        ino: u32,
+        links_count: u16,
+        refs: u32,
+    },
+    /// i_blocks doesn't count the blocks mapped by the inode.
+    BlocksCount {
+        ino: u32,
+        blocks: u64,
+        expected: u64,
+    },
+    /// i_size doesn't cover the initialized blocks, or a directory size
+    /// isn't its blocks.
+    Size { ino: u32, size: u64, expected: u64 },
+    /// The htree index of the directory is wrong at the logical block: a
+    /// bad header, count or limit, hashes out of order, a leaf indexed
+    /// twice or not at all, or a name in a leaf which doesn't cover its
+    /// hash. Linux wouldn't find the names.
+    DirIndex { ino: u32, lblock: u32 },
+}
+
+/// The result of a check.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    /// The allocated inodes.
+    pub inodes: usize,
+    pub directories: usize,
+    /// The blocks referenced by the metadata and the inodes.
+    pub blocks: u64,
+    pub problems: Vec<Problem>,
+}
+
+#[cfg(feature = "testsuite")]
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// The file_type of the directory entries of the i_mode.
+fn mode_dirent_type(mode: u16) -> Option<u8> {
+    match mode & 0xF000 {
+        0x8000 => Some(1),
+        0x4000 => Some(2),
+        0x2000 => Some(3),
+        0x6000 => Some(4),
+        0x1000 => Some(5),
+        0xC000 => Some(6),
+        0xA000 => Some(7),
+        _ => None,
+    }
+}
+
+/// The directory block at lblock passes its checksum with metadata_csum.
+/// A leaf must end with the checksum tail, the htree index blocks of an
+/// indexed directory have dx_tail instead and aren't covered.
+fn dir_block_csum_ok(sb: &SuperBlockInfo, dir: &Directory, lblock: u32, data: &[u8]) -> bool {
+    if !sb.has_metadata_csum() {
+        return true;
+    }
+    if has_dirent_tail(data) {
+        return verify_dir_block(inode_seed(sb, dir.ino, dir.inode.generation), data);
+    }
+    dir.inode.flags & EXT4_INDEX_FL != 0 && dx_entries_offset(lblock, data).is_some()
+}
+
+/// Walk the indirect blocks of a block-mapped inode. return the mapped
+/// blocks as extents of one block and the indirect blocks.
+fn walk_indirect(disk: &impl CheckDisk, i_block: &[u8]) -> (Vec<Extent>, Vec<u64>) {
+    let per_block = disk.superblock().block_size() / 4;
+    let mut extents = Vec::new();
+    let mut nodes = Vec::new();
+    // (block, level, first logical block), level 0 is a data block.
+    let mut stack: Vec<(u64, u32, u64)> = Vec::new();
+    let mut logical = 0;
+    for (index, level) in (0..15usize).map(|x| (x, x.saturating_sub(11) as u32)) {
+        stack.push((le_u32(i_block, index * 4) as u64, level, logical));
+        logical += (per_block as u64).pow(level);
+    }
+    stack.reverse();
+    while let Some((block, level, logical)) = stack.pop() {
+        if block == 0 || logical > u32::MAX as u64 {
+            continue;
+        }
+        if level == 0 {
+            extents.push(Extent {
+                logical: logical as u32,
+                len: 1,
+                physical: block,
+                uninit: false,
+            });
+            continue;
+        }
+        nodes.push(block);
+        let data = disk.read_block(block);
+        let span = (per_block as u64).pow(level - 1);
+        for index in (0..per_block).rev() {
+            let child = le_u32(&data, index * 4) as u64;
+            stack.push((child, level - 1, logical + index as u64 * span));
+        }
+    }
+    (extents, nodes)
+}
+
+/// The blocks of the inode: the mapped blocks as extents, and the extent
+/// tree nodes or the indirect blocks. The inline data, the fast symlinks
+/// and the special inodes have no blocks, the xattr block isn't included.
+pub fn inode_blocks(
+    disk: &impl CheckDisk,
+    inode: &InodeInfo,
+) -> VfsResult<(Vec<Extent>, Vec<u64>)> {
+    let is_fast_symlink = inode.mode & 0xF000 == 0xA000 && inode.blocks == 0;
+    if inode.has_inline_data() || is_fast_symlink || inode.is_special() {
+        return Ok((Vec::new(), Vec::new()));
+    }
+    if !inode.uses_extents() {
+        return Ok(walk_indirect(disk, &inode.i_block));
+    }
+    let mut nodes = Vec::new();
+    let extents = walk_extent_tree(
+        &inode.i_block,
+        |block| Ok(disk.read_block(block)),
+        |block| nodes.push(block),
+    )?;
+    Ok((extents, nodes))
+}
+
+/// The blocks of the allocated inodes and the metadata, and the block
+/// bitmaps they are checked against.
+struct BlockMap<'a> {
+    sb: &'a SuperBlockInfo,
+    bitmaps: Vec<Option<Vec<u8>>>,
+    used: Vec<u8>,
+}
+
+impl BlockMap<'_> {
+    /// The block is allocated in the bitmap, the groups without the
+    /// initialized bitmap are trusted.
+    fn allocated(&self, block: u64) -> bool {
+        let index = (block - self.sb.first_data_block as u64) as usize;
+        let bpg = self.sb.blocks_per_group as usize;
+        match &self.bitmaps[index / bpg] {
+            Some(bitmap) => bitmap_test(bitmap, index % bpg),
+            None => true,
+        }
+    }
+
+    fn is_used(&self, block: u64) -> bool {
+        bitmap_test(&self.used, block as usize)
+    }
+
+    /// Mark the block as referenced by ino, 0 for the filesystem metadata.
+    fn mark(&mut self, block: u64, ino: u32, problems: &mut Vec<Problem>) {
+        if block < self.sb.first_data_block as u64 || block >= self.sb.blocks_count {
+            problems.push(Problem::BlockOutOfRange { ino, block });
+            return;
+        }
+        if self.is_used(block) {
+            problems.push(Problem::BlockDuplicated { ino, block });
+            return;
+        }
+        if !self.allocated(block) {
+            problems.push(Problem::BlockNotAllocated { ino, block });
+        }
+        self.used[block as usize / 8] |= 1 << (block % 8);
+    }
+}
+
+/// An allocated directory, its entries are checked after all the inodes
+/// are known.
+struct Directory {
+    ino: u32,
+    inode: InodeInfo,
+    extents: Vec<Extent>,
+}
+
+/// Read the logical block of the directory, None if it isn't mapped.
+fn dir_lblock(disk: &impl CheckDisk, dir: &Directory, lblock: u32) -> Option<Vec<u8>> {
+    let extent = dir
+        .extents
+        .iter()
+        .find(|x| x.contains(lblock) && !x.uninit)?;
+    let mut data = disk.read_block(extent.physical + (lblock - extent.logical) as u64);
+    data.truncate(disk.superblock().block_size());
+    Some(data)
+}
+
+/// Walk the htree index of the directory like dx_probe of Linux and
+/// check every leaf against the hashes its index entry covers. return
+/// the first wrong block.
+fn check_dx_index(disk: &impl CheckDisk, dir: &Directory) -> Result<(), u32> {
+    let sb = disk.superblock();
+    let block_size = sb.block_size();
+    let csum = sb.has_metadata_csum();
+    let root = dir_lblock(disk, dir, 0).ok_or(0u32)?;
+    let info = DxRootInfo::parse(&root).map_err(|_| 0u32)?;
+    let version = info.hash_version(sb);
+    let blocks = (dir.inode.size / block_size as u64) as u32;
+    let mut indexed = vec![false; blocks as usize];
+    // (lblock, the level below dx_root, the hash of its entry and the one
+    // of the next entry, u64::MAX for none). The lowest bit of the next
+    // hash marks that its names may continue in the block.
+    let mut stack = vec![(0u32, 0u8, 0u32, u64::MAX)];
+    while let Some((lblock, level, low, high)) = stack.pop() {
+        if lblock >= blocks || core::mem::replace(&mut indexed[lblock as usize], true) {
+            return Err(lblock);
+        }
+        let data = match lblock {
+            0 => root.clone(),
+            _ => dir_lblock(disk, dir, lblock).ok_or(lblock)?,
+        };
+        if level <= info.indirect_levels {
+            let offset = dx_entries_offset(lblock, &data).ok_or(lblock)?;
+            let header = DxCountLimit::ref_from(&data[offset..]).ok_or(lblock)?;
+            let (limit, count) = (header.limit.get() as usize, header.count.get() as usize);
+            if limit != dx_limit(block_size, offset, csum) || count == 0 || count > limit {
+                return Err(lblock);
+            }
+            // the hash of the first entry is the count and the limit.
+            let entries: Vec<(u32, u32)> = (0..count)
+                .map_while(|i| DxEntry::ref_from(data.get(offset + i * size_of::<DxEntry>()..)?))
+                .enumerate()
+                .map(|(i, x)| match i {
+                    0 => (low, x.block.get()),
+                    _ => (x.hash.get(), x.block.get()),
+                })
+                .collect();
+            for (i, &(hash, child)) in entries.iter().enumerate() {
+                let next = entries.get(i + 1).map_or(high, |x| x.0 as u64);
+                if hash & !1 < low & !1 || hash as u64 & !1 > next & !1 {
+                    return Err(lblock);
+                }
+                stack.push((child, level + 1, hash, next));
+            }
+            continue;
+        }
+        let covered = |hash: u64| match high {
+            u64::MAX => true,
+            _ => hash < high & !1 || (hash == high & !1 && high & 1 != 0),
+        };
+        for dirent in DirentIter::new(&data) {
+            let dirent = dirent.map_err(|_| lblock)?;
+            let hash = dirhash(dirent.name, version, &sb.hash_seed).map_err(|_| lblock)?;
+            if hash < low & !1 || !covered(hash as u64) {
+                return Err(lblock);
+            }
+        }
+    }
+    match indexed.iter().position(|x| !x) {
+        Some(i) => Err(i as u32),
+        None => Ok(()),
+    }
+}
+
+/// Check the filesystem on the disk and report the discrepancies.
+/// The groups flagged BLOCK_UNINIT or INODE_UNINIT have no bitmaps to
+/// compare with. The unreferenced blocks aren't reported with meta_bg,
+/// its descriptor blocks aren't located.
+pub fn check(disk: &impl CheckDisk) -> CheckReport {
+    // never cancelled.
+    check_cancel(disk, &cancel::never).unwrap_or_default()
+}
+
+/// Check like check, cancelled is checked between the groups and the
+/// directories, a check stops with Blocking when it returns true.
+pub fn check_cancel(disk: &impl CheckDisk, cancelled: &dyn Fn() -> bool) -> VfsResult<CheckReport> {
+    let poll = || match cancelled() {
+        true => Err(VfsError::Blocking),
+        false => Ok(()),
+    };
+    let sb = disk.superblock();
+    let block_size = sb.block_size();
+    let bpg = sb.blocks_per_group as usize;
+    let ipg = sb.inodes_per_group as usize;
+    let groups = sb.groups_count();
+    let descs: Vec<GroupDesc> = (0..groups).map(|x| disk.group_desc(x)).collect();
+    let mut report = CheckReport::default();
+    let mut problems = Vec::new();
+
+    let mut blocks = BlockMap {
+        sb,
+        bitmaps: descs
+            .iter()
+            .map(|desc| match desc.flags & BG_BLOCK_UNINIT {
+                0 => Some(disk.read_block(desc.block_bitmap)[..bpg / 8].to_vec()),
+                _ => None,
+            })
+            .collect(),
+        used: vec![0u8; (sb.blocks_count as usize).div_ceil(8)],
+    };
+
+    // the superblocks, the descriptors and the bitmaps and inode tables.
+    let table_blocks = (ipg * sb.inode_size as usize).div_ceil(block_size) as u64;
+    for (group, desc) in descs.iter().enumerate() {
+        let first = sb.first_data_block as u64 + (group * bpg) as u64;
+        if sb.group_has_super(group) {
+            let count = match sb.feature_incompat & INCOMPAT_META_BG {
+                0 => 1 + sb.group_desc_blocks() as u64 + sb.reserved_gdt_blocks as u64,
+                _ => 1,
+            };
+            for block in first..first + count {
+                blocks.mark(block, 0, &mut problems);
+            }
+        }
+        blocks.mark(desc.block_bitmap, 0, &mut problems);
+        blocks.mark(desc.inode_bitmap, 0, &mut problems);
+        for block in desc.inode_table..desc.inode_table + table_blocks {
+            blocks.mark(block, 0, &mut problems);
+        }
+    }
+
+    // the allocated inodes: (mode, links_count)
+    let mut inodes: BTreeMap<u32, (u16, u16)> = BTreeMap::new();
+    let mut directories = Vec::new();
+    let sectors = (block_size / 512) as u64;
+    for (group, desc) in descs.iter().enumerate() {
+        poll()?;
+        if desc.flags & BG_INODE_UNINIT != 0 {
+            continue;
+        }
+        let bitmap = disk.read_block(desc.inode_bitmap);
+        let mut free = 0u32;
+        for index in 0..ipg {
+            if !bitmap_test(&bitmap, index) {
+                free += 1;
+                continue;
+            }
+            let ino = (group * ipg + index + 1) as u32;
+            if ino > sb.inodes_count {
+                break;
+            }
+            let inode = disk.read_inode(ino);
+            report.inodes += 1;
+            inodes.insert(ino, (inode.mode, inode.links_count));
+            let reserved = ino < sb.first_ino && ino != ROOT_INO;
+            if mode_dirent_type(inode.mode).is_none() && !reserved {
+                problems.push(Problem::BadMode {
+                    ino,
+                    mode: inode.mode,
+                });
+            }
+
+            // the resize inode maps the reserved descriptor blocks, they
+            // are marked as the metadata.
+            if ino == RESIZE_INO {
+                let dind = le_u32(&inode.i_block, 13 * 4) as u64;
+                if dind != 0 {
+                    blocks.mark(dind, ino, &mut problems);
+                }
+                continue;
+            }
+            let (extents, nodes) = match inode_blocks(disk, &inode) {
+                Ok(blocks) => blocks,
+                Err(err) => {
+                    problems.push(Problem::Unreadable { ino, err });
+                    continue;
+                }
+            };
+
+            let mut count = nodes.len() as u64;
+            for extent in extents.iter() {
+                for block in extent.physical..extent.physical + extent.len as u64 {
+                    blocks.mark(block, ino, &mut problems);
+                }
+                count += extent.len as u64;
+            }
+            for block in nodes.iter() {
+                blocks.mark(*block, ino, &mut problems);
+            }
+            if inode.file_acl != 0 {
+                blocks.mark(inode.file_acl, ino, &mut problems);
+                count += 1;
+            }
+            let i_blocks = match sb.feature_ro_compat & RO_COMPAT_HUGE_FILE != 0
+                && inode.flags & EXT4_HUGE_FILE_FL != 0
+            {
+                true => inode.blocks * sectors,
+                false => inode.blocks,
+            };
+            if i_blocks != count * sectors {
+                problems.push(Problem::BlocksCount {
+                    ino,
+                    blocks: i_blocks,
+                    expected: count * sectors,
+                });
+            }
+
+            // the end of the mapped blocks, and of the initialized ones.
+            let end = |with_uninit: bool| {
+                extents
+                    .iter()
+                    .filter(|x| with_uninit || !x.uninit)
+                    .map(|x| x.logical as u64 + x.len as u64)
+                    .max()
+                    .unwrap_or(0)
+            };
+            let (mapped_end, data_end) = (end(true), end(false));
+            match inode.mode & 0xF000 {
+                0x4000 => {
+                    let expected = mapped_end * block_size as u64;
+                    if !inode.has_inline_data() && inode.size != expected {
+                        problems.push(Problem::Size {
+                            ino,
+                            size: inode.size,
+                            expected,
+                        });
+                    }
+                    directories.push(Directory {
+                        ino,
+                        inode,
+                        extents,
+                    });
+                }
+                // the last initialized block must hold data.
+                0x8000 if data_end > 0 && inode.size <= (data_end - 1) * block_size as u64 => {
+                    problems.push(Problem::Size {
+                        ino,
+                        size: inode.size,
+                        expected: (data_end - 1) * block_size as u64 + 1,
+                    });
+                }
+                _ => {}
+            }
+        }
+        if free != desc.free_inodes {
+            problems.push(Problem::FreeInodes {
+                group,
+                desc: desc.free_inodes,
+                bitmap: free,
+            });
+        }
+    }
+
+    // the references from the directory entries, "." and ".." included.
+    let mut refs: BTreeMap<u32, u32> = BTreeMap::new();
+    let filetype = sb.feature_incompat & INCOMPAT_FILETYPE != 0;
+    report.directories = directories.len();
+    for dir in directories.iter() {
+        poll()?;
+        let mut blocks_data = Vec::new();
+        if dir.inode.has_inline_data() {
+            // the inline directory starts with the parent inode number.
+            let data = dir.inode.inline_data();
+            if data.len() < 4 {
+                problems.push(Problem::Unreadable {
+                    ino: dir.ino,
+                    err: VfsError::InvalidData,
+                });
+                continue;
+            }
+            *refs.entry(le_u32(&data, 0)).or_default() += 1;
+            *refs.entry(dir.ino).or_default() += 1;
+            blocks_data.push(data[4..].to_vec());
+        } else {
+            for extent in dir.extents.iter().filter(|x| !x.uninit) {
+                for i in 0..extent.len {
+                    let block = extent.physical + i as u64;
+                    let mut data = disk.read_block(block);
+                    data.truncate(block_size);
+                    if !dir_block_csum_ok(sb, dir, extent.logical + i, &data) {
+                        problems.push(Problem::DirChecksum {
+                            ino: dir.ino,
+                            block,
+                        });
+                    }
+                    blocks_data.push(data);
+                }
+            }
+            if dir.inode.flags & EXT4_INDEX_FL != 0
+                && let Err(lblock) = check_dx_index(disk, dir)
+            {
+                problems.push(Problem::DirIndex {
+                    ino: dir.ino,
+                    lblock,
+                });
+            }
+        }
+        for data in blocks_data.iter() {
+            for dirent in DirentIter::new(data) {
+                let dirent = match dirent {
+                    Ok(dirent) => dirent,
+                    Err(err) => {
+                        problems.push(Problem::Unreadable { ino: dir.ino, err });
+                        break;
+                    }
+                };
+                let name = name_from_bytes(dirent.name);
+                let Some((mode, _)) = inodes.get(&dirent.inode) else {
+                    problems.push(Problem::InodeNotAllocated {
+                        dir: dir.ino,
+                        ino: dirent.inode,
+                        name,
+                    });
+                    continue;
+                };
+                let expected = match filetype {
+                    true => mode_dirent_type(*mode),
+                    false => Some(0),
+                };
+                if expected != Some(dirent.file_type) {
+                    problems.push(Problem::DirentType {
+                        dir: dir.ino,
+                        ino: dirent.inode,
+                        name,
+                        file_type: dirent.file_type,
+                    });
+                }
+                *refs.entry(dirent.inode).or_default() += 1;
+            }
+        }
+    }
+
+    for (&ino, &(mode, links_count)) in inodes.iter() {
+        // the reserved inodes aren't linked.
+        if ino < sb.first_ino && ino != ROOT_INO {
+            continue;
+        }
+        let count = refs.get(&ino).copied().unwrap_or(0);
+        if count == 0 {
+            problems.push(Problem::InodeLeaked { ino });
+        } else if mode & 0xF000 == 0x4000 && links_count == 1 {
+            // dir_nlink: the directory has too many subdirectories.
+        } else if count != links_count as u32 {
+            problems.push(Problem::LinkCount {
+                ino,
+                links_count,
+                refs: count,
+            });
+        }
+    }
+
+    // the block bitmaps against the referenced blocks.
+    for (group, desc) in descs.iter().enumerate() {
+        poll()?;
+        if desc.flags & BG_BLOCK_UNINIT != 0 {
+            continue;
+        }
+        let first = sb.first_data_block as u64 + (group * bpg) as u64;
+        let last = (first + bpg as u64).min(sb.blocks_count);
+        let mut free = 0;
+        for block in first..last {
+            if !blocks.allocated(block) {
+                free += 1;
+            } else if !blocks.is_used(block) && sb.feature_incompat & INCOMPAT_META_BG == 0 {
+                problems.push(Problem::BlockLeaked { block });
+            }
+        }
+        if free != desc.free_blocks {
+            problems.push(Problem::FreeBlocks {
+                group,
+                desc: desc.free_blocks,
+                bitmap: free,
+            });
+        }
+    }
+
+    // the free counts of the filesystem against those of the groups.
+    let (total_blocks, total_inodes) = disk.free_counts();
+    let groups = descs.iter().map(|x| x.free_blocks as u64).sum::<u64>();
+    if total_blocks != groups {
+        problems.push(Problem::TotalFreeBlocks {
+            total: total_blocks,
+            groups,
+        });
+    }
+    let groups = descs.iter().map(|x| x.free_inodes as u64).sum::<u64>();
+    if total_inodes != groups {
+        problems.push(Problem::TotalFreeInodes {
+            total: total_inodes,
+            groups,
+        });
+    }
+
+    report.blocks = (0..sb.blocks_count).filter(|x| blocks.is_used(*x)).count() as u64;
+    report.problems = problems;
+    Ok(report)
+}
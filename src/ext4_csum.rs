@@ -0,0 +1,235 @@
+// The metadata_csum checksums of ext4. Every checksum is a crc32c seeded
+// with the checksum seed of the filesystem (from the uuid), the inode
+// structures are seeded again with the inode number and generation.
+// Like ext4_layout, the functions only work on byte slices, the
+// structures are the ones of disk_layout. shim.rs sets them in the blocks
+// of the transactions of a mounted volume.
+
+use core::mem::{offset_of, size_of};
+
+use crate::crc32c::crc32c;
+use crate::disk_layout::{
+    self as disk, DirEntryTail, DxCountLimit, DxEntry, DxTail, ExtentTail, GroupDesc32,
+    GroupDesc64, Inode, InodeExtra, Layout, XattrHeader, DIRENT_TAIL_FT, GOOD_OLD_INODE_SIZE,
+};
+use crate::ext4_layout::{ExtentHeader, SuperBlockInfo, SB_CHECKSUM_OFFSET};
+
+#[cfg(root_fs = "ext4_rs")]
+mod shim;
+
+/// The offset of bg_checksum in the group descriptor.
+const BG_CHECKSUM: usize = offset_of!(GroupDesc32, checksum);
+/// The halves of the checksum of the inode.
+pub const I_CHECKSUM_LO: usize = offset_of!(Inode, checksum_lo);
+pub const I_CHECKSUM_HI: usize = disk::extra_offset(offset_of!(InodeExtra, checksum_hi));
+/// The fake directory entry at the end of a leaf block holding the checksum.
+pub const DIRENT_TAIL_SIZE: usize = size_of::<DirEntryTail>();
+
+fn put_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+/// The structure at the offset of the bytes, which must hold it.
+fn at<T: Layout>(data: &[u8], offset: usize) -> &T {
+    T::ref_from(&data[offset..]).expect("the structure is in the block")
+}
+
+fn at_mut<T: Layout>(data: &mut [u8], offset: usize) -> &mut T {
+    T::mut_from(&mut data[offset..]).expect("the structure is in the block")
+}
+
+/// The checksum of the superblock, sb holds the 1024 bytes superblock.
+pub fn superblock_csum(sb: &[u8]) -> u32 {
+    crc32c(!0, &sb[..SB_CHECKSUM_OFFSET])
+}
+
+pub fn verify_superblock(sb: &[u8]) -> bool {
+    superblock_csum(sb) == at::<disk::SuperBlock>(sb, 0).checksum.get()
+}
+
+pub fn set_superblock_csum(sb: &mut [u8]) {
+    let csum = superblock_csum(sb);
+    at_mut::<disk::SuperBlock>(sb, 0).checksum.set(csum);
+}
+
+/// The checksum of the group descriptor, desc holds group_desc_size bytes.
+pub fn group_desc_csum(sb: &SuperBlockInfo, group: u32, desc: &[u8]) -> u16 {
+    let size = sb.group_desc_size();
+    let csum = crc32c(sb.csum_seed, &group.to_le_bytes());
+    let csum = crc32c(csum, &desc[..BG_CHECKSUM]);
+    let csum = crc32c(csum, &[0, 0]);
+    crc32c(csum, &desc[BG_CHECKSUM + 2..size]) as u16
+}
+
+pub fn verify_group_desc(sb: &SuperBlockInfo, group: u32, desc: &[u8]) -> bool {
+    group_desc_csum(sb, group, desc) == at::<GroupDesc32>(desc, 0).checksum.get()
+}
+
+pub fn set_group_desc_csum(sb: &SuperBlockInfo, group: u32, desc: &mut [u8]) {
+    let csum = group_desc_csum(sb, group, desc);
+    at_mut::<GroupDesc32>(desc, 0).checksum.set(csum);
+}
+
+/// Store the checksums of the bitmaps of the group in its descriptor,
+/// the descriptor checksum must be updated after.
+pub fn set_bitmap_csums(
+    sb: &SuperBlockInfo,
+    desc: &mut [u8],
+    block_bitmap: &[u8],
+    inode_bitmap: &[u8],
+) {
+    let block = crc32c(
+        sb.csum_seed,
+        &block_bitmap[..sb.blocks_per_group as usize / 8],
+    );
+    let inode = crc32c(
+        sb.csum_seed,
+        &inode_bitmap[..sb.inodes_per_group as usize / 8],
+    );
+    let lo = at_mut::<GroupDesc32>(desc, 0);
+    lo.block_bitmap_csum_lo.set(block as u16);
+    lo.inode_bitmap_csum_lo.set(inode as u16);
+    if sb.group_desc_size() >= size_of::<GroupDesc64>() {
+        let desc = at_mut::<GroupDesc64>(desc, 0);
+        desc.block_bitmap_csum_hi.set((block >> 16) as u16);
+        desc.inode_bitmap_csum_hi.set((inode >> 16) as u16);
+    }
+}
+
+/// The seed of the structures owned by the inode: the inode itself, its
+/// extent blocks and directory blocks.
+pub fn inode_seed(sb: &SuperBlockInfo, ino: u32, generation: u32) -> u32 {
+    crc32c(
+        crc32c(sb.csum_seed, &ino.to_le_bytes()),
+        &generation.to_le_bytes(),
+    )
+}
+
+/// i_checksum_hi exists if i_extra_isize covers it.
+fn has_csum_hi(sb: &SuperBlockInfo, raw: &[u8]) -> bool {
+    sb.inode_size as usize > GOOD_OLD_INODE_SIZE
+        && GOOD_OLD_INODE_SIZE
+            + at::<InodeExtra>(raw, GOOD_OLD_INODE_SIZE).extra_isize.get() as usize
+            >= I_CHECKSUM_HI + 2
+}
+
+/// The checksum of the on-disk inode, raw holds inode_size bytes.
+pub fn inode_csum(sb: &SuperBlockInfo, ino: u32, raw: &[u8]) -> u32 {
+    let size = sb.inode_size as usize;
+    let mut copy = raw[..size].to_vec();
+    put_u16(&mut copy, I_CHECKSUM_LO, 0);
+    let has_hi = has_csum_hi(sb, raw);
+    if has_hi {
+        put_u16(&mut copy, I_CHECKSUM_HI, 0);
+    }
+    let generation = at::<Inode>(raw, 0).generation.get();
+    let csum = crc32c(inode_seed(sb, ino, generation), &copy);
+    match has_hi {
+        true => csum,
+        false => csum & 0xFFFF,
+    }
+}
+
+pub fn verify_inode(sb: &SuperBlockInfo, ino: u32, raw: &[u8]) -> bool {
+    let mut stored = at::<Inode>(raw, 0).checksum_lo.get() as u32;
+    if has_csum_hi(sb, raw) {
+        stored |= (at::<InodeExtra>(raw, GOOD_OLD_INODE_SIZE).checksum_hi.get() as u32) << 16;
+    }
+    inode_csum(sb, ino, raw) == stored
+}
+
+pub fn set_inode_csum(sb: &SuperBlockInfo, ino: u32, raw: &mut [u8]) {
+    let csum = inode_csum(sb, ino, raw);
+    put_u16(raw, I_CHECKSUM_LO, csum as u16);
+    if has_csum_hi(sb, raw) {
+        put_u16(raw, I_CHECKSUM_HI, (csum >> 16) as u16);
+    }
+}
+
+/// Store the checksum of the xattr block, the blocks may be shared by the
+/// inodes so it's seeded with the block number instead of an inode.
+pub fn set_xattr_block_csum(sb: &SuperBlockInfo, block: u64, data: &mut [u8]) {
+    at_mut::<XattrHeader>(data, 0).checksum.set(0);
+    let csum = crc32c(
+        crc32c(sb.csum_seed, &block.to_le_bytes()),
+        &data[..sb.block_size()],
+    );
+    at_mut::<XattrHeader>(data, 0).checksum.set(csum);
+}
+
+/// The offset of the checksum after the entries of an extent tree node.
+fn extent_tail(block: &[u8]) -> Option<usize> {
+    let header = ExtentHeader::parse(block).ok()?;
+    let offset =
+        size_of::<disk::ExtentHeader>() + header.max as usize * size_of::<disk::ExtentLeaf>();
+    (offset + size_of::<ExtentTail>() <= block.len()).then_some(offset)
+}
+
+/// Verify the extent tree node, seed is the inode seed of the owner.
+pub fn verify_extent_block(seed: u32, block: &[u8]) -> bool {
+    match extent_tail(block) {
+        Some(offset) => {
+            crc32c(seed, &block[..offset]) == at::<ExtentTail>(block, offset).checksum.get()
+        }
+        None => false,
+    }
+}
+
+pub fn set_extent_block_csum(seed: u32, block: &mut [u8]) {
+    if let Some(offset) = extent_tail(block) {
+        let csum = crc32c(seed, &block[..offset]);
+        at_mut::<ExtentTail>(block, offset).checksum.set(csum);
+    }
+}
+
+/// The directory leaf block ends with the checksum entry. The blocks of
+/// the htree index have a different tail and aren't covered.
+pub fn has_dirent_tail(block: &[u8]) -> bool {
+    let Some(tail) = block.len().checked_sub(DIRENT_TAIL_SIZE) else {
+        return false;
+    };
+    let tail = at::<DirEntryTail>(block, tail);
+    tail.reserved_zero1.get() == 0
+        && tail.rec_len.get() as usize == DIRENT_TAIL_SIZE
+        && tail.reserved_zero2 == 0
+        && tail.reserved_ft == DIRENT_TAIL_FT
+}
+
+/// Write the empty checksum entry at the end of the directory leaf block.
+pub fn init_dirent_tail(block: &mut [u8]) {
+    let tail = block.len() - DIRENT_TAIL_SIZE;
+    block[tail..].fill(0);
+    let tail = at_mut::<DirEntryTail>(block, tail);
+    tail.rec_len.set(DIRENT_TAIL_SIZE as u16);
+    tail.reserved_ft = DIRENT_TAIL_FT;
+}
+
+/// Verify the directory leaf block, seed is the inode seed of the
+/// directory. A block without the tail can't be verified.
+pub fn verify_dir_block(seed: u32, block: &[u8]) -> bool {
+    let tail = block.len() - DIRENT_TAIL_SIZE;
+    crc32c(seed, &block[..tail]) == at::<DirEntryTail>(block, tail).checksum.get()
+}
+
+pub fn set_dir_block_csum(seed: u32, block: &mut [u8]) {
+    let tail = block.len() - DIRENT_TAIL_SIZE;
+    let csum = crc32c(seed, &block[..tail]);
+    at_mut::<DirEntryTail>(block, tail).checksum.set(csum);
+}
+
+/// Set the checksum in dx_tail of the htree index block, offset is the
+/// offset of its count and limit. The checksum covers the used entries,
+/// the first half of the tail and a zero checksum, like the kernel, the
+/// block must have room for it.
+pub fn set_dx_block_csum(seed: u32, block: &mut [u8], offset: usize) {
+    let header = at::<DxCountLimit>(block, offset);
+    let (limit, count) = (header.limit.get() as usize, header.count.get() as usize);
+    let tail = offset + limit * size_of::<DxEntry>();
+    if count > limit || tail + size_of::<DxTail>() > block.len() {
+        return;
+    }
+    let csum = crc32c(seed, &block[..offset + count * size_of::<DxEntry>()]);
+    let csum = crc32c(csum, &block[tail..tail + offset_of!(DxTail, checksum)]);
+    let csum = crc32c(csum, &[0; 4]);
+    at_mut::<DxTail>(block, tail).checksum.set(csum);
+}
@@ -0,0 +1,154 @@
+// The checksums of the blocks a transaction of the ext4_rs shim logged,
+// set before its commit, and the check of the directory blocks it reads.
+
+use alloc::{collections::BTreeSet, vec::Vec};
+use vfscore::{FileType, VfsResult};
+
+use ext4_rs::BlockDevice;
+
+use crate::ext4_check;
+use crate::ext4_csum::{
+    has_dirent_tail, inode_seed, set_bitmap_csums, set_dir_block_csum, set_dx_block_csum,
+    set_extent_block_csum, set_group_desc_csum, set_inode_csum, set_superblock_csum,
+    verify_dir_block,
+};
+use crate::ext4_htree::dx_entries_offset;
+use crate::ext4_layout::{InodeInfo, EXT4_INDEX_FL, SUPERBLOCK_OFFSET};
+use crate::ext4_rs_shim::{mode_file_type, Ext4Volume};
+
+impl Ext4Volume {
+    /// Recompute the metadata checksums of the structures logged in the
+    /// running transaction, ext4_rs doesn't maintain them. The inodes
+    /// changed in the inode tables are found by comparing with the disk,
+    /// inodes adds the ones whose blocks may be logged without changing.
+    pub(crate) fn update_checksums(&self, inodes: &[u32]) -> VfsResult<()> {
+        let sb = &self.sb;
+        let block_size = sb.block_size();
+        let inode_size = sb.inode_size as usize;
+        let per_block = block_size / inode_size;
+        let ipg = sb.inodes_per_group as usize;
+        let table_blocks = ipg.div_ceil(per_block) as u64;
+        let logged = self.disk.logged_blocks();
+        if logged.is_empty() {
+            return Ok(());
+        }
+
+        let mut touched: BTreeSet<u32> = inodes.iter().copied().collect();
+        let mut bitmap_groups = Vec::new();
+        for group in 0..sb.groups_count() {
+            let desc = self.disk.group_desc(sb, group)?;
+            for &block in logged.range(desc.inode_table..desc.inode_table + table_blocks) {
+                let new = self.read_block(block);
+                let mut old = vec![0; block_size];
+                self.disk
+                    .read_device_into(block as usize * block_size, &mut old);
+                for slot in 0..per_block {
+                    let range = slot * inode_size..(slot + 1) * inode_size;
+                    if new[range.clone()] != old[range] {
+                        let index = (block - desc.inode_table) as usize * per_block + slot;
+                        touched.insert((group * ipg + index + 1) as u32);
+                    }
+                }
+            }
+            if logged.contains(&desc.block_bitmap) || logged.contains(&desc.inode_bitmap) {
+                bitmap_groups.push((group, desc));
+            }
+        }
+
+        // the extent and directory blocks, then the inode itself.
+        for ino in touched {
+            let offset = self.inode_offset(ino)?;
+            let mut raw = self.disk.read_offset(offset)[..inode_size].to_vec();
+            let inode = InodeInfo::parse(&raw, inode_size);
+            let seed = inode_seed(sb, ino, inode.generation);
+            let is_dir = matches!(mode_file_type(inode.mode), Some(FileType::Directory));
+            if inode.uses_extents() || is_dir {
+                // the extent nodes, then the directory blocks by their
+                // logical block. The indirect blocks of a block-mapped
+                // directory have no checksum, its leaves do.
+                let (extents, nodes) = ext4_check::inode_blocks(self, &inode)?;
+                let nodes = match inode.uses_extents() {
+                    true => nodes,
+                    false => Vec::new(),
+                };
+                let mut blocks: Vec<(Option<u32>, u64)> =
+                    nodes.iter().map(|x| (None, *x)).collect();
+                if is_dir {
+                    for extent in extents.iter() {
+                        blocks.extend(
+                            (0..extent.len)
+                                .map(|i| (Some(extent.logical + i), extent.physical + i as u64)),
+                        );
+                    }
+                }
+                let indexed = inode.flags & EXT4_INDEX_FL != 0;
+                for (lblock, block) in blocks.into_iter().filter(|x| logged.contains(&x.1)) {
+                    let mut data = self.read_block(block);
+                    data.truncate(block_size);
+                    match lblock {
+                        None => set_extent_block_csum(seed, &mut data),
+                        Some(_) if has_dirent_tail(&data) => set_dir_block_csum(seed, &mut data),
+                        Some(lblock) => {
+                            match dx_entries_offset(lblock, &data).filter(|_| indexed) {
+                                Some(offset) => set_dx_block_csum(seed, &mut data, offset),
+                                None => continue,
+                            }
+                        }
+                    }
+                    self.disk.write_offset(block as usize * block_size, &data);
+                }
+            }
+            set_inode_csum(sb, ino, &mut raw);
+            self.disk.write_offset(offset, &raw);
+        }
+
+        for (group, desc) in bitmap_groups {
+            let offset = sb.group_desc_offset(group);
+            let mut raw = self.disk.read_offset(offset)[..sb.group_desc_size()].to_vec();
+            set_bitmap_csums(
+                sb,
+                &mut raw,
+                &self.read_block(desc.block_bitmap),
+                &self.read_block(desc.inode_bitmap),
+            );
+            self.disk.write_offset(offset, &raw);
+        }
+        // the descriptors in the logged blocks, the ones updated above too.
+        let logged = self.disk.logged_blocks();
+        for group in 0..sb.groups_count() {
+            let offset = sb.group_desc_offset(group);
+            if !logged.contains(&((offset / block_size) as u64)) {
+                continue;
+            }
+            let mut raw = self.disk.read_offset(offset)[..sb.group_desc_size()].to_vec();
+            set_group_desc_csum(sb, group as u32, &mut raw);
+            self.disk.write_offset(offset, &raw);
+        }
+        if logged.contains(&((SUPERBLOCK_OFFSET / block_size) as u64)) {
+            let mut raw = self.disk.read_offset(SUPERBLOCK_OFFSET)[..1024].to_vec();
+            set_superblock_csum(&mut raw);
+            self.disk.write_offset(SUPERBLOCK_OFFSET, &raw);
+        }
+        Ok(())
+    }
+
+    /// Verify the directory leaf block with metadata_csum, the blocks
+    /// without the checksum tail pass.
+    pub(crate) fn verify_dir_block(
+        &self,
+        ino: u32,
+        seed: u32,
+        block: u64,
+        data: &[u8],
+    ) -> VfsResult<()> {
+        let block_size = self.sb.block_size();
+        if self.sb.has_metadata_csum()
+            && has_dirent_tail(data)
+            && !self.disk.is_logged(block as usize * block_size, block_size)
+            && !verify_dir_block(seed, data)
+        {
+            return Err(self.corrupted("verify_dir_block", "directory checksum", ino, block));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,408 @@
+// debugfs-like dumps of the on-disk structures of ext4, for the tests and
+// the logs. Every dump is a structured value read through CheckDisk, the
+// Display impls format them for humans. Nothing is verified, a corrupted
+// structure is dumped as far as it can be parsed.
+
+use core::fmt::{self, Display, Formatter};
+use core::ops::Range;
+
+use alloc::vec::Vec;
+use vfscore::{VfsError, VfsResult};
+
+use crate::disk_layout::{self as disk, DirEntry, ExtentIdx, Layout};
+use crate::ext4_check::{inode_blocks, CheckDisk};
+use crate::ext4_layout::{
+    bitmap_test, Extent, ExtentHeader, GroupDesc, InodeInfo, SuperBlockInfo, DIRENT_HEADER,
+    EXTENT_ENTRY, EXTENT_MAX_DEPTH, SUPERBLOCK_OFFSET,
+};
+use crate::ops::name_from_bytes;
+
+/// The superblock as it's on the disk now, with the current counters.
+#[derive(Debug, Clone)]
+pub struct SuperblockDump {
+    pub info: SuperBlockInfo,
+    pub state: u16,
+}
+
+pub fn dump_superblock(disk: &impl CheckDisk) -> SuperblockDump {
+    let block_size = disk.superblock().block_size();
+    let data = disk.read_block((SUPERBLOCK_OFFSET / block_size) as u64);
+    let raw = &data[SUPERBLOCK_OFFSET % block_size..];
+    SuperblockDump {
+        info: SuperBlockInfo::parse(raw),
+        state: disk::SuperBlock::ref_from(raw).map_or(0, |x| x.state.get()),
+    }
+}
+
+impl Display for SuperblockDump {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let sb = &self.info;
+        writeln!(f, "magic: {:#x}, state: {:#x}", sb.magic, sb.state)?;
+        writeln!(
+            f,
+            "blocks: {} ({} free, {} reserved), block size: {}, first data block: {}",
+            sb.blocks_count,
+            sb.free_blocks_count,
+            sb.r_blocks_count,
+            sb.block_size(),
+            sb.first_data_block
+        )?;
+        writeln!(
+            f,
+            "inodes: {} ({} free), inode size: {}, first inode: {}",
+            sb.inodes_count, sb.free_inodes_count, sb.inode_size, sb.first_ino
+        )?;
+        writeln!(
+            f,
+            "groups: {}, blocks per group: {}, inodes per group: {}, desc size: {}",
+            sb.groups_count(),
+            sb.blocks_per_group,
+            sb.inodes_per_group,
+            sb.group_desc_size()
+        )?;
+        writeln!(
+            f,
+            "features: compat {:#x}, incompat {:#x}, ro_compat {:#x}",
+            sb.feature_compat, sb.feature_incompat, sb.feature_ro_compat
+        )?;
+        write!(
+            f,
+            "journal inode: {}, last orphan: {}",
+            sb.journal_inum, sb.last_orphan
+        )
+    }
+}
+
+/// An entry of an extent tree node.
+#[derive(Debug, Clone, Copy)]
+pub enum ExtentEntry {
+    /// The child node covering the logical blocks from logical.
+    Index {
+        logical: u32,
+        child: u64,
+    },
+    Leaf(Extent),
+}
+
+/// A node of the extent tree, in the depth-first order of the tree.
+#[derive(Debug, Clone)]
+pub struct ExtentNode {
+    /// The block of the node, None for the root in the inode.
+    pub block: Option<u64>,
+    /// The depth of the node in the tree, the root is 0.
+    pub level: u16,
+    /// The header, None if the node isn't a valid extent node.
+    pub header: Option<ExtentHeader>,
+    pub entries: Vec<ExtentEntry>,
+}
+
+/// The fields of the inode and its extent tree.
+#[derive(Debug, Clone)]
+pub struct InodeDump {
+    pub ino: u32,
+    pub inode: InodeInfo,
+    /// The nodes of the extent tree, empty if the inode doesn't map its
+    /// blocks by extents.
+    pub tree: Vec<ExtentNode>,
+}
+
+impl InodeDump {
+    /// The extents in the leaves of the tree.
+    pub fn extents(&self) -> impl Iterator<Item = &Extent> {
+        self.tree
+            .iter()
+            .flat_map(|x| x.entries.iter())
+            .filter_map(|x| match x {
+                ExtentEntry::Leaf(extent) => Some(extent),
+                ExtentEntry::Index { .. } => None,
+            })
+    }
+}
+
+pub fn dump_inode(disk: &impl CheckDisk, ino: u32) -> VfsResult<InodeDump> {
+    let sb = disk.superblock();
+    if ino == 0 || ino > sb.inodes_count {
+        return Err(VfsError::InvalidInput);
+    }
+    let inode = disk.read_inode(ino);
+    let mut tree = Vec::new();
+    if inode.uses_extents() && !inode.has_inline_data() {
+        dump_extent_node(disk, None, inode.i_block.to_vec(), 0, &mut tree);
+    }
+    Ok(InodeDump { ino, inode, tree })
+}
+
+/// Dump the node and its children, the children beyond the filesystem or
+/// below the max depth aren't read.
+fn dump_extent_node(
+    disk: &impl CheckDisk,
+    block: Option<u64>,
+    node: Vec<u8>,
+    level: u16,
+    tree: &mut Vec<ExtentNode>,
+) {
+    let header = ExtentHeader::parse(&node).ok();
+    let entries: Vec<ExtentEntry> = match header {
+        Some(header) => (1..=header.entries as usize)
+            .map_while(|i| node.get(i * EXTENT_ENTRY..(i + 1) * EXTENT_ENTRY))
+            .map(|entry| match header.depth {
+                0 => ExtentEntry::Leaf(Extent::decode(entry)),
+                _ => {
+                    let index = ExtentIdx::ref_from(entry).expect("the entry is 12 bytes");
+                    ExtentEntry::Index {
+                        logical: index.block.get(),
+                        child: index.leaf(),
+                    }
+                }
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    let children: Vec<u64> = entries
+        .iter()
+        .filter_map(|x| match x {
+            ExtentEntry::Index { child, .. } => Some(*child),
+            ExtentEntry::Leaf(_) => None,
+        })
+        .collect();
+    tree.push(ExtentNode {
+        block,
+        level,
+        header,
+        entries,
+    });
+    if level >= EXTENT_MAX_DEPTH {
+        return;
+    }
+    let blocks_count = disk.superblock().blocks_count;
+    for child in children.into_iter().filter(|x| *x < blocks_count) {
+        let data = disk.read_block(child);
+        dump_extent_node(disk, Some(child), data, level + 1, tree);
+    }
+}
+
+impl Display for InodeDump {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let inode = &self.inode;
+        writeln!(
+            f,
+            "inode {}: mode {:#o}, links {}, size {}, blocks {}, flags {:#x}",
+            self.ino, inode.mode, inode.links_count, inode.size, inode.blocks, inode.flags
+        )?;
+        write!(
+            f,
+            "generation {}, dtime {}, file_acl {}",
+            inode.generation, inode.dtime, inode.file_acl
+        )?;
+        for node in self.tree.iter() {
+            let indent = node.level as usize * 2;
+            writeln!(f)?;
+            match (node.block, node.header) {
+                (_, None) => write!(f, "{:indent$}node {:?}: bad header", "", node.block)?,
+                (None, Some(h)) => write!(
+                    f,
+                    "{:indent$}root: depth {}, entries {}/{}",
+                    "", h.depth, h.entries, h.max
+                )?,
+                (Some(block), Some(h)) => write!(
+                    f,
+                    "{:indent$}node {}: depth {}, entries {}/{}",
+                    "", block, h.depth, h.entries, h.max
+                )?,
+            }
+            for entry in node.entries.iter() {
+                writeln!(f)?;
+                match entry {
+                    ExtentEntry::Index { logical, child } => {
+                        write!(f, "{:indent$}  [{}..] -> node {}", "", logical, child)?
+                    }
+                    ExtentEntry::Leaf(x) => write!(
+                        f,
+                        "{:indent$}  [{}..{}] -> {}..{}{}",
+                        "",
+                        x.logical,
+                        x.logical as u64 + x.len as u64,
+                        x.physical,
+                        x.physical + x.len as u64,
+                        if x.uninit { " uninit" } else { "" }
+                    )?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A directory entry as it's in the block, the unused ones included.
+#[derive(Debug, Clone)]
+pub struct RawDirent {
+    pub offset: usize,
+    pub inode: u32,
+    pub rec_len: u16,
+    pub name_len: u8,
+    pub file_type: u8,
+    pub name: Vec<u8>,
+}
+
+/// The entries of a directory block.
+#[derive(Debug, Clone)]
+pub struct DirBlockDump {
+    pub ino: u32,
+    pub lblock: u32,
+    pub block: u64,
+    pub entries: Vec<RawDirent>,
+    /// The offset of the malformed entry ending the walk.
+    pub bad_offset: Option<usize>,
+}
+
+/// Dump the logical block of the directory.
+pub fn dump_dir_block(disk: &impl CheckDisk, ino: u32, lblock: u32) -> VfsResult<DirBlockDump> {
+    let dump = dump_inode(disk, ino)?;
+    if dump.inode.has_inline_data() {
+        return Err(VfsError::NotSupported);
+    }
+    let (extents, _) = inode_blocks(disk, &dump.inode)?;
+    let block = extents
+        .iter()
+        .find(|x| x.contains(lblock))
+        .map(|x| x.physical + (lblock - x.logical) as u64)
+        .ok_or(VfsError::InvalidInput)?;
+    let mut data = disk.read_block(block);
+    data.truncate(disk.superblock().block_size());
+
+    let mut entries = Vec::new();
+    let mut bad_offset = None;
+    let mut offset = 0;
+    while let Some(entry) = DirEntry::read_from(&data[offset..]) {
+        let rec_len = entry.rec_len.get();
+        let name_len = entry.name_len;
+        if (rec_len as usize) < DIRENT_HEADER + name_len as usize
+            || offset + rec_len as usize > data.len()
+        {
+            bad_offset = Some(offset);
+            break;
+        }
+        let name = &data[offset + DIRENT_HEADER..offset + DIRENT_HEADER + name_len as usize];
+        entries.push(RawDirent {
+            offset,
+            inode: entry.inode.get(),
+            rec_len,
+            name_len,
+            file_type: entry.file_type,
+            name: name.to_vec(),
+        });
+        offset += rec_len as usize;
+    }
+    Ok(DirBlockDump {
+        ino,
+        lblock,
+        block,
+        entries,
+        bad_offset,
+    })
+}
+
+impl Display for DirBlockDump {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "directory {} block {} (physical {})",
+            self.ino, self.lblock, self.block
+        )?;
+        for x in self.entries.iter() {
+            write!(
+                f,
+                "\n  {:>4}: inode {}, rec_len {}, name_len {}, type {:#x}, {:?}",
+                x.offset,
+                x.inode,
+                x.rec_len,
+                x.name_len,
+                x.file_type,
+                name_from_bytes(&x.name)
+            )?;
+        }
+        if let Some(offset) = self.bad_offset {
+            write!(f, "\n  {:>4}: malformed entry", offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// The bitmaps of a group, as the ranges of the used blocks and inodes.
+#[derive(Debug, Clone)]
+pub struct BitmapDump {
+    pub group: usize,
+    pub desc: GroupDesc,
+    pub used_blocks: Vec<Range<u64>>,
+    pub used_inodes: Vec<Range<u32>>,
+    /// The free counts of the bitmaps, the descriptor has its own.
+    pub free_blocks: u32,
+    pub free_inodes: u32,
+}
+
+pub fn dump_bitmap(disk: &impl CheckDisk, group: usize) -> VfsResult<BitmapDump> {
+    let sb = disk.superblock();
+    if group >= sb.groups_count() {
+        return Err(VfsError::InvalidInput);
+    }
+    let desc = disk.group_desc(group);
+    let first = sb.first_data_block as u64 + group as u64 * sb.blocks_per_group as u64;
+    let blocks = (sb.blocks_count - first).min(sb.blocks_per_group as u64) as usize;
+    let bitmap = disk.read_block(desc.block_bitmap);
+    let used_blocks = used_ranges(&bitmap, blocks, first);
+    let bitmap = disk.read_block(desc.inode_bitmap);
+    let first_ino = group as u64 * sb.inodes_per_group as u64 + 1;
+    let used_inodes = used_ranges(&bitmap, sb.inodes_per_group as usize, first_ino)
+        .into_iter()
+        .map(|x| x.start as u32..x.end as u32)
+        .collect::<Vec<_>>();
+    let count = |ranges: &[Range<u64>]| ranges.iter().map(|x| x.end - x.start).sum::<u64>();
+    let inodes_used: u32 = used_inodes.iter().map(|x| x.end - x.start).sum();
+    Ok(BitmapDump {
+        group,
+        desc,
+        free_blocks: blocks as u32 - count(&used_blocks) as u32,
+        free_inodes: sb.inodes_per_group - inodes_used,
+        used_blocks,
+        used_inodes,
+    })
+}
+
+/// The ranges of the set bits in the first bits of the bitmap, offset by
+/// base.
+fn used_ranges(bitmap: &[u8], bits: usize, base: u64) -> Vec<Range<u64>> {
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+    for bit in (0..bits).filter(|x| bitmap_test(bitmap, *x)) {
+        let bit = base + bit as u64;
+        match ranges.last_mut() {
+            Some(last) if last.end == bit => last.end += 1,
+            _ => ranges.push(bit..bit + 1),
+        }
+    }
+    ranges
+}
+
+impl Display for BitmapDump {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let desc = &self.desc;
+        writeln!(
+            f,
+            "group {}: block bitmap {}, inode bitmap {}, inode table {}, flags {:#x}",
+            self.group, desc.block_bitmap, desc.inode_bitmap, desc.inode_table, desc.flags
+        )?;
+        writeln!(
+            f,
+            "free blocks {} (descriptor {}), free inodes {} (descriptor {})",
+            self.free_blocks, desc.free_blocks, self.free_inodes, desc.free_inodes
+        )?;
+        write!(f, "used blocks:")?;
+        for x in self.used_blocks.iter() {
+            write!(f, " {}-{}", x.start, x.end - 1)?;
+        }
+        write!(f, "\nused inodes:")?;
+        for x in self.used_inodes.iter() {
+            write!(f, " {}-{}", x.start, x.end - 1)?;
+        }
+        Ok(())
+    }
+}
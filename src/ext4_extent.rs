@@ -0,0 +1,470 @@
+// The changes of the extent trees of ext4 files. The shim loads the tree
+// of a file as its leaves, changes the extents in them and stores it back:
+// - a new extent is merged with the extents next to it when they are
+//   contiguous, in its leaf or in the leaves beside it.
+// - a full leaf is split in two, like ext4_ext_split of fs/ext4/extents.c,
+//   an append leaves the full leaf as it is and starts a new one.
+// - a removed range splits the extents across its ends, like the
+//   relocation of a bad block.
+// - the leaves which get empty are freed, and the neighbour leaves which
+//   fit in half a node are merged.
+// - the index nodes are rebuilt from the leaves at every store, so the
+//   tree grows a level when the root can't index the nodes below it, and
+//   it goes back into the inode when the extents fit in the root again.
+// Like the rest of ext4_layout, the nodes are byte slices, the shim reads,
+// allocates and writes them in shim.rs. Their checksum tails are set with
+// the rest of the metadata of the transaction.
+
+use alloc::{vec, vec::Vec};
+use vfscore::{VfsError, VfsResult};
+
+use crate::ext4_layout::{
+    le_u16, le_u32, Extent, ExtentHeader, EXTENT_MAGIC, EXTENT_MAX_DEPTH, EXT_INIT_MAX_LEN,
+};
+
+mod shim;
+
+/// The entries of the root in i_block.
+pub const ROOT_ENTRIES: usize = 4;
+/// The size of i_block.
+pub const ROOT_SIZE: usize = 12 + ROOT_ENTRIES * 12;
+
+/// The entries of a node in a block, the checksum tail fits after them.
+pub fn node_entries(block_size: usize) -> usize {
+    (block_size - 12) / 12
+}
+
+fn put_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_header(node: &mut [u8], entries: usize, max: usize, depth: u16) {
+    put_u16(node, 0, EXTENT_MAGIC);
+    put_u16(node, 2, entries as u16);
+    put_u16(node, 4, max as u16);
+    put_u16(node, 6, depth);
+    put_u32(node, 8, 0);
+}
+
+/// Fill node with a leaf of the extents, max entries fit in it.
+fn leaf_node(node: &mut [u8], max: usize, extents: &[Extent]) {
+    put_header(node, extents.len(), max, 0);
+    for (i, extent) in extents.iter().enumerate() {
+        extent.encode(&mut node[12 + i * 12..24 + i * 12]);
+    }
+}
+
+/// Fill node with an index of the (first logical block, block) of its
+/// children at depth - 1.
+fn index_node(node: &mut [u8], max: usize, depth: u16, children: &[(u32, u64)]) {
+    put_header(node, children.len(), max, depth);
+    for (i, &(logical, block)) in children.iter().enumerate() {
+        let entry = &mut node[12 + i * 12..24 + i * 12];
+        put_u32(entry, 0, logical);
+        put_u32(entry, 4, block as u32);
+        put_u16(entry, 8, (block >> 32) as u16);
+        put_u16(entry, 10, 0);
+    }
+}
+
+/// The extents can be one extent.
+fn joins(x: &Extent, next: &Extent) -> bool {
+    x.uninit == next.uninit
+        && x.logical + x.len == next.logical
+        && x.physical + x.len as u64 == next.physical
+        && x.len + next.len <= max_len(x.uninit)
+}
+
+/// The max length of an extent, an uninitialized one has one block less.
+fn max_len(uninit: bool) -> u32 {
+    match uninit {
+        true => EXT_INIT_MAX_LEN as u32 - 1,
+        false => EXT_INIT_MAX_LEN as u32,
+    }
+}
+
+/// A leaf of the tree and its node block, None in the root or before the
+/// node is allocated.
+struct Leaf {
+    block: Option<u64>,
+    extents: Vec<Extent>,
+    /// The extents were changed since the load, the node is written.
+    dirty: bool,
+}
+
+/// The extent tree of a file as its leaves. The leaves are sorted by
+/// their extents, none of them is empty but the root of an empty tree.
+pub struct ExtentTree {
+    leaves: Vec<Leaf>,
+    /// The single leaf is the root in the inode.
+    in_root: bool,
+    /// The blocks of the index nodes below the root, reused by store.
+    index: Vec<u64>,
+    /// The blocks of the leaves dropped since the load, freed by store.
+    dropped: Vec<u64>,
+    node_entries: usize,
+}
+
+impl ExtentTree {
+    /// Load the tree whose root is i_block, read_block reads the nodes
+    /// below it. The tree must be valid, like the one of walk_extents.
+    pub fn parse(
+        i_block: &[u8],
+        block_size: usize,
+        mut read_block: impl FnMut(u64) -> VfsResult<Vec<u8>>,
+    ) -> VfsResult<Self> {
+        let mut tree = Self {
+            leaves: Vec::new(),
+            in_root: ExtentHeader::parse(i_block)?.depth == 0,
+            index: Vec::new(),
+            dropped: Vec::new(),
+            node_entries: node_entries(block_size),
+        };
+        // the nodes are visited in order, the first child first.
+        let mut stack: Vec<(Option<u64>, Vec<u8>)> = vec![(None, i_block.to_vec())];
+        while let Some((block, node)) = stack.pop() {
+            let header = ExtentHeader::parse(&node)?;
+            let entries = (0..header.entries as usize).map(|i| &node[12 + i * 12..24 + i * 12]);
+            if header.depth == 0 {
+                let extents: Vec<Extent> = entries.map(Extent::decode).collect();
+                match extents.is_empty() && block.is_some() {
+                    true => tree.dropped.extend(block),
+                    false => tree.leaves.push(Leaf {
+                        block,
+                        extents,
+                        dirty: false,
+                    }),
+                }
+                continue;
+            }
+            tree.index.extend(block);
+            let children: Vec<u64> = entries
+                .map(|entry| le_u32(entry, 4) as u64 | (le_u16(entry, 8) as u64) << 32)
+                .collect();
+            for child in children.into_iter().rev() {
+                stack.push((Some(child), read_block(child)?));
+            }
+        }
+        if tree.leaves.is_empty() {
+            tree.clear();
+        }
+        Ok(tree)
+    }
+
+    /// Make the tree an empty root, its nodes are dropped.
+    fn clear(&mut self) {
+        let blocks = self.leaves.drain(..).filter_map(|x| x.block);
+        self.dropped.extend(blocks);
+        self.leaves.push(Leaf {
+            block: None,
+            extents: Vec::new(),
+            dirty: true,
+        });
+        self.in_root = true;
+    }
+
+    /// The index of the leaf where the extent at lblock is or would be.
+    fn leaf_of(&self, lblock: u32) -> usize {
+        self.leaves
+            .partition_point(|x| x.extents.first().is_some_and(|x| x.logical <= lblock))
+            .saturating_sub(1)
+    }
+
+    /// The last extent starting at or before lblock.
+    pub fn before(&self, lblock: u32) -> Option<Extent> {
+        let leaf = &self.leaves[self.leaf_of(lblock)].extents;
+        let index = leaf.partition_point(|x| x.logical <= lblock);
+        index.checked_sub(1).map(|x| leaf[x])
+    }
+
+    /// The extent covering lblock.
+    pub fn find(&self, lblock: u32) -> Option<Extent> {
+        self.before(lblock).filter(|x| x.contains(lblock))
+    }
+
+    /// The first block mapped after lblock, where a hole at lblock ends.
+    pub fn next_mapped(&self, lblock: u32) -> Option<u32> {
+        let l = self.leaf_of(lblock);
+        let leaf = &self.leaves[l].extents;
+        match leaf.get(leaf.partition_point(|x| x.logical <= lblock)) {
+            Some(x) => Some(x.logical),
+            None => self.leaves.get(l + 1).map(|x| x.extents[0].logical),
+        }
+    }
+
+    /// Add the extent of blocks which aren't mapped, it's merged with the
+    /// extents next to it.
+    pub fn insert(&mut self, new: Extent) {
+        let mut l = self.leaf_of(new.logical);
+        let leaf = &mut self.leaves[l];
+        let mut i = leaf.extents.partition_point(|x| x.logical < new.logical);
+        leaf.extents.insert(i, new);
+        leaf.dirty = true;
+        if i > 0 && joins(&leaf.extents[i - 1], &leaf.extents[i]) {
+            let x = leaf.extents.remove(i);
+            leaf.extents[i - 1].len += x.len;
+            i -= 1;
+        }
+        if i + 1 < leaf.extents.len() && joins(&leaf.extents[i], &leaf.extents[i + 1]) {
+            let x = leaf.extents.remove(i + 1);
+            leaf.extents[i].len += x.len;
+        }
+        // the extents next to it in the leaves beside it.
+        if i == 0 && l > 0 {
+            let (prev, rest) = self.leaves.split_at_mut(l);
+            let prev = prev.last_mut().unwrap();
+            let last = prev.extents.last_mut().unwrap();
+            if joins(last, &rest[0].extents[0]) {
+                last.len += rest[0].extents.remove(0).len;
+                prev.dirty = true;
+                if rest[0].extents.is_empty() {
+                    self.drop_leaf(l);
+                }
+                l -= 1;
+                i = self.leaves[l].extents.len() - 1;
+            }
+        }
+        let len = self.leaves[l].extents.len();
+        if i + 1 == len && l + 1 < self.leaves.len() {
+            let (leaf, rest) = self.leaves.split_at_mut(l + 1);
+            let last = leaf[l].extents.last_mut().unwrap();
+            if joins(last, &rest[0].extents[0]) {
+                last.len += rest[0].extents.remove(0).len;
+                rest[0].dirty = true;
+                if rest[0].extents.is_empty() {
+                    self.drop_leaf(l + 1);
+                }
+            }
+        }
+        self.split(l, i);
+    }
+
+    fn drop_leaf(&mut self, l: usize) {
+        let leaf = self.leaves.remove(l);
+        self.dropped.extend(leaf.block);
+    }
+
+    /// Split the leaf l if it's over full after the extent i was added.
+    fn split(&mut self, l: usize, i: usize) {
+        let len = self.leaves[l].extents.len();
+        if self.in_root && len > ROOT_ENTRIES {
+            // the root moves to a node, store indexes it.
+            self.in_root = false;
+        }
+        if len <= self.node_entries {
+            return;
+        }
+        let at = match l + 1 == self.leaves.len() && i + 1 == len {
+            true => len - 1,
+            false => len / 2,
+        };
+        let extents = self.leaves[l].extents.split_off(at);
+        self.leaves.insert(
+            l + 1,
+            Leaf {
+                block: None,
+                extents,
+                dirty: true,
+            },
+        );
+    }
+
+    /// Remove the blocks from keep on, return the extents removed.
+    pub fn truncate(&mut self, keep: u32) -> Vec<Extent> {
+        let mut removed = Vec::new();
+        for leaf in self.leaves.iter_mut() {
+            for extent in leaf.extents.iter_mut() {
+                let end = extent.logical + extent.len;
+                if end <= keep {
+                    continue;
+                }
+                let cut = (end - keep).min(extent.len);
+                extent.len -= cut;
+                removed.push(Extent {
+                    logical: extent.logical + extent.len,
+                    len: cut,
+                    physical: extent.physical + extent.len as u64,
+                    uninit: extent.uninit,
+                });
+                leaf.dirty = true;
+            }
+            leaf.extents.retain(|x| x.len > 0);
+        }
+        while self.leaves.len() > 1 && self.leaves.last().unwrap().extents.is_empty() {
+            self.drop_leaf(self.leaves.len() - 1);
+        }
+        if self.leaves[0].extents.is_empty() {
+            self.clear();
+        }
+        removed
+    }
+
+    /// Unmap the len blocks from start, an extent across an end of the
+    /// range is split. return the extents removed.
+    pub fn remove(&mut self, start: u32, len: u32) -> Vec<Extent> {
+        let end = start + len;
+        let mut removed = Vec::new();
+        let mut l = 0;
+        while l < self.leaves.len() {
+            let leaf = &mut self.leaves[l];
+            if leaf.extents.first().is_some_and(|x| x.logical >= end) {
+                break;
+            }
+            let mut tail = None;
+            for extent in leaf.extents.iter_mut() {
+                let x = *extent;
+                let (from, to) = (x.logical.max(start), (x.logical + x.len).min(end));
+                if from >= to {
+                    continue;
+                }
+                removed.push(Extent {
+                    logical: from,
+                    len: to - from,
+                    physical: x.physical + (from - x.logical) as u64,
+                    uninit: x.uninit,
+                });
+                extent.len = from - x.logical;
+                leaf.dirty = true;
+                if to < x.logical + x.len {
+                    tail = Some(Extent {
+                        logical: to,
+                        len: x.logical + x.len - to,
+                        physical: x.physical + (to - x.logical) as u64,
+                        uninit: x.uninit,
+                    });
+                }
+            }
+            leaf.extents.retain(|x| x.len > 0);
+            // the range ends in this leaf, its tail follows the extents
+            // before it.
+            if let Some(tail) = tail {
+                let i = leaf.extents.partition_point(|x| x.logical < tail.logical);
+                leaf.extents.insert(i, tail);
+                self.split(l, i);
+                break;
+            }
+            match self.leaves[l].extents.is_empty() && self.leaves.len() > 1 {
+                true => self.drop_leaf(l),
+                false => l += 1,
+            }
+        }
+        if !removed.is_empty() && self.leaves[0].extents.is_empty() {
+            self.clear();
+        }
+        removed
+    }
+
+    /// Merge the leaves which are nearly empty, and move the tree back
+    /// to the root if its extents fit.
+    fn rebalance(&mut self) {
+        if self.in_root {
+            return;
+        }
+        let total: usize = self.leaves.iter().map(|x| x.extents.len()).sum();
+        if total <= ROOT_ENTRIES {
+            let extents = self.leaves.drain(..).fold(Vec::new(), |mut all, leaf| {
+                all.extend(leaf.extents);
+                self.dropped.extend(leaf.block);
+                all
+            });
+            self.leaves.push(Leaf {
+                block: None,
+                extents,
+                dirty: true,
+            });
+            self.in_root = true;
+            return;
+        }
+        let mut l = 0;
+        while l + 1 < self.leaves.len() {
+            let len = self.leaves[l].extents.len() + self.leaves[l + 1].extents.len();
+            if len > self.node_entries / 2 {
+                l += 1;
+                continue;
+            }
+            let next = self.leaves.remove(l + 1);
+            self.leaves[l].extents.extend(next.extents);
+            self.leaves[l].dirty = true;
+            self.dropped.extend(next.block);
+        }
+    }
+
+    /// Write the changed nodes and return the new i_block and the nodes
+    /// allocated, negative if more were freed. alloc allocates a block
+    /// near the goal, write writes a node and free frees one. The index
+    /// nodes are rewritten, the leaves if they changed. A tree which needs
+    /// more than EXTENT_MAX_DEPTH levels fails with InvalidInput, the
+    /// write sees it like the EFBIG of check_range.
+    pub fn store(
+        &mut self,
+        block_size: usize,
+        mut alloc: impl FnMut(u64) -> VfsResult<u64>,
+        mut write: impl FnMut(u64, &[u8]),
+        mut free: impl FnMut(u64) -> VfsResult<()>,
+    ) -> VfsResult<([u8; ROOT_SIZE], i64)> {
+        self.rebalance();
+        let mut root = [0; ROOT_SIZE];
+        let mut nodes = 0;
+        let mut unused = core::mem::take(&mut self.index);
+        if self.in_root {
+            leaf_node(&mut root, ROOT_ENTRIES, &self.leaves[0].extents);
+            self.leaves[0].dirty = false;
+        } else {
+            let max = self.node_entries;
+            let mut node = vec![0; block_size];
+            for leaf in self.leaves.iter_mut() {
+                if leaf.block.is_none() {
+                    leaf.block = Some(alloc(leaf.extents[0].physical)?);
+                    nodes += 1;
+                    leaf.dirty = true;
+                }
+                if leaf.dirty {
+                    node.fill(0);
+                    leaf_node(&mut node, max, &leaf.extents);
+                    write(leaf.block.unwrap(), &node);
+                    leaf.dirty = false;
+                }
+            }
+            let mut level: Vec<(u32, u64)> = self
+                .leaves
+                .iter()
+                .map(|x| (x.extents[0].logical, x.block.unwrap()))
+                .collect();
+            let mut depth = 1;
+            // the index nodes are reused in the order of the tree.
+            unused.reverse();
+            while level.len() > ROOT_ENTRIES {
+                if depth >= EXTENT_MAX_DEPTH {
+                    return Err(VfsError::InvalidInput);
+                }
+                let mut parents = Vec::new();
+                for children in level.chunks(max) {
+                    let block = match unused.pop() {
+                        Some(block) => block,
+                        None => {
+                            nodes += 1;
+                            alloc(children[0].1)?
+                        }
+                    };
+                    node.fill(0);
+                    index_node(&mut node, max, depth, children);
+                    write(block, &node);
+                    self.index.push(block);
+                    parents.push((children[0].0, block));
+                }
+                level = parents;
+                depth += 1;
+            }
+            index_node(&mut root, ROOT_ENTRIES, depth, &level);
+        }
+        for block in unused.into_iter().chain(self.dropped.drain(..)) {
+            free(block)?;
+            nodes -= 1;
+        }
+        Ok((root, nodes))
+    }
+}
@@ -0,0 +1,140 @@
+// The extent trees of the files of the ext4_rs shim: it loads the tree of
+// an inode, maps new blocks into it near the blocks before them and
+// stores it back through the running transaction.
+
+use alloc::vec::Vec;
+use vfscore::{VfsError, VfsResult};
+
+use crate::ext4_csum::{inode_seed, verify_extent_block};
+use crate::ext4_extent::ExtentTree;
+use crate::ext4_layout::{walk_extents, Extent, InodeInfo, EXT_INIT_MAX_LEN};
+use crate::ext4_rs_shim::{set_u16, set_u32, Ext4Volume, ALLOC_WINDOW, I_BLOCK, I_BLOCKS};
+
+impl Ext4Volume {
+    /// The extents of the data blocks of the file, the blocks of the
+    /// extent tree itself aren't included.
+    pub(crate) fn data_extents(&self, ino: u32) -> Vec<Extent> {
+        match self.read_inode(ino) {
+            Ok(inode) if inode.uses_extents() => {
+                self.inode_extents(ino, &inode).unwrap_or_default()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Walk the extent tree of the inode, the nodes are verified with
+    /// metadata_csum. The nodes and the extents must be within the
+    /// filesystem, block 0 of the error log is the root in the inode.
+    pub(crate) fn inode_extents(&self, ino: u32, inode: &InodeInfo) -> VfsResult<Vec<Extent>> {
+        let block_size = self.sb.block_size();
+        let seed = inode_seed(&self.sb, ino, inode.generation);
+        let mut node = 0;
+        let mut reason = "extent tree";
+        let extents = walk_extents(&inode.i_block, |block| {
+            node = block;
+            if block >= self.sb.blocks_count {
+                return Err(VfsError::InvalidData);
+            }
+            let data = self.read_block(block);
+            if self.sb.has_metadata_csum()
+                && !self.disk.is_logged(block as usize * block_size, block_size)
+                && !verify_extent_block(seed, &data[..block_size.min(data.len())])
+            {
+                reason = "extent checksum";
+                return Err(VfsError::InvalidData);
+            }
+            Ok(data)
+        })
+        .map_err(|_| self.corrupted("inode_extents", reason, ino, node))?;
+        if let Some(extent) = extents
+            .iter()
+            .find(|x| x.physical + x.len as u64 > self.sb.blocks_count)
+        {
+            return Err(self.corrupted("inode_extents", "extent", ino, extent.physical));
+        }
+        Ok(extents)
+    }
+
+    /// The extent tree of the file for a change, it's verified like the
+    /// extents of inode_extents.
+    pub(crate) fn extent_tree(&self, ino: u32, inode: &InodeInfo) -> VfsResult<ExtentTree> {
+        self.inode_extents(ino, inode)?;
+        ExtentTree::parse(&inode.i_block, self.sb.block_size(), |block| {
+            Ok(self.read_block(block))
+        })
+        .map_err(|_| self.corrupted("extent_tree", "extent tree", ino, 0))
+    }
+
+    /// Write the changed nodes of the tree of the file and its root, the
+    /// nodes allocated and freed change i_blocks and are charged.
+    pub(crate) fn store_extents(&self, ino: u32, tree: &mut ExtentTree) -> VfsResult<()> {
+        let inode = self.read_inode(ino)?;
+        let (root, nodes) = tree.store(
+            self.sb.block_size(),
+            |goal| self.alloc_blocks(goal, 1, &inode).map(|x| x.0),
+            |block, node| self.write_block(block, node),
+            |block| self.free_blocks(block, 1).map(|_| ()),
+        )?;
+        let i_blocks = inode
+            .blocks
+            .saturating_add_signed(nodes * self.block_sectors(&inode) as i64);
+        self.charge(inode.uid, inode.gid, nodes * self.sb.block_size() as i64, 0);
+        self.modify_inode(ino, |raw| {
+            raw[I_BLOCK..I_BLOCK + root.len()].copy_from_slice(&root);
+            set_u32(raw, I_BLOCKS.0, i_blocks as u32);
+            set_u16(raw, I_BLOCKS.1, (i_blocks >> 32) as u16);
+        })
+    }
+
+    /// The goal of a new block of the file at lblock: the block after
+    /// before, the extent mapped before lblock, so an append continues
+    /// it, or the start of the group of the inode.
+    fn block_goal(&self, ino: u32, before: Option<&Extent>, lblock: u32) -> u64 {
+        match before {
+            Some(x) => x.physical + (lblock - x.logical) as u64,
+            None => self.group_start(self.sb.inode_group(ino).0),
+        }
+    }
+
+    /// Map the hole of the file at lblock to a run of up to count new
+    /// blocks near the blocks before it. The run is added to the tree,
+    /// store_extents writes it. return the first block and the length of
+    /// the run.
+    pub(crate) fn map_new_blocks(
+        &self,
+        ino: u32,
+        tree: &mut ExtentTree,
+        lblock: u32,
+        count: u32,
+    ) -> VfsResult<(u64, u32)> {
+        // the hole ends at the next extent.
+        let next = tree.next_mapped(lblock);
+        let count = match next {
+            Some(next) => count.min(next - lblock),
+            None => count,
+        };
+        let count = count.min(EXT_INIT_MAX_LEN as u32);
+        let goal = self.block_goal(ino, tree.before(lblock).as_ref(), lblock);
+        let inode = self.read_inode(ino)?;
+        let (physical, len) = self.alloc_file_blocks(Some(ino), goal, count, &inode)?;
+        // the next append of an open file continues the run.
+        if next.is_none() && self.open.lock().wrappers.contains_key(&ino) {
+            let end = physical + len as u64;
+            self.windows.lock().set(ino, end..end + ALLOC_WINDOW);
+        }
+        tree.insert(Extent {
+            logical: lblock,
+            len,
+            physical,
+            uninit: false,
+        });
+        let i_blocks = inode.blocks + len as u64 * self.block_sectors(&inode);
+        let bytes = len as i64 * self.sb.block_size() as i64;
+        self.charge(inode.uid, inode.gid, bytes, 0);
+        self.modify_inode(ino, |raw| {
+            set_u32(raw, I_BLOCKS.0, i_blocks as u32);
+            set_u16(raw, I_BLOCKS.1, (i_blocks >> 32) as u16);
+        })?;
+        Ok((physical, len))
+    }
+}
@@ -0,0 +1,480 @@
+// The hash tree (htree) index of ext4 directories.
+// The hash functions follow fs/ext4/hash.c of Linux, a lookup descends the
+// dx_root and dx_node blocks to the leaf block which contains the name.
+// An insertion into a full leaf splits it by the hashes of its entries,
+// like do_split of fs/ext4/namei.c, and adds the new leaf to the index
+// block above it. A full index block is split in two halves added to its
+// parent, a full dx_root moves its entries to a new dx_node under it,
+// like ext4_dx_add_entry, up to DX_MAX_INDIRECT levels of dx_node. Like
+// the rest of ext4_layout, the blocks are byte slices, the shim allocates
+// and writes them in shim.rs.
+
+use alloc::vec::Vec;
+use vfscore::{VfsError, VfsResult};
+
+use crate::ext4_layout::{dirent_len, le_u16, le_u32, write_dirents, DirentIter, SuperBlockInfo};
+
+#[cfg(root_fs = "ext4_rs")]
+mod shim;
+
+pub const DX_HASH_LEGACY: u8 = 0;
+pub const DX_HASH_HALF_MD4: u8 = 1;
+pub const DX_HASH_TEA: u8 = 2;
+pub const DX_HASH_LEGACY_UNSIGNED: u8 = 3;
+pub const DX_HASH_HALF_MD4_UNSIGNED: u8 = 4;
+pub const DX_HASH_TEA_UNSIGNED: u8 = 5;
+
+/// The directory hash of the superblock is unsigned.
+const EXT2_FLAGS_UNSIGNED_HASH: u32 = 0x2;
+const EXT4_HTREE_EOF_32BIT: u32 = 0x7fffffff;
+/// The max levels of the index below dx_root.
+const DX_MAX_LEVELS: u8 = 3;
+/// The levels of dx_node an index grows to without largedir, a larger
+/// index isn't read by Linux.
+pub const DX_MAX_INDIRECT: u8 = 1;
+/// The offset of the count and the limit in dx_root, after ".", ".." and
+/// dx_root_info. The entries follow in the same 8 bytes slots.
+pub const DX_ROOT_ENTRIES: usize = 0x20;
+/// The offset of the count and the limit in dx_node, after the fake
+/// empty entry covering the block.
+pub const DX_NODE_ENTRIES: usize = 0x8;
+/// dx_tail after the entries with metadata_csum, its checksum is the
+/// second half.
+pub const DX_TAIL_SIZE: usize = 8;
+const DX_ENTRY_SIZE: usize = 8;
+
+fn str2hashbuf(msg: &[u8], buf: &mut [u32], signed: bool) {
+    let num = buf.len();
+    let mut pad = msg.len() as u32 | ((msg.len() as u32) << 8);
+    pad |= pad << 16;
+    let mut val = pad;
+    let len = msg.len().min(num * 4);
+    let mut index = 0;
+    for (i, &c) in msg[..len].iter().enumerate() {
+        let c = match signed {
+            true => c as i8 as i32 as u32,
+            false => c as u32,
+        };
+        val = c.wrapping_add(val << 8);
+        if i % 4 == 3 {
+            buf[index] = val;
+            index += 1;
+            val = pad;
+        }
+    }
+    if index < num {
+        buf[index] = val;
+        index += 1;
+    }
+    buf[index..].fill(pad);
+}
+
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8]) -> u32 {
+    let (mut a, mut b, mut c, mut d) = (buf[0], buf[1], buf[2], buf[3]);
+    let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+    let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+    macro_rules! round {
+        ($f:expr, $a:ident, $b:ident, $c:ident, $d:ident, $x:expr, $s:expr) => {
+            $a = $a
+                .wrapping_add($f($b, $c, $d))
+                .wrapping_add($x)
+                .rotate_left($s)
+        };
+    }
+    const K2: u32 = 0x5A827999;
+    const K3: u32 = 0x6ED9EBA1;
+
+    round!(f, a, b, c, d, input[0], 3);
+    round!(f, d, a, b, c, input[1], 7);
+    round!(f, c, d, a, b, input[2], 11);
+    round!(f, b, c, d, a, input[3], 19);
+    round!(f, a, b, c, d, input[4], 3);
+    round!(f, d, a, b, c, input[5], 7);
+    round!(f, c, d, a, b, input[6], 11);
+    round!(f, b, c, d, a, input[7], 19);
+
+    round!(g, a, b, c, d, input[1].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[3].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[5].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[7].wrapping_add(K2), 13);
+    round!(g, a, b, c, d, input[0].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[2].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[4].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[6].wrapping_add(K2), 13);
+
+    round!(h, a, b, c, d, input[3].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[7].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[2].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[6].wrapping_add(K3), 15);
+    round!(h, a, b, c, d, input[1].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[5].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[0].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[4].wrapping_add(K3), 15);
+
+    buf[0] = buf[0].wrapping_add(a);
+    buf[1] = buf[1].wrapping_add(b);
+    buf[2] = buf[2].wrapping_add(c);
+    buf[3] = buf[3].wrapping_add(d);
+    buf[1]
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 4]) {
+    const DELTA: u32 = 0x9E3779B9;
+    let (mut sum, mut b0, mut b1) = (0u32, buf[0], buf[1]);
+    let [a, b, c, d] = *input;
+    for _ in 0..16 {
+        sum = sum.wrapping_add(DELTA);
+        b0 = b0.wrapping_add(
+            ((b1 << 4).wrapping_add(a)) ^ (b1.wrapping_add(sum)) ^ ((b1 >> 5).wrapping_add(b)),
+        );
+        b1 = b1.wrapping_add(
+            ((b0 << 4).wrapping_add(c)) ^ (b0.wrapping_add(sum)) ^ ((b0 >> 5).wrapping_add(d)),
+        );
+    }
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+fn dx_hack_hash(name: &[u8], signed: bool) -> u32 {
+    let (mut hash0, mut hash1) = (0x12a3fe2du32, 0x37abe8f9u32);
+    for &c in name {
+        let c = match signed {
+            true => c as i8 as i32 as u32,
+            false => c as u32,
+        };
+        let mut hash = hash1.wrapping_add(hash0 ^ c.wrapping_mul(7152373));
+        if hash & 0x80000000 != 0 {
+            hash = hash.wrapping_sub(0x7fffffff);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+    hash0 << 1
+}
+
+/// Compute the major hash of the name, the lowest bit is always 0.
+pub fn dirhash(name: &[u8], version: u8, seed: &[u32; 4]) -> VfsResult<u32> {
+    let mut buf = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    if seed.iter().any(|x| *x != 0) {
+        buf = *seed;
+    }
+    let hash = match version {
+        DX_HASH_LEGACY | DX_HASH_LEGACY_UNSIGNED => dx_hack_hash(name, version == DX_HASH_LEGACY),
+        DX_HASH_HALF_MD4 | DX_HASH_HALF_MD4_UNSIGNED => {
+            let mut input = [0u32; 8];
+            let mut p = name;
+            while !p.is_empty() {
+                str2hashbuf(p, &mut input, version == DX_HASH_HALF_MD4);
+                half_md4_transform(&mut buf, &input);
+                p = &p[p.len().min(32)..];
+            }
+            buf[1]
+        }
+        DX_HASH_TEA | DX_HASH_TEA_UNSIGNED => {
+            let mut input = [0u32; 4];
+            let mut p = name;
+            while !p.is_empty() {
+                str2hashbuf(p, &mut input, version == DX_HASH_TEA);
+                tea_transform(&mut buf, &input);
+                p = &p[p.len().min(16)..];
+            }
+            buf[0]
+        }
+        _ => return Err(VfsError::NotSupported),
+    };
+    let hash = hash & !1;
+    if hash == EXT4_HTREE_EOF_32BIT << 1 {
+        return Ok((EXT4_HTREE_EOF_32BIT - 1) << 1);
+    }
+    Ok(hash)
+}
+
+/// The header of dx_root, it's after the "." and ".." entries.
+#[derive(Debug, Clone, Copy)]
+pub struct DxRootInfo {
+    pub hash_version: u8,
+    pub indirect_levels: u8,
+}
+
+impl DxRootInfo {
+    pub fn parse(block: &[u8]) -> VfsResult<Self> {
+        let info = Self {
+            hash_version: block[0x1C],
+            indirect_levels: block[0x1E],
+        };
+        if block[0x1D] != 8 || info.indirect_levels >= DX_MAX_LEVELS {
+            return Err(VfsError::InvalidData);
+        }
+        Ok(info)
+    }
+
+    /// Get the hash version with the signedness of the superblock.
+    pub fn hash_version(&self, sb: &SuperBlockInfo) -> u8 {
+        if self.hash_version <= DX_HASH_TEA && sb.flags & EXT2_FLAGS_UNSIGNED_HASH != 0 {
+            self.hash_version + 3
+        } else {
+            self.hash_version
+        }
+    }
+}
+
+/// Find the child block in the dx entries starting at offset of the block.
+/// The first entry has no hash, it covers the hashes lower than the second.
+/// return the logical block of the child and the (hash, block) of the entry
+/// after it.
+fn dx_find(block: &[u8], offset: usize, hash: u32) -> VfsResult<(u32, Option<(u32, u32)>)> {
+    let index = dx_position(block, offset, hash)?;
+    let count = le_u16(block, offset + 2) as usize;
+    let entry_hash = |i: usize| le_u32(block, offset + i * 8);
+    let entry_block = |i: usize| le_u32(block, offset + i * 8 + 4);
+    let next = (index + 1 < count).then(|| (entry_hash(index + 1), entry_block(index + 1)));
+    Ok((entry_block(index), next))
+}
+
+/// Find the index of the entry covering hash in the dx entries starting
+/// at offset of the block.
+fn dx_position(block: &[u8], offset: usize, hash: u32) -> VfsResult<usize> {
+    let limit = le_u16(block, offset) as usize;
+    let count = le_u16(block, offset + 2) as usize;
+    if count == 0 || count > limit || offset + limit * 8 > block.len() {
+        return Err(VfsError::InvalidData);
+    }
+    let entry_hash = |i: usize| le_u32(block, offset + i * 8);
+    // binary search the last entry whose hash <= hash.
+    let (mut low, mut high) = (1, count);
+    while low < high {
+        let mid = (low + high) / 2;
+        if entry_hash(mid) > hash {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+    Ok(low - 1)
+}
+
+/// Descend the hash tree to the leaf blocks which may contain name.
+/// The first leaf always exists, the next one is only needed when the
+/// hash continues in it (hash collision).
+/// read_block reads the logical block of the directory.
+pub fn dx_lookup(
+    sb: &SuperBlockInfo,
+    root: &[u8],
+    name: &[u8],
+    mut read_block: impl FnMut(u32) -> VfsResult<Vec<u8>>,
+) -> VfsResult<Vec<u32>> {
+    let info = DxRootInfo::parse(root)?;
+    let hash = dirhash(name, info.hash_version(sb), &sb.hash_seed)?;
+    // dx_root: ".", "..", dx_root_info, then the entries.
+    let (mut block, mut next) = dx_find(root, DX_ROOT_ENTRIES, hash)?;
+    // the next entry of the deepest level which has one, and that level.
+    let mut next_level = 0;
+    for level in 1..=info.indirect_levels {
+        // dx_node: a fake empty entry covering the block, then the entries.
+        let (child, child_next) = dx_find(&read_block(block)?, DX_NODE_ENTRIES, hash)?;
+        block = child;
+        if child_next.is_some() {
+            (next, next_level) = (child_next, level);
+        }
+    }
+    let mut leaves = vec![block];
+    // the lowest bit of the next hash marks the collision continues there,
+    // the next of a dx_node above is its first leaf.
+    if let Some((next_hash, mut next_block)) = next
+        && next_hash & 1 != 0
+        && next_hash & !1 == hash
+    {
+        for _ in next_level..info.indirect_levels {
+            let node = read_block(next_block)?;
+            dx_position(&node, DX_NODE_ENTRIES, 0)?;
+            next_block = le_u32(&node, DX_NODE_ENTRIES + 4);
+        }
+        leaves.push(next_block);
+    }
+    Ok(leaves)
+}
+
+/// The max entries of an index block whose count and limit are at
+/// offset, dx_tail takes the end of the block with metadata_csum.
+pub fn dx_limit(block_size: usize, offset: usize, csum: bool) -> usize {
+    let tail = if csum { DX_TAIL_SIZE } else { 0 };
+    (block_size - offset - tail) / DX_ENTRY_SIZE
+}
+
+/// The offset of the count and the limit of the index block at lblock
+/// of an indexed directory, None for a leaf. dx_root is the first block,
+/// a dx_node starts with an unused entry covering the whole block.
+pub fn dx_entries_offset(lblock: u32, block: &[u8]) -> Option<usize> {
+    match lblock {
+        0 => Some(DX_ROOT_ENTRIES),
+        _ if le_u32(block, 0) == 0 && le_u16(block, 4) as usize == block.len() => {
+            Some(DX_NODE_ENTRIES)
+        }
+        _ => None,
+    }
+}
+
+/// An index block on the way to a leaf: its logical block, the offset of
+/// its entries and the index of the entry followed.
+#[derive(Debug, Clone, Copy)]
+pub struct DxFrame {
+    pub lblock: u32,
+    pub offset: usize,
+    pub position: usize,
+}
+
+/// Descend the hash tree to the leaf block covering hash, like dx_lookup.
+/// return the index blocks from dx_root down and the leaf.
+pub fn dx_path(
+    root: &[u8],
+    hash: u32,
+    mut read_block: impl FnMut(u32) -> VfsResult<Vec<u8>>,
+) -> VfsResult<(Vec<DxFrame>, u32)> {
+    let info = DxRootInfo::parse(root)?;
+    let mut frame = DxFrame {
+        lblock: 0,
+        offset: DX_ROOT_ENTRIES,
+        position: dx_position(root, DX_ROOT_ENTRIES, hash)?,
+    };
+    let mut block = le_u32(root, DX_ROOT_ENTRIES + frame.position * 8 + 4);
+    let mut path = vec![frame];
+    for _ in 0..info.indirect_levels {
+        let node = read_block(block)?;
+        frame = DxFrame {
+            lblock: block,
+            offset: DX_NODE_ENTRIES,
+            position: dx_position(&node, DX_NODE_ENTRIES, hash)?,
+        };
+        block = le_u32(&node, DX_NODE_ENTRIES + frame.position * 8 + 4);
+        path.push(frame);
+    }
+    Ok((path, block))
+}
+
+/// Insert the entry (hash, lblock) after the entry at position of the
+/// index block. return false if the block is full.
+pub fn dx_insert(block: &mut [u8], offset: usize, position: usize, hash: u32, lblock: u32) -> bool {
+    let limit = le_u16(block, offset) as usize;
+    let count = le_u16(block, offset + 2) as usize;
+    if count >= limit {
+        return false;
+    }
+    let at = offset + (position + 1) * DX_ENTRY_SIZE;
+    block.copy_within(at..offset + count * DX_ENTRY_SIZE, at + DX_ENTRY_SIZE);
+    block[at..at + 4].copy_from_slice(&hash.to_le_bytes());
+    block[at + 4..at + 8].copy_from_slice(&lblock.to_le_bytes());
+    block[offset + 2..offset + 4].copy_from_slice(&(count as u16 + 1).to_le_bytes());
+    true
+}
+
+/// Whether the index block is full, its count and limit are at offset.
+pub fn dx_is_full(block: &[u8], offset: usize) -> bool {
+    le_u16(block, offset + 2) >= le_u16(block, offset)
+}
+
+/// Build an empty dx_node of the limit from dx_limit, its fake entry
+/// covers the block.
+pub fn init_dx_node(block: &mut [u8], limit: usize) {
+    let len = block.len();
+    block.fill(0);
+    block[4..6].copy_from_slice(&(len as u16).to_le_bytes());
+    block[DX_NODE_ENTRIES..DX_NODE_ENTRIES + 2].copy_from_slice(&(limit as u16).to_le_bytes());
+}
+
+/// Move the entries of the index block at offset to the empty dx_node
+/// new, like the new level of ext4_dx_add_entry. The block keeps one
+/// entry covering all the hashes in new at lblock.
+pub fn move_dx_entries(block: &mut [u8], offset: usize, new: &mut [u8], lblock: u32) {
+    let count = le_u16(block, offset + 2) as usize;
+    let len = count * DX_ENTRY_SIZE;
+    // the first entry has the count and limit in place of its hash.
+    new[DX_NODE_ENTRIES + 4..DX_NODE_ENTRIES + len]
+        .copy_from_slice(&block[offset + 4..offset + len]);
+    new[DX_NODE_ENTRIES + 2..DX_NODE_ENTRIES + 4].copy_from_slice(&(count as u16).to_le_bytes());
+    block[offset + 2..offset + 4].copy_from_slice(&1u16.to_le_bytes());
+    block[offset + 4..offset + 8].copy_from_slice(&lblock.to_le_bytes());
+    block[offset + 8..offset + len].fill(0);
+}
+
+/// Split the full dx_node old into the empty dx_node new, the upper half
+/// of the entries moves. return the hash of the first moved entry, for
+/// the entry of new in the parent, and the entries kept in old.
+pub fn split_dx_node(old: &mut [u8], new: &mut [u8]) -> (u32, usize) {
+    let count = le_u16(old, DX_NODE_ENTRIES + 2) as usize;
+    let kept = count / 2;
+    let at = DX_NODE_ENTRIES + kept * DX_ENTRY_SIZE;
+    let hash = le_u32(old, at);
+    let moved = (count - kept) * DX_ENTRY_SIZE;
+    new[DX_NODE_ENTRIES + 4..DX_NODE_ENTRIES + moved].copy_from_slice(&old[at + 4..at + moved]);
+    new[DX_NODE_ENTRIES + 2..DX_NODE_ENTRIES + 4]
+        .copy_from_slice(&((count - kept) as u16).to_le_bytes());
+    old[at..at + moved].fill(0);
+    old[DX_NODE_ENTRIES + 2..DX_NODE_ENTRIES + 4].copy_from_slice(&(kept as u16).to_le_bytes());
+    (hash, kept)
+}
+
+/// Build the dx_root of the directory ino in its parent, with one entry
+/// covering all the hashes in the leaf at lblock. hash_version is the
+/// default of the superblock, limit is from dx_limit.
+pub fn init_dx_root(
+    block: &mut [u8],
+    ino: u32,
+    parent: u32,
+    hash_version: u8,
+    limit: usize,
+    lblock: u32,
+) {
+    const FT_DIR: u8 = 2;
+    let len = block.len();
+    block.fill(0);
+    write_dirents(&mut block[..12], 12, &[(ino, FT_DIR, b".")]);
+    write_dirents(&mut block[12..], len - 12, &[(parent, FT_DIR, b"..")]);
+    // dx_root_info: reserved_zero, hash_version, info_length, indirect_levels.
+    block[0x1C] = hash_version;
+    block[0x1D] = 8;
+    let entries = &mut block[DX_ROOT_ENTRIES..];
+    entries[..2].copy_from_slice(&(limit as u16).to_le_bytes());
+    entries[2..4].copy_from_slice(&1u16.to_le_bytes());
+    entries[4..8].copy_from_slice(&lblock.to_le_bytes());
+}
+
+/// Split the full leaf block old into the empty new by the hashes of its
+/// entries, the entries of the blocks end at old_end and new_end. The
+/// lower half stays in old, the entries from the split hash move to new.
+/// return the hash of the new leaf for its index entry, with the lowest
+/// bit set if the entries of the split hash continue from old (a
+/// collision).
+pub fn split_leaf(
+    (old, old_end): (&mut [u8], usize),
+    (new, new_end): (&mut [u8], usize),
+    hash: impl Fn(&[u8]) -> VfsResult<u32>,
+) -> VfsResult<u32> {
+    let mut entries = Vec::new();
+    for dirent in DirentIter::new(&old[..old_end]) {
+        let dirent = dirent?;
+        let name = dirent.name.to_vec();
+        entries.push((hash(&name)?, dirent.inode, dirent.file_type, name));
+    }
+    if entries.len() < 2 {
+        return Err(VfsError::InvalidData);
+    }
+    entries.sort_by_key(|x| x.0);
+    // move the entries beyond half of the used space.
+    let total: usize = entries.iter().map(|x| dirent_len(x.3.len())).sum();
+    let mut used = 0;
+    let mut split = entries.len() - 1;
+    for (i, entry) in entries.iter().enumerate().skip(1) {
+        used += dirent_len(entries[i - 1].3.len());
+        if used + dirent_len(entry.3.len()) > total / 2 {
+            split = i;
+            break;
+        }
+    }
+    let split_hash = entries[split].0;
+    let continued = (entries[split - 1].0 == split_hash) as u32;
+    let packed: Vec<(u32, u8, &[u8])> = entries.iter().map(|x| (x.1, x.2, &x.3[..])).collect();
+    write_dirents(old, old_end, &packed[..split]);
+    write_dirents(new, new_end, &packed[split..]);
+    Ok(split_hash | continued)
+}
@@ -0,0 +1,212 @@
+// The htree index of the directories of the ext4_rs shim: a linear
+// directory is indexed when it outgrows its first block, and an entry
+// added to a full leaf splits it and the index blocks above it. Every
+// function here writes through the running transaction.
+
+use alloc::vec::Vec;
+use vfscore::{VfsError, VfsResult};
+
+use crate::ext4_htree::{
+    dirhash, dx_insert, dx_is_full, dx_limit, dx_lookup, dx_path, init_dx_node, init_dx_root,
+    move_dx_entries, split_dx_node, split_leaf, DxFrame, DxRootInfo, DX_MAX_INDIRECT,
+    DX_NODE_ENTRIES, DX_ROOT_ENTRIES,
+};
+use crate::ext4_layout::{insert_dirent, Extent, InodeInfo, EXT4_INDEX_FL};
+use crate::ext4_rs_shim::{entries_end, set_u32, Ext4Volume, I_FLAGS};
+
+impl Ext4Volume {
+    /// Index the full directory of one block, like make_indexed_dir of
+    /// Linux: its entries move to a new leaf and the first block becomes
+    /// dx_root over it. return false if the first block doesn't start
+    /// with "." and "..", the directory stays linear then.
+    pub(crate) fn make_indexed(
+        &self,
+        ino: u32,
+        dir: &InodeInfo,
+        extents: &[Extent],
+    ) -> VfsResult<bool> {
+        let (block, data) = self.dir_block(ino, dir, extents, 0)?;
+        let entries = self.parse_dir_block(ino, block, &data)?;
+        if entries.len() < 2
+            || entries[0].offset != 0
+            || entries[0].name != b"."
+            || entries[1].name != b".."
+        {
+            return Ok(false);
+        }
+        let moved: Vec<(u32, u8, &[u8])> = entries[2..]
+            .iter()
+            .map(|x| (x.inode, x.file_type, x.name))
+            .collect();
+        let (lblock, leaf) = self.append_dir_block(ino)?;
+        self.write_dir_block(ino, leaf, &self.new_dir_block(&moved));
+        let block_size = self.sb.block_size();
+        let limit = dx_limit(block_size, DX_ROOT_ENTRIES, self.sb.has_metadata_csum());
+        let mut root = vec![0; block_size];
+        init_dx_root(
+            &mut root,
+            ino,
+            entries[1].inode,
+            self.sb.def_hash_version,
+            limit,
+            lblock,
+        );
+        self.write_dir_block(ino, block, &root);
+        let flags = dir.flags | EXT4_INDEX_FL;
+        self.modify_inode(ino, |raw| set_u32(raw, I_FLAGS, flags))?;
+        Ok(true)
+    }
+
+    /// Add the entry to the indexed directory, into the leaf covering the
+    /// hash of its name. A full leaf is split, the new leaf is added to
+    /// the index block above it, see split_dx_path for a full one.
+    pub(crate) fn add_dx_entry(
+        &self,
+        ino: u32,
+        dir: &InodeInfo,
+        extents: &[Extent],
+        entry: (u32, u8, &[u8]),
+    ) -> VfsResult<()> {
+        let bad_index = |_| self.corrupted("add_dx_entry", "directory index", ino, 0);
+        let read = |lblock: u32| -> VfsResult<Vec<u8>> {
+            Ok(self.dir_block(ino, dir, extents, lblock)?.1)
+        };
+        let root = read(0)?;
+        let info = DxRootInfo::parse(&root).map_err(bad_index)?;
+        let version = info.hash_version(&self.sb);
+        let hash_of = |name: &[u8]| dirhash(name, version, &self.sb.hash_seed);
+        let hash = hash_of(entry.2)?;
+        // the leaves of the hash, with those its collisions continue in.
+        let leaves = dx_lookup(&self.sb, &root, entry.2, read).map_err(|err| match err {
+            VfsError::InvalidData => self.corrupted("add_dx_entry", "directory index", ino, 0),
+            err => err,
+        })?;
+        for lblock in leaves {
+            if self.block_has_name(ino, dir, extents, lblock, entry.2)? {
+                return Err(VfsError::AlreadyExists);
+            }
+        }
+        let (mut path, leaf) = dx_path(&root, hash, read).map_err(bad_index)?;
+        let (block, mut data) = self.dir_block(ino, dir, extents, leaf)?;
+        let end = entries_end(&data);
+        let bad_leaf = |_| self.corrupted("add_dx_entry", "directory entry", ino, block);
+        if insert_dirent(&mut data, end, entry).map_err(bad_leaf)? {
+            self.write_dir_block(ino, block, &data);
+            return Ok(());
+        }
+
+        let mut nodes = path
+            .iter()
+            .map(|x| self.dir_block(ino, dir, extents, x.lblock))
+            .collect::<VfsResult<Vec<_>>>()?;
+        self.split_dx_path(ino, &mut path, &mut nodes)?;
+        let frame = path[path.len() - 1];
+        let (new_lblock, new_block) = self.append_dir_block(ino)?;
+        let mut new = self.new_dir_block(&[]);
+        let new_end = entries_end(&new);
+        let split =
+            split_leaf((&mut data[..], end), (&mut new[..], new_end), hash_of).map_err(|err| {
+                match err {
+                    VfsError::InvalidData => {
+                        self.corrupted("add_dx_entry", "directory entry", ino, block)
+                    }
+                    err => err,
+                }
+            })?;
+        let index = &mut nodes[path.len() - 1].1;
+        dx_insert(index, frame.offset, frame.position, split, new_lblock);
+        // the hashes from split are looked up in the new leaf.
+        let (target, target_end) = match hash >= split {
+            true => (&mut new, new_end),
+            false => (&mut data, end),
+        };
+        if !insert_dirent(target, target_end, entry).map_err(bad_leaf)? {
+            return Err(VfsError::StorageFull);
+        }
+        self.write_dir_block(ino, block, &data);
+        self.write_dir_block(ino, new_block, &new);
+        for (block, node) in nodes.iter() {
+            self.write_dir_block(ino, *block, node);
+        }
+        Ok(())
+    }
+
+    /// Make room for an entry in the last index block of the path, the
+    /// index blocks from dx_root down with their (block, data). The full
+    /// blocks from the lowest one with room down are split like Linux,
+    /// the upper half of their entries moves to a new dx_node added to
+    /// the parent, and the frames follow the hash to the half covering
+    /// it. A full dx_root gets a new level of dx_node under it, up to
+    /// DX_MAX_INDIRECT levels, beyond them the entry fails with
+    /// StorageFull. The new blocks join nodes, the caller writes them.
+    fn split_dx_path(
+        &self,
+        ino: u32,
+        path: &mut Vec<DxFrame>,
+        nodes: &mut Vec<(u64, Vec<u8>)>,
+    ) -> VfsResult<()> {
+        let full = |path: &[DxFrame], nodes: &[(u64, Vec<u8>)], level: usize| {
+            dx_is_full(&nodes[level].1, path[level].offset)
+        };
+        if !full(path, nodes, path.len() - 1) {
+            return Ok(());
+        }
+        let block_size = self.sb.block_size();
+        let limit = dx_limit(block_size, DX_NODE_ENTRIES, self.sb.has_metadata_csum());
+        if (0..path.len()).all(|x| full(path, nodes, x)) {
+            let levels = path.len() as u8 - 1;
+            if levels >= DX_MAX_INDIRECT {
+                log::warn!("ext4 directory {}: the htree index is full", ino);
+                return Err(VfsError::StorageFull);
+            }
+            // the entries of dx_root move to a new dx_node under it.
+            let (lblock, block) = self.append_dir_block(ino)?;
+            let mut node = vec![0; block_size];
+            init_dx_node(&mut node, limit);
+            let root = &mut nodes[0].1;
+            move_dx_entries(root, DX_ROOT_ENTRIES, &mut node, lblock);
+            root[0x1E] = levels + 1;
+            let position = core::mem::replace(&mut path[0].position, 0);
+            path.insert(
+                1,
+                DxFrame {
+                    lblock,
+                    offset: DX_NODE_ENTRIES,
+                    position,
+                },
+            );
+            nodes.insert(1, (block, node));
+        }
+        let first = (0..path.len())
+            .rev()
+            .find(|x| !full(path, nodes, *x))
+            .unwrap();
+        for level in first + 1..path.len() {
+            if !full(path, nodes, level) {
+                continue;
+            }
+            let (lblock, block) = self.append_dir_block(ino)?;
+            let mut node = vec![0; block_size];
+            init_dx_node(&mut node, limit);
+            let (hash, kept) = split_dx_node(&mut nodes[level].1, &mut node);
+            let parent = path[level - 1];
+            dx_insert(
+                &mut nodes[level - 1].1,
+                parent.offset,
+                parent.position,
+                hash,
+                lblock,
+            );
+            // the frame follows the half covering its entry.
+            let mut new = (block, node);
+            if path[level].position >= kept {
+                path[level - 1].position += 1;
+                path[level].lblock = lblock;
+                path[level].position -= kept;
+                core::mem::swap(&mut nodes[level], &mut new);
+            }
+            nodes.push(new);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,423 @@
+// The JBD2 journal of ext4, the recovery and the logging of transactions.
+// The journal structures are big-endian. Like ext4_layout, the parsers only
+// work on byte slices, the caller reads and writes the journal blocks by
+// the logical block number in the journal inode. The structures are the
+// ones of disk_layout. The recovery and the commits of a mounted volume
+// are in shim.rs.
+
+use core::mem::{offset_of, size_of};
+
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use vfscore::{VfsError, VfsResult};
+
+use crate::crc32c::crc32c;
+use crate::disk_layout::{
+    self as disk, CommitHeader, JournalBlockTag, JournalBlockTag3, JournalBlockTail, JournalHeader,
+    Layout,
+};
+
+#[cfg(root_fs = "ext4_rs")]
+mod shim;
+
+pub const JBD2_MAGIC: u32 = 0xC03B3998;
+
+const BLOCKTYPE_DESCRIPTOR: u32 = 1;
+const BLOCKTYPE_COMMIT: u32 = 2;
+const BLOCKTYPE_SUPERBLOCK_V1: u32 = 3;
+const BLOCKTYPE_SUPERBLOCK_V2: u32 = 4;
+const BLOCKTYPE_REVOKE: u32 = 5;
+
+const INCOMPAT_REVOKE: u32 = 0x1;
+const INCOMPAT_64BIT: u32 = 0x2;
+const INCOMPAT_ASYNC_COMMIT: u32 = 0x4;
+const INCOMPAT_CSUM_V2: u32 = 0x8;
+const INCOMPAT_CSUM_V3: u32 = 0x10;
+/// The incompat features the recovery knows.
+const INCOMPAT_KNOWN: u32 =
+    INCOMPAT_REVOKE | INCOMPAT_64BIT | INCOMPAT_ASYNC_COMMIT | INCOMPAT_CSUM_V2 | INCOMPAT_CSUM_V3;
+
+/// The data block starts with JBD2_MAGIC, it's zeroed in the journal.
+const FLAG_ESCAPE: u32 = 0x1;
+/// The tag isn't followed by the 16 bytes uuid.
+const FLAG_SAME_UUID: u32 = 0x2;
+const FLAG_LAST_TAG: u32 = 0x8;
+
+/// The header of every journal metadata block, JournalHeader.
+const HEADER_SIZE: usize = size_of::<JournalHeader>();
+
+pub fn be_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+/// The fields of the journal superblock used by the recovery.
+#[derive(Debug, Clone)]
+pub struct JournalSuperBlock {
+    pub blocksize: u32,
+    pub maxlen: u32,
+    /// The first block of the log area.
+    pub first: u32,
+    /// The sequence of the first transaction expected in the log.
+    pub sequence: u32,
+    /// The block of the first transaction, 0 means the journal is empty.
+    pub start: u32,
+    pub feature_incompat: u32,
+    pub uuid: [u8; 16],
+}
+
+impl JournalSuperBlock {
+    pub fn parse(block: &[u8]) -> VfsResult<Self> {
+        let raw = disk::JournalSuperBlock::ref_from(block).ok_or(VfsError::InvalidData)?;
+        if raw.header.magic.get() != JBD2_MAGIC {
+            return Err(VfsError::InvalidData);
+        }
+        let feature_incompat = match raw.header.blocktype.get() {
+            BLOCKTYPE_SUPERBLOCK_V1 => 0,
+            BLOCKTYPE_SUPERBLOCK_V2 => raw.feature_incompat.get(),
+            _ => return Err(VfsError::InvalidData),
+        };
+        if feature_incompat & !INCOMPAT_KNOWN != 0 {
+            return Err(VfsError::NotSupported);
+        }
+        let jsb = Self {
+            blocksize: raw.blocksize.get(),
+            maxlen: raw.maxlen.get(),
+            first: raw.first.get(),
+            sequence: raw.sequence.get(),
+            start: raw.start.get(),
+            feature_incompat,
+            uuid: raw.uuid,
+        };
+        if jsb.first == 0 || jsb.first >= jsb.maxlen {
+            return Err(VfsError::InvalidData);
+        }
+        Ok(jsb)
+    }
+
+    fn has_incompat(&self, feature: u32) -> bool {
+        self.feature_incompat & feature != 0
+    }
+
+    fn has_csum(&self) -> bool {
+        self.has_incompat(INCOMPAT_CSUM_V2 | INCOMPAT_CSUM_V3)
+    }
+
+    /// The size of a block tag in the descriptor block: JournalBlockTag3,
+    /// or JournalBlockTag with the high half of the block with 64bit, the
+    /// 2 bytes of csum_v2 are counted but left unused like Linux.
+    fn tag_bytes(&self) -> usize {
+        if self.has_incompat(INCOMPAT_CSUM_V3) {
+            return size_of::<JournalBlockTag3>();
+        }
+        let size = size_of::<JournalBlockTag>()
+            + match self.has_incompat(INCOMPAT_CSUM_V2) {
+                true => 2,
+                false => 0,
+            };
+        match self.has_incompat(INCOMPAT_64BIT) {
+            true => size + 4,
+            false => size,
+        }
+    }
+
+    /// The home block and the flags of the tag at the start of tag.
+    fn read_tag(&self, tag: &[u8]) -> (u64, u32) {
+        if self.has_incompat(INCOMPAT_CSUM_V3) {
+            let tag = JournalBlockTag3::ref_from(tag).expect("the tag is in the block");
+            let home = match self.has_incompat(INCOMPAT_64BIT) {
+                true => disk::lo_hi(tag.blocknr.get(), tag.blocknr_high.get()),
+                false => tag.blocknr.get() as u64,
+            };
+            return (home, tag.flags.get());
+        }
+        let raw = JournalBlockTag::ref_from(tag).expect("the tag is in the block");
+        let mut home = raw.blocknr.get() as u64;
+        if self.has_incompat(INCOMPAT_64BIT) {
+            home |= (be_u32(tag, size_of::<JournalBlockTag>()) as u64) << 32;
+        }
+        (home, raw.flags.get() as u32)
+    }
+
+    /// The next block of the log, the log wraps to first at the end.
+    fn next(&self, lblock: u32) -> u32 {
+        match lblock + 1 {
+            x if x >= self.maxlen => self.first,
+            x => x,
+        }
+    }
+
+    /// The seed of the checksums of the journal blocks.
+    fn csum_seed(&self) -> u32 {
+        crc32c(!0, &self.uuid)
+    }
+
+    /// Point the journal superblock at the transaction sequence logged
+    /// from start, the recovery replays it after a crash.
+    pub fn set_start(&self, block: &mut [u8], start: u32, sequence: u32) {
+        let raw = disk::JournalSuperBlock::mut_from(block).expect("the block holds the superblock");
+        raw.sequence.set(sequence);
+        raw.start.set(start);
+        if self.has_csum() {
+            raw.checksum.set(0);
+            let csum = crc32c(!0, raw.as_bytes());
+            raw.checksum.set(csum);
+        }
+    }
+}
+
+/// A block to write back to its home location.
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayBlock {
+    /// The block number on the filesystem.
+    pub home: u64,
+    /// The logical block in the journal holding the data.
+    pub lblock: u32,
+    /// The first 4 bytes of the data must be restored to JBD2_MAGIC.
+    pub escaped: bool,
+}
+
+/// The result of the journal scanning.
+#[derive(Debug, Default)]
+pub struct Replay {
+    /// The blocks of the committed transactions to replay, in order.
+    pub blocks: Vec<ReplayBlock>,
+    pub transactions: u32,
+    /// The sequence of the next transaction.
+    pub next_sequence: u32,
+}
+
+/// Scan the log from the start of the journal, collect the blocks of the
+/// committed transactions except the revoked ones.
+/// An uncommitted transaction at the end of the log is discarded.
+pub fn scan_journal(
+    jsb: &JournalSuperBlock,
+    mut read_block: impl FnMut(u32) -> VfsResult<Vec<u8>>,
+) -> VfsResult<Replay> {
+    let mut sequence = jsb.sequence;
+    if jsb.start == 0 {
+        return Ok(Replay {
+            next_sequence: sequence,
+            ..Default::default()
+        });
+    }
+    let block_size = jsb.blocksize as usize;
+    let tail = match jsb.has_csum() {
+        true => size_of::<JournalBlockTail>(),
+        false => 0,
+    };
+    // the blocks and the revoked blocks of the committed transactions.
+    let mut blocks: Vec<(u32, ReplayBlock)> = Vec::new();
+    let mut revoked: BTreeMap<u64, u32> = BTreeMap::new();
+    // the blocks of the current transaction.
+    let mut pending: Vec<ReplayBlock> = Vec::new();
+    let mut pending_revoked: Vec<u64> = Vec::new();
+
+    let mut lblock = jsb.start;
+    // the log can't be longer than the journal.
+    let mut remain = jsb.maxlen - jsb.first;
+    while remain > 0 {
+        let block = read_block(lblock)?;
+        let Some(header) = JournalHeader::ref_from(&block) else {
+            break;
+        };
+        if block.len() < block_size
+            || header.magic.get() != JBD2_MAGIC
+            || header.sequence.get() != sequence
+        {
+            break;
+        }
+        remain -= 1;
+        match header.blocktype.get() {
+            BLOCKTYPE_DESCRIPTOR => {
+                let tag_bytes = jsb.tag_bytes();
+                let mut offset = HEADER_SIZE;
+                while offset + tag_bytes <= block_size - tail && remain > 0 {
+                    let (home, flags) = jsb.read_tag(&block[offset..]);
+                    lblock = jsb.next(lblock);
+                    remain -= 1;
+                    pending.push(ReplayBlock {
+                        home,
+                        lblock,
+                        escaped: flags & FLAG_ESCAPE != 0,
+                    });
+                    offset += tag_bytes;
+                    if flags & FLAG_SAME_UUID == 0 {
+                        offset += 16;
+                    }
+                    if flags & FLAG_LAST_TAG != 0 {
+                        break;
+                    }
+                }
+            }
+            BLOCKTYPE_REVOKE => {
+                let record = match jsb.has_incompat(INCOMPAT_64BIT) {
+                    true => 8,
+                    false => 4,
+                };
+                // r_count is the bytes used, including the header.
+                let count = (be_u32(&block, HEADER_SIZE) as usize).min(block_size - tail);
+                let mut offset = HEADER_SIZE + 4;
+                while offset + record <= count {
+                    pending_revoked.push(match record {
+                        8 => {
+                            (be_u32(&block, offset) as u64) << 32
+                                | be_u32(&block, offset + 4) as u64
+                        }
+                        _ => be_u32(&block, offset) as u64,
+                    });
+                    offset += record;
+                }
+            }
+            BLOCKTYPE_COMMIT => {
+                blocks.extend(pending.drain(..).map(|x| (sequence, x)));
+                for block in pending_revoked.drain(..) {
+                    revoked.insert(block, sequence);
+                }
+                sequence = sequence.wrapping_add(1);
+            }
+            _ => break,
+        }
+        lblock = jsb.next(lblock);
+    }
+
+    // a block is skipped if it's revoked by its transaction or a later one.
+    let blocks = blocks
+        .into_iter()
+        .filter(|(seq, x)| match revoked.get(&x.home) {
+            Some(revoke) => revoke < seq,
+            None => true,
+        })
+        .map(|(_, x)| x)
+        .collect();
+    Ok(Replay {
+        blocks,
+        transactions: sequence.wrapping_sub(jsb.sequence),
+        next_sequence: sequence,
+    })
+}
+
+/// Build the log of the transaction sequence, blocks are the new contents
+/// of the home blocks. The log is written from the first block of the
+/// journal: the descriptor blocks with the blocks they tag, then the
+/// commit block. return the log blocks in order.
+/// Fail with StorageFull if the log doesn't fit in the journal.
+pub fn build_log(
+    jsb: &JournalSuperBlock,
+    sequence: u32,
+    blocks: &[(u64, Vec<u8>)],
+) -> VfsResult<Vec<Vec<u8>>> {
+    let block_size = jsb.blocksize as usize;
+    let tag_bytes = jsb.tag_bytes();
+    let tail = match jsb.has_csum() {
+        true => size_of::<JournalBlockTail>(),
+        false => 0,
+    };
+    let seed = jsb.csum_seed();
+    let header = |blocktype: u32| {
+        let mut block = vec![0u8; block_size];
+        let header = JournalHeader::mut_from(&mut block).expect("the block holds the header");
+        header.magic.set(JBD2_MAGIC);
+        header.blocktype.set(blocktype);
+        header.sequence.set(sequence);
+        block
+    };
+    // the checksum of a whole block, the checksum field must be zero.
+    let seal = |block: &mut [u8], offset: usize| {
+        let csum = crc32c(seed, block);
+        block[offset..offset + 4].copy_from_slice(&csum.to_be_bytes());
+    };
+
+    let mut log = Vec::new();
+    let mut rest = blocks;
+    while !rest.is_empty() {
+        let mut descriptor = header(BLOCKTYPE_DESCRIPTOR);
+        let mut offset = HEADER_SIZE;
+        let mut data = Vec::new();
+        let mut last_tag = 0;
+        for (home, block) in rest.iter() {
+            // the first tag of a descriptor is followed by the uuid.
+            let size = match data.is_empty() {
+                true => tag_bytes + 16,
+                false => tag_bytes,
+            };
+            if offset + size > block_size - tail {
+                break;
+            }
+            if *home > u32::MAX as u64 && !jsb.has_incompat(INCOMPAT_64BIT) {
+                return Err(VfsError::InvalidInput);
+            }
+            let mut copy = block[..block_size].to_vec();
+            let mut flags = match data.is_empty() {
+                true => 0,
+                false => FLAG_SAME_UUID,
+            };
+            if be_u32(&copy, 0x0) == JBD2_MAGIC {
+                copy[..4].fill(0);
+                flags |= FLAG_ESCAPE;
+            }
+            // the tag checksum covers the sequence and the logged copy.
+            let csum = crc32c(crc32c(seed, &sequence.to_be_bytes()), &copy);
+            let tag = &mut descriptor[offset..offset + tag_bytes];
+            if jsb.has_incompat(INCOMPAT_CSUM_V3) {
+                let tag = JournalBlockTag3::mut_from(tag).expect("the tag is tag_bytes");
+                tag.blocknr.set(*home as u32);
+                tag.flags.set(flags);
+                tag.blocknr_high.set((*home >> 32) as u32);
+                tag.checksum.set(csum);
+            } else {
+                let (tag, high) = tag.split_at_mut(size_of::<JournalBlockTag>());
+                let tag = JournalBlockTag::mut_from(tag).expect("the tag is tag_bytes");
+                tag.blocknr.set(*home as u32);
+                if jsb.has_incompat(INCOMPAT_CSUM_V2) {
+                    tag.checksum.set(csum as u16);
+                }
+                tag.flags.set(flags as u16);
+                if jsb.has_incompat(INCOMPAT_64BIT) {
+                    high[..4].copy_from_slice(&((*home >> 32) as u32).to_be_bytes());
+                }
+            }
+            if flags & FLAG_SAME_UUID == 0 {
+                descriptor[offset + tag_bytes..offset + size].copy_from_slice(&jsb.uuid);
+            }
+            last_tag = offset;
+            offset += size;
+            data.push(copy);
+        }
+        if data.is_empty() {
+            return Err(VfsError::InvalidInput);
+        }
+        // mark the last tag of the descriptor.
+        let tag = &mut descriptor[last_tag..];
+        match jsb.has_incompat(INCOMPAT_CSUM_V3) {
+            true => {
+                let tag = JournalBlockTag3::mut_from(tag).expect("the tag is in the block");
+                tag.flags.set(tag.flags.get() | FLAG_LAST_TAG);
+            }
+            false => {
+                let tag = JournalBlockTag::mut_from(tag).expect("the tag is in the block");
+                tag.flags.set(tag.flags.get() | FLAG_LAST_TAG as u16);
+            }
+        }
+        if jsb.has_csum() {
+            seal(&mut descriptor, block_size - size_of::<JournalBlockTail>());
+        }
+        rest = &rest[data.len()..];
+        log.push(descriptor);
+        log.extend(data);
+    }
+
+    // h_chksum_type and h_chksum_size stay zero with the csum v2/v3.
+    let mut commit = header(BLOCKTYPE_COMMIT);
+    if jsb.has_csum() {
+        seal(&mut commit, offset_of!(CommitHeader, checksum));
+    }
+    log.push(commit);
+    if log.len() > (jsb.maxlen - jsb.first) as usize {
+        return Err(VfsError::StorageFull);
+    }
+    Ok(log)
+}
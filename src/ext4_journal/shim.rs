@@ -0,0 +1,589 @@
+// The journal of a mounted volume of the ext4_rs shim: the recovery at
+// the mount, the replay in memory of a read-only mount or at the reads of
+// a fast one, and the commit of the transactions and of the blocks the
+// write-back policy deferred.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use vfscore::{VfsError, VfsResult};
+
+use ext4_rs::BlockDevice;
+
+use crate::blockdev;
+use crate::ext4_csum::{set_superblock_csum, verify_inode};
+use crate::ext4_journal::{build_log, scan_journal, JournalSuperBlock, JBD2_MAGIC};
+use crate::ext4_layout::{
+    le_u32, walk_extents, Extent, ExtentCache, GroupDesc, InodeInfo, SuperBlockInfo,
+    COMPAT_HAS_JOURNAL, INCOMPAT_RECOVER, SUPERBLOCK_OFFSET,
+};
+use crate::ext4_rs_shim::{
+    copy_overlap, is_time_byte, Ext4Disk, Ext4Volume, Journal, LazyReplay, MountPhase,
+    ReadOnlyReason, Transaction, TxnOwners, WriteKind, BLOCK_SIZE,
+};
+use crate::fsync::SyncPolicy;
+
+impl Ext4Disk {
+    /// Copy the replayed and the deferred blocks overlapping the read into
+    /// buf.
+    pub(crate) fn overlay_replayed(&self, offset: usize, buf: &mut [u8]) {
+        self.replay_lazily(offset, buf.len());
+        let replayed = self.replayed.lock();
+        let start = offset.saturating_sub(BLOCK_SIZE - 1);
+        for (&block_off, data) in replayed.range(start..offset + buf.len()) {
+            copy_overlap(buf, offset, data, block_off);
+        }
+        drop(replayed);
+        for (block_off, data) in self.deferred.lock().overlapping(offset, buf.len()) {
+            copy_overlap(buf, offset, data, block_off);
+        }
+    }
+
+    /// Defer the replay of the journal of a fast mount to the reads, see
+    /// replay_lazily.
+    fn defer_replay(&self, sb: &SuperBlockInfo) {
+        *self.lazy_replay.lock() = LazyReplay::Pending(sb.clone());
+    }
+
+    /// Replay the journal blocks of a fast mount overlapping the read of
+    /// len bytes at offset into replayed. The first read scans the log
+    /// into the index, then a block is read from the journal by the first
+    /// read of its home, so the blocks no read touches are never read.
+    /// A journal which can't be scanned is logged and not replayed, like
+    /// by a read-only mount.
+    fn replay_lazily(&self, offset: usize, len: usize) {
+        let mut lazy = self.lazy_replay.lock();
+        if let LazyReplay::Pending(sb) = &*lazy {
+            let index = match self.journal_index(sb) {
+                Ok(index) => {
+                    info!("ext4 journal indexed, {} blocks to replay", index.len());
+                    index
+                }
+                Err(err) => {
+                    log::warn!(
+                        "the ext4 journal can't be replayed: {:?}, the files may be stale",
+                        err
+                    );
+                    BTreeMap::new()
+                }
+            };
+            *lazy = LazyReplay::Indexed(index);
+        }
+        let LazyReplay::Indexed(index) = &mut *lazy else {
+            return;
+        };
+        let start = offset.saturating_sub(BLOCK_SIZE - 1);
+        let homes: Vec<usize> = index.range(start..offset + len).map(|x| *x.0).collect();
+        for home in homes {
+            let (at, escaped) = index.remove(&home).unwrap();
+            let mut data = vec![0; BLOCK_SIZE];
+            self.read_raw(at, &mut data);
+            if escaped {
+                data[..4].copy_from_slice(&JBD2_MAGIC.to_be_bytes());
+            }
+            self.replayed.lock().insert(home, data);
+        }
+    }
+
+    /// Scan the journal of the superblock for replay_lazily. It's read
+    /// from the device, under the replayed blocks: the journal inode and
+    /// the log are in place, like for the replay of a mount.
+    fn journal_index(&self, sb: &SuperBlockInfo) -> VfsResult<BTreeMap<usize, (usize, bool)>> {
+        let block_size = sb.block_size();
+        let read = |offset: usize, len: usize| {
+            let mut buf = vec![0; len];
+            self.read_raw(offset, &mut buf);
+            self.overlay_backup(offset, &mut buf);
+            buf
+        };
+        if sb.journal_inum == 0 {
+            return Err(VfsError::NotSupported);
+        }
+        let (group, index) = sb.inode_group(sb.journal_inum);
+        let desc = GroupDesc::parse(sb, &read(sb.group_desc_offset(group), BLOCK_SIZE));
+        desc.validate(sb).map_err(|_| VfsError::InvalidData)?;
+        let inode_size = sb.inode_size as usize;
+        let raw = read(
+            desc.inode_table as usize * block_size + index * inode_size,
+            inode_size,
+        );
+        if sb.has_metadata_csum() && !verify_inode(sb, sb.journal_inum, &raw) {
+            return Err(VfsError::InvalidData);
+        }
+        let inode = InodeInfo::parse(&raw, inode_size);
+        if !inode.uses_extents() {
+            return Err(VfsError::NotSupported);
+        }
+        let mut extents = ExtentCache::new();
+        extents.fill(walk_extents(&inode.i_block, |block| {
+            Ok(read(block as usize * block_size, block_size))
+        })?);
+        let sb_block = extents.lookup(0).ok_or(VfsError::InvalidData)?.physical;
+        let jsb_block = read(sb_block as usize * block_size, block_size);
+        let journal = Journal {
+            jsb: JournalSuperBlock::parse(&jsb_block)?,
+            jsb_block,
+            extents,
+        };
+        if journal.jsb.blocksize as usize != block_size {
+            return Err(VfsError::InvalidData);
+        }
+        let read_block = |lblock: u32| -> VfsResult<Vec<u8>> {
+            Ok(read(
+                journal.physical(lblock)? as usize * block_size,
+                block_size,
+            ))
+        };
+        let replay = scan_journal(&journal.jsb, read_block)?;
+        let mut index = BTreeMap::new();
+        for block in replay.blocks {
+            let at = journal.physical(block.lblock)? as usize * block_size;
+            index.insert(block.home as usize * block_size, (at, block.escaped));
+        }
+        Ok(index)
+    }
+
+    /// Keep the block replayed by a read-only mount in memory, over the
+    /// device.
+    fn replay_block(&self, offset: usize, data: &[u8]) {
+        let mut groups = self.groups.lock();
+        self.replayed.lock().insert(offset, data.to_vec());
+        groups.patch(offset, data);
+    }
+}
+
+impl Ext4Volume {
+    /// Replay the journal of the superblock of a read-only volume in
+    /// memory, or defer it to the reads for a fast mount. A journal which
+    /// can't be replayed is logged, only a cancelled mount fails.
+    pub(crate) fn replay_in_memory(&self, sb: &SuperBlockInfo) -> VfsResult<()> {
+        if self.options.fast {
+            self.disk.defer_replay(sb);
+            info!("ext4 journal replay deferred to the reads");
+            return Ok(());
+        }
+        match self.replay_journal(true) {
+            Ok(transactions) => {
+                info!(
+                    "ext4 journal replayed in memory, {} transactions",
+                    transactions
+                );
+            }
+            Err(err) if self.disk.mount_cancelled() => return Err(err),
+            Err(err) => log::warn!(
+                "the ext4 journal can't be replayed: {:?}, the files may be stale",
+                err
+            ),
+        }
+        Ok(())
+    }
+
+    /// Replay the journal if the filesystem wasn't unmounted cleanly, it
+    /// must be done before ext4_rs reads any metadata.
+    /// The volume becomes read-only if the superblock is corrupted or
+    /// marked with errors, or if the journal can't be replayed. A
+    /// read-only volume is never modified, its journal is replayed in
+    /// memory. The journal of a writable volume is kept to commit the
+    /// transactions.
+    /// Only a cancelled mount fails.
+    pub(crate) fn recover(&mut self) -> VfsResult<()> {
+        self.disk.mount_step(MountPhase::Journal)?;
+        if let Some(reason) = self.read_only_reason() {
+            self.set_read_only(reason);
+        }
+        if self.is_read_only() {
+            if self.sb.needs_recovery() {
+                self.replay_in_memory(&self.sb)?;
+            }
+            return Ok(());
+        }
+        if self.sb.needs_recovery() {
+            match self.replay_journal(false) {
+                Ok(transactions) => {
+                    info!("ext4 journal replayed, {} transactions", transactions);
+                }
+                Err(err) if self.disk.mount_cancelled() => return Err(err),
+                Err(err) => {
+                    self.set_read_only(ReadOnlyReason::JournalReplay(err));
+                    if self.is_read_only() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        self.open_journal();
+        Ok(())
+    }
+
+    /// Load the journal of a writable volume to commit the transactions.
+    pub(crate) fn open_journal(&self) {
+        if self.sb.feature_compat & COMPAT_HAS_JOURNAL == 0 {
+            return;
+        }
+        match self.load_journal() {
+            Ok(journal) if journal.jsb.start == 0 => *self.journal.lock() = Some(journal),
+            Ok(_) => log::warn!("the ext4 journal isn't empty, write without journaling"),
+            Err(err) => {
+                log::warn!(
+                    "can't load the ext4 journal: {:?}, write without journaling",
+                    err
+                )
+            }
+        }
+    }
+
+    /// Read the journal superblock and map the journal inode.
+    fn load_journal(&self) -> VfsResult<Journal> {
+        if self.sb.journal_inum == 0 {
+            return Err(VfsError::NotSupported);
+        }
+        let inode = self.read_inode(self.sb.journal_inum)?;
+        if !inode.uses_extents() {
+            return Err(VfsError::NotSupported);
+        }
+        let mut extents = ExtentCache::new();
+        extents.fill(self.inode_extents(self.sb.journal_inum, &inode)?);
+        let sb_block = extents.lookup(0).ok_or(VfsError::InvalidData)?.physical;
+        let jsb_block = self.read_block(sb_block);
+        let jsb = JournalSuperBlock::parse(&jsb_block)?;
+        if jsb.blocksize as usize != self.sb.block_size() {
+            return Err(VfsError::InvalidData);
+        }
+        Ok(Journal {
+            jsb,
+            jsb_block,
+            extents,
+        })
+    }
+
+    /// Replay the journal to the home locations of its blocks, then empty
+    /// it. in_memory keeps the replayed blocks over the device instead,
+    /// for a read-only mount, and the journal stays as it is.
+    fn replay_journal(&self, in_memory: bool) -> VfsResult<u32> {
+        let block_size = self.sb.block_size();
+        let mut journal = self.load_journal()?;
+        let read_block = |lblock: u32| -> VfsResult<Vec<u8>> {
+            self.disk.mount_poll()?;
+            Ok(self.read_block(journal.physical(lblock)?))
+        };
+        let replay = scan_journal(&journal.jsb, &read_block)?;
+        for block in replay.blocks.iter() {
+            let mut data = read_block(block.lblock)?;
+            if block.escaped {
+                data[..4].copy_from_slice(&JBD2_MAGIC.to_be_bytes());
+            }
+            let home = block.home as usize * block_size;
+            match in_memory {
+                true => self.disk.replay_block(home, &data[..block_size]),
+                false => self.disk.write_offset(home, &data[..block_size]),
+            }
+        }
+        // the replayed blocks may include the group descriptors.
+        self.disk.groups.lock().descs.clear();
+        if !in_memory {
+            // the home locations are up to date, empty the journal then
+            // clear the recovery flag.
+            blockdev::flush_device(self.disk.dev);
+            self.write_jsb(&mut journal, 0, replay.next_sequence, true)?;
+            self.write_recover_flag(false, None, false);
+        }
+        Ok(replay.transactions)
+    }
+
+    /// Write the start and the sequence of the log to the journal superblock,
+    /// durable writes it with write_durable.
+    fn write_jsb(
+        &self,
+        journal: &mut Journal,
+        start: u32,
+        sequence: u32,
+        durable: bool,
+    ) -> VfsResult<()> {
+        let block_size = self.sb.block_size();
+        journal
+            .jsb
+            .set_start(&mut journal.jsb_block, start, sequence);
+        journal.jsb.start = start;
+        journal.jsb.sequence = sequence;
+        let offset = journal.physical(0)? as usize * block_size;
+        match durable {
+            true => self
+                .disk
+                .write_durable(offset, &journal.jsb_block[..block_size]),
+            false => self
+                .disk
+                .write_offset(offset, &journal.jsb_block[..block_size]),
+        }
+        Ok(())
+    }
+
+    /// Set or clear the recovery flag of the superblock. sb is the new
+    /// superblock to write, the one on the disk is modified if it's None.
+    /// durable writes it with write_durable.
+    fn write_recover_flag(&self, recover: bool, sb: Option<&[u8]>, durable: bool) {
+        let mut sb = match sb {
+            Some(sb) => sb[..1024].to_vec(),
+            None => self.disk.read_offset(SUPERBLOCK_OFFSET)[..1024].to_vec(),
+        };
+        let incompat = match recover {
+            true => le_u32(&sb, 0x60) | INCOMPAT_RECOVER,
+            false => le_u32(&sb, 0x60) & !INCOMPAT_RECOVER,
+        };
+        sb[0x60..0x64].copy_from_slice(&incompat.to_le_bytes());
+        if self.sb.has_metadata_csum() {
+            set_superblock_csum(&mut sb);
+        }
+        match durable {
+            true => self.disk.write_durable(SUPERBLOCK_OFFSET, &sb),
+            false => self.disk.write_offset(SUPERBLOCK_OFFSET, &sb),
+        }
+    }
+
+    /// Roll back a committed transaction whose writes failed, so the
+    /// operation fails with EIO and leaves the filesystem unchanged, like
+    /// an abort: a create leaves no inode without its entry, and a retry
+    /// starts over. The blocks of the transaction are written back as they
+    /// were before it and the log of the journal is dropped, the mount
+    /// doesn't replay it. If the writes of the roll back fail too, the
+    /// disk is left for a fsck.
+    pub(crate) fn roll_back(&self, journal: Option<&mut Journal>, undo: &Transaction) {
+        let errors = self.disk.unrecovered_errors();
+        self.disk.roll_back(undo);
+        if let Some(journal) = journal {
+            let sequence = journal.jsb.sequence;
+            if let Err(err) = self.write_jsb(journal, 0, sequence, false) {
+                log::error!("drop the ext4 journal log failed: {:?}", err);
+            }
+            self.write_recover_flag(false, None, false);
+        }
+        blockdev::flush_device(self.disk.dev);
+        if self.disk.unrecovered_errors() != errors {
+            log::error!("the roll back of the ext4 transaction failed, the disk needs a fsck");
+        }
+    }
+
+    /// Write the transaction to the disk. The data blocks are written
+    /// first, then the metadata blocks are logged and committed in the
+    /// journal, the recovery flag is set and the metadata is written home.
+    /// At last the journal is emptied and the recovery flag is cleared.
+    /// The superblock is written home with the flag cleared, so it's the
+    /// last block of the transaction to reach the disk.
+    /// Without a journal, or if the transaction doesn't fit in it, the
+    /// metadata is written in place with the superblock last. Every step
+    /// writes its blocks by runs, see write_runs, so a small write without
+    /// a journal is a request for its data and one for its inode.
+    /// The sync policy may defer the blocks instead, dirtied for the
+    /// inodes of the transaction, or flush the write cache between the
+    /// steps, see commit_blocks.
+    pub(crate) fn commit(
+        &self,
+        mut journal: Option<&mut Journal>,
+        txn: Transaction,
+        data: &[Extent],
+        (inodes, data_ino): (&[u32], Option<u32>),
+    ) -> VfsResult<()> {
+        let block_size = txn.block_size;
+        let (data_blocks, metadata): (Vec<_>, Vec<_>) =
+            txn.blocks.into_iter().partition(|(block, _)| {
+                data.iter()
+                    .any(|x| (x.physical..x.physical + x.len as u64).contains(block))
+            });
+        let policy = self.effective_sync_policy();
+        let SyncPolicy::WriteBack {
+            max_dirty_blocks, ..
+        } = policy
+        else {
+            let strict = policy == SyncPolicy::StrictOrdered;
+            return self.commit_blocks(journal, block_size, &data_blocks, &metadata, strict);
+        };
+        // the deferred metadata is logged as one transaction, it must fit
+        // in the journal with room for the descriptors.
+        if let Some(journal) = journal.as_deref_mut() {
+            let room = journal.jsb.maxlen.saturating_sub(journal.jsb.first) as usize / 2;
+            let pending = self.disk.deferred.lock().metadata.len();
+            if pending > 0 && pending + metadata.len() > room {
+                self.write_deferred(Some(journal))?;
+            }
+        }
+        let owners = self.txn_owners(inodes, data_ino, &metadata);
+        if self.disk.defer(block_size, data_blocks, metadata, &owners) > max_dirty_blocks {
+            self.write_deferred(journal)?;
+        }
+        Ok(())
+    }
+
+    /// The owners of a committed transaction, before its metadata is
+    /// deferred. It changed only the times if its metadata is the inode
+    /// blocks of its inodes and they differ from the disk by the times of
+    /// the inodes, like a utimes or the mtime of an overwrite.
+    fn txn_owners(
+        &self,
+        inodes: &[u32],
+        data_ino: Option<u32>,
+        metadata: &[(u64, Vec<u8>)],
+    ) -> TxnOwners {
+        let block_size = self.sb.block_size();
+        let inode_size = self.sb.inode_size as usize;
+        let mut owners = TxnOwners {
+            inodes: inodes.to_vec(),
+            data_ino,
+            times_only: false,
+        };
+        let slots: Vec<_> = inodes
+            .iter()
+            .chain(data_ino.iter())
+            .filter_map(|x| self.inode_offset(*x).ok())
+            .collect();
+        if metadata.is_empty() || slots.len() < inodes.len() + data_ino.iter().count() {
+            return owners;
+        }
+        let mut old = vec![0; block_size];
+        owners.times_only = metadata.iter().all(|(block, buf)| {
+            let offset = *block as usize * block_size;
+            let slots: Vec<_> = slots
+                .iter()
+                .filter(|x| **x / block_size == *block as usize)
+                .map(|x| x - offset)
+                .collect();
+            self.disk.read_device_into(offset, &mut old);
+            (0..block_size).all(|x| {
+                old[x] == buf[x]
+                    || slots.iter().any(|slot| {
+                        (*slot..slot + inode_size).contains(&x) && is_time_byte(x - slot)
+                    })
+            })
+        });
+        owners
+    }
+
+    /// Write the data blocks and the metadata blocks of a transaction, see
+    /// commit. strict flushes the write cache of the device after each
+    /// step, so none of its writes reach the media before those of the
+    /// step before, and the transaction is on the media when it returns.
+    /// Otherwise a journaled commit on a device with a write cache orders
+    /// its durability points only: the data and the log are flushed, then
+    /// the start of the log and the recovery flag are written with FUA, or
+    /// each followed by a flush, and the home blocks are flushed before the
+    /// journal is emptied the same way. The flag cleared at the end stays
+    /// cached until the next commit flushes it, before its log has a start.
+    pub(crate) fn commit_blocks(
+        &self,
+        journal: Option<&mut Journal>,
+        block_size: usize,
+        data_blocks: &[(u64, Vec<u8>)],
+        metadata: &[(u64, Vec<u8>)],
+        strict: bool,
+    ) -> VfsResult<()> {
+        let barrier = || {
+            if strict {
+                blockdev::flush_device(self.disk.dev);
+            }
+        };
+        self.write_runs(block_size, WriteKind::Data, data_blocks.iter())?;
+        if metadata.is_empty() {
+            if !data_blocks.is_empty() {
+                barrier();
+            }
+            return Ok(());
+        }
+        barrier();
+
+        let sb_block = (SUPERBLOCK_OFFSET / block_size) as u64;
+        let in_place = metadata.iter().filter(|(x, _)| *x != sb_block);
+        let write_in_place = || {
+            self.write_runs(block_size, WriteKind::Metadata, in_place.clone())?;
+            if let Some((_, buf)) = metadata.iter().find(|(x, _)| *x == sb_block) {
+                barrier();
+                self.disk.write_offset(sb_block as usize * block_size, buf);
+            }
+            barrier();
+            Ok(())
+        };
+        let Some(journal) = journal else {
+            return write_in_place();
+        };
+        // the durability points, strict has a barrier at each of them.
+        let cached = !strict && blockdev::has_write_cache(self.disk.dev);
+        let preflush = || {
+            if cached {
+                blockdev::flush_device(self.disk.dev);
+            }
+        };
+        let sequence = journal.jsb.sequence;
+        let log = match build_log(&journal.jsb, sequence, metadata) {
+            Ok(log) => log,
+            Err(err) => {
+                log::warn!("can't journal {} blocks: {:?}", metadata.len(), err);
+                return write_in_place();
+            }
+        };
+        let log = log
+            .into_iter()
+            .enumerate()
+            .map(|(index, buf)| Ok((journal.physical(journal.jsb.first + index as u32)?, buf)))
+            .collect::<VfsResult<Vec<_>>>()?;
+        self.write_runs(block_size, WriteKind::Metadata, log.iter())?;
+        barrier();
+        preflush();
+        let first = journal.jsb.first;
+        self.write_jsb(journal, first, sequence, cached)?;
+        barrier();
+        // the transaction is committed once the flag is on the disk.
+        self.write_recover_flag(true, None, cached);
+        barrier();
+
+        // checkpoint the metadata.
+        self.write_runs(block_size, WriteKind::Metadata, in_place)?;
+        barrier();
+        // the rest of the block holding the superblock is the boot sector.
+        let sb_off = SUPERBLOCK_OFFSET % block_size;
+        let sb = metadata
+            .iter()
+            .find(|(x, _)| *x == sb_block)
+            .map(|x| &x.1[sb_off..sb_off + 1024]);
+        // the cached clear of the flag may be lost, the superblock is on the
+        // media with the flag set before the journal is emptied.
+        if cached && sb.is_some() {
+            self.write_recover_flag(true, sb, false);
+        }
+        preflush();
+        self.write_jsb(journal, 0, sequence.wrapping_add(1), cached)?;
+        barrier();
+        self.write_recover_flag(false, sb, false);
+        barrier();
+        Ok(())
+    }
+
+    /// Commit the transactions deferred by the write-back policy as one
+    /// transaction, the caller holds the journal lock. They stay deferred
+    /// if the commit fails. return the number of the written blocks.
+    pub(crate) fn write_deferred(&self, journal: Option<&mut Journal>) -> VfsResult<usize> {
+        let (block_size, data, metadata) = {
+            let deferred = self.disk.deferred.lock();
+            if deferred.len() == 0 {
+                return Ok(0);
+            }
+            let blocks = |x: &BTreeMap<u64, Vec<u8>>| -> Vec<_> {
+                x.iter()
+                    .map(|(block, data)| (*block, data.clone()))
+                    .collect()
+            };
+            (
+                deferred.block_size,
+                blocks(&deferred.data),
+                blocks(&deferred.metadata),
+            )
+        };
+        self.commit_blocks(journal, block_size, &data, &metadata, false)?;
+        let mut deferred = self.disk.deferred.lock();
+        deferred.data.clear();
+        deferred.metadata.clear();
+        deferred.dirty.clear();
+        deferred.since = None;
+        Ok(data.len() + metadata.len())
+    }
+
+    /// Write back the deferred transactions, see write_deferred.
+    pub(crate) fn write_back(&self) -> VfsResult<usize> {
+        let mut journal = self.journal.lock();
+        self.write_deferred(journal.as_mut())
+    }
+}
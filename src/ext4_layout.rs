@@ -0,0 +1,1343 @@
+// On-disk structures of ext4 used by the ext4 shim.
+// The parsers only work on byte slices, reading the blocks from the device
+// is up to the caller. They read the structures of disk_layout.
+
+use core::fmt::{self, Display, Formatter};
+use core::mem::{offset_of, size_of};
+
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use alloc::vec::Vec;
+use vfscore::{VfsError, VfsResult};
+
+use crate::crc32c::crc32c;
+use crate::disk_layout::{
+    self as disk, lo_hi, DirEntry, ExtentIdx, ExtentLeaf, GroupDesc32, GroupDesc64, Inode,
+    InodeExtra, Layout, XattrEntry, XattrIbodyHeader, GOOD_OLD_INODE_SIZE,
+};
+use crate::statfs;
+
+/// The offset of the superblock on the disk.
+pub const SUPERBLOCK_OFFSET: usize = 1024;
+/// EXT4_SUPER_MAGIC of the superblock, the f_type of statfs.rs.
+pub const EXT4_SUPER_MAGIC: u16 = statfs::EXT4_SUPER_MAGIC as u16;
+/// The magic number in the header of every extent tree node.
+pub const EXTENT_MAGIC: u16 = 0xF30A;
+/// The inode uses the extent tree to map the blocks.
+pub const EXT4_EXTENTS_FL: u32 = 0x80000;
+/// The data of the inode is stored in i_block and the system.data xattr.
+pub const EXT4_INLINE_DATA_FL: u32 = 0x10000000;
+/// The magic number of the xattr header in the inode body.
+const XATTR_MAGIC: u32 = 0xEA020000;
+/// The name index of the "system." xattrs.
+const XATTR_INDEX_SYSTEM: u8 = 7;
+/// The compat feature of the journal.
+pub const COMPAT_HAS_JOURNAL: u32 = 0x4;
+/// The incompat feature set while the journal needs to be replayed.
+pub const INCOMPAT_RECOVER: u32 = 0x4;
+/// The ro_compat feature of the metadata checksums.
+pub const RO_COMPAT_METADATA_CSUM: u32 = 0x400;
+/// The s_state bit set when the kernel found errors in the filesystem.
+pub const STATE_ERROR_FS: u16 = 0x2;
+/// The offset of s_checksum in the superblock.
+pub const SB_CHECKSUM_OFFSET: usize = offset_of!(disk::SuperBlock, checksum);
+/// The checksum seed is stored in the superblock instead of derived from
+/// the uuid.
+pub const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+/// The 64bit incompat feature, the group descriptors have the _hi fields.
+pub const INCOMPAT_64BIT: u32 = 0x80;
+/// The group descriptors are stored in the meta block groups.
+pub const INCOMPAT_META_BG: u32 = 0x10;
+/// The files may map their blocks by extents.
+pub const INCOMPAT_EXTENTS: u32 = 0x40;
+/// The metadata of the groups may be in other groups.
+pub const INCOMPAT_FLEX_BG: u32 = 0x200;
+/// The small files and directories may be stored in the inode.
+pub const INCOMPAT_INLINE_DATA: u32 = 0x8000;
+/// The inodes with EXT4_ENCRYPT_FL are encrypted by fscrypt.
+pub const INCOMPAT_ENCRYPT: u32 = 0x10000;
+
+/// The names of the incompat features, as mke2fs calls them.
+const INCOMPAT_NAMES: &[(u32, &str)] = &[
+    (0x1, "compression"),
+    (0x2, "filetype"),
+    (0x4, "needs_recovery"),
+    (0x8, "journal_dev"),
+    (0x10, "meta_bg"),
+    (0x40, "extent"),
+    (0x80, "64bit"),
+    (0x100, "mmp"),
+    (0x200, "flex_bg"),
+    (0x400, "ea_inode"),
+    (0x1000, "dirdata"),
+    (0x2000, "metadata_csum_seed"),
+    (0x4000, "large_dir"),
+    (0x8000, "inline_data"),
+    (0x10000, "encrypt"),
+    (0x20000, "casefold"),
+];
+
+/// The names of the compat features, as mke2fs calls them.
+const COMPAT_NAMES: &[(u32, &str)] = &[
+    (0x1, "dir_prealloc"),
+    (0x2, "imagic_inodes"),
+    (0x4, "has_journal"),
+    (0x8, "ext_attr"),
+    (0x10, "resize_inode"),
+    (0x20, "dir_index"),
+    (0x40, "lazy_bg"),
+    (0x80, "exclude_inode"),
+    (0x100, "exclude_bitmap"),
+    (0x200, "sparse_super2"),
+    (0x400, "fast_commit"),
+    (0x800, "stable_inodes"),
+    (0x1000, "orphan_file"),
+];
+
+/// The names of the compat features in flags, the unknown ones in hex.
+pub fn compat_names(flags: u32) -> String {
+    feature_names(flags, COMPAT_NAMES)
+}
+
+/// The names of the incompat features in flags, the unknown ones in hex.
+pub fn incompat_names(flags: u32) -> String {
+    feature_names(flags, INCOMPAT_NAMES)
+}
+
+/// The names of the ro_compat features in flags, the unknown ones in hex.
+pub fn ro_compat_names(flags: u32) -> String {
+    feature_names(flags, RO_COMPAT_NAMES)
+}
+
+fn feature_names(flags: u32, table: &[(u32, &str)]) -> String {
+    let mut names = Vec::new();
+    let mut rest = flags;
+    for &(flag, name) in table {
+        if flags & flag != 0 {
+            names.push(String::from(name));
+            rest &= !flag;
+        }
+    }
+    if rest != 0 {
+        names.push(format!("{:#x}", rest));
+    }
+    names.join(", ")
+}
+
+/// The names of the ro_compat features, as mke2fs calls them.
+const RO_COMPAT_NAMES: &[(u32, &str)] = &[
+    (0x1, "sparse_super"),
+    (0x2, "large_file"),
+    (0x8, "huge_file"),
+    (0x10, "uninit_bg"),
+    (0x20, "dir_nlink"),
+    (0x40, "extra_isize"),
+    (0x100, "quota"),
+    (0x200, "bigalloc"),
+    (0x400, "metadata_csum"),
+    (0x1000, "read-only"),
+    (0x2000, "project"),
+    (0x4000, "shared_blocks"),
+    (0x8000, "verity"),
+    (0x10000, "orphan_present"),
+];
+
+/// The superblock backups are only in the groups 0, 1 and the powers of
+/// 3, 5 and 7.
+pub const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+/// The sizes of the files may be above 2 GiB, i_size_high is used.
+pub const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+/// i_blocks of the inodes with EXT4_HUGE_FILE_FL counts the fs blocks.
+pub const RO_COMPAT_HUGE_FILE: u32 = 0x8;
+/// A directory with more than EXT4_LINK_MAX links has a count of 1.
+pub const RO_COMPAT_DIR_NLINK: u32 = 0x20;
+/// The large inodes hold at least s_min_extra_isize bytes of extra fields.
+pub const RO_COMPAT_EXTRA_ISIZE: u32 = 0x40;
+/// The bitmaps count the clusters of s_log_cluster_size blocks.
+pub const RO_COMPAT_BIGALLOC: u32 = 0x200;
+/// The inodes with EXT4_VERITY_FL have a Merkle tree after their data.
+pub const RO_COMPAT_VERITY: u32 = 0x8000;
+/// The superblock backups are only in the groups of s_backup_bgs.
+pub const COMPAT_SPARSE_SUPER2: u32 = 0x200;
+pub const EXT4_HUGE_FILE_FL: u32 = 0x40000;
+/// The inode table of the group isn't initialized.
+pub const BG_INODE_UNINIT: u16 = 0x1;
+/// The block bitmap of the group isn't initialized.
+pub const BG_BLOCK_UNINIT: u16 = 0x2;
+/// The inode table of the group is zeroed, the inodes beyond
+/// bg_itable_unused read as zeros.
+pub const BG_INODE_ZEROED: u16 = 0x4;
+pub const ROOT_INO: u32 = 2;
+/// The reserved inode mapping the reserved group descriptor blocks.
+pub const RESIZE_INO: u32 = 7;
+/// The extent whose ee_len is larger than this is uninitialized.
+pub const EXT_INIT_MAX_LEN: u16 = 0x8000;
+/// i_block of the inode is 60 bytes.
+const I_BLOCK_SIZE: usize = 60;
+/// i_extra_isize, the bytes of the large inode used after the first 128.
+const I_EXTRA_ISIZE: usize = disk::extra_offset(offset_of!(InodeExtra, extra_isize));
+
+/// The inode flags reported as the statx attributes.
+pub const EXT4_COMPR_FL: u32 = 0x4;
+pub const EXT4_IMMUTABLE_FL: u32 = 0x10;
+pub const EXT4_APPEND_FL: u32 = 0x20;
+pub const EXT4_NODUMP_FL: u32 = 0x40;
+pub const EXT4_ENCRYPT_FL: u32 = 0x800;
+pub const EXT4_VERITY_FL: u32 = 0x100000;
+
+/// A timestamp of the inode: the offset of its seconds and of its extra
+/// field in the large inodes.
+#[derive(Debug, Clone, Copy)]
+pub struct TimeField {
+    pub sec: usize,
+    pub extra: usize,
+}
+
+pub const I_ATIME: TimeField = TimeField {
+    sec: offset_of!(Inode, atime),
+    extra: disk::extra_offset(offset_of!(InodeExtra, atime_extra)),
+};
+pub const I_CTIME: TimeField = TimeField {
+    sec: offset_of!(Inode, ctime),
+    extra: disk::extra_offset(offset_of!(InodeExtra, ctime_extra)),
+};
+pub const I_MTIME: TimeField = TimeField {
+    sec: offset_of!(Inode, mtime),
+    extra: disk::extra_offset(offset_of!(InodeExtra, mtime_extra)),
+};
+/// The creation time, only in the large inodes.
+pub const I_CRTIME: TimeField = TimeField {
+    sec: disk::extra_offset(offset_of!(InodeExtra, crtime)),
+    extra: disk::extra_offset(offset_of!(InodeExtra, crtime_extra)),
+};
+
+/// A time of the inode. The seconds are a signed 32 bits field, the extra
+/// field holds 2 more bits of the epoch and the nanoseconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    pub sec: i64,
+    pub nsec: u32,
+}
+
+impl Timestamp {
+    /// The last time with the extra field, in 2446.
+    pub const MAX: Self = Self {
+        sec: i32::MAX as i64 + (3 << 32),
+        nsec: 999_999_999,
+    };
+    /// The first time, in 1901, the epoch bits only extend the future.
+    pub const MIN: Self = Self {
+        sec: i32::MIN as i64,
+        nsec: 0,
+    };
+    /// The last time without the extra field, in 2038.
+    pub const MAX_SMALL: Self = Self {
+        sec: i32::MAX as i64,
+        nsec: 0,
+    };
+
+    pub fn decode(sec: u32, extra: u32) -> Self {
+        Self {
+            sec: sec as i32 as i64 + (((extra & 3) as i64) << 32),
+            nsec: (extra >> 2).min(999_999_999),
+        }
+    }
+
+    /// The seconds and the extra field, a time out of MIN..=MAX is
+    /// saturated to them and never wraps.
+    pub fn encode(self) -> (u32, u32) {
+        let time = self.clamp(Self::MIN, Self::MAX);
+        let nsec = time.nsec.min(999_999_999);
+        let epoch = ((time.sec - time.sec as i32 as i64) >> 32) as u32 & 3;
+        (time.sec as u32, epoch | nsec << 2)
+    }
+
+    /// The time an inode without the extra field keeps: the seconds,
+    /// saturated to the signed 32 bits.
+    pub fn small(self) -> Self {
+        Self {
+            sec: self.sec.clamp(Self::MIN.sec, Self::MAX_SMALL.sec),
+            nsec: 0,
+        }
+    }
+}
+
+/// The end of the fields in the inode, the small inodes and the fields
+/// beyond i_extra_isize don't have the extra fields.
+pub fn inode_fields_end(raw: &[u8], inode_size: usize) -> usize {
+    match inode_size > GOOD_OLD_INODE_SIZE {
+        true => (GOOD_OLD_INODE_SIZE + le_u16(raw, I_EXTRA_ISIZE) as usize).min(inode_size),
+        false => GOOD_OLD_INODE_SIZE,
+    }
+}
+
+/// Read the time of the inode, None if the inode doesn't have the field.
+/// The time is rounded to the second without the extra field.
+pub fn inode_time(raw: &[u8], inode_size: usize, field: TimeField) -> Option<Timestamp> {
+    let end = inode_fields_end(raw, inode_size);
+    if field.sec + 4 > end {
+        return None;
+    }
+    let extra = match field.extra + 4 <= end {
+        true => le_u32(raw, field.extra),
+        false => 0,
+    };
+    Some(Timestamp::decode(le_u32(raw, field.sec), extra))
+}
+
+pub fn le_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+pub fn le_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+/// The fields of the on-disk superblock used by the shim.
+#[derive(Debug, Clone)]
+pub struct SuperBlockInfo {
+    pub inodes_count: u32,
+    pub blocks_count: u64,
+    pub r_blocks_count: u64,
+    pub free_blocks_count: u64,
+    pub free_inodes_count: u32,
+    pub first_data_block: u32,
+    pub log_block_size: u32,
+    pub blocks_per_group: u32,
+    pub inodes_per_group: u32,
+    pub magic: u16,
+    pub state: u16,
+    pub inode_size: u16,
+    pub feature_compat: u32,
+    pub feature_incompat: u32,
+    pub feature_ro_compat: u32,
+    pub uuid: [u8; 16],
+    pub hash_seed: [u32; 4],
+    pub def_hash_version: u8,
+    pub desc_size: u16,
+    pub journal_inum: u32,
+    pub flags: u32,
+    /// The first inode which isn't reserved.
+    pub first_ino: u32,
+    pub reserved_gdt_blocks: u16,
+    /// The groups with the superblock backups with sparse_super2.
+    pub backup_bgs: [u32; 2],
+    /// The seed of the metadata checksums.
+    pub csum_seed: u32,
+    /// The head of the orphan list, the inodes to release at mount.
+    pub last_orphan: u32,
+    /// The extra fields every inode holds, with extra_isize.
+    pub min_extra_isize: u16,
+    /// The extra fields of the new inodes, with extra_isize.
+    pub want_extra_isize: u16,
+}
+
+impl SuperBlockInfo {
+    /// Parse the superblock from the bytes starting at SUPERBLOCK_OFFSET.
+    /// The caller checks the bytes hold the 1024 of the superblock.
+    pub fn parse(data: &[u8]) -> Self {
+        let raw = disk::SuperBlock::ref_from(data).expect("the superblock is 1024 bytes");
+        let feature_incompat = raw.feature_incompat.get();
+        // the hi halves of the block counts are garbage without 64bit.
+        let is_64bit = feature_incompat & INCOMPAT_64BIT != 0;
+        let count = |lo: disk::Le32, hi: disk::Le32| match is_64bit {
+            true => lo_hi(lo.get(), hi.get()),
+            false => lo.get() as u64,
+        };
+        let csum_seed = match feature_incompat & INCOMPAT_CSUM_SEED {
+            0 => crc32c(!0, &raw.uuid),
+            _ => raw.checksum_seed.get(),
+        };
+        Self {
+            inodes_count: raw.inodes_count.get(),
+            blocks_count: count(raw.blocks_count_lo, raw.blocks_count_hi),
+            r_blocks_count: count(raw.r_blocks_count_lo, raw.r_blocks_count_hi),
+            free_blocks_count: count(raw.free_blocks_count_lo, raw.free_blocks_count_hi),
+            free_inodes_count: raw.free_inodes_count.get(),
+            first_data_block: raw.first_data_block.get(),
+            log_block_size: raw.log_block_size.get(),
+            blocks_per_group: raw.blocks_per_group.get(),
+            inodes_per_group: raw.inodes_per_group.get(),
+            magic: raw.magic.get(),
+            state: raw.state.get(),
+            inode_size: raw.inode_size.get(),
+            feature_compat: raw.feature_compat.get(),
+            feature_incompat,
+            feature_ro_compat: raw.feature_ro_compat.get(),
+            uuid: raw.uuid,
+            hash_seed: raw.hash_seed.map(|x| x.get()),
+            def_hash_version: raw.def_hash_version,
+            desc_size: raw.desc_size.get(),
+            journal_inum: raw.journal_inum.get(),
+            flags: raw.flags.get(),
+            first_ino: raw.first_ino.get(),
+            reserved_gdt_blocks: raw.reserved_gdt_blocks.get(),
+            backup_bgs: raw.backup_bgs.map(|x| x.get()),
+            csum_seed,
+            last_orphan: raw.last_orphan.get(),
+            min_extra_isize: raw.min_extra_isize.get(),
+            want_extra_isize: raw.want_extra_isize.get(),
+        }
+    }
+
+    pub fn block_size(&self) -> usize {
+        1024 << self.log_block_size
+    }
+
+    /// Check the geometry before anything is derived from it, the
+    /// superblock may come from an untrusted image. return the reason if
+    /// it's invalid, the checks are the ones of the kernel at mount.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.magic != EXT4_SUPER_MAGIC {
+            return Err("bad magic");
+        }
+        // the block size is at most 64K.
+        if self.log_block_size > 6 {
+            return Err("bad block size");
+        }
+        let bits = self.block_size() as u32 * 8;
+        if self.blocks_per_group < 8 || self.blocks_per_group > bits {
+            return Err("bad blocks per group");
+        }
+        if self.inodes_per_group == 0 || self.inodes_per_group > bits {
+            return Err("bad inodes per group");
+        }
+        if self.inode_size < 128
+            || !self.inode_size.is_power_of_two()
+            || self.inode_size as usize > self.block_size()
+        {
+            return Err("bad inode size");
+        }
+        if self.is_64bit()
+            && (self.desc_size < 64 || !self.desc_size.is_power_of_two() || self.desc_size > 1024)
+        {
+            return Err("bad group descriptor size");
+        }
+        if self.first_data_block as u64 >= self.blocks_count {
+            return Err("bad first data block");
+        }
+        if (self.groups_count() as u64).checked_mul(self.inodes_per_group as u64)
+            != Some(self.inodes_count as u64)
+        {
+            return Err("bad inodes count");
+        }
+        if self.first_ino < 11 || self.first_ino > self.inodes_count {
+            return Err("bad first inode");
+        }
+        if self.journal_inum > self.inodes_count || self.last_orphan > self.inodes_count {
+            return Err("bad inode reference");
+        }
+        Ok(())
+    }
+
+    /// The metadata carries the crc32c checksums.
+    pub fn has_metadata_csum(&self) -> bool {
+        self.feature_ro_compat & RO_COMPAT_METADATA_CSUM != 0
+    }
+
+    /// The directory entries carry the file type, without it the type
+    /// byte is the high byte of name_len.
+    pub fn has_filetype(&self) -> bool {
+        self.feature_incompat & INCOMPAT_FILETYPE != 0
+    }
+
+    pub fn is_64bit(&self) -> bool {
+        self.feature_incompat & INCOMPAT_64BIT != 0
+    }
+
+    /// The size of a group descriptor, it's 32 without the 64bit feature.
+    pub fn group_desc_size(&self) -> usize {
+        if self.is_64bit() && self.desc_size >= 64 {
+            self.desc_size as usize
+        } else {
+            32
+        }
+    }
+
+    /// The filesystem was marked with errors and must be checked first.
+    pub fn has_errors(&self) -> bool {
+        self.state & STATE_ERROR_FS != 0
+    }
+
+    /// The journal has transactions to replay.
+    pub fn needs_recovery(&self) -> bool {
+        self.feature_compat & COMPAT_HAS_JOURNAL != 0
+            && self.feature_incompat & INCOMPAT_RECOVER != 0
+    }
+
+    /// The max size of an extent-mapped file, the logical block numbers
+    /// are 32 bits.
+    pub fn max_file_size(&self) -> u64 {
+        (u32::MAX as u64) * self.block_size() as u64
+    }
+
+    pub fn groups_count(&self) -> usize {
+        ((self.blocks_count - self.first_data_block as u64) as usize)
+            .div_ceil(self.blocks_per_group as usize)
+    }
+
+    /// The number of the blocks holding the group descriptors.
+    pub fn group_desc_blocks(&self) -> usize {
+        (self.groups_count() * self.group_desc_size()).div_ceil(self.block_size())
+    }
+
+    /// The group has a superblock backup, followed by the descriptors.
+    pub fn group_has_super(&self, group: usize) -> bool {
+        if self.feature_compat & COMPAT_SPARSE_SUPER2 != 0 {
+            return group == 0 || self.backup_bgs.contains(&(group as u32));
+        }
+        if group <= 1 || self.feature_ro_compat & RO_COMPAT_SPARSE_SUPER == 0 {
+            return true;
+        }
+        [3, 5, 7].iter().any(|base| {
+            let mut power = *base;
+            while power < group {
+                power *= base;
+            }
+            power == group
+        })
+    }
+
+    /// The byte offset of the group descriptor on the disk.
+    pub fn group_desc_offset(&self, group: usize) -> usize {
+        (self.first_data_block as usize + 1) * self.block_size() + group * self.group_desc_size()
+    }
+
+    /// Get the group of the inode and the index in the group's inode table.
+    pub fn inode_group(&self, ino: u32) -> (usize, usize) {
+        let index = (ino - 1) as usize;
+        let ipg = self.inodes_per_group as usize;
+        (index / ipg, index % ipg)
+    }
+
+    /// Derive the fsid from the uuid, it's stable across mounts.
+    pub fn fsid(&self) -> u64 {
+        let (lo, hi) = self.uuid.split_at(8);
+        u64::from_le_bytes(lo.try_into().unwrap()) ^ u64::from_le_bytes(hi.try_into().unwrap())
+    }
+}
+
+fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// s_error_count, the errors since the last fsck.
+const S_ERROR_COUNT: usize = offset_of!(disk::SuperBlock, error_count);
+
+/// The offsets of an error record in the superblock.
+struct ErrorFields {
+    time: usize,
+    /// The high byte of the seconds.
+    time_hi: usize,
+    ino: usize,
+    block: usize,
+    func: usize,
+    line: usize,
+    errcode: usize,
+}
+
+/// s_first_error_*.
+const FIRST_ERROR: ErrorFields = ErrorFields {
+    time: offset_of!(disk::SuperBlock, first_error_time),
+    time_hi: offset_of!(disk::SuperBlock, first_error_time_hi),
+    ino: offset_of!(disk::SuperBlock, first_error_ino),
+    block: offset_of!(disk::SuperBlock, first_error_block),
+    func: offset_of!(disk::SuperBlock, first_error_func),
+    line: offset_of!(disk::SuperBlock, first_error_line),
+    errcode: offset_of!(disk::SuperBlock, first_error_errcode),
+};
+/// s_last_error_*.
+const LAST_ERROR: ErrorFields = ErrorFields {
+    time: offset_of!(disk::SuperBlock, last_error_time),
+    time_hi: offset_of!(disk::SuperBlock, last_error_time_hi),
+    ino: offset_of!(disk::SuperBlock, last_error_ino),
+    block: offset_of!(disk::SuperBlock, last_error_block),
+    func: offset_of!(disk::SuperBlock, last_error_func),
+    line: offset_of!(disk::SuperBlock, last_error_line),
+    errcode: offset_of!(disk::SuperBlock, last_error_errcode),
+};
+/// The bytes of the function of a record, without NUL.
+pub const ERROR_FUNC_LEN: usize = 32;
+
+/// The kind of an error record, the EXT4_ERR_* codes of Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Unknown,
+    Io,
+    NoMemory,
+    /// A checksum mismatch.
+    BadCrc,
+    /// Metadata which can't be right.
+    Corrupted,
+    NoSpace,
+    Other(u8),
+}
+
+impl ErrorCode {
+    pub fn from_raw(raw: u8) -> Self {
+        match raw {
+            0 => Self::Unknown,
+            1 => Self::Io,
+            2 => Self::NoMemory,
+            3 => Self::BadCrc,
+            4 => Self::Corrupted,
+            5 => Self::NoSpace,
+            x => Self::Other(x),
+        }
+    }
+
+    pub fn raw(self) -> u8 {
+        match self {
+            Self::Unknown => 0,
+            Self::Io => 1,
+            Self::NoMemory => 2,
+            Self::BadCrc => 3,
+            Self::Corrupted => 4,
+            Self::NoSpace => 5,
+            Self::Other(x) => x,
+        }
+    }
+}
+
+/// The errno names of dumpe2fs.
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown => write!(f, "EXT4_ERR_UNKNOWN"),
+            Self::Io => write!(f, "EIO"),
+            Self::NoMemory => write!(f, "ENOMEM"),
+            Self::BadCrc => write!(f, "EFSBADCRC"),
+            Self::Corrupted => write!(f, "EFSCORRUPTED"),
+            Self::NoSpace => write!(f, "ENOSPC"),
+            Self::Other(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+/// The first or the last error found in the filesystem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorRecord {
+    /// The seconds since the epoch.
+    pub time: u64,
+    /// The function of the shim which found it, at most ERROR_FUNC_LEN
+    /// bytes, and the line of its source.
+    pub function: String,
+    pub line: u32,
+    /// 0 if it isn't of an inode or a block.
+    pub ino: u32,
+    pub block: u64,
+    pub code: ErrorCode,
+}
+
+impl ErrorRecord {
+    /// The record at the fields, None if they are empty.
+    fn parse(raw: &[u8], fields: &ErrorFields) -> Option<Self> {
+        let time = le_u32(raw, fields.time) as u64 | (raw[fields.time_hi] as u64) << 32;
+        let func = &raw[fields.func..fields.func + ERROR_FUNC_LEN];
+        let len = func.iter().position(|x| *x == 0).unwrap_or(func.len());
+        if time == 0 && len == 0 {
+            return None;
+        }
+        let block = le_u32(raw, fields.block) as u64 | (le_u32(raw, fields.block + 4) as u64) << 32;
+        Some(Self {
+            time,
+            function: String::from_utf8_lossy(&func[..len]).into_owned(),
+            line: le_u32(raw, fields.line),
+            ino: le_u32(raw, fields.ino),
+            block,
+            code: ErrorCode::from_raw(raw[fields.errcode]),
+        })
+    }
+
+    fn write(&self, raw: &mut [u8], fields: &ErrorFields) {
+        put_u32(raw, fields.time, self.time as u32);
+        raw[fields.time_hi] = (self.time >> 32) as u8;
+        put_u32(raw, fields.ino, self.ino);
+        put_u32(raw, fields.block, self.block as u32);
+        put_u32(raw, fields.block + 4, (self.block >> 32) as u32);
+        let func = &mut raw[fields.func..fields.func + ERROR_FUNC_LEN];
+        func.fill(0);
+        let len = self.function.len().min(ERROR_FUNC_LEN);
+        func[..len].copy_from_slice(&self.function.as_bytes()[..len]);
+        put_u32(raw, fields.line, self.line);
+        raw[fields.errcode] = self.code.raw();
+    }
+}
+
+/// The errors recorded in the superblock, like ext4_error of Linux: the
+/// count, the first error since the last fsck and the last one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorHistory {
+    pub count: u32,
+    pub first: Option<ErrorRecord>,
+    pub last: Option<ErrorRecord>,
+}
+
+impl ErrorHistory {
+    /// The history of the raw superblock.
+    pub fn parse(raw: &[u8]) -> Self {
+        Self {
+            count: le_u32(raw, S_ERROR_COUNT),
+            first: ErrorRecord::parse(raw, &FIRST_ERROR),
+            last: ErrorRecord::parse(raw, &LAST_ERROR),
+        }
+    }
+
+    /// Write the history to the raw superblock, the checksum is left to
+    /// the caller.
+    pub fn write(&self, raw: &mut [u8]) {
+        put_u32(raw, S_ERROR_COUNT, self.count);
+        for (record, fields) in [(&self.first, &FIRST_ERROR), (&self.last, &LAST_ERROR)] {
+            match record {
+                Some(record) => record.write(raw, fields),
+                None => {
+                    for (offset, len) in [
+                        (fields.time, 4),
+                        (fields.ino, 4),
+                        (fields.block, 8),
+                        (fields.func, ERROR_FUNC_LEN),
+                        (fields.line, 4),
+                        (fields.time_hi, 1),
+                        (fields.errcode, 1),
+                    ] {
+                        raw[offset..offset + len].fill(0);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Add the error: the count goes up, the first one is kept and the
+    /// last one replaced.
+    pub fn add(&mut self, record: ErrorRecord) {
+        self.count = self.count.saturating_add(1);
+        if self.first.is_none() {
+            self.first = Some(record.clone());
+        }
+        self.last = Some(record);
+    }
+}
+
+/// The error fields like dumpe2fs prints them, the times in seconds.
+impl Display for ErrorHistory {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "FS Error count:           {}", self.count)?;
+        for (name, record) in [("First", &self.first), ("Last", &self.last)] {
+            let Some(x) = record else {
+                continue;
+            };
+            let pad = |field: &str| format!("{} error {}:", name, field);
+            writeln!(f, "{:<26}{}", pad("time"), x.time)?;
+            writeln!(f, "{:<26}{}", pad("function"), x.function)?;
+            writeln!(f, "{:<26}{}", pad("line #"), x.line)?;
+            writeln!(f, "{:<26}{}", pad("inode #"), x.ino)?;
+            writeln!(f, "{:<26}{}", pad("block #"), x.block)?;
+            writeln!(f, "{:<26}{}", pad("err"), x.code)?;
+        }
+        Ok(())
+    }
+}
+
+/// The fields of the group descriptor used by the shim.
+#[derive(Debug, Clone, Copy)]
+pub struct GroupDesc {
+    pub block_bitmap: u64,
+    pub inode_bitmap: u64,
+    pub inode_table: u64,
+    pub free_blocks: u32,
+    pub free_inodes: u32,
+    pub used_dirs: u32,
+    pub flags: u16,
+}
+
+impl GroupDesc {
+    /// desc holds group_desc_size bytes.
+    pub fn parse(sb: &SuperBlockInfo, desc: &[u8]) -> Self {
+        let lo = GroupDesc32::ref_from(desc).expect("the descriptor is 32 bytes");
+        let mut gd = Self {
+            block_bitmap: lo.block_bitmap_lo.get() as u64,
+            inode_bitmap: lo.inode_bitmap_lo.get() as u64,
+            inode_table: lo.inode_table_lo.get() as u64,
+            free_blocks: lo.free_blocks_count_lo.get() as u32,
+            free_inodes: lo.free_inodes_count_lo.get() as u32,
+            used_dirs: lo.used_dirs_count_lo.get() as u32,
+            flags: lo.flags.get(),
+        };
+        if sb.group_desc_size() >= size_of::<GroupDesc64>() {
+            let desc = GroupDesc64::ref_from(desc).expect("the descriptor is 64 bytes");
+            gd.block_bitmap |= (desc.block_bitmap_hi.get() as u64) << 32;
+            gd.inode_bitmap |= (desc.inode_bitmap_hi.get() as u64) << 32;
+            gd.inode_table |= (desc.inode_table_hi.get() as u64) << 32;
+            gd.free_blocks |= (desc.free_blocks_count_hi.get() as u32) << 16;
+            gd.free_inodes |= (desc.free_inodes_count_hi.get() as u32) << 16;
+            gd.used_dirs |= (desc.used_dirs_count_hi.get() as u32) << 16;
+        }
+        gd
+    }
+
+    /// Check the metadata of the group is in the filesystem, with flex_bg
+    /// it may be in any group.
+    pub fn validate(&self, sb: &SuperBlockInfo) -> Result<(), &'static str> {
+        let start = sb.first_data_block as u64;
+        let in_fs = |block: u64| block >= start && block < sb.blocks_count;
+        if !in_fs(self.block_bitmap) || !in_fs(self.inode_bitmap) {
+            return Err("bitmap out of range");
+        }
+        let table_blocks =
+            (sb.inodes_per_group as u64 * sb.inode_size as u64).div_ceil(sb.block_size() as u64);
+        if !in_fs(self.inode_table)
+            || self.inode_table.saturating_add(table_blocks) > sb.blocks_count
+        {
+            return Err("inode table out of range");
+        }
+        Ok(())
+    }
+}
+
+/// Test the bit of the block or inode bitmap.
+pub fn bitmap_test(bitmap: &[u8], bit: usize) -> bool {
+    bitmap[bit / 8] & (1 << (bit % 8)) != 0
+}
+
+/// Set or clear the bit of the block or inode bitmap.
+pub fn bitmap_set(bitmap: &mut [u8], bit: usize, value: bool) {
+    match value {
+        true => bitmap[bit / 8] |= 1 << (bit % 8),
+        false => bitmap[bit / 8] &= !(1 << (bit % 8)),
+    }
+}
+
+/// The fields of the on-disk inode used by the shim.
+#[derive(Debug, Clone)]
+pub struct InodeInfo {
+    pub mode: u16,
+    /// The owner and the group, with their high halves.
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub flags: u32,
+    pub links_count: u16,
+    /// The deletion time, the next inode of the orphan list while the
+    /// inode is on it.
+    pub dtime: u32,
+    pub generation: u32,
+    /// i_blocks, in 512 bytes sectors unless EXT4_HUGE_FILE_FL is set.
+    pub blocks: u64,
+    /// The block of the extended attributes, 0 if there is none.
+    pub file_acl: u64,
+    pub i_block: [u8; I_BLOCK_SIZE],
+    /// The value of the system.data xattr, the inline data after i_block.
+    pub inline_tail: Vec<u8>,
+    pub atime: Timestamp,
+    /// The last change of the inode.
+    pub ctime: Timestamp,
+    pub mtime: Timestamp,
+    /// The creation time, None in the small inodes.
+    pub crtime: Option<Timestamp>,
+}
+
+impl InodeInfo {
+    /// Parse the inode, data holds at least inode_size bytes.
+    pub fn parse(data: &[u8], inode_size: usize) -> Self {
+        let raw = Inode::ref_from(data).expect("the inode is 128 bytes");
+        let flags = raw.flags.get();
+        let inline_tail = match flags & EXT4_INLINE_DATA_FL {
+            0 => Vec::new(),
+            _ => find_system_data(&data[..inode_size.min(data.len())])
+                .map(|x| x.to_vec())
+                .unwrap_or_default(),
+        };
+        Self {
+            mode: raw.mode.get(),
+            uid: raw.uid_lo.get() as u32 | (raw.uid_hi.get() as u32) << 16,
+            gid: raw.gid_lo.get() as u32 | (raw.gid_hi.get() as u32) << 16,
+            size: lo_hi(raw.size_lo.get(), raw.size_hi.get()),
+            flags,
+            links_count: raw.links_count.get(),
+            dtime: raw.dtime.get(),
+            generation: raw.generation.get(),
+            blocks: lo_hi(raw.blocks_lo.get(), raw.blocks_hi.get() as u32),
+            file_acl: lo_hi(raw.file_acl_lo.get(), raw.file_acl_hi.get() as u32),
+            i_block: raw.block,
+            inline_tail,
+            atime: inode_time(data, inode_size, I_ATIME).unwrap_or_default(),
+            ctime: inode_time(data, inode_size, I_CTIME).unwrap_or_default(),
+            mtime: inode_time(data, inode_size, I_MTIME).unwrap_or_default(),
+            crtime: inode_time(data, inode_size, I_CRTIME),
+        }
+    }
+
+    pub fn uses_extents(&self) -> bool {
+        self.flags & EXT4_EXTENTS_FL != 0
+    }
+
+    pub fn has_inline_data(&self) -> bool {
+        self.flags & EXT4_INLINE_DATA_FL != 0
+    }
+
+    /// A FIFO, a socket or a device node, its i_block maps no blocks.
+    pub fn is_special(&self) -> bool {
+        matches!(self.mode & 0xF000, 0x1000 | 0x2000 | 0x6000 | 0xC000)
+    }
+
+    /// The major and the minor of a device node, in the old encoding of
+    /// i_block[0], or in the new one of i_block[1] when the old is 0.
+    pub fn device(&self) -> (u32, u32) {
+        let old = le_u32(&self.i_block, 0);
+        if old != 0 {
+            return ((old >> 8) & 0xff, old & 0xff);
+        }
+        let new = le_u32(&self.i_block, 4);
+        ((new & 0xfff00) >> 8, (new & 0xff) | ((new >> 12) & 0xfff00))
+    }
+
+    /// Get i_blocks in 512 bytes sectors like st_blocks, the blocks of
+    /// the huge files are converted.
+    pub fn sectors(&self, sb: &SuperBlockInfo) -> u64 {
+        match sb.feature_ro_compat & RO_COMPAT_HUGE_FILE != 0 && self.flags & EXT4_HUGE_FILE_FL != 0
+        {
+            true => self.blocks * (sb.block_size() / 512) as u64,
+            false => self.blocks,
+        }
+    }
+
+    /// Get the inline data, i_block then the system.data xattr, cut at
+    /// the file size.
+    pub fn inline_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(I_BLOCK_SIZE + self.inline_tail.len());
+        data.extend_from_slice(&self.i_block);
+        data.extend_from_slice(&self.inline_tail);
+        data.truncate(self.size as usize);
+        data
+    }
+}
+
+/// i_block[0] and i_block[1] of a device node, like ext4_mknod of Linux:
+/// the old encoding in i_block[0] when the major and the minor fit in 8
+/// bits, the new one in i_block[1] otherwise. None if they don't fit in
+/// the 12 and 20 bits of the new one.
+pub fn encode_device(major: u32, minor: u32) -> Option<[u32; 2]> {
+    if major < 0x100 && minor < 0x100 {
+        return Some([(major << 8) | minor, 0]);
+    }
+    if major > 0xfff || minor > 0xfffff {
+        return None;
+    }
+    Some([0, (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)])
+}
+
+/// Find the value of the system.data xattr in the inode body.
+/// inode: the whole on-disk inode, the xattrs are after i_extra_isize.
+fn find_system_data(inode: &[u8]) -> Option<&[u8]> {
+    if inode.len() <= I_EXTRA_ISIZE + 2 {
+        return None;
+    }
+    let start = GOOD_OLD_INODE_SIZE + le_u16(inode, I_EXTRA_ISIZE) as usize;
+    let header = XattrIbodyHeader::ref_from(inode.get(start..)?)?;
+    if header.magic.get() != XATTR_MAGIC {
+        return None;
+    }
+    // the value offsets are relative to the first entry.
+    let entries = &inode[start + size_of::<XattrIbodyHeader>()..];
+    let mut pos = 0;
+    // an entry is XattrEntry then the name, padded to 4 bytes.
+    while let Some(entry) = entries.get(pos..).and_then(XattrEntry::ref_from) {
+        if le_u32(entries, pos) == 0 {
+            break;
+        }
+        let name_len = entry.name_len as usize;
+        let value_offs = entry.value_offs.get() as usize;
+        let value_size = entry.value_size.get() as usize;
+        let name_start = pos + size_of::<XattrEntry>();
+        let name = entries.get(name_start..name_start + name_len)?;
+        if entry.name_index == XATTR_INDEX_SYSTEM && name == b"data" {
+            return entries.get(value_offs..value_offs + value_size);
+        }
+        pos = (name_start + name_len).next_multiple_of(4);
+    }
+    None
+}
+
+/// Drop the system.data xattr from the inode body, once its inline data
+/// moved to a block. The entries after it move up over it and its value
+/// is zeroed, the values of the others stay where they are. return
+/// whether it was found.
+pub fn remove_system_data(inode: &mut [u8]) -> bool {
+    if inode.len() <= I_EXTRA_ISIZE + 2 {
+        return false;
+    }
+    let start = GOOD_OLD_INODE_SIZE + le_u16(inode, I_EXTRA_ISIZE) as usize;
+    let base = start + size_of::<XattrIbodyHeader>();
+    if inode.len() < base || le_u32(inode, start) != XATTR_MAGIC {
+        return false;
+    }
+    let entries = &mut inode[base..];
+    // the entry, the start of the next one and the value.
+    let mut found = None;
+    let mut pos = 0;
+    while let Some(entry) = entries.get(pos..).and_then(XattrEntry::ref_from) {
+        if le_u32(entries, pos) == 0 {
+            break;
+        }
+        let (name_len, name_index) = (entry.name_len as usize, entry.name_index);
+        let value = (
+            entry.value_offs.get() as usize,
+            entry.value_size.get() as usize,
+        );
+        let name_start = pos + size_of::<XattrEntry>();
+        let next = (name_start + name_len).next_multiple_of(4);
+        let name = entries.get(name_start..name_start + name_len);
+        if name_index == XATTR_INDEX_SYSTEM && name == Some(&b"data"[..]) {
+            found = Some((pos, next, value));
+        }
+        pos = next;
+    }
+    let Some((at, next, (offs, size))) = found else {
+        return false;
+    };
+    let end = pos.min(entries.len());
+    if let Some(value) = entries.get_mut(offs..offs + size.next_multiple_of(4)) {
+        value.fill(0);
+    }
+    entries.copy_within(next..end, at);
+    entries[end - (next - at)..end].fill(0);
+    true
+}
+
+/// A mapping from the logical blocks of a file to the physical blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub logical: u32,
+    pub len: u32,
+    pub physical: u64,
+    /// The extent is preallocated, it reads as zeros.
+    pub uninit: bool,
+}
+
+impl Extent {
+    pub fn contains(&self, lblock: u32) -> bool {
+        lblock >= self.logical && lblock - self.logical < self.len
+    }
+
+    /// Decode the 12 bytes entry of a leaf node.
+    pub fn decode(entry: &[u8]) -> Self {
+        Self::from_leaf(ExtentLeaf::ref_from(entry).expect("the entry is 12 bytes"))
+    }
+
+    fn from_leaf(leaf: &ExtentLeaf) -> Self {
+        let len = leaf.len.get();
+        let (len, uninit) = match len > EXT_INIT_MAX_LEN {
+            true => (len - EXT_INIT_MAX_LEN, true),
+            false => (len, false),
+        };
+        Self {
+            logical: leaf.block.get(),
+            len: len as u32,
+            physical: leaf.start(),
+            uninit,
+        }
+    }
+
+    /// Encode the extent into the 12 bytes entry of a leaf node.
+    pub fn encode(&self, entry: &mut [u8]) {
+        let len = match self.uninit {
+            true => self.len as u16 + EXT_INIT_MAX_LEN,
+            false => self.len as u16,
+        };
+        let leaf = ExtentLeaf::mut_from(entry).expect("the entry is 12 bytes");
+        leaf.block.set(self.logical);
+        leaf.len.set(len);
+        leaf.start_hi.set((self.physical >> 32) as u16);
+        leaf.start_lo.set(self.physical as u32);
+    }
+}
+
+/// The header of an extent tree node.
+#[derive(Debug, Clone, Copy)]
+pub struct ExtentHeader {
+    pub entries: u16,
+    pub max: u16,
+    pub depth: u16,
+}
+
+impl ExtentHeader {
+    /// Parse and validate the header of the node, the entries must fit in
+    /// the node (60 bytes for the root in the inode).
+    pub fn parse(node: &[u8]) -> VfsResult<Self> {
+        let raw = disk::ExtentHeader::ref_from(node).ok_or(VfsError::InvalidData)?;
+        if raw.magic.get() != EXTENT_MAGIC {
+            return Err(VfsError::InvalidData);
+        }
+        let header = Self {
+            entries: raw.entries.get(),
+            max: raw.max.get(),
+            depth: raw.depth.get(),
+        };
+        if header.entries > header.max || EXTENT_ENTRY * (header.max as usize + 1) > node.len() {
+            return Err(VfsError::InvalidData);
+        }
+        Ok(header)
+    }
+}
+
+/// The size of the header and of the entries of an extent tree node.
+pub const EXTENT_ENTRY: usize = size_of::<disk::ExtentHeader>();
+const _: () =
+    assert!(size_of::<ExtentIdx>() == EXTENT_ENTRY && size_of::<ExtentLeaf>() == EXTENT_ENTRY);
+
+/// The max depth of the extent tree, ext4 limits it to 5.
+pub const EXTENT_MAX_DEPTH: u16 = 5;
+
+/// Walk the extent tree whose root is i_block, read_block reads the
+/// index and leaf nodes from the disk. The extents are sorted by the
+/// logical block.
+pub fn walk_extents(
+    i_block: &[u8],
+    read_block: impl FnMut(u64) -> VfsResult<Vec<u8>>,
+) -> VfsResult<Vec<Extent>> {
+    walk_extent_tree(i_block, read_block, |_| {})
+}
+
+/// Like walk_extents, visit_node is called with the block of every index
+/// and leaf node below the root.
+/// A corrupted tree fails with InvalidData: a node is referenced twice, or
+/// the extents overlap, aren't sorted or are empty. So the walk reads every
+/// node at most once and the extents are valid for ExtentCache.
+pub fn walk_extent_tree(
+    i_block: &[u8],
+    mut read_block: impl FnMut(u64) -> VfsResult<Vec<u8>>,
+    mut visit_node: impl FnMut(u64),
+) -> VfsResult<Vec<Extent>> {
+    let mut extents = Vec::new();
+    let root = ExtentHeader::parse(i_block)?;
+    if root.depth > EXTENT_MAX_DEPTH {
+        return Err(VfsError::InvalidData);
+    }
+    // (node, depth expected), nodes are visited in order.
+    let mut stack: Vec<(Vec<u8>, u16)> = vec![(i_block.to_vec(), root.depth)];
+    let mut visited = BTreeSet::new();
+    // the end of the last extent, the next one must start at or after it.
+    let mut end = 0u64;
+    while let Some((node, depth)) = stack.pop() {
+        let header = ExtentHeader::parse(&node)?;
+        if header.depth != depth {
+            return Err(VfsError::InvalidData);
+        }
+        let entries = (1..=header.entries as usize).map(|i| &node[i * EXTENT_ENTRY..]);
+        if depth == 0 {
+            for entry in entries {
+                let extent = Extent::decode(entry);
+                if extent.len == 0 || (extent.logical as u64) < end {
+                    return Err(VfsError::InvalidData);
+                }
+                // logical + len must not overflow the u32 logical blocks.
+                end = extent.logical as u64 + extent.len as u64;
+                if end > u32::MAX as u64 {
+                    return Err(VfsError::InvalidData);
+                }
+                extents.push(extent);
+            }
+        } else {
+            // push the children reversed, so the first child is visited first.
+            let children: Vec<u64> = entries
+                .map(|entry| {
+                    ExtentIdx::ref_from(entry)
+                        .expect("the entry is 12 bytes")
+                        .leaf()
+                })
+                .collect();
+            for block in children.into_iter().rev() {
+                if !visited.insert(block) {
+                    return Err(VfsError::InvalidData);
+                }
+                visit_node(block);
+                stack.push((read_block(block)?, depth - 1));
+            }
+        }
+    }
+    Ok(extents)
+}
+
+/// ExtentCache caches the extents of a file which were already resolved.
+/// complete: the extents are the whole extent tree, a logical block
+/// which isn't covered is a hole.
+#[derive(Debug, Default)]
+pub struct ExtentCache {
+    extents: Vec<Extent>,
+    complete: bool,
+}
+
+impl ExtentCache {
+    pub const fn new() -> Self {
+        Self {
+            extents: Vec::new(),
+            complete: false,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Replace the cache with the whole extent tree.
+    pub fn fill(&mut self, extents: Vec<Extent>) {
+        self.extents = extents;
+        self.complete = true;
+    }
+
+    /// Find the extent covering lblock, the extents are sorted.
+    pub fn lookup(&self, lblock: u32) -> Option<Extent> {
+        let index = self.extents.partition_point(|x| x.logical <= lblock);
+        index
+            .checked_sub(1)
+            .map(|x| self.extents[x])
+            .filter(|x| x.contains(lblock))
+    }
+
+    /// Drop the extents overlapping [start, end] after the blocks were
+    /// allocated, freed or punched.
+    pub fn invalidate(&mut self, start: u32, end: u32) {
+        self.extents
+            .retain(|x| x.logical > end || x.logical + x.len <= start);
+        // a new allocation may fill a hole in the range.
+        self.complete = false;
+    }
+
+    pub fn clear(&mut self) {
+        self.extents.clear();
+        self.complete = false;
+    }
+}
+
+/// The directory is indexed by the hash tree.
+pub const EXT4_INDEX_FL: u32 = 0x1000;
+/// The compat feature of the hash tree index, the directories which grow
+/// past a block are indexed.
+pub const COMPAT_DIR_INDEX: u32 = 0x20;
+/// The directory entries' file_type byte is valid.
+pub const INCOMPAT_FILETYPE: u32 = 0x2;
+
+/// The fixed part of a directory entry, DirEntry.
+pub const DIRENT_HEADER: usize = size_of::<DirEntry>();
+
+/// A directory entry in a directory block.
+#[derive(Debug, Clone, Copy)]
+pub struct Dirent<'a> {
+    /// The offset of the entry in the block.
+    pub offset: usize,
+    pub inode: u32,
+    pub rec_len: u16,
+    pub file_type: u8,
+    pub name: &'a [u8],
+}
+
+/// Iterate the entries in a directory block, the unused entries (inode 0)
+/// are skipped. The iteration stops with an error if an entry is malformed.
+pub struct DirentIter<'a> {
+    block: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> DirentIter<'a> {
+    pub fn new(block: &'a [u8]) -> Self {
+        Self { block, offset: 0 }
+    }
+}
+
+impl<'a> Iterator for DirentIter<'a> {
+    type Item = VfsResult<Dirent<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(entry) = DirEntry::ref_from(&self.block[self.offset..]) {
+            let offset = self.offset;
+            let data = &self.block[offset..];
+            let rec_len = entry.rec_len.get();
+            let name_len = entry.name_len as usize;
+            // rec_len must move forward and the name must fit in the record.
+            if (rec_len as usize) < DIRENT_HEADER + name_len
+                || rec_len % 4 != 0
+                || rec_len as usize > data.len()
+            {
+                self.offset = self.block.len();
+                return Some(Err(VfsError::InvalidData));
+            }
+            self.offset += rec_len as usize;
+            let inode = entry.inode.get();
+            if inode == 0 {
+                continue;
+            }
+            return Some(Ok(Dirent {
+                offset,
+                inode,
+                rec_len,
+                file_type: entry.file_type,
+                name: &data[DIRENT_HEADER..DIRENT_HEADER + name_len],
+            }));
+        }
+        None
+    }
+}
+
+/// The length of the entry of a name, rounded up to 4 bytes.
+pub const fn dirent_len(name_len: usize) -> usize {
+    (DIRENT_HEADER + name_len).next_multiple_of(4)
+}
+
+fn put_dirent(block: &mut [u8], offset: usize, rec_len: usize, entry: (u32, u8, &[u8])) {
+    let (inode, file_type, name) = entry;
+    let header = DirEntry::mut_from(&mut block[offset..]).expect("the entry is in the block");
+    header.inode.set(inode);
+    header.rec_len.set(rec_len as u16);
+    header.name_len = name.len() as u8;
+    header.file_type = file_type;
+    block[offset + DIRENT_HEADER..offset + DIRENT_HEADER + name.len()].copy_from_slice(name);
+}
+
+/// Write the entries (inode, file_type, name) packed at the start of the
+/// directory block, the last one takes the rest up to end. Without
+/// entries the block holds one unused entry. The bytes from end, the
+/// checksum tail, are kept.
+pub fn write_dirents(block: &mut [u8], end: usize, entries: &[(u32, u8, &[u8])]) {
+    block[..end].fill(0);
+    let mut offset = 0;
+    for (i, entry) in entries.iter().enumerate() {
+        let rec_len = match i + 1 == entries.len() {
+            true => end - offset,
+            false => dirent_len(entry.2.len()),
+        };
+        put_dirent(block, offset, rec_len, *entry);
+        offset += rec_len;
+    }
+    if entries.is_empty() {
+        put_dirent(block, 0, end, (0, 0, &[]));
+    }
+}
+
+/// Insert the entry (inode, file_type, name) into the directory block
+/// whose entries end at end. It takes the unused space before the first
+/// entry, or the slack after an entry, which is cut to its length.
+/// return false if there is no space for it.
+pub fn insert_dirent(block: &mut [u8], end: usize, entry: (u32, u8, &[u8])) -> VfsResult<bool> {
+    let need = dirent_len(entry.2.len());
+    let used: Vec<(usize, usize, usize)> = DirentIter::new(&block[..end])
+        .map(|x| x.map(|x| (x.offset, x.rec_len as usize, dirent_len(x.name.len()))))
+        .collect::<VfsResult<_>>()?;
+    let first = used.first().map_or(end, |x| x.0);
+    if first >= need {
+        put_dirent(block, 0, first, entry);
+        return Ok(true);
+    }
+    for (offset, rec_len, len) in used {
+        if rec_len - len >= need {
+            block[offset + 4..offset + 6].copy_from_slice(&(len as u16).to_le_bytes());
+            put_dirent(block, offset + len, rec_len - len, entry);
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
@@ -0,0 +1,532 @@
+// Format a device with a minimal ext4, for the tests building the images
+// in memory: the superblock and its backups, the group descriptors, the
+// bitmaps and inode tables, the root directory and lost+found.
+// The features are limited to what the ext4_rs shim supports: extents,
+// filetype, sparse_super, large_file, dir_nlink, extra_isize and
+// optionally metadata_csum, 64bit, dir_index, a journal and the lazy
+// initialization of the groups. There is no flex_bg and no resize inode,
+// every group holds its own metadata. The journal is an empty JBD2 log of the journal inode, without checksums.
+// Like ext4_layout, the image is built in byte slices, the caller passes
+// the blocks to the device.
+
+use alloc::vec::Vec;
+use vfscore::{VfsError, VfsResult};
+
+use crate::ext4_csum::{
+    init_dirent_tail, inode_seed, set_bitmap_csums, set_dir_block_csum, set_group_desc_csum,
+    set_inode_csum, set_superblock_csum, DIRENT_TAIL_SIZE,
+};
+use crate::ext4_journal::JBD2_MAGIC;
+use crate::ext4_layout::{
+    bitmap_set, Extent, SuperBlockInfo, BG_BLOCK_UNINIT, BG_INODE_UNINIT, COMPAT_DIR_INDEX,
+    COMPAT_HAS_JOURNAL, EXT4_EXTENTS_FL, EXT4_SUPER_MAGIC, EXTENT_MAGIC, INCOMPAT_64BIT,
+    INCOMPAT_FILETYPE, ROOT_INO, RO_COMPAT_DIR_NLINK, RO_COMPAT_EXTRA_ISIZE, RO_COMPAT_LARGE_FILE,
+    RO_COMPAT_METADATA_CSUM, RO_COMPAT_SPARSE_SUPER, SUPERBLOCK_OFFSET,
+};
+use crate::sys::get_blk_device;
+
+const SECTOR_SIZE: usize = 512;
+/// The first inode which isn't reserved, it's lost+found.
+const FIRST_INO: u32 = 11;
+const LOST_FOUND_INO: u32 = FIRST_INO;
+const JOURNAL_INO: u32 = 8;
+const INCOMPAT_EXTENTS: u32 = 0x40;
+/// The size of the group descriptors with the 64bit feature, it's 32
+/// without it.
+const DESC_SIZE_64BIT: u16 = 64;
+/// i_extra_isize of the large inodes, up to i_crtime_extra.
+const EXTRA_ISIZE: u16 = 32;
+const S_IFDIR: u16 = 0x4000;
+const S_IFREG: u16 = 0x8000;
+/// The longest initialized extent.
+const MAX_EXTENT_LEN: u32 = 32768;
+/// h_blocktype of the JBD2 superblock v2.
+const JBD2_SUPERBLOCK_V2: u32 = 4;
+/// The 64bit feature of the journal, the block tags have the hi halves.
+const JBD2_INCOMPAT_64BIT: u32 = 0x2;
+/// The file_type of a directory in the directory entries.
+const FT_DIR: u8 = 2;
+
+/// The options of the format.
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// 1024, 2048 or 4096.
+    pub block_size: usize,
+    /// 128, or a power of 2 from 256 to the block size.
+    pub inode_size: u16,
+    /// The bytes of the filesystem per inode.
+    pub bytes_per_inode: u64,
+    pub metadata_csum: bool,
+    /// The group descriptors of 64 bytes with the hi halves of the block
+    /// numbers and the counters, like mke2fs -O 64bit.
+    pub bit64: bool,
+    /// The hash tree index of the directories which outgrow a block,
+    /// like mke2fs -O dir_index.
+    pub dir_index: bool,
+    /// The file type in the directory entries, like mke2fs -O filetype.
+    /// Without it the entries are those of the old ext2 images, their
+    /// type byte is 0.
+    pub filetype: bool,
+    pub uuid: [u8; 16],
+    /// s_volume_name, like mke2fs -L, at most 16 bytes, empty is none.
+    pub label: &'static str,
+    /// The blocks of the journal, like mke2fs -J size, 0 is no journal.
+    /// They follow lost+found in group 0.
+    pub journal_blocks: u32,
+    /// The creation time of the filesystem and its inodes, there is no
+    /// clock.
+    pub time: u32,
+    /// Leave the groups besides group 0 uninitialized, like mke2fs -E
+    /// lazy_itable_init: they are flagged INODE_UNINIT and BLOCK_UNINIT,
+    /// their bitmaps and inode tables aren't written and bg_itable_unused
+    /// marks the whole table unused. Group 0 has only the blocks of its
+    /// used inodes written. It needs metadata_csum.
+    pub lazy_itable_init: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            block_size: 4096,
+            inode_size: 256,
+            bytes_per_inode: 16384,
+            metadata_csum: true,
+            bit64: false,
+            dir_index: false,
+            filetype: true,
+            uuid: *b"Byte-OS ext4mkfs",
+            label: "",
+            journal_blocks: 0,
+            time: 0,
+            lazy_itable_init: false,
+        }
+    }
+}
+
+/// The geometry of the formatted filesystem.
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    pub blocks_count: u64,
+    pub groups: usize,
+    pub inodes_per_group: u32,
+    pub free_blocks: u64,
+    pub free_inodes: u32,
+}
+
+/// The blocks of the metadata of a group.
+struct Group {
+    start: u64,
+    blocks: u64,
+    block_bitmap: u64,
+    inode_bitmap: u64,
+    inode_table: u64,
+    /// The first block after the metadata.
+    data: u64,
+}
+
+/// Format the device with the filesystem filling size bytes of it.
+pub fn format_device(device_id: usize, size: u64, options: &Options) -> VfsResult<Layout> {
+    let device = get_blk_device(device_id).ok_or(VfsError::InvalidInput)?;
+    format(size, options, |block, data| {
+        device.write_blocks(block as usize * options.block_size / SECTOR_SIZE, data)
+    })
+}
+
+/// Build the filesystem filling size bytes, write(block, data) stores the
+/// whole blocks from the block. The blocks which aren't written are free,
+/// their content doesn't matter.
+pub fn format(
+    size: u64,
+    options: &Options,
+    mut write: impl FnMut(u64, &[u8]),
+) -> VfsResult<Layout> {
+    let bs = options.block_size;
+    let inode_size = options.inode_size as usize;
+    if !matches!(bs, 1024 | 2048 | 4096)
+        || !(inode_size == 128 || inode_size.is_power_of_two() && (256..=bs).contains(&inode_size))
+        || options.bytes_per_inode < bs as u64
+        || options.journal_blocks == 1
+        || options.journal_blocks > MAX_EXTENT_LEN
+        || options.lazy_itable_init && !options.metadata_csum
+        || options.label.len() > 16
+    {
+        return Err(VfsError::InvalidInput);
+    }
+    let (mut sb_raw, groups) = geometry(size / bs as u64, options)?;
+    let sb = SuperBlockInfo::parse(&sb_raw);
+    let ipg = sb.inodes_per_group as usize;
+    let csum = options.metadata_csum;
+    let desc_size = sb.group_desc_size();
+
+    // the root directory, lost+found and the journal are the first blocks
+    // after the metadata of group 0.
+    let root_block = groups[0].data;
+    let lost_found_block = root_block + 1;
+    let journal_block = root_block + 2;
+    let journal_blocks = options.journal_blocks as u64;
+    if journal_block + journal_blocks > groups[0].start + groups[0].blocks {
+        return Err(VfsError::InvalidInput);
+    }
+    let mut descs = vec![0u8; sb.group_desc_blocks() * bs];
+    let (mut free_blocks, mut free_inodes) = (0u64, 0u32);
+    let mut bitmaps = Vec::with_capacity(groups.len());
+    for (i, group) in groups.iter().enumerate() {
+        let used = match i {
+            0 => group.data + 2 + journal_blocks - group.start,
+            _ => group.data - group.start,
+        };
+        // the bits beyond the blocks and the inodes of the group are set.
+        let mut block_bitmap = vec![0u8; bs];
+        for bit in (0..used).chain(group.blocks..8 * bs as u64) {
+            bitmap_set(&mut block_bitmap, bit as usize, true);
+        }
+        let used_inodes = if i == 0 { FIRST_INO as usize } else { 0 };
+        let mut inode_bitmap = vec![0u8; bs];
+        for bit in (0..used_inodes).chain(ipg..8 * bs) {
+            bitmap_set(&mut inode_bitmap, bit, true);
+        }
+
+        let desc = &mut descs[i * desc_size..(i + 1) * desc_size];
+        let free = (group.blocks - used) as u32;
+        let free_inodes = (ipg - used_inodes) as u32;
+        put_u32(desc, 0x0, group.block_bitmap as u32);
+        put_u32(desc, 0x4, group.inode_bitmap as u32);
+        put_u32(desc, 0x8, group.inode_table as u32);
+        put_u16(desc, 0xC, free as u16);
+        put_u16(desc, 0xE, free_inodes as u16);
+        put_u16(desc, 0x10, if i == 0 { 2 } else { 0 });
+        if options.lazy_itable_init {
+            // the tables aren't zeroed, not even the one of group 0.
+            if i > 0 {
+                put_u16(desc, 0x12, BG_INODE_UNINIT | BG_BLOCK_UNINIT);
+            }
+            put_u16(desc, 0x1C, (ipg - used_inodes) as u16);
+        }
+        if desc_size >= 64 {
+            put_u32(desc, 0x20, (group.block_bitmap >> 32) as u32);
+            put_u32(desc, 0x24, (group.inode_bitmap >> 32) as u32);
+            put_u32(desc, 0x28, (group.inode_table >> 32) as u32);
+            put_u16(desc, 0x2C, (free >> 16) as u16);
+            put_u16(desc, 0x2E, (free_inodes >> 16) as u16);
+            if options.lazy_itable_init {
+                put_u16(desc, 0x32, ((ipg - used_inodes) >> 16) as u16);
+            }
+        }
+        if csum {
+            set_bitmap_csums(&sb, desc, &block_bitmap, &inode_bitmap);
+            set_group_desc_csum(&sb, i as u32, desc);
+        }
+        free_blocks += group.blocks - used;
+        free_inodes += (ipg - used_inodes) as u32;
+        bitmaps.push((block_bitmap, inode_bitmap));
+    }
+    put_u32(&mut sb_raw, 0xC, free_blocks as u32);
+    if options.bit64 {
+        put_u32(&mut sb_raw, 0x158, (free_blocks >> 32) as u32);
+    }
+    put_u32(&mut sb_raw, 0x10, free_inodes);
+
+    for (i, (group, (block_bitmap, inode_bitmap))) in groups.iter().zip(bitmaps).enumerate() {
+        if sb.group_has_super(i) {
+            // the superblock is at byte 1024 of the disk, the backups are
+            // at the start of their groups.
+            let mut block = vec![0u8; bs];
+            let offset = match (i, bs) {
+                (0, 1024) | (1.., _) => 0,
+                (0, _) => SUPERBLOCK_OFFSET,
+            };
+            let copy = &mut block[offset..offset + 1024];
+            copy.copy_from_slice(&sb_raw);
+            put_u16(copy, 0x5A, i as u16);
+            if csum {
+                set_superblock_csum(copy);
+            }
+            write(group.start, &block);
+            write(group.start + 1, &descs);
+        }
+        if options.lazy_itable_init && i > 0 {
+            continue;
+        }
+        write(group.block_bitmap, &block_bitmap);
+        write(group.inode_bitmap, &inode_bitmap);
+        // the blocks of the used inodes of a lazy table.
+        let table_blocks = match options.lazy_itable_init {
+            true => (FIRST_INO as usize * inode_size).div_ceil(bs),
+            false => (group.data - group.inode_table) as usize,
+        };
+        let mut table = vec![0u8; table_blocks * bs];
+        if i == 0 {
+            let blocks = [
+                (ROOT_INO, 3, 0o755, root_block),
+                (LOST_FOUND_INO, 2, 0o700, lost_found_block),
+            ];
+            for (ino, links, perm, block) in blocks {
+                let raw = &mut table[(ino as usize - 1) * inode_size..ino as usize * inode_size];
+                init_dir_inode(options, raw, links, perm, block);
+                if csum {
+                    set_inode_csum(&sb, ino, raw);
+                }
+            }
+            if journal_blocks > 0 {
+                let ino = JOURNAL_INO as usize;
+                let raw = &mut table[(ino - 1) * inode_size..ino * inode_size];
+                let len = options.journal_blocks;
+                init_inode(options, raw, S_IFREG | 0o600, 1, journal_block, len);
+                if csum {
+                    set_inode_csum(&sb, JOURNAL_INO, raw);
+                }
+            }
+        }
+        write(group.inode_table, &table);
+    }
+
+    let entries: [&[(u32, &[u8])]; 2] = [
+        &[
+            (ROOT_INO, b"."),
+            (ROOT_INO, b".."),
+            (LOST_FOUND_INO, b"lost+found"),
+        ],
+        &[(LOST_FOUND_INO, b"."), (ROOT_INO, b"..")],
+    ];
+    for ((ino, block), entries) in [(ROOT_INO, root_block), (LOST_FOUND_INO, lost_found_block)]
+        .into_iter()
+        .zip(entries)
+    {
+        let file_type = if options.filetype { FT_DIR } else { 0 };
+        let seed = csum.then(|| inode_seed(&sb, ino, 0));
+        let data = dir_block(bs, entries, file_type, seed);
+        write(block, &data);
+    }
+    if journal_blocks > 0 {
+        // the log is zeroed, so no stale block passes for a transaction.
+        let mut log = vec![0u8; journal_blocks as usize * bs];
+        journal_superblock(options, &mut log[..bs]);
+        write(journal_block, &log);
+    }
+
+    Ok(Layout {
+        blocks_count: sb.blocks_count,
+        groups: groups.len(),
+        inodes_per_group: sb.inodes_per_group,
+        free_blocks,
+        free_inodes,
+    })
+}
+
+/// Fit the groups in the blocks. The last group is dropped if its
+/// metadata doesn't fit, the inodes are spread over the groups.
+/// return the superblock without the counters and the groups.
+fn geometry(mut blocks_count: u64, options: &Options) -> VfsResult<(Vec<u8>, Vec<Group>)> {
+    let bs = options.block_size as u64;
+    let first_data_block = (bs == 1024) as u64;
+    let bpg = 8 * bs;
+    let per_block = bs / options.inode_size as u64;
+    blocks_count = blocks_count.min(u32::MAX as u64);
+    loop {
+        if blocks_count <= first_data_block {
+            return Err(VfsError::InvalidInput);
+        }
+        let groups_count = (blocks_count - first_data_block).div_ceil(bpg);
+        // the inode tables fill whole blocks and the bitmap fills bytes.
+        let align = per_block.max(8);
+        let inodes = blocks_count * bs / options.bytes_per_inode;
+        let ipg = inodes
+            .div_ceil(groups_count)
+            .max(2 * FIRST_INO as u64)
+            .next_multiple_of(align)
+            .min(8 * bs);
+        let sb_raw = superblock(options, blocks_count, ipg as u32, groups_count as u32);
+        let sb = SuperBlockInfo::parse(&sb_raw);
+        let inode_table_blocks = ipg / per_block;
+        let mut groups = Vec::new();
+        for i in 0..groups_count {
+            let start = first_data_block + i * bpg;
+            let mut next = start;
+            if sb.group_has_super(i as usize) {
+                next += 1 + sb.group_desc_blocks() as u64;
+            }
+            groups.push(Group {
+                start,
+                blocks: bpg.min(blocks_count - start),
+                block_bitmap: next,
+                inode_bitmap: next + 1,
+                inode_table: next + 2,
+                data: next + 2 + inode_table_blocks,
+            });
+        }
+        let last = groups.last().unwrap();
+        // group 0 holds the blocks of the root directory and lost+found.
+        let needed = last.data - last.start + if groups.len() == 1 { 2 } else { 1 };
+        if last.blocks >= needed {
+            return Ok((sb_raw, groups));
+        }
+        if groups.len() == 1 {
+            return Err(VfsError::InvalidInput);
+        }
+        blocks_count = last.start;
+    }
+}
+
+/// The superblock of the geometry, the free counters are left to 0.
+fn superblock(options: &Options, blocks_count: u64, ipg: u32, groups: u32) -> Vec<u8> {
+    let bs = options.block_size;
+    let mut sb = vec![0u8; 1024];
+    let mut ro_compat = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE | RO_COMPAT_DIR_NLINK;
+    if options.inode_size > 128 {
+        ro_compat |= RO_COMPAT_EXTRA_ISIZE;
+        put_u16(&mut sb, 0x15C, EXTRA_ISIZE);
+        put_u16(&mut sb, 0x15E, EXTRA_ISIZE);
+    }
+    if options.metadata_csum {
+        ro_compat |= RO_COMPAT_METADATA_CSUM;
+        // crc32c
+        sb[0x175] = 1;
+    }
+    let log_block_size = (bs / 1024).trailing_zeros();
+    put_u32(&mut sb, 0x0, ipg * groups);
+    put_u32(&mut sb, 0x4, blocks_count as u32);
+    put_u32(&mut sb, 0x14, (bs == 1024) as u32);
+    put_u32(&mut sb, 0x18, log_block_size);
+    put_u32(&mut sb, 0x1C, log_block_size);
+    put_u32(&mut sb, 0x20, 8 * bs as u32);
+    put_u32(&mut sb, 0x24, 8 * bs as u32);
+    put_u32(&mut sb, 0x28, ipg);
+    put_u32(&mut sb, 0x30, options.time);
+    put_u16(&mut sb, 0x36, 0xFFFF);
+    put_u16(&mut sb, 0x38, EXT4_SUPER_MAGIC);
+    // clean, continue on errors.
+    put_u16(&mut sb, 0x3A, 1);
+    put_u16(&mut sb, 0x3C, 1);
+    put_u32(&mut sb, 0x40, options.time);
+    // the dynamic revision with the variable inode size.
+    put_u32(&mut sb, 0x4C, 1);
+    put_u32(&mut sb, 0x54, FIRST_INO);
+    put_u16(&mut sb, 0x58, options.inode_size);
+    let mut incompat = INCOMPAT_EXTENTS;
+    if options.filetype {
+        incompat |= INCOMPAT_FILETYPE;
+    }
+    if options.bit64 {
+        incompat |= INCOMPAT_64BIT;
+        put_u32(&mut sb, 0x150, (blocks_count >> 32) as u32);
+        put_u16(&mut sb, 0xFE, DESC_SIZE_64BIT);
+    }
+    let mut compat = 0;
+    if options.dir_index {
+        compat |= COMPAT_DIR_INDEX;
+    }
+    if options.journal_blocks > 0 {
+        compat |= COMPAT_HAS_JOURNAL;
+        put_u32(&mut sb, 0xE0, JOURNAL_INO);
+    }
+    put_u32(&mut sb, 0x5C, compat);
+    put_u32(&mut sb, 0x60, incompat);
+    put_u32(&mut sb, 0x64, ro_compat);
+    sb[0x68..0x78].copy_from_slice(&options.uuid);
+    sb[0x78..0x78 + options.label.len()].copy_from_slice(options.label.as_bytes());
+    // the hash seed of the htree, derived from the uuid.
+    for (i, x) in options.uuid.iter().enumerate() {
+        sb[0xEC + i] = x.rotate_left(4);
+    }
+    // half_md4, the htree is only used with dir_index.
+    sb[0xFC] = 1;
+    put_u32(&mut sb, 0x108, options.time);
+    // signed directory hash.
+    put_u32(&mut sb, 0x160, 1);
+    sb
+}
+
+/// Fill the inode of an empty directory stored in one block.
+fn init_dir_inode(options: &Options, raw: &mut [u8], links: u16, perm: u16, block: u64) {
+    init_inode(options, raw, S_IFDIR | perm, links, block, 1);
+}
+
+/// Fill the inode of the len blocks from block, mapped by one extent.
+fn init_inode(options: &Options, raw: &mut [u8], mode: u16, links: u16, block: u64, len: u32) {
+    let bs = options.block_size;
+    let size = len as u64 * bs as u64;
+    put_u16(raw, 0x0, mode);
+    put_u32(raw, 0x4, size as u32);
+    put_u32(raw, 0x6C, (size >> 32) as u32);
+    for time in [0x8, 0xC, 0x10] {
+        put_u32(raw, time, options.time);
+    }
+    put_u16(raw, 0x1A, links);
+    put_u32(raw, 0x1C, (size / SECTOR_SIZE as u64) as u32);
+    put_u32(raw, 0x20, EXT4_EXTENTS_FL);
+    // the extent tree in i_block: the header and one extent.
+    let i_block = &mut raw[0x28..0x28 + 60];
+    put_u16(i_block, 0, EXTENT_MAGIC);
+    put_u16(i_block, 2, 1);
+    put_u16(i_block, 4, 4);
+    let extent = Extent {
+        logical: 0,
+        len,
+        physical: block,
+        uninit: false,
+    };
+    extent.encode(&mut i_block[12..24]);
+    if options.inode_size > 128 {
+        put_u16(raw, 0x80, EXTRA_ISIZE);
+        put_u32(raw, 0x90, options.time);
+    }
+}
+
+/// Fill the JBD2 superblock of an empty journal, the first transaction
+/// is sequence 1.
+fn journal_superblock(options: &Options, block: &mut [u8]) {
+    let put = |block: &mut [u8], offset: usize, value: u32| {
+        block[offset..offset + 4].copy_from_slice(&value.to_be_bytes())
+    };
+    put(block, 0x0, JBD2_MAGIC);
+    put(block, 0x4, JBD2_SUPERBLOCK_V2);
+    put(block, 0xC, options.block_size as u32);
+    put(block, 0x10, options.journal_blocks);
+    // the log starts after the superblock.
+    put(block, 0x14, 1);
+    put(block, 0x18, 1);
+    if options.bit64 {
+        put(block, 0x28, JBD2_INCOMPAT_64BIT);
+    }
+    block[0x30..0x40].copy_from_slice(&options.uuid);
+    // one user, the filesystem of the uuid.
+    put(block, 0x40, 1);
+    block[0x100..0x110].copy_from_slice(&options.uuid);
+}
+
+/// Build a directory block with the entries of the file_type, the last one
+/// takes the rest of the block. seed: the checksum seed of the directory
+/// with metadata_csum, the block ends with the checksum entry.
+fn dir_block(bs: usize, entries: &[(u32, &[u8])], file_type: u8, seed: Option<u32>) -> Vec<u8> {
+    let mut block = vec![0u8; bs];
+    let end = match seed {
+        Some(_) => bs - DIRENT_TAIL_SIZE,
+        None => bs,
+    };
+    let mut offset = 0;
+    for (i, (ino, name)) in entries.iter().enumerate() {
+        let rec_len = match i + 1 == entries.len() {
+            true => end - offset,
+            false => (8 + name.len()).next_multiple_of(4),
+        };
+        put_u32(&mut block, offset, *ino);
+        put_u16(&mut block, offset + 4, rec_len as u16);
+        block[offset + 6] = name.len() as u8;
+        block[offset + 7] = file_type;
+        block[offset + 8..offset + 8 + name.len()].copy_from_slice(name);
+        offset += rec_len;
+    }
+    if let Some(seed) = seed {
+        init_dirent_tail(&mut block);
+        set_dir_block_csum(seed, &mut block);
+    }
+    block
+}
+
+fn put_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
@@ -0,0 +1,215 @@
+// The orphan list of the ext4_rs shim: the inodes which lost their last
+// link while open, or are being truncated, are linked from s_last_orphan
+// through i_dtime. They are released at the last close, or at the next
+// mount if the system crashed before. Every function here writes through
+// the running transaction.
+
+use alloc::collections::BTreeSet;
+use vfscore::VfsResult;
+
+use crate::cache::{self, InodeId};
+use crate::ext4_check;
+use crate::ext4_layout::{InodeInfo, SUPERBLOCK_OFFSET};
+use crate::ext4_rs_shim::{
+    set_u16, set_u32, Ext4Volume, OpenInodes, I_BLOCK, I_BLOCKS, I_DTIME, I_FILE_ACL,
+    I_LINKS_COUNT, I_SIZE, S_LAST_ORPHAN,
+};
+
+impl Ext4Volume {
+    fn set_last_orphan(&self, ino: u32) {
+        self.modify(SUPERBLOCK_OFFSET, 1024, |raw| {
+            set_u32(raw, S_LAST_ORPHAN, ino)
+        });
+    }
+
+    /// Put the inode at the head of the orphan list, in the transaction
+    /// dropping the last link of an open file.
+    fn orphan_add(&self, ino: u32) -> VfsResult<()> {
+        let head = self.read_superblock().last_orphan;
+        self.modify_inode(ino, |raw| set_u32(raw, I_DTIME, head))?;
+        self.set_last_orphan(ino);
+        Ok(())
+    }
+
+    /// Unlink the inode from the orphan list before it's released at the
+    /// last close. The list is singly linked, it's walked from the head.
+    fn orphan_remove(&self, ino: u32) -> VfsResult<()> {
+        let next = self.read_inode(ino)?.dtime;
+        let mut prev = 0;
+        let mut cur = self.read_superblock().last_orphan;
+        let mut steps = 0;
+        while cur != ino {
+            if cur == 0 {
+                // it isn't on the list.
+                return Ok(());
+            }
+            steps += 1;
+            if steps > self.sb.inodes_count {
+                return Err(self.corrupted("orphan_remove", "orphan list", ino, 0));
+            }
+            prev = cur;
+            cur = self.read_inode(cur)?.dtime;
+        }
+        match prev {
+            0 => self.set_last_orphan(next),
+            prev => self.modify_inode(prev, |raw| set_u32(raw, I_DTIME, next))?,
+        }
+        self.modify_inode(ino, |raw| set_u32(raw, I_DTIME, 0))?;
+        Ok(())
+    }
+
+    /// Drop a link of the inode in the transaction removing its entry.
+    /// open is held for the transaction: the last link frees the inode,
+    /// or puts it on the orphan list if it has wrappers.
+    pub(crate) fn drop_link(&self, open: &mut OpenInodes, ino: u32) -> VfsResult<()> {
+        let inode = self.read_inode(ino)?;
+        let links = inode.links_count.saturating_sub(1);
+        self.modify_inode(ino, |raw| set_u16(raw, I_LINKS_COUNT, links))?;
+        if links > 0 {
+            return Ok(());
+        }
+        if open.wrappers.contains_key(&ino) {
+            self.orphan_add(ino)?;
+            open.unlinked.insert(ino);
+            return Ok(());
+        }
+        self.free_orphan(ino, &inode)
+    }
+
+    /// Count a new wrapper of the inode.
+    pub(crate) fn file_opened(&self, ino: u32) {
+        *self.open.lock().wrappers.entry(ino).or_insert(0) += 1;
+    }
+
+    /// Count a dropped wrapper of the inode, the last wrapper of an
+    /// unlinked inode releases it. If it fails the inode stays on the
+    /// orphan list and is released at the next mount.
+    pub(crate) fn file_closed(&self, ino: u32) {
+        let release = {
+            let mut open = self.open.lock();
+            let Some(count) = open.wrappers.get_mut(&ino) else {
+                return;
+            };
+            *count -= 1;
+            if *count > 0 {
+                return;
+            }
+            open.wrappers.remove(&ino);
+            self.windows.lock().remove(ino);
+            open.unlinked.remove(&ino)
+        };
+        if !release {
+            return;
+        }
+        let r = self.transaction(&[ino], None, || {
+            self.orphan_remove(ino)?;
+            self.free_orphan(ino, &self.read_inode(ino)?)
+        });
+        if let Err(err) = r {
+            log::error!("release the unlinked ext4 inode {} failed: {:?}", ino, err);
+        }
+        cache::invalidate(InodeId {
+            dev: self.disk.dev,
+            ino: ino as u64,
+        });
+    }
+
+    /// Release the orphans left by a crash, it must run after the journal
+    /// is replayed since the list may be in the replayed blocks. Every
+    /// orphan is released in its own transaction. Only a cancelled mount
+    /// fails, the orphans left are released by the next one.
+    pub(crate) fn cleanup_orphans(&self) -> VfsResult<()> {
+        if self.is_read_only() {
+            return Ok(());
+        }
+        let mut visited = BTreeSet::new();
+        let mut released = 0;
+        loop {
+            self.disk.mount_poll()?;
+            let ino = self.read_superblock().last_orphan;
+            if ino == 0 {
+                break;
+            }
+            if ino < self.sb.first_ino || ino > self.sb.inodes_count || !visited.insert(ino) {
+                log::error!("ext4 orphan list is corrupted at inode {}, drop it", ino);
+                let r = self.transaction(&[], None, || {
+                    self.set_last_orphan(0);
+                    Ok(())
+                });
+                if let Err(err) = r {
+                    log::error!("drop the ext4 orphan list failed: {:?}", err);
+                }
+                break;
+            }
+            if let Err(err) = self.transaction(&[ino], None, || self.release_orphan(ino)) {
+                log::error!("release the ext4 orphan inode {} failed: {:?}", ino, err);
+                break;
+            }
+            released += 1;
+        }
+        if released > 0 {
+            info!("ext4 released {} orphan inodes", released);
+        }
+        Ok(())
+    }
+
+    /// Pop the orphan at the head of the list and release it. An unlinked
+    /// inode is freed with its blocks, a linked one was being truncated
+    /// and loses the blocks beyond its size.
+    fn release_orphan(&self, ino: u32) -> VfsResult<()> {
+        let inode = self.read_inode(ino)?;
+        self.set_last_orphan(inode.dtime);
+        match inode.links_count {
+            0 => self.free_orphan(ino, &inode),
+            _ => self.truncate_orphan(ino, &inode),
+        }
+    }
+
+    /// Free the inode and all its blocks.
+    fn free_orphan(&self, ino: u32, inode: &InodeInfo) -> VfsResult<()> {
+        self.charge(inode.uid, inode.gid, -self.allocated(inode), -1);
+        let (extents, nodes) = ext4_check::inode_blocks(self, inode)?;
+        for extent in extents.iter() {
+            self.free_data_blocks(extent.physical, extent.len as u64)?;
+        }
+        for block in nodes {
+            self.free_blocks(block, 1)?;
+        }
+        if inode.file_acl != 0 {
+            self.release_xattr_block(inode.file_acl)?;
+        }
+        self.free_inode(ino, inode.mode & 0xF000 == 0x4000)?;
+        let dtime = self.now().max(1);
+        let uses_extents = inode.uses_extents();
+        self.modify_inode(ino, |raw| {
+            set_u32(raw, I_DTIME, dtime);
+            set_u32(raw, I_SIZE.0, 0);
+            set_u32(raw, I_SIZE.1, 0);
+            set_u32(raw, I_BLOCKS.0, 0);
+            set_u16(raw, I_BLOCKS.1, 0);
+            set_u32(raw, I_FILE_ACL.0, 0);
+            set_u16(raw, I_FILE_ACL.1, 0);
+            // an empty extent tree, or no indirect blocks.
+            if uses_extents {
+                set_u16(raw, I_BLOCK + 2, 0);
+                set_u16(raw, I_BLOCK + 6, 0);
+            } else {
+                raw[I_BLOCK..I_BLOCK + 60].fill(0);
+            }
+        })
+    }
+
+    /// Free the blocks of the orphan beyond its size. The indirect blocks
+    /// and the inline data are left as they are.
+    fn truncate_orphan(&self, ino: u32, inode: &InodeInfo) -> VfsResult<()> {
+        if !inode.uses_extents() || inode.has_inline_data() {
+            log::warn!(
+                "ext4 orphan inode {} can't be truncated, the blocks beyond the size are kept",
+                ino
+            );
+            return self.modify_inode(ino, |raw| set_u32(raw, I_DTIME, 0));
+        }
+        self.truncate_data(ino, inode.size)?;
+        self.modify_inode(ino, |raw| set_u32(raw, I_DTIME, 0))
+    }
+}
@@ -1,213 +1,7854 @@
+use core::{
+    cmp::min,
+    mem::offset_of,
+    ops::Range,
+    panic::Location,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+};
+
+#[cfg(feature = "async")]
+use alloc::boxed::Box;
 use alloc::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
     string::{String, ToString},
-    sync::Arc,
+    sync::{Arc, Weak},
     vec::Vec,
 };
-use devices::get_blk_device;
 
-use sync::Mutex;
-use vfscore::{
-    DirEntry, FileSystem, FileType, INodeInterface, Metadata, OpenFlags, StatFS, StatMode,
-    TimeSpec, VfsResult,
-};
+use vfscore::{
+    DirEntry, FileSystem, FileType, INodeInterface, Metadata, OpenFlags, StatFS, StatMode,
+    TimeSpec, VfsError, VfsResult, UTIME_NOW, UTIME_OMIT,
+};
+
+use ext4_rs::*;
+
+#[cfg(feature = "async")]
+use crate::aio::{self, AsyncBlockDevice, AsyncINode, IoFuture};
+use crate::atime::{AccessTime, AtimePolicy};
+use crate::batch::BatchINode;
+#[cfg(feature = "async")]
+use crate::blockdev::SECTOR_SIZE;
+use crate::blockdev::{self, anon_dev, DeviceErrors, RetryPolicy, SectorDevice, READ_SIZE};
+use crate::cache::{self, InodeId, Shrinker, PAGE_SIZE};
+use crate::cancel::{self, CancelIo, CANCEL_CHUNK};
+use crate::crc32c::crc32c;
+use crate::devnode::{make_dev, split_dev};
+use crate::direct::DirectINode;
+use crate::disk_layout::{self as disk, GroupDesc32, GroupDesc64, Inode, InodeExtra, XattrHeader};
+use crate::error::{Errno, ErrorContext, FsError, FsResult, VfsErrorContext};
+use crate::export::{self, Export, FileHandleId};
+use crate::ext4_check::{self, CheckDisk, CheckReport};
+use crate::ext4_csum::{
+    has_dirent_tail, init_dirent_tail, inode_seed, set_superblock_csum, set_xattr_block_csum,
+    verify_dir_block, verify_group_desc, verify_inode, verify_superblock, DIRENT_TAIL_SIZE,
+    I_CHECKSUM_HI, I_CHECKSUM_LO,
+};
+#[cfg(feature = "ext4_debug")]
+use crate::ext4_debug;
+use crate::ext4_extent::ExtentTree;
+use crate::ext4_htree::{dirhash, dx_lookup, DxRootInfo};
+use crate::ext4_journal::JournalSuperBlock;
+use crate::ext4_layout::{
+    bitmap_set, bitmap_test, compat_names, encode_device, incompat_names, inode_fields_end,
+    insert_dirent, le_u16, le_u32, remove_system_data, ro_compat_names, write_dirents, Dirent,
+    DirentIter, ErrorCode, ErrorHistory, ErrorRecord, Extent, ExtentCache, GroupDesc, InodeInfo,
+    SuperBlockInfo, TimeField, Timestamp, BG_BLOCK_UNINIT, BG_INODE_UNINIT, BG_INODE_ZEROED,
+    COMPAT_DIR_INDEX, EXT4_APPEND_FL, EXT4_COMPR_FL, EXT4_ENCRYPT_FL, EXT4_EXTENTS_FL,
+    EXT4_HUGE_FILE_FL, EXT4_IMMUTABLE_FL, EXT4_INDEX_FL, EXT4_INLINE_DATA_FL, EXT4_NODUMP_FL,
+    EXT4_SUPER_MAGIC, EXT4_VERITY_FL, EXTENT_MAGIC, INCOMPAT_64BIT, INCOMPAT_CSUM_SEED,
+    INCOMPAT_ENCRYPT, INCOMPAT_EXTENTS, INCOMPAT_FILETYPE, INCOMPAT_FLEX_BG, INCOMPAT_INLINE_DATA,
+    INCOMPAT_RECOVER, I_ATIME, I_CRTIME, I_CTIME, I_MTIME, ROOT_INO, RO_COMPAT_BIGALLOC,
+    RO_COMPAT_DIR_NLINK, RO_COMPAT_EXTRA_ISIZE, RO_COMPAT_HUGE_FILE, RO_COMPAT_LARGE_FILE,
+    RO_COMPAT_METADATA_CSUM, RO_COMPAT_SPARSE_SUPER, RO_COMPAT_VERITY, SUPERBLOCK_OFFSET,
+};
+use crate::freeze::{self, ClosedGate, Freeze, FreezeGate, GateGuard};
+use crate::fstype::{self, FsType};
+use crate::fsync::{SyncINode, SyncMode, SyncPolicy};
+use crate::handle::{AccessMode, AppendINode};
+use crate::inode_flags::{FlagsINode, InodeFlags};
+use crate::iosched;
+use crate::mapping;
+use crate::mknod::{MknodINode, NodeKind};
+use crate::mounts::{self, MountFlags, Remount};
+use crate::node::FsNode;
+use crate::ops::{
+    check_lookup_name, check_name, check_range, check_same_dev, check_str_name, d_type_file_type,
+    name_from_bytes, name_to_bytes, DT_BLK, DT_CHR, DT_DIR, DT_FIFO, DT_LNK, DT_REG, DT_SOCK,
+    DT_UNKNOWN, NAME_MAX, PATH_MAX,
+};
+use crate::owner::OwnerINode;
+use crate::quota::{self, Charge, Quota, QuotaId, QuotaLimits, QuotaTable, QuotaUsage};
+use crate::readdir::{EntryAttrs, PlusEntry, PosEntry, SeekDir};
+use crate::rename::{RenameFlags, RenameINode};
+use crate::revalidate::{self, Revalidate, RevalidateINode};
+use crate::snapshot::{Meta, Snapshot};
+use crate::stats::{self, FsCounters, StatsSource};
+use crate::statx::{
+    Statx, StatxINode, StatxTimestamp, STATX_ATTR_APPEND, STATX_ATTR_COMPRESSED,
+    STATX_ATTR_ENCRYPTED, STATX_ATTR_IMMUTABLE, STATX_ATTR_NODUMP, STATX_ATTR_VERITY, STATX_BTIME,
+};
+use crate::sys::Mutex;
+use crate::trace::{self, Target, TraceOp};
+use crate::tunefs::{self, Tunable};
+use crate::volume::{self, Volume};
+
+pub(crate) const BLOCK_SIZE: usize = 4096;
+
+/// Ext4Disk is the BlockDevice ext4_rs works on, it keeps the group cache
+/// and the transactions over the device of the mount.
+pub struct Ext4Disk {
+    /// The device number of the page cache and the stats, the device id
+    /// of sys or an anon_dev.
+    pub(crate) dev: usize,
+    device: Arc<dyn BlockDevice + Send + Sync>,
+    /// The group descriptors and bitmaps, every disk access goes through
+    /// it so the cached blocks stay coherent with the writes of ext4_rs.
+    pub(crate) groups: Mutex<GroupCache>,
+    /// The writes of the running transaction, locked after groups.
+    txn: Mutex<Option<Transaction>>,
+    /// Drop every write to the device, the backstop of a read-only mount.
+    read_only: AtomicBool,
+    /// The writes dropped by read_only.
+    violations: AtomicUsize,
+    /// The replay deferred by a fast mount, see replay_lazily. Locked
+    /// after txn.
+    pub(crate) lazy_replay: Mutex<LazyReplay>,
+    /// The journal blocks replayed by a read-only mount by their byte
+    /// offset, the reads see them over the device. Locked after
+    /// lazy_replay.
+    pub(crate) replayed: Mutex<BTreeMap<usize, Vec<u8>>>,
+    /// The transactions deferred by the write-back policy, the reads see
+    /// them over the device. Locked after replayed.
+    pub(crate) deferred: Mutex<Deferred>,
+    /// The backup read in place of the primary superblock and group
+    /// descriptors by a recovery mount, see select_superblock.
+    backup: Mutex<Option<Backup>>,
+    /// The byte ranges of the write guard, see guard_write. Empty without
+    /// the write_guard option.
+    protected: Mutex<Vec<Protected>>,
+    /// The data writes refused by the write guard.
+    guard_trips: AtomicUsize,
+    /// The progress of the running mount, see mount_poll. None once
+    /// mounted.
+    mounting: Mutex<Option<Arc<MountProgress>>>,
+    /// The tries of the failed device requests, see read_retried.
+    retry: RetryPolicy,
+    /// Find the blocks of a write failing at every try, the relocate
+    /// option, see write_retried.
+    relocate: bool,
+    /// The device errors and the retries of them.
+    health: Health,
+}
+
+/// The journal replay of a fast mount, see MountOptions::fast.
+pub(crate) enum LazyReplay {
+    /// The journal is replayed at the mount, or needs no replay.
+    None,
+    /// The journal isn't scanned yet, with the superblock locating it.
+    Pending(SuperBlockInfo),
+    /// The blocks of the journal not replayed yet, the byte offset of the
+    /// journal block holding each home by the byte offset of the home,
+    /// and whether its magic is escaped.
+    Indexed(BTreeMap<usize, (usize, bool)>),
+}
+
+/// The sectors whose errors are counted, the others count in the totals
+/// only.
+const MAX_SECTOR_ERRORS: usize = 1024;
+
+/// The failed tries of the device requests starting at a sector.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SectorErrors {
+    pub reads: u32,
+    pub writes: u32,
+}
+
+/// The device errors of a mount and what the retries made of them, see
+/// Ext4FileSystem::health.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskHealth {
+    /// The tries of the requests after a failed one.
+    pub read_retries: usize,
+    pub write_retries: usize,
+    /// The requests which failed at every try.
+    pub read_failures: usize,
+    pub write_failures: usize,
+    /// The logical blocks moved off a block whose write failed, see
+    /// MountOptions::relocate.
+    pub relocated: usize,
+    /// The blocks of the badlist, the allocations skip them.
+    pub bad_blocks: usize,
+}
+
+/// The counts of DiskHealth kept by the disk, with the errors by sector.
+struct Health {
+    read_retries: AtomicUsize,
+    write_retries: AtomicUsize,
+    read_failures: AtomicUsize,
+    write_failures: AtomicUsize,
+    /// The failed writes of the device followed by a try which succeeded,
+    /// the transactions don't see them, see unrecovered_errors.
+    recovered: AtomicU64,
+    /// The failed tries by the first sector of their request.
+    sectors: Mutex<BTreeMap<usize, SectorErrors>>,
+    /// The byte ranges whose writes failed at every try, since the last
+    /// take_failed_writes.
+    failed_writes: Mutex<Vec<(usize, usize)>>,
+}
+
+impl Health {
+    fn new() -> Self {
+        Self {
+            read_retries: AtomicUsize::new(0),
+            write_retries: AtomicUsize::new(0),
+            read_failures: AtomicUsize::new(0),
+            write_failures: AtomicUsize::new(0),
+            recovered: AtomicU64::new(0),
+            sectors: Mutex::new(BTreeMap::new()),
+            failed_writes: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Count the failed tries of the request at offset by its sector.
+    fn count(&self, offset: usize, failed: u32, write: bool) {
+        let mut sectors = self.sectors.lock();
+        let sector = offset / SECTOR_SIZE;
+        if sectors.len() >= MAX_SECTOR_ERRORS && !sectors.contains_key(&sector) {
+            return;
+        }
+        let errors = sectors.entry(sector).or_default();
+        match write {
+            true => errors.writes = errors.writes.saturating_add(failed),
+            false => errors.reads = errors.reads.saturating_add(failed),
+        }
+    }
+}
+
+/// What a write of the disk holds, only the metadata writes may touch
+/// the ranges of the write guard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WriteKind {
+    /// The blocks of a file, at the physical blocks of its extents.
+    Data,
+    /// The superblock, the descriptors, the bitmaps, the inodes, the
+    /// extent and directory blocks and the journal.
+    Metadata,
+}
+
+/// A byte range of the write guard, sorted and disjoint with the others.
+#[derive(Debug, Clone, Copy)]
+struct Protected {
+    offset: usize,
+    len: usize,
+    /// What it holds, for the log of a trip.
+    what: &'static str,
+}
+
+/// The backup superblock and group descriptors of a group, read at the
+/// offsets of the primary ones.
+#[derive(Debug, Clone)]
+struct Backup {
+    group: u32,
+    /// The (primary offset, length, backup offset) of the remapped ranges.
+    ranges: Vec<(usize, usize, usize)>,
+}
+
+impl Ext4Disk {
+    /// Create a disk on the device caching at most block_cache_bytes of
+    /// bitmaps, with the retries of the options.
+    pub fn new(
+        dev: usize,
+        device: Arc<dyn BlockDevice + Send + Sync>,
+        options: &MountOptions,
+    ) -> Self {
+        Self {
+            dev,
+            device,
+            groups: Mutex::new(GroupCache::new(options.block_cache_bytes / BLOCK_SIZE)),
+            txn: Mutex::new(None),
+            read_only: AtomicBool::new(false),
+            violations: AtomicUsize::new(0),
+            lazy_replay: Mutex::new(LazyReplay::None),
+            replayed: Mutex::new(BTreeMap::new()),
+            deferred: Mutex::new(Deferred::new()),
+            backup: Mutex::new(None),
+            protected: Mutex::new(Vec::new()),
+            guard_trips: AtomicUsize::new(0),
+            mounting: Mutex::new(None),
+            retry: options.retry,
+            relocate: options.relocate,
+            health: Health::new(),
+        }
+    }
+
+    /// Enter the phase of the running mount, then poll it.
+    pub(crate) fn mount_step(&self, phase: MountPhase) -> VfsResult<()> {
+        let Some(progress) = self.mounting.lock().clone() else {
+            return Ok(());
+        };
+        *progress.phase.lock() = phase;
+        progress.poll()
+    }
+
+    /// Fail with Blocking if the running mount is cancelled or past its
+    /// deadline, the check between the device requests of its phases.
+    /// Nothing is checked once mounted.
+    pub(crate) fn mount_poll(&self) -> VfsResult<()> {
+        match self.mounting.lock().clone() {
+            Some(progress) => progress.poll(),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the running mount was cancelled, the phases which log their
+    /// errors and go on stop then.
+    pub(crate) fn mount_cancelled(&self) -> bool {
+        let mounting = self.mounting.lock();
+        mounting
+            .as_ref()
+            .is_some_and(|x| x.cancelled.load(Ordering::Relaxed))
+    }
+}
+
+impl Drop for Ext4Disk {
+    fn drop(&mut self) {
+        MOUNTED_DEVICES.lock().remove(&self.dev);
+    }
+}
+
+impl core::fmt::Debug for Ext4Disk {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Ext4Disk")
+            .field("dev", &self.dev)
+            .field("groups", &self.groups)
+            .field("txn", &self.txn)
+            .finish()
+    }
+}
+
+/// The blocks per group of the 4K blocks, where the backups of a recovery
+/// mount are looked for.
+const BACKUP_BLOCKS_PER_GROUP: usize = 8 * BLOCK_SIZE;
+
+/// Check the 1024 bytes of a superblock: its magic, its geometry and its
+/// checksum with metadata_csum. return the reason if it's invalid.
+fn check_superblock(raw: &[u8]) -> Result<(), &'static str> {
+    let sb = SuperBlockInfo::parse(raw);
+    sb.validate()?;
+    if sb.has_metadata_csum() && !verify_superblock(raw) {
+        return Err("checksum mismatch");
+    }
+    Ok(())
+}
+
+/// The mount count and the write time of the superblock, another writer
+/// of the disk changes one of them.
+fn superblock_stamp(raw: &[u8]) -> (u16, u32) {
+    (le_u16(raw, S_MNT_COUNT), le_u32(raw, S_WTIME))
+}
+
+/// The superblocks have the same geometry and features, the recovery
+/// flag aside, a volume can revalidate from one to the other.
+fn same_geometry(a: &SuperBlockInfo, b: &SuperBlockInfo) -> bool {
+    let fields = |x: &SuperBlockInfo| {
+        (
+            x.inodes_count,
+            x.blocks_count,
+            x.first_data_block,
+            x.log_block_size,
+            x.blocks_per_group,
+            x.inodes_per_group,
+            x.inode_size,
+            x.desc_size,
+            x.journal_inum,
+            x.uuid,
+            x.csum_seed,
+        )
+    };
+    let features = |x: &SuperBlockInfo| {
+        (
+            x.feature_compat,
+            x.feature_incompat & !INCOMPAT_RECOVER,
+            x.feature_ro_compat,
+        )
+    };
+    fields(a) == fields(b) && features(a) == features(b)
+}
+
+/// The incompat features the shim implements, the images with another
+/// one aren't mounted.
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE
+    | INCOMPAT_RECOVER
+    | INCOMPAT_EXTENTS
+    | INCOMPAT_64BIT
+    | INCOMPAT_FLEX_BG
+    | INCOMPAT_CSUM_SEED
+    | INCOMPAT_INLINE_DATA
+    // the encrypted inodes are sealed, the others are plaintext.
+    | INCOMPAT_ENCRYPT;
+
+/// The flags of the inodes whose names and data the shim can't read: the
+/// names and the data of fscrypt are ciphertext, the compressed data
+/// isn't decompressed and verity isn't verified. Their metadata is read
+/// as usual.
+/// TODO: list the encrypted directories by the no-key names and verify
+/// the verity files.
+const SEALED_FLAGS: u32 = EXT4_ENCRYPT_FL | EXT4_COMPR_FL | EXT4_VERITY_FL;
+
+/// The ro_compat features whose invariants the writes of the shim keep,
+/// the images with them are mounted read-write: the sparse backups are
+/// written by write_backups, the large and huge sizes are encoded, the
+/// new directories and inodes follow dir_nlink and extra_isize, the
+/// checksums are updated and the verity files are sealed. The images with
+/// the other ones, like quota, uninit_bg or an unknown one, are read
+/// correctly but mounted read-only, the writes would break them.
+/// TODO: update the quota files and the crc16 of uninit_bg.
+const SUPPORTED_RO_COMPAT: u32 = RO_COMPAT_SPARSE_SUPER
+    | RO_COMPAT_LARGE_FILE
+    | RO_COMPAT_HUGE_FILE
+    | RO_COMPAT_DIR_NLINK
+    | RO_COMPAT_EXTRA_ISIZE
+    | RO_COMPAT_METADATA_CSUM
+    | RO_COMPAT_VERITY;
+
+/// The ro_compat features which aren't mounted even read-only. The
+/// bitmaps of bigalloc count clusters, the shim would free and allocate
+/// the wrong blocks and report the wrong sizes.
+/// TODO: allocate by the clusters.
+const REFUSED_RO_COMPAT: u32 = RO_COMPAT_BIGALLOC;
+
+/// The most links of an inode, a directory with dir_nlink has a count of
+/// 1 past it.
+const EXT4_LINK_MAX: u16 = 65000;
+
+/// The default memory of the cached bitmap blocks.
+const DEFAULT_BLOCK_CACHE_BYTES: usize = 64 * BLOCK_SIZE;
+/// The longest readahead, in pages.
+const MAX_READAHEAD_BLOCKS: usize = 256;
+/// The pages of a readahead read with the miss, the rest is deferred to
+/// background_work.
+const DEFAULT_READAHEAD_INLINE: usize = 8;
+/// The most readaheads deferred, the oldest ones over it are dropped.
+const MAX_DEFERRED_READAHEAD: usize = 64;
+/// The pages or blocks background_work reads or writes between its looks
+/// at the pressure, what a foreground request waits for at most.
+const BACKGROUND_CHUNK: usize = 8;
+/// The calls of background_work in a row yielding to the pressure, the
+/// next one works anyway, so a busy device doesn't starve it.
+const MAX_BACKGROUND_YIELDS: usize = 16;
+
+/// The blocks of zeros of a write of secure_delete.
+const ZERO_CHUNK_BLOCKS: usize = 16;
+/// The bytes of a transaction of a long write, its data blocks are held
+/// until the commit, so the memory of a write doesn't grow with it.
+const WRITE_CHUNK: usize = 0x40000;
+/// The scratch buffers of the data runs kept for the next writes.
+const SCRATCH_BUFFERS: usize = 4;
+/// The deferred blocks of a batch of batch.rs written back before its
+/// end, so its memory doesn't grow with it.
+const BATCH_DIRTY_BLOCKS: usize = 1024;
+
+/// The free scratch buffers of the data runs.
+static SCRATCH: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+/// Run f with a zeroed scratch buffer of len bytes, taken from the free
+/// ones and given back after.
+fn with_scratch<R>(len: usize, f: impl FnOnce(&mut [u8]) -> R) -> R {
+    let mut scratch = SCRATCH.lock().pop().unwrap_or_default();
+    scratch.clear();
+    scratch.resize(len, 0);
+    let r = f(&mut scratch);
+    let mut free = SCRATCH.lock();
+    if free.len() < SCRATCH_BUFFERS {
+        free.push(scratch);
+    }
+    r
+}
+
+/// The counters of the group cache.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GroupStats {
+    pub desc_loads: usize,
+    pub bitmap_loads: usize,
+    pub bitmap_hits: usize,
+    pub writebacks: usize,
+    pub evictions: usize,
+}
+
+#[derive(Debug)]
+struct BitmapBlock {
+    data: Vec<u8>,
+    dirty: bool,
+    stamp: u64,
+}
+
+/// GroupCache loads the group descriptors and bitmaps on the first touch.
+/// The descriptors are kept until umount, only the location fields of
+/// them are reliable since ext4_rs updates the counters on the disk.
+/// The bitmaps are BLOCK_SIZE bytes keyed by the byte offset, the clean
+/// ones are evicted when there are too many of them.
+#[derive(Debug)]
+pub(crate) struct GroupCache {
+    pub(crate) descs: BTreeMap<usize, GroupDesc>,
+    bitmaps: BTreeMap<usize, BitmapBlock>,
+    /// The max number of the cached bitmap blocks.
+    max_bitmaps: usize,
+    tick: u64,
+    stats: GroupStats,
+}
+
+impl GroupCache {
+    const fn new(max_bitmaps: usize) -> Self {
+        Self {
+            descs: BTreeMap::new(),
+            bitmaps: BTreeMap::new(),
+            max_bitmaps,
+            tick: 0,
+            stats: GroupStats {
+                desc_loads: 0,
+                bitmap_loads: 0,
+                bitmap_hits: 0,
+                writebacks: 0,
+                evictions: 0,
+            },
+        }
+    }
+
+    /// Copy the cached bitmaps overlapping [offset, offset + buf.len())
+    /// into buf, they are newer than the disk if they are dirty.
+    fn overlay(&self, offset: usize, buf: &mut [u8]) {
+        let start = offset.saturating_sub(BLOCK_SIZE - 1);
+        for (&block_off, block) in self.bitmaps.range(start..offset + buf.len()) {
+            copy_overlap(buf, offset, &block.data, block_off);
+        }
+    }
+
+    /// Apply the write at offset to the cached bitmaps, a bitmap is clean
+    /// again if the write covers it.
+    pub(crate) fn patch(&mut self, offset: usize, buf: &[u8]) {
+        let start = offset.saturating_sub(BLOCK_SIZE - 1);
+        for (&block_off, block) in self.bitmaps.range_mut(start..offset + buf.len()) {
+            copy_overlap(&mut block.data, block_off, buf, offset);
+            if offset <= block_off && block_off + BLOCK_SIZE <= offset + buf.len() {
+                block.dirty = false;
+            }
+        }
+    }
+
+    /// Pick the bitmap to evict, the least recently used clean one, or the
+    /// least recently used one if all of them are dirty.
+    fn victim(&self) -> Option<usize> {
+        let lru = |dirty: bool| {
+            self.bitmaps
+                .iter()
+                .filter(|(_, x)| x.dirty == dirty)
+                .min_by_key(|(_, x)| x.stamp)
+                .map(|(offset, _)| *offset)
+        };
+        lru(false).or_else(|| lru(true))
+    }
+}
+
+/// Transaction keeps the blocks written during an operation in memory,
+/// the reads see them and they reach the disk when it's committed.
+#[derive(Debug)]
+pub(crate) struct Transaction {
+    pub(crate) block_size: usize,
+    pub(crate) blocks: BTreeMap<u64, Vec<u8>>,
+    /// The cached bitmaps (data, dirty) before the transaction patched
+    /// them, they are restored if it's aborted.
+    saved: BTreeMap<usize, (Vec<u8>, bool)>,
+}
+
+impl Transaction {
+    const fn new(block_size: usize) -> Self {
+        Self {
+            block_size,
+            blocks: BTreeMap::new(),
+            saved: BTreeMap::new(),
+        }
+    }
+
+    /// Save the cached bitmaps the write at offset will patch. A bitmap
+    /// whose block is already logged was loaded with the logged data, it
+    /// isn't saved and is dropped from the cache on abort instead.
+    fn save_bitmaps(&mut self, groups: &GroupCache, offset: usize, len: usize) {
+        let start = offset.saturating_sub(BLOCK_SIZE - 1);
+        for (&block_off, block) in groups.bitmaps.range(start..offset + len) {
+            if !self.saved.contains_key(&block_off)
+                && self
+                    .blocks
+                    .range(self.block_range(block_off, BLOCK_SIZE))
+                    .next()
+                    .is_none()
+            {
+                self.saved
+                    .insert(block_off, (block.data.clone(), block.dirty));
+            }
+        }
+    }
+
+    /// The blocks overlapping [offset, offset + len).
+    fn block_range(&self, offset: usize, len: usize) -> core::ops::Range<u64> {
+        (offset / self.block_size) as u64..(offset + len).div_ceil(self.block_size) as u64
+    }
+
+    /// Copy the logged blocks overlapping the read into buf.
+    fn overlay(&self, offset: usize, buf: &mut [u8]) {
+        for (&block, data) in self.blocks.range(self.block_range(offset, buf.len())) {
+            copy_overlap(buf, offset, data, block as usize * self.block_size);
+        }
+    }
+
+    /// Log the write, load reads a block which isn't logged yet.
+    fn write(&mut self, offset: usize, buf: &[u8], mut load: impl FnMut(usize, &mut [u8])) {
+        let block_size = self.block_size;
+        for block in self.block_range(offset, buf.len()) {
+            let block_off = block as usize * block_size;
+            let data = self.blocks.entry(block).or_insert_with(|| {
+                let mut data = vec![0; block_size];
+                load(block_off, &mut data);
+                data
+            });
+            copy_overlap(data, block_off, buf, offset);
+        }
+    }
+}
+
+/// The blocks of the committed transactions which the write-back policy
+/// keeps in memory, merged as one transaction. The newest write of a
+/// block wins, a block freed and reused as data or metadata moves to the
+/// other map.
+#[derive(Debug)]
+pub(crate) struct Deferred {
+    pub(crate) block_size: usize,
+    /// Written in place before the metadata, like the data of a commit.
+    pub(crate) data: BTreeMap<u64, Vec<u8>>,
+    pub(crate) metadata: BTreeMap<u64, Vec<u8>>,
+    /// The tick of the oldest deferred transaction.
+    pub(crate) since: Option<u64>,
+    /// The writeback steps since the mount.
+    tick: u64,
+    /// The deferred blocks by the inodes of the transactions which dirtied
+    /// them, see Ext4Volume::sync_inode. A block written back is dropped
+    /// from them.
+    pub(crate) dirty: BTreeMap<u32, DirtyBlocks>,
+}
+
+/// The deferred blocks the transactions of an inode dirtied.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DirtyBlocks {
+    /// The data blocks written by it.
+    data: BTreeSet<u64>,
+    /// The metadata blocks of its transactions which changed more than
+    /// the times of their inodes.
+    metadata: BTreeSet<u64>,
+    /// The inode blocks of its transactions which changed only the times,
+    /// fdatasync leaves them deferred.
+    times: BTreeSet<u64>,
+}
+
+impl DirtyBlocks {
+    fn contains(&self, block: u64) -> bool {
+        self.data.contains(&block) || self.metadata.contains(&block) || self.times.contains(&block)
+    }
+
+    fn remove(&mut self, block: u64) {
+        self.data.remove(&block);
+        self.metadata.remove(&block);
+        self.times.remove(&block);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty() && self.metadata.is_empty() && self.times.is_empty()
+    }
+}
+
+/// The inodes a deferred transaction dirtied its blocks for.
+#[derive(Debug, Default)]
+pub(crate) struct TxnOwners {
+    pub(crate) inodes: Vec<u32>,
+    /// The inode of the data blocks.
+    pub(crate) data_ino: Option<u32>,
+    /// The transaction changed only the times of the inodes.
+    pub(crate) times_only: bool,
+}
+
+impl Deferred {
+    const fn new() -> Self {
+        Self {
+            block_size: BLOCK_SIZE,
+            data: BTreeMap::new(),
+            metadata: BTreeMap::new(),
+            since: None,
+            tick: 0,
+            dirty: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.data.len() + self.metadata.len()
+    }
+
+    /// Merge the blocks of a committed transaction, dirtied for the owners.
+    fn add(
+        &mut self,
+        block_size: usize,
+        data: Vec<(u64, Vec<u8>)>,
+        metadata: Vec<(u64, Vec<u8>)>,
+        owners: &TxnOwners,
+    ) {
+        self.block_size = block_size;
+        for ino in owners.inodes.iter().chain(owners.data_ino.iter()) {
+            let dirty = self.dirty.entry(*ino).or_default();
+            if owners.data_ino == Some(*ino) {
+                dirty.data.extend(data.iter().map(|x| x.0));
+            }
+            match owners.times_only {
+                true => dirty.times.extend(metadata.iter().map(|x| x.0)),
+                false => dirty.metadata.extend(metadata.iter().map(|x| x.0)),
+            }
+        }
+        for (block, buf) in data {
+            self.metadata.remove(&block);
+            self.data.insert(block, buf);
+        }
+        for (block, buf) in metadata {
+            self.data.remove(&block);
+            self.metadata.insert(block, buf);
+        }
+        self.since.get_or_insert(self.tick);
+    }
+
+    /// The deferred blocks overlapping [offset, offset + len).
+    pub(crate) fn overlapping(
+        &mut self,
+        offset: usize,
+        len: usize,
+    ) -> impl Iterator<Item = (usize, &mut Vec<u8>)> + '_ {
+        let block_size = self.block_size;
+        let range = (offset / block_size) as u64..(offset + len).div_ceil(block_size) as u64;
+        self.data
+            .range_mut(range.clone())
+            .chain(self.metadata.range_mut(range))
+            .map(move |(block, data)| (*block as usize * block_size, data))
+    }
+}
+
+/// Copy the overlapped part of src at src_off into dst at dst_off,
+/// the offsets are the byte offsets on the disk.
+pub(crate) fn copy_overlap(dst: &mut [u8], dst_off: usize, src: &[u8], src_off: usize) {
+    let start = dst_off.max(src_off);
+    let end = (dst_off + dst.len()).min(src_off + src.len());
+    if start < end {
+        dst[start - dst_off..end - dst_off].copy_from_slice(&src[start - src_off..end - src_off]);
+    }
+}
+
+impl Ext4Disk {
+    /// Read buf.len() bytes at offset from the device, by the READ_SIZE
+    /// reads of read_offset, with the replayed and the deferred blocks
+    /// over them. return the failed reads, see read_raw.
+    pub(crate) fn read_device_into(&self, offset: usize, buf: &mut [u8]) -> Vec<usize> {
+        let failed = self.read_raw(offset, buf);
+        self.overlay_backup(offset, buf);
+        self.overlay_replayed(offset, buf);
+        failed
+    }
+
+    /// Read buf.len() bytes at offset as they are on the disk, without the
+    /// deferred blocks.
+    fn read_home(&self, offset: usize, buf: &mut [u8]) {
+        self.read_raw(offset, buf);
+        self.overlay_backup(offset, buf);
+    }
+
+    /// Read buf.len() bytes at offset from the device as they are, the
+    /// failed reads are retried. return the offsets of the READ_SIZE reads
+    /// which failed at every try, they read what the device returned,
+    /// zeros for a MockDisk. The metadata reads can't fail and read them
+    /// as they are.
+    pub(crate) fn read_raw(&self, offset: usize, buf: &mut [u8]) -> Vec<usize> {
+        let errors = blockdev::error_counts(self.dev);
+        let mut failed = Vec::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            let (data, done) = self.read_retried(errors.as_deref(), offset + pos);
+            if !done {
+                failed.push(offset + pos);
+            }
+            let len = min(min(data.len(), READ_SIZE), buf.len() - pos);
+            buf[pos..pos + len].copy_from_slice(&data[..len]);
+            pos += len;
+        }
+        failed
+    }
+
+    /// Read at offset like read_offset of the device, a read failing on a
+    /// device counting its errors is tried again by the retry policy.
+    /// return the data of the last try and whether it succeeded.
+    fn read_retried(&self, errors: Option<&dyn DeviceErrors>, offset: usize) -> (Vec<u8>, bool) {
+        let Some(errors) = errors else {
+            return (self.device.read_offset(offset), true);
+        };
+        let mut data = Vec::new();
+        let (done, failed) = self.retry.run(|| {
+            let before = errors.read_errors();
+            data = self.device.read_offset(offset);
+            errors.read_errors() == before
+        });
+        if failed > 0 {
+            self.health.count(offset, failed, false);
+            let retries = failed - !done as u32;
+            self.health
+                .read_retries
+                .fetch_add(retries as usize, Ordering::Relaxed);
+        }
+        if !done {
+            self.health.read_failures.fetch_add(1, Ordering::Relaxed);
+            log::error!("ext4 read at {:#x} failed {} times", offset, failed);
+        }
+        (data, done)
+    }
+
+    /// Copy the backup ranges overlapping the read into buf, from their
+    /// backup offsets.
+    pub(crate) fn overlay_backup(&self, offset: usize, buf: &mut [u8]) {
+        let backup = self.backup.lock();
+        let Some(backup) = backup.as_ref() else {
+            return;
+        };
+        for &(from, len, to) in backup.ranges.iter() {
+            let start = offset.max(from);
+            let end = (offset + buf.len()).min(from + len);
+            if start < end {
+                self.read_raw(to + start - from, &mut buf[start - offset..end - offset]);
+            }
+        }
+    }
+
+    /// Read the superblock and the group descriptors from the backup of
+    /// the group if the primary superblock is invalid, a recovery mount.
+    /// The backups are found like e2fsck -b, with the 32768 blocks per
+    /// group of the 4K blocks. Fail with InvalidData if the backup is
+    /// invalid too or the group has none.
+    fn select_superblock(&self, group: u32) -> VfsResult<()> {
+        let mut raw = vec![0; 1024];
+        self.read_raw(SUPERBLOCK_OFFSET, &mut raw);
+        match check_superblock(&raw) {
+            Ok(()) => return Ok(()),
+            Err(reason) => log::error!("the primary ext4 superblock is invalid: {}", reason),
+        }
+        let start = group as usize * BACKUP_BLOCKS_PER_GROUP * BLOCK_SIZE;
+        self.read_raw(start, &mut raw);
+        let sb = SuperBlockInfo::parse(&raw);
+        let reason = match check_superblock(&raw) {
+            Err(reason) => reason,
+            Ok(()) if group == 0 || group as usize >= sb.groups_count() => "no such group",
+            Ok(()) if !sb.group_has_super(group as usize) => "no backup in the group",
+            Ok(()) if sb.blocks_per_group as usize != BACKUP_BLOCKS_PER_GROUP => {
+                "bad blocks per group"
+            }
+            Ok(()) if le_u16(&raw, S_BLOCK_GROUP_NR) as u32 != group => "bad group number",
+            Ok(()) => {
+                let descs = sb.group_desc_blocks() * BLOCK_SIZE;
+                let ranges = vec![
+                    (SUPERBLOCK_OFFSET, 1024, start),
+                    (sb.group_desc_offset(0), descs, start + BLOCK_SIZE),
+                ];
+                info!("ext4 reads the backup superblock of group {}", group);
+                *self.backup.lock() = Some(Backup { group, ranges });
+                return Ok(());
+            }
+        };
+        log::error!(
+            "the ext4 backup superblock of group {} is invalid: {}",
+            group,
+            reason
+        );
+        Err(VfsError::InvalidData)
+    }
+
+    /// The group of the backup read in place of the primary superblock.
+    fn backup_group(&self) -> Option<u32> {
+        self.backup.lock().as_ref().map(|x| x.group)
+    }
+
+    /// Drop the cached group descriptors and bitmaps and the replayed
+    /// journal blocks after the device changed under a read-only mount,
+    /// see Ext4Volume::revalidate. Nothing of a read-only mount is dirty.
+    fn drop_caches(&self) {
+        let mut groups = self.groups.lock();
+        groups.descs.clear();
+        groups.bitmaps.clear();
+        drop(groups);
+        *self.lazy_replay.lock() = LazyReplay::None;
+        self.replayed.lock().clear();
+    }
+
+    /// Drop the writes to the device from now on, or allow them again.
+    fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Relaxed);
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Read into buf from the device, then apply the running transaction
+    /// and the cached bitmaps over it.
+    fn read_overlaid(&self, groups: &GroupCache, offset: usize, buf: &mut [u8]) -> Vec<usize> {
+        let failed = self.read_device_into(offset, buf);
+        if let Some(txn) = self.txn.lock().as_ref() {
+            txn.overlay(offset, buf);
+        }
+        groups.overlay(offset, buf);
+        failed
+    }
+
+    /// Read into buf through the group cache, return the failed reads.
+    fn read_into(&self, offset: usize, buf: &mut [u8]) -> Vec<usize> {
+        let groups = self.groups.lock();
+        self.read_overlaid(&groups, offset, buf)
+    }
+
+    /// Like read_into, the device is read by its DirectRead if it has one,
+    /// so the read doesn't fill the cache of the device. The DirectRead
+    /// isn't retried.
+    fn read_direct_into(&self, offset: usize, buf: &mut [u8]) -> Vec<usize> {
+        let groups = self.groups.lock();
+        let failed = match blockdev::direct_read_device(self.dev) {
+            Some(device) => {
+                device.read_direct(offset, buf);
+                Vec::new()
+            }
+            None => self.read_raw(offset, buf),
+        };
+        self.overlay_backup(offset, buf);
+        self.overlay_replayed(offset, buf);
+        if let Some(txn) = self.txn.lock().as_ref() {
+            txn.overlay(offset, buf);
+        }
+        groups.overlay(offset, buf);
+        failed
+    }
+
+    /// Like read_into, awaiting the read on the async driver of the
+    /// device. offset and the length of buf are whole sectors, the locks
+    /// are taken for the overlays after the read.
+    #[cfg(feature = "async")]
+    async fn read_into_async(&self, device: &dyn AsyncBlockDevice, offset: usize, buf: &mut [u8]) {
+        device.read_blocks_async(offset / SECTOR_SIZE, buf).await;
+        self.overlay_backup(offset, buf);
+        self.overlay_replayed(offset, buf);
+        let groups = self.groups.lock();
+        if let Some(txn) = self.txn.lock().as_ref() {
+            txn.overlay(offset, buf);
+        }
+        groups.overlay(offset, buf);
+    }
+
+    /// Write buf at offset to the device. A read-only disk drops and
+    /// counts the write instead, BlockDevice has no error to return, so a
+    /// path writing on a read-only mount is a bug of the shim. The
+    /// deferred blocks it overlaps are patched, so they don't hide it.
+    fn write_device(&self, offset: usize, buf: &[u8]) {
+        if self.accept_write(offset, buf) {
+            self.write_retried(offset, buf, |offset, buf| {
+                self.device.write_offset(offset, buf)
+            });
+        }
+    }
+
+    /// Write buf at offset by write, a write failing on a device counting
+    /// its errors is tried again by the retry policy. The errors of a
+    /// write which succeeded at last are recovered, the transactions don't
+    /// see them, see unrecovered_errors. A write failing at every try is
+    /// one of take_failed_writes, with relocate it's written again by
+    /// READ_SIZE pieces and the pieces which fail again are.
+    fn write_retried(&self, offset: usize, buf: &[u8], write: impl Fn(usize, &[u8])) {
+        let Some(errors) = blockdev::error_counts(self.dev) else {
+            return write(offset, buf);
+        };
+        let start = errors.write_errors();
+        let try_write = |offset: usize, buf: &[u8]| {
+            let before = errors.write_errors();
+            write(offset, buf);
+            errors.write_errors() == before
+        };
+        let (done, failed) = self.retry.run(|| try_write(offset, buf));
+        if failed > 0 {
+            self.health.count(offset, failed, true);
+            let retries = failed - !done as u32;
+            self.health
+                .write_retries
+                .fetch_add(retries as usize, Ordering::Relaxed);
+        }
+        if done {
+            let recovered = errors.write_errors() - start;
+            self.health
+                .recovered
+                .fetch_add(recovered, Ordering::Relaxed);
+            return;
+        }
+        self.health.write_failures.fetch_add(1, Ordering::Relaxed);
+        log::error!("ext4 write at {:#x} failed {} times", offset, failed);
+        let mut lost = Vec::new();
+        if self.relocate && buf.len() > READ_SIZE {
+            for (i, piece) in buf.chunks(READ_SIZE).enumerate() {
+                let at = offset + i * READ_SIZE;
+                if !try_write(at, piece) {
+                    self.health.count(at, 1, true);
+                    lost.push((at, piece.len()));
+                }
+            }
+        }
+        // every piece was written again, the whole range is the failure.
+        if lost.is_empty() {
+            lost.push((offset, buf.len()));
+        }
+        self.health.failed_writes.lock().extend(lost);
+    }
+
+    /// The failed writes of the device but the recovered ones, None if the
+    /// device doesn't count them. A transaction is rolled back when they
+    /// change across its commit.
+    pub(crate) fn unrecovered_errors(&self) -> Option<u64> {
+        let recovered = self.health.recovered.load(Ordering::Relaxed);
+        blockdev::device_errors(self.dev).map(|x| x.saturating_sub(recovered))
+    }
+
+    /// The byte ranges whose writes failed at every try since the last
+    /// call.
+    fn take_failed_writes(&self) -> Vec<(usize, usize)> {
+        core::mem::take(&mut *self.health.failed_writes.lock())
+    }
+
+    /// Drop and count the write of a read-only disk, or patch the deferred
+    /// blocks it overlaps. return whether the write goes to the device.
+    fn accept_write(&self, offset: usize, buf: &[u8]) -> bool {
+        if self.is_read_only() {
+            if self.violations.fetch_add(1, Ordering::Relaxed) == 0 {
+                log::error!("ext4 write at {:#x} on a read-only mount dropped", offset);
+            }
+            return false;
+        }
+        for (block_off, data) in self.deferred.lock().overlapping(offset, buf.len()) {
+            copy_overlap(data, block_off, buf, offset);
+        }
+        true
+    }
+
+    /// Protect the ranges from the data writes, they replace the ones
+    /// protected before. The overlapping ranges are merged.
+    fn protect(&self, mut ranges: Vec<Protected>) {
+        ranges.sort_unstable_by_key(|x| x.offset);
+        let mut merged: Vec<Protected> = Vec::new();
+        for range in ranges.into_iter().filter(|x| x.len > 0) {
+            match merged.last_mut() {
+                Some(last) if last.offset + last.len >= range.offset => {
+                    last.len = last.len.max(range.offset + range.len - last.offset)
+                }
+                _ => merged.push(range),
+            }
+        }
+        *self.protected.lock() = merged;
+    }
+
+    /// Check a write of the kind against the protected ranges. A data
+    /// write into one of them is a wrong block computed by a path of the
+    /// shim: it's counted and logged with its offset, it panics in a debug
+    /// build and fails with Io otherwise, before anything is written. The
+    /// metadata writes pass.
+    fn guard_write(&self, offset: usize, len: usize, kind: WriteKind) -> VfsResult<()> {
+        if kind == WriteKind::Metadata {
+            return Ok(());
+        }
+        let hit = {
+            let protected = self.protected.lock();
+            let at = protected.partition_point(|x| x.offset + x.len <= offset);
+            protected
+                .get(at)
+                .filter(|x| x.offset < offset + len)
+                .copied()
+        };
+        let Some(range) = hit else {
+            return Ok(());
+        };
+        self.guard_trips.fetch_add(1, Ordering::Relaxed);
+        log::error!(
+            "ext4 data write of {} bytes at {:#x} into the {} at {:#x}",
+            len,
+            offset,
+            range.what,
+            range.offset
+        );
+        if cfg!(debug_assertions) {
+            panic!("ext4 data write at {:#x} into the {}", offset, range.what);
+        }
+        Err(VfsError::Io)
+    }
+
+    /// Write buf at offset like write_offset, a data write is checked by
+    /// the write guard first.
+    fn write_tagged(&self, offset: usize, buf: &[u8], kind: WriteKind) -> VfsResult<()> {
+        self.guard_write(offset, buf.len(), kind)?;
+        self.write_offset(offset, buf);
+        Ok(())
+    }
+
+    /// Like write_offset outside a transaction, the write is on the media
+    /// when it returns: with FUA if the device has it, else followed by a
+    /// flush. A device without a write cache is written as usual.
+    pub(crate) fn write_durable(&self, offset: usize, buf: &[u8]) {
+        if !blockdev::has_write_cache(self.dev) {
+            return self.write_offset(offset, buf);
+        }
+        let mut groups = self.groups.lock();
+        if self.accept_write(offset, buf) {
+            self.write_retried(offset, buf, |offset, buf| {
+                let fua = offset % SECTOR_SIZE == 0
+                    && blockdev::write_fua(self.dev, offset / SECTOR_SIZE, buf);
+                if !fua {
+                    self.device.write_offset(offset, buf);
+                    blockdev::flush_device(self.dev);
+                }
+            });
+        }
+        groups.patch(offset, buf);
+    }
+
+    /// Keep the blocks of a committed transaction in memory, return the
+    /// number of the deferred blocks.
+    pub(crate) fn defer(
+        &self,
+        block_size: usize,
+        data: Vec<(u64, Vec<u8>)>,
+        metadata: Vec<(u64, Vec<u8>)>,
+        owners: &TxnOwners,
+    ) -> usize {
+        let mut deferred = self.deferred.lock();
+        deferred.add(block_size, data, metadata, owners);
+        deferred.len()
+    }
+
+    /// The number of the deferred blocks.
+    fn deferred_blocks(&self) -> usize {
+        self.deferred.lock().len()
+    }
+
+    /// Get the group descriptor, it's read from the disk on the first touch.
+    /// The checksum is verified with metadata_csum.
+    pub(crate) fn group_desc(&self, sb: &SuperBlockInfo, group: usize) -> VfsResult<GroupDesc> {
+        let mut groups = self.groups.lock();
+        if let Some(desc) = groups.descs.get(&group) {
+            return Ok(*desc);
+        }
+        let offset = sb.group_desc_offset(group);
+        let mut buf = vec![0; BLOCK_SIZE];
+        self.read_overlaid(&groups, offset, &mut buf);
+        if sb.has_metadata_csum()
+            && !self.is_logged(offset, sb.group_desc_size())
+            && !verify_group_desc(sb, group as u32, &buf)
+        {
+            log::error!("ext4 group descriptor {} checksum mismatch", group);
+            return Err(VfsError::InvalidData);
+        }
+        let desc = GroupDesc::parse(sb, &buf);
+        if let Err(reason) = desc.validate(sb) {
+            log::error!("ext4 group descriptor {}: {}", group, reason);
+            return Err(VfsError::InvalidData);
+        }
+        groups.descs.insert(group, desc);
+        groups.stats.desc_loads += 1;
+        Ok(desc)
+    }
+
+    /// Get the cached bitmap block at offset, load it if it isn't cached.
+    fn load_bitmap<'a>(&self, groups: &'a mut GroupCache, offset: usize) -> &'a mut BitmapBlock {
+        groups.tick += 1;
+        let tick = groups.tick;
+        if groups.bitmaps.contains_key(&offset) {
+            groups.stats.bitmap_hits += 1;
+        } else {
+            if groups.bitmaps.len() >= groups.max_bitmaps
+                && let Some(victim) = groups.victim()
+            {
+                let block = groups.bitmaps.remove(&victim).unwrap();
+                if block.dirty {
+                    self.write_device(victim, &block.data);
+                    groups.stats.writebacks += 1;
+                }
+                groups.stats.evictions += 1;
+            }
+            let mut data = vec![0; BLOCK_SIZE];
+            self.read_overlaid(groups, offset, &mut data);
+            groups.bitmaps.insert(
+                offset,
+                BitmapBlock {
+                    data,
+                    dirty: false,
+                    stamp: 0,
+                },
+            );
+            groups.stats.bitmap_loads += 1;
+        }
+        let block = groups.bitmaps.get_mut(&offset).unwrap();
+        block.stamp = tick;
+        block
+    }
+
+    /// Read the bitmap block at offset through the cache.
+    fn read_bitmap(&self, offset: usize) -> Vec<u8> {
+        let mut groups = self.groups.lock();
+        self.load_bitmap(&mut groups, offset).data.clone()
+    }
+
+    /// Modify the bitmap block at offset in the cache, it's written back
+    /// by sync_groups or when it's evicted.
+    fn update_bitmap<R>(&self, offset: usize, f: impl FnOnce(&mut [u8]) -> R) -> R {
+        let mut groups = self.groups.lock();
+        let block = self.load_bitmap(&mut groups, offset);
+        block.dirty = true;
+        f(&mut block.data)
+    }
+
+    /// Write back at most max dirty bitmaps, the least recently used ones
+    /// first. return the number of written bitmaps.
+    fn writeback_groups(&self, max: usize) -> usize {
+        let mut groups = self.groups.lock();
+        let mut dirty: Vec<(u64, usize)> = groups
+            .bitmaps
+            .iter()
+            .filter(|(_, x)| x.dirty)
+            .map(|(offset, x)| (x.stamp, *offset))
+            .collect();
+        dirty.sort_unstable();
+        dirty.truncate(max);
+        for (_, offset) in dirty.iter() {
+            let block = groups.bitmaps.get_mut(offset).unwrap();
+            self.write_device(*offset, &block.data);
+            block.dirty = false;
+        }
+        groups.stats.writebacks += dirty.len();
+        dirty.len()
+    }
+
+    /// Write back all the dirty bitmaps.
+    fn sync_groups(&self) -> usize {
+        self.writeback_groups(usize::MAX)
+    }
+
+    /// The memory of the cached bitmaps.
+    fn cache_bytes(&self) -> usize {
+        self.groups.lock().max_bitmaps * BLOCK_SIZE
+    }
+
+    /// Cache at most bytes of bitmaps, the least recently used ones over
+    /// it are evicted at once, written back if they are dirty.
+    fn set_cache_bytes(&self, bytes: usize) {
+        let excess = {
+            let mut groups = self.groups.lock();
+            groups.max_bitmaps = bytes / BLOCK_SIZE;
+            groups.bitmaps.len().saturating_sub(groups.max_bitmaps)
+        };
+        self.shrink(excess * BLOCK_SIZE, true);
+    }
+
+    fn has_dirty_groups(&self) -> bool {
+        self.groups.lock().bitmaps.values().any(|x| x.dirty)
+    }
+
+    /// Log the following writes in a transaction instead of writing them.
+    fn begin_transaction(&self, block_size: usize) {
+        let _groups = self.groups.lock();
+        *self.txn.lock() = Some(Transaction::new(block_size));
+    }
+
+    /// Stop logging the writes, return the logged blocks.
+    fn end_transaction(&self) -> Option<Transaction> {
+        let _groups = self.groups.lock();
+        self.txn.lock().take()
+    }
+
+    /// Drop the running transaction, none of its writes reach the disk.
+    /// The cached bitmaps are restored as they were before it, the ones
+    /// loaded with its writes are dropped and will be read from the disk.
+    fn abort_transaction(&self) {
+        let mut groups = self.groups.lock();
+        let Some(txn) = self.txn.lock().take() else {
+            return;
+        };
+        Self::restore_groups(&mut groups, &txn);
+    }
+
+    /// Restore the cached bitmaps patched by the transaction, see
+    /// abort_transaction.
+    fn restore_groups(groups: &mut GroupCache, txn: &Transaction) {
+        groups.bitmaps.retain(|offset, block| {
+            if let Some((data, dirty)) = txn.saved.get(offset) {
+                block.data.copy_from_slice(data);
+                block.dirty = *dirty;
+                return true;
+            }
+            txn.blocks
+                .range(txn.block_range(*offset, BLOCK_SIZE))
+                .next()
+                .is_none()
+        });
+        // the descriptors may have been loaded through the transaction.
+        groups.descs.clear();
+    }
+
+    /// The blocks of the ended transaction as they are on the device, and
+    /// the cached bitmaps it saved, to roll back its commit.
+    fn undo_log(&self, txn: &Transaction) -> Transaction {
+        let mut undo = Transaction::new(txn.block_size);
+        for &block in txn.blocks.keys() {
+            let mut data = vec![0; txn.block_size];
+            self.read_raw(block as usize * txn.block_size, &mut data);
+            undo.blocks.insert(block, data);
+        }
+        undo.saved = txn.saved.clone();
+        undo
+    }
+
+    /// Write back the blocks of the undo log, and restore the cached
+    /// bitmaps like an abort.
+    pub(crate) fn roll_back(&self, undo: &Transaction) {
+        for (&block, data) in undo.blocks.iter() {
+            self.write_offset(block as usize * undo.block_size, data);
+        }
+        Self::restore_groups(&mut self.groups.lock(), undo);
+    }
+
+    /// The blocks logged in the running transaction.
+    pub(crate) fn logged_blocks(&self) -> BTreeSet<u64> {
+        match self.txn.lock().as_ref() {
+            Some(txn) => txn.blocks.keys().copied().collect(),
+            None => BTreeSet::new(),
+        }
+    }
+
+    /// The running transaction has written [offset, offset + len), its
+    /// checksums aren't updated until the commit.
+    pub(crate) fn is_logged(&self, offset: usize, len: usize) -> bool {
+        match self.txn.lock().as_ref() {
+            Some(txn) => txn
+                .blocks
+                .range(txn.block_range(offset, len))
+                .next()
+                .is_some(),
+            None => false,
+        }
+    }
+}
+
+impl Shrinker for Ext4Disk {
+    fn name(&self) -> &'static str {
+        "ext4_groups"
+    }
+
+    // the descriptors are small and kept, only the bitmaps are counted.
+    fn usage(&self) -> usize {
+        self.groups.lock().bitmaps.len() * BLOCK_SIZE
+    }
+
+    fn shrink(&self, target: usize, writeback: bool) -> usize {
+        let mut groups = self.groups.lock();
+        let mut freed = 0;
+        while freed < target {
+            let Some(victim) = groups.victim() else {
+                break;
+            };
+            if groups.bitmaps[&victim].dirty {
+                if !writeback {
+                    break;
+                }
+                self.write_device(victim, &groups.bitmaps[&victim].data);
+                groups.stats.writebacks += 1;
+            }
+            groups.bitmaps.remove(&victim);
+            groups.stats.evictions += 1;
+            freed += BLOCK_SIZE;
+        }
+        freed
+    }
+}
+
+impl BlockDevice for Ext4Disk {
+    // the group cache is locked while accessing the device, so the cached
+    // bitmaps can't be changed between the device access and the overlay.
+    fn read_offset(&self, offset: usize) -> Vec<u8> {
+        let groups = self.groups.lock();
+        if let Some(block) = groups.bitmaps.get(&offset) {
+            return block.data.clone();
+        }
+        let mut buf = vec![0; BLOCK_SIZE];
+        self.read_overlaid(&groups, offset, &mut buf);
+        buf
+    }
+
+    fn write_offset(&self, offset: usize, buf: &[u8]) {
+        let mut groups = self.groups.lock();
+        match self.txn.lock().as_mut() {
+            // the write reaches the disk when the transaction is committed.
+            Some(txn) => {
+                txn.save_bitmaps(&groups, offset, buf.len());
+                txn.write(offset, buf, |block_off, data| {
+                    self.read_device_into(block_off, data);
+                    groups.overlay(block_off, data);
+                });
+            }
+            None => self.write_device(offset, buf),
+        }
+        groups.patch(offset, buf);
+    }
+}
+
+/// The blocks of a file open for writing after its last append are its
+/// allocation window: the allocations for the other files leave them
+/// while they find others, so the files appended to in turn stay in long
+/// extents. Nothing is taken on the disk, a full volume gives them out.
+/// The windows of the files appended to last are kept, the others are
+/// dropped, like the reservation windows of ext3.
+pub(crate) struct AllocWindows {
+    /// The window of the inode and the sequence of its last append.
+    files: BTreeMap<u32, (Range<u64>, u64)>,
+    next: u64,
+}
+
+impl AllocWindows {
+    const fn new() -> Self {
+        Self {
+            files: BTreeMap::new(),
+            next: 0,
+        }
+    }
+
+    /// Move the window of the inode to the blocks, drop the one of the
+    /// least recent append beyond MAX_WINDOWS.
+    pub(crate) fn set(&mut self, ino: u32, blocks: Range<u64>) {
+        self.next += 1;
+        self.files.insert(ino, (blocks, self.next));
+        if self.files.len() <= MAX_WINDOWS {
+            return;
+        }
+        if let Some(oldest) = self.files.iter().min_by_key(|x| x.1 .1).map(|x| *x.0) {
+            self.files.remove(&oldest);
+        }
+    }
+
+    /// Drop the window of the inode, its file is closed.
+    pub(crate) fn remove(&mut self, ino: u32) {
+        self.files.remove(&ino);
+    }
+
+    /// The windows of the files but the inode.
+    fn others(&self, ino: Option<u32>) -> Vec<Range<u64>> {
+        self.files
+            .iter()
+            .filter(|x| Some(*x.0) != ino)
+            .map(|x| x.1 .0.clone())
+            .collect()
+    }
+}
+
+/// The blocks of an allocation window, 1 MiB of 4 KiB blocks.
+pub(crate) const ALLOC_WINDOW: u64 = 256;
+/// The max allocation windows of a volume.
+const MAX_WINDOWS: usize = 32;
+
+/// A free count of the volume, of the blocks or of the inodes, read by
+/// statfs without a lock. An allocation reserves from it first, so two
+/// racing ones can't both take the last block, and the freed ones join it
+/// when their transaction commits. The counts of the groups are the
+/// authority, see GroupFree.
+struct FreeCount {
+    /// The free ones committed, less those taken by the running
+    /// transaction.
+    free: AtomicU64,
+    pending: Mutex<PendingCount>,
+}
+
+/// What the running transaction did to a FreeCount.
+#[derive(Debug, Default)]
+struct PendingCount {
+    /// The reserved ones taken from free.
+    taken: u64,
+    /// The ones freed and not reserved again, they may be reserved by the
+    /// same transaction.
+    freed: u64,
+}
+
+impl FreeCount {
+    const fn new() -> Self {
+        Self {
+            free: AtomicU64::new(0),
+            pending: Mutex::new(PendingCount { taken: 0, freed: 0 }),
+        }
+    }
+
+    fn get(&self) -> u64 {
+        self.free.load(Ordering::Acquire)
+    }
+
+    /// Reserve up to count of them, leaving keep of the committed ones
+    /// free. return the number reserved.
+    fn reserve(&self, count: u64, keep: u64) -> u64 {
+        let mut pending = self.pending.lock();
+        let freed = count.min(pending.freed);
+        pending.freed -= freed;
+        let want = count - freed;
+        let take = |free: u64| want.min(free.saturating_sub(keep));
+        let old = self
+            .free
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |x| Some(x - take(x)))
+            .unwrap();
+        pending.taken += take(old);
+        freed + take(old)
+    }
+
+    /// Give back count reserved ones which weren't used.
+    fn release(&self, count: u64) {
+        let mut pending = self.pending.lock();
+        let taken = count.min(pending.taken);
+        pending.taken -= taken;
+        pending.freed += count - taken;
+        self.free.fetch_add(taken, Ordering::AcqRel);
+    }
+
+    /// Count the ones freed by the running transaction.
+    fn add_freed(&self, count: u64) {
+        self.pending.lock().freed += count;
+    }
+
+    /// End the running transaction: what it took is given back, and delta,
+    /// the change of the counts of the groups by its commit, is applied.
+    fn end(&self, delta: i64) {
+        let mut pending = self.pending.lock();
+        let change = pending.taken as i64 + delta;
+        *pending = PendingCount::default();
+        self.free
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |x| {
+                Some((x as i64 + change).max(0) as u64)
+            })
+            .unwrap();
+    }
+}
+
+/// The committed free blocks and inodes of each group, and their totals.
+/// They are counted from the group descriptors at the mount, and moved by
+/// the descriptors each commit changes, so the allocations of ext4_rs
+/// count too. The totals are written to the superblock in the same
+/// transaction.
+#[derive(Debug, Default)]
+struct GroupFree {
+    groups: Vec<(u32, u32)>,
+    blocks: u64,
+    inodes: u64,
+}
+
+/// The change of the free counts by a transaction, applied once it's
+/// committed.
+#[derive(Debug, Default)]
+struct FreeChange {
+    groups: Vec<(usize, (u32, u32))>,
+    blocks: i64,
+    inodes: i64,
+}
+
+/// Ext4Volume is shared by the filesystem and all the file wrappers.
+/// sb: the superblock read at mount, only the geometry fields are
+/// reliable, the counters must be read again.
+pub struct Ext4Volume {
+    pub(crate) disk: Arc<Ext4Disk>,
+    pub(crate) sb: SuperBlockInfo,
+    counters: FsCounters,
+    /// The free blocks and inodes for statfs and the allocations.
+    free_block_count: FreeCount,
+    free_inode_count: FreeCount,
+    group_free: Mutex<GroupFree>,
+    /// The free counts are counted, a fast mount counts them on demand,
+    /// see count_free_lazily.
+    free_counted: AtomicBool,
+    /// Why the volume is read-only, None if it's writable. It changes
+    /// with a remount.
+    read_only: Mutex<Option<ReadOnlyReason>>,
+    /// Why the volume should be read-only when force_rw mounted it writable.
+    forced_rw: Option<ReadOnlyReason>,
+    pub(crate) options: MountOptions,
+    pub(crate) open: Mutex<OpenInodes>,
+    /// The journal committing the transactions, None if the image has no
+    /// usable journal. It's locked for the whole transaction.
+    pub(crate) journal: Mutex<Option<Journal>>,
+    /// The generations given to the new inodes since the mount.
+    generations: AtomicU32,
+    /// The root directory of the mount, ".." of it is itself. It's not
+    /// ROOT_INO if a subtree is mounted.
+    root_ino: AtomicU32,
+    /// The wrappers of the files by their id, a remount to read-only
+    /// writes back their buffered writes. A wrapper removes itself when
+    /// it's dropped.
+    wrappers: Mutex<BTreeMap<u64, Weak<Ext4FileWrapper>>>,
+    /// The id of the next wrapper.
+    next_wrapper: AtomicU64,
+    /// The inodes and the ids of the wrappers holding buffered writes, a
+    /// read of an inode writes back only the buffers of its wrappers.
+    buffered: Mutex<BTreeSet<(u32, u64)>>,
+    /// The blocks the write buffers may allocate at their flush, held
+    /// against the free blocks, see hold_buffered.
+    buffered_blocks: AtomicU64,
+    /// The allocation windows of the open files, see take_blocks.
+    pub(crate) windows: Mutex<AllocWindows>,
+    /// The mutating operations enter it, see begin_write.
+    gate: FreezeGate,
+    /// The changes of the directory entries enter it, see
+    /// begin_dir_write.
+    dir_gate: Arc<FreezeGate>,
+    /// The usage of the owners with the quota option, counted at the mount.
+    quota: Option<QuotaTable>,
+    /// The charges of the running transaction, applied with its commit.
+    quota_pending: Mutex<Vec<Charge>>,
+    /// The data blocks freed by the running transaction with
+    /// secure_delete, zeroed after its commit.
+    zero_pending: Mutex<Vec<(u64, u64)>>,
+    /// The directories whose blocks the running transaction wrote, see
+    /// write_dir_block.
+    dirs_pending: Mutex<BTreeSet<u32>>,
+    /// The snapshots of the inodes with wrappers, for stat, see
+    /// snapshot.rs. The dropped ones are removed lazily.
+    snapshots: Mutex<BTreeMap<u32, Weak<Snapshot>>>,
+    /// The append locks of the inodes appended to, see append_lock. The
+    /// dropped ones are removed lazily.
+    appends: Mutex<BTreeMap<u32, Weak<Mutex<()>>>>,
+    /// The inodes changed by the running transaction, their snapshots are
+    /// published at its end.
+    snapshot_pending: Mutex<Vec<u32>>,
+    /// The sync policy, the one of the options until a remount.
+    sync_policy: Mutex<SyncPolicy>,
+    /// The readahead, the one of the options until tunefs changes it.
+    readahead_blocks: AtomicUsize,
+    /// The wrappers of the files given to the callers, see open_file.
+    /// The directories and the wrappers of the lookups aren't counted,
+    /// the resolvers walk through them without keeping them open.
+    open_files: AtomicUsize,
+    /// The max of open_files, usize::MAX for no limit.
+    max_open_files: AtomicUsize,
+    /// The running batches of batch.rs, the transactions are deferred
+    /// while there is one.
+    batches: AtomicUsize,
+    /// The blocks whose device requests failed at every try, with the
+    /// relocate option. It's in memory only, the allocations skip them
+    /// until the unmount.
+    bad_blocks: Mutex<BTreeSet<u64>>,
+    /// The logical blocks moved off a bad block, see relocate_block.
+    relocated: AtomicUsize,
+    /// The last transaction rolled back badlisted the data blocks of its
+    /// failed writes, see write_direct.
+    relocation: AtomicBool,
+    /// The errors of the superblock, with those found since the mount.
+    errors: Mutex<ErrorLog>,
+    /// The readaheads deferred by the reads, see background_work.
+    background: Mutex<Background>,
+    /// The mount count and the write time of the superblock as of the
+    /// mount or the last revalidate, see revalidate.
+    seen: Mutex<(u16, u32)>,
+}
+
+/// The pages of a readahead deferred by a read of the inode.
+#[derive(Debug, Clone, Copy)]
+struct Prefetch {
+    ino: u32,
+    index: usize,
+    pages: usize,
+}
+
+/// The work deferred to Ext4FileSystem::background_work.
+#[derive(Debug, Default)]
+struct Background {
+    prefetch: VecDeque<Prefetch>,
+    /// The calls in a row which yielded to the pressure.
+    yields: usize,
+    stats: BackgroundStats,
+}
+
+/// The counters of Ext4FileSystem::background_work.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BackgroundStats {
+    /// The pages of the readaheads the reads deferred.
+    pub deferred_pages: usize,
+    /// The deferred pages read into the page cache.
+    pub prefetched_pages: usize,
+    /// The deferred pages not read: cached or truncated meanwhile, of a
+    /// closed file, over MAX_DEFERRED_READAHEAD or failing.
+    pub dropped_pages: usize,
+    /// The calls which yielded to the pressure of the device.
+    pub yields: usize,
+}
+
+/// The error history of the volume, dirty until it's in the superblock.
+struct ErrorLog {
+    history: ErrorHistory,
+    dirty: bool,
+}
+
+/// The journal inode, it's empty between the transactions since every
+/// transaction is checkpointed right after its commit.
+pub(crate) struct Journal {
+    pub(crate) jsb: JournalSuperBlock,
+    /// The journal superblock as it's on the disk.
+    pub(crate) jsb_block: Vec<u8>,
+    /// The blocks of the journal inode.
+    pub(crate) extents: ExtentCache,
+}
+
+impl Journal {
+    /// Map the logical block of the journal to the disk.
+    pub(crate) fn physical(&self, lblock: u32) -> VfsResult<u64> {
+        self.extents
+            .lookup(lblock)
+            .map(|x| x.physical + (lblock - x.logical) as u64)
+            .ok_or(VfsError::InvalidData)
+    }
+}
+
+/// The wrappers alive for each inode. An unlinked inode which is still
+/// open stays on the orphan list until its last wrapper is dropped.
+pub(crate) struct OpenInodes {
+    pub(crate) wrappers: BTreeMap<u32, usize>,
+    pub(crate) unlinked: BTreeSet<u32>,
+}
+
+/// The options of the ext4 mount, see Ext4FileSystem::builder.
+#[derive(Debug, Clone, Copy)]
+pub struct MountOptions {
+    /// Mount writable even if the image should be mounted read-only, for
+    /// the development only: writing may corrupt the image further.
+    pub force_rw: bool,
+    /// Never write the image, the journal is replayed in memory and the
+    /// disk drops any write as a backstop.
+    pub read_only: bool,
+    /// The memory of the cached bitmap blocks, at least two blocks.
+    pub block_cache_bytes: usize,
+    /// The pages read with a page cache miss after the missed one.
+    pub readahead_blocks: usize,
+    /// The pages of the readahead read with the miss, the rest is deferred
+    /// to background_work, none if the device is busy with foreground
+    /// requests. MAX_READAHEAD_BLOCKS reads the readahead with the miss.
+    pub readahead_inline: usize,
+    /// When the reads update the access times, see atime.rs.
+    pub atime: AtimePolicy,
+    /// Count the usage of the owners and the groups, see quota.rs.
+    pub quota: bool,
+    /// Zero the data blocks of the deleted and truncated files when they
+    /// are freed, the metadata blocks are freed as they are.
+    pub secure_delete: bool,
+    /// Every allocation may take the reserved blocks, not only those of
+    /// root and of s_def_resuid and s_def_resgid.
+    pub reserve_override: bool,
+    /// The seconds since the epoch, the last write time of the superblock
+    /// stands for the time without it.
+    pub time_source: Option<fn() -> u64>,
+    /// When the operations reach the disk, see fsync.rs.
+    pub sync_policy: SyncPolicy,
+    /// The group whose backup superblock and group descriptors are read if
+    /// the primary superblock is invalid, the mount is read-only then.
+    pub backup_superblock: Option<u32>,
+    /// Fail the unaligned direct transfers with InvalidInput instead of
+    /// passing them to the caches, see direct.rs.
+    pub strict_direct: bool,
+    /// The files of the mount open at once, a busy process can't pin the
+    /// memory of more, see Ext4Volume::open_files. None is no limit.
+    pub max_open_files: Option<usize>,
+    /// Build the same image from the same operations, for the reproducible
+    /// builds: the new directories stay in the group of their parent like
+    /// the files, so every inode is the first free one from that group on
+    /// and every run of blocks the first free one from its goal on, in the
+    /// order of the groups. The generations are of the uuid and the inode,
+    /// the times of the time source, which should be a constant, or of the
+    /// superblock. The transactions aren't written back by the writeback
+    /// steps of a timer: the write_back policy is refused.
+    pub deterministic: bool,
+    /// Refuse the data writes into the superblocks, the group descriptors
+    /// and the journal superblock, see Ext4Disk::guard_write. On by
+    /// default in the tests.
+    pub write_guard: bool,
+    /// Refuse the data writes into the bitmaps too, with write_guard.
+    pub guard_bitmaps: bool,
+    /// The tries of a device request which fails, on a device counting its
+    /// errors, see DeviceErrors of blockdev.
+    pub retry: RetryPolicy,
+    /// Badlist the data blocks whose device requests fail at every try on
+    /// a writable mount, and move a logical block whose write fails to a
+    /// new block so the file stays writable. The read of a bad block still
+    /// fails with EIO. The write-back policy defers the writes, their
+    /// failures aren't seen.
+    pub relocate: bool,
+    /// Mount read-only for the boot, with the work the first reads don't
+    /// need deferred: the journal is replayed block by block by their
+    /// first reads, and the free counts are counted by the first statfs.
+    /// The group descriptors and the inodes are validated when they're
+    /// first read, like by every mount. Refused without read_only and
+    /// with quota, which counts every inode.
+    pub fast: bool,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        Self {
+            force_rw: false,
+            read_only: false,
+            block_cache_bytes: DEFAULT_BLOCK_CACHE_BYTES,
+            readahead_blocks: 0,
+            readahead_inline: DEFAULT_READAHEAD_INLINE,
+            atime: AtimePolicy::Relatime,
+            quota: false,
+            secure_delete: false,
+            reserve_override: false,
+            time_source: None,
+            sync_policy: SyncPolicy::WriteThrough,
+            backup_superblock: None,
+            strict_direct: false,
+            max_open_files: None,
+            deterministic: false,
+            write_guard: cfg!(feature = "testsuite"),
+            guard_bitmaps: false,
+            retry: RetryPolicy::default(),
+            relocate: false,
+            fast: false,
+        }
+    }
+}
+
+impl MountOptions {
+    /// Reject the options which can't be honored together.
+    fn validate(&self) -> VfsResult<()> {
+        let reason = if self.read_only && self.force_rw {
+            "read_only and force_rw"
+        } else if self.block_cache_bytes < 2 * BLOCK_SIZE {
+            "block cache under two blocks"
+        } else if let Some(reason) = readahead_error(self.readahead_blocks) {
+            reason
+        } else if !self.sync_policy.is_valid() {
+            "write back of no block"
+        } else if self.max_open_files == Some(0) {
+            "no open file"
+        } else if self.deterministic && matches!(self.sync_policy, SyncPolicy::WriteBack { .. }) {
+            "deterministic and write_back"
+        } else if self.guard_bitmaps && !self.write_guard {
+            "guard_bitmaps without write_guard"
+        } else if self.retry.attempts == 0 {
+            "retry of no attempt"
+        } else if self.backup_superblock.is_some() && self.force_rw {
+            // the writes would go to the primary, under the backup.
+            "backup_superblock and force_rw"
+        } else if self.fast && !self.read_only {
+            "fast without read_only"
+        } else if self.fast && self.quota {
+            "fast and quota"
+        } else {
+            return Ok(());
+        };
+        log::error!("invalid ext4 mount options: {}", reason);
+        Err(VfsError::InvalidInput)
+    }
+}
+
+/// Why the readahead can't be honored, None if it can.
+fn readahead_error(blocks: usize) -> Option<&'static str> {
+    if blocks > MAX_READAHEAD_BLOCKS {
+        Some("readahead too long")
+    } else if (blocks + 1) * PAGE_SIZE > cache::budget() {
+        // the readahead would evict the pages it reads.
+        Some("readahead over the page cache budget")
+    } else {
+        None
+    }
+}
+
+/// Build a mount of ext4 with the options.
+pub struct Ext4Builder {
+    source: MountSource,
+    options: MountOptions,
+    subtree: Option<String>,
+    cancel: Option<MountCancel>,
+    deadline: Option<(fn() -> u64, u64)>,
+}
+
+/// The cancellation of a mount, it's cancelled once it returns true, see
+/// Ext4Builder::cancel.
+pub type MountCancel = Arc<dyn Fn() -> bool + Send + Sync>;
+
+/// How far a mount got, the phases in their order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MountPhase {
+    /// The superblock and the group descriptors are read.
+    Superblock,
+    /// The superblock and its features are validated.
+    Features,
+    /// The journal is replayed.
+    Journal,
+    /// The free counts of the groups are read from their descriptors.
+    FreeCounts,
+    /// The orphans left by a crash are released.
+    Orphans,
+    /// The quota usage is counted from the inodes.
+    Quota,
+    /// The root directory is loaded.
+    Root,
+}
+
+/// A mount which failed, with the phase it got to.
+#[derive(Debug, Clone)]
+pub struct MountError {
+    pub error: VfsError,
+    /// ETIMEDOUT past the deadline, EINTR for a cancellation and EBUSY for
+    /// a device already mounted.
+    pub errno: Errno,
+    pub phase: MountPhase,
+    /// The mount was cancelled or ran past its deadline, error is Blocking
+    /// then.
+    pub cancelled: bool,
+}
+
+impl From<MountError> for VfsError {
+    fn from(err: MountError) -> Self {
+        err.error
+    }
+}
+
+impl From<MountError> for Errno {
+    fn from(err: MountError) -> Self {
+        err.errno
+    }
+}
+
+/// The phase and the cancellation of a running mount, the disk polls it
+/// between the device requests of the phases, see Ext4Disk::mount_poll.
+struct MountProgress {
+    cancel: Option<MountCancel>,
+    /// The clock and the time the mount times out at.
+    deadline: Option<(fn() -> u64, u64)>,
+    phase: Mutex<MountPhase>,
+    cancelled: AtomicBool,
+    /// The cancellation was the deadline.
+    timed_out: AtomicBool,
+}
+
+impl MountProgress {
+    /// Blocking once the mount is cancelled or past its deadline, the
+    /// errno of MountError tells which.
+    fn poll(&self) -> VfsResult<()> {
+        let expired = self.deadline.is_some_and(|(now, at)| now() >= at);
+        if !expired && !self.cancel.as_ref().is_some_and(|x| x()) {
+            return Ok(());
+        }
+        if !self.cancelled.swap(true, Ordering::Relaxed) {
+            self.timed_out.store(expired, Ordering::Relaxed);
+            log::error!(
+                "the ext4 mount is {} in the {:?} phase",
+                match expired {
+                    true => "timed out",
+                    false => "cancelled",
+                },
+                *self.phase.lock()
+            );
+        }
+        Err(VfsError::Blocking)
+    }
+}
+
+/// The ext4 type of fstype.rs, it detects the magic of the superblock.
+pub(crate) const FSTYPE: FsType = FsType {
+    name: "ext4",
+    detect: Some(fstype::has_ext4_magic),
+    identify: Some(fstype::ext4_volume_id),
+    mount: mount_source,
+    priority: 10,
+};
+
+/// Mount the source of fstype.rs, a partition is a Partition of its sys
+/// device and a file a LoopDevice.
+fn mount_source(source: fstype::MountSource, flags: MountFlags) -> VfsResult<Arc<dyn FileSystem>> {
+    let builder = match source {
+        fstype::MountSource::Device(device_id) => Ext4FileSystem::builder(device_id),
+        fstype::MountSource::Partition {
+            device_id,
+            start,
+            size,
+        } => {
+            let Some(device) = SectorDevice::new(device_id) else {
+                log::error!("can't mount ext4, no block device {}", device_id);
+                return Err(VfsError::InvalidInput);
+            };
+            let partition = blockdev::Partition::new(Arc::new(device), start, size);
+            Ext4FileSystem::builder_from_device(Arc::new(partition))
+        }
+        fstype::MountSource::File(file) => {
+            Ext4FileSystem::builder_from_device(Arc::new(blockdev::LoopDevice::new(file)))
+        }
+        fstype::MountSource::None => return Err(VfsError::InvalidInput),
+    };
+    let fs = builder
+        .read_only(flags.contains(MountFlags::RDONLY))
+        .atime(AtimePolicy::from_flags(flags))
+        .mount()?;
+    Ok(fs as Arc<dyn FileSystem>)
+}
+
+/// The sys devices with a mount, an Ext4Disk removes its device when it's
+/// dropped. The anon_dev numbers are never reused, they aren't in it.
+static MOUNTED_DEVICES: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+
+/// The device of a mount.
+enum MountSource {
+    /// The device id of sys, read by a SectorDevice.
+    DeviceId(usize),
+    Device(Arc<dyn BlockDevice + Send + Sync>),
+}
+
+impl Ext4Builder {
+    pub fn force_rw(mut self, force_rw: bool) -> Self {
+        self.options.force_rw = force_rw;
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.options.read_only = read_only;
+        self
+    }
+
+    pub fn block_cache_bytes(mut self, bytes: usize) -> Self {
+        self.options.block_cache_bytes = bytes;
+        self
+    }
+
+    pub fn readahead_blocks(mut self, blocks: usize) -> Self {
+        self.options.readahead_blocks = blocks;
+        self
+    }
+
+    pub fn readahead_inline(mut self, pages: usize) -> Self {
+        self.options.readahead_inline = pages;
+        self
+    }
+
+    pub fn atime(mut self, atime: AtimePolicy) -> Self {
+        self.options.atime = atime;
+        self
+    }
+
+    /// noatime, or relatime without it.
+    pub fn noatime(mut self, noatime: bool) -> Self {
+        self.options.atime = match noatime {
+            true => AtimePolicy::Noatime,
+            false => AtimePolicy::Relatime,
+        };
+        self
+    }
+
+    pub fn quota(mut self, quota: bool) -> Self {
+        self.options.quota = quota;
+        self
+    }
+
+    pub fn secure_delete(mut self, secure_delete: bool) -> Self {
+        self.options.secure_delete = secure_delete;
+        self
+    }
+
+    pub fn strict_direct(mut self, strict_direct: bool) -> Self {
+        self.options.strict_direct = strict_direct;
+        self
+    }
+
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.options.sync_policy = policy;
+        self
+    }
+
+    pub fn max_open_files(mut self, max: usize) -> Self {
+        self.options.max_open_files = Some(max);
+        self
+    }
+
+    pub fn reserve_override(mut self, reserve_override: bool) -> Self {
+        self.options.reserve_override = reserve_override;
+        self
+    }
+
+    pub fn time_source(mut self, now: fn() -> u64) -> Self {
+        self.options.time_source = Some(now);
+        self
+    }
+
+    /// Build the same image from the same operations, see MountOptions.
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.options.deterministic = deterministic;
+        self
+    }
+
+    /// Refuse the data writes into the metadata, see MountOptions.
+    pub fn write_guard(mut self, write_guard: bool) -> Self {
+        self.options.write_guard = write_guard;
+        self
+    }
+
+    pub fn guard_bitmaps(mut self, guard_bitmaps: bool) -> Self {
+        self.options.guard_bitmaps = guard_bitmaps;
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.options.retry = retry;
+        self
+    }
+
+    /// Move the blocks whose writes fail, see MountOptions.
+    pub fn relocate(mut self, relocate: bool) -> Self {
+        self.options.relocate = relocate;
+        self
+    }
+
+    /// Mount read-only for the boot, see MountOptions::fast.
+    pub fn fast(mut self, fast: bool) -> Self {
+        self.options.fast = fast;
+        self.options.read_only |= fast;
+        self
+    }
+
+    /// Recover an image whose primary superblock is corrupted: read the
+    /// superblock and the group descriptors from the backup of the group,
+    /// like e2fsck -b, and mount read-only. An image with a valid primary
+    /// superblock is mounted as usual.
+    pub fn backup_superblock(mut self, group: u32) -> Self {
+        self.options.backup_superblock = Some(group);
+        self
+    }
+
+    /// Cancel the mount once cancel returns true, like a sick device which
+    /// doesn't answer. It's checked between the device requests while the
+    /// superblock is read, the features are validated, the journal is
+    /// replayed and the orphans are released. A request which never
+    /// returns still hangs the mount.
+    pub fn cancel(mut self, cancel: MountCancel) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Time the mount out once now returns at or later, checked like
+    /// cancel.
+    pub fn deadline(mut self, now: fn() -> u64, at: u64) -> Self {
+        self.deadline = Some((now, at));
+        self
+    }
+
+    /// Mount the directory at path of the image as the root, its ".." is
+    /// itself. The path is walked from the root of the image without
+    /// following the links, ".." fails the mount with InvalidInput.
+    pub fn subtree(mut self, path: &str) -> Self {
+        self.subtree = Some(String::from(path));
+        self
+    }
+
+    /// Mount the device, fail with InvalidInput if the options are invalid
+    /// or there is no such device, InvalidData if the disk isn't a valid
+    /// ext4 image, and NotSupported if the image needs a feature the shim
+    /// doesn't have. The images of a removable device can't be trusted.
+    pub fn mount(self) -> VfsResult<Arc<Ext4FileSystem>> {
+        self.try_mount().map_err(VfsError::from)
+    }
+
+    /// Mount like mount, a failure tells the phase it got to. A mount
+    /// cancelled or past its deadline fails with Blocking, see cancel, the
+    /// boot can log it and try the next device.
+    pub fn try_mount(self) -> Result<Arc<Ext4FileSystem>, MountError> {
+        let progress = Arc::new(MountProgress {
+            cancel: self.cancel.clone(),
+            deadline: self.deadline,
+            phase: Mutex::new(MountPhase::Superblock),
+            cancelled: AtomicBool::new(false),
+            timed_out: AtomicBool::new(false),
+        });
+        self.mount_with(progress.clone()).map_err(|err| {
+            let cancelled = progress.cancelled.load(Ordering::Relaxed);
+            let errno = match progress.timed_out.load(Ordering::Relaxed) {
+                true if cancelled => Errno::ETIMEDOUT,
+                false if cancelled => Errno::EINTR,
+                _ => err.errno,
+            };
+            MountError {
+                error: err.error,
+                errno,
+                phase: *progress.phase.lock(),
+                cancelled,
+            }
+        })
+    }
+
+    fn mount_with(self, progress: Arc<MountProgress>) -> FsResult<Arc<Ext4FileSystem>> {
+        self.options.validate()?;
+        let (dev, device) = match self.source {
+            MountSource::DeviceId(device_id) => {
+                let Some(device) = SectorDevice::new(device_id) else {
+                    log::error!("can't mount ext4, no block device {}", device_id);
+                    return Err(VfsError::InvalidInput);
+                };
+                // the two mounts would share the pages of dev and
+                // overwrite each other's metadata.
+                if !MOUNTED_DEVICES.lock().insert(device_id) {
+                    log::error!("can't mount ext4, device {} is mounted", device_id);
+                    return Err(FsError::new(VfsError::InvalidInput, Errno::EBUSY));
+                }
+                (
+                    device_id,
+                    Arc::new(device) as Arc<dyn BlockDevice + Send + Sync>,
+                )
+            }
+            MountSource::Device(device) => (anon_dev(), device),
+        };
+        Ok(Ext4FileSystem::mount(
+            dev,
+            device,
+            self.options,
+            self.subtree.as_deref(),
+            progress,
+        )?)
+    }
+}
+
+/// Why the image was mounted read-only.
+#[derive(Debug, Clone)]
+pub enum ReadOnlyReason {
+    /// The superblock doesn't match its checksum.
+    SuperblockChecksum,
+    /// The journal needs to be replayed but the replay failed.
+    JournalReplay(VfsError),
+    /// The filesystem was marked with errors (s_state), run e2fsck first.
+    ErrorState,
+    /// The primary superblock is invalid, the backup of the group is read.
+    BackupSuperblock(u32),
+    /// The image has the ro_compat features, whose invariants the writes
+    /// wouldn't keep.
+    RoCompatFeatures(u32),
+    /// The read_only option.
+    Requested,
+}
+
+/// The state of the mount reported to the kernel.
+#[derive(Debug, Clone)]
+pub struct MountInfo {
+    /// None if the volume is writable.
+    pub read_only: Option<ReadOnlyReason>,
+    /// The image should be read-only but force_rw mounted it writable.
+    pub forced_rw: Option<ReadOnlyReason>,
+    /// The modifications are committed by the journal.
+    pub journaled: bool,
+}
+
+/// How the mount handles a feature of the superblock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeatureHandling {
+    /// The shim implements the feature or can ignore it, the image is
+    /// writable.
+    Supported,
+    /// The image is read correctly but mounted read-only, unless force_rw.
+    ReadOnly,
+    /// The image isn't mounted.
+    Refused,
+}
+
+/// The fields of the features in the superblock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeatureKind {
+    /// s_feature_compat, the features an implementation without them can
+    /// still write.
+    Compat,
+    /// s_feature_incompat, the features needed to read the image.
+    Incompat,
+    /// s_feature_ro_compat, the features needed to write the image.
+    RoCompat,
+}
+
+impl FeatureKind {
+    /// The handling of a feature bit of the field.
+    pub fn handling(self, bit: u32) -> FeatureHandling {
+        match self {
+            Self::Compat => FeatureHandling::Supported,
+            Self::Incompat if bit & SUPPORTED_INCOMPAT != 0 => FeatureHandling::Supported,
+            Self::Incompat => FeatureHandling::Refused,
+            Self::RoCompat if bit & SUPPORTED_RO_COMPAT != 0 => FeatureHandling::Supported,
+            Self::RoCompat if bit & REFUSED_RO_COMPAT != 0 => FeatureHandling::Refused,
+            Self::RoCompat => FeatureHandling::ReadOnly,
+        }
+    }
+
+    /// The bits of flags with the handling.
+    fn select(self, flags: u32, handling: FeatureHandling) -> u32 {
+        (0..32)
+            .map(|x| 1 << x)
+            .filter(|&bit| flags & bit != 0 && self.handling(bit) == handling)
+            .fold(0, |acc, bit| acc | bit)
+    }
+
+    fn names(self, flags: u32) -> String {
+        match self {
+            Self::Compat => compat_names(flags),
+            Self::Incompat => incompat_names(flags),
+            Self::RoCompat => ro_compat_names(flags),
+        }
+    }
+}
+
+/// A feature set in the superblock.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Feature {
+    pub kind: FeatureKind,
+    pub bit: u32,
+    /// The name of mke2fs, the unknown features in hex.
+    pub name: String,
+    pub handling: FeatureHandling,
+}
+
+/// The features of the image and how it was mounted for them.
+#[derive(Debug, Clone)]
+pub struct MountReport {
+    /// Every feature bit set, by field and bit.
+    pub features: Vec<Feature>,
+    pub mount: MountInfo,
+}
+
+/// The handling of every feature bit set in the superblock.
+fn feature_matrix(sb: &SuperBlockInfo) -> Vec<Feature> {
+    let fields = [
+        (FeatureKind::Compat, sb.feature_compat),
+        (FeatureKind::Incompat, sb.feature_incompat),
+        (FeatureKind::RoCompat, sb.feature_ro_compat),
+    ];
+    let mut features = Vec::new();
+    for (kind, flags) in fields {
+        for bit in (0..32).map(|x| 1u32 << x).filter(|&x| flags & x != 0) {
+            features.push(Feature {
+                kind,
+                bit,
+                name: kind.names(bit),
+                handling: kind.handling(bit),
+            });
+        }
+    }
+    features
+}
+
+impl Ext4Volume {
+    /// Fail with InvalidData if the disk isn't ext4 or the geometry of the
+    /// superblock is invalid, nothing else can be trusted then. Fail with
+    /// NotSupported if the image needs a feature the shim doesn't have.
+    fn new(disk: Arc<Ext4Disk>, options: MountOptions) -> VfsResult<Self> {
+        let raw = disk.read_offset(SUPERBLOCK_OFFSET);
+        let sb = SuperBlockInfo::parse(&raw);
+        disk.mount_step(MountPhase::Features)?;
+        if sb.magic != EXT4_SUPER_MAGIC {
+            log::error!("can't mount ext4, the disk has no ext4 superblock");
+            return Err(VfsError::InvalidData);
+        }
+        if let Err(reason) = sb.validate() {
+            log::error!("can't mount ext4, the superblock is invalid: {}", reason);
+            return Err(VfsError::InvalidData);
+        }
+        // ext4_rs and the disk only work on 4K blocks.
+        if sb.block_size() != BLOCK_SIZE {
+            log::error!(
+                "can't mount ext4, the block size {} isn't supported",
+                sb.block_size()
+            );
+            return Err(VfsError::NotSupported);
+        }
+        // the ro_compat features of FeatureHandling::ReadOnly are left to
+        // read_only_reason, force_rw can override them.
+        let fields = [
+            (FeatureKind::Incompat, sb.feature_incompat),
+            (FeatureKind::RoCompat, sb.feature_ro_compat),
+        ];
+        for (kind, flags) in fields {
+            let refused = kind.select(flags, FeatureHandling::Refused);
+            if refused != 0 {
+                log::error!(
+                    "can't mount ext4, the features aren't supported: {}",
+                    kind.names(refused)
+                );
+                return Err(VfsError::NotSupported);
+            }
+        }
+        Ok(Self {
+            disk,
+            sb,
+            counters: FsCounters::new(),
+            free_block_count: FreeCount::new(),
+            free_inode_count: FreeCount::new(),
+            group_free: Mutex::new(GroupFree {
+                groups: Vec::new(),
+                blocks: 0,
+                inodes: 0,
+            }),
+            free_counted: AtomicBool::new(false),
+            read_only: Mutex::new(options.read_only.then_some(ReadOnlyReason::Requested)),
+            forced_rw: None,
+            options,
+            open: Mutex::new(OpenInodes {
+                wrappers: BTreeMap::new(),
+                unlinked: BTreeSet::new(),
+            }),
+            journal: Mutex::new(None),
+            generations: AtomicU32::new(0),
+            root_ino: AtomicU32::new(ROOT_INO),
+            wrappers: Mutex::new(BTreeMap::new()),
+            next_wrapper: AtomicU64::new(0),
+            buffered: Mutex::new(BTreeSet::new()),
+            buffered_blocks: AtomicU64::new(0),
+            windows: Mutex::new(AllocWindows::new()),
+            gate: FreezeGate::new(),
+            dir_gate: Arc::new(FreezeGate::new()),
+            quota: None,
+            quota_pending: Mutex::new(Vec::new()),
+            zero_pending: Mutex::new(Vec::new()),
+            dirs_pending: Mutex::new(BTreeSet::new()),
+            snapshots: Mutex::new(BTreeMap::new()),
+            appends: Mutex::new(BTreeMap::new()),
+            snapshot_pending: Mutex::new(Vec::new()),
+            sync_policy: Mutex::new(options.sync_policy),
+            readahead_blocks: AtomicUsize::new(options.readahead_blocks),
+            open_files: AtomicUsize::new(0),
+            max_open_files: AtomicUsize::new(options.max_open_files.unwrap_or(usize::MAX)),
+            batches: AtomicUsize::new(0),
+            bad_blocks: Mutex::new(BTreeSet::new()),
+            relocated: AtomicUsize::new(0),
+            relocation: AtomicBool::new(false),
+            errors: Mutex::new(ErrorLog {
+                history: ErrorHistory::parse(&raw),
+                dirty: false,
+            }),
+            background: Mutex::new(Background::default()),
+            seen: Mutex::new(superblock_stamp(&raw)),
+        })
+    }
+
+    /// The time of the time source, or the last write time of the
+    /// superblock without a clock.
+    pub(crate) fn now(&self) -> u32 {
+        match self.options.time_source {
+            Some(now) => now() as u32,
+            None => le_u32(&self.disk.read_offset(SUPERBLOCK_OFFSET), S_WTIME),
+        }
+    }
+
+    /// now() as an inode time, the time source has no nanoseconds. Without
+    /// a clock the last write time has the high byte of s_wtime_hi, past
+    /// 2106.
+    fn timestamp(&self) -> Timestamp {
+        let sec = match self.options.time_source {
+            Some(now) => now().min(i64::MAX as u64) as i64,
+            None => {
+                let sb = self.disk.read_offset(SUPERBLOCK_OFFSET);
+                le_u32(&sb, S_WTIME) as i64 | (sb[S_WTIME_HI] as i64) << 32
+            }
+        };
+        Timestamp { sec, nsec: 0 }
+    }
+
+    /// Set up the inode created by ext4_rs, in the transaction creating
+    /// it: its times and its generation.
+    fn init_inode(&self, ino: u32) -> VfsResult<()> {
+        let generation = self.new_generation(ino)?;
+        self.modify_inode(ino, |raw| set_u32(raw, I_GENERATION, generation))?;
+        self.touch_times(ino, NEW_TIMES)?;
+        let inode = self.read_inode(ino)?;
+        self.charge(inode.uid, inode.gid, self.allocated(&inode), 1);
+        Ok(())
+    }
+
+    /// The bytes of the blocks of the inode, for the quota.
+    pub(crate) fn allocated(&self, inode: &InodeInfo) -> i64 {
+        (inode.sectors(&self.sb) * 512) as i64
+    }
+
+    /// Charge the owner and the group of a file with the quota option, in
+    /// the transaction of the change. The charges are applied with its
+    /// commit, the transaction fails if they pass a limit.
+    pub(crate) fn charge(&self, uid: u32, gid: u32, bytes: i64, inodes: i64) {
+        if self.quota.is_some() && (bytes != 0 || inodes != 0) {
+            self.quota_pending
+                .lock()
+                .extend(Charge::owner(uid, gid, bytes, inodes));
+        }
+    }
+
+    /// Run op and charge the owner of the inode for the blocks it
+    /// allocates, in the transaction of op.
+    fn charged<R>(&self, ino: u32, op: impl FnOnce() -> VfsResult<R>) -> VfsResult<R> {
+        if self.quota.is_none() {
+            return op();
+        }
+        let before = self.read_inode(ino)?;
+        let r = op()?;
+        let after = self.read_inode(ino)?;
+        let bytes = self.allocated(&after) - self.allocated(&before);
+        self.charge(after.uid, after.gid, bytes, 0);
+        Ok(r)
+    }
+
+    /// Check if the owners have limits, the writes aren't buffered then
+    /// so that they fail when they pass them.
+    fn quota_limited(&self) -> bool {
+        self.quota.as_ref().is_some_and(|x| x.has_limits())
+    }
+
+    /// Count the usage of the owners and the groups, like quotacheck. The
+    /// reserved inodes besides the root belong to the filesystem, they
+    /// aren't charged.
+    fn scan_quota(&self) -> VfsResult<QuotaTable> {
+        let ipg = self.sb.inodes_per_group as usize;
+        let mut charges = Vec::new();
+        for group in 0..self.sb.groups_count() {
+            self.disk.mount_poll()?;
+            let desc = self.disk.group_desc(&self.sb, group)?;
+            if desc.flags & BG_INODE_UNINIT != 0 {
+                continue;
+            }
+            let bitmap = self.read_block(desc.inode_bitmap);
+            for index in 0..ipg {
+                let ino = (group * ipg + index + 1) as u32;
+                if ino > self.sb.inodes_count {
+                    break;
+                }
+                let reserved = ino < self.sb.first_ino && ino != ROOT_INO;
+                if reserved || !bitmap_test(&bitmap, index) {
+                    continue;
+                }
+                let inode = self.read_inode(ino)?;
+                charges.extend(Charge::owner(
+                    inode.uid,
+                    inode.gid,
+                    self.allocated(&inode),
+                    1,
+                ));
+            }
+        }
+        let table = QuotaTable::new();
+        table.apply(&charges)?;
+        Ok(table)
+    }
+
+    /// The i_generation of a new inode, so the handles of the file deleted
+    /// before the inode was reused are stale. The freed inodes keep their
+    /// generation, the next one is its successor. If ext4_rs cleared it,
+    /// it's pseudo-random like Linux: the uuid, the time and a counter of
+    /// the mount make the old generation come back with a chance of 1 in
+    /// 2^32, without a time source the mounts of an image at the same
+    /// superblock time may repeat their generations. A deterministic
+    /// mount takes only the uuid and the inode.
+    fn new_generation(&self, ino: u32) -> VfsResult<u32> {
+        let old = self.read_inode(ino)?.generation;
+        if old != 0 {
+            return Ok(old.wrapping_add(1).max(1));
+        }
+        if self.options.deterministic {
+            let crc = crc32c(!0, &self.sb.uuid);
+            return Ok(crc32c(crc, &ino.to_le_bytes()).max(1));
+        }
+        let n = self.generations.fetch_add(1, Ordering::Relaxed);
+        let crc = crc32c(!0, &self.sb.uuid);
+        let crc = crc32c(crc, &self.timestamp().sec.to_le_bytes());
+        let crc = crc32c(crc, &ino.to_le_bytes());
+        Ok(crc32c(crc, &n.to_le_bytes()).max(1))
+    }
+
+    /// Set the times of the inode to now, in the transaction of the
+    /// change.
+    fn touch_times(&self, ino: u32, fields: &[TimeField]) -> VfsResult<()> {
+        let now = self.timestamp();
+        let inode_size = self.sb.inode_size as usize;
+        self.modify_inode(ino, |raw| {
+            for field in fields {
+                set_time(raw, inode_size, *field, now);
+            }
+        })
+    }
+
+    /// Fail the modifications if the volume is mounted read-only, with
+    /// NotSupported. The syscalls check mounts::check_writable first, for
+    /// their EROFS.
+    fn check_writable(&self) -> VfsResult<()> {
+        match *self.read_only.lock() {
+            Some(_) => Err(VfsError::NotSupported),
+            None => Ok(()),
+        }
+    }
+
+    /// Start a modification: wait while the volume is frozen, then fail
+    /// if it's read-only. The modification holds the guard until it's
+    /// done, it must begin before taking the locks of the wrapper.
+    fn begin_write(&self) -> VfsResult<GateGuard<'_>> {
+        let guard = self.gate.enter();
+        self.check_writable()?;
+        Ok(guard)
+    }
+
+    /// Start a modification of the entries of a directory, begin_write
+    /// then the directory gate, which lock_dir closes for every directory
+    /// of the volume.
+    fn begin_dir_write(&self) -> VfsResult<(GateGuard<'_>, GateGuard<'_>)> {
+        let write = self.begin_write()?;
+        Ok((write, self.dir_gate.enter()))
+    }
+
+    /// Start a modification which is skipped on a frozen or read-only
+    /// volume, None then.
+    fn try_begin_write(&self) -> Option<GateGuard<'_>> {
+        let guard = self.gate.try_enter()?;
+        self.check_writable().ok()?;
+        Some(guard)
+    }
+
+    pub(crate) fn is_read_only(&self) -> bool {
+        self.read_only.lock().is_some()
+    }
+
+    /// Mount read-only for the reason unless force_rw is set.
+    pub(crate) fn set_read_only(&mut self, reason: ReadOnlyReason) {
+        if self.options.force_rw {
+            log::warn!(
+                "ext4 should be read-only: {:?}, force_rw mounts it writable",
+                reason
+            );
+            self.forced_rw.get_or_insert(reason);
+        } else {
+            log::error!("ext4 mounted read-only: {:?}", reason);
+            *self.read_only.lock() = Some(reason);
+            self.disk.set_read_only(true);
+        }
+    }
+
+    /// The reason the mount should be read-only, even if it was forced.
+    pub(crate) fn read_only_reason(&self) -> Option<ReadOnlyReason> {
+        if let Some(group) = self.disk.backup_group() {
+            return Some(ReadOnlyReason::BackupSuperblock(group));
+        }
+        if self.sb.has_metadata_csum()
+            && !verify_superblock(&self.disk.read_offset(SUPERBLOCK_OFFSET))
+        {
+            return Some(ReadOnlyReason::SuperblockChecksum);
+        }
+        if self.sb.has_errors() {
+            return Some(ReadOnlyReason::ErrorState);
+        }
+        let features =
+            FeatureKind::RoCompat.select(self.sb.feature_ro_compat, FeatureHandling::ReadOnly);
+        if features != 0 {
+            return Some(ReadOnlyReason::RoCompatFeatures(features));
+        }
+        None
+    }
+
+    /// Copy the primary superblock and group descriptors to the backups of
+    /// the sparse groups, on a sync and at the umount, so a recovery mount
+    /// finds the files of the last sync. The backups aren't journaled, a
+    /// crash leaves one stale, never the primary. The backups up to date
+    /// aren't written.
+    fn write_backups(&self) {
+        if self.disk.is_read_only() {
+            return;
+        }
+        // don't copy the primary in the middle of a transaction.
+        let _journal = self.journal.lock();
+        let sb = &self.sb;
+        let primary = self.disk.read_offset(SUPERBLOCK_OFFSET);
+        let mut descs = vec![0; sb.group_desc_blocks() * BLOCK_SIZE];
+        self.disk.read_into(sb.group_desc_offset(0), &mut descs);
+        for group in 1..sb.groups_count() {
+            if !sb.group_has_super(group) {
+                continue;
+            }
+            let start =
+                (sb.first_data_block as usize + group * sb.blocks_per_group as usize) * BLOCK_SIZE;
+            let mut copy = primary[..1024].to_vec();
+            set_u16(&mut copy, S_BLOCK_GROUP_NR, group as u16);
+            if sb.has_metadata_csum() {
+                set_superblock_csum(&mut copy);
+            }
+            for (offset, data) in [(start, &copy), (start + BLOCK_SIZE, &descs)] {
+                let mut old = vec![0; data.len()];
+                self.disk.read_into(offset, &mut old);
+                if old != *data {
+                    self.disk.write_device(offset, data);
+                }
+            }
+        }
+    }
+
+    /// Deny the writes, then write back the buffered writes, the deferred
+    /// transactions and the dirty bitmaps. The transaction running at the
+    /// switch is committed first, the journal lock waits for it.
+    /// TODO: an operation past check_writable but before its transaction
+    /// still commits it.
+    fn remount_ro(&self) -> VfsResult<()> {
+        {
+            let _journal = self.journal.lock();
+            let mut read_only = self.read_only.lock();
+            if read_only.is_some() {
+                return Ok(());
+            }
+            *read_only = Some(ReadOnlyReason::Requested);
+        }
+        if let Err(err) = self.sync_wrappers().and_then(|_| self.write_back()) {
+            log::error!("ext4 stays writable");
+            *self.read_only.lock() = None;
+            return Err(err);
+        }
+        self.disk.sync_groups();
+        self.write_backups();
+        self.disk.set_read_only(true);
+        info!("ext4 remounted read-only");
+        Ok(())
+    }
+
+    /// The wrappers holding buffered writes of the inodes, of all the
+    /// inodes without inos.
+    fn buffered_wrappers(&self, inos: Option<&[u32]>) -> Vec<Arc<Ext4FileWrapper>> {
+        let ids: Vec<u64> = {
+            let buffered = self.buffered.lock();
+            match inos {
+                Some(inos) => inos
+                    .iter()
+                    .flat_map(|&ino| buffered.range((ino, 0)..=(ino, u64::MAX)))
+                    .map(|x| x.1)
+                    .collect(),
+                None => buffered.iter().map(|x| x.1).collect(),
+            }
+        };
+        if ids.is_empty() {
+            return Vec::new();
+        }
+        let wrappers = self.wrappers.lock();
+        ids.iter()
+            .filter_map(|id| wrappers.get(id).and_then(Weak::upgrade))
+            .collect()
+    }
+
+    /// Write back the buffered writes of the open files of the inodes. A
+    /// failure is kept by the wrapper for its fsync and its close.
+    fn sync_inodes(&self, inos: &[u32]) {
+        for wrapper in self.buffered_wrappers(Some(inos)) {
+            wrapper.sync_wbuf();
+        }
+    }
+
+    /// Write back the buffered writes of every wrapper, fail with the
+    /// first failure kept by one. The wrapper still reports it.
+    fn sync_wrappers(&self) -> VfsResult<()> {
+        for wrapper in self.buffered_wrappers(None) {
+            wrapper.sync_wbuf();
+        }
+        let wrappers: Vec<_> = self
+            .wrappers
+            .lock()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        match wrappers.iter().find_map(|x| x.wbuf.lock().error) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Hold count blocks for a write buffer, false if the free blocks out
+    /// of the reserved ones can't take them besides those held already.
+    /// The allocations leave the held blocks free, see kept_blocks.
+    fn hold_buffered(&self, count: u64) -> bool {
+        // the reserve of the superblock on the disk, set_reserved_blocks
+        // moves it.
+        let raw = self.disk.read_offset(SUPERBLOCK_OFFSET);
+        let reserved = SuperBlockInfo::parse(&raw).r_blocks_count;
+        let free = self.free_block_count.get().saturating_sub(reserved);
+        self.buffered_blocks
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |x| {
+                (x + count <= free).then_some(x + count)
+            })
+            .is_ok()
+    }
+
+    /// Freeze the volume, see Freeze. Every transaction is checkpointed
+    /// when it's committed, so once the buffered writes, the deferred
+    /// transactions and the bitmaps are written the journal is empty and
+    /// the disk is consistent.
+    /// TODO: the releases of the orphans by the drop of the last wrapper
+    /// don't wait for the thaw.
+    fn freeze(&self) -> FsResult<()> {
+        self.gate.freeze()?;
+        if let Err(err) = self.sync_wrappers().and_then(|_| self.write_back()) {
+            self.gate.thaw()?;
+            return Err(err.into());
+        }
+        self.disk.sync_groups();
+        self.write_backups();
+        info!("ext4 frozen");
+        Ok(())
+    }
+
+    /// Allow the writes on a volume mounted read-only by the option. The
+    /// journal was replayed in memory by a read-only mount, so a volume
+    /// which needs the recovery stays read-only, like the ones read-only for
+    /// the other reasons. The journal is loaded to commit the transactions
+    /// and the orphans left by a crash are released.
+    fn remount_rw(&self) -> VfsResult<()> {
+        match self.read_only.lock().clone() {
+            None => return Ok(()),
+            Some(ReadOnlyReason::Requested) => {}
+            Some(reason) => {
+                log::error!(
+                    "can't remount ext4 read-write, it's read-only: {:?}",
+                    reason
+                );
+                return Err(VfsError::InvalidInput);
+            }
+        }
+        if let Some(reason) = self.read_only_reason() {
+            log::error!("can't remount ext4 read-write: {:?}", reason);
+            return Err(VfsError::InvalidInput);
+        }
+        if self.sb.needs_recovery() {
+            log::error!("can't remount ext4 read-write, the journal needs a replay");
+            return Err(VfsError::NotSupported);
+        }
+        self.count_free_lazily()?;
+        self.disk.set_read_only(false);
+        if self.journal.lock().is_none() {
+            self.open_journal();
+        }
+        // the guard a fast mount skipped.
+        if self.options.fast && self.options.write_guard {
+            self.protect_metadata();
+        }
+        *self.read_only.lock() = None;
+        self.cleanup_orphans()?;
+        info!("ext4 remounted read-write");
+        Ok(())
+    }
+
+    /// Run op as one transaction, the metadata blocks it writes reach the
+    /// disk through the journal so a crash leaves either the old or the
+    /// new state. inodes are the inodes whose blocks op may write besides
+    /// those changed in the inode tables, like the parent directory.
+    /// data_ino is the file whose data op writes, its data blocks are
+    /// written in place before the journal (ordered mode).
+    /// Without a journal the blocks are written in place at the end.
+    /// If op fails the transaction is aborted, so a failed operation, like
+    /// one running out of space halfway, leaves the filesystem unchanged,
+    /// and a commit whose writes failed is rolled back, see roll_back.
+    /// The errors found meanwhile are written to the superblock after it.
+    pub(crate) fn transaction<R>(
+        &self,
+        inodes: &[u32],
+        data_ino: Option<u32>,
+        op: impl FnOnce() -> VfsResult<R>,
+    ) -> VfsResult<R> {
+        let r = self.run_transaction(inodes, data_ino, op);
+        self.write_errors();
+        r
+    }
+
+    fn run_transaction<R>(
+        &self,
+        inodes: &[u32],
+        data_ino: Option<u32>,
+        op: impl FnOnce() -> VfsResult<R>,
+    ) -> VfsResult<R> {
+        let mut journal = self.journal.lock();
+        self.disk.begin_transaction(self.sb.block_size());
+        let r = self.apply_charges(op());
+        let mut zeroed = core::mem::take(&mut *self.zero_pending.lock());
+        let dirs = core::mem::take(&mut *self.dirs_pending.lock());
+        if r.is_err() {
+            self.disk.abort_transaction();
+            self.end_free_counts(None);
+            self.publish_snapshots(inodes, data_ino);
+            return r;
+        }
+        // the superblock gets the free counts before the checksums.
+        let change = match self.derive_free_counts() {
+            Ok(change) => change,
+            Err(err) => {
+                self.disk.abort_transaction();
+                self.end_free_counts(None);
+                self.publish_snapshots(inodes, data_ino);
+                return Err(err);
+            }
+        };
+        if self.sb.has_metadata_csum() {
+            let mut inodes = inodes.to_vec();
+            inodes.extend(data_ino);
+            inodes.extend(dirs);
+            if let Err(err) = self.update_checksums(&inodes) {
+                log::error!("update the ext4 checksums failed: {:?}", err);
+            }
+        }
+        // the extents are read through the transaction, so they include
+        // the blocks allocated by op.
+        let data = match data_ino {
+            Some(ino) => self.data_extents(ino),
+            None => Vec::new(),
+        };
+        let txn = self.disk.end_transaction().unwrap();
+        // the blocks before the commit, if the device counts its errors. The
+        // write-back policy only defers the transaction.
+        let errors = self
+            .disk
+            .unrecovered_errors()
+            .filter(|_| !matches!(self.effective_sync_policy(), SyncPolicy::WriteBack { .. }));
+        let undo = errors.map(|_| self.disk.undo_log(&txn));
+        self.disk.take_failed_writes();
+        if let Err(err) = self.commit(journal.as_mut(), txn, &data, (inodes, data_ino)) {
+            log::error!("commit the ext4 transaction failed: {:?}", err);
+            self.end_free_counts(None);
+            self.publish_snapshots(inodes, data_ino);
+            return Err(err);
+        }
+        if let Some(undo) = undo
+            && self.disk.unrecovered_errors() != errors
+        {
+            log::error!("a write of the ext4 transaction failed, roll it back");
+            self.record_error("transaction", data_ino.unwrap_or(0), 0, ErrorCode::Io);
+            self.roll_back(journal.as_mut(), &undo);
+            if self.badlist_failed(&data) {
+                self.relocation.store(true, Ordering::Relaxed);
+            }
+            self.end_free_counts(None);
+            self.publish_snapshots(inodes, data_ino);
+            return Err(VfsError::WriteZero);
+        }
+        self.end_free_counts(Some(change));
+        // the journal is still locked, no transaction can allocate the
+        // freed blocks before they are zeroed. The deferred writes of them
+        // go first, the zeros would be written over otherwise.
+        if !zeroed.is_empty()
+            && let Err(err) = self.write_deferred(journal.as_mut())
+        {
+            log::error!("write back the ext4 transactions failed: {:?}", err);
+        }
+        self.zero_blocks(&mut zeroed);
+        self.publish_snapshots(inodes, data_ino);
+        r
+    }
+
+    /// The snapshot of the inode shared by its wrappers, published from
+    /// the inode.
+    fn snapshot(&self, ino: u32) -> Arc<Snapshot> {
+        let mut snapshots = self.snapshots.lock();
+        let snapshot = match snapshots.get(&ino).and_then(Weak::upgrade) {
+            Some(snapshot) => snapshot,
+            None => {
+                snapshots.retain(|_, x| x.strong_count() > 0);
+                let snapshot = Arc::new(Snapshot::new());
+                snapshots.insert(ino, Arc::downgrade(&snapshot));
+                snapshot
+            }
+        };
+        drop(snapshots);
+        self.publish_snapshot(ino, &snapshot);
+        snapshot
+    }
+
+    /// The lock of the appends to the inode through all its wrappers, held
+    /// by an O_APPEND write from taking the end until it's written.
+    fn append_lock(&self, ino: u32) -> Arc<Mutex<()>> {
+        let mut appends = self.appends.lock();
+        if let Some(lock) = appends.get(&ino).and_then(Weak::upgrade) {
+            return lock;
+        }
+        appends.retain(|_, x| x.strong_count() > 0);
+        let lock = Arc::new(Mutex::new(()));
+        appends.insert(ino, Arc::downgrade(&lock));
+        lock
+    }
+
+    /// Publish the inode as it's now to the snapshot, an inode which can't
+    /// be read keeps the last one.
+    fn publish_snapshot(&self, ino: u32, snapshot: &Snapshot) {
+        snapshot.update(|| {
+            let inode = self.read_inode(ino).ok()?;
+            Some(Meta {
+                size: inode.size,
+                blocks: inode.sectors(&self.sb),
+                mode: inode.mode as u32,
+                nlink: inode.links_count as u32,
+                uid: inode.uid,
+                gid: inode.gid,
+                atime: time_spec(inode.atime),
+                mtime: time_spec(inode.mtime),
+                ctime: time_spec(inode.ctime),
+                rdev: match inode.mode & 0xF000 {
+                    0x2000 | 0x6000 => {
+                        let (major, minor) = inode.device();
+                        make_dev(major, minor)
+                    }
+                    _ => 0,
+                },
+            })
+        })
+    }
+
+    /// Publish the snapshots of the inodes of a transaction which ended,
+    /// committed or aborted, and of those it changed in the inode tables.
+    fn publish_snapshots(&self, inodes: &[u32], data_ino: Option<u32>) {
+        let mut changed = core::mem::take(&mut *self.snapshot_pending.lock());
+        changed.extend(inodes.iter().copied().chain(data_ino));
+        changed.sort_unstable();
+        changed.dedup();
+        for ino in changed {
+            let snapshot = self.snapshots.lock().get(&ino).and_then(Weak::upgrade);
+            if let Some(snapshot) = snapshot {
+                self.publish_snapshot(ino, &snapshot);
+            }
+        }
+    }
+
+    /// Overwrite the freed data blocks with zeros, by the write zeroes of
+    /// the device if it has one. The adjacent ranges are merged, so a file
+    /// is zeroed by a few large writes. A range refused by the write guard
+    /// is left as it is.
+    fn zero_blocks(&self, ranges: &mut [(u64, u64)]) {
+        if ranges.is_empty() {
+            return;
+        }
+        ranges.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = Vec::new();
+        for &(start, len) in ranges.iter() {
+            match merged.last_mut() {
+                Some(last) if last.0 + last.1 == start => last.1 += len,
+                _ => merged.push((start, len)),
+            }
+        }
+        let block_size = self.sb.block_size();
+        let device = blockdev::write_zeroes_device(self.disk.dev);
+        let zeros = match device {
+            Some(_) => Vec::new(),
+            None => vec![0; ZERO_CHUNK_BLOCKS * block_size],
+        };
+        for (start, len) in merged {
+            let offset = start as usize * block_size;
+            let end = offset + len as usize * block_size;
+            if self
+                .disk
+                .guard_write(offset, end - offset, WriteKind::Data)
+                .is_err()
+            {
+                continue;
+            }
+            if let Some(device) = &device {
+                device.write_zeroes(offset, end - offset);
+                continue;
+            }
+            let mut pos = offset;
+            while pos < end {
+                let n = min(zeros.len(), end - pos);
+                self.disk.write_offset(pos, &zeros[..n]);
+                pos += n;
+            }
+        }
+    }
+
+    /// Apply the charges of the transaction with the result r of its op to
+    /// the quota, they are dropped if op failed or they pass a limit.
+    fn apply_charges<R>(&self, r: VfsResult<R>) -> VfsResult<R> {
+        let charges = core::mem::take(&mut *self.quota_pending.lock());
+        let r = r?;
+        if let Some(quota) = &self.quota
+            && !charges.is_empty()
+        {
+            quota.apply(&charges)?;
+        }
+        Ok(r)
+    }
+
+    /// Write back the deferred blocks the transactions of the inode
+    /// dirtied, without those which changed only its times with
+    /// SyncMode::Data, the other deferred blocks stay. A block shared with
+    /// the transactions of other inodes is written as it is if it's a
+    /// bitmap, a group descriptor or the superblock, their allocations
+    /// leak at most, and an inode block with the synced inodes only. The
+    /// inodes sharing the other blocks, like a directory block, or a bitmap
+    /// freeing what is used on the disk, are synced with it. A sync which
+    /// would write another orphan list writes back everything.
+    /// return the number of the written blocks.
+    fn sync_inode(&self, ino: u32, mode: SyncMode) -> VfsResult<usize> {
+        let mut journal = self.journal.lock();
+        let block_size = self.sb.block_size();
+        let inode_size = self.sb.inode_size as usize;
+        let sb_block = (SUPERBLOCK_OFFSET / block_size) as u64;
+        let dirty = self.disk.deferred.lock().dirty.clone();
+        if !dirty.contains_key(&ino) {
+            return Ok(0);
+        }
+        let (bitmaps, descs): (BTreeSet<u64>, BTreeSet<u64>) = {
+            let groups = self.disk.groups.lock();
+            let descs = groups.descs.iter();
+            (
+                descs
+                    .clone()
+                    .flat_map(|(_, x)| [x.block_bitmap, x.inode_bitmap])
+                    .collect(),
+                descs
+                    .map(|(group, _)| (self.sb.group_desc_offset(*group) / block_size) as u64)
+                    .collect(),
+            )
+        };
+        // the deferred bitmap clears a bit set on the disk.
+        let frees = |block: u64| {
+            let Some(buf) = self.disk.deferred.lock().metadata.get(&block).cloned() else {
+                return false;
+            };
+            let mut home = vec![0; block_size];
+            self.disk.read_home(block as usize * block_size, &mut home);
+            home.iter().zip(buf.iter()).any(|(x, y)| x & !y != 0)
+        };
+        let blocks_of = |x: u32| {
+            let dirty = &dirty[&x];
+            let times = (x == ino && mode == SyncMode::Full).then_some(&dirty.times);
+            let blocks = dirty.data.iter().chain(dirty.metadata.iter());
+            blocks.chain(times.into_iter().flatten()).copied()
+        };
+        let mut synced = BTreeSet::from([ino]);
+        let (written, slots) = loop {
+            // the synced inodes by their inode blocks.
+            let mut slots = BTreeMap::<u64, Vec<usize>>::new();
+            for x in synced.iter() {
+                let offset = self.inode_offset(*x)?;
+                let block = (offset / block_size) as u64;
+                slots.entry(block).or_default().push(offset % block_size);
+            }
+            let written: BTreeSet<u64> = synced.iter().flat_map(|x| blocks_of(*x)).collect();
+            let mut more = Vec::new();
+            for block in written.iter().copied() {
+                let shared =
+                    if slots.contains_key(&block) || descs.contains(&block) || block == sb_block {
+                        false
+                    } else if bitmaps.contains(&block) {
+                        frees(block)
+                    } else {
+                        true
+                    };
+                if shared {
+                    let owners = dirty
+                        .iter()
+                        .filter(|(x, y)| !synced.contains(x) && y.contains(block));
+                    more.extend(owners.map(|(x, _)| *x));
+                }
+            }
+            if more.is_empty() {
+                break (written, slots);
+            }
+            synced.extend(more);
+        };
+
+        let deferred = self.disk.deferred.lock();
+        let sb = deferred
+            .metadata
+            .get(&sb_block)
+            .filter(|_| written.contains(&sb_block));
+        if let Some(sb) = sb {
+            let offset = SUPERBLOCK_OFFSET % block_size;
+            let mut home = vec![0; 1024];
+            self.disk.read_home(SUPERBLOCK_OFFSET, &mut home);
+            let orphan = offset + S_LAST_ORPHAN..offset + S_LAST_ORPHAN + 4;
+            if sb[orphan] != home[S_LAST_ORPHAN..S_LAST_ORPHAN + 4] {
+                drop(deferred);
+                return self.write_deferred(journal.as_mut());
+            }
+        }
+        // kept: the deferred inode blocks written with the synced inodes
+        // only, they stay deferred for the others.
+        let (mut data, mut metadata, mut kept) = (Vec::new(), Vec::new(), Vec::new());
+        for block in written {
+            if let Some(buf) = deferred.data.get(&block) {
+                data.push((block, buf.clone()));
+                continue;
+            }
+            let Some(buf) = deferred.metadata.get(&block) else {
+                continue;
+            };
+            let mut buf = buf.clone();
+            if let Some(slots) = slots.get(&block) {
+                let mut home = vec![0; block_size];
+                self.disk.read_home(block as usize * block_size, &mut home);
+                for x in slots.iter().copied() {
+                    home[x..x + inode_size].copy_from_slice(&buf[x..x + inode_size]);
+                }
+                if home != buf {
+                    kept.push((block, core::mem::replace(&mut buf, home)));
+                }
+            }
+            metadata.push((block, buf));
+        }
+        drop(deferred);
+        if data.is_empty() && metadata.is_empty() {
+            return Ok(0);
+        }
+        let r = self.commit_blocks(journal.as_mut(), block_size, &data, &metadata, false);
+        // the writes patched the kept blocks.
+        let mut deferred = self.disk.deferred.lock();
+        for (block, buf) in kept.iter() {
+            deferred.metadata.insert(*block, buf.clone());
+        }
+        r?;
+        for (block, _) in data.iter().chain(metadata.iter()) {
+            match kept.iter().any(|x| x.0 == *block) {
+                true => synced
+                    .iter()
+                    .filter_map(|x| deferred.dirty.get_mut(x))
+                    .for_each(|x| x.remove(*block)),
+                false => {
+                    deferred.data.remove(block);
+                    deferred.metadata.remove(block);
+                    deferred.dirty.values_mut().for_each(|x| x.remove(*block));
+                }
+            }
+        }
+        deferred.dirty.retain(|_, x| !x.is_empty());
+        if deferred.len() == 0 {
+            deferred.since = None;
+        }
+        Ok(data.len() + metadata.len())
+    }
+
+    /// Switch to the sync policy, the deferred transactions are written
+    /// back if it doesn't defer them.
+    fn set_sync_policy(&self, policy: SyncPolicy) -> VfsResult<()> {
+        self.update_sync_policy(|_| Ok(policy))
+    }
+
+    /// Switch to the sync policy made by f of the policy now, no policy
+    /// can be set between them.
+    fn update_sync_policy(
+        &self,
+        f: impl FnOnce(SyncPolicy) -> VfsResult<SyncPolicy>,
+    ) -> VfsResult<()> {
+        let mut journal = self.journal.lock();
+        let policy = f(*self.sync_policy.lock())?;
+        if !policy.is_valid() {
+            log::error!("invalid ext4 sync policy {:?}", policy);
+            return Err(VfsError::InvalidInput);
+        }
+        if self.options.deterministic && matches!(policy, SyncPolicy::WriteBack { .. }) {
+            log::error!("a deterministic ext4 can't write back");
+            return Err(VfsError::InvalidInput);
+        }
+        if !matches!(policy, SyncPolicy::WriteBack { .. }) {
+            self.write_deferred(journal.as_mut())?;
+        }
+        *self.sync_policy.lock() = policy;
+        Ok(())
+    }
+
+    /// The sync policy the commits follow: a batch defers the transactions
+    /// of write_through like write_back until its end, strict_ordered
+    /// keeps its order.
+    pub(crate) fn effective_sync_policy(&self) -> SyncPolicy {
+        match *self.sync_policy.lock() {
+            SyncPolicy::WriteThrough if self.batches.load(Ordering::Relaxed) > 0 => {
+                SyncPolicy::WriteBack {
+                    max_dirty_blocks: BATCH_DIRTY_BLOCKS,
+                    max_age_ticks: u64::MAX,
+                }
+            }
+            policy => policy,
+        }
+    }
+
+    /// Start a batch of batch.rs.
+    fn begin_batch(&self) {
+        self.batches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// End a batch of batch.rs, the last one writes back the deferred
+    /// transactions unless the sync policy defers them anyway.
+    fn end_batch(&self) -> VfsResult<()> {
+        let mut journal = self.journal.lock();
+        let last = self.batches.fetch_sub(1, Ordering::Relaxed) == 1;
+        if last && !matches!(*self.sync_policy.lock(), SyncPolicy::WriteBack { .. }) {
+            self.write_deferred(journal.as_mut())?;
+        }
+        Ok(())
+    }
+
+    /// Advance the tick of the write-back policy, and write back the
+    /// deferred transactions once the oldest one is max_age_ticks old.
+    /// They are written back all together, the journal can't commit a
+    /// part of them. return the number of the written blocks.
+    fn writeback_tick(&self) -> VfsResult<usize> {
+        let mut journal = self.journal.lock();
+        let due = {
+            let mut deferred = self.disk.deferred.lock();
+            deferred.tick += 1;
+            match (self.effective_sync_policy(), deferred.since) {
+                (SyncPolicy::WriteBack { max_age_ticks, .. }, Some(since)) => {
+                    deferred.tick - since >= max_age_ticks
+                }
+                // a switch of the policy writes them back.
+                (_, since) => since.is_some(),
+            }
+        };
+        match due {
+            true => self.write_deferred(journal.as_mut()),
+            false => Ok(0),
+        }
+    }
+
+    /// The pages of a readahead of ahead pages read with the miss, see
+    /// MountOptions::readahead_inline.
+    fn readahead_inline(&self, ahead: usize) -> usize {
+        match iosched::pressure(self.disk.dev) {
+            0 => min(ahead, self.options.readahead_inline),
+            _ => 0,
+        }
+    }
+
+    fn defer_readahead(&self, prefetch: Prefetch) {
+        let mut background = self.background.lock();
+        background.stats.deferred_pages += prefetch.pages;
+        if background.prefetch.len() == MAX_DEFERRED_READAHEAD {
+            let dropped = background.prefetch.pop_front().map_or(0, |x| x.pages);
+            background.stats.dropped_pages += dropped;
+        }
+        background.prefetch.push_back(prefetch);
+    }
+
+    /// Take at most pages pages of the oldest deferred readahead.
+    fn next_prefetch(&self, pages: usize) -> Option<Prefetch> {
+        let mut background = self.background.lock();
+        let next = background.prefetch.front_mut()?;
+        if next.pages > pages {
+            let taken = Prefetch { pages, ..*next };
+            next.index += pages;
+            next.pages -= pages;
+            return Some(taken);
+        }
+        background.prefetch.pop_front()
+    }
+
+    /// Read the deferred readahead by a wrapper of its inode, a closed
+    /// file drops it. return the pages read.
+    fn prefetch(&self, prefetch: Prefetch) -> usize {
+        let wrapper = self
+            .wrappers
+            .lock()
+            .values()
+            .filter_map(Weak::upgrade)
+            .find(|x| x.snapshot_ino == prefetch.ino);
+        let read = match wrapper.map(|x| x.prefetch(prefetch.index, prefetch.pages)) {
+            Some(Ok(read)) => read,
+            Some(Err(err)) => {
+                log::warn!("ext4 readahead of inode {} failed: {:?}", prefetch.ino, err);
+                0
+            }
+            None => 0,
+        };
+        let mut background = self.background.lock();
+        background.stats.prefetched_pages += read;
+        background.stats.dropped_pages += prefetch.pages - read;
+        read
+    }
+
+    /// Whether background_work yields to the pressure of the device now,
+    /// see MAX_BACKGROUND_YIELDS.
+    fn background_yields(&self) -> bool {
+        let mut background = self.background.lock();
+        if iosched::pressure(self.disk.dev) > 0 && background.yields < MAX_BACKGROUND_YIELDS {
+            background.yields += 1;
+            background.stats.yields += 1;
+            return true;
+        }
+        background.yields = 0;
+        false
+    }
+
+    /// Write the blocks of the kind to the disk in their order, a run of
+    /// consecutive blocks is merged into one request, so the device sees
+    /// as few requests as the order allows. A run refused by the write
+    /// guard fails it, the runs before it are written.
+    pub(crate) fn write_runs<'a>(
+        &self,
+        block_size: usize,
+        kind: WriteKind,
+        blocks: impl Iterator<Item = &'a (u64, Vec<u8>)>,
+    ) -> VfsResult<()> {
+        let mut run: Option<(u64, Vec<u8>)> = None;
+        for (block, buf) in blocks {
+            match run.as_mut() {
+                Some((start, data)) if *start + (data.len() / block_size) as u64 == *block => {
+                    data.extend_from_slice(buf)
+                }
+                _ => {
+                    if let Some((start, data)) = run.replace((*block, buf.clone())) {
+                        self.disk
+                            .write_tagged(start as usize * block_size, &data, kind)?;
+                    }
+                }
+            }
+        }
+        if let Some((start, data)) = run {
+            self.disk
+                .write_tagged(start as usize * block_size, &data, kind)?;
+        }
+        Ok(())
+    }
+
+    /// Read the superblock from the disk.
+    pub(crate) fn read_superblock(&self) -> SuperBlockInfo {
+        SuperBlockInfo::parse(&self.disk.read_offset(SUPERBLOCK_OFFSET))
+    }
+
+    /// Read the block, BLOCK_SIZE bytes are returned.
+    pub(crate) fn read_block(&self, block: u64) -> Vec<u8> {
+        self.disk.read_offset(block as usize * self.sb.block_size())
+    }
+
+    /// Read the consecutive data blocks from block into buf, buf.len() is
+    /// a multiple of the block size. A device read failing at every try
+    /// fails it with Io, see check_read.
+    fn read_blocks_into(&self, block: u64, buf: &mut [u8]) -> VfsResult<()> {
+        let offset = block as usize * self.sb.block_size();
+        let failed = self.disk.read_into(offset, buf);
+        self.check_read(offset, buf.len(), &failed)
+    }
+
+    /// Like read_blocks_into, bypassing the cache of the device.
+    fn read_blocks_direct(&self, block: u64, buf: &mut [u8]) -> VfsResult<()> {
+        let offset = block as usize * self.sb.block_size();
+        let failed = self.disk.read_direct_into(offset, buf);
+        self.check_read(offset, buf.len(), &failed)
+    }
+
+    /// Fail the read of len bytes of data blocks at offset with Io if some
+    /// of its device reads failed at every try. With relocate the blocks
+    /// of the failed reads are badlisted on a writable mount: the file
+    /// keeps them, a write of them moves them, see write_data, and the
+    /// allocations skip them once they're free.
+    fn check_read(&self, offset: usize, len: usize, failed: &[usize]) -> VfsResult<()> {
+        if failed.is_empty() {
+            return Ok(());
+        }
+        if self.options.relocate && !self.is_read_only() {
+            let block_size = self.sb.block_size();
+            let mut bad = self.bad_blocks.lock();
+            for &at in failed {
+                let end = (at + READ_SIZE).min(offset + len);
+                bad.extend((at / block_size) as u64..end.div_ceil(block_size) as u64);
+            }
+        }
+        let block = (failed[0] / self.sb.block_size()) as u64;
+        self.record_error("check_read", 0, block, ErrorCode::Io);
+        Err(VfsError::Io)
+    }
+
+    /// Log the corrupted metadata found by the function and record it in
+    /// the error history, a checksum mismatch as BadCrc. It's InvalidData,
+    /// EIO at the syscalls: it's found under the methods of INodeInterface,
+    /// whose VfsError has no EUCLEAN.
+    #[track_caller]
+    pub(crate) fn corrupted(
+        &self,
+        function: &'static str,
+        what: &str,
+        ino: u32,
+        block: u64,
+    ) -> VfsError {
+        log::error!("ext4 inode {}: corrupted {} in block {}", ino, what, block);
+        let code = match what.ends_with("checksum") {
+            true => ErrorCode::BadCrc,
+            false => ErrorCode::Corrupted,
+        };
+        self.record_error(function, ino, block, code);
+        VfsError::InvalidData
+    }
+
+    /// The d_types of the entries (inode, file_type), from their type
+    /// bytes. Without the filetype feature the byte is the high byte of
+    /// name_len and always 0, a byte out of range is never trusted: the
+    /// types of those entries come from the i_mode of their inodes, read
+    /// together. An inode which can't be read or has no type gives
+    /// DT_UNKNOWN. The DirEntry of them take ops::d_type_file_type.
+    fn entry_types(&self, entries: &[(u32, u8)]) -> Vec<u8> {
+        let known: Vec<Option<u8>> = entries
+            .iter()
+            .map(|&(_, byte)| dirent_d_type(byte).filter(|_| self.sb.has_filetype()))
+            .collect();
+        let unknown: Vec<u32> = entries
+            .iter()
+            .zip(&known)
+            .filter(|(_, x)| x.is_none())
+            .map(|(x, _)| x.0)
+            .collect();
+        let mut inodes = self.read_inodes(&unknown).into_iter();
+        known
+            .into_iter()
+            .map(|x| {
+                x.unwrap_or_else(|| {
+                    inodes
+                        .next()
+                        .flatten()
+                        .map_or(DT_UNKNOWN, |x| mode_d_type(x.mode))
+                })
+            })
+            .collect()
+    }
+
+    /// The type byte of a new entry, 0 without the filetype feature.
+    fn type_byte(&self, file_type: u8) -> u8 {
+        match self.sb.has_filetype() {
+            true => file_type,
+            false => 0,
+        }
+    }
+
+    pub(crate) fn parse_dir_block<'a>(
+        &self,
+        ino: u32,
+        block: u64,
+        data: &'a [u8],
+    ) -> VfsResult<Vec<Dirent<'a>>> {
+        DirentIter::new(data)
+            .collect::<VfsResult<Vec<_>>>()
+            .map_err(|_| self.corrupted("parse_dir_block", "directory entry", ino, block))
+    }
+
+    /// Add an error to the history, at the line of the caller. It's in
+    /// memory until the next transaction, sync or unmount writes it, so it
+    /// can be recorded with the journal locked.
+    #[track_caller]
+    fn record_error(&self, function: &'static str, ino: u32, block: u64, code: ErrorCode) {
+        let record = ErrorRecord {
+            time: self.now() as u64,
+            function: String::from(function),
+            line: Location::caller().line(),
+            ino,
+            block,
+            code,
+        };
+        let mut errors = self.errors.lock();
+        errors.history.add(record);
+        errors.dirty = true;
+    }
+
+    /// Write the error history to the superblock if it changed, a volume
+    /// which can't be written keeps it in memory. It's best effort: a
+    /// failed write is only logged and never records an error itself.
+    fn write_errors(&self) {
+        let history = {
+            let mut errors = self.errors.lock();
+            if !errors.dirty {
+                return;
+            }
+            errors.dirty = false;
+            errors.history.clone()
+        };
+        let Some(_write) = self.try_begin_write() else {
+            self.errors.lock().dirty = true;
+            return;
+        };
+        let r = self.run_transaction(&[], None, || {
+            self.modify(SUPERBLOCK_OFFSET, 1024, |raw| history.write(raw));
+            Ok(())
+        });
+        if let Err(err) = r {
+            log::warn!("write the ext4 error history failed: {:?}", err);
+            self.errors.lock().dirty = true;
+        }
+    }
+
+    /// The byte offset of the on-disk inode.
+    pub(crate) fn inode_offset(&self, ino: u32) -> VfsResult<usize> {
+        let (group, index) = self.sb.inode_group(ino);
+        let desc = self.disk.group_desc(&self.sb, group)?;
+        Ok(desc.inode_table as usize * self.sb.block_size() + index * self.sb.inode_size as usize)
+    }
+
+    /// Read the on-disk inode, the checksum is verified with metadata_csum.
+    pub(crate) fn read_inode(&self, ino: u32) -> VfsResult<InodeInfo> {
+        let offset = self.inode_offset(ino)?;
+        self.parse_inode(ino, offset, &self.disk.read_offset(offset))
+    }
+
+    /// Parse the inode read at offset, like read_inode.
+    fn parse_inode(&self, ino: u32, offset: usize, raw: &[u8]) -> VfsResult<InodeInfo> {
+        let inode_size = self.sb.inode_size as usize;
+        if self.sb.has_metadata_csum()
+            && !self.disk.is_logged(offset, inode_size)
+            && !verify_inode(&self.sb, ino, raw)
+        {
+            log::error!("ext4 inode {} checksum mismatch", ino);
+            return Err(VfsError::InvalidData);
+        }
+        Ok(InodeInfo::parse(raw, inode_size))
+    }
+
+    /// Read the inodes like read_inode, each block of the inode table
+    /// once. The inodes which can't be read are None.
+    fn read_inodes(&self, inos: &[u32]) -> Vec<Option<InodeInfo>> {
+        let block_size = self.sb.block_size();
+        let mut blocks: BTreeMap<usize, Vec<u8>> = BTreeMap::new();
+        let mut read = |ino: u32| {
+            let offset = self.inode_offset(ino).ok()?;
+            let start = offset - offset % block_size;
+            let block = blocks
+                .entry(start)
+                .or_insert_with(|| self.disk.read_offset(start));
+            self.parse_inode(ino, offset, &block[offset - start..]).ok()
+        };
+        inos.iter().map(|&ino| read(ino)).collect()
+    }
+
+    /// Read the block bitmap of the group, the one of a BLOCK_UNINIT group
+    /// isn't read, see uninit_block_bitmap.
+    fn block_bitmap(&self, group: usize) -> VfsResult<Vec<u8>> {
+        let desc = self.group_counts(group)?;
+        if desc.flags & BG_BLOCK_UNINIT != 0 {
+            return self.uninit_block_bitmap(group);
+        }
+        Ok(self
+            .disk
+            .read_bitmap(desc.block_bitmap as usize * self.sb.block_size()))
+    }
+
+    /// Read the inode bitmap of the group, the one of an INODE_UNINIT
+    /// group has every inode free.
+    fn inode_bitmap(&self, group: usize) -> VfsResult<Vec<u8>> {
+        let desc = self.group_counts(group)?;
+        if desc.flags & BG_INODE_UNINIT != 0 {
+            return Ok(self.uninit_inode_bitmap());
+        }
+        Ok(self
+            .disk
+            .read_bitmap(desc.inode_bitmap as usize * self.sb.block_size()))
+    }
+
+    /// The block bitmap of a BLOCK_UNINIT group, like
+    /// ext4_init_block_bitmap of Linux: only the superblock backup and the
+    /// descriptors of the group, the bitmaps and the inode tables in it
+    /// and the bits beyond its blocks are set. The descriptor blocks of
+    /// meta_bg aren't located, such a group fails with NotSupported.
+    fn uninit_block_bitmap(&self, group: usize) -> VfsResult<Vec<u8>> {
+        let sb = &self.sb;
+        if sb.feature_incompat & INCOMPAT_META_BG != 0 {
+            return Err(VfsError::NotSupported);
+        }
+        let block_size = sb.block_size();
+        let start = self.group_start(group);
+        let blocks = (sb.blocks_per_group as u64).min(sb.blocks_count - start);
+        let mut bitmap = vec![0; block_size];
+        (blocks as usize..8 * block_size).for_each(|x| bitmap_set(&mut bitmap, x, true));
+        if sb.group_has_super(group) {
+            let count = 1 + sb.group_desc_blocks() + sb.reserved_gdt_blocks as usize;
+            (0..count).for_each(|x| bitmap_set(&mut bitmap, x, true));
+        }
+        let table_blocks =
+            (sb.inodes_per_group as u64 * sb.inode_size as u64).div_ceil(block_size as u64);
+        // with flex_bg the metadata of other groups may be in the group.
+        for other in 0..sb.groups_count() {
+            let desc = self.disk.group_desc(sb, other)?;
+            let metadata = [desc.block_bitmap, desc.inode_bitmap]
+                .into_iter()
+                .chain(desc.inode_table..desc.inode_table + table_blocks);
+            for block in metadata.filter(|x| (start..start + blocks).contains(x)) {
+                bitmap_set(&mut bitmap, (block - start) as usize, true);
+            }
+        }
+        Ok(bitmap)
+    }
+
+    /// The inode bitmap of an INODE_UNINIT group, the bits beyond its
+    /// inodes are set.
+    fn uninit_inode_bitmap(&self) -> Vec<u8> {
+        let block_size = self.sb.block_size();
+        let mut bitmap = vec![0; block_size];
+        let ipg = self.sb.inodes_per_group as usize;
+        (ipg..8 * block_size).for_each(|x| bitmap_set(&mut bitmap, x, true));
+        bitmap
+    }
+
+    /// Write the bitmap of the uninitialized group and clear its flag
+    /// before the first allocation in it: BLOCK_UNINIT writes the block
+    /// bitmap, INODE_UNINIT the inode bitmap, with the whole inode table
+    /// unused. The bitmap checksums are set by update_checksums.
+    fn init_group(&self, group: usize, flag: u16) -> VfsResult<()> {
+        let sb = &self.sb;
+        let desc = self.disk.group_desc(sb, group)?;
+        match flag {
+            BG_BLOCK_UNINIT => {
+                let bitmap = self.uninit_block_bitmap(group)?;
+                self.write_block(desc.block_bitmap, &bitmap);
+            }
+            _ => self.write_block(desc.inode_bitmap, &self.uninit_inode_bitmap()),
+        }
+        let ipg = sb.inodes_per_group as i64;
+        self.modify(sb.group_desc_offset(group), sb.group_desc_size(), |desc| {
+            set_u16(desc, BG_FLAGS, le_u16(desc, BG_FLAGS) & !flag);
+            if flag == BG_INODE_UNINIT {
+                let unused = desc_counter(sb, desc, BG_ITABLE_UNUSED) as i64;
+                add_desc_counter(sb, desc, BG_ITABLE_UNUSED, ipg - unused);
+            }
+        });
+        Ok(())
+    }
+
+    /// Modify the block bitmap of the group in the cache.
+    #[allow(dead_code)]
+    fn update_block_bitmap<R>(&self, group: usize, f: impl FnOnce(&mut [u8]) -> R) -> VfsResult<R> {
+        let desc = self.disk.group_desc(&self.sb, group)?;
+        Ok(self
+            .disk
+            .update_bitmap(desc.block_bitmap as usize * self.sb.block_size(), f))
+    }
+
+    /// Modify the inode bitmap of the group in the cache.
+    #[allow(dead_code)]
+    fn update_inode_bitmap<R>(&self, group: usize, f: impl FnOnce(&mut [u8]) -> R) -> VfsResult<R> {
+        let desc = self.disk.group_desc(&self.sb, group)?;
+        Ok(self
+            .disk
+            .update_bitmap(desc.inode_bitmap as usize * self.sb.block_size(), f))
+    }
+}
+
+/// The fields written by the orphan handling, see disk_layout.
+const S_R_BLOCKS_LO: usize = offset_of!(disk::SuperBlock, r_blocks_count_lo);
+const S_FREE_BLOCKS_LO: usize = offset_of!(disk::SuperBlock, free_blocks_count_lo);
+const S_FREE_INODES: usize = offset_of!(disk::SuperBlock, free_inodes_count);
+const S_WTIME: usize = offset_of!(disk::SuperBlock, wtime);
+const S_MNT_COUNT: usize = offset_of!(disk::SuperBlock, mnt_count);
+const S_WTIME_HI: usize = offset_of!(disk::SuperBlock, wtime_hi);
+/// The group of a backup superblock, 0 in the primary.
+const S_BLOCK_GROUP_NR: usize = offset_of!(disk::SuperBlock, block_group_nr);
+const S_DEF_RESUID: usize = offset_of!(disk::SuperBlock, def_resuid);
+const S_DEF_RESGID: usize = offset_of!(disk::SuperBlock, def_resgid);
+const S_VOLUME_NAME: usize = offset_of!(disk::SuperBlock, volume_name);
+pub(crate) const S_LAST_ORPHAN: usize = offset_of!(disk::SuperBlock, last_orphan);
+const S_R_BLOCKS_HI: usize = offset_of!(disk::SuperBlock, r_blocks_count_hi);
+const S_FREE_BLOCKS_HI: usize = offset_of!(disk::SuperBlock, free_blocks_count_hi);
+/// The (lo, hi) halves of the counters in the group descriptor.
+const BG_FREE_BLOCKS: (usize, usize) = (
+    offset_of!(GroupDesc32, free_blocks_count_lo),
+    offset_of!(GroupDesc64, free_blocks_count_hi),
+);
+const BG_FREE_INODES: (usize, usize) = (
+    offset_of!(GroupDesc32, free_inodes_count_lo),
+    offset_of!(GroupDesc64, free_inodes_count_hi),
+);
+const BG_USED_DIRS: (usize, usize) = (
+    offset_of!(GroupDesc32, used_dirs_count_lo),
+    offset_of!(GroupDesc64, used_dirs_count_hi),
+);
+pub(crate) const I_SIZE: (usize, usize) = (offset_of!(Inode, size_lo), offset_of!(Inode, size_hi));
+pub(crate) const I_FLAGS: usize = offset_of!(Inode, flags);
+/// The (lo, hi) halves of the owner and the group.
+const I_UID: (usize, usize) = (offset_of!(Inode, uid_lo), offset_of!(Inode, uid_hi));
+const I_GID: (usize, usize) = (offset_of!(Inode, gid_lo), offset_of!(Inode, gid_hi));
+pub(crate) const I_DTIME: usize = offset_of!(Inode, dtime);
+pub(crate) const I_LINKS_COUNT: usize = offset_of!(Inode, links_count);
+pub(crate) const I_BLOCKS: (usize, usize) =
+    (offset_of!(Inode, blocks_lo), offset_of!(Inode, blocks_hi));
+pub(crate) const I_BLOCK: usize = offset_of!(Inode, block);
+const I_GENERATION: usize = offset_of!(Inode, generation);
+pub(crate) const I_FILE_ACL: (usize, usize) = (
+    offset_of!(Inode, file_acl_lo),
+    offset_of!(Inode, file_acl_hi),
+);
+const I_MODE: usize = offset_of!(Inode, mode);
+const I_EXTRA_ISIZE: usize = disk::extra_offset(offset_of!(InodeExtra, extra_isize));
+/// The size of the original inode, the extra fields follow it.
+const GOOD_OLD_INODE_SIZE: usize = disk::GOOD_OLD_INODE_SIZE;
+/// i_extra_isize of the new inodes, up to i_crtime_extra like Linux.
+const NEW_EXTRA_ISIZE: u16 = 32;
+/// The extents in i_block, with its header.
+const IN_INODE_EXTENTS: u16 = 4;
+/// The unused inodes at the end of the inode table of the group.
+const BG_ITABLE_UNUSED: (usize, usize) = (
+    offset_of!(GroupDesc32, itable_unused_lo),
+    offset_of!(GroupDesc64, itable_unused_hi),
+);
+const BG_FLAGS: usize = offset_of!(GroupDesc32, flags);
+const S_IFMT: u16 = 0xF000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFREG: u16 = 0x8000;
+/// The permissions of the created files and directories, there is no
+/// mode to create with and no umask.
+const NEW_FILE_PERM: u16 = 0o644;
+const NEW_DIR_PERM: u16 = 0o755;
+/// The file_type of the directory entries.
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_CHRDEV: u8 = 3;
+const FT_BLKDEV: u8 = 4;
+const FT_FIFO: u8 = 5;
+const FT_SOCK: u8 = 6;
+/// The reference count in the header of the xattr block.
+const XATTR_REFCOUNT: usize = offset_of!(XattrHeader, refcount);
+
+/// The times of a new inode.
+const NEW_TIMES: &[TimeField] = &[I_ATIME, I_CTIME, I_MTIME, I_CRTIME];
+/// The times of a change of the content, like a write or a new entry of
+/// a directory.
+const CHANGE_TIMES: &[TimeField] = &[I_CTIME, I_MTIME];
+
+/// The byte at the offset of the inode is a time but the creation time,
+/// or the checksum a change of the times updates.
+pub(crate) fn is_time_byte(offset: usize) -> bool {
+    let fields = [I_ATIME, I_CTIME, I_MTIME];
+    let times = fields.iter().flat_map(|x| [x.sec, x.extra]);
+    let checksums = [I_CHECKSUM_LO, I_CHECKSUM_HI].into_iter();
+    times
+        .map(|x| x..x + 4)
+        .chain(checksums.map(|x| x..x + 2))
+        .any(|x| x.contains(&offset))
+}
+
+/// Write the time of the inode, the fields the inode doesn't have are
+/// skipped. Without its extra field the time is saturated to 2038, like
+/// Linux, and loses the nanoseconds.
+fn set_time(raw: &mut [u8], inode_size: usize, field: TimeField, time: Timestamp) {
+    let end = inode_fields_end(raw, inode_size);
+    let time = match field.extra + 4 <= end {
+        true => time,
+        false => time.small(),
+    };
+    let (sec, extra) = time.encode();
+    if field.sec + 4 <= end {
+        set_u32(raw, field.sec, sec);
+    }
+    if field.extra + 4 <= end {
+        set_u32(raw, field.extra, extra);
+    }
+}
+
+pub(crate) fn set_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+pub(crate) fn set_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// The counter of the group descriptor, the hi half only exists in the
+/// 64 bytes descriptors.
+fn desc_counter(sb: &SuperBlockInfo, desc: &[u8], (lo, hi): (usize, usize)) -> u32 {
+    let mut value = le_u16(desc, lo) as u32;
+    if sb.group_desc_size() >= 64 {
+        value |= (le_u16(desc, hi) as u32) << 16;
+    }
+    value
+}
+
+/// Add delta to the counter of the group descriptor, the hi half only
+/// exists in the 64 bytes descriptors.
+fn add_desc_counter(sb: &SuperBlockInfo, desc: &mut [u8], (lo, hi): (usize, usize), delta: i64) {
+    let value = desc_counter(sb, desc, (lo, hi)) as i64;
+    let value = (value + delta).clamp(0, u32::MAX as i64) as u32;
+    set_u16(desc, lo, value as u16);
+    if sb.group_desc_size() >= 64 {
+        set_u16(desc, hi, (value >> 16) as u16);
+    }
+}
+
+// The changes of the inodes and of the free counts, and the frees of the
+// blocks and the inodes, the orphan list is in ext4_orphan.rs. Every
+// function here writes through the running transaction.
+impl Ext4Volume {
+    /// Modify len bytes at offset on the disk.
+    pub(crate) fn modify<R>(&self, offset: usize, len: usize, f: impl FnOnce(&mut [u8]) -> R) -> R {
+        let mut data = vec![0; len];
+        self.disk.read_into(offset, &mut data);
+        let r = f(&mut data);
+        self.disk.write_offset(offset, &data);
+        r
+    }
+
+    /// Modify the on-disk inode.
+    pub(crate) fn modify_inode<R>(&self, ino: u32, f: impl FnOnce(&mut [u8]) -> R) -> VfsResult<R> {
+        let offset = self.inode_offset(ino)?;
+        self.snapshot_pending.lock().push(ino);
+        Ok(self.modify(offset, self.sb.inode_size as usize, f))
+    }
+
+    /// Add the freed blocks and inodes to the counters of the group, dirs
+    /// is the change of the used directories of the group. The superblock
+    /// gets the totals at the commit, see derive_free_counts.
+    fn add_free_counts(&self, group: usize, blocks: i64, inodes: i64, dirs: i64) {
+        let sb = &self.sb;
+        self.modify(sb.group_desc_offset(group), sb.group_desc_size(), |desc| {
+            add_desc_counter(sb, desc, BG_FREE_BLOCKS, blocks);
+            add_desc_counter(sb, desc, BG_FREE_INODES, inodes);
+            add_desc_counter(sb, desc, BG_USED_DIRS, dirs);
+        });
+    }
+
+    /// Count the free blocks and inodes of the groups, at the mount after
+    /// the journal is replayed. A superblock which counts others, like
+    /// after a crash without a journal, gets the counts of the groups with
+    /// the next commit changing them.
+    fn count_free(&self) -> VfsResult<()> {
+        let groups = (0..self.sb.groups_count())
+            .map(|x| {
+                self.disk.mount_poll()?;
+                self.group_counts(x).map(|x| (x.free_blocks, x.free_inodes))
+            })
+            .collect::<VfsResult<Vec<_>>>()?;
+        let blocks = groups.iter().map(|x| x.0 as u64).sum::<u64>();
+        let inodes = groups.iter().map(|x| x.1 as u64).sum::<u64>();
+        let sb = self.read_superblock();
+        if (sb.free_blocks_count, sb.free_inodes_count as u64) != (blocks, inodes) {
+            log::warn!(
+                "the ext4 superblock counts {} free blocks and {} free inodes, the groups {} and {}",
+                sb.free_blocks_count,
+                sb.free_inodes_count,
+                blocks,
+                inodes
+            );
+        }
+        self.free_block_count.free.store(blocks, Ordering::Release);
+        self.free_inode_count.free.store(inodes, Ordering::Release);
+        *self.group_free.lock() = GroupFree {
+            groups,
+            blocks,
+            inodes,
+        };
+        self.free_counted.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Count the free blocks and inodes if the mount didn't, for a fast
+    /// mount. The volume is read-only until they're counted, two first
+    /// readers count the same.
+    fn count_free_lazily(&self) -> VfsResult<()> {
+        match self.free_counted.load(Ordering::Acquire) {
+            true => Ok(()),
+            false => self.count_free(),
+        }
+    }
+
+    /// Drop the caches of a read-only volume whose disk was changed by
+    /// another writer, see revalidate.rs. The disk changed if the mount
+    /// count or the write time of the superblock did since the mount or
+    /// the last revalidate. Then the group cache, the replayed journal,
+    /// the free counts and the pages of the device are made again from
+    /// the disk, and every file with a wrapper reads its inode again.
+    /// A writable volume is the only writer of its disk, it's not
+    /// revalidated. Fail with InvalidData if the new superblock is
+    /// invalid or its geometry changed, it must be mounted again then.
+    /// return whether the disk changed.
+    fn revalidate(&self) -> VfsResult<bool> {
+        if !self.is_read_only() {
+            log::warn!("ext4 revalidate of a writable mount, nothing to do");
+            return Ok(false);
+        }
+        let mut raw = vec![0; 1024];
+        self.disk.read_raw(SUPERBLOCK_OFFSET, &mut raw);
+        let stamp = superblock_stamp(&raw);
+        let mut seen = self.seen.lock();
+        if *seen == stamp {
+            return Ok(false);
+        }
+        if let Err(reason) = check_superblock(&raw) {
+            log::error!("the changed ext4 superblock is invalid: {}", reason);
+            return Err(VfsError::InvalidData);
+        }
+        let sb = SuperBlockInfo::parse(&raw);
+        if !same_geometry(&self.sb, &sb) {
+            log::error!("the geometry of the ext4 disk changed, it must be mounted again");
+            return Err(VfsError::InvalidData);
+        }
+        *seen = stamp;
+        drop(seen);
+        info!("the ext4 disk changed, its caches are dropped");
+        self.disk.drop_caches();
+        if sb.needs_recovery() {
+            self.replay_in_memory(&sb)?;
+        }
+        if self.free_counted.load(Ordering::Acquire) {
+            self.count_free()?;
+        }
+        let mut errors = self.errors.lock();
+        if !errors.dirty {
+            errors.history = ErrorHistory::parse(&raw);
+        }
+        drop(errors);
+        cache::invalidate_dev(self.disk.dev);
+        let wrappers: Vec<_> = self
+            .wrappers
+            .lock()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        for wrapper in wrappers {
+            if let Err(err) = wrapper.reload(true) {
+                log::warn!(
+                    "ext4 inode of {} can't be read again: {:?}",
+                    wrapper.file_name,
+                    err
+                );
+            }
+        }
+        Ok(true)
+    }
+
+    /// The change of the free counts by the running transaction, from the
+    /// group descriptors it logged, and the superblock set to the new
+    /// totals in it. end_free_counts applies it.
+    fn derive_free_counts(&self) -> VfsResult<FreeChange> {
+        let sb = &self.sb;
+        let logged = self.disk.logged_blocks();
+        let group_free = self.group_free.lock();
+        let mut change = FreeChange::default();
+        for group in 0..group_free.groups.len() {
+            let block = (sb.group_desc_offset(group) / sb.block_size()) as u64;
+            if !logged.contains(&block) {
+                continue;
+            }
+            let desc = self.group_counts(group)?;
+            let (blocks, inodes) = group_free.groups[group];
+            if (desc.free_blocks, desc.free_inodes) == (blocks, inodes) {
+                continue;
+            }
+            change.blocks += desc.free_blocks as i64 - blocks as i64;
+            change.inodes += desc.free_inodes as i64 - inodes as i64;
+            change
+                .groups
+                .push((group, (desc.free_blocks, desc.free_inodes)));
+        }
+        if change.groups.is_empty() {
+            return Ok(change);
+        }
+        let blocks = (group_free.blocks as i64 + change.blocks).max(0) as u64;
+        let inodes = (group_free.inodes as i64 + change.inodes).max(0) as u32;
+        self.modify(SUPERBLOCK_OFFSET, 1024, |raw| {
+            set_u32(raw, S_FREE_BLOCKS_LO, blocks as u32);
+            if sb.is_64bit() {
+                set_u32(raw, S_FREE_BLOCKS_HI, (blocks >> 32) as u32);
+            }
+            set_u32(raw, S_FREE_INODES, inodes);
+        });
+        Ok(change)
+    }
+
+    /// End the free counts of the running transaction, with the change of
+    /// derive_free_counts if it's committed, None if it isn't.
+    fn end_free_counts(&self, change: Option<FreeChange>) {
+        let change = change.unwrap_or_default();
+        if !change.groups.is_empty() {
+            let mut group_free = self.group_free.lock();
+            for (group, counts) in change.groups.iter() {
+                group_free.groups[*group] = *counts;
+            }
+            group_free.blocks = (group_free.blocks as i64 + change.blocks).max(0) as u64;
+            group_free.inodes = (group_free.inodes as i64 + change.inodes).max(0) as u64;
+        }
+        self.free_block_count.end(change.blocks);
+        self.free_inode_count.end(change.inodes);
+    }
+
+    /// Clear the blocks [start, start + len) in the block bitmaps and count
+    /// them as free. return the number of the blocks which were in use.
+    pub(crate) fn free_blocks(&self, start: u64, len: u64) -> VfsResult<u64> {
+        let sb = &self.sb;
+        let first_data = sb.first_data_block as u64;
+        if start < first_data || start + len > sb.blocks_count {
+            return Err(VfsError::InvalidData);
+        }
+        let bpg = sb.blocks_per_group as u64;
+        let mut freed = 0;
+        let mut block = start;
+        while block < start + len {
+            let group = ((block - first_data) / bpg) as usize;
+            let first = ((block - first_data) % bpg) as usize;
+            let count = min(bpg as usize - first, (start + len - block) as usize);
+            let desc = self.disk.group_desc(sb, group)?;
+            let bitmap = desc.block_bitmap as usize * sb.block_size();
+            let used = self.modify(bitmap, sb.block_size(), |bitmap| {
+                let mut used = 0;
+                for bit in first..first + count {
+                    if bitmap_test(bitmap, bit) {
+                        bitmap_set(bitmap, bit, false);
+                        used += 1;
+                    }
+                }
+                used
+            });
+            self.add_free_counts(group, used, 0, 0);
+            freed += used as u64;
+            block += count as u64;
+        }
+        self.free_block_count.add_freed(freed);
+        Ok(freed)
+    }
+
+    /// Free the data blocks [start, start + len) like free_blocks, with
+    /// secure_delete they are zeroed after the commit of the transaction.
+    /// The blocks of a range which was partly free already aren't zeroed,
+    /// the bitmap can't be trusted and another file may own some.
+    pub(crate) fn free_data_blocks(&self, start: u64, len: u64) -> VfsResult<u64> {
+        let freed = self.free_blocks(start, len)?;
+        if !self.options.secure_delete {
+            return Ok(freed);
+        }
+        if freed == len {
+            self.zero_pending.lock().push((start, len));
+        } else {
+            log::warn!(
+                "ext4 blocks {}..{} were partly free, they aren't zeroed",
+                start,
+                start + len
+            );
+        }
+        Ok(freed)
+    }
+
+    /// Clear the inode in the inode bitmap and count it as free.
+    pub(crate) fn free_inode(&self, ino: u32, is_dir: bool) -> VfsResult<()> {
+        let (group, index) = self.sb.inode_group(ino);
+        let desc = self.disk.group_desc(&self.sb, group)?;
+        let bitmap = desc.inode_bitmap as usize * self.sb.block_size();
+        let used = self.modify(bitmap, self.sb.block_size(), |bitmap| {
+            let used = bitmap_test(bitmap, index);
+            bitmap_set(bitmap, index, false);
+            used
+        });
+        if used {
+            self.add_free_counts(group, 0, 1, if is_dir { -1 } else { 0 });
+            self.free_inode_count.add_freed(1);
+        }
+        Ok(())
+    }
+
+    /// Drop a reference to the xattr block, the last one frees it.
+    pub(crate) fn release_xattr_block(&self, block: u64) -> VfsResult<()> {
+        if block >= self.sb.blocks_count {
+            return Err(VfsError::InvalidData);
+        }
+        let block_size = self.sb.block_size();
+        let mut data = self.read_block(block);
+        data.truncate(block_size);
+        let refcount = le_u32(&data, XATTR_REFCOUNT);
+        if refcount <= 1 {
+            self.free_blocks(block, 1)?;
+            return Ok(());
+        }
+        set_u32(&mut data, XATTR_REFCOUNT, refcount - 1);
+        if self.sb.has_metadata_csum() {
+            set_xattr_block_csum(&self.sb, block, &mut data);
+        }
+        self.disk.write_offset(block as usize * block_size, &data);
+        Ok(())
+    }
+
+    /// Check if the directory has no entries besides "." and "..".
+    fn dir_is_empty(&self, ino: u32, dir: &InodeInfo) -> VfsResult<bool> {
+        if dir.has_inline_data() || !dir.uses_extents() {
+            return Err(VfsError::NotSupported);
+        }
+        let (extents, _) = ext4_check::inode_blocks(self, dir)?;
+        let seed = inode_seed(&self.sb, ino, dir.generation);
+        for extent in extents.iter() {
+            for block in extent.physical..extent.physical + extent.len as u64 {
+                let mut data = self.read_block(block);
+                data.truncate(self.sb.block_size());
+                self.verify_dir_block(ino, seed, block, &data)?;
+                let used = self
+                    .parse_dir_block(ino, block, &data)?
+                    .iter()
+                    .any(|x| x.name != b"." && x.name != b"..");
+                if used {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Take a slot of open_files, the mount at its max fails with
+    /// StorageFull. Linux has ENFILE for it, the opens reach here by
+    /// INodeInterface::open, so the syscall sees ENOSPC.
+    fn open_file(&self) -> VfsResult<()> {
+        let max = self.max_open_files.load(Ordering::Relaxed);
+        self.open_files
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| {
+                (x < max).then_some(x + 1)
+            })
+            .map(|_| ())
+            .map_err(|open| {
+                log::warn!("ext4 dev {}: {} files are open", self.disk.dev, open);
+                VfsError::StorageFull
+            })
+    }
+}
+
+// The entries of the files created by the shim: it allocates the inode
+// and adds its entry, the data of the file goes in its extent tree as
+// ext4_extent.rs changes it, ext4_rs only writes what the shim can't. A
+// full directory grows by a block, with dir_index its index is changed
+// in ext4_htree/shim.rs. A rename moves the entry and the ".." of a
+// directory with it. Every function here writes through the running
+// transaction, the checksums are set by update_checksums.
+impl Ext4Volume {
+    /// The units of i_blocks per block, the sectors or the blocks with
+    /// huge_file.
+    pub(crate) fn block_sectors(&self, inode: &InodeInfo) -> u64 {
+        match self.sb.feature_ro_compat & RO_COMPAT_HUGE_FILE != 0
+            && inode.flags & EXT4_HUGE_FILE_FL != 0
+        {
+            true => 1,
+            false => (self.sb.block_size() / 512) as u64,
+        }
+    }
+
+    /// The group descriptor with the counters and the flags on the disk,
+    /// only the locations of the cached ones are reliable.
+    fn group_counts(&self, group: usize) -> VfsResult<GroupDesc> {
+        self.disk.group_desc(&self.sb, group)?;
+        let raw = self.disk.read_offset(self.sb.group_desc_offset(group));
+        Ok(GroupDesc::parse(&self.sb, &raw))
+    }
+
+    /// Allocate a run of up to count free blocks, from the first free
+    /// block from goal on, wrapping around at the end of the filesystem.
+    /// The blocks are reserved from the free count first, and the run
+    /// stays out of the reserved blocks unless the owner of the file may
+    /// use them. return the first block and the length of the run.
+    pub(crate) fn alloc_blocks(
+        &self,
+        goal: u64,
+        count: u32,
+        owner: &InodeInfo,
+    ) -> VfsResult<(u64, u32)> {
+        self.alloc_file_blocks(None, goal, count, owner)
+    }
+
+    /// alloc_blocks for the data of the file ino, the run may be in its
+    /// allocation window.
+    pub(crate) fn alloc_file_blocks(
+        &self,
+        ino: Option<u32>,
+        goal: u64,
+        count: u32,
+        owner: &InodeInfo,
+    ) -> VfsResult<(u64, u32)> {
+        let reserved = self
+            .free_block_count
+            .reserve(count as u64, self.kept_blocks(owner));
+        if reserved == 0 {
+            return Err(VfsError::StorageFull);
+        }
+        let r = self.take_blocks(ino, goal, reserved as u32);
+        let used = r.as_ref().map_or(0, |x| x.1 as u64);
+        self.free_block_count.release(reserved - used);
+        r
+    }
+
+    /// Take a run of up to count free blocks for the file ino, out of the
+    /// allocation windows of the others if there are free blocks besides
+    /// them, see take_free_blocks.
+    fn take_blocks(&self, ino: Option<u32>, goal: u64, count: u32) -> VfsResult<(u64, u32)> {
+        let others = self.windows.lock().others(ino);
+        if !others.is_empty() {
+            if let Ok(run) = self.take_free_blocks(goal, count, &others) {
+                return Ok(run);
+            }
+        }
+        self.take_free_blocks(goal, count, &[])
+    }
+
+    /// Take a run of up to count free blocks in the bitmaps for
+    /// alloc_blocks, which reserved them, the badlisted blocks and those
+    /// in avoid aren't taken. The bitmap of a BLOCK_UNINIT group is
+    /// initialized by the first run in it.
+    fn take_free_blocks(
+        &self,
+        goal: u64,
+        count: u32,
+        avoid: &[Range<u64>],
+    ) -> VfsResult<(u64, u32)> {
+        let sb = &self.sb;
+        let first_data = sb.first_data_block as u64;
+        let bpg = sb.blocks_per_group as u64;
+        let goal = goal.clamp(first_data, sb.blocks_count - 1);
+        let groups = sb.groups_count();
+        let goal_group = ((goal - first_data) / bpg) as usize;
+        // the goal group is searched again from its start at the end.
+        for i in 0..=groups {
+            let group = (goal_group + i) % groups;
+            let desc = self.group_counts(group)?;
+            if desc.free_blocks == 0 {
+                continue;
+            }
+            if desc.flags & BG_BLOCK_UNINIT != 0 && self.init_group(group, BG_BLOCK_UNINIT).is_err()
+            {
+                continue;
+            }
+            let start = first_data + group as u64 * bpg;
+            let blocks = bpg.min(sb.blocks_count - start) as usize;
+            let from = if i == 0 { (goal - start) as usize } else { 0 };
+            let bitmap = self.block_bitmap(group)?;
+            let bad: BTreeSet<usize> = self
+                .bad_blocks
+                .lock()
+                .range(start..start + blocks as u64)
+                .map(|x| (x - start) as usize)
+                .collect();
+            let free = |x: &usize| {
+                !bitmap_test(&bitmap, *x)
+                    && !bad.contains(x)
+                    && !avoid.iter().any(|r| r.contains(&(start + *x as u64)))
+            };
+            let Some(bit) = (from..blocks).find(free) else {
+                continue;
+            };
+            let len = (bit..blocks).take(count as usize).take_while(free).count();
+            let offset = desc.block_bitmap as usize * sb.block_size();
+            self.modify(offset, sb.block_size(), |bitmap| {
+                (bit..bit + len).for_each(|x| bitmap_set(bitmap, x, true))
+            });
+            self.add_free_counts(group, -(len as i64), 0, 0);
+            return Ok((start + bit as u64, len as u32));
+        }
+        Err(VfsError::StorageFull)
+    }
+
+    /// The free blocks an allocation for the files of the owner must leave:
+    /// the reserved ones are left to root, to s_def_resuid and
+    /// s_def_resgid, and to every owner with the reserve_override option,
+    /// like ext4_has_free_clusters of Linux. The blocks held by the write
+    /// buffers are left to their flush.
+    /// TODO: check the cred of the task instead of the owner of the file
+    /// when vfscore has it.
+    fn kept_blocks(&self, owner: &InodeInfo) -> u64 {
+        let raw = self.disk.read_offset(SUPERBLOCK_OFFSET);
+        let sb = SuperBlockInfo::parse(&raw);
+        let reserve = self.options.reserve_override
+            || owner.uid == 0
+            || owner.uid == le_u16(&raw, S_DEF_RESUID) as u32
+            || owner.gid == le_u16(&raw, S_DEF_RESGID) as u32;
+        // the blocks held by the write buffers of the other files.
+        let held = self.buffered_blocks.load(Ordering::Acquire);
+        match reserve {
+            true => held,
+            false => sb.r_blocks_count + held,
+        }
+    }
+
+    /// The first block of the group.
+    pub(crate) fn group_start(&self, group: usize) -> u64 {
+        self.sb.first_data_block as u64 + group as u64 * self.sb.blocks_per_group as u64
+    }
+
+    /// Protect the superblocks, the group descriptors with their reserved
+    /// blocks and the journal superblock from the data writes, and the
+    /// bitmaps with guard_bitmaps, see Ext4Disk::guard_write. A range which
+    /// can't be read, like the descriptor of a corrupted group, is logged
+    /// and left out.
+    fn protect_metadata(&self) {
+        let sb = &self.sb;
+        let block_size = sb.block_size();
+        let mut ranges = Vec::new();
+        let gdt_blocks = match sb.feature_incompat & INCOMPAT_META_BG {
+            0 => sb.group_desc_blocks() + sb.reserved_gdt_blocks as usize,
+            _ => 0,
+        };
+        for group in (0..sb.groups_count()).filter(|x| sb.group_has_super(*x)) {
+            ranges.push(Protected {
+                offset: self.group_start(group) as usize * block_size,
+                len: (1 + gdt_blocks) * block_size,
+                what: "superblock and group descriptors",
+            });
+        }
+        if let Some(journal) = self.journal.lock().as_ref() {
+            match journal.physical(0) {
+                Ok(block) => ranges.push(Protected {
+                    offset: block as usize * block_size,
+                    len: block_size,
+                    what: "journal superblock",
+                }),
+                Err(err) => log::warn!("can't protect the journal superblock: {:?}", err),
+            }
+        }
+        if self.options.guard_bitmaps {
+            for group in 0..sb.groups_count() {
+                let desc = match self.disk.group_desc(sb, group) {
+                    Ok(desc) => desc,
+                    Err(err) => {
+                        log::warn!("can't protect the bitmaps of group {}: {:?}", group, err);
+                        continue;
+                    }
+                };
+                for (block, what) in [
+                    (desc.block_bitmap, "block bitmap"),
+                    (desc.inode_bitmap, "inode bitmap"),
+                ] {
+                    ranges.push(Protected {
+                        offset: block as usize * block_size,
+                        len: block_size,
+                        what,
+                    });
+                }
+            }
+        }
+        self.disk.protect(ranges);
+    }
+
+    /// The group of a new directory in dir_ino, like the Orlov allocator
+    /// of Linux. The directories in the root spread over the groups with
+    /// more free inodes and blocks than the average, the one with the
+    /// fewest directories first, so the trees below them have room near
+    /// them. A deeper directory stays in the group of its parent unless
+    /// it's fuller than the average. Every directory of a deterministic
+    /// mount stays in the group of its parent.
+    fn dir_group(&self, dir_ino: u32) -> VfsResult<usize> {
+        let (parent, _) = self.sb.inode_group(dir_ino);
+        if self.options.deterministic {
+            return Ok(parent);
+        }
+        let groups = self.sb.groups_count();
+        let descs = (0..groups)
+            .map(|x| self.group_counts(x))
+            .collect::<VfsResult<Vec<_>>>()?;
+        let average = |count: fn(&GroupDesc) -> u32| {
+            descs.iter().map(|x| count(x) as u64).sum::<u64>() / groups as u64
+        };
+        let (inodes, blocks) = (average(|x| x.free_inodes), average(|x| x.free_blocks));
+        let roomy = |desc: &GroupDesc| {
+            desc.free_inodes > 0
+                && desc.free_inodes as u64 >= inodes
+                && desc.free_blocks as u64 >= blocks
+        };
+        if dir_ino != ROOT_INO && roomy(&descs[parent]) {
+            return Ok(parent);
+        }
+        let best = (0..groups)
+            .map(|i| (parent + i) % groups)
+            .filter(|x| roomy(&descs[*x]))
+            .min_by_key(|x| descs[*x].used_dirs);
+        Ok(best.unwrap_or(parent))
+    }
+
+    /// Allocate an inode, the first free one from the group goal on, it's
+    /// reserved from the free count first.
+    fn alloc_inode(&self, goal: usize, is_dir: bool) -> VfsResult<u32> {
+        if self.free_inode_count.reserve(1, 0) == 0 {
+            return Err(VfsError::StorageFull);
+        }
+        let r = self.take_inode(goal, is_dir);
+        if r.is_err() {
+            self.free_inode_count.release(1);
+        }
+        r
+    }
+
+    /// Take a free inode in the bitmaps for alloc_inode, which reserved
+    /// it. The inode bitmap of an INODE_UNINIT group is initialized by the
+    /// first inode in it. The unused inodes at the end of a table, from
+    /// bg_itable_unused, must stay after the new one: the inodes leaving
+    /// them are zeroed unless the table is, they may hold anything.
+    fn take_inode(&self, goal: usize, is_dir: bool) -> VfsResult<u32> {
+        let sb = &self.sb;
+        let ipg = sb.inodes_per_group as usize;
+        let inode_size = sb.inode_size as usize;
+        let groups = sb.groups_count();
+        for i in 0..groups {
+            let group = (goal + i) % groups;
+            let mut desc = self.group_counts(group)?;
+            if desc.free_inodes == 0 {
+                continue;
+            }
+            if desc.flags & BG_INODE_UNINIT != 0 {
+                self.init_group(group, BG_INODE_UNINIT)?;
+                desc = self.group_counts(group)?;
+            }
+            let bitmap = self.inode_bitmap(group)?;
+            let free = (0..ipg).find(|&index| {
+                let ino = (group * ipg + index + 1) as u32;
+                ino >= sb.first_ino && ino <= sb.inodes_count && !bitmap_test(&bitmap, index)
+            });
+            let Some(index) = free else {
+                continue;
+            };
+            let offset = desc.inode_bitmap as usize * sb.block_size();
+            self.modify(offset, sb.block_size(), |bitmap| {
+                bitmap_set(bitmap, index, true)
+            });
+            self.add_free_counts(group, 0, -1, is_dir as i64);
+            let used = self.modify(sb.group_desc_offset(group), sb.group_desc_size(), |desc| {
+                let unused = desc_counter(sb, desc, BG_ITABLE_UNUSED) as usize;
+                let max = ipg - index - 1;
+                if unused > max {
+                    add_desc_counter(sb, desc, BG_ITABLE_UNUSED, max as i64 - unused as i64);
+                }
+                ipg.saturating_sub(unused)
+            });
+            if index >= used && desc.flags & BG_INODE_ZEROED == 0 {
+                let zeros = vec![0; (index + 1 - used) * inode_size];
+                let offset = desc.inode_table as usize * sb.block_size() + used * inode_size;
+                self.disk.write_offset(offset, &zeros);
+            }
+            return Ok((group * ipg + index + 1) as u32);
+        }
+        Err(VfsError::StorageFull)
+    }
+
+    /// Allocate and fill a new inode of the mode near the directory
+    /// dir_ino, with an empty extent tree. Its times, its generation and
+    /// its charge are set by init_inode.
+    fn new_inode(&self, dir_ino: u32, mode: u16, links: u16) -> VfsResult<u32> {
+        let is_dir = mode & S_IFMT == S_IFDIR;
+        // a file stays in the group of its directory.
+        let group = match is_dir {
+            true => self.dir_group(dir_ino)?,
+            false => self.sb.inode_group(dir_ino).0,
+        };
+        let ino = self.alloc_inode(group, is_dir)?;
+        let inode_size = self.sb.inode_size as usize;
+        let extra_isize = self.new_extra_isize();
+        self.modify_inode(ino, |raw| {
+            // the old generation stays, the new one is its successor.
+            let generation = le_u32(raw, I_GENERATION);
+            raw.fill(0);
+            set_u16(raw, I_MODE, mode);
+            set_u16(raw, I_LINKS_COUNT, links);
+            set_u32(raw, I_FLAGS, EXT4_EXTENTS_FL);
+            set_u32(raw, I_GENERATION, generation);
+            set_u16(raw, I_BLOCK, EXTENT_MAGIC);
+            set_u16(raw, I_BLOCK + 4, IN_INODE_EXTENTS);
+            if inode_size > GOOD_OLD_INODE_SIZE {
+                set_u16(raw, I_EXTRA_ISIZE, extra_isize);
+            }
+        })?;
+        self.init_inode(ino)?;
+        Ok(ino)
+    }
+
+    /// i_extra_isize of the new inodes: NEW_EXTRA_ISIZE, raised to
+    /// s_min_extra_isize and s_want_extra_isize with extra_isize, within
+    /// the inode and aligned to 4 bytes like the xattrs after it.
+    fn new_extra_isize(&self) -> u16 {
+        let mut extra = NEW_EXTRA_ISIZE;
+        if self.sb.feature_ro_compat & RO_COMPAT_EXTRA_ISIZE != 0 {
+            extra = extra
+                .max(self.sb.min_extra_isize)
+                .max(self.sb.want_extra_isize);
+        }
+        let room = self
+            .sb
+            .inode_size
+            .saturating_sub(GOOD_OLD_INODE_SIZE as u16);
+        extra.min(room) & !3
+    }
+
+    fn health(&self) -> DiskHealth {
+        let health = &self.disk.health;
+        DiskHealth {
+            read_retries: health.read_retries.load(Ordering::Relaxed),
+            write_retries: health.write_retries.load(Ordering::Relaxed),
+            read_failures: health.read_failures.load(Ordering::Relaxed),
+            write_failures: health.write_failures.load(Ordering::Relaxed),
+            relocated: self.relocated.load(Ordering::Relaxed),
+            bad_blocks: self.bad_blocks.lock().len(),
+        }
+    }
+
+    /// The index of the first badlisted block of the len blocks from
+    /// physical.
+    fn first_bad(&self, physical: u64, len: u32) -> Option<u32> {
+        let bad = self.bad_blocks.lock();
+        let first = bad.range(physical..physical + len as u64).next()?;
+        Some((first - physical) as u32)
+    }
+
+    /// Move the logical block of the file off its bad physical block old:
+    /// a new block near it is mapped in its place and old is freed, the
+    /// badlist keeps it from being allocated again. The caller writes the
+    /// new block, return it.
+    fn relocate_block(
+        &self,
+        ino: u32,
+        tree: &mut ExtentTree,
+        lblock: u32,
+        old: u64,
+    ) -> VfsResult<u64> {
+        let inode = self.read_inode(ino)?;
+        let (physical, _) = self.alloc_blocks(old + 1, 1, &inode)?;
+        tree.remove(lblock, 1);
+        tree.insert(Extent {
+            logical: lblock,
+            len: 1,
+            physical,
+            uninit: false,
+        });
+        self.free_blocks(old, 1)?;
+        self.relocated.fetch_add(1, Ordering::Relaxed);
+        log::warn!(
+            "ext4 block {} of inode {} moved from {} to {}",
+            lblock,
+            ino,
+            old,
+            physical
+        );
+        Ok(physical)
+    }
+
+    /// Badlist the data blocks of the rolled back transaction whose writes
+    /// failed at every try, with relocate, so the write moves them when
+    /// it's tried again, see write_direct. return whether every failed
+    /// write was of them.
+    fn badlist_failed(&self, data: &[Extent]) -> bool {
+        let failed = self.disk.take_failed_writes();
+        if !self.options.relocate || failed.is_empty() {
+            return false;
+        }
+        let block_size = self.sb.block_size();
+        let blocks: BTreeSet<u64> = failed
+            .iter()
+            .flat_map(|&(at, len)| (at / block_size) as u64..(at + len).div_ceil(block_size) as u64)
+            .collect();
+        let in_data = blocks.iter().all(|block| {
+            data.iter()
+                .any(|x| (x.physical..x.physical + x.len as u64).contains(block))
+        });
+        if in_data {
+            self.bad_blocks.lock().extend(blocks);
+        }
+        in_data
+    }
+
+    /// Append a block to the directory, return the logical and the
+    /// physical block, the new block must be filled by the caller.
+    pub(crate) fn append_dir_block(&self, ino: u32) -> VfsResult<(u32, u64)> {
+        let inode = self.read_inode(ino)?;
+        let mut tree = self.extent_tree(ino, &inode)?;
+        let lblock = dir_blocks(&self.sb, &inode);
+        let (physical, _) = self.map_new_blocks(ino, &mut tree, lblock, 1)?;
+        self.store_extents(ino, &mut tree)?;
+        let size = inode.size + self.sb.block_size() as u64;
+        self.modify_inode(ino, |raw| {
+            set_u32(raw, I_SIZE.0, size as u32);
+            set_u32(raw, I_SIZE.1, (size >> 32) as u32);
+        })?;
+        Ok((lblock, physical))
+    }
+
+    /// Move the inline data of the file to a data block and give it an
+    /// extent tree, like ext4_convert_inline_data of Linux: the flags
+    /// lose EXT4_INLINE_DATA_FL for EXT4_EXTENTS_FL and the system.data
+    /// xattr goes. A file converted already is left as it is.
+    fn convert_inline(&self, ino: u32) -> VfsResult<()> {
+        let inode = self.read_inode(ino)?;
+        if !inode.has_inline_data() {
+            return Ok(());
+        }
+        if self.sb.feature_incompat & INCOMPAT_EXTENTS == 0 {
+            return Err(VfsError::NotSupported);
+        }
+        let data = inode.inline_data();
+        self.modify_inode(ino, |raw| {
+            let flags = (le_u32(raw, I_FLAGS) & !EXT4_INLINE_DATA_FL) | EXT4_EXTENTS_FL;
+            set_u32(raw, I_FLAGS, flags);
+            raw[I_BLOCK..I_BLOCK + 60].fill(0);
+            set_u16(raw, I_BLOCK, EXTENT_MAGIC);
+            set_u16(raw, I_BLOCK + 4, IN_INODE_EXTENTS);
+            remove_system_data(raw);
+        })?;
+        if !data.is_empty() {
+            let (written, _) = self.write_data(ino, 0, &data, &cancel::never)?;
+            if written < data.len() {
+                return Err(VfsError::StorageFull);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the data of the file at offset, the holes get new blocks
+    /// near the blocks before them. It fails with NotSupported for the
+    /// inline data, the block maps and the preallocated extents the shim
+    /// can't change, ext4_rs writes after the aborted transaction then. The write stops
+    /// between its runs of CANCEL_CHUNK when cancelled or out of space,
+    /// return the bytes written and the new size of the file.
+    fn write_data(
+        &self,
+        ino: u32,
+        offset: usize,
+        buffer: &[u8],
+        cancelled: &dyn Fn() -> bool,
+    ) -> VfsResult<(usize, u64)> {
+        let inode = self.read_inode(ino)?;
+        if inode.has_inline_data() || !inode.uses_extents() {
+            return Err(VfsError::NotSupported);
+        }
+        let mut tree = self.extent_tree(ino, &inode)?;
+        let block_size = self.sb.block_size();
+        let end = offset + buffer.len();
+        let last = ((end - 1) / block_size) as u32;
+        let chunk = (CANCEL_CHUNK / block_size).max(1) as u32;
+        let mut lblock = (offset / block_size) as u32;
+        let mut done = 0;
+        while lblock <= last {
+            if cancelled() {
+                match done {
+                    0 => return Err(VfsError::Blocking),
+                    _ => break,
+                }
+            }
+            let mapped = tree.find(lblock);
+            // the partial blocks of a run keep their bytes from source.
+            let (physical, len, fresh, source) = match mapped {
+                Some(x) if x.uninit => return Err(VfsError::NotSupported),
+                Some(x) => {
+                    let skip = lblock - x.logical;
+                    let (physical, len) = (x.physical + skip as u64, x.len - skip);
+                    // the run stops before a bad block, a bad one is moved.
+                    match self.first_bad(physical, len) {
+                        Some(0) => {
+                            let new = self.relocate_block(ino, &mut tree, lblock, physical)?;
+                            (new, 1, false, physical)
+                        }
+                        Some(good) => (physical, good, false, physical),
+                        None => (physical, len, false, physical),
+                    }
+                }
+                None => {
+                    let count = (last - lblock + 1).min(chunk);
+                    match self.map_new_blocks(ino, &mut tree, lblock, count) {
+                        Ok((physical, len)) => (physical, len, true, physical),
+                        Err(VfsError::StorageFull) if done > 0 => break,
+                        Err(err) => return Err(err),
+                    }
+                }
+            };
+            let len = len.min(last - lblock + 1).min(chunk) as usize;
+            // the run of the blocks from lblock at physical.
+            let run = lblock as usize * block_size;
+            let (start, stop) = (run.max(offset), (run + len * block_size).min(end));
+            with_scratch(len * block_size, |data| {
+                // the partial blocks at the ends keep their other bytes.
+                if !fresh && start > run {
+                    self.read_blocks_into(source, &mut data[..block_size])?;
+                }
+                if !fresh && stop < run + data.len() {
+                    let tail = (len - 1) * block_size;
+                    self.read_blocks_into(source + len as u64 - 1, &mut data[tail..])?;
+                }
+                data[start - run..stop - run]
+                    .copy_from_slice(&buffer[start - offset..stop - offset]);
+                self.write_data_block(physical, data)
+            })?;
+            done = stop - offset;
+            lblock += len as u32;
+        }
+        self.store_extents(ino, &mut tree)?;
+        let size = inode.size.max((offset + done) as u64);
+        if size != inode.size {
+            self.modify_inode(ino, |raw| {
+                set_u32(raw, I_SIZE.0, size as u32);
+                set_u32(raw, I_SIZE.1, (size >> 32) as u32);
+            })?;
+        }
+        Ok((done, size))
+    }
+
+    /// Set the size of the file, the blocks beyond a size not larger are
+    /// freed and the rest of its last block is zeroed, a larger size adds
+    /// a hole. It fails with NotSupported for the inline data and the
+    /// block maps.
+    pub(crate) fn truncate_data(&self, ino: u32, size: u64) -> VfsResult<()> {
+        let inode = self.read_inode(ino)?;
+        if inode.has_inline_data() || !inode.uses_extents() {
+            return Err(VfsError::NotSupported);
+        }
+        // the blocks beyond the size, of a smaller size or of an orphan.
+        if size <= inode.size {
+            let block_size = self.sb.block_size() as u64;
+            let mut tree = self.extent_tree(ino, &inode)?;
+            let tail = (size % block_size) as usize;
+            if tail > 0
+                && let Some(x) = tree.find((size / block_size) as u32)
+                && !x.uninit
+            {
+                let block = x.physical + (size / block_size - x.logical as u64);
+                let mut data = self.read_block(block);
+                data[tail..].fill(0);
+                self.write_data_block(block, &data)?;
+            }
+            let mut freed = 0;
+            for x in tree.truncate(size.div_ceil(block_size) as u32) {
+                freed += self.free_data_blocks(x.physical, x.len as u64)?;
+            }
+            let inode = self.read_inode(ino)?;
+            let i_blocks = inode
+                .blocks
+                .saturating_sub(freed * self.block_sectors(&inode));
+            self.charge(inode.uid, inode.gid, -((freed * block_size) as i64), 0);
+            self.modify_inode(ino, |raw| {
+                set_u32(raw, I_BLOCKS.0, i_blocks as u32);
+                set_u16(raw, I_BLOCKS.1, (i_blocks >> 32) as u16);
+            })?;
+            self.store_extents(ino, &mut tree)?;
+        }
+        self.modify_inode(ino, |raw| {
+            set_u32(raw, I_SIZE.0, size as u32);
+            set_u32(raw, I_SIZE.1, (size >> 32) as u32);
+        })
+    }
+
+    pub(crate) fn write_block(&self, block: u64, data: &[u8]) {
+        self.disk
+            .write_offset(block as usize * self.sb.block_size(), data);
+    }
+
+    /// Write the blocks of a file from block, checked by the write guard.
+    fn write_data_block(&self, block: u64, data: &[u8]) -> VfsResult<()> {
+        let offset = block as usize * self.sb.block_size();
+        self.disk.write_tagged(offset, data, WriteKind::Data)
+    }
+
+    /// Write the block of the directory ino, the running transaction sets
+    /// its checksum even if the inode of ino doesn't change.
+    pub(crate) fn write_dir_block(&self, ino: u32, block: u64, data: &[u8]) {
+        self.dirs_pending.lock().insert(ino);
+        self.write_block(block, data);
+    }
+
+    /// A new directory block of the entries, it ends with the checksum
+    /// tail with metadata_csum.
+    pub(crate) fn new_dir_block(&self, entries: &[(u32, u8, &[u8])]) -> Vec<u8> {
+        let entries: Vec<(u32, u8, &[u8])> = entries
+            .iter()
+            .map(|&(ino, file_type, name)| (ino, self.type_byte(file_type), name))
+            .collect();
+        let block_size = self.sb.block_size();
+        let mut block = vec![0; block_size];
+        match self.sb.has_metadata_csum() {
+            true => {
+                write_dirents(&mut block, block_size - DIRENT_TAIL_SIZE, &entries);
+                init_dirent_tail(&mut block);
+            }
+            false => write_dirents(&mut block, block_size, &entries),
+        }
+        block
+    }
+
+    /// Read the logical block of the directory mapped by extents, it's
+    /// verified with metadata_csum.
+    pub(crate) fn dir_block(
+        &self,
+        ino: u32,
+        dir: &InodeInfo,
+        extents: &[Extent],
+        lblock: u32,
+    ) -> VfsResult<(u64, Vec<u8>)> {
+        let block = extents
+            .iter()
+            .find(|x| x.contains(lblock) && !x.uninit)
+            .map(|x| x.physical + (lblock - x.logical) as u64)
+            .ok_or_else(|| self.corrupted("dir_block", "directory extent", ino, lblock as u64))?;
+        let mut data = self.read_block(block);
+        data.truncate(self.sb.block_size());
+        let seed = inode_seed(&self.sb, ino, dir.generation);
+        self.verify_dir_block(ino, seed, block, &data)?;
+        Ok((block, data))
+    }
+
+    /// Create a regular file or a directory and its entry in the
+    /// directory dir_ino. A directory gets its block with "." and "..".
+    /// return the new inode. It fails with NotSupported if the shim can't
+    /// modify the directory, ext4_rs creates the file after the aborted
+    /// transaction then.
+    fn create_entry(&self, dir_ino: u32, name: &[u8], is_dir: bool) -> VfsResult<u32> {
+        let dir = self.read_inode(dir_ino)?;
+        if dir.has_inline_data() || !dir.uses_extents() {
+            return Err(VfsError::NotSupported);
+        }
+        let (mode, links, file_type) = match is_dir {
+            true => (S_IFDIR | NEW_DIR_PERM, 2, FT_DIR),
+            false => (S_IFREG | NEW_FILE_PERM, 1, FT_REG_FILE),
+        };
+        let dir_links = match is_dir {
+            true => self.dir_link_added(dir.links_count)?,
+            false => dir.links_count,
+        };
+        let ino = self.new_inode(dir_ino, mode, links)?;
+        if is_dir {
+            let (_, block) = self.append_dir_block(ino)?;
+            let entries = [(ino, FT_DIR, &b"."[..]), (dir_ino, FT_DIR, &b".."[..])];
+            self.write_dir_block(ino, block, &self.new_dir_block(&entries));
+            // the ".." of the child, see unlink_entry for a count of 1.
+            if dir_links != dir.links_count {
+                self.modify_inode(dir_ino, |raw| set_u16(raw, I_LINKS_COUNT, dir_links))?;
+            }
+        }
+        self.add_entry(dir_ino, (ino, file_type, name))?;
+        self.touch_times(dir_ino, CHANGE_TIMES)?;
+        Ok(ino)
+    }
+
+    /// Create the special inode of the kind and its entry in the directory
+    /// dir_ino, like create_entry. Its i_block maps no blocks, it holds
+    /// the device of a device node, see encode_device.
+    fn create_node(
+        &self,
+        dir_ino: u32,
+        name: &[u8],
+        kind: NodeKind,
+        perm: u16,
+        device: [u32; 2],
+    ) -> VfsResult<u32> {
+        let dir = self.read_inode(dir_ino)?;
+        if dir.has_inline_data() || !dir.uses_extents() {
+            return Err(VfsError::NotSupported);
+        }
+        let file_type = match kind {
+            NodeKind::Fifo => FT_FIFO,
+            NodeKind::Char => FT_CHRDEV,
+            NodeKind::Block => FT_BLKDEV,
+            NodeKind::Socket => FT_SOCK,
+        };
+        let ino = self.new_inode(dir_ino, kind.mode() as u16 | perm, 1)?;
+        // like Linux, only the files, the directories and the symlinks
+        // get an extent tree.
+        self.modify_inode(ino, |raw| {
+            let flags = le_u32(raw, I_FLAGS) & !EXT4_EXTENTS_FL;
+            set_u32(raw, I_FLAGS, flags);
+            raw[I_BLOCK..I_BLOCK + 60].fill(0);
+            set_u32(raw, I_BLOCK, device[0]);
+            set_u32(raw, I_BLOCK + 4, device[1]);
+        })?;
+        self.add_entry(dir_ino, (ino, file_type, name))?;
+        self.touch_times(dir_ino, CHANGE_TIMES)?;
+        Ok(ino)
+    }
+
+    /// Add the entry (inode, file_type, name) to the directory. A linear
+    /// directory takes it in the first block with space for it, or grows
+    /// by a block, an indexed one in the leaf covering the hash. A name
+    /// which is in the directory fails with AlreadyExists, the check and
+    /// the insert are in the transaction, so of two creates of a name
+    /// only one adds it.
+    fn add_entry(&self, ino: u32, entry: (u32, u8, &[u8])) -> VfsResult<()> {
+        let entry = (entry.0, self.type_byte(entry.1), entry.2);
+        let dir = self.read_inode(ino)?;
+        if dir.has_inline_data() || !dir.uses_extents() {
+            return Err(VfsError::NotSupported);
+        }
+        let extents = self.inode_extents(ino, &dir)?;
+        if dir.flags & EXT4_INDEX_FL != 0 {
+            return self.add_dx_entry(ino, &dir, &extents, entry);
+        }
+        let blocks = dir_blocks(&self.sb, &dir);
+        for lblock in 0..blocks {
+            if self.block_has_name(ino, &dir, &extents, lblock, entry.2)? {
+                return Err(VfsError::AlreadyExists);
+            }
+        }
+        for lblock in 0..blocks {
+            let (block, mut data) = self.dir_block(ino, &dir, &extents, lblock)?;
+            let end = entries_end(&data);
+            if insert_dirent(&mut data, end, entry)
+                .map_err(|_| self.corrupted("add_entry", "directory entry", ino, block))?
+            {
+                self.write_dir_block(ino, block, &data);
+                return Ok(());
+            }
+        }
+        if blocks == 1
+            && self.sb.feature_compat & COMPAT_DIR_INDEX != 0
+            && self.make_indexed(ino, &dir, &extents)?
+        {
+            return self.add_entry(ino, entry);
+        }
+        let (_, block) = self.append_dir_block(ino)?;
+        self.write_dir_block(ino, block, &self.new_dir_block(&[entry]));
+        Ok(())
+    }
+
+    /// Whether the logical block of the directory has an entry of the name.
+    pub(crate) fn block_has_name(
+        &self,
+        ino: u32,
+        dir: &InodeInfo,
+        extents: &[Extent],
+        lblock: u32,
+        name: &[u8],
+    ) -> VfsResult<bool> {
+        let (block, data) = self.dir_block(ino, dir, extents, lblock)?;
+        Ok(self
+            .parse_dir_block(ino, block, &data)?
+            .iter()
+            .any(|x| x.name == name))
+    }
+
+    /// Point the entry of the name in the directory to the inode of the
+    /// file type, rewritten in place so the name is never missing.
+    fn set_dir_entry(&self, ino: u32, name: &[u8], entry: (u32, u8)) -> VfsResult<()> {
+        let dir = self.read_inode(ino)?;
+        let extents = self.inode_extents(ino, &dir)?;
+        for lblock in 0..dir_blocks(&self.sb, &dir) {
+            let (block, mut data) = self.dir_block(ino, &dir, &extents, lblock)?;
+            let Some(offset) = self
+                .parse_dir_block(ino, block, &data)?
+                .iter()
+                .find(|x| x.name == name)
+                .map(|x| x.offset)
+            else {
+                continue;
+            };
+            set_u32(&mut data, offset, entry.0);
+            // the file type follows the inode, the rec_len and the name_len.
+            data[offset + 7] = entry.1;
+            self.write_dir_block(ino, block, &data);
+            return Ok(());
+        }
+        Err(VfsError::FileNotFound)
+    }
+
+    /// Find the entry of the name in the directory by a scan of its
+    /// blocks, return its inode and its file type. remove removes it like
+    /// unlink_entry.
+    fn dir_entry(&self, ino: u32, name: &[u8], remove: bool) -> VfsResult<Option<(u32, u8)>> {
+        let dir = self.read_inode(ino)?;
+        if !matches!(mode_file_type(dir.mode), Some(FileType::Directory)) {
+            return Err(VfsError::NotDir);
+        }
+        if dir.has_inline_data() || !dir.uses_extents() {
+            return Err(VfsError::NotSupported);
+        }
+        let extents = self.inode_extents(ino, &dir)?;
+        for lblock in 0..dir_blocks(&self.sb, &dir) {
+            let (block, mut data) = self.dir_block(ino, &dir, &extents, lblock)?;
+            let entries = self.parse_dir_block(ino, block, &data)?;
+            let Some(i) = entries.iter().position(|x| x.name == name) else {
+                continue;
+            };
+            let found = (entries[i].inode, entries[i].file_type);
+            let (offset, end) = (
+                entries[i].offset,
+                entries[i].offset + entries[i].rec_len as usize,
+            );
+            let prev = i.checked_sub(1).map(|x| entries[x].offset);
+            if remove {
+                match prev {
+                    Some(prev) => set_u16(&mut data, prev + 4, (end - prev) as u16),
+                    None => set_u32(&mut data, offset, 0),
+                }
+                self.write_dir_block(ino, block, &data);
+            }
+            return Ok(Some(found));
+        }
+        Ok(None)
+    }
+
+    /// Point ".." of the directory to parent.
+    fn set_dotdot(&self, ino: u32, parent: u32) -> VfsResult<()> {
+        let dir = self.read_inode(ino)?;
+        let extents = self.inode_extents(ino, &dir)?;
+        let (block, mut data) = self.dir_block(ino, &dir, &extents, 0)?;
+        let offset = self
+            .parse_dir_block(ino, block, &data)?
+            .iter()
+            .find(|x| x.name == b"..")
+            .map(|x| x.offset)
+            .ok_or_else(|| self.corrupted("set_dotdot", "directory \"..\"", ino, block))?;
+        set_u32(&mut data, offset, parent);
+        self.write_dir_block(ino, block, &data);
+        Ok(())
+    }
+
+    /// Count a ".." more or less in the links of the directory, a links
+    /// count of 1 doesn't count them, see unlink_entry.
+    fn add_dir_link(&self, ino: u32, delta: i16) -> VfsResult<()> {
+        let links = self.read_inode(ino)?.links_count;
+        if links <= 1 {
+            return Ok(());
+        }
+        let links = match delta {
+            1 => self.dir_link_added(links)?,
+            _ => links.saturating_add_signed(delta).max(1),
+        };
+        self.modify_inode(ino, |raw| set_u16(raw, I_LINKS_COUNT, links))
+    }
+
+    /// The links of a directory with links after a new subdirectory. A
+    /// count of 1 isn't a count, it stays. Past EXT4_LINK_MAX it becomes 1
+    /// with dir_nlink, like Linux, and the subdirectory is refused with
+    /// InvalidInput without it, the EMLINK of Linux is lost in the
+    /// VfsError of INodeInterface::mkdir.
+    fn dir_link_added(&self, links: u16) -> VfsResult<u16> {
+        match links {
+            0 | 1 => Ok(links),
+            x if x < EXT4_LINK_MAX => Ok(x + 1),
+            _ if self.sb.feature_ro_compat & RO_COMPAT_DIR_NLINK != 0 => Ok(1),
+            _ => Err(VfsError::InvalidInput),
+        }
+    }
+
+    fn is_dir_inode(&self, ino: u32) -> VfsResult<bool> {
+        Ok(matches!(
+            mode_file_type(self.read_inode(ino)?.mode),
+            Some(FileType::Directory)
+        ))
+    }
+
+    /// Move the entry old_name of old_dir to new_name of new_dir with the
+    /// flags, see RenameINode. The caller checked that new_dir isn't under
+    /// the entry. The target is replaced in the same transaction, so the
+    /// name is never missing after a crash, and the transactions are
+    /// serialized, so a target created by another one is seen by
+    /// NOREPLACE.
+    fn rename_entry(
+        &self,
+        open: &mut OpenInodes,
+        old_dir: u32,
+        old_name: &[u8],
+        new_dir: u32,
+        new_name: &[u8],
+        flags: RenameFlags,
+    ) -> VfsResult<()> {
+        let (ino, file_type) = self
+            .dir_entry(old_dir, old_name, false)?
+            .ok_or(VfsError::FileNotFound)?;
+        let is_dir = self.is_dir_inode(ino)?;
+        let target = self.dir_entry(new_dir, new_name, false)?;
+        if flags.contains(RenameFlags::NOREPLACE) && target.is_some() {
+            return Err(VfsError::AlreadyExists);
+        }
+        if flags.contains(RenameFlags::EXCHANGE) {
+            let target = target.ok_or(VfsError::FileNotFound)?;
+            return self.exchange_entries(
+                (old_dir, old_name, ino, file_type),
+                (new_dir, new_name, target.0, target.1),
+            );
+        }
+        if let Some((target, _)) = target {
+            // two links of the same file, rename does nothing.
+            if target == ino {
+                return Ok(());
+            }
+            let inode = self.read_inode(target)?;
+            let target_dir = matches!(mode_file_type(inode.mode), Some(FileType::Directory));
+            match (is_dir, target_dir) {
+                (true, false) => return Err(VfsError::NotDir),
+                // EISDIR, rename::rename tells it apart first.
+                (false, true) => return Err(VfsError::InvalidInput),
+                _ => {}
+            }
+            if target_dir && !self.dir_is_empty(target, &inode)? {
+                return Err(VfsError::DirectoryNotEmpty);
+            }
+            self.dir_entry(new_dir, new_name, true)?;
+            if target_dir {
+                self.add_dir_link(new_dir, -1)?;
+                self.modify_inode(target, |raw| set_u16(raw, I_LINKS_COUNT, 1))?;
+            }
+            self.touch_times(target, &[I_CTIME])?;
+            self.drop_link(open, target)?;
+        }
+        self.dir_entry(old_dir, old_name, true)?;
+        self.add_entry(new_dir, (ino, file_type, new_name))?;
+        if is_dir && old_dir != new_dir {
+            self.set_dotdot(ino, new_dir)?;
+            self.add_dir_link(old_dir, -1)?;
+            self.add_dir_link(new_dir, 1)?;
+        }
+        self.touch_times(old_dir, CHANGE_TIMES)?;
+        self.touch_times(new_dir, CHANGE_TIMES)?;
+        self.touch_times(ino, &[I_CTIME])
+    }
+
+    /// Swap the entries (directory, name, inode, file type) a and b for
+    /// the EXCHANGE of rename_entry. Each entry is rewritten in place, so
+    /// both names stay, and an exchanged directory gets its new ".." and
+    /// moves its link between the parents.
+    fn exchange_entries(
+        &self,
+        a: (u32, &[u8], u32, u8),
+        b: (u32, &[u8], u32, u8),
+    ) -> VfsResult<()> {
+        // two links of the same file, the exchange does nothing.
+        if a.2 == b.2 {
+            return Ok(());
+        }
+        self.set_dir_entry(a.0, a.1, (b.2, b.3))?;
+        self.set_dir_entry(b.0, b.1, (a.2, a.3))?;
+        if a.0 != b.0 {
+            for (moved, from, to) in [(a.2, a.0, b.0), (b.2, b.0, a.0)] {
+                if self.is_dir_inode(moved)? {
+                    self.set_dotdot(moved, to)?;
+                    self.add_dir_link(from, -1)?;
+                    self.add_dir_link(to, 1)?;
+                }
+            }
+        }
+        for dir in [a.0, b.0] {
+            self.touch_times(dir, CHANGE_TIMES)?;
+        }
+        for ino in [a.2, b.2] {
+            self.touch_times(ino, &[I_CTIME])?;
+        }
+        Ok(())
+    }
+}
+
+impl CheckDisk for Ext4Volume {
+    fn superblock(&self) -> &SuperBlockInfo {
+        &self.sb
+    }
+
+    // the cached descriptors have stale counters, read it again.
+    fn group_desc(&self, group: usize) -> GroupDesc {
+        GroupDesc::parse(
+            &self.sb,
+            &self.disk.read_offset(self.sb.group_desc_offset(group)),
+        )
+    }
+
+    fn read_block(&self, block: u64) -> Vec<u8> {
+        Ext4Volume::read_block(self, block)
+    }
+
+    // the checker reports the inodes, don't verify them.
+    fn read_inode(&self, ino: u32) -> InodeInfo {
+        let (group, index) = self.sb.inode_group(ino);
+        let desc = CheckDisk::group_desc(self, group);
+        let offset =
+            desc.inode_table as usize * self.sb.block_size() + index * self.sb.inode_size as usize;
+        InodeInfo::parse(&self.disk.read_offset(offset), self.sb.inode_size as usize)
+    }
+
+    // the counts of statfs, the superblock gets them with the commits.
+    fn free_counts(&self) -> (u64, u64) {
+        if let Err(err) = self.count_free_lazily() {
+            log::warn!("count the ext4 free blocks failed: {:?}", err);
+        }
+        (self.free_block_count.get(), self.free_inode_count.get())
+    }
+}
+
+impl StatsSource for Ext4Volume {
+    fn name(&self) -> String {
+        format!("ext4.dev{}", self.disk.dev)
+    }
+
+    fn counters(&self) -> Vec<(&'static str, usize)> {
+        let mut counters = self.counters.counters();
+        let groups = self.disk.groups.lock();
+        let dirty = groups.bitmaps.values().filter(|x| x.dirty).count();
+        counters.extend([
+            ("dirty_blocks", dirty + self.disk.deferred_blocks()),
+            ("group_desc_loads", groups.stats.desc_loads),
+            ("bitmap_loads", groups.stats.bitmap_loads),
+            ("bitmap_hits", groups.stats.bitmap_hits),
+            ("bitmap_writebacks", groups.stats.writebacks),
+            ("bitmap_evictions", groups.stats.evictions),
+            ("open_files", self.open_files.load(Ordering::Relaxed)),
+            (
+                "max_open_files",
+                match self.max_open_files.load(Ordering::Relaxed) {
+                    usize::MAX => 0,
+                    max => max,
+                },
+            ),
+            (
+                "read_only_violations",
+                self.disk.violations.load(Ordering::Relaxed),
+            ),
+        ]);
+        let background = self.background.lock().stats;
+        counters.extend([
+            ("deferred_readahead_pages", background.deferred_pages),
+            ("prefetched_pages", background.prefetched_pages),
+            ("background_yields", background.yields),
+        ]);
+        let health = self.health();
+        counters.extend([
+            ("read_retries", health.read_retries),
+            ("write_retries", health.write_retries),
+            ("read_failures", health.read_failures),
+            ("write_failures", health.write_failures),
+            ("relocated_blocks", health.relocated),
+            ("bad_blocks", health.bad_blocks),
+        ]);
+        counters
+    }
+}
+
+/// TODO: use inner fields AND Fix some warnings.
+#[allow(dead_code)]
+pub struct Ext4FileSystem {
+    inner: Arc<Ext4>,
+    volume: Arc<Ext4Volume>,
+    root: Arc<Ext4FileWrapper>,
+    file_type: FileType,
+    // file_name: String,
+    /// Run the consistency checker when the filesystem is dropped.
+    check_on_umount: AtomicBool,
+}
+
+impl Volume for Ext4FileSystem {
+    fn uuid(&self) -> Option<[u8; 16]> {
+        Some(Ext4FileSystem::uuid(self))
+    }
+
+    fn label(&self) -> Option<String> {
+        Ext4FileSystem::label(self)
+    }
+}
+
+impl Freeze for Ext4FileSystem {
+    fn freeze(&self) -> FsResult<()> {
+        self.volume.freeze()
+    }
+
+    fn thaw(&self) -> VfsResult<()> {
+        self.volume.gate.thaw()?;
+        info!("ext4 thawed");
+        Ok(())
+    }
+}
+
+/// The usage is counted from a mount with the quota option, the limits
+/// are in memory.
+impl Revalidate for Ext4FileSystem {
+    fn revalidate(&self) -> VfsResult<bool> {
+        self.volume.revalidate()
+    }
+
+    fn root(&self) -> Arc<dyn INodeInterface> {
+        Ext4FileSystem::root(self)
+    }
+}
+
+impl Quota for Ext4FileSystem {
+    fn quota_usage(&self, id: QuotaId) -> VfsResult<QuotaUsage> {
+        let quota = self.volume.quota.as_ref().ok_or(VfsError::NotSupported)?;
+        Ok(quota.usage(id))
+    }
+
+    fn set_quota_limit(&self, id: QuotaId, limits: QuotaLimits) -> VfsResult<()> {
+        let quota = self.volume.quota.as_ref().ok_or(VfsError::NotSupported)?;
+        quota.set_limits(id, limits);
+        Ok(())
+    }
+}
+
+/// The options of the mount can't change yet, a remount accepts the ones
+/// it has and the atime options, the policy of the mount is in its flags.
+impl Remount for Ext4FileSystem {
+    fn dev(&self) -> usize {
+        Ext4FileSystem::dev(self)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.volume.is_read_only()
+    }
+
+    fn remount(&self, flags: MountFlags, options: &str) -> VfsResult<()> {
+        let mut policy = None;
+        for option in options.split(',').filter(|x| !x.is_empty()) {
+            let kept = match option {
+                "ro" | "rw" | "noatime" | "relatime" | "strictatime" => true,
+                "force_rw" => self.volume.options.force_rw,
+                "deterministic" => self.volume.options.deterministic,
+                _ => match SyncPolicy::from_option(option) {
+                    Some(x) => {
+                        policy = Some(x);
+                        x.is_valid()
+                    }
+                    None => false,
+                },
+            };
+            if !kept {
+                log::error!("can't remount ext4 with the option {}", option);
+                return Err(VfsError::NotSupported);
+            }
+        }
+        match flags.contains(MountFlags::RDONLY) {
+            true => self.volume.remount_ro()?,
+            false => self.volume.remount_rw()?,
+        }
+        match policy {
+            Some(policy) => self.volume.set_sync_policy(policy),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The handles of ext4 are checked against the inode bitmap, the links
+/// and the generation of the inode, like ext4_nfs_get_inode of Linux.
+impl Export for Ext4FileSystem {
+    fn file_handle(&self, node: &dyn INodeInterface) -> VfsResult<FileHandleId> {
+        let mut stat = vfscore::Stat::default();
+        node.stat(&mut stat)?;
+        if stat.dev as usize != self.dev() {
+            return Err(VfsError::InvalidInput);
+        }
+        let ino = node.metadata()?.inode as u32;
+        Ok(FileHandleId {
+            ino: ino as u64,
+            generation: self.volume.read_inode(ino)?.generation,
+        })
+    }
+
+    fn open_by_handle(&self, handle: FileHandleId) -> FsResult<Arc<dyn INodeInterface>> {
+        let stale = FsError::new(VfsError::FileNotFound, Errno::ESTALE);
+        let sb = &self.volume.sb;
+        let ino = u32::try_from(handle.ino).map_err(|_| stale)?;
+        if ino == 0 || ino > sb.inodes_count || (ino != ROOT_INO && ino < sb.first_ino) {
+            return Err(stale);
+        }
+        let (group, index) = sb.inode_group(ino);
+        let inode = self.volume.read_inode(ino)?;
+        if !bitmap_test(&self.volume.inode_bitmap(group)?, index)
+            || inode.links_count == 0
+            || inode.generation != handle.generation
+        {
+            return Err(stale);
+        }
+        let file_type = mode_file_type(inode.mode).ok_or(VfsError::InvalidData)?;
+        let root_ino = self.volume.root_ino.load(Ordering::Relaxed);
+        if ino == root_ino {
+            return Ok(self.root());
+        }
+        // the root of the image is above a mounted subtree.
+        if ino == ROOT_INO {
+            return Err(stale);
+        }
+        // the path isn't known, the name only shows in the metadata.
+        let mut ext4_file = Ext4File::new();
+        ext4_file.inode = ino as _;
+        ext4_file.fsize = inode.size as _;
+        Ok(self
+            .root
+            .child(ext4_file, file_type, &format!("<ino {}>", ino))
+            .into_arc())
+    }
+}
+
+impl FileSystem for Ext4FileSystem {
+    fn root_dir(&'static self) -> Arc<dyn INodeInterface> {
+        self.root()
+    }
+
+    fn name(&self) -> &str {
+        "ext4"
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        // a read-only mount has nothing to write.
+        if self.volume.disk.is_read_only() {
+            return Ok(());
+        }
+        // every transaction is committed and checkpointed when its
+        // operation returns or when it's written back, only those deferred
+        // and the bitmaps modified in the group cache are dirty.
+        self.volume.write_errors();
+        self.volume.write_back()?;
+        self.volume.disk.sync_groups();
+        self.volume.write_backups();
+        Ok(())
+    }
+}
+
+// the wrappers may outlive the filesystem and commit their buffered
+// writes when they are dropped, the last of them writes back what's
+// deferred.
+impl Drop for Ext4Volume {
+    fn drop(&mut self) {
+        self.write_errors();
+        if let Err(err) = self.write_back() {
+            log::error!("write back the ext4 transactions failed: {:?}", err);
+        }
+        self.write_backups();
+    }
+}
+
+/// The tunables of the mount in tunefs.rs, they don't keep it alive.
+fn tunables(fs: &Arc<Ext4FileSystem>) -> Vec<Tunable> {
+    let tunable = |name, get: fn(&Ext4FileSystem) -> String, set: SetTunable| {
+        let (weak, weak_set) = (Arc::downgrade(fs), Arc::downgrade(fs));
+        Tunable::new(
+            name,
+            move || weak.upgrade().map(|x| get(&x)).unwrap_or_default(),
+            move |value| set(&weak_set.upgrade().ok_or(VfsError::FileNotFound)?, value),
+        )
+    };
+    vec![
+        tunable(
+            "readahead_blocks",
+            |fs| fs.readahead_blocks().to_string(),
+            |fs, value| fs.set_readahead_blocks(tunefs::parse_usize(value)?),
+        ),
+        tunable(
+            "dirty_threshold",
+            |fs| fs.dirty_threshold().to_string(),
+            |fs, value| fs.set_dirty_threshold(tunefs::parse_usize(value)?),
+        ),
+        tunable(
+            "cache_bytes",
+            |fs| fs.block_cache_bytes().to_string(),
+            |fs, value| fs.set_block_cache_bytes(tunefs::parse_usize(value)?),
+        ),
+        tunable(
+            "max_open_files",
+            |fs| fs.max_open_files().unwrap_or(0).to_string(),
+            |fs, value| {
+                let max = tunefs::parse_usize(value)?;
+                fs.set_max_open_files((max > 0).then_some(max))
+            },
+        ),
+        tunable(
+            "sync_policy",
+            |fs| fs.sync_policy().to_string(),
+            |fs, value| {
+                let policy = SyncPolicy::from_value(value).ok_or(VfsError::InvalidInput)?;
+                fs.set_sync_policy(policy)
+            },
+        ),
+    ]
+}
+
+/// The setter of a tunable of ext4, with the value written.
+type SetTunable = fn(&Ext4FileSystem, &str) -> VfsResult<()>;
+
+impl Drop for Ext4FileSystem {
+    fn drop(&mut self) {
+        tunefs::unregister("ext4", &self.dev().to_string());
+        if !self.check_on_umount.load(Ordering::Relaxed) {
+            return;
+        }
+        let report = self.check();
+        for problem in report.problems.iter() {
+            log::error!("ext4 check: {:?}", problem);
+        }
+        info!(
+            "ext4 check: {} inodes, {} directories, {} blocks, {} problems",
+            report.inodes,
+            report.directories,
+            report.blocks,
+            report.problems.len()
+        );
+    }
+}
+
+// Ext4FileSystem must be shared by the harts without any unsafe impl:
+// ext4_rs only takes &self and keeps its mutable state on the disk, the
+// disk serializes the accesses by the group cache lock, and the wrappers
+// keep their state in Mutexes.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Ext4FileSystem>();
+    assert_send_sync::<Ext4FileWrapper>();
+};
+
+impl Ext4FileSystem {
+    /// Mount the device with the default options. It fails instead of
+    /// panicking if the device can't be mounted, see Ext4Builder::mount.
+    /// The device can be any BlockDevice, like a RamDevice or a Partition
+    /// of another device, see blockdev.rs.
+    pub fn new_from_device(device: Arc<dyn BlockDevice + Send + Sync>) -> VfsResult<Arc<Self>> {
+        Self::builder_from_device(device).mount()
+    }
+
+    /// Mount the sys device device_id with the default options, like
+    /// new_from_device of a SectorDevice.
+    pub fn new(device_id: usize) -> VfsResult<Arc<Self>> {
+        Self::new_with_options(device_id, MountOptions::default())
+    }
+
+    pub fn new_with_options(device_id: usize, options: MountOptions) -> VfsResult<Arc<Self>> {
+        Ext4Builder {
+            source: MountSource::DeviceId(device_id),
+            options,
+            subtree: None,
+            cancel: None,
+            deadline: None,
+        }
+        .mount()
+    }
+
+    /// Build a mount with the options, like
+    /// Ext4FileSystem::builder(0).read_only(true).mount().
+    pub fn builder(device_id: usize) -> Ext4Builder {
+        Ext4Builder {
+            source: MountSource::DeviceId(device_id),
+            options: MountOptions::default(),
+            subtree: None,
+            cancel: None,
+            deadline: None,
+        }
+    }
+
+    pub fn builder_from_device(device: Arc<dyn BlockDevice + Send + Sync>) -> Ext4Builder {
+        Ext4Builder {
+            source: MountSource::Device(device),
+            options: MountOptions::default(),
+            subtree: None,
+            cancel: None,
+            deadline: None,
+        }
+    }
+
+    /// The root directory. FileSystem::root_dir takes a static filesystem,
+    /// this one doesn't, so a mount which isn't in FILESYSTEMS doesn't
+    /// have to be leaked.
+    pub fn root(&self) -> Arc<dyn INodeInterface> {
+        self.root.clone()
+    }
+
+    /// The device number of the mount, the st_dev of its files.
+    pub fn dev(&self) -> usize {
+        self.volume.disk.dev
+    }
+
+    fn mount(
+        dev: usize,
+        device: Arc<dyn BlockDevice + Send + Sync>,
+        options: MountOptions,
+        subtree: Option<&str>,
+        progress: Arc<MountProgress>,
+    ) -> VfsResult<Arc<Self>> {
+        let disk = Arc::new(Ext4Disk::new(dev, device, &options));
+        disk.set_read_only(options.read_only);
+        *disk.mounting.lock() = Some(progress);
+        disk.mount_step(MountPhase::Superblock)?;
+        if let Some(group) = options.backup_superblock {
+            disk.select_superblock(group)?;
+        }
+        let mut volume = Ext4Volume::new(disk.clone(), options)?;
+        volume.recover()?;
+        // the guard backs up the writes a fast mount never makes.
+        if options.write_guard && !options.fast {
+            volume.protect_metadata();
+        }
+        disk.mount_step(MountPhase::FreeCounts)?;
+        if !options.fast {
+            volume.count_free()?;
+        }
+        disk.mount_step(MountPhase::Orphans)?;
+        volume.cleanup_orphans()?;
+        if options.quota {
+            disk.mount_step(MountPhase::Quota)?;
+            match volume.scan_quota() {
+                Ok(table) => volume.quota = Some(table),
+                Err(err) if disk.mount_cancelled() => return Err(err),
+                Err(err) => log::error!("count the ext4 quota failed, no quota: {:?}", err),
+            }
+        }
+        disk.mount_step(MountPhase::Root)?;
+        let ext4 = Ext4::open(disk.clone());
+        cache::register_shrinker(Arc::downgrade(&disk) as Weak<dyn Shrinker>);
+        let volume = Arc::new(volume);
+        stats::register(Arc::downgrade(&volume) as Weak<dyn StatsSource>);
+
+        let mut root = Ext4FileWrapper::load_root(ext4.clone(), volume.clone())?;
+        if let Some(path) = subtree {
+            root = root.subtree(path)?;
+            let ino = root.ino(&root.inner.lock());
+            volume.root_ino.store(ino, Ordering::Relaxed);
+        }
+        let root = root.into_arc();
+        *disk.mounting.lock() = None;
+        let fs = Arc::new(Self {
+            inner: ext4,
+            volume,
+            root,
+            file_type: FileType::Directory,
+            check_on_umount: AtomicBool::new(false),
+        });
+        volume::register(
+            Arc::downgrade(&fs) as Weak<dyn FileSystem>,
+            Arc::downgrade(&fs) as Weak<dyn Volume>,
+        );
+        export::register(
+            Arc::downgrade(&fs) as Weak<dyn FileSystem>,
+            Arc::downgrade(&fs) as Weak<dyn Export>,
+        );
+        freeze::register(
+            Arc::downgrade(&fs) as Weak<dyn FileSystem>,
+            Arc::downgrade(&fs) as Weak<dyn Freeze>,
+        );
+        quota::register(
+            Arc::downgrade(&fs) as Weak<dyn FileSystem>,
+            Arc::downgrade(&fs) as Weak<dyn Quota>,
+        );
+        revalidate::register(
+            Arc::downgrade(&fs) as Weak<dyn FileSystem>,
+            Arc::downgrade(&fs) as Weak<dyn Revalidate>,
+        );
+        let flags = match fs.volume.is_read_only() {
+            true => MountFlags::RDONLY,
+            false => MountFlags::NONE,
+        } | fs.volume.options.atime.flags();
+        mounts::register(
+            Arc::downgrade(&fs) as Weak<dyn FileSystem>,
+            Arc::downgrade(&fs) as Weak<dyn Remount>,
+            flags,
+        );
+        tunefs::register("ext4", &dev.to_string(), tunables(&fs));
+        Ok(fs)
+    }
+
+    /// Check the consistency of the filesystem on the disk, like a
+    /// lightweight e2fsck without repairing. Writes buffered in the open
+    /// files aren't on the disk yet, they must be flushed first.
+    pub fn check(&self) -> CheckReport {
+        // don't look at the disk in the middle of a transaction.
+        let _journal = self.volume.journal.lock();
+        self.volume.disk.sync_groups();
+        ext4_check::check(self.volume.as_ref())
+    }
+
+    /// Check like check, it fails with Blocking once cancelled returns
+    /// true, checked between the groups and the directories.
+    pub fn check_cancel(&self, cancelled: &dyn Fn() -> bool) -> VfsResult<CheckReport> {
+        let _journal = self.volume.journal.lock();
+        self.volume.disk.sync_groups();
+        ext4_check::check_cancel(self.volume.as_ref(), cancelled)
+    }
+
+    /// The options of the mount, for the logs, with the values changed
+    /// since then by a remount or tunefs.rs.
+    pub fn options(&self) -> MountOptions {
+        MountOptions {
+            block_cache_bytes: self.block_cache_bytes(),
+            readahead_blocks: self.readahead_blocks(),
+            sync_policy: self.sync_policy(),
+            max_open_files: self.max_open_files(),
+            ..self.volume.options
+        }
+    }
+
+    /// s_uuid, it doesn't change while mounted.
+    pub fn uuid(&self) -> [u8; 16] {
+        self.volume.sb.uuid
+    }
+
+    /// s_volume_name without the NUL padding, None if it's empty.
+    pub fn label(&self) -> Option<String> {
+        let raw = self.volume.disk.read_offset(SUPERBLOCK_OFFSET);
+        let name = &raw[S_VOLUME_NAME..S_VOLUME_NAME + 16];
+        let len = name.iter().position(|x| *x == 0).unwrap_or(name.len());
+        match len {
+            0 => None,
+            _ => Some(String::from_utf8_lossy(&name[..len]).into_owned()),
+        }
+    }
+
+    /// Set s_volume_name in a transaction, like e2label. The label is at
+    /// most 16 bytes without NUL, an empty one clears it.
+    pub fn set_label(&self, label: &str) -> VfsResult<()> {
+        let _write = self.volume.begin_write()?;
+        if label.len() > 16 || label.contains('\0') {
+            log::warn!("invalid ext4 label {:?}", label);
+            return Err(VfsError::InvalidInput);
+        }
+        let mut name = [0u8; 16];
+        name[..label.len()].copy_from_slice(label.as_bytes());
+        self.volume.transaction(&[], None, || {
+            self.volume.modify(SUPERBLOCK_OFFSET, 1024, |raw| {
+                raw[S_VOLUME_NAME..S_VOLUME_NAME + 16].copy_from_slice(&name)
+            });
+            Ok(())
+        })
+    }
+
+    /// Set s_r_blocks_count in a transaction, like tune2fs -r. The blocks
+    /// are reserved for root, see available_blocks, at most half of the
+    /// filesystem like tune2fs.
+    pub fn set_reserved_blocks(&self, count: u64) -> VfsResult<()> {
+        let _write = self.volume.begin_write()?;
+        let sb = &self.volume.sb;
+        if count > sb.blocks_count / 2 {
+            log::warn!(
+                "can't reserve {} of the {} ext4 blocks",
+                count,
+                sb.blocks_count
+            );
+            return Err(VfsError::InvalidInput);
+        }
+        self.volume.transaction(&[], None, || {
+            self.volume.modify(SUPERBLOCK_OFFSET, 1024, |raw| {
+                set_u32(raw, S_R_BLOCKS_LO, count as u32);
+                if sb.is_64bit() {
+                    set_u32(raw, S_R_BLOCKS_HI, (count >> 32) as u32);
+                }
+            });
+            Ok(())
+        })
+    }
+
+    /// The writes dropped by the disk while the volume was read-only, 0
+    /// unless a path of the shim writes on a read-only mount.
+    pub fn read_only_violations(&self) -> usize {
+        self.volume.disk.violations.load(Ordering::Relaxed)
+    }
+
+    /// The data writes refused by the write guard, 0 unless a path of the
+    /// shim computed a block in the metadata, see MountOptions::write_guard.
+    pub fn guard_trips(&self) -> usize {
+        self.volume.disk.guard_trips.load(Ordering::Relaxed)
+    }
+
+    /// The errors of the superblock, the first and the last one since the
+    /// last fsck with their count, like dumpe2fs prints them. Those found
+    /// since the mount are included before they're written.
+    pub fn error_history(&self) -> ErrorHistory {
+        self.volume.errors.lock().history.clone()
+    }
+
+    /// The device errors of the mount and what the retries made of them.
+    pub fn health(&self) -> DiskHealth {
+        self.volume.health()
+    }
+
+    /// The failed tries of the device requests by the sector they start
+    /// at, for the first MAX_SECTOR_ERRORS sectors which failed.
+    pub fn sector_errors(&self) -> Vec<(usize, SectorErrors)> {
+        let sectors = self.volume.disk.health.sectors.lock();
+        sectors.iter().map(|(x, errors)| (*x, *errors)).collect()
+    }
+
+    /// How the image was mounted, the kernel logs why it's read-only.
+    pub fn mount_info(&self) -> MountInfo {
+        MountInfo {
+            read_only: self.volume.read_only.lock().clone(),
+            forced_rw: self.volume.forced_rw.clone(),
+            journaled: self.volume.journal.lock().is_some(),
+        }
+    }
+
+    /// The features of the image, each with its handling, and the mount
+    /// they led to. The kernel logs it at the mount.
+    pub fn mount_report(&self) -> MountReport {
+        MountReport {
+            features: feature_matrix(&self.volume.sb),
+            mount: self.mount_info(),
+        }
+    }
+
+    /// The debug option checking the filesystem when it's unmounted, the
+    /// problems are logged.
+    pub fn set_check_on_umount(&self, enable: bool) {
+        self.check_on_umount.store(enable, Ordering::Relaxed);
+    }
+
+    /// Check if there are dirty cached blocks to write back.
+    pub fn writeback_pending(&self) -> bool {
+        self.volume.disk.has_dirty_groups() || self.volume.disk.deferred_blocks() > 0
+    }
+
+    /// Write back at most max_blocks dirty cached blocks, the kernel can
+    /// call it from a timer or the idle task to keep the sync work off the
+    /// hot path. flush still writes back everything.
+    /// return the number of written blocks.
+    /// Every step is a tick of the write-back policy: the transactions it
+    /// deferred are written back together once the oldest one is
+    /// max_age_ticks old, even past max_blocks, the journal can't commit
+    /// a part of them. The other policies commit the transactions when
+    /// their operations return, only the bitmaps are deferred, so no
+    /// ordering is broken by a step.
+    pub fn writeback_step(&self, max_blocks: usize) -> VfsResult<usize> {
+        let written = self.volume.writeback_tick()?;
+        Ok(written
+            + self
+                .volume
+                .disk
+                .writeback_groups(max_blocks.saturating_sub(written)))
+    }
+
+    /// The work of the idle task, like writeback_step: the readaheads the
+    /// reads deferred, then a tick of the write-back policy and the dirty
+    /// bitmaps, at most max_blocks pages and blocks but the due
+    /// transactions. It yields to the foreground requests queued on the
+    /// iosched.rs scheduler of the device: a call under pressure does
+    /// nothing, but after MAX_BACKGROUND_YIELDS such calls in a row, and
+    /// a call which works stops at the first chunk of BACKGROUND_CHUNK
+    /// which finds the device busy. The ticks wait for the pressure to
+    /// go. The foreground operations never wait for the background work
+    /// but for a chunk of it holding their locks.
+    /// return the pages read and the blocks written.
+    pub fn background_work(&self, max_blocks: usize) -> VfsResult<usize> {
+        let volume = &self.volume;
+        if volume.background_yields() {
+            return Ok(0);
+        }
+        let busy = || iosched::pressure(volume.disk.dev) > 0;
+        let mut done = 0;
+        // the readahead first, its reader is coming for it.
+        while done < max_blocks {
+            let Some(prefetch) = volume.next_prefetch(min(BACKGROUND_CHUNK, max_blocks - done))
+            else {
+                break;
+            };
+            done += volume.prefetch(prefetch);
+            if busy() {
+                return Ok(done);
+            }
+        }
+        done += volume.writeback_tick()?;
+        while done < max_blocks {
+            let written = volume
+                .disk
+                .writeback_groups(min(BACKGROUND_CHUNK, max_blocks - done));
+            done += written;
+            if written == 0 || busy() {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    /// Check if background_work has work, the deferred readaheads or the
+    /// dirty blocks.
+    pub fn background_pending(&self) -> bool {
+        !self.volume.background.lock().prefetch.is_empty() || self.writeback_pending()
+    }
+
+    pub fn background_stats(&self) -> BackgroundStats {
+        self.volume.background.lock().stats
+    }
+
+    /// The blocks written in memory only: the transactions deferred by the
+    /// write-back policy and the dirty bitmaps.
+    pub fn dirty_blocks(&self) -> usize {
+        let groups = self.volume.disk.groups.lock();
+        let dirty = groups.bitmaps.values().filter(|x| x.dirty).count();
+        dirty + self.volume.disk.deferred_blocks()
+    }
+
+    /// The sync policy now, the one of the options until a remount.
+    pub fn sync_policy(&self) -> SyncPolicy {
+        *self.volume.sync_policy.lock()
+    }
+
+    /// Switch to the sync policy, like a remount with its option. Leaving
+    /// the write-back policy writes back the deferred transactions.
+    pub fn set_sync_policy(&self, policy: SyncPolicy) -> VfsResult<()> {
+        self.volume.set_sync_policy(policy)
+    }
+
+    /// The pages read with a page cache miss after the missed one.
+    pub fn readahead_blocks(&self) -> usize {
+        self.volume.readahead_blocks.load(Ordering::Relaxed)
+    }
+
+    /// Read blocks pages ahead from now on, they are checked like the
+    /// option.
+    pub fn set_readahead_blocks(&self, blocks: usize) -> VfsResult<()> {
+        if let Some(reason) = readahead_error(blocks) {
+            log::error!("invalid ext4 readahead {}: {}", blocks, reason);
+            return Err(VfsError::InvalidInput);
+        }
+        self.volume
+            .readahead_blocks
+            .store(blocks, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The memory of the cached bitmap blocks.
+    pub fn block_cache_bytes(&self) -> usize {
+        self.volume.disk.cache_bytes()
+    }
+
+    /// Cache at most bytes of bitmap blocks, at least two blocks. The
+    /// blocks over it are evicted at once.
+    pub fn set_block_cache_bytes(&self, bytes: usize) -> VfsResult<()> {
+        if bytes < 2 * BLOCK_SIZE {
+            log::error!("invalid ext4 block cache of {} bytes", bytes);
+            return Err(VfsError::InvalidInput);
+        }
+        // the evicted blocks are written back, not in a transaction.
+        let _journal = self.volume.journal.lock();
+        self.volume.disk.set_cache_bytes(bytes);
+        Ok(())
+    }
+
+    /// The max_dirty_blocks of the write-back policy, 0 for the policies
+    /// which don't defer.
+    pub fn dirty_threshold(&self) -> usize {
+        match self.sync_policy() {
+            SyncPolicy::WriteBack {
+                max_dirty_blocks, ..
+            } => max_dirty_blocks,
+            _ => 0,
+        }
+    }
+
+    /// Set the max_dirty_blocks of the write-back policy, the other
+    /// policies fail with InvalidInput.
+    pub fn set_dirty_threshold(&self, blocks: usize) -> VfsResult<()> {
+        self.volume.update_sync_policy(|policy| match policy {
+            SyncPolicy::WriteBack { max_age_ticks, .. } => Ok(SyncPolicy::WriteBack {
+                max_dirty_blocks: blocks,
+                max_age_ticks,
+            }),
+            _ => {
+                log::error!("the ext4 sync policy {} has no dirty threshold", policy);
+                Err(VfsError::InvalidInput)
+            }
+        })
+    }
+
+    /// The files open now, see MountOptions::max_open_files.
+    pub fn open_files(&self) -> usize {
+        self.volume.open_files.load(Ordering::Relaxed)
+    }
+
+    pub fn max_open_files(&self) -> Option<usize> {
+        match self.volume.max_open_files.load(Ordering::Relaxed) {
+            usize::MAX => None,
+            max => Some(max),
+        }
+    }
+
+    /// Limit the files open at once, None for no limit. The files open
+    /// beyond a lower max stay open, the next opens fail until they are
+    /// closed.
+    pub fn set_max_open_files(&self, max: Option<usize>) -> VfsResult<()> {
+        if max == Some(0) {
+            log::error!("invalid ext4 max of no open file");
+            return Err(VfsError::InvalidInput);
+        }
+        let max = max.unwrap_or(usize::MAX);
+        self.volume.max_open_files.store(max, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Get the counters of the group cache.
+    pub fn group_stats(&self) -> GroupStats {
+        self.volume.disk.groups.lock().stats
+    }
+}
+
+/// The dumps of the on-disk structures, like debugfs. They read the disk
+/// like check, the writes buffered in the open files aren't there yet.
+#[cfg(feature = "ext4_debug")]
+impl Ext4FileSystem {
+    pub fn dump_superblock(&self) -> ext4_debug::SuperblockDump {
+        let _journal = self.volume.journal.lock();
+        ext4_debug::dump_superblock(self.volume.as_ref())
+    }
+
+    /// Dump the fields of the inode and its extent tree.
+    pub fn dump_inode(&self, ino: u32) -> VfsResult<ext4_debug::InodeDump> {
+        let _journal = self.volume.journal.lock();
+        ext4_debug::dump_inode(self.volume.as_ref(), ino)
+    }
+
+    /// Dump the entries of the logical block of the directory, the unused
+    /// entries included.
+    pub fn dump_dir_block(&self, ino: u32, lblock: u32) -> VfsResult<ext4_debug::DirBlockDump> {
+        let _journal = self.volume.journal.lock();
+        ext4_debug::dump_dir_block(self.volume.as_ref(), ino, lblock)
+    }
+
+    /// Dump the block and inode bitmaps of the group.
+    pub fn dump_bitmap(&self, group: usize) -> VfsResult<ext4_debug::BitmapDump> {
+        let _journal = self.volume.journal.lock();
+        self.volume.disk.sync_groups();
+        ext4_debug::dump_bitmap(self.volume.as_ref(), group)
+    }
+}
+
+/// The listing positions of an indexed directory, by the hashes of the
+/// names, see dir_entries_at. The byte offsets in a directory are below.
+const HASH_POS: u64 = 1 << 62;
+
+/// The max size of the buffered small sequential writes.
+const WRITE_BUFFER_SIZE: usize = 0x10000;
+/// The max size of the buffered appends. The blocks of one write are
+/// allocated together, appending in larger runs keeps the extents of log
+/// files and downloads long, the allocation windows keep them long across
+/// the flushes, see AllocWindows.
+const APPEND_BUFFER_SIZE: usize = 0x40000;
+
+/// WriteBuffer coalesces the sequential small writes of a file.
+/// data is the content at the file offset `offset`, the blocks it may
+/// allocate at its flush are held by `blocks`, see hold_buffered. The
+/// writes returned already, so a failed flush is kept in `error` for the
+/// fsync or the close of the file.
+struct WriteBuffer {
+    offset: usize,
+    data: Vec<u8>,
+    ino: u32,
+    blocks: u64,
+    error: Option<VfsError>,
+}
+
+impl WriteBuffer {
+    const fn new() -> Self {
+        Self {
+            offset: 0,
+            data: Vec::new(),
+            ino: 0,
+            blocks: 0,
+            error: None,
+        }
+    }
+
+    fn end(&self) -> usize {
+        self.offset + self.data.len()
+    }
+}
+
+/// The blocks a flush of the data at start..end may allocate, the data
+/// blocks and one for the extent tree.
+fn buffered_blocks(start: usize, end: usize) -> u64 {
+    (end.div_ceil(BLOCK_SIZE) - start / BLOCK_SIZE) as u64 + 1
+}
+
+pub struct Ext4FileWrapper {
+    inner: Mutex<Ext4File>,
+    ext4: Arc<Ext4>,
+    volume: Arc<Ext4Volume>,
+    file_type: FileType,
+    file_name: String,
+    wbuf: Mutex<WriteBuffer>,
+    /// The extents resolved from the extent tree of the inode.
+    extents: Mutex<ExtentCache>,
+    /// The data is stored inline in the inode until the first write moves
+    /// it to a block, see convert_inline.
+    inline: AtomicBool,
+    /// The SEALED_FLAGS of the inode, see check_sealed.
+    sealed: u32,
+    /// The access mode of the open, the created files are read-write.
+    access: AccessMode,
+    /// The snapshot of the inode for stat, shared with the other wrappers
+    /// of the inode.
+    snapshot: Arc<Snapshot>,
+    /// The inode of the snapshot, read without locking inner.
+    snapshot_ino: u32,
+    /// wbuf holds data, stat flushes it first.
+    buffered: AtomicBool,
+    /// The slot of open_files of the wrapper of an open.
+    slot: Option<OpenSlot>,
+    /// The generation and the change time of the inode when it was last
+    /// read, see reload.
+    seen: Mutex<(u32, Timestamp)>,
+    /// The key of the wrapper in Ext4Volume::wrappers.
+    id: u64,
+}
+
+/// A slot of Ext4Volume::open_files, given back when it's dropped.
+struct OpenSlot(Arc<Ext4Volume>);
+
+impl OpenSlot {
+    fn take(volume: &Arc<Ext4Volume>) -> VfsResult<Self> {
+        volume.open_file()?;
+        Ok(Self(volume.clone()))
+    }
+}
+
+impl Drop for OpenSlot {
+    fn drop(&mut self) {
+        self.0.open_files.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Ext4FileWrapper {
+    fn load_root(ext4: Arc<Ext4>, volume: Arc<Ext4Volume>) -> VfsResult<Self> {
+        let inode = volume.read_inode(ROOT_INO)?;
+        let sealed = inode.flags & SEALED_FLAGS;
+        let mut ext4_file = Ext4File::new();
+        ext4.ext4_open(&mut ext4_file, "/", "r", false)
+            .map_err(ext4_error("open", "/"))?;
+        volume.counters.open_inodes.fetch_add(1, Ordering::Relaxed);
+        volume.file_opened(2);
+        let snapshot = volume.snapshot(ROOT_INO);
+        let id = volume.next_wrapper.fetch_add(1, Ordering::Relaxed);
+
+        Ok(Self {
+            inner: Mutex::new(ext4_file),
+            ext4,
+            volume,
+            file_type: FileType::Directory,
+            file_name: "/".to_string(),
+            wbuf: Mutex::new(WriteBuffer::new()),
+            extents: Mutex::new(ExtentCache::new()),
+            inline: AtomicBool::new(false),
+            sealed,
+            access: AccessMode::ReadWrite,
+            snapshot,
+            snapshot_ino: ROOT_INO,
+            buffered: AtomicBool::new(false),
+            slot: None,
+            seen: Mutex::new((inode.generation, inode.ctime)),
+            id,
+        })
+    }
+
+    /// Walk down the path from this directory for Ext4Builder::subtree,
+    /// every component must be a directory.
+    fn subtree(self, path: &str) -> VfsResult<Self> {
+        let mut dir = self;
+        for name in path.split('/').filter(|x| !x.is_empty() && *x != ".") {
+            if name == ".." {
+                return Err(VfsError::InvalidInput);
+            }
+            check_lookup_name(name)?;
+            dir.check_sealed()?;
+            dir = dir.lookup_child(name)?;
+            if !matches!(dir.file_type, FileType::Directory) {
+                return Err(VfsError::NotDir);
+            }
+        }
+        Ok(dir)
+    }
+
+    /// Share the wrapper as a node, it's added to the wrappers of the
+    /// volume.
+    fn into_arc(self) -> Arc<Self> {
+        let node = Arc::new(self);
+        let weak = Arc::downgrade(&node);
+        node.volume.wrappers.lock().insert(node.id, weak);
+        node
+    }
+
+    /// Create a wrapper of the file that shares the filesystem with self,
+    /// it takes the path as its file_name.
+    fn child(&self, ext4_file: Ext4File, file_type: FileType, path: String) -> Self {
+        debug_assert!(ext4_file.inode != 0, "ext4 file {} has no inode", path);
+        // the files created by ext4_rs never have inline data.
+        self.volume
+            .counters
+            .open_inodes
+            .fetch_add(1, Ordering::Relaxed);
+        let inode = match ext4_file.inode {
+            0 => None,
+            ino => self.volume.read_inode(ino as u32).ok(),
+        };
+        let inline = inode.as_ref().is_some_and(|x| x.has_inline_data());
+        let seen = inode
+            .as_ref()
+            .map_or(Default::default(), |x| (x.generation, x.ctime));
+        let sealed = inode.map_or(0, |x| x.flags & SEALED_FLAGS);
+        let snapshot_ino = ext4_file.inode as u32;
+        let snapshot = self.volume.snapshot(snapshot_ino);
+        let wrapper = Self {
+            inner: Mutex::new(ext4_file),
+            ext4: self.ext4.clone(),
+            volume: self.volume.clone(),
+            file_type,
+            file_name: path,
+            wbuf: Mutex::new(WriteBuffer::new()),
+            extents: Mutex::new(ExtentCache::new()),
+            inline: AtomicBool::new(inline),
+            sealed,
+            access: AccessMode::ReadWrite,
+            snapshot,
+            snapshot_ino,
+            buffered: AtomicBool::new(false),
+            slot: None,
+            seen: Mutex::new(seen),
+            id: self.volume.next_wrapper.fetch_add(1, Ordering::Relaxed),
+        };
+        self.volume.file_opened(wrapper.ino(&wrapper.inner.lock()));
+        wrapper
+    }
+
+    /// Fail with NotSupported if the names or the data of the inode are
+    /// sealed, they are never read as plaintext or written over.
+    fn check_sealed(&self) -> VfsResult<()> {
+        if self.sealed == 0 {
+            return Ok(());
+        }
+        log::debug!(
+            "ext4 inode {} is sealed by the flags {:#x}",
+            self.traced_ino(),
+            self.sealed
+        );
+        Err(VfsError::NotSupported)
+    }
+
+    /// Get the inode number, the root is opened with inode 0.
+    /// The inode of the traced operations.
+    fn traced_ino(&self) -> u64 {
+        self.ino(&self.inner.lock()) as u64
+    }
+
+    fn ino(&self, ext4_file: &Ext4File) -> u32 {
+        match ext4_file.inode {
+            0 if self.file_name == "/" => 2,
+            ino => ino as u32,
+        }
+    }
+
+    fn inode_id(&self, ext4_file: &Ext4File) -> InodeId {
+        InodeId {
+            dev: self.volume.disk.dev,
+            ino: self.ino(ext4_file) as u64,
+        }
+    }
+
+    /// Read the pages of a deferred readahead into the page cache, up to
+    /// a cached one or the end of the file like the readahead of a read.
+    /// return the pages read.
+    fn prefetch(&self, index: usize, pages: usize) -> VfsResult<usize> {
+        self.sync_wbuf();
+        let mut ext4_file = self.inner.lock();
+        let file_size = ext4_file.fsize as usize;
+        let id = self.inode_id(&ext4_file);
+        let pages = (index..index + pages)
+            .take_while(|x| x * PAGE_SIZE < file_size && !cache::contains(id, *x))
+            .count();
+        if pages == 0 {
+            return Ok(0);
+        }
+        let start = index * PAGE_SIZE;
+        let mut data = vec![0u8; min((index + pages) * PAGE_SIZE, file_size) - start];
+        self.read_uncached(&mut ext4_file, start, &mut data, false)?;
+        for (i, page) in data.chunks(PAGE_SIZE).enumerate() {
+            cache::insert(id, index + i, page.to_vec());
+        }
+        Ok(pages)
+    }
+
+    /// Read the inode again after the device changed under a read-only
+    /// mount. If its generation or its change time changed, or always
+    /// with force, the size of the file is the one of the inode and its
+    /// extents, its cached and mapped pages are dropped. return whether
+    /// it changed.
+    fn reload(&self, force: bool) -> VfsResult<bool> {
+        let mut ext4_file = self.inner.lock();
+        let inode = self.volume.read_inode(self.ino(&ext4_file))?;
+        let stamp = (inode.generation, inode.ctime);
+        let changed = *self.seen.lock() != stamp;
+        if !changed && !force {
+            return Ok(false);
+        }
+        *self.seen.lock() = stamp;
+        ext4_file.fsize = inode.size as _;
+        self.extents.lock().clear();
+        let id = self.inode_id(&ext4_file);
+        cache::invalidate(id);
+        mapping::invalidate(id, 0..usize::MAX);
+        drop(ext4_file);
+        self.volume
+            .publish_snapshot(self.snapshot_ino, &self.snapshot);
+        Ok(changed)
+    }
+
+    /// Invalidate the mapped pages of a write which returned, see
+    /// mapping.rs.
+    fn mapped_write(&self, offset: usize, r: VfsResult<usize>) -> VfsResult<usize> {
+        if let Ok(written) = r
+            && mapping::is_active()
+        {
+            let id = self.inode_id(&self.inner.lock());
+            mapping::invalidate(id, offset..offset + written);
+        }
+        r
+    }
+
+    /// Resolve the extent tree of the inode into the extent cache.
+    /// return false if the inode doesn't map its blocks by extents.
+    fn load_extents(&self, extents: &mut ExtentCache, ino: u32) -> VfsResult<bool> {
+        if !extents.is_complete() {
+            let inode = self.volume.read_inode(ino)?;
+            if inode.uses_extents() {
+                extents.fill(self.volume.inode_extents(ino, &inode)?);
+            }
+        }
+        Ok(extents.is_complete())
+    }
+
+    /// load_extents for the directory dir. The cache is dropped first if
+    /// the directory grew since it was filled, by another wrapper.
+    fn load_dir_extents(
+        &self,
+        extents: &mut ExtentCache,
+        ino: u32,
+        dir: &InodeInfo,
+    ) -> VfsResult<bool> {
+        let blocks = dir_blocks(&self.volume.sb, dir);
+        if blocks > 0 && extents.is_complete() && extents.lookup(blocks - 1).is_none() {
+            extents.clear();
+        }
+        self.load_extents(extents, ino)
+    }
+
+    /// Create the regular file or the directory name in this directory,
+    /// its entry is added by the shim, see create_entry. return None if
+    /// the shim can't modify this directory.
+    fn create_child(
+        &self,
+        dir_ino: u32,
+        name: &str,
+        file_type: FileType,
+    ) -> VfsResult<Option<Self>> {
+        let is_dir = matches!(file_type, FileType::Directory);
+        let r = self.volume.transaction(&[dir_ino], None, || {
+            self.volume
+                .create_entry(dir_ino, &name_to_bytes(name), is_dir)
+        });
+        let ino = match r {
+            Ok(ino) => ino,
+            Err(VfsError::NotSupported) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let mut ext4_file = Ext4File::new();
+        ext4_file.inode = ino as _;
+        Ok(Some(self.child(
+            ext4_file,
+            file_type,
+            self.child_path(name),
+        )))
+    }
+
+    /// Map the logical block of the file through the extents.
+    /// return None if the block is a hole or uninitialized.
+    fn map_lblock(&self, extents: &ExtentCache, lblock: u32) -> Option<u64> {
+        let extent = extents.lookup(lblock).filter(|x| !x.uninit)?;
+        Some(extent.physical + (lblock - extent.logical) as u64)
+    }
+
+    /// Get the path of the child in this directory, allocated once with
+    /// its length.
+    fn child_path(&self, name: &str) -> String {
+        let parent = match self.file_name.as_str() {
+            "/" => "",
+            parent => parent,
+        };
+        let mut path = String::with_capacity(parent.len() + 1 + name.len());
+        path.push_str(parent);
+        path.push('/');
+        path.push_str(name);
+        path
+    }
+
+    /// The type of the inode by its i_mode, for the files opened by
+    /// ext4_rs which doesn't tell it.
+    fn inode_file_type(&self, ino: u32) -> VfsResult<FileType> {
+        mode_file_type(self.volume.read_inode(ino)?.mode).ok_or(VfsError::InvalidData)
+    }
+
+    /// Find the child in this directory by the directory blocks. A child
+    /// directory whose ".." isn't this directory is a loop or a lost
+    /// directory of a corrupted image, it fails with InvalidData.
+    fn lookup_child(&self, name: &str) -> VfsResult<Self> {
+        let ino = self.ino(&self.inner.lock());
+        let child_ino = match name {
+            ".." if ino == self.volume.root_ino.load(Ordering::Relaxed) => ino,
+            name => self.find_entry(ino, name)?,
+        };
+        let inode = self.volume.read_inode(child_ino)?;
+
+        let mut ext4_file = Ext4File::new();
+        ext4_file.inode = child_ino as _;
+        ext4_file.fsize = inode.size as _;
+        let file_type = mode_file_type(inode.mode).ok_or(VfsError::InvalidData)?;
+        let child = self.child(ext4_file, file_type, self.child_path(name));
+        if matches!(file_type, FileType::Directory)
+            && name != "."
+            && name != ".."
+            && child.parent_entry(child_ino)?.is_some_and(|x| x != ino)
+        {
+            // a rename may be moving the child, check again between the
+            // transactions.
+            let _journal = self.volume.journal.lock();
+            if self.find_entry(ino, name)? == child_ino
+                && child.parent_entry(child_ino)?.is_some_and(|x| x != ino)
+            {
+                return Err(self
+                    .volume
+                    .corrupted("lookup_child", "parent entry", child_ino, 0));
+            }
+        }
+        Ok(child)
+    }
+
+    /// The inode of the ".." of the directory, the second entry of its
+    /// first block in a linear and an indexed directory. None if the
+    /// directory isn't in blocks.
+    fn parent_entry(&self, ino: u32) -> VfsResult<Option<u32>> {
+        let dir = self.volume.read_inode(ino)?;
+        let mut extents = self.extents.lock();
+        if !self.load_dir_extents(&mut extents, ino, &dir)? {
+            return Ok(None);
+        }
+        let (block, data) = self.read_dir_block(&extents, ino, &dir, 0)?;
+        let parent = self
+            .volume
+            .parse_dir_block(ino, block, &data)?
+            .into_iter()
+            .find(|x| x.name == b"..")
+            .map(|x| x.inode);
+        match parent {
+            Some(parent) => Ok(Some(parent)),
+            None => Err(self
+                .volume
+                .corrupted("parent_entry", "parent entry", ino, block)),
+        }
+    }
+
+    /// Find the inode number of the name in this directory.
+    /// Indexed directories are searched by the hash of the name, others
+    /// are scanned block by block.
+    fn find_entry(&self, ino: u32, name: &str) -> VfsResult<u32> {
+        let dir = self.volume.read_inode(ino)?;
+        if !matches!(mode_file_type(dir.mode), Some(FileType::Directory)) {
+            return Err(VfsError::NotDir);
+        }
+        let mut extents = self.extents.lock();
+        if !self.load_dir_extents(&mut extents, ino, &dir)? {
+            return Err(VfsError::NotSupported);
+        }
+        let read_dir_block = |lblock: u32| -> VfsResult<Vec<u8>> {
+            Ok(self.read_dir_block(&extents, ino, &dir, lblock)?.1)
+        };
+
+        // the entries are compared in the block read, a lookup only
+        // allocates the blocks.
+        let name = name_to_bytes(name);
+        let scan = |lblock: u32| -> VfsResult<Option<u32>> {
+            let (block, data) = self.read_dir_block(&extents, ino, &dir, lblock)?;
+            for dirent in DirentIter::new(&data) {
+                let dirent = dirent.map_err(|_| {
+                    self.volume
+                        .corrupted("parse_dir_block", "directory entry", ino, block)
+                })?;
+                if dirent.name == &name[..] {
+                    return Ok(Some(dirent.inode));
+                }
+            }
+            Ok(None)
+        };
+        if dir.flags & EXT4_INDEX_FL == 0 {
+            for lblock in 0..dir_blocks(&self.volume.sb, &dir) {
+                if let Some(child) = scan(lblock)? {
+                    return Ok(child);
+                }
+            }
+            return Err(VfsError::FileNotFound);
+        }
+        let leaves = dx_lookup(&self.volume.sb, &read_dir_block(0)?, &name, &read_dir_block)
+            .map_err(|err| match err {
+                VfsError::InvalidData => {
+                    self.volume
+                        .corrupted("find_entry", "directory index", ino, 0)
+                }
+                err => err,
+            })?;
+        for lblock in leaves {
+            if let Some(child) = scan(lblock)? {
+                return Ok(child);
+            }
+        }
+        Err(VfsError::FileNotFound)
+    }
+
+    /// Read the logical block of the directory, the block is verified with
+    /// metadata_csum. return the physical block and the data.
+    fn read_dir_block(
+        &self,
+        extents: &ExtentCache,
+        ino: u32,
+        dir: &InodeInfo,
+        lblock: u32,
+    ) -> VfsResult<(u64, Vec<u8>)> {
+        if lblock >= dir_blocks(&self.volume.sb, dir) {
+            return Err(self.volume.corrupted(
+                "read_dir_block",
+                "directory index",
+                ino,
+                lblock as u64,
+            ));
+        }
+        // a directory never has holes.
+        let block = self.map_lblock(extents, lblock).ok_or_else(|| {
+            self.volume
+                .corrupted("read_dir_block", "directory extent", ino, lblock as u64)
+        })?;
+        let mut data = self.volume.read_block(block);
+        data.truncate(self.volume.sb.block_size());
+        let seed = inode_seed(&self.volume.sb, ino, dir.generation);
+        self.volume.verify_dir_block(ino, seed, block, &data)?;
+        Ok((block, data))
+    }
+
+    /// List the directory by its blocks. The blocks of the htree index
+    /// look like unused entries, so they are skipped like in a linear
+    /// directory.
+    fn dir_entries(&self, ino: u32) -> VfsResult<Vec<DirEntry>> {
+        let dir = self.volume.read_inode(ino)?;
+        let mut extents = self.extents.lock();
+        if !self.load_dir_extents(&mut extents, ino, &dir)? {
+            return Err(VfsError::NotSupported);
+        }
+        let mut dirents = Vec::new();
+        for lblock in 0..dir_blocks(&self.volume.sb, &dir) {
+            let (block, data) = self.read_dir_block(&extents, ino, &dir, lblock)?;
+            for dirent in self.volume.parse_dir_block(ino, block, &data)? {
+                let filename = name_from_bytes(dirent.name);
+                dirents.push((dirent.inode, dirent.file_type, filename, dirent.rec_len));
+            }
+        }
+        let types: Vec<(u32, u8)> = dirents.iter().map(|x| (x.0, x.1)).collect();
+        let types = self.volume.entry_types(&types);
+        let entries = dirents.into_iter().zip(types).map(|(x, d_type)| DirEntry {
+            filename: x.2,
+            len: x.3 as usize,
+            file_type: d_type_file_type(d_type),
+        });
+        Ok(entries.collect())
+    }
+
+    /// List the directory from its position pos for read_dir_at. pos is
+    /// the byte offset of the next entry in a linear directory, the
+    /// entries there never move. An indexed directory is listed in the
+    /// order of the hashes after "." and "..", pos is HASH_POS with the
+    /// hash of the next name plus 2, a split leaf moves the entries but
+    /// not their hashes. The names of a hash are listed together. A
+    /// position of the other kind restarts the listing, the directory was
+    /// indexed or rebuilt since. The entries come with their inode.
+    fn dir_entries_at(&self, ino: u32, pos: u64, max: usize) -> VfsResult<Vec<(u32, PosEntry)>> {
+        let listed = self.list_at(ino, pos, max)?;
+        let types: Vec<(u32, u8)> = listed.iter().map(|x| (x.0, x.1)).collect();
+        let types = self.volume.entry_types(&types);
+        let listed = listed
+            .into_iter()
+            .zip(types)
+            .map(|((ino, _, mut x), d_type)| {
+                x.entry.file_type = d_type_file_type(d_type);
+                x.d_type = d_type;
+                (ino, x)
+            });
+        Ok(listed.collect())
+    }
+
+    /// The listing of dir_entries_at with the type bytes of the entries,
+    /// their types are set from them by entry_types.
+    fn list_at(&self, ino: u32, pos: u64, max: usize) -> VfsResult<Vec<(u32, u8, PosEntry)>> {
+        let dir = self.volume.read_inode(ino)?;
+        if !matches!(mode_file_type(dir.mode), Some(FileType::Directory)) {
+            return Err(VfsError::NotDir);
+        }
+        let mut extents = self.extents.lock();
+        if !self.load_dir_extents(&mut extents, ino, &dir)? {
+            return Err(VfsError::NotSupported);
+        }
+        let indexed = dir.flags & EXT4_INDEX_FL != 0;
+        // a position of the other kind restarts the listing.
+        let pos = match (pos & HASH_POS != 0) == indexed {
+            true => pos & !HASH_POS,
+            false => 0,
+        };
+        let dir_entry = |dirent: &Dirent| DirEntry {
+            filename: name_from_bytes(dirent.name),
+            len: dirent.rec_len as usize,
+            file_type: FileType::File,
+        };
+        let block_size = self.volume.sb.block_size() as u64;
+        let blocks = dir_blocks(&self.volume.sb, &dir);
+        let mut listed = Vec::new();
+        if !indexed {
+            for lblock in (pos / block_size).min(blocks as u64) as u32..blocks {
+                let (block, data) = self.read_dir_block(&extents, ino, &dir, lblock)?;
+                let start = lblock as u64 * block_size;
+                for dirent in self.volume.parse_dir_block(ino, block, &data)? {
+                    let offset = start + dirent.offset as u64;
+                    if offset < pos {
+                        continue;
+                    }
+                    if listed.len() == max {
+                        return Ok(listed);
+                    }
+                    let entry = PosEntry {
+                        entry: dir_entry(&dirent),
+                        next: offset + dirent.rec_len as u64,
+                        ino: dirent.inode as u64,
+                        d_type: DT_UNKNOWN,
+                    };
+                    listed.push((dirent.inode, dirent.file_type, entry));
+                }
+            }
+            return Ok(listed);
+        }
+        let (_, root) = self.read_dir_block(&extents, ino, &dir, 0)?;
+        let info = DxRootInfo::parse(&root).map_err(|_| {
+            self.volume
+                .corrupted("dir_entries_at", "directory index", ino, 0)
+        })?;
+        let version = info.hash_version(&self.volume.sb);
+        let mut keyed = Vec::new();
+        for lblock in 0..blocks {
+            let (block, data) = self.read_dir_block(&extents, ino, &dir, lblock)?;
+            for dirent in self.volume.parse_dir_block(ino, block, &data)? {
+                let key = match dirent.name {
+                    b"." => 0,
+                    b".." => 1,
+                    name => dirhash(name, version, &self.volume.sb.hash_seed)? as u64 + 2,
+                };
+                if key >= pos {
+                    keyed.push((key, dirent.inode, dirent.file_type, dir_entry(&dirent)));
+                }
+            }
+        }
+        keyed.sort_by_key(|x| x.0);
+        for (key, ino, file_type, entry) in keyed {
+            let next = HASH_POS | (key + 1);
+            // the page isn't full while the names of one hash go on.
+            if listed.len() >= max
+                && listed
+                    .last()
+                    .is_some_and(|x: &(u32, u8, PosEntry)| x.2.next != next)
+            {
+                break;
+            }
+            listed.push((
+                ino,
+                file_type,
+                PosEntry {
+                    entry,
+                    next,
+                    ino: ino as u64,
+                    d_type: DT_UNKNOWN,
+                },
+            ));
+        }
+        Ok(listed)
+    }
+
+    /// Check the directory before ext4_rs walks or modifies it, ext4_rs
+    /// trusts the entries on the disk. The directories that don't map
+    /// their blocks by extents can't be checked.
+    fn check_dir(&self, ino: u32) -> VfsResult<()> {
+        match self.dir_entries(ino) {
+            Ok(_) | Err(VfsError::NotSupported) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Check the directories on the path of this one before a fallback
+    /// hands the path to ext4_rs, which walks it from the root: they're
+    /// looked up by the checked parser, so a corrupted one fails with
+    /// InvalidData, and a path which no longer leads here, after a rename
+    /// of a directory on the way, fails with NotSupported. The walk stops
+    /// at a directory which isn't mapped by extents, like check_dir.
+    fn check_path(&self, dir_ino: u32) -> VfsResult<()> {
+        let mut ino = self.volume.root_ino.load(Ordering::Relaxed);
+        for name in self.file_name.split('/').filter(|x| !x.is_empty()) {
+            match self.find_entry(ino, name) {
+                Ok(child) => ino = child,
+                Err(VfsError::NotSupported) => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+        match ino == dir_ino {
+            true => Ok(()),
+            false => Err(VfsError::NotSupported),
+        }
+    }
+
+    /// Remove the entry of the name from this directory. The entry is
+    /// merged into the previous one in its block, or marked unused if it's
+    /// the first. The blocks of the htree index are left as they are, the
+    /// leaf still covers the hash of the name.
+    /// rmdir: the entry must be an empty directory instead of a non
+    /// directory, its ".." link of this directory is dropped with it.
+    fn unlink_entry(&self, name: &str, rmdir: bool) -> VfsResult<()> {
+        check_name(name)?;
+        self.check_sealed()?;
+        if name == "." || name == ".." {
+            return Err(VfsError::InvalidInput);
+        }
+        let _write = self.volume.begin_dir_write()?;
+        let ino = self.ino(&self.inner.lock());
+        let dir = self.volume.read_inode(ino)?;
+        if !matches!(mode_file_type(dir.mode), Some(FileType::Directory)) {
+            return Err(VfsError::NotDir);
+        }
+        let mut extents = self.extents.lock();
+        if !self.load_dir_extents(&mut extents, ino, &dir)? {
+            return Err(VfsError::NotSupported);
+        }
+        let bytes = name_to_bytes(name);
+        let block_size = self.volume.sb.block_size();
+        let mut open = self.volume.open.lock();
+        self.volume.transaction(&[ino], None, || {
+            for lblock in 0..dir_blocks(&self.volume.sb, &dir) {
+                let (block, mut data) = self.read_dir_block(&extents, ino, &dir, lblock)?;
+                let entries = self.volume.parse_dir_block(ino, block, &data)?;
+                let Some(i) = entries.iter().position(|x| x.name == &*bytes) else {
+                    continue;
+                };
+                let (child, offset, end) = (
+                    entries[i].inode,
+                    entries[i].offset,
+                    entries[i].offset + entries[i].rec_len as usize,
+                );
+                let prev = i.checked_sub(1).map(|x| entries[x].offset);
+                // a directory unlinked is EISDIR, ops::unlinkat tells it
+                // apart first.
+                let inode = self.volume.read_inode(child)?;
+                let is_dir = matches!(mode_file_type(inode.mode), Some(FileType::Directory));
+                match (is_dir, rmdir) {
+                    (true, false) => return Err(VfsError::InvalidInput),
+                    (false, true) => return Err(VfsError::NotDir),
+                    _ => {}
+                }
+                if rmdir && !self.volume.dir_is_empty(child, &inode)? {
+                    return Err(VfsError::DirectoryNotEmpty);
+                }
+                match prev {
+                    Some(prev) => set_u16(&mut data, prev + 4, (end - prev) as u16),
+                    None => set_u32(&mut data, offset, 0),
+                }
+                self.volume
+                    .disk
+                    .write_offset(block as usize * block_size, &data);
+                if rmdir {
+                    // "." of the child goes with the entry, a links count
+                    // of 1 means the directory doesn't count its links.
+                    let links = self.volume.read_inode(ino)?.links_count;
+                    if links > 1 {
+                        self.volume
+                            .modify_inode(ino, |raw| set_u16(raw, I_LINKS_COUNT, links - 1))?;
+                    }
+                    self.volume
+                        .modify_inode(child, |raw| set_u16(raw, I_LINKS_COUNT, 1))?;
+                }
+                self.volume.touch_times(ino, CHANGE_TIMES)?;
+                self.volume.touch_times(child, &[I_CTIME])?;
+                return self.volume.drop_link(&mut open, child);
+            }
+            Err(VfsError::FileNotFound)
+        })
+    }
+
+    /// List the directory stored inline in the inode, the inline data
+    /// starts with the parent inode number instead of "." and "..".
+    fn read_inline_dir(&self) -> VfsResult<Vec<DirEntry>> {
+        let data = self
+            .volume
+            .read_inode(self.ino(&self.inner.lock()))?
+            .inline_data();
+        if data.len() < 4 {
+            return Err(VfsError::InvalidData);
+        }
+        let mut entries = vec![
+            DirEntry {
+                filename: ".".to_string(),
+                len: 0,
+                file_type: FileType::Directory,
+            },
+            DirEntry {
+                filename: "..".to_string(),
+                len: 0,
+                file_type: FileType::Directory,
+            },
+        ];
+        let dirents = DirentIter::new(&data[4..]).collect::<VfsResult<Vec<_>>>()?;
+        let types: Vec<(u32, u8)> = dirents.iter().map(|x| (x.inode, x.file_type)).collect();
+        let types = self.volume.entry_types(&types);
+        for (dirent, d_type) in dirents.into_iter().zip(types) {
+            entries.push(DirEntry {
+                filename: name_from_bytes(dirent.name),
+                len: dirent.rec_len as usize,
+                file_type: d_type_file_type(d_type),
+            });
+        }
+        Ok(entries)
+    }
 
-use ext4_rs::*;
+    /// Read the file from the disk, bypass the page cache.
+    /// Holes and uninitialized extents read as zeros. direct reads the
+    /// data blocks bypassing the cache of the device too, the metadata
+    /// still goes through it.
+    fn read_uncached(
+        &self,
+        ext4_file: &mut Ext4File,
+        offset: usize,
+        buffer: &mut [u8],
+        direct: bool,
+    ) -> VfsResult<()> {
+        buffer.fill(0);
+        let mut extents = self.extents.lock();
+        if !self.load_extents(&mut extents, self.ino(ext4_file))? {
+            drop(extents);
+            if self.inline.load(Ordering::Acquire) {
+                let data = self.volume.read_inode(self.ino(ext4_file))?.inline_data();
+                if offset < data.len() {
+                    let len = min(buffer.len(), data.len() - offset);
+                    buffer[..len].copy_from_slice(&data[offset..offset + len]);
+                }
+                return Ok(());
+            }
+            // the inode maps blocks in other ways, let ext4_rs read it.
+            return self.read_by_ext4(ext4_file, offset, buffer);
+        }
+
+        let block_size = self.volume.sb.block_size();
+        let read_len = buffer.len();
+        let mut pos = 0;
+        while pos < read_len {
+            let file_off = offset + pos;
+            let block_off = file_off % block_size;
+            let block_len = min(block_size - block_off, read_len - pos);
+            let lblock = (file_off / block_size) as u32;
+            let whole_blocks = (read_len - pos) / block_size;
+            // a block which isn't covered by any extent is a hole.
+            let Some(extent) = extents.lookup(lblock).filter(|x| !x.uninit) else {
+                pos += block_len;
+                continue;
+            };
+            if block_off == 0 && whole_blocks > 0 {
+                // read the whole blocks of the extent into the buffer directly.
+                let count = min(
+                    whole_blocks,
+                    (extent.logical + extent.len - lblock) as usize,
+                );
+                let len = count * block_size;
+                let block = extent.physical + (lblock - extent.logical) as u64;
+                match direct {
+                    true => self
+                        .volume
+                        .read_blocks_direct(block, &mut buffer[pos..pos + len])?,
+                    false => self
+                        .volume
+                        .read_blocks_into(block, &mut buffer[pos..pos + len])?,
+                }
+                pos += len;
+                continue;
+            }
+            // the partial head or tail block.
+            let block = extent.physical + (lblock - extent.logical) as u64;
+            let mut data = vec![0; block_size];
+            match direct {
+                true => self.volume.read_blocks_direct(block, &mut data)?,
+                false => self.volume.read_blocks_into(block, &mut data)?,
+            }
+            buffer[pos..pos + block_len].copy_from_slice(&data[block_off..block_off + block_len]);
+            pos += block_len;
+        }
+        Ok(())
+    }
 
-const BLOCK_SIZE: usize = 4096;
+    /// Read the file through ext4_file_read block by block.
+    fn read_by_ext4(
+        &self,
+        ext4_file: &mut Ext4File,
+        offset: usize,
+        buffer: &mut [u8],
+    ) -> VfsResult<()> {
+        let read_len = buffer.len();
+        let mut pos = 0;
+        while pos < read_len {
+            let file_off = offset + pos;
+            let block_len = min(BLOCK_SIZE - file_off % BLOCK_SIZE, read_len - pos);
+            let mut read_cnt = 0;
+            ext4_file.fpos = file_off;
 
-#[derive(Debug)]
-pub struct Ext4Disk {
-    device_id: usize,
-}
+            let r = self.ext4.ext4_file_read(
+                ext4_file,
+                &mut buffer[pos..pos + block_len],
+                block_len,
+                &mut read_cnt,
+            );
 
-impl Ext4Disk {
-    /// Create a new disk.
-    pub fn new(device_id: usize) -> Self {
-        Self { device_id }
+            if let Err(e) = r {
+                match e.error() {
+                    // no extent maps this logical block, it is a hole.
+                    Errnum::ENOENT => {}
+                    _ => return Err(ext4_error("read", &self.file_name)(e)),
+                }
+            }
+            pos += block_len;
+        }
+        Ok(())
     }
-}
 
-impl BlockDevice for Ext4Disk {
-    fn read_offset(&self, offset: usize) -> Vec<u8> {
-        let mut buf = vec![0; BLOCK_SIZE];
-        let device = get_blk_device(self.device_id).unwrap();
+    /// Write the buffer to the file at offset without buffering, until
+    /// cancelled. return the bytes written, a write which stops midway
+    /// keeps them. Every WRITE_CHUNK of the buffer is a transaction, a
+    /// chunk which fails after the first one ends the write with the
+    /// chunks before it. touch updates the mtime and the ctime in the
+    /// transactions of the chunks, the writes of O_DSYNC touch them after.
+    fn write_direct(
+        &self,
+        offset: usize,
+        buffer: &[u8],
+        cancelled: &dyn Fn() -> bool,
+        touch: bool,
+    ) -> VfsResult<usize> {
+        // the inline data is written by the extents, the other wrappers of
+        // the inode find it converted.
+        if self.inline.load(Ordering::Acquire) {
+            let ino = self.ino(&self.inner.lock());
+            self.volume
+                .transaction(&[], Some(ino), || self.volume.convert_inline(ino))?;
+            self.inline.store(false, Ordering::Release);
+            self.extents.lock().clear();
+        }
+        let mut ext4_file = self.inner.lock();
+        ext4_file.fpos = offset;
+        let ino = self.ino(&ext4_file);
+        // the shim chooses the blocks, ext4_rs writes what it can't.
+        let mut done = 0;
+        let mut r = Ok(());
+        let mut relocations = 0;
+        while done < buffer.len() {
+            let chunk = &buffer[done..min(done + WRITE_CHUNK, buffer.len())];
+            self.volume.relocation.store(false, Ordering::Relaxed);
+            let written = self.volume.transaction(&[], Some(ino), || {
+                let written = self
+                    .volume
+                    .write_data(ino, offset + done, chunk, cancelled)?;
+                if touch {
+                    self.volume.touch_times(ino, CHANGE_TIMES)?;
+                }
+                Ok(written)
+            });
+            // the rolled back chunk badlisted the blocks of its failed
+            // writes, it's written again off them.
+            if matches!(written, Err(VfsError::WriteZero))
+                && self.volume.relocation.swap(false, Ordering::Relaxed)
+                && relocations < self.volume.options.retry.attempts
+            {
+                relocations += 1;
+                continue;
+            }
+            match written {
+                Ok((written, size)) => {
+                    done += written;
+                    ext4_file.fpos = offset + done;
+                    ext4_file.fsize = size as _;
+                    if written < chunk.len() {
+                        break;
+                    }
+                }
+                // ext4_rs writes the rest.
+                Err(VfsError::NotSupported) => {
+                    r = Err(VfsError::NotSupported);
+                    break;
+                }
+                // the chunks before are committed, the write returns them.
+                Err(_) if done > 0 => break,
+                Err(err) => {
+                    r = Err(err);
+                    break;
+                }
+            }
+        }
+        let r = match r {
+            Ok(()) => Ok(done),
+            Err(VfsError::NotSupported) => {
+                // ext4_rs walks the extent tree without validating it.
+                self.load_extents(&mut self.extents.lock(), ino)?;
+                // ext4_rs writes the whole buffer, so every chunk commits
+                // on its own.
+                let mut r = Ok(());
+                for chunk in buffer[done..].chunks(CANCEL_CHUNK) {
+                    if cancelled() {
+                        if done == 0 {
+                            r = Err(VfsError::Blocking);
+                        }
+                        break;
+                    }
+                    ext4_file.fpos = offset + done;
+                    let written = self.volume.transaction(&[], Some(ino), || {
+                        self.volume.charged(ino, || {
+                            self.ext4
+                                .ext4_file_write(&mut ext4_file, chunk, chunk.len())
+                                .map_err(ext4_error("write", &self.file_name))?;
+                            match touch {
+                                true => self.volume.touch_times(ino, CHANGE_TIMES),
+                                false => Ok(()),
+                            }
+                        })
+                    });
+                    match written {
+                        Ok(()) => done += chunk.len(),
+                        Err(VfsError::StorageFull) if done > 0 => break,
+                        Err(err) => {
+                            r = Err(err);
+                            break;
+                        }
+                    }
+                }
+                r.map(|_| done)
+            }
+            Err(err) => Err(err),
+        };
+        // the failed write was rolled back, but ext4_rs may have moved the
+        // size of the file already.
+        if r.is_err()
+            && let Ok(inode) = self.volume.read_inode(ino)
+        {
+            ext4_file.fsize = inode.size as _;
+        }
+        // the write may allocate new blocks.
+        if !buffer.is_empty() {
+            let block_size = self.volume.sb.block_size();
+            self.extents.lock().invalidate(
+                (offset / block_size) as u32,
+                ((offset + buffer.len() - 1) / block_size) as u32,
+            );
+        }
+        // the cache writes through, drop the stale pages.
+        cache::invalidate_range(
+            self.inode_id(&ext4_file),
+            offset / PAGE_SIZE,
+            (offset + buffer.len() - 1) / PAGE_SIZE,
+        );
 
-        let start_block_id = offset / 512;
-        let mut offset_in_block = offset % 512;
+        r
+    }
 
-        let mut total_bytes_read = 0;
-        for i in 0..(BLOCK_SIZE / 512) {
-            let mut data = vec![0u8; 512];
-            let current_block_id = start_block_id + i;
+    /// Write the buffered data back to the file. The buffered writes
+    /// returned already, so the data is written whole or fails. The data
+    /// is dropped either way and the blocks it held are released.
+    fn flush_wbuf(&self, wbuf: &mut WriteBuffer) -> VfsResult<()> {
+        if wbuf.data.is_empty() {
+            return Ok(());
+        }
+        // its blocks aren't left free for the others by the allocation.
+        self.volume
+            .buffered_blocks
+            .fetch_sub(core::mem::take(&mut wbuf.blocks), Ordering::AcqRel);
+        let r = match self.write_direct(wbuf.offset, &wbuf.data, &cancel::never, true) {
+            Ok(done) if done < wbuf.data.len() => Err(VfsError::StorageFull),
+            Ok(_) => Ok(()),
+            Err(err) => Err(err),
+        };
+        wbuf.data.clear();
+        self.volume.buffered.lock().remove(&(wbuf.ino, self.id));
+        self.buffered.store(false, Ordering::Release);
+        r
+    }
 
-            device.read_blocks(current_block_id, &mut data);
-            let bytes_to_copy = if total_bytes_read == 0 {
-                512 - offset_in_block
-            } else {
-                512
-            };
+    /// Make the buffered writes visible to the file, the readers and the
+    /// size must observe them. A failure is kept for the fsync and the
+    /// close, the first one only.
+    fn sync_wbuf(&self) {
+        let mut wbuf = self.wbuf.lock();
+        if let Err(err) = self.flush_wbuf(&mut wbuf) {
+            log::error!(
+                "flush buffered writes of {} failed: {:?}",
+                self.file_name,
+                err
+            );
+            wbuf.error.get_or_insert(err);
+        }
+    }
 
-            let buf_start = total_bytes_read;
-            let buf_end = buf_start + bytes_to_copy;
-            buf[buf_start..buf_end]
-                .copy_from_slice(&data[offset_in_block..(offset_in_block + bytes_to_copy)]);
+    /// Write back the buffered writes and fail with a flush failure not
+    /// reported yet, for the fsync and the close.
+    fn take_wbuf_error(&self) -> VfsResult<()> {
+        self.sync_wbuf();
+        match self.wbuf.lock().error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
 
-            total_bytes_read += bytes_to_copy;
-            offset_in_block = 0; // only the first block has an offset within the block
+    /// Write back the buffered writes of all the wrappers of the inode
+    /// for a read and return its size, the one of the shared snapshot.
+    /// The fsize of the wrapper misses the writes through the others, it
+    /// takes the size and its extents are read again if it changed.
+    fn sync_size(&self) -> VfsResult<usize> {
+        self.sync_wbuf();
+        let ino = self.ino(&self.inner.lock());
+        self.volume.sync_inodes(&[ino]);
+        let mut ext4_file = self.inner.lock();
+        let size = match self.snapshot.load() {
+            Some(meta) => meta.size as usize,
+            None => ext4_file.fsize as usize,
+        };
+        if ext4_file.fsize as usize != size {
+            ext4_file.fsize = size as _;
+            self.extents.lock().clear();
         }
+        Ok(size)
+    }
+}
 
-        buf
+impl Drop for Ext4FileWrapper {
+    fn drop(&mut self) {
+        self.volume
+            .counters
+            .open_inodes
+            .fetch_sub(1, Ordering::Relaxed);
+        // the close of the file reported a failure already, see flush.
+        self.sync_wbuf();
+        let ino = self.ino(&self.inner.lock());
+        self.volume.file_closed(ino);
+        self.volume.wrappers.lock().remove(&self.id);
     }
+}
 
-    fn write_offset(&self, offset: usize, buf: &[u8]) {
-        // log::info!("write_offset: {:x?} buf_len{:x?}", offset, buf.len());
-        let device = get_blk_device(self.device_id).unwrap();
+impl FsNode for Ext4FileWrapper {
+    #[cfg(feature = "async")]
+    fn as_async(&self) -> Option<&dyn AsyncINode> {
+        Some(self)
+    }
 
-        let start_block_id = offset / 512;
-        let mut offset_in_block = offset % 512;
+    fn as_statx(&self) -> Option<&dyn StatxINode> {
+        Some(self)
+    }
 
-        // assert_eq!(offset_in_block, 0);
+    fn as_atime(&self) -> Option<&dyn AccessTime> {
+        Some(self)
+    }
 
-        let bytes_to_write = buf.len();
-        let mut total_bytes_written = 0;
+    fn as_flags(&self) -> Option<&dyn FlagsINode> {
+        Some(self)
+    }
 
-        for i in 0..((bytes_to_write + 511) / 512) {
-            // round up to cover partial blocks
-            let current_block_id = start_block_id + i;
-            let mut data = [0u8; 512];
+    fn as_owner(&self) -> Option<&dyn OwnerINode> {
+        Some(self)
+    }
 
-            if bytes_to_write < 512 {
-                // Read the current block data first if we're writing less than a full block
-                device.read_blocks(current_block_id, &mut data);
-            }
+    fn as_seek_dir(&self) -> Option<&dyn SeekDir> {
+        Some(self)
+    }
 
-            let buf_start = total_bytes_written;
-            let buf_end = if buf_start + 512 > bytes_to_write {
-                bytes_to_write
-            } else {
-                buf_start + 512
-            };
-            let bytes_to_copy = buf_end - buf_start;
+    fn as_cancel(&self) -> Option<&dyn CancelIo> {
+        Some(self)
+    }
+
+    fn as_rename(&self) -> Option<&dyn RenameINode> {
+        Some(self)
+    }
 
-            data[offset_in_block..offset_in_block + bytes_to_copy]
-                .copy_from_slice(&buf[buf_start..buf_end]);
-            device.write_blocks(current_block_id as usize, &data);
+    fn as_mknod(&self) -> Option<&dyn MknodINode> {
+        Some(self)
+    }
 
-            total_bytes_written += bytes_to_copy;
-            offset_in_block = 0; // only the first block has an offset within the block
-        }
+    fn as_sync(&self) -> Option<&dyn SyncINode> {
+        Some(self)
+    }
+
+    fn as_direct(&self) -> Option<&dyn DirectINode> {
+        Some(self)
+    }
+
+    fn as_batch(&self) -> Option<&dyn BatchINode> {
+        Some(self)
+    }
+
+    fn as_revalidate(&self) -> Option<&dyn RevalidateINode> {
+        Some(self)
+    }
+
+    fn as_append(&self) -> Option<&dyn AppendINode> {
+        Some(self)
     }
 }
 
-/// TODO: use inner fields AND Fix some warnings.
-#[allow(dead_code)]
-pub struct Ext4FileSystem {
-    inner: Arc<Ext4>,
-    root: Arc<dyn INodeInterface>,
-    file_type: FileType,
-    // file_name: String,
+/// The buffered writes of the wrappers of the inode are written back
+/// before the end is taken, so it's the size of the inode.
+impl AppendINode for Ext4FileWrapper {
+    fn append(
+        &self,
+        write: &mut dyn FnMut(usize) -> VfsResult<usize>,
+    ) -> VfsResult<(usize, usize)> {
+        let ino = self.ino(&self.inner.lock());
+        let lock = self.volume.append_lock(ino);
+        let _append = lock.lock();
+        let end = self.sync_size()?;
+        Ok((end, write(end)?))
+    }
 }
 
-impl FileSystem for Ext4FileSystem {
-    fn root_dir(&'static self) -> Arc<dyn INodeInterface> {
-        self.root.clone()
+/// The reads of the extent mapped files await the async driver of the
+/// device, the other operations and the files without one run the sync
+/// methods. ext4_rs writes synchronously, so do the async writes.
+#[cfg(feature = "async")]
+impl AsyncINode for Ext4FileWrapper {
+    fn readat_async<'a>(&'a self, offset: usize, buffer: &'a mut [u8]) -> IoFuture<'a, usize> {
+        Box::pin(async move {
+            let Some(device) = aio::async_device(self.volume.disk.dev) else {
+                return self.readat(offset, buffer);
+            };
+            self.access.check_read()?;
+            self.check_sealed()?;
+            check_range(offset, buffer.len(), u64::MAX)?;
+            let file_size = self.sync_size()?;
+            let (ino, id) = {
+                let ext4_file = self.inner.lock();
+                (self.ino(&ext4_file), self.inode_id(&ext4_file))
+            };
+            if buffer.is_empty() || offset >= file_size {
+                return Ok(0);
+            }
+            let read_len = min(buffer.len(), file_size - offset);
+            // the cached pages may be newer than the disk.
+            let mut pages = offset / PAGE_SIZE..(offset + read_len).div_ceil(PAGE_SIZE);
+            if pages.any(|x| cache::contains(id, x)) {
+                return self.readat(offset, buffer);
+            }
+            let block_size = self.volume.sb.block_size();
+            // the blocks of the range: (pos, offset in the block, len,
+            // physical block), None for the holes.
+            let mut blocks = Vec::new();
+            {
+                let mut extents = self.extents.lock();
+                if !self.load_extents(&mut extents, ino)? {
+                    drop(extents);
+                    return self.readat(offset, buffer);
+                }
+                let mut pos = 0;
+                while pos < read_len {
+                    let file_off = offset + pos;
+                    let block_off = file_off % block_size;
+                    let len = min(block_size - block_off, read_len - pos);
+                    let lblock = (file_off / block_size) as u32;
+                    let physical = extents
+                        .lookup(lblock)
+                        .filter(|x| !x.uninit)
+                        .map(|x| x.physical + (lblock - x.logical) as u64);
+                    blocks.push((pos, block_off, len, physical));
+                    pos += len;
+                }
+            }
+            buffer[..read_len].fill(0);
+            let mut data = vec![0u8; block_size];
+            for (pos, block_off, len, physical) in blocks {
+                let Some(physical) = physical else {
+                    continue;
+                };
+                self.volume
+                    .disk
+                    .read_into_async(device.as_ref(), physical as usize * block_size, &mut data)
+                    .await;
+                buffer[pos..pos + len].copy_from_slice(&data[block_off..block_off + len]);
+            }
+            Ok(read_len)
+        })
     }
+}
 
-    fn name(&self) -> &str {
-        "ext4"
+impl CancelIo for Ext4FileWrapper {
+    fn readat_cancel(
+        &self,
+        offset: usize,
+        buffer: &mut [u8],
+        cancelled: &dyn Fn() -> bool,
+    ) -> VfsResult<usize> {
+        trace::traced(
+            TraceOp::Read,
+            "ext4",
+            || Target::Inode(self.traced_ino()),
+            offset,
+            buffer.len(),
+            || {
+                self.access.check_read()?;
+                self.check_sealed()?;
+                check_range(offset, buffer.len(), u64::MAX)?;
+                if buffer.is_empty() {
+                    return Ok(0);
+                }
+                self.sync_size()?;
+                let mut ext4_file = self.inner.lock();
+
+                let file_size = ext4_file.fsize as usize;
+                if offset >= file_size {
+                    return Ok(0);
+                }
+                let read_len = min(buffer.len(), file_size - offset);
+                let id = self.inode_id(&ext4_file);
+
+                let mut pos = 0;
+                while pos < read_len {
+                    // a cancelled read returns the bytes read so far.
+                    if cancelled() {
+                        match pos {
+                            0 => return Err(VfsError::Blocking),
+                            _ => break,
+                        }
+                    }
+                    let file_off = offset + pos;
+                    let index = file_off / PAGE_SIZE;
+                    let page = match cache::get(id, index) {
+                        Some(page) => page,
+                        None => {
+                            // read the pages ahead with it, up to a cached one.
+                            let ahead = (index + 1..)
+                                .take(self.volume.readahead_blocks.load(Ordering::Relaxed))
+                                .take_while(|x| {
+                                    x * PAGE_SIZE < file_size && !cache::contains(id, *x)
+                                })
+                                .count();
+                            // the pages past those read with it are deferred.
+                            let inline = self.volume.readahead_inline(ahead);
+                            if inline < ahead {
+                                self.volume.defer_readahead(Prefetch {
+                                    ino: id.ino as u32,
+                                    index: index + 1 + inline,
+                                    pages: ahead - inline,
+                                });
+                            }
+                            let page_start = index * PAGE_SIZE;
+                            let end = min((index + 1 + inline) * PAGE_SIZE, file_size);
+                            let mut data = vec![0u8; end - page_start];
+                            // the bytes before a page which can't be read
+                            // are returned, the next read fails.
+                            if let Err(err) =
+                                self.read_uncached(&mut ext4_file, page_start, &mut data, false)
+                            {
+                                match pos {
+                                    0 => return Err(err),
+                                    _ => break,
+                                }
+                            }
+                            for (i, ahead) in data.chunks(PAGE_SIZE).enumerate().skip(1) {
+                                cache::insert(id, index + i, ahead.to_vec());
+                            }
+                            data.truncate(PAGE_SIZE);
+                            cache::insert(id, index, data)
+                        }
+                    };
+                    let page_off = file_off % PAGE_SIZE;
+                    let len = min(page.len().saturating_sub(page_off), read_len - pos);
+                    if len == 0 {
+                        break;
+                    }
+                    buffer[pos..pos + len].copy_from_slice(&page[page_off..page_off + len]);
+                    pos += len;
+                }
+                ext4_file.fpos = offset + pos;
+                self.volume.counters.record_read(pos);
+                Ok(pos)
+            },
+        )
+    }
+
+    fn writeat_cancel(
+        &self,
+        offset: usize,
+        buffer: &[u8],
+        cancelled: &dyn Fn() -> bool,
+    ) -> VfsResult<usize> {
+        let r = trace::traced(
+            TraceOp::Write,
+            "ext4",
+            || Target::Inode(self.traced_ino()),
+            offset,
+            buffer.len(),
+            || {
+                self.access.check_write()?;
+                self.check_sealed()?;
+                check_range(offset, buffer.len(), self.volume.sb.max_file_size())?;
+                let _write = self.volume.begin_write()?;
+                if buffer.is_empty() {
+                    return Ok(0);
+                }
+                self.volume.counters.record_write(buffer.len());
+                let mut wbuf = self.wbuf.lock();
+                // a buffered write would pass a quota limit at its flush.
+                if self.volume.quota_limited() {
+                    self.flush_wbuf(&mut wbuf)?;
+                    return self.write_direct(offset, buffer, cancelled, true);
+                }
+                // only the sequential writes can be coalesced.
+                if !wbuf.data.is_empty() && offset != wbuf.end() {
+                    self.flush_wbuf(&mut wbuf)?;
+                }
+                // the buffered data isn't written yet, so the appends stay beyond
+                // the file size until it's flushed.
+                let limit = match offset >= self.inner.lock().fsize as usize {
+                    true => APPEND_BUFFER_SIZE,
+                    false => WRITE_BUFFER_SIZE,
+                };
+                if wbuf.data.len() + buffer.len() > limit {
+                    self.flush_wbuf(&mut wbuf)?;
+                }
+                // large writes don't benefit from the buffer.
+                if buffer.len() >= limit {
+                    return self.write_direct(offset, buffer, cancelled, true);
+                }
+                // the blocks are held now, a flush can't run out of them after
+                // the write returned. Near a full disk it's written at once.
+                let start = match wbuf.data.is_empty() {
+                    true => offset,
+                    false => wbuf.offset,
+                };
+                let blocks = buffered_blocks(start, offset + buffer.len());
+                if !self.volume.hold_buffered(blocks - wbuf.blocks) {
+                    self.flush_wbuf(&mut wbuf)?;
+                    return self.write_direct(offset, buffer, cancelled, true);
+                }
+                wbuf.blocks = blocks;
+                if wbuf.data.is_empty() {
+                    wbuf.offset = offset;
+                    wbuf.ino = self.ino(&self.inner.lock());
+                    self.volume.buffered.lock().insert((wbuf.ino, self.id));
+                }
+                wbuf.data.extend_from_slice(buffer);
+                self.buffered.store(true, Ordering::Release);
+                if wbuf.data.len() == limit {
+                    self.flush_wbuf(&mut wbuf)?;
+                }
+                Ok(buffer.len())
+            },
+        );
+        self.mapped_write(offset, r)
     }
 }
 
-unsafe impl Sync for Ext4FileSystem {}
-unsafe impl Send for Ext4FileSystem {}
+/// The direct reads write the buffered writes back first and read the
+/// data blocks by the DirectRead of the device, the deferred transactions
+/// over them. The direct writes skip the write buffer, the pages they
+/// overwrite are dropped.
+impl DirectINode for Ext4FileWrapper {
+    fn readat_direct(
+        &self,
+        offset: usize,
+        buffer: &mut [u8],
+        cancelled: &dyn Fn() -> bool,
+    ) -> VfsResult<usize> {
+        trace::traced(
+            TraceOp::Read,
+            "ext4",
+            || Target::Inode(self.traced_ino()),
+            offset,
+            buffer.len(),
+            || {
+                self.access.check_read()?;
+                self.check_sealed()?;
+                check_range(offset, buffer.len(), u64::MAX)?;
+                if buffer.is_empty() {
+                    return Ok(0);
+                }
+                self.sync_size()?;
+                let mut ext4_file = self.inner.lock();
+                let file_size = ext4_file.fsize as usize;
+                if offset >= file_size {
+                    return Ok(0);
+                }
+                let read_len = min(buffer.len(), file_size - offset);
+                let mut pos = 0;
+                while pos < read_len {
+                    // a cancelled read returns the bytes read so far.
+                    if cancelled() {
+                        match pos {
+                            0 => return Err(VfsError::Blocking),
+                            _ => break,
+                        }
+                    }
+                    let len = min(CANCEL_CHUNK, read_len - pos);
+                    let chunk = &mut buffer[pos..pos + len];
+                    self.read_uncached(&mut ext4_file, offset + pos, chunk, true)?;
+                    pos += len;
+                }
+                ext4_file.fpos = offset + pos;
+                self.volume.counters.record_read(pos);
+                Ok(pos)
+            },
+        )
+    }
 
-impl Ext4FileSystem {
-    pub fn new(device_id: usize) -> Arc<Self> {
-        let disk = Arc::new(Ext4Disk::new(device_id));
-        let ext4 = Ext4::open(disk);
+    fn writeat_direct(
+        &self,
+        offset: usize,
+        buffer: &[u8],
+        cancelled: &dyn Fn() -> bool,
+    ) -> VfsResult<usize> {
+        let r = trace::traced(
+            TraceOp::Write,
+            "ext4",
+            || Target::Inode(self.traced_ino()),
+            offset,
+            buffer.len(),
+            || {
+                self.access.check_write()?;
+                self.check_sealed()?;
+                check_range(offset, buffer.len(), self.volume.sb.max_file_size())?;
+                let _write = self.volume.begin_write()?;
+                if buffer.is_empty() {
+                    return Ok(0);
+                }
+                self.volume.counters.record_write(buffer.len());
+                // the buffered writes come first, they returned before.
+                self.sync_wbuf();
+                self.write_direct(offset, buffer, cancelled, true)
+            },
+        );
+        self.mapped_write(offset, r)
+    }
 
-        let root = Arc::new(Ext4FileWrapper::load_root(ext4.clone()));
-        Arc::new(Self {
-            inner: ext4,
-            root,
-            file_type: FileType::Directory,
-        })
+    fn strict_alignment(&self) -> bool {
+        self.volume.options.strict_direct
     }
 }
 
-pub struct Ext4FileWrapper {
-    inner: Mutex<Ext4File>,
-    ext4: Arc<Ext4>,
-    file_type: FileType,
-    file_name: String,
+/// A batch defers the transactions of the whole volume, see
+/// effective_sync_policy.
+impl BatchINode for Ext4FileWrapper {
+    fn begin_batch(&self) {
+        self.volume.begin_batch();
+    }
+
+    fn end_batch(&self) -> VfsResult<()> {
+        self.volume.end_batch()
+    }
 }
 
-impl Ext4FileWrapper {
-    fn load_root(ext4: Arc<Ext4>) -> Self {
-        let mut ext4_file = Ext4File::new();
-        let _ = ext4.ext4_open(&mut ext4_file, "/", "r", false);
+/// The transactions are committed and checkpointed when they end, so a
+/// sync writes the buffered data back and flushes the write cache of the
+/// device. A write of O_DSYNC which only moves the times commits them after
+/// the flush, the inode is written when the data is already durable.
+impl RevalidateINode for Ext4FileWrapper {
+    /// A writable mount owns its disk, it does nothing then, see
+    /// Ext4Volume::revalidate.
+    fn revalidate(&self) -> VfsResult<bool> {
+        if !self.volume.is_read_only() {
+            log::warn!(
+                "ext4 revalidate of {} on a writable mount, nothing to do",
+                self.file_name
+            );
+            return Ok(false);
+        }
+        self.reload(false)
+    }
+}
 
-        Self {
-            inner: Mutex::new(ext4_file),
-            ext4,
-            file_type: FileType::Directory,
-            file_name: "/".to_string(),
+impl SyncINode for Ext4FileWrapper {
+    fn sync_range(&self, range: core::ops::Range<usize>, mode: SyncMode) -> VfsResult<()> {
+        if mapping::is_active() {
+            let id = self.inode_id(&self.inner.lock());
+            mapping::writeback(id, range.clone())?;
         }
+        let mut wbuf = self.wbuf.lock();
+        if !wbuf.data.is_empty() && wbuf.offset < range.end && range.start < wbuf.end() {
+            self.flush_wbuf(&mut wbuf)?;
+        }
+        // a flush failed after its writes returned.
+        if let Some(err) = wbuf.error.take() {
+            return Err(err);
+        }
+        drop(wbuf);
+        let ino = self.ino(&self.inner.lock());
+        self.volume.sync_inode(ino, mode)?;
+        blockdev::flush_device(self.volume.disk.dev);
+        Ok(())
+    }
+
+    fn write_sync(
+        &self,
+        offset: usize,
+        buffer: &[u8],
+        mode: SyncMode,
+        cancelled: &dyn Fn() -> bool,
+    ) -> VfsResult<usize> {
+        let r = trace::traced(
+            TraceOp::Write,
+            "ext4",
+            || Target::Inode(self.traced_ino()),
+            offset,
+            buffer.len(),
+            || {
+                self.access.check_write()?;
+                self.check_sealed()?;
+                check_range(offset, buffer.len(), self.volume.sb.max_file_size())?;
+                let _write = self.volume.begin_write()?;
+                if buffer.is_empty() {
+                    return Ok(0);
+                }
+                self.volume.counters.record_write(buffer.len());
+                // the buffered writes come first, they returned before.
+                self.sync_wbuf();
+                let touch = mode == SyncMode::Full;
+                let written = self.write_direct(offset, buffer, cancelled, touch)?;
+                let ino = self.ino(&self.inner.lock());
+                self.volume.sync_inode(ino, mode)?;
+                blockdev::flush_device(self.volume.disk.dev);
+                if !touch && written > 0 {
+                    self.volume
+                        .transaction(&[ino], None, || self.volume.touch_times(ino, CHANGE_TIMES))?;
+                }
+                Ok(written)
+            },
+        );
+        self.mapped_write(offset, r)
     }
 }
 
 impl INodeInterface for Ext4FileWrapper {
     fn open(&self, path: &str, flags: vfscore::OpenFlags) -> VfsResult<Arc<dyn INodeInterface>> {
-        let mut ext4_file = Ext4File::new();
-
-        let mut create = false;
-
-        if flags.contains(OpenFlags::O_CREAT) {
-            create = true;
-        };
-        // let mut parse_flags: &str;
-        // match flags {
-        //     OpenFlags::O_RDONLY => parse_flags = "r",
-        //     OpenFlags::O_WRONLY | OpenFlags::O_CREAT | OpenFlags::O_TRUNC => parse_flags = "w",
-        //     OpenFlags::O_WRONLY | OpenFlags::O_CREAT | OpenFlags::O_APPEND => parse_flags = "a",
-        //     OpenFlags::O_RDWR => parse_flags = "r+",
-        //     OpenFlags::O_RDWR | OpenFlags::O_CREAT | OpenFlags::O_TRUNC => parse_flags = "w+",
-        //     OpenFlags::O_RDWR | OpenFlags::O_CREAT | OpenFlags::O_APPEND => parse_flags = "a+",
-        //     _ => parse_flags = "r+",
-        // };
-
-        let r = self.ext4.ext4_open(&mut ext4_file, path, "r+", create);
+        trace::traced(
+            TraceOp::Open,
+            "ext4",
+            || Target::Entry {
+                dir: self.traced_ino(),
+                name: path,
+            },
+            0,
+            0,
+            || {
+                check_name(path)?;
+                self.check_sealed()?;
+                // find the file by the directory index first and create a
+                // missing one by the shim, fall back to ext4_open if the
+                // directory can't be parsed or modified. O_EXCL skips the
+                // lookup, add_entry checks the name in the transaction.
+                let access = AccessMode::from_flags(flags);
+                let create = flags.contains(OpenFlags::O_CREAT);
+                let exclusive = create && flags.contains(OpenFlags::O_EXCL);
+                let found = match exclusive {
+                    true => Err(VfsError::FileNotFound),
+                    false => self.lookup_child(path),
+                };
+                // missing: the file is created, its times are set.
+                let missing = match found {
+                    Ok(mut child) => {
+                        child.access = access;
+                        if !matches!(child.file_type, FileType::Directory) {
+                            child.slot = Some(OpenSlot::take(&self.volume)?);
+                        }
+                        return Ok(child.into_arc());
+                    }
+                    Err(VfsError::FileNotFound) if !flags.contains(OpenFlags::O_CREAT) => {
+                        return Err(VfsError::FileNotFound);
+                    }
+                    Err(VfsError::FileNotFound) => true,
+                    Err(VfsError::NotSupported) => false,
+                    // a corrupted directory or inode must not reach ext4_rs.
+                    Err(err) => return Err(err),
+                };
+                let mut ext4_file = Ext4File::new();
 
-        if let Err(e) = r {
-            match e.error() {
-                Errnum::ENOENT => Err(vfscore::VfsError::FileNotFound),
-                Errnum::EALLOCFIAL => Err(vfscore::VfsError::UnexpectedEof),
-                Errnum::ELINKFIAL => Err(vfscore::VfsError::UnexpectedEof),
+                let _write = match create {
+                    true => {
+                        check_str_name(path)?;
+                        Some(self.volume.begin_dir_write()?)
+                    }
+                    false => None,
+                };
+                // let mut parse_flags: &str;
+                // match flags {
+                //     OpenFlags::O_RDONLY => parse_flags = "r",
+                //     OpenFlags::O_WRONLY | OpenFlags::O_CREAT | OpenFlags::O_TRUNC => parse_flags = "w",
+                //     OpenFlags::O_WRONLY | OpenFlags::O_CREAT | OpenFlags::O_APPEND => parse_flags = "a",
+                //     OpenFlags::O_RDWR => parse_flags = "r+",
+                //     OpenFlags::O_RDWR | OpenFlags::O_CREAT | OpenFlags::O_TRUNC => parse_flags = "w+",
+                //     OpenFlags::O_RDWR | OpenFlags::O_CREAT | OpenFlags::O_APPEND => parse_flags = "a+",
+                //     _ => parse_flags = "r+",
+                // };
 
-                _ => Err(vfscore::VfsError::UnexpectedEof),
-            }
-        } else {
-            Ok(Arc::new(Ext4FileWrapper {
-                inner: Mutex::new(ext4_file),
-                ext4: self.ext4.clone(),
-                file_type: self.file_type,
-                file_name: String::from(path),
-            }))
-        }
+                let dir_ino = self.ino(&self.inner.lock());
+                if create {
+                    self.check_dir(dir_ino)?;
+                }
+                // the slot is taken before the file is created.
+                let slot = OpenSlot::take(&self.volume)?;
+                if create && missing {
+                    match self.create_child(dir_ino, path, FileType::File) {
+                        Ok(Some(mut child)) => {
+                            child.access = access;
+                            child.slot = Some(slot);
+                            return Ok(child.into_arc());
+                        }
+                        Ok(None) => {}
+                        // another open created it since the lookup.
+                        Err(VfsError::AlreadyExists) if !exclusive => {
+                            let mut child = self.lookup_child(path)?;
+                            child.access = access;
+                            if !matches!(child.file_type, FileType::Directory) {
+                                child.slot = Some(slot);
+                            }
+                            return Ok(child.into_arc());
+                        }
+                        Err(err) => return Err(err),
+                    }
+                }
+                // ext4_rs walks the path from the root.
+                self.check_path(dir_ino)?;
+                let child_path = self.child_path(path);
+                self.volume.transaction(&[dir_ino], None, || {
+                    self.volume.charged(dir_ino, || {
+                        // ext4_rs opens a file which exists, O_EXCL checks
+                        // it in the transaction.
+                        if exclusive
+                            && self
+                                .ext4
+                                .ext4_open(&mut Ext4File::new(), &child_path, "r+", false)
+                                .is_ok()
+                        {
+                            return Err(VfsError::AlreadyExists);
+                        }
+                        self.ext4
+                            .ext4_open(&mut ext4_file, &child_path, "r+", create)
+                            .map_err(ext4_error("open", &child_path))?;
+                        if create && missing {
+                            self.volume.init_inode(ext4_file.inode as u32)?;
+                            self.volume.touch_times(dir_ino, CHANGE_TIMES)?;
+                        }
+                        Ok(())
+                    })
+                })?;
+                let file_type = self.inode_file_type(ext4_file.inode as u32)?;
+                let mut child = self.child(ext4_file, file_type, child_path);
+                child.access = access;
+                if !matches!(child.file_type, FileType::Directory) {
+                    child.slot = Some(slot);
+                }
+                Ok(child.into_arc())
+            },
+        )
     }
 
     fn mkdir(&self, path: &str) -> VfsResult<Arc<dyn INodeInterface>> {
-        let _ = self.ext4.ext4_dir_mk(path);
-        let mut ext4_file = Ext4File::new();
+        trace::traced(
+            TraceOp::Mkdir,
+            "ext4",
+            || Target::Entry {
+                dir: self.traced_ino(),
+                name: path,
+            },
+            0,
+            0,
+            || {
+                check_str_name(path)?;
+                self.check_sealed()?;
+                let _write = self.volume.begin_dir_write()?;
+                let mut ext4_file = Ext4File::new();
+                // the new directory and its entry in the parent are one transaction.
+                let dir_ino = self.ino(&self.inner.lock());
+                self.check_dir(dir_ino)?;
+                match self.find_entry(dir_ino, path) {
+                    Ok(_) => return Err(VfsError::AlreadyExists),
+                    Err(VfsError::FileNotFound) => {
+                        if let Some(child) =
+                            self.create_child(dir_ino, path, FileType::Directory)?
+                        {
+                            return Ok(child.into_arc());
+                        }
+                    }
+                    Err(VfsError::NotSupported) => {}
+                    Err(err) => return Err(err),
+                }
+                // ext4_rs walks the path from the root.
+                self.check_path(dir_ino)?;
+                let child_path = self.child_path(path);
+                self.volume.transaction(&[dir_ino], None, || {
+                    self.volume.charged(dir_ino, || {
+                        self.ext4
+                            .ext4_dir_mk(&child_path)
+                            .map_err(ext4_error("mkdir", &child_path))?;
+                        self.ext4
+                            .ext4_open(&mut ext4_file, &child_path, "w", false)
+                            .map_err(ext4_error("open", &child_path))?;
+                        self.volume.init_inode(ext4_file.inode as u32)?;
+                        self.volume.touch_times(dir_ino, CHANGE_TIMES)
+                    })
+                })?;
 
-        let _ = self.ext4.ext4_open(&mut ext4_file, path, "w", false);
-
-        Ok(Arc::new(Ext4FileWrapper {
-            inner: Mutex::new(ext4_file),
-            ext4: self.ext4.clone(),
-            file_type: FileType::Directory,
-            file_name: String::from(path),
-        }))
+                let file_type = self.inode_file_type(ext4_file.inode as u32)?;
+                Ok(self.child(ext4_file, file_type, child_path).into_arc())
+            },
+        )
     }
 
     fn metadata(&self) -> VfsResult<vfscore::Metadata> {
@@ -223,66 +7864,133 @@ impl INodeInterface for Ext4FileWrapper {
             });
         }
 
-        let mut ext4_file = Ext4File::new();
-        let _ = self
-            .ext4
-            .ext4_open(&mut ext4_file, &self.file_name, "r+", false);
+        if self.buffered.load(Ordering::Acquire) {
+            self.sync_wbuf();
+        }
+        // the snapshot of the inode instead of walking the path with
+        // ext4_rs, the directories on the way aren't validated by it.
+        let ino = self.snapshot_ino;
+        let size = match self.snapshot.load() {
+            Some(meta) => meta.size,
+            None => self.volume.read_inode(ino)?.size,
+        };
 
         Ok(Metadata {
             filename: &self.file_name,
-            inode: ext4_file.inode as usize,
+            inode: ino as usize,
             file_type: self.file_type,
-            size: ext4_file.fsize as _,
+            size: size as _,
             childrens: 0,
         })
     }
 
     fn readat(&self, offset: usize, buffer: &mut [u8]) -> VfsResult<usize> {
-        let mut ext4_file = self.inner.lock();
-
-        ext4_file.fpos = offset;
-
-        let read_len = buffer.len();
-        let mut read_cnt = 0;
-
-        let r = self
-            .ext4
-            .ext4_file_read(&mut ext4_file, buffer, read_len, &mut read_cnt);
+        self.readat_cancel(offset, buffer, &cancel::never)
+    }
 
-        if let Err(e) = r {
-            match e.error() {
-                Errnum::EINVAL => Err(vfscore::VfsError::InvalidInput),
-                _ => Err(vfscore::VfsError::UnexpectedEof),
-            }
-        } else {
-            Ok(ext4_file.fpos - offset)
-        }
+    fn writeat(&self, offset: usize, buffer: &[u8]) -> VfsResult<usize> {
+        self.writeat_cancel(offset, buffer, &cancel::never)
     }
 
-    fn writeat(&self, _offset: usize, _buffer: &[u8]) -> VfsResult<usize> {
-        todo!("ext4 loopup")
+    fn flush(&self) -> VfsResult<()> {
+        trace::traced(
+            TraceOp::Flush,
+            "ext4",
+            || Target::Inode(self.traced_ino()),
+            0,
+            0,
+            || self.take_wbuf_error(),
+        )
     }
 
-    fn rmdir(&self, _name: &str) -> VfsResult<()> {
-        todo!("ext4 loopup")
+    fn rmdir(&self, name: &str) -> VfsResult<()> {
+        trace::traced(
+            TraceOp::Rmdir,
+            "ext4",
+            || Target::Entry {
+                dir: self.traced_ino(),
+                name,
+            },
+            0,
+            0,
+            || self.unlink_entry(name, true),
+        )
     }
 
-    fn remove(&self, _name: &str) -> VfsResult<()> {
-        todo!("ext4 loopup")
+    fn remove(&self, name: &str) -> VfsResult<()> {
+        trace::traced(
+            TraceOp::Unlink,
+            "ext4",
+            || Target::Entry {
+                dir: self.traced_ino(),
+                name,
+            },
+            0,
+            0,
+            || self.unlink_entry(name, false),
+        )
     }
 
     fn touch(&self, path: &str) -> VfsResult<Arc<dyn INodeInterface>> {
-        let mut ext4_file = Ext4File::new();
-        let _ = self.ext4.ext4_open(&mut ext4_file, path, "w+", true);
-        Ok(Arc::new(Ext4FileWrapper {
-            inner: Mutex::new(ext4_file),
-            ext4: self.ext4.clone(),
-            file_type: FileType::File,
-            file_name: String::from(path),
-        }))
+        trace::traced(
+            TraceOp::Touch,
+            "ext4",
+            || Target::Entry {
+                dir: self.traced_ino(),
+                name: path,
+            },
+            0,
+            0,
+            || {
+                check_str_name(path)?;
+                self.check_sealed()?;
+                let _write = self.volume.begin_dir_write()?;
+                let mut ext4_file = Ext4File::new();
+                let dir_ino = self.ino(&self.inner.lock());
+                self.check_dir(dir_ino)?;
+                // an indexed lookup may not be possible, the file is created
+                // by ext4_rs then and its times are left to it.
+                let missing = matches!(self.find_entry(dir_ino, path), Err(VfsError::FileNotFound));
+                let slot = OpenSlot::take(&self.volume)?;
+                if missing
+                    && let Some(mut child) = self.create_child(dir_ino, path, FileType::File)?
+                {
+                    child.slot = Some(slot);
+                    return Ok(child.into_arc());
+                }
+                // ext4_rs walks the path from the root.
+                self.check_path(dir_ino)?;
+                let child_path = self.child_path(path);
+                self.volume.transaction(&[dir_ino], None, || {
+                    self.volume.charged(dir_ino, || {
+                        self.ext4
+                            .ext4_open(&mut ext4_file, &child_path, "w+", true)
+                            .map_err(ext4_error("touch", &child_path))?;
+                        if missing {
+                            self.volume.init_inode(ext4_file.inode as u32)?;
+                            self.volume.touch_times(dir_ino, CHANGE_TIMES)?;
+                        }
+                        Ok(())
+                    })
+                })?;
+                let file_type = self.inode_file_type(ext4_file.inode as u32)?;
+                let mut child = self.child(ext4_file, file_type, child_path);
+                child.slot = Some(slot);
+                Ok(child.into_arc())
+            },
+        )
     }
 
     fn read_dir(&self) -> VfsResult<Vec<DirEntry>> {
+        self.check_sealed()?;
+        if self.inline.load(Ordering::Acquire) {
+            return self.read_inline_dir();
+        }
+        match self.dir_entries(self.ino(&self.inner.lock())) {
+            // the directory maps the blocks in other ways, let ext4_rs list it.
+            Err(VfsError::NotSupported) => {}
+            r => return r,
+        }
         let ext4file = self.inner.lock();
         let mut inode_num = ext4file.inode;
         if inode_num == 0 && self.file_name == "/" {
@@ -290,16 +7998,23 @@ impl INodeInterface for Ext4FileWrapper {
         }
         let v: Vec<Ext4DirEntry> = self.ext4.read_dir_entry(inode_num as _);
 
-        let mut entries = Vec::new();
-
-        for i in v.iter() {
-            let file_type =
-                map_ext4_type(unsafe { DirEntryType::from_bits(i.inner.inode_type).unwrap() });
+        // the checksum tail and the removed entries have no inode.
+        let v: Vec<&Ext4DirEntry> = v.iter().filter(|x| x.inode != 0).collect();
+        // SAFETY: inner is a union of name_len_hi and inode_type, both are
+        // u8 so any bits read from the disk are valid for either.
+        let types: Vec<(u32, u8)> = v
+            .iter()
+            .map(|x| (x.inode, unsafe { x.inner.inode_type }))
+            .collect();
+        let types = self.volume.entry_types(&types);
 
+        let mut entries = Vec::with_capacity(v.len());
+        for (i, d_type) in v.into_iter().zip(types) {
+            // get_name allocates the name once, DirEntry takes it as is.
             let entry = DirEntry {
                 filename: i.get_name(),
                 len: i.entry_len as usize,
-                file_type,
+                file_type: d_type_file_type(d_type),
             };
 
             entries.push(entry);
@@ -307,81 +8022,574 @@ impl INodeInterface for Ext4FileWrapper {
         Ok(entries)
     }
 
-    fn lookup(&self, _name: &str) -> VfsResult<Arc<dyn INodeInterface>> {
-        todo!("ext4 loopup")
+    fn lookup(&self, name: &str) -> VfsResult<Arc<dyn INodeInterface>> {
+        trace::traced(
+            TraceOp::Lookup,
+            "ext4",
+            || Target::Entry {
+                dir: self.traced_ino(),
+                name,
+            },
+            0,
+            0,
+            || {
+                check_lookup_name(name)?;
+                self.check_sealed()?;
+                Ok(self.lookup_child(name)?.into_arc())
+            },
+        )
     }
 
-    fn truncate(&self, _size: usize) -> VfsResult<()> {
-        Ok(())
+    fn truncate(&self, size: usize) -> VfsResult<()> {
+        trace::traced(
+            TraceOp::Truncate,
+            "ext4",
+            || Target::Inode(self.traced_ino()),
+            size,
+            0,
+            || {
+                self.access.check_write()?;
+                self.check_sealed()?;
+                check_range(size, 0, self.volume.sb.max_file_size())?;
+                let _write = self.volume.begin_write()?;
+                self.sync_wbuf();
+                let mut ext4_file = self.inner.lock();
+                let ino = self.ino(&ext4_file);
+                // the mappings drop the cut pages before their blocks go.
+                let cut = size..ext4_file.fsize as usize;
+                mapping::invalidate(self.inode_id(&ext4_file), cut);
+                let r = self.volume.transaction(&[], Some(ino), || {
+                    self.volume.truncate_data(ino, size as u64)?;
+                    self.volume.touch_times(ino, CHANGE_TIMES)
+                });
+                if r.is_ok() {
+                    ext4_file.fsize = size as _;
+                }
+                cache::invalidate(self.inode_id(&ext4_file));
+                self.extents.lock().clear();
+                r
+            },
+        )
     }
 
+    /// The target of the symbol link, a fast link keeps it in i_block and
+    /// the others in the data like a file.
     fn resolve_link(&self) -> VfsResult<alloc::string::String> {
-        Err(vfscore::VfsError::NotSupported)
+        if !matches!(self.file_type, FileType::Link) {
+            return Err(VfsError::InvalidInput);
+        }
+        self.check_sealed()?;
+        let mut ext4_file = self.inner.lock();
+        let ino = self.ino(&ext4_file);
+        let inode = self.volume.read_inode(ino)?;
+        let size = inode.size as usize;
+        if size >= PATH_MAX {
+            return Err(self
+                .volume
+                .corrupted("resolve_link", "symbol link size", ino, 0));
+        }
+        let target = match size < inode.i_block.len() && !self.inline.load(Ordering::Acquire) {
+            true => inode.i_block[..size].to_vec(),
+            false => {
+                let mut data = vec![0; size];
+                self.read_uncached(&mut ext4_file, 0, &mut data, false)?;
+                data
+            }
+        };
+        Ok(name_from_bytes(&target))
     }
 
-    fn link(&self, _name: &str, _src: Arc<dyn INodeInterface>) -> VfsResult<()> {
-        Err(vfscore::VfsError::NotSupported)
+    fn link(&self, name: &str, src: Arc<dyn INodeInterface>) -> VfsResult<()> {
+        trace::traced(
+            TraceOp::Link,
+            "ext4",
+            || Target::Entry {
+                dir: self.traced_ino(),
+                name,
+            },
+            0,
+            0,
+            || {
+                check_same_dev(self, src.as_ref())?;
+                Err(vfscore::VfsError::NotSupported)
+            },
+        )
     }
 
-    fn sym_link(&self, _name: &str, _src: &str) -> VfsResult<()> {
-        Err(vfscore::VfsError::NotSupported)
+    fn sym_link(&self, name: &str, _src: &str) -> VfsResult<()> {
+        trace::traced(
+            TraceOp::Symlink,
+            "ext4",
+            || Target::Entry {
+                dir: self.traced_ino(),
+                name,
+            },
+            0,
+            0,
+            || Err(vfscore::VfsError::NotSupported),
+        )
     }
 
-    fn unlink(&self, _name: &str) -> VfsResult<()> {
-        Ok(())
+    fn unlink(&self, name: &str) -> VfsResult<()> {
+        trace::traced(
+            TraceOp::Unlink,
+            "ext4",
+            || Target::Entry {
+                dir: self.traced_ino(),
+                name,
+            },
+            0,
+            0,
+            || self.unlink_entry(name, false),
+        )
     }
 
+    /// The fields are of the snapshot of the inode, so a stat doesn't
+    /// wait for a write holding the file, only the buffered writes of
+    /// this wrapper are flushed first.
     fn stat(&self, stat: &mut vfscore::Stat) -> VfsResult<()> {
-        stat.ino = 1; // TODO: convert path to number(ino)
-        stat.mode = match self.file_type {
-            FileType::File => StatMode::FILE,
-            FileType::Directory => StatMode::DIR,
-            FileType::Device => StatMode::BLOCK,
-            FileType::Socket => StatMode::SOCKET,
-            FileType::Link => StatMode::LINK,
-        };
-        stat.nlink = 1;
-        stat.uid = 0;
-        stat.gid = 0;
-        stat.size = self.inner.lock().fsize as _;
+        if self.buffered.load(Ordering::Acquire) {
+            self.sync_wbuf();
+        }
+        stat.ino = self.snapshot_ino as _;
+        // an open file may be unlinked already.
+        let meta = self.snapshot.load();
+        stat.mode = stat_mode(self.file_type, meta.map(|x| x.mode));
+        stat.nlink = meta.map_or(1, |x| x.nlink as _);
+        stat.uid = meta.map_or(0, |x| x.uid) as _;
+        stat.gid = meta.map_or(0, |x| x.gid) as _;
+        stat.size = match meta {
+            Some(meta) => meta.size as _,
+            None => self.inner.lock().fsize as _,
+        };
         stat.blksize = 4096;
-        stat.blocks = 0;
-        stat.rdev = 0; // TODO: add device id
-        stat.atime.nsec = 0;
-        stat.atime.sec = 0;
-        stat.ctime.nsec = 0;
-        stat.ctime.sec = 0;
-        stat.mtime.nsec = 0;
-        stat.mtime.sec = 0;
+        // the blocks really allocated, the holes of a sparse file aren't
+        // counted.
+        stat.blocks = meta.map_or(0, |x| x.blocks);
+        stat.dev = self.volume.disk.dev as _;
+        stat.rdev = meta.map_or(0, |x| x.rdev) as _;
+        if let Some(meta) = meta {
+            stat.atime = meta.atime;
+            stat.ctime = meta.ctime;
+            stat.mtime = meta.mtime;
+        }
         Ok(())
     }
 
     fn statfs(&self, statfs: &mut StatFS) -> VfsResult<()> {
-        statfs.ftype = 32;
-        statfs.bsize = 4096;
-        statfs.blocks = 80;
-        statfs.bfree = 40;
-        statfs.bavail = 0;
-        statfs.files = 32;
-        statfs.ffree = 0;
-        statfs.fsid = 32;
-        statfs.namelen = 20;
+        self.volume.count_free_lazily()?;
+        let sb = self.volume.read_superblock();
+        // the free counts without the lock of the transactions, the
+        // running one has its reservations taken out.
+        let bfree = self.volume.free_block_count.get();
+        statfs.ftype = EXT4_SUPER_MAGIC as _;
+        statfs.bsize = sb.block_size() as _;
+        statfs.blocks = sb.blocks_count as _;
+        statfs.bfree = bfree as _;
+        statfs.bavail = bfree.saturating_sub(sb.r_blocks_count) as _;
+        statfs.files = sb.inodes_count as _;
+        statfs.ffree = self.volume.free_inode_count.get() as _;
+        statfs.fsid = sb.fsid() as _;
+        statfs.namelen = NAME_MAX as _;
         Ok(())
     }
 
-    fn utimes(&self, _times: &mut [TimeSpec]) -> VfsResult<()> {
+    /// Set the access and the modification times, UTIME_NOW sets one to
+    /// now and UTIME_OMIT keeps it. The change time is set to now.
+    fn utimes(&self, times: &mut [TimeSpec]) -> VfsResult<()> {
+        let _write = self.volume.begin_write()?;
+        self.sync_wbuf();
+        let ino = self.ino(&self.inner.lock());
+        let now = self.volume.timestamp();
+        let inode_size = self.volume.sb.inode_size as usize;
+        self.volume.transaction(&[], None, || {
+            self.volume.modify_inode(ino, |raw| {
+                for (field, time) in [I_ATIME, I_MTIME].into_iter().zip(times.iter()) {
+                    let time = match time.nsec {
+                        UTIME_OMIT => continue,
+                        UTIME_NOW => now,
+                        _ => timestamp_of(time),
+                    };
+                    set_time(raw, inode_size, field, time);
+                }
+                set_time(raw, inode_size, I_CTIME, now);
+            })
+        })
+    }
+}
+
+/// The access time is written in a transaction of its own, a frozen or
+/// read-only volume keeps it and the read doesn't wait for the thaw.
+impl AccessTime for Ext4FileWrapper {
+    fn now(&self) -> TimeSpec {
+        time_spec(self.volume.timestamp())
+    }
+
+    fn set_atime(&self, time: TimeSpec) -> VfsResult<()> {
+        let Some(_write) = self.volume.try_begin_write() else {
+            return Ok(());
+        };
+        let ino = self.ino(&self.inner.lock());
+        let inode_size = self.volume.sb.inode_size as usize;
+        let time = timestamp_of(&time);
+        self.volume.transaction(&[], None, || {
+            self.volume
+                .modify_inode(ino, |raw| set_time(raw, inode_size, I_ATIME, time))
+        })
+    }
+}
+
+/// The owner is i_uid and i_gid, with the quota option the charges of
+/// the file move to the new owner and group, the change fails if they
+/// pass their limits.
+impl OwnerINode for Ext4FileWrapper {
+    fn set_owner(&self, uid: u32, gid: u32) -> VfsResult<()> {
+        let _write = self.volume.begin_write()?;
+        self.sync_wbuf();
+        let ino = self.ino(&self.inner.lock());
+        let now = self.volume.timestamp();
+        let inode_size = self.volume.sb.inode_size as usize;
+        self.volume.transaction(&[], None, || {
+            let inode = self.volume.read_inode(ino)?;
+            let bytes = self.volume.allocated(&inode);
+            self.volume.charge(inode.uid, inode.gid, -bytes, -1);
+            self.volume.charge(uid, gid, bytes, 1);
+            self.volume.modify_inode(ino, |raw| {
+                set_u16(raw, I_UID.0, uid as u16);
+                set_u16(raw, I_UID.1, (uid >> 16) as u16);
+                set_u16(raw, I_GID.0, gid as u16);
+                set_u16(raw, I_GID.1, (gid >> 16) as u16);
+                set_time(raw, inode_size, I_CTIME, now);
+            })
+        })
+    }
+}
+
+/// The flags chattr may change, like EXT4_FL_USER_MODIFIABLE of Linux
+/// without the ones the shim doesn't honor.
+const EXT4_USER_MODIFIABLE: u32 = EXT4_IMMUTABLE_FL | EXT4_APPEND_FL | EXT4_NODUMP_FL;
+/// The flags lsattr shows, EXT4_FL_USER_VISIBLE.
+const EXT4_USER_VISIBLE: u32 = 0x705B_DFFF;
+
+/// The flags are i_flags of the inode.
+impl RenameINode for Ext4FileWrapper {
+    fn rename_to(
+        &self,
+        old: &str,
+        new_dir: &Arc<dyn INodeInterface>,
+        new: &str,
+        flags: RenameFlags,
+    ) -> VfsResult<()> {
+        trace::traced(
+            TraceOp::Rename,
+            "ext4",
+            || Target::Entry {
+                dir: self.traced_ino(),
+                name: old,
+            },
+            0,
+            0,
+            || {
+                for name in [old, new] {
+                    check_name(name)?;
+                    if name == "." || name == ".." {
+                        return Err(VfsError::InvalidInput);
+                    }
+                }
+                self.check_sealed()?;
+                check_same_dev(self, new_dir.as_ref())?;
+                // the inode of the wrapper, another node of the dev isn't
+                // a directory of this volume.
+                let new_ino = match new_dir.downcast_ref::<Ext4FileWrapper>() {
+                    Some(dir) if Arc::ptr_eq(&dir.volume, &self.volume) => dir.snapshot_ino,
+                    _ => return Err(VfsError::NotSupported),
+                };
+                let _write = self.volume.begin_dir_write()?;
+                let ino = self.ino(&self.inner.lock());
+                let mut open = self.volume.open.lock();
+                self.volume.transaction(&[ino, new_ino], None, || {
+                    self.volume.rename_entry(
+                        &mut open,
+                        ino,
+                        &name_to_bytes(old),
+                        new_ino,
+                        &name_to_bytes(new),
+                        flags,
+                    )
+                })
+            },
+        )
+    }
+}
+
+impl MknodINode for Ext4FileWrapper {
+    /// A device number beyond the 12 bits of the major and the 20 bits of
+    /// the minor of ext4 fails with InvalidInput.
+    fn mknod(
+        &self,
+        name: &str,
+        kind: NodeKind,
+        perm: u32,
+        rdev: u64,
+    ) -> VfsResult<Arc<dyn INodeInterface>> {
+        trace::traced(
+            TraceOp::Mknod,
+            "ext4",
+            || Target::Entry {
+                dir: self.traced_ino(),
+                name,
+            },
+            0,
+            0,
+            || {
+                check_str_name(name)?;
+                self.check_sealed()?;
+                let (major, minor) = split_dev(rdev);
+                let device = encode_device(major, minor).ok_or(VfsError::InvalidInput)?;
+                let _write = self.volume.begin_dir_write()?;
+                let dir_ino = self.ino(&self.inner.lock());
+                self.check_dir(dir_ino)?;
+                match self.find_entry(dir_ino, name) {
+                    Ok(_) => return Err(VfsError::AlreadyExists),
+                    Err(VfsError::FileNotFound) => {}
+                    Err(err) => return Err(err),
+                }
+                let ino = self.volume.transaction(&[dir_ino], None, || {
+                    self.volume.create_node(
+                        dir_ino,
+                        &name_to_bytes(name),
+                        kind,
+                        (perm & 0o7777) as u16,
+                        device,
+                    )
+                })?;
+                let mut ext4_file = Ext4File::new();
+                ext4_file.inode = ino as _;
+                let child = self.child(ext4_file, kind.file_type(), self.child_path(name));
+                Ok(child.into_arc() as Arc<dyn INodeInterface>)
+            },
+        )
+    }
+}
+
+impl SeekDir for Ext4FileWrapper {
+    fn read_dir_at(&self, pos: u64, max: usize) -> VfsResult<Vec<PosEntry>> {
+        self.check_sealed()?;
+        if self.inline.load(Ordering::Acquire) {
+            return Err(VfsError::NotSupported);
+        }
+        let listed = self.dir_entries_at(self.ino(&self.inner.lock()), pos, max)?;
+        Ok(listed.into_iter().map(|x| x.1).collect())
+    }
+
+    /// The inodes of the page are read together, each block of the inode
+    /// table once, after the buffered writes of the open ones.
+    fn read_dir_plus(&self, pos: u64, max: usize) -> VfsResult<Vec<PlusEntry>> {
+        self.check_sealed()?;
+        if self.inline.load(Ordering::Acquire) {
+            return Err(VfsError::NotSupported);
+        }
+        let listed = self.dir_entries_at(self.ino(&self.inner.lock()), pos, max)?;
+        let inos: Vec<u32> = listed.iter().map(|x| x.0).collect();
+        self.volume.sync_inodes(&inos);
+        let inodes = self.volume.read_inodes(&inos);
+        let plus = listed.into_iter().zip(inodes).map(|((ino, entry), inode)| {
+            let attrs = inode.map(|inode| EntryAttrs {
+                size: inode.size,
+                mode: stat_mode(entry.entry.file_type, Some(inode.mode as u32)),
+                nlink: inode.links_count as _,
+                mtime: time_spec(inode.mtime),
+                ino: ino as _,
+            });
+            PlusEntry { entry, attrs }
+        });
+        Ok(plus.collect())
+    }
+
+    /// The gate of the volume, a snapshot holds off the changes of every
+    /// directory.
+    fn lock_dir(&self) -> Option<ClosedGate> {
+        Some(self.volume.dir_gate.close())
+    }
+}
+
+impl FlagsINode for Ext4FileWrapper {
+    fn get_flags(&self) -> VfsResult<InodeFlags> {
+        let ino = self.ino(&self.inner.lock());
+        let flags = self.volume.read_inode(ino)?.flags;
+        Ok(InodeFlags::from_bits_retain(flags & EXT4_USER_VISIBLE))
+    }
+
+    /// Set the modifiable flags and the ctime, the buffered writes go to
+    /// the disk first.
+    fn set_flags(&self, flags: InodeFlags) -> VfsResult<()> {
+        let _write = self.volume.begin_write()?;
+        self.sync_wbuf();
+        let ino = self.ino(&self.inner.lock());
+        let now = self.volume.timestamp();
+        let inode_size = self.volume.sb.inode_size as usize;
+        self.volume.transaction(&[], None, || {
+            self.volume.modify_inode(ino, |raw| {
+                let old = le_u32(raw, I_FLAGS);
+                let new = (old & !EXT4_USER_MODIFIABLE) | (flags.bits() & EXT4_USER_MODIFIABLE);
+                set_u32(raw, I_FLAGS, new);
+                set_time(raw, inode_size, I_CTIME, now);
+            })
+        })
+    }
+}
+
+/// The inode flags and their statx attributes, the attributes_mask of
+/// ext4.
+const STATX_ATTRIBUTES: [(u32, u64); 6] = [
+    (EXT4_COMPR_FL, STATX_ATTR_COMPRESSED),
+    (EXT4_IMMUTABLE_FL, STATX_ATTR_IMMUTABLE),
+    (EXT4_APPEND_FL, STATX_ATTR_APPEND),
+    (EXT4_NODUMP_FL, STATX_ATTR_NODUMP),
+    (EXT4_ENCRYPT_FL, STATX_ATTR_ENCRYPTED),
+    (EXT4_VERITY_FL, STATX_ATTR_VERITY),
+];
+
+/// The stat with the inode number, the birth time of the large inodes
+/// and the attributes of the inode flags.
+impl StatxINode for Ext4FileWrapper {
+    fn statx(&self, _mask: u32, out: &mut Statx) -> VfsResult<()> {
+        let mut stat = vfscore::Stat::default();
+        self.stat(&mut stat)?;
+        *out = Statx::from_stat(&stat);
+        let ino = self.ino(&self.inner.lock());
+        let inode = self.volume.read_inode(ino)?;
+        out.ino = ino as u64;
+        if let Some(crtime) = inode.crtime {
+            out.btime = StatxTimestamp {
+                sec: crtime.sec,
+                nsec: crtime.nsec,
+                reserved: 0,
+            };
+            out.mask |= STATX_BTIME;
+        }
+        for (flag, attribute) in STATX_ATTRIBUTES {
+            out.attributes_mask |= attribute;
+            if inode.flags & flag != 0 {
+                out.attributes |= attribute;
+            }
+        }
         Ok(())
     }
 }
 
-pub fn map_ext4_type(value: DirEntryType) -> FileType {
-    match value {
-        DirEntryType::EXT4_DE_REG_FILE => FileType::File,
-        DirEntryType::EXT4_DE_DIR => FileType::Directory,
-        DirEntryType::EXT4_DE_CHRDEV => FileType::Device,
-        DirEntryType::EXT4_DE_BLKDEV => FileType::Device,
-        DirEntryType::EXT4_DE_SOCK => FileType::Socket,
-        DirEntryType::EXT4_DE_SYMLINK => FileType::Link,
-        _ => FileType::File,
+fn time_spec(time: Timestamp) -> TimeSpec {
+    TimeSpec {
+        sec: time.sec as _,
+        nsec: time.nsec as _,
+    }
+}
+
+/// The inode time of a TimeSpec, the nanoseconds beyond a second are
+/// saturated, set_time saturates the seconds.
+fn timestamp_of(time: &TimeSpec) -> Timestamp {
+    Timestamp {
+        sec: time.sec as _,
+        nsec: (time.nsec as u64).min(999_999_999) as u32,
+    }
+}
+
+/// The mode of stat by the type of the entry, the FIFOs and the devices
+/// by the i_mode of the inode.
+fn stat_mode(file_type: FileType, mode: Option<u32>) -> StatMode {
+    match mode.map(|x| x & 0xF000) {
+        Some(0x1000) => return StatMode::FIFO,
+        Some(0x2000) => return StatMode::CHAR,
+        _ => {}
+    }
+    match file_type {
+        FileType::File => StatMode::FILE,
+        FileType::Directory => StatMode::DIR,
+        FileType::Device => StatMode::BLOCK,
+        FileType::Socket => StatMode::SOCKET,
+        FileType::Link => StatMode::LINK,
+    }
+}
+
+/// Get the file type from the i_mode of the inode.
+pub(crate) fn mode_file_type(mode: u16) -> Option<FileType> {
+    match mode & 0xF000 {
+        0x4000 => Some(FileType::Directory),
+        0x8000 => Some(FileType::File),
+        0xA000 => Some(FileType::Link),
+        // vfscore has no FIFO type, stat and pipe::open_fifo tell them
+        // apart by the i_mode.
+        0x1000 | 0x2000 | 0x6000 => Some(FileType::Device),
+        0xC000 => Some(FileType::Socket),
+        _ => None,
+    }
+}
+
+/// The number of blocks of the directory.
+fn dir_blocks(sb: &SuperBlockInfo, dir: &InodeInfo) -> u32 {
+    (dir.size as usize).div_ceil(sb.block_size()) as u32
+}
+
+/// The end of the entries of the directory block, its checksum tail
+/// follows.
+pub(crate) fn entries_end(data: &[u8]) -> usize {
+    match has_dirent_tail(data) {
+        true => data.len() - DIRENT_TAIL_SIZE,
+        false => data.len(),
+    }
+}
+
+/// Map the errors of ext4_rs to vfs errors, every backend failure of the
+/// shim goes through it.
+fn map_errnum(errnum: Errnum) -> VfsError {
+    match errnum {
+        Errnum::ENOENT => VfsError::FileNotFound,
+        Errnum::EEXIST => VfsError::AlreadyExists,
+        Errnum::ENOTDIR => VfsError::NotDir,
+        Errnum::ENOTEMPTY => VfsError::DirectoryNotEmpty,
+        Errnum::EINVAL => VfsError::InvalidInput,
+        Errnum::ENOSPC | Errnum::EALLOCFIAL => VfsError::StorageFull,
+        _ => VfsError::Io,
+    }
+}
+
+/// Map the error of ext4_rs in the operation op on the path, the error
+/// is logged with the context.
+fn ext4_error(op: &'static str, path: &str) -> impl FnOnce(Ext4Error) -> VfsError + '_ {
+    move |e| log_context(map_errnum(e.error()).with_op(op).with_path(path))
+}
+
+/// Log the failure of the shim at debug, return its VfsError.
+fn log_context(err: VfsErrorContext) -> VfsError {
+    log::debug!("ext4: {:?}", err);
+    err.into()
+}
+
+/// Get the d_type from the file_type byte of the directory entry, None
+/// for 0 and the bytes out of range, see Ext4Volume::entry_types.
+fn dirent_d_type(file_type: u8) -> Option<u8> {
+    match file_type {
+        1 => Some(DT_REG),
+        2 => Some(DT_DIR),
+        3 => Some(DT_CHR),
+        4 => Some(DT_BLK),
+        5 => Some(DT_FIFO),
+        6 => Some(DT_SOCK),
+        7 => Some(DT_LNK),
+        _ => None,
+    }
+}
+
+/// Get the d_type of the i_mode, DT_UNKNOWN if it isn't a type.
+fn mode_d_type(mode: u16) -> u8 {
+    match mode & 0xF000 {
+        0x1000 => DT_FIFO,
+        0x2000 => DT_CHR,
+        0x4000 => DT_DIR,
+        0x6000 => DT_BLK,
+        0x8000 => DT_REG,
+        0xA000 => DT_LNK,
+        0xC000 => DT_SOCK,
+        _ => DT_UNKNOWN,
     }
 }
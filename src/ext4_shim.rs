@@ -1,23 +1,30 @@
 use core::iter::zip;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use alloc::{
-    ffi::CString,
     string::{String, ToString},
     sync::Arc,
     vec::Vec,
 };
-use devices::get_blk_device;
 use lwext4_rust::{
     bindings::{O_CREAT, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY},
     Ext4BlockWrapper, Ext4File, InodeTypes, KernelDevOp,
 };
-use sync::Mutex;
 use vfscore::{
     DirEntry, FileSystem, FileType, INodeInterface, Metadata, OpenFlags, StatFS, StatMode,
     TimeSpec, VfsError, VfsResult,
 };
 
+use crate::fstype::{self, FsType, MountSource};
+use crate::ops::{add_dot_entries, check_lookup_name, check_str_name, name_from_bytes, NAME_MAX};
+use crate::statfs::{next_fsid, EXT4_SUPER_MAGIC};
+use crate::sys::{get_blk_device, Mutex};
+
 const BLOCK_SIZE: usize = 0x200;
+/// The fsid of the mount, lwext4 mounts one filesystem and its nodes
+/// don't know it.
+/// TODO: derive it from the uuid like ext4_rs_shim.
+static FSID: AtomicU64 = AtomicU64::new(0);
 
 pub struct Ext4DiskWrapper {
     block_id: usize,
@@ -192,10 +199,25 @@ impl KernelDevOp for Ext4DiskWrapper {
     where
         Self: Sized,
     {
-        todo!()
+        // the block device writes through, nothing is cached here.
+        Ok(0)
     }
 }
 
+/// The ext4 type of fstype.rs, lwext4 mounts the whole sys devices only.
+pub(crate) const FSTYPE: FsType = FsType {
+    name: "ext4",
+    detect: Some(fstype::has_ext4_magic),
+    identify: Some(fstype::ext4_volume_id),
+    mount: |source, _| match source {
+        MountSource::Device(device_id) => {
+            Ok(Ext4FileSystem::new(device_id)? as Arc<dyn FileSystem>)
+        }
+        _ => Err(VfsError::NotSupported),
+    },
+    priority: 10,
+};
+
 pub struct Ext4FileSystem {
     _inner: Ext4BlockWrapper<Ext4DiskWrapper>,
     root: Arc<dyn INodeInterface>,
@@ -205,16 +227,21 @@ unsafe impl Sync for Ext4FileSystem {}
 unsafe impl Send for Ext4FileSystem {}
 
 impl Ext4FileSystem {
-    pub fn new(blk_id: usize) -> Arc<Self> {
+    /// Mount the ext4 volume of the block device, lwext4 failing to mount
+    /// it is an error.
+    pub fn new(blk_id: usize) -> VfsResult<Arc<Self>> {
         let disk = Ext4DiskWrapper::new(blk_id);
         info!("Got position:{}", disk.position());
-        let inner = Ext4BlockWrapper::<Ext4DiskWrapper>::new(disk)
-            .expect("failed to initialize EXT4 filesystem");
+        let inner = Ext4BlockWrapper::<Ext4DiskWrapper>::new(disk).map_err(|err| {
+            log::error!("ext4: can't mount the device {}: {}", blk_id, err);
+            map_ext4_err(err)
+        })?;
         let root = Arc::new(Ext4FileWrapper::new("/", InodeTypes::EXT4_DE_DIR));
-        Arc::new(Self {
+        FSID.store(next_fsid(), Ordering::Relaxed);
+        Ok(Arc::new(Self {
             _inner: inner,
             root,
-        })
+        }))
     }
 }
 
@@ -233,6 +260,12 @@ impl FileSystem for Ext4FileSystem {
     fn name(&self) -> &str {
         "ext4"
     }
+
+    fn flush(&self) -> VfsResult<()> {
+        // lwext4 writes the metadata back when the files are closed,
+        // every operation in the shim closes the file it opened.
+        Ok(())
+    }
 }
 
 pub struct Ext4FileWrapper {
@@ -352,10 +385,18 @@ impl INodeInterface for Ext4FileWrapper {
     }
 
     fn readat(&self, offset: usize, buffer: &mut [u8]) -> VfsResult<usize> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
         let mut file = self.inner.lock();
         let path = file.get_path();
         let path = path.to_str().unwrap();
         file.file_open(path, O_RDONLY).map_err(map_ext4_err)?;
+        // reading at or beyond the end isn't an error.
+        if offset as u64 >= file.file_size() as u64 {
+            let _ = file.file_close();
+            return Ok(0);
+        }
         file.file_seek(offset as _, 0).map_err(map_ext4_err)?;
         let rsize = file.file_read(buffer).map_err(map_ext4_err)?;
         let _ = file.file_close();
@@ -363,6 +404,9 @@ impl INodeInterface for Ext4FileWrapper {
     }
 
     fn writeat(&self, offset: usize, buffer: &[u8]) -> VfsResult<usize> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
         let mut file = self.inner.lock();
         let path = file.get_path();
         let path = path.to_str().unwrap();
@@ -374,6 +418,7 @@ impl INodeInterface for Ext4FileWrapper {
     }
 
     fn mkdir(&self, name: &str) -> VfsResult<Arc<dyn INodeInterface>> {
+        check_str_name(name)?;
         // let path = format!("{}/{}", self.inner.lock().get_path().to_str().unwrap(), name);
         let fpath = self.path_deal_with(&name);
         self.inner.lock().dir_mk(&fpath).map_err(map_ext4_err)?;
@@ -393,6 +438,7 @@ impl INodeInterface for Ext4FileWrapper {
     }
 
     fn touch(&self, name: &str) -> VfsResult<Arc<dyn INodeInterface>> {
+        check_str_name(name)?;
         let fpath = self.path_deal_with(name);
         info!("touch {fpath}");
         let mut file = self.inner.lock();
@@ -414,29 +460,26 @@ impl INodeInterface for Ext4FileWrapper {
             .map_err(map_ext4_err)?;
         let mut ans = Vec::new();
         for (name, file_type) in zip(iters.0, iters.1).skip(3) {
-            info!(
-                "iter once {} {:?}",
-                String::from_utf8(name.clone()).map_err(|_| VfsError::InvalidData)?,
-                file_type
-            );
+            // the names are NUL-terminated, the invalid UTF-8 is escaped.
+            let name = name_from_bytes(name.strip_suffix(&[0]).unwrap_or(&name));
+            info!("iter once {} {:?}", name, file_type);
             ans.push(DirEntry {
-                filename: CString::from_vec_with_nul(name)
-                    .map_err(|_| VfsError::InvalidData)?
-                    .to_str()
-                    .map_err(|_| VfsError::InvalidData)?
-                    .to_string(),
+                filename: name,
                 len: 0,
                 file_type: map_ext4_type(file_type),
             })
         }
+        add_dot_entries(&mut ans);
         Ok(ans)
     }
 
-    fn lookup(&self, _name: &str) -> VfsResult<Arc<dyn INodeInterface>> {
-        todo!("ext4 loopup")
+    fn lookup(&self, name: &str) -> VfsResult<Arc<dyn INodeInterface>> {
+        check_lookup_name(name)?;
+        self.open(name, OpenFlags::NONE)
     }
 
     fn open(&self, name: &str, flags: vfscore::OpenFlags) -> VfsResult<Arc<dyn INodeInterface>> {
+        check_str_name(name)?;
         info!("open file {name}");
         let fpath = self.path_deal_with(name);
         let mut file = self.inner.lock();
@@ -500,6 +543,7 @@ impl INodeInterface for Ext4FileWrapper {
     }
 
     fn unlink(&self, name: &str) -> VfsResult<()> {
+        check_str_name(name)?;
         info!("unlink {}", name);
         let fpath = self.path_deal_with(name);
         let mut file = self.inner.lock();
@@ -541,15 +585,16 @@ impl INodeInterface for Ext4FileWrapper {
     }
 
     fn statfs(&self, statfs: &mut StatFS) -> VfsResult<()> {
-        statfs.ftype = 32;
+        // TODO: read the block counts from the superblock.
+        statfs.ftype = EXT4_SUPER_MAGIC as _;
         statfs.bsize = 512;
         statfs.blocks = 80;
         statfs.bfree = 40;
         statfs.bavail = 0;
         statfs.files = 32;
         statfs.ffree = 0;
-        statfs.fsid = 32;
-        statfs.namelen = 20;
+        statfs.fsid = FSID.load(Ordering::Relaxed);
+        statfs.namelen = NAME_MAX as _;
         Ok(())
     }
 
@@ -0,0 +1,177 @@
+// The space of the files, like fallocate: punch_hole frees a range of a
+// file, which reads zeros after it and keeps its size, and allocate
+// gives a range its blocks so the writes in it don't fail with
+// StorageFull, growing the file unless keep_size. The nodes which do
+// them natively implement SpaceINode and hand it out by their FsNode,
+// like the nodes of direct.rs. The operations a node can't do fall back
+// by the policy table, per operation, to an emulation or to NotSupported,
+// EOPNOTSUPP, so the callers don't depend on the backend:
+// - punch_hole writes zeros over the range up to the size. The range
+//   reads zeros like after a punch, but its blocks stay allocated, the
+//   st_blocks of the file and the statfs of its filesystem still count
+//   them.
+// - allocate writes zeros from the size to the end of the range. The
+//   holes below the size stay holes, a write into them can still fail
+//   with StorageFull. A keep_size beyond the size can't be emulated, it
+//   fails with NotSupported.
+// support() tells how an operation is done on a node, and the
+// capabilities of capabilities.rs have PUNCH_HOLE and PREALLOCATE when
+// it's done natively or emulated.
+
+use core::cmp;
+
+use alloc::sync::Arc;
+use vfscore::{INodeInterface, Stat, VfsError, VfsResult};
+
+use crate::node;
+use crate::ops::check_range;
+use crate::sys::Mutex;
+
+/// The bytes of zeros written at once by the emulations.
+const ZERO_CHUNK: usize = 64 << 10;
+
+/// The operations on the space of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpaceOp {
+    /// FALLOC_FL_PUNCH_HOLE with FALLOC_FL_KEEP_SIZE.
+    PunchHole,
+    /// fallocate of mode 0, or FALLOC_FL_KEEP_SIZE.
+    Allocate,
+}
+
+impl SpaceOp {
+    pub const ALL: [SpaceOp; 2] = [SpaceOp::PunchHole, SpaceOp::Allocate];
+}
+
+/// What an operation a node can't do falls back to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fallback {
+    Emulate,
+    /// Fail with NotSupported.
+    Refuse,
+}
+
+/// How an operation is done on a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Support {
+    Native,
+    Emulated,
+    Unsupported,
+}
+
+pub trait SpaceINode: Send + Sync {
+    /// Native for the operations the node does, Unsupported for those
+    /// which fall back. A node passing them to another node, like
+    /// FileHandle, returns the support of that node.
+    fn support(&self, op: SpaceOp) -> Support;
+
+    /// Punch the hole, the range is checked and isn't empty.
+    fn punch_hole(&self, offset: usize, len: usize) -> VfsResult<()>;
+
+    /// Allocate the range, the range is checked and isn't empty.
+    fn allocate(&self, offset: usize, len: usize, keep_size: bool) -> VfsResult<()>;
+}
+
+/// The fallbacks of the operations, by their index in SpaceOp::ALL.
+static POLICY: Mutex<[Fallback; 2]> = Mutex::new([Fallback::Emulate, Fallback::Emulate]);
+
+fn node_of(file: &Arc<dyn INodeInterface>) -> Option<&dyn SpaceINode> {
+    node::fs_node(file.as_ref())?.as_space()
+}
+
+/// The fallback of the operation.
+pub fn policy(op: SpaceOp) -> Fallback {
+    POLICY.lock()[op as usize]
+}
+
+/// Set the fallback of the operation, return the previous one.
+pub fn set_policy(op: SpaceOp, fallback: Fallback) -> Fallback {
+    core::mem::replace(&mut POLICY.lock()[op as usize], fallback)
+}
+
+fn fallback(op: SpaceOp) -> Support {
+    match policy(op) {
+        Fallback::Emulate => Support::Emulated,
+        Fallback::Refuse => Support::Unsupported,
+    }
+}
+
+/// How the operation is done on the file.
+pub fn support(file: &Arc<dyn INodeInterface>, op: SpaceOp) -> Support {
+    match node_of(file).map(|x| x.support(op)) {
+        Some(Support::Unsupported) | None => fallback(op),
+        Some(support) => support,
+    }
+}
+
+/// The node doing the operation on the file, None if it falls back.
+fn doer(file: &Arc<dyn INodeInterface>, op: SpaceOp) -> Option<Arc<dyn SpaceINode>> {
+    node_of(file).filter(|x| x.support(op) != Support::Unsupported)
+}
+
+fn check(offset: usize, len: usize) -> VfsResult<()> {
+    if len == 0 {
+        return Err(VfsError::InvalidInput);
+    }
+    check_range(offset, len, u64::MAX)?;
+    Ok(())
+}
+
+fn file_size(file: &Arc<dyn INodeInterface>) -> VfsResult<usize> {
+    let mut stat = Stat::default();
+    file.stat(&mut stat)?;
+    Ok(stat.size as usize)
+}
+
+/// Write zeros over start..end of the file.
+fn write_zeros(file: &Arc<dyn INodeInterface>, start: usize, end: usize) -> VfsResult<()> {
+    let zeros = vec![0; cmp::min(ZERO_CHUNK, end.saturating_sub(start))];
+    let mut pos = start;
+    while pos < end {
+        let n = cmp::min(zeros.len(), end - pos);
+        match file.writeat(pos, &zeros[..n])? {
+            0 => return Err(VfsError::WriteZero),
+            written => pos += written,
+        }
+    }
+    Ok(())
+}
+
+/// Punch a hole of len bytes at offset in the file, see the module. The
+/// range beyond the size is ignored, an empty range fails with
+/// InvalidInput.
+pub fn punch_hole(file: &Arc<dyn INodeInterface>, offset: usize, len: usize) -> VfsResult<()> {
+    check(offset, len)?;
+    if let Some(node) = doer(file, SpaceOp::PunchHole) {
+        return node.punch_hole(offset, len);
+    }
+    if fallback(SpaceOp::PunchHole) == Support::Unsupported {
+        return Err(VfsError::NotSupported);
+    }
+    let end = cmp::min(offset + len, file_size(file)?);
+    write_zeros(file, offset, end)
+}
+
+/// Allocate len bytes at offset in the file, see the module. An empty
+/// range fails with InvalidInput.
+pub fn allocate(
+    file: &Arc<dyn INodeInterface>,
+    offset: usize,
+    len: usize,
+    keep_size: bool,
+) -> VfsResult<()> {
+    check(offset, len)?;
+    if let Some(node) = doer(file, SpaceOp::Allocate) {
+        return node.allocate(offset, len, keep_size);
+    }
+    if fallback(SpaceOp::Allocate) == Support::Unsupported {
+        return Err(VfsError::NotSupported);
+    }
+    let size = file_size(file)?;
+    let end = offset + len;
+    match end > size {
+        true if keep_size => Err(VfsError::NotSupported),
+        true => write_zeros(file, size, end),
+        false => Ok(()),
+    }
+}
@@ -0,0 +1,270 @@
+// The on-disk structures of FAT32 the shim reads itself: the fields of the
+// boot sector locating the FAT and the FSInfo sector, the FSInfo sector
+// and a scan of the FAT counting the free clusters. rust-fatfs keeps the
+// free count of FSInfo up to date while mounted, the shim checks the
+// sector before mounting since fatfs refuses a volume whose FSInfo has a
+// bad signature, where the spec only makes its counts unknown. The clean
+// bit of FAT[1] is the dirty state of the volume Windows checks, the shim
+// clears it while the volume is modified. The civil dates convert the FAT
+// dates to the Unix time and back.
+
+/// The free count or the next free cluster isn't known.
+pub const FS_INFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+const FS_INFO_LEAD_SIG: u32 = 0x4161_5252;
+const FS_INFO_STRUC_SIG: u32 = 0x6141_7272;
+const FS_INFO_TRAIL_SIG: u32 = 0xAA55_0000;
+const FSI_LEAD_SIG: usize = 0;
+const FSI_STRUC_SIG: usize = 484;
+const FSI_FREE_COUNT: usize = 488;
+const FSI_NXT_FREE: usize = 492;
+const FSI_TRAIL_SIG: usize = 508;
+/// The bytes of the FSInfo sector which are read, its size in a volume of
+/// 512 bytes sectors.
+pub const FS_INFO_SIZE: usize = 512;
+
+/// The bits of a FAT32 entry, the top 4 bits are reserved.
+const FAT32_MASK: u32 = 0x0FFF_FFFF;
+/// The first data cluster, 0 and 1 are reserved.
+pub const FIRST_CLUSTER: u32 = 2;
+/// The bit of FAT[1] set while the volume is clean, the other OSes check
+/// the volume if it's clear.
+const FAT32_CLEAN_SHUTDOWN: u32 = 0x0800_0000;
+/// The state byte of the FAT32 boot sector, Linux and fatfs set its bit 0
+/// while the volume is dirty.
+pub const BS_STATE: usize = 0x41;
+const STATE_DIRTY: u8 = 1;
+/// BS_BootSig of the FAT32 boot sector, BS_VolID and BS_VolLab follow it
+/// if it's EXTENDED_BOOT_SIG.
+const BS_BOOT_SIG: usize = 0x42;
+const EXTENDED_BOOT_SIG: u8 = 0x29;
+const BS_VOL_ID: usize = 0x43;
+const BS_VOL_LAB: usize = 0x47;
+/// The label of a volume without one.
+const NO_NAME: &[u8] = b"NO NAME";
+
+fn le_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn le_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn set_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// The fields of the BIOS parameter block of a FAT32 boot sector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bpb {
+    pub bytes_per_sector: u32,
+    pub sectors_per_cluster: u32,
+    pub reserved_sectors: u32,
+    pub fats: u32,
+    pub total_sectors: u32,
+    /// The sectors of each FAT.
+    pub fat_size: u32,
+    /// The sector of FSInfo, 0 or 0xFFFF if there is none.
+    pub fs_info_sector: u32,
+    /// The first cluster of the root directory.
+    pub root_cluster: u32,
+}
+
+impl Bpb {
+    /// Parse the boot sector, None if it isn't the one of a FAT32 volume.
+    pub fn parse(boot: &[u8]) -> Option<Self> {
+        if boot.len() < 512 || le_u16(boot, 510) != 0xAA55 {
+            return None;
+        }
+        let bpb = Self {
+            bytes_per_sector: le_u16(boot, 0x0B) as u32,
+            sectors_per_cluster: boot[0x0D] as u32,
+            reserved_sectors: le_u16(boot, 0x0E) as u32,
+            fats: boot[0x10] as u32,
+            total_sectors: match le_u16(boot, 0x13) {
+                0 => le_u32(boot, 0x20),
+                x => x as u32,
+            },
+            fat_size: le_u32(boot, 0x24),
+            root_cluster: le_u32(boot, 0x2C),
+            fs_info_sector: le_u16(boot, 0x30) as u32,
+        };
+        // FAT12 and FAT16 have the size of their FAT in BPB_FATSz16 and the
+        // entries of the root directory, neither has FSInfo.
+        let fat32 = le_u16(boot, 0x16) == 0 && le_u16(boot, 0x11) == 0;
+        let valid = fat32
+            && bpb.bytes_per_sector.is_power_of_two()
+            && (512..=4096).contains(&bpb.bytes_per_sector)
+            && bpb.sectors_per_cluster.is_power_of_two()
+            && bpb.fats > 0
+            && bpb.fat_size > 0;
+        valid.then_some(bpb)
+    }
+
+    /// The byte offset of the first FAT.
+    pub fn fat_offset(&self) -> usize {
+        self.reserved_sectors as usize * self.bytes_per_sector as usize
+    }
+
+    /// The byte offset of the data cluster.
+    #[cfg(feature = "testsuite")]
+    pub fn cluster_offset(&self, cluster: u32) -> usize {
+        let data = self.reserved_sectors as usize + (self.fats * self.fat_size) as usize;
+        let sector = data + (cluster - FIRST_CLUSTER) as usize * self.sectors_per_cluster as usize;
+        sector * self.bytes_per_sector as usize
+    }
+
+    /// The bytes of a cluster.
+    pub fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * self.bytes_per_sector as usize
+    }
+
+    /// The data clusters of the volume, the FAT has 2 more entries.
+    pub fn clusters(&self) -> u32 {
+        let meta = self.reserved_sectors + self.fats * self.fat_size;
+        let clusters = self.total_sectors.saturating_sub(meta) / self.sectors_per_cluster;
+        // the entries of the clusters must fit in the FAT.
+        let entries = self.fat_size as u64 * self.bytes_per_sector as u64 / 4;
+        clusters.min(entries.saturating_sub(FIRST_CLUSTER as u64) as u32)
+    }
+
+    /// The byte offsets of FAT[1] in every FAT.
+    pub fn fat1_offsets(&self) -> impl Iterator<Item = usize> {
+        let fat_len = self.fat_size as usize * self.bytes_per_sector as usize;
+        let first = self.fat_offset() + 4;
+        (0..self.fats as usize).map(move |x| first + x * fat_len)
+    }
+
+    /// The byte offset of FSInfo, None if the volume has none.
+    pub fn fs_info_offset(&self) -> Option<usize> {
+        match self.fs_info_sector {
+            0 | 0xFFFF => None,
+            x if x >= self.reserved_sectors => None,
+            x => Some(x as usize * self.bytes_per_sector as usize),
+        }
+    }
+}
+
+/// The counts of the FSInfo sector, they are hints: FS_INFO_UNKNOWN if
+/// they aren't known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsInfo {
+    pub free_count: u32,
+    pub next_free: u32,
+}
+
+impl FsInfo {
+    pub const UNKNOWN: FsInfo = FsInfo {
+        free_count: FS_INFO_UNKNOWN,
+        next_free: FS_INFO_UNKNOWN,
+    };
+
+    /// Parse the sector, None if its signatures are wrong. The counts out
+    /// of the range of the volume are unknown, like the spec says.
+    pub fn parse(sector: &[u8], clusters: u32) -> Option<Self> {
+        if sector.len() < FS_INFO_SIZE
+            || le_u32(sector, FSI_LEAD_SIG) != FS_INFO_LEAD_SIG
+            || le_u32(sector, FSI_STRUC_SIG) != FS_INFO_STRUC_SIG
+            || le_u32(sector, FSI_TRAIL_SIG) != FS_INFO_TRAIL_SIG
+        {
+            return None;
+        }
+        let free_count = match le_u32(sector, FSI_FREE_COUNT) {
+            x if x <= clusters => x,
+            _ => FS_INFO_UNKNOWN,
+        };
+        let next_free = match le_u32(sector, FSI_NXT_FREE) {
+            x if (FIRST_CLUSTER..clusters + FIRST_CLUSTER).contains(&x) => x,
+            _ => FS_INFO_UNKNOWN,
+        };
+        Some(Self {
+            free_count,
+            next_free,
+        })
+    }
+
+    /// Write the sector with its signatures, the reserved bytes are zeroed.
+    pub fn encode(&self, sector: &mut [u8]) {
+        sector[..FS_INFO_SIZE].fill(0);
+        set_u32(sector, FSI_LEAD_SIG, FS_INFO_LEAD_SIG);
+        set_u32(sector, FSI_STRUC_SIG, FS_INFO_STRUC_SIG);
+        set_u32(sector, FSI_FREE_COUNT, self.free_count);
+        set_u32(sector, FSI_NXT_FREE, self.next_free);
+        set_u32(sector, FSI_TRAIL_SIG, FS_INFO_TRAIL_SIG);
+    }
+}
+
+/// Whether the volume is marked dirty, by the state byte of the boot
+/// sector or the clean bit of FAT[1].
+pub fn is_dirty(state: u8, fat1: u32) -> bool {
+    state & STATE_DIRTY != 0 || fat1 & FAT32_CLEAN_SHUTDOWN == 0
+}
+
+/// The state byte and FAT[1] with the marks of a clean or a dirty volume.
+pub fn set_clean(state: u8, fat1: u32, clean: bool) -> (u8, u32) {
+    match clean {
+        true => (state & !STATE_DIRTY, fat1 | FAT32_CLEAN_SHUTDOWN),
+        false => (state | STATE_DIRTY, fat1 & !FAT32_CLEAN_SHUTDOWN),
+    }
+}
+
+/// The serial number and the label of the FAT32 boot sector, None if it
+/// has no extended boot signature. The label is without its padding, None
+/// if it's empty or NO NAME.
+pub fn volume_id(boot: &[u8]) -> Option<(u32, Option<&[u8]>)> {
+    if boot.len() < BS_VOL_LAB + 11 || boot[BS_BOOT_SIG] != EXTENDED_BOOT_SIG {
+        return None;
+    }
+    let label = &boot[BS_VOL_LAB..BS_VOL_LAB + 11];
+    let len = label
+        .iter()
+        .rposition(|x| *x != b' ' && *x != 0)
+        .map_or(0, |x| x + 1);
+    let label = &label[..len];
+    let label = (len > 0 && label != NO_NAME).then_some(label);
+    Some((le_u32(boot, BS_VOL_ID), label))
+}
+
+/// Count the free data clusters in the FAT, the bytes of the first FAT.
+#[cfg(feature = "testsuite")]
+pub fn count_free_clusters(fat: &[u8], clusters: u32) -> u32 {
+    let end = ((clusters + FIRST_CLUSTER) as usize * 4).min(fat.len());
+    fat.get(FIRST_CLUSTER as usize * 4..end)
+        .unwrap_or_default()
+        .chunks_exact(4)
+        .filter(|x| le_u32(x, 0) & FAT32_MASK == 0)
+        .count() as u32
+}
+
+/// The range of the FAT times in seconds since the Unix epoch, from
+/// 1980-01-01 to 2107-12-31 23:59:58.
+pub const FAT_MIN_TIME: i64 = 315_532_800;
+pub const FAT_MAX_TIME: i64 = 4_354_819_198;
+
+/// The days since 1970-01-01 of the date of the proleptic Gregorian
+/// calendar, month and day start at 1.
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The date of the days since 1970-01-01, (year, month, day).
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -1,19 +1,116 @@
 use core::cmp::{self, min};
 
+use crate::fat_layout::{
+    self, civil_from_days, days_from_civil, volume_id, Bpb, FsInfo, BS_STATE, FAT_MAX_TIME,
+    FAT_MIN_TIME, FS_INFO_SIZE, FS_INFO_UNKNOWN,
+};
+use crate::fstype::{FsType, MountSource, VolumeId};
+use crate::handle::AppendINode;
+use crate::mounts::MountFlags;
+use crate::node::FsNode;
+use crate::ops::{add_dot_entries, check_lookup_name, check_name, check_range, NAME_MAX};
+use crate::statfs::{next_fsid, MSDOS_SUPER_MAGIC};
+use crate::statx::{Statx, StatxINode, STATX_BTIME};
+use crate::sys::{get_blk_device, Mutex};
 use alloc::string::String;
 use alloc::sync::Arc;
-use devices::get_blk_device;
-use fatfs::{Dir, Error, File, LossyOemCpConverter, NullTimeProvider};
-use fatfs::{Read, Seek, SeekFrom, Write};
+use alloc::vec::Vec;
+use fatfs::{Date, DateTime, Dir, Error, File, FileAttributes, LossyOemCpConverter};
+use fatfs::{NullTimeProvider, Read, Seek, SeekFrom, Time, Write};
 use log::debug;
-use sync::Mutex;
 use vfscore::{
-    DirEntry, FileSystem, FileType, INodeInterface, Metadata, Stat, StatFS, StatMode, VfsError,
-    VfsResult,
+    DirEntry, FileSystem, FileType, INodeInterface, Metadata, Stat, StatFS, StatMode, TimeSpec,
+    VfsError, VfsResult, UTIME_NOW, UTIME_OMIT,
 };
 
+/// The size of a FAT file is 32 bits.
+const FAT_MAX_FILE_SIZE: u64 = u32::MAX as u64;
+/// FAT has no permission bits, the files are rwx for everyone and the
+/// read-only attribute takes the write bits.
+const FAT_MODE: u32 = 0o777;
+const FAT_WRITE_BITS: u32 = 0o222;
+
+/// The FAT32 type of fstype.rs, it detects the boot sector. The mounts
+/// are of the whole sys devices.
+pub(crate) const FSTYPE: FsType = FsType {
+    name: "vfat",
+    detect: Some(|source| {
+        let mut boot = [0; 512];
+        source.read_at(0, &mut boot).is_ok() && Bpb::parse(&boot).is_some()
+    }),
+    identify: Some(fat_volume_id),
+    mount: |source, flags| match source {
+        MountSource::Device(device_id) => {
+            let options = FatOptions {
+                read_only: flags.contains(MountFlags::RDONLY),
+                ..FatOptions::default()
+            };
+            Ok(Fat32FileSystem::with_options(device_id, options)? as Arc<dyn FileSystem>)
+        }
+        _ => Err(VfsError::NotSupported),
+    },
+    priority: 5,
+};
+
+/// The serial of the boot sector on the source as the uuid, formatted
+/// like blkid, and its label.
+fn fat_volume_id(source: &MountSource) -> VolumeId {
+    let mut boot = [0; 512];
+    if source.read_at(0, &mut boot).is_err() {
+        return VolumeId::default();
+    }
+    match volume_id(&boot) {
+        Some((serial, label)) => VolumeId {
+            uuid: Some(format!("{:04X}-{:04X}", serial >> 16, serial & 0xFFFF)),
+            label: label.map(|x| String::from_utf8_lossy(x).into_owned()),
+        },
+        None => VolumeId::default(),
+    }
+}
+
+/// The options of a FAT mount.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FatOptions {
+    /// The clock of the new times in seconds since the Unix epoch. fatfs
+    /// stamps its writes with 1980-01-01 without one.
+    pub time_source: Option<fn() -> u64>,
+    /// The modifications fail with NotSupported.
+    pub read_only: bool,
+    /// Mount read-only if the volume is dirty, left by another OS or a
+    /// crash without a clean unmount.
+    pub read_only_if_dirty: bool,
+}
+
+/// The dirty bit of the mount, see begin_write.
+struct DirtyState {
+    /// The volume is marked dirty on the disk.
+    marked: bool,
+    /// The running modifications.
+    writers: usize,
+}
+
 pub struct Fat32FileSystem {
     inner: fatfs::FileSystem<DiskCursor, NullTimeProvider, LossyOemCpConverter>,
+    device_id: usize,
+    /// The boot sector, None if it isn't of FAT32, the dirty bit is left
+    /// to fatfs then.
+    bpb: Option<Bpb>,
+    time_source: Option<fn() -> u64>,
+    read_only: bool,
+    /// The volume was dirty at the mount, it stays dirty.
+    was_dirty: bool,
+    dirty: Mutex<DirtyState>,
+    /// The volume id of FAT isn't unique, the fsid is of the mount.
+    fsid: u64,
+}
+
+/// A running modification of the volume, see begin_write.
+struct WriteGuard<'a>(&'a Fat32FileSystem);
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        self.0.dirty.lock().writers -= 1;
+    }
 }
 
 unsafe impl Send for Fat32FileSystem {}
@@ -29,45 +126,332 @@ impl FileSystem for Fat32FileSystem {
         Arc::new(FatDir {
             filename: String::from(""),
             inner: self.inner.root_dir(),
+            fs: self,
         })
     }
 
+    /// Write FSInfo back and mark the volume clean once no modification
+    /// is running. A volume which was dirty at the mount stays dirty, and
+    /// a sync without a modification since the last one writes nothing.
     fn flush(&self) -> VfsResult<()> {
-        self.inner.flush_fs_info().map_err(as_vfs_err)
+        // the state is held so no modification starts before the volume
+        // is marked clean.
+        let mut state = self.dirty.lock();
+        self.inner.flush_fs_info().map_err(as_vfs_err)?;
+        if state.marked && state.writers == 0 && !self.was_dirty {
+            self.set_clean(true)?;
+            state.marked = false;
+        }
+        Ok(())
     }
 }
 
 impl Fat32FileSystem {
-    pub fn new(device_id: usize) -> Arc<Self> {
-        let cursor: DiskCursor = DiskCursor {
-            sector: 0,
-            offset: 0,
-            device_id,
+    pub fn new(device_id: usize) -> VfsResult<Arc<Self>> {
+        Self::with_options(device_id, FatOptions::default())
+    }
+
+    /// Mount with a clock, the times of the writes and UTIME_NOW are its
+    /// time in seconds since the Unix epoch.
+    pub fn with_time_source(device_id: usize, now: fn() -> u64) -> VfsResult<Arc<Self>> {
+        let options = FatOptions {
+            time_source: Some(now),
+            ..FatOptions::default()
         };
-        let inner = fatfs::FileSystem::new(cursor, fatfs::FsOptions::new()).expect("open fs wrong");
+        Self::with_options(device_id, options)
+    }
+
+    /// Mount with the options, a volume which is dirty is reported by
+    /// was_dirty. A volume fatfs can't open is an error.
+    pub fn with_options(device_id: usize, options: FatOptions) -> VfsResult<Arc<Self>> {
+        let cursor = DiskCursor::new(device_id);
+        let scan = check_fs_info(device_id);
+        let bpb = read_bytes(device_id, 0, 512)
+            .ok()
+            .and_then(|x| Bpb::parse(&x));
+        let was_dirty = bpb.is_some_and(|bpb| is_dirty(device_id, &bpb));
+        let read_only = options.read_only || (was_dirty && options.read_only_if_dirty);
+        if was_dirty {
+            log::warn!(
+                "fat32: the volume wasn't unmounted cleanly, check it with fsck.fat{}",
+                if read_only { ", mounted read-only" } else { "" }
+            );
+        }
+        let inner = fatfs::FileSystem::new(cursor, fatfs::FsOptions::new()).map_err(|err| {
+            log::error!("fat32: can't open the device {}: {:?}", device_id, err);
+            as_vfs_err(err)
+        })?;
+        // the free count isn't known, count the free clusters now so the
+        // statfs calls take the count kept by fatfs.
+        if scan && let Err(err) = inner.stats() {
+            log::error!("fat32: can't count the free clusters: {:?}", err);
+        }
         log::warn!("init fs");
-        Arc::new(Self { inner })
+        Ok(Arc::new(Self {
+            inner,
+            device_id,
+            bpb,
+            time_source: options.time_source,
+            read_only,
+            was_dirty,
+            dirty: Mutex::new(DirtyState {
+                marked: was_dirty,
+                writers: 0,
+            }),
+            fsid: next_fsid(),
+        }))
+    }
+
+    /// The volume was dirty at the mount.
+    pub fn was_dirty(&self) -> bool {
+        self.was_dirty
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Start a modification: fail on a read-only mount, mark the volume
+    /// dirty before the first modification since it was marked clean.
+    /// The modification holds the guard until it's on the disk. The
+    /// read-only mount is NotSupported like on ext4, FAT doesn't remount,
+    /// so it isn't in mounts and the syscalls see EOPNOTSUPP.
+    fn begin_write(&self) -> VfsResult<WriteGuard<'_>> {
+        if self.read_only {
+            return Err(VfsError::NotSupported);
+        }
+        let mut state = self.dirty.lock();
+        if !state.marked {
+            self.set_clean(false)?;
+            state.marked = true;
+        }
+        state.writers += 1;
+        Ok(WriteGuard(self))
+    }
+
+    /// Mark the volume clean or dirty, in the state byte of the boot
+    /// sector and FAT[1] of every FAT. The state byte is the one fatfs
+    /// marks itself, it doesn't write it again once it marked it.
+    fn set_clean(&self, clean: bool) -> VfsResult<()> {
+        let Some(bpb) = self.bpb else {
+            return Ok(());
+        };
+        let state = read_bytes(self.device_id, BS_STATE, 1)?[0];
+        for offset in bpb.fat1_offsets() {
+            let fat1 = le_u32(&read_bytes(self.device_id, offset, 4)?);
+            let (_, fat1) = fat_layout::set_clean(state, fat1, clean);
+            write_bytes(self.device_id, offset, &fat1.to_le_bytes())?;
+        }
+        let (state, _) = fat_layout::set_clean(state, 0, clean);
+        write_bytes(self.device_id, BS_STATE, &[state])
+    }
+
+    /// The time of the clock as a FAT time, None without a clock.
+    fn now(&self) -> Option<i64> {
+        self.time_source.map(|now| now() as i64)
+    }
+
+    /// Fill the statfs with the cluster usage of the volume. The free
+    /// count is the one of FSInfo, kept up to date by fatfs as the
+    /// clusters are allocated and freed and written back by flush.
+    fn statfs(&self, statfs: &mut StatFS) -> VfsResult<()> {
+        let stats = self.inner.stats().map_err(as_vfs_err)?;
+        statfs.ftype = MSDOS_SUPER_MAGIC as _;
+        statfs.bsize = stats.cluster_size() as _;
+        statfs.blocks = stats.total_clusters() as _;
+        statfs.bfree = stats.free_clusters() as _;
+        statfs.bavail = stats.free_clusters() as _;
+        // FAT has no inode table, the files aren't limited.
+        statfs.files = 0;
+        statfs.ffree = 0;
+        statfs.fsid = self.fsid;
+        statfs.namelen = NAME_MAX as _;
+        Ok(())
     }
 }
 
 pub struct FatFileInner {
     inner: File<'static, DiskCursor, NullTimeProvider, LossyOemCpConverter>,
     size: usize,
+    /// The times and the read-only attribute of the directory entry, the
+    /// File of fatfs writes them but can't read them back.
+    times: FatTimes,
+    read_only: bool,
+}
+
+/// The times of a directory entry. FAT has the date of the last access
+/// only, the last modification in units of 2 seconds and the creation in
+/// units of 10 ms. The times are local times without a zone, the shim
+/// takes them as UTC.
+#[derive(Clone, Copy)]
+struct FatTimes {
+    created: DateTime,
+    accessed: Date,
+    modified: DateTime,
+}
+
+impl FatTimes {
+    /// The times of FAT_MIN_TIME, the ones of the new files of fatfs
+    /// without a clock.
+    fn epoch() -> Self {
+        Self::at(FAT_MIN_TIME)
+    }
+
+    fn at(sec: i64) -> Self {
+        let time = fat_date_time(sec);
+        Self {
+            created: time,
+            accessed: time.date,
+            modified: time,
+        }
+    }
+}
+
+/// The FAT time of the seconds since the Unix epoch, clamped to the range
+/// of FAT and rounded down to the 2 seconds of its field.
+fn fat_date_time(sec: i64) -> DateTime {
+    let sec = sec.clamp(FAT_MIN_TIME, FAT_MAX_TIME);
+    let sec = sec - sec % 2;
+    let (year, month, day) = civil_from_days(sec.div_euclid(86400));
+    let time = sec.rem_euclid(86400);
+    DateTime::new(
+        Date::new(year as u16, month as u16, day as u16),
+        Time::new(
+            (time / 3600) as u16,
+            (time / 60 % 60) as u16,
+            (time % 60) as u16,
+            0,
+        ),
+    )
+}
+
+/// The midnight of the date.
+fn date_spec(date: Date) -> TimeSpec {
+    date_time_spec(DateTime::new(date, Time::new(0, 0, 0, 0)))
+}
+
+fn date_time_spec(time: DateTime) -> TimeSpec {
+    let days = days_from_civil(
+        time.date.year as i64,
+        time.date.month as u32,
+        time.date.day as u32,
+    );
+    let sec = days * 86400
+        + time.time.hour as i64 * 3600
+        + time.time.min as i64 * 60
+        + time.time.sec as i64;
+    TimeSpec {
+        sec: sec as _,
+        nsec: (time.time.millis as u64 * 1_000_000) as _,
+    }
 }
 
 #[allow(dead_code)]
 pub struct FatFile {
     filename: String,
     inner: Mutex<FatFileInner>,
+    /// Held over an append, see AppendINode.
+    append: Mutex<()>,
+    fs: &'static Fat32FileSystem,
 }
 
 // TODO: impl Sync and send in safe way
 unsafe impl Sync for FatFile {}
 unsafe impl Send for FatFile {}
 
+impl FatFile {
+    /// The node of the file, with its birth time.
+    fn new(
+        filename: &str,
+        inner: FatFileInner,
+        fs: &'static Fat32FileSystem,
+    ) -> Arc<dyn INodeInterface> {
+        Arc::new(FatFile {
+            filename: String::from(filename),
+            inner: Mutex::new(inner),
+            append: Mutex::new(()),
+            fs,
+        })
+    }
+
+    /// Set the permission bits of mode. FAT keeps the write bits only, as
+    /// the read-only attribute, the other bits are ignored.
+    /// TODO: rust-fatfs can't set the attributes of an entry, a change of
+    /// the read-only attribute fails with NotSupported.
+    pub fn set_mode(&self, mode: u32) -> VfsResult<()> {
+        let read_only = mode & FAT_WRITE_BITS == 0;
+        match read_only == self.inner.lock().read_only {
+            true => Ok(()),
+            false => Err(VfsError::NotSupported),
+        }
+    }
+
+    /// FAT has no owners.
+    pub fn set_owner(&self, _uid: u32, _gid: u32) -> VfsResult<()> {
+        Err(VfsError::NotSupported)
+    }
+
+    /// Stamp the modification of a write. fatfs stamps it with the time
+    /// of NullTimeProvider, it's set to the clock if there is one.
+    fn modified(&self, inner: &mut FatFileInner) {
+        match self.fs.now() {
+            Some(now) => {
+                inner.times.modified = fat_date_time(now);
+                inner.inner.set_modified(inner.times.modified);
+            }
+            None => inner.times.modified = fat_date_time(FAT_MIN_TIME),
+        }
+    }
+
+    /// The read-only attribute fails the writes with InvalidInput, the
+    /// mode shows it without the write bits. Linux fails the open with
+    /// EACCES, there's no permission check of the opens here.
+    fn check_writable(inner: &FatFileInner) -> VfsResult<()> {
+        match inner.read_only {
+            true => Err(VfsError::InvalidInput),
+            false => Ok(()),
+        }
+    }
+}
+
+impl FsNode for FatFile {
+    fn as_statx(&self) -> Option<&dyn StatxINode> {
+        Some(self)
+    }
+
+    fn as_append(&self) -> Option<&dyn AppendINode> {
+        Some(self)
+    }
+}
+
+/// The appends of the opens of the node, the other nodes of the file have
+/// sizes of their own.
+impl AppendINode for FatFile {
+    fn append(
+        &self,
+        write: &mut dyn FnMut(usize) -> VfsResult<usize>,
+    ) -> VfsResult<(usize, usize)> {
+        let _append = self.append.lock();
+        let end = self.inner.lock().size;
+        Ok((end, write(end)?))
+    }
+}
+
+impl StatxINode for FatFile {
+    fn statx(&self, _mask: u32, out: &mut Statx) -> VfsResult<()> {
+        let mut stat = Stat::default();
+        self.stat(&mut stat)?;
+        *out = Statx::from_stat(&stat);
+        out.btime = date_time_spec(self.inner.lock().times.created).into();
+        out.mask |= STATX_BTIME;
+        Ok(())
+    }
+}
+
 pub struct FatDir {
     filename: String,
     inner: Dir<'static, DiskCursor, NullTimeProvider, LossyOemCpConverter>,
+    fs: &'static Fat32FileSystem,
 }
 
 // TODO: impl Sync and send in safe way
@@ -76,6 +460,10 @@ unsafe impl Send for FatDir {}
 
 impl INodeInterface for FatFile {
     fn readat(&self, offset: usize, buffer: &mut [u8]) -> VfsResult<usize> {
+        check_range(offset, buffer.len(), u64::MAX)?;
+        if buffer.is_empty() {
+            return Ok(0);
+        }
         let mut inner = self.inner.lock();
 
         if offset >= inner.size {
@@ -99,7 +487,13 @@ impl INodeInterface for FatFile {
     }
 
     fn writeat(&self, offset: usize, buffer: &[u8]) -> VfsResult<usize> {
+        check_range(offset, buffer.len(), FAT_MAX_FILE_SIZE)?;
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let _write = self.fs.begin_write()?;
         let mut inner = self.inner.lock();
+        Self::check_writable(&inner)?;
 
         // if offset > len
         let seek_curr = SeekFrom::Start(offset as _);
@@ -122,6 +516,9 @@ impl INodeInterface for FatFile {
         if offset + buffer.len() > inner.size {
             inner.size = offset + buffer.len();
         }
+        self.modified(&mut inner);
+        // the entry gets the size before the volume can be marked clean.
+        inner.inner.flush().map_err(as_vfs_err)?;
         Ok(buffer.len())
     }
 
@@ -142,68 +539,126 @@ impl INodeInterface for FatFile {
     }
 
     fn truncate(&self, size: usize) -> VfsResult<()> {
-        self.inner
-            .lock()
+        check_range(size, 0, FAT_MAX_FILE_SIZE)?;
+        let _write = self.fs.begin_write()?;
+        let mut inner = self.inner.lock();
+        Self::check_writable(&inner)?;
+        inner
             .inner
             .seek(SeekFrom::Start(size as u64))
             .map_err(as_vfs_err)?;
-        self.inner.lock().inner.truncate().map_err(as_vfs_err)
+        inner.inner.truncate().map_err(as_vfs_err)?;
+        self.modified(&mut inner);
+        inner.inner.flush().map_err(as_vfs_err)
     }
 
+    fn statfs(&self, statfs: &mut StatFS) -> VfsResult<()> {
+        self.fs.statfs(statfs)
+    }
+
+    /// The mode is FAT_MODE without the write bits if the file is
+    /// read-only. The access time is the midnight of the access date, and
+    /// FAT has no change time, it's the modification time.
     fn stat(&self, stat: &mut Stat) -> VfsResult<()> {
+        let inner = self.inner.lock();
+        let perm = match inner.read_only {
+            true => FAT_MODE & !FAT_WRITE_BITS,
+            false => FAT_MODE,
+        };
         stat.ino = 1; // TODO: convert path to number(ino)
-        stat.mode = StatMode::FILE; // TODO: add access mode
+        stat.mode = StatMode::FILE | StatMode::from_bits_truncate(perm);
         stat.nlink = 1;
         stat.uid = 0;
         stat.gid = 0;
-        stat.size = self.metadata().unwrap().size as u64;
+        stat.size = inner.size as u64;
         stat.blksize = 512;
-        stat.blocks = self.metadata().unwrap().size as u64 / 512;
+        stat.blocks = inner.size as u64 / 512;
         stat.rdev = 0; // TODO: add device id
-                       // TODO: add A/M/C time
-        stat.atime.nsec = 0;
-        stat.atime.sec = 0;
-        stat.ctime.nsec = 0;
-        stat.ctime.sec = 0;
-        stat.mtime.nsec = 0;
-        stat.mtime.sec = 0;
+        stat.atime = date_spec(inner.times.accessed);
+        stat.mtime = date_time_spec(inner.times.modified);
+        stat.ctime = stat.mtime;
         Ok(())
     }
+
+    /// Set the access and the modification times, UTIME_NOW sets one to
+    /// the clock and UTIME_OMIT keeps it. The access time is rounded down
+    /// to its date and the modification time to 2 seconds, the times out
+    /// of the range of FAT are clamped. UTIME_NOW fails with NotSupported
+    /// without a clock.
+    fn utimes(&self, times: &mut [TimeSpec]) -> VfsResult<()> {
+        let _write = self.fs.begin_write()?;
+        let mut inner = self.inner.lock();
+        for (i, time) in times.iter().take(2).enumerate() {
+            let sec = match time.nsec {
+                UTIME_OMIT => continue,
+                UTIME_NOW => self.fs.now().ok_or(VfsError::NotSupported)?,
+                _ => time.sec as i64,
+            };
+            let time = fat_date_time(sec);
+            if i == 0 {
+                inner.times.accessed = time.date;
+                inner.inner.set_accessed(time.date);
+            } else {
+                inner.times.modified = time;
+                inner.inner.set_modified(time);
+            }
+        }
+        inner.inner.flush().map_err(as_vfs_err)
+    }
 }
 
 impl INodeInterface for FatDir {
     fn mkdir(&self, name: &str) -> VfsResult<Arc<dyn INodeInterface>> {
+        check_name(name)?;
+        let _write = self.fs.begin_write()?;
         self.inner
             .create_dir(name)
             .map(|dir| -> Arc<dyn INodeInterface> {
                 Arc::new(FatDir {
                     filename: String::from(name),
                     inner: dir,
+                    fs: self.fs,
                 })
             })
             .map_err(as_vfs_err)
     }
 
     fn touch(&self, name: &str) -> VfsResult<Arc<dyn INodeInterface>> {
+        check_name(name)?;
+        let _write = self.fs.begin_write()?;
         self.inner
             .create_file(name)
-            .map(|file| -> Arc<dyn INodeInterface> {
-                Arc::new(FatFile {
-                    filename: String::from(name),
-                    inner: Mutex::new(FatFileInner {
-                        inner: file,
-                        size: 0,
-                    }),
-                })
+            .map(|mut file| {
+                // fatfs stamps the new entry with 1980-01-01 without a
+                // clock.
+                let times = match self.fs.now() {
+                    Some(now) => {
+                        let times = FatTimes::at(now);
+                        file.set_created(times.created);
+                        file.set_accessed(times.accessed);
+                        file.set_modified(times.modified);
+                        times
+                    }
+                    None => FatTimes::epoch(),
+                };
+                let inner = FatFileInner {
+                    inner: file,
+                    size: 0,
+                    times,
+                    read_only: false,
+                };
+                FatFile::new(name, inner, self.fs)
             })
             .map_err(as_vfs_err)
     }
 
-    fn lookup(&self, _name: &str) -> VfsResult<Arc<dyn INodeInterface>> {
-        todo!()
+    fn lookup(&self, name: &str) -> VfsResult<Arc<dyn INodeInterface>> {
+        check_lookup_name(name)?;
+        self.open(name, vfscore::OpenFlags::NONE)
     }
 
     fn open(&self, name: &str, _flags: vfscore::OpenFlags) -> VfsResult<Arc<dyn INodeInterface>> {
+        check_name(name)?;
         let file = self
             .inner
             .iter()
@@ -213,30 +668,39 @@ impl INodeInterface for FatDir {
             Ok(Arc::new(FatDir {
                 filename: String::from(name),
                 inner: file.to_dir(),
+                fs: self.fs,
             }))
         } else if file.is_file() {
-            Ok(Arc::new(FatFile {
-                filename: String::from(name),
-                inner: Mutex::new(FatFileInner {
-                    inner: file.to_file(),
-                    size: file.len() as usize,
-                }),
-            }))
+            let inner = FatFileInner {
+                inner: file.to_file(),
+                size: file.len() as usize,
+                times: FatTimes {
+                    created: file.created(),
+                    accessed: file.accessed(),
+                    modified: file.modified(),
+                },
+                read_only: file.attributes().contains(FileAttributes::READ_ONLY),
+            };
+            Ok(FatFile::new(name, inner, self.fs))
         } else {
             unreachable!()
         }
     }
 
     fn rmdir(&self, name: &str) -> VfsResult<()> {
+        let _write = self.fs.begin_write()?;
         self.inner.remove(name).map_err(as_vfs_err)
     }
 
     fn remove(&self, name: &str) -> VfsResult<()> {
+        let _write = self.fs.begin_write()?;
         self.inner.remove(name).map_err(as_vfs_err)
     }
 
     fn read_dir(&self) -> VfsResult<alloc::vec::Vec<vfscore::DirEntry>> {
-        Ok(self
+        // the root directory of FAT has no "." and "..", they are added to
+        // every listing.
+        let mut entries: alloc::vec::Vec<_> = self
             .inner
             .iter()
             .filter_map(|x| {
@@ -259,7 +723,9 @@ impl INodeInterface for FatDir {
                     file_type,
                 })
             })
-            .collect())
+            .collect();
+        add_dot_entries(&mut entries);
+        Ok(entries)
     }
 
     fn metadata(&self) -> VfsResult<vfscore::Metadata> {
@@ -292,16 +758,7 @@ impl INodeInterface for FatDir {
     }
 
     fn statfs(&self, statfs: &mut StatFS) -> VfsResult<()> {
-        statfs.ftype = 32;
-        statfs.bsize = 512;
-        statfs.blocks = 80;
-        statfs.bfree = 40;
-        statfs.bavail = 0;
-        statfs.files = 32;
-        statfs.ffree = 0;
-        statfs.fsid = 32;
-        statfs.namelen = 20;
-        Ok(())
+        self.fs.statfs(statfs)
     }
 
     fn link(&self, _name: &str, _src: Arc<dyn INodeInterface>) -> VfsResult<()> {
@@ -349,6 +806,90 @@ pub const fn as_vfs_err(err: Error<()>) -> vfscore::VfsError {
     }
 }
 
+/// Check the FSInfo sector before fatfs reads it. fatfs refuses a sector
+/// with a bad signature, it's rewritten with the counts unknown, like the
+/// spec says they are. return whether the free count isn't known, so the
+/// FAT must be scanned.
+fn check_fs_info(device_id: usize) -> bool {
+    let Ok(boot) = read_bytes(device_id, 0, 512) else {
+        return true;
+    };
+    // fatfs reports the volumes which aren't FAT32.
+    let Some(bpb) = Bpb::parse(&boot) else {
+        return true;
+    };
+    let Some(offset) = bpb.fs_info_offset() else {
+        return true;
+    };
+    let Ok(mut sector) = read_bytes(device_id, offset, FS_INFO_SIZE) else {
+        return true;
+    };
+    match FsInfo::parse(&sector, bpb.clusters()) {
+        Some(info) => info.free_count == FS_INFO_UNKNOWN,
+        None => {
+            log::warn!("fat32: bad FSInfo signature, the free clusters are counted");
+            FsInfo::UNKNOWN.encode(&mut sector);
+            if write_bytes(device_id, offset, &sector).is_err() {
+                log::error!("fat32: can't rewrite the FSInfo sector");
+            }
+            true
+        }
+    }
+}
+
+/// Whether the volume is marked dirty by its boot sector or its first
+/// FAT.
+fn is_dirty(device_id: usize, bpb: &Bpb) -> bool {
+    let Some(offset) = bpb.fat1_offsets().next() else {
+        return false;
+    };
+    match (
+        read_bytes(device_id, BS_STATE, 1),
+        read_bytes(device_id, offset, 4),
+    ) {
+        (Ok(state), Ok(fat1)) => fat_layout::is_dirty(state[0], le_u32(&fat1)),
+        _ => false,
+    }
+}
+
+fn le_u32(data: &[u8]) -> u32 {
+    u32::from_le_bytes(data[..4].try_into().unwrap())
+}
+
+/// Read len bytes at the byte offset of the device.
+pub(crate) fn read_bytes(device_id: usize, offset: usize, len: usize) -> VfsResult<Vec<u8>> {
+    let mut cursor = DiskCursor::new(device_id);
+    let mut buf = vec![0; len];
+    cursor
+        .seek(SeekFrom::Start(offset as u64))
+        .map_err(|_| VfsError::Io)?;
+    // the cursor reads a sector at most per call.
+    let mut pos = 0;
+    while pos < len {
+        match cursor.read(&mut buf[pos..]) {
+            Ok(0) | Err(_) => return Err(VfsError::Io),
+            Ok(n) => pos += n,
+        }
+    }
+    Ok(buf)
+}
+
+/// Write the bytes at the byte offset of the device.
+pub(crate) fn write_bytes(device_id: usize, offset: usize, buf: &[u8]) -> VfsResult<()> {
+    let mut cursor = DiskCursor::new(device_id);
+    cursor
+        .seek(SeekFrom::Start(offset as u64))
+        .map_err(|_| VfsError::Io)?;
+    let mut pos = 0;
+    while pos < buf.len() {
+        match cursor.write(&buf[pos..]) {
+            Ok(0) | Err(_) => return Err(VfsError::Io),
+            Ok(n) => pos += n,
+        }
+    }
+    Ok(())
+}
+
 pub struct DiskCursor {
     sector: u64,
     offset: usize,
@@ -359,6 +900,14 @@ unsafe impl Sync for DiskCursor {}
 unsafe impl Send for DiskCursor {}
 
 impl DiskCursor {
+    fn new(device_id: usize) -> Self {
+        Self {
+            sector: 0,
+            offset: 0,
+            device_id,
+        }
+    }
+
     fn get_position(&self) -> usize {
         (self.sector * 0x200) as usize + self.offset
     }
@@ -0,0 +1,192 @@
+// Freezing a filesystem for a block level snapshot of its disk, like
+// FIFREEZE and FITHAW. A frozen filesystem has written back everything
+// and its disk stays consistent until it's thawed: the mutating
+// operations wait at the FreezeGate of the filesystem, the reads go on.
+// FileSystem of vfscore has no freeze, so the filesystems which can be
+// frozen register a Freeze with their FileSystem, like the Volume of
+// volume.rs. The waits call the wait hook of the pipes, see
+// pipe::set_wait_hook, and spin without one.
+// A gate is also the lock of the entries of a directory, see
+// readdir::SeekDir::lock_dir: the changes of the entries enter it and a
+// snapshot of the directory closes it.
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use vfscore::{FileSystem, VfsError, VfsResult};
+
+use crate::error::{Errno, FsError, FsResult};
+use crate::pipe::wait_hook;
+use crate::sys::Mutex;
+
+/// The gate of the mutating operations, they enter it like the readers
+/// of a RwLock and freeze takes it like the writer.
+pub struct FreezeGate {
+    state: Mutex<GateState>,
+}
+
+struct GateState {
+    /// The mutating operations inside the gate.
+    active: usize,
+    frozen: bool,
+}
+
+/// A mutating operation inside the gate, it leaves when it's dropped.
+pub struct GateGuard<'a>(&'a FreezeGate);
+
+/// The gate closed by close, it's opened when it's dropped.
+pub struct ClosedGate(Arc<FreezeGate>);
+
+fn relax() {
+    match wait_hook() {
+        Some(hook) => hook(),
+        None => core::hint::spin_loop(),
+    }
+}
+
+impl FreezeGate {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(GateState {
+                active: 0,
+                frozen: false,
+            }),
+        }
+    }
+
+    /// Enter a mutating operation, wait while the gate is frozen. It must
+    /// be entered before the locks of the operation, a frozen gate waits
+    /// with them held otherwise.
+    pub fn enter(&self) -> GateGuard<'_> {
+        loop {
+            {
+                let mut state = self.state.lock();
+                if !state.frozen {
+                    state.active += 1;
+                    return GateGuard(self);
+                }
+            }
+            relax();
+        }
+    }
+
+    /// Enter a mutating operation which is skipped while the gate is
+    /// frozen, like the update of an access time, None if it's frozen.
+    pub fn try_enter(&self) -> Option<GateGuard<'_>> {
+        let mut state = self.state.lock();
+        if state.frozen {
+            return None;
+        }
+        state.active += 1;
+        Some(GateGuard(self))
+    }
+
+    /// Close the gate and wait for the operations inside to leave. It
+    /// fails with InvalidInput and EBUSY if the gate is frozen already.
+    pub fn freeze(&self) -> FsResult<()> {
+        {
+            let mut state = self.state.lock();
+            if state.frozen {
+                return Err(FsError::new(VfsError::InvalidInput, Errno::EBUSY));
+            }
+            state.frozen = true;
+        }
+        while self.state.lock().active > 0 {
+            relax();
+        }
+        Ok(())
+    }
+
+    /// Close the gate while the guard lives, like the writer of a RwLock:
+    /// wait for another close or a freeze to end, then for the operations
+    /// inside to leave.
+    pub fn close(self: &Arc<Self>) -> ClosedGate {
+        loop {
+            {
+                let mut state = self.state.lock();
+                if !state.frozen {
+                    state.frozen = true;
+                    break;
+                }
+            }
+            relax();
+        }
+        while self.state.lock().active > 0 {
+            relax();
+        }
+        ClosedGate(self.clone())
+    }
+
+    /// Open the gate, the waiting operations go on. It fails with
+    /// InvalidInput if the gate isn't frozen.
+    pub fn thaw(&self) -> VfsResult<()> {
+        let mut state = self.state.lock();
+        if !state.frozen {
+            return Err(VfsError::InvalidInput);
+        }
+        state.frozen = false;
+        Ok(())
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.state.lock().frozen
+    }
+}
+
+impl Default for FreezeGate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for GateGuard<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().active -= 1;
+    }
+}
+
+impl Drop for ClosedGate {
+    fn drop(&mut self) {
+        self.0.state.lock().frozen = false;
+    }
+}
+
+/// The freeze of a filesystem.
+pub trait Freeze: Send + Sync {
+    /// Wait for the mutating operations to finish and hold off the new
+    /// ones, then write back the buffered data and the metadata.
+    fn freeze(&self) -> FsResult<()>;
+
+    /// Let the mutating operations go on.
+    fn thaw(&self) -> VfsResult<()>;
+}
+
+/// The registered filesystems, the dropped ones are removed lazily.
+static FREEZES: Mutex<Vec<(Weak<dyn FileSystem>, Weak<dyn Freeze>)>> = Mutex::new(Vec::new());
+
+/// Register the freeze of the filesystem.
+pub fn register(fs: Weak<dyn FileSystem>, freeze: Weak<dyn Freeze>) {
+    let mut freezes = FREEZES.lock();
+    freezes.retain(|(fs, freeze)| fs.strong_count() > 0 && freeze.strong_count() > 0);
+    freezes.push((fs, freeze));
+}
+
+fn freeze_of(fs: &Arc<dyn FileSystem>) -> VfsResult<Arc<dyn Freeze>> {
+    FREEZES
+        .lock()
+        .iter()
+        .find(|(x, _)| core::ptr::addr_eq(x.as_ptr(), Arc::as_ptr(fs)))
+        .and_then(|(_, freeze)| freeze.upgrade())
+        .ok_or(VfsError::NotSupported)
+}
+
+/// Freeze the filesystem, NotSupported if it can't be frozen.
+pub fn freeze(fs: &Arc<dyn FileSystem>) -> FsResult<()> {
+    freeze_of(fs)?.freeze()
+}
+
+/// Thaw the filesystem frozen by freeze.
+pub fn thaw(fs: &Arc<dyn FileSystem>) -> VfsResult<()> {
+    freeze_of(fs)?.thaw()
+}
@@ -0,0 +1,390 @@
+// The types of the filesystems and their mounts. Every enabled shim
+// registers an FsType at init, with the detection of its images and the
+// constructor of its mount, so a new filesystem is one registration and
+// not an edit of the boot, the detection and the mount helper. The types
+// in memory, like tmpfs, detect nothing and mount MountSource::None.
+// mount_auto asks the types with a detect in their priority order, the
+// first one which knows the image mounts it. The registry is rendered as
+// /proc/filesystems, the types without a device are "nodev" like Linux.
+// The types with a device read the uuid and the label of their images
+// without a mount too, like blkid, so resolve_source finds the device of
+// a UUID= or LABEL= spec among the sys devices and their partitions.
+
+use core::fmt::Write;
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use vfscore::{FileSystem, INodeInterface, OpenFlags, VfsError, VfsResult};
+
+use crate::dentry::{dentry_open, dentry_root, DentryNode};
+use crate::devnode::BlockNode;
+use crate::error::{Errno, FsError, FsResult};
+use crate::mounts::MountFlags;
+use crate::pseudo::{CallbackInode, SizeMode};
+use crate::sys::{get_blk_device, get_blk_devices, Mutex};
+
+/// The size of the sectors of the devices of sys.
+const SECTOR_SIZE: usize = 512;
+
+/// What a filesystem is mounted from.
+#[derive(Clone)]
+pub enum MountSource {
+    /// The sys device device_id, a whole disk.
+    Device(usize),
+    /// The size bytes at the byte offset start of the sys device
+    /// device_id, like a partition of the MBR.
+    Partition {
+        device_id: usize,
+        start: usize,
+        size: usize,
+    },
+    /// A file, like a loop device.
+    File(Arc<dyn INodeInterface>),
+    /// Nothing, the filesystems in memory.
+    None,
+}
+
+impl MountSource {
+    /// Read buf at the byte offset of the source, for the detections. The
+    /// bytes beyond the end of a partition or a file read as zeros, and
+    /// MountSource::None and a missing device fail with InvalidInput.
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> VfsResult<()> {
+        buf.fill(0);
+        let (device_id, offset, len) = match self {
+            Self::Device(device_id) => (*device_id, offset, buf.len()),
+            Self::Partition {
+                device_id,
+                start,
+                size,
+            } => (
+                *device_id,
+                start + offset,
+                buf.len().min(size.saturating_sub(offset)),
+            ),
+            Self::File(file) => {
+                let mut pos = 0;
+                while pos < buf.len() {
+                    match file.readat(offset + pos, &mut buf[pos..])? {
+                        0 => break,
+                        n => pos += n,
+                    }
+                }
+                return Ok(());
+            }
+            Self::None => return Err(VfsError::InvalidInput),
+        };
+        let device = get_blk_device(device_id).ok_or(VfsError::InvalidInput)?;
+        if len == 0 {
+            return Ok(());
+        }
+        // the sectors of the bytes, read whole.
+        let first = offset / SECTOR_SIZE;
+        let skip = offset % SECTOR_SIZE;
+        let mut sectors = vec![0; (skip + len).div_ceil(SECTOR_SIZE) * SECTOR_SIZE];
+        device.read_blocks(first, &mut sectors);
+        buf[..len].copy_from_slice(&sectors[skip..skip + len]);
+        Ok(())
+    }
+}
+
+/// The identity of an image read from its source, without a mount.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VolumeId {
+    /// Formatted like blkid, the 8-4-4-4-12 hex digits of ext4 or the
+    /// XXXX-XXXX serial of FAT.
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+}
+
+/// A type of filesystem.
+#[derive(Clone, Copy)]
+pub struct FsType {
+    /// The name of mount -t, unique among the types.
+    pub name: &'static str,
+    /// Whether the source holds an image of the type, None for the types
+    /// without a device.
+    pub detect: Option<fn(&MountSource) -> bool>,
+    /// The uuid and the label of the image on a source it detects, None
+    /// for the types without a device.
+    pub identify: Option<fn(&MountSource) -> VolumeId>,
+    /// Mount the source with the flags of the mount.
+    pub mount: fn(MountSource, MountFlags) -> VfsResult<Arc<dyn FileSystem>>,
+    /// The types with a higher priority are detected first.
+    pub priority: i32,
+}
+
+/// The registered types in their priority order, then their registration
+/// order.
+static FSTYPES: Mutex<Vec<FsType>> = Mutex::new(Vec::new());
+
+/// Register the type, a type with the same name fails with AlreadyExists.
+pub fn register_fstype(fstype: FsType) -> VfsResult<()> {
+    let mut fstypes = FSTYPES.lock();
+    if fstypes.iter().any(|x| x.name == fstype.name) {
+        return Err(VfsError::AlreadyExists);
+    }
+    let at = fstypes
+        .iter()
+        .position(|x| x.priority < fstype.priority)
+        .unwrap_or(fstypes.len());
+    fstypes.insert(at, fstype);
+    Ok(())
+}
+
+/// Unregister the type of the name, false if there is none.
+pub fn unregister_fstype(name: &str) -> bool {
+    let mut fstypes = FSTYPES.lock();
+    let len = fstypes.len();
+    fstypes.retain(|x| x.name != name);
+    fstypes.len() != len
+}
+
+/// The registered types in their priority order.
+pub fn fstypes() -> Vec<FsType> {
+    FSTYPES.lock().clone()
+}
+
+pub fn fstype(name: &str) -> Option<FsType> {
+    FSTYPES.lock().iter().find(|x| x.name == name).copied()
+}
+
+/// The error of a type which isn't registered, NotSupported with ENODEV.
+pub const UNKNOWN_TYPE: FsError = FsError::new(VfsError::NotSupported, Errno::ENODEV);
+
+/// Mount the source as the type of the name, UNKNOWN_TYPE if it isn't
+/// registered.
+pub fn mount_by_name(
+    name: &str,
+    source: MountSource,
+    flags: MountFlags,
+) -> FsResult<Arc<dyn FileSystem>> {
+    let Some(fstype) = fstype(name) else {
+        log::error!("can't mount {}, the filesystem type is unknown", name);
+        return Err(UNKNOWN_TYPE);
+    };
+    Ok((fstype.mount)(source, flags)?)
+}
+
+/// The name of the first type, in the priority order, which detects an
+/// image on the source.
+pub fn detect(source: &MountSource) -> Option<&'static str> {
+    // the detections read the source, they run without the lock.
+    fstypes()
+        .into_iter()
+        .find(|x| x.detect.is_some_and(|detect| detect(source)))
+        .map(|x| x.name)
+}
+
+/// Mount the source as the type detected on it, InvalidData if no type
+/// knows the image.
+pub fn mount_auto(source: MountSource, flags: MountFlags) -> FsResult<Arc<dyn FileSystem>> {
+    let Some(name) = detect(&source) else {
+        log::error!("can't mount, no filesystem type detects the image");
+        return Err(VfsError::InvalidData.into());
+    };
+    mount_by_name(name, source, flags)
+}
+
+/// The content of /proc/filesystems, a line for each type.
+pub fn render() -> String {
+    let mut out = String::new();
+    for fstype in fstypes() {
+        let nodev = match fstype.detect {
+            Some(_) => "",
+            None => "nodev",
+        };
+        let _ = writeln!(out, "{}\t{}", nodev, fstype.name);
+    }
+    out
+}
+
+/// The types in memory, ramfs and procfs are of their crates.
+#[cfg(feature = "kernel")]
+const KERNEL_FSTYPES: [FsType; 2] = [
+    FsType {
+        name: "ramfs",
+        detect: None,
+        identify: None,
+        mount: |_, _| Ok(crate::new_ramfs()),
+        priority: 0,
+    },
+    FsType {
+        name: "proc",
+        detect: None,
+        identify: None,
+        mount: |_, _| {
+            let procfs = procfs::ProcFS::new();
+            Ok(crate::proc_pid::TaskProcFs::new(procfs) as Arc<dyn FileSystem>)
+        },
+        priority: 0,
+    },
+];
+
+/// Register the types of the enabled shims, the registered ones are
+/// kept.
+pub fn init() {
+    let mut builtin = vec![crate::tmpfs::FSTYPE, crate::tunefs::FSTYPE];
+    #[cfg(feature = "kernel")]
+    builtin.extend(KERNEL_FSTYPES);
+    #[cfg(root_fs = "ext4_rs")]
+    builtin.push(crate::ext4_rs_shim::FSTYPE);
+    #[cfg(root_fs = "ext4")]
+    builtin.push(crate::ext4_shim::FSTYPE);
+    #[cfg(root_fs = "fat32")]
+    builtin.push(crate::fatfs_shim::FSTYPE);
+    for fstype in builtin {
+        let _ = register_fstype(fstype);
+    }
+}
+
+/// The ext4 superblock magic at its byte offset on the source, for the
+/// detections of the ext4 shims.
+#[cfg(any(root_fs = "ext4_rs", root_fs = "ext4"))]
+pub(crate) fn has_ext4_magic(source: &MountSource) -> bool {
+    let mut magic = [0; 2];
+    source.read_at(1024 + 0x38, &mut magic).is_ok()
+        && u16::from_le_bytes(magic) as u32 == crate::statfs::EXT4_SUPER_MAGIC
+}
+
+/// s_uuid and s_volume_name of the ext4 superblock on the source, for the
+/// identify of the ext4 shims.
+#[cfg(any(root_fs = "ext4_rs", root_fs = "ext4"))]
+pub(crate) fn ext4_volume_id(source: &MountSource) -> VolumeId {
+    let mut fields = [0; 32];
+    if source.read_at(1024 + 0x68, &mut fields).is_err() {
+        return VolumeId::default();
+    }
+    let uuid: [u8; 16] = fields[..16].try_into().unwrap();
+    let name = &fields[16..];
+    let len = name.iter().position(|x| *x == 0).unwrap_or(name.len());
+    VolumeId {
+        uuid: Some(crate::volume::format_uuid(&uuid)),
+        label: (len > 0).then(|| String::from_utf8_lossy(&name[..len]).into_owned()),
+    }
+}
+
+/// A sys device or a partition of one holding an image, by its device
+/// node.
+#[derive(Clone)]
+pub struct SourceCandidate {
+    /// The name of the node in /dev, like vda or vda1.
+    pub name: String,
+    pub source: MountSource,
+    /// The type detected on it.
+    pub fstype: &'static str,
+    pub id: VolumeId,
+}
+
+/// The sys devices and their partitions, by the names of their nodes.
+fn block_sources() -> Vec<(String, MountSource)> {
+    let mut sources = Vec::new();
+    for device_id in 0..get_blk_devices().len() {
+        let disk = BlockNode::disk(device_id).into_iter();
+        for node in disk.chain(BlockNode::partitions(device_id)) {
+            sources.push((node.name().to_string(), node.source()));
+        }
+    }
+    sources
+}
+
+/// The sources holding an image a type detects, with the identity read
+/// by its identify, in the order of the devices.
+pub fn probe_sources() -> Vec<SourceCandidate> {
+    let mut candidates = Vec::new();
+    for (name, source) in block_sources() {
+        let Some(found) = detect(&source).and_then(fstype) else {
+            continue;
+        };
+        let id = found.identify.map(|x| x(&source)).unwrap_or_default();
+        candidates.push(SourceCandidate {
+            name,
+            source,
+            fstype: found.name,
+            id,
+        });
+    }
+    candidates
+}
+
+/// The uuids are equal, without their case and their dashes like blkid.
+fn same_uuid(a: &str, b: &str) -> bool {
+    let digits = |x: &str| -> Vec<char> {
+        x.chars()
+            .filter(|x| *x != '-')
+            .map(|x| x.to_ascii_lowercase())
+            .collect()
+    };
+    digits(a) == digits(b)
+}
+
+/// The candidates of a UUID= or a LABEL= spec, empty for another spec.
+pub fn source_candidates(spec: &str) -> Vec<SourceCandidate> {
+    let (key, value) = spec.split_once('=').unwrap_or_default();
+    if !matches!(key, "UUID" | "LABEL") {
+        return Vec::new();
+    }
+    probe_sources()
+        .into_iter()
+        .filter(|x| match key {
+            "UUID" => {
+                x.id.uuid
+                    .as_deref()
+                    .is_some_and(|uuid| same_uuid(uuid, value))
+            }
+            _ => x.id.label.as_deref() == Some(value),
+        })
+        .collect()
+}
+
+/// The source of a mount spec, like the first field of fstab:
+/// - UUID=<uuid> and LABEL=<label>, the source whose image has it, see
+///   probe_sources.
+/// - /dev/vda or /dev/vda1, the sys device or the partition of the node.
+/// - a number, the sys device of the index.
+///
+/// A spec of no device fails with FileNotFound, a uuid or a label on more
+/// than one source with InvalidInput, the candidates are logged, see
+/// source_candidates. Any other spec fails with InvalidInput.
+pub fn resolve_source(spec: &str) -> VfsResult<MountSource> {
+    if spec.starts_with("UUID=") || spec.starts_with("LABEL=") {
+        let mut candidates = source_candidates(spec);
+        return match candidates.len() {
+            0 => Err(VfsError::FileNotFound),
+            1 => Ok(candidates.remove(0).source),
+            _ => {
+                let names: Vec<_> = candidates.iter().map(|x| x.name.as_str()).collect();
+                log::error!("{} is ambiguous, it's on {}", spec, names.join(" and "));
+                Err(VfsError::InvalidInput)
+            }
+        };
+    }
+    if let Some(name) = spec.strip_prefix("/dev/") {
+        return block_sources()
+            .into_iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, source)| source)
+            .ok_or(VfsError::FileNotFound);
+    }
+    match spec.parse::<usize>() {
+        Ok(device_id) if get_blk_device(device_id).is_some() => Ok(MountSource::Device(device_id)),
+        Ok(_) => Err(VfsError::FileNotFound),
+        Err(_) => Err(VfsError::InvalidInput),
+    }
+}
+
+/// Attach /proc/filesystems to the dentry tree, like /proc/fsstats of
+/// stats.rs. Do nothing if /proc isn't mounted.
+pub fn init_procfs() {
+    let Ok(proc) = dentry_open(dentry_root(), "/proc", OpenFlags::NONE) else {
+        return;
+    };
+    let node = Arc::new(DentryNode::new(
+        "filesystems".to_string(),
+        CallbackInode::read_only(|| render().into_bytes(), SizeMode::Snapshot),
+        Arc::downgrade(&proc),
+    ));
+    proc.children.lock().push(node);
+}
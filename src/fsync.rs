@@ -0,0 +1,196 @@
+// The durability of the writes: fsync, fdatasync and the writes of the
+// files opened with O_SYNC or O_DSYNC. flush of INodeInterface makes the
+// buffered writes of a node visible, not durable, so the nodes which can
+// make them durable implement SyncINode and hand it out by their FsNode,
+// like the nodes of owner.rs. A synchronous write returns once its data
+// is on the disk, with the write cache of the device flushed: O_DSYNC
+// and fdatasync keep only the metadata needed to read the data back, like
+// the size and the mapped blocks, O_SYNC and fsync keep the times too.
+// A sync writes what the node dirtied where the filesystem tracks it,
+// like the transactions ext4 defers, the other dirty blocks stay.
+// The nodes without a SyncINode are flushed instead, like a filesystem in
+// memory has nothing more to write.
+// The sync policy of a mount picks when the committed operations reach
+// the disk: each one as it returns, merged and written back later, or
+// each one with the write cache flushed between its steps.
+// TODO: fail a sync with EIO when the devices can report their errors.
+
+use core::{fmt, ops::Range};
+
+use alloc::sync::Arc;
+use vfscore::{INodeInterface, OpenFlags, VfsResult};
+
+use crate::cancel;
+use crate::mapping;
+use crate::node;
+
+/// What a synchronous write or a sync keeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMode {
+    /// The data and the metadata needed to read it, O_DSYNC.
+    Data,
+    /// The data and all the metadata, O_SYNC.
+    Full,
+}
+
+impl SyncMode {
+    /// The mode of the open flags, None if the writes aren't synchronous.
+    /// O_SYNC holds the bit of O_DSYNC, like Linux.
+    pub fn from_flags(flags: OpenFlags) -> Option<Self> {
+        if flags.contains(OpenFlags::O_SYNC) {
+            Some(Self::Full)
+        } else if flags.contains(OpenFlags::O_DSYNC) {
+            Some(Self::Data)
+        } else {
+            None
+        }
+    }
+
+    pub fn flags(self) -> OpenFlags {
+        match self {
+            Self::Data => OpenFlags::O_DSYNC,
+            Self::Full => OpenFlags::O_SYNC,
+        }
+    }
+}
+
+/// When the operations of a mount reach the disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Every operation is written when it returns.
+    #[default]
+    WriteThrough,
+    /// The operations are kept in memory and written back together, once
+    /// they hold more than max_dirty_blocks blocks, once the oldest one
+    /// is max_age_ticks writeback steps old, or by a sync. A crash loses
+    /// the operations not written back, never a part of one.
+    WriteBack {
+        max_dirty_blocks: usize,
+        max_age_ticks: u64,
+    },
+    /// Every operation is written when it returns, with the write cache of
+    /// the device flushed between its steps, so the blocks reach the
+    /// media in the order they depend on each other.
+    StrictOrdered,
+}
+
+impl SyncPolicy {
+    /// The policy of an option of mount, None for the other options:
+    /// sync_policy=write_through, sync_policy=strict_ordered or
+    /// sync_policy=write_back:<max_dirty_blocks>:<max_age_ticks>.
+    pub fn from_option(option: &str) -> Option<Self> {
+        Self::from_value(option.strip_prefix("sync_policy=")?)
+    }
+
+    /// The policy of the value of its option, like tunefs.rs writes it.
+    pub fn from_value(value: &str) -> Option<Self> {
+        match value {
+            "write_through" => Some(Self::WriteThrough),
+            "strict_ordered" => Some(Self::StrictOrdered),
+            x => {
+                let mut args = x.strip_prefix("write_back:")?.split(':');
+                let max_dirty_blocks = args.next()?.parse().ok()?;
+                let max_age_ticks = args.next()?.parse().ok()?;
+                if args.next().is_some() {
+                    return None;
+                }
+                Some(Self::WriteBack {
+                    max_dirty_blocks,
+                    max_age_ticks,
+                })
+            }
+        }
+    }
+
+    /// A write back needs one block at least.
+    pub fn is_valid(self) -> bool {
+        !matches!(
+            self,
+            Self::WriteBack {
+                max_dirty_blocks: 0,
+                ..
+            }
+        )
+    }
+}
+
+/// The value of the option, from_value parses it back.
+impl fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WriteThrough => f.write_str("write_through"),
+            Self::WriteBack {
+                max_dirty_blocks,
+                max_age_ticks,
+            } => write!(f, "write_back:{}:{}", max_dirty_blocks, max_age_ticks),
+            Self::StrictOrdered => f.write_str("strict_ordered"),
+        }
+    }
+}
+
+pub trait SyncINode: Send + Sync {
+    /// Make the bytes of the range written so far durable, with the
+    /// metadata of the mode. The data outside the range may stay
+    /// buffered.
+    fn sync_range(&self, range: Range<usize>, mode: SyncMode) -> VfsResult<()>;
+
+    /// Write the buffer at offset like writeat_cancel of cancel.rs, and
+    /// make it durable before returning, with the metadata of the mode.
+    fn write_sync(
+        &self,
+        offset: usize,
+        buffer: &[u8],
+        mode: SyncMode,
+        cancelled: &dyn Fn() -> bool,
+    ) -> VfsResult<usize>;
+}
+
+fn node_of(file: &Arc<dyn INodeInterface>) -> Option<&dyn SyncINode> {
+    node::fs_node(file.as_ref())?.as_sync()
+}
+
+/// Make the bytes of the range durable with the metadata of the mode, a
+/// node without a SyncINode writes its mappings back and is flushed, see
+/// mapping.rs.
+pub fn sync_range(
+    file: &Arc<dyn INodeInterface>,
+    range: Range<usize>,
+    mode: SyncMode,
+) -> VfsResult<()> {
+    match node_of(file) {
+        Some(node) => node.sync_range(range, mode),
+        None => {
+            mapping::writeback_file(file, range)?;
+            file.flush()
+        }
+    }
+}
+
+/// Make the whole file and all its metadata durable, fsync(2).
+pub fn fsync(file: &Arc<dyn INodeInterface>) -> VfsResult<()> {
+    sync_range(file, 0..usize::MAX, SyncMode::Full)
+}
+
+/// Make the whole file and the metadata needed to read it durable,
+/// fdatasync(2).
+pub fn fdatasync(file: &Arc<dyn INodeInterface>) -> VfsResult<()> {
+    sync_range(file, 0..usize::MAX, SyncMode::Data)
+}
+
+/// Write the buffer at offset and make it durable with the metadata of
+/// the mode, the writes of the O_SYNC and O_DSYNC opens. A node without a
+/// SyncINode writes it by cancel::write_at and is flushed after.
+pub fn write_at(
+    file: &Arc<dyn INodeInterface>,
+    offset: usize,
+    buffer: &[u8],
+    mode: SyncMode,
+    cancelled: &dyn Fn() -> bool,
+) -> VfsResult<usize> {
+    if let Some(node) = node_of(file) {
+        return node.write_sync(offset, buffer, mode, cancelled);
+    }
+    let written = cancel::write_at(file, offset, buffer, cancelled)?;
+    file.flush()?;
+    Ok(written)
+}
@@ -0,0 +1,193 @@
+// The entry points of the fuzz targets in fuzz/, they feed arbitrary bytes
+// to the parsers of the ext4 structures read at mount and on lookup. An
+// entry point must not panic on any input, and its work and allocations
+// are bounded by the length of the input, the iterations are asserted.
+// seeds extracts the inputs of the targets from a real image for the seed
+// corpora.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use vfscore::{VfsError, VfsResult};
+
+use crate::ext4_check::{inode_blocks, CheckDisk};
+use crate::ext4_csum::verify_group_desc;
+use crate::ext4_layout::{
+    le_u16, walk_extent_tree, DirentIter, GroupDesc, InodeInfo, SuperBlockInfo, DIRENT_HEADER,
+    SUPERBLOCK_OFFSET,
+};
+
+/// The size of the superblock on the disk.
+const SUPERBLOCK_SIZE: usize = 1024;
+/// The size of i_block, the root of the extent tree.
+const I_BLOCK_SIZE: usize = 60;
+/// The largest extent tree node of an input.
+const MAX_NODE_SIZE: usize = u16::MAX as usize;
+/// The max inodes and directory blocks seeds reads from an image.
+const MAX_SEED_INODES: u32 = 4096;
+const MAX_SEED_DIR_BLOCKS: usize = 16;
+
+/// Like the mount of an image whose first bytes are region: validate the
+/// superblock and load the group descriptors in the region.
+/// return the number of loaded descriptors.
+pub fn mount(region: &[u8]) -> VfsResult<usize> {
+    let Some(raw) = region.get(SUPERBLOCK_OFFSET..SUPERBLOCK_OFFSET + SUPERBLOCK_SIZE) else {
+        return Err(VfsError::InvalidInput);
+    };
+    let sb = SuperBlockInfo::parse(raw);
+    sb.validate().map_err(|_| VfsError::InvalidData)?;
+    let mut descs = Vec::new();
+    for group in 0..sb.groups_count() {
+        let offset = sb.group_desc_offset(group);
+        let Some(raw) = region.get(offset..offset + sb.group_desc_size()) else {
+            break;
+        };
+        if sb.has_metadata_csum() && !verify_group_desc(&sb, group as u32, raw) {
+            return Err(VfsError::InvalidData);
+        }
+        let desc = GroupDesc::parse(&sb, raw);
+        desc.validate(&sb).map_err(|_| VfsError::InvalidData)?;
+        descs.push(desc);
+    }
+    assert!(descs.len() <= region.len() / 32);
+    Ok(descs.len())
+}
+
+/// Iterate the entries of the directory block, return the number of used
+/// entries.
+pub fn dirents(block: &[u8]) -> VfsResult<usize> {
+    let mut count = 0;
+    for entry in DirentIter::new(block) {
+        entry?;
+        count += 1;
+        assert!(count <= block.len() / DIRENT_HEADER);
+    }
+    Ok(count)
+}
+
+/// Walk the extent tree of the input, return the number of extents.
+/// The input is i_block, the size of the nodes as a u16 and the nodes,
+/// each a u64 block number followed by the node.
+pub fn extents(data: &[u8]) -> VfsResult<usize> {
+    let (i_block, nodes) = parse_extent_input(data).ok_or(VfsError::InvalidInput)?;
+    let mut visited = 0;
+    let extents = walk_extent_tree(
+        i_block,
+        |block| nodes.get(&block).map(|x| x.to_vec()).ok_or(VfsError::Io),
+        |_| visited += 1,
+    )?;
+    // every node is read once, an entry is at least 12 bytes.
+    assert!(visited <= nodes.len());
+    assert!(extents.len() <= data.len() / 12);
+    Ok(extents.len())
+}
+
+/// i_block and the nodes by their block.
+type ExtentInput<'a> = (&'a [u8], BTreeMap<u64, &'a [u8]>);
+
+fn parse_extent_input(data: &[u8]) -> Option<ExtentInput<'_>> {
+    let i_block = data.get(..I_BLOCK_SIZE)?;
+    let node_size =
+        (le_u16(data.get(..I_BLOCK_SIZE + 2)?, I_BLOCK_SIZE) as usize).clamp(12, MAX_NODE_SIZE);
+    let mut nodes = BTreeMap::new();
+    for record in data[I_BLOCK_SIZE + 2..].chunks_exact(8 + node_size) {
+        let block = u64::from_le_bytes(record[..8].try_into().unwrap());
+        nodes.insert(block, &record[8..]);
+    }
+    Some((i_block, nodes))
+}
+
+/// The inputs of the targets extracted from an image.
+#[derive(Debug, Clone, Default)]
+pub struct Seeds {
+    /// The superblock and the group descriptors.
+    pub mount: Vec<u8>,
+    pub dirents: Vec<Vec<u8>>,
+    pub extents: Vec<Vec<u8>>,
+}
+
+/// Extract the seeds from the image, at most the first MAX_SEED_INODES
+/// inodes are read. The extent trees of 64K blocks don't fit the input.
+pub fn seeds(image: &[u8]) -> VfsResult<Seeds> {
+    let raw = image
+        .get(SUPERBLOCK_OFFSET..SUPERBLOCK_OFFSET + SUPERBLOCK_SIZE)
+        .ok_or(VfsError::InvalidInput)?;
+    let sb = SuperBlockInfo::parse(raw);
+    sb.validate().map_err(|_| VfsError::InvalidData)?;
+    let bs = sb.block_size();
+    let end = (sb.first_data_block as usize + 1 + sb.group_desc_blocks()) * bs;
+    let disk = SliceDisk { image, sb };
+    let mut seeds = Seeds {
+        mount: image.get(..end).ok_or(VfsError::InvalidData)?.to_vec(),
+        ..Default::default()
+    };
+    for ino in 1..=disk.sb.inodes_count.min(MAX_SEED_INODES) {
+        let inode = disk.read_inode(ino);
+        if inode.links_count == 0 || !inode.uses_extents() || inode.has_inline_data() {
+            continue;
+        }
+        let Ok((extents, nodes)) = inode_blocks(&disk, &inode) else {
+            continue;
+        };
+        if inode.mode & 0xF000 == 0x4000 {
+            let blocks = extents
+                .iter()
+                .flat_map(|x| (0..x.len as u64).map(move |i| x.physical + i));
+            for block in blocks.take(MAX_SEED_DIR_BLOCKS) {
+                seeds.dirents.push(disk.read_block(block));
+            }
+        }
+        if bs > MAX_NODE_SIZE {
+            continue;
+        }
+        let mut input = inode.i_block.to_vec();
+        input.extend_from_slice(&(bs as u16).to_le_bytes());
+        for block in nodes {
+            input.extend_from_slice(&block.to_le_bytes());
+            input.extend_from_slice(&disk.read_block(block));
+        }
+        seeds.extents.push(input);
+    }
+    Ok(seeds)
+}
+
+/// An image in memory, the blocks beyond it are read as zeros.
+struct SliceDisk<'a> {
+    image: &'a [u8],
+    sb: SuperBlockInfo,
+}
+
+impl SliceDisk<'_> {
+    fn read(&self, offset: usize, len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        if let Some(x) = self.image.get(offset..) {
+            let n = x.len().min(len);
+            data[..n].copy_from_slice(&x[..n]);
+        }
+        data
+    }
+}
+
+impl CheckDisk for SliceDisk<'_> {
+    fn superblock(&self) -> &SuperBlockInfo {
+        &self.sb
+    }
+
+    fn group_desc(&self, group: usize) -> GroupDesc {
+        let raw = self.read(self.sb.group_desc_offset(group), self.sb.group_desc_size());
+        GroupDesc::parse(&self.sb, &raw)
+    }
+
+    fn read_block(&self, block: u64) -> Vec<u8> {
+        let bs = self.sb.block_size();
+        self.read((block as usize).saturating_mul(bs), bs)
+    }
+
+    fn read_inode(&self, ino: u32) -> InodeInfo {
+        let (group, index) = self.sb.inode_group(ino);
+        let desc = self.group_desc(group);
+        let offset = (desc.inode_table as usize)
+            .saturating_mul(self.sb.block_size())
+            .saturating_add(index * self.sb.inode_size as usize);
+        let raw = self.read(offset, self.sb.inode_size as usize);
+        InodeInfo::parse(&raw, self.sb.inode_size as usize)
+    }
+}
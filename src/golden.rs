@@ -0,0 +1,508 @@
+// The differential tests against e2fsprogs. A script is a sequence of
+// operations, run here through a filesystem and by golden/reference.py
+// through the host, which builds an image of the result with mkfs.ext4 -d
+// and records what the image holds in a manifest. The manifests of the
+// scripts are checked in next to them, run compares the logical contents
+// of the filesystem with them instead of the raw bytes.
+//
+// A script has an operation per line, the paths are relative to the
+// directory of the script and have no spaces, # starts a comment:
+//   mkdir <path>
+//   write <path> <offset> <len> <seed>   the file is created if missing
+//   truncate <path> <len>
+//   symlink <path> <target>
+//   link <path> <existing>
+//   unlink <path>
+//   rmdir <path>
+//
+// A manifest has an entry per line, sorted by path:
+//   <kind> <nlink> <size> <crc32c> <path>[ -> <target>]
+// kind is d, f or l. The size and crc of a directory are 0, the crc of a
+// link is 0 and its size is the length of the target.
+// TODO: compare the permissions when the shims report them in stat.
+
+use core::fmt::{self, Display, Formatter};
+
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+use vfscore::{FileSystem, OpenFlags, Stat, StatMode};
+
+use crate::crc32c::crc32c;
+use crate::ops::copy_recursive;
+use crate::testsuite::{Caps, Failure};
+use crate::File;
+
+/// The directory of the scripts in the root of the filesystem.
+pub const GOLDEN_DIR: &str = "golden";
+
+/// The scripts with their manifests.
+pub const SCRIPTS: &[(&str, &str, &str)] = &[
+    (
+        "basic",
+        include_str!("../golden/basic.script"),
+        include_str!("../golden/basic.manifest"),
+    ),
+    (
+        "overwrite",
+        include_str!("../golden/overwrite.script"),
+        include_str!("../golden/overwrite.manifest"),
+    ),
+    (
+        "large",
+        include_str!("../golden/large.script"),
+        include_str!("../golden/large.manifest"),
+    ),
+    (
+        "tree",
+        include_str!("../golden/tree.script"),
+        include_str!("../golden/tree.manifest"),
+    ),
+    (
+        "symlink",
+        include_str!("../golden/symlink.script"),
+        include_str!("../golden/symlink.manifest"),
+    ),
+    (
+        "link",
+        include_str!("../golden/link.script"),
+        include_str!("../golden/link.manifest"),
+    ),
+    (
+        "rmdir",
+        include_str!("../golden/rmdir.script"),
+        include_str!("../golden/rmdir.manifest"),
+    ),
+];
+
+/// An operation of a script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Mkdir(String),
+    /// Write len bytes of the pattern of seed at offset.
+    Write {
+        path: String,
+        offset: usize,
+        len: usize,
+        seed: u32,
+    },
+    Truncate(String, usize),
+    Symlink(String, String),
+    /// Link path to the existing file.
+    Link(String, String),
+    Unlink(String),
+    Rmdir(String),
+}
+
+impl Op {
+    /// The features the filesystem needs to run the operation.
+    pub fn needs(&self) -> Caps {
+        match self {
+            Op::Mkdir(_) | Op::Write { .. } => Caps::NONE,
+            Op::Truncate(..) => Caps::TRUNCATE,
+            Op::Symlink(..) => Caps::SYMLINK,
+            Op::Link(..) => Caps::HARD_LINK,
+            Op::Unlink(_) => Caps::REMOVE,
+            Op::Rmdir(_) => Caps::RMDIR,
+        }
+    }
+}
+
+/// Parse the script, the errors have the line of the bad operation.
+pub fn parse_script(text: &str) -> Result<Vec<Op>, String> {
+    let mut ops = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let op = match words.as_slice() {
+            [] => continue,
+            ["mkdir", path] => Some(Op::Mkdir((*path).into())),
+            ["write", path, offset, len, seed] => {
+                match (offset.parse(), len.parse(), seed.parse()) {
+                    (Ok(offset), Ok(len), Ok(seed)) => Some(Op::Write {
+                        path: (*path).into(),
+                        offset,
+                        len,
+                        seed,
+                    }),
+                    _ => None,
+                }
+            }
+            ["truncate", path, len] => len.parse().ok().map(|x| Op::Truncate((*path).into(), x)),
+            ["symlink", path, target] => Some(Op::Symlink((*path).into(), (*target).into())),
+            ["link", path, existing] => Some(Op::Link((*path).into(), (*existing).into())),
+            ["unlink", path] => Some(Op::Unlink((*path).into())),
+            ["rmdir", path] => Some(Op::Rmdir((*path).into())),
+            _ => None,
+        };
+        match op {
+            Some(op) => ops.push(op),
+            None => {
+                return Err(format!(
+                    "line {}: bad operation {:?}",
+                    index + 1,
+                    line.trim()
+                ))
+            }
+        }
+    }
+    Ok(ops)
+}
+
+/// The bytes of the pattern at offset, a byte only depends on the seed and
+/// its position, so the overlapping writes of a seed agree.
+pub fn pattern(seed: u32, offset: usize, len: usize) -> Vec<u8> {
+    (offset..offset + len)
+        .map(|pos| {
+            ((pos as u32)
+                .wrapping_add(seed.wrapping_mul(0x51))
+                .wrapping_mul(0x9E37_79B1)
+                >> 24) as u8
+        })
+        .collect()
+}
+
+/// Fail the script with the operation if it fails.
+fn ok<T>(what: &str, path: &str, r: vfscore::VfsResult<T>) -> Result<T, String> {
+    r.map_err(|err| format!("{} {}: {:?}", what, path, err))
+}
+
+/// Look up the parent of the path, return it with the last name.
+fn parent<'a>(dir: &File, path: &'a str) -> Result<(File, &'a str), String> {
+    let (parents, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let mut dir = dir.clone();
+    for x in parents.split('/').filter(|x| !x.is_empty()) {
+        dir = ok("lookup", path, dir.lookup(x))?;
+    }
+    Ok((dir, name))
+}
+
+/// Run the operations of the script in dir.
+pub fn run_script(dir: &File, ops: &[Op]) -> Result<(), String> {
+    for op in ops {
+        match op {
+            Op::Mkdir(path) => {
+                let (parent, name) = parent(dir, path)?;
+                ok("mkdir", path, parent.mkdir(name))?;
+            }
+            Op::Write {
+                path,
+                offset,
+                len,
+                seed,
+            } => {
+                let (parent, name) = parent(dir, path)?;
+                let file = ok("open", path, parent.open(name, OpenFlags::O_CREAT))?;
+                let data = pattern(*seed, *offset, *len);
+                let mut done = 0;
+                while done < data.len() {
+                    match ok("writeat", path, file.writeat(offset + done, &data[done..]))? {
+                        0 => return Err(format!("writeat {}: no progress", path)),
+                        n => done += n,
+                    }
+                }
+            }
+            Op::Truncate(path, len) => {
+                let (parent, name) = parent(dir, path)?;
+                let file = ok("lookup", path, parent.lookup(name))?;
+                ok("truncate", path, file.truncate(*len))?;
+            }
+            Op::Symlink(path, target) => {
+                let (parent, name) = parent(dir, path)?;
+                ok("sym_link", path, parent.sym_link(name, target))?;
+            }
+            Op::Link(path, existing) => {
+                let (src_parent, src_name) = parent(dir, existing)?;
+                let file = ok("lookup", existing, src_parent.lookup(src_name))?;
+                let (parent, name) = parent(dir, path)?;
+                ok("link", path, parent.link(name, file))?;
+            }
+            Op::Unlink(path) => {
+                let (parent, name) = parent(dir, path)?;
+                ok("remove", path, parent.remove(name))?;
+            }
+            Op::Rmdir(path) => {
+                let (parent, name) = parent(dir, path)?;
+                ok("rmdir", path, parent.rmdir(name))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The kind of an entry of the manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Dir,
+    File,
+    Link,
+}
+
+/// An entry of the manifest, the logical contents of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub path: String,
+    pub kind: Kind,
+    pub nlink: u64,
+    pub size: u64,
+    /// The crc32c of the data of a file.
+    pub crc: u32,
+    pub target: Option<String>,
+}
+
+impl Display for Entry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            Kind::Dir => 'd',
+            Kind::File => 'f',
+            Kind::Link => 'l',
+        };
+        write!(
+            f,
+            "{} {} {} {:08x} {}",
+            kind, self.nlink, self.size, self.crc, self.path
+        )?;
+        if let Some(target) = &self.target {
+            write!(f, " -> {}", target)?;
+        }
+        Ok(())
+    }
+}
+
+/// The logical contents of a directory tree, sorted by path.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest(pub Vec<Entry>);
+
+impl Manifest {
+    /// Parse the manifest, the errors have the line of the bad entry.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut entries = Vec::new();
+        for (index, line) in text.lines().enumerate().filter(|x| !x.1.is_empty()) {
+            let entry = Self::parse_entry(line)
+                .ok_or_else(|| format!("line {}: bad entry {:?}", index + 1, line))?;
+            entries.push(entry);
+        }
+        entries.sort_by(|a: &Entry, b| a.path.cmp(&b.path));
+        Ok(Self(entries))
+    }
+
+    fn parse_entry(line: &str) -> Option<Entry> {
+        let mut fields = line.splitn(5, ' ');
+        let kind = match fields.next()? {
+            "d" => Kind::Dir,
+            "f" => Kind::File,
+            "l" => Kind::Link,
+            _ => return None,
+        };
+        let nlink = fields.next()?.parse().ok()?;
+        let size = fields.next()?.parse().ok()?;
+        let crc = u32::from_str_radix(fields.next()?, 16).ok()?;
+        let rest = fields.next()?;
+        let (path, target) = match kind {
+            Kind::Link => {
+                let (path, target) = rest.split_once(" -> ")?;
+                (path, Some(target.into()))
+            }
+            _ => (rest, None),
+        };
+        Some(Entry {
+            path: path.into(),
+            kind,
+            nlink,
+            size,
+            crc,
+            target,
+        })
+    }
+
+    /// The manifest of the tree under dir.
+    pub fn from_dir(dir: &File) -> Result<Self, String> {
+        let mut entries = Vec::new();
+        walk(dir, "", &mut entries)?;
+        entries.sort_by(|a: &Entry, b| a.path.cmp(&b.path));
+        Ok(Self(entries))
+    }
+}
+
+impl Display for Manifest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for entry in self.0.iter() {
+            writeln!(f, "{}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+fn walk(dir: &File, prefix: &str, entries: &mut Vec<Entry>) -> Result<(), String> {
+    let names = ok("read_dir", prefix, dir.read_dir())?;
+    for name in names.into_iter().map(|x| x.filename) {
+        // lost+found of mkfs isn't a part of the scripts.
+        if name == "." || name == ".." || (prefix.is_empty() && name == "lost+found") {
+            continue;
+        }
+        let path = match prefix {
+            "" => name.clone(),
+            _ => format!("{}/{}", prefix, name),
+        };
+        let file = ok("lookup", &path, dir.lookup(&name))?;
+        let mut stat = Stat::default();
+        ok("stat", &path, file.stat(&mut stat))?;
+        let mut entry = Entry {
+            path: path.clone(),
+            kind: Kind::File,
+            nlink: stat.nlink as _,
+            size: stat.size as _,
+            crc: 0,
+            target: None,
+        };
+        if stat.mode.contains(StatMode::DIR) {
+            entry.kind = Kind::Dir;
+            entry.size = 0;
+            entries.push(entry);
+            walk(&file, &path, entries)?;
+            continue;
+        }
+        if stat.mode.contains(StatMode::LINK) {
+            let target = ok("resolve_link", &path, file.resolve_link())?;
+            entry.kind = Kind::Link;
+            entry.size = target.len() as u64;
+            entry.target = Some(target);
+        } else {
+            let mut buf = vec![0; 0x10000];
+            let mut crc = !0;
+            let mut offset = 0;
+            loop {
+                match ok("readat", &path, file.readat(offset, &mut buf))? {
+                    0 => break,
+                    n => {
+                        crc = crc32c(crc, &buf[..n]);
+                        offset += n;
+                    }
+                }
+            }
+            entry.crc = crc;
+        }
+        entries.push(entry);
+    }
+    Ok(())
+}
+
+/// The first entry where the manifests diverge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Divergence {
+    /// The entry of the expected manifest is missing.
+    Missing(Entry),
+    /// The entry isn't in the expected manifest.
+    Extra(Entry),
+    Differs {
+        expected: Entry,
+        actual: Entry,
+    },
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Divergence::Missing(x) => write!(f, "{}: missing, expected {}", x.path, x),
+            Divergence::Extra(x) => write!(f, "{}: unexpected {}", x.path, x),
+            Divergence::Differs { expected, actual } => {
+                let field = if expected.kind != actual.kind {
+                    "kind"
+                } else if expected.nlink != actual.nlink {
+                    "nlink"
+                } else if expected.size != actual.size {
+                    "size"
+                } else if expected.crc != actual.crc {
+                    "data"
+                } else {
+                    "target"
+                };
+                write!(
+                    f,
+                    "{}: {} differs, expected {}, actual {}",
+                    expected.path, field, expected, actual
+                )
+            }
+        }
+    }
+}
+
+/// Compare the manifests in the order of the paths.
+pub fn diff(expected: &Manifest, actual: &Manifest) -> Option<Divergence> {
+    let (mut a, mut b) = (expected.0.iter().peekable(), actual.0.iter().peekable());
+    loop {
+        match (a.peek(), b.peek()) {
+            (None, None) => return None,
+            (Some(x), None) => return Some(Divergence::Missing((*x).clone())),
+            (None, Some(y)) => return Some(Divergence::Extra((*y).clone())),
+            (Some(x), Some(y)) if x.path < y.path => {
+                return Some(Divergence::Missing((*x).clone()))
+            }
+            (Some(x), Some(y)) if x.path > y.path => return Some(Divergence::Extra((*y).clone())),
+            (Some(x), Some(y)) if x != y => {
+                return Some(Divergence::Differs {
+                    expected: (*x).clone(),
+                    actual: (*y).clone(),
+                })
+            }
+            _ => {
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+/// Run the scripts in the root of fs and compare the results with their
+/// manifests, return the failed scripts. Like testsuite::run, fs must be
+/// empty and writable, every script runs in its own directory and the
+/// scripts needing a feature not in caps are skipped.
+pub fn run(fs: Arc<dyn FileSystem>, caps: Caps) -> Vec<Failure> {
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(fs));
+    let mut failures = Vec::new();
+    let base = match fs.root_dir().mkdir(GOLDEN_DIR) {
+        Ok(base) => base,
+        Err(err) => {
+            failures.push(Failure {
+                case: "setup",
+                reason: format!("mkdir {}: {:?}", GOLDEN_DIR, err),
+            });
+            return failures;
+        }
+    };
+    for &(case, script, manifest) in SCRIPTS.iter() {
+        let r = parse_script(script).and_then(|ops| {
+            if !ops.iter().all(|x| caps.contains(x.needs())) {
+                info!("golden {}: skipped", case);
+                return Ok(());
+            }
+            let expected = Manifest::parse(manifest)?;
+            let dir = ok("mkdir", case, base.mkdir(case))?;
+            run_script(&dir, &ops)?;
+            if let Some(divergence) = diff(&expected, &Manifest::from_dir(&dir)?) {
+                return Err(format!("{}", divergence));
+            }
+            // a copy of the tree must have the same manifest.
+            let name = format!("{}.copy", case);
+            let copy = ok("mkdir", &name, base.mkdir(&name))?;
+            ok(
+                "copy_recursive",
+                case,
+                copy_recursive(dir, copy.clone(), Default::default()),
+            )?;
+            match diff(&expected, &Manifest::from_dir(&copy)?) {
+                Some(divergence) => Err(format!("copy: {}", divergence)),
+                None => Ok(()),
+            }
+        });
+        if let Err(reason) = r {
+            log::error!("golden {} of {}: {}", case, fs.name(), reason);
+            failures.push(Failure { case, reason });
+        }
+    }
+    info!(
+        "golden of {}: {} scripts, {} failed",
+        fs.name(),
+        SCRIPTS.len(),
+        failures.len()
+    );
+    failures
+}
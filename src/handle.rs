@@ -0,0 +1,856 @@
+// Access modes of the opened files.
+// The dentry tree shares one node between all the opens of a path, so the
+// access mode of an open is kept in a FileHandle around the node, with
+// the position of its directory listing, see readdir.rs. A FileHandle is
+// an open file description of POSIX: every open makes a new one, and dup
+// and fork share it, the fds of the kernel hold the same Arc. The file
+// offset and the status flags of F_SETFL are in it, so the fds sharing it
+// see the moves and the changes of each other. The writes of an open with
+// O_SYNC or O_DSYNC are durable when they return, see fsync.rs, and the
+// transfers of a direct handle bypass the caches, see direct.rs. The
+// holes and the allocations of fallocate.rs are checked like the writes.
+// An open of a pseudo file reads a snapshot of its content, see pseudo.rs.
+
+#[cfg(feature = "async")]
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use vfscore::{
+    DirEntry, INodeInterface, Metadata, OpenFlags, PollEvent, SeekFrom, Stat, StatFS, StatMode,
+    TimeSpec, VfsError, VfsResult,
+};
+
+#[cfg(feature = "async")]
+use crate::aio::{self, AsyncINode, IoFuture};
+use crate::atime;
+use crate::cancel::{self, CancelIo};
+use crate::dentry::{self, DentryNode};
+use crate::direct::{self, DirectINode};
+use crate::error::{Errno, FsError, FsResult};
+use crate::fallocate::{self, SpaceINode, SpaceOp, Support};
+use crate::freeze::ClosedGate;
+use crate::fsync::{self, SyncINode, SyncMode};
+use crate::inode_flags::{self, FlagsINode, InodeFlags};
+use crate::mounts;
+use crate::node::{self, FsNode};
+use crate::ops::check_range;
+use crate::owner::{self, OwnerINode};
+use crate::pipe;
+use crate::pseudo;
+use crate::readdir::{self, DirChange, PlusEntry, PosEntry, SeekDir};
+use crate::reflink::{self, CloneINode};
+use crate::statx::{self, Statx, StatxINode};
+use crate::sys::Mutex;
+use crate::tmpfs::{self, PageRef, SharedPages};
+use crate::trace::{self, Target, TraceOp};
+
+/// The access mode of an open file, from the low bits of the open flags.
+/// Path is an open with O_PATH, it neither reads nor writes the file, it's
+/// for stat and as the directory of the *at() calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessMode {
+    ReadOnly,
+    WriteOnly,
+    ReadWrite,
+    Path,
+}
+
+impl AccessMode {
+    pub fn from_flags(flags: OpenFlags) -> Self {
+        if flags.contains(OpenFlags::O_PATH) {
+            Self::Path
+        } else if flags.contains(OpenFlags::O_RDWR) {
+            Self::ReadWrite
+        } else if flags.contains(OpenFlags::O_WRONLY) {
+            Self::WriteOnly
+        } else {
+            Self::ReadOnly
+        }
+    }
+
+    pub fn can_read(&self) -> bool {
+        matches!(self, Self::ReadOnly | Self::ReadWrite)
+    }
+
+    pub fn can_write(&self) -> bool {
+        matches!(self, Self::WriteOnly | Self::ReadWrite)
+    }
+
+    /// Check the read on the file opened with this mode, InvalidInput with
+    /// EBADF. The methods of the handle return the VfsError, the syscalls
+    /// check the mode of the handle first for the errno.
+    pub fn check_read(&self) -> FsResult<()> {
+        match self.can_read() {
+            true => Ok(()),
+            false => Err(FsError::new(VfsError::InvalidInput, Errno::EBADF)),
+        }
+    }
+
+    /// Check the write or truncate on the file opened with this mode, see
+    /// check_read.
+    pub fn check_write(&self) -> FsResult<()> {
+        match self.can_write() {
+            true => Ok(()),
+            false => Err(FsError::new(VfsError::InvalidInput, Errno::EBADF)),
+        }
+    }
+}
+
+/// The status flags of an open file which F_SETFL changes.
+pub const STATUS_FLAGS: OpenFlags = OpenFlags::O_APPEND.union(OpenFlags::O_NONBLOCK);
+
+/// The writes of O_APPEND of a node, see FileHandle::write.
+pub trait AppendINode {
+    /// Call write with the end of the file and return the end with the
+    /// bytes written. The end counts the writes of the other opens of the
+    /// inode, and the other appends of the inode wait until write returns.
+    fn append(&self, write: &mut dyn FnMut(usize) -> VfsResult<usize>)
+        -> VfsResult<(usize, usize)>;
+}
+
+/// FileHandle is a regular file opened with an access mode, it rejects
+/// the operations the mode doesn't allow and passes the others to the node.
+pub struct FileHandle {
+    node: Arc<dyn INodeInterface>,
+    mode: AccessMode,
+    /// The position of the next read_dir_next, telldir.
+    dir_pos: Mutex<u64>,
+    /// The offset of read and write, it's locked over a transfer so the
+    /// transfers through the shared handle don't interleave.
+    pos: Mutex<usize>,
+    /// The flags of STATUS_FLAGS.
+    status: Mutex<OpenFlags>,
+    /// O_SYNC or O_DSYNC of the open, F_SETFL doesn't change it.
+    sync: Option<SyncMode>,
+    /// The transfers bypass the caches, O_DIRECT.
+    direct: AtomicBool,
+}
+
+impl FileHandle {
+    pub fn new(node: Arc<dyn INodeInterface>, flags: OpenFlags) -> Arc<Self> {
+        let handle = Arc::new(Self {
+            node: pseudo::open(node),
+            mode: AccessMode::from_flags(flags),
+            dir_pos: Mutex::new(0),
+            pos: Mutex::new(0),
+            status: Mutex::new(flags & STATUS_FLAGS),
+            sync: SyncMode::from_flags(flags),
+            direct: AtomicBool::new(false),
+        });
+        mounts::open_file(&handle, &handle.node);
+        if handle.mode.can_write() {
+            mounts::open_writer(&handle);
+        }
+        handle
+    }
+
+    /// Open the node of the dentry with the access mode of flags.
+    pub fn from_dentry(dentry: &Arc<DentryNode>, flags: OpenFlags) -> Arc<Self> {
+        let handle = Self::new(dentry.node.clone(), flags);
+        mounts::opened_at(&handle, dentry);
+        handle
+    }
+
+    pub fn mode(&self) -> AccessMode {
+        self.mode
+    }
+
+    /// Share the open file description, like dup and fork. The handles
+    /// are the same, closing one keeps the other open.
+    pub fn dup(self: &Arc<Self>) -> Arc<Self> {
+        self.clone()
+    }
+
+    /// The status flags, F_GETFL, with O_SYNC or O_DSYNC of the open.
+    pub fn status_flags(&self) -> OpenFlags {
+        let sync = self.sync.map_or(OpenFlags::NONE, SyncMode::flags);
+        *self.status.lock() | sync
+    }
+
+    /// Set the status flags, F_SETFL. The flags which aren't in
+    /// STATUS_FLAGS are ignored, like the access mode.
+    pub fn set_status_flags(&self, flags: OpenFlags) {
+        *self.status.lock() = flags & STATUS_FLAGS;
+    }
+
+    /// Make the reads and the writes bypass the caches, or go through them
+    /// again, O_DIRECT of open and F_SETFL.
+    /// TODO: take O_DIRECT from the flags when vfscore has it.
+    pub fn set_direct(&self, direct: bool) {
+        self.direct.store(direct, Ordering::Relaxed);
+    }
+
+    pub fn is_direct(&self) -> bool {
+        self.direct.load(Ordering::Relaxed)
+    }
+
+    /// Read at the offset and move it past the bytes read, read(2).
+    pub fn read(&self, buffer: &mut [u8]) -> VfsResult<usize> {
+        let mut pos = self.pos.lock();
+        let read = self.readat(*pos, buffer)?;
+        *pos += read;
+        Ok(read)
+    }
+
+    /// Write at the offset, or at the end of the file with O_APPEND, and
+    /// move the offset past the bytes written, write(2).
+    pub fn write(&self, buffer: &[u8]) -> VfsResult<usize> {
+        let mut pos = self.pos.lock();
+        if self.status_flags().contains(OpenFlags::O_APPEND) {
+            let (end, written) = append(&self.node, &mut |end| self.writeat(end, buffer))?;
+            *pos = end + written;
+            return Ok(written);
+        }
+        let written = self.writeat(*pos, buffer)?;
+        *pos += written;
+        Ok(written)
+    }
+
+    /// Move the offset, return the new one, lseek. An offset before the
+    /// start fails with InvalidInput, one past the end is for a hole.
+    pub fn seek(&self, from: SeekFrom) -> VfsResult<usize> {
+        let mut pos = self.pos.lock();
+        let new = match from {
+            SeekFrom::SET(offset) => Some(offset),
+            SeekFrom::CURRENT(delta) => pos.checked_add_signed(delta),
+            SeekFrom::END(delta) => {
+                let mut stat = Stat::default();
+                self.node.stat(&mut stat)?;
+                usize::try_from(stat.size)
+                    .ok()
+                    .and_then(|x| x.checked_add_signed(delta))
+            }
+        };
+        let new = new.ok_or(VfsError::InvalidInput)?;
+        *pos = new;
+        Ok(new)
+    }
+
+    /// Read up to max entries of the directory from the position of this
+    /// open and move past them, like getdents.
+    pub fn read_dir_next(&self, max: usize) -> VfsResult<Vec<PosEntry>> {
+        let mut pos = self.dir_pos.lock();
+        let entries = self.read_dir_at(*pos, max)?;
+        if let Some(last) = entries.last() {
+            *pos = last.next;
+        }
+        Ok(entries)
+    }
+
+    /// The position of the listing of this open, telldir.
+    pub fn dir_tell(&self) -> u64 {
+        *self.dir_pos.lock()
+    }
+
+    /// Move the listing of this open to pos, a position from dir_tell or
+    /// of a listed entry, seekdir. 0 rewinds it.
+    pub fn dir_seek(&self, pos: u64) {
+        *self.dir_pos.lock() = pos;
+    }
+
+    /// Close this open, close(2). The buffered writes go to the file, a
+    /// failure to write them back is returned even if they were written
+    /// back before. The dups of the open keep it.
+    pub fn close(self: Arc<Self>) -> VfsResult<()> {
+        self.node.flush()
+    }
+
+    /// The entry name may be created in the directory, it isn't missing
+    /// any more for the dentries of the node.
+    fn created<T>(&self, name: &str, r: VfsResult<T>) -> VfsResult<T> {
+        if r.is_ok() {
+            dentry::invalidate_negative(&self.node, name);
+            readdir::changed(&self.node, name, DirChange::Added);
+        }
+        r
+    }
+
+    /// The entry name may be removed from the directory.
+    fn removed(&self, name: &str, r: VfsResult<()>) -> VfsResult<()> {
+        if r.is_ok() {
+            readdir::changed(&self.node, name, DirChange::Removed);
+        }
+        r
+    }
+
+    /// Check the flags of the entry name before it's removed.
+    fn check_unlink(&self, name: &str) -> VfsResult<()> {
+        match self.node.lookup(name) {
+            Ok(node) => Ok(inode_flags::check_unlink(&node)?),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Sync the range of a fallocate of an open with O_SYNC or O_DSYNC.
+    fn sync_space(&self, offset: usize, len: usize) -> VfsResult<()> {
+        match self.sync {
+            Some(mode) => fsync::sync_range(&self.node, offset..offset + len, mode),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Open the node with the access mode of flags, a FIFO opens an end of
+/// its shared pipe and any other node, or a FIFO opened with O_PATH, a
+/// FileHandle.
+pub fn open_node(
+    node: Arc<dyn INodeInterface>,
+    flags: OpenFlags,
+) -> FsResult<Arc<dyn INodeInterface>> {
+    let mut stat = Stat::default();
+    node.stat(&mut stat)?;
+    if stat.mode.contains(StatMode::FIFO) && !flags.contains(OpenFlags::O_PATH) {
+        return pipe::open_fifo(node.as_ref(), flags).map(|x| x as _);
+    }
+    Ok(FileHandle::new(node, flags))
+}
+
+/// Call write with the end of the file under the append lock of its
+/// inode, see AppendINode. A node without one takes the size of stat and
+/// its appends through two opens may overwrite each other.
+fn append(
+    file: &Arc<dyn INodeInterface>,
+    write: &mut dyn FnMut(usize) -> VfsResult<usize>,
+) -> VfsResult<(usize, usize)> {
+    if let Some(node) = node::fs_node(file.as_ref()).and_then(|x| x.as_append()) {
+        return node.append(write);
+    }
+    let mut stat = Stat::default();
+    file.stat(&mut stat)?;
+    let end = stat.size as usize;
+    Ok((end, write(end)?))
+}
+
+impl Drop for FileHandle {
+    fn drop(&mut self) {
+        mounts::close_writer(self);
+        mounts::close_file(self);
+    }
+}
+
+/// A handle passes the operations to its node, after the checks of its
+/// access mode.
+impl FsNode for FileHandle {
+    #[cfg(feature = "async")]
+    fn as_async(&self) -> Option<&dyn AsyncINode> {
+        Some(self)
+    }
+
+    fn as_statx(&self) -> Option<&dyn StatxINode> {
+        Some(self)
+    }
+
+    fn as_flags(&self) -> Option<&dyn FlagsINode> {
+        Some(self)
+    }
+
+    fn as_owner(&self) -> Option<&dyn OwnerINode> {
+        Some(self)
+    }
+
+    fn as_seek_dir(&self) -> Option<&dyn SeekDir> {
+        Some(self)
+    }
+
+    fn as_cancel(&self) -> Option<&dyn CancelIo> {
+        Some(self)
+    }
+
+    fn as_sync(&self) -> Option<&dyn SyncINode> {
+        Some(self)
+    }
+
+    fn as_direct(&self) -> Option<&dyn DirectINode> {
+        Some(self)
+    }
+
+    fn as_space(&self) -> Option<&dyn SpaceINode> {
+        Some(self)
+    }
+
+    fn as_clone(&self) -> Option<&dyn CloneINode> {
+        Some(self)
+    }
+
+    fn as_pages(&self) -> Option<&dyn SharedPages> {
+        Some(self)
+    }
+
+    fn as_append(&self) -> Option<&dyn AppendINode> {
+        Some(self)
+    }
+}
+
+impl StatxINode for FileHandle {
+    fn statx(&self, mask: u32, out: &mut Statx) -> VfsResult<()> {
+        statx::statx(&self.node, mask, out)
+    }
+}
+
+impl FlagsINode for FileHandle {
+    fn get_flags(&self) -> VfsResult<InodeFlags> {
+        inode_flags::get_flags(&self.node)
+    }
+
+    fn set_flags(&self, flags: InodeFlags) -> VfsResult<()> {
+        inode_flags::set_flags(&self.node, flags)
+    }
+}
+
+impl SeekDir for FileHandle {
+    fn read_dir_at(&self, pos: u64, max: usize) -> VfsResult<Vec<PosEntry>> {
+        self.mode.check_read()?;
+        readdir::read_dir_at(&self.node, pos, max)
+    }
+
+    fn read_dir_plus(&self, pos: u64, max: usize) -> VfsResult<Vec<PlusEntry>> {
+        self.mode.check_read()?;
+        readdir::read_dir_plus(&self.node, pos, max, true)
+    }
+
+    fn lock_dir(&self) -> Option<ClosedGate> {
+        readdir::node_of(&self.node)?.lock_dir()
+    }
+
+    /// The version of the node, counted by the ops layer for the changes
+    /// through the handle.
+    fn dir_version(&self) -> Option<u64> {
+        readdir::dir_version(&self.node).ok()
+    }
+
+    fn read_dir_since(&self, version: u64) -> VfsResult<Option<Vec<DirEntry>>> {
+        self.mode.check_read()?;
+        readdir::read_dir_since(&self.node, version)
+    }
+}
+
+impl OwnerINode for FileHandle {
+    fn set_owner(&self, uid: u32, gid: u32) -> VfsResult<()> {
+        Ok(owner::chown(&self.node, uid, gid)?)
+    }
+}
+
+impl AppendINode for FileHandle {
+    fn append(
+        &self,
+        write: &mut dyn FnMut(usize) -> VfsResult<usize>,
+    ) -> VfsResult<(usize, usize)> {
+        append(&self.node, write)
+    }
+}
+
+/// A mapping of the pages reads them.
+impl SharedPages for FileHandle {
+    fn page(&self, index: usize) -> VfsResult<PageRef> {
+        self.mode.check_read()?;
+        tmpfs::page(&self.node, index)
+    }
+}
+
+/// The checks of the sync methods, then the async I/O of the node.
+#[cfg(feature = "async")]
+impl AsyncINode for FileHandle {
+    fn readat_async<'a>(&'a self, offset: usize, buffer: &'a mut [u8]) -> IoFuture<'a, usize> {
+        Box::pin(async move {
+            self.mode.check_read()?;
+            check_range(offset, buffer.len(), u64::MAX)?;
+            if buffer.is_empty() {
+                return Ok(0);
+            }
+            let read = aio::readat(&self.node, offset, buffer).await?;
+            atime::accessed(&self.node);
+            Ok(read)
+        })
+    }
+
+    fn writeat_async<'a>(&'a self, offset: usize, buffer: &'a [u8]) -> IoFuture<'a, usize> {
+        Box::pin(async move {
+            self.mode.check_write()?;
+            check_range(offset, buffer.len(), u64::MAX)?;
+            if buffer.is_empty() {
+                return Ok(0);
+            }
+            inode_flags::check_write(&self.node, offset)?;
+            let written = aio::writeat(&self.node, offset, buffer).await?;
+            if let Some(mode) = self.sync {
+                fsync::sync_range(&self.node, offset..offset + written, mode)?;
+            }
+            Ok(written)
+        })
+    }
+
+    fn flush_async(&self) -> IoFuture<'_, ()> {
+        aio::flush(&self.node)
+    }
+}
+
+impl CancelIo for FileHandle {
+    fn readat_cancel(
+        &self,
+        offset: usize,
+        buffer: &mut [u8],
+        cancelled: &dyn Fn() -> bool,
+    ) -> VfsResult<usize> {
+        trace::traced(
+            TraceOp::Read,
+            "handle",
+            || Target::Unknown,
+            offset,
+            buffer.len(),
+            || {
+                self.mode.check_read()?;
+                check_range(offset, buffer.len(), u64::MAX)?;
+                if buffer.is_empty() {
+                    return Ok(0);
+                }
+                let read = match self.is_direct() {
+                    true => direct::read_at(&self.node, offset, buffer, cancelled)?,
+                    false => cancel::read_at(&self.node, offset, buffer, cancelled)?,
+                };
+                atime::accessed(&self.node);
+                Ok(read)
+            },
+        )
+    }
+
+    fn writeat_cancel(
+        &self,
+        offset: usize,
+        buffer: &[u8],
+        cancelled: &dyn Fn() -> bool,
+    ) -> VfsResult<usize> {
+        trace::traced(
+            TraceOp::Write,
+            "handle",
+            || Target::Unknown,
+            offset,
+            buffer.len(),
+            || {
+                if self.is_direct() {
+                    return self.writeat_direct(offset, buffer, cancelled);
+                }
+                self.mode.check_write()?;
+                check_range(offset, buffer.len(), u64::MAX)?;
+                if buffer.is_empty() {
+                    return Ok(0);
+                }
+                inode_flags::check_write(&self.node, offset)?;
+                match self.sync {
+                    Some(mode) => fsync::write_at(&self.node, offset, buffer, mode, cancelled),
+                    None => Ok(cancel::write_at(&self.node, offset, buffer, cancelled)?),
+                }
+            },
+        )
+    }
+}
+
+/// The direct transfers of any open, a write of O_SYNC or O_DSYNC is
+/// synced after it.
+/// The checks of a write, then the operation of the node or its
+/// emulation on the node, not through the offset and the status flags of
+/// the handle.
+impl SpaceINode for FileHandle {
+    fn support(&self, op: SpaceOp) -> Support {
+        fallocate::support(&self.node, op)
+    }
+
+    fn punch_hole(&self, offset: usize, len: usize) -> VfsResult<()> {
+        self.mode.check_write()?;
+        inode_flags::check_truncate(&self.node)?;
+        fallocate::punch_hole(&self.node, offset, len)?;
+        self.sync_space(offset, len)
+    }
+
+    fn allocate(&self, offset: usize, len: usize, keep_size: bool) -> VfsResult<()> {
+        self.mode.check_write()?;
+        inode_flags::check_write(&self.node, offset)?;
+        fallocate::allocate(&self.node, offset, len, keep_size)?;
+        self.sync_space(offset, len)
+    }
+}
+
+/// A clone reads the source and writes the node, like the writes.
+impl CloneINode for FileHandle {
+    fn clone_source(&self) -> VfsResult<Arc<dyn INodeInterface>> {
+        self.mode.check_read()?;
+        reflink::source(&self.node)
+    }
+
+    fn clone_from(&self, src: &Arc<dyn INodeInterface>) -> VfsResult<()> {
+        self.mode.check_write()?;
+        inode_flags::check_truncate(&self.node)?;
+        Ok(reflink::clone_from(&self.node, src)?)
+    }
+
+    fn clone_range(
+        &self,
+        src: &Arc<dyn INodeInterface>,
+        src_offset: usize,
+        len: usize,
+        offset: usize,
+    ) -> VfsResult<()> {
+        self.mode.check_write()?;
+        inode_flags::check_write(&self.node, offset)?;
+        Ok(reflink::clone_range(
+            &self.node, src, src_offset, len, offset,
+        )?)
+    }
+}
+
+impl DirectINode for FileHandle {
+    fn readat_direct(
+        &self,
+        offset: usize,
+        buffer: &mut [u8],
+        cancelled: &dyn Fn() -> bool,
+    ) -> VfsResult<usize> {
+        self.mode.check_read()?;
+        check_range(offset, buffer.len(), u64::MAX)?;
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let read = direct::read_at(&self.node, offset, buffer, cancelled)?;
+        atime::accessed(&self.node);
+        Ok(read)
+    }
+
+    fn writeat_direct(
+        &self,
+        offset: usize,
+        buffer: &[u8],
+        cancelled: &dyn Fn() -> bool,
+    ) -> VfsResult<usize> {
+        self.mode.check_write()?;
+        check_range(offset, buffer.len(), u64::MAX)?;
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        inode_flags::check_write(&self.node, offset)?;
+        let written = direct::write_at(&self.node, offset, buffer, cancelled)?;
+        if let Some(mode) = self.sync {
+            fsync::sync_range(&self.node, offset..offset + written, mode)?;
+        }
+        Ok(written)
+    }
+
+    fn strict_alignment(&self) -> bool {
+        direct::strict_alignment(&self.node)
+    }
+}
+
+/// fsync and fdatasync of any open, the synchronous writes of an open
+/// without O_SYNC, like pwritev2 with RWF_SYNC.
+impl SyncINode for FileHandle {
+    fn sync_range(&self, range: core::ops::Range<usize>, mode: SyncMode) -> VfsResult<()> {
+        fsync::sync_range(&self.node, range, mode)
+    }
+
+    fn write_sync(
+        &self,
+        offset: usize,
+        buffer: &[u8],
+        mode: SyncMode,
+        cancelled: &dyn Fn() -> bool,
+    ) -> VfsResult<usize> {
+        self.mode.check_write()?;
+        check_range(offset, buffer.len(), u64::MAX)?;
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        inode_flags::check_write(&self.node, offset)?;
+        fsync::write_at(&self.node, offset, buffer, mode, cancelled)
+    }
+}
+
+impl INodeInterface for FileHandle {
+    fn readat(&self, offset: usize, buffer: &mut [u8]) -> VfsResult<usize> {
+        self.readat_cancel(offset, buffer, &cancel::never)
+    }
+
+    fn writeat(&self, offset: usize, buffer: &[u8]) -> VfsResult<usize> {
+        self.writeat_cancel(offset, buffer, &cancel::never)
+    }
+
+    fn truncate(&self, size: usize) -> VfsResult<()> {
+        trace::traced(
+            TraceOp::Truncate,
+            "handle",
+            || Target::Unknown,
+            size,
+            0,
+            || {
+                self.mode.check_write()?;
+                inode_flags::check_truncate(&self.node)?;
+                self.node.truncate(size)
+            },
+        )
+    }
+
+    fn mkdir(&self, name: &str) -> VfsResult<Arc<dyn INodeInterface>> {
+        trace::traced(
+            TraceOp::Mkdir,
+            "handle",
+            || Target::Path(name),
+            0,
+            0,
+            || self.created(name, self.node.mkdir(name)),
+        )
+    }
+
+    fn touch(&self, name: &str) -> VfsResult<Arc<dyn INodeInterface>> {
+        trace::traced(
+            TraceOp::Touch,
+            "handle",
+            || Target::Path(name),
+            0,
+            0,
+            || self.created(name, self.node.touch(name)),
+        )
+    }
+
+    fn open(&self, name: &str, flags: OpenFlags) -> VfsResult<Arc<dyn INodeInterface>> {
+        trace::traced(
+            TraceOp::Open,
+            "handle",
+            || Target::Path(name),
+            0,
+            0,
+            || match flags.contains(OpenFlags::O_CREAT) {
+                true => self.created(name, self.node.open(name, flags)),
+                false => self.node.open(name, flags),
+            },
+        )
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<Arc<dyn INodeInterface>> {
+        trace::traced(
+            TraceOp::Lookup,
+            "handle",
+            || Target::Path(name),
+            0,
+            0,
+            || self.node.lookup(name),
+        )
+    }
+
+    fn rmdir(&self, name: &str) -> VfsResult<()> {
+        trace::traced(
+            TraceOp::Rmdir,
+            "handle",
+            || Target::Path(name),
+            0,
+            0,
+            || {
+                self.check_unlink(name)?;
+                self.removed(name, self.node.rmdir(name))
+            },
+        )
+    }
+
+    fn remove(&self, name: &str) -> VfsResult<()> {
+        trace::traced(
+            TraceOp::Unlink,
+            "handle",
+            || Target::Path(name),
+            0,
+            0,
+            || {
+                self.check_unlink(name)?;
+                self.removed(name, self.node.remove(name))
+            },
+        )
+    }
+
+    fn unlink(&self, name: &str) -> VfsResult<()> {
+        trace::traced(
+            TraceOp::Unlink,
+            "handle",
+            || Target::Path(name),
+            0,
+            0,
+            || {
+                self.check_unlink(name)?;
+                self.removed(name, self.node.unlink(name))
+            },
+        )
+    }
+
+    fn link(&self, name: &str, src: Arc<dyn INodeInterface>) -> VfsResult<()> {
+        trace::traced(
+            TraceOp::Link,
+            "handle",
+            || Target::Path(name),
+            0,
+            0,
+            || {
+                inode_flags::check_unlink(&src)?;
+                self.created(name, self.node.link(name, src))
+            },
+        )
+    }
+
+    fn sym_link(&self, name: &str, src: &str) -> VfsResult<()> {
+        trace::traced(
+            TraceOp::Symlink,
+            "handle",
+            || Target::Path(name),
+            0,
+            0,
+            || self.created(name, self.node.sym_link(name, src)),
+        )
+    }
+
+    fn resolve_link(&self) -> VfsResult<String> {
+        self.node.resolve_link()
+    }
+
+    fn read_dir(&self) -> VfsResult<Vec<DirEntry>> {
+        self.mode.check_read()?;
+        self.node.read_dir()
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        trace::traced(
+            TraceOp::Flush,
+            "handle",
+            || Target::Unknown,
+            0,
+            0,
+            || self.node.flush(),
+        )
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        self.node.metadata()
+    }
+
+    fn stat(&self, stat: &mut Stat) -> VfsResult<()> {
+        self.node.stat(stat)
+    }
+
+    fn statfs(&self, statfs: &mut StatFS) -> VfsResult<()> {
+        self.node.statfs(statfs)
+    }
+
+    fn utimes(&self, times: &mut [TimeSpec]) -> VfsResult<()> {
+        inode_flags::check_setattr(&self.node)?;
+        self.node.utimes(times)
+    }
+
+    fn poll(&self, events: PollEvent) -> VfsResult<PollEvent> {
+        self.node.poll(events)
+    }
+
+    /// The flags of chattr and lsattr and the clones of reflink.rs, the
+    /// other commands go to the node.
+    fn ioctl(&self, command: usize, arg: usize) -> VfsResult<usize> {
+        if let Some(r) = inode_flags::ioctl(&self.node, command, arg) {
+            return r;
+        }
+        if let Some(r) = reflink::ioctl(self, command, arg) {
+            return r;
+        }
+        self.node.ioctl(command, arg)
+    }
+}
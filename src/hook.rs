@@ -0,0 +1,77 @@
+// The function pointers the kernel installs in the fs crate: the trace
+// hook and clock, the readdir change hook, the pipe wait hook and the
+// current pid of /proc. A HookCell holds one in an AtomicPtr, reading it
+// costs one relaxed load, and the unsafe conversion back to the fn is
+// only here.
+
+use core::marker::PhantomData;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// The fn pointer types a HookCell holds.
+///
+/// # Safety
+///
+/// from_ptr(into_ptr(hook)) is hook.
+pub unsafe trait FnPtr: Copy {
+    fn into_ptr(self) -> *mut ();
+
+    /// # Safety
+    ///
+    /// ptr is the into_ptr of a Self.
+    unsafe fn from_ptr(ptr: *mut ()) -> Self;
+}
+
+// SAFETY: the fn pointers convert to and from a data pointer losslessly
+// on the targets of the kernel.
+unsafe impl<R> FnPtr for fn() -> R {
+    fn into_ptr(self) -> *mut () {
+        self as *mut ()
+    }
+
+    unsafe fn from_ptr(ptr: *mut ()) -> Self {
+        // SAFETY: ptr is the into_ptr of a fn() -> R.
+        unsafe { core::mem::transmute::<*mut (), Self>(ptr) }
+    }
+}
+
+// SAFETY: like fn() -> R.
+unsafe impl<T> FnPtr for fn(&T) {
+    fn into_ptr(self) -> *mut () {
+        self as *mut ()
+    }
+
+    unsafe fn from_ptr(ptr: *mut ()) -> Self {
+        // SAFETY: ptr is the into_ptr of a fn(&T).
+        unsafe { core::mem::transmute::<*mut (), Self>(ptr) }
+    }
+}
+
+/// An installed hook of type F, or none.
+pub struct HookCell<F: FnPtr> {
+    ptr: AtomicPtr<()>,
+    _hook: PhantomData<F>,
+}
+
+impl<F: FnPtr> HookCell<F> {
+    pub const fn new() -> Self {
+        Self {
+            ptr: AtomicPtr::new(ptr::null_mut()),
+            _hook: PhantomData,
+        }
+    }
+
+    pub fn set(&self, hook: F) {
+        self.ptr.store(hook.into_ptr(), Ordering::Relaxed);
+    }
+
+    pub fn clear(&self) {
+        self.ptr.store(ptr::null_mut(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Option<F> {
+        let ptr = self.ptr.load(Ordering::Relaxed);
+        // SAFETY: a non-null ptr was stored by set from an F.
+        (!ptr.is_null()).then(|| unsafe { F::from_ptr(ptr) })
+    }
+}
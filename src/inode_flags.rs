@@ -0,0 +1,161 @@
+// The inode flags of chattr and lsattr, FS_IOC_GETFLAGS and
+// FS_IOC_SETFLAGS of Linux. INodeInterface of vfscore has no flags, so the
+// nodes which keep them implement FlagsINode and hand it out by their
+// FsNode, like the nodes of statx.rs. The flags are enforced here, by
+// the FileHandles and the ops above the filesystems:
+// - immutable rejects the writes, truncate, unlink, rename and link of
+//   the node and the changes of its times, its mode and its owner.
+// - append only allows the writes at the end of the file and rejects
+//   truncate, unlink, rename and link.
+// TODO: check CAP_LINUX_IMMUTABLE when Cred has the capabilities, any
+// caller may set and clear the flags now.
+
+use core::ops::BitOr;
+
+use alloc::sync::Arc;
+use vfscore::{INodeInterface, VfsError, VfsResult};
+
+use crate::error::{Errno, FsError, FsResult};
+use crate::node;
+
+/// The ioctl of the flags, the int at the pointer arg.
+pub const FS_IOC_GETFLAGS: usize = 0x8008_6601;
+pub const FS_IOC_SETFLAGS: usize = 0x4008_6602;
+
+/// The flags of an inode, the bits of FS_IOC_GETFLAGS.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InodeFlags(u32);
+
+impl InodeFlags {
+    pub const NONE: Self = Self(0);
+    /// FS_IMMUTABLE_FL, chattr +i.
+    pub const IMMUTABLE: Self = Self(0x10);
+    /// FS_APPEND_FL, chattr +a.
+    pub const APPEND: Self = Self(0x20);
+    /// FS_NODUMP_FL, chattr +d.
+    pub const NODUMP: Self = Self(0x40);
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub const fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// The flags of the bits, the unknown ones are kept, lsattr shows
+    /// them.
+    pub const fn from_bits_retain(bits: u32) -> Self {
+        Self(bits)
+    }
+}
+
+impl BitOr for InodeFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// The flags of a node, the default has none.
+pub trait FlagsINode: Send + Sync {
+    fn get_flags(&self) -> VfsResult<InodeFlags> {
+        Err(VfsError::NotSupported)
+    }
+
+    /// Set the flags the filesystem can change, it keeps the others.
+    fn set_flags(&self, _flags: InodeFlags) -> VfsResult<()> {
+        Err(VfsError::NotSupported)
+    }
+}
+
+fn node_of(file: &Arc<dyn INodeInterface>) -> VfsResult<&dyn FlagsINode> {
+    let node = node::fs_node(file.as_ref()).and_then(|x| x.as_flags());
+    node.ok_or(VfsError::NotSupported)
+}
+
+/// The flags of the node, NotSupported if it has none.
+pub fn get_flags(file: &Arc<dyn INodeInterface>) -> VfsResult<InodeFlags> {
+    node_of(file)?.get_flags()
+}
+
+/// Set the flags of the node, NotSupported if it has none.
+pub fn set_flags(file: &Arc<dyn INodeInterface>, flags: InodeFlags) -> VfsResult<()> {
+    node_of(file)?.set_flags(flags)
+}
+
+/// The error of a node denying a change by its flags.
+const DENIED: FsError = FsError::new(VfsError::NotSupported, Errno::EPERM);
+
+/// Fail with EPERM if the node has one of the flags, a node without
+/// flags has none.
+fn deny(file: &Arc<dyn INodeInterface>, denied: InodeFlags) -> FsResult<()> {
+    match get_flags(file) {
+        Ok(flags) if flags.intersects(denied) => Err(DENIED),
+        _ => Ok(()),
+    }
+}
+
+/// Check a write at offset, an append only file is only written at its
+/// end.
+pub fn check_write(file: &Arc<dyn INodeInterface>, offset: usize) -> FsResult<()> {
+    deny(file, InodeFlags::IMMUTABLE)?;
+    if get_flags(file).is_ok_and(|x| x.contains(InodeFlags::APPEND))
+        && offset != file.metadata()?.size
+    {
+        return Err(DENIED);
+    }
+    Ok(())
+}
+
+/// Check the truncate of the node.
+pub fn check_truncate(file: &Arc<dyn INodeInterface>) -> FsResult<()> {
+    deny(file, InodeFlags::IMMUTABLE | InodeFlags::APPEND)
+}
+
+/// Check the unlink, the rename or a new link of the node.
+pub fn check_unlink(file: &Arc<dyn INodeInterface>) -> FsResult<()> {
+    deny(file, InodeFlags::IMMUTABLE | InodeFlags::APPEND)
+}
+
+/// Check a change of the times, the mode or the owner of the node.
+pub fn check_setattr(file: &Arc<dyn INodeInterface>) -> FsResult<()> {
+    deny(file, InodeFlags::IMMUTABLE)
+}
+
+/// FS_IOC_GETFLAGS and FS_IOC_SETFLAGS of the node, arg is the pointer of
+/// the int in the address space of the caller. None for the other
+/// commands.
+pub fn ioctl(
+    file: &Arc<dyn INodeInterface>,
+    command: usize,
+    arg: usize,
+) -> Option<VfsResult<usize>> {
+    if command != FS_IOC_GETFLAGS && command != FS_IOC_SETFLAGS {
+        return None;
+    }
+    if arg == 0 {
+        return Some(Err(VfsError::InvalidInput));
+    }
+    // SAFETY: the kernel passes a pointer checked for an int, like the
+    // ioctls of the devices.
+    let r = match command {
+        FS_IOC_GETFLAGS => {
+            get_flags(file).map(|flags| unsafe { (arg as *mut u32).write_unaligned(flags.bits()) })
+        }
+        _ => {
+            let bits = unsafe { (arg as *const u32).read_unaligned() };
+            set_flags(file, InodeFlags::from_bits_retain(bits))
+        }
+    };
+    Some(r.map(|_| 0))
+}
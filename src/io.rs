@@ -0,0 +1,351 @@
+// The streams over the inodes, like std::io without std. Read, Write and
+// Seek are the small parts of the traits of std::io the helpers use, and
+// BufRead reads from a buffer by fill_buf and consume, with read_line.
+// InodeReader and InodeWriter keep the offset of a stream over an inode
+// and buffer its readat and writeat calls, so the archives and the copies
+// stream a file by small pieces with a call of the node for each buffer.
+// A transfer as large as the buffer skips it. The writer writes
+// its buffer back on flush and on drop, where an error is only logged,
+// flush it to see it. The errors are the ones of vfscore, an end of the
+// stream in read_exact is UnexpectedEof and a write of no bytes is
+// WriteZero, like in cancel.rs.
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use vfscore::{INodeInterface, Stat, VfsError, VfsResult};
+
+/// The size of the buffers of the streams.
+pub const DEFAULT_BUF_SIZE: usize = 0x10000;
+
+/// A source of bytes, like std::io::Read.
+pub trait Read {
+    /// Read into buf, return the bytes read, 0 at the end of the stream.
+    fn read(&mut self, buf: &mut [u8]) -> VfsResult<usize>;
+
+    /// Fill buf, it fails with UnexpectedEof at the end of the stream. The
+    /// bytes before a failure are consumed.
+    fn read_exact(&mut self, buf: &mut [u8]) -> VfsResult<()> {
+        let mut pos = 0;
+        while pos < buf.len() {
+            match self.read(&mut buf[pos..])? {
+                0 => return Err(VfsError::UnexpectedEof),
+                n => pos += n,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A sink of bytes, like std::io::Write.
+pub trait Write {
+    /// Write a part of buf, return the bytes written.
+    fn write(&mut self, buf: &[u8]) -> VfsResult<usize>;
+
+    /// Write the buffered bytes through.
+    fn flush(&mut self) -> VfsResult<()> {
+        Ok(())
+    }
+
+    /// Write all of buf or fail.
+    fn write_all(&mut self, buf: &[u8]) -> VfsResult<()> {
+        let mut pos = 0;
+        while pos < buf.len() {
+            match self.write(&buf[pos..])? {
+                0 => return Err(VfsError::WriteZero),
+                n => pos += n,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The position of a seek, like std::io::SeekFrom.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    /// From the size of the file.
+    End(i64),
+    Current(i64),
+}
+
+/// A stream with a position, like std::io::Seek.
+pub trait Seek {
+    /// Move the position, return it from the start. A position before the
+    /// start fails with InvalidInput.
+    fn seek(&mut self, pos: SeekFrom) -> VfsResult<u64>;
+}
+
+/// A source reading from a buffer, like std::io::BufRead.
+pub trait BufRead: Read {
+    /// The buffered bytes, read from the source if there are none. Empty
+    /// at the end of the stream.
+    fn fill_buf(&mut self) -> VfsResult<&[u8]>;
+
+    /// Mark amount bytes of the buffer as read.
+    fn consume(&mut self, amount: usize);
+
+    /// Append the bytes up to the next newline to line, with it. return
+    /// the bytes read, 0 at the end of the stream. A line which isn't
+    /// UTF-8 fails with InvalidData and isn't appended.
+    fn read_line(&mut self, line: &mut String) -> VfsResult<usize> {
+        let mut bytes = Vec::new();
+        loop {
+            let buf = self.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            let (len, done) = match buf.iter().position(|x| *x == b'\n') {
+                Some(i) => (i + 1, true),
+                None => (buf.len(), false),
+            };
+            bytes.extend_from_slice(&buf[..len]);
+            self.consume(len);
+            if done {
+                break;
+            }
+        }
+        let text = core::str::from_utf8(&bytes).map_err(|_| VfsError::InvalidData)?;
+        line.push_str(text);
+        Ok(bytes.len())
+    }
+}
+
+impl Write for Vec<u8> {
+    fn write(&mut self, buf: &[u8]) -> VfsResult<usize> {
+        self.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+}
+
+impl Read for &[u8] {
+    fn read(&mut self, buf: &mut [u8]) -> VfsResult<usize> {
+        let len = buf.len().min(self.len());
+        buf[..len].copy_from_slice(&self[..len]);
+        *self = &self[len..];
+        Ok(len)
+    }
+}
+
+impl BufRead for &[u8] {
+    fn fill_buf(&mut self) -> VfsResult<&[u8]> {
+        Ok(self)
+    }
+
+    fn consume(&mut self, amount: usize) {
+        *self = &self[amount..];
+    }
+}
+
+/// Copy the reader to its end into the writer, return the bytes copied.
+pub fn copy(reader: &mut dyn Read, writer: &mut dyn Write) -> VfsResult<u64> {
+    let mut buf = vec![0; DEFAULT_BUF_SIZE];
+    let mut copied = 0;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(copied);
+        }
+        writer.write_all(&buf[..n])?;
+        copied += n as u64;
+    }
+}
+
+/// The offset of the seek from the position and the size of the file.
+fn seek_offset(pos: SeekFrom, current: usize, file: &dyn INodeInterface) -> VfsResult<usize> {
+    let (base, delta) = match pos {
+        SeekFrom::Start(offset) => {
+            return usize::try_from(offset).map_err(|_| VfsError::InvalidInput);
+        }
+        SeekFrom::Current(delta) => (current, delta),
+        SeekFrom::End(delta) => {
+            let mut stat = Stat::default();
+            file.stat(&mut stat)?;
+            (stat.size as usize, delta)
+        }
+    };
+    base.checked_add_signed(delta as isize)
+        .ok_or(VfsError::InvalidInput)
+}
+
+/// A stream reading an inode from an offset through a buffer.
+pub struct InodeReader {
+    file: Arc<dyn INodeInterface>,
+    /// The offset in the file of the end of the buffered bytes.
+    offset: usize,
+    buf: Vec<u8>,
+    /// The bytes of buf read and the bytes of buf filled.
+    pos: usize,
+    filled: usize,
+}
+
+impl InodeReader {
+    /// Read the file from its start with a buffer of DEFAULT_BUF_SIZE.
+    pub fn new(file: Arc<dyn INodeInterface>) -> Self {
+        Self::with_capacity(DEFAULT_BUF_SIZE, file)
+    }
+
+    pub fn with_capacity(capacity: usize, file: Arc<dyn INodeInterface>) -> Self {
+        Self {
+            file,
+            offset: 0,
+            buf: vec![0; capacity.max(1)],
+            pos: 0,
+            filled: 0,
+        }
+    }
+
+    /// The offset in the file of the next byte of the stream.
+    pub fn position(&self) -> usize {
+        self.offset - (self.filled - self.pos)
+    }
+}
+
+impl Read for InodeReader {
+    fn read(&mut self, buf: &mut [u8]) -> VfsResult<usize> {
+        // a read as large as the buffer goes to the file.
+        if self.pos == self.filled && buf.len() >= self.buf.len() {
+            (self.pos, self.filled) = (0, 0);
+            let n = self.file.readat(self.offset, buf)?;
+            self.offset += n;
+            return Ok(n);
+        }
+        let data = self.fill_buf()?;
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl BufRead for InodeReader {
+    fn fill_buf(&mut self) -> VfsResult<&[u8]> {
+        if self.pos == self.filled {
+            let n = self.file.readat(self.offset, &mut self.buf)?;
+            self.offset += n;
+            self.pos = 0;
+            self.filled = n;
+        }
+        Ok(&self.buf[self.pos..self.filled])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.pos = (self.pos + amount).min(self.filled);
+    }
+}
+
+impl Seek for InodeReader {
+    /// A position in the buffer keeps it, another one drops it.
+    fn seek(&mut self, pos: SeekFrom) -> VfsResult<u64> {
+        let offset = seek_offset(pos, self.position(), self.file.as_ref())?;
+        let start = self.offset - self.filled;
+        if (start..=self.offset).contains(&offset) {
+            self.pos = offset - start;
+        } else {
+            self.offset = offset;
+            self.pos = 0;
+            self.filled = 0;
+        }
+        Ok(offset as u64)
+    }
+}
+
+/// A stream writing an inode from an offset through a buffer.
+pub struct InodeWriter {
+    file: Arc<dyn INodeInterface>,
+    /// The offset in the file of the first buffered byte.
+    offset: usize,
+    buf: Vec<u8>,
+    capacity: usize,
+}
+
+impl InodeWriter {
+    /// Write the file from its start with a buffer of DEFAULT_BUF_SIZE.
+    pub fn new(file: Arc<dyn INodeInterface>) -> Self {
+        Self::with_capacity(DEFAULT_BUF_SIZE, file)
+    }
+
+    pub fn with_capacity(capacity: usize, file: Arc<dyn INodeInterface>) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            file,
+            offset: 0,
+            buf: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// The offset in the file of the next byte of the stream.
+    pub fn position(&self) -> usize {
+        self.offset + self.buf.len()
+    }
+
+    /// Write the buffered bytes back and return the file, like
+    /// BufWriter::into_inner.
+    pub fn into_inner(mut self) -> VfsResult<Arc<dyn INodeInterface>> {
+        self.write_buf()?;
+        Ok(self.file.clone())
+    }
+
+    /// Write the buffered bytes to the file. The bytes written before a
+    /// failure leave the buffer.
+    fn write_buf(&mut self) -> VfsResult<()> {
+        let mut pos = 0;
+        let r = loop {
+            if pos == self.buf.len() {
+                break Ok(());
+            }
+            match self.file.writeat(self.offset + pos, &self.buf[pos..]) {
+                Ok(0) => break Err(VfsError::WriteZero),
+                Ok(n) => pos += n,
+                Err(err) => break Err(err),
+            }
+        };
+        self.buf.drain(..pos);
+        self.offset += pos;
+        r
+    }
+}
+
+impl Write for InodeWriter {
+    /// Fill the buffer, a full buffer is written before the next write.
+    fn write(&mut self, buf: &[u8]) -> VfsResult<usize> {
+        if self.buf.len() == self.capacity {
+            self.write_buf()?;
+        }
+        // a write as large as the buffer goes to the file.
+        if self.buf.is_empty() && buf.len() >= self.capacity {
+            let n = self.file.writeat(self.offset, buf)?;
+            self.offset += n;
+            return Ok(n);
+        }
+        let len = buf.len().min(self.capacity - self.buf.len());
+        self.buf.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    /// Write the buffered bytes to the file, the file isn't synced, see
+    /// fsync.rs.
+    fn flush(&mut self) -> VfsResult<()> {
+        self.write_buf()
+    }
+}
+
+impl Seek for InodeWriter {
+    /// The buffered bytes are written before the position moves.
+    fn seek(&mut self, pos: SeekFrom) -> VfsResult<u64> {
+        self.write_buf()?;
+        self.offset = seek_offset(pos, self.offset, self.file.as_ref())?;
+        Ok(self.offset as u64)
+    }
+}
+
+impl Drop for InodeWriter {
+    fn drop(&mut self) {
+        if let Err(err) = self.write_buf() {
+            log::error!(
+                "the write back of {} buffered bytes failed: {:?}",
+                self.buf.len(),
+                err
+            );
+        }
+    }
+}
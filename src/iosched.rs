@@ -0,0 +1,526 @@
+// The I/O scheduler between the filesystems and a device, so a stream of
+// background requests doesn't starve the requests a caller waits on. A
+// request carries an IoPriority and waits in the queue of its priority,
+// the dispatches take from the queues by their weights in a round, so
+// the lower priorities get their share and never starve. The queue is
+// bounded by its depth: a submit to a full queue dispatches first, like
+// a writer throttled by the device, except the Sync requests which are
+// queued at once. A dispatch merges the next requests of the same queue
+// and kind which start where the batch ends, up to MAX_MERGE bytes, into
+// one request of the device.
+//
+// The requests overlapping a pending one with a write among them keep
+// the order of their submission: they go to the queue of the highest
+// priority of them, the lower ones are boosted. sync() boosts the
+// pending writes to Sync and dispatches them, so an fsync doesn't wait
+// behind the readahead for the background writeback it needs, and
+// flush_device and write_fua of blockdev sync the scheduler of the device
+// first.
+//
+// The queued Sync requests are the pressure of the device, the
+// background work of the filesystems yields to them, see
+// Ext4FileSystem::background_work.
+//
+// There's no dispatcher thread, the callers waiting on their requests
+// dispatch the queue, one dispatch at a time. IoScheduler is a
+// BlockDevice of ext4_rs whose requests are Sync, the callers knowing the
+// priority of their requests submit them.
+// TODO: tag the readahead and the writeback of ext4_rs_shim, its reads go
+// through ext4_rs which can't pass a priority.
+
+use core::{
+    cmp::min,
+    fmt::{self, Debug},
+};
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+
+use crate::blockdev::{BlockDevice, RamDevice, SectorDevice, READ_SIZE, SECTOR_SIZE};
+use crate::stats::{self, StatsSource};
+use crate::sys::{get_blk_device, Mutex};
+
+/// The most bytes of a request merged by a dispatch.
+pub const MAX_MERGE: usize = 128 << 10;
+
+/// The dispatches of the priorities in a round, by their index.
+const WEIGHTS: [usize; 3] = [8, 2, 1];
+
+/// The priority of a request, the first one is the highest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IoPriority {
+    /// The metadata, the reads a caller waits on and the fsync.
+    Sync,
+    /// The reads ahead of a reader.
+    Readahead,
+    /// The background writeback.
+    Writeback,
+}
+
+impl IoPriority {
+    pub const ALL: [IoPriority; 3] = [
+        IoPriority::Sync,
+        IoPriority::Readahead,
+        IoPriority::Writeback,
+    ];
+}
+
+/// A device taking the requests of any length at any byte offset, the
+/// merged requests of IoScheduler.
+pub trait RequestDevice: Send + Sync {
+    fn read_range(&self, offset: usize, buf: &mut [u8]);
+    fn write_range(&self, offset: usize, buf: &[u8]);
+}
+
+/// A submitted request, wait on it for its end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ticket(u64);
+
+/// The counters of a scheduler, the requests by their priority.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SchedStats {
+    /// The submitted requests by the index of their priority.
+    pub submitted: [usize; 3],
+    /// The requests of the device, a merged batch is one.
+    pub device_requests: usize,
+    /// The requests merged into the batch of another one.
+    pub merged: usize,
+    /// The requests moved to a higher priority.
+    pub boosted: usize,
+    /// The submits which dispatched to make room.
+    pub throttled: usize,
+    pub max_depth: usize,
+}
+
+/// The range of a request, and whether it writes.
+type Span = (usize, usize, bool);
+
+struct Request {
+    id: u64,
+    offset: usize,
+    len: usize,
+    /// The data of a write, None for a read.
+    data: Option<Vec<u8>>,
+}
+
+impl Request {
+    fn span(&self) -> Span {
+        (self.offset, self.offset + self.len, self.data.is_some())
+    }
+
+    /// Whether the request and the span overlap with a write among them.
+    fn conflicts(&self, (start, end, write): Span) -> bool {
+        (write || self.data.is_some()) && self.offset < end && start < self.offset + self.len
+    }
+}
+
+struct Queue {
+    classes: [VecDeque<Request>; 3],
+    /// The dispatches left to the priorities in this round.
+    credits: [usize; 3],
+    /// The requests taken by the running dispatch.
+    in_flight: BTreeSet<u64>,
+    /// The data of the done reads until they're waited.
+    reads: BTreeMap<u64, Vec<u8>>,
+    next_id: u64,
+    stats: SchedStats,
+}
+
+impl Queue {
+    fn depth(&self) -> usize {
+        self.classes.iter().map(VecDeque::len).sum()
+    }
+
+    fn pending(&self, id: u64) -> bool {
+        self.in_flight.contains(&id) || self.classes.iter().flatten().any(|x| x.id == id)
+    }
+
+    /// The pending requests conflicting with the spans, and with those,
+    /// by their id and the index of their priority.
+    fn linked(&self, mut spans: Vec<Span>, mut linked: Vec<(usize, u64)>) -> Vec<(usize, u64)> {
+        loop {
+            let found = linked.len();
+            for (class, queue) in self.classes.iter().enumerate() {
+                for x in queue {
+                    if !linked.iter().any(|(_, id)| *id == x.id)
+                        && spans.iter().any(|span| x.conflicts(*span))
+                    {
+                        linked.push((class, x.id));
+                        spans.push(x.span());
+                    }
+                }
+            }
+            if linked.len() == found {
+                return linked;
+            }
+        }
+    }
+
+    /// Move the linked requests of the lower priorities to the class,
+    /// in the order of their submission.
+    fn raise(&mut self, linked: &[(usize, u64)], class: usize) {
+        let mut moved = Vec::new();
+        for lower in class + 1..self.classes.len() {
+            let queue: Vec<_> = self.classes[lower].drain(..).collect();
+            for x in queue {
+                match linked.iter().any(|(_, id)| *id == x.id) {
+                    true => moved.push(x),
+                    false => self.classes[lower].push_back(x),
+                }
+            }
+        }
+        moved.sort_by_key(|x| x.id);
+        self.stats.boosted += moved.len();
+        self.classes[class].extend(moved);
+    }
+
+    fn push(
+        &mut self,
+        offset: usize,
+        len: usize,
+        data: Option<Vec<u8>>,
+        priority: IoPriority,
+    ) -> Ticket {
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = Request {
+            id,
+            offset,
+            len,
+            data,
+        };
+        self.stats.submitted[priority as usize] += 1;
+        // the conflicting requests share a queue, which keeps their order.
+        let linked = self.linked(vec![request.span()], Vec::new());
+        let class = linked
+            .iter()
+            .map(|(class, _)| *class)
+            .fold(priority as usize, min);
+        self.raise(&linked, class);
+        self.classes[class].push_back(request);
+        self.stats.max_depth = self.stats.max_depth.max(self.depth());
+        Ticket(id)
+    }
+
+    /// The class of the next dispatch, by the credits of the round.
+    fn pick(&mut self) -> Option<usize> {
+        let ready = |q: &Self, class: usize| !q.classes[class].is_empty() && q.credits[class] > 0;
+        if !(0..WEIGHTS.len()).any(|x| ready(self, x)) {
+            self.credits = WEIGHTS;
+        }
+        let class = (0..WEIGHTS.len()).find(|x| ready(self, *x))?;
+        self.credits[class] -= 1;
+        Some(class)
+    }
+
+    /// Take the next request of the class with the requests it merges.
+    fn batch(&mut self, class: usize) -> Vec<Request> {
+        let queue = &mut self.classes[class];
+        let Some(head) = queue.pop_front() else {
+            return Vec::new();
+        };
+        let write = head.data.is_some();
+        let mut end = head.offset + head.len;
+        let mut batch = vec![head];
+        while let Some(pos) = queue
+            .iter()
+            .position(|x| x.offset == end && x.data.is_some() == write)
+            && end - batch[0].offset + queue[pos].len <= MAX_MERGE
+            // the request can't pass those it conflicts with.
+            && !queue.iter().take(pos).any(|x| x.conflicts(queue[pos].span()))
+        {
+            let next = queue.remove(pos).unwrap();
+            end += next.len;
+            batch.push(next);
+        }
+        self.stats.merged += batch.len() - 1;
+        batch
+    }
+}
+
+/// The scheduler of a device, see the module.
+pub struct IoScheduler {
+    name: String,
+    device: Arc<dyn RequestDevice>,
+    /// The most queued requests but the Sync ones.
+    depth: usize,
+    queue: Mutex<Queue>,
+    /// Held across a dispatch, the batches reach the device in the order
+    /// they were taken.
+    dispatch: Mutex<()>,
+}
+
+impl IoScheduler {
+    /// A scheduler queueing at most depth requests, its counters are in
+    /// the stats under name.
+    pub fn new(name: &str, device: Arc<dyn RequestDevice>, depth: usize) -> Arc<Self> {
+        let scheduler = Arc::new(Self {
+            name: name.to_string(),
+            device,
+            depth: depth.max(1),
+            queue: Mutex::new(Queue {
+                classes: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+                credits: WEIGHTS,
+                in_flight: BTreeSet::new(),
+                reads: BTreeMap::new(),
+                next_id: 0,
+                stats: SchedStats::default(),
+            }),
+            dispatch: Mutex::new(()),
+        });
+        stats::register(Arc::downgrade(&scheduler) as Weak<dyn StatsSource>);
+        scheduler
+    }
+
+    fn submit(
+        &self,
+        offset: usize,
+        len: usize,
+        data: Option<Vec<u8>>,
+        priority: IoPriority,
+    ) -> Ticket {
+        loop {
+            {
+                let mut queue = self.queue.lock();
+                if priority == IoPriority::Sync || queue.depth() < self.depth {
+                    return queue.push(offset, len, data, priority);
+                }
+                queue.stats.throttled += 1;
+            }
+            self.dispatch();
+        }
+    }
+
+    /// Submit a read of len bytes at offset, its ticket must be waited.
+    pub fn submit_read(&self, offset: usize, len: usize, priority: IoPriority) -> Ticket {
+        self.submit(offset, len, None, priority)
+    }
+
+    pub fn submit_write(&self, offset: usize, data: Vec<u8>, priority: IoPriority) -> Ticket {
+        self.submit(offset, data.len(), Some(data), priority)
+    }
+
+    /// Dispatch until the request is done, return the data of a read, an
+    /// empty one for a write.
+    pub fn wait(&self, ticket: Ticket) -> Vec<u8> {
+        loop {
+            {
+                let mut queue = self.queue.lock();
+                if let Some(data) = queue.reads.remove(&ticket.0) {
+                    return data;
+                }
+                if !queue.pending(ticket.0) {
+                    return Vec::new();
+                }
+            }
+            self.dispatch();
+        }
+    }
+
+    pub fn read(&self, offset: usize, buf: &mut [u8], priority: IoPriority) {
+        let data = self.wait(self.submit_read(offset, buf.len(), priority));
+        buf.copy_from_slice(&data);
+    }
+
+    pub fn write(&self, offset: usize, buf: &[u8], priority: IoPriority) {
+        self.wait(self.submit_write(offset, buf.to_vec(), priority));
+    }
+
+    /// Dispatch the next batch, return false if the queue was empty.
+    pub fn dispatch(&self) -> bool {
+        let _dispatch = self.dispatch.lock();
+        let batch = {
+            let mut queue = self.queue.lock();
+            let Some(class) = queue.pick() else {
+                return false;
+            };
+            let batch = queue.batch(class);
+            queue.in_flight.extend(batch.iter().map(|x| x.id));
+            queue.stats.device_requests += 1;
+            batch
+        };
+        let offset = batch[0].offset;
+        let len = batch.iter().map(|x| x.len).sum();
+        let mut queue = match batch[0].data.is_some() {
+            true => {
+                let data: Vec<u8> = batch
+                    .iter()
+                    .flat_map(|x| x.data.iter().flatten())
+                    .copied()
+                    .collect();
+                self.device.write_range(offset, &data);
+                self.queue.lock()
+            }
+            false => {
+                let mut data = vec![0; len];
+                self.device.read_range(offset, &mut data);
+                let mut queue = self.queue.lock();
+                let mut pos = 0;
+                for x in batch.iter() {
+                    queue.reads.insert(x.id, data[pos..pos + x.len].to_vec());
+                    pos += x.len;
+                }
+                queue
+            }
+        };
+        for x in batch.iter() {
+            queue.in_flight.remove(&x.id);
+        }
+        true
+    }
+
+    /// Dispatch until the queue is empty.
+    pub fn drain(&self) {
+        while self.dispatch() {}
+    }
+
+    /// Boost the pending writes to Sync and dispatch until they're done,
+    /// see the module.
+    pub fn sync(&self) {
+        {
+            let mut queue = self.queue.lock();
+            let seeds: Vec<_> = queue
+                .classes
+                .iter()
+                .enumerate()
+                .flat_map(|(class, x)| x.iter().map(move |x| (class, x)))
+                .filter(|(_, x)| x.data.is_some())
+                .map(|(class, x)| ((class, x.id), x.span()))
+                .collect();
+            let (linked, spans): (Vec<_>, Vec<_>) = seeds.into_iter().unzip();
+            let linked = queue.linked(spans, linked);
+            queue.raise(&linked, IoPriority::Sync as usize);
+        }
+        let writing = |q: &Queue| q.classes.iter().flatten().any(|x| x.data.is_some());
+        while writing(&self.queue.lock()) {
+            self.dispatch();
+        }
+        // and the batch of the running dispatch.
+        drop(self.dispatch.lock());
+    }
+
+    /// The queued requests.
+    pub fn depth(&self) -> usize {
+        self.queue.lock().depth()
+    }
+
+    /// The queued Sync requests, see the module.
+    pub fn pressure(&self) -> usize {
+        self.queue.lock().classes[IoPriority::Sync as usize].len()
+    }
+
+    pub fn stats(&self) -> SchedStats {
+        self.queue.lock().stats.clone()
+    }
+}
+
+impl Debug for IoScheduler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let queue = self.queue.lock();
+        f.debug_struct("IoScheduler")
+            .field("name", &self.name)
+            .field("depth", &queue.depth())
+            .field("stats", &queue.stats)
+            .finish()
+    }
+}
+
+impl StatsSource for IoScheduler {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn counters(&self) -> Vec<(&'static str, usize)> {
+        let queue = self.queue.lock();
+        let stats = &queue.stats;
+        vec![
+            ("queue_depth", queue.depth()),
+            ("pressure", queue.classes[IoPriority::Sync as usize].len()),
+            ("max_depth", stats.max_depth),
+            ("sync_requests", stats.submitted[IoPriority::Sync as usize]),
+            (
+                "readahead_requests",
+                stats.submitted[IoPriority::Readahead as usize],
+            ),
+            (
+                "writeback_requests",
+                stats.submitted[IoPriority::Writeback as usize],
+            ),
+            ("device_requests", stats.device_requests),
+            ("merged", stats.merged),
+            ("boosted", stats.boosted),
+            ("throttled", stats.throttled),
+        ]
+    }
+}
+
+/// The requests of ext4_rs are Sync.
+impl BlockDevice for IoScheduler {
+    fn read_offset(&self, offset: usize) -> Vec<u8> {
+        self.wait(self.submit_read(offset, READ_SIZE, IoPriority::Sync))
+    }
+
+    fn write_offset(&self, offset: usize, buf: &[u8]) {
+        self.write(offset, buf, IoPriority::Sync);
+    }
+}
+
+/// The aligned requests are one request of the sys device.
+impl RequestDevice for SectorDevice {
+    fn read_range(&self, offset: usize, buf: &mut [u8]) {
+        if offset % SECTOR_SIZE == 0 && buf.len() % SECTOR_SIZE == 0 {
+            let device = get_blk_device(self.device_id()).unwrap();
+            device.read_blocks(offset / SECTOR_SIZE, buf);
+            return;
+        }
+        for (i, chunk) in buf.chunks_mut(READ_SIZE).enumerate() {
+            let data = self.read_offset(offset + i * READ_SIZE);
+            chunk.copy_from_slice(&data[..chunk.len()]);
+        }
+    }
+
+    fn write_range(&self, offset: usize, buf: &[u8]) {
+        self.write_offset(offset, buf);
+    }
+}
+
+impl RequestDevice for RamDevice {
+    fn read_range(&self, offset: usize, buf: &mut [u8]) {
+        for (i, chunk) in buf.chunks_mut(READ_SIZE).enumerate() {
+            let data = self.read_offset(offset + i * READ_SIZE);
+            chunk.copy_from_slice(&data[..chunk.len()]);
+        }
+    }
+
+    fn write_range(&self, offset: usize, buf: &[u8]) {
+        self.write_offset(offset, buf);
+    }
+}
+
+/// The schedulers of the devices by the device number.
+static SCHEDULERS: Mutex<BTreeMap<usize, Arc<IoScheduler>>> = Mutex::new(BTreeMap::new());
+
+/// Set the scheduler of the device, flush_device syncs it.
+pub fn set_scheduler(dev: usize, scheduler: Arc<IoScheduler>) {
+    SCHEDULERS.lock().insert(dev, scheduler);
+}
+
+pub fn scheduler(dev: usize) -> Option<Arc<IoScheduler>> {
+    SCHEDULERS.lock().get(&dev).cloned()
+}
+
+/// The pressure of the scheduler of the device, 0 if it has none.
+pub fn pressure(dev: usize) -> usize {
+    scheduler(dev).map_or(0, |x| x.pressure())
+}
+
+/// Sync the scheduler of the device, nothing if it has none.
+pub fn sync(dev: usize) {
+    if let Some(scheduler) = scheduler(dev) {
+        scheduler.sync();
+    }
+}
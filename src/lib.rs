@@ -1,4 +1,4 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 #![feature(extract_if)]
 #![feature(associated_type_bounds)]
 #![feature(let_chains)]
@@ -10,27 +10,87 @@ use core::{
     usize,
 };
 
-use alloc::{
-    string::{String, ToString},
-    sync::Arc,
-    vec::Vec,
-};
+#[cfg(feature = "kernel")]
+use alloc::string::{String, ToString};
+use alloc::{sync::Arc, vec::Vec};
+#[cfg(feature = "kernel")]
 use devfs::{DevDir, DevFS, Sdx};
-use devices::get_blk_devices;
-use procfs::ProcFS;
+#[cfg(feature = "kernel")]
 use ramfs::RamFs;
-use sync::LazyInit;
 use vfscore::{FileSystem, VfsResult};
 
-use crate::dentry::{dentry_init, DentryNode};
+#[cfg(feature = "kernel")]
+use crate::capabilities::FsCapabilities;
+#[cfg(feature = "kernel")]
+use crate::dentry::{DentryNode, DENTRY_TREE};
+use crate::sys::LazyInit;
+#[cfg(feature = "kernel")]
+use crate::sys::Mutex;
 
 #[macro_use]
 extern crate alloc;
+#[cfg(feature = "kernel")]
 #[macro_use]
 extern crate logging;
+#[cfg(feature = "std")]
+#[macro_use]
+extern crate log;
 
+#[cfg(all(feature = "kernel", feature = "std"))]
+compile_error!("the kernel and std features are exclusive, build std with --no-default-features");
+#[cfg(not(any(feature = "kernel", feature = "std")))]
+compile_error!("either the kernel or the std feature is required");
+
+#[cfg(feature = "async")]
+pub mod aio;
+pub mod archive;
+pub mod atime;
+pub mod batch;
+#[cfg(feature = "bench")]
+pub mod bench;
+#[cfg(root_fs = "ext4_rs")]
+pub mod blockdev;
+pub mod boot;
+pub mod cache;
+pub mod cancel;
+pub mod capabilities;
+pub mod chardev;
+#[cfg(feature = "testsuite")]
+pub mod crash;
+// The on-disk modules below are shared by ext4_mkfs and the ext4_rs shim,
+// without the shim the parts only it reaches are unused.
+#[cfg_attr(not(root_fs = "ext4_rs"), allow(dead_code))]
+mod crc32c;
 pub mod dentry;
+pub mod devnode;
+pub mod direct;
+#[cfg_attr(not(root_fs = "ext4_rs"), allow(dead_code))]
+mod disk_layout;
+pub mod error;
+pub mod export;
+#[cfg(any(root_fs = "ext4_rs", feature = "fuzzing"))]
+#[cfg_attr(not(root_fs = "ext4_rs"), allow(dead_code))]
+mod ext4_check;
+#[cfg_attr(not(root_fs = "ext4_rs"), allow(dead_code))]
+mod ext4_csum;
+#[cfg(all(root_fs = "ext4_rs", feature = "ext4_debug"))]
+mod ext4_debug;
+#[cfg(root_fs = "ext4_rs")]
+mod ext4_extent;
+#[cfg(any(root_fs = "ext4_rs", feature = "fuzzing"))]
+#[cfg_attr(not(root_fs = "ext4_rs"), allow(dead_code))]
+mod ext4_htree;
+#[cfg_attr(not(root_fs = "ext4_rs"), allow(dead_code))]
+mod ext4_journal;
+#[cfg_attr(not(root_fs = "ext4_rs"), allow(dead_code))]
+mod ext4_layout;
+pub mod ext4_mkfs;
+pub mod fallocate;
+#[cfg(root_fs = "fat32")]
+mod fat_layout;
 
+#[cfg(root_fs = "ext4_rs")]
+mod ext4_orphan;
 #[cfg(root_fs = "ext4_rs")]
 mod ext4_rs_shim;
 #[cfg(root_fs = "ext4")]
@@ -38,16 +98,70 @@ mod ext4_shim;
 #[cfg(root_fs = "fat32")]
 mod fatfs_shim;
 
+pub mod freeze;
+pub mod fstype;
+pub mod fsync;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+#[cfg(feature = "testsuite")]
+pub mod golden;
+pub mod handle;
+mod hook;
+pub mod inode_flags;
+pub mod io;
+#[cfg(root_fs = "ext4_rs")]
+pub mod iosched;
+pub mod mapping;
+pub mod mknod;
+#[cfg(all(feature = "std", feature = "testsuite"))]
+pub mod model;
+pub mod mounts;
+mod node;
+pub mod ops;
+pub mod owner;
+pub mod pathconf;
 pub mod pipe;
+pub mod proc_pid;
+pub mod pseudo;
+pub mod quota;
+pub mod readdir;
+pub mod reflink;
+pub mod rename;
+pub mod revalidate;
+pub mod snapshot;
+pub mod statfs;
+pub mod stats;
+pub mod statx;
+pub mod sys;
+#[cfg(feature = "testsuite")]
+pub mod testing;
+#[cfg(feature = "testsuite")]
+pub mod testsuite;
+pub mod tmpfs;
+pub mod trace;
+pub mod tunefs;
+pub mod volume;
+pub mod walk;
 
 pub type File = Arc<dyn INodeInterface>;
 
+#[cfg(root_fs = "ext4_rs")]
+pub use ext4_layout::{ErrorCode, ErrorHistory, ErrorRecord};
+#[cfg(root_fs = "ext4_rs")]
+pub use ext4_rs_shim::{
+    BackgroundStats, DiskHealth, Ext4Builder, Ext4FileSystem, MountCancel, MountError,
+    MountOptions, MountPhase, SectorErrors,
+};
+pub use fstype::resolve_source;
+pub use ops::{NAME_MAX, PATH_MAX};
 pub use vfscore::{
     FileType, INodeInterface, OpenFlags, PollEvent, PollFd, SeekFrom, Stat, StatFS, StatMode,
     TimeSpec, VfsError, UTIME_NOW, UTIME_OMIT,
 };
+
 pub static FILESYSTEMS: LazyInit<Vec<Arc<dyn FileSystem>>> = LazyInit::new();
 
+#[cfg(feature = "kernel")]
 pub fn build_devfs(filesystems: &Vec<(Arc<dyn FileSystem>, &str)>) -> Arc<DevFS> {
     let dev_sdxs: Vec<_> = filesystems
         .iter()
@@ -66,68 +180,85 @@ pub fn build_devfs(filesystems: &Vec<(Arc<dyn FileSystem>, &str)>) -> Arc<DevFS>
 
     // TODO: add fs normal, not fixed.
     dev_dir.add("sda", dev_sdxs[0].clone());
+    // the raw disks and their partitions, /dev/vda and /dev/vda1. devfs
+    // keeps the names, they live as long as the nodes.
+    for (name, node) in devnode::block_nodes() {
+        dev_dir.add(alloc::boxed::Box::leak(name.into_boxed_str()), node);
+    }
+    for (name, node) in chardev::mem_nodes() {
+        dev_dir.add(name, node);
+    }
 
     DevFS::new_with_dir(dev_dir)
 }
 
+/// Bring up the default mount tree, see init_with. A root which doesn't
+/// mount panics, the kernel can't go on without one.
+#[cfg(feature = "kernel")]
 pub fn init() {
+    if let Err(err) = init_with(boot::BootFsConfig::default()) {
+        panic!("can't mount the rootfs: {:?}", err);
+    }
+}
+
+/// Bring up the mount tree of the config with boot.rs, then install it
+/// as the dentry tree and its filesystems as FILESYSTEMS. Only a root
+/// which doesn't mount fails, the report tells what else did.
+#[cfg(feature = "kernel")]
+pub fn init_with(config: boot::BootFsConfig) -> VfsResult<boot::BootReport> {
     info!("fs module initialized");
 
-    // TODO: Identify the filesystem at the device.
-    let mut filesystems: Vec<(Arc<dyn FileSystem>, &str)> = Vec::new();
-    if get_blk_devices().len() > 0 {
-        #[cfg(root_fs = "fat32")]
-        filesystems.push((fatfs_shim::Fat32FileSystem::new(0), "/"));
-        #[cfg(root_fs = "ext4")]
-        filesystems.push((ext4_shim::Ext4FileSystem::new(0), "/"));
-        #[cfg(root_fs = "ext4_rs")]
-        filesystems.push((ext4_rs_shim::Ext4FileSystem::new(0), "/"));
-    } else {
-        filesystems.push((RamFs::new(), "/"));
+    fstype::init();
+    let report = boot::boot(&config)?;
+    info!("mount rootfs end, rootfs: {}", report.root_fstype);
+    for line in report.to_string().lines() {
+        info!("{}", line);
     }
-    info!("mount rootfs end, rootfs: {}", filesystems[0].0.name());
-    filesystems.push((build_devfs(&filesystems), "/dev"));
-    filesystems.push((RamFs::new(), "/tmp"));
-    filesystems.push((RamFs::new(), "/dev/shm"));
-    filesystems.push((RamFs::new(), "/home"));
-    filesystems.push((RamFs::new(), "/var"));
-    filesystems.push((ProcFS::new(), "/proc"));
-    // filesystems.push((RamFs::new(), "/bin"));
 
     // mount to FILESYSTEMS
-    FILESYSTEMS.init_by(filesystems.iter().map(|(fs, _)| fs.clone()).collect());
-
-    // init mount points
-    info!("create fatfs mount file");
-    {
-        // create monnt point dev, tmp
-        // let fs = &filesystems[0].0;
-        // let rootfs = filesystems[0].0.root_dir();
-        let rootfs = get_filesystem(0).root_dir();
-        let dev = rootfs.mkdir("dev").expect("can't create devfs dir");
-        dev.mkdir("shm").expect("can't create shm dir");
-        rootfs.mkdir("tmp").expect("can't create tmp dir");
-        // rootfs.mkdir("lib").expect("can't create lib dir");
-        rootfs.mkdir("home").expect("can't create home dir");
-        rootfs.mkdir("var").expect("can't create var dir");
-        rootfs.mkdir("proc").expect("can't create proc dir");
-        rootfs.mkdir("bin").expect("can't create var dir");
-    }
-    for (i, (_, mount_point)) in filesystems.iter().enumerate() {
-        // mount(mount_point.to_string(), i).expect(&format!("can't mount fs_{i} {mount_point}"));
-        if *mount_point == "/" {
-            dentry_init(get_filesystem(i).root_dir())
-        } else {
-            DentryNode::mount(mount_point.to_string(), get_filesystem(i).root_dir())
-                .expect(&format!("can't mount fs_{i} {mount_point}"));
-        }
-    }
+    FILESYSTEMS.init_by(
+        report
+            .filesystems
+            .iter()
+            .map(|(fs, _)| fs.clone())
+            .collect(),
+    );
+    DENTRY_TREE.init_by(Mutex::new(report.root.clone()));
+    stats::init_procfs();
+    fstype::init_procfs();
+    mounts::init_procfs();
+    proc_pid::init();
+    Ok(report)
+}
+
+/// A RamFs with its capabilities, its f_type isn't one of statfs.rs.
+#[cfg(feature = "kernel")]
+fn new_ramfs() -> Arc<dyn FileSystem> {
+    let fs: Arc<dyn FileSystem> = RamFs::new();
+    capabilities::register(&fs, FsCapabilities::RAMFS);
+    fs
 }
 
 pub fn get_filesystem(id: usize) -> &'static Arc<dyn FileSystem> {
     &FILESYSTEMS[id]
 }
 
+/// Flush every mounted filesystem, used by the sync syscall and shutdown.
+/// All filesystems are flushed even if some of them failed,
+/// the first error will be returned.
+pub fn sync_all() -> VfsResult<()> {
+    let mut res = Ok(());
+    for fs in FILESYSTEMS.iter() {
+        if let Err(err) = fs.flush() {
+            log::warn!("sync filesystem {} failed: {:?}", fs.name(), err);
+            if res.is_ok() {
+                res = Err(err);
+            }
+        }
+    }
+    res
+}
+
 pub struct WaitBlockingRead<'a>(pub Arc<dyn INodeInterface>, pub &'a mut [u8], pub usize);
 
 impl<'a> Future for WaitBlockingRead<'a> {
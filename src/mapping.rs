@@ -0,0 +1,110 @@
+// The mappings of the files by the mm of the kernel. A mapping of ext4
+// holds copies of the pages, so a file which changes under it must tell
+// it: the mm registers a MappingHook for the inode it maps, by its
+// InodeId, and the filesystems call it around their changes. The fs crate
+// owns the order of the calls, the mm the pages:
+// - truncate shrinking a file and a punched hole invalidate the range
+//   before the change, a mapping drops its pages there, and zeroes the
+//   tail of a page the range starts in, before the blocks are freed, so
+//   its writeback can't extend the file again or fill the hole.
+// - a write invalidates the range it wrote after the data is in place
+//   and before it returns, a fault after it reads the new data.
+// - a sync writes the range of the mappings back first, so the sync
+//   makes their stores durable too.
+// The invalidations of the truncations and the holes are made with the
+// locks of the file held, the hook mustn't call the file then. The
+// writeback is made without them, it writes the dirty pages through
+// writeat, whose invalidations the mm ignores for the pages it writes.
+// tmpfs shares its pages with the mappings, see tmpfs.rs, so its writes
+// are coherent and only its truncations and holes invalidate.
+
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::{collections::BTreeMap, sync::Arc};
+use vfscore::{INodeInterface, Stat, VfsResult};
+
+use crate::cache::InodeId;
+use crate::sys::Mutex;
+
+/// The callbacks of the mm for a mapped inode, the ranges are in bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct MappingHook {
+    /// Drop the pages of the range, zero the part of the first page from
+    /// the start of the range.
+    pub invalidate_range: fn(InodeId, Range<usize>),
+    /// Write the dirty pages of the range back to the file.
+    pub writeback_range: fn(InodeId, Range<usize>) -> VfsResult<()>,
+}
+
+static HOOKS: Mutex<BTreeMap<InodeId, MappingHook>> = Mutex::new(BTreeMap::new());
+/// The count of HOOKS, the files which aren't mapped skip the lock.
+static MAPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Register the hook of the inode, replacing its previous one. The mm
+/// unregisters it when the last mapping of the inode goes.
+pub fn register(id: InodeId, hook: MappingHook) {
+    let mut hooks = HOOKS.lock();
+    hooks.insert(id, hook);
+    MAPPED.store(hooks.len(), Ordering::Relaxed);
+}
+
+pub fn unregister(id: InodeId) {
+    let mut hooks = HOOKS.lock();
+    hooks.remove(&id);
+    MAPPED.store(hooks.len(), Ordering::Relaxed);
+}
+
+/// The InodeId of the file, by the dev and ino of its stat.
+pub fn inode_id(file: &Arc<dyn INodeInterface>) -> VfsResult<InodeId> {
+    let mut stat = Stat::default();
+    file.stat(&mut stat)?;
+    Ok(InodeId {
+        dev: stat.dev as usize,
+        ino: stat.ino as u64,
+    })
+}
+
+fn hook_of(id: InodeId) -> Option<MappingHook> {
+    match MAPPED.load(Ordering::Relaxed) {
+        0 => None,
+        _ => HOOKS.lock().get(&id).copied(),
+    }
+}
+
+/// Whether an inode has a hook, the callers skip the InodeId otherwise.
+pub(crate) fn is_active() -> bool {
+    MAPPED.load(Ordering::Relaxed) != 0
+}
+
+/// Invalidate the range of the mappings of the inode, see the module.
+pub(crate) fn invalidate(id: InodeId, range: Range<usize>) {
+    if range.is_empty() {
+        return;
+    }
+    if let Some(hook) = hook_of(id) {
+        (hook.invalidate_range)(id, range);
+    }
+}
+
+/// Write the range of the mappings of the inode back, see the module.
+pub(crate) fn writeback(id: InodeId, range: Range<usize>) -> VfsResult<()> {
+    match hook_of(id) {
+        Some(hook) if !range.is_empty() => (hook.writeback_range)(id, range),
+        _ => Ok(()),
+    }
+}
+
+/// Write the range of the mappings of the file back, a file which can't
+/// be stat'ed isn't mapped.
+pub(crate) fn writeback_file(file: &Arc<dyn INodeInterface>, range: Range<usize>) -> VfsResult<()> {
+    if !is_active() {
+        return Ok(());
+    }
+    match inode_id(file) {
+        Ok(id) => writeback(id, range),
+        Err(_) => Ok(()),
+    }
+}
@@ -0,0 +1,119 @@
+// The special files of mknod(2): the FIFOs, the sockets and the device
+// nodes. INodeInterface of vfscore only makes files and directories, so the
+// directories which can make the others implement MknodINode and hand
+// it out by their FsNode, like the nodes of owner.rs. A device node keeps its
+// device number, the rdev of its stat, but it isn't opened as the device,
+// the devices are those of devnode.rs and chardev.rs. A mode of a regular
+// file makes an empty file, like Linux. mknod has no cred, the syscall
+// checks that only root makes a device node.
+
+use alloc::sync::Arc;
+use vfscore::{FileType, INodeInterface, VfsError, VfsResult};
+
+use crate::error::{Errno, FsError, FsResult};
+use crate::node;
+use crate::readdir::{self, DirChange};
+
+/// The bits of the type in a mode, S_IFMT.
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFIFO: u32 = 0o010000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFBLK: u32 = 0o060000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFSOCK: u32 = 0o140000;
+
+/// The type of a special file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    Fifo,
+    Char,
+    Block,
+    Socket,
+}
+
+impl NodeKind {
+    /// The kind of the type bits of the mode, None for the other types.
+    pub fn from_mode(mode: u32) -> Option<Self> {
+        match mode & S_IFMT {
+            S_IFIFO => Some(Self::Fifo),
+            S_IFCHR => Some(Self::Char),
+            S_IFBLK => Some(Self::Block),
+            S_IFSOCK => Some(Self::Socket),
+            _ => None,
+        }
+    }
+
+    /// The type bits of the mode of the kind.
+    pub fn mode(self) -> u32 {
+        match self {
+            Self::Fifo => S_IFIFO,
+            Self::Char => S_IFCHR,
+            Self::Block => S_IFBLK,
+            Self::Socket => S_IFSOCK,
+        }
+    }
+
+    /// The FileType of vfscore, which has no FIFO, the FIFOs and the
+    /// devices are Device and told apart by their mode.
+    pub fn file_type(self) -> FileType {
+        match self {
+            Self::Socket => FileType::Socket,
+            _ => FileType::Device,
+        }
+    }
+
+    /// A device node, with a device number.
+    pub fn is_device(self) -> bool {
+        matches!(self, Self::Char | Self::Block)
+    }
+}
+
+pub trait MknodINode: Send + Sync {
+    /// Make the special file name of the kind in this directory, with the
+    /// permission bits perm. rdev is the device number of Linux of a
+    /// device node, 0 for the others. An existing name fails with
+    /// AlreadyExists, an rdev the filesystem can't store with InvalidInput.
+    fn mknod(
+        &self,
+        name: &str,
+        kind: NodeKind,
+        perm: u32,
+        rdev: u64,
+    ) -> VfsResult<Arc<dyn INodeInterface>>;
+}
+
+/// Make the file name in the directory like mknod, mode holds the type and
+/// the permission bits. A FIFO, a socket or a device node of a directory
+/// which can't make them fails with NotSupported, a directory with EPERM
+/// like Linux, an unknown type with InvalidInput.
+pub fn mknod(
+    dir: &Arc<dyn INodeInterface>,
+    name: &str,
+    mode: u32,
+    rdev: u64,
+) -> FsResult<Arc<dyn INodeInterface>> {
+    let kind = match mode & S_IFMT {
+        // the permission bits of a file are those of the filesystem.
+        0 | S_IFREG => {
+            if dir.lookup(name).is_ok() {
+                return Err(VfsError::AlreadyExists.into());
+            }
+            let node = dir.touch(name)?;
+            readdir::changed(dir, name, DirChange::Added);
+            return Ok(node);
+        }
+        S_IFDIR => return Err(FsError::new(VfsError::InvalidInput, Errno::EPERM)),
+        _ => NodeKind::from_mode(mode).ok_or(VfsError::InvalidInput)?,
+    };
+    let rdev = match kind.is_device() {
+        true => rdev,
+        false => 0,
+    };
+    let node = node::fs_node(dir.as_ref()).and_then(|x| x.as_mknod());
+    let node = node
+        .ok_or(VfsError::NotSupported)?
+        .mknod(name, kind, mode & 0o7777, rdev)?;
+    readdir::changed(dir, name, DirChange::Added);
+    Ok(node)
+}
@@ -0,0 +1,653 @@
+// The model-based tests of the filesystems. A run generates a random
+// sequence of operations from a seed and applies it both to a model in
+// memory, a map of the paths to their contents, and to a directory of the
+// filesystem under test. After every operation the tree of the directory
+// must match the model, through the manifests of golden.rs, and the
+// operations must succeed and fail alike. The filesystem is checked at
+// the end of the run, like check of ext4.
+//
+// An operation whose precondition doesn't hold in the model, a write to a
+// directory or a rename of a missing path, is skipped on both sides, so a
+// sequence stays valid when operations are removed from it. A failing
+// sequence is shrunk: chunks of the operations are removed and the writes
+// and truncates made smaller while it still fails, and the failure has
+// the seed and the shrunk sequence to replay it.
+// TODO: compare the errors themselves when the shims agree on them.
+
+use core::fmt::{self, Display, Formatter};
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use vfscore::{VfsError, VfsResult};
+
+use crate::crc32c::crc32c;
+use crate::golden::{diff, pattern, Entry, Kind, Manifest};
+use crate::File;
+
+/// The names of the paths, few so the operations collide.
+const NAMES: &[&str] = &["a", "b", "c", "d"];
+/// The deepest path generated.
+const MAX_DEPTH: usize = 3;
+
+/// A random generator, splitmix64 like the random of chardev.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in [0, max), max must not be 0.
+    pub fn below(&mut self, max: usize) -> usize {
+        (self.next_u64() % max as u64) as usize
+    }
+}
+
+/// An operation of a run, the paths are relative to its directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Create(String),
+    /// Write len bytes of the pattern of seed at offset.
+    Write {
+        path: String,
+        offset: usize,
+        len: usize,
+        seed: u32,
+    },
+    Truncate(String, usize),
+    Rename(String, String),
+    Unlink(String),
+    Mkdir(String),
+    Rmdir(String),
+    /// List the directory and compare the names with the model.
+    Readdir(String),
+    Symlink(String, String),
+}
+
+/// The weights of the operations, 0 never generates one. The filesystems
+/// without renames or symlinks run with 0 for them.
+#[derive(Debug, Clone, Copy)]
+pub struct Weights {
+    pub create: u32,
+    pub write: u32,
+    pub truncate: u32,
+    pub rename: u32,
+    pub unlink: u32,
+    pub mkdir: u32,
+    pub rmdir: u32,
+    pub readdir: u32,
+    pub symlink: u32,
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Self {
+            create: 4,
+            write: 6,
+            truncate: 2,
+            rename: 3,
+            unlink: 2,
+            mkdir: 3,
+            rmdir: 1,
+            readdir: 1,
+            symlink: 1,
+        }
+    }
+}
+
+/// The parameters of a run.
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    pub seed: u64,
+    /// The operations of the sequence.
+    pub steps: usize,
+    pub weights: Weights,
+    /// The writes and truncates stay below this size.
+    pub max_size: usize,
+    /// The links of a directory count its subdirectories, like ext4. The
+    /// links of the directories aren't compared otherwise.
+    pub dir_links: bool,
+    /// The most runs of the shrinking of a failing sequence.
+    pub shrink_runs: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            seed: 0x5eed,
+            steps: 200,
+            weights: Weights::default(),
+            max_size: 0x6000,
+            dir_links: true,
+            shrink_runs: 500,
+        }
+    }
+}
+
+/// The filesystem under test.
+pub trait Target {
+    /// An empty directory for a run, the runs don't share it.
+    fn fresh(&mut self) -> Result<File, String>;
+    /// Check the filesystem at the end of a run.
+    fn check(&mut self) -> Result<(), String>;
+}
+
+/// A failed run, with the sequence to replay it.
+#[derive(Debug, Clone)]
+pub struct ModelFailure {
+    pub seed: u64,
+    /// The operations of the generated sequence.
+    pub generated: usize,
+    /// The shrunk sequence, it fails at step, or at its end for check.
+    pub ops: Vec<Op>,
+    pub step: usize,
+    pub reason: String,
+}
+
+impl Display for ModelFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "seed {:#x}: step {} of {} shrunk from {}: {}",
+            self.seed,
+            self.step,
+            self.ops.len(),
+            self.generated,
+            self.reason
+        )?;
+        for (i, op) in self.ops.iter().enumerate() {
+            writeln!(f, "  {}: {:?}", i, op)?;
+        }
+        Ok(())
+    }
+}
+
+/// A node of the model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    File(Vec<u8>),
+    Dir,
+    Link(String),
+}
+
+/// The tree of a run by the paths, "" is the directory of the run.
+#[derive(Debug, Clone, Default)]
+struct Model(BTreeMap<String, Node>);
+
+fn parent_path(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |x| x.0)
+}
+
+fn is_under(path: &str, dir: &str) -> bool {
+    path.len() > dir.len() && path.starts_with(dir) && path.as_bytes()[dir.len()] == b'/'
+}
+
+impl Model {
+    fn is_dir(&self, path: &str) -> bool {
+        path.is_empty() || self.0.get(path) == Some(&Node::Dir)
+    }
+
+    /// The names in the directory.
+    fn names(&self, dir: &str) -> Vec<String> {
+        self.0
+            .keys()
+            .filter(|x| parent_path(x) == dir)
+            .map(|x| x.rsplit('/').next().unwrap_or(x).to_string())
+            .collect()
+    }
+
+    /// Apply the operation if its precondition holds, return if it must
+    /// succeed or None if it's skipped.
+    fn apply(&mut self, op: &Op) -> Option<bool> {
+        match op {
+            Op::Create(path) | Op::Mkdir(path) | Op::Symlink(path, _) => {
+                if !self.is_dir(parent_path(path)) {
+                    return None;
+                }
+                if self.0.contains_key(path.as_str()) {
+                    return Some(false);
+                }
+                let node = match op {
+                    Op::Create(_) => Node::File(Vec::new()),
+                    Op::Mkdir(_) => Node::Dir,
+                    Op::Symlink(_, target) => Node::Link(target.clone()),
+                    _ => unreachable!(),
+                };
+                self.0.insert(path.clone(), node);
+                Some(true)
+            }
+            Op::Write {
+                path,
+                offset,
+                len,
+                seed,
+            } => {
+                let Some(Node::File(data)) = self.0.get_mut(path.as_str()) else {
+                    return None;
+                };
+                if data.len() < offset + len {
+                    data.resize(offset + len, 0);
+                }
+                data[*offset..offset + len].copy_from_slice(&pattern(*seed, *offset, *len));
+                Some(true)
+            }
+            Op::Truncate(path, size) => {
+                let Some(Node::File(data)) = self.0.get_mut(path.as_str()) else {
+                    return None;
+                };
+                data.resize(*size, 0);
+                Some(true)
+            }
+            Op::Unlink(path) | Op::Rmdir(path) => {
+                let rmdir = matches!(op, Op::Rmdir(_));
+                match self.0.get(path.as_str()) {
+                    None if self.is_dir(parent_path(path)) => return Some(false),
+                    None => return None,
+                    Some(Node::Dir) if !rmdir => return None,
+                    Some(Node::File(_) | Node::Link(_)) if rmdir => return None,
+                    Some(Node::Dir) if self.0.keys().any(|x| is_under(x, path)) => {
+                        return Some(false)
+                    }
+                    Some(_) => {}
+                }
+                self.0.remove(path.as_str());
+                Some(true)
+            }
+            Op::Readdir(path) => self.is_dir(path).then_some(true),
+            Op::Rename(old, new) => self.rename(old, new),
+        }
+    }
+
+    /// The rename of RenameINode, see apply.
+    fn rename(&mut self, old: &str, new: &str) -> Option<bool> {
+        let moved = self.0.get(old)?.clone();
+        if old == new || !self.is_dir(parent_path(new)) {
+            return None;
+        }
+        let is_dir = moved == Node::Dir;
+        if is_dir && is_under(new, old) {
+            return Some(false);
+        }
+        match self.0.get(new) {
+            Some(Node::Dir) if !is_dir => return Some(false),
+            Some(Node::Dir) if self.0.keys().any(|x| is_under(x, new)) => return Some(false),
+            Some(Node::File(_) | Node::Link(_)) if is_dir => return Some(false),
+            _ => {}
+        }
+        let paths: Vec<String> = self
+            .0
+            .keys()
+            .filter(|x| *x == old || is_under(x, old))
+            .cloned()
+            .collect();
+        self.0.remove(new);
+        for path in paths {
+            let node = self.0.remove(&path).unwrap();
+            self.0
+                .insert(format!("{}{}", new, &path[old.len()..]), node);
+        }
+        Some(true)
+    }
+
+    /// The manifest of the model, like Manifest::from_dir of the run.
+    fn manifest(&self, dir_links: bool) -> Manifest {
+        let entries = self.0.iter().map(|(path, node)| {
+            let (kind, nlink, size, crc, target) = match node {
+                Node::File(data) => (Kind::File, 1, data.len(), crc32c(!0, data), None),
+                Node::Dir => {
+                    let subdirs = self
+                        .0
+                        .iter()
+                        .filter(|(x, node)| **node == Node::Dir && parent_path(x) == path)
+                        .count();
+                    (Kind::Dir, 2 + subdirs as u64, 0, 0, None)
+                }
+                Node::Link(target) => (Kind::Link, 1, target.len(), 0, Some(target.clone())),
+            };
+            Entry {
+                path: path.clone(),
+                kind,
+                nlink: if kind == Kind::Dir && !dir_links {
+                    0
+                } else {
+                    nlink
+                },
+                size: size as u64,
+                crc,
+                target,
+            }
+        });
+        Manifest(entries.collect())
+    }
+}
+
+/// A path of the directory, existing if it can.
+fn pick(rng: &mut Rng, model: &Model, filter: impl Fn(&Node) -> bool) -> String {
+    let existing: Vec<&String> = model
+        .0
+        .iter()
+        .filter(|x| filter(x.1))
+        .map(|x| x.0)
+        .collect();
+    if !existing.is_empty() && rng.below(4) != 0 {
+        return existing[rng.below(existing.len())].clone();
+    }
+    let depth = 1 + rng.below(MAX_DEPTH);
+    let names: Vec<&str> = (0..depth).map(|_| NAMES[rng.below(NAMES.len())]).collect();
+    names.join("/")
+}
+
+/// A new path in an existing directory, if it can.
+fn pick_new(rng: &mut Rng, model: &Model) -> String {
+    let dir = match rng.below(3) {
+        0 => String::new(),
+        _ => pick(rng, model, |x| *x == Node::Dir),
+    };
+    let name = NAMES[rng.below(NAMES.len())];
+    match dir.as_str() {
+        "" => name.to_string(),
+        _ => format!("{}/{}", dir, name),
+    }
+}
+
+fn is_file(node: &Node) -> bool {
+    matches!(node, Node::File(_))
+}
+
+/// Generate the sequence of the config, the operations follow the model
+/// so most of them apply.
+pub fn generate(config: &Config) -> Vec<Op> {
+    let w = config.weights;
+    let weights = [
+        w.create, w.write, w.truncate, w.rename, w.unlink, w.mkdir, w.rmdir, w.readdir, w.symlink,
+    ];
+    let total: u32 = weights.iter().sum();
+    assert!(total > 0, "all the weights are 0");
+    let mut rng = Rng::new(config.seed);
+    let mut model = Model::default();
+    let mut ops = Vec::with_capacity(config.steps);
+    while ops.len() < config.steps {
+        let mut roll = rng.below(total as usize) as u32;
+        let kind = weights
+            .iter()
+            .position(|x| {
+                let hit = roll < *x;
+                roll = roll.saturating_sub(*x);
+                hit
+            })
+            .unwrap();
+        let op = match kind {
+            0 => Op::Create(pick_new(&mut rng, &model)),
+            1 => {
+                let offset = rng.below(config.max_size);
+                Op::Write {
+                    path: pick(&mut rng, &model, is_file),
+                    offset,
+                    len: 1 + rng.below(config.max_size - offset),
+                    seed: rng.next_u64() as u32,
+                }
+            }
+            2 => Op::Truncate(
+                pick(&mut rng, &model, is_file),
+                rng.below(config.max_size + 1),
+            ),
+            3 => Op::Rename(pick(&mut rng, &model, |_| true), pick_new(&mut rng, &model)),
+            4 => Op::Unlink(pick(&mut rng, &model, |x| *x != Node::Dir)),
+            5 => Op::Mkdir(pick_new(&mut rng, &model)),
+            6 => Op::Rmdir(pick(&mut rng, &model, |x| *x == Node::Dir)),
+            7 => Op::Readdir(match rng.below(2) {
+                0 => String::new(),
+                _ => pick(&mut rng, &model, |x| *x == Node::Dir),
+            }),
+            _ => Op::Symlink(pick_new(&mut rng, &model), pick(&mut rng, &model, |_| true)),
+        };
+        model.apply(&op);
+        ops.push(op);
+    }
+    ops
+}
+
+/// Look up the parent of the path in dir, return it with the last name.
+fn parent<'a>(dir: &File, path: &'a str) -> VfsResult<(File, &'a str)> {
+    let (parents, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let mut dir = dir.clone();
+    for x in parents.split('/').filter(|x| !x.is_empty()) {
+        dir = dir.lookup(x)?;
+    }
+    Ok((dir, name))
+}
+
+/// Run the operation in dir, return the names of a Readdir.
+fn exec(dir: &File, op: &Op) -> VfsResult<Option<Vec<String>>> {
+    match op {
+        Op::Create(path) => {
+            let (parent, name) = parent(dir, path)?;
+            parent.touch(name)?;
+        }
+        Op::Write {
+            path,
+            offset,
+            len,
+            seed,
+        } => {
+            let (parent, name) = parent(dir, path)?;
+            let file = parent.lookup(name)?;
+            let data = pattern(*seed, *offset, *len);
+            let mut done = 0;
+            while done < data.len() {
+                match file.writeat(offset + done, &data[done..])? {
+                    0 => return Err(VfsError::WriteZero),
+                    n => done += n,
+                }
+            }
+            file.flush()?;
+        }
+        Op::Truncate(path, size) => {
+            let (parent, name) = parent(dir, path)?;
+            let file = parent.lookup(name)?;
+            file.truncate(*size)?;
+            file.flush()?;
+        }
+        Op::Rename(old, new) => {
+            let (old_dir, old_name) = parent(dir, old)?;
+            let (new_dir, new_name) = parent(dir, new)?;
+            let flags = crate::rename::RenameFlags::NONE;
+            crate::rename::rename(&old_dir, old_name, &new_dir, new_name, flags)?;
+        }
+        Op::Unlink(path) => {
+            let (parent, name) = parent(dir, path)?;
+            parent.remove(name)?;
+        }
+        Op::Mkdir(path) => {
+            let (parent, name) = parent(dir, path)?;
+            parent.mkdir(name)?;
+        }
+        Op::Rmdir(path) => {
+            let (parent, name) = parent(dir, path)?;
+            parent.rmdir(name)?;
+        }
+        Op::Readdir(path) => {
+            let listed = match path.as_str() {
+                "" => dir.clone(),
+                _ => {
+                    let (parent, name) = parent(dir, path)?;
+                    parent.lookup(name)?
+                }
+            };
+            let mut names: Vec<String> = listed
+                .read_dir()?
+                .into_iter()
+                .map(|x| x.filename)
+                .filter(|x| x != "." && x != "..")
+                .collect();
+            names.sort();
+            return Ok(Some(names));
+        }
+        Op::Symlink(path, target) => {
+            let (parent, name) = parent(dir, path)?;
+            parent.sym_link(name, target)?;
+        }
+    }
+    Ok(None)
+}
+
+/// The manifest of the run without lost+found, Manifest::from_dir skips
+/// it in the root of a filesystem only.
+fn observe(dir: &File, dir_links: bool) -> Result<Manifest, String> {
+    let mut manifest = Manifest::from_dir(dir)?;
+    if !dir_links {
+        for entry in manifest.0.iter_mut().filter(|x| x.kind == Kind::Dir) {
+            entry.nlink = 0;
+        }
+    }
+    Ok(manifest)
+}
+
+/// Run the operations on a fresh directory of the target, return the
+/// failing step and the reason.
+fn replay(target: &mut dyn Target, ops: &[Op], config: &Config) -> Result<(), (usize, String)> {
+    let dir = target.fresh().map_err(|x| (0, x))?;
+    let mut model = Model::default();
+    for (step, op) in ops.iter().enumerate() {
+        let Some(expected) = model.apply(op) else {
+            continue;
+        };
+        let listed = match (exec(&dir, op), expected) {
+            (Ok(listed), true) => listed,
+            (Err(_), false) => None,
+            (Ok(_), false) => return Err((step, format!("{:?} succeeded", op))),
+            (Err(err), true) => return Err((step, format!("{:?}: {:?}", op, err))),
+        };
+        if let (Some(names), Op::Readdir(path)) = (listed, op) {
+            let mut names = names;
+            // lost+found of mkfs isn't in the model.
+            if path.is_empty() {
+                names.retain(|x| x != "lost+found");
+            }
+            let expected = model.names(path);
+            if names != expected {
+                return Err((step, format!("{:?} lists {:?}", op, names)));
+            }
+        }
+        let actual = observe(&dir, config.dir_links).map_err(|x| (step, x))?;
+        if let Some(divergence) = diff(&model.manifest(config.dir_links), &actual) {
+            return Err((step, format!("after {:?}: {}", op, divergence)));
+        }
+    }
+    drop(dir);
+    target.check().map_err(|x| (ops.len(), x))
+}
+
+/// The smaller versions of a write or a truncate.
+fn smaller(op: &Op) -> Vec<Op> {
+    match op {
+        Op::Write {
+            path,
+            offset,
+            len,
+            seed,
+        } => {
+            let mut ops = Vec::new();
+            if *offset > 0 {
+                ops.push(Op::Write {
+                    path: path.clone(),
+                    offset: 0,
+                    len: *len,
+                    seed: *seed,
+                });
+            }
+            if *len > 1 {
+                ops.push(Op::Write {
+                    path: path.clone(),
+                    offset: *offset,
+                    len: len / 2,
+                    seed: *seed,
+                });
+            }
+            ops
+        }
+        Op::Truncate(path, size) if *size > 0 => {
+            vec![
+                Op::Truncate(path.clone(), 0),
+                Op::Truncate(path.clone(), size / 2),
+            ]
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Shrink the failing sequence within the runs of the config.
+fn shrink(
+    target: &mut dyn Target,
+    mut ops: Vec<Op>,
+    mut failure: (usize, String),
+    config: &Config,
+) -> (Vec<Op>, (usize, String)) {
+    let mut runs = 0;
+    // the operations after the failing one don't matter.
+    ops.truncate(failure.0 + 1);
+    let mut chunk = ops.len().div_ceil(2);
+    while chunk > 0 {
+        let mut start = 0;
+        while start < ops.len() && runs < config.shrink_runs {
+            let mut candidate = ops.clone();
+            candidate.drain(start..(start + chunk).min(ops.len()));
+            runs += 1;
+            match replay(target, &candidate, config) {
+                Err(x) => (ops, failure) = (candidate, x),
+                Ok(()) => start += chunk,
+            }
+        }
+        chunk /= 2;
+    }
+    let mut i = 0;
+    while i < ops.len() && runs < config.shrink_runs {
+        let mut progress = false;
+        for op in smaller(&ops[i]) {
+            let mut candidate = ops.clone();
+            candidate[i] = op;
+            runs += 1;
+            if let Err(x) = replay(target, &candidate, config) {
+                (ops, failure) = (candidate, x);
+                progress = true;
+                break;
+            }
+        }
+        if !progress {
+            i += 1;
+        }
+    }
+    (ops, failure)
+}
+
+/// Generate the sequence of the config and run it on the target, a
+/// failing one is shrunk.
+pub fn run(target: &mut dyn Target, config: &Config) -> Result<(), ModelFailure> {
+    let ops = generate(config);
+    let Err(failure) = replay(target, &ops, config) else {
+        return Ok(());
+    };
+    let generated = ops.len();
+    let (ops, (step, reason)) = shrink(target, ops, failure, config);
+    let failure = ModelFailure {
+        seed: config.seed,
+        generated,
+        ops,
+        step,
+        reason,
+    };
+    log::error!("model run failed, {}", failure);
+    Err(failure)
+}
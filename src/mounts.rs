@@ -0,0 +1,679 @@
+// Remounting a mounted filesystem with other flags and options, like
+// mount -o remount: the boot mounts the root read-only for the check and
+// remounts it read-write after. FileSystem of vfscore has no remount, so
+// the filesystems which can be remounted register a Remount with their
+// FileSystem, like the Volume of volume.rs, and the flags of the mounts
+// are kept here. The FileHandles open for writing are registered by
+// their address, a remount to read-only is refused while the filesystem
+// has one. The atime flags pick the AtimePolicy of atime.rs.
+//
+// Every FileHandle is registered too, with the dentry it was opened by
+// when it's known, so an unmount refused while a file is open can tell
+// what holds the mount, like lsof. The opens of a mount are found by the
+// st_dev and the f_fsid of their nodes, a tmpfs has no st_dev.
+//
+// mount_spec mounts by a spec like the first field of fstab, UUID=,
+// LABEL= or /dev/vda1, see fstype::resolve_source, and keeps the spec
+// with the root of the mount, so /proc/mounts shows the source the mount
+// was asked by. The mounts of the boot keep theirs too.
+
+use core::fmt::Write;
+use core::ops::BitOr;
+
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use vfscore::{FileSystem, INodeInterface, OpenFlags, Stat, StatFS, StatMode, VfsError, VfsResult};
+
+use crate::atime::AtimePolicy;
+use crate::dentry::{
+    self, dentry_open, dentry_open_at, dentry_root, mount_of, DentryNode, ResolveContext,
+};
+use crate::error::{Errno, FsError, FsResult};
+use crate::fstype;
+use crate::pseudo::{CallbackInode, SizeMode};
+use crate::sys::Mutex;
+
+/// The flags of a mount, the bits of the flags of mount(2).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MountFlags(u32);
+
+impl MountFlags {
+    pub const NONE: Self = Self(0);
+    /// MS_RDONLY.
+    pub const RDONLY: Self = Self(1);
+    /// MS_NOATIME.
+    pub const NOATIME: Self = Self(1 << 10);
+    /// MS_RELATIME.
+    pub const RELATIME: Self = Self(1 << 21);
+    /// MS_STRICTATIME.
+    pub const STRICTATIME: Self = Self(1 << 24);
+    const ATIME: Self = Self(Self::NOATIME.0 | Self::RELATIME.0 | Self::STRICTATIME.0);
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// The flags of the bits, the unknown ones are dropped.
+    pub const fn from_bits_truncate(bits: u32) -> Self {
+        Self(bits & (Self::RDONLY.0 | Self::ATIME.0))
+    }
+}
+
+impl BitOr for MountFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// The remount of a filesystem.
+pub trait Remount: Send + Sync {
+    /// The device number of the filesystem, the st_dev of its files.
+    fn dev(&self) -> usize;
+
+    /// Switch to the flags and the options, a comma separated list like
+    /// the data of mount(2). Going read-only, the buffered writes are
+    /// written back and the writes are denied once it returns. The
+    /// filesystem keeps its state if it fails.
+    fn remount(&self, flags: MountFlags, options: &str) -> VfsResult<()>;
+
+    /// The filesystem denies the writes, by its mount or by itself, like
+    /// after an error with errors=remount-ro.
+    fn is_read_only(&self) -> bool;
+}
+
+/// A registered filesystem with the flags of its mount.
+struct Registered {
+    fs: Weak<dyn FileSystem>,
+    remount: Weak<dyn Remount>,
+    flags: MountFlags,
+}
+
+/// The registered filesystems, the dropped ones are removed lazily.
+static MOUNTS: Mutex<Vec<Registered>> = Mutex::new(Vec::new());
+
+/// Register the remount of the filesystem, mounted with the flags.
+pub fn register(fs: Weak<dyn FileSystem>, remount: Weak<dyn Remount>, flags: MountFlags) {
+    let mut mounts = MOUNTS.lock();
+    mounts.retain(|x| x.fs.strong_count() > 0 && x.remount.strong_count() > 0);
+    mounts.push(Registered { fs, remount, flags });
+}
+
+/// The flags of the mount of the filesystem, None if it isn't registered.
+pub fn flags(fs: &Arc<dyn FileSystem>) -> Option<MountFlags> {
+    MOUNTS
+        .lock()
+        .iter()
+        .find(|x| core::ptr::addr_eq(x.fs.as_ptr(), Arc::as_ptr(fs)))
+        .map(|x| x.flags)
+}
+
+/// The flags of the mount of the filesystem with the device number dev,
+/// None if it isn't registered.
+pub fn dev_flags(dev: usize) -> Option<MountFlags> {
+    MOUNTS
+        .lock()
+        .iter()
+        .find(|x| x.remount.upgrade().is_some_and(|x| x.dev() == dev))
+        .map(|x| x.flags)
+}
+
+/// Check that the filesystem of the node takes the writes, a read-only one
+/// fails with NotSupported and EROFS. The filesystem denies them itself,
+/// with NotSupported only, this is for the errno of the syscalls. A node
+/// without a stat is taken as writable.
+pub fn check_writable(node: &dyn INodeInterface) -> FsResult<()> {
+    let mut stat = Stat::default();
+    if node.stat(&mut stat).is_err() {
+        return Ok(());
+    }
+    let dev = stat.dev as usize;
+    let read_only = MOUNTS
+        .lock()
+        .iter()
+        .filter_map(|x| Some((x.remount.upgrade()?, x.flags)))
+        .find(|(x, _)| x.dev() == dev)
+        .is_some_and(|(x, flags)| flags.contains(MountFlags::RDONLY) || x.is_read_only());
+    match read_only {
+        true => Err(FsError::new(VfsError::NotSupported, Errno::EROFS)),
+        false => Ok(()),
+    }
+}
+
+/// The FileHandles open for writing by their address.
+static WRITERS: Mutex<BTreeMap<usize, Weak<dyn INodeInterface>>> = Mutex::new(BTreeMap::new());
+
+/// Register the handle open for writing, it must call close_writer when
+/// it's dropped.
+pub fn open_writer<T: INodeInterface + 'static>(handle: &Arc<T>) {
+    let weak: Weak<dyn INodeInterface> = Arc::downgrade(handle) as _;
+    WRITERS.lock().insert(Arc::as_ptr(handle) as usize, weak);
+}
+
+/// Unregister the handle, from its drop.
+pub fn close_writer<T>(handle: &T) {
+    WRITERS.lock().remove(&(handle as *const T as usize));
+}
+
+/// Remount the filesystem mounted on the path, see remount_at.
+pub fn remount(path: &str, flags: MountFlags, options: &str) -> FsResult<()> {
+    remount_at(&dentry_root(), path, flags, options)
+}
+
+/// Remount the filesystem mounted on the path in the view of root with
+/// the flags and the options. The path must be the root of a mount,
+/// InvalidInput otherwise, and NotSupported if its filesystem can't be
+/// remounted. Going read-only fails with InvalidInput while a file of the
+/// filesystem is open for writing. The new opens for writing wait for the
+/// remount, their writes fail on a read-only filesystem. The options
+/// noatime, relatime and strictatime set the atime flags, the mount keeps
+/// its atime flags without one, like Linux. The open files for writing
+/// fail it with EBUSY.
+pub fn remount_at(
+    root: &Arc<DentryNode>,
+    path: &str,
+    flags: MountFlags,
+    options: &str,
+) -> FsResult<()> {
+    let ctx = ResolveContext::with_root(root.clone());
+    let dentry = dentry_open_at(&ctx, path, OpenFlags::NONE)?;
+    let is_root = dentry.parent.upgrade().is_none()
+        || mount_of(&dentry).is_some_and(|x| Arc::ptr_eq(&x.root, &dentry));
+    if !is_root {
+        return Err(VfsError::InvalidInput.into());
+    }
+    let mut stat = Stat::default();
+    dentry.node.stat(&mut stat)?;
+    let dev = stat.dev as usize;
+
+    let mut mounts = MOUNTS.lock();
+    let entry = mounts
+        .iter_mut()
+        .find(|x| x.remount.upgrade().is_some_and(|x| x.dev() == dev))
+        .ok_or(VfsError::NotSupported)?;
+    let hook = entry.remount.upgrade().ok_or(VfsError::NotSupported)?;
+    let atime = options
+        .split(',')
+        .filter_map(AtimePolicy::from_option)
+        .last()
+        .map(AtimePolicy::flags);
+    let flags = match atime {
+        Some(atime) => MountFlags(flags.0 & !MountFlags::ATIME.0) | atime,
+        None if !flags.intersects(MountFlags::ATIME) => {
+            flags | MountFlags(entry.flags.0 & MountFlags::ATIME.0)
+        }
+        None => flags,
+    };
+    // the writers are held off until the filesystem switched. The handles
+    // are dropped after the lock, their drop unregisters them.
+    let mut handles = Vec::new();
+    let writers = WRITERS.lock();
+    if flags.contains(MountFlags::RDONLY) && !entry.flags.contains(MountFlags::RDONLY) {
+        handles.extend(writers.values().filter_map(Weak::upgrade));
+        let busy = handles.iter().any(|x| {
+            let mut stat = Stat::default();
+            x.stat(&mut stat).is_ok() && stat.dev as usize == dev
+        });
+        if busy {
+            log::warn!(
+                "can't remount dev {} read-only, a file is open for writing",
+                dev
+            );
+            return Err(FsError::new(VfsError::InvalidInput, Errno::EBUSY));
+        }
+    }
+    hook.remount(flags, options)?;
+    entry.flags = flags;
+    Ok(())
+}
+
+/// The spec and the type a mount was made by, for /proc/mounts.
+struct Source {
+    root: Weak<DentryNode>,
+    fs: Weak<dyn FileSystem>,
+    spec: String,
+    fstype: &'static str,
+    flags: MountFlags,
+}
+
+/// The sources by the root of their mount, the unmounted ones are removed
+/// lazily.
+static SOURCES: Mutex<Vec<Source>> = Mutex::new(Vec::new());
+
+/// Keep the spec and the type of the mount of fs whose root is the
+/// dentry, mounted with the flags.
+pub fn set_source(
+    root: &Arc<DentryNode>,
+    fs: &Arc<dyn FileSystem>,
+    spec: &str,
+    fstype: &'static str,
+    flags: MountFlags,
+) {
+    let mut sources = SOURCES.lock();
+    sources.retain(|x| x.root.strong_count() > 0 && !core::ptr::eq(x.root.as_ptr(), &**root));
+    sources.push(Source {
+        root: Arc::downgrade(root),
+        fs: Arc::downgrade(fs),
+        spec: spec.to_string(),
+        fstype,
+        flags,
+    });
+}
+
+/// Mount the spec on the path, see mount_spec_at.
+pub fn mount_spec(
+    spec: &str,
+    path: &str,
+    fstype: &str,
+    flags: MountFlags,
+) -> FsResult<Arc<dyn FileSystem>> {
+    mount_spec_at(&dentry_root(), spec, path, fstype, flags)
+}
+
+/// Mount the source of the spec, see fstype::resolve_source, as the type
+/// on the path in the view of root, like mount(8). The type auto is the
+/// one detected on the source. The filesystem lives as long as the
+/// kernel, like the ones of the boot, its root_dir needs it 'static.
+pub fn mount_spec_at(
+    root: &Arc<DentryNode>,
+    spec: &str,
+    path: &str,
+    fstype: &str,
+    flags: MountFlags,
+) -> FsResult<Arc<dyn FileSystem>> {
+    let source = fstype::resolve_source(spec)?;
+    let name = match fstype {
+        "auto" => fstype::detect(&source).ok_or(VfsError::InvalidData)?,
+        name => name,
+    };
+    let Some(found) = fstype::fstype(name) else {
+        log::error!(
+            "can't mount {}, the filesystem type {} is unknown",
+            spec,
+            name
+        );
+        return Err(fstype::UNKNOWN_TYPE);
+    };
+    let fs = (found.mount)(source, flags)?;
+    mount_with_source(root, path, &fs, spec, found.name, flags)?;
+    log::info!("mounted {} on {} as {}", spec, path, found.name);
+    Ok(fs)
+}
+
+/// Mount fs on the path in the view of root and keep its source, see
+/// set_source. fs is leaked, see mount_spec_at.
+pub(crate) fn mount_with_source(
+    root: &Arc<DentryNode>,
+    path: &str,
+    fs: &Arc<dyn FileSystem>,
+    spec: &str,
+    fstype: &'static str,
+    flags: MountFlags,
+) -> VfsResult<()> {
+    let node = Box::leak(Box::new(fs.clone())).root_dir();
+    DentryNode::mount_at(root, path, node.clone())?;
+    let mount = dentry::mounts()
+        .into_iter()
+        .rfind(|x| core::ptr::addr_eq(Arc::as_ptr(&x.root.node), Arc::as_ptr(&node)))
+        .ok_or(VfsError::InvalidInput)?;
+    set_source(&mount.root, fs, spec, fstype, flags);
+    Ok(())
+}
+
+/// Escape a field of /proc/mounts like Linux, the spaces, the tabs, the
+/// newlines and the backslashes are octal.
+fn escape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    for x in field.chars() {
+        match x {
+            ' ' | '\t' | '\n' | '\\' => {
+                let _ = write!(out, "\\{:03o}", x as u32);
+            }
+            x => out.push(x),
+        }
+    }
+    out
+}
+
+/// Whether the dentry is root or under it.
+fn is_under(dentry: &Arc<DentryNode>, root: &Arc<DentryNode>) -> bool {
+    let mut dentry = Some(dentry.clone());
+    while let Some(x) = dentry {
+        if Arc::ptr_eq(&x, root) {
+            return true;
+        }
+        dentry = x.parent.upgrade();
+    }
+    false
+}
+
+/// The content of /proc/mounts, see render_mounts_at.
+pub fn render_mounts() -> String {
+    render_mounts_at(&dentry_root())
+}
+
+/// Render the mounts in the view of root, root first, a `<source> <path>
+/// <type> <options> 0 0` line per mount like /proc/mounts. The source is
+/// the spec of set_source, none for the mounts without one, and so is
+/// their type.
+pub fn render_mounts_at(root: &Arc<DentryNode>) -> String {
+    let mut mounts = Vec::from([root.clone()]);
+    mounts.extend(
+        dentry::mounts()
+            .into_iter()
+            .map(|x| x.root.clone())
+            .filter(|x| is_under(x, root)),
+    );
+    let sources = SOURCES.lock();
+    let mut out = String::new();
+    for mount in mounts {
+        let source = sources
+            .iter()
+            .find(|x| core::ptr::eq(x.root.as_ptr(), &*mount));
+        let (spec, fstype, flags) = match source {
+            Some(x) => {
+                let current = x.fs.upgrade().and_then(|fs| flags(&fs));
+                (x.spec.as_str(), x.fstype, current.unwrap_or(x.flags))
+            }
+            None => ("none", "none", MountFlags::NONE),
+        };
+        let mut options = String::from(match flags.contains(MountFlags::RDONLY) {
+            true => "ro",
+            false => "rw",
+        });
+        if flags.contains(MountFlags::NOATIME) {
+            options.push_str(",noatime");
+        } else if flags.contains(MountFlags::RELATIME) {
+            options.push_str(",relatime");
+        }
+        let _ = writeln!(
+            out,
+            "{} {} {} {} 0 0",
+            escape(spec),
+            escape(&mount.path_from(root)),
+            fstype,
+            options
+        );
+    }
+    out
+}
+
+/// An open file, the handle and the node it opens by their address.
+struct Opened {
+    handle: Weak<dyn INodeInterface>,
+    node: usize,
+    /// The dentry of the open, None if it was opened by the node.
+    dentry: Option<Weak<DentryNode>>,
+}
+
+/// The open FileHandles by their address.
+static OPENED: Mutex<BTreeMap<usize, Opened>> = Mutex::new(BTreeMap::new());
+
+/// Register the open handle of the node, it must call close_file when
+/// it's dropped.
+pub fn open_file<T: INodeInterface + 'static>(handle: &Arc<T>, node: &Arc<dyn INodeInterface>) {
+    let weak: Weak<dyn INodeInterface> = Arc::downgrade(handle) as _;
+    let opened = Opened {
+        handle: weak,
+        node: Arc::as_ptr(node) as *const () as usize,
+        dentry: None,
+    };
+    OPENED.lock().insert(Arc::as_ptr(handle) as usize, opened);
+}
+
+/// Keep the dentry the handle was opened by, for the path of the report.
+pub fn opened_at<T>(handle: &Arc<T>, dentry: &Arc<DentryNode>) {
+    if let Some(x) = OPENED.lock().get_mut(&(Arc::as_ptr(handle) as usize)) {
+        x.dentry = Some(Arc::downgrade(dentry));
+    }
+}
+
+/// Unregister the handle, from its drop.
+pub fn close_file<T>(handle: &T) {
+    OPENED.lock().remove(&(handle as *const T as usize));
+}
+
+/// An inode of a mount held by the open files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BusyEntry {
+    pub ino: u64,
+    /// The path of an open of it in the view of the root of the report,
+    /// with " (deleted)" if it was removed, None if no dentry of it is
+    /// known.
+    pub path_hint: Option<String>,
+    /// The references to its open files, a dup counts as one more.
+    pub ref_count: usize,
+}
+
+/// The st_dev and the f_fsid of the filesystem of the node.
+fn mount_id(node: &dyn INodeInterface) -> Option<(u64, u64)> {
+    let mut stat = Stat::default();
+    let mut statfs = StatFS::default();
+    node.stat(&mut stat).ok()?;
+    node.statfs(&mut statfs).ok()?;
+    Some((stat.dev as u64, statfs.fsid))
+}
+
+/// The cached dentry of the node under dir, in the same mount.
+fn find_dentry(dir: &Arc<DentryNode>, node: usize) -> Option<Arc<DentryNode>> {
+    let mut stack = Vec::from([dir.clone()]);
+    while let Some(dentry) = stack.pop() {
+        if Arc::as_ptr(&dentry.node) as *const () as usize == node {
+            return Some(dentry);
+        }
+        stack.extend(dentry.children.lock().iter().cloned());
+    }
+    None
+}
+
+/// The inodes held by the open files of the filesystem mounted on the
+/// path, see busy_report_at.
+pub fn busy_report(path: &str) -> VfsResult<Vec<BusyEntry>> {
+    busy_report_at(&dentry_root(), path)
+}
+
+/// The inodes held by the open files of the filesystem mounted on the
+/// path in the view of root, by their inode numbers. The path must be the
+/// root of a mount, InvalidInput otherwise. The path of a file opened by
+/// its node is looked up in the cached dentries of the mount.
+pub fn busy_report_at(root: &Arc<DentryNode>, path: &str) -> VfsResult<Vec<BusyEntry>> {
+    let mount = mount_root(root, path)?;
+    Ok(report(root, &mount))
+}
+
+/// The dentry of the root of the mount on the path.
+fn mount_root(root: &Arc<DentryNode>, path: &str) -> VfsResult<Arc<DentryNode>> {
+    let ctx = ResolveContext::with_root(root.clone());
+    let dentry = dentry_open_at(&ctx, path, OpenFlags::NONE)?;
+    let is_root = dentry.parent.upgrade().is_none()
+        || mount_of(&dentry).is_some_and(|x| Arc::ptr_eq(&x.root, &dentry));
+    match is_root {
+        true => Ok(dentry),
+        false => Err(VfsError::InvalidInput),
+    }
+}
+
+fn report(root: &Arc<DentryNode>, mount: &Arc<DentryNode>) -> Vec<BusyEntry> {
+    let Some(id) = mount_id(mount.node.as_ref()) else {
+        return Vec::new();
+    };
+    // the handles are dropped after the lock, their drop unregisters them.
+    let opened: Vec<_> = OPENED
+        .lock()
+        .values()
+        .filter_map(|x| {
+            let count = x.handle.strong_count();
+            let dentry = x.dentry.as_ref().and_then(Weak::upgrade);
+            Some((x.handle.upgrade()?, x.node, dentry, count))
+        })
+        .collect();
+    let mut entries: BTreeMap<u64, BusyEntry> = BTreeMap::new();
+    for (handle, node, dentry, count) in opened.iter() {
+        if mount_id(handle.as_ref()) != Some(id) {
+            continue;
+        }
+        let Ok(metadata) = handle.metadata() else {
+            continue;
+        };
+        let entry = entries.entry(metadata.inode as u64).or_insert(BusyEntry {
+            ino: metadata.inode as u64,
+            path_hint: None,
+            ref_count: 0,
+        });
+        entry.ref_count += count;
+        if entry.path_hint.is_none() {
+            let dentry = dentry.clone().or_else(|| find_dentry(mount, *node));
+            entry.path_hint = dentry.map(|x| match x.is_removed() {
+                true => x.path_from(root) + " (deleted)",
+                false => x.path_from(root),
+            });
+        }
+    }
+    entries.into_values().collect()
+}
+
+/// An unmount which failed, with the open files holding the mount if it
+/// was busy.
+#[derive(Debug, Clone)]
+pub struct UmountError {
+    pub error: FsError,
+    pub busy: Vec<BusyEntry>,
+}
+
+impl From<FsError> for UmountError {
+    fn from(error: FsError) -> Self {
+        Self {
+            error,
+            busy: Vec::new(),
+        }
+    }
+}
+
+impl From<VfsError> for UmountError {
+    fn from(error: VfsError) -> Self {
+        FsError::from(error).into()
+    }
+}
+
+impl From<UmountError> for VfsError {
+    fn from(err: UmountError) -> Self {
+        err.error.into()
+    }
+}
+
+/// EBUSY for the open files and the mounts under it.
+impl From<UmountError> for Errno {
+    fn from(err: UmountError) -> Self {
+        err.error.into()
+    }
+}
+
+/// Unmount the filesystem mounted on the path, see umount_at.
+pub fn umount(path: &str) -> Result<(), UmountError> {
+    umount_at(&dentry_root(), path)
+}
+
+/// Unmount the filesystem mounted on the path in the view of root, the
+/// path shows what it covered again. The path must be the root of a
+/// mount, InvalidInput otherwise, and a mount with mounts under it fails
+/// with EBUSY, see dentry::detach. A mount with open files fails with
+/// EBUSY and the report of them.
+/// TODO: count the working directories and hold off the opens racing the
+/// unmount.
+pub fn umount_at(root: &Arc<DentryNode>, path: &str) -> Result<(), UmountError> {
+    let mount = mount_root(root, path)?;
+    let busy = report(root, &mount);
+    if !busy.is_empty() {
+        log::warn!("can't unmount {}, {} files are open", path, busy.len());
+        return Err(UmountError {
+            error: FsError::new(VfsError::InvalidInput, Errno::EBUSY),
+            busy,
+        });
+    }
+    dentry::detach(&mount)?;
+    Ok(())
+}
+
+/// Render the open files of every mount, a `<path> <ino> <refs> <path
+/// of the open>` line per inode, `?` for an unknown path.
+pub fn render_busy() -> String {
+    let root = dentry_root();
+    let mut mounts = Vec::from([root.clone()]);
+    mounts.extend(dentry::mounts().into_iter().map(|x| x.root.clone()));
+    let mut out = String::new();
+    for mount in mounts {
+        for entry in report(&root, &mount) {
+            let _ = writeln!(
+                out,
+                "{} {} {} {}",
+                mount.path_from(&root),
+                entry.ino,
+                entry.ref_count,
+                entry.path_hint.as_deref().unwrap_or("?")
+            );
+        }
+    }
+    out
+}
+
+/// The directory /proc/fs holding the synthetic files of the mounts.
+struct ProcFsDir;
+
+impl INodeInterface for ProcFsDir {
+    fn stat(&self, stat: &mut vfscore::Stat) -> VfsResult<()> {
+        stat.mode = StatMode::DIR;
+        stat.nlink = 2;
+        stat.blksize = 4096;
+        Ok(())
+    }
+}
+
+/// Attach /proc/mounts and /proc/fs/busy to the dentry tree, like
+/// /proc/fsstats of stats.rs, /proc/fs is made if procfs has none. The
+/// mounts of procfs are hidden. Do nothing if /proc isn't mounted.
+pub fn init_procfs() {
+    let Ok(proc) = dentry_open(dentry_root(), "/proc", OpenFlags::NONE) else {
+        return;
+    };
+    let node = Arc::new(DentryNode::new(
+        "mounts".to_string(),
+        CallbackInode::read_only(|| render_mounts().into_bytes(), SizeMode::Snapshot),
+        Arc::downgrade(&proc),
+    ));
+    let mut children = proc.children.lock();
+    children.retain(|x| x.filename != "mounts");
+    children.push(node);
+    drop(children);
+    let dir = match dentry_open(proc.clone(), "fs", OpenFlags::NONE) {
+        Ok(dir) => dir,
+        Err(_) => {
+            let dir = Arc::new(DentryNode::new(
+                "fs".to_string(),
+                Arc::new(ProcFsDir),
+                Arc::downgrade(&proc),
+            ));
+            proc.children.lock().push(dir.clone());
+            dir
+        }
+    };
+    let node = Arc::new(DentryNode::new(
+        "busy".to_string(),
+        CallbackInode::read_only(|| render_busy().into_bytes(), SizeMode::Snapshot),
+        Arc::downgrade(&dir),
+    ));
+    dir.children.lock().push(node);
+}
@@ -0,0 +1,127 @@
+// The capabilities of the nodes of the fs crate beyond INodeInterface.
+// INodeInterface is of vfscore, so the statx, the syncs, the renames and
+// the other operations of a node are in the traits of their modules, and
+// a node type of the crate implements FsNode to hand out the ones it
+// implements. fs_node downcasts a dyn INodeInterface to the node types of
+// the crate, the nodes of the other crates have none of the capabilities
+// and the modules fall back to INodeInterface for them.
+
+use vfscore::INodeInterface;
+
+#[cfg(feature = "async")]
+use crate::aio::AsyncINode;
+use crate::atime::AccessTime;
+use crate::batch::BatchINode;
+use crate::cancel::CancelIo;
+use crate::direct::DirectINode;
+use crate::fallocate::SpaceINode;
+use crate::fsync::SyncINode;
+use crate::handle::{AppendINode, FileHandle};
+use crate::inode_flags::FlagsINode;
+use crate::mknod::MknodINode;
+use crate::owner::OwnerINode;
+use crate::readdir::SeekDir;
+use crate::reflink::CloneINode;
+use crate::rename::RenameINode;
+use crate::revalidate::RevalidateINode;
+use crate::statx::StatxINode;
+use crate::tmpfs::{SharedPages, TmpDir, TmpFile};
+
+/// The capabilities of a node, None for those it hasn't.
+pub trait FsNode {
+    #[cfg(feature = "async")]
+    fn as_async(&self) -> Option<&dyn AsyncINode> {
+        None
+    }
+
+    fn as_statx(&self) -> Option<&dyn StatxINode> {
+        None
+    }
+
+    fn as_atime(&self) -> Option<&dyn AccessTime> {
+        None
+    }
+
+    fn as_flags(&self) -> Option<&dyn FlagsINode> {
+        None
+    }
+
+    fn as_owner(&self) -> Option<&dyn OwnerINode> {
+        None
+    }
+
+    fn as_seek_dir(&self) -> Option<&dyn SeekDir> {
+        None
+    }
+
+    fn as_cancel(&self) -> Option<&dyn CancelIo> {
+        None
+    }
+
+    fn as_rename(&self) -> Option<&dyn RenameINode> {
+        None
+    }
+
+    fn as_mknod(&self) -> Option<&dyn MknodINode> {
+        None
+    }
+
+    fn as_sync(&self) -> Option<&dyn SyncINode> {
+        None
+    }
+
+    fn as_direct(&self) -> Option<&dyn DirectINode> {
+        None
+    }
+
+    fn as_batch(&self) -> Option<&dyn BatchINode> {
+        None
+    }
+
+    fn as_revalidate(&self) -> Option<&dyn RevalidateINode> {
+        None
+    }
+
+    fn as_space(&self) -> Option<&dyn SpaceINode> {
+        None
+    }
+
+    fn as_clone(&self) -> Option<&dyn CloneINode> {
+        None
+    }
+
+    fn as_pages(&self) -> Option<&dyn SharedPages> {
+        None
+    }
+
+    fn as_append(&self) -> Option<&dyn AppendINode> {
+        None
+    }
+}
+
+/// The capabilities of the node, None if it isn't a node of the crate.
+pub fn fs_node(node: &dyn INodeInterface) -> Option<&dyn FsNode> {
+    macro_rules! downcast {
+        ($($(#[$cfg:meta])* $ty:ty),* $(,)?) => {
+            $(
+                $(#[$cfg])*
+                if let Some(node) = node.downcast_ref::<$ty>() {
+                    return Some(node);
+                }
+            )*
+        };
+    }
+
+    downcast!(
+        FileHandle,
+        TmpDir,
+        TmpFile,
+        #[cfg(root_fs = "ext4_rs")]
+        crate::ext4_rs_shim::Ext4FileWrapper,
+        #[cfg(root_fs = "fat32")]
+        crate::fatfs_shim::FatFile,
+        #[cfg(feature = "testsuite")]
+        crate::testsuite::Crippled,
+    );
+    None
+}
@@ -0,0 +1,1486 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::{
+    borrow::Cow,
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use vfscore::{DirEntry, FileType, INodeInterface, OpenFlags, Stat, TimeSpec, VfsError, VfsResult};
+
+use crate::batch;
+use crate::capabilities::{self, FsCapabilities};
+use crate::dentry::{
+    dentry_open_at, invalidate_negative, is_mount_point, Cred, DentryNode, ResolveContext,
+};
+use crate::error::{Errno, FsError, FsResult};
+use crate::fsync;
+use crate::inode_flags;
+use crate::io::{self, InodeReader, InodeWriter, Read, Write};
+use crate::mknod::{mknod, S_IFMT, S_IFREG};
+use crate::mounts;
+use crate::readdir::{self, DirChange, PosEntry};
+use crate::rename::{self, RenameFlags};
+use crate::tmpfs::TmpFs;
+use crate::walk::{identity, WalkDir};
+
+/// The max length of a file name in bytes, excluding the NUL terminator.
+pub const NAME_MAX: usize = 255;
+/// The max length of a path in bytes, including the NUL terminator.
+pub const PATH_MAX: usize = 4096;
+
+/// The fixed part of a linux_dirent64: d_ino(8) + d_off(8) + d_reclen(2) + d_type(1).
+const DIRENT64_HEADER: usize = 19;
+
+pub const DT_UNKNOWN: u8 = 0;
+pub const DT_FIFO: u8 = 1;
+pub const DT_CHR: u8 = 2;
+pub const DT_DIR: u8 = 4;
+pub const DT_BLK: u8 = 6;
+pub const DT_REG: u8 = 8;
+pub const DT_LNK: u8 = 10;
+pub const DT_SOCK: u8 = 12;
+
+/// Convert the vfs file type to the d_type byte of linux_dirent64.
+pub const fn dirent_type(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::File => DT_REG,
+        FileType::Directory => DT_DIR,
+        FileType::Device => DT_CHR,
+        FileType::Socket => DT_SOCK,
+        FileType::Link => DT_LNK,
+        #[allow(unreachable_patterns)]
+        _ => DT_UNKNOWN,
+    }
+}
+
+/// The vfs file type of a d_type byte, the reverse of dirent_type. The
+/// FIFOs and the block devices are devices like in vfscore. vfscore has
+/// no unknown type, a DirEntry of DT_UNKNOWN is a file, the getdents of
+/// fill_dirents64_at report the d_type of PosEntry instead.
+pub const fn d_type_file_type(d_type: u8) -> FileType {
+    match d_type {
+        DT_DIR => FileType::Directory,
+        DT_CHR | DT_BLK | DT_FIFO => FileType::Device,
+        DT_SOCK => FileType::Socket,
+        DT_LNK => FileType::Link,
+        _ => FileType::File,
+    }
+}
+
+/// Get the record length of an entry, the name is NUL-terminated and
+/// the record is aligned to 8 bytes.
+pub const fn dirent64_reclen(name_len: usize) -> usize {
+    (DIRENT64_HEADER + name_len + 1 + 7) & !7
+}
+
+/// Pack entries into buf with the linux_dirent64 layout.
+/// entries: the whole directory listing.
+/// offset: the d_off to resume from, 0 means the start of the directory.
+/// return the number of entries consumed, the caller should pass
+/// `offset + consumed` as the next offset. The d_off of an entry is the
+/// offset of the entry after it, so resuming from the last returned d_off
+/// never skips any entry.
+/// DirEntry doesn't carry the inode, d_ino is hashed_ino of the name, the
+/// listings of fill_dirents64_at have the real one.
+pub fn fill_dirents64(buf: &mut [u8], entries: &[DirEntry], offset: usize) -> usize {
+    let entries = entries.iter().enumerate().skip(offset);
+    fill_records(
+        buf,
+        entries.map(|(i, entry)| {
+            let d_type = dirent_type(entry.file_type);
+            (entry, hashed_ino(&entry.filename), d_type, i as u64 + 1)
+        }),
+    )
+}
+
+/// Like fill_dirents64 for the entries of readdir::read_dir_at, d_off is
+/// the position after the entry, so the next getdents resumes at its
+/// d_off even if the directory changed. d_ino is the inode of the entry,
+/// hashed_ino if the filesystem doesn't tell it, and d_type the d_type of
+/// the entry, which tells the FIFOs, the block devices and DT_UNKNOWN
+/// apart from the types of DirEntry.
+pub fn fill_dirents64_at(buf: &mut [u8], entries: &[PosEntry]) -> usize {
+    let entries = entries.iter().map(|x| match x.ino {
+        0 => (&x.entry, hashed_ino(&x.entry.filename), x.d_type, x.next),
+        ino => (&x.entry, ino, x.d_type, x.next),
+    });
+    fill_records(buf, entries)
+}
+
+/// The d_ino of an entry whose inode isn't known, the FNV-1a hash of the
+/// bytes of its name. It's never 0, some readers skip the entries of
+/// inode 0 like the deleted ones.
+pub fn hashed_ino(name: &str) -> u64 {
+    let hash = name_to_bytes(name)
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+    hash.max(1)
+}
+
+/// Pack the entries with their d_ino, d_type and d_off until buf is
+/// full, return the number packed.
+fn fill_records<'a>(
+    buf: &mut [u8],
+    entries: impl Iterator<Item = (&'a DirEntry, u64, u8, u64)>,
+) -> usize {
+    let mut pos = 0;
+    let mut consumed = 0;
+    for (entry, d_ino, d_type, d_off) in entries {
+        // the bytes on the disk, not the escapes of name_from_bytes.
+        let name = name_to_bytes(&entry.filename);
+        let reclen = dirent64_reclen(name.len());
+        if pos + reclen > buf.len() {
+            break;
+        }
+        let record = &mut buf[pos..pos + reclen];
+        record[0..8].copy_from_slice(&d_ino.to_ne_bytes());
+        record[8..16].copy_from_slice(&(d_off as i64).to_ne_bytes());
+        record[16..18].copy_from_slice(&(reclen as u16).to_ne_bytes());
+        record[18] = d_type;
+        record[DIRENT64_HEADER..DIRENT64_HEADER + name.len()].copy_from_slice(&name);
+        // NUL terminator and the alignment padding.
+        record[DIRENT64_HEADER + name.len()..].fill(0);
+        pos += reclen;
+        consumed += 1;
+    }
+    consumed
+}
+
+/// The names on the disk are bytes, but DirEntry and the lookups take
+/// String. A byte which isn't part of valid UTF-8 is mapped to the char
+/// NAME_ESCAPE + byte, in the supplementary private use area. The chars
+/// in the escape range which are really in the name are escaped byte by
+/// byte too, so the mapping is reversible: a listed name is looked up
+/// by the same bytes.
+pub const NAME_ESCAPE: u32 = 0x10FF00;
+
+fn escaped_byte(c: char) -> Option<u8> {
+    let c = c as u32;
+    (NAME_ESCAPE..=NAME_ESCAPE + 0xFF)
+        .contains(&c)
+        .then(|| (c - NAME_ESCAPE) as u8)
+}
+
+fn escape_byte(buf: &mut String, byte: u8) {
+    // NAME_ESCAPE + 0xFF is a valid char.
+    buf.push(char::from_u32(NAME_ESCAPE + byte as u32).unwrap());
+}
+
+/// Convert the name on the disk to the name of the vfs.
+pub fn name_from_bytes(bytes: &[u8]) -> String {
+    let mut name = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match escaped_byte(c) {
+                Some(_) => {
+                    let mut utf8 = [0; 4];
+                    for byte in c.encode_utf8(&mut utf8).bytes() {
+                        escape_byte(&mut name, byte);
+                    }
+                }
+                None => name.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            escape_byte(&mut name, *byte);
+        }
+    }
+    name
+}
+
+/// Convert the name of the vfs to the name on the disk, the reverse of
+/// name_from_bytes.
+pub fn name_to_bytes(name: &str) -> Cow<'_, [u8]> {
+    if !is_escaped(name) {
+        return Cow::Borrowed(name.as_bytes());
+    }
+    let mut bytes = Vec::with_capacity(name.len());
+    for c in name.chars() {
+        match escaped_byte(c) {
+            Some(byte) => bytes.push(byte),
+            None => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    Cow::Owned(bytes)
+}
+
+/// The name contains escaped bytes, it isn't valid UTF-8 on the disk.
+pub fn is_escaped(name: &str) -> bool {
+    name.chars().any(|c| escaped_byte(c).is_some())
+}
+
+/// Check the name which will be created or looked up in a directory.
+/// The name can't be empty, longer than NAME_MAX or contain '/' and NUL.
+/// The escaped bytes are checked as they are on the disk. A long name is
+/// InvalidInput with ENAMETOOLONG.
+pub fn check_name(name: &str) -> FsResult<()> {
+    let bytes = name_to_bytes(name);
+    if bytes.len() > NAME_MAX {
+        return Err(FsError::new(VfsError::InvalidInput, Errno::ENAMETOOLONG));
+    }
+    if bytes.is_empty() || bytes.iter().any(|x| *x == b'/' || *x == b'\0') {
+        return Err(VfsError::InvalidInput.into());
+    }
+    Ok(())
+}
+
+/// Check that the nodes are on the same filesystem by the st_dev of their
+/// stat, before a link or a rename across them: NotSupported with EXDEV.
+pub fn check_same_dev(a: &dyn INodeInterface, b: &dyn INodeInterface) -> FsResult<()> {
+    let (mut x, mut y) = (Stat::default(), Stat::default());
+    a.stat(&mut x)?;
+    b.stat(&mut y)?;
+    match x.dev == y.dev {
+        true => Ok(()),
+        false => Err(FsError::new(VfsError::NotSupported, Errno::EXDEV)),
+    }
+}
+
+/// Check the name looked up in a directory. Like Linux, an empty name
+/// isn't found instead of being invalid.
+pub fn check_lookup_name(name: &str) -> FsResult<()> {
+    match name.is_empty() {
+        true => Err(VfsError::FileNotFound.into()),
+        false => check_name(name),
+    }
+}
+
+/// Put "." and ".." at the start of the listing if the backend doesn't
+/// store them, every read_dir lists both like Linux.
+pub fn add_dot_entries(entries: &mut Vec<DirEntry>) {
+    for name in ["..", "."] {
+        if !entries.iter().any(|x| x.filename == name) {
+            entries.insert(
+                0,
+                DirEntry {
+                    filename: String::from(name),
+                    len: 0,
+                    file_type: FileType::Directory,
+                },
+            );
+        }
+    }
+}
+
+/// Check the name passed to a backend which takes the names as str, the
+/// escaped bytes can't be represented in it.
+pub fn check_str_name(name: &str) -> FsResult<()> {
+    check_name(name)?;
+    match is_escaped(name) {
+        true => Err(VfsError::InvalidInput.into()),
+        false => Ok(()),
+    }
+}
+
+/// Check the range [offset, offset + len) of a read or write.
+/// max_size: the max file size of the filesystem, the range must end
+/// within it, InvalidInput with EFBIG beyond. return the end of the
+/// range.
+pub fn check_range(offset: usize, len: usize, max_size: u64) -> FsResult<usize> {
+    let end = offset.checked_add(len).ok_or(VfsError::InvalidInput)?;
+    if end as u64 > max_size {
+        return Err(FsError::new(VfsError::InvalidInput, Errno::EFBIG));
+    }
+    Ok(end)
+}
+
+/// Check the path passed to the path resolver.
+/// The path must be shorter than PATH_MAX and every component must be
+/// shorter than NAME_MAX, ENAMETOOLONG otherwise. The lengths are of the
+/// bytes on the disk.
+pub fn check_path(path: &str) -> FsResult<()> {
+    if path.bytes().any(|x| x == b'\0') {
+        return Err(VfsError::InvalidInput.into());
+    }
+    if name_to_bytes(path).len() >= PATH_MAX
+        || path.split("/").any(|x| name_to_bytes(x).len() > NAME_MAX)
+    {
+        return Err(FsError::new(VfsError::InvalidInput, Errno::ENAMETOOLONG));
+    }
+    Ok(())
+}
+
+/// The stat of the path, like fstatat. dir is the context of the call,
+/// the relative paths are resolved from its cwd, the dirfd. follow: stat
+/// the target of a symbol link at the end of the path like stat, or the
+/// link itself like lstat. The resolution doesn't open the file, so a
+/// dangling link can be stat'ed without following.
+pub fn stat_at(dir: &ResolveContext, path: &str, follow: bool, out: &mut Stat) -> FsResult<()> {
+    let flags = match follow {
+        true => OpenFlags::O_PATH,
+        false => OpenFlags::O_PATH | OpenFlags::O_NOFOLLOW,
+    };
+    Ok(dentry_open_at(dir, path, flags)?.node.stat(out)?)
+}
+
+/// The target of the symbol link at the path, the bytes on the disk
+/// without resolving them. A path which isn't a link is InvalidInput,
+/// EINVAL of readlink.
+pub fn readlinkat(dir: &ResolveContext, path: &str) -> VfsResult<Vec<u8>> {
+    let dentry = dentry_open_at(dir, path, OpenFlags::O_PATH | OpenFlags::O_NOFOLLOW)?;
+    if !matches!(dentry.node.metadata()?.file_type, FileType::Link) {
+        return Err(VfsError::InvalidInput);
+    }
+    let target = dentry.node.resolve_link()?;
+    Ok(name_to_bytes(&target).into_owned())
+}
+
+/// The sticky bit of st_mode, the entries of a sticky directory are only
+/// removed or renamed by their owner, the owner of the directory or root.
+pub const S_ISVTX: u32 = 0o1000;
+
+/// Check that cred may remove or rename the entry node of the directory
+/// dir, by the sticky bit of dir and the owners in the stats, EPERM
+/// otherwise.
+pub fn check_sticky(
+    cred: Cred,
+    dir: &dyn INodeInterface,
+    node: &dyn INodeInterface,
+) -> FsResult<()> {
+    if cred.is_root() {
+        return Ok(());
+    }
+    let mut stat = Stat::default();
+    dir.stat(&mut stat)?;
+    if stat.mode.bits() & S_ISVTX == 0 || stat.uid == cred.uid {
+        return Ok(());
+    }
+    node.stat(&mut stat)?;
+    match stat.uid == cred.uid {
+        true => Ok(()),
+        false => Err(FsError::new(VfsError::InvalidInput, Errno::EPERM)),
+    }
+}
+
+/// The bits of st_mode of the links checks.
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_ISUID: u32 = 0o4000;
+const S_ISGID: u32 = 0o2000;
+const S_IXGRP: u32 = 0o010;
+const S_IWOTH: u32 = 0o002;
+
+/// Check that cred may follow the symbol link node of the directory dir,
+/// like fs.protected_symlinks: in a sticky directory writable by anyone,
+/// only the links of the follower or of the owner of the directory are
+/// followed, root included, so a link planted in /tmp can't redirect the
+/// opens of another user, EACCES.
+pub fn check_follow_link(
+    cred: Cred,
+    dir: &dyn INodeInterface,
+    node: &dyn INodeInterface,
+) -> FsResult<()> {
+    let mut stat = Stat::default();
+    dir.stat(&mut stat)?;
+    let mode = stat.mode.bits();
+    if mode & S_ISVTX == 0 || mode & S_IWOTH == 0 {
+        return Ok(());
+    }
+    let dir_uid = stat.uid;
+    node.stat(&mut stat)?;
+    match stat.uid == cred.uid || stat.uid == dir_uid {
+        true => Ok(()),
+        false => Err(FsError::new(VfsError::InvalidInput, Errno::EACCES)),
+    }
+}
+
+/// Check that cred may make a hard link to node, like
+/// fs.protected_hardlinks: the owner and root link anything, the others
+/// only a regular file they may read and write which isn't setuid or
+/// setgid and executable, EPERM otherwise.
+pub fn check_hard_link(cred: Cred, node: &dyn INodeInterface) -> FsResult<()> {
+    let mut stat = Stat::default();
+    node.stat(&mut stat)?;
+    if cred.is_root() || stat.uid == cred.uid {
+        return Ok(());
+    }
+    let mode = stat.mode.bits();
+    let setid = mode & S_ISUID != 0 || mode & (S_ISGID | S_IXGRP) == S_ISGID | S_IXGRP;
+    // the read and write bits of the group or the others.
+    let rw = match stat.gid == cred.gid {
+        true => 0o060,
+        false => 0o006,
+    };
+    match mode & S_IFMT == S_IFREG && !setid && mode & rw == rw {
+        true => Ok(()),
+        false => Err(FsError::new(VfsError::InvalidInput, Errno::EPERM)),
+    }
+}
+
+/// Split the path of an entry to the path of its directory and its name.
+/// The name can't be "." or "..", the entry of a directory.
+fn split_entry(path: &str) -> VfsResult<(&str, &str)> {
+    let path = path.trim_end_matches('/');
+    let (dir, name) = match path.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some((dir, name)) => (dir, name),
+        None => (".", path),
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(VfsError::InvalidInput);
+    }
+    Ok((dir, name))
+}
+
+/// Resolve the directory of the entry at path and the cached dentry of
+/// the entry, without following a link at the end.
+fn entry_at(dir: &ResolveContext, path: &str) -> VfsResult<(Arc<DentryNode>, Arc<DentryNode>)> {
+    let (parent, name) = split_entry(path)?;
+    let parent = dentry_open_at(dir, parent, OpenFlags::NONE)?;
+    let entry = parent
+        .clone()
+        .open(name, OpenFlags::NONE)
+        .ok_or(VfsError::FileNotFound)?;
+    Ok((parent, entry))
+}
+
+/// Remove the entry at path like unlinkat, remove_dir is AT_REMOVEDIR:
+/// the entry must be a directory, otherwise it must not be one. The
+/// sticky bit of the directory is checked with the cred of dir, and an
+/// immutable or append only entry isn't removed. A directory without
+/// remove_dir is EISDIR and a mount point EBUSY, both InvalidInput. A
+/// read-only filesystem is EROFS, see mounts::check_writable.
+pub fn unlinkat(dir: &ResolveContext, path: &str, remove_dir: bool) -> FsResult<()> {
+    let (parent, entry) = entry_at(dir, path)?;
+    let is_dir = matches!(entry.node.metadata()?.file_type, FileType::Directory);
+    match (remove_dir, is_dir) {
+        (true, false) => return Err(VfsError::NotDir.into()),
+        (false, true) => return Err(FsError::new(VfsError::InvalidInput, Errno::EISDIR)),
+        _ => {}
+    }
+    if is_mount_point(&parent.node, &entry.filename) {
+        return Err(FsError::new(VfsError::InvalidInput, Errno::EBUSY));
+    }
+    mounts::check_writable(parent.node.as_ref())?;
+    check_sticky(dir.cred, parent.node.as_ref(), entry.node.as_ref())?;
+    inode_flags::check_unlink(&entry.node)?;
+    Ok(parent.remove_child(&entry.filename)?)
+}
+
+/// Rename the entry at old to new like renameat, see renameat2.
+pub fn renameat(
+    olddir: &ResolveContext,
+    old: &str,
+    newdir: &ResolveContext,
+    new: &str,
+) -> FsResult<()> {
+    renameat2(olddir, old, newdir, new, RenameFlags::NONE)
+}
+
+/// Rename the entry at old to new like renameat2, the sticky bits of both
+/// directories and the inode flags are checked for the entry and an
+/// existing target, then the filesystem renames it with the flags, see
+/// rename.rs. The mount points don't move, InvalidInput with EBUSY, and
+/// a read-only filesystem is EROFS.
+pub fn renameat2(
+    olddir: &ResolveContext,
+    old: &str,
+    newdir: &ResolveContext,
+    new: &str,
+    flags: RenameFlags,
+) -> FsResult<()> {
+    let (old_parent, entry) = entry_at(olddir, old)?;
+    mounts::check_writable(old_parent.node.as_ref())?;
+    check_sticky(olddir.cred, old_parent.node.as_ref(), entry.node.as_ref())?;
+    inode_flags::check_unlink(&entry.node)?;
+    let (new_parent, name) = split_entry(new)?;
+    let new_parent = dentry_open_at(newdir, new_parent, OpenFlags::NONE)?;
+    match new_parent.clone().open(name, OpenFlags::NONE) {
+        // the filesystem checks it again under its locks.
+        Some(_) if flags.contains(RenameFlags::NOREPLACE) => {
+            return Err(VfsError::AlreadyExists.into());
+        }
+        Some(target) => {
+            check_sticky(newdir.cred, new_parent.node.as_ref(), target.node.as_ref())?;
+            inode_flags::check_unlink(&target.node)?;
+        }
+        None if flags.contains(RenameFlags::EXCHANGE) => return Err(VfsError::FileNotFound.into()),
+        None => {}
+    }
+    if is_mount_point(&old_parent.node, &entry.filename) || is_mount_point(&new_parent.node, name) {
+        return Err(FsError::new(VfsError::InvalidInput, Errno::EBUSY));
+    }
+    rename::rename(
+        &old_parent.node,
+        &entry.filename,
+        &new_parent.node,
+        name,
+        flags,
+    )?;
+    let changes = match flags.contains(RenameFlags::EXCHANGE) {
+        true => {
+            old_parent.exchanged(&entry.filename, &new_parent, name);
+            [DirChange::Replaced, DirChange::Replaced]
+        }
+        false => {
+            old_parent.renamed(&entry.filename, &new_parent, name);
+            [DirChange::Removed, DirChange::Added]
+        }
+    };
+    readdir::changed(&old_parent.node, &entry.filename, changes[0]);
+    readdir::changed(&new_parent.node, name, changes[1]);
+    Ok(())
+}
+
+/// Link the entry at old to new like linkat, follow is AT_SYMLINK_FOLLOW.
+/// The links are checked with the cred of newdir when it's protected,
+/// see check_hard_link, and an immutable or append only entry isn't
+/// linked. A directory fails with InvalidInput, the entries of two
+/// filesystems with EXDEV, see check_same_dev, a read-only filesystem
+/// with EROFS.
+pub fn linkat(
+    olddir: &ResolveContext,
+    old: &str,
+    newdir: &ResolveContext,
+    new: &str,
+    follow: bool,
+) -> FsResult<()> {
+    let flags = match follow {
+        true => OpenFlags::NONE,
+        false => OpenFlags::O_NOFOLLOW | OpenFlags::O_PATH,
+    };
+    let entry = dentry_open_at(olddir, old, flags)?;
+    if matches!(entry.node.metadata()?.file_type, FileType::Directory) {
+        return Err(VfsError::InvalidInput.into());
+    }
+    if newdir.protected {
+        check_hard_link(newdir.cred, entry.node.as_ref())?;
+    }
+    inode_flags::check_unlink(&entry.node)?;
+    let (new_parent, name) = split_entry(new)?;
+    let new_parent = dentry_open_at(newdir, new_parent, OpenFlags::NONE)?;
+    check_same_dev(new_parent.node.as_ref(), entry.node.as_ref())?;
+    mounts::check_writable(new_parent.node.as_ref())?;
+    check_name(name)?;
+    new_parent.node.link(name, entry.node.clone())?;
+    invalidate_negative(&new_parent.node, name);
+    readdir::changed(&new_parent.node, name, DirChange::Added);
+    Ok(())
+}
+
+/// The result of the creation of the entry name of dir by a node method,
+/// counted as a change of dir if it succeeded, see readdir.
+fn added<T>(dir: &Arc<dyn INodeInterface>, name: &str, r: VfsResult<T>) -> VfsResult<T> {
+    if r.is_ok() {
+        readdir::changed(dir, name, DirChange::Added);
+    }
+    r
+}
+
+/// A directory being removed by remove_dir_all.
+struct RemoveFrame {
+    parent: Arc<dyn INodeInterface>,
+    name: String,
+    node: Arc<dyn INodeInterface>,
+    /// The entries were removed or pushed, only the directory is left.
+    expanded: bool,
+}
+
+/// Remove the entry name of dir and everything below it, like rm -rf.
+/// The symbol links are removed, not followed, and a mount point stops
+/// the removal of its entry. The tree is walked with a stack on the
+/// heap, so its depth doesn't matter.
+/// The removal goes on after a failure: every other entry is still
+/// tried and the first error is returned, the directories above the
+/// failed entries are left since they aren't empty. A directory reached
+/// twice, by a loop of a corrupted filesystem, fails with InvalidInput
+/// instead of being walked again, identified like walk. A mount point is
+/// InvalidInput with EBUSY, a read-only filesystem EROFS.
+pub fn remove_dir_all(dir: Arc<dyn INodeInterface>, name: &str) -> FsResult<()> {
+    check_name(name)?;
+    if name == "." || name == ".." {
+        return Err(VfsError::InvalidInput.into());
+    }
+    if is_mount_point(&dir, name) {
+        return Err(FsError::new(VfsError::InvalidInput, Errno::EBUSY));
+    }
+    mounts::check_writable(dir.as_ref())?;
+    let node = dir.lookup(name)?;
+    if !matches!(node.metadata()?.file_type, FileType::Directory) {
+        dir.remove(name)?;
+        readdir::changed(&dir, name, DirChange::Removed);
+        return Ok(());
+    }
+    let mut first: Option<FsError> = None;
+    let mut fail = |err: FsError| {
+        first.get_or_insert(err);
+    };
+    let mut visited: BTreeSet<_> = identity(node.as_ref()).into_iter().collect();
+    let mut stack = vec![RemoveFrame {
+        parent: dir,
+        name: String::from(name),
+        node,
+        expanded: false,
+    }];
+    while let Some(frame) = stack.last_mut() {
+        if frame.expanded {
+            let frame = stack.pop().unwrap();
+            match frame.parent.rmdir(&frame.name) {
+                Ok(()) => readdir::changed(&frame.parent, &frame.name, DirChange::Removed),
+                Err(err) => fail(err.into()),
+            }
+            continue;
+        }
+        frame.expanded = true;
+        let dir = frame.node.clone();
+        let entries = match dir.read_dir() {
+            Ok(entries) => entries,
+            Err(err) => {
+                fail(err.into());
+                continue;
+            }
+        };
+        for entry in entries {
+            let name = entry.filename;
+            if name == "." || name == ".." {
+                continue;
+            }
+            if !matches!(entry.file_type, FileType::Directory) {
+                match dir.remove(&name) {
+                    Ok(()) => readdir::changed(&dir, &name, DirChange::Removed),
+                    Err(err) => fail(err.into()),
+                }
+                continue;
+            }
+            if is_mount_point(&dir, &name) {
+                fail(FsError::new(VfsError::InvalidInput, Errno::EBUSY));
+                continue;
+            }
+            match dir.lookup(&name) {
+                Ok(node) if identity(node.as_ref()).is_some_and(|x| !visited.insert(x)) => {
+                    fail(VfsError::InvalidInput.into())
+                }
+                Ok(node) => stack.push(RemoveFrame {
+                    parent: dir.clone(),
+                    name,
+                    node,
+                    expanded: false,
+                }),
+                Err(err) => fail(err.into()),
+            }
+        }
+    }
+    first.map_or(Ok(()), Err)
+}
+
+/// What copy_recursive does with the files it can't create, like the
+/// devices and sockets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecialFiles {
+    Skip,
+    /// Stop the copy with NotSupported.
+    Error,
+}
+
+/// An entry handled by copy_recursive, path is relative to the source.
+#[derive(Debug, Clone, Copy)]
+pub enum CopyProgress<'a> {
+    /// bytes is the size of the data of the regular files, 0 for the
+    /// others.
+    Copied {
+        path: &'a str,
+        file_type: FileType,
+        bytes: usize,
+    },
+    /// A hard link to the copy of the path seen before.
+    Linked {
+        path: &'a str,
+        to: &'a str,
+    },
+    Skipped {
+        path: &'a str,
+    },
+    /// The copy stops with the error.
+    Failed {
+        path: &'a str,
+        error: &'a VfsError,
+    },
+}
+
+pub struct CopyOptions<'a> {
+    /// Set the access and modification times of the copies with utimes,
+    /// the destinations which don't keep the times are ignored.
+    pub timestamps: bool,
+    /// Link the copies of the files linked in the source, they are
+    /// identified by the inode of their metadata. A destination without
+    /// HARD_LINKS of capabilities.rs gets a copy of each link.
+    pub hard_links: bool,
+    pub special: SpecialFiles,
+    pub progress: Option<&'a mut dyn FnMut(CopyProgress)>,
+}
+
+impl Default for CopyOptions<'_> {
+    fn default() -> Self {
+        Self {
+            timestamps: true,
+            hard_links: true,
+            special: SpecialFiles::Skip,
+            progress: None,
+        }
+    }
+}
+
+/// The size of the buffer of copy_file_data.
+const COPY_CHUNK: usize = 0x10000;
+
+/// Copy the data of the file src to the start of dst, return the bytes
+/// copied.
+pub fn copy_file_data(
+    src: &Arc<dyn INodeInterface>,
+    dst: &Arc<dyn INodeInterface>,
+) -> VfsResult<usize> {
+    let mut reader = InodeReader::with_capacity(COPY_CHUNK, src.clone());
+    let mut writer = InodeWriter::with_capacity(COPY_CHUNK, dst.clone());
+    let copied = io::copy(&mut reader, &mut writer)?;
+    writer.flush()?;
+    Ok(copied as usize)
+}
+
+/// A directory being copied by copy_recursive.
+struct CopyFrame {
+    src: Arc<dyn INodeInterface>,
+    dst: Arc<dyn INodeInterface>,
+    /// The path of the directory in the source, empty for the top.
+    path: String,
+    /// The entries were copied or pushed, only the times are left.
+    expanded: bool,
+}
+
+/// Copy the entries of src_dir into dst_dir with their trees, like
+/// cp -a. The directories, the regular files and the symbol links are
+/// recreated, the link targets as they are. The source is walked with a
+/// stack on the heap like in remove_dir_all, the mount points below it
+/// aren't crossed since only the dentry tree has them. A source
+/// directory reached twice, by a loop or by a copy into its own tree,
+/// fails with InvalidInput, identified like walk. A symbol link is
+/// handled like the special files when the destination has no SYMLINKS.
+/// The first error stops the copy, the copied entries are left.
+/// TODO: keep the modes and the owners when INodeInterface can set them.
+pub fn copy_recursive(
+    src_dir: Arc<dyn INodeInterface>,
+    dst_dir: Arc<dyn INodeInterface>,
+    mut options: CopyOptions,
+) -> VfsResult<()> {
+    options.hard_links &= capabilities::of_node(&dst_dir).contains(FsCapabilities::HARD_LINKS);
+    // the source inodes of the linked files and the paths of their copies.
+    let mut seen: BTreeMap<usize, (String, Arc<dyn INodeInterface>)> = BTreeMap::new();
+    // the directories copied and their copies.
+    let mut visited: BTreeSet<_> = [src_dir.as_ref(), dst_dir.as_ref()]
+        .into_iter()
+        .filter_map(identity)
+        .collect();
+    let mut stack = vec![CopyFrame {
+        src: src_dir,
+        dst: dst_dir,
+        path: String::new(),
+        expanded: false,
+    }];
+    while let Some(frame) = stack.last_mut() {
+        if frame.expanded {
+            let frame = stack.pop().unwrap();
+            // the times of a directory are set after its entries changed it.
+            if options.timestamps && !frame.path.is_empty() {
+                copy_times(frame.src.as_ref(), frame.dst.as_ref())?;
+            }
+            continue;
+        }
+        frame.expanded = true;
+        let (src, dst, base) = (frame.src.clone(), frame.dst.clone(), frame.path.clone());
+        for entry in src.read_dir()? {
+            let name = entry.filename;
+            if name == "." || name == ".." {
+                continue;
+            }
+            let path = match base.is_empty() {
+                true => name.clone(),
+                false => format!("{}/{}", base, name),
+            };
+            let mut r = copy_entry(
+                &src,
+                &dst,
+                &name,
+                &path,
+                entry.file_type,
+                &mut options,
+                &mut seen,
+            );
+            if let Ok(Some(frame)) = &r
+                && [&frame.src, &frame.dst]
+                    .into_iter()
+                    .any(|x| identity(x.as_ref()).is_some_and(|x| !visited.insert(x)))
+            {
+                r = Err(VfsError::InvalidInput);
+            }
+            match r {
+                Ok(Some(frame)) => stack.push(frame),
+                Ok(None) => {}
+                Err(error) => {
+                    if let Some(progress) = options.progress.as_mut() {
+                        progress(CopyProgress::Failed {
+                            path: &path,
+                            error: &error,
+                        });
+                    }
+                    return Err(error);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Copy the entry name of src to dst, return the frame of a directory.
+fn copy_entry(
+    src: &Arc<dyn INodeInterface>,
+    dst: &Arc<dyn INodeInterface>,
+    name: &str,
+    path: &str,
+    file_type: FileType,
+    options: &mut CopyOptions,
+    seen: &mut BTreeMap<usize, (String, Arc<dyn INodeInterface>)>,
+) -> VfsResult<Option<CopyFrame>> {
+    let node = src.lookup(name)?;
+    let mut report = |event| {
+        if let Some(progress) = options.progress.as_mut() {
+            progress(event);
+        }
+    };
+    match file_type {
+        FileType::Directory => {
+            let copy = match dst.mkdir(name) {
+                Err(VfsError::AlreadyExists) => dst.lookup(name)?,
+                r => added(dst, name, r)?,
+            };
+            report(CopyProgress::Copied {
+                path,
+                file_type,
+                bytes: 0,
+            });
+            Ok(Some(CopyFrame {
+                src: node,
+                dst: copy,
+                path: String::from(path),
+                expanded: false,
+            }))
+        }
+        FileType::File => {
+            let ino = node.metadata()?.inode;
+            let mut stat = Stat::default();
+            node.stat(&mut stat)?;
+            let linked = options.hard_links && stat.nlink > 1 && ino != 0;
+            if linked && let Some((to, copy)) = seen.get(&ino) {
+                added(dst, name, dst.link(name, copy.clone()))?;
+                report(CopyProgress::Linked { path, to });
+                return Ok(None);
+            }
+            let copy = added(dst, name, dst.touch(name))?;
+            let bytes = copy_file_data(&node, &copy)?;
+            if options.timestamps {
+                copy_times(node.as_ref(), copy.as_ref())?;
+            }
+            if linked {
+                seen.insert(ino, (String::from(path), copy));
+            }
+            report(CopyProgress::Copied {
+                path,
+                file_type,
+                bytes,
+            });
+            Ok(None)
+        }
+        FileType::Link if capabilities::of_node(dst).contains(FsCapabilities::SYMLINKS) => {
+            added(dst, name, dst.sym_link(name, &node.resolve_link()?))?;
+            report(CopyProgress::Copied {
+                path,
+                file_type,
+                bytes: 0,
+            });
+            Ok(None)
+        }
+        _ => match options.special {
+            SpecialFiles::Skip => {
+                report(CopyProgress::Skipped { path });
+                Ok(None)
+            }
+            SpecialFiles::Error => Err(VfsError::NotSupported),
+        },
+    }
+}
+
+/// Set the access and modification times of dst to the ones of src.
+fn copy_times(src: &dyn INodeInterface, dst: &dyn INodeInterface) -> VfsResult<()> {
+    let mut stat = Stat::default();
+    src.stat(&mut stat)?;
+    let mut times = [stat.atime, stat.mtime];
+    match dst.utimes(&mut times) {
+        Err(VfsError::NotSupported) => Ok(()),
+        r => r,
+    }
+}
+
+/// The kind of an item of bulk_create.
+pub enum CreateKind<'a> {
+    Dir,
+    /// A regular file with the data of the reader, to its end.
+    File(Box<dyn io::Read + 'a>),
+    /// A symbol link to the target.
+    Symlink(String),
+}
+
+/// An entry made by bulk_create.
+pub struct CreateItem<'a> {
+    /// The path below the directory, the missing directories in it are
+    /// made. A ".." fails the item with InvalidInput.
+    pub path: String,
+    pub kind: CreateKind<'a>,
+    /// The permission bits.
+    pub mode: u32,
+    /// The access and modification time, where the filesystem keeps it.
+    pub mtime: TimeSpec,
+}
+
+/// An item bulk_create couldn't make.
+#[derive(Debug, Clone)]
+pub struct CreateError {
+    /// The position of the item in the iterator.
+    pub index: usize,
+    pub path: String,
+    pub error: VfsError,
+}
+
+/// The result of bulk_create.
+#[derive(Debug, Clone, Default)]
+pub struct BulkReport {
+    /// The items made.
+    pub created: usize,
+    /// The bytes of the regular files.
+    pub bytes: u64,
+    pub errors: Vec<CreateError>,
+}
+
+/// Make the items in dir, like the extraction of an archive, cheaper than
+/// an open, a write and a close for each of them: the directories are
+/// looked up once and kept for the next items, and the items are made in
+/// a batch of batch.rs, so a filesystem which can batch writes its
+/// metadata back once at the end. An existing file is replaced, an
+/// existing directory is kept. A failed item is reported and the next
+/// ones are made, the items below a failed directory fail too. The
+/// directories get their mtimes at the end, after their entries. The
+/// error is the one of the writeback of the batch.
+/// TODO: keep the modes when INodeInterface can set them.
+pub fn bulk_create<'a>(
+    dir: &Arc<dyn INodeInterface>,
+    items: impl Iterator<Item = CreateItem<'a>>,
+) -> VfsResult<BulkReport> {
+    let mut report = BulkReport::default();
+    // the directories by their path below dir, "" is dir itself.
+    let mut dirs: BTreeMap<String, Arc<dyn INodeInterface>> = BTreeMap::new();
+    dirs.insert(String::new(), dir.clone());
+    let mut times: Vec<(Arc<dyn INodeInterface>, TimeSpec)> = Vec::new();
+    batch::begin_batch(dir);
+    for (index, item) in items.enumerate() {
+        match create_item(&mut dirs, item.kind, &item.path) {
+            Ok((node, bytes)) => {
+                report.created += 1;
+                report.bytes += bytes as u64;
+                times.push((node, item.mtime));
+            }
+            Err(error) => report.errors.push(CreateError {
+                index,
+                path: item.path,
+                error,
+            }),
+        }
+    }
+    // the times of a directory change with its entries, the last ones
+    // are set first.
+    for (node, mtime) in times.into_iter().rev() {
+        match node.utimes(&mut [mtime, mtime]) {
+            Ok(()) | Err(VfsError::NotSupported) => {}
+            Err(error) => log::warn!("bulk_create can't set a time: {:?}", error),
+        }
+    }
+    batch::end_batch(dir)?;
+    Ok(report)
+}
+
+/// Make the item of bulk_create at the path, return its node and the
+/// bytes written.
+fn create_item(
+    dirs: &mut BTreeMap<String, Arc<dyn INodeInterface>>,
+    kind: CreateKind,
+    path: &str,
+) -> VfsResult<(Arc<dyn INodeInterface>, usize)> {
+    let names: Vec<&str> = path
+        .split('/')
+        .filter(|x| !x.is_empty() && *x != ".")
+        .collect();
+    if names.contains(&"..") {
+        return Err(VfsError::InvalidInput);
+    }
+    let Some((name, parents)) = names.split_last() else {
+        return Err(VfsError::InvalidInput);
+    };
+    check_name(name)?;
+    // the nearest known directory, then the missing ones below it.
+    let mut known = parents.len();
+    while !dirs.contains_key(&parents[..known].join("/")) {
+        known -= 1;
+    }
+    let mut parent = dirs[&parents[..known].join("/")].clone();
+    for (i, dir_name) in parents.iter().enumerate().skip(known) {
+        parent = match parent.lookup(dir_name) {
+            Err(VfsError::FileNotFound) => added(&parent, dir_name, parent.mkdir(dir_name))?,
+            r => r?,
+        };
+        dirs.insert(parents[..=i].join("/"), parent.clone());
+    }
+    match kind {
+        CreateKind::Dir => {
+            let node = match parent.mkdir(name) {
+                Err(VfsError::AlreadyExists) => parent.lookup(name)?,
+                r => added(&parent, name, r)?,
+            };
+            dirs.insert(names.join("/"), node.clone());
+            Ok((node, 0))
+        }
+        CreateKind::File(mut data) => {
+            let file = match parent.lookup(name) {
+                Ok(file) => {
+                    file.truncate(0)?;
+                    file
+                }
+                Err(VfsError::FileNotFound) => added(&parent, name, parent.touch(name))?,
+                Err(err) => return Err(err),
+            };
+            let mut writer = InodeWriter::with_capacity(COPY_CHUNK, file.clone());
+            let copied = io::copy(&mut *data, &mut writer)?;
+            writer.flush()?;
+            drop(writer);
+            file.flush()?;
+            Ok((file, copied as usize))
+        }
+        CreateKind::Symlink(target) => {
+            added(&parent, name, parent.sym_link(name, &target))?;
+            Ok((parent.lookup(name)?, 0))
+        }
+    }
+}
+
+/// When create_with replaces an existing file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overwrite {
+    /// An existing file is kept.
+    Never,
+    Always,
+    /// An existing file is kept if it holds the contents already.
+    IfDifferent,
+}
+
+/// What create_with did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CreateOutcome {
+    Created,
+    Replaced,
+    /// The existing file was kept.
+    Unchanged,
+}
+
+/// The number of the next temporary file of create_with.
+static NEXT_TEMP: AtomicUsize = AtomicUsize::new(0);
+
+/// Create the file name of dir with the contents unless it exists, like a
+/// config writer: the check, the creation, the write and the fsync are
+/// done under the create lock of dir, so two create_withs of a name don't
+/// interleave. A file another writer creates meanwhile is kept by Never
+/// and replaced by the others, as if it was there before. On
+/// a filesystem which can rename, the contents are written to a temporary
+/// file in dir which is renamed over name, so the readers see the old
+/// file or the whole new one, never a part. Elsewhere, see capabilities,
+/// the file is written in place and truncated to the contents after.
+/// IfDifferent compares the existing file with the contents by chunks,
+/// so a file already right isn't written again. mode holds the
+/// permission bits and maybe S_IFREG, another type or an existing entry
+/// which isn't a regular file fails with InvalidInput, a mount point too,
+/// an existing directory with EISDIR.
+/// TODO: keep the permission bits when INodeInterface can set them.
+pub fn create_with(
+    dir: &Arc<DentryNode>,
+    name: &str,
+    mode: u32,
+    contents: &[u8],
+    overwrite: Overwrite,
+) -> FsResult<CreateOutcome> {
+    check_name(name)?;
+    if !matches!(mode & S_IFMT, 0 | S_IFREG) || is_mount_point(&dir.node, name) {
+        return Err(VfsError::InvalidInput.into());
+    }
+    let _create = dir.create.lock();
+    loop {
+        match create_once(dir, name, mode, contents, overwrite) {
+            Err(FsError {
+                error: VfsError::AlreadyExists,
+                ..
+            }) if overwrite != Overwrite::Never => {}
+            Err(FsError {
+                error: VfsError::AlreadyExists,
+                ..
+            }) => return Ok(CreateOutcome::Unchanged),
+            r => return r,
+        }
+    }
+}
+
+/// One try of create_with, AlreadyExists if another writer created name
+/// since the lookup.
+fn create_once(
+    dir: &Arc<DentryNode>,
+    name: &str,
+    mode: u32,
+    contents: &[u8],
+    overwrite: Overwrite,
+) -> FsResult<CreateOutcome> {
+    let existing = match dir.node.lookup(name) {
+        Ok(node) => match node.metadata()?.file_type {
+            FileType::File => Some(node),
+            FileType::Directory => {
+                return Err(FsError::new(VfsError::InvalidInput, Errno::EISDIR));
+            }
+            _ => return Err(VfsError::InvalidInput.into()),
+        },
+        Err(VfsError::FileNotFound) => None,
+        Err(err) => return Err(err.into()),
+    };
+    if let Some(node) = existing.as_ref() {
+        match overwrite {
+            Overwrite::Never => return Ok(CreateOutcome::Unchanged),
+            Overwrite::IfDifferent if same_contents(node, contents)? => {
+                return Ok(CreateOutcome::Unchanged);
+            }
+            _ => {}
+        }
+    }
+    let outcome = match existing {
+        Some(_) => CreateOutcome::Replaced,
+        None => CreateOutcome::Created,
+    };
+    if capabilities::of_node(&dir.node).contains(FsCapabilities::RENAME) {
+        match replace_by_rename(dir, name, mode, contents, existing.as_ref()) {
+            Err(FsError {
+                error: VfsError::NotSupported,
+                ..
+            }) => {}
+            r => return r.map(|_| outcome),
+        }
+    }
+    let file = match existing {
+        Some(file) => {
+            write_contents(&file, contents)?;
+            file.truncate(contents.len())?;
+            file
+        }
+        None => {
+            let file = mknod(&dir.node, name, S_IFREG | (mode & 0o7777), 0)?;
+            invalidate_negative(&dir.node, name);
+            write_contents(&file, contents)?;
+            file
+        }
+    };
+    fsync::fsync(&file)?;
+    Ok(outcome)
+}
+
+/// Write the contents to a new temporary file of dir and rename it over
+/// name, for create_with. The temporary file is made durable before the
+/// rename, and the directory after it. Without an existing file the
+/// rename doesn't replace, a name another writer made since fails with
+/// AlreadyExists. The temporary file is removed if it fails.
+fn replace_by_rename(
+    dir: &Arc<DentryNode>,
+    name: &str,
+    mode: u32,
+    contents: &[u8],
+    existing: Option<&Arc<dyn INodeInterface>>,
+) -> FsResult<()> {
+    if let Some(existing) = existing {
+        inode_flags::check_unlink(existing)?;
+    }
+    let (temp, file) = loop {
+        let temp = format!(".create_with.{}", NEXT_TEMP.fetch_add(1, Ordering::Relaxed));
+        match mknod(&dir.node, &temp, S_IFREG | (mode & 0o7777), 0) {
+            // left by a create_with which didn't finish.
+            Err(FsError {
+                error: VfsError::AlreadyExists,
+                ..
+            }) => continue,
+            r => break (temp, r?),
+        }
+    };
+    let flags = match existing {
+        Some(_) => RenameFlags::NONE,
+        None => RenameFlags::NOREPLACE,
+    };
+    let result = write_contents(&file, contents)
+        .and_then(|_| fsync::fsync(&file))
+        .map_err(FsError::from)
+        .and_then(|_| rename::rename(&dir.node, &temp, &dir.node, name, flags));
+    if let Err(err) = result {
+        match dir.node.remove(&temp) {
+            Ok(()) => readdir::changed(&dir.node, &temp, DirChange::Removed),
+            Err(err) => log::warn!("create_with can't remove {}: {:?}", temp, err),
+        }
+        return Err(err);
+    }
+    dir.renamed(&temp, dir, name);
+    readdir::changed(&dir.node, &temp, DirChange::Removed);
+    readdir::changed(&dir.node, name, DirChange::Added);
+    match fsync::fsync(&dir.node) {
+        Err(VfsError::NotSupported) => Ok(()),
+        r => Ok(r?),
+    }
+}
+
+/// Write the contents at the start of the file.
+fn write_contents(file: &Arc<dyn INodeInterface>, contents: &[u8]) -> VfsResult<()> {
+    let mut writer = InodeWriter::with_capacity(COPY_CHUNK, file.clone());
+    writer.write_all(contents)?;
+    writer.flush()
+}
+
+/// Whether the file holds the contents and nothing more.
+fn same_contents(file: &Arc<dyn INodeInterface>, contents: &[u8]) -> VfsResult<bool> {
+    if file.metadata()?.size != contents.len() {
+        return Ok(false);
+    }
+    let mut buf = vec![0; COPY_CHUNK.min(contents.len())];
+    let mut pos = 0;
+    while pos < contents.len() {
+        let n = file.readat(pos, &mut buf)?;
+        if n == 0 || contents.get(pos..pos + n) != Some(&buf[..n]) {
+            return Ok(false);
+        }
+        pos += n;
+    }
+    // a file grown since has more.
+    Ok(file.readat(pos, &mut [0])? == 0)
+}
+
+/// The space used by a tree, counted by disk_usage.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// The sum of the file sizes, like du --apparent-size.
+    pub bytes_apparent: u64,
+    /// The sum of st_blocks in bytes, the holes of the sparse files and
+    /// the inline data take no blocks.
+    pub bytes_allocated: u64,
+    pub inodes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, stat: &Stat) {
+        self.bytes_apparent += stat.size;
+        self.bytes_allocated += stat.blocks * 512;
+        self.inodes += 1;
+    }
+}
+
+/// Count the space used by dir and the tree below it, like du -s. The
+/// symbol links are counted, not followed. The filesystems mounted below
+/// dir are only counted with cross_mounts, the mount points are matched
+/// like is_mount_point. A file with more than one link is counted once,
+/// by the (fsid, inode) of its metadata, the ones reporting inode 0 are
+/// counted at each link.
+/// The first error stops the counting, a loop fails with ELOOP.
+pub fn disk_usage(dir: Arc<dyn INodeInterface>, cross_mounts: bool) -> FsResult<Usage> {
+    let mut usage = Usage::default();
+    let mut seen = BTreeSet::new();
+    let mut stat = Stat::default();
+    dir.stat(&mut stat)?;
+    usage.add(&stat);
+    for entry in WalkDir::new(dir).same_fs(!cross_mounts) {
+        let entry = entry.map_err(|x| x.error)?;
+        let mut stat = Stat::default();
+        entry.inode.stat(&mut stat)?;
+        if stat.nlink > 1
+            && !matches!(entry.file_type, FileType::Directory)
+            && let Some(id) = identity(entry.inode.as_ref())
+            && !seen.insert(id)
+        {
+            continue;
+        }
+        usage.add(&stat);
+    }
+    Ok(usage)
+}
+
+/// The entries of a snapshot kept in memory by dir_iter_snapshot, the
+/// others spill to a file.
+pub const SNAPSHOT_INLINE: usize = 4096;
+
+/// The entries of a page listed into a snapshot.
+const SNAPSHOT_PAGE: usize = 256;
+
+/// An entry of a snapshot of a directory.
+#[derive(Debug, Clone)]
+pub struct SnapshotEntry {
+    pub name: String,
+    /// The inode number, 0 if the node doesn't have one or was removed
+    /// between the listing and its stat.
+    pub ino: u64,
+    pub file_type: FileType,
+}
+
+/// The entries of a directory at an instant, see dir_iter_snapshot. The
+/// entries beyond the inline ones are read back from the spill file as
+/// they're iterated, a failed read is yielded and ends the iteration.
+pub struct DirSnapshot {
+    inline: alloc::vec::IntoIter<SnapshotEntry>,
+    spill: Option<InodeReader>,
+    /// The entries left in the spill file.
+    spilled: usize,
+}
+
+impl DirSnapshot {
+    /// The entries left to iterate.
+    pub fn len(&self) -> usize {
+        self.inline.len() + self.spilled
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The entries left in the spill file.
+    pub fn spilled(&self) -> usize {
+        self.spilled
+    }
+
+    fn read_spilled(&mut self) -> VfsResult<SnapshotEntry> {
+        let reader = self.spill.as_mut().ok_or(VfsError::UnexpectedEof)?;
+        let mut header = [0; 11];
+        reader.read_exact(&mut header)?;
+        let mut name = vec![0; u16::from_le_bytes([header[9], header[10]]) as usize];
+        reader.read_exact(&mut name)?;
+        Ok(SnapshotEntry {
+            name: String::from_utf8(name).map_err(|_| VfsError::InvalidData)?,
+            ino: u64::from_le_bytes(header[..8].try_into().unwrap()),
+            file_type: d_type_file_type(header[8]),
+        })
+    }
+}
+
+impl Iterator for DirSnapshot {
+    type Item = VfsResult<SnapshotEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entry) = self.inline.next() {
+            return Some(Ok(entry));
+        }
+        if self.spilled == 0 {
+            return None;
+        }
+        self.spilled -= 1;
+        let r = self.read_spilled();
+        if r.is_err() {
+            self.spilled = 0;
+        }
+        Some(r)
+    }
+}
+
+/// Write the entry to the spill file, the inode number, the d_type, the
+/// length of the name and the name.
+fn spill_entry(writer: &mut InodeWriter, entry: &SnapshotEntry) -> VfsResult<()> {
+    let len = u16::try_from(entry.name.len()).map_err(|_| VfsError::InvalidInput)?;
+    writer.write_all(&entry.ino.to_le_bytes())?;
+    writer.write_all(&[dirent_type(entry.file_type)])?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(entry.name.as_bytes())
+}
+
+/// Snapshot the entries of dir, without "." and "..", see
+/// dir_iter_snapshot_with. The entries beyond SNAPSHOT_INLINE spill to
+/// a temporary file of a TmpFs.
+pub fn dir_iter_snapshot(dir: &Arc<dyn INodeInterface>) -> VfsResult<DirSnapshot> {
+    dir_iter_snapshot_with(dir, SNAPSHOT_INLINE, None)
+}
+
+/// Snapshot the entries of dir with their inode numbers, not their data,
+/// and iterate them. The directory is listed under its lock_dir, so the
+/// snapshot has exactly the entries of an instant whatever is created
+/// and removed meanwhile, the changes wait for the listing. A node
+/// without lock_dir is listed by one read_dir, as consistent as the
+/// node makes it, and its entries are stat'ed after it.
+/// inline: the entries kept in memory, the others are written to spill,
+/// or to TmpFs::temp_file if it's None, and read back by the iteration.
+/// spill is written from its start.
+pub fn dir_iter_snapshot_with(
+    dir: &Arc<dyn INodeInterface>,
+    inline: usize,
+    mut spill: Option<Arc<dyn INodeInterface>>,
+) -> VfsResult<DirSnapshot> {
+    let mut entries = Vec::new();
+    let mut writer = None;
+    let mut spilled = 0;
+    let mut add = |entry: SnapshotEntry| -> VfsResult<()> {
+        if entry.name == "." || entry.name == ".." {
+            return Ok(());
+        }
+        if entries.len() < inline {
+            entries.push(entry);
+            return Ok(());
+        }
+        let writer = writer
+            .get_or_insert_with(|| InodeWriter::new(spill.take().unwrap_or_else(TmpFs::temp_file)));
+        spill_entry(writer, &entry)?;
+        spilled += 1;
+        Ok(())
+    };
+    let locked = readdir::node_of(dir).and_then(|x| x.lock_dir());
+    match locked {
+        Some(_locked) => {
+            let mut pos = 0;
+            loop {
+                let page = readdir::read_dir_plus(dir, pos, SNAPSHOT_PAGE, true)?;
+                let Some(last) = page.last() else {
+                    break;
+                };
+                pos = last.entry.next;
+                for plus in page {
+                    add(SnapshotEntry {
+                        ino: plus.attrs.map_or(0, |x| x.ino),
+                        file_type: plus.entry.entry.file_type,
+                        name: plus.entry.entry.filename,
+                    })?;
+                }
+            }
+        }
+        None => {
+            for entry in dir.read_dir()? {
+                let ino = match entry.filename.as_str() {
+                    "." | ".." => 0,
+                    name => readdir::stat_entry(dir, name).map_or(0, |x| x.ino),
+                };
+                add(SnapshotEntry {
+                    ino,
+                    file_type: entry.file_type,
+                    name: entry.filename,
+                })?;
+            }
+        }
+    }
+    let spill = match writer {
+        Some(writer) => Some(InodeReader::new(writer.into_inner()?)),
+        None => None,
+    };
+    Ok(DirSnapshot {
+        inline: entries.into_iter(),
+        spill,
+        spilled,
+    })
+}
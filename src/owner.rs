@@ -0,0 +1,25 @@
+// The owners of the files, chown. INodeInterface of vfscore can't change
+// the owner, so the nodes which can implement OwnerINode and hand it out
+// by their FsNode, like the nodes of statx.rs. A filesystem with quotas
+// moves the charges of the file to the new owner, see quota.rs.
+// TODO: check the cred of the caller, only root may give a file away.
+
+use alloc::sync::Arc;
+use vfscore::{INodeInterface, VfsError, VfsResult};
+
+use crate::error::FsResult;
+use crate::inode_flags;
+use crate::node;
+
+pub trait OwnerINode: Send + Sync {
+    /// Set the owner and the group of the node.
+    fn set_owner(&self, uid: u32, gid: u32) -> VfsResult<()>;
+}
+
+/// Change the owner and the group of the node like chown, NotSupported
+/// if it can't, EPERM if it's immutable.
+pub fn chown(file: &Arc<dyn INodeInterface>, uid: u32, gid: u32) -> FsResult<()> {
+    inode_flags::check_setattr(file)?;
+    let node = node::fs_node(file.as_ref()).and_then(|x| x.as_owner());
+    Ok(node.ok_or(VfsError::NotSupported)?.set_owner(uid, gid)?)
+}
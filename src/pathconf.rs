@@ -0,0 +1,174 @@
+// The limits of pathconf and fpathconf. FileSystem and INodeInterface of
+// vfscore have no pathconf, so the limits are of the filesystem of the
+// node by the f_type of its statfs, like pathconf of glibc, and NAME_MAX
+// is the namelen of the statfs when it's set. The nodes without a statfs,
+// like the pipes, and the filesystems which aren't known get the limits
+// of Linux. A limit which isn't fixed, or an option which isn't
+// supported, is NO_LIMIT, the -1 without an error of pathconf. The
+// unknown names fail with InvalidInput, the EINVAL of pathconf.
+
+use alloc::sync::Arc;
+use vfscore::{INodeInterface, StatFS, VfsError, VfsResult};
+
+use crate::ops::{NAME_MAX, PATH_MAX};
+use crate::pipe::PIPE_BUF;
+use crate::statfs::{EXT4_SUPER_MAGIC, MSDOS_SUPER_MAGIC};
+
+/// The limit which isn't fixed, or the option which isn't supported.
+pub const NO_LIMIT: isize = -1;
+
+/// EXT4_LINK_MAX.
+pub const EXT4_LINK_MAX: isize = 65000;
+/// LINUX_LINK_MAX of glibc, the links of the filesystems which don't
+/// tell theirs.
+pub const LINUX_LINK_MAX: isize = 127;
+/// MAX_CANON and MAX_INPUT of the terminals.
+const MAX_CANON: isize = 255;
+/// The transfer size of the nodes without a statfs.
+const DEFAULT_BLOCK_SIZE: isize = 4096;
+
+/// The names of pathconf, _PC_* of Linux.
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathconfName {
+    LinkMax = 0,
+    MaxCanon = 1,
+    MaxInput = 2,
+    NameMax = 3,
+    PathMax = 4,
+    PipeBuf = 5,
+    ChownRestricted = 6,
+    NoTrunc = 7,
+    Vdisable = 8,
+    SyncIo = 9,
+    AsyncIo = 10,
+    PrioIo = 11,
+    SockMaxbuf = 12,
+    FilesizeBits = 13,
+    RecIncrXferSize = 14,
+    RecMaxXferSize = 15,
+    RecMinXferSize = 16,
+    RecXferAlign = 17,
+    AllocSizeMin = 18,
+    SymlinkMax = 19,
+    TwoSymlinks = 20,
+}
+
+impl PathconfName {
+    pub const ALL: [Self; 21] = [
+        Self::LinkMax,
+        Self::MaxCanon,
+        Self::MaxInput,
+        Self::NameMax,
+        Self::PathMax,
+        Self::PipeBuf,
+        Self::ChownRestricted,
+        Self::NoTrunc,
+        Self::Vdisable,
+        Self::SyncIo,
+        Self::AsyncIo,
+        Self::PrioIo,
+        Self::SockMaxbuf,
+        Self::FilesizeBits,
+        Self::RecIncrXferSize,
+        Self::RecMaxXferSize,
+        Self::RecMinXferSize,
+        Self::RecXferAlign,
+        Self::AllocSizeMin,
+        Self::SymlinkMax,
+        Self::TwoSymlinks,
+    ];
+
+    /// The name of the _PC_* value of the syscall, an unknown one fails
+    /// with InvalidInput.
+    pub fn from_raw(name: i32) -> VfsResult<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|x| *x as i32 == name)
+            .ok_or(VfsError::InvalidInput)
+    }
+}
+
+/// The limits of a filesystem.
+struct Limits {
+    link_max: isize,
+    filesize_bits: isize,
+    symlinks: bool,
+}
+
+/// The limits of the filesystem of the f_type, those of Linux for the
+/// unknown ones.
+fn limits(magic: u32) -> Limits {
+    match magic {
+        EXT4_SUPER_MAGIC => Limits {
+            link_max: EXT4_LINK_MAX,
+            filesize_bits: 64,
+            symlinks: true,
+        },
+        // FAT has no hard links, a file is its only entry.
+        MSDOS_SUPER_MAGIC => Limits {
+            link_max: 1,
+            filesize_bits: 32,
+            symlinks: false,
+        },
+        // tmpfs, procfs and the others.
+        _ => Limits {
+            link_max: LINUX_LINK_MAX,
+            filesize_bits: 64,
+            symlinks: true,
+        },
+    }
+}
+
+/// The statfs of the node, the nodes without one have an empty statfs.
+fn statfs_of(node: &Arc<dyn INodeInterface>) -> VfsResult<StatFS> {
+    let mut statfs = StatFS::default();
+    match node.statfs(&mut statfs) {
+        Ok(()) => Ok(statfs),
+        Err(VfsError::NotSupported) => Ok(StatFS::default()),
+        Err(err) => Err(err),
+    }
+}
+
+/// The value of the name for the node, see the module. The values of a
+/// directory are those of the entries made in it.
+pub fn fpathconf(node: &Arc<dyn INodeInterface>, name: PathconfName) -> VfsResult<isize> {
+    let statfs = statfs_of(node)?;
+    let limits = limits(statfs.ftype as u32);
+    let block_size = match statfs.bsize as isize {
+        0 => DEFAULT_BLOCK_SIZE,
+        x => x,
+    };
+    Ok(match name {
+        PathconfName::LinkMax => limits.link_max,
+        PathconfName::MaxCanon | PathconfName::MaxInput => MAX_CANON,
+        PathconfName::NameMax => match statfs.namelen as isize {
+            0 => NAME_MAX as isize,
+            x => x,
+        },
+        PathconfName::PathMax => PATH_MAX as isize,
+        PathconfName::PipeBuf => PIPE_BUF as isize,
+        // chown needs the privilege, and the names longer than NAME_MAX
+        // fail instead of being cut.
+        PathconfName::ChownRestricted | PathconfName::NoTrunc => 1,
+        // _POSIX_VDISABLE, the NUL.
+        PathconfName::Vdisable => 0,
+        PathconfName::SyncIo | PathconfName::AsyncIo | PathconfName::PrioIo => NO_LIMIT,
+        PathconfName::SockMaxbuf | PathconfName::RecMaxXferSize => NO_LIMIT,
+        PathconfName::FilesizeBits => limits.filesize_bits,
+        PathconfName::RecIncrXferSize
+        | PathconfName::RecMinXferSize
+        | PathconfName::RecXferAlign
+        | PathconfName::AllocSizeMin => block_size,
+        PathconfName::SymlinkMax => match limits.symlinks {
+            true => PATH_MAX as isize - 1,
+            false => NO_LIMIT,
+        },
+        PathconfName::TwoSymlinks => limits.symlinks as isize,
+    })
+}
+
+/// fpathconf of the syscall, name is a _PC_* value.
+pub fn fpathconf_raw(node: &Arc<dyn INodeInterface>, name: i32) -> VfsResult<isize> {
+    fpathconf(node, PathconfName::from_raw(name)?)
+}
@@ -1,88 +1,356 @@
-use core::cmp;
+// The pipes of pipe2 and the named FIFOs. A pipe is a ring buffer of a
+// fixed capacity shared by its ends, with the POSIX semantics:
+// - a write of at most PIPE_BUF bytes is atomic, it's written whole or
+//   waits for the room; a larger one is written in parts.
+// - a read of an empty pipe waits for a writer, it's at the end of the
+//   file once all the writers are closed.
+// - a write fails once all the readers are closed.
+// The fs crate can't sleep, so a blocking end waits by calling the hook
+// of set_wait_hook, the scheduler yielding the task. A nonblocking end,
+// or any end without the hook, fails with Blocking instead of waiting.
+// The FIFOs open a pipe shared by the opens of their inode, keyed by its
+// identity like the walks, so any filesystem reporting S_IFIFO has them.
+// Like Linux, an open for reading waits for a writer and an open for
+// writing waits for a reader, the waiting open counts as its side so the
+// other side's open doesn't wait. A nonblocking open for reading doesn't
+// wait, one for writing fails without a reader, and O_RDWR never waits.
+// The pipe and its data stay while an end is open, the next open after
+// the last close has an empty pipe.
+
+use core::{
+    cmp,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use alloc::{
-    collections::VecDeque,
+    collections::{BTreeMap, VecDeque},
     sync::{Arc, Weak},
 };
-use sync::Mutex;
-use vfscore::{INodeInterface, PollEvent, VfsResult};
+use vfscore::{INodeInterface, OpenFlags, PollEvent, Stat, StatMode, VfsError, VfsResult};
 
-// pipe sender, just can write.
-pub struct PipeSender(Arc<Mutex<VecDeque<u8>>>);
+use crate::error::{Errno, FsError, FsResult};
+use crate::handle::AccessMode;
+use crate::hook::HookCell;
+use crate::sys::Mutex;
+use crate::walk::identity;
 
-impl INodeInterface for PipeSender {
-    fn writeat(&self, _offset: usize, buffer: &[u8]) -> VfsResult<usize> {
-        let mut queue = self.0.lock();
-        if queue.len() > 0x50000 {
-            Err(vfscore::VfsError::Blocking)
-        } else {
-            let wlen = buffer.len();
-            queue.extend(buffer.iter());
-            Ok(wlen)
+/// The writes up to PIPE_BUF bytes are atomic.
+pub const PIPE_BUF: usize = 4096;
+/// The capacity of the FIFOs, the default of Linux.
+pub const PIPE_CAPACITY: usize = 0x10000;
+/// The capacity of create_pipe, the bytes the first pipes buffered.
+const LEGACY_CAPACITY: usize = 0x50000;
+
+struct PipeState {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    readers: usize,
+    writers: usize,
+    /// The opens of each side so far. A FIFO may be opened by one side
+    /// first, its reads and writes wait for the other side instead of
+    /// seeing it closed, and its open waits for a new open of the other side.
+    read_opens: usize,
+    write_opens: usize,
+}
+
+impl PipeState {
+    fn had_reader(&self) -> bool {
+        self.read_opens > 0
+    }
+
+    fn had_writer(&self) -> bool {
+        self.write_opens > 0
+    }
+}
+
+struct Pipe(Mutex<PipeState>);
+
+impl Pipe {
+    fn new(capacity: usize) -> Arc<Self> {
+        Arc::new(Self(Mutex::new(PipeState {
+            buf: VecDeque::with_capacity(capacity),
+            capacity,
+            readers: 0,
+            writers: 0,
+            read_opens: 0,
+            write_opens: 0,
+        })))
+    }
+
+    /// Open an end, return it and the opens of the other side before it.
+    fn open(self: &Arc<Self>, read: bool, write: bool, nonblocking: bool) -> (Arc<PipeEnd>, usize) {
+        let mut state = self.0.lock();
+        let peer_opens = match read {
+            true => state.write_opens,
+            false => state.read_opens,
+        };
+        if read {
+            state.readers += 1;
+            state.read_opens += 1;
         }
+        if write {
+            state.writers += 1;
+            state.write_opens += 1;
+        }
+        let end = Arc::new(PipeEnd {
+            pipe: self.clone(),
+            read,
+            write,
+            nonblocking: AtomicBool::new(nonblocking),
+        });
+        (end, peer_opens)
     }
+}
 
-    fn poll(&self, events: PollEvent) -> VfsResult<PollEvent> {
-        let mut res = PollEvent::NONE;
-        if events.contains(PollEvent::POLLOUT) {
-            if self.0.lock().len() <= 0x50000 {
-                res |= PollEvent::POLLOUT;
+/// The hook waiting for a pipe.
+static WAIT: HookCell<fn()> = HookCell::new();
+
+/// Set the hook the blocking ends call while they wait, it yields to the
+/// other tasks and returns to retry.
+pub fn set_wait_hook(hook: fn()) {
+    WAIT.set(hook);
+}
+
+pub(crate) fn wait_hook() -> Option<fn()> {
+    WAIT.get()
+}
+
+/// An open end of a pipe, a FIFO opened O_RDWR has both sides.
+pub struct PipeEnd {
+    pipe: Arc<Pipe>,
+    read: bool,
+    write: bool,
+    nonblocking: AtomicBool,
+}
+
+impl PipeEnd {
+    /// O_NONBLOCK of the end, set by fcntl F_SETFL.
+    pub fn set_nonblocking(&self, nonblocking: bool) {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+    }
+
+    pub fn nonblocking(&self) -> bool {
+        self.nonblocking.load(Ordering::Relaxed)
+    }
+
+    /// Write the buffer like writeat. With no reader left the write fails
+    /// with Io and EPIPE, the kernel raises SIGPIPE for it.
+    pub fn write(&self, buffer: &[u8]) -> FsResult<usize> {
+        if !self.write {
+            return Err(VfsError::InvalidInput.into());
+        }
+        let mut written = 0;
+        while written < buffer.len() {
+            {
+                let mut state = self.pipe.0.lock();
+                if state.readers == 0 && state.had_reader() {
+                    return match written {
+                        0 => Err(FsError::new(VfsError::Io, Errno::EPIPE)),
+                        n => Ok(n),
+                    };
+                }
+                let room = state.capacity - state.buf.len();
+                let rest = &buffer[written..];
+                // an atomic write waits for the room of all its bytes, a
+                // pipe smaller than PIPE_BUF can't hold it whole.
+                let len = match buffer.len() <= cmp::min(PIPE_BUF, state.capacity) {
+                    true if room < rest.len() => 0,
+                    _ => cmp::min(room, rest.len()),
+                };
+                state.buf.extend(&rest[..len]);
+                written += len;
+                if written == buffer.len() {
+                    break;
+                }
+            }
+            match self.wait() {
+                Ok(()) => {}
+                Err(_) if written > 0 => break,
+                Err(err) => return Err(err.into()),
             }
         }
-        Ok(res)
+        Ok(written)
+    }
+
+    /// Wait for the other side, or fail with Blocking if the end can't.
+    fn wait(&self) -> VfsResult<()> {
+        match wait_hook() {
+            Some(hook) if !self.nonblocking() => {
+                hook();
+                Ok(())
+            }
+            _ => Err(VfsError::Blocking),
+        }
+    }
+
+    /// Wait in the open of a FIFO end of one side for the other side, an
+    /// end of it still open or opened since peer_opens.
+    fn wait_peer(&self, peer_opens: usize) -> VfsResult<()> {
+        loop {
+            {
+                let state = self.pipe.0.lock();
+                let (open, opens) = match self.read {
+                    true => (state.writers, state.write_opens),
+                    false => (state.readers, state.read_opens),
+                };
+                if open > 0 || opens != peer_opens {
+                    return Ok(());
+                }
+            }
+            self.wait()?;
+        }
     }
 }
 
-// pipe reader, just can read.
-pub struct PipeReceiver {
-    queue: Arc<Mutex<VecDeque<u8>>>,
-    sender: Weak<PipeSender>,
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        let mut state = self.pipe.0.lock();
+        if self.read {
+            state.readers -= 1;
+        }
+        if self.write {
+            state.writers -= 1;
+        }
+    }
 }
 
-impl INodeInterface for PipeReceiver {
+impl INodeInterface for PipeEnd {
     fn readat(&self, _offset: usize, buffer: &mut [u8]) -> VfsResult<usize> {
-        let mut queue = self.queue.lock();
-        let rlen = cmp::min(queue.len(), buffer.len());
-        queue
-            .drain(..rlen)
-            .enumerate()
-            .into_iter()
-            .for_each(|(i, x)| {
-                buffer[i] = x;
-            });
-        if rlen == 0 && Weak::strong_count(&self.sender) > 0 {
-            Err(vfscore::VfsError::Blocking)
-        } else {
-            Ok(rlen)
+        if !self.read {
+            return Err(VfsError::InvalidInput);
         }
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            {
+                let mut state = self.pipe.0.lock();
+                if !state.buf.is_empty() {
+                    let len = cmp::min(state.buf.len(), buffer.len());
+                    for (x, byte) in buffer.iter_mut().zip(state.buf.drain(..len)) {
+                        *x = byte;
+                    }
+                    return Ok(len);
+                }
+                if state.writers == 0 && state.had_writer() {
+                    return Ok(0);
+                }
+            }
+            self.wait()?;
+        }
+    }
+
+    fn writeat(&self, _offset: usize, buffer: &[u8]) -> VfsResult<usize> {
+        Ok(self.write(buffer)?)
     }
 
+    /// POLLIN with the data or at the end of the file, POLLOUT with the
+    /// room of PIPE_BUF bytes, and POLLERR once the other side is closed.
     fn poll(&self, events: PollEvent) -> VfsResult<PollEvent> {
+        let state = self.pipe.0.lock();
         let mut res = PollEvent::NONE;
-        if events.contains(PollEvent::POLLIN) {
-            if self.queue.lock().len() > 0 {
+        let no_writer = state.writers == 0 && state.had_writer();
+        let no_reader = state.readers == 0 && state.had_reader();
+        if self.read && events.contains(PollEvent::POLLIN) {
+            if !state.buf.is_empty() {
                 res |= PollEvent::POLLIN;
-            } else if Weak::strong_count(&self.sender) == 0 {
+            } else if no_writer {
                 res |= PollEvent::POLLERR;
             }
         }
-        if events.contains(PollEvent::POLLERR) {
-            if self.queue.lock().len() == 0 && Weak::strong_count(&self.sender) == 0 {
+        if self.read && events.contains(PollEvent::POLLERR) && state.buf.is_empty() && no_writer {
+            res |= PollEvent::POLLERR;
+        }
+        if self.write && events.contains(PollEvent::POLLOUT) {
+            if no_reader {
                 res |= PollEvent::POLLERR;
+            } else if state.capacity - state.buf.len() >= cmp::min(PIPE_BUF, state.capacity) {
+                res |= PollEvent::POLLOUT;
             }
         }
         Ok(res)
     }
+
+    fn stat(&self, stat: &mut Stat) -> VfsResult<()> {
+        stat.mode = StatMode::FIFO;
+        stat.nlink = 1;
+        stat.size = self.pipe.0.lock().buf.len() as _;
+        stat.blksize = PIPE_BUF as _;
+        Ok(())
+    }
 }
 
-pub fn create_pipe() -> (Arc<PipeReceiver>, Arc<PipeSender>) {
-    let queue = Arc::new(Mutex::new(VecDeque::new()));
-    let sender = Arc::new(PipeSender(queue.clone()));
+/// An anonymous pipe with the capacity, (the read end, the write end).
+/// O_NONBLOCK of flags makes both ends nonblocking, like pipe2.
+pub fn pipe_ends(capacity: usize, flags: OpenFlags) -> (Arc<PipeEnd>, Arc<PipeEnd>) {
+    let pipe = Pipe::new(capacity.max(1));
+    let nonblocking = flags.contains(OpenFlags::O_NONBLOCK);
     (
-        Arc::new(PipeReceiver {
-            queue: queue.clone(),
-            sender: Arc::downgrade(&sender),
-        }),
-        sender,
+        pipe.open(true, false, nonblocking).0,
+        pipe.open(false, true, nonblocking).0,
     )
 }
+
+/// An anonymous pipe with the capacity, (the read end, the write end).
+pub fn make_pipe(capacity: usize) -> (Arc<dyn INodeInterface>, Arc<dyn INodeInterface>) {
+    let (read, write) = pipe_ends(capacity, OpenFlags::NONE);
+    (read, write)
+}
+
+pub type PipeReceiver = PipeEnd;
+pub type PipeSender = PipeEnd;
+
+/// The pipe of the first pipes, blocking without a wait hook.
+pub fn create_pipe() -> (Arc<PipeReceiver>, Arc<PipeSender>) {
+    pipe_ends(LEGACY_CAPACITY, OpenFlags::NONE)
+}
+
+/// The pipes of the open FIFOs by the identity of their inode.
+static FIFOS: Mutex<BTreeMap<(u64, usize), Weak<Pipe>>> = Mutex::new(BTreeMap::new());
+
+/// Open the FIFO node with the access mode of flags, the opens of the
+/// same inode share the pipe until all of them are closed. An open of one
+/// side waits for the other side, see the top of the file, without the
+/// wait hook it fails with Blocking. The FIFOs without an inode number
+/// can't be shared, they fail with NotSupported. A nonblocking open for
+/// writing without a reader fails with Blocking and ENXIO.
+pub fn open_fifo(node: &dyn INodeInterface, flags: OpenFlags) -> FsResult<Arc<PipeEnd>> {
+    let mut stat = Stat::default();
+    node.stat(&mut stat)?;
+    if !stat.mode.contains(StatMode::FIFO) {
+        return Err(VfsError::InvalidInput.into());
+    }
+    let id = identity(node).ok_or(VfsError::NotSupported)?;
+    let mode = AccessMode::from_flags(flags);
+    let nonblocking = flags.contains(OpenFlags::O_NONBLOCK);
+    let mut fifos = FIFOS.lock();
+    fifos.retain(|_, x| x.strong_count() > 0);
+    let pipe = match fifos.get(&id).and_then(Weak::upgrade) {
+        Some(pipe) => pipe,
+        None => {
+            let pipe = Pipe::new(PIPE_CAPACITY);
+            fifos.insert(id, Arc::downgrade(&pipe));
+            pipe
+        }
+    };
+    if mode == AccessMode::WriteOnly && nonblocking && pipe.0.lock().readers == 0 {
+        return Err(FsError::new(VfsError::Blocking, Errno::ENXIO));
+    }
+    let (end, peer_opens) = pipe.open(mode.can_read(), mode.can_write(), nonblocking);
+    // the open waits without the lock, so the other side can open.
+    drop(fifos);
+    let waits = match mode {
+        AccessMode::ReadOnly | AccessMode::WriteOnly => !nonblocking,
+        _ => false,
+    };
+    if waits && let Err(err) = end.wait_peer(peer_opens) {
+        // the open failed, the other side doesn't see it.
+        let mut state = end.pipe.0.lock();
+        match end.read {
+            true => state.read_opens -= 1,
+            false => state.write_opens -= 1,
+        }
+        return Err(err.into());
+    }
+    Ok(end)
+}
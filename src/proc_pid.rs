@@ -0,0 +1,372 @@
+// The directories of the processes in /proc, /proc/<pid> with its fd
+// directory and the cwd, root and exe links, and /proc/self linking to
+// the directory of the current process. The fs crate doesn't know the
+// processes: the kernel registers a provider describing the files of a
+// pid with set_task_provider and the hook of the current pid with
+// set_current_pid_hook, the nodes here render them on every lookup.
+// ProcFS is of the procfs crate, so its root is wrapped by ProcRoot,
+// mounted as /proc by TaskProcFs, and the dentry of /proc is volatile so
+// the pids which come and go aren't cached.
+
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use vfscore::{
+    DirEntry, FileSystem, FileType, INodeInterface, Metadata, OpenFlags, PollEvent, Stat, StatFS,
+    StatMode, VfsError, VfsResult,
+};
+
+use crate::dentry::{dentry_open, dentry_root};
+use crate::hook::HookCell;
+use crate::ops::add_dot_entries;
+use crate::statfs::{next_fsid, PROC_SUPER_MAGIC};
+use crate::sys::Mutex;
+
+/// The files of a process, the paths are absolute paths of the dentry
+/// tree.
+#[derive(Debug, Clone, Default)]
+pub struct TaskFsInfo {
+    pub cwd: String,
+    pub root: String,
+    /// The executable, None for a kernel thread.
+    pub exe: Option<String>,
+    /// The open files, (fd, path).
+    pub fds: Vec<(usize, String)>,
+}
+
+type TaskProvider = Arc<dyn Fn(usize) -> Option<TaskFsInfo> + Send + Sync>;
+
+static PROVIDER: Mutex<Option<TaskProvider>> = Mutex::new(None);
+
+/// Set the provider of the files of the processes, None for a pid which
+/// doesn't exist. It's called without the locks of the fs crate, so it
+/// may resolve paths.
+pub fn set_task_provider(provider: Box<dyn Fn(usize) -> Option<TaskFsInfo> + Send + Sync>) {
+    *PROVIDER.lock() = Some(provider.into());
+}
+
+fn task(pid: usize) -> Option<TaskFsInfo> {
+    let provider = PROVIDER.lock().clone()?;
+    provider(pid)
+}
+
+/// The hook returning the current pid.
+static CURRENT_PID: HookCell<fn() -> usize> = HookCell::new();
+
+/// Set the hook returning the pid of the current process, /proc/self
+/// links to its directory.
+pub fn set_current_pid_hook(hook: fn() -> usize) {
+    CURRENT_PID.set(hook);
+}
+
+fn current_pid() -> Option<usize> {
+    CURRENT_PID.get().map(|hook| hook())
+}
+
+/// The filesystem of /proc, the filesystem inner with the directories of
+/// the processes in its root.
+pub struct TaskProcFs {
+    inner: Arc<dyn FileSystem>,
+    fsid: u64,
+}
+
+impl TaskProcFs {
+    pub fn new(inner: Arc<dyn FileSystem>) -> Arc<Self> {
+        Arc::new(Self {
+            inner,
+            fsid: next_fsid(),
+        })
+    }
+}
+
+impl FileSystem for TaskProcFs {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn root_dir(&'static self) -> Arc<dyn INodeInterface> {
+        Arc::new(ProcRoot::new(self.inner.root_dir(), self.fsid))
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        self.inner.flush()
+    }
+}
+
+/// The root of /proc: the entries of inner, "self" and the pids.
+/// TODO: the provider can't list the pids, read_dir lists "self" and the
+/// current pid only.
+pub struct ProcRoot {
+    inner: Arc<dyn INodeInterface>,
+    /// The fsid of the mount of /proc.
+    fsid: u64,
+}
+
+impl ProcRoot {
+    pub fn new(inner: Arc<dyn INodeInterface>, fsid: u64) -> Self {
+        Self { inner, fsid }
+    }
+
+    /// The node of "self" or a pid, None for the names of inner.
+    fn task_entry(&self, name: &str) -> Option<VfsResult<Arc<dyn INodeInterface>>> {
+        if name == "self" {
+            return current_pid().map(|pid| Ok(ProcLink::new("self", pid.to_string())));
+        }
+        // the pids are written in decimal without a leading zero.
+        let pid: usize = name.parse().ok().filter(|_| !name.starts_with('0'))?;
+        Some(match task(pid) {
+            Some(_) => Ok(TaskDir::new(name, TaskDirKind::Task(pid))),
+            None => Err(VfsError::FileNotFound),
+        })
+    }
+}
+
+impl INodeInterface for ProcRoot {
+    fn readat(&self, offset: usize, buffer: &mut [u8]) -> VfsResult<usize> {
+        self.inner.readat(offset, buffer)
+    }
+
+    fn writeat(&self, offset: usize, buffer: &[u8]) -> VfsResult<usize> {
+        self.inner.writeat(offset, buffer)
+    }
+
+    fn mkdir(&self, name: &str) -> VfsResult<Arc<dyn INodeInterface>> {
+        self.inner.mkdir(name)
+    }
+
+    fn touch(&self, name: &str) -> VfsResult<Arc<dyn INodeInterface>> {
+        self.inner.touch(name)
+    }
+
+    fn open(&self, name: &str, flags: OpenFlags) -> VfsResult<Arc<dyn INodeInterface>> {
+        match self.task_entry(name) {
+            Some(node) => node,
+            None => self.inner.open(name, flags),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<Arc<dyn INodeInterface>> {
+        match self.task_entry(name) {
+            Some(node) => node,
+            None => self.inner.lookup(name),
+        }
+    }
+
+    fn rmdir(&self, name: &str) -> VfsResult<()> {
+        self.inner.rmdir(name)
+    }
+
+    fn remove(&self, name: &str) -> VfsResult<()> {
+        self.inner.remove(name)
+    }
+
+    fn unlink(&self, name: &str) -> VfsResult<()> {
+        self.inner.unlink(name)
+    }
+
+    fn read_dir(&self) -> VfsResult<Vec<DirEntry>> {
+        let mut entries = self.inner.read_dir()?;
+        if let Some(pid) = current_pid() {
+            entries.push(DirEntry {
+                filename: String::from("self"),
+                len: 0,
+                file_type: FileType::Link,
+            });
+            if task(pid).is_some() {
+                entries.push(DirEntry {
+                    filename: pid.to_string(),
+                    len: 0,
+                    file_type: FileType::Directory,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        self.inner.metadata()
+    }
+
+    fn stat(&self, stat: &mut Stat) -> VfsResult<()> {
+        self.inner.stat(stat)
+    }
+
+    /// The statfs of inner as PROC_SUPER_MAGIC, procfs has no blocks and
+    /// may have no statfs.
+    fn statfs(&self, statfs: &mut StatFS) -> VfsResult<()> {
+        match self.inner.statfs(statfs) {
+            Ok(()) | Err(VfsError::NotSupported) => {}
+            Err(err) => return Err(err),
+        }
+        statfs.ftype = PROC_SUPER_MAGIC as _;
+        statfs.fsid = self.fsid;
+        Ok(())
+    }
+
+    fn poll(&self, events: PollEvent) -> VfsResult<PollEvent> {
+        self.inner.poll(events)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TaskDirKind {
+    /// /proc/<pid>.
+    Task(usize),
+    /// /proc/<pid>/fd.
+    Fds(usize),
+}
+
+/// A directory of a process, its entries are the ones of the provider at
+/// each lookup. It fails with FileNotFound once the process is gone.
+struct TaskDir {
+    filename: String,
+    kind: TaskDirKind,
+}
+
+enum TaskEntry {
+    Dir(TaskDirKind),
+    Link(String),
+}
+
+impl TaskDir {
+    fn new(filename: &str, kind: TaskDirKind) -> Arc<dyn INodeInterface> {
+        Arc::new(Self {
+            filename: String::from(filename),
+            kind,
+        })
+    }
+
+    fn entries(&self) -> VfsResult<Vec<(String, TaskEntry)>> {
+        let pid = match self.kind {
+            TaskDirKind::Task(pid) | TaskDirKind::Fds(pid) => pid,
+        };
+        let task = task(pid).ok_or(VfsError::FileNotFound)?;
+        Ok(match self.kind {
+            TaskDirKind::Task(pid) => {
+                let mut entries = vec![
+                    (String::from("fd"), TaskEntry::Dir(TaskDirKind::Fds(pid))),
+                    (String::from("cwd"), TaskEntry::Link(task.cwd)),
+                    (String::from("root"), TaskEntry::Link(task.root)),
+                ];
+                if let Some(exe) = task.exe {
+                    entries.push((String::from("exe"), TaskEntry::Link(exe)));
+                }
+                entries
+            }
+            TaskDirKind::Fds(_) => {
+                let mut fds = task.fds;
+                fds.sort_by_key(|(fd, _)| *fd);
+                fds.into_iter()
+                    .map(|(fd, path)| (fd.to_string(), TaskEntry::Link(path)))
+                    .collect()
+            }
+        })
+    }
+}
+
+impl INodeInterface for TaskDir {
+    fn open(&self, name: &str, _flags: OpenFlags) -> VfsResult<Arc<dyn INodeInterface>> {
+        self.lookup(name)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<Arc<dyn INodeInterface>> {
+        let (name, entry) = self
+            .entries()?
+            .into_iter()
+            .find(|(x, _)| x == name)
+            .ok_or(VfsError::FileNotFound)?;
+        Ok(match entry {
+            TaskEntry::Dir(kind) => TaskDir::new(&name, kind),
+            TaskEntry::Link(target) => ProcLink::new(&name, target),
+        })
+    }
+
+    fn read_dir(&self) -> VfsResult<Vec<DirEntry>> {
+        let mut entries: Vec<_> = self
+            .entries()?
+            .into_iter()
+            .map(|(filename, entry)| DirEntry {
+                filename,
+                len: 0,
+                file_type: match entry {
+                    TaskEntry::Dir(_) => FileType::Directory,
+                    TaskEntry::Link(_) => FileType::Link,
+                },
+            })
+            .collect();
+        add_dot_entries(&mut entries);
+        Ok(entries)
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(Metadata {
+            filename: &self.filename,
+            inode: usize::MAX,
+            file_type: FileType::Directory,
+            size: 0,
+            childrens: self.entries()?.len(),
+        })
+    }
+
+    fn stat(&self, stat: &mut Stat) -> VfsResult<()> {
+        self.entries()?;
+        stat.mode = StatMode::DIR | StatMode::from_bits_truncate(0o555);
+        stat.nlink = 1;
+        stat.size = 0;
+        stat.blksize = 4096;
+        stat.blocks = 0;
+        Ok(())
+    }
+}
+
+/// A symbol link of /proc, to the path of the provider when it was
+/// looked up.
+struct ProcLink {
+    filename: String,
+    target: String,
+}
+
+impl ProcLink {
+    fn new(filename: &str, target: String) -> Arc<dyn INodeInterface> {
+        Arc::new(Self {
+            filename: String::from(filename),
+            target,
+        })
+    }
+}
+
+impl INodeInterface for ProcLink {
+    fn resolve_link(&self) -> VfsResult<String> {
+        Ok(self.target.clone())
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(Metadata {
+            filename: &self.filename,
+            inode: usize::MAX,
+            file_type: FileType::Link,
+            size: self.target.len(),
+            childrens: 0,
+        })
+    }
+
+    fn stat(&self, stat: &mut Stat) -> VfsResult<()> {
+        stat.mode = StatMode::LINK | StatMode::from_bits_truncate(0o777);
+        stat.nlink = 1;
+        stat.size = self.target.len() as _;
+        stat.blksize = 4096;
+        stat.blocks = 0;
+        Ok(())
+    }
+}
+
+/// Mark the dentry of /proc volatile, after it's mounted. Do nothing if
+/// /proc isn't mounted.
+pub fn init() {
+    if let Ok(proc) = dentry_open(dentry_root(), "/proc", OpenFlags::NONE) {
+        proc.set_volatile();
+    }
+}
@@ -0,0 +1,184 @@
+// The pseudo files, whose content is made by a callback on every read
+// and parsed by another on the writes, like the files of /proc and the
+// tunables of /proc/sys. An open of a CallbackInode through FileHandle
+// takes a snapshot of the content at its first read, the next reads of
+// the open copy from it, so a file read in chunks isn't torn by a change
+// between them. A read at offset 0 takes a new snapshot, like the
+// seq_file of Linux after a rewind, and a write drops it. Every write is
+// parsed whole, whatever its offset, the callback rejects a bad value
+// with InvalidInput, EINVAL. The size of the stat is 0 like /proc, or
+// the length of the snapshot for the readers which trust it. A truncate
+// of a writable node does nothing, so an open with O_TRUNC works, and
+// the nodes are always ready for poll. FileHandle finds them by the
+// downcast of its node.
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use vfscore::{INodeInterface, PollEvent, Stat, StatMode, VfsError, VfsResult};
+
+use crate::sys::Mutex;
+
+type ReadFn = Box<dyn Fn() -> Vec<u8> + Send + Sync>;
+type WriteFn = Box<dyn Fn(&[u8]) -> VfsResult<()> + Send + Sync>;
+
+/// The size in the stat of a pseudo file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeMode {
+    /// 0, like the files of /proc.
+    Zero,
+    /// The length of the snapshot of the open, or of the content now.
+    Snapshot,
+}
+
+/// A file made by callbacks, see the module.
+pub struct CallbackInode {
+    read: ReadFn,
+    write: Option<WriteFn>,
+    size: SizeMode,
+}
+
+impl CallbackInode {
+    /// A pseudo file of size 0, without write it's read-only.
+    pub fn new(
+        read: impl Fn() -> Vec<u8> + Send + Sync + 'static,
+        write: Option<impl Fn(&[u8]) -> VfsResult<()> + Send + Sync + 'static>,
+    ) -> Arc<Self> {
+        Self::with_size(read, write, SizeMode::Zero)
+    }
+
+    /// A read-only pseudo file.
+    pub fn read_only(
+        read: impl Fn() -> Vec<u8> + Send + Sync + 'static,
+        size: SizeMode,
+    ) -> Arc<Self> {
+        Self::with_size(read, None::<fn(&[u8]) -> VfsResult<()>>, size)
+    }
+
+    pub fn with_size(
+        read: impl Fn() -> Vec<u8> + Send + Sync + 'static,
+        write: Option<impl Fn(&[u8]) -> VfsResult<()> + Send + Sync + 'static>,
+        size: SizeMode,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            read: Box::new(read),
+            write: write.map(|x| Box::new(x) as WriteFn),
+            size,
+        })
+    }
+
+    pub fn writable(&self) -> bool {
+        self.write.is_some()
+    }
+
+    fn fill_stat(&self, stat: &mut Stat, len: impl FnOnce() -> usize) {
+        let perm = match self.writable() {
+            true => 0o644,
+            false => 0o444,
+        };
+        stat.mode = StatMode::FILE | StatMode::from_bits_truncate(perm);
+        stat.nlink = 1;
+        stat.size = match self.size {
+            SizeMode::Zero => 0,
+            SizeMode::Snapshot => len() as _,
+        };
+        stat.blksize = 4096;
+        stat.blocks = 0;
+    }
+}
+
+/// Copy the content at offset into the buffer.
+fn copy_at(content: &[u8], offset: usize, buffer: &mut [u8]) -> usize {
+    if offset >= content.len() {
+        return 0;
+    }
+    let len = buffer.len().min(content.len() - offset);
+    buffer[..len].copy_from_slice(&content[offset..offset + len]);
+    len
+}
+
+fn ready(events: PollEvent) -> PollEvent {
+    events & (PollEvent::POLLIN | PollEvent::POLLOUT)
+}
+
+/// The node without an open renders its content on every read.
+impl INodeInterface for CallbackInode {
+    fn readat(&self, offset: usize, buffer: &mut [u8]) -> VfsResult<usize> {
+        Ok(copy_at(&(self.read)(), offset, buffer))
+    }
+
+    fn writeat(&self, _offset: usize, buffer: &[u8]) -> VfsResult<usize> {
+        let write = self.write.as_ref().ok_or(VfsError::NotSupported)?;
+        write(buffer)?;
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, _size: usize) -> VfsResult<()> {
+        match self.writable() {
+            true => Ok(()),
+            false => Err(VfsError::NotSupported),
+        }
+    }
+
+    fn stat(&self, stat: &mut Stat) -> VfsResult<()> {
+        self.fill_stat(stat, || (self.read)().len());
+        Ok(())
+    }
+
+    fn poll(&self, events: PollEvent) -> VfsResult<PollEvent> {
+        Ok(ready(events))
+    }
+}
+
+/// An open of a CallbackInode with its snapshot.
+struct CallbackFile {
+    inode: Arc<CallbackInode>,
+    snapshot: Mutex<Option<Vec<u8>>>,
+}
+
+impl INodeInterface for CallbackFile {
+    fn readat(&self, offset: usize, buffer: &mut [u8]) -> VfsResult<usize> {
+        let mut snapshot = self.snapshot.lock();
+        if offset == 0 || snapshot.is_none() {
+            *snapshot = Some((self.inode.read)());
+        }
+        Ok(copy_at(
+            snapshot.as_deref().unwrap_or_default(),
+            offset,
+            buffer,
+        ))
+    }
+
+    fn writeat(&self, offset: usize, buffer: &[u8]) -> VfsResult<usize> {
+        let written = self.inode.writeat(offset, buffer)?;
+        *self.snapshot.lock() = None;
+        Ok(written)
+    }
+
+    fn truncate(&self, size: usize) -> VfsResult<()> {
+        self.inode.truncate(size)
+    }
+
+    fn stat(&self, stat: &mut Stat) -> VfsResult<()> {
+        let snapshot = self.snapshot.lock();
+        self.inode.fill_stat(stat, || match snapshot.as_ref() {
+            Some(content) => content.len(),
+            None => (self.inode.read)().len(),
+        });
+        Ok(())
+    }
+
+    fn poll(&self, events: PollEvent) -> VfsResult<PollEvent> {
+        Ok(ready(events))
+    }
+}
+
+/// The node of an open, a CallbackInode gets its own snapshot, the other
+/// nodes are returned as they are.
+pub fn open(node: Arc<dyn INodeInterface>) -> Arc<dyn INodeInterface> {
+    match node.downcast_arc::<CallbackInode>() {
+        Ok(inode) => Arc::new(CallbackFile {
+            inode,
+            snapshot: Mutex::new(None),
+        }),
+        Err(node) => node,
+    }
+}
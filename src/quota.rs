@@ -0,0 +1,181 @@
+// The disk quotas of the users and the groups, like usrquota and grpquota
+// of Linux. A filesystem with quotas charges the allocated bytes and the
+// inode of every file to its owner and its group, and an allocation which
+// would pass a hard limit fails. FileSystem of vfscore has no quotas, so
+// the filesystems with them register a Quota with their FileSystem, like
+// the Volume of volume.rs, and keep the usage in a QuotaTable. The limits
+// are in memory, they are set after the mount.
+// TODO: keep the limits in the quota inodes of ext4, and the soft limits
+// with their grace time.
+// An allocation beyond a limit fails with StorageFull, under the writes of
+// INodeInterface, so the syscalls see ENOSPC where Linux has EDQUOT.
+
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use vfscore::{FileSystem, VfsError, VfsResult};
+
+use crate::sys::Mutex;
+
+/// The user or the group charged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum QuotaId {
+    User(u32),
+    Group(u32),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaUsage {
+    /// The allocated bytes, in whole blocks.
+    pub bytes: u64,
+    pub inodes: u64,
+}
+
+/// The hard limits, None is unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaLimits {
+    pub bytes: Option<u64>,
+    pub inodes: Option<u64>,
+}
+
+/// A change of the usage of an id, negative for a release.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Charge {
+    pub id: QuotaId,
+    pub bytes: i64,
+    pub inodes: i64,
+}
+
+impl Charge {
+    /// The charges of a file of the owner and the group.
+    pub fn owner(uid: u32, gid: u32, bytes: i64, inodes: i64) -> [Charge; 2] {
+        [
+            Charge {
+                id: QuotaId::User(uid),
+                bytes,
+                inodes,
+            },
+            Charge {
+                id: QuotaId::Group(gid),
+                bytes,
+                inodes,
+            },
+        ]
+    }
+}
+
+/// The usage and the limits of the ids of a filesystem.
+pub struct QuotaTable {
+    ids: Mutex<BTreeMap<QuotaId, (QuotaUsage, QuotaLimits)>>,
+}
+
+fn add(value: u64, delta: i64) -> u64 {
+    value.saturating_add_signed(delta)
+}
+
+impl QuotaTable {
+    pub const fn new() -> Self {
+        Self {
+            ids: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn usage(&self, id: QuotaId) -> QuotaUsage {
+        self.ids.lock().get(&id).map(|x| x.0).unwrap_or_default()
+    }
+
+    pub fn limits(&self, id: QuotaId) -> QuotaLimits {
+        self.ids.lock().get(&id).map(|x| x.1).unwrap_or_default()
+    }
+
+    /// Set the limits of the id, the usage beyond them stays but can't
+    /// grow.
+    pub fn set_limits(&self, id: QuotaId, limits: QuotaLimits) {
+        self.ids.lock().entry(id).or_default().1 = limits;
+    }
+
+    /// Check if an id has a limit, the allocations are checked then.
+    pub fn has_limits(&self) -> bool {
+        self.ids
+            .lock()
+            .values()
+            .any(|x| x.1 != QuotaLimits::default())
+    }
+
+    /// Apply the charges together. They fail with StorageFull if the usage
+    /// of an id grows beyond its limit, the table is unchanged then. The
+    /// releases and the charges within the limits always succeed.
+    pub fn apply(&self, charges: &[Charge]) -> VfsResult<()> {
+        let mut net: BTreeMap<QuotaId, (i64, i64)> = BTreeMap::new();
+        for charge in charges {
+            let entry = net.entry(charge.id).or_default();
+            entry.0 += charge.bytes;
+            entry.1 += charge.inodes;
+        }
+        let mut ids = self.ids.lock();
+        for (id, (bytes, inodes)) in net.iter() {
+            let (usage, limits) = ids.get(id).copied().unwrap_or_default();
+            let over = |value: u64, delta: i64, limit: Option<u64>| {
+                delta > 0 && limit.is_some_and(|x| add(value, delta) > x)
+            };
+            if over(usage.bytes, *bytes, limits.bytes) || over(usage.inodes, *inodes, limits.inodes)
+            {
+                return Err(VfsError::StorageFull);
+            }
+        }
+        for (id, (bytes, inodes)) in net {
+            let usage = &mut ids.entry(id).or_default().0;
+            usage.bytes = add(usage.bytes, bytes);
+            usage.inodes = add(usage.inodes, inodes);
+        }
+        Ok(())
+    }
+}
+
+impl Default for QuotaTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The quotas of a filesystem.
+pub trait Quota: Send + Sync {
+    fn quota_usage(&self, id: QuotaId) -> VfsResult<QuotaUsage>;
+
+    fn set_quota_limit(&self, id: QuotaId, limits: QuotaLimits) -> VfsResult<()>;
+}
+
+/// The registered filesystems, the dropped ones are removed lazily.
+static QUOTAS: Mutex<Vec<(Weak<dyn FileSystem>, Weak<dyn Quota>)>> = Mutex::new(Vec::new());
+
+/// Register the quotas of the filesystem.
+pub fn register(fs: Weak<dyn FileSystem>, quota: Weak<dyn Quota>) {
+    let mut quotas = QUOTAS.lock();
+    quotas.retain(|(fs, quota)| fs.strong_count() > 0 && quota.strong_count() > 0);
+    quotas.push((fs, quota));
+}
+
+fn quota_of(fs: &Arc<dyn FileSystem>) -> VfsResult<Arc<dyn Quota>> {
+    QUOTAS
+        .lock()
+        .iter()
+        .find(|(x, _)| core::ptr::addr_eq(x.as_ptr(), Arc::as_ptr(fs)))
+        .and_then(|(_, quota)| quota.upgrade())
+        .ok_or(VfsError::NotSupported)
+}
+
+/// The usage of the id on the filesystem, NotSupported without quotas.
+pub fn quota_usage(fs: &Arc<dyn FileSystem>, id: QuotaId) -> VfsResult<QuotaUsage> {
+    quota_of(fs)?.quota_usage(id)
+}
+
+/// Set the hard limits of the id on the filesystem.
+pub fn set_quota_limit(
+    fs: &Arc<dyn FileSystem>,
+    id: QuotaId,
+    limits: QuotaLimits,
+) -> VfsResult<()> {
+    quota_of(fs)?.set_quota_limit(id, limits)
+}
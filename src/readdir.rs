@@ -0,0 +1,331 @@
+// The positions of the directory listings, telldir and seekdir. A listing
+// is read in pages by read_dir_at, every entry comes with the position
+// after it, and a listing resumed from a position neither skips nor
+// repeats the entries which stay in the directory while others are
+// created and removed. The names created during the listing, or removed
+// and created again, may or may not be listed. INodeInterface of vfscore
+// lists the whole directory, so the nodes with stable positions
+// implement SeekDir and hand it out by their FsNode, like the nodes of
+// statx.rs:
+// - ext4 uses the byte offset of the next entry in a linear directory,
+//   and the hash of the next name in an indexed one, whose entries move
+//   between the blocks when a leaf splits.
+// - tmpfs numbers the entries of a directory as they're created.
+// The other nodes are listed by the index in read_dir, which is only
+// stable while the directory doesn't change.
+//
+// read_dir_plus lists the entries with the attributes ls -l shows, on
+// demand so the plain listings don't pay for them. The nodes of SeekDir
+// may read them together, ext4 reads each block of the inode table once
+// for the whole page, and the others are stat'ed one by one.
+//
+// lock_dir holds off the changes of the entries of a directory, for the
+// snapshots of ops::dir_iter_snapshot. It's advisory: the listings don't
+// take it, the operations of the node adding and removing entries enter
+// its gate before their own locks. tmpfs has a gate in each directory,
+// ext4 one for the volume.
+// TODO: ramfs is another crate, its listings are by the index.
+//
+// dir_version counts the changes of the entries of a directory, for the
+// collectors tailing it like tail -F: a version which didn't change means
+// no entry was created, removed or renamed. A node of SeekDir may count
+// them itself, tmpfs does, so the changes through the node count too. The
+// others are counted by the ops layer, by the address of the node of the
+// dentry, so only the changes through its functions and the FileHandle
+// count. read_dir_since returns the entries added since a version, or
+// None when the node can't tell without a full listing: tmpfs keeps the
+// positions of its recent versions, ext4 and the others only tell that
+// nothing changed. Every change of the ops layer is an event of the hook
+// of set_change_hook, with the version after it, so a consumer sees the
+// versions it missed.
+// TODO: ramfs is another crate, its changes are counted by the ops layer.
+
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use vfscore::{DirEntry, FileType, INodeInterface, Stat, StatMode, TimeSpec, VfsError, VfsResult};
+
+use crate::freeze::ClosedGate;
+use crate::hook::HookCell;
+use crate::node;
+use crate::ops::dirent_type;
+use crate::sys::Mutex;
+
+/// An entry of a listing and the position of the entry after it, the
+/// listing resumes at next.
+pub struct PosEntry {
+    pub entry: DirEntry,
+    pub next: u64,
+    /// The inode number of the entry, 0 if the filesystem doesn't tell it.
+    pub ino: u64,
+    /// The d_type of the entry, DT_UNKNOWN if the filesystem can't tell it.
+    pub d_type: u8,
+}
+
+/// The attributes of an entry in read_dir_plus, as stat gives them.
+#[derive(Debug, Clone, Copy)]
+pub struct EntryAttrs {
+    pub size: u64,
+    pub mode: StatMode,
+    pub nlink: u32,
+    pub mtime: TimeSpec,
+    /// The inode number, 0 if the node doesn't have one.
+    pub ino: u64,
+}
+
+impl EntryAttrs {
+    pub fn from_stat(stat: &Stat) -> Self {
+        Self {
+            ino: stat.ino as _,
+            size: stat.size as _,
+            mode: stat.mode,
+            nlink: stat.nlink as _,
+            mtime: stat.mtime,
+        }
+    }
+}
+
+/// An entry of read_dir_plus. attrs is None if they weren't asked for, or
+/// if the entry can't be stat'ed, removed since it was listed.
+pub struct PlusEntry {
+    pub entry: PosEntry,
+    pub attrs: Option<EntryAttrs>,
+}
+
+/// The stable positions of a directory.
+pub trait SeekDir: Send + Sync {
+    /// List up to max entries from the position pos, 0 is the start of
+    /// the directory and an empty list is its end. NotSupported lists the
+    /// directory by the index instead.
+    fn read_dir_at(&self, pos: u64, max: usize) -> VfsResult<Vec<PosEntry>>;
+
+    /// Like read_dir_at, with the attributes of the entries. NotSupported
+    /// stats the entries of read_dir_at one by one instead.
+    fn read_dir_plus(&self, _pos: u64, _max: usize) -> VfsResult<Vec<PlusEntry>> {
+        Err(VfsError::NotSupported)
+    }
+
+    /// Hold off the changes of the entries while the gate is closed, see
+    /// the module. None if the node can't.
+    fn lock_dir(&self) -> Option<ClosedGate> {
+        None
+    }
+
+    /// The version of the entries counted by the node, see the module.
+    /// None if the ops layer counts them.
+    fn dir_version(&self) -> Option<u64> {
+        None
+    }
+
+    /// The entries added since the version and still there, None if the
+    /// node can't tell without a full listing.
+    fn read_dir_since(&self, _version: u64) -> VfsResult<Option<Vec<DirEntry>>> {
+        Ok(None)
+    }
+}
+
+pub(crate) fn node_of(dir: &Arc<dyn INodeInterface>) -> Option<&dyn SeekDir> {
+    node::fs_node(dir.as_ref())?.as_seek_dir()
+}
+
+/// List up to max entries of the directory from the position pos, by its
+/// SeekDir or by the index in read_dir. max must not be 0.
+pub fn read_dir_at(
+    dir: &Arc<dyn INodeInterface>,
+    pos: u64,
+    max: usize,
+) -> VfsResult<Vec<PosEntry>> {
+    if max == 0 {
+        return Err(VfsError::InvalidInput);
+    }
+    if let Some(node) = node_of(dir) {
+        match node.read_dir_at(pos, max) {
+            Err(VfsError::NotSupported) => {}
+            r => return r,
+        }
+    }
+    let entries = dir.read_dir()?;
+    let listed = entries
+        .into_iter()
+        .enumerate()
+        .skip(pos.try_into().unwrap_or(usize::MAX))
+        .take(max)
+        .map(|(i, entry)| PosEntry {
+            d_type: dirent_type(entry.file_type),
+            entry,
+            next: i as u64 + 1,
+            ino: 0,
+        });
+    Ok(listed.collect())
+}
+
+/// The attributes of the entry of dir by its stat, "." is dir itself.
+pub(crate) fn stat_entry(dir: &Arc<dyn INodeInterface>, name: &str) -> Option<EntryAttrs> {
+    let node = match name {
+        "." => dir.clone(),
+        name => dir.lookup(name).ok()?,
+    };
+    let mut stat = Stat::default();
+    node.stat(&mut stat).ok()?;
+    Some(EntryAttrs::from_stat(&stat))
+}
+
+/// Like read_dir_at, with the attributes of the entries if attrs is set,
+/// read together by the SeekDir of the directory or by a stat of each.
+pub fn read_dir_plus(
+    dir: &Arc<dyn INodeInterface>,
+    pos: u64,
+    max: usize,
+    attrs: bool,
+) -> VfsResult<Vec<PlusEntry>> {
+    if attrs
+        && max > 0
+        && let Some(node) = node_of(dir)
+    {
+        match node.read_dir_plus(pos, max) {
+            Err(VfsError::NotSupported) => {}
+            r => return r,
+        }
+    }
+    let listed = read_dir_at(dir, pos, max)?
+        .into_iter()
+        .map(|entry| PlusEntry {
+            attrs: match attrs {
+                true => stat_entry(dir, &entry.entry.filename),
+                false => None,
+            },
+            entry,
+        });
+    Ok(listed.collect())
+}
+
+/// What a change did to an entry of a directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirChange {
+    Added,
+    Removed,
+    /// The node of the entry is another one, by an exchange of rename.
+    Replaced,
+}
+
+/// A change of the entries of a directory by the ops layer.
+pub struct ChangeEvent<'a> {
+    pub dir: &'a Arc<dyn INodeInterface>,
+    pub name: &'a str,
+    pub change: DirChange,
+    /// The version of dir after the change, see dir_version.
+    pub version: u64,
+}
+
+pub type ChangeHook = fn(&ChangeEvent);
+
+static CHANGE_HOOK: HookCell<ChangeHook> = HookCell::new();
+
+/// Set the hook of the changes of the ops layer, like the watches of
+/// inotify. It's called without the locks of the fs crate.
+pub fn set_change_hook(hook: ChangeHook) {
+    CHANGE_HOOK.set(hook);
+}
+
+pub fn clear_change_hook() {
+    CHANGE_HOOK.clear();
+}
+
+fn change_hook() -> Option<ChangeHook> {
+    CHANGE_HOOK.get()
+}
+
+/// The versions counted by the ops layer, by the address of the node.
+struct Versions {
+    dirs: BTreeMap<usize, (Weak<dyn INodeInterface>, u64)>,
+    /// The count of dirs pruning the dropped nodes.
+    prune_at: usize,
+}
+
+static VERSIONS: Mutex<Versions> = Mutex::new(Versions {
+    dirs: BTreeMap::new(),
+    prune_at: 64,
+});
+
+fn addr_of(dir: &Arc<dyn INodeInterface>) -> usize {
+    Arc::as_ptr(dir) as *const () as usize
+}
+
+/// The version counted by the ops layer, 0 for a node it never saw
+/// change. A dropped node at the same address doesn't count.
+fn counted_version(dir: &Arc<dyn INodeInterface>) -> u64 {
+    let versions = VERSIONS.lock();
+    match versions.dirs.get(&addr_of(dir)) {
+        Some((node, version)) if node.strong_count() > 0 => *version,
+        _ => 0,
+    }
+}
+
+/// Count a change of dir by the ops layer, return the new version.
+fn count_change(dir: &Arc<dyn INodeInterface>) -> u64 {
+    let mut versions = VERSIONS.lock();
+    if versions.dirs.len() >= versions.prune_at {
+        versions.dirs.retain(|_, x| x.0.strong_count() > 0);
+        versions.prune_at = (versions.dirs.len() * 2).max(64);
+    }
+    let counted = versions
+        .dirs
+        .entry(addr_of(dir))
+        .or_insert_with(|| (Arc::downgrade(dir), 0));
+    if counted.0.strong_count() == 0 {
+        *counted = (Arc::downgrade(dir), 0);
+    }
+    counted.1 += 1;
+    counted.1
+}
+
+/// The change of the entry name of dir by the ops layer, after it
+/// succeeded. It's counted if the node doesn't count its changes, and
+/// it's an event of the change hook.
+pub(crate) fn changed(dir: &Arc<dyn INodeInterface>, name: &str, change: DirChange) {
+    let version = match node_of(dir).and_then(|x| x.dir_version()) {
+        Some(version) => version,
+        None => count_change(dir),
+    };
+    if let Some(hook) = change_hook() {
+        hook(&ChangeEvent {
+            dir,
+            name,
+            change,
+            version,
+        });
+    }
+}
+
+/// The version of the entries of the directory, see the module.
+pub fn dir_version(dir: &Arc<dyn INodeInterface>) -> VfsResult<u64> {
+    if !matches!(dir.metadata()?.file_type, FileType::Directory) {
+        return Err(VfsError::NotDir);
+    }
+    match node_of(dir).and_then(|x| x.dir_version()) {
+        Some(version) => Ok(version),
+        None => Ok(counted_version(dir)),
+    }
+}
+
+/// The entries of the directory added since the version of dir_version
+/// and still there, without "." and "..". None asks for a full listing:
+/// the node can't tell, or the version is too old or isn't one of dir.
+/// The directory may change during the call, a later dir_version tells.
+pub fn read_dir_since(
+    dir: &Arc<dyn INodeInterface>,
+    version: u64,
+) -> VfsResult<Option<Vec<DirEntry>>> {
+    let current = dir_version(dir)?;
+    if let Some(node) = node_of(dir)
+        && node.dir_version().is_some()
+    {
+        return node.read_dir_since(version);
+    }
+    match version == current {
+        true => Ok(Some(Vec::new())),
+        false => Ok(None),
+    }
+}
@@ -0,0 +1,154 @@
+// The clones of the files, FICLONE and FICLONERANGE of cp --reflink. A
+// clone shares the data of the source instead of copying it, so it's
+// instant: the files share the pages of the range until one of them
+// writes one, the write copies that page then. INodeInterface of vfscore
+// has no clone, so the nodes which share their data implement CloneINode
+// and hand it out by their FsNode, like the nodes of fallocate.rs. The
+// other nodes fail with NotSupported, EOPNOTSUPP, like ext4 and FAT, so
+// cp --reflink=auto falls back to a copy. A clone between two filesystems
+// fails with EXDEV, the ioctls flatten it to the NotSupported of
+// INodeInterface, so cp falls back to a copy too.
+//
+// The ioctls take the fd of the source, the kernel resolves it by the
+// hook of set_fd_resolver.
+
+use alloc::{boxed::Box, sync::Arc};
+use vfscore::{INodeInterface, VfsError, VfsResult};
+
+use crate::cache::PAGE_SIZE;
+use crate::error::FsResult;
+use crate::node;
+use crate::ops::{check_range, check_same_dev};
+use crate::sys::Mutex;
+
+/// The ioctl of a clone of the whole file, the fd of the source is arg.
+pub const FICLONE: usize = 0x4004_9409;
+/// The ioctl of a clone of a range, the FileCloneRange at the pointer arg.
+pub const FICLONERANGE: usize = 0x4020_940d;
+
+/// struct file_clone_range of FICLONERANGE.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileCloneRange {
+    pub src_fd: i64,
+    pub src_offset: u64,
+    /// 0 to the end of the source.
+    pub src_length: u64,
+    pub dest_offset: u64,
+}
+
+/// The clones of a node sharing its data.
+pub trait CloneINode: Send + Sync {
+    /// The node whose data a clone of this one shares, for the checks of
+    /// a node passing it to another node, like FileHandle.
+    fn clone_source(&self) -> VfsResult<Arc<dyn INodeInterface>>;
+
+    /// Replace the data of the node by the data of src, with its size.
+    fn clone_from(&self, src: &Arc<dyn INodeInterface>) -> VfsResult<()>;
+
+    /// Share len bytes at src_offset of src at offset of the node, see
+    /// clone_range. The range is checked.
+    fn clone_range(
+        &self,
+        src: &Arc<dyn INodeInterface>,
+        src_offset: usize,
+        len: usize,
+        offset: usize,
+    ) -> VfsResult<()>;
+}
+
+fn node_of(file: &Arc<dyn INodeInterface>) -> VfsResult<&dyn CloneINode> {
+    let node = node::fs_node(file.as_ref()).and_then(|x| x.as_clone());
+    node.ok_or(VfsError::NotSupported)
+}
+
+/// The node whose data a clone of the file shares.
+pub fn source(file: &Arc<dyn INodeInterface>) -> VfsResult<Arc<dyn INodeInterface>> {
+    node_of(file)?.clone_source()
+}
+
+/// Make the file a clone of src, like FICLONE. NotSupported if one of
+/// them can't share its data, EXDEV if they're on two filesystems.
+pub fn clone_from(file: &Arc<dyn INodeInterface>, src: &Arc<dyn INodeInterface>) -> FsResult<()> {
+    let node = node_of(file)?;
+    check_same_dev(file.as_ref(), src.as_ref())?;
+    Ok(node.clone_from(&source(src)?)?)
+}
+
+/// Share len bytes at src_offset of src at offset of the file, like
+/// FICLONERANGE, len 0 shares up to the end of src. The file grows to the
+/// end of the range. The offsets are aligned to PAGE_SIZE and so is len,
+/// unless the range ends at the end of src and at or beyond the end of
+/// the file, InvalidInput otherwise. The range of a node in itself
+/// mustn't overlap. EXDEV if they're on two filesystems.
+pub fn clone_range(
+    file: &Arc<dyn INodeInterface>,
+    src: &Arc<dyn INodeInterface>,
+    src_offset: usize,
+    len: usize,
+    offset: usize,
+) -> FsResult<()> {
+    let node = node_of(file)?;
+    check_clone(src_offset, len, offset)?;
+    check_same_dev(file.as_ref(), src.as_ref())?;
+    Ok(node.clone_range(&source(src)?, src_offset, len, offset)?)
+}
+
+/// Check the offsets of a clone, the alignment of len is checked by the
+/// node knowing the sizes.
+fn check_clone(src_offset: usize, len: usize, offset: usize) -> VfsResult<()> {
+    check_range(src_offset, len, u64::MAX)?;
+    check_range(offset, len, u64::MAX)?;
+    match src_offset % PAGE_SIZE == 0 && offset % PAGE_SIZE == 0 {
+        true => Ok(()),
+        false => Err(VfsError::InvalidInput),
+    }
+}
+
+type FdResolver = Arc<dyn Fn(usize) -> Option<Arc<dyn INodeInterface>> + Send + Sync>;
+
+static RESOLVER: Mutex<Option<FdResolver>> = Mutex::new(None);
+
+/// Set the hook of the ioctls returning the open file of an fd of the
+/// current process, None for a closed fd. It's called without the locks
+/// of the fs crate.
+pub fn set_fd_resolver(
+    resolver: Box<dyn Fn(usize) -> Option<Arc<dyn INodeInterface>> + Send + Sync>,
+) {
+    *RESOLVER.lock() = Some(resolver.into());
+}
+
+/// The open file of the fd. A closed fd fails with InvalidInput, the
+/// ioctl of INodeInterface can't return EBADF.
+fn resolve(fd: i64) -> VfsResult<Arc<dyn INodeInterface>> {
+    let resolver = RESOLVER.lock().clone().ok_or(VfsError::InvalidInput)?;
+    let fd = usize::try_from(fd).map_err(|_| VfsError::InvalidInput)?;
+    resolver(fd).ok_or(VfsError::InvalidInput)
+}
+
+/// FICLONE and FICLONERANGE of the node, arg is the fd of the source or
+/// the pointer of the FileCloneRange in the address space of the caller.
+/// None for the other commands.
+pub fn ioctl(node: &dyn CloneINode, command: usize, arg: usize) -> Option<VfsResult<usize>> {
+    let r = match command {
+        FICLONE => resolve(arg as i64).and_then(|src| node.clone_from(&source(&src)?)),
+        FICLONERANGE if arg == 0 => Err(VfsError::InvalidInput),
+        FICLONERANGE => {
+            // SAFETY: the kernel passes a pointer checked for the struct,
+            // like the ioctls of the devices.
+            let range = unsafe { (arg as *const FileCloneRange).read_unaligned() };
+            clone_ioctl_range(node, &range)
+        }
+        _ => return None,
+    };
+    Some(r.map(|_| 0))
+}
+
+fn clone_ioctl_range(node: &dyn CloneINode, range: &FileCloneRange) -> VfsResult<()> {
+    let field = |x: u64| usize::try_from(x).map_err(|_| VfsError::InvalidInput);
+    let (src_offset, len) = (field(range.src_offset)?, field(range.src_length)?);
+    let offset = field(range.dest_offset)?;
+    check_clone(src_offset, len, offset)?;
+    let src = resolve(range.src_fd)?;
+    node.clone_range(&source(&src)?, src_offset, len, offset)
+}
@@ -0,0 +1,163 @@
+// The renames of the entries, rename(2). INodeInterface of vfscore has no
+// rename, so the directories which can move their entries implement
+// RenameINode and hand it out by their FsNode, like the nodes of
+// statx.rs. A directory can't move into itself or under itself: rename
+// walks ".." up from the target directory to the root of its filesystem,
+// comparing the identity of every directory with the moved one, like walk,
+// so a path which doesn't match the tree any more can't fool the check.
+// The renames are serialized while they check and move, like the
+// s_vfs_rename_mutex of Linux, so two renames can't make a loop together.
+// The flags are those of renameat2: NOREPLACE fails if the target exists,
+// checked by the filesystem under the locks of its move, and EXCHANGE
+// swaps two existing entries, which may be of different types, so both
+// names are checked against the tree. The errnos VfsError has no variant
+// for, EXDEV, EISDIR and ELOOP, are carried by FsError.
+
+use core::ops::BitOr;
+
+use alloc::sync::Arc;
+use vfscore::{FileType, INodeInterface, VfsError, VfsResult};
+
+use crate::error::{Errno, FsError, FsResult};
+use crate::node;
+use crate::ops::check_same_dev;
+use crate::sys::Mutex;
+use crate::walk;
+
+/// The most directories walked up from the target of a rename, a deeper
+/// tree fails with InvalidInput and ELOOP.
+pub const MAX_DEPTH: usize = 4096;
+
+/// The flags of a rename, the bits of the flags of renameat2.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RenameFlags(u32);
+
+impl RenameFlags {
+    pub const NONE: Self = Self(0);
+    /// RENAME_NOREPLACE.
+    pub const NOREPLACE: Self = Self(1);
+    /// RENAME_EXCHANGE.
+    pub const EXCHANGE: Self = Self(1 << 1);
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// The flags of the bits of the syscall. The unknown bits, like
+    /// RENAME_WHITEOUT, and NOREPLACE with EXCHANGE fail with InvalidInput.
+    pub fn from_bits(bits: u32) -> VfsResult<Self> {
+        let flags = Self(bits);
+        if bits & !(Self::NOREPLACE.0 | Self::EXCHANGE.0) != 0
+            || flags.contains(Self::NOREPLACE | Self::EXCHANGE)
+        {
+            return Err(VfsError::InvalidInput);
+        }
+        Ok(flags)
+    }
+}
+
+impl BitOr for RenameFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// The renames of a directory.
+pub trait RenameINode: Send + Sync {
+    /// Move the entry old of this directory to the entry new of new_dir,
+    /// on the same filesystem. An existing new is replaced: a file by a
+    /// file, an empty directory by a directory, a directory which isn't
+    /// empty fails with DirectoryNotEmpty. A moved directory gets its ".."
+    /// and the links of ".." move with it.
+    ///
+    /// With NOREPLACE an existing new fails with AlreadyExists instead.
+    /// With EXCHANGE both entries must exist, FileNotFound otherwise, and
+    /// each takes the place of the other, with their "..", and no lookup
+    /// finds either name missing while they swap.
+    fn rename_to(
+        &self,
+        old: &str,
+        new_dir: &Arc<dyn INodeInterface>,
+        new: &str,
+        flags: RenameFlags,
+    ) -> VfsResult<()>;
+}
+
+/// The running rename.
+static RENAME: Mutex<()> = Mutex::new(());
+
+fn node_of(dir: &Arc<dyn INodeInterface>) -> Option<&dyn RenameINode> {
+    node::fs_node(dir.as_ref())?.as_rename()
+}
+
+/// The identity of the node, the nodes without one can't be checked.
+fn identity(node: &Arc<dyn INodeInterface>) -> VfsResult<(u64, usize)> {
+    walk::identity(node.as_ref()).ok_or(VfsError::NotSupported)
+}
+
+/// Check that dir isn't node or a directory under it, by walking ".." up
+/// from dir. The root of the filesystem is its own "..".
+pub fn check_not_under(
+    node: &Arc<dyn INodeInterface>,
+    dir: &Arc<dyn INodeInterface>,
+) -> FsResult<()> {
+    let moved = identity(node)?;
+    let mut dir = dir.clone();
+    for _ in 0..MAX_DEPTH {
+        let here = identity(&dir)?;
+        if here == moved {
+            return Err(VfsError::InvalidInput.into());
+        }
+        let parent = dir.lookup("..")?;
+        if identity(&parent)? == here {
+            return Ok(());
+        }
+        dir = parent;
+    }
+    Err(FsError::new(VfsError::InvalidInput, Errno::ELOOP))
+}
+
+fn is_dir(node: &Arc<dyn INodeInterface>) -> VfsResult<bool> {
+    Ok(matches!(node.metadata()?.file_type, FileType::Directory))
+}
+
+/// Move the entry old of old_dir to new of new_dir with the flags, see
+/// RenameINode. A directory moved under itself fails with InvalidInput,
+/// an exchanged one too, and old_dir without renames with NotSupported.
+pub fn rename(
+    old_dir: &Arc<dyn INodeInterface>,
+    old: &str,
+    new_dir: &Arc<dyn INodeInterface>,
+    new: &str,
+    flags: RenameFlags,
+) -> FsResult<()> {
+    let node = node_of(old_dir).ok_or(VfsError::NotSupported)?;
+    check_same_dev(old_dir.as_ref(), new_dir.as_ref())?;
+    let _rename = RENAME.lock();
+    let entry = old_dir.lookup(old)?;
+    if is_dir(&entry)? {
+        check_not_under(&entry, new_dir)?;
+    }
+    if flags.contains(RenameFlags::EXCHANGE) {
+        let target = new_dir.lookup(new)?;
+        if is_dir(&target)? {
+            check_not_under(&target, old_dir)?;
+        }
+    } else if !flags.contains(RenameFlags::NOREPLACE) {
+        // the filesystem checks the types too, without the errno.
+        if let Ok(target) = new_dir.lookup(new) {
+            match (is_dir(&entry)?, is_dir(&target)?) {
+                (false, true) => return Err(FsError::new(VfsError::InvalidInput, Errno::EISDIR)),
+                (true, false) => return Err(VfsError::NotDir.into()),
+                _ => {}
+            }
+        }
+    }
+    Ok(node.rename_to(old, new_dir, new, flags)?)
+}
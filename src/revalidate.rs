@@ -0,0 +1,79 @@
+// Revalidating a filesystem whose disk was changed by another writer,
+// like a host writing the image of a VM or a debugger patching a disk.
+// The caches of a mount trust that nothing else writes its disk, so a
+// read-only mount sees the changes of the other writer only after it's
+// told: revalidate checks the disk of the filesystem and drops what is
+// stale, the cached metadata, the pages and the dentries under its root.
+// revalidate_inode does it for one inode only. A writable mount owns its
+// disk, another writer corrupts it, so revalidating it does nothing.
+// FileSystem and INodeInterface of vfscore have no revalidate, so the
+// filesystems which can revalidate register it, like the Freeze of
+// freeze.rs, and the nodes hand out a RevalidateINode by their FsNode,
+// like the SyncINode of fsync.rs.
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use vfscore::{FileSystem, INodeInterface, VfsError, VfsResult};
+
+use crate::dentry;
+use crate::node;
+use crate::sys::Mutex;
+
+/// The revalidation of a filesystem.
+pub trait Revalidate: Send + Sync {
+    /// Check the disk for the changes of another writer and drop the
+    /// caches they made stale. return whether the disk changed.
+    fn revalidate(&self) -> VfsResult<bool>;
+
+    /// The root directory, the dentries under it are dropped after a
+    /// change.
+    fn root(&self) -> Arc<dyn INodeInterface>;
+}
+
+/// The revalidation of a node.
+pub trait RevalidateINode: Send + Sync {
+    /// Read the inode again and drop its cached data if it changed.
+    /// return whether it changed.
+    fn revalidate(&self) -> VfsResult<bool>;
+}
+
+/// The registered filesystems, the dropped ones are removed lazily.
+static FILESYSTEMS: Mutex<Vec<(Weak<dyn FileSystem>, Weak<dyn Revalidate>)>> =
+    Mutex::new(Vec::new());
+
+/// Register the revalidation of the filesystem.
+pub fn register(fs: Weak<dyn FileSystem>, revalidate: Weak<dyn Revalidate>) {
+    let mut filesystems = FILESYSTEMS.lock();
+    filesystems.retain(|(fs, x)| fs.strong_count() > 0 && x.strong_count() > 0);
+    filesystems.push((fs, revalidate));
+}
+
+fn revalidate_of(fs: &Arc<dyn FileSystem>) -> VfsResult<Arc<dyn Revalidate>> {
+    FILESYSTEMS
+        .lock()
+        .iter()
+        .find(|(x, _)| core::ptr::addr_eq(x.as_ptr(), Arc::as_ptr(fs)))
+        .and_then(|(_, x)| x.upgrade())
+        .ok_or(VfsError::NotSupported)
+}
+
+/// Revalidate the filesystem, see the module. The dentries cached under
+/// its root are dropped if its disk changed. return whether it changed,
+/// NotSupported if the filesystem can't be revalidated.
+pub fn revalidate(fs: &Arc<dyn FileSystem>) -> VfsResult<bool> {
+    let revalidate = revalidate_of(fs)?;
+    let changed = revalidate.revalidate()?;
+    if changed {
+        dentry::invalidate_fs(&revalidate.root());
+    }
+    Ok(changed)
+}
+
+/// Revalidate the inode of the file, see the module. return whether it
+/// changed, NotSupported if the node can't be revalidated.
+pub fn revalidate_inode(file: &Arc<dyn INodeInterface>) -> VfsResult<bool> {
+    let node = node::fs_node(file.as_ref()).and_then(|x| x.as_revalidate());
+    node.ok_or(VfsError::NotSupported)?.revalidate()
+}
@@ -0,0 +1,166 @@
+// The snapshots of the metadata of the inodes, for the stats which don't
+// wait for the writes. A write holds the lock of its inode for its whole
+// length, so a stat taking the lock stalls behind it. A Snapshot is
+// published by the filesystem at the end of every change of the inode,
+// and read without a lock like a seqlock: the publisher makes the version
+// odd, stores the fields and makes it even again, the reader copies the
+// fields between two loads of the same even version and retries
+// otherwise. A reader never sees the fields of two changes together, like
+// the size of one write with the mtime of the next. The fields are
+// atomics, so a torn copy is thrown away and never undefined. The
+// publishers are serialized by the version, a second one spins while the
+// first one stores.
+
+use core::hint::spin_loop;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+
+use vfscore::TimeSpec;
+
+/// The fields of stat kept in a snapshot.
+#[derive(Debug, Clone, Copy)]
+pub struct Meta {
+    pub size: u64,
+    /// The sectors of 512 bytes allocated.
+    pub blocks: u64,
+    /// The mode of the inode, with the bits of its type.
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: TimeSpec,
+    pub mtime: TimeSpec,
+    pub ctime: TimeSpec,
+    /// The device number of Linux of a device node.
+    pub rdev: u64,
+}
+
+/// The words of a Meta.
+const WORDS: usize = 11;
+
+impl Meta {
+    fn to_words(self) -> [u64; WORDS] {
+        let time = |x: TimeSpec| [x.sec as u64, x.nsec as u64];
+        let [a, b] = time(self.atime);
+        let [m, n] = time(self.mtime);
+        let [c, d] = time(self.ctime);
+        [
+            self.size,
+            self.blocks,
+            self.mode as u64 | ((self.nlink as u64) << 32),
+            self.uid as u64 | ((self.gid as u64) << 32),
+            a,
+            b,
+            m,
+            n,
+            c,
+            d,
+            self.rdev,
+        ]
+    }
+
+    fn from_words(words: [u64; WORDS]) -> Self {
+        let time = |sec: u64, nsec: u64| TimeSpec {
+            sec: sec as _,
+            nsec: nsec as _,
+        };
+        Self {
+            size: words[0],
+            blocks: words[1],
+            mode: words[2] as u32,
+            nlink: (words[2] >> 32) as u32,
+            uid: words[3] as u32,
+            gid: (words[3] >> 32) as u32,
+            atime: time(words[4], words[5]),
+            mtime: time(words[6], words[7]),
+            ctime: time(words[8], words[9]),
+            rdev: words[10],
+        }
+    }
+}
+
+/// The published metadata of an inode, see the module.
+pub struct Snapshot {
+    /// Odd while a publisher stores, 0 before the first publication.
+    version: AtomicU64,
+    words: [AtomicU64; WORDS],
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Snapshot {
+    pub const fn new() -> Self {
+        Self {
+            version: AtomicU64::new(0),
+            words: [const { AtomicU64::new(0) }; WORDS],
+        }
+    }
+
+    /// Publish the metadata, the readers see all of it or none.
+    pub fn publish(&self, meta: &Meta) {
+        self.update(|| Some(*meta))
+    }
+
+    /// Publish the metadata read by f, None keeps the last one. f runs
+    /// while the version is odd, so the publishers are serialized and the
+    /// last one to read the inode publishes last.
+    pub fn update(&self, f: impl FnOnce() -> Option<Meta>) {
+        let mut version = self.version.load(Ordering::Relaxed);
+        loop {
+            if version % 2 == 1 {
+                spin_loop();
+                version = self.version.load(Ordering::Relaxed);
+                continue;
+            }
+            match self.version.compare_exchange_weak(
+                version,
+                version + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(x) => version = x,
+            }
+        }
+        // the stores can't move before the odd version.
+        fence(Ordering::Release);
+        match f() {
+            Some(meta) => {
+                for (word, value) in self.words.iter().zip(meta.to_words()) {
+                    word.store(value, Ordering::Relaxed);
+                }
+                self.version.store(version + 2, Ordering::Release);
+            }
+            None => self.version.store(version, Ordering::Release),
+        }
+    }
+
+    /// The last published metadata, None before the first one. It never
+    /// waits for a lock, only for a publisher storing right now.
+    pub fn load(&self) -> Option<Meta> {
+        loop {
+            let version = self.version.load(Ordering::Acquire);
+            if version == 0 {
+                return None;
+            }
+            if version % 2 == 1 {
+                spin_loop();
+                continue;
+            }
+            let words = self.words.each_ref().map(|x| x.load(Ordering::Relaxed));
+            // the loads can't move after the second load of the version.
+            fence(Ordering::Acquire);
+            if self.version.load(Ordering::Relaxed) == version {
+                return Some(Meta::from_words(words));
+            }
+        }
+    }
+
+    /// The number of publications, for the tests.
+    pub fn publications(&self) -> u64 {
+        self.version.load(Ordering::Acquire) / 2
+    }
+}
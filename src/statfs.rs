@@ -0,0 +1,89 @@
+// The filesystem types and ids of statfs, for the fstatfs checks of the
+// programs. f_type is the magic number of the filesystem, like Linux, and
+// f_fsid tells the filesystems apart: ext4 derives it from its uuid, so
+// it's stable across mounts, the others take a new one at every mount
+// from a counter, and so do the st_dev of the filesystems which aren't on
+// a device of sys. FileSystem of vfscore has no type, so magic reads the
+// f_type of its root. The flags of the mount are of mounts.rs, fstatfs
+// adds them as the f_flag of statvfs.
+// TODO: fill f_flags of StatFS when vfscore has it.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use alloc::sync::Arc;
+use vfscore::{FileSystem, INodeInterface, Stat, StatFS, VfsResult};
+
+use crate::mounts::{self, MountFlags};
+
+/// EXT4_SUPER_MAGIC, the magic of the superblock too.
+pub const EXT4_SUPER_MAGIC: u32 = 0xEF53;
+/// TMPFS_MAGIC.
+pub const TMPFS_MAGIC: u32 = 0x0102_1994;
+/// PROC_SUPER_MAGIC.
+pub const PROC_SUPER_MAGIC: u32 = 0x9fa0;
+/// MSDOS_SUPER_MAGIC, FAT of any size.
+pub const MSDOS_SUPER_MAGIC: u32 = 0x4d44;
+
+/// The f_flag bits of statvfs.
+pub const ST_RDONLY: u64 = 1;
+pub const ST_NOATIME: u64 = 1 << 10;
+pub const ST_RELATIME: u64 = 1 << 12;
+
+/// The fsids of the mounts without a uuid, 0 is no fsid.
+static NEXT_FSID: AtomicU64 = AtomicU64::new(1);
+
+/// Allocate the fsid of a mount without a uuid, it's kept for the
+/// lifetime of the mount.
+pub fn next_fsid() -> u64 {
+    NEXT_FSID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The device numbers of the filesystems which aren't on a device of sys
+/// start here, so the page cache and the stats can't mix them up with a
+/// sys device.
+const ANON_DEV_BASE: usize = 1 << 20;
+static NEXT_ANON_DEV: AtomicUsize = AtomicUsize::new(ANON_DEV_BASE);
+
+/// Allocate the st_dev of a filesystem without a device id, like a tmpfs
+/// or a BlockDevice mounted without one.
+pub fn anon_dev() -> usize {
+    NEXT_ANON_DEV.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The f_type of the filesystem, the one of its root.
+pub fn magic(fs: &'static dyn FileSystem) -> VfsResult<u32> {
+    let mut statfs = StatFS::default();
+    fs.root_dir().statfs(&mut statfs)?;
+    Ok(statfs.ftype as u32)
+}
+
+/// The statfs of a node and the f_flag of its mount.
+pub struct FsStat {
+    pub statfs: StatFS,
+    pub flags: u64,
+}
+
+/// The f_flag of the mount flags.
+pub fn st_flags(flags: MountFlags) -> u64 {
+    [
+        (MountFlags::RDONLY, ST_RDONLY),
+        (MountFlags::NOATIME, ST_NOATIME),
+        (MountFlags::RELATIME, ST_RELATIME),
+    ]
+    .into_iter()
+    .filter(|(flag, _)| flags.contains(*flag))
+    .fold(0, |bits, (_, bit)| bits | bit)
+}
+
+/// fstatfs of the node, with the flags of the mount of its device. The
+/// filesystems without registered mounts have no flags.
+pub fn fstatfs(node: &Arc<dyn INodeInterface>) -> VfsResult<FsStat> {
+    let mut statfs = StatFS::default();
+    node.statfs(&mut statfs)?;
+    let mut stat = Stat::default();
+    let flags = match node.stat(&mut stat) {
+        Ok(()) => mounts::dev_flags(stat.dev as usize).map_or(0, st_flags),
+        Err(_) => 0,
+    };
+    Ok(FsStat { statfs, flags })
+}
@@ -0,0 +1,128 @@
+// Statistics of the mounted filesystems and the caches.
+// The counters are atomics updated on the I/O path, render() only reads
+// them and formats `key: value` lines, it's exposed as /proc/fsstats.
+
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::{
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use vfscore::OpenFlags;
+
+use crate::cache;
+use crate::dentry::{dentry_open, dentry_root, DentryNode};
+use crate::pseudo::{CallbackInode, SizeMode};
+use crate::sys::Mutex;
+
+/// A mounted filesystem which reports its counters.
+pub trait StatsSource: Send + Sync {
+    /// The prefix of the keys, unique among the mounts.
+    fn name(&self) -> String;
+    fn counters(&self) -> Vec<(&'static str, usize)>;
+}
+
+/// The I/O counters shared by the nodes of a filesystem.
+#[derive(Debug, Default)]
+pub struct FsCounters {
+    pub reads: AtomicUsize,
+    pub writes: AtomicUsize,
+    pub read_bytes: AtomicUsize,
+    pub write_bytes: AtomicUsize,
+    pub open_inodes: AtomicUsize,
+}
+
+impl FsCounters {
+    pub const fn new() -> Self {
+        Self {
+            reads: AtomicUsize::new(0),
+            writes: AtomicUsize::new(0),
+            read_bytes: AtomicUsize::new(0),
+            write_bytes: AtomicUsize::new(0),
+            open_inodes: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn record_read(&self, bytes: usize) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.read_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_write(&self, bytes: usize) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.write_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn counters(&self) -> Vec<(&'static str, usize)> {
+        vec![
+            ("reads", self.reads.load(Ordering::Relaxed)),
+            ("writes", self.writes.load(Ordering::Relaxed)),
+            ("read_bytes", self.read_bytes.load(Ordering::Relaxed)),
+            ("write_bytes", self.write_bytes.load(Ordering::Relaxed)),
+            ("open_inodes", self.open_inodes.load(Ordering::Relaxed)),
+        ]
+    }
+}
+
+/// The registered filesystems, the dropped ones are removed lazily.
+static SOURCES: Mutex<Vec<Weak<dyn StatsSource>>> = Mutex::new(Vec::new());
+
+pub fn register(source: Weak<dyn StatsSource>) {
+    SOURCES.lock().push(source);
+}
+
+/// Render the statistics, one `key: value` per line.
+/// The keys of a filesystem are prefixed with its name, the keys of a
+/// cache are prefixed with `cache.<name>`.
+pub fn render() -> String {
+    let sources: Vec<_> = {
+        let mut sources = SOURCES.lock();
+        sources.retain(|x| x.strong_count() > 0);
+        sources.iter().filter_map(|x| x.upgrade()).collect()
+    };
+    let mut out = String::new();
+    for source in sources {
+        let name = source.name();
+        for (key, value) in source.counters() {
+            let _ = writeln!(out, "{}.{}: {}", name, key, value);
+        }
+    }
+
+    let stats = cache::stats();
+    let lookups = stats.hits + stats.misses;
+    let _ = writeln!(out, "cache.page.pages: {}", stats.pages);
+    let _ = writeln!(out, "cache.page.hits: {}", stats.hits);
+    let _ = writeln!(out, "cache.page.misses: {}", stats.misses);
+    let _ = writeln!(out, "cache.page.evictions: {}", stats.evictions);
+    // the hit rate in percent, 0 before the first lookup.
+    let _ = writeln!(
+        out,
+        "cache.page.hit_rate: {}",
+        (stats.hits * 100).checked_div(lookups).unwrap_or(0)
+    );
+    for (name, bytes) in cache::usage() {
+        let _ = writeln!(out, "cache.{}.bytes: {}", name, bytes);
+    }
+    for (name, bytes) in cache::pinned_usage() {
+        let _ = writeln!(out, "cache.{}.pinned_bytes: {}", name, bytes);
+    }
+    out
+}
+
+/// Attach /proc/fsstats to the dentry tree, procfs itself doesn't know
+/// the file. Do nothing if /proc isn't mounted.
+pub fn init_procfs() {
+    let Ok(proc) = dentry_open(dentry_root(), "/proc", OpenFlags::NONE) else {
+        return;
+    };
+    let node = Arc::new(DentryNode::new(
+        "fsstats".to_string(),
+        CallbackInode::read_only(|| render().into_bytes(), SizeMode::Snapshot),
+        Arc::downgrade(&proc),
+    ));
+    proc.children.lock().push(node);
+}
@@ -0,0 +1,134 @@
+// statx for the kernel side. INodeInterface is of vfscore, so the statx
+// of a node is in StatxINode, and the nodes implementing it hand it out
+// by their FsNode, see node.rs. statx takes any node, the others are
+// described by their stat, without the birth time.
+
+use alloc::sync::Arc;
+use vfscore::{INodeInterface, Stat, TimeSpec, VfsResult};
+
+use crate::devnode::split_dev;
+use crate::node;
+
+pub const STATX_TYPE: u32 = 0x1;
+pub const STATX_MODE: u32 = 0x2;
+pub const STATX_NLINK: u32 = 0x4;
+pub const STATX_UID: u32 = 0x8;
+pub const STATX_GID: u32 = 0x10;
+pub const STATX_ATIME: u32 = 0x20;
+pub const STATX_MTIME: u32 = 0x40;
+pub const STATX_CTIME: u32 = 0x80;
+pub const STATX_INO: u32 = 0x100;
+pub const STATX_SIZE: u32 = 0x200;
+pub const STATX_BLOCKS: u32 = 0x400;
+/// The fields of stat.
+pub const STATX_BASIC_STATS: u32 = 0x7ff;
+pub const STATX_BTIME: u32 = 0x800;
+
+pub const STATX_ATTR_COMPRESSED: u64 = 0x4;
+pub const STATX_ATTR_IMMUTABLE: u64 = 0x10;
+pub const STATX_ATTR_APPEND: u64 = 0x20;
+pub const STATX_ATTR_NODUMP: u64 = 0x40;
+pub const STATX_ATTR_ENCRYPTED: u64 = 0x800;
+pub const STATX_ATTR_VERITY: u64 = 0x100000;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatxTimestamp {
+    pub sec: i64,
+    pub nsec: u32,
+    pub reserved: i32,
+}
+
+impl From<TimeSpec> for StatxTimestamp {
+    fn from(time: TimeSpec) -> Self {
+        Self {
+            sec: time.sec as _,
+            nsec: time.nsec as _,
+            reserved: 0,
+        }
+    }
+}
+
+/// struct statx of Linux, copied to the user as it is.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Statx {
+    /// The fields which are filled, STATX_*.
+    pub mask: u32,
+    pub blksize: u32,
+    /// STATX_ATTR_*.
+    pub attributes: u64,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u16,
+    pub spare0: u16,
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    /// The attributes the filesystem supports.
+    pub attributes_mask: u64,
+    pub atime: StatxTimestamp,
+    pub btime: StatxTimestamp,
+    pub ctime: StatxTimestamp,
+    pub mtime: StatxTimestamp,
+    pub rdev_major: u32,
+    pub rdev_minor: u32,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    pub mnt_id: u64,
+    pub spare: [u64; 13],
+}
+
+const _: () = assert!(core::mem::size_of::<Statx>() == 0x100);
+
+impl Statx {
+    /// The fields of stat, everything but the birth time.
+    pub fn from_stat(stat: &Stat) -> Self {
+        let (rdev_major, rdev_minor) = split_dev(stat.rdev as _);
+        let (dev_major, dev_minor) = split_dev(stat.dev as _);
+        Self {
+            mask: STATX_BASIC_STATS,
+            blksize: stat.blksize as _,
+            nlink: stat.nlink as _,
+            uid: stat.uid as _,
+            gid: stat.gid as _,
+            mode: stat.mode.bits() as _,
+            ino: stat.ino as _,
+            size: stat.size as _,
+            blocks: stat.blocks as _,
+            atime: stat.atime.into(),
+            ctime: stat.ctime.into(),
+            mtime: stat.mtime.into(),
+            rdev_major,
+            rdev_minor,
+            dev_major,
+            dev_minor,
+            ..Default::default()
+        }
+    }
+}
+
+/// The statx of a node, the default describes the node by its stat. mask
+/// is the fields asked for, more may be filled, out.mask tells which.
+pub trait StatxINode: INodeInterface + Sync {
+    fn statx(&self, _mask: u32, out: &mut Statx) -> VfsResult<()> {
+        let mut stat = Stat::default();
+        self.stat(&mut stat)?;
+        *out = Statx::from_stat(&stat);
+        Ok(())
+    }
+}
+
+/// The statx of the node, by its stat if it has no StatxINode.
+pub fn statx(file: &Arc<dyn INodeInterface>, mask: u32, out: &mut Statx) -> VfsResult<()> {
+    match node::fs_node(file.as_ref()).and_then(|x| x.as_statx()) {
+        Some(node) => node.statx(mask, out),
+        None => {
+            let mut stat = Stat::default();
+            file.stat(&mut stat)?;
+            *out = Statx::from_stat(&stat);
+            Ok(())
+        }
+    }
+}
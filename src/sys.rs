@@ -0,0 +1,283 @@
+// The environment of the crate: the locks and the block devices. The
+// kernel feature takes them from the sync and devices crates, the std
+// feature has host versions with the same interface, so the modules and
+// the shims build on a host for the tests and fuzzing. The host devices
+// are registered with add_blk_device, FileDisk backs one with an image
+// file and RamDisk with memory. LatencyDisk is an async driver with a
+// latency for the tests of the async feature.
+
+#[cfg(feature = "kernel")]
+pub use devices::{get_blk_device, get_blk_devices};
+#[cfg(feature = "kernel")]
+pub use sync::{LazyInit, Mutex};
+
+#[cfg(feature = "std")]
+pub use host::*;
+
+#[cfg(feature = "std")]
+mod host {
+    use core::ops::Deref;
+    #[cfg(feature = "async")]
+    use core::{
+        future::Future,
+        pin::Pin,
+        sync::atomic::{AtomicUsize, Ordering},
+        task::{Context, Poll},
+    };
+    use std::{
+        fs::{File, OpenOptions},
+        io,
+        os::unix::fs::FileExt,
+        path::Path,
+        sync::{Arc, MutexGuard, OnceLock},
+        vec::Vec,
+    };
+
+    #[cfg(feature = "async")]
+    use crate::aio::{AsyncBlockDevice, BlockFuture};
+
+    /// The size of the blocks of read_blocks and write_blocks, like the
+    /// sectors of the kernel devices.
+    pub const SECTOR_SIZE: usize = 512;
+
+    /// std::sync::Mutex without the poisoning, like sync::Mutex.
+    #[derive(Debug, Default)]
+    pub struct Mutex<T: ?Sized>(std::sync::Mutex<T>);
+
+    impl<T> Mutex<T> {
+        pub const fn new(value: T) -> Self {
+            Self(std::sync::Mutex::new(value))
+        }
+    }
+
+    impl<T: ?Sized> Mutex<T> {
+        pub fn lock(&self) -> MutexGuard<'_, T> {
+            self.0.lock().unwrap_or_else(|x| x.into_inner())
+        }
+    }
+
+    /// A value initialized once by init_by, like sync::LazyInit.
+    pub struct LazyInit<T>(OnceLock<T>);
+
+    impl<T> LazyInit<T> {
+        pub const fn new() -> Self {
+            Self(OnceLock::new())
+        }
+
+        pub fn init_by(&self, value: T) {
+            assert!(self.0.set(value).is_ok(), "LazyInit initialized twice");
+        }
+
+        pub fn is_init(&self) -> bool {
+            self.0.get().is_some()
+        }
+    }
+
+    impl<T> Default for LazyInit<T> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<T> Deref for LazyInit<T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            self.0.get().expect("LazyInit used before init_by")
+        }
+    }
+
+    /// A block device of the host, the blocks are SECTOR_SIZE bytes.
+    pub trait BlockDriver: Send + Sync {
+        fn read_blocks(&self, block: usize, buf: &mut [u8]);
+        fn write_blocks(&self, block: usize, buf: &[u8]);
+        /// The size in bytes.
+        fn capacity(&self) -> usize;
+    }
+
+    static BLK_DEVICES: Mutex<Vec<Arc<dyn BlockDriver>>> = Mutex::new(Vec::new());
+
+    /// Add the device, return its id for get_blk_device and the mounts.
+    pub fn add_blk_device(device: Arc<dyn BlockDriver>) -> usize {
+        let mut devices = BLK_DEVICES.lock();
+        devices.push(device);
+        devices.len() - 1
+    }
+
+    pub fn get_blk_device(id: usize) -> Option<Arc<dyn BlockDriver>> {
+        BLK_DEVICES.lock().get(id).cloned()
+    }
+
+    pub fn get_blk_devices() -> Vec<Arc<dyn BlockDriver>> {
+        BLK_DEVICES.lock().clone()
+    }
+
+    /// A device in memory.
+    pub struct RamDisk(Mutex<Vec<u8>>);
+
+    impl RamDisk {
+        pub fn new(size: usize) -> Self {
+            Self::from_image(vec![0; size])
+        }
+
+        pub fn from_image(image: Vec<u8>) -> Self {
+            Self(Mutex::new(image))
+        }
+
+        /// A copy of the content, to save or compare it.
+        pub fn image(&self) -> Vec<u8> {
+            self.0.lock().clone()
+        }
+    }
+
+    impl BlockDriver for RamDisk {
+        fn read_blocks(&self, block: usize, buf: &mut [u8]) {
+            let data = self.0.lock();
+            let offset = block * SECTOR_SIZE;
+            buf.fill(0);
+            if let Some(x) = data.get(offset..) {
+                let n = x.len().min(buf.len());
+                buf[..n].copy_from_slice(&x[..n]);
+            }
+        }
+
+        fn write_blocks(&self, block: usize, buf: &[u8]) {
+            let mut data = self.0.lock();
+            let offset = block * SECTOR_SIZE;
+            assert!(offset + buf.len() <= data.len(), "write beyond the RamDisk");
+            data[offset..offset + buf.len()].copy_from_slice(buf);
+        }
+
+        fn capacity(&self) -> usize {
+            self.0.lock().len()
+        }
+    }
+
+    /// A device backed by an image file, the size is the one of the file
+    /// when it's opened.
+    pub struct FileDisk {
+        file: File,
+        size: usize,
+    }
+
+    impl FileDisk {
+        pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+            let file = OpenOptions::new().read(true).write(true).open(path)?;
+            let size = file.metadata()?.len() as usize;
+            Ok(Self { file, size })
+        }
+    }
+
+    impl BlockDriver for FileDisk {
+        fn read_blocks(&self, block: usize, buf: &mut [u8]) {
+            buf.fill(0);
+            let mut pos = 0;
+            // the bytes beyond the end of the file read as zeros.
+            while pos < buf.len() {
+                match self
+                    .file
+                    .read_at(&mut buf[pos..], (block * SECTOR_SIZE + pos) as u64)
+                {
+                    Ok(0) => break,
+                    Ok(n) => pos += n,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => panic!("read the FileDisk failed: {}", err),
+                }
+            }
+        }
+
+        fn write_blocks(&self, block: usize, buf: &[u8]) {
+            assert!(
+                block * SECTOR_SIZE + buf.len() <= self.size,
+                "write beyond the FileDisk"
+            );
+            self.file
+                .write_all_at(buf, (block * SECTOR_SIZE) as u64)
+                .expect("write the FileDisk failed");
+        }
+
+        fn capacity(&self) -> usize {
+            self.size
+        }
+    }
+
+    /// The async driver of a host device, a request is pending for polls
+    /// polls before it's done on the device. The waker is woken at each
+    /// pending poll, so the executors polling on wakes make progress.
+    #[cfg(feature = "async")]
+    pub struct LatencyDisk {
+        device: Arc<dyn BlockDriver>,
+        polls: usize,
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    #[cfg(feature = "async")]
+    impl LatencyDisk {
+        pub fn new(device: Arc<dyn BlockDriver>, polls: usize) -> Self {
+            Self {
+                device,
+                polls,
+                in_flight: AtomicUsize::new(0),
+                max_in_flight: AtomicUsize::new(0),
+            }
+        }
+
+        /// The most requests which were pending at once.
+        pub fn max_in_flight(&self) -> usize {
+            self.max_in_flight.load(Ordering::Relaxed)
+        }
+
+        fn latency(&self) -> Latency<'_> {
+            Latency {
+                disk: self,
+                left: self.polls,
+                started: false,
+            }
+        }
+    }
+
+    #[cfg(feature = "async")]
+    struct Latency<'a> {
+        disk: &'a LatencyDisk,
+        left: usize,
+        started: bool,
+    }
+
+    #[cfg(feature = "async")]
+    impl Future for Latency<'_> {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if !self.started {
+                self.started = true;
+                let count = self.disk.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+                self.disk.max_in_flight.fetch_max(count, Ordering::Relaxed);
+            }
+            if self.left > 0 {
+                self.left -= 1;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.disk.in_flight.fetch_sub(1, Ordering::Relaxed);
+            Poll::Ready(())
+        }
+    }
+
+    #[cfg(feature = "async")]
+    impl AsyncBlockDevice for LatencyDisk {
+        fn read_blocks_async<'a>(&'a self, block: usize, buf: &'a mut [u8]) -> BlockFuture<'a> {
+            Box::pin(async move {
+                self.latency().await;
+                self.device.read_blocks(block, buf);
+            })
+        }
+
+        fn write_blocks_async<'a>(&'a self, block: usize, buf: &'a [u8]) -> BlockFuture<'a> {
+            Box::pin(async move {
+                self.latency().await;
+                self.device.write_blocks(block, buf);
+            })
+        }
+    }
+}
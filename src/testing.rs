@@ -0,0 +1,740 @@
+// The devices of the tests. MockDisk is a disk in memory which fails on
+// demand, for the tests of the robustness: a write, the next write of
+// some bytes, the sectors of a range or their next reads fail, a write
+// is torn, or the
+// power is cut and the writes after the cut are lost while the earlier
+// ones are kept. Every request is logged with its sequence number, so a
+// test can check the order of the writes and the flushes, and the async
+// requests are pending for some polls like LatencyDisk of sys. A delay
+// makes every request take some time of a clock of the disk, elapsed, so
+// a test can time out a sick device without waiting for it. MockDisk is a
+// BlockDevice of ext4_rs, a RequestDevice of iosched, a BlockDriver of
+// the host and an AsyncBlockDevice, by the features.
+//
+// The devices can't return their errors, like LoopDevice of blockdev: a
+// failed read returns zeros and a failed write is lost, the failed
+// reads and writes are counted for the DeviceErrors of blockdev. The
+// sectors are
+// written atomically, a torn write persists the first half of its sectors
+// and a one sector write is all or nothing.
+//
+// The write cache of a MockDisk is the DeviceFlush of blockdev. By
+// default it reports a cache but writes through, the flushes are logged
+// only. A Reordering cache keeps the writes volatile until a flush, the
+// FUA writes excepted, and the image after a power cut has a subset of
+// them picked by its seed, as if the cache wrote them to the media in any
+// order, so a filesystem misses the barriers it needs.
+//
+// CountingAlloc is an allocator of the host counting the allocated bytes,
+// their peak and the allocations, for the tests bounding the memory of an operation. The
+// binary running the tests installs it as its #[global_allocator].
+
+use core::{
+    fmt::{self, Debug},
+    ops::Range,
+};
+#[cfg(feature = "async")]
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+#[cfg(feature = "std")]
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(feature = "std")]
+use std::alloc::{GlobalAlloc, Layout, System};
+
+#[cfg(feature = "async")]
+use alloc::boxed::Box;
+use alloc::{collections::BTreeSet, vec::Vec};
+
+#[cfg(feature = "async")]
+use crate::aio::{AsyncBlockDevice, BlockFuture};
+use crate::sys::Mutex;
+
+/// The size of the blocks of read_blocks and write_blocks, the sectors of
+/// sys whatever the sector size of the MockDisk.
+pub const BLOCK_SIZE: usize = 512;
+
+/// The kind of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockOp {
+    Read,
+    Write,
+    /// A write past the write cache, the write_fua of DeviceFlush.
+    WriteFua,
+    /// A flush of the write cache, the DeviceFlush of blockdev.
+    Flush,
+}
+
+/// The write cache of a MockDisk, see the module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteCache {
+    /// No cache, the flushes and the FUA writes of blockdev are plain.
+    None,
+    /// A cache whose writes are durable as they're done.
+    #[default]
+    WriteThrough,
+    /// The writes are durable after a flush, a power cut keeps those of
+    /// them picked by the seed.
+    Reordering { seed: u64 },
+}
+
+/// A recorded write, see record_writes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedWrite {
+    pub offset: usize,
+    pub data: Vec<u8>,
+    /// A FUA write, durable once it's done.
+    pub fua: bool,
+    /// The done flushes before the write.
+    pub flushes: usize,
+}
+
+/// Whether the power cut of the seed keeps the cached write i, about one
+/// out of two. The image of a Reordering MockDisk and the crashes of
+/// crash.rs pick them the same way.
+pub fn keeps_cached(seed: u64, i: usize) -> bool {
+    let x = seed ^ (i as u64).wrapping_mul(0x9e3779b97f4a7c15);
+    x.wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407)
+        >> 63
+        == 1
+}
+
+/// What became of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Done,
+    /// The request failed, wholly or on some of its sectors.
+    Failed,
+    /// The write persisted only its first bytes.
+    Torn {
+        persisted: usize,
+    },
+    /// The write came after the power cut.
+    Dropped,
+}
+
+/// A logged request, seq counts the requests of the disk from 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub seq: u64,
+    pub op: MockOp,
+    pub offset: usize,
+    pub len: usize,
+    pub outcome: Outcome,
+}
+
+/// The content, the faults and the log of a MockDisk.
+struct MockState {
+    data: Vec<u8>,
+    seq: u64,
+    /// The writes so far, the faults count the writes from 0.
+    writes: u64,
+    failed_writes: BTreeSet<u64>,
+    /// The next write with one of these bytes fails, once.
+    failed_patterns: Vec<Vec<u8>>,
+    torn_writes: BTreeSet<u64>,
+    bad_sectors: Vec<Range<usize>>,
+    /// The sectors whose next reads fail, with the reads left to fail.
+    flaky_sectors: Vec<(Range<usize>, u32)>,
+    /// The writes from this one are dropped.
+    power_cut: Option<u64>,
+    log: Vec<Request>,
+    /// The failed reads and writes, the DeviceErrors of blockdev.
+    read_errors: u64,
+    write_errors: u64,
+    /// The data of the done writes, while they're recorded.
+    recorded: Option<Vec<RecordedWrite>>,
+    cache: WriteCache,
+    fua: bool,
+    /// The done flushes.
+    flushes: usize,
+    /// The content on the media and the cached writes after it, of a
+    /// Reordering cache.
+    durable: Option<Vec<u8>>,
+    cached: Vec<(usize, Vec<u8>)>,
+}
+
+/// A disk in memory with the faults of the tests, see the module.
+pub struct MockDisk {
+    sector_size: usize,
+    latency: usize,
+    /// The nanoseconds of a request on the clock of the disk.
+    delay: u64,
+    state: Mutex<MockState>,
+}
+
+impl MockDisk {
+    /// A zeroed disk of size bytes, a multiple of the sector size.
+    pub fn new(size: usize, sector_size: usize) -> Self {
+        Self::from_image(vec![0; size], sector_size)
+    }
+
+    pub fn from_image(image: Vec<u8>, sector_size: usize) -> Self {
+        assert!(
+            sector_size > 0 && image.len() % sector_size == 0,
+            "the MockDisk isn't made of sectors"
+        );
+        Self {
+            sector_size,
+            latency: 0,
+            delay: 0,
+            state: Mutex::new(MockState {
+                data: image,
+                seq: 0,
+                writes: 0,
+                failed_writes: BTreeSet::new(),
+                failed_patterns: Vec::new(),
+                torn_writes: BTreeSet::new(),
+                bad_sectors: Vec::new(),
+                flaky_sectors: Vec::new(),
+                power_cut: None,
+                log: Vec::new(),
+                read_errors: 0,
+                write_errors: 0,
+                recorded: None,
+                cache: WriteCache::default(),
+                fua: false,
+                flushes: 0,
+                durable: None,
+                cached: Vec::new(),
+            }),
+        }
+    }
+
+    /// The async requests are pending for polls polls before they're done.
+    pub fn with_latency(mut self, polls: usize) -> Self {
+        self.latency = polls;
+        self
+    }
+
+    /// Every request takes nanos on the clock of the disk, see elapsed.
+    pub fn with_delay(mut self, nanos: u64) -> Self {
+        self.delay = nanos;
+        self
+    }
+
+    /// The nanoseconds of the requests so far, by the delay.
+    pub fn elapsed(&self) -> u64 {
+        self.state.lock().seq * self.delay
+    }
+
+    pub fn size(&self) -> usize {
+        self.state.lock().data.len()
+    }
+
+    pub fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    /// A copy of the content, what a remount after a power cut reads. A
+    /// Reordering cache loses the cached writes its seed doesn't keep.
+    pub fn image(&self) -> Vec<u8> {
+        let state = self.state.lock();
+        let (Some(durable), WriteCache::Reordering { seed }) = (&state.durable, state.cache) else {
+            return state.data.clone();
+        };
+        let mut image = durable.clone();
+        for (i, (offset, data)) in state.cached.iter().enumerate() {
+            if keeps_cached(seed, i) {
+                image[*offset..offset + data.len()].copy_from_slice(data);
+            }
+        }
+        image
+    }
+
+    /// Switch the write cache, the writes cached by a Reordering one are
+    /// made durable first.
+    pub fn set_write_cache(&self, cache: WriteCache) {
+        let mut state = self.state.lock();
+        state.cache = cache;
+        state.durable = match cache {
+            WriteCache::Reordering { .. } => Some(state.data.clone()),
+            _ => None,
+        };
+        state.cached.clear();
+    }
+
+    pub fn write_cache(&self) -> WriteCache {
+        self.state.lock().cache
+    }
+
+    /// Take the FUA writes of blockdev, or leave them to a write and a
+    /// flush.
+    pub fn set_fua(&self, fua: bool) {
+        self.state.lock().fua = fua;
+    }
+
+    /// The number of the writes so far, dropped and failed ones included.
+    pub fn writes(&self) -> u64 {
+        self.state.lock().writes
+    }
+
+    /// The requests so far in their order.
+    pub fn log(&self) -> Vec<Request> {
+        self.state.lock().log.clone()
+    }
+
+    pub fn clear_log(&self) {
+        self.state.lock().log.clear();
+    }
+
+    /// Record the offset and the data of the next done writes, to rebuild
+    /// the images of the prefixes of the writes.
+    pub fn record_writes(&self) {
+        self.state.lock().recorded = Some(Vec::new());
+    }
+
+    /// The offset and the data of the recorded writes in their order.
+    pub fn recorded_writes(&self) -> Vec<(usize, Vec<u8>)> {
+        let recorded = self.recorded();
+        recorded.into_iter().map(|x| (x.offset, x.data)).collect()
+    }
+
+    /// The recorded writes with their FUA and the flushes before them.
+    pub fn recorded(&self) -> Vec<RecordedWrite> {
+        self.state.lock().recorded.clone().unwrap_or_default()
+    }
+
+    /// Fail the write n, 0 is the first write of the disk.
+    pub fn fail_write(&self, n: u64) {
+        self.state.lock().failed_writes.insert(n);
+    }
+
+    /// Fail the next write whose data has the bytes, like a fault on the
+    /// block of a directory entry whatever its place on the disk.
+    pub fn fail_write_of(&self, bytes: &[u8]) {
+        assert!(!bytes.is_empty(), "the failed write has no bytes");
+        self.state.lock().failed_patterns.push(bytes.to_vec());
+    }
+
+    /// The failed writes so far, a torn or a dropped write isn't one, the
+    /// disk loses it without knowing.
+    pub fn write_errors(&self) -> u64 {
+        self.state.lock().write_errors
+    }
+
+    /// The failed reads so far, of the failed sectors.
+    pub fn read_errors(&self) -> u64 {
+        self.state.lock().read_errors
+    }
+
+    /// Tear the write n, it persists the first half of its sectors.
+    pub fn tear_write(&self, n: u64) {
+        self.state.lock().torn_writes.insert(n);
+    }
+
+    /// Fail the accesses of the sectors of the range, until clear_faults.
+    pub fn fail_sectors(&self, sectors: Range<usize>) {
+        self.state.lock().bad_sectors.push(sectors);
+    }
+
+    /// Fail the next times reads of the sectors of the range, the reads
+    /// after them succeed, like a transient error of an SD card.
+    pub fn fail_reads(&self, sectors: Range<usize>, times: u32) {
+        self.state.lock().flaky_sectors.push((sectors, times));
+    }
+
+    /// Cut the power at the write n: it and the writes after it are
+    /// dropped, the writes before it are kept. The reads go on and read
+    /// the kept writes.
+    pub fn cut_power_after(&self, n: u64) {
+        self.state.lock().power_cut = Some(n);
+    }
+
+    /// Cut the power now, the next writes are dropped.
+    pub fn cut_power(&self) {
+        let mut state = self.state.lock();
+        state.power_cut = Some(state.writes);
+    }
+
+    pub fn restore_power(&self) {
+        self.state.lock().power_cut = None;
+    }
+
+    /// Drop the faults and restore the power, the log is kept.
+    pub fn clear_faults(&self) {
+        let mut state = self.state.lock();
+        state.failed_writes.clear();
+        state.failed_patterns.clear();
+        state.torn_writes.clear();
+        state.bad_sectors.clear();
+        state.flaky_sectors.clear();
+        state.power_cut = None;
+    }
+
+    /// The bytes of the sectors of the range within [offset, offset + len).
+    fn sector_bytes(&self, sectors: &Range<usize>, offset: usize, len: usize) -> Range<usize> {
+        let start = (sectors.start * self.sector_size).max(offset);
+        let end = (sectors.end * self.sector_size).min(offset + len);
+        start..end.max(start)
+    }
+
+    /// Read buf at offset, the bytes beyond the end of the disk and those
+    /// of the failed sectors read as zeros.
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) {
+        let mut state = self.state.lock();
+        buf.fill(0);
+        if let Some(x) = state.data.get(offset..) {
+            let n = x.len().min(buf.len());
+            buf[..n].copy_from_slice(&x[..n]);
+        }
+        let mut outcome = Outcome::Done;
+        for sectors in state.bad_sectors.iter() {
+            let bytes = self.sector_bytes(sectors, offset, buf.len());
+            if !bytes.is_empty() {
+                buf[bytes.start - offset..bytes.end - offset].fill(0);
+                outcome = Outcome::Failed;
+            }
+        }
+        for (sectors, times) in state.flaky_sectors.iter_mut() {
+            let bytes = self.sector_bytes(sectors, offset, buf.len());
+            if *times > 0 && !bytes.is_empty() {
+                buf[bytes.start - offset..bytes.end - offset].fill(0);
+                outcome = Outcome::Failed;
+                *times -= 1;
+            }
+        }
+        if outcome == Outcome::Failed {
+            state.read_errors += 1;
+        }
+        state.record(MockOp::Read, offset, buf.len(), outcome);
+    }
+
+    /// Write buf at offset, within the disk, with the faults.
+    pub fn write_at(&self, offset: usize, buf: &[u8]) {
+        self.write(offset, buf, false);
+    }
+
+    /// Write buf at offset past the write cache, it's durable once done.
+    pub fn write_fua(&self, offset: usize, buf: &[u8]) {
+        self.write(offset, buf, true);
+    }
+
+    fn write(&self, offset: usize, buf: &[u8], fua: bool) {
+        let mut state = self.state.lock();
+        assert!(
+            offset + buf.len() <= state.data.len(),
+            "write beyond the MockDisk"
+        );
+        let n = state.writes;
+        state.writes += 1;
+        let mut outcome = Outcome::Done;
+        let mut persisted = buf.len();
+        if state.power_cut.is_some_and(|cut| n >= cut) {
+            outcome = Outcome::Dropped;
+            persisted = 0;
+        } else if state.failed_writes.contains(&n) {
+            outcome = Outcome::Failed;
+            persisted = 0;
+        } else if let Some(i) = state
+            .failed_patterns
+            .iter()
+            .position(|x| buf.windows(x.len()).any(|w| w == &x[..]))
+        {
+            state.failed_patterns.remove(i);
+            outcome = Outcome::Failed;
+            persisted = 0;
+        } else if state.torn_writes.contains(&n) {
+            let first = offset / self.sector_size;
+            let sectors = (offset + buf.len()).div_ceil(self.sector_size) - first;
+            let end = (first + sectors / 2) * self.sector_size;
+            persisted = end.saturating_sub(offset).min(buf.len());
+            outcome = Outcome::Torn { persisted };
+        }
+        let mut kept = vec![true; persisted];
+        for sectors in state.bad_sectors.iter() {
+            let bytes = self.sector_bytes(sectors, offset, persisted);
+            if !bytes.is_empty() {
+                kept[bytes.start - offset..bytes.end - offset].fill(false);
+                outcome = Outcome::Failed;
+            }
+        }
+        for (i, _) in kept.iter().enumerate().filter(|(_, x)| **x) {
+            state.data[offset + i] = buf[i];
+        }
+        if outcome == Outcome::Failed {
+            state.write_errors += 1;
+        }
+        if persisted > 0 {
+            state.cache_write(offset, buf.len(), fua);
+        }
+        let flushes = state.flushes;
+        if outcome == Outcome::Done
+            && let Some(recorded) = state.recorded.as_mut()
+        {
+            recorded.push(RecordedWrite {
+                offset,
+                data: buf.to_vec(),
+                fua,
+                flushes,
+            });
+        }
+        let op = match fua {
+            true => MockOp::WriteFua,
+            false => MockOp::Write,
+        };
+        state.record(op, offset, buf.len(), outcome);
+    }
+
+    /// Flush the write cache. Without a Reordering cache it's logged only:
+    /// the writes are durable as they are done, a power cut drops the
+    /// writes after it instead.
+    pub fn flush(&self) {
+        let mut state = self.state.lock();
+        let outcome = match state.power_cut.is_some_and(|cut| state.writes >= cut) {
+            true => Outcome::Dropped,
+            false => Outcome::Done,
+        };
+        if outcome == Outcome::Done {
+            state.flushes += 1;
+            let cached = core::mem::take(&mut state.cached);
+            if let Some(durable) = state.durable.as_mut() {
+                for (offset, data) in cached {
+                    durable[offset..offset + data.len()].copy_from_slice(&data);
+                }
+            }
+        }
+        state.record(MockOp::Flush, 0, 0, outcome);
+    }
+}
+
+impl MockState {
+    /// Keep the written range of a Reordering cache in the cache, or on the
+    /// media for a FUA write, whose data the cached writes get too so they
+    /// can't put back older bytes.
+    fn cache_write(&mut self, offset: usize, len: usize, fua: bool) {
+        let Some(durable) = self.durable.as_mut() else {
+            return;
+        };
+        let data = &self.data[offset..offset + len];
+        if !fua {
+            self.cached.push((offset, data.to_vec()));
+            return;
+        }
+        durable[offset..offset + len].copy_from_slice(data);
+        for (start, cached) in self.cached.iter_mut() {
+            let from = offset.max(*start);
+            let to = (offset + len).min(*start + cached.len());
+            if from < to {
+                cached[from - *start..to - *start].copy_from_slice(&self.data[from..to]);
+            }
+        }
+    }
+
+    fn record(&mut self, op: MockOp, offset: usize, len: usize, outcome: Outcome) {
+        self.log.push(Request {
+            seq: self.seq,
+            op,
+            offset,
+            len,
+            outcome,
+        });
+        self.seq += 1;
+    }
+}
+
+impl Debug for MockDisk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock();
+        f.debug_struct("MockDisk")
+            .field("size", &state.data.len())
+            .field("sector_size", &self.sector_size)
+            .field("writes", &state.writes)
+            .field("power_cut", &state.power_cut)
+            .finish()
+    }
+}
+
+#[cfg(root_fs = "ext4_rs")]
+impl crate::blockdev::BlockDevice for MockDisk {
+    fn read_offset(&self, offset: usize) -> Vec<u8> {
+        let mut buf = vec![0; crate::blockdev::READ_SIZE];
+        self.read_at(offset, &mut buf);
+        buf
+    }
+
+    fn write_offset(&self, offset: usize, buf: &[u8]) {
+        self.write_at(offset, buf);
+    }
+}
+
+#[cfg(root_fs = "ext4_rs")]
+impl crate::blockdev::DeviceFlush for MockDisk {
+    fn flush(&self) {
+        MockDisk::flush(self);
+    }
+
+    fn has_write_cache(&self) -> bool {
+        self.write_cache() != WriteCache::None
+    }
+
+    fn supports_fua(&self) -> bool {
+        self.state.lock().fua
+    }
+
+    fn write_fua(&self, sector: usize, buf: &[u8]) -> vfscore::VfsResult<()> {
+        MockDisk::write_fua(self, sector * crate::blockdev::SECTOR_SIZE, buf);
+        Ok(())
+    }
+}
+
+#[cfg(root_fs = "ext4_rs")]
+impl crate::blockdev::DeviceErrors for MockDisk {
+    fn write_errors(&self) -> u64 {
+        MockDisk::write_errors(self)
+    }
+
+    fn read_errors(&self) -> u64 {
+        MockDisk::read_errors(self)
+    }
+}
+
+#[cfg(root_fs = "ext4_rs")]
+impl crate::iosched::RequestDevice for MockDisk {
+    fn read_range(&self, offset: usize, buf: &mut [u8]) {
+        self.read_at(offset, buf);
+    }
+
+    fn write_range(&self, offset: usize, buf: &[u8]) {
+        self.write_at(offset, buf);
+    }
+}
+
+#[cfg(feature = "std")]
+impl crate::sys::BlockDriver for MockDisk {
+    fn read_blocks(&self, block: usize, buf: &mut [u8]) {
+        self.read_at(block * BLOCK_SIZE, buf);
+    }
+
+    fn write_blocks(&self, block: usize, buf: &[u8]) {
+        self.write_at(block * BLOCK_SIZE, buf);
+    }
+
+    fn capacity(&self) -> usize {
+        self.size()
+    }
+}
+
+/// The latency of an async request, pending for left polls. The waker is
+/// woken at each pending poll, like the Latency of LatencyDisk.
+#[cfg(feature = "async")]
+struct Delay {
+    left: usize,
+}
+
+#[cfg(feature = "async")]
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.left > 0 {
+            self.left -= 1;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        Poll::Ready(())
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncBlockDevice for MockDisk {
+    fn read_blocks_async<'a>(&'a self, block: usize, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            Delay { left: self.latency }.await;
+            self.read_at(block * BLOCK_SIZE, buf);
+        })
+    }
+
+    fn write_blocks_async<'a>(&'a self, block: usize, buf: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            Delay { left: self.latency }.await;
+            self.write_at(block * BLOCK_SIZE, buf);
+        })
+    }
+}
+
+/// The allocator of System counting the allocated bytes, see the module.
+#[cfg(feature = "std")]
+pub struct CountingAlloc;
+
+#[cfg(feature = "std")]
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+#[cfg(feature = "std")]
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+#[cfg(feature = "std")]
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+#[cfg(feature = "std")]
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "std")]
+impl CountingAlloc {
+    fn grow(bytes: usize) {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        let now = ALLOCATED.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        PEAK.fetch_max(now, Ordering::Relaxed);
+    }
+
+    /// Whether the allocations go through a CountingAlloc.
+    pub fn installed() -> bool {
+        INSTALLED.load(Ordering::Relaxed)
+    }
+
+    /// The bytes allocated now.
+    pub fn allocated() -> usize {
+        ALLOCATED.load(Ordering::Relaxed)
+    }
+
+    /// The allocations and the growing reallocations made so far, the
+    /// ones of the other threads count too.
+    pub fn allocations() -> usize {
+        ALLOCATIONS.load(Ordering::Relaxed)
+    }
+
+    /// Start a measure, the peak is the bytes allocated now, which are
+    /// returned.
+    pub fn reset_peak() -> usize {
+        let now = ALLOCATED.load(Ordering::Relaxed);
+        PEAK.store(now, Ordering::Relaxed);
+        now
+    }
+
+    /// The most bytes allocated since reset_peak, the allocations of the
+    /// other threads count too.
+    pub fn peak() -> usize {
+        PEAK.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(feature = "std")]
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        INSTALLED.store(true, Ordering::Relaxed);
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            Self::grow(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = unsafe { System.realloc(ptr, layout, new_size) };
+        if !new.is_null() {
+            match new_size >= layout.size() {
+                true => Self::grow(new_size - layout.size()),
+                false => {
+                    ALLOCATED.fetch_sub(layout.size() - new_size, Ordering::Relaxed);
+                }
+            }
+        }
+        new
+    }
+}
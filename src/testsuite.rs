@@ -0,0 +1,374 @@
+// The conformance tests of the INodeInterface contract, shared by every
+// filesystem. tests/conformance.rs runs them against tmpfs and the ext4
+// volumes in memory of run_ext4_ram, with the standalone cases of the
+// files of testsuite/ that take no device. Caps lists the features the shim doesn't have so their
+// cases are skipped instead of failing, and the capabilities of
+// capabilities.rs take away the cases of the links it can't make. The
+// cases work in their own directories under CONFORMANCE_DIR and leave
+// them behind.
+// TODO: cover rename when INodeInterface has it, and the concurrent
+// accesses when the suite can spawn threads.
+
+use core::{
+    fmt::Debug,
+    ops::BitOr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+use vfscore::{
+    FileSystem, FileType, INodeInterface, OpenFlags, PollEvent, Stat, StatFS, StatMode, VfsError,
+    VfsResult,
+};
+
+use crate::capabilities::{self, FsCapabilities};
+use crate::error::{syscall_result, Errno, ErrorContext, FsError, FsResult};
+use crate::handle::FileHandle;
+use crate::ops::{
+    dirent64_reclen, disk_usage, fill_dirents64, fill_dirents64_at, hashed_ino, name_from_bytes,
+    remove_dir_all, NAME_MAX,
+};
+use crate::sys::Mutex;
+use crate::trace::{self as tracing, TraceEvent, TraceOp};
+use crate::walk::WalkDir;
+use crate::File;
+
+/// The directory of the suite in the root of the filesystem.
+pub const CONFORMANCE_DIR: &str = "conformance";
+
+/// The optional features of a filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Caps(u32);
+
+impl Caps {
+    pub const NONE: Self = Self(0);
+    pub const TRUNCATE: Self = Self(1 << 0);
+    /// remove of the files.
+    pub const REMOVE: Self = Self(1 << 1);
+    pub const RMDIR: Self = Self(1 << 2);
+    /// The open files keep their data after their last link is removed.
+    pub const UNLINK_OPEN: Self = Self(1 << 3);
+    pub const SYMLINK: Self = Self(1 << 4);
+    pub const HARD_LINK: Self = Self(1 << 5);
+    /// The positions of readdir::read_dir_at stay valid while the
+    /// directory changes.
+    pub const DIR_POSITIONS: Self = Self(1 << 6);
+    pub const ALL: Self = Self((1 << 7) - 1);
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    /// Like |, in the constants.
+    pub const fn with(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// The caps which the capabilities of the filesystem allow, the links
+    /// need SYMLINKS and HARD_LINKS.
+    pub const fn of_capabilities(capabilities: FsCapabilities) -> Self {
+        let mut caps = Self::ALL;
+        if !capabilities.contains(FsCapabilities::SYMLINKS) {
+            caps = caps.without(Self::SYMLINK);
+        }
+        if !capabilities.contains(FsCapabilities::HARD_LINKS) {
+            caps = caps.without(Self::HARD_LINK);
+        }
+        caps
+    }
+}
+
+impl BitOr for Caps {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// A failed case of the suite.
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub case: &'static str,
+    pub reason: String,
+}
+
+type CaseResult = Result<(), String>;
+
+/// The cases, with the features they need.
+const CASES: &[(&str, Caps, fn(&File) -> CaseResult)] = &[
+    ("read_write", Caps::NONE, read_write),
+    ("truncate", Caps::TRUNCATE, truncate),
+    ("read_dir", Caps::NONE, read_dir),
+    (
+        "dir_positions",
+        Caps::DIR_POSITIONS.with(Caps::REMOVE),
+        dir_positions,
+    ),
+    ("dir_snapshot", Caps::REMOVE, dir_snapshot),
+    ("stat", Caps::NONE, stat),
+    ("lookup", Caps::NONE, lookup),
+    ("errors", Caps::NONE, errors),
+    ("remove", Caps::REMOVE, remove),
+    ("rmdir", Caps::RMDIR, rmdir),
+    ("unlink_open", Caps::UNLINK_OPEN, unlink_open),
+    ("symlink", Caps::SYMLINK, symlink),
+    ("hard_link", Caps::HARD_LINK, hard_link),
+    ("trace", Caps::NONE, trace),
+    ("shared_offset", Caps::NONE, shared_offset),
+    (
+        "remove_dir_all",
+        Caps::REMOVE.with(Caps::RMDIR),
+        remove_tree,
+    ),
+    ("walk", Caps::NONE, walk),
+    ("disk_usage", Caps::NONE, usage),
+    ("errno", Caps::NONE, errno),
+    ("negative_dentries", Caps::NONE, negative_dentries),
+    ("exclusive_create", Caps::NONE, exclusive_create),
+    ("open_symlink", Caps::SYMLINK, open_symlink),
+    ("lstat", Caps::SYMLINK, lstat),
+    ("sticky", Caps::REMOVE.with(Caps::RMDIR), sticky),
+    (
+        "protected_links",
+        Caps::SYMLINK.with(Caps::HARD_LINK),
+        protected_links,
+    ),
+    ("proc_pid", Caps::NONE, proc_pid),
+    ("mem_devices", Caps::NONE, mem_devices),
+    ("mount_crossing", Caps::NONE, mount_crossing),
+    ("punch_hole", Caps::NONE, punch_hole),
+    ("allocate", Caps::NONE, allocate),
+];
+
+/// Run the suite in the root of fs, return the failed cases. fs must be
+/// empty and writable. root_dir takes a static filesystem, so fs is leaked
+/// like the mounted filesystems.
+pub fn run(fs: Arc<dyn FileSystem>, caps: Caps) -> Vec<Failure> {
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(fs));
+    let caps = Caps(caps.0 & Caps::of_capabilities(capabilities::capabilities(fs)).0);
+    let mut failures = Vec::new();
+    let base = match fs.root_dir().mkdir(CONFORMANCE_DIR) {
+        Ok(base) => base,
+        Err(err) => {
+            failures.push(Failure {
+                case: "setup",
+                reason: format!("mkdir {}: {:?}", CONFORMANCE_DIR, err),
+            });
+            return failures;
+        }
+    };
+    for &(case, needs, f) in CASES.iter() {
+        if !caps.contains(needs) {
+            info!("conformance {}: skipped", case);
+            continue;
+        }
+        let r = ok("mkdir", base.mkdir(case)).and_then(|dir| f(&dir));
+        if let Err(reason) = r {
+            log::error!("conformance {} of {}: {}", case, fs.name(), reason);
+            failures.push(Failure { case, reason });
+        }
+    }
+    info!(
+        "conformance of {}: {} cases, {} failed",
+        fs.name(),
+        CASES.len(),
+        failures.len()
+    );
+    failures
+}
+
+/// The offset of the ext4 volume of run_ext4_ram in its RamDevice.
+#[cfg(root_fs = "ext4_rs")]
+const EXT4_RAM_START: usize = 1 << 20;
+
+/// Run the suite on a fresh ext4 volume of size bytes in memory, see
+/// ram_ext4. No device of sys is used.
+#[cfg(root_fs = "ext4_rs")]
+pub fn run_ext4_ram(size: usize, caps: Caps) -> Vec<Failure> {
+    match ram_ext4(size, *b"Byte-OS ext4mkfs") {
+        Ok(fs) => run(fs, caps),
+        Err(reason) => vec![Failure {
+            case: "setup",
+            reason,
+        }],
+    }
+}
+
+/// Run the suite on a fresh ext4 volume of size bytes in memory with the
+/// 64bit feature, like mke2fs -O 64bit: the group descriptors are 64 bytes
+/// with the hi halves.
+#[cfg(root_fs = "ext4_rs")]
+pub fn run_ext4_ram_64bit(size: usize, caps: Caps) -> Vec<Failure> {
+    let options = crate::ext4_mkfs::Options {
+        bit64: true,
+        uuid: *b"Byte-OS ext4-64b",
+        ..Default::default()
+    };
+    let fs = ram_ext4_image(size, &options)
+        .and_then(|(_, device)| ok("mount", crate::Ext4FileSystem::new_from_device(device)));
+    match fs {
+        Ok(fs) => run(fs, caps),
+        Err(reason) => vec![Failure {
+            case: "setup",
+            reason,
+        }],
+    }
+}
+
+/// Mount a fresh ext4 volume of size bytes with the uuid, see
+/// ram_ext4_device.
+#[cfg(root_fs = "ext4_rs")]
+fn ram_ext4(size: usize, uuid: [u8; 16]) -> Result<Arc<crate::Ext4FileSystem>, String> {
+    let device = ram_ext4_device(size, uuid)?;
+    ok("mount", crate::Ext4FileSystem::new_from_device(device))
+}
+
+/// Format a fresh ext4 volume of size bytes with the uuid. It's formatted
+/// by ext4_mkfs in a RamDevice after EXT4_RAM_START bytes, and returned
+/// as a Partition over a CachedDevice, so the decorators of blockdev are
+/// under the tests too.
+#[cfg(root_fs = "ext4_rs")]
+fn ram_ext4_device(size: usize, uuid: [u8; 16]) -> Result<Arc<crate::blockdev::Partition>, String> {
+    let options = crate::ext4_mkfs::Options {
+        uuid,
+        ..Default::default()
+    };
+    Ok(ram_ext4_image(size, &options)?.1)
+}
+
+/// The RamDevice under a ram_ext4_device, for the tests which look at the
+/// raw image.
+#[cfg(root_fs = "ext4_rs")]
+type RamImage = (
+    Arc<crate::blockdev::RamDevice>,
+    Arc<crate::blockdev::Partition>,
+);
+
+#[cfg(root_fs = "ext4_rs")]
+fn ram_ext4_image(size: usize, options: &crate::ext4_mkfs::Options) -> Result<RamImage, String> {
+    use crate::blockdev::{BlockDevice, CachedDevice, Partition, RamDevice};
+    use crate::ext4_mkfs::format;
+
+    let ram = Arc::new(RamDevice::new(EXT4_RAM_START + size));
+    ok(
+        "format",
+        format(size as u64, options, |block, data| {
+            ram.write_offset(EXT4_RAM_START + block as usize * options.block_size, data)
+        }),
+    )?;
+    let cached = Arc::new(CachedDevice::new(ram.clone(), 64));
+    Ok((ram, Arc::new(Partition::new(cached, EXT4_RAM_START, size))))
+}
+
+/// Fail the case if the condition doesn't hold.
+macro_rules! ensure {
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            return Err(format!($($arg)+));
+        }
+    };
+}
+
+/// Fail the case unless the operation fails with the error.
+macro_rules! ensure_err {
+    ($op:expr, $err:pat) => {
+        match $op {
+            Err($err) => {}
+            Err(err) => return Err(format!("{}: {:?}", stringify!($op), err)),
+            Ok(_) => return Err(format!("{}: succeeded", stringify!($op))),
+        }
+    };
+}
+
+/// Fail the case unless the operation fails with the errno, of a VfsError
+/// or of an FsError.
+macro_rules! ensure_errno {
+    ($op:expr, $errno:expr) => {
+        match $op {
+            Err(err) if Errno::from(err.clone()) == $errno => {}
+            Err(err) => return Err(format!("{}: {:?}", stringify!($op), err)),
+            Ok(_) => return Err(format!("{}: succeeded", stringify!($op))),
+        }
+    };
+}
+
+/// Fail the case with the operation if it fails.
+fn ok<T, E: Debug>(what: &str, r: Result<T, E>) -> Result<T, String> {
+    r.map_err(|err| format!("{}: {:?}", what, err))
+}
+
+fn read_all(file: &File, len: usize) -> Result<Vec<u8>, String> {
+    let mut buf = vec![0; len];
+    let n = ok("readat", file.readat(0, &mut buf))?;
+    buf.truncate(n);
+    Ok(buf)
+}
+
+fn size(file: &File) -> Result<usize, String> {
+    Ok(ok("metadata", file.metadata())?.size)
+}
+
+fn names(dir: &File) -> Result<Vec<String>, String> {
+    let entries = ok("read_dir", dir.read_dir())?;
+    Ok(entries.into_iter().map(|x| x.filename).collect())
+}
+
+// The cases by the feature they cover, after the macros they use.
+mod caps;
+#[cfg(root_fs = "ext4_rs")]
+mod crash;
+mod dentry;
+mod dirs;
+#[cfg(root_fs = "ext4_rs")]
+mod ext4_dir;
+#[cfg(root_fs = "ext4_rs")]
+mod ext4_inode;
+#[cfg(root_fs = "ext4_rs")]
+mod ext4_io;
+#[cfg(root_fs = "ext4_rs")]
+mod ext4_mount;
+#[cfg(root_fs = "fat32")]
+mod fat;
+mod files;
+mod io;
+mod layout;
+mod mapping;
+mod model;
+mod mounts;
+mod pipe;
+mod pseudo;
+#[cfg(feature = "std")]
+mod races;
+mod rename;
+
+pub use caps::*;
+#[cfg(root_fs = "ext4_rs")]
+pub use crash::*;
+pub use dentry::*;
+pub use dirs::*;
+#[cfg(root_fs = "ext4_rs")]
+pub use ext4_dir::*;
+#[cfg(root_fs = "ext4_rs")]
+pub use ext4_inode::*;
+#[cfg(root_fs = "ext4_rs")]
+pub use ext4_io::*;
+#[cfg(root_fs = "ext4_rs")]
+pub use ext4_mount::*;
+#[cfg(root_fs = "fat32")]
+pub use fat::*;
+use files::*;
+pub use io::*;
+pub use layout::*;
+pub use mapping::*;
+pub use model::*;
+pub use mounts::*;
+pub use pipe::*;
+pub use pseudo::*;
+#[cfg(feature = "std")]
+pub use races::*;
+pub use rename::*;
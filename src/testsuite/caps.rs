@@ -0,0 +1,364 @@
+// The cases of capabilities.rs: the probed capabilities of the
+// filesystems, and the fallbacks of the modules for the nodes without
+// them.
+
+use super::*;
+
+/// The capabilities seen by trying them in dir, the entries made are
+/// removed. XATTRS can't be tried, INodeInterface has no xattrs.
+fn probe_capabilities(dir: &File) -> Result<FsCapabilities, String> {
+    use crate::fallocate;
+    use crate::mknod::{mknod, S_IFIFO};
+
+    let supported = |what: &str, r: VfsResult<()>| match r {
+        Ok(()) => Ok(true),
+        Err(VfsError::NotSupported) => Ok(false),
+        Err(err) => Err(format!("{}: {:?}", what, err)),
+    };
+    let Ok(file) = dir.touch("probe") else {
+        // nothing else can be made.
+        return Ok(FsCapabilities::NONE);
+    };
+    let mut observed = FsCapabilities::WRITE;
+    let mut add = |capability, seen: bool| {
+        if seen {
+            observed = observed | capability;
+        }
+    };
+    add(
+        FsCapabilities::SYMLINKS,
+        supported("sym_link", dir.sym_link("probe-symlink", "probe"))?,
+    );
+    add(
+        FsCapabilities::HARD_LINKS,
+        supported("link", dir.link("probe-link", file.clone()))?,
+    );
+    ok("write", file.writeat(1 << 20, &[1]))?;
+    let mut stat = Stat::default();
+    ok("stat", file.stat(&mut stat))?;
+    add(
+        FsCapabilities::SPARSE_FILES,
+        (stat.blocks as usize) * 512 < 1 << 19,
+    );
+    let chown = crate::owner::chown(&file, 1234, 56).map_err(VfsError::from);
+    let chown = supported("chown", chown)?;
+    ok("stat", file.stat(&mut stat))?;
+    add(
+        FsCapabilities::OWNERSHIP,
+        chown && (stat.uid, stat.gid) == (1234, 56),
+    );
+    add(
+        FsCapabilities::PUNCH_HOLE,
+        supported("punch_hole", fallocate::punch_hole(&file, 0, 4096))?,
+    );
+    add(
+        FsCapabilities::PREALLOCATE,
+        supported("allocate", fallocate::allocate(&file, 0, 4096, false))?,
+    );
+    let clone = ok("touch", dir.touch("probe-clone"))?;
+    add(
+        FsCapabilities::REFLINK,
+        supported(
+            "clone",
+            crate::reflink::clone_from(&clone, &file).map_err(VfsError::from),
+        )?,
+    );
+    let fifo = mknod(dir, "probe-fifo", S_IFIFO | 0o644, 0).map(|_| ());
+    let fifo = fifo.map_err(VfsError::from);
+    add(FsCapabilities::SPECIAL_FILES, supported("mknod", fifo)?);
+    add(FsCapabilities::CASEFOLD, dir.lookup("PROBE").is_ok());
+    let renamed = crate::rename::rename(dir, "probe", dir, "probe-renamed", Default::default())
+        .map_err(VfsError::from);
+    add(FsCapabilities::RENAME, supported("rename", renamed)?);
+    for name in [
+        "probe",
+        "probe-renamed",
+        "probe-symlink",
+        "probe-link",
+        "probe-fifo",
+        "probe-clone",
+    ] {
+        match dir.remove(name) {
+            Ok(()) | Err(VfsError::FileNotFound) => {}
+            Err(err) => return Err(format!("remove {}: {:?}", name, err)),
+        }
+    }
+    Ok(observed)
+}
+
+/// Check the declared capabilities of the filesystem against those seen
+/// in its root, XATTRS aside.
+pub(super) fn check_capabilities(fs: &'static Arc<dyn FileSystem>) -> Result<(), String> {
+    let declared = capabilities::capabilities(fs).without(FsCapabilities::XATTRS);
+    let observed = probe_capabilities(&fs.root_dir())?;
+    ensure!(
+        declared == observed,
+        "{} declares {:#x} but has {:#x}",
+        fs.name(),
+        declared.bits(),
+        observed.bits()
+    );
+    Ok(())
+}
+
+/// The capabilities of tmpfs are those it shows, and the conformance
+/// suite takes away the cases of the links it hasn't.
+pub fn tmpfs_capabilities() -> Result<(), String> {
+    use crate::tmpfs::TmpFs;
+
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>));
+    ensure!(
+        capabilities::capabilities(fs) == FsCapabilities::TMPFS,
+        "tmpfs declares {:#x}",
+        capabilities::capabilities(fs).bits()
+    );
+    check_capabilities(fs)?;
+    let caps = Caps::of_capabilities(FsCapabilities::TMPFS);
+    ensure!(
+        !caps.contains(Caps::SYMLINK) && !caps.contains(Caps::HARD_LINK),
+        "the suite runs the links on tmpfs"
+    );
+    Ok(())
+}
+
+/// A backend without the native holes: the nodes of a TmpFs behind a
+/// wrapper, whose files allocate through the tmpfs and leave the punches
+/// to the fallbacks of fallocate.rs.
+pub(crate) struct Crippled(File);
+
+impl Crippled {
+    fn wrap(node: File) -> File {
+        Arc::new(Self(node))
+    }
+}
+
+impl crate::node::FsNode for Crippled {
+    fn as_space(&self) -> Option<&dyn crate::fallocate::SpaceINode> {
+        Some(self)
+    }
+}
+
+impl crate::fallocate::SpaceINode for Crippled {
+    fn support(&self, op: crate::fallocate::SpaceOp) -> crate::fallocate::Support {
+        use crate::fallocate::{SpaceOp, Support};
+
+        match op {
+            SpaceOp::PunchHole => Support::Unsupported,
+            SpaceOp::Allocate => Support::Native,
+        }
+    }
+
+    fn punch_hole(&self, _offset: usize, _len: usize) -> VfsResult<()> {
+        Err(VfsError::NotSupported)
+    }
+
+    fn allocate(&self, offset: usize, len: usize, keep_size: bool) -> VfsResult<()> {
+        crate::fallocate::allocate(&self.0, offset, len, keep_size)
+    }
+}
+
+impl INodeInterface for Crippled {
+    fn readat(&self, offset: usize, buffer: &mut [u8]) -> VfsResult<usize> {
+        self.0.readat(offset, buffer)
+    }
+
+    fn writeat(&self, offset: usize, buffer: &[u8]) -> VfsResult<usize> {
+        self.0.writeat(offset, buffer)
+    }
+
+    fn truncate(&self, size: usize) -> VfsResult<()> {
+        self.0.truncate(size)
+    }
+
+    fn mkdir(&self, name: &str) -> VfsResult<File> {
+        self.0.mkdir(name).map(Self::wrap)
+    }
+
+    fn touch(&self, name: &str) -> VfsResult<File> {
+        self.0.touch(name).map(Self::wrap)
+    }
+
+    fn open(&self, name: &str, flags: OpenFlags) -> VfsResult<File> {
+        self.0.open(name, flags).map(Self::wrap)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<File> {
+        self.0.lookup(name).map(Self::wrap)
+    }
+
+    fn rmdir(&self, name: &str) -> VfsResult<()> {
+        self.0.rmdir(name)
+    }
+
+    fn remove(&self, name: &str) -> VfsResult<()> {
+        self.0.remove(name)
+    }
+
+    fn read_dir(&self) -> VfsResult<Vec<vfscore::DirEntry>> {
+        self.0.read_dir()
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        self.0.flush()
+    }
+
+    fn metadata(&self) -> VfsResult<vfscore::Metadata> {
+        self.0.metadata()
+    }
+
+    fn stat(&self, stat: &mut Stat) -> VfsResult<()> {
+        self.0.stat(stat)
+    }
+
+    /// Not the f_type of tmpfs, the capabilities are those registered.
+    fn statfs(&self, statfs: &mut StatFS) -> VfsResult<()> {
+        self.0.statfs(statfs)?;
+        statfs.ftype = 0;
+        Ok(())
+    }
+
+    fn utimes(&self, times: &mut [vfscore::TimeSpec]) -> VfsResult<()> {
+        self.0.utimes(times)
+    }
+}
+
+pub(super) struct CrippledFs(&'static Arc<dyn FileSystem>);
+
+impl FileSystem for CrippledFs {
+    fn name(&self) -> &str {
+        "crippled"
+    }
+
+    fn root_dir(&'static self) -> File {
+        Crippled::wrap(self.0.root_dir())
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        self.0.flush()
+    }
+}
+
+/// The fallbacks of fallocate.rs on a backend without the native holes:
+/// the suite passes on it, the punches are emulated and the allocations
+/// native, the emulated punch keeps the blocks where the tmpfs frees
+/// them, and with the punches refused the capabilities lose PUNCH_HOLE
+/// and the punch fails with NotSupported while tmpfs still punches.
+pub fn crippled_fallbacks() -> Result<(), String> {
+    use crate::fallocate::{self, Fallback, SpaceOp, Support};
+    use crate::tmpfs::TmpFs;
+
+    let tmp: &'static Arc<dyn FileSystem> =
+        Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>));
+    let fs: Arc<dyn FileSystem> = Arc::new(CrippledFs(tmp));
+    capabilities::register(&fs, FsCapabilities::WRITE | FsCapabilities::SPARSE_FILES);
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(fs));
+    let caps = Caps::TRUNCATE.with(Caps::REMOVE).with(Caps::RMDIR);
+    let failures = run(fs.clone(), caps);
+    ensure!(failures.is_empty(), "the suite failed: {:?}", failures);
+    let space = FsCapabilities::PUNCH_HOLE | FsCapabilities::PREALLOCATE;
+    ensure!(
+        capabilities::capabilities(fs).contains(space),
+        "the crippled backend declares {:#x}",
+        capabilities::capabilities(fs).bits()
+    );
+    check_capabilities(fs)?;
+
+    let data = vec![7; 8 * 4096];
+    let file = ok("touch", fs.root_dir().touch("file"))?;
+    let native = ok("touch", tmp.root_dir().touch("native"))?;
+    let blocks = |file: &File| -> Result<u64, String> {
+        let mut stat = Stat::default();
+        ok("stat", file.stat(&mut stat))?;
+        Ok(stat.blocks as u64)
+    };
+    for (node, punch) in [(&file, Support::Emulated), (&native, Support::Native)] {
+        let handle: File = FileHandle::new(node.clone(), OpenFlags::O_RDWR);
+        ensure!(
+            fallocate::support(&handle, SpaceOp::PunchHole) == punch
+                && fallocate::support(&handle, SpaceOp::Allocate) == Support::Native,
+            "the support of the punches isn't {:?}",
+            punch
+        );
+        ok("writeat", handle.writeat(0, &data))?;
+        let before = blocks(node)?;
+        ok("punch_hole", fallocate::punch_hole(&handle, 4096, 4 * 4096))?;
+        let after = blocks(node)?;
+        ensure!(
+            read_all(node, data.len())?[4096..5 * 4096]
+                .iter()
+                .all(|x| *x == 0),
+            "the {:?} punch doesn't read zeros",
+            punch
+        );
+        match punch {
+            Support::Native => ensure!(after < before, "the native punch kept the pages"),
+            _ => ensure!(after == before, "the emulated punch freed blocks"),
+        }
+    }
+
+    let old = fallocate::set_policy(SpaceOp::PunchHole, Fallback::Refuse);
+    let refused = || -> Result<(), String> {
+        let declared = capabilities::capabilities(fs);
+        ensure!(
+            !declared.contains(FsCapabilities::PUNCH_HOLE)
+                && declared.contains(FsCapabilities::PREALLOCATE),
+            "the refused punches are declared: {:#x}",
+            declared.bits()
+        );
+        ensure!(
+            capabilities::capabilities(tmp).contains(space),
+            "tmpfs lost its native punches"
+        );
+        ensure!(
+            fallocate::support(&file, SpaceOp::PunchHole) == Support::Unsupported,
+            "the refused punch is supported"
+        );
+        let err = fallocate::punch_hole(&file, 0, 4096).err();
+        ensure!(
+            err.is_some_and(|x| Errno::from(x) == Errno::EOPNOTSUPP),
+            "the refused punch isn't EOPNOTSUPP"
+        );
+        ok("punch_hole", fallocate::punch_hole(&native, 0, 4096))?;
+        check_capabilities(fs)
+    };
+    let r = refused();
+    fallocate::set_policy(SpaceOp::PunchHole, old);
+    r
+}
+
+/// The capabilities of ext4 are those it shows, and a read-only mount
+/// can't write.
+#[cfg(root_fs = "ext4_rs")]
+pub fn ext4_capabilities() -> Result<(), String> {
+    let fs = ram_ext4(16 << 20, *b"ext4-caps-probe!")?;
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(fs as Arc<dyn FileSystem>));
+    ensure!(
+        capabilities::capabilities(fs) == capabilities::with_fallbacks(FsCapabilities::EXT4),
+        "ext4 declares {:#x}",
+        capabilities::capabilities(fs).bits()
+    );
+    check_capabilities(fs)?;
+
+    let device = ram_ext4_device(8 << 20, *b"ext4-caps-rdonly")?;
+    let ro = ok(
+        "mount",
+        crate::Ext4FileSystem::builder_from_device(device)
+            .read_only(true)
+            .mount(),
+    )?;
+    let ro: &'static Arc<dyn FileSystem> = Box::leak(Box::new(ro as Arc<dyn FileSystem>));
+    let declared = capabilities::capabilities(ro);
+    ensure!(
+        declared == FsCapabilities::EXT4.without(FsCapabilities::WRITE),
+        "a read-only ext4 declares {:#x}",
+        declared.bits()
+    );
+    // nothing can be tried without writing.
+    let observed = probe_capabilities(&ro.root_dir())?;
+    ensure!(
+        observed == FsCapabilities::NONE,
+        "a read-only ext4 has {:#x}",
+        observed.bits()
+    );
+    Ok(())
+}
@@ -0,0 +1,424 @@
+// The crash cases of ext4: the workloads crash.rs cuts the power of, and
+// the power cuts of the MockDisk.
+
+use super::*;
+
+/// Cut the power of an ext4 volume on a MockDisk after a clean point: the
+/// writes after the cut are dropped and the image stays the one of the
+/// cut, and the remount has the files of before the cut, none of after
+/// it, and passes check.
+pub fn ext4_power_cut() -> Result<(), String> {
+    use crate::ext4_mkfs::{format, Options};
+    use crate::testing::{MockDisk, MockOp, Outcome};
+
+    const SIZE: usize = 8 << 20;
+    let options = Options {
+        uuid: *b"ext4-power-cut-t",
+        ..Default::default()
+    };
+    let disk = Arc::new(MockDisk::new(SIZE, 512));
+    ok(
+        "format",
+        format(SIZE as u64, &options, |block, data| {
+            disk.write_at(block as usize * options.block_size, data)
+        }),
+    )?;
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::new_from_device(disk.clone()),
+    )?;
+    let root = fs.root();
+    let kept = ok("touch", root.touch("kept"))?;
+    ok("writeat", kept.writeat(0, b"before the cut"))?;
+    ok("flush", kept.flush())?;
+    // check syncs the group descriptors, the image is clean at the cut.
+    let report = fs.check();
+    ensure!(
+        report.problems.is_empty(),
+        "the image has problems before the cut: {:?}",
+        report.problems
+    );
+    let snapshot = disk.image();
+    let cut = disk.writes() as usize;
+    disk.cut_power();
+    // the shim reads the image of the cut from now on, so these may fail.
+    let after = || -> VfsResult<()> {
+        root.mkdir("lost")?;
+        let lost = root.touch("lost.txt")?;
+        lost.writeat(0, &[7; 0x3000])?;
+        lost.flush()?;
+        kept.writeat(0, b"after")?;
+        kept.flush()
+    };
+    let _ = after();
+    drop((kept, root, fs));
+
+    let log = disk.log();
+    let writes: Vec<_> = log.iter().filter(|x| x.op == MockOp::Write).collect();
+    ensure!(writes.len() > cut, "nothing was written after the cut");
+    ensure!(
+        writes[..cut].iter().all(|x| x.outcome == Outcome::Done)
+            && writes[cut..].iter().all(|x| x.outcome == Outcome::Dropped),
+        "the writes aren't dropped from the cut"
+    );
+    ensure!(
+        log.windows(2).all(|x| x[0].seq < x[1].seq),
+        "the log is out of order"
+    );
+    ensure!(disk.image() == snapshot, "the image changed after the cut");
+
+    disk.restore_power();
+    let fs = ok(
+        "remount",
+        crate::Ext4FileSystem::new_from_device(disk.clone()),
+    )?;
+    let root = fs.root();
+    ensure!(
+        read_all(&ok("lookup", root.lookup("kept"))?, 64)? == b"before the cut",
+        "the file of before the cut changed"
+    );
+    ensure_err!(root.lookup("lost"), VfsError::FileNotFound);
+    ensure_err!(root.lookup("lost.txt"), VfsError::FileNotFound);
+    let report = fs.check();
+    ensure!(
+        report.problems.is_empty(),
+        "the image has problems after the cut: {:?}",
+        report.problems
+    );
+    Ok(())
+}
+
+/// The ext4 volumes of the crash tests, mounted on the MockDisks of the
+/// images. bitmap_lag drops the problems of the bitmaps out of date.
+/// barriers sets the MockDisk as the DeviceFlush of the mount, so the
+/// journal knows of its write cache, with FUA writes if fua.
+struct Ext4Crash {
+    fs: Option<Arc<crate::Ext4FileSystem>>,
+    bitmap_lag: bool,
+    policy: crate::fsync::SyncPolicy,
+    barriers: bool,
+    fua: bool,
+}
+
+impl crate::crash::CrashTarget for Ext4Crash {
+    fn mount(&mut self, disk: Arc<crate::testing::MockDisk>) -> Result<File, String> {
+        let builder = crate::Ext4FileSystem::builder_from_device(disk.clone());
+        let fs = ok("mount", builder.sync_policy(self.policy).mount())?;
+        if self.barriers {
+            disk.set_fua(self.fua);
+            crate::blockdev::set_device_flush(fs.dev(), disk);
+        }
+        let root = fs.root();
+        self.fs = Some(fs);
+        Ok(root)
+    }
+
+    fn sync(&mut self) -> Result<(), String> {
+        match &self.fs {
+            Some(fs) => ok("flush", FileSystem::flush(fs.as_ref())),
+            None => Ok(()),
+        }
+    }
+
+    fn check(&mut self) -> Vec<String> {
+        use crate::ext4_check::Problem;
+
+        let Some(fs) = &self.fs else {
+            return Vec::new();
+        };
+        let lagging = |x: &Problem| {
+            matches!(
+                x,
+                Problem::BlockNotAllocated { .. }
+                    | Problem::BlockLeaked { .. }
+                    | Problem::FreeBlocks { .. }
+                    | Problem::FreeInodes { .. }
+                    | Problem::InodeNotAllocated { .. }
+                    | Problem::InodeLeaked { .. }
+            )
+        };
+        fs.check()
+            .problems
+            .into_iter()
+            .filter(|x| !(self.bitmap_lag && lagging(x)))
+            .map(|x| format!("{:?}", x))
+            .collect()
+    }
+
+    fn unmount(&mut self) {
+        if let Some(fs) = self.fs.take() {
+            let dev = fs.dev();
+            drop(fs);
+            crate::blockdev::remove_device_flush(dev);
+        }
+    }
+}
+
+fn crash_setup_create(root: &File) -> VfsResult<()> {
+    root.mkdir("d").map(drop)
+}
+
+fn crash_touch(root: &File) -> VfsResult<()> {
+    root.lookup("d")?.touch("new").map(drop)
+}
+
+fn crash_write(root: &File) -> VfsResult<()> {
+    let file = root.lookup("d")?.lookup("new")?;
+    file.writeat(0, b"created by the crash test")?;
+    file.flush()
+}
+
+fn crash_setup_rename(root: &File) -> VfsResult<()> {
+    root.mkdir("from")?;
+    root.mkdir("to")?;
+    for (name, len) in [("moved", 0x1800), ("replaced", 0x800)] {
+        let file = root.touch(name)?;
+        file.writeat(0, &crate::golden::pattern(len as u32, 0, len))?;
+        file.flush()?;
+    }
+    Ok(())
+}
+
+/// Move moved to from/moved, then over to/replaced by way of to.
+fn crash_rename_into(root: &File) -> VfsResult<()> {
+    let flags = crate::rename::RenameFlags::NONE;
+    Ok(crate::rename::rename(
+        root,
+        "moved",
+        &root.lookup("from")?,
+        "moved",
+        flags,
+    )?)
+}
+
+fn crash_rename_over(root: &File) -> VfsResult<()> {
+    let from = root.lookup("from")?;
+    let flags = crate::rename::RenameFlags::NONE;
+    Ok(crate::rename::rename(
+        &from, "moved", root, "replaced", flags,
+    )?)
+}
+
+fn crash_setup_append(root: &File) -> VfsResult<()> {
+    let file = root.touch("log")?;
+    file.writeat(0, &crate::golden::pattern(1, 0, 0x1000))?;
+    file.flush()
+}
+
+fn crash_append(root: &File) -> VfsResult<()> {
+    let file = root.lookup("log")?;
+    file.writeat(0x1000, &crate::golden::pattern(1, 0x1000, 0x3000))?;
+    file.flush()
+}
+
+/// The workloads of the ext4 crash tests: a file created then written, a
+/// file renamed into a directory then over another file, and an append.
+const CRASH_WORKLOADS: [crate::crash::Workload; 3] = [
+    crate::crash::Workload {
+        name: "create",
+        setup: crash_setup_create,
+        steps: &[crash_touch, crash_write],
+        atomic: false,
+    },
+    crate::crash::Workload {
+        name: "rename",
+        setup: crash_setup_rename,
+        steps: &[crash_rename_into, crash_rename_over],
+        atomic: true,
+    },
+    crate::crash::Workload {
+        name: "append",
+        setup: crash_setup_append,
+        steps: &[crash_append],
+        atomic: false,
+    },
+];
+
+/// A fresh ext4 image for the crash tests, with a journal of
+/// journal_blocks blocks or none.
+pub(super) fn crash_image(journal_blocks: u32) -> Result<Vec<u8>, String> {
+    use crate::ext4_mkfs::{format, Options};
+
+    const SIZE: usize = 4 << 20;
+    let options = Options {
+        uuid: *b"ext4-crash-tests",
+        journal_blocks,
+        ..Default::default()
+    };
+    let mut image = vec![0; SIZE];
+    ok(
+        "format",
+        format(SIZE as u64, &options, |block, data| {
+            let offset = block as usize * options.block_size;
+            image[offset..offset + data.len()].copy_from_slice(data);
+        }),
+    )?;
+    Ok(image)
+}
+
+/// Crash the workloads on ext4 with a journal after every write: every
+/// prefix mounts, replaying the journal, and has every file in one of the
+/// states of the steps, the renames keep the whole tree in one.
+pub fn ext4_crash_journaled() -> Result<(), String> {
+    use crate::crash::{run, Prefixes};
+
+    let image = crash_image(256)?;
+    let disk = Arc::new(crate::testing::MockDisk::from_image(image.clone(), 512));
+    let fs = ok("mount", crate::Ext4FileSystem::new_from_device(disk))?;
+    ensure!(fs.mount_info().journaled, "the image isn't journaled");
+    drop(fs);
+    // TODO: check the bitmaps too when they're journaled, they're written
+    // back after the transactions so a crash between loses their bits.
+    let mut target = Ext4Crash {
+        fs: None,
+        bitmap_lag: true,
+        policy: Default::default(),
+        barriers: false,
+        fua: false,
+    };
+    for workload in CRASH_WORKLOADS.iter() {
+        let report = run(&mut target, &image, 512, workload, Prefixes::All)?;
+        ensure!(report.writes > 0, "{}: nothing was written", workload.name);
+        ensure!(report.is_consistent(), "{}", report);
+    }
+    Ok(())
+}
+
+/// The same crashes on ext4 without a journal, the writes land in place
+/// so the report only documents what a crash loses. The image before the
+/// steps and the one after all their writes must be clean though.
+pub fn ext4_crash_no_journal() -> Result<(), String> {
+    use crate::crash::{run, Prefixes};
+
+    let image = crash_image(0)?;
+    let mut target = Ext4Crash {
+        fs: None,
+        bitmap_lag: false,
+        policy: Default::default(),
+        barriers: false,
+        fua: false,
+    };
+    for workload in CRASH_WORKLOADS.iter() {
+        let report = run(&mut target, &image, 512, workload, Prefixes::All)?;
+        info!("crash without a journal, {}", report);
+        ensure!(
+            report.prefixes == report.writes + 1,
+            "{}: {} prefixes of {} writes",
+            workload.name,
+            report.prefixes,
+            report.writes
+        );
+        let ends = [0, report.writes];
+        let failures = report.unmountable.iter();
+        let failures = failures
+            .chain(report.problems.iter())
+            .chain(report.torn.iter());
+        for x in failures.filter(|x| ends.contains(&x.prefix)) {
+            return Err(format!(
+                "{}: the image after {} writes fails: {}",
+                workload.name, x.prefix, x.reason
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The same crashes on ext4 with a journal under the strict-ordered and
+/// the write-back policies. The harness syncs after every step, so the
+/// write back commits each step as one transaction.
+pub fn ext4_crash_sync_policies() -> Result<(), String> {
+    use crate::crash::{run, Prefixes};
+    use crate::fsync::SyncPolicy;
+
+    let image = crash_image(256)?;
+    let policies = [
+        SyncPolicy::StrictOrdered,
+        SyncPolicy::WriteBack {
+            max_dirty_blocks: 64,
+            max_age_ticks: 1,
+        },
+    ];
+    for policy in policies {
+        let mut target = Ext4Crash {
+            fs: None,
+            bitmap_lag: true,
+            policy,
+            barriers: false,
+            fua: false,
+        };
+        for workload in CRASH_WORKLOADS.iter() {
+            let report = run(&mut target, &image, 512, workload, Prefixes::All)?;
+            ensure!(report.writes > 0, "{}: nothing was written", workload.name);
+            ensure!(report.is_consistent(), "{:?}: {}", policy, report);
+        }
+    }
+    Ok(())
+}
+
+/// The same crashes on ext4 with a journal when the MockDisk has a
+/// Reordering write cache, see run_reordering. The journal knowing of
+/// the cache orders its commits by flushes, or by FUA writes, and every
+/// image is consistent under each policy. Unaware of it the journal
+/// doesn't flush, a crash loses some writes of a commit and keeps the
+/// others, and some image isn't consistent.
+pub fn ext4_crash_reordering() -> Result<(), String> {
+    use crate::crash::{run_reordering, Prefixes};
+    use crate::fsync::SyncPolicy;
+
+    let image = crash_image(256)?;
+    let seeds = [1, 2, 3];
+    let policies = [
+        SyncPolicy::WriteThrough,
+        SyncPolicy::StrictOrdered,
+        SyncPolicy::WriteBack {
+            max_dirty_blocks: 64,
+            max_age_ticks: 1,
+        },
+    ];
+    for (policy, fua) in policies.into_iter().flat_map(|x| [(x, false), (x, true)]) {
+        let mut target = Ext4Crash {
+            fs: None,
+            bitmap_lag: true,
+            policy,
+            barriers: true,
+            fua,
+        };
+        for workload in CRASH_WORKLOADS.iter() {
+            let report = run_reordering(&mut target, &image, 512, workload, Prefixes::All, &seeds)?;
+            ensure!(report.writes > 0, "{}: nothing was written", workload.name);
+            ensure!(
+                report.prefixes == (report.writes + 1) * seeds.len(),
+                "{}: {} images of {} writes",
+                workload.name,
+                report.prefixes,
+                report.writes
+            );
+            ensure!(
+                report.is_consistent(),
+                "{:?}, fua {}: {}",
+                policy,
+                fua,
+                report
+            );
+        }
+    }
+
+    let mut target = Ext4Crash {
+        fs: None,
+        bitmap_lag: true,
+        policy: SyncPolicy::WriteThrough,
+        barriers: false,
+        fua: false,
+    };
+    let mut inconsistent = 0;
+    for workload in CRASH_WORKLOADS.iter() {
+        let report = run_reordering(&mut target, &image, 512, workload, Prefixes::All, &seeds)?;
+        info!("crash without barriers, {}", report);
+        inconsistent += !report.is_consistent() as usize;
+    }
+    ensure!(
+        inconsistent > 0,
+        "the crashes without barriers are consistent"
+    );
+    Ok(())
+}
@@ -0,0 +1,913 @@
+// The cases of the dentries and the path resolution: the negative
+// dentries, the exclusive creates, the symlinks, the sticky directories
+// and the protected links, the mount crossings and create_with.
+
+use super::*;
+
+/// Check that two ext4 volumes mounted at once are independent: the same
+/// names hold their own data in each, the statfs of one doesn't change
+/// with the writes to the other, and a link across them fails.
+/// A directory counting the opens of its entries.
+struct CountingDir {
+    dir: File,
+    opens: AtomicUsize,
+}
+
+impl INodeInterface for CountingDir {
+    fn open(&self, name: &str, flags: OpenFlags) -> VfsResult<File> {
+        self.opens.fetch_add(1, Ordering::Relaxed);
+        self.dir.open(name, flags)
+    }
+
+    fn touch(&self, name: &str) -> VfsResult<File> {
+        self.dir.touch(name)
+    }
+
+    fn mkdir(&self, name: &str) -> VfsResult<File> {
+        self.dir.mkdir(name)
+    }
+
+    fn metadata(&self) -> VfsResult<vfscore::Metadata> {
+        self.dir.metadata()
+    }
+}
+
+pub(super) fn negative_dentries(dir: &File) -> CaseResult {
+    use crate::dentry::{
+        dentry_open_at, set_negative_budget, DentryNode, ResolveContext, NEGATIVE_BUDGET,
+    };
+
+    let counting = Arc::new(CountingDir {
+        dir: dir.clone(),
+        opens: AtomicUsize::new(0),
+    });
+    let root = Arc::new(DentryNode::new(
+        String::from("/"),
+        counting.clone(),
+        alloc::sync::Weak::new(),
+    ));
+    let ctx = ResolveContext::with_root(root.clone());
+    for _ in 0..100 {
+        ensure_errno!(
+            dentry_open_at(&ctx, "missing", OpenFlags::O_RDONLY),
+            Errno::ENOENT
+        );
+    }
+    let opens = counting.opens.load(Ordering::Relaxed);
+    ensure!(
+        opens == 1,
+        "100 opens of a missing file looked up {} times",
+        opens
+    );
+
+    // a file created by the node of the dentry is visible at once.
+    let handle = FileHandle::new(root.node.clone(), OpenFlags::O_RDWR);
+    ok("touch", handle.touch("missing"))?;
+    ok(
+        "open after touch",
+        dentry_open_at(&ctx, "missing", OpenFlags::O_RDONLY),
+    )?;
+
+    // and so is a file created by an open with O_CREAT.
+    ensure_errno!(
+        dentry_open_at(&ctx, "created", OpenFlags::O_RDONLY),
+        Errno::ENOENT
+    );
+    ok(
+        "open O_CREAT",
+        dentry_open_at(&ctx, "created", OpenFlags::O_RDWR | OpenFlags::O_CREAT),
+    )?;
+    ok(
+        "open after O_CREAT",
+        dentry_open_at(&ctx, "created", OpenFlags::O_RDONLY),
+    )?;
+
+    // beyond the budget the clock evicts a name which wasn't looked up
+    // again, b, and keeps a.
+    set_negative_budget(2);
+    let before = counting.opens.load(Ordering::Relaxed);
+    let missed = ["a", "b", "a", "c", "a", "b"]
+        .iter()
+        .all(|x| dentry_open_at(&ctx, x, OpenFlags::O_RDONLY).is_err());
+    let opens = counting.opens.load(Ordering::Relaxed) - before;
+    set_negative_budget(NEGATIVE_BUDGET);
+    ensure!(missed, "a missing name was opened");
+    ensure!(opens == 4, "the misses looked up {} times", opens);
+    Ok(())
+}
+
+/// O_CREAT with O_EXCL through the dentries: it creates a name cached as
+/// missing and fails with AlreadyExists on a name which exists, cached or
+/// not, a plain O_CREAT opens it. O_EXCL is of the last item of the path.
+/// A lookup failing for another reason than a missing name, or a name
+/// past NAME_MAX, fails the open instead of creating.
+pub(super) fn exclusive_create(dir: &File) -> CaseResult {
+    use crate::dentry::{dentry_open_at, DentryNode, ResolveContext};
+
+    let create = OpenFlags::O_RDWR | OpenFlags::O_CREAT;
+    let exclusive = create | OpenFlags::O_EXCL;
+    ok("touch", dir.touch("uncached"))?;
+    ok("mkdir", dir.mkdir("sub"))?;
+    let root = Arc::new(DentryNode::new(
+        String::from("/"),
+        dir.clone(),
+        alloc::sync::Weak::new(),
+    ));
+    let ctx = ResolveContext::with_root(root);
+    ensure_errno!(dentry_open_at(&ctx, "uncached", exclusive), Errno::EEXIST);
+    ensure_errno!(
+        dentry_open_at(&ctx, "file", OpenFlags::O_RDONLY),
+        Errno::ENOENT
+    );
+    ok("open O_EXCL", dentry_open_at(&ctx, "file", exclusive))?;
+    ensure_errno!(dentry_open_at(&ctx, "file", exclusive), Errno::EEXIST);
+    ok("open O_CREAT", dentry_open_at(&ctx, "file", create))?;
+    ok("lookup", dir.lookup("file"))?;
+    ok("open O_EXCL", dentry_open_at(&ctx, "sub/file", exclusive))?;
+    ensure_errno!(dentry_open_at(&ctx, "sub/file", exclusive), Errno::EEXIST);
+
+    // only a missing name is created, the other failures are returned.
+    let under = dentry_open_at(&ctx, "file/x", OpenFlags::O_RDONLY).map(|_| ());
+    ensure!(
+        under.as_ref().is_err_and(|x| x.errno != Errno::ENOENT),
+        "the open of a name under a file gave {:?}",
+        under
+    );
+    let long = "x".repeat(NAME_MAX + 1);
+    ensure_errno!(dentry_open_at(&ctx, &long, create), Errno::ENAMETOOLONG);
+    ensure_errno!(
+        dentry_open_at(&ctx, &long, OpenFlags::O_RDONLY),
+        Errno::ENAMETOOLONG
+    );
+    Ok(())
+}
+
+/// The opens of a path ending with a symbol link, with and without
+/// O_NOFOLLOW and O_PATH.
+pub(super) fn open_symlink(dir: &File) -> CaseResult {
+    use crate::dentry::{dentry_open_at, Cwd, DentryNode, ResolveContext};
+
+    let target = ok("touch", dir.touch("target"))?;
+    ok("writeat", target.writeat(0, b"target"))?;
+    ok("mkdir", dir.mkdir("sub"))?;
+    ok("sym_link", dir.sym_link("link", "target"))?;
+    ok("sym_link", dir.sym_link("sublink", "sub"))?;
+    let root = Arc::new(DentryNode::new(
+        String::from("/"),
+        dir.clone(),
+        alloc::sync::Weak::new(),
+    ));
+    let ctx = ResolveContext::with_root(root.clone());
+    let open = |path: &str, flags: OpenFlags| -> FsResult<Arc<FileHandle>> {
+        let dentry = dentry_open_at(&ctx, path, flags)?;
+        Ok(FileHandle::from_dentry(&dentry, flags))
+    };
+    let mode = |file: &FileHandle| -> Result<StatMode, String> {
+        let mut stat = Stat::default();
+        ok("stat", file.stat(&mut stat))?;
+        Ok(stat.mode)
+    };
+    let mut buf = [0u8; 16];
+
+    // the last link is followed by default.
+    let file = ok("open link", open("link", OpenFlags::O_RDONLY))?;
+    ensure!(
+        ok("readat", file.readat(0, &mut buf))? == 6,
+        "read through the link"
+    );
+    ensure!(&buf[..6] == b"target", "read {:?}", &buf[..6]);
+
+    // O_NOFOLLOW fails on the link but not on a link in the middle.
+    ensure_errno!(
+        open("link", OpenFlags::O_RDONLY | OpenFlags::O_NOFOLLOW),
+        Errno::ELOOP
+    );
+    ok(
+        "open O_NOFOLLOW",
+        open("target", OpenFlags::O_RDONLY | OpenFlags::O_NOFOLLOW),
+    )?;
+    ok(
+        "touch",
+        dir.open("sub", OpenFlags::NONE)
+            .and_then(|x| x.touch("file")),
+    )?;
+    ok(
+        "open O_NOFOLLOW through a link",
+        open("sublink/file", OpenFlags::O_RDONLY | OpenFlags::O_NOFOLLOW),
+    )?;
+
+    // O_PATH follows the link and can't read the file.
+    let path = ok("open O_PATH", open("link", OpenFlags::O_PATH))?;
+    ensure!(
+        !mode(&path)?.contains(StatMode::LINK),
+        "O_PATH didn't follow the link"
+    );
+    ensure_err!(path.readat(0, &mut buf), VfsError::InvalidInput);
+    ensure_err!(path.writeat(0, b"x"), VfsError::InvalidInput);
+
+    // O_PATH with O_NOFOLLOW opens the link itself.
+    let link = ok(
+        "open O_PATH|O_NOFOLLOW",
+        open("link", OpenFlags::O_PATH | OpenFlags::O_NOFOLLOW),
+    )?;
+    ensure!(
+        mode(&link)?.contains(StatMode::LINK),
+        "the link isn't a link"
+    );
+    ensure!(
+        ok("resolve_link", link.resolve_link())? == "target",
+        "the link target"
+    );
+    ensure_err!(link.readat(0, &mut buf), VfsError::InvalidInput);
+
+    // an O_PATH directory anchors the relative paths but isn't read.
+    let sub = ok(
+        "open O_PATH dir",
+        dentry_open_at(&ctx, "sublink", OpenFlags::O_PATH),
+    )?;
+    let handle = FileHandle::from_dentry(&sub, OpenFlags::O_PATH);
+    ensure!(
+        mode(&handle)?.contains(StatMode::DIR),
+        "O_PATH didn't follow to the dir"
+    );
+    ensure_err!(handle.read_dir(), VfsError::InvalidInput);
+    let anchored = ResolveContext {
+        cwd: Cwd::new(sub, &root),
+        ..ctx.clone()
+    };
+    ok(
+        "open at the O_PATH dir",
+        dentry_open_at(&anchored, "file", OpenFlags::O_RDONLY),
+    )?;
+    Ok(())
+}
+
+/// stat and lstat of a file, a link to it and a dangling link.
+pub(super) fn lstat(dir: &File) -> CaseResult {
+    use crate::dentry::{DentryNode, ResolveContext};
+    use crate::ops::{readlinkat, stat_at};
+
+    let file = ok("touch", dir.touch("file"))?;
+    ok("writeat", file.writeat(0, b"content!"))?;
+    ok("sym_link", dir.sym_link("link", "file"))?;
+    ok("sym_link", dir.sym_link("dead", "nowhere"))?;
+    let root = Arc::new(DentryNode::new(
+        String::from("/"),
+        dir.clone(),
+        alloc::sync::Weak::new(),
+    ));
+    let ctx = ResolveContext::with_root(root);
+    let stat = |path: &str, follow: bool| -> Result<Stat, String> {
+        let mut stat = Stat::default();
+        ok(path, stat_at(&ctx, path, follow, &mut stat))?;
+        Ok(stat)
+    };
+
+    let file = stat("file", true)?;
+    ensure!(
+        file.size == 8 && !file.mode.contains(StatMode::LINK),
+        "stat of the file: {:?} size {}",
+        file.mode,
+        file.size
+    );
+    let lfile = stat("file", false)?;
+    ensure!(lfile.size == 8, "lstat of the file differs from stat");
+
+    let link = stat("link", true)?;
+    ensure!(
+        link.size == 8 && !link.mode.contains(StatMode::LINK),
+        "stat didn't follow the link"
+    );
+    let llink = stat("link", false)?;
+    ensure!(
+        llink.mode.contains(StatMode::LINK),
+        "lstat of the link: {:?}",
+        llink.mode
+    );
+    ensure!(
+        llink.size == 4,
+        "lstat size {} isn't the target length",
+        llink.size
+    );
+    ensure!(llink.nlink == 1, "lstat nlink {}", llink.nlink);
+
+    let mut out = Stat::default();
+    ensure_errno!(stat_at(&ctx, "dead", true, &mut out), Errno::ENOENT);
+    let dead = stat("dead", false)?;
+    ensure!(
+        dead.mode.contains(StatMode::LINK) && dead.size == 7,
+        "lstat of the dangling link: {:?} size {}",
+        dead.mode,
+        dead.size
+    );
+
+    ensure!(
+        ok("readlinkat", readlinkat(&ctx, "link"))? == b"file",
+        "the target of link"
+    );
+    ensure!(
+        ok("readlinkat", readlinkat(&ctx, "dead"))? == b"nowhere",
+        "the target of dead"
+    );
+    ensure_err!(readlinkat(&ctx, "file"), VfsError::InvalidInput);
+    Ok(())
+}
+
+/// A node reporting an owner and extra mode bits, the shims can't chown
+/// or chmod. The entries opened in it are owned by the uids of owners,
+/// and have the permission bits of perms.
+struct Owned {
+    node: File,
+    uid: u32,
+    mode: u32,
+    /// The permission bits replacing those of the node.
+    perm: Option<u32>,
+    owners: Vec<(&'static str, u32)>,
+    perms: Vec<(&'static str, u32)>,
+}
+
+impl Owned {
+    fn new(node: File, uid: u32, mode: u32, owners: &[(&'static str, u32)]) -> Self {
+        Self {
+            node,
+            uid,
+            mode,
+            perm: None,
+            owners: owners.to_vec(),
+            perms: Vec::new(),
+        }
+    }
+
+    fn with_perms(mut self, perms: &[(&'static str, u32)]) -> Self {
+        self.perms = perms.to_vec();
+        self
+    }
+}
+
+impl INodeInterface for Owned {
+    fn open(&self, name: &str, flags: OpenFlags) -> VfsResult<File> {
+        let node = self.node.open(name, flags)?;
+        let uid = self.owners.iter().find(|x| x.0 == name).map_or(0, |x| x.1);
+        let mut owned = Owned::new(node, uid, 0, &[]);
+        owned.perm = self.perms.iter().find(|x| x.0 == name).map(|x| x.1);
+        Ok(Arc::new(owned))
+    }
+
+    fn resolve_link(&self) -> VfsResult<String> {
+        self.node.resolve_link()
+    }
+
+    /// Link the entry of the node under src, found by its inode number.
+    fn link(&self, name: &str, src: File) -> VfsResult<()> {
+        let inode = src.metadata()?.inode;
+        for entry in self.node.read_dir()? {
+            if let Ok(node) = self.node.lookup(&entry.filename)
+                && node.metadata()?.inode == inode
+            {
+                return self.node.link(name, node);
+            }
+        }
+        Err(VfsError::FileNotFound)
+    }
+
+    fn remove(&self, name: &str) -> VfsResult<()> {
+        self.node.remove(name)
+    }
+
+    fn rmdir(&self, name: &str) -> VfsResult<()> {
+        self.node.rmdir(name)
+    }
+
+    fn metadata(&self) -> VfsResult<vfscore::Metadata> {
+        self.node.metadata()
+    }
+
+    fn stat(&self, stat: &mut Stat) -> VfsResult<()> {
+        self.node.stat(stat)?;
+        stat.uid = self.uid;
+        stat.mode |= StatMode::from_bits_truncate(self.mode);
+        if let Some(perm) = self.perm {
+            stat.mode = StatMode::from_bits_truncate(stat.mode.bits() & !0o7777 | perm);
+        }
+        Ok(())
+    }
+}
+
+/// The removals and renames in a sticky directory owned by root: only
+/// the owner of an entry and root may remove it.
+pub(super) fn sticky(dir: &File) -> CaseResult {
+    use crate::dentry::{Cred, DentryNode, ResolveContext};
+    use crate::ops::{renameat, unlinkat, S_ISVTX};
+
+    let tmp = ok("mkdir", dir.mkdir("tmp"))?;
+    for name in ["a", "b", "c", "d"] {
+        ok("touch", tmp.touch(name))?;
+    }
+    ok("mkdir", tmp.mkdir("sub"))?;
+    let owners = [("a", 2), ("b", 2), ("c", 2), ("d", 3), ("sub", 2)];
+    let root = Arc::new(DentryNode::new(
+        String::from("/"),
+        Arc::new(Owned::new(tmp.clone(), 0, S_ISVTX, &owners)),
+        alloc::sync::Weak::new(),
+    ));
+    let root_ctx = ResolveContext::with_root(root);
+    let user = |uid| root_ctx.clone().with_cred(Cred { uid, gid: uid });
+    let exists = |name: &str| tmp.lookup(name).is_ok();
+
+    ensure_errno!(unlinkat(&user(1), "a", false), Errno::EPERM);
+    ensure!(exists("a"), "uid 1 removed the file of uid 2");
+    ok("unlinkat by the owner", unlinkat(&user(2), "a", false))?;
+    ensure!(!exists("a"), "the owner didn't remove the file");
+    ok("unlinkat by root", unlinkat(&root_ctx, "b", false))?;
+    ensure!(!exists("b"), "root didn't remove the file");
+    ensure_errno!(unlinkat(&user(1), "sub", true), Errno::EPERM);
+    ensure_errno!(unlinkat(&user(2), "sub", false), Errno::EISDIR);
+    ensure_errno!(unlinkat(&user(2), "c", true), Errno::ENOTDIR);
+    ok("rmdir by the owner", unlinkat(&user(2), "sub", true))?;
+
+    // rename checks the entry and an existing target, then Owned has no
+    // renames.
+    ensure_errno!(renameat(&user(1), "c", &user(1), "x"), Errno::EPERM);
+    ensure_errno!(renameat(&user(2), "c", &user(2), "d"), Errno::EPERM);
+    ensure_err!(
+        renameat(&user(2), "c", &user(2), "x"),
+        FsError {
+            error: VfsError::NotSupported,
+            ..
+        }
+    );
+
+    // anyone removes the entries of a directory without the sticky bit.
+    let open = Arc::new(DentryNode::new(
+        String::from("/"),
+        Arc::new(Owned::new(tmp.clone(), 0, 0, &owners)),
+        alloc::sync::Weak::new(),
+    ));
+    let open_ctx = ResolveContext::with_root(open).with_cred(Cred { uid: 1, gid: 1 });
+    ok(
+        "unlinkat without the sticky bit",
+        unlinkat(&open_ctx, "c", false),
+    )?;
+    ensure!(!exists("c"), "the file wasn't removed");
+    Ok(())
+}
+
+/// The protected links in a sticky directory writable by anyone and owned
+/// by root: a link of uid 1 isn't followed by uid 2 or root, only by its
+/// owner, and a hard link to a file of uid 2 which uid 1 can't write is
+/// refused to uid 1.
+pub(super) fn protected_links(dir: &File) -> CaseResult {
+    use crate::dentry::{dentry_open_at, Cred, DentryNode, ResolveContext};
+    use crate::ops::{linkat, S_ISVTX};
+
+    let tmp = ok("mkdir", dir.mkdir("protected"))?;
+    let secret = ok("touch", tmp.touch("secret"))?;
+    ok("writeat", secret.writeat(0, b"secret"))?;
+    for name in ["private", "shared", "setuid"] {
+        ok("touch", tmp.touch(name))?;
+    }
+    ok("symlink", tmp.sym_link("planted", "secret"))?;
+    ok("symlink", tmp.sym_link("rooted", "secret"))?;
+    let owners = [("planted", 1), ("private", 2), ("shared", 2), ("setuid", 2)];
+    let perms = [("private", 0o600), ("shared", 0o666), ("setuid", 0o4666)];
+    let root_with = |mode| {
+        let owned = Owned::new(tmp.clone(), 0, mode, &owners).with_perms(&perms);
+        ResolveContext::with_root(Arc::new(DentryNode::new(
+            String::from("/"),
+            Arc::new(owned),
+            alloc::sync::Weak::new(),
+        )))
+    };
+    let sticky = root_with(0o777 | S_ISVTX);
+    let user = |ctx: &ResolveContext, uid| ctx.clone().with_cred(Cred { uid, gid: uid });
+    let open = |ctx: &ResolveContext, path| dentry_open_at(ctx, path, OpenFlags::O_RDONLY);
+
+    ensure_errno!(open(&user(&sticky, 2), "planted"), Errno::EACCES);
+    ensure_errno!(
+        open(&sticky.clone().with_cred(Cred::ROOT), "planted"),
+        Errno::EACCES
+    );
+    let followed = ok("open by the owner", open(&user(&sticky, 1), "planted"))?;
+    ensure!(
+        read_all(&followed.node, 16)? == b"secret",
+        "the owner of the link didn't reach the target"
+    );
+    // a link of the owner of the directory, the link itself and a
+    // directory without the sticky bit are followed by anyone.
+    ok("open the link of root", open(&user(&sticky, 2), "rooted"))?;
+    let path = OpenFlags::O_NOFOLLOW | OpenFlags::O_PATH;
+    ok(
+        "open the link itself",
+        dentry_open_at(&user(&sticky, 2), "planted", path),
+    )?;
+    ok(
+        "open without the sticky bit",
+        open(&user(&root_with(0o777), 2), "planted"),
+    )?;
+    ok(
+        "open unprotected",
+        open(&sticky.clone().with_protected(false), "planted"),
+    )?;
+
+    ensure_errno!(
+        linkat(
+            &user(&sticky, 1),
+            "private",
+            &user(&sticky, 1),
+            "stolen",
+            false
+        ),
+        Errno::EPERM
+    );
+    ensure_errno!(
+        linkat(
+            &user(&sticky, 1),
+            "setuid",
+            &user(&sticky, 1),
+            "stolen",
+            false
+        ),
+        Errno::EPERM
+    );
+    ensure!(
+        tmp.lookup("stolen").is_err(),
+        "uid 1 linked the file of uid 2"
+    );
+    ok(
+        "link by the owner",
+        linkat(
+            &user(&sticky, 2),
+            "private",
+            &user(&sticky, 2),
+            "mine",
+            false,
+        ),
+    )?;
+    ok(
+        "link by root",
+        linkat(
+            &sticky,
+            "private",
+            &sticky.clone().with_cred(Cred::ROOT),
+            "kept",
+            false,
+        ),
+    )?;
+    ok(
+        "link of a writable file",
+        linkat(
+            &user(&sticky, 1),
+            "shared",
+            &user(&sticky, 1),
+            "also",
+            false,
+        ),
+    )?;
+    for name in ["mine", "kept", "also"] {
+        ok("lookup the link", tmp.lookup(name))?;
+    }
+    Ok(())
+}
+
+/// The lookups through the mount points: a tmpfs mounted on /a/b is
+/// entered by the lookup of b, `..` at its root goes back to /a, and the
+/// paths through it are the ones of the mount points. An overmount hides
+/// the mount below, a bind mount shows the entries of its source.
+pub(super) fn mount_crossing(dir: &File) -> CaseResult {
+    use crate::dentry::{dentry_open_at, DentryNode, ResolveContext};
+    use crate::tmpfs::TmpFs;
+
+    let covered = ok("mkdir", dir.mkdir("a").and_then(|x| x.mkdir("b")))?;
+    ok("touch", covered.touch("covered"))?;
+    let root = Arc::new(DentryNode::new(
+        String::from("/"),
+        dir.clone(),
+        alloc::sync::Weak::new(),
+    ));
+    let mut ctx = ResolveContext::with_root(root.clone());
+    // the mountpoint is cached before the mount, the mount must hide it.
+    ok(
+        "open b",
+        dentry_open_at(&ctx, "/a/b/covered", OpenFlags::NONE),
+    )?;
+    let new_fs = || -> &'static Arc<dyn FileSystem> {
+        Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>))
+    };
+    let fs = new_fs();
+    ok("touch", fs.root_dir().touch("mounted"))?;
+    ok("mount", DentryNode::mount_at(&root, "/a/b", fs.root_dir()))?;
+
+    let path = |ctx: &ResolveContext, path: &str| -> Result<String, String> {
+        let dentry = ok(path, dentry_open_at(ctx, path, OpenFlags::NONE))?;
+        Ok(dentry.path_from(&ctx.root))
+    };
+    ok(
+        "open mounted",
+        dentry_open_at(&ctx, "/a/b/mounted", OpenFlags::NONE),
+    )?;
+    ensure_errno!(
+        dentry_open_at(&ctx, "/a/b/covered", OpenFlags::NONE),
+        Errno::ENOENT
+    );
+    ok("chdir", ctx.chdir("/a/b"))?;
+    ensure!(
+        ctx.getcwd() == "/a/b",
+        "getcwd {} in the mount",
+        ctx.getcwd()
+    );
+    for (rel, want) in [
+        (".", "/a/b"),
+        ("..", "/a"),
+        ("../..", "/"),
+        ("../../..", "/"),
+        ("../b/mounted", "/a/b/mounted"),
+        ("./../../a/b/.", "/a/b"),
+    ] {
+        let got = path(&ctx, rel)?;
+        ensure!(got == want, "{} from /a/b is {}, not {}", rel, got, want);
+    }
+    ok("chdir", ctx.chdir(".."))?;
+    ensure!(
+        ctx.getcwd() == "/a",
+        "getcwd {} after `cd ..`",
+        ctx.getcwd()
+    );
+    ok("chdir", ctx.chdir("b"))?;
+    ensure!(ctx.getcwd() == "/a/b", "getcwd {} back in", ctx.getcwd());
+
+    // the mount in the mount, and `..` at its root.
+    ok("mkdir", fs.root_dir().mkdir("c"))?;
+    let inner = new_fs();
+    ok(
+        "mount",
+        DentryNode::mount_at(&root, "/a/b/c", inner.root_dir()),
+    )?;
+    ok("chdir", ctx.chdir("c"))?;
+    ensure!(
+        ctx.getcwd() == "/a/b/c",
+        "getcwd {} in the mount",
+        ctx.getcwd()
+    );
+    let got = path(&ctx, "../mounted")?;
+    ensure!(got == "/a/b/mounted", "../mounted from /a/b/c is {}", got);
+    let got = path(&ctx, "../../..")?;
+    ensure!(got == "/", "../../.. from /a/b/c is {}", got);
+
+    // the overmount hides the mount, `..` crosses both.
+    let over = new_fs();
+    ok("touch", over.root_dir().touch("over"))?;
+    ok(
+        "overmount",
+        DentryNode::mount_at(&root, "/a/b", over.root_dir()),
+    )?;
+    ok(
+        "open over",
+        dentry_open_at(&ctx, "/a/b/over", OpenFlags::NONE),
+    )?;
+    ensure_errno!(
+        dentry_open_at(&ctx, "/a/b/mounted", OpenFlags::NONE),
+        Errno::ENOENT
+    );
+    let got = path(&ctx, "/a/b/..")?;
+    ensure!(got == "/a", "/a/b/.. under the overmount is {}", got);
+    // the cwd in the covered mount still works.
+    let got = path(&ctx, "../mounted")?;
+    ensure!(
+        got == "/a/b/mounted",
+        "../mounted from the covered cwd is {}",
+        got
+    );
+
+    // a bind mount of /a on /d, its `..` is the parent of /d.
+    ok("mkdir", dir.mkdir("d"))?;
+    let source = ok("open a", dentry_open_at(&ctx, "/a", OpenFlags::NONE))?;
+    ok("bind", DentryNode::bind_at(&root, &source, "/d"))?;
+    ok(
+        "open bound",
+        dentry_open_at(&ctx, "/d/b/covered", OpenFlags::NONE),
+    )?;
+    let got = path(&ctx, "/d/b/..")?;
+    ensure!(got == "/d", "/d/b/.. is {}", got);
+    let got = path(&ctx, "/d/..")?;
+    ensure!(got == "/", "/d/.. is {}", got);
+    ok("chdir", ctx.chdir("/d/b"))?;
+    ensure!(
+        ctx.getcwd() == "/d/b",
+        "getcwd {} in the bind",
+        ctx.getcwd()
+    );
+    Ok(())
+}
+
+/// The dentry of the root of the filesystem, for the ops taking one.
+fn root_dentry(fs: &'static Arc<dyn FileSystem>) -> Arc<crate::dentry::DentryNode> {
+    Arc::new(crate::dentry::DentryNode::new(
+        String::from("/"),
+        fs.root_dir(),
+        alloc::sync::Weak::new(),
+    ))
+}
+
+/// The overwrite modes of create_with in a new directory of dir: the
+/// outcomes, the contents, and no temporary file left behind.
+fn check_create_with(dir: &Arc<crate::dentry::DentryNode>) -> CaseResult {
+    use crate::dentry::DentryNode;
+    use crate::ops::{create_with, CreateOutcome, Overwrite};
+
+    let node = ok("mkdir", dir.node.mkdir("create_with"))?;
+    let dir = Arc::new(DentryNode::new(
+        String::from("create_with"),
+        node,
+        Arc::downgrade(dir),
+    ));
+    let file = &dir.node;
+    let steps: [(&[u8], Overwrite, CreateOutcome, &[u8]); 7] = [
+        (b"first", Overwrite::Never, CreateOutcome::Created, b"first"),
+        (
+            b"second",
+            Overwrite::Never,
+            CreateOutcome::Unchanged,
+            b"first",
+        ),
+        (
+            b"a longer third",
+            Overwrite::Always,
+            CreateOutcome::Replaced,
+            b"a longer third",
+        ),
+        // shorter, the old bytes after it are gone.
+        (b"4th", Overwrite::Always, CreateOutcome::Replaced, b"4th"),
+        (
+            b"4th",
+            Overwrite::IfDifferent,
+            CreateOutcome::Unchanged,
+            b"4th",
+        ),
+        (
+            b"5th",
+            Overwrite::IfDifferent,
+            CreateOutcome::Replaced,
+            b"5th",
+        ),
+        (
+            b"5th!",
+            Overwrite::IfDifferent,
+            CreateOutcome::Replaced,
+            b"5th!",
+        ),
+    ];
+    for (contents, overwrite, outcome, expected) in steps {
+        let r = ok(
+            "create_with",
+            create_with(&dir, "config", 0o644, contents, overwrite),
+        )?;
+        let read = read_all(&ok("lookup", file.lookup("config"))?, 64)?;
+        ensure!(
+            r == outcome && read == expected,
+            "create_with {:?} of {:?} gives {:?} and reads {:?}",
+            overwrite,
+            contents,
+            r,
+            read
+        );
+    }
+    let empty = create_with(&dir, "empty", 0o600, b"", Overwrite::IfDifferent);
+    ensure!(
+        ok("create_with", empty)? == CreateOutcome::Created,
+        "the empty file isn't created"
+    );
+    ensure!(
+        ok(
+            "create_with",
+            create_with(&dir, "empty", 0o600, b"", Overwrite::IfDifferent)
+        )? == CreateOutcome::Unchanged,
+        "the empty file is rewritten"
+    );
+    ok("mkdir", file.mkdir("sub"))?;
+    for overwrite in [Overwrite::Never, Overwrite::Always] {
+        ensure_errno!(
+            create_with(&dir, "sub", 0o644, b"x", overwrite),
+            Errno::EISDIR
+        );
+    }
+    ensure_errno!(
+        create_with(&dir, "dev", 0o020644, b"x", Overwrite::Always),
+        Errno::EINVAL
+    );
+    let mut listed = names(file)?;
+    listed.sort();
+    ensure!(
+        listed == ["config", "empty", "sub"],
+        "the directory has {:?}",
+        listed
+    );
+    Ok(())
+}
+
+/// The contents of the file open by name, read to its end.
+#[cfg(feature = "std")]
+fn read_to_end(dir: &File, name: &str) -> Result<Vec<u8>, String> {
+    let file = ok("open", dir.open(name, OpenFlags::O_RDONLY))?;
+    let mut data = Vec::new();
+    let mut buf = vec![0; 0x4000];
+    loop {
+        match ok("readat", file.readat(data.len(), &mut buf))? {
+            0 => return Ok(data),
+            n => data.extend_from_slice(&buf[..n]),
+        }
+    }
+}
+
+/// Replace a file by create_with while two readers open and read it:
+/// each read is the whole of one of the two contents.
+#[cfg(feature = "std")]
+fn check_create_with_readers(dir: &Arc<crate::dentry::DentryNode>) -> CaseResult {
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    use crate::ops::{create_with, Overwrite};
+
+    const ROUNDS: usize = 50;
+    let contents = [vec![1u8; 96 << 10], vec![2u8; 40 << 10]];
+    ok(
+        "create_with",
+        create_with(dir, "swapped", 0o644, &contents[0], Overwrite::Always),
+    )?;
+    let done = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..2)
+        .map(|_| {
+            let (node, done, contents) = (dir.node.clone(), done.clone(), contents.clone());
+            thread::spawn(move || -> Result<usize, String> {
+                let mut reads = 0;
+                while !done.load(Ordering::Acquire) {
+                    let data = read_to_end(&node, "swapped")?;
+                    ensure!(
+                        contents.contains(&data),
+                        "a reader sees {} bytes starting with {:?}",
+                        data.len(),
+                        data.first()
+                    );
+                    reads += 1;
+                }
+                Ok(reads)
+            })
+        })
+        .collect();
+    let mut written = Ok(());
+    for i in 0..ROUNDS {
+        let r = create_with(dir, "swapped", 0o644, &contents[i % 2], Overwrite::Always);
+        if let Err(err) = r {
+            written = Err(format!("create_with: {:?}", err));
+            break;
+        }
+        thread::yield_now();
+    }
+    done.store(true, Ordering::Release);
+    for reader in readers {
+        reader
+            .join()
+            .map_err(|_| String::from("a reader panicked"))??;
+    }
+    written
+}
+
+/// create_with on tmpfs by its renames, and by writes in place on a
+/// backend whose nodes can't rename.
+pub fn tmpfs_create_with() -> Result<(), String> {
+    use crate::tmpfs::TmpFs;
+
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>));
+    check_create_with(&root_dentry(fs))?;
+    let tmp: &'static Arc<dyn FileSystem> =
+        Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>));
+    let crippled: Arc<dyn FileSystem> = Arc::new(CrippledFs(tmp));
+    check_create_with(&root_dentry(Box::leak(Box::new(crippled))))
+}
+
+/// The readers of a file replaced by create_with on tmpfs never see a
+/// part of it.
+#[cfg(feature = "std")]
+pub fn tmpfs_create_with_readers() -> Result<(), String> {
+    use crate::tmpfs::TmpFs;
+
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>));
+    check_create_with_readers(&root_dentry(fs))
+}
+
+/// create_with on ext4, and its readers never see a part of a file.
+#[cfg(root_fs = "ext4_rs")]
+pub fn ext4_create_with() -> Result<(), String> {
+    let ext4 = ram_ext4(16 << 20, *b"ext4-create-with")?;
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(ext4.clone() as Arc<dyn FileSystem>));
+    let root = root_dentry(fs);
+    check_create_with(&root)?;
+    #[cfg(feature = "std")]
+    check_create_with_readers(&root)?;
+    let report = ext4.check();
+    ensure!(
+        report.problems.is_empty(),
+        "the image has problems: {:?}",
+        report.problems
+    );
+    Ok(())
+}
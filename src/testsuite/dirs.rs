@@ -0,0 +1,486 @@
+// The cases of the directories: read_dir, its positions and snapshots,
+// the walks, the disk usage and the versions of the changes.
+
+use super::*;
+
+pub(super) fn read_dir(dir: &File) -> CaseResult {
+    let empty = ok("mkdir", dir.mkdir("empty"))?;
+    let mut listed = names(&empty)?;
+    listed.sort();
+    ensure!(listed == [".", ".."], "empty directory lists {:?}", listed);
+
+    let sub = ok("mkdir", dir.mkdir("full"))?;
+    for i in 0..40 {
+        ok("touch", sub.touch(&format!("f{}", i)))?;
+    }
+    let listed = names(&sub)?;
+    for i in 0..40 {
+        let name = format!("f{}", i);
+        let count = listed.iter().filter(|x| **x == name).count();
+        ensure!(count == 1, "{} is listed {} times", name, count);
+    }
+
+    // getdents with a small buffer returns every entry once.
+    let entries = ok("read_dir", sub.read_dir())?;
+    let mut buf = [0u8; 64];
+    let mut offset = 0;
+    while offset < entries.len() {
+        let consumed = fill_dirents64(&mut buf, &entries, offset);
+        ensure!(consumed > 0, "getdents is stuck at {}", offset);
+        offset += consumed;
+    }
+    ensure!(
+        offset == entries.len(),
+        "getdents returned {} entries",
+        offset
+    );
+
+    // getdents returns the bytes of a name which isn't UTF-8, not their
+    // escapes, and sizes the record by them.
+    let raw = vfscore::DirEntry {
+        filename: name_from_bytes(b"raw-\xff\xfe"),
+        len: 0,
+        file_type: FileType::File,
+    };
+    let mut buf = [0xa5u8; 64];
+    ensure!(
+        fill_dirents64(&mut buf, &[raw], 0) == 1,
+        "getdents didn't take the raw name"
+    );
+    let reclen = u16::from_ne_bytes([buf[16], buf[17]]) as usize;
+    ensure!(
+        reclen == dirent64_reclen(6) && &buf[19..26] == b"raw-\xff\xfe\0",
+        "the raw name is {:?} in a record of {}",
+        &buf[19..26],
+        reclen
+    );
+
+    // the d_ino of getdents is the inode of the entry, or its hashed_ino
+    // if the filesystem doesn't tell it.
+    let listed = ok("read_dir_at", crate::readdir::read_dir_at(&sub, 0, 64))?;
+    let mut buf = vec![0u8; listed.len() * dirent64_reclen(NAME_MAX)];
+    ensure!(
+        fill_dirents64_at(&mut buf, &listed) == listed.len(),
+        "getdents didn't take the listing"
+    );
+    let mut pos = 0;
+    for x in listed.iter() {
+        let d_ino = u64::from_ne_bytes(buf[pos..pos + 8].try_into().unwrap());
+        pos += u16::from_ne_bytes(buf[pos + 16..pos + 18].try_into().unwrap()) as usize;
+        let name = &x.entry.filename;
+        if name == "." || name == ".." {
+            continue;
+        }
+        let mut stat = Stat::default();
+        ok("stat", ok("lookup", sub.lookup(name))?.stat(&mut stat))?;
+        let expected = match x.ino {
+            0 => hashed_ino(name),
+            _ => stat.ino,
+        };
+        ensure!(
+            d_ino == expected,
+            "d_ino of {} is {}, not {}",
+            name,
+            d_ino,
+            expected
+        );
+    }
+    Ok(())
+}
+
+/// List dir in pages of page entries by read_dir_next, removing a name
+/// of gone and creating a new one after every page. Every entry must be
+/// listed at most once, and the names of stay exactly once.
+pub(super) fn paged_listing(
+    dir: &File,
+    page: usize,
+    stay: &[String],
+    gone: &[String],
+) -> CaseResult {
+    use alloc::collections::BTreeMap;
+
+    let handle = FileHandle::new(dir.clone(), OpenFlags::O_RDONLY);
+    let mut listed: BTreeMap<String, usize> = BTreeMap::new();
+    let mut pages = 0;
+    loop {
+        let entries = ok("read_dir_next", handle.read_dir_next(page))?;
+        if entries.is_empty() {
+            break;
+        }
+        for x in entries {
+            *listed.entry(x.entry.filename).or_default() += 1;
+        }
+        if let Some(name) = gone.get(pages) {
+            ok("remove", dir.remove(name))?;
+        }
+        ok("touch", dir.touch(&format!("new-{:05}", pages)))?;
+        pages += 1;
+        ensure!(pages <= 100_000, "the listing doesn't end");
+    }
+    for name in stay {
+        let count = listed.get(name).copied().unwrap_or(0);
+        ensure!(count == 1, "{} is listed {} times", name, count);
+    }
+    let twice: Vec<_> = listed.iter().filter(|x| *x.1 > 1).collect();
+    ensure!(twice.is_empty(), "listed more than once: {:?}", twice);
+    Ok(())
+}
+
+pub(super) fn dir_positions(dir: &File) -> CaseResult {
+    let stay: Vec<String> = (0..40).map(|i| format!("stay-{:02}", i)).collect();
+    let gone: Vec<String> = (0..40).map(|i| format!("gone-{:02}", i)).collect();
+    // the names interleave by the order of the creation.
+    for (stay, gone) in stay.iter().zip(gone.iter()) {
+        ok("touch", dir.touch(stay))?;
+        ok("touch", dir.touch(gone))?;
+    }
+    paged_listing(dir, 7, &stay, &gone)?;
+
+    // telldir and seekdir on a directory which doesn't change.
+    let handle = FileHandle::new(dir.clone(), OpenFlags::O_RDONLY);
+    let names = |entries: Vec<crate::readdir::PosEntry>| -> Vec<String> {
+        entries.into_iter().map(|x| x.entry.filename).collect()
+    };
+    let first = names(ok("read_dir_next", handle.read_dir_next(5))?);
+    let pos = handle.dir_tell();
+    let second = names(ok("read_dir_next", handle.read_dir_next(5))?);
+    handle.dir_seek(pos);
+    let again = names(ok("read_dir_next", handle.read_dir_next(5))?);
+    ensure!(
+        again == second,
+        "seekdir to {} lists {:?}, not {:?}",
+        pos,
+        again,
+        second
+    );
+    handle.dir_seek(0);
+    let rewound = names(ok("read_dir_next", handle.read_dir_next(5))?);
+    ensure!(
+        rewound == first,
+        "rewinddir lists {:?}, not {:?}",
+        rewound,
+        first
+    );
+    Ok(())
+}
+
+/// A snapshot iterated while its directory changes yields the entries of
+/// the snapshot, the spilled ones too.
+pub(super) fn dir_snapshot(dir: &File) -> CaseResult {
+    use alloc::collections::BTreeSet;
+
+    use crate::ops::dir_iter_snapshot_with;
+
+    let before: BTreeSet<String> = (0..60).map(|i| format!("snap-{:02}", i)).collect();
+    for name in before.iter() {
+        ok("touch", dir.touch(name))?;
+    }
+    let mut snapshot = ok("snapshot", dir_iter_snapshot_with(dir, 16, None))?;
+    ensure!(
+        snapshot.len() == 60 && snapshot.spilled() == 44,
+        "the snapshot has {} entries, {} spilled",
+        snapshot.len(),
+        snapshot.spilled()
+    );
+    let mut yielded = BTreeSet::new();
+    let mut i = 0;
+    for entry in snapshot {
+        let entry = ok("next", entry)?;
+        ensure!(
+            matches!(entry.file_type, FileType::File),
+            "{} isn't a file",
+            entry.name
+        );
+        ensure!(
+            yielded.insert(entry.name.clone()),
+            "{} is yielded twice",
+            entry.name
+        );
+        if let Some(name) = before.iter().nth(i) {
+            ok("remove", dir.remove(name))?;
+        }
+        ok("touch", dir.touch(&format!("late-{:02}", i)))?;
+        i += 1;
+    }
+    ensure!(
+        yielded == before,
+        "the snapshot differs by {:?}",
+        yielded.symmetric_difference(&before).collect::<Vec<_>>()
+    );
+    Ok(())
+}
+
+/// remove_dir_all removes a deep tree and nothing outside of it, the
+/// symbol links out of the tree aren't followed.
+pub(super) fn remove_tree(dir: &File) -> CaseResult {
+    let outside = ok("mkdir", dir.mkdir("outside"))?;
+    ok(
+        "writeat",
+        ok("touch", outside.touch("kept"))?.writeat(0, b"kept"),
+    )?;
+    let mut level = ok("mkdir", dir.mkdir("tree"))?;
+    for i in 0..5 {
+        ok(
+            "writeat",
+            ok("touch", level.touch("file"))?.writeat(0, b"data"),
+        )?;
+        match level.sym_link("link", "../../outside") {
+            Ok(()) | Err(VfsError::NotSupported) => {}
+            Err(err) => return Err(format!("sym_link: {:?}", err)),
+        }
+        level = ok("mkdir", level.mkdir(&format!("level{}", i)))?;
+    }
+    ok("remove_dir_all", remove_dir_all(dir.clone(), "tree"))?;
+    ensure_err!(dir.lookup("tree"), VfsError::FileNotFound);
+    let kept = ok("lookup", outside.lookup("kept"))?;
+    ensure!(read_all(&kept, 16)? == b"kept", "removed outside the tree");
+    ensure_errno!(remove_dir_all(dir.clone(), "tree"), Errno::ENOENT);
+    Ok(())
+}
+
+/// The paths and the depths of the walk, an error fails the case.
+pub(super) fn walked(walk: WalkDir) -> Result<Vec<(String, usize)>, String> {
+    walk.into_iter()
+        .map(|x| x.map(|x| (x.path, x.depth)))
+        .map(|x| x.map_err(|x| format!("walk {}: {:?}", x.path, x.error)))
+        .collect()
+}
+
+/// WalkDir yields the parents before the children and stops at the max
+/// depth, a followed link back to the top is a loop.
+pub(super) fn walk(dir: &File) -> CaseResult {
+    let a = ok("mkdir", dir.mkdir("a"))?;
+    let b = ok("mkdir", a.mkdir("b"))?;
+    ok("touch", b.touch("file"))?;
+    ok("touch", dir.touch("top"))?;
+    let all = walked(WalkDir::new(dir.clone()))?;
+    let pos = |path: &str| all.iter().position(|x| x.0 == path);
+    ensure!(all.len() == 4, "walked {:?}", all);
+    ensure!(
+        pos("a").is_some() && pos("a") < pos("a/b") && pos("a/b") < pos("a/b/file"),
+        "the parents aren't first: {:?}",
+        all
+    );
+    ensure!(
+        all.contains(&(String::from("a/b/file"), 3)),
+        "the depth of a/b/file: {:?}",
+        all
+    );
+    let shallow = walked(WalkDir::new(dir.clone()).max_depth(2))?;
+    ensure!(
+        shallow.len() == 3 && shallow.iter().all(|x| x.1 <= 2),
+        "max_depth 2 walked {:?}",
+        shallow
+    );
+    match b.sym_link("up", "../..") {
+        Ok(()) => {}
+        Err(VfsError::NotSupported) => return Ok(()),
+        Err(err) => return Err(format!("sym_link: {:?}", err)),
+    }
+    let looped = WalkDir::new(dir.clone())
+        .follow_symlinks(true)
+        .into_iter()
+        .take(64)
+        .any(|x| matches!(x, Err(err) if err.path == "a/b/up"));
+    ensure!(looped, "the loop of a/b/up wasn't found");
+    Ok(())
+}
+
+/// disk_usage counts the sizes of the new files and a hard link once, the
+/// blocks it counts are at most the blocks statfs reports used. The sizes
+/// of the directories are the ones stat reports.
+pub(super) fn usage(dir: &File) -> CaseResult {
+    let top = ok("mkdir", dir.mkdir("du"))?;
+    let empty = ok("disk_usage", disk_usage(top.clone(), false))?;
+    let empty_size = stat_size(&top)?;
+    let mut before = StatFS::default();
+    ok("statfs", dir.statfs(&mut before))?;
+    ok(
+        "writeat",
+        ok("touch", top.touch("a"))?.writeat(0, &[1; 100]),
+    )?;
+    let sub = ok("mkdir", top.mkdir("sub"))?;
+    let b = ok("touch", sub.touch("b"))?;
+    ok("writeat", b.writeat(0, &vec![2; 0x5000]))?;
+    let full = ok("disk_usage", disk_usage(top.clone(), false))?;
+    let mut after = StatFS::default();
+    ok("statfs", dir.statfs(&mut after))?;
+    let expected = 100 + 0x5000 + stat_size(&sub)? + stat_size(&top)? - empty_size;
+    ensure!(
+        full.bytes_apparent == empty.bytes_apparent + expected,
+        "apparent bytes {:?} before, {:?} after",
+        empty,
+        full
+    );
+    ensure!(full.inodes == empty.inodes + 3, "inodes {:?}", full);
+    let used = before.bfree.saturating_sub(after.bfree) * after.bsize;
+    ensure!(
+        used == 0 || full.bytes_allocated - empty.bytes_allocated <= used,
+        "allocated {:?}, statfs used {}",
+        full,
+        used
+    );
+    let full_size = stat_size(&top)?;
+    match top.link("c", b) {
+        Ok(()) => {}
+        Err(VfsError::NotSupported) => return Ok(()),
+        Err(err) => return Err(format!("link: {:?}", err)),
+    }
+    let linked = ok("disk_usage", disk_usage(top.clone(), false))?;
+    ensure!(
+        linked.inodes == full.inodes
+            && linked.bytes_apparent - stat_size(&top)? == full.bytes_apparent - full_size,
+        "the link is counted: {:?}",
+        linked
+    );
+    Ok(())
+}
+
+fn stat_size(file: &File) -> Result<u64, String> {
+    let mut stat = Stat::default();
+    ok("stat", file.stat(&mut stat))?;
+    Ok(stat.size)
+}
+
+/// The changes of the entries named tail- seen by dir_versions_on.
+static DIR_CHANGES: Mutex<Vec<(String, crate::readdir::DirChange, u64)>> = Mutex::new(Vec::new());
+
+fn collect_dir_change(event: &crate::readdir::ChangeEvent) {
+    if event.name.starts_with("tail-") {
+        let change = (String::from(event.name), event.change, event.version);
+        DIR_CHANGES.lock().push(change);
+    }
+}
+
+/// Tail a directory of root through the ops layer: every create, unlink
+/// and rename in it moves its version and is an event with the version
+/// after it, one by one. With incremental the node tells the entries
+/// added since a version, until the version is too old, otherwise it
+/// only tells that nothing changed and asks for a rescan.
+fn dir_versions_on(root: &File, incremental: bool) -> CaseResult {
+    use crate::dentry::{dentry_open_at, DentryNode, ResolveContext};
+    use crate::ops::{renameat, unlinkat};
+    use crate::readdir::{
+        clear_change_hook, dir_version, read_dir_since, set_change_hook, DirChange,
+    };
+
+    let root = Arc::new(DentryNode::new(
+        String::from("/"),
+        root.clone(),
+        alloc::sync::Weak::new(),
+    ));
+    let ctx = ResolveContext::with_root(root);
+    let create = OpenFlags::O_RDWR | OpenFlags::O_CREAT;
+    ok(
+        "mkdir",
+        dentry_open_at(&ctx, "tailed", create | OpenFlags::O_DIRECTORY),
+    )?;
+    ok("touch", dentry_open_at(&ctx, "outside", create))?;
+    let dir = ok("open", dentry_open_at(&ctx, "tailed", OpenFlags::NONE))?.node;
+    ensure_err!(
+        dir_version(&ok("lookup", dentry_open_at(&ctx, "outside", OpenFlags::NONE))?.node),
+        VfsError::NotDir
+    );
+    let since = |version| -> Result<Option<Vec<String>>, String> {
+        let added = ok("read_dir_since", read_dir_since(&dir, version))?;
+        Ok(added.map(|x| x.into_iter().map(|x| x.filename).collect()))
+    };
+    let start = ok("dir_version", dir_version(&dir))?;
+    ensure!(
+        since(start)? == Some(Vec::new()),
+        "changes of an unchanged directory"
+    );
+
+    DIR_CHANGES.lock().clear();
+    set_change_hook(collect_dir_change);
+    let changed = || -> CaseResult {
+        ok("create", dentry_open_at(&ctx, "tailed/tail-1", create))?;
+        let first = ok("dir_version", dir_version(&dir))?;
+        ok("create", dentry_open_at(&ctx, "tailed/tail-2", create))?;
+        ok("unlink", unlinkat(&ctx, "tailed/tail-1", false))?;
+        ok("rename", renameat(&ctx, "outside", &ctx, "tailed/tail-3"))?;
+        let handle = FileHandle::new(dir.clone(), OpenFlags::O_RDONLY);
+        ok("touch", handle.touch("tail-4"))?;
+        let version = ok("dir_version", dir_version(&dir))?;
+        let through = ok("handle version", dir_version(&(handle as File)))?;
+        ensure!(
+            through == version,
+            "the handle has version {} of {}",
+            through,
+            version
+        );
+        let names = ["tail-2", "tail-3", "tail-4"].map(String::from).to_vec();
+        match incremental {
+            true => {
+                ensure!(
+                    since(start)? == Some(names.clone()),
+                    "added since the start {:?}",
+                    since(start)?
+                );
+                ensure!(
+                    since(first)? == Some(names),
+                    "added since tail-1 {:?}",
+                    since(first)?
+                );
+            }
+            false => ensure!(
+                since(start)?.is_none(),
+                "changes of a node without versions"
+            ),
+        }
+        ensure!(
+            since(version)? == Some(Vec::new()),
+            "changes since the last version"
+        );
+        ensure!(
+            since(version + 1)?.is_none(),
+            "changes since a future version"
+        );
+        Ok(())
+    };
+    let r = changed();
+    clear_change_hook();
+    r?;
+    let events = core::mem::take(&mut *DIR_CHANGES.lock());
+    let kinds: Vec<_> = events.iter().map(|x| (x.0.as_str(), x.1)).collect();
+    let expected = [
+        ("tail-1", DirChange::Added),
+        ("tail-2", DirChange::Added),
+        ("tail-1", DirChange::Removed),
+        ("tail-3", DirChange::Added),
+        ("tail-4", DirChange::Added),
+    ];
+    ensure!(kinds == expected, "events {:?}", kinds);
+    let versions: Vec<u64> = events.iter().map(|x| x.2).collect();
+    let counted: Vec<u64> = (start + 1..start + 6).collect();
+    ensure!(
+        versions == counted,
+        "versions {:?} from {}",
+        versions,
+        start
+    );
+
+    // a version older than the ones the node keeps asks for a rescan.
+    let version = ok("dir_version", dir_version(&dir))?;
+    for i in 0..100 {
+        let name = format!("tailed/tail-old-{}", i);
+        ok("create", dentry_open_at(&ctx, &name, create))?;
+    }
+    ensure!(since(version)?.is_none(), "changes since a dropped version");
+    Ok(())
+}
+
+/// dir_versions_on tmpfs, whose directories keep their recent versions.
+pub fn tmpfs_dir_versions() -> Result<(), String> {
+    use crate::tmpfs::TmpFs;
+
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>));
+    dir_versions_on(&fs.root_dir(), true)
+}
+
+/// dir_versions_on ext4, whose directories are counted by the ops layer.
+#[cfg(root_fs = "ext4_rs")]
+pub fn ext4_dir_versions() -> Result<(), String> {
+    let fs = ram_ext4(16 << 20, *b"ext4-dir-version")?;
+    dir_versions_on(&fs.root(), false)
+}
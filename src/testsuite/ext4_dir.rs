@@ -0,0 +1,963 @@
+// The ext4 cases of the directories: the htree index, read_dir_plus, the
+// deep trees, the checksum tails and the types of the entries, and the
+// rollback of a failed create.
+
+use super::*;
+
+/// Check the growth of the ext4 directories: 10000 files and a
+/// subdirectory created in one directory are all found, in a linear
+/// directory and in one indexed by dir_index. They are removed again, the
+/// next files reuse the freed entries without growing the directory, and
+/// the image passes check.
+pub fn ext4_many_entries() -> Result<(), String> {
+    use crate::ext4_layout::EXT4_INDEX_FL;
+
+    const FILES: usize = 10_000;
+    let name = |i: usize| format!("entry-{:05}", i);
+    let dir_size = |dir: &Arc<dyn INodeInterface>| -> Result<u64, String> {
+        let mut stat = Stat::default();
+        ok("stat", dir.stat(&mut stat))?;
+        Ok(stat.size as u64)
+    };
+    for dir_index in [false, true] {
+        let options = crate::ext4_mkfs::Options {
+            bytes_per_inode: 4096,
+            dir_index,
+            uuid: *b"ext4-many-entry!",
+            ..Default::default()
+        };
+        let (_, device) = ram_ext4_image(64 << 20, &options)?;
+        let fs = ok("mount", crate::Ext4FileSystem::new_from_device(device))?;
+        let dir = ok("mkdir", fs.root().mkdir("many"))?;
+        for i in 0..FILES {
+            ok("touch", dir.touch(&name(i)))?;
+        }
+        let sub = ok("mkdir sub", dir.mkdir("sub"))?;
+        ok("touch inner", sub.touch("inner"))?;
+        let indexed =
+            crate::inode_flags::get_flags(&dir).is_ok_and(|x| x.bits() & EXT4_INDEX_FL != 0);
+        ensure!(
+            indexed == dir_index,
+            "the directory is indexed: {}, with dir_index: {}",
+            indexed,
+            dir_index
+        );
+        for i in (0..FILES).step_by(7) {
+            ok("lookup", dir.lookup(&name(i)))?;
+        }
+        ok(
+            "lookup inner",
+            ok("lookup sub", dir.lookup("sub"))?.lookup("inner"),
+        )?;
+        let entries = ok("read_dir", dir.read_dir())?;
+        ensure!(
+            entries.len() == FILES + 3,
+            "the directory lists {} entries",
+            entries.len()
+        );
+
+        for i in 0..FILES {
+            ok("remove", dir.remove(&name(i)))?;
+        }
+        ensure_err!(dir.lookup(&name(FILES / 2)), VfsError::FileNotFound);
+        let size = dir_size(&dir)?;
+        for i in 0..FILES / 2 {
+            ok("touch again", dir.touch(&name(i)))?;
+        }
+        ensure!(
+            dir_size(&dir)? == size,
+            "the directory grew from {} to {} bytes over the freed entries",
+            size,
+            dir_size(&dir)?
+        );
+        ok("lookup again", dir.lookup(&name(FILES / 2 - 1)))?;
+        let stay: Vec<String> = (0..FILES / 2).step_by(2).map(name).collect();
+        let gone: Vec<String> = (1..FILES / 2).step_by(2).map(name).collect();
+        paged_listing(&dir, 256, &stay, &gone)?;
+        let report = fs.check();
+        ensure!(
+            report.problems.is_empty(),
+            "the image with dir_index {} has problems: {:?}",
+            dir_index,
+            report.problems
+        );
+    }
+    Ok(())
+}
+
+/// Check a huge ext4 directory: 200000 subdirectories in one directory of
+/// 1K blocks, whose dx_root indexes at most 123 leaves, so the index gets
+/// a level of dx_node. The directory has a count of 1 with dir_nlink past
+/// 65000 links, which stays through the removals. A random sample is
+/// found before and after a remount, the removed ones aren't, and the
+/// image passes check, which walks the index like Linux.
+pub fn ext4_huge_directory() -> Result<(), String> {
+    const DIRS: usize = 200_000;
+    let name = |i: usize| format!("d{:06}", i);
+    let options = crate::ext4_mkfs::Options {
+        block_size: 1024,
+        inode_size: 128,
+        bytes_per_inode: 1280,
+        dir_index: true,
+        uuid: *b"ext4-huge-direct",
+        ..Default::default()
+    };
+    let (_, device) = ram_ext4_image(320 << 20, &options)?;
+    let mount = || {
+        ok(
+            "mount",
+            crate::Ext4FileSystem::new_from_device(device.clone()),
+        )
+    };
+    let nlink = |dir: &File| -> Result<u32, String> {
+        let mut stat = Stat::default();
+        ok("stat", dir.stat(&mut stat))?;
+        Ok(stat.nlink as u32)
+    };
+    let mut seed = 0x9e37_79b9_u64;
+    let mut sample = || -> Vec<usize> {
+        (0..1000)
+            .map(|_| {
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (seed >> 33) as usize % DIRS
+            })
+            .collect()
+    };
+    let removed: Vec<usize> = (0..DIRS).step_by(1000).collect();
+    {
+        let fs = mount()?;
+        let dir = ok("mkdir", fs.root().mkdir("huge"))?;
+        for i in 0..DIRS {
+            if let Err(err) = dir.mkdir(&name(i)) {
+                return Err(format!("mkdir {}: {:?}", name(i), err));
+            }
+            if i == 65000 - 3 {
+                ensure!(nlink(&dir)? == 65000, "the links are {}", nlink(&dir)?);
+            }
+        }
+        ensure!(
+            nlink(&dir)? == 1,
+            "the links past the max are {}",
+            nlink(&dir)?
+        );
+        for i in sample() {
+            ok("lookup", dir.lookup(&name(i)))?;
+        }
+        for i in removed.iter() {
+            ok("rmdir", dir.rmdir(&name(*i)))?;
+        }
+        ensure!(nlink(&dir)? == 1, "the count of 1 became {}", nlink(&dir)?);
+        ensure_err!(dir.lookup("d200000"), VfsError::FileNotFound);
+    }
+    let fs = mount()?;
+    let dir = ok("lookup", fs.root().lookup("huge"))?;
+    for i in sample() {
+        match removed.contains(&i) {
+            true => ensure_err!(dir.lookup(&name(i)), VfsError::FileNotFound),
+            false => {
+                ok("lookup again", dir.lookup(&name(i)))?;
+            }
+        }
+    }
+    for i in removed.iter().take(10) {
+        ensure_err!(dir.lookup(&name(*i)), VfsError::FileNotFound);
+    }
+    let entries = ok("read_dir", dir.read_dir())?.len();
+    ensure!(
+        entries == DIRS - removed.len() + 2,
+        "the directory lists {} entries",
+        entries
+    );
+    let report = fs.check();
+    ensure!(
+        report.problems.is_empty(),
+        "the huge directory has problems: {:?}",
+        report.problems
+    );
+    Ok(())
+}
+
+/// Look up names in an indexed ext4 directory of 50000 files after a
+/// remount: a lookup reads the dx_root, a leaf and the inode, a handful
+/// of blocks of the disk instead of the hundreds of the directory.
+pub fn ext4_htree_lookup_blocks() -> Result<(), String> {
+    use crate::ext4_layout::EXT4_INDEX_FL;
+    use crate::ext4_mkfs::{format, Options};
+    use crate::testing::{MockDisk, MockOp};
+
+    const SIZE: usize = 96 << 20;
+    const FILES: usize = 50_000;
+    // the dx_root, a dx_node, the leaf and the block of the inode.
+    const MAX_READS: usize = 4;
+    let name = |i: usize| format!("htree-entry-{:05}", i);
+    let options = Options {
+        bytes_per_inode: 1024,
+        dir_index: true,
+        uuid: *b"ext4-htree-reads",
+        ..Default::default()
+    };
+    let disk = Arc::new(MockDisk::new(SIZE, 512));
+    ok(
+        "format",
+        format(SIZE as u64, &options, |block, data| {
+            disk.write_at(block as usize * options.block_size, data)
+        }),
+    )?;
+    let dir_blocks = {
+        let fs = ok(
+            "mount",
+            crate::Ext4FileSystem::new_from_device(disk.clone()),
+        )?;
+        let dir = ok("mkdir", fs.root().mkdir("big"))?;
+        for i in 0..FILES {
+            ok("touch", dir.touch(&name(i)))?;
+        }
+        ensure!(
+            crate::inode_flags::get_flags(&dir).is_ok_and(|x| x.bits() & EXT4_INDEX_FL != 0),
+            "the directory of {} files isn't indexed",
+            FILES
+        );
+        let mut stat = Stat::default();
+        ok("stat", dir.stat(&mut stat))?;
+        ok("flush", FileSystem::flush(fs.as_ref()))?;
+        stat.size as usize / options.block_size
+    };
+
+    let fs = ok(
+        "remount",
+        crate::Ext4FileSystem::new_from_device(disk.clone()),
+    )?;
+    let dir = ok("lookup", fs.root().lookup("big"))?;
+    // the first lookup may read the bitmaps and the descriptors.
+    ok("lookup", dir.lookup(&name(0)))?;
+    let reads = || disk.log().iter().filter(|x| x.op == MockOp::Read).count();
+    let mut most = 0;
+    for i in (1..FILES).step_by(FILES / 25) {
+        disk.clear_log();
+        ok("lookup", dir.lookup(&name(i)))?;
+        most = most.max(reads());
+    }
+    disk.clear_log();
+    ensure_err!(dir.lookup("htree-missing"), VfsError::FileNotFound);
+    most = most.max(reads());
+    ensure!(
+        most <= MAX_READS,
+        "a lookup in the directory of {} blocks read {} blocks",
+        dir_blocks,
+        most
+    );
+    Ok(())
+}
+
+/// Repeat the lookups, stats and listings of a directory of 100 files on
+/// ext4: each stays under a fixed count of allocations, a lookup doesn't
+/// allocate per entry scanned or per name compared and a stat doesn't
+/// allocate. The binary of the test installs CountingAlloc as its
+/// allocator.
+#[cfg(feature = "std")]
+pub fn ext4_lookup_allocations() -> Result<(), String> {
+    use crate::testing::CountingAlloc;
+
+    const FILES: usize = 100;
+    const ROUNDS: usize = 300;
+    // the blocks read, the path and the wrapper of the child.
+    const LOOKUP_BUDGET: usize = 32;
+    // a name per entry, the list and the blocks read.
+    const LIST_BUDGET: usize = FILES + 32;
+    ensure!(
+        CountingAlloc::installed(),
+        "CountingAlloc isn't the allocator of the test binary"
+    );
+    let name = |i: usize| format!("lookup-entry-{:03}", i);
+    let fs = ram_ext4(8 << 20, *b"ext4-lookup-allo")?;
+    let dir = ok("mkdir", fs.root().mkdir("dir"))?;
+    for i in 0..FILES {
+        ok("touch", dir.touch(&name(i)))?;
+    }
+    let names: Vec<String> = (0..FILES).map(name).collect();
+    // the first lookup and listing may fill the caches.
+    ok("lookup", dir.lookup(&names[0]))?;
+    ok("read_dir", dir.read_dir())?;
+
+    let (mut lookups, mut stats, mut lists) = (0, 0, 0);
+    let mut stat = Stat::default();
+    for round in 0..ROUNDS {
+        let base = CountingAlloc::allocations();
+        let child = ok("lookup", dir.lookup(&names[round * 7 % FILES]))?;
+        lookups = lookups.max(CountingAlloc::allocations() - base);
+        let base = CountingAlloc::allocations();
+        ok("stat", child.stat(&mut stat))?;
+        stats = stats.max(CountingAlloc::allocations() - base);
+        drop(child);
+        if round % 10 == 0 {
+            let base = CountingAlloc::allocations();
+            let entries = ok("read_dir", dir.read_dir())?;
+            lists = lists.max(CountingAlloc::allocations() - base);
+            ensure!(
+                entries.len() == FILES + 2,
+                "the directory lists {} entries",
+                entries.len()
+            );
+        }
+    }
+    let base = CountingAlloc::allocations();
+    ensure_err!(dir.lookup("lookup-missing"), VfsError::FileNotFound);
+    let missing = CountingAlloc::allocations() - base;
+    ensure!(
+        lookups <= LOOKUP_BUDGET && missing <= LOOKUP_BUDGET,
+        "a lookup in {} entries made {} allocations, a missing name {}",
+        FILES,
+        lookups,
+        missing
+    );
+    ensure!(stats == 0, "a stat made {} allocations", stats);
+    ensure!(
+        lists <= LIST_BUDGET,
+        "a read_dir of {} entries made {} allocations",
+        FILES,
+        lists
+    );
+    Ok(())
+}
+
+/// List a directory of 1000 files with read_dir_plus, linear and indexed:
+/// each block of the inode table is read once, not once per entry, the
+/// attributes and the inode are those of stat, and an open file gives its
+/// buffered size.
+pub fn ext4_read_dir_plus() -> Result<(), String> {
+    use crate::ext4_mkfs::{format, Options};
+    use crate::readdir::read_dir_plus;
+    use crate::testing::{MockDisk, MockOp};
+
+    const SIZE: usize = 16 << 20;
+    const FILES: usize = 1000;
+    for dir_index in [false, true] {
+        let options = Options {
+            bytes_per_inode: 4096,
+            dir_index,
+            uuid: *b"ext4-dir-plus-t!",
+            ..Default::default()
+        };
+        let disk = Arc::new(MockDisk::new(SIZE, 512));
+        ok(
+            "format",
+            format(SIZE as u64, &options, |block, data| {
+                disk.write_at(block as usize * options.block_size, data)
+            }),
+        )?;
+        let fs = ok(
+            "mount",
+            crate::Ext4FileSystem::new_from_device(disk.clone()),
+        )?;
+        let dir = ok("mkdir", fs.root().mkdir("plus"))?;
+        for i in 0..FILES {
+            let file = ok("touch", dir.touch(&format!("file-{:04}", i)))?;
+            ok("writeat", file.writeat(0, &vec![1; i % 7]))?;
+            ok("flush", file.flush())?;
+        }
+        let reads = || disk.log().iter().filter(|x| x.op == MockOp::Read).count();
+
+        disk.clear_log();
+        let plain = ok("read_dir_plus", read_dir_plus(&dir, 0, 2 * FILES, false))?;
+        let plain_reads = reads();
+        disk.clear_log();
+        let plus = ok("read_dir_plus", read_dir_plus(&dir, 0, 2 * FILES, true))?;
+        let plus_reads = reads();
+        ensure!(
+            plain.len() == FILES + 2 && plus.len() == plain.len(),
+            "read_dir_plus lists {} and {} entries",
+            plain.len(),
+            plus.len()
+        );
+        ensure!(
+            plain.iter().all(|x| x.attrs.is_none()),
+            "the plain listing has attributes"
+        );
+        let table_blocks = (FILES + 12) * options.inode_size as usize / options.block_size + 1;
+        ensure!(
+            plus_reads.saturating_sub(plain_reads) <= table_blocks,
+            "the attributes of {} entries took {} reads, the inode table has {} blocks",
+            plus.len(),
+            plus_reads.saturating_sub(plain_reads),
+            table_blocks
+        );
+
+        disk.clear_log();
+        for x in plus.iter() {
+            let name = x.entry.entry.filename.as_str();
+            let node = match name {
+                "." => dir.clone(),
+                name => ok("lookup", dir.lookup(name))?,
+            };
+            let mut stat = Stat::default();
+            ok("stat", node.stat(&mut stat))?;
+            let attrs = x.attrs.ok_or(format!("{} has no attributes", name))?;
+            ensure!(
+                attrs.size == stat.size as u64
+                    && attrs.ino == stat.ino as u64
+                    && attrs.mode == stat.mode
+                    && attrs.nlink == stat.nlink as u32
+                    && (attrs.mtime.sec, attrs.mtime.nsec) == (stat.mtime.sec, stat.mtime.nsec),
+                "{}: read_dir_plus gives {:?}, stat the inode {}, the size {} and {} links",
+                name,
+                attrs,
+                stat.ino,
+                stat.size,
+                stat.nlink
+            );
+        }
+        ensure!(
+            reads() > FILES,
+            "the stats of {} entries took {} reads",
+            plus.len(),
+            reads()
+        );
+
+        let open = ok("lookup", dir.lookup("file-0003"))?;
+        ok("writeat", open.writeat(0, &[7; 300]))?;
+        let plus = ok("read_dir_plus", read_dir_plus(&dir, 0, 2 * FILES, true))?;
+        let size = plus
+            .iter()
+            .find(|x| x.entry.entry.filename == "file-0003")
+            .and_then(|x| x.attrs)
+            .map(|x| x.size);
+        ensure!(
+            size == Some(300),
+            "the open file has the size {:?}, not its buffered 300",
+            size
+        );
+        drop(open);
+        let report = fs.check();
+        ensure!(
+            report.problems.is_empty(),
+            "the image has problems: {:?}",
+            report.problems
+        );
+    }
+    Ok(())
+}
+
+/// A tree of ext4 one directory deeper than DEFAULT_MAX_PATH_DEPTH: the
+/// walk, copy_recursive and remove_dir_all go down to the bottom and the
+/// dentry resolution to the max depth. A directory whose ".." was
+/// rewritten to another one in the image fails its lookup with
+/// InvalidData.
+pub fn ext4_deep_tree() -> Result<(), String> {
+    use crate::blockdev::{BlockDevice, READ_SIZE};
+    use crate::dentry::{dentry_open_at, DentryNode, ResolveContext, DEFAULT_MAX_PATH_DEPTH};
+    use crate::ops::{copy_recursive, CopyOptions};
+    use crate::tmpfs::TmpFs;
+
+    const DEPTH: usize = DEFAULT_MAX_PATH_DEPTH + 1;
+    let fs = ram_ext4(16 << 20, *b"ext4-deep-tree!!")?;
+    let root = fs.root();
+    let mut dir = root.clone();
+    for i in 0..DEPTH {
+        dir = ok("mkdir", dir.mkdir(&format!("d{}", i)))?;
+    }
+    ok("touch", dir.touch("bottom"))?;
+    let all = walked(WalkDir::new(root.clone()))?;
+    ensure!(
+        all.iter()
+            .any(|x| x.1 == DEPTH + 1 && x.0.ends_with("/bottom")),
+        "the walk didn't reach the bottom: {} entries",
+        all.len()
+    );
+    let copy = Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>)).root_dir();
+    ok(
+        "copy",
+        copy_recursive(root.clone(), copy.clone(), CopyOptions::default()),
+    )?;
+    ensure!(
+        walked(WalkDir::new(copy))?.len() == all.len(),
+        "the copy of the tree differs"
+    );
+
+    let dentry_root = Arc::new(DentryNode::new(
+        String::from("/"),
+        root.clone(),
+        alloc::sync::Weak::new(),
+    ));
+    let ctx = ResolveContext::with_root(dentry_root);
+    let path = |depth: usize| {
+        (0..depth)
+            .map(|i| format!("d{}", i))
+            .collect::<Vec<_>>()
+            .join("/")
+    };
+    ok(
+        "open at the max depth",
+        dentry_open_at(&ctx, &path(DEFAULT_MAX_PATH_DEPTH), OpenFlags::NONE),
+    )?;
+    ensure_errno!(
+        dentry_open_at(&ctx, &path(DEPTH), OpenFlags::NONE),
+        Errno::ENAMETOOLONG
+    );
+    ok(
+        "open with a deeper max",
+        dentry_open_at(
+            &ctx.clone().with_max_depth(DEPTH),
+            &path(DEPTH),
+            OpenFlags::NONE,
+        ),
+    )?;
+    drop(ctx);
+    ok("remove_dir_all", remove_dir_all(root.clone(), "d0"))?;
+    ensure_err!(root.lookup("d0"), VfsError::FileNotFound);
+    drop((dir, root, fs));
+
+    // c moves under a by its entry, but its ".." names b.
+    let options = crate::ext4_mkfs::Options {
+        uuid: *b"ext4-lost-parent",
+        metadata_csum: false,
+        ..Default::default()
+    };
+    let (_, device) = ram_ext4_image(8 << 20, &options)?;
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::new_from_device(device.clone()),
+    )?;
+    let a = ok("mkdir", fs.root().mkdir("a"))?;
+    let b = ok("mkdir", fs.root().mkdir("b"))?;
+    let c = ok("mkdir", a.mkdir("c"))?;
+    let ino = |node: &File| ok("metadata", node.metadata()).map(|x| x.inode as u32);
+    let (a_ino, b_ino, c_ino) = (ino(&a)?, ino(&b)?, ino(&c)?);
+    ok("flush", FileSystem::flush(fs.as_ref()))?;
+    drop((a, b, c, fs));
+    // the first block of c starts with "." of 12 bytes, then "..".
+    let mut dot = c_ino.to_le_bytes().to_vec();
+    dot.extend_from_slice(&[12, 0, 1, 2, b'.', 0, 0, 0]);
+    dot.extend_from_slice(&a_ino.to_le_bytes());
+    let block = (0..(8 << 20) / READ_SIZE)
+        .map(|x| x * READ_SIZE)
+        .find(|x| device.read_offset(*x).starts_with(&dot))
+        .ok_or("no first block of c")?;
+    device.write_offset(block + 12, &b_ino.to_le_bytes());
+    let fs = ok("remount", crate::Ext4FileSystem::new_from_device(device))?;
+    let a = ok("lookup", fs.root().lookup("a"))?;
+    ensure_err!(a.lookup("c"), VfsError::InvalidData);
+    ok("lookup b", fs.root().lookup("b"))?;
+    Ok(())
+}
+
+/// A create and a mkdir whose write of the directory entry fails on a
+/// MockDisk counting its errors, with and without a journal: the name
+/// doesn't exist after the failure, no inode is leaked, an immediate
+/// retry succeeds, and the retried name is there after a remount.
+pub fn ext4_create_rollback() -> Result<(), String> {
+    use crate::blockdev::set_device_errors;
+    use crate::testing::MockDisk;
+
+    const NAME: &str = "half-done-entry";
+    for journal_blocks in [0, 64] {
+        for dir in [false, true] {
+            let create = |root: &File| match dir {
+                true => root.mkdir(NAME),
+                false => root.touch(NAME),
+            };
+            let disk = Arc::new(MockDisk::from_image(crash_image(journal_blocks)?, 512));
+            let fs = ok(
+                "mount",
+                crate::Ext4FileSystem::new_from_device(disk.clone()),
+            )?;
+            set_device_errors(fs.dev(), disk.clone());
+            let root = fs.root();
+
+            disk.fail_write_of(NAME.as_bytes());
+            let errors = disk.write_errors();
+            let err = create(&root).err();
+            ensure!(
+                err.is_some_and(|x| Errno::from(x) == Errno::EIO),
+                "the failed create of {} returned {:?}",
+                NAME,
+                err
+            );
+            ensure!(disk.write_errors() > errors, "no write failed");
+            ensure_err!(root.lookup(NAME), VfsError::FileNotFound);
+            let problems = fs.check().problems;
+            ensure!(
+                problems.is_empty(),
+                "problems after the failure {:?}",
+                problems
+            );
+
+            ok("retry", create(&root))?;
+            ok("lookup", root.lookup(NAME))?;
+            let problems = fs.check().problems;
+            ensure!(
+                problems.is_empty(),
+                "problems after the retry {:?}",
+                problems
+            );
+            ok("flush", FileSystem::flush(fs.as_ref()))?;
+            drop((root, fs));
+
+            let fs = ok(
+                "remount",
+                crate::Ext4FileSystem::new_from_device(disk.clone()),
+            )?;
+            ok("lookup", fs.root().lookup(NAME))?;
+            let problems = fs.check().problems;
+            ensure!(
+                problems.is_empty(),
+                "problems after the remount {:?}",
+                problems
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The checksum tails of the ext4 directory blocks with metadata_csum,
+/// on a linear and an indexed directory: the directory grows over blocks
+/// by creations, loses a third of its entries, gives a subdirectory to
+/// another parent and exchanges an entry with it. After a remount the
+/// listing has the entries and never the tail, and the checker passes
+/// the checksums of every directory block. A changed name in a block is
+/// reported by the checker.
+pub fn ext4_dirent_tails() -> Result<(), String> {
+    use crate::blockdev::{BlockDevice, READ_SIZE};
+    use crate::ext4_check::Problem;
+    use crate::ext4_mkfs::Options;
+    use crate::rename::{rename, RenameFlags};
+
+    const SIZE: usize = 8 << 20;
+    let name = |i: usize| format!("{:040}", i);
+    for dir_index in [false, true] {
+        let options = Options {
+            uuid: *b"ext4-dirent-tail",
+            dir_index,
+            ..Options::default()
+        };
+        let (_, device) = ram_ext4_image(SIZE, &options)?;
+        let mount = || {
+            ok(
+                "mount",
+                crate::Ext4FileSystem::new_from_device(device.clone()),
+            )
+        };
+        {
+            let fs = mount()?;
+            let root = fs.root();
+            let a = ok("mkdir", root.mkdir("a"))?;
+            let b = ok("mkdir", root.mkdir("b"))?;
+            for i in 0..200 {
+                ok("touch", a.touch(&name(i)))?;
+            }
+            for i in (0..200).step_by(3) {
+                ok("remove", a.remove(&name(i)))?;
+            }
+            ok("mkdir", a.mkdir("sub"))?;
+            ok("rename", rename(&a, "sub", &b, "sub", RenameFlags::NONE))?;
+            ok("touch", b.touch("x"))?;
+            ok(
+                "exchange",
+                rename(&a, &name(1), &b, "x", RenameFlags::EXCHANGE),
+            )?;
+            ok("flush", FileSystem::flush(fs.as_ref()))?;
+        }
+
+        let fs = mount()?;
+        let a = ok("lookup", fs.root().lookup("a"))?;
+        let mut names: Vec<String> = ok("read_dir", a.read_dir())?
+            .into_iter()
+            .map(|x| x.filename)
+            .filter(|x| x != "." && x != "..")
+            .collect();
+        names.sort();
+        let expected: Vec<String> = (0..200).filter(|x| x % 3 != 0).map(name).collect();
+        ensure!(
+            names == expected,
+            "dir_index {}: {} entries listed, {:?} not expected",
+            dir_index,
+            names.len(),
+            names.iter().find(|x| !expected.contains(x))
+        );
+        let sub = ok(
+            "lookup",
+            fs.root().lookup("b").and_then(|x| x.lookup("sub")),
+        )?;
+        ok("lookup ..", sub.lookup(".."))?;
+        let report = fs.check();
+        ensure!(
+            report.problems.is_empty(),
+            "dir_index {}: the image has problems: {:?}",
+            dir_index,
+            report.problems
+        );
+        drop((sub, a, fs));
+
+        // the last name of the directory, in its leaf block.
+        let needle = name(199);
+        let offset = (0..SIZE)
+            .step_by(READ_SIZE)
+            .find_map(|offset| {
+                let data = device.read_offset(offset);
+                data.windows(needle.len())
+                    .position(|x| x == needle.as_bytes())
+                    .map(|x| offset + x)
+            })
+            .ok_or("the name isn't on the image")?;
+        device.write_offset(offset, b"x");
+        let fs = mount()?;
+        let report = fs.check();
+        ensure!(
+            report
+                .problems
+                .iter()
+                .any(|x| matches!(x, Problem::DirChecksum { .. })),
+            "dir_index {}: the changed block passes: {:?}",
+            dir_index,
+            report.problems
+        );
+    }
+    Ok(())
+}
+
+/// An image with Latin-1 file names, as written by a system whose locale
+/// isn't UTF-8: the names are patched on the disk after their files are
+/// created, next to a name in UTF-8. read_dir lists each by its escape of
+/// name_from_bytes, getdents gives back the bytes on the disk, and the
+/// exact listed name opens, stats and unlinks the file. A new file can't
+/// take an escaped name, ext4_rs creates the names from str, and the
+/// image passes check.
+pub fn ext4_latin1_names() -> Result<(), String> {
+    use crate::blockdev::{BlockDevice, READ_SIZE};
+    use crate::ext4_mkfs::Options;
+
+    const SIZE: usize = 8 << 20;
+    // the names created and their bytes on the patched image.
+    const NAMES: [(&str, &[u8]); 3] = [
+        ("latin-cafe", b"latin-caf\xe9"),
+        ("latin-naive", b"latin-na\xefve"),
+        ("utf8-café", "utf8-café".as_bytes()),
+    ];
+    // without metadata_csum, so the patched block passes.
+    let options = Options {
+        metadata_csum: false,
+        uuid: *b"ext4-latin1-name",
+        ..Default::default()
+    };
+    let (_, device) = ram_ext4_image(SIZE, &options)?;
+    let mount = || {
+        ok(
+            "mount",
+            crate::Ext4FileSystem::new_from_device(device.clone()),
+        )
+    };
+    // the data isn't the name, a search of the name finds the entry.
+    let data = |i: usize| format!("the data of the file {}", i).into_bytes();
+    let find = |needle: &[u8]| {
+        (0..SIZE).step_by(READ_SIZE).find_map(|offset| {
+            let data = device.read_offset(offset);
+            data.windows(needle.len())
+                .position(|x| x == needle)
+                .map(|x| offset + x)
+        })
+    };
+    {
+        let fs = mount()?;
+        let dir = ok("mkdir", fs.root().mkdir("names"))?;
+        for (i, (name, _)) in NAMES.iter().enumerate() {
+            let file = ok("touch", dir.touch(name))?;
+            ok("writeat", file.writeat(0, &data(i)))?;
+        }
+        ok("flush", FileSystem::flush(fs.as_ref()))?;
+    }
+    for (name, raw) in NAMES.iter().filter(|x| x.0.as_bytes() != x.1) {
+        let offset = find(name.as_bytes()).ok_or(format!("{} isn't on the image", name))?;
+        device.write_offset(offset, raw);
+    }
+
+    let fs = mount()?;
+    let dir = ok("lookup", fs.root().lookup("names"))?;
+    let entries = ok("read_dir", dir.read_dir())?;
+    for (i, (_, raw)) in NAMES.into_iter().enumerate() {
+        let listed = name_from_bytes(raw);
+        let entry = entries
+            .iter()
+            .find(|x| x.filename == listed)
+            .ok_or_else(|| format!("{:?} isn't listed", raw))?;
+        ensure!(
+            crate::ops::is_escaped(&entry.filename) == core::str::from_utf8(raw).is_err(),
+            "{:?} is listed as {:?}",
+            raw,
+            entry.filename
+        );
+        let mut buf = [0u8; 64];
+        ensure!(
+            fill_dirents64(&mut buf, core::slice::from_ref(entry), 0) == 1
+                && &buf[19..19 + raw.len() + 1] == [raw, b"\0"].concat(),
+            "getdents gives {:?} for {:?}",
+            &buf[19..19 + raw.len() + 1],
+            raw
+        );
+        let file = ok("lookup", dir.lookup(&entry.filename))?;
+        let read = read_all(&file, 64)?;
+        ensure!(read == data(i), "{:?} reads {:?}", raw, read);
+        let mut stat = Stat::default();
+        ok("stat", file.stat(&mut stat))?;
+        ensure!(
+            stat.size as usize == read.len(),
+            "{:?} has {} bytes",
+            raw,
+            stat.size
+        );
+    }
+    let cafe = name_from_bytes(NAMES[0].1);
+    ok("remove", dir.remove(&cafe))?;
+    ensure_err!(dir.lookup(&cafe), VfsError::FileNotFound);
+    ensure!(
+        !names(&dir)?.contains(&cafe),
+        "the removed name is still listed"
+    );
+
+    ensure_err!(
+        dir.touch(&name_from_bytes(b"r\xe9sum\xe9")),
+        VfsError::InvalidInput
+    );
+    ok("flush", FileSystem::flush(fs.as_ref()))?;
+    let problems = fs.check().problems;
+    ensure!(problems.is_empty(), "the image: {:?}", problems);
+    Ok(())
+}
+
+/// The types of the directory entries with and without the filetype
+/// feature: without it the type byte of the new entries is 0 and the
+/// types come from the inodes, with it a byte out of range isn't trusted.
+/// read_dir_at and read_dir_plus give the d_type of the mode stat gives,
+/// FIFOs and block devices included, read_dir its vfs type.
+pub fn ext4_dirent_types() -> Result<(), String> {
+    use crate::devnode::make_dev;
+    use crate::ext4_mkfs::{format, Options};
+    use crate::mknod::{mknod, S_IFBLK, S_IFCHR, S_IFIFO, S_IFSOCK};
+    use crate::ops::{
+        d_type_file_type, dirent_type, DT_BLK, DT_CHR, DT_DIR, DT_FIFO, DT_LNK, DT_REG, DT_SOCK,
+        DT_UNKNOWN,
+    };
+    use crate::readdir::{read_dir_at, read_dir_plus};
+    use crate::testing::MockDisk;
+
+    const SIZE: usize = 16 << 20;
+    // the entry whose type byte is patched.
+    const ODD: &str = "odd-type-entry";
+    let type_of = |mode: StatMode| match mode.bits() & 0o170000 {
+        0o010000 => DT_FIFO,
+        0o020000 => DT_CHR,
+        0o040000 => DT_DIR,
+        0o060000 => DT_BLK,
+        0o100000 => DT_REG,
+        0o120000 => DT_LNK,
+        0o140000 => DT_SOCK,
+        _ => DT_UNKNOWN,
+    };
+    for filetype in [false, true] {
+        // without metadata_csum, so a patched entry passes.
+        let options = Options {
+            filetype,
+            metadata_csum: false,
+            uuid: *b"ext4-dirent-type",
+            ..Default::default()
+        };
+        let disk = Arc::new(MockDisk::new(SIZE, 512));
+        ok(
+            "format",
+            format(SIZE as u64, &options, |block, data| {
+                disk.write_at(block as usize * options.block_size, data)
+            }),
+        )?;
+        {
+            let fs = ok(
+                "mount",
+                crate::Ext4FileSystem::new_from_device(disk.clone()),
+            )?;
+            let root = fs.root();
+            ok("mkdir", root.mkdir("dir"))?;
+            ok("touch", root.touch("file"))?;
+            ok("touch", root.touch(ODD))?;
+            ok("mknod", mknod(&root, "fifo", S_IFIFO | 0o644, 0))?;
+            ok(
+                "mknod",
+                mknod(&root, "tty", S_IFCHR | 0o620, make_dev(4, 64)),
+            )?;
+            ok("mknod", mknod(&root, "sock", S_IFSOCK | 0o644, 0))?;
+            ok(
+                "mknod",
+                mknod(&root, "disk", S_IFBLK | 0o660, make_dev(8, 0)),
+            )?;
+            let report = fs.check();
+            ensure!(
+                report.problems.is_empty(),
+                "filetype {}: the image has problems: {:?}",
+                filetype,
+                report.problems
+            );
+        }
+        // name_len and the type byte before the name.
+        let image = disk.image();
+        let written = [ODD.len() as u8, filetype as u8];
+        let at = image
+            .windows(2 + ODD.len())
+            .position(|x| x[..2] == written && &x[2..] == ODD.as_bytes());
+        let Some(at) = at else {
+            return Err(format!(
+                "filetype {}: no entry {:?} of {:?}",
+                filetype, ODD, written
+            ));
+        };
+        if filetype {
+            disk.write_at(at + 1, &[0x55]);
+        }
+
+        let fs = ok(
+            "mount",
+            crate::Ext4FileSystem::new_from_device(disk.clone()),
+        )?;
+        let root = fs.root();
+        let stat_type = |name: &str| -> Result<u8, String> {
+            let node = ok("lookup", root.lookup(name))?;
+            let mut stat = Stat::default();
+            ok("stat", node.stat(&mut stat))?;
+            Ok(type_of(stat.mode))
+        };
+        let listed = ok("read_dir", root.read_dir())?;
+        let at = ok("read_dir_at", read_dir_at(&root, 0, 64))?;
+        let plus = ok("read_dir_plus", read_dir_plus(&root, 0, 64, true))?;
+        ensure!(
+            listed.len() == 10 && at.len() == 10 && plus.len() == 10,
+            "filetype {}: listed {}, {} and {} entries",
+            filetype,
+            listed.len(),
+            at.len(),
+            plus.len()
+        );
+        // the entry, its d_type and the d_type of its attrs.
+        let plus_types = plus.iter().map(|x| {
+            let attrs = x.attrs.as_ref().map(|x| type_of(x.mode));
+            (&x.entry.entry, Some(x.entry.d_type), attrs)
+        });
+        let at_types = at.iter().map(|x| (&x.entry, Some(x.d_type), None));
+        let listed_types = listed.iter().map(|x| (x, None, None));
+        for (entry, d_type, attrs) in listed_types.chain(at_types).chain(plus_types) {
+            let expected = match entry.filename.as_str() {
+                "." | ".." => DT_DIR,
+                name => stat_type(name)?,
+            };
+            ensure!(
+                dirent_type(entry.file_type) == dirent_type(d_type_file_type(expected))
+                    && d_type.is_none_or(|x| x == expected)
+                    && attrs.is_none_or(|x| x == expected),
+                "filetype {}: {} is listed as {:?}, d_type {:?} and {:?}, stat gives {}",
+                filetype,
+                entry.filename,
+                entry.file_type,
+                d_type,
+                attrs,
+                expected
+            );
+        }
+    }
+    Ok(())
+}
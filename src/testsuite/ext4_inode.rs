@@ -0,0 +1,1094 @@
+// The ext4 cases of the inodes: the times, the flags, the quotas and the
+// reserved blocks, the secure delete, the sealed inodes, the device
+// numbers and the file handles.
+
+use super::*;
+
+/// The clock of ext4_times, every reading is a second later.
+static SECONDS: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(1 << 32);
+
+fn tick() -> u64 {
+    SECONDS.fetch_add(1, core::sync::atomic::Ordering::Relaxed)
+}
+
+/// Check the times of ext4: a new file has its birth time in statx, and
+/// the changes of the file move its ctime but not its birth time. The
+/// clock starts beyond 2038, so the epoch bits are used too.
+/// TODO: change the mode instead of the times when INodeInterface can.
+pub fn ext4_times() -> Result<(), String> {
+    use vfscore::TimeSpec;
+
+    use crate::statx::{statx, Statx, StatxTimestamp, STATX_BTIME};
+
+    let device = ram_ext4_device(8 << 20, *b"Byte-OS ext4mkfs")?;
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::builder_from_device(device)
+            .time_source(tick)
+            .mount(),
+    )?;
+    let file = ok("touch", fs.root().touch("file"))?;
+    let times = |file: &File| {
+        let mut out = Statx::default();
+        ok("statx", statx(file, STATX_BTIME, &mut out)).map(|_| out)
+    };
+    let created = times(&file)?;
+    ensure!(
+        created.mask & STATX_BTIME != 0,
+        "no btime, mask {:#x}",
+        created.mask
+    );
+    ensure!(
+        created.btime.sec >= 1 << 32 && created.btime == created.ctime,
+        "btime {:?} ctime {:?}",
+        created.btime,
+        created.ctime
+    );
+
+    let mut set = [
+        TimeSpec { sec: 1000, nsec: 5 },
+        TimeSpec { sec: 2000, nsec: 7 },
+    ];
+    ok("utimes", file.utimes(&mut set))?;
+    let changed = times(&file)?;
+    ensure!(
+        changed.btime == created.btime,
+        "btime moved to {:?}",
+        changed.btime
+    );
+    ensure!(
+        changed.ctime.sec > created.ctime.sec,
+        "ctime {:?} after {:?}",
+        changed.ctime,
+        created.ctime
+    );
+    let expected = |sec, nsec| StatxTimestamp {
+        sec,
+        nsec,
+        reserved: 0,
+    };
+    ensure!(
+        changed.atime == expected(1000, 5) && changed.mtime == expected(2000, 7),
+        "atime {:?} mtime {:?}",
+        changed.atime,
+        changed.mtime
+    );
+
+    ok("writeat", file.writeat(0, b"data"))?;
+    ok("flush", file.flush())?;
+    let written = times(&file)?;
+    ensure!(
+        written.btime == created.btime,
+        "btime moved to {:?}",
+        written.btime
+    );
+    ensure!(
+        written.ctime.sec > changed.ctime.sec && written.mtime.sec > 2000,
+        "ctime {:?} mtime {:?} after the write",
+        written.ctime,
+        written.mtime
+    );
+    Ok(())
+}
+
+/// Check the times beyond 2038 of ext4: a time of 2045 with nanoseconds
+/// set by utimes is read back exactly after a remount with the inodes of
+/// 256 bytes, and saturated to 2038 without its nanoseconds with the
+/// inodes of 128 bytes, which have no extra fields.
+pub fn ext4_times_2045() -> Result<(), String> {
+    use vfscore::TimeSpec;
+
+    use crate::ext4_mkfs::{format, Options};
+    use crate::testing::MockDisk;
+
+    const SIZE: usize = 4 << 20;
+    // 2045-01-01.
+    const SEC: usize = 2_366_755_200;
+    for (inode_size, expected) in [(256, (SEC, 123_456_789)), (128, (i32::MAX as usize, 0))] {
+        let options = Options {
+            inode_size,
+            uuid: *b"ext4-times-2045!",
+            ..Default::default()
+        };
+        let disk = Arc::new(MockDisk::new(SIZE, 512));
+        ok(
+            "format",
+            format(SIZE as u64, &options, |block, data| {
+                disk.write_at(block as usize * options.block_size, data)
+            }),
+        )?;
+        let fs = ok(
+            "mount",
+            crate::Ext4FileSystem::new_from_device(disk.clone()),
+        )?;
+        let file = ok("touch", fs.root().touch("file"))?;
+        let time = TimeSpec {
+            sec: SEC as _,
+            nsec: 123_456_789,
+        };
+        ok("utimes", file.utimes(&mut [time, time]))?;
+        ok("flush", FileSystem::flush(fs.as_ref()))?;
+        drop(file);
+        drop(fs);
+
+        let fs = ok("remount", crate::Ext4FileSystem::new_from_device(disk))?;
+        let file = ok("lookup", fs.root().lookup("file"))?;
+        let mut stat = Stat::default();
+        ok("stat", file.stat(&mut stat))?;
+        for (name, time) in [("atime", stat.atime), ("mtime", stat.mtime)] {
+            ensure!(
+                (time.sec as usize, time.nsec as usize) == expected,
+                "{} {}.{:09} with {} bytes inodes, expected {:?}",
+                name,
+                time.sec,
+                time.nsec,
+                inode_size,
+                expected
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Check the access times of ext4 by the policy of the mount: under
+/// relatime the first read after a write moves the atime and a second
+/// read right after it doesn't write the inode, strictatime moves it on
+/// both and noatime on neither. A remount with strictatime switches the
+/// policy, and relatime updates a day old atime anyway.
+pub fn ext4_atime() -> Result<(), String> {
+    use vfscore::TimeSpec;
+
+    use crate::atime::{AtimePolicy, RELATIME_STALE};
+    use crate::dentry::DentryNode;
+    use crate::mounts::{self, remount_at, MountFlags};
+
+    let at = |sec| TimeSpec { sec, nsec: 0 };
+    let stale = at(10 + RELATIME_STALE as usize);
+    ensure!(
+        AtimePolicy::Relatime.needs_update(at(10), at(5), at(5), stale)
+            && !AtimePolicy::Relatime.needs_update(at(10), at(5), at(5), at(11)),
+        "relatime of a day old atime"
+    );
+
+    let atime = |file: &File| {
+        let mut stat = Stat::default();
+        ok("stat", file.stat(&mut stat)).map(|_| stat.atime.sec)
+    };
+    // the atime of a new file after its write, its first and its second
+    // read through a handle.
+    let reads = |fs: &Arc<crate::Ext4FileSystem>, name: &str| -> Result<[usize; 3], String> {
+        let file = ok("touch", fs.root().touch(name))?;
+        ok("write", file.writeat(0, b"atime"))?;
+        let handle: File = FileHandle::new(file.clone(), OpenFlags::O_RDONLY);
+        let written = atime(&file)?;
+        read_all(&handle, 5)?;
+        let first = atime(&file)?;
+        read_all(&handle, 5)?;
+        Ok([written, first, atime(&file)?])
+    };
+    let mount = |uuid: &[u8; 16], policy| {
+        let device = ram_ext4_device(8 << 20, *uuid)?;
+        ok(
+            "mount",
+            crate::Ext4FileSystem::builder_from_device(device)
+                .time_source(tick)
+                .atime(policy)
+                .mount(),
+        )
+    };
+
+    let relatime = mount(b"ext4-atime-relat", AtimePolicy::Relatime)?;
+    let [written, first, second] = reads(&relatime, "file")?;
+    ensure!(
+        first > written && second == first,
+        "relatime atimes {} {} {}",
+        written,
+        first,
+        second
+    );
+    let strict = mount(b"ext4-atime-stric", AtimePolicy::Strictatime)?;
+    let [written, first, second] = reads(&strict, "file")?;
+    ensure!(
+        first > written && second > first,
+        "strictatime atimes {} {} {}",
+        written,
+        first,
+        second
+    );
+    let noatime = mount(b"ext4-atime-noatm", AtimePolicy::Noatime)?;
+    let [written, first, second] = reads(&noatime, "file")?;
+    ensure!(
+        first == written && second == written,
+        "noatime atimes {} {} {}",
+        written,
+        first,
+        second
+    );
+
+    // relatime to strictatime by a remount, the flags keep ro and rw.
+    let root = Arc::new(DentryNode::new(
+        String::from("/"),
+        relatime.root(),
+        alloc::sync::Weak::new(),
+    ));
+    ok(
+        "remount strictatime",
+        remount_at(&root, "/", MountFlags::NONE, "strictatime"),
+    )?;
+    let as_fs = relatime.clone() as Arc<dyn FileSystem>;
+    ensure!(
+        mounts::flags(&as_fs) == Some(MountFlags::STRICTATIME),
+        "flags {:?} after the remount",
+        mounts::flags(&as_fs)
+    );
+    let [_, first, second] = reads(&relatime, "remounted")?;
+    ensure!(
+        second > first,
+        "remounted strictatime atimes {} {}",
+        first,
+        second
+    );
+    Ok(())
+}
+
+/// Check chattr +i and +a on ext4, through the handles and the ops: an
+/// immutable file rejects the writes, truncate, utimes, unlink and link,
+/// an append only file takes the writes at its end only, and the file
+/// works again once the flags are cleared. lsattr reads the flags by the
+/// ioctl and a new mount of the image has them.
+pub fn ext4_inode_flags() -> Result<(), String> {
+    use vfscore::TimeSpec;
+
+    use crate::dentry::{DentryNode, ResolveContext};
+    use crate::inode_flags::{get_flags, set_flags, InodeFlags, FS_IOC_GETFLAGS, FS_IOC_SETFLAGS};
+    use crate::ops::{renameat, unlinkat};
+
+    let device = ram_ext4_device(8 << 20, *b"ext4-inode-flags")?;
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::builder_from_device(device.clone()).mount(),
+    )?;
+    let file = ok("touch", fs.root().touch("file"))?;
+    ok("write", file.writeat(0, b"data"))?;
+    let handle: File = FileHandle::new(file.clone(), OpenFlags::O_RDWR);
+    let dir: File = FileHandle::new(fs.root(), OpenFlags::O_RDONLY);
+    let ctx = ResolveContext::with_root(Arc::new(DentryNode::new(
+        String::from("/"),
+        fs.root(),
+        alloc::sync::Weak::new(),
+    )));
+    let mut times = [TimeSpec { sec: 1, nsec: 0 }; 2];
+
+    // chattr +i and lsattr by the ioctls.
+    let mut bits = InodeFlags::IMMUTABLE.bits();
+    ok(
+        "FS_IOC_SETFLAGS",
+        handle.ioctl(FS_IOC_SETFLAGS, &mut bits as *mut u32 as usize),
+    )?;
+    let mut bits = 0u32;
+    ok(
+        "FS_IOC_GETFLAGS",
+        handle.ioctl(FS_IOC_GETFLAGS, &mut bits as *mut u32 as usize),
+    )?;
+    ensure!(
+        InodeFlags::from_bits_retain(bits).contains(InodeFlags::IMMUTABLE),
+        "lsattr flags {:#x}",
+        bits
+    );
+    ensure_err!(handle.writeat(0, b"x"), VfsError::NotSupported);
+    ensure_err!(handle.writeat(4, b"x"), VfsError::NotSupported);
+    ensure_err!(handle.truncate(0), VfsError::NotSupported);
+    ensure_err!(handle.utimes(&mut times), VfsError::NotSupported);
+    ensure_err!(dir.remove("file"), VfsError::NotSupported);
+    ensure_err!(dir.link("hard", file.clone()), VfsError::NotSupported);
+    ensure_errno!(unlinkat(&ctx, "/file", false), Errno::EPERM);
+    ensure_errno!(renameat(&ctx, "/file", &ctx, "/moved"), Errno::EPERM);
+    ensure!(
+        read_all(&handle, 8)? == b"data",
+        "the reads of an immutable file"
+    );
+
+    // chattr -i +a, the writes at the end only.
+    ok("chattr +a", set_flags(&file, InodeFlags::APPEND))?;
+    ensure_err!(handle.writeat(0, b"x"), VfsError::NotSupported);
+    ok("append", handle.writeat(4, b"more"))?;
+    ensure_err!(handle.truncate(4), VfsError::NotSupported);
+    ensure_err!(dir.remove("file"), VfsError::NotSupported);
+    ensure_err!(dir.link("hard", file.clone()), VfsError::NotSupported);
+    ensure_errno!(unlinkat(&ctx, "/file", false), Errno::EPERM);
+    ok("utimes of an append only file", handle.utimes(&mut times))?;
+    ok("flush", handle.flush())?;
+
+    // the flags are in the inode on the disk.
+    drop((handle, dir, ctx, file, fs));
+    let fs = ok(
+        "mount again",
+        crate::Ext4FileSystem::builder_from_device(device).mount(),
+    )?;
+    let file = ok("lookup", fs.root().lookup("file"))?;
+    let flags = ok("get flags", get_flags(&file))?;
+    ensure!(
+        flags.contains(InodeFlags::APPEND) && !flags.contains(InodeFlags::IMMUTABLE),
+        "flags {:?} after the mount",
+        flags
+    );
+
+    // chattr -a, everything goes again.
+    ok("chattr -a", set_flags(&file, InodeFlags::NONE))?;
+    let handle: File = FileHandle::new(file.clone(), OpenFlags::O_RDWR);
+    let dir: File = FileHandle::new(fs.root(), OpenFlags::O_RDONLY);
+    ok("write at 0", handle.writeat(0, b"DATA"))?;
+    ensure!(
+        read_all(&handle, 16)? == b"DATAmore",
+        "the content after the append"
+    );
+    ok("truncate", handle.truncate(0))?;
+    ok("utimes", handle.utimes(&mut times))?;
+    ok("remove", dir.remove("file"))?;
+    Ok(())
+}
+
+/// Check the quotas of ext4 with a 1 MiB limit of uid 1000: the writes to
+/// a file given to the user fail with StorageFull once they'd pass it, the
+/// usage of the user and its group is the blocks of the file, removing
+/// the file releases them and the writes succeed again. chown moves the
+/// charges back to root.
+pub fn ext4_quota() -> Result<(), String> {
+    use crate::owner::chown;
+    use crate::quota::{quota_usage, set_quota_limit, QuotaId, QuotaLimits, QuotaUsage};
+
+    const LIMIT: u64 = 1 << 20;
+    let device = ram_ext4_device(16 << 20, *b"ext4-quota-limit")?;
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::builder_from_device(device)
+            .quota(true)
+            .mount(),
+    )?;
+    let as_fs = fs.clone() as Arc<dyn FileSystem>;
+    let user = QuotaId::User(1000);
+    let limits = QuotaLimits {
+        bytes: Some(LIMIT),
+        inodes: None,
+    };
+    ok("set the limit", set_quota_limit(&as_fs, user, limits))?;
+    let usage = |id| ok("quota usage", quota_usage(&as_fs, id));
+    let root_inodes = usage(QuotaId::User(0))?.inodes;
+    ensure!(root_inodes >= 1, "the root directory isn't charged");
+
+    let file = ok("touch", fs.root().touch("file"))?;
+    ok("chown", chown(&file, 1000, 1000))?;
+    ensure!(
+        usage(user)?
+            == QuotaUsage {
+                bytes: 0,
+                inodes: 1
+            },
+        "usage {:?} of the empty file",
+        usage(user)?
+    );
+    let chunk = [0x5a; 64 << 10];
+    let mut written = 0;
+    let err = loop {
+        match file.writeat(written, &chunk) {
+            Ok(n) => written += n,
+            Err(err) => break err,
+        }
+        ensure!(
+            written as u64 <= LIMIT,
+            "wrote {} bytes over the limit",
+            written
+        );
+    };
+    ensure!(
+        matches!(err, VfsError::StorageFull) && written as u64 >= LIMIT / 2,
+        "the write after {} bytes failed with {:?}",
+        written,
+        err
+    );
+    let mut stat = Stat::default();
+    ok("stat", file.stat(&mut stat))?;
+    let charged = usage(user)?;
+    ensure!(
+        stat.uid == 1000 && charged.bytes == stat.blocks as u64 * 512 && charged.bytes <= LIMIT,
+        "usage {:?} of a file of {} sectors",
+        charged,
+        stat.blocks
+    );
+    ensure!(
+        usage(QuotaId::Group(1000))? == charged,
+        "group usage {:?}",
+        usage(QuotaId::Group(1000))?
+    );
+
+    // the removal releases the blocks and the inode.
+    drop(file);
+    ok("remove", fs.root().remove("file"))?;
+    ensure!(
+        usage(user)? == QuotaUsage::default(),
+        "usage {:?} after the removal",
+        usage(user)?
+    );
+    let file = ok("touch", fs.root().touch("again"))?;
+    ok("chown", chown(&file, 1000, 1000))?;
+    for i in 0..8 {
+        ok("write again", file.writeat(i * chunk.len(), &chunk))?;
+    }
+    ensure!(
+        usage(user)?.bytes >= 8 * chunk.len() as u64,
+        "usage {:?} of the second file",
+        usage(user)?
+    );
+
+    // chown gives the charges back to root.
+    ok("chown to root", chown(&file, 0, 0))?;
+    ensure!(
+        usage(user)? == QuotaUsage::default() && usage(QuotaId::User(0))?.inodes == root_inodes + 1,
+        "usage {:?} and root {:?} after chown",
+        usage(user)?,
+        usage(QuotaId::User(0))?
+    );
+    Ok(())
+}
+
+/// Check the reserved blocks of ext4: a file of uid 1000 fills the image
+/// until StorageFull while statfs still has free blocks, none available,
+/// the reserve persists, and the files of root or a mount with
+/// reserve_override write into it.
+pub fn ext4_reserved_blocks() -> Result<(), String> {
+    use crate::owner::chown;
+
+    const RESERVED: u64 = 256;
+    let device = ram_ext4_device(8 << 20, *b"ext4-reserve-blk")?;
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::new_from_device(device.clone()),
+    )?;
+    ensure_err!(fs.set_reserved_blocks(1 << 40), VfsError::InvalidInput);
+    ok("reserve", fs.set_reserved_blocks(RESERVED))?;
+    let statfs = |node: &File| {
+        let mut statfs = StatFS::default();
+        ok("statfs", node.statfs(&mut statfs)).map(|_| statfs)
+    };
+    let before = statfs(&fs.root())?;
+    ensure!(
+        (before.bfree - before.bavail) as u64 == RESERVED,
+        "statfs {} free, {} available with the reserve",
+        before.bfree,
+        before.bavail
+    );
+
+    let file = ok("touch", fs.root().touch("user"))?;
+    ok("chown", chown(&file, 1000, 1000))?;
+    let chunk = [0x3c; 64 << 10];
+    let mut written = 0;
+    let err = loop {
+        match file.writeat(written, &chunk) {
+            Ok(n) => written += n,
+            Err(err) => break err,
+        }
+    };
+    ensure!(
+        matches!(err, VfsError::StorageFull) && written > 0,
+        "the write after {} bytes failed with {:?}",
+        written,
+        err
+    );
+    let full = statfs(&fs.root())?;
+    ensure!(
+        full.bavail == 0 && full.bfree > 0 && full.bfree as u64 <= RESERVED,
+        "statfs {} free, {} available when the user filled the image",
+        full.bfree,
+        full.bavail
+    );
+
+    // the reserve is on the disk.
+    ok("flush", FileSystem::flush(fs.as_ref()))?;
+    drop((file, fs));
+    let fs = ok(
+        "remount",
+        crate::Ext4FileSystem::new_from_device(device.clone()),
+    )?;
+    let again = statfs(&fs.root())?;
+    ensure!(
+        again.bfree == full.bfree && again.bavail == 0,
+        "statfs {} free, {} available after the remount",
+        again.bfree,
+        again.bavail
+    );
+    let file = ok("lookup", fs.root().lookup("user"))?;
+    ensure_err!(file.writeat(written, &chunk), VfsError::StorageFull);
+
+    // root digs into the reserve.
+    let root_file = ok("touch", fs.root().touch("root"))?;
+    ok("root write", root_file.writeat(0, &chunk[..4096]))?;
+    ensure!(
+        statfs(&fs.root())?.bfree < full.bfree,
+        "the write of root took no reserved block"
+    );
+    ok("flush", FileSystem::flush(fs.as_ref()))?;
+    drop((file, root_file, fs));
+
+    // and so does any owner with reserve_override.
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::builder_from_device(device)
+            .reserve_override(true)
+            .mount(),
+    )?;
+    let file = ok("lookup", fs.root().lookup("user"))?;
+    ok("override write", file.writeat(written, &chunk[..4096]))?;
+    let report = fs.check();
+    ensure!(
+        report.problems.is_empty(),
+        "the image with the reserve used has problems: {:?}",
+        report.problems
+    );
+    Ok(())
+}
+
+/// Check secure_delete of ext4: the blocks of a removed file with a known
+/// pattern are zeroed in the raw image, by writes of zeros, and by the
+/// write zeroes of the device for a file released at its last close. The
+/// image passes check afterwards.
+pub fn ext4_secure_delete() -> Result<(), String> {
+    use crate::blockdev::{set_write_zeroes, BlockDevice, Partition, WriteZeroes};
+
+    /// Zero through the partition, counting the calls.
+    struct Zeroes(Arc<Partition>, AtomicUsize);
+
+    impl WriteZeroes for Zeroes {
+        fn write_zeroes(&self, offset: usize, len: usize) {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            self.0.write_offset(offset, &alloc::vec![0; len]);
+        }
+    }
+
+    let options = crate::ext4_mkfs::Options {
+        uuid: *b"ext4-secure-del!",
+        ..Default::default()
+    };
+    let (ram, device) = ram_ext4_image(16 << 20, &options)?;
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::builder_from_device(device.clone())
+            .secure_delete(true)
+            .mount(),
+    )?;
+    let pattern = |seed: u8| -> Vec<u8> {
+        (0..256 << 10)
+            .map(|i: usize| (i as u8).wrapping_mul(31) ^ seed)
+            .collect()
+    };
+    let marker = |data: &[u8]| data[1000..1064].to_vec();
+    let in_image = |marker: &[u8]| ram.image().windows(marker.len()).any(|x| x == marker);
+
+    let first = pattern(0xa5);
+    let file = ok("touch", fs.root().touch("first"))?;
+    ok("write", file.writeat(0, &first))?;
+    ok("flush", file.flush())?;
+    ensure!(in_image(&marker(&first)), "the pattern isn't in the image");
+    drop(file);
+    ok("remove", fs.root().remove("first"))?;
+    ensure!(
+        !in_image(&marker(&first)),
+        "the pattern of the removed file is in the image"
+    );
+
+    // a file removed while it's open is released at the last close.
+    let mut stat = Stat::default();
+    ok("stat", fs.root().stat(&mut stat))?;
+    let zeroes = Arc::new(Zeroes(device, AtomicUsize::new(0)));
+    set_write_zeroes(stat.dev as usize, zeroes.clone());
+    let second = pattern(0x3c);
+    let file = ok("touch", fs.root().touch("second"))?;
+    ok("write", file.writeat(0, &second))?;
+    ok("flush", file.flush())?;
+    ok("remove", fs.root().remove("second"))?;
+    ensure!(
+        in_image(&marker(&second)),
+        "the open file lost its blocks at the removal"
+    );
+    drop(file);
+    ensure!(
+        !in_image(&marker(&second)) && zeroes.1.load(Ordering::Relaxed) > 0,
+        "the released file isn't zeroed by the write zeroes, {} calls",
+        zeroes.1.load(Ordering::Relaxed)
+    );
+    let report = fs.check();
+    ensure!(
+        report.problems.is_empty(),
+        "the image has problems: {:?}",
+        report.problems
+    );
+    Ok(())
+}
+
+/// Set flags in i_flags of the inode ino of the unmounted ext4 image of
+/// the device, with its checksum.
+fn set_raw_inode_flags(
+    device: &dyn crate::blockdev::BlockDevice,
+    ino: u32,
+    flags: u32,
+) -> Result<(), String> {
+    patch_raw_inode(device, ino, |raw| {
+        let old = u32::from_le_bytes(raw[0x20..0x24].try_into().unwrap());
+        raw[0x20..0x24].copy_from_slice(&(old | flags).to_le_bytes());
+    })
+}
+
+/// The byte offset of the inode ino in the inode table of the ext4 image
+/// of the device, with the superblock.
+pub(super) fn raw_inode_offset(
+    device: &dyn crate::blockdev::BlockDevice,
+    ino: u32,
+) -> (crate::ext4_layout::SuperBlockInfo, usize) {
+    use crate::ext4_layout::{GroupDesc, SuperBlockInfo, SUPERBLOCK_OFFSET};
+
+    let sb = SuperBlockInfo::parse(&device.read_offset(SUPERBLOCK_OFFSET)[..1024]);
+    let (group, index) = sb.inode_group(ino);
+    let desc = device.read_offset(sb.group_desc_offset(group));
+    let desc = GroupDesc::parse(&sb, &desc[..sb.group_desc_size()]);
+    let offset = desc.inode_table as usize * sb.block_size() + index * sb.inode_size as usize;
+    (sb, offset)
+}
+
+/// Change the inode ino of the unmounted ext4 image of the device by f,
+/// with its checksum.
+pub(super) fn patch_raw_inode(
+    device: &dyn crate::blockdev::BlockDevice,
+    ino: u32,
+    f: impl FnOnce(&mut [u8]),
+) -> Result<(), String> {
+    use crate::ext4_csum::set_inode_csum;
+
+    let (sb, offset) = raw_inode_offset(device, ino);
+    let mut raw = device.read_offset(offset)[..sb.inode_size as usize].to_vec();
+    ensure!(raw[..2] != [0, 0], "inode {} isn't in use", ino);
+    f(&mut raw);
+    if sb.has_metadata_csum() {
+        set_inode_csum(&sb, ino, &mut raw);
+    }
+    device.write_offset(offset, &raw);
+    Ok(())
+}
+
+/// Check the sealed inodes of ext4 on an image with the encrypt feature:
+/// an encrypted directory, a compressed file and a verity file are found
+/// and stat'ed, but their names and data fail with NotSupported instead
+/// of reading as plaintext, and the rest of the filesystem works as
+/// usual.
+pub fn ext4_sealed_inodes() -> Result<(), String> {
+    use crate::blockdev::BlockDevice;
+    use crate::ext4_csum::set_superblock_csum;
+    use crate::ext4_layout::{
+        EXT4_COMPR_FL, EXT4_ENCRYPT_FL, EXT4_VERITY_FL, INCOMPAT_ENCRYPT, SUPERBLOCK_OFFSET,
+    };
+
+    let device = ram_ext4_device(16 << 20, *b"ext4-sealed-test")?;
+    let mount = || {
+        let fs = crate::Ext4FileSystem::builder_from_device(device.clone()).mount();
+        ok("mount", fs)
+    };
+    let ino = |file: &File| -> Result<u32, String> {
+        let mut stat = Stat::default();
+        ok("stat", file.stat(&mut stat))?;
+        Ok(stat.ino as u32)
+    };
+    let mut sealed = Vec::new();
+    {
+        let fs = mount()?;
+        let root = fs.root();
+        let secret = ok("mkdir", root.mkdir("secret"))?;
+        let inner = ok("touch", secret.touch("inner"))?;
+        ok("write", inner.writeat(0, b"ciphertext"))?;
+        ok("flush", inner.flush())?;
+        sealed.push((ino(&secret)?, EXT4_ENCRYPT_FL));
+        for (name, flag) in [("packed", EXT4_COMPR_FL), ("verity", EXT4_VERITY_FL)] {
+            let file = ok("touch", root.touch(name))?;
+            ok("write", file.writeat(0, b"sealed data"))?;
+            ok("flush", file.flush())?;
+            sealed.push((ino(&file)?, flag));
+        }
+        let plain = ok("touch", root.touch("plain"))?;
+        ok("write", plain.writeat(0, b"plain data"))?;
+        ok("flush", plain.flush())?;
+    }
+    let mut sb = device.read_offset(SUPERBLOCK_OFFSET)[..1024].to_vec();
+    let incompat = u32::from_le_bytes(sb[0x60..0x64].try_into().unwrap()) | INCOMPAT_ENCRYPT;
+    sb[0x60..0x64].copy_from_slice(&incompat.to_le_bytes());
+    set_superblock_csum(&mut sb);
+    device.write_offset(SUPERBLOCK_OFFSET, &sb);
+    for &(ino, flag) in sealed.iter() {
+        set_raw_inode_flags(device.as_ref(), ino, flag)?;
+    }
+
+    let fs = mount()?;
+    let root = fs.root();
+    let names = names(&root)?;
+    for name in ["secret", "packed", "verity", "plain"] {
+        ensure!(
+            names.iter().any(|x| x == name),
+            "{} isn't listed in {:?}",
+            name,
+            names
+        );
+    }
+    let secret = ok("lookup secret", root.lookup("secret"))?;
+    let mut stat = Stat::default();
+    ok("stat secret", secret.stat(&mut stat))?;
+    ensure_err!(secret.read_dir(), VfsError::NotSupported);
+    ensure_err!(secret.lookup("inner"), VfsError::NotSupported);
+    ensure_err!(
+        secret.open("inner", OpenFlags::NONE),
+        VfsError::NotSupported
+    );
+    ensure_err!(secret.touch("new"), VfsError::NotSupported);
+    ensure_err!(secret.mkdir("new"), VfsError::NotSupported);
+    ensure_err!(secret.remove("inner"), VfsError::NotSupported);
+    let mut buf = [0u8; 16];
+    for name in ["packed", "verity"] {
+        let file = ok("lookup", root.lookup(name))?;
+        ok("stat", file.stat(&mut stat))?;
+        ensure!(stat.size == 11, "{} has the size {}", name, stat.size);
+        ensure_err!(file.readat(0, &mut buf), VfsError::NotSupported);
+        ensure_err!(file.writeat(0, b"over"), VfsError::NotSupported);
+        ensure_err!(file.truncate(0), VfsError::NotSupported);
+    }
+
+    let plain = ok("lookup plain", root.lookup("plain"))?;
+    ensure!(
+        read_all(&plain, 10)? == b"plain data",
+        "the plain file reads wrong"
+    );
+    ok("write plain", plain.writeat(10, b" more"))?;
+    let file = ok("touch", root.touch("fresh"))?;
+    ok("write fresh", file.writeat(0, b"fresh"))?;
+    ensure!(read_all(&file, 5)? == b"fresh", "the new file reads wrong");
+    ok("mkdir", root.mkdir("dir"))?;
+    Ok(())
+}
+
+/// Write past the 60 bytes of i_block to a file with inline data on
+/// ext4: the data moves to a block, the inode loses EXT4_INLINE_DATA_FL
+/// and the system.data xattr for an extent tree, and the file reads back
+/// whole, after a remount too.
+pub fn ext4_inline_write() -> Result<(), String> {
+    use crate::blockdev::BlockDevice;
+    use crate::ext4_csum::set_superblock_csum;
+    use crate::ext4_layout::{
+        EXT4_EXTENTS_FL, EXT4_INLINE_DATA_FL, INCOMPAT_INLINE_DATA, SUPERBLOCK_OFFSET,
+    };
+
+    const INLINE: &[u8] = b"forty bytes stored in i_block of inode!!";
+    const MORE: &[u8] = b"and sixty more bytes after them, which outgrow the i_block!!";
+    let device = ram_ext4_device(16 << 20, *b"ext4-inline-data")?;
+    let mount = || {
+        ok(
+            "mount",
+            crate::Ext4FileSystem::new_from_device(device.clone()),
+        )
+    };
+    let ino = {
+        let fs = mount()?;
+        let file = ok("touch", fs.root().touch("inline"))?;
+        let mut stat = Stat::default();
+        ok("stat", file.stat(&mut stat))?;
+        stat.ino as u32
+    };
+    let mut sb = device.read_offset(SUPERBLOCK_OFFSET)[..1024].to_vec();
+    let incompat = u32::from_le_bytes(sb[0x60..0x64].try_into().unwrap()) | INCOMPAT_INLINE_DATA;
+    sb[0x60..0x64].copy_from_slice(&incompat.to_le_bytes());
+    set_superblock_csum(&mut sb);
+    device.write_offset(SUPERBLOCK_OFFSET, &sb);
+    // i_block holds the data, system.data in the body is empty.
+    patch_raw_inode(device.as_ref(), ino, |raw| {
+        let flags = u32::from_le_bytes(raw[0x20..0x24].try_into().unwrap());
+        let flags = (flags & !EXT4_EXTENTS_FL) | EXT4_INLINE_DATA_FL;
+        raw[0x20..0x24].copy_from_slice(&flags.to_le_bytes());
+        raw[0x04..0x08].copy_from_slice(&(INLINE.len() as u32).to_le_bytes());
+        raw[0x28..0x28 + 60].fill(0);
+        raw[0x28..0x28 + INLINE.len()].copy_from_slice(INLINE);
+        let body = 128 + u16::from_le_bytes([raw[0x80], raw[0x81]]) as usize;
+        raw[body..].fill(0);
+        raw[body..body + 4].copy_from_slice(&0xea02_0000u32.to_le_bytes());
+        // name_len, name_index system, then no value.
+        raw[body + 4] = 4;
+        raw[body + 5] = 7;
+        raw[body + 20..body + 24].copy_from_slice(b"data");
+    })?;
+
+    let fs = mount()?;
+    let file = ok("lookup", fs.root().lookup("inline"))?;
+    ensure!(
+        read_all(&file, 256)? == INLINE,
+        "the inline data reads wrong"
+    );
+    ok("write", file.writeat(INLINE.len(), MORE))?;
+    ok("flush", file.flush())?;
+    let whole = [INLINE, MORE].concat();
+    ensure!(
+        read_all(&file, 256)? == whole,
+        "the converted file reads wrong"
+    );
+    let report = fs.check();
+    ensure!(
+        report.problems.is_empty(),
+        "the converted image has problems: {:?}",
+        report.problems
+    );
+    ok("flush", FileSystem::flush(fs.as_ref()))?;
+    drop((file, fs));
+
+    let (sb, offset) = raw_inode_offset(device.as_ref(), ino);
+    let raw = device.read_offset(offset)[..sb.inode_size as usize].to_vec();
+    let flags = u32::from_le_bytes(raw[0x20..0x24].try_into().unwrap());
+    ensure!(
+        flags & EXT4_INLINE_DATA_FL == 0 && flags & EXT4_EXTENTS_FL != 0,
+        "the converted inode has the flags {:#x}",
+        flags
+    );
+    let body = 128 + u16::from_le_bytes([raw[0x80], raw[0x81]]) as usize;
+    ensure!(
+        raw[body + 4..body + 24].iter().all(|&x| x == 0),
+        "the converted inode keeps system.data"
+    );
+    let fs = mount()?;
+    let file = ok("lookup", fs.root().lookup("inline"))?;
+    ensure!(
+        read_all(&file, 256)? == whole,
+        "the converted file reads wrong after the remount"
+    );
+    Ok(())
+}
+
+/// Check the device numbers of the ext4 device nodes in both encodings of
+/// i_block: a small one is stored in the old encoding of i_block[0], a big
+/// one in the new encoding of i_block[1], and a small one rewritten in the
+/// new encoding, like other implementations may write it, reads back the
+/// same. None of the nodes maps a block, they pass the check before and
+/// after their removal.
+pub fn ext4_device_numbers() -> Result<(), String> {
+    use crate::devnode::make_dev;
+    use crate::mknod::{mknod, S_IFBLK, S_IFCHR, S_IFIFO};
+    use crate::statx::Statx;
+
+    let device = ram_ext4_device(16 << 20, *b"ext4-device-nums")?;
+    let mount = || {
+        ok(
+            "mount",
+            crate::Ext4FileSystem::new_from_device(device.clone()),
+        )
+    };
+    let check = |fs: &crate::Ext4FileSystem| {
+        let report = fs.check();
+        ensure!(
+            report.problems.is_empty(),
+            "the image has problems: {:?}",
+            report.problems
+        );
+        Ok(())
+    };
+    // the name, the mode, the major and the minor.
+    let nodes = [
+        ("tty", S_IFCHR | 0o620, 4, 64),
+        ("nvme", S_IFBLK | 0o660, 259, 70000),
+        ("zero", S_IFCHR | 0o666, 1, 5),
+        ("fifo", S_IFIFO | 0o644, 0, 0),
+    ];
+    let mut inos = Vec::new();
+    {
+        let fs = mount()?;
+        let root = fs.root();
+        for (name, mode, major, minor) in nodes {
+            let node = ok("mknod", mknod(&root, name, mode, make_dev(major, minor)))?;
+            let mut stat = Stat::default();
+            ok("stat", node.stat(&mut stat))?;
+            inos.push(stat.ino as u32);
+        }
+        check(fs.as_ref())?;
+    }
+
+    let i_block = |ino: u32| -> Result<[u32; 2], String> {
+        let mut words = [0; 2];
+        patch_raw_inode(device.as_ref(), ino, |raw| {
+            for (i, word) in words.iter_mut().enumerate() {
+                let at = 0x28 + i * 4;
+                *word = u32::from_le_bytes(raw[at..at + 4].try_into().unwrap());
+            }
+        })?;
+        Ok(words)
+    };
+    ensure!(
+        i_block(inos[0])? == [0x440, 0],
+        "4:64 isn't in the old encoding: {:x?}",
+        i_block(inos[0])?
+    );
+    ensure!(
+        i_block(inos[1])? == [0, 0x1111_0370],
+        "259:70000 isn't in the new encoding: {:x?}",
+        i_block(inos[1])?
+    );
+    ensure!(i_block(inos[3])? == [0, 0], "the FIFO has a device");
+    // 1:5 in the new encoding, i_block[0] is 0 then.
+    patch_raw_inode(device.as_ref(), inos[2], |raw| {
+        raw[0x28..0x2c].fill(0);
+        raw[0x2c..0x30].copy_from_slice(&0x105u32.to_le_bytes());
+    })?;
+
+    let fs = mount()?;
+    let root = fs.root();
+    for (name, mode, major, minor) in nodes {
+        let node = ok("lookup", root.lookup(name))?;
+        let mut stat = Stat::default();
+        ok("stat", node.stat(&mut stat))?;
+        let kind = match mode & 0o170000 {
+            S_IFCHR => StatMode::CHAR,
+            S_IFBLK => StatMode::BLOCK,
+            _ => StatMode::FIFO,
+        };
+        let statx = Statx::from_stat(&stat);
+        ensure!(
+            stat.mode.contains(kind)
+                && (statx.rdev_major, statx.rdev_minor) == (major, minor)
+                && stat.rdev == make_dev(major, minor) as _
+                && stat.blocks == 0,
+            "{} has the mode {:?}, the device {}:{} and {} blocks",
+            name,
+            stat.mode,
+            statx.rdev_major,
+            statx.rdev_minor,
+            stat.blocks
+        );
+    }
+    check(fs.as_ref())?;
+    for (name, ..) in nodes {
+        ok("remove", root.remove(name))?;
+    }
+    check(fs.as_ref())
+}
+
+/// Make a block device node of a device number beyond the 16 bits of the
+/// old encoding with mknod and stat it, through the node and a lookup. An
+/// existing name, a device number ext4 can't store and a directory fail.
+pub fn ext4_mknod_round_trip() -> Result<(), String> {
+    use crate::devnode::make_dev;
+    use crate::mknod::{mknod, S_IFBLK, S_IFDIR};
+    use crate::statx::Statx;
+
+    let fs = ram_ext4(16 << 20, *b"ext4-mknod-round")?;
+    let root = fs.root();
+    let rdev = make_dev(259, 70000);
+    let node = ok("mknod", mknod(&root, "nvme0n1p1", S_IFBLK | 0o600, rdev))?;
+    let found = ok("lookup", root.lookup("nvme0n1p1"))?;
+    for node in [node, found] {
+        let mut stat = Stat::default();
+        ok("stat", node.stat(&mut stat))?;
+        let statx = Statx::from_stat(&stat);
+        ensure!(
+            stat.mode.contains(StatMode::BLOCK)
+                && stat.rdev == rdev as _
+                && (statx.rdev_major, statx.rdev_minor) == (259, 70000),
+            "the node has the mode {:?} and the device {}:{}",
+            stat.mode,
+            statx.rdev_major,
+            statx.rdev_minor
+        );
+    }
+    ensure_errno!(
+        mknod(&root, "nvme0n1p1", S_IFBLK | 0o600, rdev),
+        Errno::EEXIST
+    );
+    ensure_errno!(
+        mknod(&root, "huge", S_IFBLK | 0o600, make_dev(0x1000, 0)),
+        Errno::EINVAL
+    );
+    ensure_errno!(mknod(&root, "dir", S_IFDIR | 0o755, 0), Errno::EPERM);
+    let report = fs.check();
+    ensure!(
+        report.problems.is_empty(),
+        "the image has problems: {:?}",
+        report.problems
+    );
+    Ok(())
+}
+
+/// The pathconf limits of ext4: LINK_MAX 65000, NAME_MAX 255 and the
+/// transfers by the block size.
+pub fn ext4_pathconf() -> Result<(), String> {
+    use crate::pathconf::{fpathconf, PathconfName, EXT4_LINK_MAX};
+
+    let fs = ram_ext4(4 << 20, [193; 16])?;
+    let root = fs.root();
+    let dir = ok("mkdir", root.mkdir("dir"))?;
+    let file = ok("touch", dir.touch("file"))?;
+    for node in [&root, &dir, &file] {
+        let value = |name| ok("fpathconf", fpathconf(node, name));
+        let expected = [
+            (PathconfName::LinkMax, EXT4_LINK_MAX),
+            (PathconfName::NameMax, 255),
+            (PathconfName::FilesizeBits, 64),
+            (PathconfName::TwoSymlinks, 1),
+            (PathconfName::RecXferAlign, 4096),
+            (PathconfName::AllocSizeMin, 4096),
+        ];
+        for (name, expected) in expected {
+            let actual = value(name)?;
+            ensure!(actual == expected, "{:?} of ext4 is {}", name, actual);
+        }
+    }
+    Ok(())
+}
+
+/// Check the handles of ext4: the handle of a deleted file is stale once
+/// a new file reuses its inode, and the handles stay valid across a
+/// remount of the image.
+pub fn ext4_handles() -> Result<(), String> {
+    use crate::export::{file_handle, open_by_handle};
+
+    let device = ram_ext4_device(8 << 20, *b"Byte-OS ext4mkfs")?;
+    let mount = || {
+        let fs = crate::Ext4FileSystem::builder_from_device(device.clone())
+            .time_source(tick)
+            .mount();
+        ok("mount", fs).map(|fs| (fs.root(), fs as Arc<dyn FileSystem>))
+    };
+    let (root, fs) = mount()?;
+    let old = {
+        let file = ok("touch", root.touch("old"))?;
+        ok("writeat", file.writeat(0, b"old"))?;
+        ok("file_handle", file_handle(&fs, &*file))?
+    };
+    ok("remove", root.remove("old"))?;
+    let new = ok("touch", root.touch("new"))?;
+    ok("writeat", new.writeat(0, b"new"))?;
+    ok("flush", new.flush())?;
+    let handle = ok("file_handle", file_handle(&fs, &*new))?;
+    ensure!(
+        handle.ino == old.ino,
+        "inode {} isn't reused, the new file is {}",
+        old.ino,
+        handle.ino
+    );
+    ensure!(
+        handle.generation != old.generation,
+        "the reused inode kept generation {}",
+        old.generation
+    );
+    ensure_errno!(open_by_handle(&fs, old), Errno::ESTALE);
+    let opened = ok("open_by_handle", open_by_handle(&fs, handle))?;
+    ensure!(
+        read_all(&opened, 16)? == b"new",
+        "the handle opened another file"
+    );
+
+    drop((opened, new, root, fs));
+    let (_root, fs) = mount()?;
+    let opened = ok("open_by_handle after remount", open_by_handle(&fs, handle))?;
+    ensure!(
+        read_all(&opened, 16)? == b"new",
+        "the handle opened another file after remount"
+    );
+    ensure_errno!(open_by_handle(&fs, old), Errno::ESTALE);
+    Ok(())
+}
@@ -0,0 +1,1880 @@
+// The ext4 cases of the data: the direct io, the extent trees and the
+// allocation, the write requests and the syncs, the bulk creates, the
+// cancelled writes, the write guard, the async reads and iosched.
+
+use super::*;
+
+/// Check the direct I/O of ext4 over a block cache: a file of 32 MiB
+/// streamed by a direct handle reads back whole without filling the block
+/// cache or the page cache, a cached read fills them. An unaligned direct
+/// read goes through the caches, or fails on a strict mount.
+pub fn ext4_direct_io() -> Result<(), String> {
+    use crate::blockdev::{set_direct_read, BlockDevice, CachedDevice};
+    use crate::cache::{self, InodeId, PAGE_SIZE};
+    use crate::ext4_mkfs::{format, Options};
+    use crate::testing::MockDisk;
+
+    const SIZE: usize = 64 << 20;
+    const FILE: usize = 32 << 20;
+    const CHUNK: usize = 1 << 20;
+    let options = Options {
+        uuid: *b"ext4-direct-io-t",
+        ..Default::default()
+    };
+    let disk = Arc::new(MockDisk::new(SIZE, 512));
+    ok(
+        "format",
+        format(SIZE as u64, &options, |block, data| {
+            disk.write_at(block as usize * options.block_size, data)
+        }),
+    )?;
+    let cached = Arc::new(CachedDevice::new(disk.clone(), 1 << 16));
+    let data: Vec<u8> = (0..FILE).map(|x| (x / 4096 + x % 251) as u8).collect();
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::new_from_device(cached.clone() as Arc<dyn BlockDevice + Send + Sync>),
+    )?;
+    set_direct_read(fs.dev(), cached.clone());
+    let file = ok("touch", fs.root().touch("stream"))?;
+    for (i, chunk) in data.chunks(CHUNK).enumerate() {
+        ok("writeat", file.writeat(i * CHUNK, chunk))?;
+    }
+    ok("flush", file.flush())?;
+    cache::drop_caches();
+    let id = InodeId {
+        dev: fs.dev(),
+        ino: ok("metadata", file.metadata())?.inode as u64,
+    };
+
+    let handle = FileHandle::new(file.clone(), OpenFlags::O_RDONLY);
+    handle.set_direct(true);
+    let before = cached.cached();
+    let mut buf = vec![0; CHUNK];
+    for (i, chunk) in data.chunks(CHUNK).enumerate() {
+        let n = ok("direct readat", handle.readat(i * CHUNK, &mut buf))?;
+        ensure!(
+            n == CHUNK && buf == chunk,
+            "direct read {} of {} bytes",
+            i,
+            n
+        );
+    }
+    // the lookups of the inode and its extents, not the data.
+    let grown = cached.cached() - before;
+    ensure!(grown <= 8, "the direct reads cached {} reads", grown);
+    ensure!(
+        (0..FILE / PAGE_SIZE).all(|x| !cache::contains(id, x)),
+        "the direct reads filled the page cache"
+    );
+
+    // an unaligned read falls back to the caches.
+    let n = ok("unaligned readat", handle.readat(1000, &mut buf[..3000]))?;
+    ensure!(
+        n == 3000 && buf[..3000] == data[1000..4000],
+        "unaligned direct read of {} bytes",
+        n
+    );
+    ensure!(cache::contains(id, 0), "the unaligned read isn't cached");
+    handle.set_direct(false);
+    let before = cached.cached();
+    ok("cached readat", handle.readat(CHUNK, &mut buf))?;
+    ensure!(
+        cached.cached() >= before + CHUNK / 4096,
+        "the cached read added {} reads",
+        cached.cached() - before
+    );
+
+    // a direct write is read back by both.
+    let block = vec![0x5a; 4096];
+    let rw = FileHandle::new(file.clone(), OpenFlags::O_RDWR);
+    rw.set_direct(true);
+    ok("direct writeat", rw.writeat(CHUNK, &block))?;
+    ok("direct readat", rw.readat(CHUNK, &mut buf[..4096]))?;
+    ensure!(
+        buf[..4096] == block[..],
+        "direct read after the direct write"
+    );
+    rw.set_direct(false);
+    ok("cached readat", rw.readat(CHUNK, &mut buf[..4096]))?;
+    ensure!(
+        buf[..4096] == block[..],
+        "cached read after the direct write"
+    );
+    drop((handle, rw, file, fs));
+
+    let fs = ok(
+        "mount strict",
+        crate::Ext4FileSystem::builder_from_device(cached as Arc<dyn BlockDevice + Send + Sync>)
+            .strict_direct(true)
+            .mount(),
+    )?;
+    let file = ok("lookup", fs.root().lookup("stream"))?;
+    let handle = FileHandle::new(file, OpenFlags::O_RDONLY);
+    handle.set_direct(true);
+    ensure_err!(
+        handle.readat(1000, &mut buf[..3000]),
+        VfsError::InvalidInput
+    );
+    ok("aligned readat", handle.readat(0, &mut buf[..4096]))?;
+    ensure!(buf[..4096] == data[..4096], "strict direct read");
+    Ok(())
+}
+
+/// Write and truncate a sparse file at random on ext4 with 1 KiB blocks,
+/// its extents outgrow the inode and a leaf. The file matches a copy in
+/// memory all along, and the tree is shallower again once the file is
+/// cut to a few blocks.
+#[cfg(feature = "ext4_debug")]
+pub fn ext4_extent_tree_random() -> Result<(), String> {
+    const SPAN: usize = 2 << 20;
+    let options = crate::ext4_mkfs::Options {
+        block_size: 1024,
+        uuid: *b"ext4-extent-tree",
+        ..Default::default()
+    };
+    let (_, device) = ram_ext4_image(16 << 20, &options)?;
+    let fs = ok("mount", crate::Ext4FileSystem::new_from_device(device))?;
+    let file = ok("touch", fs.root().touch("sparse"))?;
+    let mut stat = Stat::default();
+    ok("stat", file.stat(&mut stat))?;
+    let ino = stat.ino as u32;
+    let mut model: Vec<u8> = Vec::new();
+    let mut seed = 0x2545_f491_u64;
+    let mut next = |max: usize| {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (seed >> 33) as usize % max
+    };
+    let mut nodes = 0;
+    for round in 0..2000 {
+        if next(10) == 0 {
+            let size = next(SPAN);
+            ok("truncate", file.truncate(size))?;
+            model.resize(size, 0);
+        } else {
+            // short writes on every other block keep the extents apart.
+            let offset = next(SPAN / 2048) * 2048 + next(1024);
+            let data = vec![round as u8 | 1; next(1500) + 1];
+            ok("write", file.writeat(offset, &data))?;
+            if model.len() < offset + data.len() {
+                model.resize(offset + data.len(), 0);
+            }
+            model[offset..offset + data.len()].copy_from_slice(&data);
+        }
+        let dump = ok("dump", fs.dump_inode(ino))?;
+        nodes = nodes.max(dump.tree.len());
+        if round % 250 == 0 || round == 1999 {
+            let mut back = vec![0; model.len()];
+            let read = ok("read", file.readat(0, &mut back))?;
+            ensure!(
+                read == model.len() && back == model,
+                "the file differs after {} rounds",
+                round
+            );
+        }
+    }
+    ensure!(nodes >= 3, "the tree had {} nodes at most", nodes);
+    ok("truncate", file.truncate(3000))?;
+    model.truncate(3000);
+    let mut back = vec![0; 4096];
+    let read = ok("read", file.readat(0, &mut back))?;
+    ensure!(back[..read] == model[..], "the file differs after the cut");
+    let dump = ok("dump", fs.dump_inode(ino))?;
+    ensure!(
+        dump.tree.len() == 1,
+        "the cut file keeps {} extent nodes",
+        dump.tree.len()
+    );
+    let report = fs.check();
+    ensure!(
+        report.problems.is_empty(),
+        "the image has problems: {:?}",
+        report.problems
+    );
+    Ok(())
+}
+
+/// Create a tree of 500 files in 10 directories of the root on ext4 with 8
+/// groups: the directories spread over the groups, the blocks of every
+/// file are in the group of its inode, written in two appends.
+#[cfg(feature = "ext4_debug")]
+pub fn ext4_allocation_locality() -> Result<(), String> {
+    use alloc::collections::BTreeSet;
+
+    const DIRS: usize = 10;
+    const FILES: usize = 50;
+    let options = crate::ext4_mkfs::Options {
+        block_size: 1024,
+        uuid: *b"ext4-locality-ok",
+        ..Default::default()
+    };
+    let (_, device) = ram_ext4_image(64 << 20, &options)?;
+    let fs = ok("mount", crate::Ext4FileSystem::new_from_device(device))?;
+    let sb = fs.dump_superblock().info;
+    ensure!(sb.groups_count() == 8, "{} groups", sb.groups_count());
+    let ino = |file: &Arc<dyn INodeInterface>| -> Result<u32, String> {
+        let mut stat = Stat::default();
+        ok("stat", file.stat(&mut stat))?;
+        Ok(stat.ino as u32)
+    };
+    let mut dir_groups = BTreeSet::new();
+    for d in 0..DIRS {
+        let dir = ok("mkdir", fs.root().mkdir(&format!("pkg-{}", d)))?;
+        dir_groups.insert(sb.inode_group(ino(&dir)?).0);
+        for f in 0..FILES {
+            let file = ok("touch", dir.touch(&format!("file-{}", f)))?;
+            let data = vec![(d * FILES + f) as u8; (f % 5 + 1) * 3000];
+            let half = data.len() / 2;
+            ok("write", file.writeat(0, &data[..half]))?;
+            ok("flush", file.flush())?;
+            ok("append", file.writeat(half, &data[half..]))?;
+            ok("flush", file.flush())?;
+            let ino = ino(&file)?;
+            let group = sb.inode_group(ino).0 as u64;
+            let dump = ok("dump", fs.dump_inode(ino))?;
+            let (mut near, mut total) = (0, 0);
+            for extent in dump.extents() {
+                for block in extent.physical..extent.physical + extent.len as u64 {
+                    let block_group =
+                        (block - sb.first_data_block as u64) / sb.blocks_per_group as u64;
+                    near += (block_group == group) as u32;
+                    total += 1;
+                }
+            }
+            ensure!(
+                total > 0 && near * 10 > total * 8,
+                "{} of the {} blocks of pkg-{}/file-{} are in the group {} of its inode",
+                near,
+                total,
+                d,
+                f,
+                group
+            );
+        }
+    }
+    ensure!(
+        dir_groups.len() >= 4,
+        "the directories of the root are in the groups {:?}",
+        dir_groups
+    );
+    let report = fs.check();
+    ensure!(
+        report.problems.is_empty(),
+        "the image has problems: {:?}",
+        report.problems
+    );
+    Ok(())
+}
+
+/// Append to two files of a directory in turn on ext4, each append
+/// written back before the next: the allocation windows keep the blocks
+/// after the appends of one file from the other, every file is one
+/// extent.
+#[cfg(feature = "ext4_debug")]
+pub fn ext4_interleaved_appends() -> Result<(), String> {
+    const ROUNDS: usize = 16;
+    const CHUNK: usize = 16 << 10;
+    let fs = ram_ext4(32 << 20, *b"ext4-interleaved")?;
+    let files = [
+        ok("touch", fs.root().touch("log-a"))?,
+        ok("touch", fs.root().touch("log-b"))?,
+    ];
+    for round in 0..ROUNDS {
+        for (i, file) in files.iter().enumerate() {
+            let data = [(round * 2 + i) as u8; CHUNK];
+            ok("append", file.writeat(round * CHUNK, &data))?;
+            ok("flush", file.flush())?;
+        }
+    }
+    for (i, file) in files.iter().enumerate() {
+        let mut stat = Stat::default();
+        ok("stat", file.stat(&mut stat))?;
+        let dump = ok("dump", fs.dump_inode(stat.ino as u32))?;
+        let extents: Vec<_> = dump.extents().collect();
+        ensure!(
+            extents.len() == 1 && extents[0].len as usize * 4096 == ROUNDS * CHUNK,
+            "the file {} has the extents {:?}",
+            i,
+            extents
+        );
+        let data = read_all(file, 1 << 20)?;
+        ensure!(
+            data.len() == ROUNDS * CHUNK
+                && data
+                    .chunks(CHUNK)
+                    .enumerate()
+                    .all(|(round, x)| x.iter().all(|&b| b == (round * 2 + i) as u8)),
+            "the file {} reads back wrong",
+            i
+        );
+    }
+    let report = fs.check();
+    ensure!(
+        report.problems.is_empty(),
+        "the image has problems: {:?}",
+        report.problems
+    );
+    Ok(())
+}
+
+/// The write requests of the small synchronous writes of ext4: an append
+/// of 100 bytes within the last block is a request for the data block
+/// and one for the inode without a journal, a long append writes its
+/// consecutive blocks by one request, and with a journal the log of the
+/// append is one request too.
+pub fn ext4_write_requests() -> Result<(), String> {
+    use crate::testing::{MockDisk, MockOp, Request};
+
+    const BLOCK: usize = 4096;
+    let writes = |disk: &MockDisk| -> Vec<Request> {
+        let log = disk.log().into_iter().filter(|x| x.op == MockOp::Write);
+        log.collect()
+    };
+    for journal_blocks in [0, 64] {
+        let disk = Arc::new(MockDisk::from_image(crash_image(journal_blocks)?, 512));
+        let fs = ok(
+            "mount",
+            crate::Ext4FileSystem::new_from_device(disk.clone()),
+        )?;
+        ensure!(
+            fs.mount_info().journaled == (journal_blocks > 0),
+            "the journal of the image with {} journal blocks",
+            journal_blocks
+        );
+        let file = ok("touch", fs.root().touch("log"))?;
+        ok("write", file.writeat(0, &[1; 1000]))?;
+        ok("flush", file.flush())?;
+        ok("flush", FileSystem::flush(fs.as_ref()))?;
+
+        disk.clear_log();
+        ok("append", file.writeat(1000, &[2; 100]))?;
+        ok("flush", file.flush())?;
+        let append = writes(&disk);
+        match journal_blocks {
+            0 => ensure!(
+                append.len() <= 2,
+                "the append without a journal wrote {:?}",
+                append
+            ),
+            // a descriptor, the inode block and the commit block.
+            _ => ensure!(
+                append.iter().any(|x| x.len >= 3 * BLOCK),
+                "the log of the append isn't one request: {:?}",
+                append
+            ),
+        }
+        let data = read_all(&file, 2000)?;
+        ensure!(
+            data.len() == 1100 && data[..1000] == [1; 1000] && data[1000..] == [2; 100],
+            "the file has {} bytes after the append",
+            data.len()
+        );
+
+        disk.clear_log();
+        let long = vec![3; 64 << 10];
+        ok("long append", file.writeat(1100, &long))?;
+        ok("flush", file.flush())?;
+        let append = writes(&disk);
+        ensure!(
+            append.iter().any(|x| x.len >= long.len()),
+            "the long append wasn't merged: {:?}",
+            append
+        );
+        ensure!(
+            read_all(&file, 1 << 20)?[1100..] == long[..],
+            "the long append reads back wrong"
+        );
+        ok("flush", FileSystem::flush(fs.as_ref()))?;
+        let report = fs.check();
+        ensure!(
+            report.problems.is_empty(),
+            "the image has problems: {:?}",
+            report.problems
+        );
+    }
+    Ok(())
+}
+
+/// The synchronous writes of ext4 on the log of a MockDisk: a write of
+/// O_SYNC returns after a flush of the device, with the inode written
+/// before it. A write of O_DSYNC which only moves the times writes the
+/// inode after the flush, an append of O_DSYNC writes the size before it.
+/// fsync and fdatasync of a buffered write flush the device too.
+pub fn ext4_sync_writes() -> Result<(), String> {
+    use crate::blockdev::set_device_flush;
+    use crate::fsync;
+    use crate::testing::{MockDisk, MockOp, Request};
+    use vfscore::TimeSpec;
+
+    // the position of the first and the last flush, and whether a write
+    // before it or after it covers the byte at. The journaled commits
+    // flush the device too.
+    let first_flush = |log: &[Request]| log.iter().position(|x| x.op == MockOp::Flush);
+    let last_flush = |log: &[Request]| log.iter().rposition(|x| x.op == MockOp::Flush);
+    let covers =
+        |x: &Request, at: usize| x.op == MockOp::Write && x.offset <= at && at < x.offset + x.len;
+    for journal_blocks in [0, 64] {
+        let disk = Arc::new(MockDisk::from_image(crash_image(journal_blocks)?, 512));
+        let fs = ok(
+            "mount",
+            crate::Ext4FileSystem::new_from_device(disk.clone()),
+        )?;
+        let node = ok("touch", fs.root().touch("sync"))?;
+        let mut stat = Stat::default();
+        ok("stat", node.stat(&mut stat))?;
+        set_device_flush(stat.dev as usize, disk.clone());
+        ok("write", node.writeat(0, &[1; 64 << 10]))?;
+        ok("flush", node.flush())?;
+        ok("flush", FileSystem::flush(fs.as_ref()))?;
+        let (_, inode) = raw_inode_offset(disk.as_ref(), stat.ino as u32);
+
+        let file = FileHandle::new(node.clone(), OpenFlags::O_RDWR | OpenFlags::O_SYNC);
+        ensure!(
+            file.status_flags().contains(OpenFlags::O_SYNC),
+            "F_GETFL lost O_SYNC"
+        );
+        disk.clear_log();
+        ok("O_SYNC write", file.writeat(4096, &[2; 4096]))?;
+        let log = disk.log();
+        ensure!(
+            log.last().is_some_and(|x| x.op == MockOp::Flush),
+            "the O_SYNC write returned before a flush: {:?}",
+            log
+        );
+        ensure!(
+            log.iter().any(|x| covers(x, inode)),
+            "the O_SYNC write didn't write the inode before the flush: {:?}",
+            log
+        );
+
+        // an overwrite moves only the times.
+        let mut set = [TimeSpec { sec: 1000, nsec: 0 }; 2];
+        ok("utimes", node.utimes(&mut set))?;
+        let file = FileHandle::new(node.clone(), OpenFlags::O_RDWR | OpenFlags::O_DSYNC);
+        disk.clear_log();
+        ok("O_DSYNC write", file.writeat(8192, &[3; 4096]))?;
+        let log = disk.log();
+        let flush =
+            first_flush(&log).ok_or(format!("the O_DSYNC write didn't flush: {:?}", log))?;
+        ensure!(
+            !log[..flush].iter().any(|x| covers(x, inode)),
+            "the O_DSYNC overwrite wrote the inode before the flush: {:?}",
+            log
+        );
+        ensure!(
+            log[flush..].iter().any(|x| covers(x, inode)),
+            "the O_DSYNC overwrite didn't write the times: {:?}",
+            log
+        );
+        ok("stat", node.stat(&mut stat))?;
+        ensure!(
+            stat.mtime.sec > 1000,
+            "the mtime of the O_DSYNC write is {:?}",
+            stat.mtime
+        );
+
+        // an append needs its size to be read back.
+        disk.clear_log();
+        ok("O_DSYNC append", file.writeat(64 << 10, &[4; 4096]))?;
+        let log = disk.log();
+        let flush =
+            last_flush(&log).ok_or(format!("the O_DSYNC append didn't flush: {:?}", log))?;
+        ensure!(
+            log[..flush].iter().any(|x| covers(x, inode)),
+            "the O_DSYNC append wrote its size after the flush: {:?}",
+            log
+        );
+
+        // the buffered writes of a plain open are synced whole.
+        let plain = FileHandle::new(node.clone(), OpenFlags::O_RDWR);
+        let plain: Arc<dyn INodeInterface> = plain;
+        let syncs: [(fn(&File) -> VfsResult<()>, u8); 2] =
+            [(fsync::fsync, 5), (fsync::fdatasync, 6)];
+        for (sync, byte) in syncs {
+            disk.clear_log();
+            ok("buffered write", plain.writeat(100, &[byte; 100]))?;
+            ok("sync", sync(&plain))?;
+            let log = disk.log();
+            ensure!(
+                log.last().is_some_and(|x| x.op == MockOp::Flush)
+                    && log.iter().any(|x| x.op == MockOp::Write),
+                "the sync of the buffered write logged {:?}",
+                log
+            );
+        }
+        let data = read_all(&node, 1 << 20)?;
+        ensure!(
+            data.len() == 68 << 10
+                && data[100..200] == [6; 100]
+                && data[4096..8192] == [2; 4096]
+                && data[8192..12288] == [3; 4096]
+                && data[64 << 10..] == [4; 4096],
+            "the synced writes read back wrong, {} bytes",
+            data.len()
+        );
+        drop((file, plain));
+        ok("flush", FileSystem::flush(fs.as_ref()))?;
+        let report = fs.check();
+        ensure!(
+            report.problems.is_empty(),
+            "the image has problems: {:?}",
+            report.problems
+        );
+    }
+    Ok(())
+}
+
+/// fsync of a file amid 100 dirty ones on ext4 with the write-back policy
+/// writes a handful of blocks, those its transactions dirtied: an image
+/// taken right after it has its data, the other files are still empty
+/// and leak the blocks they allocated at most. fdatasync after a utimes
+/// writes no inode block, fsync writes it.
+pub fn ext4_fsync_inode_blocks() -> Result<(), String> {
+    use crate::ext4_check::Problem;
+    use crate::fsync::{self, SyncPolicy};
+    use crate::testing::{MockDisk, MockOp, Request};
+    use vfscore::TimeSpec;
+
+    const FILES: usize = 100;
+    let written = |log: &[Request]| -> usize {
+        log.iter()
+            .filter(|x| x.op == MockOp::Write)
+            .map(|x| x.len.div_ceil(4096))
+            .sum()
+    };
+    let covers =
+        |x: &Request, at: usize| x.op == MockOp::Write && x.offset <= at && at < x.offset + x.len;
+    let disk = Arc::new(MockDisk::from_image(crash_image(64)?, 512));
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::builder_from_device(disk.clone())
+            .sync_policy(SyncPolicy::WriteBack {
+                max_dirty_blocks: 4096,
+                max_age_ticks: u64::MAX,
+            })
+            .mount(),
+    )?;
+    let files = (0..=FILES)
+        .map(|x| ok("touch", fs.root().touch(&format!("file-{}", x))))
+        .collect::<Result<Vec<_>, _>>()?;
+    ok("sync", FileSystem::flush(fs.as_ref()))?;
+    for (i, file) in files.iter().enumerate() {
+        ok("write", file.writeat(0, &[i as u8; 6000]))?;
+        ok("flush", file.flush())?;
+    }
+    let file = &files[FILES];
+    disk.clear_log();
+    ok("fsync", fsync::fsync(file))?;
+    let log = disk.log();
+    ensure!(
+        written(&log) <= 24 && fs.writeback_pending(),
+        "the fsync wrote {} blocks: {:?}",
+        written(&log),
+        log
+    );
+
+    let crashed = Arc::new(MockDisk::from_image(disk.image(), 512));
+    let after = ok("mount", crate::Ext4FileSystem::new_from_device(crashed))?;
+    let synced = ok("open", after.root().open("file-100", OpenFlags::NONE))?;
+    let data = read_all(&synced, 8192)?;
+    ensure!(
+        data == [FILES as u8; 6000],
+        "the synced file reads back {} bytes",
+        data.len()
+    );
+    let mut stat = Stat::default();
+    let other = ok("open", after.root().open("file-0", OpenFlags::NONE))?;
+    ok("stat", other.stat(&mut stat))?;
+    ensure!(stat.size == 0, "an unsynced file has {} bytes", stat.size);
+    let report = after.check();
+    ensure!(
+        report
+            .problems
+            .iter()
+            .all(|x| matches!(x, Problem::BlockLeaked { .. })),
+        "the image after the fsync has problems: {:?}",
+        report.problems
+    );
+    drop((synced, other, after));
+
+    ok("stat", file.stat(&mut stat))?;
+    let (_, inode) = raw_inode_offset(disk.as_ref(), stat.ino as u32);
+    let mut set = [TimeSpec { sec: 1000, nsec: 0 }; 2];
+    ok("utimes", file.utimes(&mut set))?;
+    disk.clear_log();
+    ok("fdatasync", fsync::fdatasync(file))?;
+    let log = disk.log();
+    ensure!(
+        !log.iter().any(|x| covers(x, inode)),
+        "the fdatasync of the times wrote the inode: {:?}",
+        log
+    );
+    ok("fsync", fsync::fsync(file))?;
+    let log = disk.log();
+    ensure!(
+        log.iter().any(|x| covers(x, inode)),
+        "the fsync of the times didn't write the inode: {:?}",
+        log
+    );
+
+    ok("flush", FileSystem::flush(fs.as_ref()))?;
+    let report = fs.check();
+    ensure!(
+        report.problems.is_empty(),
+        "the image has problems: {:?}",
+        report.problems
+    );
+    Ok(())
+}
+
+/// A tree of 6 directories with 15 files each, made by bulk_create and
+/// by a loop creating, writing and closing each entry from the root: both
+/// trees read back the same, and the batch of bulk_create writes the
+/// device less than half as often, with and without a journal. The failed
+/// items are reported, like a symbol link ext4 can't make, and the next
+/// ones are made.
+pub fn ext4_bulk_create() -> Result<(), String> {
+    use crate::ops::{bulk_create, CreateItem, CreateKind};
+    use crate::testing::MockDisk;
+    use vfscore::TimeSpec;
+
+    let mtime = TimeSpec {
+        sec: 1_000_000,
+        nsec: 0,
+    };
+    // the entries: the path, 0 for a directory or 1 for a file with the
+    // data.
+    let mut tree: Vec<(String, u8, Vec<u8>)> = Vec::new();
+    for d in 0..6 {
+        tree.push((format!("etc{}", d), 0, Vec::new()));
+        for f in 0..15 {
+            let data = format!("file {} of etc{}\n", f, d).repeat(f + 1);
+            tree.push((format!("etc{}/f{}", d, f), 1, data.into_bytes()));
+        }
+    }
+    let items = |tree: &[(String, u8, Vec<u8>)]| -> Vec<CreateItem<'_>> {
+        tree.iter()
+            .map(|(path, kind, data)| CreateItem {
+                path: path.clone(),
+                kind: match kind {
+                    0 => CreateKind::Dir,
+                    _ => CreateKind::File(Box::new(&data[..])),
+                },
+                mode: 0o644,
+                mtime,
+            })
+            .collect()
+    };
+    let naive = |root: &File| -> Result<(), String> {
+        for (path, kind, data) in tree.iter() {
+            let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+            let mut dir = root.clone();
+            for name in parent.split('/').filter(|x| !x.is_empty()) {
+                dir = ok("lookup", dir.lookup(name))?;
+            }
+            let node = match kind {
+                0 => ok("mkdir", dir.mkdir(name))?,
+                _ => {
+                    let file = ok("touch", dir.touch(name))?;
+                    ok("write", file.writeat(0, data))?;
+                    ok("flush", file.flush())?;
+                    file
+                }
+            };
+            ok("utimes", node.utimes(&mut [mtime, mtime]))?;
+        }
+        Ok(())
+    };
+
+    for journal_blocks in [0, 256] {
+        let mut writes = [0; 2];
+        for (i, bulk) in [false, true].into_iter().enumerate() {
+            let disk = Arc::new(MockDisk::from_image(crash_image(journal_blocks)?, 512));
+            let fs = ok(
+                "mount",
+                crate::Ext4FileSystem::new_from_device(disk.clone()),
+            )?;
+            let root = fs.root();
+            let before = disk.writes();
+            match bulk {
+                false => naive(&root)?,
+                true => {
+                    let report = ok("bulk_create", bulk_create(&root, items(&tree).into_iter()))?;
+                    ensure!(
+                        report.created == tree.len() && report.errors.is_empty(),
+                        "bulk_create made {} of {} items: {:?}",
+                        report.created,
+                        tree.len(),
+                        report.errors
+                    );
+                }
+            }
+            ok("flush", FileSystem::flush(fs.as_ref()))?;
+            writes[i] = disk.writes() - before;
+            for (path, _, data) in tree.iter().filter(|x| x.1 == 1) {
+                let (dir, name) = path.split_once('/').unwrap();
+                let file = ok("lookup", root.lookup(dir).and_then(|x| x.lookup(name)))?;
+                ensure!(read_all(&file, 4096)? == *data, "{} reads back wrong", path);
+                let mut stat = Stat::default();
+                ok("stat", file.stat(&mut stat))?;
+                ensure!(stat.mtime.sec as u64 == 1_000_000, "the mtime of {}", path);
+            }
+            let report = fs.check();
+            ensure!(
+                report.problems.is_empty(),
+                "the image has problems: {:?}",
+                report.problems
+            );
+
+            if bulk {
+                // a path with "..", a file below a file, a symbol link,
+                // then a good one.
+                let bad = [
+                    ("../escape", 1),
+                    ("etc0/f0/below", 1),
+                    ("etc0/link", 2),
+                    ("etc0/new", 0),
+                ];
+                let items = bad.iter().map(|&(path, kind)| CreateItem {
+                    path: String::from(path),
+                    kind: match kind {
+                        0 => CreateKind::Dir,
+                        1 => CreateKind::File(Box::new(&b"data"[..])),
+                        _ => CreateKind::Symlink(String::from("f0")),
+                    },
+                    mode: 0o755,
+                    mtime,
+                });
+                let report = ok("bulk_create", bulk_create(&root, items))?;
+                let failed: Vec<usize> = report.errors.iter().map(|x| x.index).collect();
+                ensure!(
+                    report.created == 1 && failed == [0, 1, 2],
+                    "the bad items: {:?}",
+                    report
+                );
+                ensure!(
+                    matches!(report.errors[0].error, VfsError::InvalidInput)
+                        && matches!(report.errors[2].error, VfsError::NotSupported),
+                    "the errors of the bad items: {:?}",
+                    report.errors
+                );
+                ok("lookup", root.lookup("etc0").and_then(|x| x.lookup("new")))?;
+            }
+        }
+        ensure!(
+            writes[1] * 2 < writes[0],
+            "bulk_create wrote {} times, the loop {} times, journal of {} blocks",
+            writes[1],
+            writes[0],
+            journal_blocks
+        );
+    }
+    Ok(())
+}
+
+/// A deterministic mount builds the same image from the same input: the
+/// tree of bulk_create and a few changes, on two fresh images of the
+/// same uuid with the clock fixed, give identical disks, and another
+/// uuid gives another image.
+pub fn ext4_reproducible_image() -> Result<(), String> {
+    use crate::crc32c::crc32c;
+    use crate::ops::{bulk_create, CreateItem, CreateKind};
+    use vfscore::TimeSpec;
+
+    fn epoch() -> u64 {
+        1_700_000_000
+    }
+    let mut tree: Vec<(String, Option<Vec<u8>>)> = Vec::new();
+    for d in 0..8 {
+        tree.push((format!("usr{}", d), None));
+        tree.push((format!("usr{}/lib", d), None));
+        for f in 0..40 {
+            let data = format!("file {} of usr{}\n", f, d).repeat(f * 37 + 1);
+            tree.push((format!("usr{}/lib/f{}", d, f), Some(data.into_bytes())));
+        }
+    }
+    let build = |uuid: [u8; 16]| -> Result<Vec<u8>, String> {
+        let options = crate::ext4_mkfs::Options {
+            uuid,
+            time: epoch() as u32,
+            dir_index: true,
+            journal_blocks: 256,
+            ..Default::default()
+        };
+        let (ram, device) = ram_ext4_image(32 << 20, &options)?;
+        let fs = ok(
+            "mount",
+            crate::Ext4FileSystem::builder_from_device(device)
+                .deterministic(true)
+                .time_source(epoch)
+                .mount(),
+        )?;
+        let mtime = TimeSpec {
+            sec: epoch() as _,
+            nsec: 0,
+        };
+        let items = tree.iter().map(|(path, data)| CreateItem {
+            path: path.clone(),
+            kind: match data {
+                None => CreateKind::Dir,
+                Some(data) => CreateKind::File(Box::new(&data[..])),
+            },
+            mode: 0o644,
+            mtime,
+        });
+        let root = fs.root();
+        let report = ok("bulk_create", bulk_create(&root, items))?;
+        ensure!(report.errors.is_empty(), "bulk_create: {:?}", report.errors);
+        let lib = ok("lookup", root.lookup("usr3").and_then(|x| x.lookup("lib")))?;
+        ok("remove", lib.remove("f7"))?;
+        ok("truncate", lib.lookup("f8").and_then(|x| x.truncate(100)))?;
+        ok(
+            "touch",
+            lib.touch("f41").and_then(|x| x.writeat(0, b"late")),
+        )?;
+        ok("flush", FileSystem::flush(fs.as_ref()))?;
+        let report = fs.check();
+        ensure!(
+            report.problems.is_empty(),
+            "the image has problems: {:?}",
+            report.problems
+        );
+        drop((lib, root, fs));
+        Ok(ram.image())
+    };
+
+    let first = build(*b"reproducible-img")?;
+    let second = build(*b"reproducible-img")?;
+    let differ = first.iter().zip(&second).position(|(x, y)| x != y);
+    ensure!(
+        differ.is_none() && crc32c(!0, &first) == crc32c(!0, &second),
+        "the images differ from the byte {:?}",
+        differ.map(|x| x - EXT4_RAM_START)
+    );
+    ensure!(
+        build(*b"reproducible-two")? != first,
+        "the image doesn't have its uuid"
+    );
+    ensure_err!(
+        crate::Ext4FileSystem::builder_from_device(ram_ext4_device(8 << 20, *b"reproducible-wb!")?)
+            .deterministic(true)
+            .sync_policy(crate::fsync::SyncPolicy::WriteBack {
+                max_dirty_blocks: 64,
+                max_age_ticks: 4,
+            })
+            .mount(),
+        VfsError::InvalidInput
+    );
+    Ok(())
+}
+
+/// A readat of 64MiB over the data and the hole of a sparse file on ext4,
+/// and the writeat of its data, allocate less than 1MiB beyond the buffers
+/// of the test while the page cache is kept small. The binary of the test
+/// installs CountingAlloc as its allocator.
+#[cfg(feature = "std")]
+pub fn ext4_bounded_transfers() -> Result<(), String> {
+    use crate::testing::CountingAlloc;
+
+    const DATA: usize = 8 << 20;
+    const SIZE: usize = 64 << 20;
+    const LIMIT: usize = 1 << 20;
+    ensure!(
+        CountingAlloc::installed(),
+        "CountingAlloc isn't the allocator of the test binary"
+    );
+    let fs = ram_ext4(32 << 20, *b"ext4-bounded-rw!")?;
+    let file = ok("touch", fs.root().touch("sparse"))?;
+    let data: Vec<u8> = (0..DATA).map(|x| (x / 4096) as u8 ^ x as u8).collect();
+    let mut buffer = vec![0xff; SIZE];
+    let budget = crate::cache::budget();
+    crate::cache::set_budget(256 << 10);
+
+    let base = CountingAlloc::reset_peak();
+    let writes = file
+        .writeat(0, &data)
+        .and_then(|_| file.writeat(SIZE - DATA, &data));
+    let write_peak = CountingAlloc::peak() - base;
+    let flushed = file.flush();
+    let base = CountingAlloc::reset_peak();
+    let read = file.readat(0, &mut buffer);
+    let read_peak = CountingAlloc::peak() - base;
+    crate::cache::set_budget(budget);
+
+    ok("write", writes)?;
+    ok("flush", flushed)?;
+    ensure!(
+        ok("read", read)? == SIZE,
+        "the readat didn't read the whole file"
+    );
+    ensure!(
+        buffer[..DATA] == data[..]
+            && buffer[SIZE - DATA..] == data[..]
+            && buffer[DATA..SIZE - DATA].iter().all(|x| *x == 0),
+        "the sparse file reads back wrong"
+    );
+    ensure!(
+        write_peak < LIMIT,
+        "the writes of 8MiB allocated {} bytes",
+        write_peak
+    );
+    ensure!(
+        read_peak < LIMIT,
+        "the read of 64MiB allocated {} bytes",
+        read_peak
+    );
+    Ok(())
+}
+
+/// Cancel a long write on ext4 midway and fill a small image: both
+/// return the bytes written, the file has them and its size ends there,
+/// and a write which can't start fails.
+pub fn ext4_cancelled_write() -> Result<(), String> {
+    use crate::cancel::{self, CANCEL_CHUNK};
+
+    let fs = ram_ext4(32 << 20, *b"ext4-cancel-test")?;
+    let data: Vec<u8> = (0..8 << 20).map(|x: usize| (x / 4093) as u8).collect();
+    let file = ok("touch", fs.root().touch("long"))?;
+    let checks = AtomicUsize::new(0);
+    let cancelled = || checks.fetch_add(1, Ordering::Relaxed) >= 32;
+    let done = ok("write", cancel::write_at(&file, 0, &data, &cancelled))?;
+    ensure!(
+        done >= CANCEL_CHUNK && done < data.len(),
+        "the cancelled write wrote {} bytes",
+        done
+    );
+    let mut stat = Stat::default();
+    ok("stat", file.stat(&mut stat))?;
+    ensure!(stat.size as usize == done, "the size is {}", stat.size);
+    let mut back = vec![0; done];
+    ok(
+        "read",
+        cancel::read_exact(&file, 0, &mut back, &cancel::never),
+    )?;
+    ensure!(back == data[..done], "the written bytes differ");
+    ensure!(
+        ok("read", file.readat(done, &mut back))? == 0,
+        "the file goes on after the written bytes"
+    );
+    ensure_errno!(
+        cancel::write_at(&file, done, &data[done..], &|| true),
+        Errno::EINTR
+    );
+    ok(
+        "write",
+        cancel::write_all(&file, done, &data[done..], &cancel::never),
+    )?;
+    ok("read", file.readat(0, &mut back))?;
+    ensure!(back == data[..done], "the bytes differ after the restart");
+
+    let fs = ram_ext4(4 << 20, *b"ext4-filled-test")?;
+    let file = ok("touch", fs.root().touch("full"))?;
+    let done = ok("write", file.writeat(0, &data))?;
+    ensure!(
+        done > 0 && done < data.len(),
+        "the write on the small image wrote {} bytes",
+        done
+    );
+    ok("stat", file.stat(&mut stat))?;
+    ensure!(
+        stat.size as usize == done,
+        "the size of the full file is {}",
+        stat.size
+    );
+    ensure_err!(file.writeat(done, &data[done..]), VfsError::StorageFull);
+    let report = fs.check();
+    ensure!(
+        report.problems.is_empty(),
+        "the full image has problems: {:?}",
+        report.problems
+    );
+    Ok(())
+}
+
+/// Check the buffered writes of ext4 near a full image: the blocks of a
+/// buffered write are held when it returns, the writes of another file
+/// which fill the image leave them, so the close of the first writes it
+/// back. A small write which finds no block fails at once, not at its
+/// close.
+pub fn ext4_buffered_write_full() -> Result<(), String> {
+    let fs = ram_ext4(4 << 20, *b"ext4-hold-blocks")?;
+    let held = ok("touch", fs.root().touch("held"))?;
+    let held = FileHandle::new(held, OpenFlags::O_RDWR);
+    ok("buffered write", held.writeat(0, &[0x5a; 16 << 10]))?;
+
+    let filler = ok("touch", fs.root().touch("filler"))?;
+    let chunk = [0xa5; 1 << 20];
+    let mut filled = 0;
+    loop {
+        match filler.writeat(filled, &chunk) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(VfsError::StorageFull) => break,
+            Err(err) => return Err(format!("the filler write failed with {:?}", err)),
+        }
+    }
+    ensure!(filled > 0, "the filler wrote nothing");
+    ok("close", held.close())?;
+    let held = ok("lookup", fs.root().lookup("held"))?;
+    let data = read_all(&held, 1 << 20)?;
+    ensure!(
+        data == [0x5a; 16 << 10],
+        "the held write read back {} bytes",
+        data.len()
+    );
+
+    // no block left: the small write fails itself.
+    let late = FileHandle::new(ok("touch", fs.root().touch("late"))?, OpenFlags::O_RDWR);
+    let mut written = 0;
+    let err = loop {
+        match late.writeat(written, &[0x11; 100]) {
+            Ok(0) => return Err("a small write wrote nothing".into()),
+            Ok(n) => written += n,
+            Err(err) => break err,
+        }
+    };
+    ensure!(
+        matches!(err, VfsError::StorageFull),
+        "the small write after {} bytes failed with {:?}",
+        written,
+        err
+    );
+    ok("close", late.close())?;
+    let late = ok("lookup", fs.root().lookup("late"))?;
+    ensure!(
+        read_all(&late, 1 << 20)?.len() == written,
+        "the small writes returned {} bytes, the file has others",
+        written
+    );
+    let report = fs.check();
+    ensure!(
+        report.problems.is_empty(),
+        "the full image has problems: {:?}",
+        report.problems
+    );
+    Ok(())
+}
+
+/// Fill an ext4 image of 8MiB to exhaustion with files of 64KiB written
+/// by small buffered writes: the first failure, of a create or of a
+/// write, is StorageFull, ENOSPC to the kernel, and leaves no block
+/// available. Every byte a writeat returned reads back after the closes
+/// and a remount, the files have the sizes written, statfs counts the
+/// same free blocks and inodes after the remount and check finds the
+/// counts of the groups and the superblock matching the bitmaps. Once
+/// half of the files are removed, as many files fill the space again.
+pub fn ext4_fill_enospc() -> Result<(), String> {
+    const FILE: usize = 64 << 10;
+    const CHUNK: usize = 4 << 10;
+    let device = ram_ext4_device(8 << 20, *b"ext4-fill-enospc")?;
+    let mount = || {
+        ok(
+            "mount",
+            crate::Ext4FileSystem::new_from_device(device.clone()),
+        )
+    };
+    let name = |i: usize| format!("fill-{:04}", i);
+    let data = |i: usize| crate::golden::pattern(i as u32, 0, FILE);
+    let statfs = |fs: &crate::Ext4FileSystem| -> Result<StatFS, String> {
+        let mut statfs = StatFS::default();
+        ok("statfs", fs.root().statfs(&mut statfs))?;
+        Ok(statfs)
+    };
+    let enospc = |op: &str, err: VfsError| -> Result<(), String> {
+        ensure!(
+            matches!(err, VfsError::StorageFull) && Errno::from(err) == Errno::ENOSPC,
+            "the {} of the full image failed with {:?}",
+            op,
+            err
+        );
+        Ok(())
+    };
+    // the files from first until the image is full, with the bytes their
+    // writes returned.
+    let fill = |fs: &crate::Ext4FileSystem, first: usize| -> Result<Vec<(usize, usize)>, String> {
+        let mut files = Vec::new();
+        for i in first.. {
+            let file = match fs.root().touch(&name(i)) {
+                Ok(file) => FileHandle::new(file, OpenFlags::O_RDWR),
+                Err(err) => {
+                    enospc("create", err)?;
+                    break;
+                }
+            };
+            let data = data(i);
+            let mut written = 0;
+            let mut failed = None;
+            while written < FILE {
+                match file.writeat(written, &data[written..FILE.min(written + CHUNK)]) {
+                    Ok(0) => return Err(format!("a write of {} wrote nothing", name(i))),
+                    Ok(n) => written += n,
+                    Err(err) => {
+                        failed = Some(err);
+                        break;
+                    }
+                }
+            }
+            // the blocks of the buffered writes are held, the close
+            // writes them.
+            ok("close", file.close())?;
+            files.push((i, written));
+            if let Some(err) = failed {
+                enospc("write", err)?;
+                break;
+            }
+        }
+        Ok(files)
+    };
+    // the files have the bytes written and the counts match.
+    let verify = |fs: &crate::Ext4FileSystem, files: &[(usize, usize)]| -> Result<(), String> {
+        for &(i, written) in files {
+            let read = read_all(&ok("lookup", fs.root().lookup(&name(i)))?, FILE + 1)?;
+            ensure!(
+                read.len() == written && read[..] == data(i)[..written],
+                "{} reads {} bytes of the {} written",
+                name(i),
+                read.len(),
+                written
+            );
+        }
+        let problems = fs.check().problems;
+        ensure!(problems.is_empty(), "the image: {:?}", problems);
+        Ok(())
+    };
+
+    let fs = mount()?;
+    let files = fill(&fs, 0)?;
+    ensure!(
+        files.len() > 16,
+        "the image is full after {} files",
+        files.len()
+    );
+    let full = statfs(&fs)?;
+    ensure!(
+        full.bavail == 0,
+        "the full image has {} blocks available",
+        full.bavail
+    );
+    verify(&fs, &files)?;
+    ok("flush", FileSystem::flush(fs.as_ref()))?;
+    drop(fs);
+
+    let fs = mount()?;
+    let remounted = statfs(&fs)?;
+    ensure!(
+        (remounted.bfree, remounted.ffree) == (full.bfree, full.ffree),
+        "the remount counts {}/{} free blocks/inodes, not {}/{}",
+        remounted.bfree,
+        remounted.ffree,
+        full.bfree,
+        full.ffree
+    );
+    verify(&fs, &files)?;
+
+    let (removed, kept): (Vec<_>, Vec<_>) = files.iter().copied().partition(|x| x.0 % 2 == 0);
+    for &(i, _) in removed.iter() {
+        ok("remove", fs.root().remove(&name(i)))?;
+    }
+    let blocks: usize = removed.iter().map(|x| x.1.div_ceil(4096)).sum();
+    let freed = statfs(&fs)?;
+    ensure!(
+        freed.bfree as usize >= full.bfree as usize + blocks,
+        "{} blocks are free after the removal of {} blocks of data",
+        freed.bfree,
+        blocks
+    );
+    let refilled = fill(&fs, files.len())?;
+    ensure!(
+        refilled.len() >= removed.len(),
+        "{} files fill the space of {}",
+        refilled.len(),
+        removed.len()
+    );
+    verify(&fs, &kept)?;
+    verify(&fs, &refilled)?;
+    Ok(())
+}
+
+/// Check the write guard of ext4: a file whose extent is turned to the
+/// group descriptors, like a wrong block computed by the write path, is
+/// written on a mount with the guard. The write panics in a debug build
+/// and fails with Io otherwise, the trip is counted and the descriptors
+/// are unchanged. The writes, the truncation and the zeroed removal of
+/// the mount before it trip nothing.
+#[cfg(feature = "std")]
+pub fn ext4_write_guard() -> Result<(), String> {
+    use crate::blockdev::BlockDevice;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let device = ram_ext4_device(16 << 20, *b"ext4-write-guard")?;
+    let mount = || {
+        ok(
+            "mount",
+            crate::Ext4FileSystem::builder_from_device(device.clone())
+                .write_guard(true)
+                .guard_bitmaps(true)
+                .secure_delete(true)
+                .mount(),
+        )
+    };
+    let ino = {
+        let fs = mount()?;
+        let file = ok("touch", fs.root().touch("victim"))?;
+        ok("write", file.writeat(0, &[0x5a; 4096]))?;
+        ok("flush", file.flush())?;
+        ok("truncate", file.truncate(1024))?;
+        ok("remove", fs.root().remove("victim"))?;
+        let file = ok("touch", fs.root().touch("victim"))?;
+        ok("write", file.writeat(0, &[0x5a; 4096]))?;
+        ok("flush", file.flush())?;
+        ensure!(
+            fs.guard_trips() == 0,
+            "the writes of the files tripped the guard {} times",
+            fs.guard_trips()
+        );
+        let mut stat = Stat::default();
+        ok("stat", file.stat(&mut stat))?;
+        stat.ino as u32
+    };
+
+    // the first extent of the inline tree points at the descriptors.
+    let (sb, _) = raw_inode_offset(device.as_ref(), ino);
+    let gdt = sb.first_data_block as u64 + 1;
+    let gdt_offset = gdt as usize * sb.block_size();
+    patch_raw_inode(device.as_ref(), ino, |raw| {
+        raw[0x3a..0x3c].copy_from_slice(&((gdt >> 32) as u16).to_le_bytes());
+        raw[0x3c..0x40].copy_from_slice(&(gdt as u32).to_le_bytes());
+    })?;
+    let before = device.read_offset(gdt_offset)[..sb.block_size()].to_vec();
+    let fs = mount()?;
+    let file = ok("lookup", fs.root().lookup("victim"))?;
+    let r = catch_unwind(AssertUnwindSafe(|| {
+        file.writeat(0, &[0xa5; 512]).and_then(|_| file.flush())
+    }));
+    match r {
+        Err(_) if cfg!(debug_assertions) => {}
+        Ok(Err(VfsError::Io)) if !cfg!(debug_assertions) => {}
+        Err(_) => return Err(String::from("the write panicked in a release build")),
+        Ok(r) => return Err(format!("the stray write returned {:?}", r)),
+    }
+    ensure!(
+        fs.guard_trips() == 1,
+        "the guard tripped {} times",
+        fs.guard_trips()
+    );
+    let after = device.read_offset(gdt_offset)[..sb.block_size()].to_vec();
+    ensure!(before == after, "the stray write reached the descriptors");
+    // the panic left its transaction open, the mount can't be dropped.
+    if cfg!(debug_assertions) {
+        core::mem::forget((file, fs));
+    }
+    Ok(())
+}
+
+/// Check that two async reads of an ext4 volume on a LatencyDisk are
+/// pending on the device at once, so a task awaiting its read doesn't
+/// block the others. device_id is a host device of at least 8M, it's
+/// formatted.
+#[cfg(all(feature = "std", feature = "async"))]
+pub fn async_reads_overlap(device_id: usize) -> Result<(), String> {
+    use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    use crate::aio::{self, IoFuture};
+    use crate::ext4_mkfs::{format_device, Options};
+    use crate::ext4_rs_shim::Ext4FileSystem;
+    use crate::sys::{get_blk_device, LatencyDisk};
+
+    const VTABLE: RawWakerVTable = RawWakerVTable::new(|_| RAW, |_| {}, |_| {}, |_| {});
+    const RAW: RawWaker = RawWaker::new(core::ptr::null(), &VTABLE);
+
+    let device = get_blk_device(device_id).ok_or("no device")?;
+    ok(
+        "format",
+        format_device(device_id, 8 << 20, &Options::default()),
+    )?;
+    let disk = Arc::new(LatencyDisk::new(device, 4));
+    aio::set_async_device(device_id, disk.clone());
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(ok(
+        "mount",
+        Ext4FileSystem::new(device_id),
+    )? as Arc<dyn FileSystem>));
+    let mut files = Vec::new();
+    for name in ["a", "b"] {
+        let file = ok("touch", fs.root_dir().touch(name))?;
+        ok(
+            "writeat",
+            file.writeat(0, &vec![name.as_bytes()[0]; 0x4000]),
+        )?;
+        ok("flush", file.flush())?;
+        files.push(file);
+    }
+    // the reads must go to the device.
+    crate::cache::drop_caches();
+    let mut bufs = [vec![0u8; 0x4000], vec![0u8; 0x4000]];
+    let (buf_a, buf_b) = bufs.split_at_mut(1);
+    let mut reads: [IoFuture<'_, usize>; 2] = [
+        aio::readat(&files[0], 0, &mut buf_a[0]),
+        aio::readat(&files[1], 0, &mut buf_b[0]),
+    ];
+    // SAFETY: the vtable functions do nothing with the null data.
+    let waker = unsafe { Waker::from_raw(RAW) };
+    let mut cx = Context::from_waker(&waker);
+    let mut done = [None, None];
+    while done.iter().any(Option::is_none) {
+        for (read, done) in reads.iter_mut().zip(done.iter_mut()) {
+            if done.is_none()
+                && let Poll::Ready(r) = read.as_mut().poll(&mut cx)
+            {
+                *done = Some(r);
+            }
+        }
+    }
+    drop(reads);
+    for ((r, buf), expected) in done.into_iter().zip(bufs.iter()).zip([b'a', b'b']) {
+        let n = ok("readat_async", r.unwrap())?;
+        ensure!(n == 0x4000, "read {} bytes", n);
+        ensure!(
+            buf.iter().all(|x| *x == expected),
+            "the data of {} is wrong",
+            expected as char
+        );
+    }
+    ensure!(
+        disk.max_in_flight() >= 2,
+        "the reads didn't overlap, {} in flight",
+        disk.max_in_flight()
+    );
+
+    // the buffered write through another wrapper of a extends the file.
+    let other = ok("lookup", fs.root_dir().lookup("a"))?;
+    ok("writeat", other.writeat(0x4000, b"c"))?;
+    let mut buf = vec![0u8; 0x4001];
+    let mut read = aio::readat(&files[0], 0, &mut buf);
+    let n = loop {
+        if let Poll::Ready(r) = read.as_mut().poll(&mut cx) {
+            break ok("readat_async", r)?;
+        }
+    };
+    drop(read);
+    ensure!(
+        n == 0x4001 && buf[0x4000] == b'c',
+        "read {} bytes after the write of another wrapper",
+        n
+    );
+    Ok(())
+}
+
+/// The retries and the relocations of an ext4 mount with relocate on a
+/// MockDisk counting its errors: a read failing once is retried and reads
+/// the data, a block failing every read fails with EIO after the bytes
+/// before it and is badlisted, a write failing once is retried, and an
+/// overwrite of a block failing every write moves it to a new block, the
+/// file is intact after a remount and no new file gets the bad block.
+pub fn ext4_retry_relocate() -> Result<(), String> {
+    use crate::blockdev::{set_device_errors, RetryPolicy};
+    use crate::testing::MockDisk;
+
+    const BLOCK: usize = 4096;
+    const BLOCKS: usize = 8;
+    let block = |i: usize, generation: usize| -> Vec<u8> {
+        let text = format!("block {} of generation {} ", i, generation);
+        text.bytes().cycle().take(BLOCK).collect()
+    };
+    let mut data: Vec<u8> = (0..BLOCKS).flat_map(|i| block(i, 0)).collect();
+    let disk = Arc::new(MockDisk::from_image(crash_image(64)?, 512));
+    let mount = || {
+        let fs = ok(
+            "mount",
+            crate::Ext4FileSystem::builder_from_device(disk.clone())
+                .retry(RetryPolicy::FLAKY)
+                .relocate(true)
+                .mount(),
+        )?;
+        set_device_errors(fs.dev(), disk.clone());
+        Ok::<_, String>(fs)
+    };
+    // the sectors of the block of the file on the disk, by its data.
+    let sectors_of = |data: &[u8]| -> Result<core::ops::Range<usize>, String> {
+        let image = disk.image();
+        let at = image.chunks(BLOCK).position(|x| x == data);
+        let at = at.ok_or_else(|| String::from("the block isn't on the disk"))?;
+        Ok(at * BLOCK / 512..(at + 1) * BLOCK / 512)
+    };
+    let read_all = |file: &File| -> Result<Vec<u8>, String> {
+        let mut buf = vec![0; BLOCKS * BLOCK];
+        let n = ok("read", file.readat(0, &mut buf))?;
+        buf.truncate(n);
+        Ok(buf)
+    };
+
+    let fs = mount()?;
+    let file = ok("touch", fs.root().touch("file"))?;
+    ok("write", file.writeat(0, &data))?;
+    ok("flush", file.flush())?;
+    drop((file, fs));
+
+    // a read failing once.
+    let fs = mount()?;
+    let file = ok("lookup", fs.root().lookup("file"))?;
+    disk.fail_reads(sectors_of(&data[5 * BLOCK..6 * BLOCK])?, 1);
+    ensure!(read_all(&file)? == data, "the retried read differs");
+    let health = fs.health();
+    ensure!(
+        health.read_retries >= 1 && health.read_failures == 0,
+        "the transient read: {:?}",
+        health
+    );
+    ensure!(
+        fs.sector_errors().iter().any(|(_, x)| x.reads > 0),
+        "no sector counts the failed read"
+    );
+    drop((file, fs));
+
+    // a block failing every read, then a write failing once.
+    let fs = mount()?;
+    let file = ok("lookup", fs.root().lookup("file"))?;
+    disk.fail_sectors(sectors_of(&data[5 * BLOCK..6 * BLOCK])?);
+    ensure!(
+        read_all(&file)? == data[..5 * BLOCK],
+        "the read before the bad block differs"
+    );
+    let err = file.readat(5 * BLOCK, &mut [0; BLOCK]).err();
+    ensure!(
+        err.is_some_and(|x| Errno::from(x) == Errno::EIO),
+        "the read of the bad block returned {:?}",
+        err
+    );
+    let health = fs.health();
+    ensure!(
+        health.read_failures >= 1 && health.bad_blocks == 1,
+        "the bad read: {:?}",
+        health
+    );
+    disk.clear_faults();
+    disk.fail_write(disk.writes());
+    data[..BLOCK].copy_from_slice(&block(0, 1));
+    ok("write", file.writeat(0, &data[..BLOCK]))?;
+    ok("flush", file.flush())?;
+    let health = fs.health();
+    ensure!(
+        health.write_retries >= 1 && health.write_failures == 0,
+        "the transient write: {:?}",
+        health
+    );
+    drop((file, fs));
+
+    // an overwrite of a block failing every write.
+    let fs = mount()?;
+    let file = ok("lookup", fs.root().lookup("file"))?;
+    let bad = sectors_of(&data[3 * BLOCK..4 * BLOCK])?;
+    disk.fail_sectors(bad.clone());
+    data[3 * BLOCK..4 * BLOCK].copy_from_slice(&block(3, 1));
+    ok(
+        "write",
+        file.writeat(3 * BLOCK, &data[3 * BLOCK..4 * BLOCK]),
+    )?;
+    ok("flush", file.flush())?;
+    let health = fs.health();
+    ensure!(
+        health.relocated == 1 && health.write_failures >= 1 && health.bad_blocks >= 1,
+        "the relocation: {:?}",
+        health
+    );
+    ensure!(read_all(&file)? == data, "the relocated file differs");
+    ensure!(
+        sectors_of(&data[3 * BLOCK..4 * BLOCK])? != bad,
+        "the block wasn't moved"
+    );
+    // the bad block is free, a new file doesn't get it.
+    let other = ok("touch", fs.root().touch("other"))?;
+    ok("write", other.writeat(0, &vec![7; 16 * BLOCK]))?;
+    ok("flush", other.flush())?;
+    ensure!(
+        fs.health().write_failures == health.write_failures,
+        "a new block was allocated on the bad one: {:?}",
+        fs.health()
+    );
+    let problems = fs.check().problems;
+    ensure!(
+        problems.is_empty(),
+        "problems after the relocation {:?}",
+        problems
+    );
+    drop((other, file, fs));
+
+    let fs = mount()?;
+    let file = ok("lookup", fs.root().lookup("file"))?;
+    ensure!(
+        read_all(&file)? == data,
+        "the file differs after the remount"
+    );
+    ensure!(
+        fs.health().read_failures == 0,
+        "the remount read the bad block"
+    );
+    disk.clear_faults();
+    Ok(())
+}
+
+/// The scheduler of iosched.rs on a MockDisk queueing 8 requests: a
+/// metadata read submitted behind a stream of readahead reaches the disk
+/// ahead of the queued readahead, a burst of Sync reads lets the
+/// readahead through within two rounds, the sequential reads and writes are
+/// merged into one request of the disk where the strided ones aren't, a
+/// read after a queued write of the writeback reads its data, and the
+/// flush of the device dispatches the queued writes ahead of the
+/// readahead.
+pub fn iosched_priorities() -> Result<(), String> {
+    use crate::blockdev::{anon_dev, flush_device};
+    use crate::iosched::{self, IoPriority, IoScheduler, MAX_MERGE};
+    use crate::testing::{MockDisk, MockOp};
+
+    const SIZE: usize = 4 << 20;
+    const BLOCK: usize = 4096;
+    let disk = Arc::new(MockDisk::new(SIZE, 512));
+    for i in 0..SIZE / BLOCK {
+        disk.write_at(i * BLOCK, &[i as u8; BLOCK]);
+    }
+    disk.clear_log();
+    let reads = |disk: &MockDisk| -> Vec<usize> {
+        disk.log()
+            .iter()
+            .filter(|x| x.op == MockOp::Read)
+            .map(|x| x.offset / BLOCK)
+            .collect()
+    };
+    let scheduler = IoScheduler::new("iosched.test", disk.clone(), 8);
+
+    // the readahead by strides of 2 blocks doesn't merge.
+    let ahead: Vec<_> = (0..32)
+        .map(|i| {
+            (
+                64 + 2 * i,
+                scheduler.submit_read((64 + 2 * i) * BLOCK, BLOCK, IoPriority::Readahead),
+            )
+        })
+        .collect();
+    ensure!(
+        scheduler.depth() == 8 && disk.log().len() == 24,
+        "{} queued and {} done of the readahead",
+        scheduler.depth(),
+        disk.log().len()
+    );
+    for block in [1, 3, 5] {
+        let mut buf = [0; BLOCK];
+        scheduler.read(block * BLOCK, &mut buf, IoPriority::Sync);
+        ensure!(
+            buf == [block as u8; BLOCK],
+            "the metadata block {} is wrong",
+            block
+        );
+    }
+    ensure!(
+        reads(&disk)[24..] == [1, 3, 5] && scheduler.depth() == 8,
+        "the metadata reads went after the readahead: {:?}",
+        reads(&disk)
+    );
+    for (block, ticket) in ahead {
+        let data = scheduler.wait(ticket);
+        ensure!(
+            data == [block as u8; BLOCK],
+            "the readahead of block {} is wrong",
+            block
+        );
+    }
+    ensure!(scheduler.depth() == 0, "the queue wasn't drained");
+
+    // the readahead isn't starved by the Sync reads.
+    disk.clear_log();
+    let ahead: Vec<_> = (0..2)
+        .map(|i| scheduler.submit_read((600 + 2 * i) * BLOCK, BLOCK, IoPriority::Readahead))
+        .collect();
+    let sync: Vec<_> = (0..20)
+        .map(|i| scheduler.submit_read((300 + 2 * i) * BLOCK, BLOCK, IoPriority::Sync))
+        .collect();
+    for ticket in sync.into_iter().chain(ahead) {
+        scheduler.wait(ticket);
+    }
+    let first = reads(&disk).iter().position(|x| *x >= 600);
+    ensure!(
+        first.is_some_and(|x| x <= 16),
+        "the readahead was starved: {:?}",
+        reads(&disk)
+    );
+
+    // the sequential reads merge, the strided ones don't.
+    let count = |stride: usize, blocks: usize| {
+        disk.clear_log();
+        let scheduler = IoScheduler::new("iosched.merge", disk.clone(), blocks);
+        let tickets: Vec<_> = (0..blocks)
+            .map(|i| {
+                (
+                    i,
+                    scheduler.submit_read((128 + stride * i) * BLOCK, BLOCK, IoPriority::Readahead),
+                )
+            })
+            .collect();
+        for (i, ticket) in tickets {
+            if scheduler.wait(ticket) != [(128 + stride * i) as u8; BLOCK] {
+                return Err(format!("the read {} by strides of {} is wrong", i, stride));
+            }
+        }
+        Ok((disk.log().len(), scheduler.stats().merged))
+    };
+    let blocks = MAX_MERGE / BLOCK;
+    let (sequential, merged) = count(1, blocks)?;
+    let (strided, _) = count(2, blocks)?;
+    ensure!(
+        sequential == 1 && merged == blocks - 1 && strided == blocks,
+        "{} sequential requests with {} merged, {} strided ones",
+        sequential,
+        merged,
+        strided
+    );
+
+    // a read after a queued write reads its data.
+    let dev = anon_dev();
+    let scheduler = IoScheduler::new("iosched.sync", disk.clone(), 64);
+    iosched::set_scheduler(dev, scheduler.clone());
+    disk.clear_log();
+    scheduler.submit_write(900 * BLOCK, vec![0xee; BLOCK], IoPriority::Writeback);
+    let mut buf = [0; BLOCK];
+    scheduler.read(900 * BLOCK, &mut buf, IoPriority::Sync);
+    ensure!(
+        buf == [0xee; BLOCK] && scheduler.stats().boosted == 1,
+        "the read passed the write, {} boosted",
+        scheduler.stats().boosted
+    );
+
+    // the flush dispatches the writeback, merged, ahead of the readahead.
+    disk.clear_log();
+    let ahead: Vec<_> = (0..4)
+        .map(|i| scheduler.submit_read((1000 + 2 * i) * BLOCK, BLOCK, IoPriority::Readahead))
+        .collect();
+    for i in 0..8 {
+        scheduler.submit_write((800 + i) * BLOCK, vec![0xaa; BLOCK], IoPriority::Writeback);
+    }
+    flush_device(dev);
+    let log = disk.log();
+    ensure!(
+        log.len() == 1 && log[0].op == MockOp::Write && log[0].len == 8 * BLOCK,
+        "the flush dispatched {:?}",
+        log
+    );
+    ensure!(
+        disk.image()[800 * BLOCK..808 * BLOCK]
+            .iter()
+            .all(|x| *x == 0xaa),
+        "the writeback didn't reach the disk"
+    );
+    for ticket in ahead {
+        scheduler.wait(ticket);
+    }
+    let stats = crate::stats::render();
+    for key in [
+        "iosched.sync.queue_depth: 0",
+        "iosched.sync.writeback_requests: 9",
+        "iosched.sync.readahead_requests: 4",
+    ] {
+        ensure!(stats.contains(key), "no {} in the stats:\n{}", key, stats);
+    }
+    Ok(())
+}
+
+/// Run the same workload under every sync policy of ext4 on a MockDisk:
+/// write-through writes each operation as it returns and flushes the
+/// device at the durability points of its commits only, write-back
+/// writes nothing until a sync, a full cache or an old enough tick and
+/// then fewer requests, and strict ordering flushes the device after
+/// every write of the superblock and at the end of the operations. The policy is switched by a remount, leaving write-back
+/// writes back what it deferred.
+pub fn ext4_sync_policies() -> Result<(), String> {
+    use crate::blockdev::set_device_flush;
+    use crate::fsync::SyncPolicy;
+    use crate::mounts::{MountFlags, Remount};
+    use crate::testing::{MockDisk, MockOp};
+
+    let workload = |root: &File| -> VfsResult<()> {
+        let dir = root.mkdir("d")?;
+        for i in 0..8u8 {
+            let file = dir.touch(&format!("f{}", i))?;
+            file.writeat(0, &[i + 1; 4096])?;
+        }
+        dir.lookup("f0")?.truncate(0)?;
+        Ok(crate::rename::rename(
+            root,
+            "d",
+            root,
+            "e",
+            crate::rename::RenameFlags::NONE,
+        )?)
+    };
+    let verify = |disk: &MockDisk| -> Result<(), String> {
+        let disk = Arc::new(MockDisk::from_image(disk.image(), 512));
+        let fs = ok("mount", crate::Ext4FileSystem::new_from_device(disk))?;
+        let dir = ok("lookup", fs.root().lookup("e"))?;
+        for i in 1..8u8 {
+            let file = ok("lookup", dir.lookup(&format!("f{}", i)))?;
+            let data = read_all(&file, 1 << 16)?;
+            ensure!(data == [i + 1; 4096], "f{} reads back wrong", i);
+        }
+        let f0 = ok("lookup", dir.lookup("f0"))?;
+        ensure!(read_all(&f0, 4096)?.is_empty(), "f0 wasn't truncated");
+        let problems = fs.check().problems;
+        ensure!(problems.is_empty(), "problems {:?}", problems);
+        Ok(())
+    };
+    let mount = |policy: SyncPolicy| -> Result<_, String> {
+        let disk = Arc::new(MockDisk::from_image(crash_image(256)?, 512));
+        let builder = crate::Ext4FileSystem::builder_from_device(disk.clone());
+        let fs = ok("mount", builder.sync_policy(policy).mount())?;
+        set_device_flush(fs.dev(), disk.clone());
+        disk.clear_log();
+        Ok((disk, fs))
+    };
+    let count = |disk: &MockDisk, op: MockOp| disk.log().iter().filter(|x| x.op == op).count();
+    let writes = |disk: &MockDisk| count(disk, MockOp::Write);
+    let flushes = |disk: &MockDisk| count(disk, MockOp::Flush);
+
+    // write-through: every operation reaches the disk as it returns.
+    let (disk, fs) = mount(SyncPolicy::WriteThrough)?;
+    ok("workload", workload(&fs.root()))?;
+    let through = writes(&disk);
+    ensure!(through > 0, "write-through wrote nothing");
+    ensure!(fs.dirty_blocks() == 0, "write-through left dirty blocks");
+    let through_flushes = flushes(&disk);
+    ensure!(
+        through_flushes > 0,
+        "write-through didn't order its commits"
+    );
+    drop(fs);
+    verify(&disk)?;
+
+    // write-back: nothing before the sync, then fewer requests.
+    let policy = SyncPolicy::WriteBack {
+        max_dirty_blocks: 1024,
+        max_age_ticks: 1000,
+    };
+    let (disk, fs) = mount(policy)?;
+    ok("workload", workload(&fs.root()))?;
+    ensure!(
+        writes(&disk) == 0,
+        "write-back wrote {} times",
+        writes(&disk)
+    );
+    ensure!(
+        fs.dirty_blocks() > 0 && fs.writeback_pending(),
+        "write-back deferred nothing"
+    );
+    let root = fs.root();
+    let f3 = ok("lookup", root.lookup("e").and_then(|x| x.lookup("f3")))?;
+    let data = read_all(&f3, 1 << 16)?;
+    ensure!(data == [4; 4096], "the deferred data reads back wrong");
+    ok("flush", FileSystem::flush(fs.as_ref()))?;
+    ensure!(fs.dirty_blocks() == 0, "the flush left dirty blocks");
+    ensure!(
+        writes(&disk) < through,
+        "write-back wrote {} times, write-through {}",
+        writes(&disk),
+        through
+    );
+    drop((f3, root, fs));
+    verify(&disk)?;
+
+    // write-back by the age of the oldest transaction, and by a full cache.
+    let policy = SyncPolicy::WriteBack {
+        max_dirty_blocks: 1024,
+        max_age_ticks: 2,
+    };
+    let (disk, fs) = mount(policy)?;
+    ok("workload", workload(&fs.root()))?;
+    ok("step", fs.writeback_step(0))?;
+    ensure!(writes(&disk) == 0, "a young transaction was written back");
+    let written = ok("step", fs.writeback_step(0))?;
+    ensure!(
+        written > 0 && writes(&disk) > 0 && fs.dirty_blocks() == 0,
+        "an old transaction wasn't written back, {} blocks",
+        written
+    );
+    drop(fs);
+    verify(&disk)?;
+    let policy = SyncPolicy::WriteBack {
+        max_dirty_blocks: 4,
+        max_age_ticks: 1000,
+    };
+    let (disk, fs) = mount(policy)?;
+    ok("workload", workload(&fs.root()))?;
+    ensure!(writes(&disk) > 0, "a full write-back cache wasn't written");
+    ok("flush", FileSystem::flush(fs.as_ref()))?;
+    drop(fs);
+    verify(&disk)?;
+
+    // strict ordering: the device is flushed after every write of the
+    // superblock, the recovery flag, and each operation ends with a flush.
+    let (disk, fs) = mount(SyncPolicy::StrictOrdered)?;
+    ok("workload", workload(&fs.root()))?;
+    let log = disk.log();
+    ensure!(fs.dirty_blocks() == 0, "strict ordering left dirty blocks");
+    ensure!(
+        log.last().is_some_and(|x| x.op == MockOp::Flush),
+        "strict ordering didn't flush at the end"
+    );
+    let superblock = |x: &crate::testing::Request| {
+        x.op == MockOp::Write && x.offset <= 1024 && 1024 < x.offset + x.len
+    };
+    for (i, request) in log.iter().enumerate() {
+        if superblock(request) {
+            ensure!(
+                log.get(i + 1).is_some_and(|x| x.op == MockOp::Flush),
+                "no flush after the superblock write {}: {:?}",
+                i,
+                log
+            );
+        }
+    }
+    ensure!(
+        log.iter().any(superblock) && flushes(&disk) > through_flushes,
+        "strict ordering flushed {} times, write-through {}",
+        flushes(&disk),
+        through_flushes
+    );
+    drop(fs);
+    verify(&disk)?;
+
+    // a remount switches the policy, leaving write-back writes back.
+    let (disk, fs) = mount(SyncPolicy::WriteThrough)?;
+    let remount = |options: &str| Remount::remount(fs.as_ref(), MountFlags::NONE, options);
+    ok("remount", remount("sync_policy=write_back:1024:1000"))?;
+    ensure!(
+        fs.sync_policy()
+            == SyncPolicy::WriteBack {
+                max_dirty_blocks: 1024,
+                max_age_ticks: 1000,
+            },
+        "the policy is {:?}",
+        fs.sync_policy()
+    );
+    ok("workload", workload(&fs.root()))?;
+    ensure!(writes(&disk) == 0, "the remounted write-back wrote");
+    ok("remount", remount("sync_policy=strict_ordered"))?;
+    ensure!(
+        fs.sync_policy() == SyncPolicy::StrictOrdered && fs.dirty_blocks() == 0,
+        "the remount to strict ordering left {} dirty blocks",
+        fs.dirty_blocks()
+    );
+    for option in [
+        "sync_policy=write_back:0:1",
+        "sync_policy=write_back:1",
+        "sync_policy=sometimes",
+    ] {
+        ensure_err!(remount(option), VfsError::NotSupported);
+    }
+    ensure!(
+        fs.sync_policy() == SyncPolicy::StrictOrdered,
+        "a refused remount changed the policy"
+    );
+    drop(fs);
+    verify(&disk)?;
+    Ok(())
+}
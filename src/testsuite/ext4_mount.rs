@@ -0,0 +1,2065 @@
+// The ext4 cases of the mounts: the remounts, the backup superblocks, the
+// error history, the journal replay of the read-only and the fast
+// mounts, the features, the lazy inode tables and the background work.
+
+use super::*;
+
+pub fn two_ext4_mounts() -> Result<(), String> {
+    let a = ram_ext4(8 << 20, *b"two-mounts-ext4a")?;
+    let b = ram_ext4(8 << 20, *b"two-mounts-ext4b")?;
+    ensure!(a.dev() != b.dev(), "both mounts are dev {}", a.dev());
+    let (root_a, root_b) = (a.root(), b.root());
+    let statfs = |root: &File| {
+        let mut statfs = StatFS::default();
+        ok("statfs", root.statfs(&mut statfs)).map(|_| statfs)
+    };
+    let before = statfs(&root_b)?;
+    let before_a = statfs(&root_a)?;
+    let file_a = ok("touch", root_a.touch("data"))?;
+    ok("writeat", file_a.writeat(0, &vec![b'a'; 0x10000]))?;
+    let file_b = ok("touch", root_b.touch("data"))?;
+    ok("writeat", file_b.writeat(0, b"b"))?;
+    for file in [&file_a, &file_b] {
+        ok("flush", file.flush())?;
+    }
+    // the reads must go to the devices.
+    crate::cache::drop_caches();
+    let data_a = read_all(&ok("lookup", root_a.lookup("data"))?, 0x20000)?;
+    ensure!(
+        data_a.len() == 0x10000 && data_a.iter().all(|x| *x == b'a'),
+        "the data of a is wrong, {} bytes",
+        data_a.len()
+    );
+    let data_b = read_all(&ok("lookup", root_b.lookup("data"))?, 0x20000)?;
+    ensure!(data_b == b"b", "the data of b is {:?}", data_b);
+
+    let (after_a, after_b) = (statfs(&root_a)?, statfs(&root_b)?);
+    ensure!(
+        after_a.fsid != after_b.fsid,
+        "both fsids are {}",
+        after_a.fsid
+    );
+    ensure!(
+        after_a.fsid == before_a.fsid && before_a.fsid != 0,
+        "the fsid of a went from {} to {}",
+        before_a.fsid,
+        after_a.fsid
+    );
+    ensure!(
+        after_a.ftype == crate::statfs::EXT4_SUPER_MAGIC as _,
+        "the f_type is {:#x}",
+        after_a.ftype
+    );
+    ensure!(
+        after_a.bfree + 16 <= after_b.bfree,
+        "free blocks a {} b {}",
+        after_a.bfree,
+        after_b.bfree
+    );
+    ensure!(
+        after_b.bfree + 1 >= before.bfree && after_b.ffree + 1 == before.ffree,
+        "the statfs of b went from {}/{} to {}/{} free blocks/inodes",
+        before.bfree,
+        before.ffree,
+        after_b.bfree,
+        after_b.ffree
+    );
+    // the node flattens the EXDEV of the syscall.
+    ensure_err!(root_b.link("moved", file_a.clone()), VfsError::NotSupported);
+    ensure_errno!(
+        crate::ops::check_same_dev(root_b.as_ref(), file_a.as_ref()),
+        Errno::EXDEV
+    );
+    Ok(())
+}
+
+/// Check the remounts of ext4: a volume mounted read-only takes the
+/// writes once remounted read-write, a remount to read-only is refused
+/// while a file is open for writing, and the writes buffered before a
+/// clean remount to read-only reach the disk. The opens for writing and
+/// the unlinks of the read-only volume fail with EROFS.
+pub fn ext4_remount() -> Result<(), String> {
+    use crate::dentry::{dentry_open_at, DentryNode, ResolveContext};
+    use crate::mounts::{self, remount_at, MountFlags};
+    use crate::ops::unlinkat;
+    use crate::statfs::{ST_RDONLY, ST_RELATIME};
+
+    let device = ram_ext4_device(8 << 20, *b"ext4-remount-dev")?;
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::builder_from_device(device)
+            .read_only(true)
+            .mount(),
+    )?;
+    let as_fs = fs.clone() as Arc<dyn FileSystem>;
+    let root = Arc::new(DentryNode::new(
+        String::from("/"),
+        fs.root(),
+        alloc::sync::Weak::new(),
+    ));
+    ensure_err!(fs.root().touch("data"), VfsError::NotSupported);
+    ensure!(
+        mounts::flags(&as_fs) == Some(MountFlags::RDONLY | MountFlags::RELATIME),
+        "flags {:?} of the read-only mount",
+        mounts::flags(&as_fs)
+    );
+    let st_flags = |root: &File| -> Result<u64, String> {
+        Ok(ok("fstatfs", crate::statfs::fstatfs(root))?.flags)
+    };
+    ensure!(
+        st_flags(&fs.root())? == ST_RDONLY | ST_RELATIME,
+        "the f_flag of the read-only mount is {:#x}",
+        st_flags(&fs.root())?
+    );
+    ensure_err!(
+        remount_at(&root, "/", MountFlags::NONE, "data=journal"),
+        FsError {
+            error: VfsError::NotSupported,
+            ..
+        }
+    );
+
+    // ro to rw, the writes start to succeed.
+    ok("remount rw", remount_at(&root, "/", MountFlags::NONE, "rw"))?;
+    ensure!(
+        mounts::flags(&as_fs) == Some(MountFlags::RELATIME),
+        "flags {:?} after the remount",
+        mounts::flags(&as_fs)
+    );
+    ensure!(
+        st_flags(&fs.root())? == ST_RELATIME,
+        "the f_flag after the remount is {:#x}",
+        st_flags(&fs.root())?
+    );
+    let node = ok("touch", fs.root().touch("data"))?;
+    ok("mkdir", fs.root().mkdir("dir"))?;
+    ensure_errno!(
+        remount_at(&root, "/dir", MountFlags::RDONLY, ""),
+        Errno::EINVAL
+    );
+
+    // rw to ro is refused with a file open for writing.
+    let file: File = FileHandle::new(node.clone(), OpenFlags::O_WRONLY);
+    ok("writeat", file.writeat(0, b"remount"))?;
+    ensure_errno!(remount_at(&root, "/", MountFlags::RDONLY, ""), Errno::EBUSY);
+    ok("writeat after the refusal", file.writeat(7, b"ed"))?;
+    ensure!(
+        mounts::flags(&as_fs) == Some(MountFlags::RELATIME),
+        "flags {:?} after the refusal",
+        mounts::flags(&as_fs)
+    );
+
+    // rw to ro once it's closed, the buffered writes are written back.
+    drop(file);
+    let reader: File = FileHandle::new(node.clone(), OpenFlags::O_RDONLY);
+    ok("remount ro", remount_at(&root, "/", MountFlags::RDONLY, ""))?;
+    ensure_err!(node.writeat(0, b"denied"), VfsError::NotSupported);
+    ensure_err!(fs.root().touch("denied"), VfsError::NotSupported);
+    let ctx = ResolveContext::with_root(root.clone());
+    ensure_errno!(
+        dentry_open_at(&ctx, "/data", OpenFlags::O_WRONLY),
+        Errno::EROFS
+    );
+    ensure_errno!(
+        dentry_open_at(&ctx, "/denied", OpenFlags::O_CREAT),
+        Errno::EROFS
+    );
+    ensure_errno!(unlinkat(&ctx, "/data", false), Errno::EROFS);
+    ok(
+        "open for reading",
+        dentry_open_at(&ctx, "/data", OpenFlags::O_RDONLY),
+    )?;
+    crate::cache::drop_caches();
+    let data = read_all(&ok("lookup", fs.root().lookup("data"))?, 64)?;
+    ensure!(data == b"remounted", "read {:?} after the remount", data);
+    let data = read_all(&reader, 64)?;
+    ensure!(data == b"remounted", "the reader read {:?}", data);
+    ensure!(
+        fs.mount_info().read_only.is_some(),
+        "mount_info is writable after the remount"
+    );
+
+    // and back to rw.
+    ok("remount rw", remount_at(&root, "/", MountFlags::NONE, ""))?;
+    ok("writeat", node.writeat(0, b"R"))?;
+    Ok(())
+}
+
+/// Check the recovery mount of ext4: the sync of a populated image of two
+/// groups copies its superblock and group descriptors to the backups of
+/// group 1. With the primary ones zeroed the image doesn't mount, but it
+/// mounts read-only from the backup of group 1 with all its files.
+pub fn ext4_backup_superblock() -> Result<(), String> {
+    use crate::ext4_mkfs::{format, Options};
+    use crate::ext4_rs_shim::ReadOnlyReason;
+    use crate::testing::MockDisk;
+
+    // a group of 4K blocks is 128 MiB, group 1 is a short one.
+    const SIZE: usize = 129 << 20;
+    let options = Options {
+        bytes_per_inode: 65536,
+        uuid: *b"ext4-backup-sb-t",
+        ..Default::default()
+    };
+    let disk = Arc::new(MockDisk::new(SIZE, 512));
+    let layout = ok(
+        "format",
+        format(SIZE as u64, &options, |block, data| {
+            disk.write_at(block as usize * options.block_size, data)
+        }),
+    )?;
+    ensure!(layout.groups == 2, "{} groups", layout.groups);
+
+    let files: Vec<(String, Vec<u8>)> = (0..20)
+        .map(|i| (format!("file-{}", i), vec![i as u8; 100 + i * 1000]))
+        .collect();
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::new_from_device(disk.clone()),
+    )?;
+    let dir = ok("mkdir", fs.root().mkdir("dir"))?;
+    for (name, data) in files.iter() {
+        let file = ok("touch", dir.touch(name))?;
+        ok("writeat", file.writeat(0, data))?;
+        ok("flush", file.flush())?;
+    }
+    ok("sync", FileSystem::flush(fs.as_ref()))?;
+    drop(dir);
+    drop(fs);
+
+    // the primary superblock and the block of the group descriptors.
+    disk.write_at(1024, &[0; 1024]);
+    disk.write_at(4096, &[0; 4096]);
+    ensure_err!(
+        crate::Ext4FileSystem::new_from_device(disk.clone()),
+        VfsError::InvalidData
+    );
+    ensure_err!(
+        crate::Ext4FileSystem::builder_from_device(disk.clone())
+            .backup_superblock(0)
+            .mount(),
+        VfsError::InvalidData
+    );
+    let fs = ok(
+        "mount from the backup",
+        crate::Ext4FileSystem::builder_from_device(disk.clone())
+            .backup_superblock(1)
+            .mount(),
+    )?;
+    ensure!(
+        matches!(
+            fs.mount_info().read_only,
+            Some(ReadOnlyReason::BackupSuperblock(1))
+        ),
+        "mount_info {:?}",
+        fs.mount_info()
+    );
+    let dir = ok("lookup", fs.root().lookup("dir"))?;
+    for (name, data) in files.iter() {
+        let file = ok("lookup", dir.lookup(name))?;
+        let read = read_all(&file, data.len() + 1)?;
+        ensure!(read == *data, "{} read {} bytes", name, read.len());
+    }
+    ensure_err!(dir.touch("new"), VfsError::NotSupported);
+    let mut primary = [1; 1024];
+    disk.read_at(1024, &mut primary);
+    ensure!(
+        primary.iter().all(|x| *x == 0),
+        "the recovery mount wrote the primary superblock"
+    );
+    Ok(())
+}
+
+/// Check the error history of ext4: a lookup in a directory whose block
+/// was doctored fails, and the error is in the superblock of the next
+/// mount as the first and the last one. Another error keeps the first.
+pub fn ext4_error_history() -> Result<(), String> {
+    use crate::blockdev::{BlockDevice, READ_SIZE};
+    use crate::ErrorCode;
+
+    let options = crate::ext4_mkfs::Options {
+        uuid: *b"ext4-err-history",
+        metadata_csum: false,
+        ..Default::default()
+    };
+    let (_, device) = ram_ext4_image(8 << 20, &options)?;
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::new_from_device(device.clone()),
+    )?;
+    ensure!(
+        fs.error_history() == Default::default(),
+        "a fresh image has errors:\n{}",
+        fs.error_history()
+    );
+    let a = ok("mkdir", fs.root().mkdir("a"))?;
+    let a_ino = ok("metadata", a.metadata())?.inode as u32;
+    ok("flush", FileSystem::flush(fs.as_ref()))?;
+    drop((a, fs));
+    // the first block of a starts with "." of 12 bytes, then "..", whose
+    // rec_len 0 can't be parsed.
+    let mut dot = a_ino.to_le_bytes().to_vec();
+    dot.extend_from_slice(&[12, 0, 1, 2, b'.', 0, 0, 0]);
+    let offset = (0..(8 << 20) / READ_SIZE)
+        .map(|x| x * READ_SIZE)
+        .find(|x| device.read_offset(*x).starts_with(&dot))
+        .ok_or("no first block of a")?;
+    device.write_offset(offset + 16, &[0, 0]);
+
+    let fs = ok(
+        "remount",
+        crate::Ext4FileSystem::new_from_device(device.clone()),
+    )?;
+    let a = ok("lookup", fs.root().lookup("a"))?;
+    ensure_err!(a.lookup("x"), VfsError::InvalidData);
+    drop((a, fs));
+
+    let fs = ok(
+        "remount",
+        crate::Ext4FileSystem::new_from_device(device.clone()),
+    )?;
+    let history = fs.error_history();
+    let first = history.first.clone().ok_or("no first error")?;
+    ensure!(
+        history.count == 1 && history.last.as_ref() == Some(&first),
+        "history:\n{}",
+        history
+    );
+    ensure!(
+        first.function == "parse_dir_block"
+            && first.ino == a_ino
+            && first.block == (offset / 4096) as u64
+            && first.code == ErrorCode::Corrupted
+            && first.line != 0,
+        "first error {:?}",
+        first
+    );
+    let dump = format!("{}", history);
+    for line in [
+        "FS Error count:           1",
+        "First error function:    parse_dir_block",
+        "First error err:         EFSCORRUPTED",
+    ] {
+        ensure!(
+            dump.lines().any(|x| x == line),
+            "no {:?} in:\n{}",
+            line,
+            dump
+        );
+    }
+
+    let a = ok("lookup", fs.root().lookup("a"))?;
+    ensure_err!(a.read_dir(), VfsError::InvalidData);
+    drop(a);
+    ok("sync", FileSystem::flush(fs.as_ref()))?;
+    let history = fs.error_history();
+    ensure!(
+        history.count == 2 && history.first == Some(first) && history.last.is_some(),
+        "history:\n{}",
+        history
+    );
+    Ok(())
+}
+
+/// Round-trip the metadata checksums of ext4: the inode of a file
+/// written and the block of its directory, reread after a remount, pass
+/// verify_inode, verify_dir_block and the checker. A byte changed in the
+/// inode fails the lookup of the file with InvalidData, one changed in a
+/// name of the directory block fails the lookups in it with InvalidData,
+/// a BadCrc error of the history, and the checker reports the block.
+pub fn ext4_csum_round_trip() -> Result<(), String> {
+    use crate::blockdev::BlockDevice;
+    use crate::ext4_check::Problem;
+    use crate::ext4_csum::{inode_seed, verify_dir_block, verify_inode};
+    use crate::ext4_layout::InodeInfo;
+    use crate::ErrorCode;
+
+    const BLOCK: usize = 4096;
+    let options = crate::ext4_mkfs::Options {
+        uuid: *b"ext4-csum-rounds",
+        ..Default::default()
+    };
+    let (_, device) = ram_ext4_image(8 << 20, &options)?;
+    let mount = || {
+        ok(
+            "mount",
+            crate::Ext4FileSystem::new_from_device(device.clone()),
+        )
+    };
+    let data = crate::golden::pattern(127, 0, 5000);
+    let (dir_ino, file_ino) = {
+        let fs = mount()?;
+        let dir = ok("mkdir", fs.root().mkdir("dir"))?;
+        let file = ok("touch", dir.touch("file"))?;
+        ok("writeat", file.writeat(0, &data))?;
+        ok("flush", FileSystem::flush(fs.as_ref()))?;
+        (
+            ok("metadata", dir.metadata())?.inode as u32,
+            ok("metadata", file.metadata())?.inode as u32,
+        )
+    };
+    {
+        let fs = mount()?;
+        let dir = ok("lookup", fs.root().lookup("dir"))?;
+        let read = read_all(&ok("lookup", dir.lookup("file"))?, data.len() + 1)?;
+        ensure!(read == data, "the file reads {} bytes", read.len());
+        let problems = fs.check().problems;
+        ensure!(problems.is_empty(), "the image: {:?}", problems);
+    }
+
+    let raw_inode = |ino: u32| {
+        let (sb, offset) = raw_inode_offset(device.as_ref(), ino);
+        let raw = device.read_offset(offset)[..sb.inode_size as usize].to_vec();
+        (sb, offset, raw)
+    };
+    let (sb, file_offset, file_raw) = raw_inode(file_ino);
+    let (_, _, dir_raw) = raw_inode(dir_ino);
+    for (ino, raw) in [(file_ino, &file_raw), (dir_ino, &dir_raw)] {
+        ensure!(
+            verify_inode(&sb, ino, raw),
+            "the inode {} doesn't match its checksum",
+            ino
+        );
+    }
+    // the first extent of i_block follows the header of 12 bytes.
+    let extent = &dir_raw[0x34..0x40];
+    let block = u32::from_le_bytes(extent[8..12].try_into().unwrap()) as usize
+        | (u16::from_le_bytes(extent[6..8].try_into().unwrap()) as usize) << 32;
+    let generation = InodeInfo::parse(&dir_raw, sb.inode_size as usize).generation;
+    let seed = inode_seed(&sb, dir_ino, generation);
+    let dir_block = device.read_offset(block * BLOCK)[..BLOCK].to_vec();
+    ensure!(
+        verify_dir_block(seed, &dir_block),
+        "the block {} of the directory doesn't match its checksum",
+        block
+    );
+
+    // the uid of the file, the checksum is left as it was.
+    device.write_offset(file_offset + 2, &[file_raw[2] ^ 1]);
+    {
+        let fs = mount()?;
+        let dir = ok("lookup", fs.root().lookup("dir"))?;
+        ensure_err!(dir.lookup("file"), VfsError::InvalidData);
+    }
+    device.write_offset(file_offset + 2, &file_raw[2..3]);
+    {
+        let fs = mount()?;
+        let dir = ok("lookup", fs.root().lookup("dir"))?;
+        ok("lookup", dir.lookup("file"))?;
+    }
+
+    let name = dir_block
+        .windows(4)
+        .position(|x| x == b"file")
+        .ok_or("no entry of the file in the block")?;
+    device.write_offset(block * BLOCK + name, b"g");
+    {
+        let fs = mount()?;
+        let dir = ok("lookup", fs.root().lookup("dir"))?;
+        ensure_err!(dir.lookup("file"), VfsError::InvalidData);
+        ensure_err!(dir.lookup("gile"), VfsError::InvalidData);
+    }
+    let fs = mount()?;
+    let last = fs.error_history().last.ok_or("no error recorded")?;
+    ensure!(
+        last.function == "verify_dir_block"
+            && last.ino == dir_ino
+            && last.block == block as u64
+            && last.code == ErrorCode::BadCrc,
+        "last error {:?}",
+        last
+    );
+    let problems = fs.check().problems;
+    ensure!(
+        problems.iter().any(|x| matches!(
+            x,
+            Problem::DirChecksum { ino, block: at } if *ino == dir_ino && *at == block as u64
+        )),
+        "the checker reports {:?}",
+        problems
+    );
+    Ok(())
+}
+
+/// Mount an image cut while the journal holds a committed transaction,
+/// read-only: the journal is replayed in memory, so the file written by
+/// the transaction reads back, while the disk sees no write at all, not
+/// even for the access times or a sync. A writable mount of the same
+/// image replays it to the disk and reads the same.
+pub fn ext4_read_only_replay() -> Result<(), String> {
+    use crate::ext4_layout::{INCOMPAT_RECOVER, SUPERBLOCK_OFFSET};
+    use crate::testing::MockDisk;
+
+    let data = crate::golden::pattern(188, 0, 0x2800);
+    let disk = Arc::new(MockDisk::from_image(crash_image(256)?, 512));
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::new_from_device(disk.clone()),
+    )?;
+    ensure!(fs.mount_info().journaled, "the image isn't journaled");
+    let before = disk.image();
+    disk.record_writes();
+    let file = ok("touch", fs.root().touch("replayed"))?;
+    ok("writeat", file.writeat(0, &data))?;
+    ok("flush", file.flush())?;
+    drop((file, fs));
+
+    // the image right after the recovery flag of the last transaction is
+    // set, its metadata is only in the journal.
+    let recover = |image: &[u8]| {
+        let incompat = SUPERBLOCK_OFFSET + 0x60;
+        u32::from_le_bytes(image[incompat..incompat + 4].try_into().unwrap()) & INCOMPAT_RECOVER
+            != 0
+    };
+    let mut image = before;
+    let mut cut = None;
+    for (offset, write) in disk.recorded_writes() {
+        let was = recover(&image);
+        image[offset..offset + write.len()].copy_from_slice(&write);
+        if !was && recover(&image) {
+            cut = Some(image.clone());
+        }
+    }
+    let image = cut.ok_or("no write sets the recovery flag")?;
+
+    let disk = Arc::new(MockDisk::from_image(image.clone(), 512));
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::builder_from_device(disk.clone())
+            .read_only(true)
+            .mount(),
+    )?;
+    let node = ok("lookup", fs.root().lookup("replayed"))?;
+    let reader: File = FileHandle::new(node, OpenFlags::O_RDONLY);
+    let read = read_all(&reader, data.len() + 1)?;
+    ensure!(
+        read == data,
+        "the read-only mount reads {} bytes",
+        read.len()
+    );
+    ensure_err!(fs.root().touch("denied"), VfsError::NotSupported);
+    ok("flush", FileSystem::flush(fs.as_ref()))?;
+    ensure!(
+        fs.read_only_violations() == 0,
+        "{} writes were dropped",
+        fs.read_only_violations()
+    );
+    drop((reader, fs));
+    ensure!(
+        disk.writes() == 0 && disk.image() == image,
+        "the read-only mount wrote {} times",
+        disk.writes()
+    );
+
+    let disk = Arc::new(MockDisk::from_image(image, 512));
+    let fs = ok(
+        "mount rw",
+        crate::Ext4FileSystem::new_from_device(disk.clone()),
+    )?;
+    ensure!(disk.writes() > 0, "the writable mount didn't replay");
+    let read = read_all(&ok("lookup", fs.root().lookup("replayed"))?, data.len() + 1)?;
+    ensure!(
+        read == data,
+        "the writable mount reads {} bytes",
+        read.len()
+    );
+    Ok(())
+}
+
+/// Mount an image whose journal holds a committed transaction followed
+/// by one without its commit block, both built in the log by build_log
+/// over the blocks of a file: the mount replays the first, its block has
+/// the new data, and discards the second, the block it rewrites too and
+/// the one only it writes keep theirs. The journal is emptied at the next
+/// sequence and the recovery flag is cleared.
+#[cfg(feature = "ext4_debug")]
+pub fn ext4_replay_committed() -> Result<(), String> {
+    use crate::ext4_csum::set_superblock_csum;
+    use crate::ext4_journal::{build_log, JournalSuperBlock};
+    use crate::ext4_layout::{SuperBlockInfo, INCOMPAT_RECOVER, SUPERBLOCK_OFFSET};
+    use crate::golden::pattern;
+    use crate::testing::MockDisk;
+
+    const BLOCK: usize = 4096;
+    let (old, committed, uncommitted) = (
+        pattern(124, 0, 2 * BLOCK),
+        pattern(125, 0, BLOCK),
+        pattern(126, 0, 2 * BLOCK),
+    );
+    let disk = Arc::new(MockDisk::from_image(crash_image(256)?, 512));
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::new_from_device(disk.clone()),
+    )?;
+    let file = ok("touch", fs.root().touch("fixture"))?;
+    ok("writeat", file.writeat(0, &old))?;
+    ok("flush", file.flush())?;
+    let ino = ok("metadata", file.metadata())?.inode as u32;
+    let sb = SuperBlockInfo::parse(&disk.image()[SUPERBLOCK_OFFSET..]);
+    // the physical blocks of the first logical blocks of the inode.
+    let physical = |ino: u32, count: u32| -> Result<Vec<usize>, String> {
+        let dump = ok("dump", fs.dump_inode(ino))?;
+        (0..count)
+            .map(|lblock| {
+                let extent = dump
+                    .extents()
+                    .find(|x| x.contains(lblock))
+                    .ok_or(format!("inode {} has no block {}", ino, lblock))?;
+                Ok((extent.physical + (lblock - extent.logical) as u64) as usize)
+            })
+            .collect()
+    };
+    let homes = physical(ino, 2)?;
+    let journal = physical(sb.journal_inum, 256)?;
+    let jsb_block = journal[0];
+    ok("flush", FileSystem::flush(fs.as_ref()))?;
+    drop((file, fs));
+
+    let mut image = disk.image();
+    let jsb_offset = jsb_block * BLOCK;
+    let jsb = ok(
+        "journal superblock",
+        JournalSuperBlock::parse(&image[jsb_offset..jsb_offset + BLOCK]),
+    )?;
+    ensure!(
+        jsb.start == 0,
+        "the journal of the clean image starts at {}",
+        jsb.start
+    );
+    let sequence = jsb.sequence;
+    let first = ok(
+        "build_log",
+        build_log(&jsb, sequence, &[(homes[0] as u64, committed.clone())]),
+    )?;
+    let mut second = ok(
+        "build_log",
+        build_log(
+            &jsb,
+            sequence + 1,
+            &[
+                (homes[0] as u64, uncommitted[..BLOCK].to_vec()),
+                (homes[1] as u64, uncommitted[BLOCK..].to_vec()),
+            ],
+        ),
+    )?;
+    // a crash before the commit block of the second transaction.
+    second.pop();
+    for (i, block) in first.iter().chain(second.iter()).enumerate() {
+        let offset = journal[jsb.first as usize + i] * BLOCK;
+        image[offset..offset + BLOCK].copy_from_slice(block);
+    }
+    jsb.set_start(
+        &mut image[jsb_offset..jsb_offset + BLOCK],
+        jsb.first,
+        sequence,
+    );
+    let incompat = SUPERBLOCK_OFFSET + 0x60;
+    let flags = u32::from_le_bytes(image[incompat..incompat + 4].try_into().unwrap());
+    image[incompat..incompat + 4].copy_from_slice(&(flags | INCOMPAT_RECOVER).to_le_bytes());
+    set_superblock_csum(&mut image[SUPERBLOCK_OFFSET..SUPERBLOCK_OFFSET + 1024]);
+
+    let disk = Arc::new(MockDisk::from_image(image, 512));
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::new_from_device(disk.clone()),
+    )?;
+    let image = disk.image();
+    let replayed = ok(
+        "journal superblock",
+        JournalSuperBlock::parse(&image[jsb_offset..jsb_offset + BLOCK]),
+    )?;
+    ensure!(
+        replayed.start == 0 && replayed.sequence == sequence + 1,
+        "the replayed journal starts at {} with the sequence {}, not {}",
+        replayed.start,
+        replayed.sequence,
+        sequence + 1
+    );
+    let flags = u32::from_le_bytes(image[incompat..incompat + 4].try_into().unwrap());
+    ensure!(
+        flags & INCOMPAT_RECOVER == 0,
+        "the recovery flag is still set"
+    );
+    for (i, home) in homes.iter().enumerate() {
+        let expected = match i {
+            0 => &committed[..],
+            _ => &old[BLOCK..],
+        };
+        ensure!(
+            image[home * BLOCK..(home + 1) * BLOCK] == *expected,
+            "the block {} of the file isn't the one of the committed transaction",
+            i
+        );
+    }
+    let read = read_all(&ok("lookup", fs.root().lookup("fixture"))?, 3 * BLOCK)?;
+    ensure!(
+        read[..BLOCK] == committed[..] && read[BLOCK..] == old[BLOCK..],
+        "the file reads {} bytes of the wrong transactions",
+        read.len()
+    );
+    let problems = fs.check().problems;
+    ensure!(problems.is_empty(), "the replayed image: {:?}", problems);
+    Ok(())
+}
+
+/// Mount an image cut while the journal holds a large committed
+/// transaction, the one creating /init and hundreds of directories, fast:
+/// the open of /init reads an order of magnitude fewer blocks than a
+/// read-only mount replaying the journal, since only the journal blocks
+/// of its path are replayed. Both mounts read the same files and count
+/// the same free blocks, the fast one writes nothing.
+pub fn ext4_fast_mount() -> Result<(), String> {
+    use crate::ext4_layout::{INCOMPAT_RECOVER, SUPERBLOCK_OFFSET};
+    use crate::ext4_mkfs::{format, Options};
+    use crate::fsync::SyncPolicy;
+    use crate::testing::{MockDisk, MockOp};
+
+    const SIZE: usize = 64 << 20;
+    const DIRS: usize = 300;
+    let options = Options {
+        uuid: *b"ext4-fast-mount!",
+        journal_blocks: 4096,
+        ..Default::default()
+    };
+    let mut image = vec![0; SIZE];
+    ok(
+        "format",
+        format(SIZE as u64, &options, |block, data| {
+            let offset = block as usize * options.block_size;
+            image[offset..offset + data.len()].copy_from_slice(data);
+        }),
+    )?;
+    let data = crate::golden::pattern(236, 0, 0x3000);
+    let disk = Arc::new(MockDisk::from_image(image, 512));
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::builder_from_device(disk.clone())
+            .sync_policy(SyncPolicy::WriteBack {
+                max_dirty_blocks: 1 << 16,
+                max_age_ticks: u64::MAX,
+            })
+            .mount(),
+    )?;
+    let before = disk.image();
+    disk.record_writes();
+    for i in 0..DIRS {
+        ok("mkdir", fs.root().mkdir(&format!("d{}", i)))?;
+    }
+    let file = ok("touch", fs.root().touch("init"))?;
+    ok("writeat", file.writeat(0, &data))?;
+    ok("sync", FileSystem::flush(fs.as_ref()))?;
+    drop((file, fs));
+
+    // the image right after the recovery flag of the write back is set.
+    let recover = |image: &[u8]| {
+        let incompat = SUPERBLOCK_OFFSET + 0x60;
+        u32::from_le_bytes(image[incompat..incompat + 4].try_into().unwrap()) & INCOMPAT_RECOVER
+            != 0
+    };
+    let mut image = before;
+    let mut cut = None;
+    for (offset, write) in disk.recorded_writes() {
+        let was = recover(&image);
+        image[offset..offset + write.len()].copy_from_slice(&write);
+        if !was && recover(&image) {
+            cut = Some(image.clone());
+        }
+    }
+    let image = cut.ok_or("no write sets the recovery flag")?;
+
+    // the reads of the mount and of the open of /init, then the mount.
+    let open_init = |fast: bool| -> Result<_, String> {
+        let disk = Arc::new(MockDisk::from_image(image.clone(), 512));
+        let fs = ok(
+            "mount",
+            crate::Ext4FileSystem::builder_from_device(disk.clone())
+                .read_only(true)
+                .fast(fast)
+                .mount(),
+        )?;
+        let node = ok("lookup", fs.root().lookup("init"))?;
+        let reader: File = FileHandle::new(node, OpenFlags::O_RDONLY);
+        let reads = disk.log().iter().filter(|x| x.op == MockOp::Read).count();
+        let read = read_all(&reader, data.len() + 1)?;
+        ensure!(
+            read == data,
+            "the mount with fast {} reads {} bytes of /init",
+            fast,
+            read.len()
+        );
+        Ok((disk, fs, reads))
+    };
+    let (_, full, full_reads) = open_init(false)?;
+    let (disk, fast, fast_reads) = open_init(true)?;
+    ensure!(
+        fast_reads * 10 <= full_reads,
+        "the fast mount reads {} blocks to open /init, the full one {}",
+        fast_reads,
+        full_reads
+    );
+
+    let (mut full_statfs, mut fast_statfs) = (StatFS::default(), StatFS::default());
+    ok("statfs", full.root().statfs(&mut full_statfs))?;
+    ok("statfs", fast.root().statfs(&mut fast_statfs))?;
+    ensure!(
+        (fast_statfs.bfree, fast_statfs.ffree) == (full_statfs.bfree, full_statfs.ffree),
+        "the fast mount counts {} free blocks and {} free inodes, the full one {} and {}",
+        fast_statfs.bfree,
+        fast_statfs.ffree,
+        full_statfs.bfree,
+        full_statfs.ffree
+    );
+    let (mut listed, mut expected) = (names(&fast.root())?, names(&full.root())?);
+    listed.sort();
+    expected.sort();
+    ensure!(
+        listed == expected && listed.len() >= DIRS + 1,
+        "the fast mount lists {} entries, the full one {}",
+        listed.len(),
+        expected.len()
+    );
+    for i in (0..DIRS).step_by(37) {
+        let dir = ok("lookup", fast.root().lookup(&format!("d{}", i)))?;
+        let listed = names(&dir)?;
+        ensure!(listed == [".", ".."], "d{} lists {:?}", i, listed);
+    }
+    ensure_err!(fast.root().touch("denied"), VfsError::NotSupported);
+    ok("flush", FileSystem::flush(fast.as_ref()))?;
+    drop(fast);
+    ensure!(
+        disk.writes() == 0,
+        "the fast mount wrote {} times",
+        disk.writes()
+    );
+    ensure_err!(
+        crate::Ext4FileSystem::builder_from_device(disk.clone())
+            .fast(true)
+            .read_only(false)
+            .mount(),
+        VfsError::InvalidInput
+    );
+    Ok(())
+}
+
+/// Time out the mounts of a sick device: with a second per request of the
+/// MockDisk, a mount cancelled at every budget of seconds fails with
+/// Blocking in a phase no earlier than with a smaller budget, within a few
+/// requests of the budget, until a budget large enough mounts. An image
+/// whose journal needs a replay times out while it's replayed and one
+/// with an orphan while it's released. A past deadline fails the mount
+/// before the superblock, a cancelled check fails too.
+pub fn ext4_mount_timeout() -> Result<(), String> {
+    use crate::ext4_layout::{INCOMPAT_RECOVER, SUPERBLOCK_OFFSET};
+    use crate::testing::MockDisk;
+    use crate::MountPhase;
+
+    const SECOND: u64 = 1_000_000_000;
+    // the requests of a step between two checks, like an orphan released
+    // in a transaction.
+    const SLACK: u64 = 64;
+    let scan = |image: &[u8]| -> Result<Vec<MountPhase>, String> {
+        let mut phases = Vec::new();
+        for budget in 0.. {
+            ensure!(
+                budget < 10000,
+                "the mount doesn't end in {} seconds",
+                budget
+            );
+            let disk = Arc::new(MockDisk::from_image(image.to_vec(), 512).with_delay(SECOND));
+            let clock = disk.clone();
+            let r = crate::Ext4FileSystem::builder_from_device(disk.clone())
+                .cancel(Arc::new(move || clock.elapsed() >= budget * SECOND))
+                .try_mount();
+            let err = match r {
+                Ok(_) => break,
+                Err(err) => err,
+            };
+            ensure!(
+                err.cancelled
+                    && matches!(err.error, VfsError::Blocking)
+                    && err.errno == Errno::EINTR,
+                "the mount with a budget of {}s failed with {:?}",
+                budget,
+                err
+            );
+            ensure!(
+                disk.elapsed() <= (budget + SLACK) * SECOND,
+                "the mount with a budget of {}s timed out after {}s",
+                budget,
+                disk.elapsed() / SECOND
+            );
+            phases.push(err.phase);
+        }
+        ensure!(
+            phases.windows(2).all(|x| x[0] <= x[1]),
+            "the phases go back: {:?}",
+            phases
+        );
+        Ok(phases)
+    };
+
+    // the image right after the recovery flag of a transaction is set.
+    let disk = Arc::new(MockDisk::from_image(crash_image(256)?, 512));
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::new_from_device(disk.clone()),
+    )?;
+    let mut image = disk.image();
+    disk.record_writes();
+    let file = ok("touch", fs.root().touch("replayed"))?;
+    ok("writeat", file.writeat(0, &[7; 0x2800]))?;
+    ok("flush", file.flush())?;
+    drop((file, fs));
+    let recover = |image: &[u8]| {
+        let incompat = SUPERBLOCK_OFFSET + 0x60;
+        u32::from_le_bytes(image[incompat..incompat + 4].try_into().unwrap()) & INCOMPAT_RECOVER
+            != 0
+    };
+    let mut cut = None;
+    for (offset, write) in disk.recorded_writes() {
+        let was = recover(&image);
+        image[offset..offset + write.len()].copy_from_slice(&write);
+        if !was && recover(&image) {
+            cut = Some(image.clone());
+        }
+    }
+    let replay = cut.ok_or("no write sets the recovery flag")?;
+    let phases = scan(&replay)?;
+    for phase in [
+        MountPhase::Superblock,
+        MountPhase::Features,
+        MountPhase::Journal,
+    ] {
+        ensure!(
+            phases.contains(&phase),
+            "the replay never times out in {:?}: {:?}",
+            phase,
+            phases
+        );
+    }
+
+    // the image of a crash while an unlinked file is open.
+    let disk = Arc::new(MockDisk::from_image(crash_image(256)?, 512));
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::new_from_device(disk.clone()),
+    )?;
+    let file = ok("touch", fs.root().touch("orphan"))?;
+    ok("writeat", file.writeat(0, &[7; 0x2800]))?;
+    ok("remove", fs.root().remove("orphan"))?;
+    ok("sync", FileSystem::flush(fs.as_ref()))?;
+    let orphan = disk.image();
+    drop((file, fs));
+    let phases = scan(&orphan)?;
+    ensure!(
+        phases.contains(&MountPhase::Orphans),
+        "the release never times out: {:?}",
+        phases
+    );
+
+    fn late() -> u64 {
+        u64::MAX
+    }
+    let disk = Arc::new(MockDisk::from_image(replay, 512));
+    let err = match crate::Ext4FileSystem::builder_from_device(disk.clone())
+        .deadline(late, 1)
+        .try_mount()
+    {
+        Ok(_) => return Err(String::from("the mount past its deadline mounted")),
+        Err(err) => err,
+    };
+    ensure!(
+        err.cancelled
+            && err.errno == Errno::ETIMEDOUT
+            && err.phase == MountPhase::Superblock
+            && disk.elapsed() == 0,
+        "the mount past its deadline failed with {:?}",
+        err
+    );
+    let fs = ok("mount", crate::Ext4FileSystem::new_from_device(disk))?;
+    ensure_err!(fs.check_cancel(&|| true), VfsError::Blocking);
+    let report = ok("check", fs.check_cancel(&crate::cancel::never))?;
+    ensure!(report.is_clean(), "the check finds {:?}", report.problems);
+    Ok(())
+}
+
+/// Mount /variants/a of an image as the root: its files read and take the
+/// writes as usual, ".." of its root is itself, the resolver of a task
+/// rooted at it can't reach /variants/b or /secret, and statfs counts the
+/// whole image. The walk to the subtree must find directories.
+pub fn ext4_subtree() -> Result<(), String> {
+    use crate::dentry::{dentry_open_at, DentryNode, ResolveContext};
+
+    let device = ram_ext4_device(8 << 20, *b"ext4-subtree-dev")?;
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::new_from_device(device.clone()),
+    )?;
+    let variants = ok("mkdir", fs.root().mkdir("variants"))?;
+    for (name, data) in [("a", b"variant a"), ("b", b"variant b")] {
+        let dir = ok("mkdir", variants.mkdir(name))?;
+        let bin = ok("mkdir bin", dir.mkdir("bin"))?;
+        ok("writeat", ok("touch", bin.touch("sh"))?.writeat(0, data))?;
+    }
+    ok("touch", fs.root().touch("secret"))?;
+    let statfs = |node: &File| {
+        let mut statfs = StatFS::default();
+        ok("statfs", node.statfs(&mut statfs)).map(|_| statfs)
+    };
+    let whole = statfs(&fs.root())?;
+    ok("flush", FileSystem::flush(fs.as_ref()))?;
+    drop((variants, fs));
+
+    let mount = |path: &str| {
+        crate::Ext4FileSystem::builder_from_device(device.clone())
+            .subtree(path)
+            .mount()
+    };
+    ensure_err!(mount("/variants/c"), VfsError::FileNotFound);
+    ensure_err!(mount("/secret"), VfsError::NotDir);
+    ensure_err!(mount("/variants/../variants/a"), VfsError::InvalidInput);
+
+    let fs = ok("mount the subtree", mount("/variants/a/"))?;
+    let root = fs.root();
+    let sh = ok("lookup", ok("lookup bin", root.lookup("bin"))?.lookup("sh"))?;
+    let data = read_all(&sh, 64)?;
+    ensure!(
+        data == b"variant a",
+        "bin/sh of the subtree reads {:?}",
+        data
+    );
+    let parent = ok("lookup ..", root.lookup(".."))?;
+    ensure!(
+        crate::walk::identity(parent.as_ref()) == crate::walk::identity(root.as_ref()),
+        "\"..\" of the subtree root is another directory"
+    );
+    ensure_err!(root.lookup("variants"), VfsError::FileNotFound);
+    ensure_err!(root.lookup("secret"), VfsError::FileNotFound);
+
+    let top = Arc::new(DentryNode::new(
+        String::from("/"),
+        root.clone(),
+        alloc::sync::Weak::new(),
+    ));
+    let ctx = ResolveContext::with_root(top);
+    for path in [
+        "/../secret",
+        "../../secret",
+        "/bin/../../b/bin/sh",
+        "../variants",
+    ] {
+        ensure_errno!(
+            dentry_open_at(&ctx, path, OpenFlags::O_RDONLY),
+            Errno::ENOENT
+        );
+    }
+    let sh = ok(
+        "open",
+        dentry_open_at(&ctx, "/../bin/../bin/sh", OpenFlags::O_RDONLY),
+    )?;
+    ensure!(
+        read_all(&sh.node, 64)? == b"variant a",
+        "the clamped path opens another sh"
+    );
+
+    let new = ok("touch", root.touch("new"))?;
+    ok("writeat", new.writeat(0, b"inside"))?;
+    ok("flush", new.flush())?;
+    let statfs = statfs(&root)?;
+    ensure!(
+        statfs.blocks == whole.blocks && statfs.files == whole.files,
+        "the subtree counts {} blocks and {} inodes, the image {} and {}",
+        statfs.blocks,
+        statfs.files,
+        whole.blocks,
+        whole.files
+    );
+    ok("flush", FileSystem::flush(fs.as_ref()))?;
+    drop((new, sh, parent, root, fs));
+
+    let fs = ok("mount", crate::Ext4FileSystem::new_from_device(device))?;
+    let path = ["variants", "a", "new"];
+    let mut node = fs.root();
+    for name in path {
+        node = ok("lookup", node.lookup(name))?;
+    }
+    ensure!(
+        read_all(&node, 64)? == b"inside",
+        "the file created in the subtree isn't in the image"
+    );
+    let report = fs.check();
+    ensure!(
+        report.problems.is_empty(),
+        "the image has problems: {:?}",
+        report.problems
+    );
+    Ok(())
+}
+
+/// Check an image with bigalloc isn't mounted, even read-only: its
+/// bitmaps count the clusters and the shim would misread them.
+pub fn ext4_bigalloc_refused() -> Result<(), String> {
+    use crate::blockdev::BlockDevice;
+    use crate::ext4_csum::set_superblock_csum;
+    use crate::ext4_layout::{RO_COMPAT_BIGALLOC, SUPERBLOCK_OFFSET};
+
+    let options = crate::ext4_mkfs::Options {
+        uuid: *b"ext4-bigalloc-no",
+        ..Default::default()
+    };
+    let (ram, device) = ram_ext4_image(16 << 20, &options)?;
+    let offset = EXT4_RAM_START + SUPERBLOCK_OFFSET;
+    let mut sb = ram.image()[offset..offset + 1024].to_vec();
+    let ro_compat = u32::from_le_bytes(sb[0x64..0x68].try_into().unwrap()) | RO_COMPAT_BIGALLOC;
+    sb[0x64..0x68].copy_from_slice(&ro_compat.to_le_bytes());
+    set_superblock_csum(&mut sb);
+    ram.write_offset(offset, &sb);
+    for read_only in [false, true] {
+        ensure_err!(
+            crate::Ext4FileSystem::builder_from_device(device.clone())
+                .read_only(read_only)
+                .mount(),
+            VfsError::NotSupported
+        );
+    }
+    Ok(())
+}
+
+/// Mount the images with a doctored superblock: a bad magic, a block size
+/// shift far past 64K, which would overflow block_size, and no inodes.
+/// The checksum is fixed up so the geometry is what's checked; each mount
+/// fails with InvalidData, EIO to the kernel, in the features phase,
+/// without a panic, read-only as well.
+pub fn ext4_doctored_superblock() -> Result<(), String> {
+    use crate::blockdev::BlockDevice;
+    use crate::ext4_csum::set_superblock_csum;
+    use crate::ext4_layout::SUPERBLOCK_OFFSET;
+    use crate::MountPhase;
+
+    let cases: [(&str, usize, u32); 3] = [
+        ("bad magic", 0x38, 0xEF54),
+        ("oversized log_block_size", 0x18, u32::MAX),
+        ("no inodes", 0x0, 0),
+    ];
+    for (what, field, value) in cases {
+        let options = crate::ext4_mkfs::Options {
+            uuid: *b"ext4-doctored-sb",
+            ..Default::default()
+        };
+        let (ram, device) = ram_ext4_image(16 << 20, &options)?;
+        let offset = EXT4_RAM_START + SUPERBLOCK_OFFSET;
+        let mut sb = ram.image()[offset..offset + 1024].to_vec();
+        match field {
+            // the magic is a u16, the rest of its word is the state.
+            0x38 => sb[field..field + 2].copy_from_slice(&(value as u16).to_le_bytes()),
+            _ => sb[field..field + 4].copy_from_slice(&value.to_le_bytes()),
+        }
+        set_superblock_csum(&mut sb);
+        ram.write_offset(offset, &sb);
+        for read_only in [false, true] {
+            let err = match crate::Ext4FileSystem::builder_from_device(device.clone())
+                .read_only(read_only)
+                .try_mount()
+            {
+                Ok(_) => return Err(format!("the image with {} is mounted", what)),
+                Err(err) => err,
+            };
+            ensure!(
+                matches!(err.error, VfsError::InvalidData)
+                    && err.errno == Errno::EIO
+                    && err.phase == MountPhase::Features
+                    && !err.cancelled,
+                "the mount of the image with {} failed with {:?}",
+                what,
+                err
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Mount an empty device: a RamDisk of no sectors read as zeros by its
+/// SectorDevice and a RamDevice of no bytes both fail in the features
+/// phase with InvalidData, EIO to the kernel, as there is no superblock,
+/// and nothing is cached. The node of the disk reads 0 bytes and its
+/// write fails with StorageFull.
+#[cfg(feature = "std")]
+pub fn ext4_empty_device() -> Result<(), String> {
+    use crate::blockdev::RamDevice;
+    use crate::devnode::BlockNode;
+    use crate::sys::{add_blk_device, RamDisk};
+    use crate::MountPhase;
+
+    let pages = crate::cache::stats().pages;
+    let device_id = add_blk_device(Arc::new(RamDisk::new(0)));
+    let mounts = [
+        crate::Ext4FileSystem::builder(device_id).try_mount(),
+        crate::Ext4FileSystem::builder_from_device(Arc::new(RamDevice::new(0))).try_mount(),
+    ];
+    for (i, r) in mounts.into_iter().enumerate() {
+        let err = match r {
+            Ok(_) => return Err(format!("the empty device {} is mounted", i)),
+            Err(err) => err,
+        };
+        ensure!(
+            matches!(err.error, VfsError::InvalidData)
+                && err.errno == Errno::EIO
+                && err.phase == MountPhase::Features
+                && !err.cancelled,
+            "the mount of the empty device {} failed with {:?}",
+            i,
+            err
+        );
+    }
+    ensure!(
+        crate::cache::stats().pages == pages,
+        "the failed mounts cached pages"
+    );
+
+    let disk = BlockNode::disk(device_id).ok_or("no node of the disk")?;
+    let mut buf = [0xff; 512];
+    ensure!(
+        ok("readat", disk.readat(0, &mut buf))? == 0,
+        "the empty disk reads bytes"
+    );
+    ensure_err!(disk.writeat(0, &buf), VfsError::StorageFull);
+    Ok(())
+}
+
+/// Set and clear the bits of the feature fields (compat, incompat,
+/// ro_compat) of the superblock of the ext4 image, through the cache.
+fn patch_features(device: &dyn crate::blockdev::BlockDevice, set: [u32; 3], clear: [u32; 3]) {
+    use crate::ext4_csum::set_superblock_csum;
+    use crate::ext4_layout::SUPERBLOCK_OFFSET;
+
+    let mut sb = device.read_offset(SUPERBLOCK_OFFSET)[..1024].to_vec();
+    for (i, at) in [0x5C, 0x60, 0x64].into_iter().enumerate() {
+        let flags = u32::from_le_bytes(sb[at..at + 4].try_into().unwrap());
+        sb[at..at + 4].copy_from_slice(&((flags | set[i]) & !clear[i]).to_le_bytes());
+    }
+    set_superblock_csum(&mut sb);
+    device.write_offset(SUPERBLOCK_OFFSET, &sb);
+}
+
+/// Check the feature matrix of the ext4 mount: the images with the
+/// compat, incompat and ro_compat features of mke2fs -O are mounted
+/// read-write, read-only or refused as mount_report lists them, force_rw
+/// overrides the read-only features and a remount can't.
+pub fn ext4_feature_matrix() -> Result<(), String> {
+    use crate::ext4_rs_shim::{FeatureHandling, FeatureKind, ReadOnlyReason};
+    use crate::mounts::{MountFlags, Remount};
+
+    use FeatureHandling::{ReadOnly, Refused, Supported};
+    use FeatureKind::{Compat, Incompat, RoCompat};
+
+    // the bits set on the image of mkfs, the expected handling of the
+    // first one and of the mount.
+    let cases: [(&str, [u32; 3], FeatureKind, FeatureHandling); 8] = [
+        ("mkfs", [0; 3], RoCompat, Supported),
+        ("huge_file", [0, 0, 0x8], RoCompat, Supported),
+        ("dir_index,0x8000", [0x8020, 0, 0], Compat, Supported),
+        ("quota", [0, 0, 0x100], RoCompat, ReadOnly),
+        ("uninit_bg", [0, 0, 0x10], RoCompat, ReadOnly),
+        ("0x100000", [0, 0, 0x100000], RoCompat, ReadOnly),
+        ("mmp", [0, 0x100, 0], Incompat, Refused),
+        ("casefold", [0, 0x20000, 0], Incompat, Refused),
+    ];
+    for (i, (name, set, kind, handling)) in cases.into_iter().enumerate() {
+        let mut uuid = *b"ext4-features-00";
+        uuid[15] = b'a' + i as u8;
+        let options = crate::ext4_mkfs::Options {
+            uuid,
+            ..Default::default()
+        };
+        let (_, device) = ram_ext4_image(16 << 20, &options)?;
+        patch_features(device.as_ref(), set, [0; 3]);
+        let mount = |force_rw: bool| {
+            crate::Ext4FileSystem::builder_from_device(device.clone())
+                .force_rw(force_rw)
+                .mount()
+        };
+        if handling == Refused {
+            ensure_err!(mount(false), VfsError::NotSupported);
+            ensure_err!(mount(true), VfsError::NotSupported);
+            continue;
+        }
+        let fs = ok(name, mount(false))?;
+        let report = fs.mount_report();
+        let bit = set
+            .iter()
+            .zip([Compat, Incompat, RoCompat])
+            .find(|x| *x.0 != 0);
+        if let Some((&flags, kind_of)) = bit {
+            let bit = flags & flags.wrapping_neg();
+            let feature = report
+                .features
+                .iter()
+                .find(|x| x.kind == kind_of && x.bit == bit);
+            ensure!(
+                feature.is_some_and(|x| x.kind == kind && x.handling == handling),
+                "{}: the report of the feature is {:?}",
+                name,
+                feature
+            );
+        }
+        // the features of mkfs are all writable.
+        for feature in report.features.iter() {
+            let expected =
+                feature.handling == Supported || set[feature.kind as usize] & feature.bit != 0;
+            ensure!(expected, "{}: {:?}", name, feature);
+        }
+        match handling {
+            ReadOnly => {
+                let flags = set[2];
+                ensure!(
+                    matches!(
+                        report.mount.read_only,
+                        Some(ReadOnlyReason::RoCompatFeatures(x)) if x == flags
+                    ),
+                    "{}: the mount is {:?}",
+                    name,
+                    report.mount
+                );
+                ensure_err!(fs.root().touch("file"), VfsError::NotSupported);
+                ensure_err!(
+                    Remount::remount(fs.as_ref(), MountFlags::NONE, "rw"),
+                    VfsError::InvalidInput
+                );
+                drop(fs);
+                let fs = ok("force_rw", mount(true))?;
+                let info = fs.mount_info();
+                ensure!(
+                    info.read_only.is_none()
+                        && matches!(info.forced_rw, Some(ReadOnlyReason::RoCompatFeatures(_))),
+                    "{}: the forced mount is {:?}",
+                    name,
+                    info
+                );
+                ok("touch forced", fs.root().touch("file"))?;
+            }
+            _ => {
+                ensure!(
+                    report.mount.read_only.is_none(),
+                    "{}: the mount is {:?}",
+                    name,
+                    report.mount
+                );
+                ok("touch", fs.root().touch("file"))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check the ext4 ro_compat features kept by the writes: a directory past
+/// 65000 links has a count of 1 with dir_nlink, which stays through its
+/// next subdirectories and their removal, and the subdirectory is refused
+/// without it. The new inodes hold s_min_extra_isize bytes of extra
+/// fields with extra_isize.
+pub fn ext4_ro_compat_shims() -> Result<(), String> {
+    use crate::ext4_layout::RO_COMPAT_DIR_NLINK;
+
+    let options = crate::ext4_mkfs::Options {
+        uuid: *b"ext4-ro-compat-s",
+        ..Default::default()
+    };
+    let (_, device) = ram_ext4_image(16 << 20, &options)?;
+    let mount = || {
+        ok(
+            "mount",
+            crate::Ext4FileSystem::builder_from_device(device.clone()).mount(),
+        )
+    };
+    let nlink = |dir: &File| -> Result<u32, String> {
+        let mut stat = Stat::default();
+        ok("stat", dir.stat(&mut stat))?;
+        Ok(stat.nlink as u32)
+    };
+    let ino = {
+        let fs = mount()?;
+        let dir = ok("mkdir", fs.root().mkdir("dir"))?;
+        let mut stat = Stat::default();
+        ok("stat", dir.stat(&mut stat))?;
+        stat.ino as u32
+    };
+    let set_links = |links: u16| {
+        patch_raw_inode(device.as_ref(), ino, |raw| {
+            raw[0x1A..0x1C].copy_from_slice(&links.to_le_bytes())
+        })
+    };
+    set_links(64999)?;
+    {
+        let fs = mount()?;
+        let dir = ok("lookup", fs.root().lookup("dir"))?;
+        ok("mkdir a", dir.mkdir("a"))?;
+        ensure!(
+            nlink(&dir)? == 65000,
+            "the links after a are {}",
+            nlink(&dir)?
+        );
+        ok("mkdir b", dir.mkdir("b"))?;
+        ensure!(
+            nlink(&dir)? == 1,
+            "the links past the max are {}",
+            nlink(&dir)?
+        );
+        ok("mkdir c", dir.mkdir("c"))?;
+        ok("rmdir a", dir.rmdir("a"))?;
+        ensure!(nlink(&dir)? == 1, "the count of 1 became {}", nlink(&dir)?);
+        ok("lookup b", dir.lookup("b"))?;
+        ok("lookup c", dir.lookup("c"))?;
+    }
+
+    // without dir_nlink, the directory can't get more links.
+    patch_features(device.as_ref(), [0; 3], [0, 0, RO_COMPAT_DIR_NLINK]);
+    set_links(65000)?;
+    {
+        let fs = mount()?;
+        let dir = ok("lookup", fs.root().lookup("dir"))?;
+        ensure_err!(dir.mkdir("d"), VfsError::InvalidInput);
+        ensure_err!(dir.lookup("d"), VfsError::FileNotFound);
+        ok("touch", dir.touch("file"))?;
+        ensure!(nlink(&dir)? == 65000, "the links became {}", nlink(&dir)?);
+    }
+
+    // s_min_extra_isize of 64.
+    let mut sb = device.read_offset(crate::ext4_layout::SUPERBLOCK_OFFSET)[..1024].to_vec();
+    sb[0x15C..0x15E].copy_from_slice(&64u16.to_le_bytes());
+    crate::ext4_csum::set_superblock_csum(&mut sb);
+    device.write_offset(crate::ext4_layout::SUPERBLOCK_OFFSET, &sb);
+    let file_ino = {
+        let fs = mount()?;
+        let file = ok("touch", fs.root().touch("extra"))?;
+        let mut stat = Stat::default();
+        ok("stat", file.stat(&mut stat))?;
+        stat.ino as u32
+    };
+    let mut extra = 0;
+    patch_raw_inode(device.as_ref(), file_ino, |raw| {
+        extra = u16::from_le_bytes([raw[0x80], raw[0x81]]);
+    })?;
+    ensure!(extra == 64, "i_extra_isize of the new inode is {}", extra);
+    let fs = mount()?;
+    let report = fs.check();
+    ensure!(
+        report.problems.is_empty(),
+        "the image has problems: {:?}",
+        report.problems
+    );
+    Ok(())
+}
+
+/// The tunables of an ext4 mount in a TuneFs mounted by its type: the
+/// readahead written to its node changes the reads of the page cache
+/// misses on the MockDisk, the bad values fail with EINVAL and change
+/// nothing, the dirty threshold is the one of the write-back policy, and
+/// the directory of the mount is gone once the mount is dropped.
+pub fn tunefs_ext4() -> Result<(), String> {
+    use crate::fstype::{self, mount_by_name, MountSource};
+    use crate::mounts::MountFlags;
+    use crate::pseudo;
+    use crate::testing::{MockDisk, MockOp};
+
+    // a file of 64 pages, read back by a new mount with a cold cache.
+    let disk = Arc::new(MockDisk::from_image(crash_image(0)?, 512));
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::new_from_device(disk.clone()),
+    )?;
+    let data: Vec<u8> = (0..64 * 4096).map(|x| (x / 4096) as u8).collect();
+    let file = ok("touch", fs.root().touch("file"))?;
+    ok("write", file.writeat(0, &data))?;
+    ok("flush", FileSystem::flush(fs.as_ref()))?;
+    drop((file, fs));
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::new_from_device(disk.clone()),
+    )?;
+    let file = ok("lookup", fs.root().lookup("file"))?;
+
+    fstype::init();
+    let tunefs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(ok(
+        "mount",
+        mount_by_name("tunefs", MountSource::None, MountFlags::NONE),
+    )?));
+    let dir = ok(
+        "lookup",
+        tunefs
+            .root_dir()
+            .lookup("ext4")
+            .and_then(|x| x.lookup(&fs.dev().to_string())),
+    )?;
+    let node = |name: &str| ok("lookup", dir.lookup(name)).map(pseudo::open);
+    let value = |name: &str| -> Result<String, String> {
+        let data = read_all(&node(name)?, 256)?;
+        Ok(String::from_utf8_lossy(&data).into_owned())
+    };
+    let read_bytes = |page: usize| -> Result<usize, String> {
+        disk.clear_log();
+        let mut buf = [0; 4096];
+        ok("read", file.readat(page * 4096, &mut buf))?;
+        ensure!(buf == [page as u8; 4096], "page {} reads back wrong", page);
+        Ok(disk
+            .log()
+            .iter()
+            .filter(|x| x.op == MockOp::Read)
+            .map(|x| x.len)
+            .sum())
+    };
+
+    ensure!(
+        value("readahead_blocks")? == "0\n",
+        "the readahead isn't the option"
+    );
+    read_bytes(0)?;
+    let cold = read_bytes(1)?;
+    ok("write", node("readahead_blocks")?.writeat(0, b"8\n"))?;
+    ensure!(
+        value("readahead_blocks")? == "8\n",
+        "the readahead wasn't set"
+    );
+    ensure!(
+        fs.options().readahead_blocks == 8,
+        "the options miss the readahead"
+    );
+    let ahead = read_bytes(16)?;
+    ensure!(
+        ahead >= cold + 8 * 4096,
+        "the miss read {} bytes, {} without readahead",
+        ahead,
+        cold
+    );
+    let cached = read_bytes(20)?;
+    ensure!(cached < cold, "the page read ahead was read again");
+
+    // the bad values change nothing.
+    for (name, bad) in [
+        ("readahead_blocks", "eight"),
+        ("readahead_blocks", "100000"),
+        ("cache_bytes", "100"),
+        ("sync_policy", "write_back:0:10"),
+        ("sync_policy", "write_sideways"),
+        ("dirty_threshold", "64"),
+    ] {
+        let before = value(name)?;
+        let err = node(name)?.writeat(0, bad.as_bytes()).err();
+        ensure!(
+            err.is_some_and(|x| Errno::from(x) == Errno::EINVAL),
+            "writing {} to {} returned {:?}",
+            bad,
+            name,
+            err
+        );
+        ensure!(value(name)? == before, "{} changed by {}", name, bad);
+    }
+
+    ok("write", node("cache_bytes")?.writeat(0, b"8192"))?;
+    ensure!(fs.block_cache_bytes() == 8192, "the block cache wasn't set");
+    ok(
+        "write",
+        node("sync_policy")?.writeat(0, b"write_back:64:10\n"),
+    )?;
+    ensure!(
+        value("dirty_threshold")? == "64\n",
+        "the threshold isn't the policy's"
+    );
+    ok("write", node("dirty_threshold")?.writeat(0, b"32"))?;
+    ensure!(
+        value("sync_policy")? == "write_back:32:10\n",
+        "the threshold didn't change the policy"
+    );
+    ok("write", node("sync_policy")?.writeat(0, b"write_through"))?;
+    ensure!(
+        fs.dirty_blocks() == 0,
+        "leaving write-back left dirty blocks"
+    );
+
+    let dev = fs.dev().to_string();
+    drop((file, fs));
+    ensure_err!(
+        tunefs
+            .root_dir()
+            .lookup("ext4")
+            .and_then(|x| x.lookup(&dev)),
+        VfsError::FileNotFound
+    );
+    ensure_err!(dir.lookup("readahead_blocks"), VfsError::FileNotFound);
+    Ok(())
+}
+
+/// An ext4 mount of at most 4 open files: the opens and the creates past
+/// the cap fail with ENOSPC, a create before the file is created, the
+/// lookups and the directories don't count, closing files lets the opens
+/// through again, the stats show the counts, and a dentry holding a
+/// closed file is pruned while the one of an open file is pinned.
+pub fn ext4_open_file_cap() -> Result<(), String> {
+    use crate::dentry::{prune_dentries_under, DentryNode};
+    use crate::testing::MockDisk;
+
+    let disk = Arc::new(MockDisk::from_image(crash_image(0)?, 512));
+    let builder = crate::Ext4FileSystem::builder_from_device(disk);
+    let fs = ok("mount", builder.max_open_files(4).mount())?;
+    let root = fs.root();
+    let dir = ok("mkdir", root.mkdir("d"))?;
+    let mut files = Vec::new();
+    for i in 0..4 {
+        files.push(ok("touch", root.touch(&format!("f{}", i)))?);
+    }
+    ensure!(fs.open_files() == 4, "{} files are open", fs.open_files());
+
+    let err = root.touch("f4").err();
+    ensure!(
+        err.is_some_and(|x| Errno::from(x) == Errno::ENOSPC),
+        "a create past the cap returned {:?}",
+        err
+    );
+    ensure_err!(root.lookup("f4"), VfsError::FileNotFound);
+    ensure_err!(root.open("f0", OpenFlags::NONE), VfsError::StorageFull);
+    ensure_err!(root.open("f4", OpenFlags::O_CREAT), VfsError::StorageFull);
+    let looked_up = ok("lookup", root.lookup("f0"))?;
+    let opened_dir = ok("open", root.open("d", OpenFlags::NONE))?;
+    ensure!(fs.open_files() == 4, "the lookup or the directory counted");
+    drop((looked_up, opened_dir, dir));
+
+    let stats = crate::stats::render();
+    let prefix = format!("ext4.dev{}.", fs.dev());
+    for line in ["open_files: 4", "max_open_files: 4"] {
+        ensure!(
+            stats.lines().any(|x| x == format!("{}{}", prefix, line)),
+            "the stats miss {}{}",
+            prefix,
+            line
+        );
+    }
+
+    files.truncate(2);
+    ensure!(
+        fs.open_files() == 2,
+        "closing left {} open",
+        fs.open_files()
+    );
+    files.push(ok("touch", root.touch("f4"))?);
+    files.push(ok("open", root.open("f0", OpenFlags::NONE))?);
+    ensure_err!(root.open("f1", OpenFlags::NONE), VfsError::StorageFull);
+    ok("max", fs.set_max_open_files(None))?;
+    files.push(ok("open", root.open("f1", OpenFlags::NONE))?);
+    ensure!(fs.open_files() == 5, "{} files are open", fs.open_files());
+    files.clear();
+    ensure!(fs.open_files() == 0, "{} files are open", fs.open_files());
+
+    // the dentries keep the nodes of the closed files until pruned.
+    let tree = Arc::new(DentryNode::new(
+        String::from("/"),
+        root.clone(),
+        alloc::sync::Weak::new(),
+    ));
+    let held = tree.clone().open("f2", OpenFlags::NONE).ok_or("open f2")?;
+    tree.clone().open("f3", OpenFlags::NONE).ok_or("open f3")?;
+    ensure!(
+        fs.open_files() == 2,
+        "the dentries hold {} files",
+        fs.open_files()
+    );
+    let pruned = prune_dentries_under(&tree, usize::MAX);
+    ensure!(pruned == 1, "{} dentries were pruned", pruned);
+    ensure!(fs.open_files() == 1, "the pruned dentry kept its file");
+    drop(held);
+    ensure!(
+        prune_dentries_under(&tree, usize::MAX) == 1,
+        "f2 wasn't pruned"
+    );
+    ensure!(fs.open_files() == 0, "{} files are open", fs.open_files());
+    Ok(())
+}
+
+/// Format a 32M ext4 over garbage with the lazy inode tables and fill
+/// directories of the root which Orlov puts in the untouched groups: the
+/// groups of the new inodes are initialized on the disk, a group nothing
+/// went to keeps its uninit flags and the image checks clean.
+pub fn ext4_lazy_itable_init() -> Result<(), String> {
+    use alloc::collections::BTreeSet;
+
+    use crate::blockdev::{BlockDevice, CachedDevice, Partition, RamDevice};
+    use crate::ext4_layout::{
+        GroupDesc, SuperBlockInfo, BG_BLOCK_UNINIT, BG_INODE_UNINIT, SUPERBLOCK_OFFSET,
+    };
+    use crate::ext4_mkfs::format;
+
+    const SIZE: usize = 32 << 20;
+    let options = crate::ext4_mkfs::Options {
+        block_size: 1024,
+        lazy_itable_init: true,
+        uuid: *b"ext4-lazy-itable",
+        ..Default::default()
+    };
+    ensure_err!(
+        format(
+            SIZE as u64,
+            &crate::ext4_mkfs::Options {
+                metadata_csum: false,
+                ..options
+            },
+            |_, _| Ok(())
+        ),
+        VfsError::InvalidInput
+    );
+    // the tables of the untouched groups keep the garbage of the disk.
+    let ram = Arc::new(RamDevice::from_image(vec![0xA5; EXT4_RAM_START + SIZE]));
+    ok(
+        "format",
+        format(SIZE as u64, &options, |block, data| {
+            ram.write_offset(EXT4_RAM_START + block as usize * options.block_size, data)
+        }),
+    )?;
+    let cached = Arc::new(CachedDevice::new(ram.clone(), 64));
+    let device = Arc::new(Partition::new(cached, EXT4_RAM_START, SIZE));
+    let sb = SuperBlockInfo::parse(&device.read_offset(SUPERBLOCK_OFFSET)[..1024]);
+    ensure!(sb.groups_count() == 4, "{} groups", sb.groups_count());
+    let descs = || -> Vec<(GroupDesc, u32)> {
+        (0..sb.groups_count())
+            .map(|group| {
+                let desc = device.read_offset(sb.group_desc_offset(group));
+                let unused = u16::from_le_bytes([desc[0x1C], desc[0x1D]]) as u32;
+                (GroupDesc::parse(&sb, &desc[..sb.group_desc_size()]), unused)
+            })
+            .collect()
+    };
+    for (group, (desc, unused)) in descs().into_iter().enumerate().skip(1) {
+        ensure!(
+            desc.flags & (BG_INODE_UNINIT | BG_BLOCK_UNINIT) == BG_INODE_UNINIT | BG_BLOCK_UNINIT
+                && unused == sb.inodes_per_group,
+            "the group {} is formatted with the flags {:#x} and {} unused inodes",
+            group,
+            desc.flags,
+            unused
+        );
+    }
+
+    let mut groups = BTreeSet::new();
+    {
+        let fs = ok(
+            "mount",
+            crate::Ext4FileSystem::new_from_device(device.clone()),
+        )?;
+        for d in 0..2 {
+            let dir = ok("mkdir", fs.root().mkdir(&format!("dir-{}", d)))?;
+            let mut stat = Stat::default();
+            ok("stat", dir.stat(&mut stat))?;
+            groups.insert(sb.inode_group(stat.ino as u32).0);
+            for f in 0..20 {
+                let file = ok("touch", dir.touch(&format!("file-{}", f)))?;
+                ok("stat", file.stat(&mut stat))?;
+                ensure!(
+                    stat.size == 0 && stat.nlink == 1,
+                    "dir-{}/file-{} is created with the size {} and {} links",
+                    d,
+                    f,
+                    stat.size,
+                    stat.nlink
+                );
+                ok("write", file.writeat(0, &vec![(d * 20 + f) as u8; 5000]))?;
+            }
+        }
+        ok("sync", FileSystem::flush(fs.as_ref()))?;
+    }
+    ensure!(
+        !groups.contains(&0),
+        "the directories of the root are in the groups {:?}",
+        groups
+    );
+
+    let fs = ok(
+        "remount",
+        crate::Ext4FileSystem::new_from_device(device.clone()),
+    )?;
+    let report = fs.check();
+    ensure!(
+        report.problems.is_empty(),
+        "the image has problems: {:?}",
+        report.problems
+    );
+    for d in 0..2 {
+        let dir = ok(
+            "open",
+            fs.root().open(&format!("dir-{}", d), OpenFlags::NONE),
+        )?;
+        for f in 0..20 {
+            let file = ok("open", dir.open(&format!("file-{}", f), OpenFlags::NONE))?;
+            let mut data = vec![0; 6000];
+            let n = ok("readat", file.readat(0, &mut data))?;
+            ensure!(
+                data[..n] == vec![(d * 20 + f) as u8; 5000],
+                "dir-{}/file-{} reads back {} bytes",
+                d,
+                f,
+                n
+            );
+        }
+    }
+    let descs = descs();
+    for group in groups.iter().copied() {
+        let (desc, unused) = descs[group];
+        ensure!(
+            desc.flags & BG_INODE_UNINIT == 0 && unused < sb.inodes_per_group - 20,
+            "the group {} of new inodes has the flags {:#x} and {} unused inodes",
+            group,
+            desc.flags,
+            unused
+        );
+    }
+    ensure!(
+        (1..sb.groups_count())
+            .any(|x| !groups.contains(&x) && descs[x].0.flags & BG_INODE_UNINIT != 0),
+        "no group is left uninit: {:?}",
+        descs.iter().map(|x| x.0.flags).collect::<Vec<_>>()
+    );
+    Ok(())
+}
+
+/// The readahead and the writeback of background_work, on a MockDisk
+/// taking a tick per request: a cold read with a readahead of 64 pages
+/// takes the ticks of the missed page and of the 8 read with it, where
+/// the unthrottled readahead takes those of the 64, the rest is read by
+/// background_work and the next reads take no tick. With a Sync request
+/// queued on the scheduler of the device, a read defers its whole
+/// readahead, background_work yields, then reads a chunk and stops, and
+/// the dirty bitmaps wait, a read doesn't write them. Once the request is
+/// done, background_work reads and writes the rest.
+pub fn ext4_background_work() -> Result<(), String> {
+    use crate::iosched::{self, IoPriority, IoScheduler};
+    use crate::testing::{MockDisk, MockOp};
+
+    const PAGES: usize = 192;
+    let disk = Arc::new(MockDisk::from_image(crash_image(0)?, 512));
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::new_from_device(disk.clone()),
+    )?;
+    let data: Vec<u8> = (0..PAGES * 4096).map(|x| (x / 4096) as u8).collect();
+    let file = ok("touch", fs.root().touch("file"))?;
+    ok("write", file.writeat(0, &data))?;
+    ok("flush", FileSystem::flush(fs.as_ref()))?;
+    drop((file, fs));
+    let image = disk.image();
+
+    // the ticks of a read of the page, a new mount has a cold cache.
+    let ticks =
+        |disk: &MockDisk, file: &Arc<dyn INodeInterface>, page: usize| -> Result<u64, String> {
+            let start = disk.elapsed();
+            let mut buf = [0; 4096];
+            ok("read", file.readat(page * 4096, &mut buf))?;
+            ensure!(buf == [page as u8; 4096], "page {} reads back wrong", page);
+            Ok(disk.elapsed() - start)
+        };
+    let mount = |inline: usize| -> Result<_, String> {
+        let disk = Arc::new(MockDisk::from_image(image.clone(), 512).with_delay(1));
+        let fs = ok(
+            "mount",
+            crate::Ext4FileSystem::builder_from_device(disk.clone())
+                .readahead_blocks(64)
+                .readahead_inline(inline)
+                .mount(),
+        )?;
+        let file = ok("lookup", fs.root().lookup("file"))?;
+        Ok((disk, fs, file))
+    };
+    let (disk, _fs, file) = mount(256)?;
+    let unthrottled = ticks(&disk, &file, 0)?;
+    let (disk, fs, file) = mount(8)?;
+    let throttled = ticks(&disk, &file, 0)?;
+    ensure!(
+        unthrottled >= 65 && throttled + 56 <= unthrottled && throttled <= 9 + 4,
+        "a cold read took {} ticks, {} unthrottled",
+        throttled,
+        unthrottled
+    );
+    let read = ok("background_work", fs.background_work(usize::MAX))?;
+    let stats = fs.background_stats();
+    ensure!(
+        read == 56 && stats.deferred_pages == 56 && stats.prefetched_pages == 56,
+        "background_work read {} pages: {:?}",
+        read,
+        stats
+    );
+    for page in 1..65 {
+        let ticks = ticks(&disk, &file, page)?;
+        ensure!(ticks == 0, "page {} read ahead took {} ticks", page, ticks);
+    }
+
+    // a queued Sync request is the pressure.
+    let scheduler = IoScheduler::new("background.test", disk.clone(), 8);
+    iosched::set_scheduler(fs.dev(), scheduler.clone());
+    let ticket = scheduler.submit_read(0, 4096, IoPriority::Sync);
+    ensure!(iosched::pressure(fs.dev()) == 1, "no pressure");
+    let busy = ticks(&disk, &file, 100)?;
+    ensure!(
+        busy <= 1 + 4 && fs.background_stats().deferred_pages == 56 + 64,
+        "a read under pressure took {} ticks: {:?}",
+        busy,
+        fs.background_stats()
+    );
+    ok("write", fs.root().touch("dirty").map(drop))?;
+    ensure!(fs.dirty_blocks() > 0, "no dirty bitmap");
+    disk.clear_log();
+    ticks(&disk, &file, 170)?;
+    ensure!(
+        !disk.log().iter().any(|x| x.op == MockOp::Write),
+        "a read wrote {:?}",
+        disk.log()
+    );
+    let mut yields = 0;
+    let read = loop {
+        match ok("background_work", fs.background_work(usize::MAX))? {
+            0 if yields < 100 => yields += 1,
+            read => break read,
+        }
+    };
+    ensure!(
+        yields > 0 && yields == fs.background_stats().yields && read > 0 && read < 64,
+        "background_work read {} pages under pressure after {} yields",
+        read,
+        yields
+    );
+    ensure!(
+        fs.dirty_blocks() > 0,
+        "the bitmaps were written under pressure"
+    );
+
+    scheduler.wait(ticket);
+    let done = ok("background_work", fs.background_work(usize::MAX))?;
+    ensure!(
+        done >= 64 + 21 - read && fs.dirty_blocks() == 0 && !fs.background_pending(),
+        "background_work did {} without pressure, {} dirty blocks",
+        done,
+        fs.dirty_blocks()
+    );
+    for page in 101..165 {
+        let ticks = ticks(&disk, &file, page)?;
+        ensure!(ticks == 0, "page {} read ahead took {} ticks", page, ticks);
+    }
+    Ok(())
+}
+
+/// Change the disk of a read-only ext4 mount under it, like another
+/// writer: the cached data is read until revalidate finds the mount count
+/// of the superblock changed, or revalidate_inode the change time of the
+/// inode. A superblock of another geometry isn't revalidated, nor a
+/// writable mount.
+pub fn ext4_revalidate() -> Result<(), String> {
+    use core::mem::offset_of;
+
+    use crate::disk_layout::{self as disk, Inode};
+    use crate::ext4_csum::{set_inode_csum, set_superblock_csum};
+    use crate::ext4_layout::{GroupDesc, SuperBlockInfo, SUPERBLOCK_OFFSET};
+    use crate::revalidate::{revalidate, revalidate_inode};
+    use crate::testing::MockDisk;
+
+    const MNT_COUNT: usize = offset_of!(disk::SuperBlock, mnt_count);
+    const BLOCKS_COUNT: usize = offset_of!(disk::SuperBlock, blocks_count_lo);
+    const CTIME: usize = offset_of!(Inode, ctime);
+
+    let old = b"revalidate: the old data";
+    let new = b"revalidate: the new data";
+    let newer = b"revalidate: the 3rd data";
+    // without a journal, the data is in one block of the disk.
+    let disk = Arc::new(MockDisk::from_image(crash_image(0)?, 512));
+    let fs = ok(
+        "mount rw",
+        crate::Ext4FileSystem::new_from_device(disk.clone()),
+    )?;
+    let file = ok("touch", fs.root().touch("file"))?;
+    ok("writeat", file.writeat(0, old))?;
+    ok("flush", file.flush())?;
+    let writable: Arc<dyn FileSystem> = fs.clone();
+    ensure!(
+        !ok("revalidate rw", revalidate(&writable))?
+            && !ok("revalidate_inode rw", revalidate_inode(&file))?,
+        "a writable mount revalidated"
+    );
+    drop((file, writable, fs));
+
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::builder_from_device(disk.clone())
+            .read_only(true)
+            .mount(),
+    )?;
+    let ro: Arc<dyn FileSystem> = fs.clone();
+    let node = ok("lookup", fs.root().lookup("file"))?;
+    ensure!(read_all(&node, 64)? == old, "the mount reads another file");
+    let image = disk.image();
+    let hits: Vec<usize> = (0..image.len() - old.len())
+        .filter(|x| image[*x..*x + old.len()] == old[..])
+        .collect();
+    let [data] = hits[..] else {
+        return Err(format!("the data is at {:?}", hits));
+    };
+    disk.write_at(data, new);
+    ensure!(
+        !ok("revalidate", revalidate(&ro))? && read_all(&node, 64)? == old,
+        "the disk changed without a new mount count"
+    );
+
+    // another writer of the disk mounted it.
+    let mut sb = vec![0; 1024];
+    disk.read_at(SUPERBLOCK_OFFSET, &mut sb);
+    let info = SuperBlockInfo::parse(&sb);
+    let mount = |sb: &mut Vec<u8>, count: u16| {
+        sb[MNT_COUNT..MNT_COUNT + 2].copy_from_slice(&count.to_le_bytes());
+        if info.has_metadata_csum() {
+            set_superblock_csum(sb);
+        }
+        disk.write_at(SUPERBLOCK_OFFSET, sb);
+    };
+    let count = u16::from_le_bytes([sb[MNT_COUNT], sb[MNT_COUNT + 1]]);
+    let good = sb.clone();
+    sb[BLOCKS_COUNT] ^= 1;
+    mount(&mut sb, count + 1);
+    ensure_err!(revalidate(&ro), VfsError::InvalidData);
+    ensure!(
+        read_all(&node, 64)? == old,
+        "a failed revalidate dropped the data"
+    );
+    let mut sb = good;
+    mount(&mut sb, count + 2);
+    ensure!(
+        ok("revalidate", revalidate(&ro))?,
+        "the new mount count wasn't found"
+    );
+    let read = read_all(&node, 64)?;
+    ensure!(read == new, "the revalidated file reads {:?}", read);
+    ensure!(
+        !ok("revalidate", revalidate(&ro))?,
+        "the disk changed again"
+    );
+
+    // the inode changed, its cached data is dropped.
+    let mut stat = Stat::default();
+    ok("stat", node.stat(&mut stat))?;
+    let ino = stat.ino as u32;
+    let (group, index) = info.inode_group(ino);
+    let mut desc = vec![0; info.group_desc_size()];
+    disk.read_at(info.group_desc_offset(group), &mut desc);
+    let table = GroupDesc::parse(&info, &desc).inode_table as usize;
+    let offset = table * info.block_size() + index * info.inode_size as usize;
+    let mut inode = vec![0; info.inode_size as usize];
+    disk.read_at(offset, &mut inode);
+    disk.write_at(data, newer);
+    ensure!(
+        !ok("revalidate_inode", revalidate_inode(&node))? && read_all(&node, 64)? == new,
+        "the inode changed without a new change time"
+    );
+    let ctime = u32::from_le_bytes(inode[CTIME..CTIME + 4].try_into().unwrap());
+    inode[CTIME..CTIME + 4].copy_from_slice(&(ctime + 1).to_le_bytes());
+    if info.has_metadata_csum() {
+        set_inode_csum(&info, ino, &mut inode);
+    }
+    disk.write_at(offset, &inode);
+    ensure!(
+        ok("revalidate_inode", revalidate_inode(&node))?,
+        "the new change time wasn't found"
+    );
+    let read = read_all(&node, 64)?;
+    ensure!(read == newer, "the revalidated inode reads {:?}", read);
+    Ok(())
+}
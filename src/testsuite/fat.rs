@@ -0,0 +1,330 @@
+// The cases of fat32.
+
+use super::*;
+
+/// The pathconf limits of FAT: one link for each file, NAME_MAX of the
+/// long names, no symlinks and 32 bits of file size. device_id is a host
+/// device with a FAT32 volume.
+pub fn fat_pathconf(device_id: usize) -> Result<(), String> {
+    use crate::fatfs_shim::Fat32FileSystem;
+    use crate::pathconf::{fpathconf, PathconfName, NO_LIMIT};
+
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(ok(
+        "mount",
+        Fat32FileSystem::new(device_id),
+    )? as Arc<dyn FileSystem>));
+    let root = fs.root_dir();
+    let file = ok("touch", root.touch("pathconf"))?;
+    for node in [&root, &file] {
+        let expected = [
+            (PathconfName::LinkMax, 1),
+            (PathconfName::NameMax, 255),
+            (PathconfName::FilesizeBits, 32),
+            (PathconfName::TwoSymlinks, 0),
+            (PathconfName::SymlinkMax, NO_LIMIT),
+        ];
+        for (name, expected) in expected {
+            let actual = ok("fpathconf", fpathconf(node, name))?;
+            ensure!(actual == expected, "{:?} of FAT is {}", name, actual);
+        }
+    }
+    ok("remove", root.remove("pathconf"))?;
+    Ok(())
+}
+
+/// The capabilities of FAT are those it shows. device_id is a host device
+/// with a FAT32 volume.
+pub fn fat_capabilities(device_id: usize) -> Result<(), String> {
+    use crate::fatfs_shim::Fat32FileSystem;
+
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(ok(
+        "mount",
+        Fat32FileSystem::new(device_id),
+    )? as Arc<dyn FileSystem>));
+    check_capabilities(fs)
+}
+
+/// Check the FSInfo of a FAT32 volume: a sector with a bad signature is
+/// repaired by the mount and the free clusters are counted, and the count
+/// written back after creating and removing a file is the one of a scan
+/// of the FAT. device_id is a host device with a FAT32 volume, it's
+/// modified.
+pub fn fat_fs_info(device_id: usize) -> Result<(), String> {
+    use crate::fat_layout::{count_free_clusters, Bpb, FsInfo, FS_INFO_SIZE};
+    use crate::fatfs_shim::{read_bytes, write_bytes, Fat32FileSystem};
+
+    // the boot sector, FSInfo and the free clusters by a scan of the FAT.
+    let volume = || -> Result<(Bpb, usize, Option<FsInfo>, u32), String> {
+        let boot = ok("read boot sector", read_bytes(device_id, 0, 512))?;
+        let bpb = Bpb::parse(&boot).ok_or("not a FAT32 volume")?;
+        let offset = bpb.fs_info_offset().ok_or("no FSInfo sector")?;
+        let sector = ok("read FSInfo", read_bytes(device_id, offset, FS_INFO_SIZE))?;
+        let fat_len = bpb.fat_size as usize * bpb.bytes_per_sector as usize;
+        let fat = ok("read FAT", read_bytes(device_id, bpb.fat_offset(), fat_len))?;
+        let free = count_free_clusters(&fat, bpb.clusters());
+        Ok((bpb, offset, FsInfo::parse(&sector, bpb.clusters()), free))
+    };
+
+    let (_, offset, _, _) = volume()?;
+    ok("corrupt FSInfo", write_bytes(device_id, offset, &[0; 4]))?;
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(ok(
+        "mount",
+        Fat32FileSystem::new(device_id),
+    )? as Arc<dyn FileSystem>));
+    let magic = ok("magic", crate::statfs::magic(fs.as_ref()))?;
+    ensure!(
+        magic == crate::statfs::MSDOS_SUPER_MAGIC,
+        "the f_type is {:#x}",
+        magic
+    );
+    let root = fs.root_dir();
+    let bfree = || -> Result<_, String> {
+        let mut statfs = StatFS::default();
+        ok("statfs", root.statfs(&mut statfs))?;
+        Ok(statfs.bfree)
+    };
+    let (_, _, info, free) = volume()?;
+    ensure!(info.is_some(), "the mount didn't repair FSInfo");
+    ensure!(
+        bfree()? == free as _,
+        "statfs {} free clusters after the scan, the FAT has {}",
+        bfree()?,
+        free
+    );
+
+    // the count kept while mounted is written back by flush.
+    let written = |what: &str| -> CaseResult {
+        ok("flush", fs.flush())?;
+        let (_, _, info, free) = volume()?;
+        let info = info.ok_or(format!("bad FSInfo after {}", what))?;
+        ensure!(
+            info.free_count == free,
+            "FSInfo {} free clusters after {}, the FAT has {}",
+            info.free_count,
+            what,
+            free
+        );
+        ensure!(
+            bfree()? == free as _,
+            "statfs {} free clusters after {}, the FAT has {}",
+            bfree()?,
+            what,
+            free
+        );
+        Ok(())
+    };
+    let file = ok("touch", root.touch("fsinfo.bin"))?;
+    ok("writeat", file.writeat(0, &vec![0x5a; 0x40000]))?;
+    drop(file);
+    written("the create")?;
+    ok("remove", root.remove("fsinfo.bin"))?;
+    written("the remove")?;
+    Ok(())
+}
+
+/// Check the times and the read-only attribute of FAT32: the times set by
+/// utimes read back within the granularity of FAT after opening the file
+/// again, and a file with the read-only attribute loses its write bits
+/// and fails the writes. device_id is a host device with a FAT32 volume,
+/// it's modified.
+pub fn fat_times(device_id: usize) -> Result<(), String> {
+    use crate::fat_layout::{Bpb, FAT_MIN_TIME};
+    use crate::fatfs_shim::{read_bytes, write_bytes, Fat32FileSystem};
+    use vfscore::{TimeSpec, UTIME_OMIT};
+
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(ok(
+        "mount",
+        Fat32FileSystem::new(device_id),
+    )? as Arc<dyn FileSystem>));
+    let root = fs.root_dir();
+    let stat_of = |name: &str| -> Result<Stat, String> {
+        let file = ok("open", root.open(name, OpenFlags::NONE))?;
+        let mut stat = Stat::default();
+        ok("stat", file.stat(&mut stat))?;
+        Ok(stat)
+    };
+
+    let file = ok("touch", root.touch("fattime.bin"))?;
+    let (atime, mtime) = (1_700_000_123, 1_700_000_001);
+    let mut times = [
+        TimeSpec {
+            sec: atime as _,
+            nsec: 0,
+        },
+        TimeSpec {
+            sec: mtime as _,
+            nsec: 0,
+        },
+    ];
+    ok("utimes", file.utimes(&mut times))?;
+    drop(file);
+    let stat = stat_of("fattime.bin")?;
+    // the modification time has 2 seconds, the access time is a date.
+    ensure!(
+        stat.mtime.sec as i64 == mtime - 1,
+        "mtime {} after utimes {}",
+        stat.mtime.sec,
+        mtime
+    );
+    ensure!(
+        stat.atime.sec as i64 == atime - atime % 86400,
+        "atime {} after utimes {}",
+        stat.atime.sec,
+        atime
+    );
+    // the times before 1980 are clamped, UTIME_OMIT keeps the access time.
+    let file = ok("open", root.open("fattime.bin", OpenFlags::NONE))?;
+    let mut times = [
+        TimeSpec {
+            sec: 0,
+            nsec: UTIME_OMIT,
+        },
+        TimeSpec { sec: 0, nsec: 0 },
+    ];
+    ok("utimes", file.utimes(&mut times))?;
+    drop(file);
+    let clamped = stat_of("fattime.bin")?;
+    ensure!(
+        clamped.mtime.sec as i64 == FAT_MIN_TIME && clamped.atime.sec == stat.atime.sec,
+        "mtime {} atime {} after utimes of 1970",
+        clamped.mtime.sec,
+        clamped.atime.sec
+    );
+    ok("remove", root.remove("fattime.bin"))?;
+
+    // set the read-only attribute of the short entry in the root
+    // directory, rust-fatfs can't.
+    let file = ok("touch", root.touch("fatro.bin"))?;
+    ok("writeat", file.writeat(0, b"fat"))?;
+    drop(file);
+    let boot = ok("read boot sector", read_bytes(device_id, 0, 512))?;
+    let bpb = Bpb::parse(&boot).ok_or("not a FAT32 volume")?;
+    let offset = bpb.cluster_offset(bpb.root_cluster);
+    let dir = ok(
+        "read root",
+        read_bytes(device_id, offset, bpb.cluster_size()),
+    )?;
+    let entry = dir
+        .chunks_exact(32)
+        .position(|x| &x[..11] == b"FATRO   BIN")
+        .ok_or("no short entry of fatro.bin in the first root cluster")?;
+    let attr = offset + entry * 32 + 11;
+    let read_only = dir[entry * 32 + 11] | 0x01;
+    ok("set read-only", write_bytes(device_id, attr, &[read_only]))?;
+
+    let stat = stat_of("fatro.bin")?;
+    ensure!(
+        stat.mode.bits() & 0o777 == 0o555,
+        "mode {:o} of a read-only file",
+        stat.mode.bits()
+    );
+    let file = ok("open", root.open("fatro.bin", OpenFlags::NONE))?;
+    ensure_err!(file.writeat(0, b"x"), VfsError::InvalidInput);
+    ensure_err!(file.truncate(0), VfsError::InvalidInput);
+    let mut buf = [0; 3];
+    ensure!(
+        matches!(file.readat(0, &mut buf), Ok(3)) && &buf == b"fat",
+        "read {:?} of a read-only file",
+        buf
+    );
+    drop(file);
+    ok("remove", root.remove("fatro.bin"))?;
+    Ok(())
+}
+
+/// The dirty bit of FAT32 in the image: a mount and the reads leave a
+/// clean volume clean, the first write marks the state byte and FAT[1] of
+/// every FAT dirty and a sync marks them clean again. A volume left dirty
+/// stays dirty through the syncs, and read_only_if_dirty mounts it
+/// read-only. device_id is a host device with a FAT32 volume, it's
+/// modified.
+pub fn fat_dirty_bit(device_id: usize) -> Result<(), String> {
+    use crate::fat_layout::{self, Bpb, BS_STATE};
+    use crate::fatfs_shim::{read_bytes, write_bytes, Fat32FileSystem, FatOptions};
+
+    let boot = ok("read boot sector", read_bytes(device_id, 0, 512))?;
+    let bpb = Bpb::parse(&boot).ok_or("not a FAT32 volume")?;
+    let le_u32 = |x: Vec<u8>| u32::from_le_bytes(x[..4].try_into().unwrap());
+    // the marks of the volume, the FATs must agree.
+    let dirty = || -> Result<bool, String> {
+        let state = ok("read state", read_bytes(device_id, BS_STATE, 1))?[0];
+        let mut marks = Vec::new();
+        for offset in bpb.fat1_offsets() {
+            let fat1 = le_u32(ok("read FAT[1]", read_bytes(device_id, offset, 4))?);
+            marks.push(fat_layout::is_dirty(state, fat1));
+        }
+        ensure!(
+            marks.iter().all(|x| *x == marks[0]),
+            "the FATs disagree: {:?}",
+            marks
+        );
+        Ok(marks[0])
+    };
+    let mark = |clean: bool| -> CaseResult {
+        let state = ok("read state", read_bytes(device_id, BS_STATE, 1))?[0];
+        for offset in bpb.fat1_offsets() {
+            let fat1 = le_u32(ok("read FAT[1]", read_bytes(device_id, offset, 4))?);
+            let (_, fat1) = fat_layout::set_clean(state, fat1, clean);
+            ok(
+                "write FAT[1]",
+                write_bytes(device_id, offset, &fat1.to_le_bytes()),
+            )?;
+        }
+        let (state, _) = fat_layout::set_clean(state, 0, clean);
+        ok("write state", write_bytes(device_id, BS_STATE, &[state]))
+    };
+    let mount = |options: FatOptions| -> Result<&'static Arc<Fat32FileSystem>, String> {
+        let fs = ok("mount", Fat32FileSystem::with_options(device_id, options))?;
+        Ok(Box::leak(Box::new(fs)))
+    };
+
+    mark(true)?;
+    let fs = mount(FatOptions::default())?;
+    ensure!(!fs.was_dirty(), "a clean volume mounted dirty");
+    let root = fs.root_dir();
+    ok("read_dir", root.read_dir())?;
+    ok("flush", fs.flush())?;
+    ensure!(!dirty()?, "the mount and a read marked the volume dirty");
+    let file = ok("touch", root.touch("dirty.bin"))?;
+    ensure!(dirty()?, "the create left the volume clean");
+    ok("flush", fs.flush())?;
+    ensure!(!dirty()?, "the sync left the volume dirty");
+    ok("writeat", file.writeat(0, &[0x5a; 0x1000]))?;
+    ensure!(dirty()?, "the write left the volume clean");
+    ok("writeat", file.writeat(0, &[]))?;
+    ok("flush", fs.flush())?;
+    ensure!(!dirty()?, "the sync left the volume dirty");
+    drop(file);
+
+    // a volume another OS left dirty.
+    mark(false)?;
+    let options = FatOptions {
+        read_only_if_dirty: true,
+        ..FatOptions::default()
+    };
+    let fs = mount(options)?;
+    ensure!(
+        fs.was_dirty() && fs.is_read_only(),
+        "the dirty volume mounted writable"
+    );
+    let root = fs.root_dir();
+    ensure_err!(root.touch("ro.bin"), VfsError::NotSupported);
+    ensure_err!(root.remove("dirty.bin"), VfsError::NotSupported);
+    let file = ok("open", root.open("dirty.bin", OpenFlags::NONE))?;
+    ensure!(read_all(&file, 1)? == [0x5a], "read the dirty volume");
+    ensure_err!(file.writeat(0, b"x"), VfsError::NotSupported);
+    ok("flush", fs.flush())?;
+    ensure!(dirty()?, "a read-only sync marked the volume clean");
+
+    let fs = mount(FatOptions::default())?;
+    ensure!(
+        fs.was_dirty() && !fs.is_read_only(),
+        "the dirty volume mounted read-only without the option"
+    );
+    let root = fs.root_dir();
+    ok("remove", root.remove("dirty.bin"))?;
+    ok("flush", fs.flush())?;
+    ensure!(dirty()?, "a sync cleaned a volume dirty at the mount");
+    mark(true)?;
+    Ok(())
+}
@@ -0,0 +1,445 @@
+// The cases of the file operations: the reads and the writes, the stats
+// and the lookups, the links and the removes, the trace events, the
+// offsets shared by the handles, the errnos and fallocate.
+
+use super::*;
+
+pub(super) fn read_write(dir: &File) -> CaseResult {
+    let file = ok("touch", dir.touch("file"))?;
+    ensure!(
+        ok("writeat", file.writeat(0, b"hello"))? == 5,
+        "short write"
+    );
+    ensure!(read_all(&file, 16)? == b"hello", "read back the write");
+
+    // the empty and EOF accesses return 0.
+    let mut buf = [0u8; 4];
+    ensure!(ok("readat", file.readat(5, &mut buf))? == 0, "read at EOF");
+    ensure!(
+        ok("readat", file.readat(6, &mut buf))? == 0,
+        "read beyond EOF"
+    );
+    ensure!(ok("readat", file.readat(0, &mut []))? == 0, "empty read");
+    ensure!(ok("writeat", file.writeat(0, &[]))? == 0, "empty write");
+    ensure!(size(&file)? == 5, "the empty write changed the size");
+
+    ok("writeat", file.writeat(1, b"EL"))?;
+    ensure!(read_all(&file, 16)? == b"hELlo", "overwrite");
+    // writing beyond the end leaves zeros in the gap.
+    ok("writeat", file.writeat(8, b"x"))?;
+    ensure!(size(&file)? == 9, "extend by a write");
+    ensure!(
+        read_all(&file, 16)? == b"hELlo\0\0\0x",
+        "the gap isn't zero"
+    );
+
+    let again = ok("open", dir.open("file", OpenFlags::NONE))?;
+    ensure!(read_all(&again, 16)? == b"hELlo\0\0\0x", "another open");
+
+    let large: Vec<u8> = (0..0x3000).map(|x| x as u8).collect();
+    let file = ok("touch", dir.touch("large"))?;
+    ok("writeat", file.writeat(0, &large))?;
+    ensure!(read_all(&file, 0x4000)? == large, "read across the blocks");
+    Ok(())
+}
+
+pub(super) fn truncate(dir: &File) -> CaseResult {
+    let file = ok("touch", dir.touch("file"))?;
+    ok("writeat", file.writeat(0, b"0123456789"))?;
+    ok("truncate", file.truncate(10))?;
+    ensure!(size(&file)? == 10, "truncate to the size");
+    ok("truncate", file.truncate(4))?;
+    ensure!(size(&file)? == 4, "shrink");
+    ensure!(read_all(&file, 16)? == b"0123", "read after shrink");
+    ok("truncate", file.truncate(8))?;
+    ensure!(read_all(&file, 16)? == b"0123\0\0\0\0", "grow isn't zero");
+    ok("truncate", file.truncate(0))?;
+    ensure!(read_all(&file, 16)?.is_empty(), "truncate to 0");
+    Ok(())
+}
+
+pub(super) fn stat(dir: &File) -> CaseResult {
+    let file = ok("touch", dir.touch("file"))?;
+    ok("writeat", file.writeat(0, &[1; 100]))?;
+    let mut stat = Stat::default();
+    ok("stat", file.stat(&mut stat))?;
+    ensure!(stat.size == 100, "stat size {}", stat.size);
+    ensure!(stat.nlink >= 1, "stat nlink {}", stat.nlink);
+    ensure!(
+        stat.mode.contains(StatMode::FILE),
+        "file mode {:?}",
+        stat.mode
+    );
+
+    let mut stat = Stat::default();
+    ok("stat", dir.stat(&mut stat))?;
+    ensure!(
+        stat.mode.contains(StatMode::DIR),
+        "dir mode {:?}",
+        stat.mode
+    );
+
+    let meta = ok("metadata", file.metadata())?;
+    ensure!(
+        matches!(meta.file_type, FileType::File),
+        "metadata file type"
+    );
+    let mut statfs = StatFS::default();
+    ok("statfs", file.statfs(&mut statfs))?;
+    ensure!(statfs.bfree <= statfs.blocks, "statfs free blocks");
+    Ok(())
+}
+
+pub(super) fn lookup(dir: &File) -> CaseResult {
+    ensure_err!(dir.lookup(""), VfsError::FileNotFound);
+    ensure_err!(dir.lookup("missing"), VfsError::FileNotFound);
+    ensure_err!(dir.open("missing", OpenFlags::NONE), VfsError::FileNotFound);
+
+    ok("touch", dir.touch("file"))?;
+    ok("mkdir", dir.mkdir("sub"))?;
+    let file = ok("lookup", dir.lookup("file"))?;
+    ensure!(
+        matches!(ok("metadata", file.metadata())?.file_type, FileType::File),
+        "lookup file type"
+    );
+    let sub = ok("lookup", dir.lookup("sub"))?;
+    ensure!(
+        matches!(
+            ok("metadata", sub.metadata())?.file_type,
+            FileType::Directory
+        ),
+        "lookup directory type"
+    );
+
+    ok("open", dir.open("created", OpenFlags::O_CREAT))?;
+    ok("lookup", dir.lookup("created"))?;
+    Ok(())
+}
+
+pub(super) fn errors(dir: &File) -> CaseResult {
+    ok("mkdir", dir.mkdir("sub"))?;
+    ensure_err!(dir.mkdir("sub"), VfsError::AlreadyExists);
+    ensure_err!(dir.touch("a/b"), VfsError::InvalidInput);
+    let long = "x".repeat(NAME_MAX + 1);
+    // the node flattens the ENAMETOOLONG of the syscall.
+    ensure_err!(dir.touch(&long), VfsError::InvalidInput);
+    ensure_errno!(crate::ops::check_name(&long), Errno::ENAMETOOLONG);
+    ensure_err!(dir.open(&long, OpenFlags::O_CREAT), VfsError::InvalidInput);
+    let file = ok("touch", dir.touch("file"))?;
+    ensure_err!(file.lookup("x"), VfsError::NotDir);
+    Ok(())
+}
+
+pub(super) fn remove(dir: &File) -> CaseResult {
+    ok("touch", dir.touch("gone"))?;
+    ok("remove", dir.remove("gone"))?;
+    ensure_err!(dir.lookup("gone"), VfsError::FileNotFound);
+    ensure!(
+        !names(dir)?.iter().any(|x| x == "gone"),
+        "the removed file is listed"
+    );
+    ensure_err!(dir.remove("gone"), VfsError::FileNotFound);
+    Ok(())
+}
+
+pub(super) fn rmdir(dir: &File) -> CaseResult {
+    let sub = ok("mkdir", dir.mkdir("sub"))?;
+    ok("touch", sub.touch("file"))?;
+    ensure_err!(dir.rmdir("sub"), VfsError::DirectoryNotEmpty);
+    ok("remove", sub.remove("file"))?;
+    ok("rmdir", dir.rmdir("sub"))?;
+    ensure_err!(dir.lookup("sub"), VfsError::FileNotFound);
+    Ok(())
+}
+
+pub(super) fn unlink_open(dir: &File) -> CaseResult {
+    let mut before = StatFS::default();
+    ok("statfs", dir.statfs(&mut before))?;
+    let data = vec![7u8; 0x10000];
+    let file = ok("touch", dir.touch("tmp"))?;
+    ok("writeat", file.writeat(0, &data))?;
+    ok("remove", dir.remove("tmp"))?;
+
+    ensure!(
+        !names(dir)?.iter().any(|x| x == "tmp"),
+        "the unlinked file is listed"
+    );
+    ensure!(read_all(&file, 0x20000)? == data, "read the unlinked file");
+    ok("writeat", file.writeat(0x10000, b"more"))?;
+    ensure!(size(&file)? == 0x10004, "write the unlinked file");
+    let mut stat = Stat::default();
+    ok("stat", file.stat(&mut stat))?;
+    ensure!(
+        stat.nlink == 0,
+        "the unlinked file has nlink {}",
+        stat.nlink
+    );
+
+    // the last close releases the blocks.
+    drop(file);
+    let mut after = StatFS::default();
+    ok("statfs", dir.statfs(&mut after))?;
+    ensure!(
+        after.bfree >= before.bfree,
+        "free blocks {} before, {} after",
+        before.bfree,
+        after.bfree
+    );
+    Ok(())
+}
+
+pub(super) fn symlink(dir: &File) -> CaseResult {
+    ok("touch", dir.touch("target"))?;
+    ok("sym_link", dir.sym_link("link", "target"))?;
+    let link = ok("lookup", dir.lookup("link"))?;
+    ensure!(
+        ok("resolve_link", link.resolve_link())? == "target",
+        "the link target"
+    );
+    ensure_err!(dir.sym_link("link", "target"), VfsError::AlreadyExists);
+    Ok(())
+}
+
+pub(super) fn hard_link(dir: &File) -> CaseResult {
+    let file = ok("touch", dir.touch("a"))?;
+    ok("writeat", file.writeat(0, b"shared"))?;
+    ok("link", dir.link("b", file.clone()))?;
+    let other = ok("lookup", dir.lookup("b"))?;
+    ensure!(read_all(&other, 16)? == b"shared", "read through the link");
+    let mut stat = Stat::default();
+    ok("stat", file.stat(&mut stat))?;
+    ensure!(stat.nlink == 2, "nlink {} after link", stat.nlink);
+    Ok(())
+}
+
+/// The events of the handles seen by the trace case, the count or None
+/// for an error.
+static EVENTS: Mutex<Vec<(TraceOp, Option<usize>)>> = Mutex::new(Vec::new());
+
+fn collect(event: &TraceEvent) {
+    if event.source == "handle" {
+        EVENTS.lock().push((event.op, event.result.ok()));
+    }
+}
+
+/// The handles emit an event per operation, the failed ones included.
+/// The hook is global, the operations of other threads on handles would
+/// be seen too, so the suite must run alone.
+pub(super) fn trace(dir: &File) -> CaseResult {
+    let file = ok("touch", dir.touch("file"))?;
+    let rw = FileHandle::new(file.clone(), OpenFlags::O_RDWR);
+    let wo = FileHandle::new(file, OpenFlags::O_WRONLY);
+    let previous = tracing::hook();
+    EVENTS.lock().clear();
+    tracing::set_hook(collect);
+    let r = trace_ops(&rw, &wo);
+    match previous {
+        Some(hook) => tracing::set_hook(hook),
+        None => tracing::clear_hook(),
+    }
+    r?;
+    let events = core::mem::take(&mut *EVENTS.lock());
+    let expected = [
+        (TraceOp::Write, Some(5)),
+        (TraceOp::Flush, Some(0)),
+        (TraceOp::Read, Some(5)),
+        (TraceOp::Read, None),
+    ];
+    ensure!(events == expected, "events {:?}", events);
+    Ok(())
+}
+
+fn trace_ops(rw: &FileHandle, wo: &FileHandle) -> CaseResult {
+    let mut buf = [0u8; 8];
+    ok("writeat", rw.writeat(0, b"trace"))?;
+    ok("flush", rw.flush())?;
+    ok("readat", rw.readat(0, &mut buf))?;
+    ensure_err!(wo.readat(0, &mut buf), VfsError::InvalidInput);
+    ensure_errno!(wo.mode().check_read(), Errno::EBADF);
+    Ok(())
+}
+
+/// A dup shares the offset and the status flags of the handle, another
+/// open of the file has its own.
+pub(super) fn shared_offset(dir: &File) -> CaseResult {
+    use vfscore::SeekFrom;
+
+    let file = ok("touch", dir.touch("file"))?;
+    ok("writeat", file.writeat(0, b"0123456789"))?;
+    let handle = FileHandle::new(file.clone(), OpenFlags::O_RDWR);
+    let dup = handle.dup();
+    let other = FileHandle::new(file, OpenFlags::O_RDWR);
+    let mut buf = [0u8; 4];
+    ok("read", handle.read(&mut buf))?;
+    ensure!(&buf == b"0123", "the first read got {:?}", buf);
+    ok("read", dup.read(&mut buf))?;
+    ensure!(
+        &buf == b"4567",
+        "the dup read {:?} after the first read",
+        buf
+    );
+    ok("read", other.read(&mut buf))?;
+    ensure!(&buf == b"0123", "the other open read {:?}", buf);
+    ensure!(
+        ok("seek", dup.seek(SeekFrom::CURRENT(-2)))? == 6,
+        "the dup moved the offset elsewhere"
+    );
+    ok("read", handle.read(&mut buf[..2]))?;
+    ensure!(&buf[..2] == b"67", "the read after the seek got {:?}", buf);
+    ensure_err!(handle.seek(SeekFrom::END(-11)), VfsError::InvalidInput);
+
+    // O_APPEND set through the dup appends through the handle.
+    dup.set_status_flags(OpenFlags::O_APPEND | OpenFlags::O_RDONLY);
+    ensure!(
+        handle.status_flags() == OpenFlags::O_APPEND,
+        "the flags of the handle are {:?}",
+        handle.status_flags()
+    );
+    ok("seek", handle.seek(SeekFrom::SET(0)))?;
+    ok("write", handle.write(b"ab"))?;
+    ensure!(
+        ok("seek", dup.seek(SeekFrom::CURRENT(0)))? == 12,
+        "the append left the offset elsewhere"
+    );
+    // the other open writes at its own offset.
+    ok("write", other.write(b"x"))?;
+    let other: File = other;
+    let data = read_all(&other, 16)?;
+    ensure!(
+        data == b"0123x56789ab",
+        "the file is {:?} after the writes",
+        data
+    );
+
+    // the appends of two opens of their own lookups don't overwrite each
+    // other.
+    let flags = OpenFlags::O_WRONLY | OpenFlags::O_APPEND;
+    let first = FileHandle::new(ok("lookup", dir.lookup("file"))?, flags);
+    let second = FileHandle::new(ok("lookup", dir.lookup("file"))?, flags);
+    for _ in 0..2 {
+        ok("write", first.write(b"c"))?;
+        ok("write", second.write(b"d"))?;
+    }
+    drop((first, second));
+    let data = read_all(&other, 32)?;
+    ensure!(
+        data == b"0123x56789abcdcd",
+        "the file is {:?} after the appends",
+        data
+    );
+    Ok(())
+}
+
+/// The errors of the operations convert to the negative errno of Linux,
+/// and the context shows in the messages.
+pub(super) fn errno(dir: &File) -> CaseResult {
+    ok("mkdir", dir.mkdir("sub"))?;
+    let file = ok("touch", dir.touch("file"))?;
+    let cases = [
+        (dir.lookup("missing").err(), -2),
+        (dir.mkdir("sub").err(), -17),
+        (file.lookup("x").err(), -20),
+        (dir.touch("a/b").err(), -22),
+    ];
+    for (err, expected) in cases {
+        let errno = isize::from(Errno::from(err.ok_or("the operation succeeded")?));
+        ensure!(errno == expected, "errno {}, not {}", errno, expected);
+    }
+    ensure!(syscall_result(Ok(5)) == 5, "the count isn't returned");
+    ensure!(
+        syscall_result(Err(VfsError::NotSupported)) == -95,
+        "NotSupported isn't EOPNOTSUPP"
+    );
+    let err = dir
+        .lookup("missing")
+        .err()
+        .ok_or("lookup of missing succeeded")?
+        .with_op("open")
+        .with_path("/etc/passwd");
+    let debug = format!("{:?}", err);
+    ensure!(
+        debug.contains("FileNotFound") && debug.contains("open") && debug.contains("/etc/passwd"),
+        "Debug {}",
+        debug
+    );
+    let display = format!("{}", err);
+    ensure!(
+        display == "open /etc/passwd: No such file or directory",
+        "Display {}",
+        display
+    );
+    ensure!(
+        matches!(VfsError::from(err), VfsError::FileNotFound),
+        "the context lost the error"
+    );
+    Ok(())
+}
+
+/// A punched range reads zeros and the file keeps its size, natively or
+/// emulated, and a punch beyond the end doesn't grow it. A filesystem
+/// whose fallback refuses it fails with NotSupported.
+pub(super) fn punch_hole(dir: &File) -> CaseResult {
+    use crate::fallocate::{self, SpaceOp, Support};
+
+    let file = ok("touch", dir.touch("file"))?;
+    let data: Vec<u8> = (0..3 * 4096 + 100).map(|x| x as u8 | 1).collect();
+    ok("writeat", file.writeat(0, &data))?;
+    if fallocate::support(&file, SpaceOp::PunchHole) == Support::Unsupported {
+        ensure_err!(
+            fallocate::punch_hole(&file, 100, 5000),
+            VfsError::NotSupported
+        );
+        return Ok(());
+    }
+    ok("punch_hole", fallocate::punch_hole(&file, 100, 5000))?;
+    ok(
+        "punch_hole",
+        fallocate::punch_hole(&file, data.len() - 10, 4096),
+    )?;
+    let mut want = data.clone();
+    want[100..5100].fill(0);
+    want[data.len() - 10..].fill(0);
+    ensure!(
+        size(&file)? == data.len(),
+        "the punch changed the size to {}",
+        size(&file)?
+    );
+    ensure!(
+        read_all(&file, data.len() + 1)? == want,
+        "the holes don't read zeros"
+    );
+    ensure_err!(fallocate::punch_hole(&file, 0, 0), VfsError::InvalidInput);
+    Ok(())
+}
+
+/// An allocation beyond the end grows the file with zeros, one inside it
+/// keeps the data and a keep_size one keeps the size, or fails with
+/// NotSupported. A filesystem whose fallback refuses them fails with
+/// NotSupported.
+pub(super) fn allocate(dir: &File) -> CaseResult {
+    use crate::fallocate::{self, SpaceOp, Support};
+
+    let file = ok("touch", dir.touch("file"))?;
+    ok("writeat", file.writeat(0, b"head"))?;
+    if fallocate::support(&file, SpaceOp::Allocate) == Support::Unsupported {
+        ensure_err!(
+            fallocate::allocate(&file, 0, 10000, false),
+            VfsError::NotSupported
+        );
+        return Ok(());
+    }
+    ok("allocate", fallocate::allocate(&file, 0, 10000, false))?;
+    ok("allocate", fallocate::allocate(&file, 100, 50, false))?;
+    let mut want = vec![0; 10000];
+    want[..4].copy_from_slice(b"head");
+    ensure!(
+        read_all(&file, 10001)? == want,
+        "the allocated file doesn't read its data and zeros"
+    );
+    match fallocate::allocate(&file, 10000, 4096, true) {
+        Ok(()) | Err(VfsError::NotSupported) => {}
+        Err(err) => return Err(format!("allocate with keep_size: {:?}", err)),
+    }
+    ensure!(size(&file)? == 10000, "the size is {}", size(&file)?);
+    Ok(())
+}
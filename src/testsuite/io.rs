@@ -0,0 +1,319 @@
+// The cases of the streams of io.rs and of the tar archives.
+
+use super::*;
+
+/// A file counting the readat and writeat calls reaching it.
+struct CountingFile {
+    file: File,
+    reads: AtomicUsize,
+    writes: AtomicUsize,
+}
+
+impl INodeInterface for CountingFile {
+    fn readat(&self, offset: usize, buffer: &mut [u8]) -> VfsResult<usize> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.file.readat(offset, buffer)
+    }
+
+    fn writeat(&self, offset: usize, buffer: &[u8]) -> VfsResult<usize> {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.file.writeat(offset, buffer)
+    }
+
+    fn stat(&self, stat: &mut Stat) -> VfsResult<()> {
+        self.file.stat(stat)
+    }
+}
+
+/// Stream 8 MiB through an InodeWriter and an InodeReader of 4 KiB
+/// buffers in tmpfs, by pieces of odd sizes: the file has the bytes, and
+/// the node sees a call for each buffer, not one for each piece. A seek
+/// back into the buffer doesn't read the file again, and the lines of a
+/// file read back by read_line across the buffers.
+pub fn io_streams() -> Result<(), String> {
+    use crate::io::{BufRead, InodeReader, InodeWriter, Read, Seek, SeekFrom, Write};
+    use crate::tmpfs::TmpFs;
+
+    const LEN: usize = 8 << 20;
+    const BUF: usize = 4096;
+    let fs = Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>));
+    let data: Vec<u8> = (0..LEN).map(|x| (x % 251) as u8).collect();
+    let counting = Arc::new(CountingFile {
+        file: ok("touch", fs.root_dir().touch("stream"))?,
+        reads: AtomicUsize::new(0),
+        writes: AtomicUsize::new(0),
+    });
+    let node: File = counting.clone();
+    // the pieces go from a byte to 1000 bytes, below the buffer.
+    let pieces = |len: usize| {
+        let mut chunk = 1;
+        let mut pos = 0;
+        core::iter::from_fn(move || {
+            (pos < len).then(|| {
+                let range = pos..(pos + chunk).min(len);
+                pos = range.end;
+                chunk = chunk * 7 % 1000 + 1;
+                range
+            })
+        })
+    };
+
+    let mut writer = InodeWriter::with_capacity(BUF, node.clone());
+    for range in pieces(LEN) {
+        ok("write_all", writer.write_all(&data[range]))?;
+    }
+    drop(writer);
+    let writes = counting.writes.load(Ordering::Relaxed);
+    ensure!(
+        writes == LEN / BUF,
+        "{} writeat calls for {} buffers",
+        writes,
+        LEN / BUF
+    );
+    ensure!(
+        read_all(&counting.file, LEN + 1)? == data,
+        "the file isn't the stream"
+    );
+
+    let mut reader = InodeReader::with_capacity(BUF, node.clone());
+    let mut read = vec![0; LEN];
+    for range in pieces(LEN) {
+        ok("read_exact", reader.read_exact(&mut read[range]))?;
+    }
+    ensure!(read == data, "the stream isn't the file");
+    ensure_err!(reader.read_exact(&mut [0]), VfsError::UnexpectedEof);
+    // a last read finds the end of the file.
+    let reads = counting.reads.load(Ordering::Relaxed);
+    ensure!(
+        reads == LEN / BUF + 1,
+        "{} readat calls for {} buffers",
+        reads,
+        LEN / BUF
+    );
+    ok("seek", reader.seek(SeekFrom::Start(0)))?;
+    ok("seek", reader.seek(SeekFrom::Current(BUF as i64 - 1)))?;
+    let mut byte = [0];
+    ok("read", reader.read(&mut byte))?;
+    ensure!(byte[0] == data[BUF - 1], "read {} after the seeks", byte[0]);
+    ok("seek", reader.seek(SeekFrom::Current(-1)))?;
+    ok("read", reader.read(&mut byte))?;
+    ensure!(
+        byte[0] == data[BUF - 1],
+        "read {} after the seek back",
+        byte[0]
+    );
+    ensure!(
+        counting.reads.load(Ordering::Relaxed) == reads + 1,
+        "the seek in the buffer read again"
+    );
+    let end = ok("seek", reader.seek(SeekFrom::End(-1)))?;
+    ensure!(end == LEN as u64 - 1, "the end is at {}", end);
+
+    // lines across the buffers, like the files of proc.
+    let lines: Vec<String> = (0..1000)
+        .map(|x| format!("line{}: {}", x, "x".repeat(x % 37)))
+        .collect();
+    let file = ok("touch", fs.root_dir().touch("lines"))?;
+    let mut writer = InodeWriter::with_capacity(64, file.clone());
+    for line in lines.iter() {
+        ok("write_all", writer.write_all(line.as_bytes()))?;
+        ok("write_all", writer.write_all(b"\n"))?;
+    }
+    ok("flush", writer.flush())?;
+    let mut reader = InodeReader::with_capacity(64, file);
+    for expected in lines.iter() {
+        let mut line = String::new();
+        ok("read_line", reader.read_line(&mut line))?;
+        ensure!(
+            line.strip_suffix('\n') == Some(expected.as_str()),
+            "read the line {:?} for {:?}",
+            line,
+            expected
+        );
+    }
+    let mut line = String::new();
+    ensure!(
+        ok("read_line", reader.read_line(&mut line))? == 0,
+        "read {:?} past the lines",
+        line
+    );
+    Ok(())
+}
+
+/// A tree of tmpfs written by write_tar and made in ext4 by extract_tar
+/// has the same manifest, with a path too long for the fields of ustar.
+/// The archive of ext4 keeps the owners and the mtimes, through a
+/// stream of io.rs in tmpfs. The hard links and the symbol links of an archive
+/// are copied in ext4, which has none, and a dangling symbol link is
+/// skipped. A path with ".." and a bad checksum fail the extraction.
+#[cfg(root_fs = "ext4_rs")]
+pub fn ext4_tar_round_trip() -> Result<(), String> {
+    use crate::archive::{extract_tar, write_tar, BLOCK_SIZE};
+    use crate::golden::{diff, pattern, Kind, Manifest};
+    use crate::io::{InodeReader, InodeWriter, Write};
+    use crate::tmpfs::TmpFs;
+    use vfscore::TimeSpec;
+
+    let manifest = |dir: &File| -> Result<Manifest, String> {
+        let mut manifest = Manifest::from_dir(dir)?;
+        // the directories of tmpfs don't count their subdirectories.
+        for entry in manifest.0.iter_mut().filter(|x| x.kind == Kind::Dir) {
+            entry.nlink = 0;
+        }
+        Ok(manifest)
+    };
+    let same = |a: &File, b: &File| -> Result<(), String> {
+        match diff(&manifest(a)?, &manifest(b)?) {
+            Some(divergence) => Err(format!("the extracted tree differs: {}", divergence)),
+            None => Ok(()),
+        }
+    };
+    // the type flags of the headers of the archive, past their data.
+    let typeflags = |tar: &[u8]| {
+        let mut flags = Vec::new();
+        let mut at = 0;
+        while at + BLOCK_SIZE <= tar.len() && tar[at..at + BLOCK_SIZE] != [0; BLOCK_SIZE] {
+            let size = core::str::from_utf8(&tar[at + 124..at + 135]).unwrap_or("0");
+            let size = usize::from_str_radix(size, 8).unwrap_or(0);
+            flags.push(tar[at + 156]);
+            at += BLOCK_SIZE + size.next_multiple_of(BLOCK_SIZE);
+        }
+        flags
+    };
+    // the checksum of a changed header.
+    let seal = |header: &mut [u8]| {
+        header[148..156].fill(b' ');
+        let sum: u32 = header[..BLOCK_SIZE].iter().map(|&x| x as u32).sum();
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    };
+
+    let tmp = Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>)).root_dir();
+    ok("touch", tmp.touch("empty"))?;
+    let file = ok("touch", tmp.touch("odd"))?;
+    ok("write", file.writeat(0, &pattern(1, 0, 5000)))?;
+    let deep = ok("mkdir", tmp.mkdir("a"))?;
+    let deep = ok("mkdir", deep.mkdir("b"))?;
+    let file = ok("touch", deep.touch("large"))?;
+    ok("write", file.writeat(0, &pattern(2, 0, 200 << 10)))?;
+    let long = ok("mkdir", tmp.mkdir(&"d".repeat(120)))?;
+    let file = ok("touch", long.touch(&"f".repeat(90)))?;
+    ok("write", file.writeat(0, &pattern(3, 0, 100)))?;
+
+    let mut tar = Vec::new();
+    ok("write_tar", write_tar(tmp.clone(), &mut tar))?;
+    ensure!(
+        tar.len() % BLOCK_SIZE == 0 && tar.ends_with(&[0; 2 * BLOCK_SIZE]),
+        "the archive of {} bytes isn't terminated",
+        tar.len()
+    );
+    ensure!(
+        &tar[257..265] == b"ustar\x0000",
+        "the first header isn't ustar"
+    );
+    let fs = ram_ext4(16 << 20, *b"ext4-tar-trip!!!")?;
+    ok("extract_tar", extract_tar(&mut &tar[..], fs.root()))?;
+    same(&tmp, &fs.root())?;
+
+    // the attributes of ext4, archived in a file.
+    let root = fs.root();
+    let file = ok("lookup", root.lookup("odd"))?;
+    ok("chown", crate::owner::chown(&file, 1234, 56))?;
+    let mut times = [TimeSpec {
+        sec: 1_000_000,
+        nsec: 0,
+    }; 2];
+    ok("utimes", file.utimes(&mut times))?;
+    let stored = ok("touch", tmp.touch("backup.tar"))?;
+    let mut writer = InodeWriter::new(stored.clone());
+    ok("write_tar", write_tar(root.clone(), &mut writer))?;
+    ok("flush", writer.flush())?;
+    let tar = read_all(&stored, 1 << 20)?;
+    ensure!(
+        !typeflags(&tar).contains(&b'1'),
+        "the archive of ext4 has a hard link: {:?}",
+        typeflags(&tar)
+    );
+    let copy = ram_ext4(16 << 20, *b"ext4-tar-copy!!!")?;
+    ok(
+        "extract_tar",
+        extract_tar(&mut InodeReader::new(stored), copy.root()),
+    )?;
+    same(&root, &copy.root())?;
+    let odd = ok("lookup", copy.root().lookup("odd"))?;
+    let mut stat = Stat::default();
+    ok("stat", odd.stat(&mut stat))?;
+    ensure!(
+        stat.uid == 1234 && stat.gid == 56 && stat.mtime.sec == 1_000_000,
+        "the extracted file has owner {}:{} mtime {:?}",
+        stat.uid,
+        stat.gid,
+        stat.mtime
+    );
+
+    // the links of an archive of a filesystem which has them: the headers
+    // after the one of src link to it, sym to nowhere dangles.
+    let one = Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>)).root_dir();
+    let file = ok("touch", one.touch("src"))?;
+    ok("write", file.writeat(0, &pattern(4, 0, 3000)))?;
+    let mut links = Vec::new();
+    ok("write_tar", write_tar(one, &mut links))?;
+    let mut header = links[..BLOCK_SIZE].to_vec();
+    links.truncate(links.len() - 2 * BLOCK_SIZE);
+    for (name, typeflag, target) in [
+        ("hard", b'1', "src"),
+        ("sym", b'2', "./src"),
+        ("dangling", b'2', "nowhere"),
+    ] {
+        header[..100].fill(0);
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..136].copy_from_slice(b"00000000000\0");
+        header[156] = typeflag;
+        header[157..257].fill(0);
+        header[157..157 + target.len()].copy_from_slice(target.as_bytes());
+        seal(&mut header);
+        links.extend_from_slice(&header);
+    }
+    links.extend_from_slice(&[0; 2 * BLOCK_SIZE]);
+    ok("extract_tar", extract_tar(&mut &links[..], copy.root()))?;
+    for name in ["hard", "sym"] {
+        let file = ok("lookup", copy.root().lookup(name))?;
+        ok("stat", file.stat(&mut stat))?;
+        ensure!(
+            stat.nlink == 1 && read_all(&file, 1 << 20)? == pattern(4, 0, 3000),
+            "the link {} isn't a copy of src, nlink {}",
+            name,
+            stat.nlink
+        );
+    }
+    ensure_err!(copy.root().lookup("dangling"), VfsError::FileNotFound);
+
+    // a path escaping the directory and a corrupted header.
+    let one = Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>)).root_dir();
+    ok("touch", one.touch("evil"))?;
+    let mut header = Vec::new();
+    ok("write_tar", write_tar(one, &mut header))?;
+    header.truncate(BLOCK_SIZE);
+    header[..100].fill(0);
+    header[..8].copy_from_slice(b"../evil\0");
+    seal(&mut header);
+    header.extend_from_slice(&[0; 2 * BLOCK_SIZE]);
+    ensure_err!(
+        extract_tar(&mut &header[..], copy.root()),
+        VfsError::InvalidInput
+    );
+    header[148] ^= 1;
+    ensure_err!(
+        extract_tar(&mut &header[..], copy.root()),
+        VfsError::InvalidData
+    );
+    for fs in [&fs, &copy] {
+        ok("flush", FileSystem::flush(fs.as_ref()))?;
+        let report = fs.check();
+        ensure!(
+            report.problems.is_empty(),
+            "the image has problems: {:?}",
+            report.problems
+        );
+    }
+    Ok(())
+}
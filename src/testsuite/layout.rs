@@ -0,0 +1,327 @@
+// The fixture images of disk_layout.rs.
+
+use super::*;
+
+/// The structures of disk_layout.rs over the blocks of e2fsprogs, from the
+/// images of golden/layout.py: the fields read what mkfs.ext4 and debugfs
+/// wrote, the structures built field by field are the same bytes, and the
+/// checksums of ext4_csum.rs and the journal of ext4_journal.rs, which
+/// read and write through them, verify and seal the blocks like e2fsprogs.
+pub fn disk_layout_fixtures() -> Result<(), String> {
+    use crate::disk_layout::{
+        self as disk, CommitHeader, DirEntry, DxCountLimit, DxEntry, ExtentHeader, ExtentIdx,
+        ExtentLeaf, GroupDesc32, GroupDesc64, Inode, InodeExtra, JournalBlockTag, JournalBlockTag3,
+        Layout, XattrEntry, XattrHeader, XattrIbodyHeader, DIRENT_TAIL_FT, GOOD_OLD_INODE_SIZE,
+    };
+    use crate::ext4_csum::{
+        has_dirent_tail, inode_seed, set_dx_block_csum, set_xattr_block_csum, verify_dir_block,
+        verify_extent_block, verify_group_desc, verify_inode, verify_superblock,
+    };
+    use crate::ext4_journal::{build_log, scan_journal, JournalSuperBlock};
+    use crate::ext4_layout::{walk_extents, DirentIter, InodeInfo, SuperBlockInfo};
+
+    let raw_sb = include_bytes!("../golden/layout/superblock.bin");
+    let sb = disk::SuperBlock::ref_from(raw_sb).ok_or("short superblock")?;
+    ensure!(
+        (
+            sb.magic.get(),
+            sb.blocks_count_lo.get(),
+            sb.inodes_count.get()
+        ) == (0xEF53, 8192, 2048),
+        "superblock {:?}",
+        sb
+    );
+    ensure!(
+        (
+            sb.feature_incompat.get(),
+            sb.feature_ro_compat.get(),
+            sb.desc_size.get()
+        ) == (0x2C6, 0x46B, 64),
+        "features {:?}",
+        sb
+    );
+    ensure!(
+        (sb.free_blocks_count_lo.get(), sb.free_inodes_count.get()) == (6434, 1914),
+        "free counts {:?}",
+        sb
+    );
+    ensure!(
+        sb.volume_name[..7] == *b"layout\0" && sb.uuid == *b"LayoutFixtures!!",
+        "names {:?}",
+        sb
+    );
+    ensure!(
+        (
+            sb.mkfs_time.get(),
+            sb.first_ino.get(),
+            sb.journal_inum.get()
+        ) == (1700000000, 11, 8),
+        "times {:?}",
+        sb
+    );
+    ensure!(sb.as_bytes() == raw_sb, "superblock bytes");
+    ensure!(verify_superblock(raw_sb), "superblock checksum");
+    let info = SuperBlockInfo::parse(raw_sb);
+
+    let raw_gd = include_bytes!("../golden/layout/group_desc64.bin");
+    let gd = GroupDesc64::ref_from(raw_gd).ok_or("short group desc")?;
+    let mut built = GroupDesc64::new_zeroed();
+    built.lo.block_bitmap_lo.set(66);
+    built.lo.inode_bitmap_lo.set(82);
+    built.lo.inode_table_lo.set(98);
+    built.lo.free_blocks_count_lo.set(6434);
+    built.lo.free_inodes_count_lo.set(1914);
+    built.lo.used_dirs_count_lo.set(3);
+    built.lo.block_bitmap_csum_lo.set(0x2C2E);
+    built.lo.inode_bitmap_csum_lo.set(0x3550);
+    built.lo.itable_unused_lo.set(1914);
+    built.lo.checksum.set(0xD44D);
+    built.block_bitmap_csum_hi.set(0xF134);
+    built.inode_bitmap_csum_hi.set(0xCC67);
+    ensure!(built.as_bytes() == raw_gd, "group desc {:?}", gd);
+    ensure!(verify_group_desc(&info, 0, raw_gd), "group desc checksum");
+    let raw_gd32 = include_bytes!("../golden/layout/group_desc32.bin");
+    let gd32 = GroupDesc32::ref_from(raw_gd32).ok_or("short group desc")?;
+    ensure!(
+        (
+            gd32.block_bitmap_lo.get(),
+            gd32.inode_table_lo.get(),
+            gd32.free_blocks_count_lo.get()
+        ) == (34, 66, 6467),
+        "group desc32 {:?}",
+        gd32
+    );
+
+    // frag, an index in i_block to a leaf of 12 extents.
+    let raw_frag = include_bytes!("../golden/layout/inode.bin");
+    let frag = Inode::ref_from(raw_frag).ok_or("short inode")?;
+    let extra = InodeExtra::ref_from(&raw_frag[GOOD_OLD_INODE_SIZE..]).ok_or("short extra")?;
+    ensure!(
+        (
+            frag.mode.get(),
+            frag.size_lo.get(),
+            frag.flags.get(),
+            frag.links_count.get()
+        ) == (0o100644, 46080, 0x80000, 1),
+        "frag {:?}",
+        frag
+    );
+    ensure!(
+        (extra.extra_isize.get(), extra.crtime.get()) == (32, 1700000000),
+        "frag extra {:?}",
+        extra
+    );
+    let header = ExtentHeader::ref_from(&frag.block).ok_or("short i_block")?;
+    let index = ExtentIdx::ref_from(&frag.block[12..]).ok_or("short i_block")?;
+    ensure!(
+        (
+            header.magic.get(),
+            header.entries.get(),
+            header.depth.get(),
+            index.leaf()
+        ) == (0xF30A, 1, 1, 1746),
+        "frag i_block {:?} {:?}",
+        header,
+        index
+    );
+    ensure!(
+        frag.file_acl_lo.get() == 1757,
+        "frag xattr block {:?}",
+        frag
+    );
+    ensure!(verify_inode(&info, 133, raw_frag), "frag checksum");
+
+    let leaf = include_bytes!("../golden/layout/extent_block.bin");
+    ensure!(
+        verify_extent_block(inode_seed(&info, 133, frag.generation.get()), leaf),
+        "extent block checksum"
+    );
+    let mut first = ExtentLeaf::new_zeroed();
+    first.len.set(1);
+    first.start_lo.set(1741);
+    ensure!(
+        leaf[12..24] == *first.as_bytes(),
+        "first extent {:?}",
+        &leaf[12..24]
+    );
+    let inode = InodeInfo::parse(raw_frag, info.inode_size as usize);
+    let extents = ok(
+        "walk_extents",
+        walk_extents(&inode.i_block, |block| match block {
+            1746 => Ok(leaf.to_vec()),
+            _ => Err(VfsError::InvalidData),
+        }),
+    )?;
+    ensure!(
+        extents.len() == 12
+            && extents
+                .iter()
+                .enumerate()
+                .all(|(i, x)| x.logical == i as u32 * 4 && x.len == 1),
+        "extents {:?}",
+        extents
+    );
+
+    // small, its attribute in the inode.
+    let raw_small = include_bytes!("../golden/layout/inode_xattr.bin");
+    ensure!(verify_inode(&info, 134, raw_small), "small checksum");
+    let ibody = GOOD_OLD_INODE_SIZE + 32;
+    let magic = XattrIbodyHeader::ref_from(&raw_small[ibody..]).ok_or("short ibody")?;
+    let entry = XattrEntry::ref_from(&raw_small[ibody + 4..]).ok_or("short ibody")?;
+    ensure!(
+        (
+            magic.magic.get(),
+            entry.name_len,
+            entry.name_index,
+            entry.value_size.get()
+        ) == (0xEA020000, 3, 1, 5),
+        "small xattr {:?} {:?}",
+        magic,
+        entry
+    );
+
+    let raw_xattr = include_bytes!("../golden/layout/xattr_block.bin");
+    let xattr = XattrHeader::ref_from(raw_xattr).ok_or("short xattr block")?;
+    let entry = XattrEntry::ref_from(&raw_xattr[32..]).ok_or("short xattr block")?;
+    ensure!(
+        (
+            xattr.magic.get(),
+            xattr.refcount.get(),
+            entry.value_size.get(),
+            entry.hash.get()
+        ) == (0xEA020000, 1, 700, 210674545),
+        "xattr block {:?} {:?}",
+        xattr,
+        entry
+    );
+    let mut resealed = raw_xattr.to_vec();
+    set_xattr_block_csum(&info, 1757, &mut resealed);
+    ensure!(resealed == raw_xattr, "xattr block checksum");
+
+    let root = include_bytes!("../golden/layout/dir_block.bin");
+    let names: Vec<_> = DirentIter::new(root)
+        .map(|x| x.map(|x| (x.name.to_vec(), x.inode)))
+        .collect::<VfsResult<_>>()
+        .map_err(|err| format!("root entries: {:?}", err))?;
+    let expect: [(&[u8], u32); 6] = [
+        (b".", 2),
+        (b"..", 2),
+        (b"lost+found", 11),
+        (b"dir", 12),
+        (b"frag", 133),
+        (b"small", 134),
+    ];
+    ensure!(
+        names
+            .iter()
+            .map(|(name, ino)| (name.as_slice(), *ino))
+            .eq(expect),
+        "root entries {:?}",
+        names
+    );
+    let tail = DirEntry::ref_from(&root[root.len() - 12..]).ok_or("short dir block")?;
+    ensure!(
+        has_dirent_tail(root) && tail.file_type == DIRENT_TAIL_FT,
+        "root tail {:?}",
+        tail
+    );
+    ensure!(
+        verify_dir_block(inode_seed(&info, 2, 0), root),
+        "root checksum"
+    );
+
+    // the htree root of dir, its entries from 0x20.
+    let dx = include_bytes!("../golden/layout/dx_block.bin");
+    let counts = DxCountLimit::ref_from(&dx[0x20..]).ok_or("short dx block")?;
+    let hashes: Vec<_> = (1..counts.count.get() as usize)
+        .filter_map(|i| DxEntry::ref_from(&dx[0x20 + i * 8..]))
+        .map(|x| (x.hash.get(), x.block.get()))
+        .collect();
+    ensure!(
+        (counts.limit.get(), counts.count.get()) == (123, 4)
+            && hashes == [(1532509154, 2), (3030526674, 3), (4282380444, 4)],
+        "dx entries {:?} {:?}",
+        counts,
+        hashes
+    );
+    let mut resealed = dx.to_vec();
+    resealed[dx.len() - 4..].fill(0);
+    set_dx_block_csum(inode_seed(&info, 12, 0), &mut resealed, 0x20);
+    ensure!(resealed == dx, "dx checksum");
+
+    // the transaction of debugfs, 300 and 301 written, 302 revoked.
+    let raw_jsb = include_bytes!("../golden/layout/journal_sb.bin");
+    let jsb = ok("journal superblock", JournalSuperBlock::parse(raw_jsb))?;
+    ensure!(
+        (
+            jsb.blocksize,
+            jsb.maxlen,
+            jsb.first,
+            jsb.sequence,
+            jsb.start
+        ) == (1024, 1024, 1, 1, 1)
+            && jsb.uuid == *b"LayoutFixtures!!",
+        "journal superblock {:?}",
+        jsb
+    );
+    let mut resealed = raw_jsb.to_vec();
+    jsb.set_start(&mut resealed, 1, 1);
+    ensure!(resealed == raw_jsb, "journal superblock checksum");
+    let descriptor = include_bytes!("../golden/layout/journal_descriptor.bin");
+    let log = [
+        raw_jsb.to_vec(),
+        descriptor.to_vec(),
+        vec![0; 1024],
+        vec![0; 1024],
+        include_bytes!("../golden/layout/journal_revoke.bin").to_vec(),
+        include_bytes!("../golden/layout/journal_commit.bin").to_vec(),
+    ];
+    let commit = CommitHeader::ref_from(&log[5]).ok_or("short commit")?;
+    ensure!(
+        (
+            commit.header.magic.get(),
+            commit.header.blocktype.get(),
+            commit.header.sequence.get()
+        ) == (0xC03B3998, 2, 1),
+        "commit {:?}",
+        commit
+    );
+    let replay = ok(
+        "scan_journal",
+        scan_journal(&jsb, |lblock| {
+            Ok(log
+                .get(lblock as usize)
+                .cloned()
+                .unwrap_or_else(|| vec![0; 1024]))
+        }),
+    )?;
+    let homes: Vec<_> = replay.blocks.iter().map(|x| x.home).collect();
+    ensure!(
+        replay.transactions == 1 && homes == [300, 301],
+        "replay {:?}",
+        homes
+    );
+    // debugfs leaves the uuid after the first tag zero, the tags agree.
+    let data = crate::golden::pattern(7, 0, 2048);
+    let blocks = [(300, data[..1024].to_vec()), (301, data[1024..].to_vec())];
+    let built = ok("build_log", build_log(&jsb, 1, &blocks))?;
+    for offset in [0xC, 0x2C] {
+        let want = JournalBlockTag3::ref_from(&descriptor[offset..]).ok_or("short descriptor")?;
+        let got = JournalBlockTag3::ref_from(&built[0][offset..]).ok_or("short log")?;
+        ensure!(
+            got.as_bytes() == want.as_bytes(),
+            "tag at {:#x}: {:?} {:?}",
+            offset,
+            got,
+            want
+        );
+    }
+    let plain = include_bytes!("../golden/layout/journal_descriptor32.bin");
+    let last = JournalBlockTag::ref_from(&plain[0x24..]).ok_or("short descriptor")?;
+    ensure!(
+        (last.blocknr.get(), last.flags.get()) == (301, 0xA),
+        "plain tag {:?}",
+        last
+    );
+    Ok(())
+}
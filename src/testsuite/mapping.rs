@@ -0,0 +1,351 @@
+// The cases of the shared pages, the reflinks and the mapping hooks.
+
+use super::*;
+
+/// The shared pages of tmpfs: a file of /dev/shm grown to 1MiB hands out
+/// its pages, a store through a page is read by readat and a writeat is
+/// seen through the page, and a shrink drops the pages beyond the end.
+pub fn tmpfs_shared_pages() -> Result<(), String> {
+    use crate::cache::PAGE_SIZE;
+    use crate::tmpfs::{page, TmpFs};
+
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>));
+    let node = ok("touch", fs.root_dir().touch("shm"))?;
+    let file: File = FileHandle::new(node, OpenFlags::O_RDWR);
+    ok("truncate", file.truncate(1 << 20))?;
+    let mut stat = Stat::default();
+    ok("stat", file.stat(&mut stat))?;
+    ensure!(stat.size == 1 << 20, "size {} after the growth", stat.size);
+
+    let index = 100;
+    let mapped = ok("page", page(&file, index))?;
+    ensure!(
+        mapped.as_ptr() as usize % PAGE_SIZE == 0,
+        "page at {:p} isn't aligned",
+        mapped.as_ptr()
+    );
+    let again = ok("page", page(&file, index))?;
+    ensure!(
+        mapped.as_ptr() == again.as_ptr(),
+        "the page moved from {:p} to {:p}",
+        mapped.as_ptr(),
+        again.as_ptr()
+    );
+    mapped.write(10, b"mapped");
+    let mut buf = [0; 6];
+    ok("readat", file.readat(index * PAGE_SIZE + 10, &mut buf))?;
+    ensure!(&buf == b"mapped", "readat read {:?} after the store", buf);
+    ok("writeat", file.writeat(index * PAGE_SIZE + 20, b"writes"))?;
+    mapped.read(20, &mut buf);
+    ensure!(&buf == b"writes", "the page has {:?} after writeat", buf);
+    ok("read hole", file.readat(PAGE_SIZE * 3, &mut buf))?;
+    ensure!(buf == [0; 6], "a hole read {:?}", buf);
+
+    ok("truncate", file.truncate(50 * PAGE_SIZE))?;
+    ensure_err!(page(&file, index), VfsError::InvalidInput);
+    ensure!(
+        matches!(file.readat(index * PAGE_SIZE, &mut buf), Ok(0)),
+        "read beyond the end after the shrink"
+    );
+    // the mapping keeps its memory after the file drops the page.
+    mapped.read(10, &mut buf);
+    ensure!(&buf == b"mapped", "the dropped page has {:?}", buf);
+    Ok(())
+}
+
+/// The clones of tmpfs: a clone of a 10MiB file copies no page, the
+/// statfs of the TmpFs counts the shared pages once, and a write to one
+/// of the files copies only its page, the other one keeps its data. The
+/// FICLONERANGE ioctl shares a range through the fd of the source, the
+/// unaligned ranges, a read-only target and a clone from another TmpFs
+/// fail, and the pages of the source removed are counted once.
+pub fn tmpfs_reflink() -> Result<(), String> {
+    use crate::cache::PAGE_SIZE;
+    use crate::reflink::{self, FileCloneRange, FICLONE, FICLONERANGE};
+    use crate::tmpfs::TmpFs;
+
+    const SIZE: usize = 10 << 20;
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>));
+    let root = fs.root_dir();
+    let used = |file: &File| -> Result<u64, String> {
+        let mut statfs = StatFS::default();
+        ok("statfs", file.statfs(&mut statfs))?;
+        Ok(statfs.blocks as u64)
+    };
+    let data = crate::golden::pattern(230, 0, SIZE);
+    let src: File = FileHandle::new(ok("touch", root.touch("src"))?, OpenFlags::O_RDWR);
+    ok("writeat", src.writeat(0, &data))?;
+    let before = used(&src)?;
+    ensure!(
+        before == (SIZE / PAGE_SIZE) as u64,
+        "{} pages are used",
+        before
+    );
+
+    let dst: File = FileHandle::new(ok("touch", root.touch("dst"))?, OpenFlags::O_RDWR);
+    ok("writeat", dst.writeat(0, b"old"))?;
+    ok("clone", reflink::clone_from(&dst, &src))?;
+    ensure!(
+        used(&dst)? == before,
+        "the clone copied {} pages",
+        used(&dst)? - before
+    );
+    ensure!(
+        read_all(&dst, SIZE + 1)? == data,
+        "the clone reads other data"
+    );
+
+    ok("writeat", dst.writeat(7 * PAGE_SIZE + 10, b"diverged"))?;
+    ok("writeat", src.writeat(9 * PAGE_SIZE, b"source"))?;
+    ensure!(
+        used(&dst)? == before + 2,
+        "{} pages are used after two writes",
+        used(&dst)?
+    );
+    let mut expected_dst = data.clone();
+    expected_dst[7 * PAGE_SIZE + 10..7 * PAGE_SIZE + 18].copy_from_slice(b"diverged");
+    let mut expected_src = data.clone();
+    expected_src[9 * PAGE_SIZE..9 * PAGE_SIZE + 6].copy_from_slice(b"source");
+    ensure!(
+        read_all(&dst, SIZE + 1)? == expected_dst,
+        "the clone doesn't diverge"
+    );
+    ensure!(
+        read_all(&src, SIZE + 1)? == expected_src,
+        "the source doesn't diverge"
+    );
+
+    // the range of the source at 8 pages shares the pages of the changed
+    // page 9 of the source.
+    let source = src.clone();
+    reflink::set_fd_resolver(Box::new(move |fd| (fd == 3).then(|| source.clone())));
+    let range = FileCloneRange {
+        src_fd: 3,
+        src_offset: 8 * PAGE_SIZE as u64,
+        src_length: 2 * PAGE_SIZE as u64,
+        dest_offset: 20 * PAGE_SIZE as u64,
+    };
+    ok(
+        "FICLONERANGE",
+        dst.ioctl(FICLONERANGE, &range as *const FileCloneRange as usize),
+    )?;
+    expected_dst[20 * PAGE_SIZE..22 * PAGE_SIZE]
+        .copy_from_slice(&expected_src[8 * PAGE_SIZE..10 * PAGE_SIZE]);
+    ensure!(
+        read_all(&dst, SIZE + 1)? == expected_dst,
+        "the range isn't cloned"
+    );
+    ensure!(
+        used(&dst)? == before + 2,
+        "the range copied {} pages",
+        used(&dst)? - before - 2
+    );
+    let unaligned = FileCloneRange {
+        src_offset: 100,
+        ..range
+    };
+    ensure_err!(
+        dst.ioctl(FICLONERANGE, &unaligned as *const FileCloneRange as usize),
+        VfsError::InvalidInput
+    );
+    let short = FileCloneRange {
+        src_length: 100,
+        ..range
+    };
+    ensure_err!(
+        dst.ioctl(FICLONERANGE, &short as *const FileCloneRange as usize),
+        VfsError::InvalidInput
+    );
+    let reader: File = FileHandle::new(ok("lookup", root.lookup("dst"))?, OpenFlags::O_RDONLY);
+    ensure_err!(reader.ioctl(FICLONE, 3), VfsError::InvalidInput);
+    ensure_err!(dst.ioctl(FICLONE, 4), VfsError::InvalidInput);
+    let other = TmpFs::temp_file();
+    ensure_err!(
+        reflink::clone_from(&other, &src),
+        FsError {
+            error: VfsError::NotSupported,
+            ..
+        }
+    );
+
+    // FICLONE shares the whole source again.
+    ok("FICLONE", dst.ioctl(FICLONE, 3))?;
+    ensure!(
+        read_all(&dst, SIZE + 1)? == expected_src,
+        "FICLONE clones other data"
+    );
+    reflink::set_fd_resolver(Box::new(|_| None));
+    ok("remove", root.remove("src"))?;
+    drop(src);
+    let mut stat = Stat::default();
+    ok("stat", dst.stat(&mut stat))?;
+    ensure!(
+        used(&dst)? == stat.blocks as u64 * 512 / PAGE_SIZE as u64,
+        "{} pages are used by a file of {} blocks",
+        used(&dst)?,
+        stat.blocks
+    );
+    Ok(())
+}
+
+/// The calls of the mapping hooks: the hook, the range and the pages of
+/// the filesystem in use at the call, 0 if they aren't counted.
+static MAP_CALLS: Mutex<Vec<(&'static str, core::ops::Range<usize>, u64)>> = Mutex::new(Vec::new());
+
+/// The mapped file, whether its pages are counted, the writeback writes
+/// "mapped" at 0 through it.
+static MAP_FILE: Mutex<Option<(File, bool)>> = Mutex::new(None);
+
+/// The pages in use of the filesystem of the mapped file, by its statfs.
+fn mapped_pages() -> u64 {
+    let Some((file, true)) = MAP_FILE.lock().clone() else {
+        return 0;
+    };
+    let mut statfs = StatFS::default();
+    match file.statfs(&mut statfs) {
+        Ok(()) => statfs.blocks - statfs.bfree,
+        Err(_) => 0,
+    }
+}
+
+fn map_invalidate(_: crate::cache::InodeId, range: core::ops::Range<usize>) {
+    let pages = mapped_pages();
+    MAP_CALLS.lock().push(("invalidate", range, pages));
+}
+
+fn map_writeback(_: crate::cache::InodeId, range: core::ops::Range<usize>) -> VfsResult<()> {
+    let pages = mapped_pages();
+    MAP_CALLS.lock().push(("writeback", range, pages));
+    let file = MAP_FILE.lock().clone().map(|x| x.0);
+    match file {
+        Some(file) => file.writeat(0, b"mapped").map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+/// Map the file with the recording hooks while f runs, return the calls.
+fn with_mapping_hooks(
+    file: &File,
+    count_pages: bool,
+    f: impl FnOnce() -> CaseResult,
+) -> Result<Vec<(&'static str, core::ops::Range<usize>, u64)>, String> {
+    use crate::mapping::{self, MappingHook};
+
+    let id = ok("inode_id", mapping::inode_id(file))?;
+    MAP_CALLS.lock().clear();
+    *MAP_FILE.lock() = Some((file.clone(), count_pages));
+    mapping::register(
+        id,
+        MappingHook {
+            invalidate_range: map_invalidate,
+            writeback_range: map_writeback,
+        },
+    );
+    let r = f();
+    mapping::unregister(id);
+    *MAP_FILE.lock() = None;
+    r?;
+    Ok(core::mem::take(&mut *MAP_CALLS.lock()))
+}
+
+/// The mapping hooks of a tmpfs file, whose pages the mappings share: the
+/// writes don't invalidate, a punched hole and a shrinking truncate
+/// invalidate their range while the pages are still there, a growing one
+/// nothing, and fsync writes the mappings back first. The files of
+/// another tmpfs with the same inode number aren't the mapped one.
+pub fn tmpfs_mapping_hooks() -> Result<(), String> {
+    use crate::cache::PAGE_SIZE;
+    use crate::fallocate::punch_hole;
+    use crate::fsync::fsync;
+    use crate::tmpfs::TmpFs;
+
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>));
+    let other: &'static Arc<dyn FileSystem> =
+        Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>));
+    let file = ok("touch", fs.root_dir().touch("mapped"))?;
+    let twin = ok("touch", other.root_dir().touch("mapped"))?;
+    ok("writeat", twin.writeat(0, b"twin"))?;
+    let calls = with_mapping_hooks(&file, true, || {
+        ok("writeat", file.writeat(0, &[7; 3 * PAGE_SIZE]))?;
+        ok("punch_hole", punch_hole(&file, PAGE_SIZE, PAGE_SIZE))?;
+        ensure!(
+            mapped_pages() == 2,
+            "{} pages after the hole",
+            mapped_pages()
+        );
+        ok("truncate", file.truncate(5000))?;
+        ensure!(
+            mapped_pages() == 1,
+            "{} pages after the truncation",
+            mapped_pages()
+        );
+        ok("grow", file.truncate(3 * PAGE_SIZE))?;
+        ok("twin", twin.truncate(0))?;
+        ok("fsync", fsync(&file))?;
+        Ok(())
+    })?;
+    let expected = [
+        ("invalidate", PAGE_SIZE..2 * PAGE_SIZE, 3),
+        ("invalidate", 5000..3 * PAGE_SIZE, 2),
+        ("writeback", 0..usize::MAX, 1),
+    ];
+    ensure!(calls == expected, "calls {:?}", calls);
+    ensure!(
+        read_all(&file, 8)? == b"mapped\x07\x07",
+        "the writeback isn't in the file"
+    );
+    ok("truncate", file.truncate(0))?;
+    ensure!(MAP_CALLS.lock().is_empty(), "a call after the unmapping");
+    Ok(())
+}
+
+/// The mapping hooks of an ext4 file, whose mappings copy the pages: each
+/// write invalidates its range after it returns, the emulated hole too,
+/// a truncation before its blocks go, and fsync writes the mappings back
+/// before the sync, whose write invalidates in turn. Another file of the
+/// mount has another InodeId, its writes don't call the hooks.
+#[cfg(root_fs = "ext4_rs")]
+pub fn ext4_mapping_hooks() -> Result<(), String> {
+    use crate::cache::PAGE_SIZE;
+    use crate::fallocate::punch_hole;
+    use crate::fsync::fsync;
+    use crate::mapping::inode_id;
+
+    let fs = ram_ext4(16 << 20, *b"ext4-mapping-hks")?;
+    let file = ok("touch", fs.root().touch("mapped"))?;
+    let other = ok("touch", fs.root().touch("other"))?;
+    let (id, other_id) = (
+        ok("inode_id", inode_id(&file))?,
+        ok("inode_id", inode_id(&other))?,
+    );
+    ensure!(id != other_id, "two files have the InodeId {:?}", id);
+    let calls = with_mapping_hooks(&file, false, || {
+        ok("other", other.writeat(0, b"other"))?;
+        ok("writeat", file.writeat(0, &[7; 3 * PAGE_SIZE]))?;
+        ok("writeat", file.writeat(5000, b"tail"))?;
+        ok("punch_hole", punch_hole(&file, 100, 100))?;
+        ok("truncate", file.truncate(6000))?;
+        ok("grow", file.truncate(7000))?;
+        ok("fsync", fsync(&file))?;
+        Ok(())
+    })?;
+    let expected = [
+        ("invalidate", 0..3 * PAGE_SIZE, 0),
+        ("invalidate", 5000..5004, 0),
+        ("invalidate", 100..200, 0),
+        ("invalidate", 6000..3 * PAGE_SIZE, 0),
+        ("writeback", 0..usize::MAX, 0),
+        ("invalidate", 0..6, 0),
+    ];
+    ensure!(calls == expected, "calls {:?}", calls);
+    let data = read_all(&file, 8000)?;
+    ensure!(
+        &data[..8] == b"mapped\x07\x07" && data[100..200].iter().all(|x| *x == 0),
+        "the data after the hooks"
+    );
+    ensure!(
+        data.len() == 7000 && data[6000..].iter().all(|x| *x == 0),
+        "the grown tail isn't zeros"
+    );
+    Ok(())
+}
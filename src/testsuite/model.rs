@@ -0,0 +1,284 @@
+// The cases of model.rs on tmpfs and ext4, and of the MockDisk.
+
+use super::*;
+
+/// Check the faults of MockDisk: a failed write is lost, a torn one keeps
+/// the first half of its sectors, the failed sectors read as zeros and
+/// aren't written, and the writes after a power cut are dropped. The log
+/// has every request in order, and an async request is pending for the
+/// latency.
+pub fn mock_disk() -> Result<(), String> {
+    use crate::testing::{MockDisk, MockOp, Outcome};
+
+    let disk = MockDisk::new(64 << 10, 0x1000);
+    let mut buf = vec![0; 0x4000];
+    disk.write_at(0, &[1; 0x2000]);
+    disk.fail_write(1);
+    disk.write_at(0, &[2; 0x2000]);
+    disk.read_at(0, &mut buf[..0x2000]);
+    ensure!(
+        buf[..0x2000].iter().all(|x| *x == 1),
+        "the failed write was persisted"
+    );
+    disk.tear_write(2);
+    disk.write_at(0x2000, &[3; 0x4000]);
+    disk.tear_write(3);
+    disk.write_at(0x100, &[4; 0x10]);
+    disk.read_at(0, &mut buf);
+    ensure!(
+        buf[0x2000..0x4000].iter().all(|x| *x == 3),
+        "the torn write lost its first sectors"
+    );
+    disk.read_at(0x4000, &mut buf);
+    ensure!(
+        buf.iter().all(|x| *x == 0),
+        "the torn write kept its last sectors"
+    );
+    disk.read_at(0, &mut buf);
+    ensure!(
+        buf[0x100..0x110].iter().all(|x| *x == 1),
+        "the torn write of one sector was persisted"
+    );
+
+    disk.fail_sectors(1..2);
+    disk.write_at(0, &[5; 0x3000]);
+    disk.read_at(0, &mut buf[..0x3000]);
+    ensure!(
+        buf[..0x1000].iter().all(|x| *x == 5)
+            && buf[0x1000..0x2000].iter().all(|x| *x == 0)
+            && buf[0x2000..0x3000].iter().all(|x| *x == 5),
+        "the failed sector is read or the others aren't written"
+    );
+    disk.clear_faults();
+    disk.read_at(0x1000, &mut buf[..0x1000]);
+    ensure!(
+        buf[..0x1000].iter().all(|x| *x == 1),
+        "the failed sector was written"
+    );
+
+    disk.cut_power_after(6);
+    disk.write_at(0, &[6; 0x1000]);
+    disk.write_at(0, &[7; 0x1000]);
+    let image = disk.image();
+    ensure!(
+        image[..0x1000].iter().all(|x| *x == 6),
+        "the write before the cut is lost or the one after it kept"
+    );
+    disk.restore_power();
+    disk.write_at(0, &[8; 0x1000]);
+    ensure!(disk.writes() == 8, "{} writes", disk.writes());
+
+    let log = disk.log();
+    ensure!(
+        log.iter().enumerate().all(|(i, x)| x.seq == i as u64),
+        "the log is out of order"
+    );
+    let outcomes: Vec<Outcome> = log
+        .iter()
+        .filter(|x| x.op == MockOp::Write)
+        .map(|x| x.outcome)
+        .collect();
+    let expected = [
+        Outcome::Done,
+        Outcome::Failed,
+        Outcome::Torn { persisted: 0x2000 },
+        Outcome::Torn { persisted: 0 },
+        Outcome::Failed,
+        Outcome::Done,
+        Outcome::Dropped,
+        Outcome::Done,
+    ];
+    ensure!(
+        outcomes == expected,
+        "the writes are logged as {:?}",
+        outcomes
+    );
+    ensure!(
+        log.iter()
+            .filter(|x| x.op == MockOp::Read)
+            .map(|x| x.outcome)
+            .eq([
+                Outcome::Done,
+                Outcome::Done,
+                Outcome::Done,
+                Outcome::Done,
+                Outcome::Failed,
+                Outcome::Done,
+            ]),
+        "the reads are logged wrong"
+    );
+
+    #[cfg(feature = "async")]
+    {
+        use core::task::{Context, RawWaker, RawWakerVTable, Waker};
+
+        use crate::aio::AsyncBlockDevice;
+
+        const VTABLE: RawWakerVTable = RawWakerVTable::new(|_| RAW, |_| {}, |_| {}, |_| {});
+        const RAW: RawWaker = RawWaker::new(core::ptr::null(), &VTABLE);
+
+        let disk = MockDisk::new(0x1000, 512).with_latency(3);
+        // SAFETY: the vtable functions do nothing with the null data.
+        let waker = unsafe { Waker::from_raw(RAW) };
+        let mut cx = Context::from_waker(&waker);
+        let data = [9; 512];
+        let mut write = disk.write_blocks_async(1, &data);
+        let mut pending = 0;
+        while write.as_mut().poll(&mut cx).is_pending() {
+            pending += 1;
+        }
+        drop(write);
+        ensure!(pending == 3, "the write was pending for {} polls", pending);
+        disk.read_at(512, &mut buf[..512]);
+        ensure!(
+            buf[..512].iter().all(|x| *x == 9),
+            "the async write is lost"
+        );
+    }
+    Ok(())
+}
+
+/// The directories of the model runs on a tmpfs, a run per directory.
+#[cfg(feature = "std")]
+struct TmpfsTarget {
+    root: File,
+    runs: usize,
+    /// The largest file check allows, to test the shrinking.
+    max_file: Option<usize>,
+    last: Option<File>,
+}
+
+#[cfg(feature = "std")]
+impl TmpfsTarget {
+    fn new(max_file: Option<usize>) -> Self {
+        let fs: &'static Arc<dyn FileSystem> =
+            Box::leak(Box::new(crate::tmpfs::TmpFs::new() as Arc<dyn FileSystem>));
+        Self {
+            root: fs.root_dir(),
+            runs: 0,
+            max_file,
+            last: None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl crate::model::Target for TmpfsTarget {
+    fn fresh(&mut self) -> Result<File, String> {
+        self.runs += 1;
+        let dir = ok("mkdir", self.root.mkdir(&format!("run{}", self.runs)))?;
+        self.last = Some(dir.clone());
+        Ok(dir)
+    }
+
+    fn check(&mut self) -> Result<(), String> {
+        let (Some(max), Some(dir)) = (self.max_file, &self.last) else {
+            return Ok(());
+        };
+        let manifest = crate::golden::Manifest::from_dir(dir)?;
+        match manifest.0.iter().find(|x| x.size as usize > max) {
+            Some(entry) => Err(format!("{} has {} bytes", entry.path, entry.size)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Run the model on tmpfs with a few seeds, tmpfs has no symlinks and
+/// its directories have 2 links. A check failing on the large files,
+/// without renames, is shrunk to the creation and the write of one, with
+/// the mkdirs of its parents.
+#[cfg(feature = "std")]
+pub fn model_tmpfs() -> Result<(), String> {
+    use crate::model::{run, Config, Op, Weights};
+
+    let config = Config {
+        weights: Weights {
+            symlink: 0,
+            ..Default::default()
+        },
+        dir_links: false,
+        ..Default::default()
+    };
+    let mut target = TmpfsTarget::new(None);
+    for seed in 1..=8 {
+        run(&mut target, &Config { seed, ..config }).map_err(|x| format!("{}", x))?;
+    }
+
+    let mut target = TmpfsTarget::new(Some(0x1000));
+    let weights = Weights {
+        rename: 0,
+        ..config.weights
+    };
+    let failure = match run(
+        &mut target,
+        &Config {
+            seed: 7,
+            weights,
+            ..config
+        },
+    ) {
+        Ok(()) => return Err(String::from("no file grew over the limit")),
+        Err(failure) => failure,
+    };
+    // the mkdirs of the parents of the file may stay.
+    let (last, rest) = failure.ops.split_last().ok_or("no operations")?;
+    ensure!(
+        matches!(last, Op::Write { .. } | Op::Truncate(..))
+            && rest.iter().filter(|x| matches!(x, Op::Create(_))).count() == 1
+            && rest
+                .iter()
+                .all(|x| matches!(x, Op::Create(_) | Op::Mkdir(_))),
+        "the failure isn't shrunk: {}",
+        failure
+    );
+    ensure!(
+        format!("{}", failure).starts_with("seed 0x7:"),
+        "the failure doesn't have its seed: {}",
+        failure
+    );
+    Ok(())
+}
+
+/// The fresh ext4 volumes of the model runs, a volume per run.
+#[cfg(all(feature = "std", root_fs = "ext4_rs"))]
+struct Ext4Target(Option<Arc<crate::Ext4FileSystem>>);
+
+#[cfg(all(feature = "std", root_fs = "ext4_rs"))]
+impl crate::model::Target for Ext4Target {
+    fn fresh(&mut self) -> Result<File, String> {
+        let fs = ram_ext4(8 << 20, *b"ext4-model-tests")?;
+        let root = fs.root();
+        self.0 = Some(fs);
+        Ok(root)
+    }
+
+    fn check(&mut self) -> Result<(), String> {
+        let Some(fs) = &self.0 else {
+            return Ok(());
+        };
+        let report = fs.check();
+        ensure!(
+            report.problems.is_empty(),
+            "the image has problems: {:?}",
+            report.problems
+        );
+        Ok(())
+    }
+}
+
+/// Run the model on ext4 with a few seeds, with every operation, and
+/// check the image at the end of every run.
+#[cfg(all(feature = "std", root_fs = "ext4_rs"))]
+pub fn model_ext4() -> Result<(), String> {
+    use crate::model::{run, Config};
+
+    let mut target = Ext4Target(None);
+    for seed in 1..=4 {
+        let config = Config {
+            seed,
+            ..Default::default()
+        };
+        run(&mut target, &config).map_err(|x| format!("{}", x))?;
+    }
+    Ok(())
+}
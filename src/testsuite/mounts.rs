@@ -0,0 +1,630 @@
+// The cases of the mount table, statfs, pathconf, the fstype registry and
+// the boot mounts.
+
+use super::*;
+
+/// Format an ext4 volume in a partition of a fresh RamDisk through its
+/// device node and mount it: the MBR is written by unaligned writes of
+/// the disk node, the partition node is found in it, ext4_mkfs writes
+/// the blocks through the node and the ext4 shim mounts the sectors of
+/// the device.
+#[cfg(all(feature = "std", root_fs = "ext4_rs"))]
+pub fn devnode_mkfs() -> Result<(), String> {
+    use crate::blockdev::{Partition, SectorDevice};
+    use crate::devnode::{disk_name, BlockNode, BLKGETSIZE64};
+    use crate::ext4_mkfs::{format, Options};
+    use crate::sys::{add_blk_device, RamDisk};
+
+    const START: u64 = 1 << 20;
+    const SIZE: u64 = 8 << 20;
+    let device_id = add_blk_device(Arc::new(RamDisk::new((START + SIZE) as usize)));
+    let disk = BlockNode::disk(device_id).ok_or("no node of the disk")?;
+    ensure!(
+        disk.name() == disk_name(device_id),
+        "disk named {}",
+        disk.name()
+    );
+    let mut entry = [0; 16];
+    entry[4] = 0x83;
+    entry[8..12].copy_from_slice(&((START / 512) as u32).to_le_bytes());
+    entry[12..16].copy_from_slice(&((SIZE / 512) as u32).to_le_bytes());
+    ok("write the partition", disk.writeat(0x1BE, &entry))?;
+    ok("write the signature", disk.writeat(510, &[0x55, 0xAA]))?;
+
+    let part = BlockNode::partitions(device_id)
+        .pop()
+        .ok_or("the partition isn't found")?;
+    ensure!(
+        part.name() == disk_name(device_id) + "1" && part.size() == SIZE,
+        "partition {} of {} bytes",
+        part.name(),
+        part.size()
+    );
+    let mut stat = Stat::default();
+    ok("stat", part.stat(&mut stat))?;
+    ensure!(
+        stat.mode.contains(StatMode::BLOCK) && stat.size == SIZE as _ && stat.rdev != 0,
+        "stat of the partition: {:?} size {} rdev {:#x}",
+        stat.mode,
+        stat.size,
+        stat.rdev
+    );
+    let mut size = 0u64;
+    ok(
+        "BLKGETSIZE64",
+        part.ioctl(BLKGETSIZE64, &mut size as *mut u64 as usize),
+    )?;
+    ensure!(size == SIZE, "BLKGETSIZE64 is {}", size);
+    ensure_err!(part.writeat(SIZE as usize, b"x"), VfsError::StorageFull);
+    ensure!(
+        matches!(part.writeat(SIZE as usize - 1, b"xy"), Ok(1)),
+        "a write crossing the end isn't cut at the end"
+    );
+
+    let options = Options::default();
+    let mut written = Ok(());
+    ok(
+        "format",
+        format(SIZE, &options, |block, data| {
+            if written.is_ok() {
+                let offset = block as usize * options.block_size;
+                written = part.writeat(offset, data).map(|_| ());
+            }
+        }),
+    )?;
+    ok("write the blocks", written)?;
+    let sectors = SectorDevice::new(device_id).ok_or("no sys device")?;
+    let device = Arc::new(Partition::new(
+        Arc::new(sectors),
+        START as usize,
+        SIZE as usize,
+    ));
+    let fs = ok("mount", crate::Ext4FileSystem::new_from_device(device))?;
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(fs as Arc<dyn FileSystem>));
+    let root = fs.root_dir();
+    let file = ok("touch", root.touch("raw"))?;
+    ok("writeat", file.writeat(0, b"raw"))?;
+    ok("lookup", root.lookup("raw"))?;
+    Ok(())
+}
+
+/// The statfs of tmpfs: every mount has TMPFS_MAGIC and its own fsid,
+/// stable across the calls, and fstatfs has no mount flags for it.
+pub fn tmpfs_statfs_ids() -> Result<(), String> {
+    use crate::mounts::MountFlags;
+    use crate::statfs::{fstatfs, magic, st_flags, ST_RDONLY, ST_RELATIME, TMPFS_MAGIC};
+    use crate::tmpfs::TmpFs;
+
+    let mount = || -> &'static Arc<dyn FileSystem> {
+        Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>))
+    };
+    let (a, b) = (mount(), mount());
+    for fs in [a, b] {
+        let magic = ok("magic", magic(fs.as_ref()))?;
+        ensure!(magic == TMPFS_MAGIC, "the f_type is {:#x}", magic);
+    }
+    let file = ok("touch", a.root_dir().touch("file"))?;
+    let fsid = |node: &Arc<dyn INodeInterface>| -> Result<u64, String> {
+        Ok(ok("fstatfs", fstatfs(node))?.statfs.fsid)
+    };
+    let (fsid_a, fsid_b) = (fsid(&a.root_dir())?, fsid(&b.root_dir())?);
+    ensure!(fsid_a != fsid_b, "both mounts have the fsid {}", fsid_a);
+    ensure!(
+        fsid(&file)? == fsid_a && fsid(&a.root_dir())? == fsid_a,
+        "the fsid of a changed"
+    );
+    let stat = ok("fstatfs", fstatfs(&file))?;
+    ensure!(stat.flags == 0, "the f_flag of tmpfs is {:#x}", stat.flags);
+    let flags = MountFlags::RDONLY | MountFlags::RELATIME;
+    ensure!(
+        st_flags(flags) == ST_RDONLY | ST_RELATIME,
+        "the f_flag of {:?} is {:#x}",
+        flags,
+        st_flags(flags)
+    );
+    Ok(())
+}
+
+/// The report of the open files of a mount, like lsof: an open by a
+/// dentry has its path, an open by the node the path of a cached dentry
+/// of it, a removed file is marked deleted and a dup counts as one more
+/// reference. The entries go with the last drop of their handles, and
+/// the unmount is refused with the report, EBUSY, until they are gone.
+pub fn mounts_busy_report() -> Result<(), String> {
+    use crate::dentry::{dentry_open_at, DentryNode, ResolveContext};
+    use crate::mounts::{busy_report_at, umount_at, UmountError};
+    use crate::tmpfs::TmpFs;
+
+    let new_fs = || -> &'static Arc<dyn FileSystem> {
+        Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>))
+    };
+    let base = new_fs();
+    ok("mkdir", base.root_dir().mkdir("mnt"))?;
+    let outside = ok("touch", base.root_dir().touch("outside"))?;
+    let root = Arc::new(DentryNode::new(
+        String::from("/"),
+        base.root_dir(),
+        alloc::sync::Weak::new(),
+    ));
+    let ctx = ResolveContext::with_root(root.clone());
+    let fs = new_fs();
+    ok("touch", fs.root_dir().touch("a"))?;
+    ok(
+        "touch",
+        fs.root_dir().mkdir("dir").and_then(|x| x.touch("b")),
+    )?;
+    ok("mount", DentryNode::mount_at(&root, "/mnt", fs.root_dir()))?;
+    let report = |path: &str| ok("report", busy_report_at(&root, path));
+    ensure!(report("/mnt")?.is_empty(), "the idle mount is busy");
+    ensure_err!(busy_report_at(&root, "/mnt/dir"), VfsError::InvalidInput);
+
+    let a = ok("open a", dentry_open_at(&ctx, "/mnt/a", OpenFlags::NONE))?;
+    let file_a: File = FileHandle::from_dentry(&a, OpenFlags::O_RDONLY);
+    let dup = file_a.clone();
+    let b = ok(
+        "open b",
+        dentry_open_at(&ctx, "/mnt/dir/b", OpenFlags::NONE),
+    )?;
+    let file_b: File = FileHandle::new(b.node.clone(), OpenFlags::O_RDWR);
+    let c = ok("touch", fs.root_dir().touch("c"))?;
+    let file_c: File = FileHandle::new(c, OpenFlags::O_RDONLY);
+    let file_outside: File = FileHandle::new(outside, OpenFlags::O_RDONLY);
+    let mnt = ok("open mnt", dentry_open_at(&ctx, "/mnt", OpenFlags::NONE))?;
+    ok("remove a", mnt.remove_child("a"))?;
+
+    let entries = report("/mnt")?;
+    let hints: Vec<_> = entries
+        .iter()
+        .map(|x| (x.path_hint.as_deref(), x.ref_count))
+        .collect();
+    for want in [
+        (Some("/mnt/a (deleted)"), 2),
+        (Some("/mnt/dir/b"), 1),
+        (None, 1),
+    ] {
+        ensure!(hints.contains(&want), "no {:?} in {:?}", want, entries);
+    }
+    ensure!(entries.len() == 3, "the report is {:?}", entries);
+    match umount_at(&root, "/mnt") {
+        Err(err) if err.busy == entries => {
+            ensure!(
+                Errno::from(err) == Errno::EBUSY,
+                "the busy unmount isn't EBUSY"
+            );
+        }
+        r => return Err(format!("the busy unmount returned {:?}", r)),
+    }
+    ensure_err!(
+        umount_at(&root, "/"),
+        UmountError {
+            error: FsError {
+                error: VfsError::InvalidInput,
+                ..
+            },
+            ..
+        }
+    );
+
+    drop((file_a, dup));
+    ensure!(report("/mnt")?.len() == 2, "a stayed after its drop");
+    drop((file_b, file_c));
+    ensure!(report("/mnt")?.is_empty(), "the dropped files stayed");
+    ok("umount", umount_at(&root, "/mnt").map_err(VfsError::from))?;
+    ensure_errno!(
+        dentry_open_at(&ctx, "/mnt/dir", OpenFlags::NONE),
+        Errno::ENOENT
+    );
+    ensure_err!(busy_report_at(&root, "/mnt"), VfsError::InvalidInput);
+    ensure!(
+        report("/")?.len() == 1,
+        "the file outside the mount isn't reported"
+    );
+    drop(file_outside);
+    Ok(())
+}
+
+/// The pathconf limits of tmpfs, procfs and a pipe, which has no
+/// statfs: LINK_MAX of Linux, NAME_MAX and PATH_MAX of the names, and the
+/// unknown _PC_* values fail with InvalidInput.
+pub fn pathconf_limits() -> Result<(), String> {
+    use crate::pathconf::{fpathconf, fpathconf_raw, PathconfName, LINUX_LINK_MAX, NO_LIMIT};
+    use crate::pipe::{pipe_ends, PIPE_BUF};
+    use crate::proc_pid::TaskProcFs;
+    use crate::tmpfs::TmpFs;
+    use crate::PATH_MAX;
+
+    let tmpfs: &'static Arc<dyn FileSystem> =
+        Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>));
+    let procfs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(
+        TaskProcFs::new(TmpFs::new()) as Arc<dyn FileSystem>
+    ));
+    let (read, _write) = pipe_ends(PIPE_BUF, OpenFlags::O_RDONLY);
+    let nodes: [(&str, Arc<dyn INodeInterface>); 4] = [
+        ("tmpfs", tmpfs.root_dir()),
+        ("tmpfs file", ok("touch", tmpfs.root_dir().touch("file"))?),
+        ("procfs", procfs.root_dir()),
+        ("pipe", read),
+    ];
+    for (what, node) in nodes.iter() {
+        let value = |name| ok("fpathconf", fpathconf(node, name));
+        let expected = [
+            (PathconfName::LinkMax, LINUX_LINK_MAX),
+            (PathconfName::NameMax, NAME_MAX as isize),
+            (PathconfName::PathMax, PATH_MAX as isize),
+            (PathconfName::PipeBuf, PIPE_BUF as isize),
+            (PathconfName::ChownRestricted, 1),
+            (PathconfName::NoTrunc, 1),
+            (PathconfName::FilesizeBits, 64),
+            (PathconfName::TwoSymlinks, 1),
+            (PathconfName::RecMaxXferSize, NO_LIMIT),
+        ];
+        for (name, expected) in expected {
+            let actual = value(name)?;
+            ensure!(
+                actual == expected,
+                "{:?} of the {} is {}, not {}",
+                name,
+                what,
+                actual,
+                expected
+            );
+        }
+        for name in PathconfName::ALL {
+            let raw = ok("fpathconf_raw", fpathconf_raw(node, name as i32))?;
+            ensure!(raw == value(name)?, "{:?} by its value is {}", name, raw);
+        }
+        for raw in [-1, 21, 1000] {
+            ensure_err!(fpathconf_raw(node, raw), VfsError::InvalidInput);
+        }
+    }
+    Ok(())
+}
+
+/// The registry of the filesystem types: a dummy type is mounted by its
+/// name and by the detection of its magic in a file, the images which no
+/// type knows and the unknown names fail, and /proc/filesystems lists
+/// the types with nodev for those without a device.
+pub fn fstype_registry() -> Result<(), String> {
+    use crate::fstype::{
+        self, detect, mount_auto, mount_by_name, register_fstype, unregister_fstype, FsType,
+        MountSource,
+    };
+    use crate::mounts::MountFlags;
+    use crate::tmpfs::TmpFs;
+
+    static MOUNTS: AtomicUsize = AtomicUsize::new(0);
+    const DUMMY: FsType = FsType {
+        name: "dummy194",
+        detect: Some(|source| {
+            let mut magic = [0; 8];
+            source.read_at(0, &mut magic).is_ok() && magic == *b"DUMMY194"
+        }),
+        identify: None,
+        mount: |_, _| {
+            MOUNTS.fetch_add(1, Ordering::Relaxed);
+            Ok(TmpFs::new() as Arc<dyn FileSystem>)
+        },
+        priority: 100,
+    };
+
+    fstype::init();
+    ok("register", register_fstype(DUMMY))?;
+    ensure_err!(register_fstype(DUMMY), VfsError::AlreadyExists);
+    ok(
+        "mount",
+        mount_by_name("dummy194", MountSource::None, MountFlags::NONE),
+    )?;
+    ensure!(
+        MOUNTS.load(Ordering::Relaxed) == 1,
+        "the mount by the name missed the type"
+    );
+
+    let tmp: &'static Arc<dyn FileSystem> =
+        Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>));
+    let image = ok("touch", tmp.root_dir().touch("image"))?;
+    ok("writeat", image.writeat(0, b"DUMMY194 image"))?;
+    let source = MountSource::File(image);
+    ensure!(
+        detect(&source) == Some("dummy194"),
+        "the image is detected as {:?}",
+        detect(&source)
+    );
+    ok("mount", mount_auto(source, MountFlags::RDONLY))?;
+    ensure!(
+        MOUNTS.load(Ordering::Relaxed) == 2,
+        "the detected mount missed the type"
+    );
+
+    let other = ok("touch", tmp.root_dir().touch("other"))?;
+    ok("writeat", other.writeat(0, b"DUMMY000"))?;
+    ensure_err!(
+        mount_auto(MountSource::File(other), MountFlags::NONE),
+        FsError {
+            error: VfsError::InvalidData,
+            ..
+        }
+    );
+    ensure_errno!(
+        mount_by_name("missing194", MountSource::None, MountFlags::NONE),
+        Errno::ENODEV
+    );
+    ensure!(
+        MOUNTS.load(Ordering::Relaxed) == 2,
+        "a failed mount reached the type"
+    );
+
+    let filesystems = fstype::render();
+    for line in ["\tdummy194\n", "nodev\ttmpfs\n"] {
+        ensure!(
+            filesystems.contains(line),
+            "/proc/filesystems has no {:?}:\n{}",
+            line,
+            filesystems
+        );
+    }
+    ensure!(
+        fstype::fstypes()[0].name == "dummy194",
+        "the types aren't in their priority order"
+    );
+    ensure!(unregister_fstype("dummy194"), "the type wasn't registered");
+    ensure!(
+        fstype::fstype("dummy194").is_none(),
+        "the type is registered after unregister"
+    );
+    Ok(())
+}
+
+/// The mount tree of boot.rs on an ext4 RamDisk of the sys devices: the
+/// root is found by its uuid after a missing device, the standard
+/// directories are created on it and the types missing on a host fail
+/// their mounts without failing the boot. A /tmp file lands in the tmpfs,
+/// a read-only boot of the same device finds the directories, one of a
+/// fresh image reports them missing, and no root fails the boot.
+#[cfg(all(feature = "std", root_fs = "ext4_rs"))]
+pub fn boot_mount_tree() -> Result<(), String> {
+    use crate::boot::{boot, BootFsConfig, BootStatus, RootSpec, STANDARD_DIRS};
+    use crate::dentry::{dentry_open_at, ResolveContext};
+    use crate::ext4_mkfs::{format_device, Options};
+    use crate::fstype;
+    use crate::mounts::MountFlags;
+    use crate::sys::{add_blk_device, RamDisk};
+
+    const SIZE: usize = 4 << 20;
+    let uuid = *b"boot-mount-tree!";
+    fstype::init();
+    let device_id = add_blk_device(Arc::new(RamDisk::new(SIZE)));
+    let options = Options {
+        uuid,
+        ..Options::default()
+    };
+    ok("format", format_device(device_id, SIZE as u64, &options))?;
+
+    let config = |root: Vec<RootSpec>, root_flags| BootFsConfig {
+        root,
+        root_flags,
+        ..BootFsConfig::default()
+    };
+    ensure_err!(
+        boot(&config(
+            vec![RootSpec::Device(usize::MAX)],
+            MountFlags::NONE
+        )),
+        VfsError::FileNotFound
+    );
+    ensure_err!(
+        boot(&config(vec![RootSpec::Uuid([0xAA; 16])], MountFlags::NONE)),
+        VfsError::FileNotFound
+    );
+    ensure_err!(
+        boot(&config(vec![], MountFlags::NONE)),
+        VfsError::InvalidInput
+    );
+
+    let roots = vec![RootSpec::Device(usize::MAX), RootSpec::Uuid(uuid)];
+    let report = ok("boot", boot(&config(roots.clone(), MountFlags::NONE)))?;
+    ensure!(
+        report.root_spec == RootSpec::Uuid(uuid) && report.root_fstype == "ext4",
+        "root {} by {:?}",
+        report.root_fstype,
+        report.root_spec
+    );
+    let expected: [(&str, &str, bool); 7] = [
+        ("/", "ext4", true),
+        ("/dev", "devfs", false),
+        ("/proc", "proc", false),
+        ("/tmp", "tmpfs", true),
+        ("/dev/shm", "tmpfs", true),
+        ("/home", "ramfs", false),
+        ("/var", "ramfs", false),
+    ];
+    ensure!(report.mounts.len() == expected.len(), "mounts:\n{}", report);
+    for (mount, (path, fstype, mounted)) in report.mounts.iter().zip(expected) {
+        let status = match &mount.status {
+            BootStatus::Mounted => mounted,
+            BootStatus::Failed(VfsError::NotSupported) => !mounted,
+            _ => false,
+        };
+        ensure!(
+            mount.path == path && mount.fstype == fstype && status,
+            "mounts:\n{}",
+            report
+        );
+    }
+    ensure!(
+        report.filesystems.len() == 3 && report.missing.is_empty(),
+        "{} filesystems:\n{}",
+        report.filesystems.len(),
+        report
+    );
+    let mounts = crate::mounts::render_mounts_at(&report.root);
+    let root_line = format!("UUID={} / ext4 rw", crate::volume::format_uuid(&uuid));
+    ensure!(
+        mounts.starts_with(&root_line) && mounts.contains("tmpfs /tmp tmpfs rw 0 0"),
+        "/proc/mounts of the boot:\n{}",
+        mounts
+    );
+    for path in STANDARD_DIRS.iter().chain(["/bin", "/dev/shm"].iter()) {
+        ensure!(
+            report.created.iter().any(|x| x == path),
+            "{} isn't created: {:?}",
+            path,
+            report.created
+        );
+    }
+
+    let ctx = ResolveContext::with_root(report.root.clone());
+    let tmp = ok("open /tmp", dentry_open_at(&ctx, "/tmp", OpenFlags::NONE))?;
+    ok("touch", tmp.node.touch("boot219"))?;
+    let (tmpfs, _) = report
+        .filesystems
+        .iter()
+        .find(|(_, path)| path == "/tmp")
+        .ok_or("no filesystem of /tmp")?;
+    let tmpfs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(tmpfs.clone()));
+    ok("lookup in the tmpfs", tmpfs.root_dir().lookup("boot219"))?;
+    let (ext4, _) = &report.filesystems[0];
+    let ext4: &'static Arc<dyn FileSystem> = Box::leak(Box::new(ext4.clone()));
+    let ext4_tmp = ok("open tmp", ext4.root_dir().open("tmp", OpenFlags::NONE))?;
+    ensure_err!(ext4_tmp.lookup("boot219"), VfsError::FileNotFound);
+    ok("flush", ext4.flush())?;
+    drop(report);
+
+    let report = ok("boot", boot(&config(roots, MountFlags::RDONLY)))?;
+    ensure!(
+        report.created.is_empty() && report.missing.is_empty(),
+        "the read-only boot created {:?}, missed {:?}",
+        report.created,
+        report.missing
+    );
+    drop(report);
+
+    let fresh = add_blk_device(Arc::new(RamDisk::new(SIZE)));
+    ok(
+        "format",
+        format_device(fresh, SIZE as u64, &Options::default()),
+    )?;
+    let report = ok(
+        "boot",
+        boot(&config(vec![RootSpec::Device(fresh)], MountFlags::RDONLY)),
+    )?;
+    ensure!(
+        report.missing.len() == STANDARD_DIRS.len() + 1,
+        "the read-only boot missed {:?}",
+        report.missing
+    );
+    ensure!(
+        matches!(
+            report.mount("/tmp").map(|x| &x.status),
+            Some(BootStatus::Failed(_))
+        ),
+        "mounts:\n{}",
+        report
+    );
+    Ok(())
+}
+
+/// The mounts by a spec of mounts.rs on ext4 RamDisks of the sys devices:
+/// two images of distinct labels mount by their LABEL=, the uuid, the
+/// node of /dev and the index find their device, /proc/mounts shows the
+/// specs, and a label on a third device makes LABEL= fail with both
+/// candidates.
+#[cfg(all(feature = "std", root_fs = "ext4_rs"))]
+pub fn mount_by_spec() -> Result<(), String> {
+    use crate::dentry::{dentry_open_at, DentryNode, ResolveContext};
+    use crate::devnode::disk_name;
+    use crate::ext4_mkfs::{format_device, Options};
+    use crate::fstype::{self, resolve_source, source_candidates, MountSource};
+    use crate::mounts::{mount_spec_at, render_mounts_at, MountFlags};
+    use crate::sys::{add_blk_device, RamDisk};
+    use crate::tmpfs::TmpFs;
+    use crate::volume::{self, format_uuid};
+
+    const SIZE: usize = 4 << 20;
+    fstype::init();
+    let disk = |uuid: [u8; 16], label: &'static str| -> Result<usize, String> {
+        let device_id = add_blk_device(Arc::new(RamDisk::new(SIZE)));
+        let options = Options {
+            uuid,
+            label,
+            ..Options::default()
+        };
+        ok("format", format_device(device_id, SIZE as u64, &options))?;
+        Ok(device_id)
+    };
+    let a = disk(*b"mount-by-spec-a!", "spec234-a")?;
+    let b = disk(*b"mount-by-spec-b!", "spec234-b")?;
+
+    let tmpfs: &'static Arc<dyn FileSystem> =
+        Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>));
+    for dir in ["a", "b", "c"] {
+        ok("mkdir", tmpfs.root_dir().mkdir(dir))?;
+    }
+    let root = Arc::new(DentryNode::new(
+        String::from("/"),
+        tmpfs.root_dir(),
+        alloc::sync::Weak::new(),
+    ));
+    let fs_a = ok(
+        "mount LABEL=spec234-a",
+        mount_spec_at(&root, "LABEL=spec234-a", "/a", "ext4", MountFlags::NONE),
+    )?;
+    let fs_b = ok(
+        "mount LABEL=spec234-b",
+        mount_spec_at(&root, "LABEL=spec234-b", "/b", "auto", MountFlags::RDONLY),
+    )?;
+    ensure!(
+        volume::label(&fs_a).as_deref() == Some("spec234-a")
+            && volume::label(&fs_b).as_deref() == Some("spec234-b"),
+        "mounted {:?} and {:?}",
+        volume::label(&fs_a),
+        volume::label(&fs_b)
+    );
+    let ctx = ResolveContext::with_root(root.clone());
+    ok(
+        "open /a/lost+found",
+        dentry_open_at(&ctx, "/a/lost+found", OpenFlags::NONE),
+    )?;
+
+    let device = |spec: &str| -> Result<usize, String> {
+        match ok(spec, resolve_source(spec))? {
+            MountSource::Device(device_id) => Ok(device_id),
+            _ => Err(format!("{} isn't a whole device", spec)),
+        }
+    };
+    let uuid = format_uuid(b"mount-by-spec-b!").to_uppercase();
+    ensure!(device(&format!("UUID={}", uuid))? == b, "UUID= of b");
+    ensure!(device(&format!("/dev/{}", disk_name(a)))? == a, "/dev of a");
+    ensure!(device(&b.to_string())? == b, "the index of b");
+    ensure_err!(resolve_source("LABEL=spec234-none"), VfsError::FileNotFound);
+    ensure_err!(resolve_source("/dev/vdzz9"), VfsError::FileNotFound);
+    ensure_err!(resolve_source("spec234"), VfsError::InvalidInput);
+
+    let mounts = render_mounts_at(&root);
+    ensure!(
+        mounts.starts_with("none / none rw 0 0")
+            && mounts.contains("LABEL=spec234-a /a ext4 rw")
+            && mounts.contains("LABEL=spec234-b /b ext4 ro"),
+        "/proc/mounts:\n{}",
+        mounts
+    );
+
+    let c = disk(*b"mount-by-spec-c!", "spec234-a")?;
+    ensure_err!(resolve_source("LABEL=spec234-a"), VfsError::InvalidInput);
+    ensure_err!(
+        mount_spec_at(&root, "LABEL=spec234-a", "/c", "ext4", MountFlags::RDONLY),
+        FsError {
+            error: VfsError::InvalidInput,
+            ..
+        }
+    );
+    let names: Vec<_> = source_candidates("LABEL=spec234-a")
+        .into_iter()
+        .map(|x| x.name)
+        .collect();
+    ensure!(
+        names == [disk_name(a), disk_name(c)],
+        "the candidates of LABEL=spec234-a are {:?}",
+        names
+    );
+    Ok(())
+}
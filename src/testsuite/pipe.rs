@@ -0,0 +1,278 @@
+// The cases of the pipes and the fifos.
+
+use super::*;
+
+/// A FIFO inode of no filesystem, for the pipes of open_fifo.
+struct FakeFifo(usize);
+
+impl INodeInterface for FakeFifo {
+    fn metadata(&self) -> VfsResult<vfscore::Metadata> {
+        Ok(vfscore::Metadata {
+            filename: "fifo",
+            inode: self.0,
+            file_type: FileType::Device,
+            size: 0,
+            childrens: 0,
+        })
+    }
+
+    fn stat(&self, stat: &mut Stat) -> VfsResult<()> {
+        stat.ino = self.0 as _;
+        stat.mode = StatMode::FIFO;
+        Ok(())
+    }
+}
+
+/// Check the close semantics of the pipes, the atomic writes, poll and
+/// the sharing of the FIFOs. The ends are nonblocking, nothing waits.
+pub fn pipe_semantics() -> Result<(), String> {
+    use crate::handle::open_node;
+    use crate::pipe::{open_fifo, pipe_ends, PIPE_BUF};
+
+    let all = PollEvent::POLLIN | PollEvent::POLLOUT | PollEvent::POLLERR;
+    let nonblock = OpenFlags::O_NONBLOCK;
+    let mut buf = [0u8; 16];
+
+    // the data written before the writer closes is read, then the EOF.
+    let (read, write) = pipe_ends(64, nonblock);
+    ensure_err!(read.readat(0, &mut buf), VfsError::Blocking);
+    ensure!(
+        ok("poll", read.poll(all))?.is_empty(),
+        "an empty pipe polls ready"
+    );
+    ok("writeat", write.writeat(0, b"data"))?;
+    ensure!(
+        ok("poll", read.poll(all))? == PollEvent::POLLIN,
+        "the data don't poll POLLIN"
+    );
+    drop(write);
+    ensure!(
+        ok("readat", read.readat(0, &mut buf))? == 4,
+        "lost the data"
+    );
+    ensure!(&buf[..4] == b"data", "read {:?}", &buf[..4]);
+    ensure!(ok("readat", read.readat(0, &mut buf))? == 0, "no EOF");
+    ensure!(
+        ok("poll", read.poll(all))? == PollEvent::POLLERR,
+        "the EOF doesn't poll POLLERR"
+    );
+
+    // a write fails once the readers are closed.
+    let (read, write) = pipe_ends(64, nonblock);
+    drop(read);
+    ensure_err!(write.writeat(0, b"data"), VfsError::Io);
+    ensure_errno!(write.write(b"data"), Errno::EPIPE);
+    ensure!(
+        ok("poll", write.poll(all))? == PollEvent::POLLERR,
+        "no reader doesn't poll POLLERR"
+    );
+
+    // the ends only do their side.
+    let (read, write) = pipe_ends(64, nonblock);
+    ensure_err!(read.writeat(0, b"data"), VfsError::InvalidInput);
+    ensure_err!(write.readat(0, &mut buf), VfsError::InvalidInput);
+
+    // an atomic write is written whole or not at all, a larger one in
+    // parts.
+    let (read, write) = pipe_ends(2 * PIPE_BUF, nonblock);
+    let small = vec![1u8; PIPE_BUF + 1];
+    ok("writeat", write.writeat(0, &small))?;
+    ensure!(
+        ok("poll", write.poll(all))?.is_empty(),
+        "POLLOUT without the room of PIPE_BUF"
+    );
+    ensure_err!(write.writeat(0, &small[..PIPE_BUF]), VfsError::Blocking);
+    let large = vec![2u8; 2 * PIPE_BUF];
+    let n = ok("writeat", write.writeat(0, &large))?;
+    ensure!(n == PIPE_BUF - 1, "the large write wrote {} bytes", n);
+    ensure_err!(write.writeat(0, &large), VfsError::Blocking);
+    let mut out = vec![0u8; 4 * PIPE_BUF];
+    let n = ok("readat", read.readat(0, &mut out))?;
+    ensure!(n == 2 * PIPE_BUF, "read {} bytes of the full pipe", n);
+    ensure!(
+        out[..PIPE_BUF + 1].iter().all(|x| *x == 1) && out[PIPE_BUF + 1..n].iter().all(|x| *x == 2),
+        "the writes are mixed up"
+    );
+    ensure!(
+        ok("poll", write.poll(all))? == PollEvent::POLLOUT,
+        "the empty pipe doesn't poll POLLOUT"
+    );
+    write.set_nonblocking(false);
+    ensure!(!write.nonblocking(), "set_nonblocking didn't clear it");
+
+    // the opens of a FIFO share its pipe, a new open after all of them
+    // are closed has a new one.
+    let fifo: Arc<dyn INodeInterface> = Arc::new(FakeFifo(7));
+    let reader = ok(
+        "open_fifo",
+        open_fifo(fifo.as_ref(), OpenFlags::O_RDONLY | nonblock),
+    )?;
+    // the writer isn't there yet, the reader waits for it.
+    ensure_err!(reader.readat(0, &mut buf), VfsError::Blocking);
+    let writer = ok(
+        "open_node",
+        open_node(fifo.clone(), OpenFlags::O_WRONLY | nonblock),
+    )?;
+    ok("writeat", writer.writeat(0, b"fifo"))?;
+    ensure!(
+        ok("readat", reader.readat(0, &mut buf))? == 4,
+        "the FIFO isn't shared"
+    );
+    let other = ok(
+        "open_fifo",
+        open_fifo(&FakeFifo(8), OpenFlags::O_RDWR | nonblock),
+    )?;
+    ok("writeat", other.writeat(0, b"other"))?;
+    ensure_err!(reader.readat(0, &mut buf), VfsError::Blocking);
+    drop((reader, writer));
+    let reader = ok(
+        "open_fifo",
+        open_fifo(fifo.as_ref(), OpenFlags::O_RDONLY | nonblock),
+    )?;
+    ensure_err!(reader.readat(0, &mut buf), VfsError::Blocking);
+    ensure_err!(
+        open_fifo(&FakeFifo(0), OpenFlags::O_RDONLY),
+        FsError {
+            error: VfsError::NotSupported,
+            ..
+        }
+    );
+    Ok(())
+}
+
+/// Open the two sides of a FIFO on two threads: the first open waits for
+/// the other side, in both orders, the reader sees the EOF once the
+/// writer closes, and the nonblocking opens don't wait. The ends wait
+/// with yield_now.
+#[cfg(feature = "std")]
+pub fn fifo_open_pairing() -> Result<(), String> {
+    use core::sync::atomic::AtomicBool;
+    use std::{thread, time::Duration};
+
+    use crate::pipe::{open_fifo, set_wait_hook};
+
+    set_wait_hook(thread::yield_now);
+    let fifo: Arc<dyn INodeInterface> = Arc::new(FakeFifo(21));
+    for writer_first in [true, false] {
+        let opened = Arc::new(AtomicBool::new(false));
+        let (node, done) = (fifo.clone(), opened.clone());
+        let first = thread::spawn(move || -> Result<(), String> {
+            let flags = match writer_first {
+                true => OpenFlags::O_WRONLY,
+                false => OpenFlags::O_RDONLY,
+            };
+            let end = ok("open_fifo", open_fifo(node.as_ref(), flags))?;
+            done.store(true, Ordering::SeqCst);
+            if writer_first {
+                ok("writeat", end.writeat(0, b"first"))?;
+            } else {
+                let mut buf = [0u8; 8];
+                let n = ok("readat", end.readat(0, &mut buf))?;
+                ensure!(&buf[..n] == b"second", "the reader got {:?}", &buf[..n]);
+            }
+            Ok(())
+        });
+        thread::sleep(Duration::from_millis(20));
+        ensure!(
+            !opened.load(Ordering::SeqCst),
+            "the open didn't wait for the other side"
+        );
+        if writer_first {
+            let reader = ok("open_fifo", open_fifo(fifo.as_ref(), OpenFlags::O_RDONLY))?;
+            first.join().map_err(|_| "the writer panicked")??;
+            let mut buf = [0u8; 8];
+            let n = ok("readat", reader.readat(0, &mut buf))?;
+            ensure!(&buf[..n] == b"first", "the reader got {:?}", &buf[..n]);
+            ensure!(
+                ok("readat", reader.readat(0, &mut buf))? == 0,
+                "no EOF after the writer closed"
+            );
+        } else {
+            let writer = ok("open_fifo", open_fifo(fifo.as_ref(), OpenFlags::O_WRONLY))?;
+            ok("writeat", writer.writeat(0, b"second"))?;
+            first.join().map_err(|_| "the reader panicked")??;
+        }
+    }
+
+    // a nonblocking reader doesn't wait, a nonblocking writer fails
+    // without a reader.
+    let nonblock = OpenFlags::O_NONBLOCK;
+    ensure_errno!(
+        open_fifo(fifo.as_ref(), OpenFlags::O_WRONLY | nonblock),
+        Errno::ENXIO
+    );
+    let reader = ok(
+        "open_fifo",
+        open_fifo(fifo.as_ref(), OpenFlags::O_RDONLY | nonblock),
+    )?;
+    let writer = ok(
+        "open_fifo",
+        open_fifo(fifo.as_ref(), OpenFlags::O_WRONLY | nonblock),
+    )?;
+    // O_RDWR doesn't wait, and its reader keeps the writes going.
+    let both = ok("open_fifo", open_fifo(fifo.as_ref(), OpenFlags::O_RDWR))?;
+    drop(reader);
+    ok("writeat", writer.writeat(0, b"kept"))?;
+    let mut buf = [0u8; 8];
+    ensure!(
+        ok("readat", both.readat(0, &mut buf))? == 4,
+        "the O_RDWR end lost the write"
+    );
+    Ok(())
+}
+
+/// Move a MiB through a blocking pipe with a fast writer and a slow
+/// reader, then a slow writer and a fast reader, and check the bytes
+/// arrive in order. The ends wait with yield_now.
+#[cfg(feature = "std")]
+pub fn pipe_stress() -> Result<(), String> {
+    use std::thread;
+
+    use crate::pipe::{make_pipe, set_wait_hook};
+
+    const TOTAL: usize = 1 << 20;
+
+    set_wait_hook(thread::yield_now);
+    for slow_reader in [true, false] {
+        let (read, write) = make_pipe(0x1000);
+        let writer = thread::spawn(move || -> Result<(), String> {
+            let data: Vec<u8> = (0..TOTAL).map(|x| (x % 251) as u8).collect();
+            let mut pos = 0;
+            let mut chunk = 1;
+            while pos < TOTAL {
+                let end = (pos + chunk).min(TOTAL);
+                pos += ok("writeat", write.writeat(0, &data[pos..end]))?;
+                // the chunks go from a byte to beyond PIPE_BUF.
+                chunk = chunk * 7 % 9001 + 1;
+                if !slow_reader {
+                    thread::yield_now();
+                }
+            }
+            Ok(())
+        });
+        let mut buf = vec![0u8; 0x3000];
+        let mut pos = 0;
+        loop {
+            let len = if slow_reader { 97 } else { buf.len() };
+            let n = ok("readat", read.readat(0, &mut buf[..len]))?;
+            if n == 0 {
+                break;
+            }
+            ensure!(
+                buf[..n]
+                    .iter()
+                    .enumerate()
+                    .all(|(i, x)| *x == ((pos + i) % 251) as u8),
+                "the bytes at {} are out of order",
+                pos
+            );
+            pos += n;
+            if slow_reader {
+                thread::yield_now();
+            }
+        }
+        writer.join().map_err(|_| "the writer panicked")??;
+        ensure!(pos == TOTAL, "read {} bytes of {}", pos, TOTAL);
+    }
+    Ok(())
+}
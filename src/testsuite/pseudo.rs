@@ -0,0 +1,267 @@
+// The cases of /proc, of the memory devices and of the pseudo files.
+
+use super::*;
+
+/// The directories of the processes of proc_pid over dir, with a fake
+/// provider: /proc/self, the fd links and their targets, read through the
+/// dentry tree, and a closed fd is gone at once.
+pub(super) fn proc_pid(dir: &File) -> CaseResult {
+    use crate::dentry::{dentry_open_at, DentryNode, ResolveContext};
+    use crate::ops::readlinkat;
+    use crate::proc_pid::{set_current_pid_hook, set_task_provider, ProcRoot, TaskFsInfo};
+    use crate::statfs::{next_fsid, PROC_SUPER_MAGIC};
+
+    let file = ok("touch", dir.touch("opened"))?;
+    ok("writeat", file.writeat(0, b"opened"))?;
+    let provider = |fds: &'static [usize]| {
+        Box::new(move |pid: usize| {
+            (pid == 42).then(|| TaskFsInfo {
+                cwd: String::from("/"),
+                root: String::from("/"),
+                exe: Some(String::from("/opened")),
+                fds: fds.iter().map(|x| (*x, String::from("/opened"))).collect(),
+            })
+        })
+    };
+    set_task_provider(provider(&[3, 0]));
+    set_current_pid_hook(|| 42);
+    let root = Arc::new(DentryNode::new(
+        String::from("/"),
+        Arc::new(ProcRoot::new(dir.clone(), next_fsid())),
+        alloc::sync::Weak::new(),
+    ));
+    let mut statfs = StatFS::default();
+    ok("statfs", root.statfs(&mut statfs))?;
+    ensure!(
+        statfs.ftype == PROC_SUPER_MAGIC as _,
+        "the f_type of /proc is {:#x}",
+        statfs.ftype
+    );
+    root.set_volatile();
+    let ctx = ResolveContext::with_root(root);
+    let link = |path: &str| -> Result<String, String> {
+        let target = ok(path, readlinkat(&ctx, path))?;
+        Ok(String::from_utf8_lossy(&target).into_owned())
+    };
+
+    ensure!(link("/self")? == "42", "/self links to {}", link("/self")?);
+    ensure!(
+        link("/self/cwd")? == "/",
+        "cwd links to {}",
+        link("/self/cwd")?
+    );
+    ensure!(
+        link("/42/exe")? == "/opened",
+        "exe links to {}",
+        link("/42/exe")?
+    );
+    let fds = ok(
+        "open fd",
+        dentry_open_at(&ctx, "/self/fd", OpenFlags::O_RDONLY),
+    )?;
+    let names: Vec<_> = ok("read_dir", fds.node.read_dir())?
+        .into_iter()
+        .map(|x| x.filename)
+        .collect();
+    ensure!(names == [".", "..", "0", "3"], "fd lists {:?}", names);
+    ensure!(
+        link("/self/fd/3")? == "/opened",
+        "fd 3 links to {}",
+        link("/self/fd/3")?
+    );
+    // the open follows the link to the file.
+    let opened = ok(
+        "open fd 3",
+        dentry_open_at(&ctx, "/self/fd/3", OpenFlags::O_RDONLY),
+    )?;
+    let mut buf = [0; 6];
+    ensure!(
+        matches!(opened.node.readat(0, &mut buf), Ok(6)) && &buf == b"opened",
+        "read {:?} through fd 3",
+        buf
+    );
+    ensure_errno!(
+        dentry_open_at(&ctx, "/7", OpenFlags::O_RDONLY),
+        Errno::ENOENT
+    );
+
+    // the lookups of /proc aren't cached.
+    set_task_provider(provider(&[0]));
+    ensure_errno!(
+        dentry_open_at(&ctx, "/self/fd/3", OpenFlags::O_RDONLY),
+        Errno::ENOENT
+    );
+    ok(
+        "open fd 0",
+        dentry_open_at(&ctx, "/self/fd/0", OpenFlags::O_RDONLY),
+    )?;
+    Ok(())
+}
+
+/// The memory devices: null, zero, full and urandom, by their nodes.
+/// random and urandom are only checked for blocking if they aren't seeded
+/// yet, the host seeds them at their first read.
+pub(super) fn mem_devices(_dir: &File) -> CaseResult {
+    use crate::chardev::{random_seeded, MemDevice, MemNode};
+    use crate::devnode::make_dev;
+
+    let null = MemNode::new(MemDevice::Null);
+    let mut buf = vec![0xa5; 0x1000];
+    ensure!(
+        matches!(null.readat(0, &mut buf), Ok(0)),
+        "/dev/null read data"
+    );
+    ensure!(
+        matches!(null.writeat(1 << 40, &buf), Ok(0x1000)),
+        "/dev/null didn't take the write"
+    );
+    for device in MemDevice::ALL {
+        let node = MemNode::new(device);
+        let mut stat = Stat::default();
+        ok("stat", node.stat(&mut stat))?;
+        ensure!(
+            stat.mode.contains(StatMode::CHAR)
+                && stat.size == 0
+                && stat.rdev == make_dev(1, device.minor()) as _,
+            "stat of /dev/{}: {:?} size {} rdev {:#x}",
+            device.name(),
+            stat.mode,
+            stat.size,
+            stat.rdev
+        );
+        let events = ok("poll", node.poll(PollEvent::POLLIN | PollEvent::POLLOUT))?;
+        let ready = match device {
+            MemDevice::Random | MemDevice::Urandom if !random_seeded() => PollEvent::POLLOUT,
+            _ => PollEvent::POLLIN | PollEvent::POLLOUT,
+        };
+        ensure!(
+            events == ready,
+            "/dev/{} isn't ready: {:?}",
+            device.name(),
+            events
+        );
+    }
+    // a read of any size is filled in place.
+    let mut big = vec![0xa5; 4 << 20];
+    for device in [MemDevice::Zero, MemDevice::Full] {
+        big.fill(0xa5);
+        let node = MemNode::new(device);
+        ensure!(
+            matches!(node.readat(0, &mut big), Ok(n) if n == big.len())
+                && big.iter().all(|x| *x == 0),
+            "/dev/{} didn't read {} zeros",
+            device.name(),
+            big.len()
+        );
+    }
+    ensure_err!(
+        MemNode::new(MemDevice::Full).writeat(0, b"x"),
+        VfsError::StorageFull
+    );
+
+    // urandom doesn't return short reads.
+    let urandom = MemNode::new(MemDevice::Urandom);
+    if !random_seeded() {
+        ensure_err!(urandom.readat(0, &mut [0; 8]), VfsError::Blocking);
+        return Ok(());
+    }
+    for len in 1..=256 {
+        let mut buf = vec![0; len];
+        ensure!(
+            matches!(urandom.readat(0, &mut buf), Ok(n) if n == len),
+            "short read of {} bytes of /dev/urandom",
+            len
+        );
+    }
+    let (mut a, mut b) = ([0; 32], [0; 32]);
+    ok("read", urandom.readat(0, &mut a))?;
+    ok("read", urandom.readat(0, &mut b))?;
+    ensure!(a != b, "/dev/urandom read the same bytes twice");
+    Ok(())
+}
+
+/// The pseudo files of pseudo.rs: an open reads a source changing on
+/// every render in chunks from one snapshot, and a read at 0 takes a new
+/// one. A writable tunable parses its writes, a bad value fails with
+/// EINVAL and keeps the old one, a truncate does nothing and a read-only
+/// file refuses the writes.
+pub fn pseudo_files() -> Result<(), String> {
+    use crate::pseudo::{CallbackInode, SizeMode};
+
+    static RENDERS: AtomicUsize = AtomicUsize::new(0);
+    let render = || {
+        let n = RENDERS.fetch_add(1, Ordering::Relaxed);
+        (0..64)
+            .map(|_| format!("render {}\n", n))
+            .collect::<String>()
+            .into_bytes()
+    };
+    let node = CallbackInode::read_only(render, SizeMode::Snapshot);
+    let file: File = FileHandle::new(node.clone(), OpenFlags::O_RDONLY);
+    let mut content = Vec::new();
+    loop {
+        let mut chunk = [0; 7];
+        let len = ok("readat", file.readat(content.len(), &mut chunk))?;
+        if len == 0 {
+            break;
+        }
+        content.extend_from_slice(&chunk[..len]);
+    }
+    let line = content
+        .iter()
+        .position(|x| *x == b'\n')
+        .map_or(0, |x| x + 1);
+    let first = content[..line].to_vec();
+    ensure!(
+        line > 0 && content.len() == line * 64 && content.chunks(line).all(|x| x == first),
+        "the chunks are of several renders:\n{}",
+        String::from_utf8_lossy(&content)
+    );
+    let mut stat = Stat::default();
+    ok("stat", file.stat(&mut stat))?;
+    ensure!(
+        stat.size as usize == content.len(),
+        "the size is {}",
+        stat.size
+    );
+    ensure!(
+        read_all(&file, line)? != first,
+        "a read at 0 kept the old snapshot"
+    );
+    ensure!(
+        ok("poll", file.poll(PollEvent::POLLIN))? == PollEvent::POLLIN,
+        "the pseudo file isn't ready"
+    );
+    ensure_err!(node.writeat(0, b"1\n"), VfsError::NotSupported);
+    ensure_err!(node.truncate(0), VfsError::NotSupported);
+
+    static VALUE: AtomicUsize = AtomicUsize::new(10);
+    let tunable = CallbackInode::new(
+        || format!("{}\n", VALUE.load(Ordering::Relaxed)).into_bytes(),
+        Some(|buffer: &[u8]| -> VfsResult<()> {
+            let value = core::str::from_utf8(buffer)
+                .ok()
+                .and_then(|x| x.trim().parse().ok())
+                .ok_or(VfsError::InvalidInput)?;
+            VALUE.store(value, Ordering::Relaxed);
+            Ok(())
+        }),
+    );
+    let file: File = FileHandle::new(tunable, OpenFlags::O_RDWR);
+    ok("stat", file.stat(&mut stat))?;
+    ensure!(stat.size == 0, "the tunable has the size {}", stat.size);
+    ok("truncate", file.truncate(0))?;
+    ensure!(read_all(&file, 16)? == b"10\n", "the tunable isn't 10");
+    ok("writeat", file.writeat(0, b"42\n"))?;
+    ensure!(read_all(&file, 16)? == b"42\n", "the write isn't read back");
+    let err = file.writeat(0, b"forty-two\n").err();
+    ensure!(
+        err.is_some_and(|x| Errno::from(x) == Errno::EINVAL),
+        "the bad value isn't EINVAL"
+    );
+    ensure!(
+        VALUE.load(Ordering::Relaxed) == 42,
+        "the bad value changed the tunable"
+    );
+    Ok(())
+}
@@ -0,0 +1,744 @@
+// The cases of the threads of std: the freeze, the concurrent allocation,
+// the torn reads of the snapshots and the races of the creates and of the
+// directory snapshots.
+
+use super::*;
+
+/// Freeze ext4 under writers on four threads: the writes wait while it's
+/// frozen, the reads don't, the image passes check, and the thaw lets the
+/// writers finish without losing a byte.
+#[cfg(root_fs = "ext4_rs")]
+pub fn ext4_freeze() -> Result<(), String> {
+    use std::{thread, time::Duration};
+
+    use crate::freeze::{freeze, thaw};
+    use crate::pipe::set_wait_hook;
+
+    const WRITERS: usize = 4;
+    const CHUNK: usize = 0x1000;
+    const CHUNKS: usize = 64;
+
+    set_wait_hook(thread::yield_now);
+    let fs = ram_ext4(16 << 20, *b"ext4-freeze-test")?;
+    let as_fs = fs.clone() as Arc<dyn FileSystem>;
+    let root = fs.root();
+    let written = Arc::new(AtomicUsize::new(0));
+    let mut writers = Vec::new();
+    for i in 0..WRITERS {
+        let file = ok("touch", root.touch(&format!("w{}", i)))?;
+        let written = written.clone();
+        writers.push(thread::spawn(move || -> Result<(), String> {
+            let data = vec![b'a' + i as u8; CHUNK];
+            for n in 0..CHUNKS {
+                ok("writeat", file.writeat(n * CHUNK, &data))?;
+                written.fetch_add(1, Ordering::SeqCst);
+            }
+            ok("flush", file.flush())
+        }));
+    }
+    while written.load(Ordering::SeqCst) < 8 {
+        thread::yield_now();
+    }
+
+    ok("freeze", freeze(&as_fs))?;
+    ensure_errno!(freeze(&as_fs), Errno::EBUSY);
+    let frozen = written.load(Ordering::SeqCst);
+    let report = fs.check();
+    ensure!(
+        report.problems.is_empty(),
+        "the frozen image has problems: {:?}",
+        report.problems
+    );
+    let mut buf = [0; 16];
+    ok(
+        "read while frozen",
+        root.lookup("w0").and_then(|x| x.readat(0, &mut buf)),
+    )?;
+    thread::sleep(Duration::from_millis(20));
+    // a write out of the gate before the freeze may count after it.
+    let now = written.load(Ordering::SeqCst);
+    ensure!(
+        now <= frozen + WRITERS,
+        "{} writes went on while frozen",
+        now - frozen
+    );
+    ok("thaw", thaw(&as_fs))?;
+    ensure_err!(thaw(&as_fs), VfsError::InvalidInput);
+
+    for writer in writers {
+        writer
+            .join()
+            .map_err(|_| String::from("a writer panicked"))??;
+    }
+    for i in 0..WRITERS {
+        let data = read_all(
+            &ok("lookup", root.lookup(&format!("w{}", i)))?,
+            CHUNK * CHUNKS + 1,
+        )?;
+        ensure!(
+            data.len() == CHUNK * CHUNKS && data.iter().all(|x| *x == b'a' + i as u8),
+            "w{} has {} bytes after the thaw",
+            i,
+            data.len()
+        );
+    }
+    let report = fs.check();
+    ensure!(
+        report.problems.is_empty(),
+        "the thawed image has problems: {:?}",
+        report.problems
+    );
+    Ok(())
+}
+
+/// Allocate and free on eight threads, each growing, truncating and
+/// removing its files, while another reads statfs: the free counts stay
+/// in the range of the filesystem, and at the end those of statfs and of
+/// the superblock are the sums of the groups, which check recounts from
+/// the bitmaps. The last free blocks raced for by every thread go to as
+/// many writers as there are blocks. A superblock counting others is
+/// corrected from the groups.
+#[cfg(root_fs = "ext4_rs")]
+pub fn ext4_concurrent_alloc() -> Result<(), String> {
+    use std::{sync::atomic::AtomicBool, thread};
+
+    use crate::blockdev::BlockDevice;
+    use crate::ext4_csum::set_superblock_csum;
+    use crate::ext4_layout::{SuperBlockInfo, SUPERBLOCK_OFFSET};
+
+    const THREADS: usize = 8;
+    const ROUNDS: usize = 24;
+    const BLOCK: usize = 4096;
+
+    let statfs = |fs: &crate::Ext4FileSystem| -> Result<StatFS, String> {
+        let mut statfs = StatFS::default();
+        ok("statfs", fs.root().statfs(&mut statfs))?;
+        Ok(statfs)
+    };
+    let clean = |fs: &crate::Ext4FileSystem, when: &str| -> Result<(), String> {
+        ok("flush", FileSystem::flush(fs))?;
+        let report = fs.check();
+        ensure!(
+            report.problems.is_empty(),
+            "the image has problems {}: {:?}",
+            when,
+            report.problems
+        );
+        Ok(())
+    };
+    // the free counts of the superblock on the disk.
+    let on_disk = |device: &dyn BlockDevice| {
+        let sb = SuperBlockInfo::parse(&device.read_offset(SUPERBLOCK_OFFSET)[..1024]);
+        (sb.free_blocks_count, sb.free_inodes_count as u64)
+    };
+
+    let options = crate::ext4_mkfs::Options {
+        uuid: *b"ext4-alloc-race!",
+        ..Default::default()
+    };
+    let (_, device) = ram_ext4_image(16 << 20, &options)?;
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::new_from_device(device.clone()),
+    )?;
+    let before = statfs(&fs)?;
+    let done = Arc::new(AtomicBool::new(false));
+    let sampler = {
+        let (root, done) = (fs.root(), done.clone());
+        let (blocks, files) = (before.blocks, before.files);
+        thread::spawn(move || -> Result<usize, String> {
+            let mut samples = 0;
+            while !done.load(Ordering::SeqCst) {
+                let mut statfs = StatFS::default();
+                ok("statfs", root.statfs(&mut statfs))?;
+                ensure!(
+                    statfs.bfree <= blocks
+                        && statfs.bavail <= statfs.bfree
+                        && statfs.ffree <= files,
+                    "statfs counts {} free blocks of {} and {} free inodes of {}",
+                    statfs.bfree,
+                    blocks,
+                    statfs.ffree,
+                    files
+                );
+                samples += 1;
+                thread::yield_now();
+            }
+            Ok(samples)
+        })
+    };
+    let workers: Vec<_> = (0..THREADS)
+        .map(|i| {
+            let root = fs.root();
+            thread::spawn(move || -> Result<(), String> {
+                let dir = ok("mkdir", root.mkdir(&format!("t{}", i)))?;
+                for round in 0..ROUNDS {
+                    let name = format!("f{}", round % 4);
+                    let file = match dir.lookup(&name) {
+                        Ok(file) => file,
+                        Err(_) => ok("touch", dir.touch(&name))?,
+                    };
+                    let len = ((i * 7 + round * 13) % 16 + 1) * BLOCK;
+                    let data = vec![i as u8; len];
+                    ok("writeat", file.writeat(round % 4 * BLOCK, &data))?;
+                    match round % 3 {
+                        0 => ok("truncate", file.truncate(len / 2))?,
+                        1 => {
+                            drop(file);
+                            ok("remove", dir.remove(&name))?;
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker
+            .join()
+            .map_err(|_| String::from("a writer panicked"))??;
+    }
+    done.store(true, Ordering::SeqCst);
+    let samples = sampler
+        .join()
+        .map_err(|_| String::from("the sampler panicked"))??;
+    ensure!(samples > 0, "statfs wasn't sampled");
+    clean(&fs, "after the threads")?;
+    let after = statfs(&fs)?;
+    ensure!(
+        on_disk(device.as_ref()) == (after.bfree as u64, after.ffree as u64),
+        "the superblock counts {:?}, statfs {} and {}",
+        on_disk(device.as_ref()),
+        after.bfree,
+        after.ffree
+    );
+
+    // the last free blocks, one for each writer while they last.
+    let root = fs.root();
+    let files = (0..THREADS)
+        .map(|i| ok("touch", root.touch(&format!("last{}", i))))
+        .collect::<Result<Vec<_>, _>>()?;
+    let full = ok("touch", root.touch("full"))?;
+    let data = vec![0x5a; 32 << 20];
+    let written = ok("write", full.writeat(0, &data))?;
+    ok(
+        "truncate",
+        full.truncate((written / BLOCK).saturating_sub(THREADS / 2) * BLOCK),
+    )?;
+    let free = statfs(&fs)?.bfree as usize;
+    let writers: Vec<_> = files
+        .into_iter()
+        .map(|file| thread::spawn(move || file.writeat(0, &[1; BLOCK])))
+        .collect();
+    let mut wins = 0;
+    for writer in writers {
+        match writer.join() {
+            Ok(Ok(BLOCK)) => wins += 1,
+            Ok(Err(VfsError::StorageFull)) => {}
+            r => return Err(format!("a writer of the last blocks got {:?}", r)),
+        }
+    }
+    ensure!(
+        wins == free.min(THREADS) && statfs(&fs)?.bfree as usize == free - wins,
+        "{} writers got the {} last blocks, {} are left",
+        wins,
+        free,
+        statfs(&fs)?.bfree
+    );
+    clean(&fs, "once full")?;
+    let full_counts = statfs(&fs)?;
+    drop((root, full, fs));
+
+    // a superblock counting others, the groups count.
+    let mut sb = device.read_offset(SUPERBLOCK_OFFSET)[..1024].to_vec();
+    sb[0xC..0x10].copy_from_slice(&7u32.to_le_bytes());
+    sb[0x10..0x14].copy_from_slice(&3u32.to_le_bytes());
+    set_superblock_csum(&mut sb);
+    device.write_offset(SUPERBLOCK_OFFSET, &sb);
+    let fs = ok(
+        "mount",
+        crate::Ext4FileSystem::new_from_device(device.clone()),
+    )?;
+    let counted = statfs(&fs)?;
+    ensure!(
+        (counted.bfree, counted.ffree) == (full_counts.bfree, full_counts.ffree),
+        "statfs counts {} free blocks and {} free inodes after the mount",
+        counted.bfree,
+        counted.ffree
+    );
+    ok("remove", fs.root().remove("full"))?;
+    clean(&fs, "after the remount")?;
+    let counted = statfs(&fs)?;
+    ensure!(
+        on_disk(device.as_ref()) == (counted.bfree as u64, counted.ffree as u64),
+        "the superblock still counts {:?}",
+        on_disk(device.as_ref())
+    );
+    Ok(())
+}
+
+/// Publish snapshots with every field set to the same counter on a thread
+/// while two others load them: a loaded snapshot has the fields of one
+/// publication, and the counter never goes back.
+pub fn snapshot_torn_reads() -> Result<(), String> {
+    use std::thread;
+
+    use vfscore::TimeSpec;
+
+    use crate::snapshot::{Meta, Snapshot};
+
+    const ROUNDS: u64 = 100_000;
+    let snapshot = Arc::new(Snapshot::new());
+    ensure!(snapshot.load().is_none(), "a new snapshot has metadata");
+    let meta = |i: u64| {
+        let time = TimeSpec {
+            sec: i as _,
+            nsec: (i % 1_000_000_000) as _,
+        };
+        Meta {
+            size: i,
+            blocks: i,
+            mode: i as u32,
+            nlink: i as u32,
+            uid: i as u32,
+            gid: i as u32,
+            atime: time,
+            mtime: time,
+            ctime: time,
+            rdev: i,
+        }
+    };
+    let readers: Vec<_> = (0..2)
+        .map(|_| {
+            let snapshot = snapshot.clone();
+            thread::spawn(move || -> Result<(), String> {
+                let mut last = 0;
+                while last < ROUNDS {
+                    let Some(meta) = snapshot.load() else {
+                        continue;
+                    };
+                    let i = meta.size;
+                    let fields = [
+                        meta.blocks,
+                        meta.mode as u64,
+                        meta.nlink as u64,
+                        meta.uid as u64,
+                        meta.gid as u64,
+                        meta.atime.sec as u64,
+                        meta.mtime.sec as u64,
+                        meta.ctime.sec as u64,
+                        meta.ctime.nsec as u64,
+                        meta.rdev,
+                    ];
+                    ensure!(
+                        fields.iter().all(|x| *x == i),
+                        "a torn snapshot of {}: {:?}",
+                        i,
+                        fields
+                    );
+                    ensure!(i >= last, "the snapshot went back from {} to {}", last, i);
+                    last = i;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for i in 1..=ROUNDS {
+        snapshot.publish(&meta(i));
+    }
+    for reader in readers {
+        reader
+            .join()
+            .map_err(|_| String::from("a reader panicked"))??;
+    }
+    ensure!(
+        snapshot.publications() == ROUNDS,
+        "{} publications, not {}",
+        snapshot.publications(),
+        ROUNDS
+    );
+    Ok(())
+}
+
+/// stat of ext4 reads the snapshot of the inode: a stat on another thread
+/// runs while a long write holds the file, and sees a size the write
+/// committed before, short of the whole write. The times set together by
+/// utimes on a thread are always seen together, never the atime of one
+/// call with the mtime of another.
+#[cfg(root_fs = "ext4_rs")]
+pub fn ext4_stat_snapshots() -> Result<(), String> {
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use vfscore::TimeSpec;
+
+    const LEN: usize = 4 << 20;
+    const ROUNDS: u64 = 2000;
+    let fs = ram_ext4(16 << 20, *b"ext4-stat-snaps!")?;
+    let root = fs.root();
+    let big = ok("touch", root.touch("big"))?;
+    let pair = ok("touch", root.touch("pair"))?;
+    let mut stat = Stat::default();
+
+    let holding = Arc::new(AtomicBool::new(false));
+    let statted = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let (big, holding, statted) = (big.clone(), holding.clone(), statted.clone());
+        thread::spawn(move || -> (VfsResult<usize>, bool) {
+            // the cancellation is checked with the file held, the first
+            // check waits for the stat.
+            let timed_out = AtomicBool::new(false);
+            let cancelled = || {
+                holding.store(true, Ordering::SeqCst);
+                let start = Instant::now();
+                while !statted.load(Ordering::SeqCst) {
+                    if start.elapsed() > Duration::from_secs(2) {
+                        timed_out.store(true, Ordering::SeqCst);
+                        break;
+                    }
+                    thread::yield_now();
+                }
+                false
+            };
+            let r = crate::cancel::write_at(&big, 0, &vec![7; LEN], &cancelled);
+            (r, timed_out.load(Ordering::SeqCst))
+        })
+    };
+    let start = Instant::now();
+    while !holding.load(Ordering::SeqCst) && start.elapsed() < Duration::from_secs(2) {
+        thread::yield_now();
+    }
+    ok("stat", big.stat(&mut stat))?;
+    let during = stat.size as usize;
+    statted.store(true, Ordering::SeqCst);
+    let (written, timed_out) = writer
+        .join()
+        .map_err(|_| String::from("the writer panicked"))?;
+    ensure!(!timed_out, "the stat waited for the write");
+    ensure!(ok("write", written)? == LEN, "the write was short");
+    ensure!(
+        during < LEN,
+        "the stat during the write saw {} bytes",
+        during
+    );
+    ok("stat", big.stat(&mut stat))?;
+    ensure!(
+        stat.size as usize == LEN,
+        "the file has {} bytes",
+        stat.size
+    );
+
+    let setter = {
+        let pair = pair.clone();
+        thread::spawn(move || -> VfsResult<()> {
+            for sec in 1..=ROUNDS {
+                let time = TimeSpec {
+                    sec: sec as _,
+                    nsec: 0,
+                };
+                pair.utimes(&mut [time, time])?;
+            }
+            Ok(())
+        })
+    };
+    let mut last = 0;
+    while last < ROUNDS && !setter.is_finished() {
+        ok("stat", pair.stat(&mut stat))?;
+        let (atime, mtime) = (stat.atime.sec as u64, stat.mtime.sec as u64);
+        // the times of the creation are the clock, past the rounds.
+        if atime.min(mtime) > ROUNDS {
+            continue;
+        }
+        ensure!(
+            atime == mtime,
+            "a torn snapshot: the atime {} with the mtime {}",
+            atime,
+            mtime
+        );
+        ensure!(
+            mtime >= last,
+            "the mtime went back from {} to {}",
+            last,
+            mtime
+        );
+        last = mtime;
+    }
+    setter
+        .join()
+        .map_err(|_| String::from("the setter panicked"))?
+        .map_err(|x| format!("utimes: {:?}", x))
+}
+
+/// Two threads create the same name in an ext4 directory at once: with
+/// O_EXCL one creates it and the other fails with AlreadyExists, with a
+/// plain O_CREAT both open the inode one of them created. The directory
+/// has one entry of the name after every round.
+#[cfg(root_fs = "ext4_rs")]
+pub fn ext4_create_race() -> Result<(), String> {
+    use std::sync::Barrier;
+    use std::thread;
+
+    const ROUNDS: usize = 2000;
+    let fs = ram_ext4(16 << 20, *b"ext4-create-race")?;
+    let root = fs.root();
+    let barrier = Arc::new(Barrier::new(2));
+    let create = OpenFlags::O_RDWR | OpenFlags::O_CREAT;
+    let ino = |file: &File| -> Result<u64, String> {
+        let mut stat = Stat::default();
+        ok("stat", file.stat(&mut stat))?;
+        Ok(stat.ino)
+    };
+    for round in 0..ROUNDS {
+        let flags = match round % 2 {
+            0 => create | OpenFlags::O_EXCL,
+            _ => create,
+        };
+        let opens: Vec<_> = (0..2)
+            .map(|_| {
+                let (root, barrier) = (root.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    root.open("race", flags)
+                })
+            })
+            .collect();
+        let mut results = Vec::new();
+        for open in opens {
+            results.push(open.join().map_err(|_| String::from("an open panicked"))?);
+        }
+        let opened: Vec<_> = results.iter().filter_map(|x| x.as_ref().ok()).collect();
+        if flags.contains(OpenFlags::O_EXCL) {
+            ensure!(
+                opened.len() == 1
+                    && results
+                        .iter()
+                        .any(|x| matches!(x, Err(VfsError::AlreadyExists))),
+                "round {}: the exclusive creates returned {:?}",
+                round,
+                results.iter().map(|x| x.as_ref().err()).collect::<Vec<_>>()
+            );
+        } else {
+            ensure!(
+                opened.len() == 2 && ino(opened[0])? == ino(opened[1])?,
+                "round {}: the creates opened different files",
+                round
+            );
+        }
+        let entries = names(&root)?;
+        let count = entries.iter().filter(|x| *x == "race").count();
+        ensure!(count == 1, "round {}: {} entries of the name", round, count);
+        ok("remove", root.remove("race"))?;
+    }
+    let problems = fs.check().problems;
+    ensure!(
+        problems.is_empty(),
+        "problems after the races {:?}",
+        problems
+    );
+    Ok(())
+}
+
+/// Stress ext4 from six threads sharing four directories: each creates,
+/// writes, reads and unlinks the same names in all of them. A file only
+/// holds the byte of its name, so a read sees it whole or cut short by a
+/// racing truncate, never another file. The races only lose the name to
+/// another thread, nothing panics, and the image passes check.
+#[cfg(root_fs = "ext4_rs")]
+pub fn ext4_concurrent_dirs() -> Result<(), String> {
+    use std::thread;
+
+    const THREADS: usize = 6;
+    const DIRS: usize = 4;
+    const NAMES: usize = 12;
+    const ROUNDS: usize = 400;
+    let fs = ram_ext4(32 << 20, *b"ext4-thread-dirs")?;
+    let dirs = (0..DIRS)
+        .map(|d| ok("mkdir", fs.root().mkdir(&format!("d{}", d))))
+        .collect::<Result<Vec<_>, _>>()?;
+    let dirs = Arc::new(dirs);
+    let byte = |name: usize| b'a' + name as u8;
+    // a race lost to another thread of the same name.
+    let lost = |err: &VfsError| matches!(err, VfsError::FileNotFound | VfsError::AlreadyExists);
+    let workers: Vec<_> = (0..THREADS)
+        .map(|i| {
+            let dirs = dirs.clone();
+            thread::spawn(move || -> Result<(), String> {
+                let create = OpenFlags::O_RDWR | OpenFlags::O_CREAT;
+                let mut buf = vec![0; 3 << 12];
+                for round in 0..ROUNDS {
+                    let dir = &dirs[(i + round) % DIRS];
+                    let n = (i * 5 + round * 7) % NAMES;
+                    let name = format!("n{}", n);
+                    match (round + i) % 4 {
+                        0 | 1 => {
+                            let file = match dir.open(&name, create) {
+                                Ok(file) => file,
+                                Err(err) if lost(&err) => continue,
+                                Err(err) => return Err(format!("open {}: {:?}", name, err)),
+                            };
+                            let len = (round % 3 + 1) << 12;
+                            ok("writeat", file.writeat(0, &vec![byte(n); len]))?;
+                        }
+                        2 => {
+                            let file = match dir.lookup(&name) {
+                                Ok(file) => file,
+                                Err(err) if lost(&err) => continue,
+                                Err(err) => return Err(format!("lookup {}: {:?}", name, err)),
+                            };
+                            let read = ok("readat", file.readat(0, &mut buf))?;
+                            ensure!(
+                                buf[..read].iter().all(|&x| x == byte(n)),
+                                "{} read bytes of another file",
+                                name
+                            );
+                        }
+                        _ => match dir.remove(&name) {
+                            Ok(()) => {}
+                            Err(err) if lost(&err) => {}
+                            Err(err) => return Err(format!("remove {}: {:?}", name, err)),
+                        },
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker
+            .join()
+            .map_err(|_| String::from("a worker panicked"))??;
+    }
+    for dir in dirs.iter() {
+        for name in names(dir)? {
+            if name == "." || name == ".." {
+                continue;
+            }
+            let n: usize = name[1..]
+                .parse()
+                .map_err(|_| format!("the entry {}", name))?;
+            let file = ok("lookup", dir.lookup(&name))?;
+            let data = read_all(&file, 3 << 12)?;
+            ensure!(
+                data.iter().all(|&x| x == byte(n)),
+                "{} holds bytes of another file",
+                name
+            );
+        }
+    }
+    ok("flush", FileSystem::flush(fs.as_ref()))?;
+    let problems = fs.check().problems;
+    ensure!(
+        problems.is_empty(),
+        "problems after the threads {:?}",
+        problems
+    );
+    Ok(())
+}
+
+/// Snapshot a directory while a thread renames its entries back and
+/// forth and creates and removes a file: every snapshot has each pair
+/// once, by its inode, and the file once at most.
+fn snapshot_race(root: &File) -> CaseResult {
+    use alloc::collections::BTreeMap;
+    use core::sync::atomic::AtomicBool;
+    use std::thread;
+
+    use crate::ops::dir_iter_snapshot;
+    use crate::rename::{rename, RenameFlags};
+
+    const PAIRS: usize = 600;
+    let root = &ok("mkdir", root.mkdir("snapshot"))?;
+    for i in 0..PAIRS {
+        ok("touch", root.touch(&format!("a{}", i)))?;
+    }
+    let mut inos = BTreeMap::new();
+    for entry in ok("snapshot", dir_iter_snapshot(root))? {
+        let entry = ok("next", entry)?;
+        inos.insert(String::from(&entry.name[1..]), entry.ino);
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    let mutator = {
+        let (root, stop) = (root.clone(), stop.clone());
+        thread::spawn(move || -> VfsResult<()> {
+            let mut round = 0;
+            while !stop.load(Ordering::Relaxed) {
+                let i = round % PAIRS;
+                let (old, new) = match (round / PAIRS) % 2 {
+                    0 => (format!("a{}", i), format!("b{}", i)),
+                    _ => (format!("b{}", i), format!("a{}", i)),
+                };
+                rename(&root, &old, &root, &new, RenameFlags::NONE)?;
+                match round % 2 {
+                    0 => root.touch("extra").map(|_| ())?,
+                    _ => root.remove("extra")?,
+                }
+                round += 1;
+            }
+            Ok(())
+        })
+    };
+    let mut checked = Ok(());
+    for _ in 0..50 {
+        let mut seen: BTreeMap<String, u64> = BTreeMap::new();
+        let mut extra = 0;
+        let mut listed = || -> CaseResult {
+            for entry in ok("snapshot", dir_iter_snapshot(root))? {
+                let entry = ok("next", entry)?;
+                if entry.name == "extra" {
+                    extra += 1;
+                    continue;
+                }
+                let pair = String::from(&entry.name[1..]);
+                ensure!(
+                    seen.insert(pair, entry.ino).is_none(),
+                    "{} is in the snapshot with its pair",
+                    entry.name
+                );
+            }
+            Ok(())
+        };
+        checked = listed().and_then(|_| {
+            ensure!(extra <= 1, "extra is listed {} times", extra);
+            ensure!(
+                seen == inos,
+                "a snapshot has {} pairs of {}",
+                seen.len(),
+                PAIRS
+            );
+            Ok(())
+        });
+        if checked.is_err() {
+            break;
+        }
+    }
+    stop.store(true, Ordering::Relaxed);
+    let mutated = mutator
+        .join()
+        .map_err(|_| String::from("the mutator panicked"))?;
+    ok("mutate", mutated)?;
+    checked
+}
+
+/// snapshot_race on tmpfs.
+pub fn tmpfs_dir_snapshot_race() -> Result<(), String> {
+    use crate::tmpfs::TmpFs;
+
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>));
+    snapshot_race(&fs.root_dir())
+}
+
+/// snapshot_race on ext4, whose volume holds off the renames.
+#[cfg(root_fs = "ext4_rs")]
+pub fn ext4_dir_snapshot_race() -> Result<(), String> {
+    let fs = ram_ext4(16 << 20, *b"ext4-snapshot-rc")?;
+    snapshot_race(&fs.root())?;
+    let problems = fs.check().problems;
+    ensure!(
+        problems.is_empty(),
+        "problems after the snapshots {:?}",
+        problems
+    );
+    Ok(())
+}
@@ -0,0 +1,346 @@
+// The cases of the renames and their flags on ext4 and tmpfs, and of the
+// directory loops.
+
+use super::*;
+
+/// Rename the files and the directories of ext4 by renameat: a directory
+/// can't move under itself, a moved one has ".." of its new parent and
+/// the links of both parents follow it, a directory replaces an empty
+/// one but not one with entries, and a file replaces a file.
+#[cfg(root_fs = "ext4_rs")]
+pub fn ext4_rename() -> Result<(), String> {
+    use crate::dentry::{DentryNode, ResolveContext};
+    use crate::ops::renameat;
+
+    let fs = ram_ext4(8 << 20, *b"ext4-rename-test")?;
+    let root = fs.root();
+    let ctx = ResolveContext::with_root(Arc::new(DentryNode::new(
+        String::from("/"),
+        root.clone(),
+        alloc::sync::Weak::new(),
+    )));
+    let stat = |node: &File| -> Result<Stat, String> {
+        let mut stat = Stat::default();
+        ok("stat", node.stat(&mut stat))?;
+        Ok(stat)
+    };
+    let a = ok("mkdir", root.mkdir("a"))?;
+    let b = ok("mkdir", a.mkdir("b"))?;
+    ok("mkdir", b.mkdir("c"))?;
+    let d = ok("mkdir", root.mkdir("d"))?;
+    ok("write", ok("touch", b.touch("file"))?.writeat(0, b"moved"))?;
+
+    ensure_errno!(renameat(&ctx, "/a", &ctx, "/a/b/c/a"), Errno::EINVAL);
+    ensure_errno!(renameat(&ctx, "/a/b", &ctx, "/a/b/b"), Errno::EINVAL);
+    ensure!(stat(&a)?.nlink == 3, "a has {} links", stat(&a)?.nlink);
+    ensure!(
+        stat(&a)?.ino != stat(&d)?.ino,
+        "a and d have the inode {}",
+        stat(&a)?.ino
+    );
+
+    ok("rename", renameat(&ctx, "/a/b", &ctx, "/d/b"))?;
+    ensure_err!(a.lookup("b"), VfsError::FileNotFound);
+    let moved = ok("lookup", d.lookup("b"))?;
+    let parent = ok("lookup", moved.lookup(".."))?;
+    ensure!(
+        stat(&parent)?.ino == stat(&d)?.ino,
+        "b/.. is the inode {} after the move",
+        stat(&parent)?.ino
+    );
+    ensure!(stat(&a)?.nlink == 2, "a has {} links", stat(&a)?.nlink);
+    ensure!(stat(&d)?.nlink == 3, "d has {} links", stat(&d)?.nlink);
+    let file = ok(
+        "open",
+        crate::dentry::dentry_open_at(&ctx, "/d/b/file", OpenFlags::NONE),
+    )?;
+    ensure!(
+        read_all(&file.node, 8)? == b"moved",
+        "the moved file differs"
+    );
+    ensure!(
+        crate::dentry::dentry_open_at(&ctx, "/a/b", OpenFlags::NONE).is_err(),
+        "the old path is still cached"
+    );
+
+    // a directory over an empty one and over a full one.
+    ok("mkdir", root.mkdir("e"))?;
+    let root_links = stat(&root)?.nlink;
+    ok("rename over empty", renameat(&ctx, "/d/b", &ctx, "/e"))?;
+    ensure!(
+        stat(&root)?.nlink == root_links,
+        "the root has {} links, not {}",
+        stat(&root)?.nlink,
+        root_links
+    );
+    ensure!(stat(&d)?.nlink == 2, "d has {} links", stat(&d)?.nlink);
+    let e = ok("lookup", root.lookup("e"))?;
+    ok("lookup", e.lookup("c"))?;
+    let f = ok("mkdir", root.mkdir("f"))?;
+    ok("touch", f.touch("x"))?;
+    ensure_errno!(renameat(&ctx, "/e", &ctx, "/f"), Errno::ENOTEMPTY);
+    ensure_errno!(renameat(&ctx, "/e", &ctx, "/f/x"), Errno::ENOTDIR);
+
+    // a file over a file, and over itself.
+    ok("write", ok("touch", root.touch("g"))?.writeat(0, b"old"))?;
+    ok("rename a file", renameat(&ctx, "/e/file", &ctx, "/g"))?;
+    let g = ok("lookup", root.lookup("g"))?;
+    ensure!(read_all(&g, 8)? == b"moved", "g wasn't replaced");
+    ensure!(stat(&g)?.nlink == 1, "g has {} links", stat(&g)?.nlink);
+    ok("rename to itself", renameat(&ctx, "/g", &ctx, "/g"))?;
+    ensure_errno!(renameat(&ctx, "/g", &ctx, "/f"), Errno::EISDIR);
+
+    let report = fs.check();
+    ensure!(
+        report.problems.is_empty(),
+        "the image has problems: {:?}",
+        report.problems
+    );
+    Ok(())
+}
+
+/// The flags of rename on the directory root: NOREPLACE fails over an
+/// existing entry and moves to a missing one, EXCHANGE swaps two files,
+/// then a directory and a file of two directories, whose ".." follows,
+/// and fails with a missing entry or under the exchanged directory.
+/// Return the directories sub and the exchanged one.
+fn rename_flags_on(root: &File) -> Result<(File, File), String> {
+    use crate::rename::{rename, RenameFlags};
+    use crate::walk::identity;
+
+    let (noreplace, exchange) = (RenameFlags::NOREPLACE, RenameFlags::EXCHANGE);
+    let content = |dir: &File, name: &str| read_all(&ok("lookup", dir.lookup(name))?, 16);
+    for (name, data) in [("a", b"file a"), ("b", b"file b")] {
+        ok("write", ok("touch", root.touch(name))?.writeat(0, data))?;
+    }
+    let d = ok("mkdir", root.mkdir("d"))?;
+    ok("touch", d.touch("inner"))?;
+    let sub = ok("mkdir", root.mkdir("sub"))?;
+    ok("write", ok("touch", sub.touch("f"))?.writeat(0, b"file f"))?;
+
+    ensure_errno!(rename(root, "a", root, "b", noreplace), Errno::EEXIST);
+    ensure_errno!(rename(root, "a", root, "a", noreplace), Errno::EEXIST);
+    ensure!(content(root, "b")? == b"file b", "NOREPLACE replaced b");
+    ok(
+        "rename to a new name",
+        rename(root, "a", root, "c", noreplace),
+    )?;
+    ensure_err!(root.lookup("a"), VfsError::FileNotFound);
+
+    ok("exchange two files", rename(root, "c", root, "b", exchange))?;
+    ensure!(
+        content(root, "c")? == b"file b" && content(root, "b")? == b"file a",
+        "the files weren't exchanged"
+    );
+    ensure_errno!(rename(root, "b", root, "missing", exchange), Errno::ENOENT);
+    ensure_errno!(rename(root, "missing", root, "b", exchange), Errno::ENOENT);
+
+    ok(
+        "exchange a directory and a file",
+        rename(root, "d", &sub, "f", exchange),
+    )?;
+    ensure!(content(root, "d")? == b"file f", "d isn't the file f");
+    let moved = ok("lookup", sub.lookup("f"))?;
+    ensure!(
+        matches!(
+            ok("metadata", moved.metadata())?.file_type,
+            FileType::Directory
+        ),
+        "sub/f isn't a directory"
+    );
+    ok("lookup", moved.lookup("inner"))?;
+    let parent = ok("lookup", moved.lookup(".."))?;
+    ensure!(
+        identity(parent.as_ref()) == identity(sub.as_ref()),
+        "sub/f/.. isn't sub after the exchange"
+    );
+    ensure_errno!(rename(&sub, "f", &moved, "inner", exchange), Errno::EINVAL);
+    ensure_err!(RenameFlags::from_bits(3), VfsError::InvalidInput);
+    ensure_err!(RenameFlags::from_bits(4), VfsError::InvalidInput);
+    Ok((sub, moved))
+}
+
+/// The flags of rename on ext4, see rename_flags_on: the links of ".."
+/// move with the exchanged directory, the image passes check, and an
+/// exchange by renameat2 drops the cached dentries of both names.
+#[cfg(root_fs = "ext4_rs")]
+pub fn ext4_rename_flags() -> Result<(), String> {
+    use crate::dentry::{dentry_open_at, DentryNode, ResolveContext};
+    use crate::ops::renameat2;
+    use crate::rename::RenameFlags;
+
+    let fs = ram_ext4(8 << 20, *b"ext4-rename-flag")?;
+    let root = fs.root();
+    let nlink = |node: &File| -> Result<u32, String> {
+        let mut stat = Stat::default();
+        ok("stat", node.stat(&mut stat))?;
+        Ok(stat.nlink as u32)
+    };
+    let (sub, moved) = rename_flags_on(&root)?;
+    ensure!(nlink(&root)? == 3, "the root has {} links", nlink(&root)?);
+    ensure!(nlink(&sub)? == 3, "sub has {} links", nlink(&sub)?);
+    ensure!(nlink(&moved)? == 2, "sub/f has {} links", nlink(&moved)?);
+
+    let ctx = ResolveContext::with_root(Arc::new(DentryNode::new(
+        String::from("/"),
+        root.clone(),
+        alloc::sync::Weak::new(),
+    )));
+    ok("open", dentry_open_at(&ctx, "/b", OpenFlags::NONE))?;
+    ok("open", dentry_open_at(&ctx, "/c", OpenFlags::NONE))?;
+    ok(
+        "exchange",
+        renameat2(&ctx, "/b", &ctx, "/c", RenameFlags::EXCHANGE),
+    )?;
+    for (path, data) in [("/b", b"file b"), ("/c", b"file a")] {
+        let file = ok("open", dentry_open_at(&ctx, path, OpenFlags::NONE))?;
+        ensure!(
+            read_all(&file.node, 16)? == data,
+            "{} is still cached",
+            path
+        );
+    }
+    ensure_errno!(
+        renameat2(&ctx, "/b", &ctx, "/c", RenameFlags::NOREPLACE),
+        Errno::EEXIST
+    );
+
+    let report = fs.check();
+    ensure!(
+        report.problems.is_empty(),
+        "the image has problems: {:?}",
+        report.problems
+    );
+    Ok(())
+}
+
+/// A directory loop in tmpfs, a/b/up linked back to a like a corrupted
+/// image: the walk, disk_usage, copy_recursive and remove_dir_all end
+/// with InvalidInput at the loop, and the dentry resolution through it
+/// stops at the max depth, which a context can raise.
+pub fn tmpfs_dir_loops() -> Result<(), String> {
+    use crate::dentry::{dentry_open_at, DentryNode, ResolveContext, DEFAULT_MAX_PATH_DEPTH};
+    use crate::ops::{copy_recursive, CopyOptions};
+    use crate::tmpfs::TmpFs;
+
+    let tmp = TmpFs::new();
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(tmp.clone() as Arc<dyn FileSystem>));
+    let root = fs.root_dir();
+    let a = ok("mkdir", root.mkdir("a"))?;
+    ok("touch", ok("mkdir", a.mkdir("b"))?.touch("file"))?;
+    ok("link", tmp.link_dir("a/b", "up", "a"))?;
+
+    let walked: Vec<_> = WalkDir::new(root.clone()).into_iter().take(64).collect();
+    let errors: Vec<_> = walked.iter().filter_map(|x| x.as_ref().err()).collect();
+    ensure!(
+        walked.len() == 4
+            && errors.len() == 1
+            && errors[0].path == "a/b/up"
+            && errors[0].error.errno == Errno::ELOOP,
+        "the walk of the loop yielded {} entries, errors {:?}",
+        walked.len(),
+        errors
+    );
+    ensure_errno!(disk_usage(root.clone(), false), Errno::ELOOP);
+    let copy = Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>)).root_dir();
+    ensure_err!(
+        copy_recursive(a.clone(), copy.clone(), CopyOptions::default()),
+        VfsError::InvalidInput
+    );
+    ok(
+        "lookup the copy",
+        copy.lookup("b").and_then(|x| x.lookup("file")),
+    )?;
+
+    let dentry_root = Arc::new(DentryNode::new(
+        String::from("/"),
+        root.clone(),
+        alloc::sync::Weak::new(),
+    ));
+    let ctx = ResolveContext::with_root(dentry_root);
+    // a, then b/up again and again, a component deeper each.
+    let path = |turns: usize| format!("a{}", "/b/up".repeat(turns));
+    let deepest = format!("{}/b", path((DEFAULT_MAX_PATH_DEPTH - 2) / 2));
+    ok(
+        "open at the max depth",
+        dentry_open_at(&ctx, &deepest, OpenFlags::NONE),
+    )?;
+    let deeper = path(DEFAULT_MAX_PATH_DEPTH / 2);
+    ensure_errno!(
+        dentry_open_at(&ctx, &deeper, OpenFlags::NONE),
+        Errno::ENAMETOOLONG
+    );
+    ok(
+        "open with a deeper max",
+        dentry_open_at(&ctx.clone().with_max_depth(128), &deeper, OpenFlags::NONE),
+    )?;
+    // ".." climbs back, the depth is of the dentry and not of the path.
+    let back = format!("{}/{}", deepest, "../".repeat(DEFAULT_MAX_PATH_DEPTH));
+    ok(
+        "open back",
+        dentry_open_at(&ctx, &format!("{}a/b", back), OpenFlags::NONE),
+    )?;
+
+    ensure_errno!(remove_dir_all(root.clone(), "a"), Errno::EINVAL);
+    ensure_err!(
+        a.lookup("b").and_then(|x| x.lookup("file")),
+        VfsError::FileNotFound
+    );
+    Ok(())
+}
+
+/// The flags of rename on tmpfs, see rename_flags_on.
+pub fn tmpfs_rename_flags() -> Result<(), String> {
+    use crate::tmpfs::TmpFs;
+
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>));
+    rename_flags_on(&fs.root_dir()).map(|_| ())
+}
+
+/// A rename with NOREPLACE racing a creator of its target on tmpfs: in
+/// every round exactly one of them makes the target, and the target is
+/// the file of the winner.
+#[cfg(feature = "std")]
+pub fn tmpfs_rename_noreplace_race() -> Result<(), String> {
+    use std::thread;
+
+    use crate::rename::{rename, RenameFlags};
+    use crate::tmpfs::TmpFs;
+
+    const ROUNDS: usize = 200;
+    let fs: &'static Arc<dyn FileSystem> = Box::leak(Box::new(TmpFs::new() as Arc<dyn FileSystem>));
+    let root = fs.root_dir();
+    for round in 0..ROUNDS {
+        ok(
+            "write",
+            ok("touch", root.touch("source"))?.writeat(0, b"renamed"),
+        )?;
+        let creator = {
+            let root = root.clone();
+            thread::spawn(move || root.touch("target").map(|_| ()))
+        };
+        let renamed = rename(&root, "source", &root, "target", RenameFlags::NOREPLACE)
+            .map_err(VfsError::from);
+        let created = creator
+            .join()
+            .map_err(|_| String::from("the creator panicked"))?;
+        let data = read_all(&ok("lookup", root.lookup("target"))?, 16)?;
+        match (renamed, created) {
+            (Ok(()), Err(VfsError::AlreadyExists)) => {
+                ensure!(data == b"renamed", "round {}: the target is empty", round)
+            }
+            (Err(VfsError::AlreadyExists), Ok(())) => {
+                ensure!(data.is_empty(), "round {}: the target was replaced", round);
+                ok("remove", root.remove("source"))?;
+            }
+            (renamed, created) => {
+                return Err(format!(
+                    "round {}: the rename returned {:?}, the creation {:?}",
+                    round, renamed, created
+                ))
+            }
+        }
+        ok("remove", root.remove("target"))?;
+    }
+    Ok(())
+}
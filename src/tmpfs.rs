@@ -0,0 +1,1101 @@
+// A filesystem in memory for /dev/shm, whose files are lists of pages
+// with stable addresses. shm_open is a file of /dev/shm, and the mappings
+// of the processes must share its memory, so the mmap layer takes the
+// pages themselves: page() returns a PageRef of a page of the file, the
+// same memory readat and writeat copy from and to, so the writes through
+// both are coherent. A page is allocated at its first write or page(),
+// truncate grows and shrinks the list, the holes read as zeros. A punched
+// hole drops its pages and an allocation makes them, see fallocate.rs.
+// A truncation and a hole tell the hooks of the mappings first, see
+// mapping.rs, the writes need not since the pages are shared.
+// A clone shares the pages of its source, see reflink.rs: a page counts
+// the files holding it, and a write or a mapping of a shared page copies
+// it first. A shared page is allocated once, the statfs of the TmpFs
+// counts it once, and a mapped page is copied by the clone since the
+// stores of the mapping would reach the clone.
+//
+// The page handles of a node are in SharedPages, and the nodes
+// implementing it hand it out by their FsNode like the async nodes of
+// aio.rs, so page() takes any node, FileHandle passes it to its node.
+//
+// The directories rename their entries, see rename.rs. A directory knows
+// its parent for "..", and the directories of a TmpFs are found by their
+// address in TmpShared, so a rename takes the target directory passed as
+// a dyn INodeInterface. A rename between two directories locks their
+// entries in the order of their inode numbers, after the gates of the
+// directories in the same order, see readdir::SeekDir::lock_dir.
+//
+// A directory counts the changes of its entries, see readdir::dir_version.
+// The entries are numbered as they're created, so the entries added since
+// a version are those numbered from the next position at that version: a
+// directory keeps the next positions of its RECENT_VERSIONS last versions,
+// an older version asks for a full listing.
+
+use core::{
+    cmp,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use vfscore::{
+    DirEntry, FileSystem, FileType, INodeInterface, Metadata, OpenFlags, Stat, StatFS, StatMode,
+    VfsError, VfsResult,
+};
+
+use crate::cache::{InodeId, PAGE_SIZE};
+use crate::fallocate::{SpaceINode, SpaceOp, Support};
+use crate::freeze::{ClosedGate, FreezeGate};
+use crate::fstype::FsType;
+use crate::handle::AppendINode;
+use crate::mapping;
+use crate::node::{self, FsNode};
+use crate::ops::{
+    add_dot_entries, check_lookup_name, check_name, check_range, DT_DIR, DT_REG, NAME_MAX,
+};
+use crate::readdir::{PosEntry, SeekDir};
+use crate::reflink::CloneINode;
+use crate::rename::{RenameFlags, RenameINode};
+use crate::statfs::{anon_dev, next_fsid, TMPFS_MAGIC};
+use crate::sys::Mutex;
+
+#[repr(C, align(4096))]
+struct PageData([u8; PAGE_SIZE]);
+
+const _: () = assert!(core::mem::align_of::<PageData>() == PAGE_SIZE);
+
+/// A page of a tmpfs file, aligned to PAGE_SIZE. Its address doesn't
+/// change while there is a PageRef of it, even after the file drops it.
+/// The copies of the file and of the PageRef lock the bytes, the stores of
+/// the mappings don't and race with them like they do on Linux.
+pub struct Page {
+    data: Mutex<Box<PageData>>,
+    /// The files holding the page, more than one for the pages shared by
+    /// a clone, the others PageRef are of the mappings.
+    owners: AtomicUsize,
+}
+
+pub type PageRef = Arc<Page>;
+
+impl Page {
+    fn new() -> PageRef {
+        Arc::new(Self {
+            data: Mutex::new(Box::new(PageData([0; PAGE_SIZE]))),
+            owners: AtomicUsize::new(1),
+        })
+    }
+
+    /// The address of the page, for the mmap layer to map.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.data.lock().0.as_mut_ptr()
+    }
+
+    /// Whether a mapping holds the page.
+    fn is_mapped(self: &PageRef) -> bool {
+        Arc::strong_count(self) > self.owners.load(Ordering::Relaxed)
+    }
+
+    /// Copy the bytes at offset of the page to buf.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) {
+        assert!(offset + buf.len() <= PAGE_SIZE, "read beyond the page");
+        buf.copy_from_slice(&self.data.lock().0[offset..offset + buf.len()]);
+    }
+
+    /// Copy buf to the bytes at offset of the page, like a store through
+    /// a mapping.
+    pub fn write(&self, offset: usize, buf: &[u8]) {
+        assert!(offset + buf.len() <= PAGE_SIZE, "write beyond the page");
+        self.data.lock().0[offset..offset + buf.len()].copy_from_slice(buf);
+    }
+
+    fn fill(&self, offset: usize, len: usize) {
+        assert!(offset + len <= PAGE_SIZE, "fill beyond the page");
+        self.data.lock().0[offset..offset + len].fill(0);
+    }
+}
+
+/// The pages of a node, for the shared mappings.
+pub trait SharedPages: INodeInterface + Sync {
+    /// The page index of the file, allocated if it's a hole. The pages
+    /// at and beyond the end of the file fail with InvalidInput, like the
+    /// SIGBUS of a mapping beyond the end.
+    fn page(&self, index: usize) -> VfsResult<PageRef>;
+}
+
+/// The page index of the file, NotSupported if its filesystem has no
+/// stable pages.
+pub fn page(file: &Arc<dyn INodeInterface>, index: usize) -> VfsResult<PageRef> {
+    let node = node::fs_node(file.as_ref()).and_then(|x| x.as_pages());
+    node.ok_or(VfsError::NotSupported)?.page(index)
+}
+
+pub struct TmpFs {
+    root: Arc<TmpDir>,
+}
+
+/// The state shared by the nodes of a TmpFs.
+struct TmpShared {
+    next_ino: AtomicU64,
+    /// The pages allocated by the files.
+    pages: AtomicUsize,
+    fsid: u64,
+    /// The st_dev of the nodes.
+    dev: usize,
+}
+
+impl TmpShared {
+    /// The state of a new TmpFs, the root is inode 1.
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            next_ino: AtomicU64::new(2),
+            pages: AtomicUsize::new(0),
+            fsid: next_fsid(),
+            dev: anon_dev(),
+        })
+    }
+}
+
+/// The tmpfs type of fstype.rs, a new TmpFs at every mount.
+pub(crate) const FSTYPE: FsType = FsType {
+    name: "tmpfs",
+    detect: None,
+    identify: None,
+    mount: |_, _| Ok(TmpFs::new() as Arc<dyn FileSystem>),
+    priority: 0,
+};
+
+impl TmpFs {
+    pub fn new() -> Arc<Self> {
+        let shared = TmpShared::new();
+        Arc::new(Self {
+            root: TmpDir::new("", 1, shared, None),
+        })
+    }
+
+    /// A file without a name in a TmpFs of its own, like O_TMPFILE, its
+    /// pages are freed with its last reference.
+    pub fn temp_file() -> Arc<dyn INodeInterface> {
+        let shared = TmpShared::new();
+        let ino = shared.next_ino.fetch_add(1, Ordering::Relaxed);
+        TmpFile::new("", ino, shared)
+    }
+
+    /// The directory at the path from the root.
+    #[cfg(feature = "testsuite")]
+    fn dir_at(&self, path: &str) -> VfsResult<Arc<TmpDir>> {
+        let mut dir = self.root.clone();
+        for name in path.split('/').filter(|x| !x.is_empty()) {
+            let next = match dir.entries.lock().get(name) {
+                Some((_, TmpEntry::Dir(x))) => x.clone(),
+                Some(_) => return Err(VfsError::NotDir),
+                None => return Err(VfsError::FileNotFound),
+            };
+            dir = next;
+        }
+        Ok(dir)
+    }
+
+    /// Link the directory at the path target as name of the directory at
+    /// dir, both from the root. It's a second entry of a directory which
+    /// can make a loop, like a corrupted image, for the tests of the
+    /// walkers.
+    #[cfg(feature = "testsuite")]
+    pub(crate) fn link_dir(&self, dir: &str, name: &str, target: &str) -> VfsResult<()> {
+        let target = self.dir_at(target)?;
+        self.dir_at(dir)?
+            .create(name, |_| TmpEntry::Dir(target))
+            .map(|_| ())
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &str {
+        "tmpfs"
+    }
+
+    fn root_dir(&'static self) -> Arc<dyn INodeInterface> {
+        self.root.clone()
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        Ok(())
+    }
+}
+
+fn statfs(shared: &TmpShared, statfs: &mut StatFS) -> VfsResult<()> {
+    statfs.ftype = TMPFS_MAGIC as _;
+    statfs.bsize = PAGE_SIZE as _;
+    // the memory isn't limited, the free pages are unknown.
+    statfs.blocks = shared.pages.load(Ordering::Relaxed) as _;
+    statfs.bfree = 0;
+    statfs.bavail = 0;
+    statfs.files = 0;
+    statfs.ffree = 0;
+    statfs.fsid = shared.fsid;
+    statfs.namelen = NAME_MAX as _;
+    Ok(())
+}
+
+#[derive(Clone)]
+enum TmpEntry {
+    Dir(Arc<TmpDir>),
+    File(Arc<TmpFile>),
+}
+
+impl TmpEntry {
+    fn node(&self) -> Arc<dyn INodeInterface> {
+        match self {
+            TmpEntry::Dir(dir) => dir.clone(),
+            TmpEntry::File(file) => file.clone(),
+        }
+    }
+
+    fn ino(&self) -> u64 {
+        match self {
+            TmpEntry::Dir(dir) => dir.ino,
+            TmpEntry::File(file) => file.ino,
+        }
+    }
+}
+
+/// The position of the first entry of a directory, after "." and "..".
+const FIRST_POS: u64 = 2;
+/// The versions of a directory whose next positions are kept.
+const RECENT_VERSIONS: usize = 64;
+
+/// The entries by the name, with their position in the listing.
+type Entries = BTreeMap<String, (u64, TmpEntry)>;
+
+/// The version of the entries of a directory and the next positions at
+/// its recent versions, the oldest first.
+struct Versions {
+    version: u64,
+    recent: VecDeque<(u64, u64)>,
+}
+
+pub struct TmpDir {
+    filename: String,
+    ino: u64,
+    entries: Mutex<Entries>,
+    /// The changes of the entries enter it before locking them.
+    gate: Arc<FreezeGate>,
+    /// The position of the next entry, they are numbered as they're
+    /// created.
+    next_pos: AtomicU64,
+    /// Locked after the entries.
+    versions: Mutex<Versions>,
+    /// The directory of "..", the root is its own parent.
+    parent: Mutex<Weak<TmpDir>>,
+    this: Weak<TmpDir>,
+    shared: Arc<TmpShared>,
+}
+
+impl TmpDir {
+    fn new(
+        filename: &str,
+        ino: u64,
+        shared: Arc<TmpShared>,
+        parent: Option<Weak<TmpDir>>,
+    ) -> Arc<Self> {
+        Arc::new_cyclic(|this: &Weak<TmpDir>| Self {
+            filename: String::from(filename),
+            ino,
+            entries: Mutex::new(BTreeMap::new()),
+            gate: Arc::new(FreezeGate::new()),
+            next_pos: AtomicU64::new(FIRST_POS),
+            versions: Mutex::new(Versions {
+                version: 0,
+                recent: VecDeque::from([(0, FIRST_POS)]),
+            }),
+            parent: Mutex::new(parent.unwrap_or_else(|| this.clone())),
+            this: this.clone(),
+            shared,
+        })
+    }
+
+    /// The directory of the node in this TmpFs, NotSupported for the
+    /// nodes of other filesystems.
+    fn dir_of(&self, node: &Arc<dyn INodeInterface>) -> VfsResult<Arc<TmpDir>> {
+        let dir = node.downcast_ref::<TmpDir>();
+        dir.filter(|x| Arc::ptr_eq(&x.shared, &self.shared))
+            .and_then(|x| x.this.upgrade())
+            .ok_or(VfsError::NotSupported)
+    }
+
+    fn next_ino(&self) -> u64 {
+        self.shared.next_ino.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn create(&self, name: &str, entry: impl FnOnce(u64) -> TmpEntry) -> VfsResult<TmpEntry> {
+        check_name(name)?;
+        let _gate = self.gate.enter();
+        let mut entries = self.entries.lock();
+        if entries.contains_key(name) {
+            return Err(VfsError::AlreadyExists);
+        }
+        let entry = entry(self.next_ino());
+        let pos = self.next_pos.fetch_add(1, Ordering::Relaxed);
+        entries.insert(String::from(name), (pos, entry.clone()));
+        self.changed();
+        Ok(entry)
+    }
+
+    /// Count a change of the entries, with them locked.
+    fn changed(&self) {
+        let mut versions = self.versions.lock();
+        versions.version += 1;
+        let at = (versions.version, self.next_pos.load(Ordering::Relaxed));
+        if versions.recent.len() == RECENT_VERSIONS {
+            versions.recent.pop_front();
+        }
+        versions.recent.push_back(at);
+    }
+}
+
+fn dir_entry(name: &str, entry: &TmpEntry) -> DirEntry {
+    DirEntry {
+        filename: String::from(name),
+        len: match entry {
+            TmpEntry::Dir(_) => 0,
+            TmpEntry::File(file) => file.data.lock().size,
+        },
+        file_type: match entry {
+            TmpEntry::Dir(_) => FileType::Directory,
+            TmpEntry::File(_) => FileType::File,
+        },
+    }
+}
+
+/// The entries of news, or of olds when both are of one directory.
+fn entries_of<'a>(olds: &'a mut Entries, news: &'a mut Option<&mut Entries>) -> &'a mut Entries {
+    match news {
+        Some(news) => news,
+        None => olds,
+    }
+}
+
+impl TmpDir {
+    /// Rename old to new of new_dir with their entries locked, news is
+    /// None when new_dir is this directory. See RenameINode.
+    fn move_entry(
+        &self,
+        olds: &mut Entries,
+        mut news: Option<&mut Entries>,
+        old: &str,
+        new_dir: &Arc<TmpDir>,
+        new: &str,
+        flags: RenameFlags,
+    ) -> VfsResult<()> {
+        let entry = olds.get(old).ok_or(VfsError::FileNotFound)?.1.clone();
+        let target = entries_of(olds, &mut news).get(new).map(|x| x.1.clone());
+        if flags.contains(RenameFlags::NOREPLACE) && target.is_some() {
+            return Err(VfsError::AlreadyExists);
+        }
+        if flags.contains(RenameFlags::EXCHANGE) {
+            let target = target.ok_or(VfsError::FileNotFound)?;
+            if let Some(x) = olds.get_mut(old) {
+                x.1 = target.clone();
+            }
+            if let Some(x) = entries_of(olds, &mut news).get_mut(new) {
+                x.1 = entry.clone();
+            }
+            for (moved, parent) in [(entry, &new_dir.this), (target, &self.this)] {
+                if let TmpEntry::Dir(dir) = moved {
+                    *dir.parent.lock() = parent.clone();
+                }
+            }
+            self.moved(new_dir);
+            return Ok(());
+        }
+        if let Some(target) = target {
+            // two links of the same node, rename does nothing.
+            if target.ino() == entry.ino() {
+                return Ok(());
+            }
+            match (&entry, &target) {
+                (TmpEntry::Dir(_), TmpEntry::File(_)) => return Err(VfsError::NotDir),
+                // EISDIR, rename::rename tells it apart first.
+                (TmpEntry::File(_), TmpEntry::Dir(_)) => return Err(VfsError::InvalidInput),
+                // this directory holds old, its entries are locked.
+                (_, TmpEntry::Dir(dir))
+                    if dir.ino == self.ino || !dir.entries.lock().is_empty() =>
+                {
+                    return Err(VfsError::DirectoryNotEmpty)
+                }
+                _ => {}
+            }
+        }
+        olds.remove(old);
+        let pos = new_dir.next_pos.fetch_add(1, Ordering::Relaxed);
+        entries_of(olds, &mut news).insert(String::from(new), (pos, entry.clone()));
+        if let TmpEntry::Dir(dir) = entry {
+            *dir.parent.lock() = new_dir.this.clone();
+        }
+        self.moved(new_dir);
+        Ok(())
+    }
+
+    /// Count the change of a rename to new_dir in both directories.
+    fn moved(&self, new_dir: &TmpDir) {
+        self.changed();
+        if new_dir.ino != self.ino {
+            new_dir.changed();
+        }
+    }
+}
+
+impl FsNode for TmpDir {
+    fn as_seek_dir(&self) -> Option<&dyn SeekDir> {
+        Some(self)
+    }
+
+    fn as_rename(&self) -> Option<&dyn RenameINode> {
+        Some(self)
+    }
+}
+
+impl RenameINode for TmpDir {
+    fn rename_to(
+        &self,
+        old: &str,
+        new_dir: &Arc<dyn INodeInterface>,
+        new: &str,
+        flags: RenameFlags,
+    ) -> VfsResult<()> {
+        for name in [old, new] {
+            check_name(name)?;
+            if name == "." || name == ".." {
+                return Err(VfsError::InvalidInput);
+            }
+        }
+        let new_dir = self.dir_of(new_dir)?;
+        if new_dir.ino == self.ino {
+            let _gate = self.gate.enter();
+            return self.move_entry(&mut self.entries.lock(), None, old, &new_dir, new, flags);
+        }
+        // the gates and the entries are locked in the order of the inode
+        // numbers.
+        let (first, second) = match self.ino < new_dir.ino {
+            true => (&self.gate, &new_dir.gate),
+            false => (&new_dir.gate, &self.gate),
+        };
+        let _gates = (first.enter(), second.enter());
+        let (mut olds, mut news) = match self.ino < new_dir.ino {
+            true => {
+                let olds = self.entries.lock();
+                (olds, new_dir.entries.lock())
+            }
+            false => {
+                let news = new_dir.entries.lock();
+                (self.entries.lock(), news)
+            }
+        };
+        self.move_entry(&mut olds, Some(&mut news), old, &new_dir, new, flags)
+    }
+}
+
+impl SeekDir for TmpDir {
+    /// The listing is in the order of the creation, after "." and "..".
+    fn read_dir_at(&self, pos: u64, max: usize) -> VfsResult<Vec<PosEntry>> {
+        let parent = self.parent.lock().upgrade().map_or(self.ino, |x| x.ino);
+        let mut listed: Vec<PosEntry> = [(".", self.ino), ("..", parent)]
+            .into_iter()
+            .zip(0..FIRST_POS)
+            .filter(|x| x.1 >= pos)
+            .map(|((name, ino), x)| PosEntry {
+                entry: DirEntry {
+                    filename: String::from(name),
+                    len: 0,
+                    file_type: FileType::Directory,
+                },
+                next: x + 1,
+                ino,
+                d_type: DT_DIR,
+            })
+            .collect();
+        let entries = self.entries.lock();
+        let mut after: Vec<_> = entries.iter().filter(|x| x.1 .0 >= pos).collect();
+        after.sort_unstable_by_key(|x| x.1 .0);
+        let after = after
+            .into_iter()
+            .take(max)
+            .map(|(name, (x, entry))| PosEntry {
+                entry: dir_entry(name, entry),
+                next: x + 1,
+                ino: entry.ino(),
+                d_type: match entry {
+                    TmpEntry::Dir(_) => DT_DIR,
+                    TmpEntry::File(_) => DT_REG,
+                },
+            });
+        listed.extend(after);
+        listed.truncate(max);
+        Ok(listed)
+    }
+
+    fn lock_dir(&self) -> Option<ClosedGate> {
+        Some(self.gate.close())
+    }
+
+    fn dir_version(&self) -> Option<u64> {
+        Some(self.versions.lock().version)
+    }
+
+    /// The entries from the next position at the version, in the order of
+    /// their creation. An entry renamed in counts as added.
+    fn read_dir_since(&self, version: u64) -> VfsResult<Option<Vec<DirEntry>>> {
+        let entries = self.entries.lock();
+        let versions = self.versions.lock();
+        let Some(&(_, pos)) = versions.recent.iter().find(|x| x.0 == version) else {
+            return Ok(None);
+        };
+        drop(versions);
+        let mut added: Vec<_> = entries.iter().filter(|x| x.1 .0 >= pos).collect();
+        added.sort_unstable_by_key(|x| x.1 .0);
+        let added = added
+            .into_iter()
+            .map(|(name, (_, entry))| dir_entry(name, entry));
+        Ok(Some(added.collect()))
+    }
+}
+
+impl INodeInterface for TmpDir {
+    fn mkdir(&self, name: &str) -> VfsResult<Arc<dyn INodeInterface>> {
+        let (shared, parent) = (self.shared.clone(), Some(self.this.clone()));
+        self.create(name, |ino| {
+            TmpEntry::Dir(TmpDir::new(name, ino, shared, parent))
+        })
+        .map(|x| x.node())
+    }
+
+    fn touch(&self, name: &str) -> VfsResult<Arc<dyn INodeInterface>> {
+        let shared = self.shared.clone();
+        self.create(name, |ino| TmpEntry::File(TmpFile::new(name, ino, shared)))
+            .map(|x| x.node())
+    }
+
+    fn open(&self, name: &str, _flags: OpenFlags) -> VfsResult<Arc<dyn INodeInterface>> {
+        self.lookup(name)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<Arc<dyn INodeInterface>> {
+        check_lookup_name(name)?;
+        let dot = match name {
+            "." => Some(self.this.clone()),
+            ".." => Some(self.parent.lock().clone()),
+            _ => None,
+        };
+        if let Some(dir) = dot {
+            let dir = dir.upgrade().ok_or(VfsError::FileNotFound)?;
+            return Ok(dir);
+        }
+        self.entries
+            .lock()
+            .get(name)
+            .map(|x| x.1.node())
+            .ok_or(VfsError::FileNotFound)
+    }
+
+    fn rmdir(&self, name: &str) -> VfsResult<()> {
+        check_name(name)?;
+        let _gate = self.gate.enter();
+        let mut entries = self.entries.lock();
+        match entries.get(name).map(|x| &x.1) {
+            Some(TmpEntry::Dir(dir)) if !dir.entries.lock().is_empty() => {
+                Err(VfsError::DirectoryNotEmpty)
+            }
+            Some(TmpEntry::Dir(_)) => {
+                entries.remove(name);
+                self.changed();
+                Ok(())
+            }
+            Some(TmpEntry::File(_)) => Err(VfsError::NotDir),
+            None => Err(VfsError::FileNotFound),
+        }
+    }
+
+    /// Remove the file, its pages stay while it's open or mapped.
+    fn remove(&self, name: &str) -> VfsResult<()> {
+        check_name(name)?;
+        let _gate = self.gate.enter();
+        let mut entries = self.entries.lock();
+        match entries.get(name).map(|x| &x.1) {
+            Some(TmpEntry::File(_)) => {
+                entries.remove(name);
+                self.changed();
+                Ok(())
+            }
+            // EISDIR, ops::unlinkat tells it apart first.
+            Some(TmpEntry::Dir(_)) => Err(VfsError::InvalidInput),
+            None => Err(VfsError::FileNotFound),
+        }
+    }
+
+    fn unlink(&self, name: &str) -> VfsResult<()> {
+        self.remove(name)
+    }
+
+    fn read_dir(&self) -> VfsResult<Vec<DirEntry>> {
+        let mut entries: Vec<_> = self
+            .entries
+            .lock()
+            .iter()
+            .map(|(name, (_, entry))| dir_entry(name, entry))
+            .collect();
+        add_dot_entries(&mut entries);
+        Ok(entries)
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(Metadata {
+            filename: &self.filename,
+            inode: self.ino as _,
+            file_type: FileType::Directory,
+            size: 0,
+            childrens: self.entries.lock().len(),
+        })
+    }
+
+    fn stat(&self, stat: &mut Stat) -> VfsResult<()> {
+        stat.dev = self.shared.dev as _;
+        stat.ino = self.ino as _;
+        stat.mode = StatMode::DIR | StatMode::from_bits_truncate(0o777);
+        stat.nlink = 2;
+        stat.uid = 0;
+        stat.gid = 0;
+        stat.size = 0;
+        stat.blksize = PAGE_SIZE as _;
+        stat.blocks = 0;
+        Ok(())
+    }
+
+    fn statfs(&self, statfs: &mut StatFS) -> VfsResult<()> {
+        self::statfs(&self.shared, statfs)
+    }
+}
+
+/// The pages of a file, None for a hole.
+struct TmpData {
+    pages: Vec<Option<PageRef>>,
+    size: usize,
+}
+
+pub struct TmpFile {
+    filename: String,
+    ino: u64,
+    data: Mutex<TmpData>,
+    /// Held over an append, see AppendINode.
+    append: Mutex<()>,
+    this: Weak<TmpFile>,
+    shared: Arc<TmpShared>,
+}
+
+impl TmpFile {
+    fn new(filename: &str, ino: u64, shared: Arc<TmpShared>) -> Arc<Self> {
+        Arc::new_cyclic(|this: &Weak<TmpFile>| Self {
+            filename: String::from(filename),
+            ino,
+            data: Mutex::new(TmpData {
+                pages: Vec::new(),
+                size: 0,
+            }),
+            append: Mutex::new(()),
+            this: this.clone(),
+            shared,
+        })
+    }
+
+    /// The page index, allocated if it's a hole. The list must hold it.
+    fn page_at(&self, data: &mut TmpData, index: usize) -> PageRef {
+        data.pages[index]
+            .get_or_insert_with(|| {
+                self.shared.pages.fetch_add(1, Ordering::Relaxed);
+                Page::new()
+            })
+            .clone()
+    }
+
+    /// The page index to write, allocated if it's a hole and copied if a
+    /// clone shares it. The list must hold it.
+    fn page_mut(&self, data: &mut TmpData, index: usize) -> PageRef {
+        let page = self.page_at(data, index);
+        if page.owners.load(Ordering::Relaxed) == 1 {
+            return page;
+        }
+        let copy = self.copy_page(&page);
+        self.release(&page);
+        data.pages[index] = Some(copy.clone());
+        copy
+    }
+
+    /// A new page with the bytes of page.
+    fn copy_page(&self, page: &Page) -> PageRef {
+        let copy = Page::new();
+        copy.data.lock().0 = page.data.lock().0;
+        self.shared.pages.fetch_add(1, Ordering::Relaxed);
+        copy
+    }
+
+    /// Drop the hold of the file on the page, it's freed with the last
+    /// file holding it.
+    fn release(&self, page: &Page) {
+        if page.owners.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.shared.pages.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Resize the list of pages to size bytes. The bytes beyond the end
+    /// of the smaller size in its page are zeroed, a store through a
+    /// mapping may have left some, so a growth reads zeros there.
+    /// The id of the file for the hooks of mapping.rs.
+    fn inode_id(&self) -> InodeId {
+        InodeId {
+            dev: self.shared.dev,
+            ino: self.ino,
+        }
+    }
+
+    fn resize(&self, data: &mut TmpData, size: usize) {
+        let edge = cmp::min(data.size, size);
+        if edge % PAGE_SIZE != 0 && matches!(data.pages.get(edge / PAGE_SIZE), Some(Some(_))) {
+            self.page_mut(data, edge / PAGE_SIZE)
+                .fill(edge % PAGE_SIZE, PAGE_SIZE - edge % PAGE_SIZE);
+        }
+        let pages = size.div_ceil(PAGE_SIZE);
+        for page in data.pages.iter().skip(pages).flatten() {
+            self.release(page);
+        }
+        data.pages.resize(pages, None);
+        data.size = size;
+    }
+}
+
+impl Drop for TmpFile {
+    fn drop(&mut self) {
+        for page in self.data.lock().pages.iter().flatten() {
+            self.release(page);
+        }
+    }
+}
+
+impl FsNode for TmpFile {
+    fn as_space(&self) -> Option<&dyn SpaceINode> {
+        Some(self)
+    }
+
+    fn as_clone(&self) -> Option<&dyn CloneINode> {
+        Some(self)
+    }
+
+    fn as_pages(&self) -> Option<&dyn SharedPages> {
+        Some(self)
+    }
+
+    fn as_append(&self) -> Option<&dyn AppendINode> {
+        Some(self)
+    }
+}
+
+impl AppendINode for TmpFile {
+    fn append(
+        &self,
+        write: &mut dyn FnMut(usize) -> VfsResult<usize>,
+    ) -> VfsResult<(usize, usize)> {
+        let _append = self.append.lock();
+        let end = self.data.lock().size;
+        Ok((end, write(end)?))
+    }
+}
+
+impl SharedPages for TmpFile {
+    fn page(&self, index: usize) -> VfsResult<PageRef> {
+        let mut data = self.data.lock();
+        if index >= data.pages.len() {
+            return Err(VfsError::InvalidInput);
+        }
+        // the stores of the mapping mustn't reach a clone.
+        Ok(self.page_mut(&mut data, index))
+    }
+}
+
+/// The holes drop their pages and the allocations make them.
+impl SpaceINode for TmpFile {
+    fn support(&self, _op: SpaceOp) -> Support {
+        Support::Native
+    }
+
+    /// Drop the pages in the range and zero the parts of the pages at its
+    /// edges. A mapped page is zeroed instead, the mapping sees the hole,
+    /// and a page shared by a clone is copied before it's zeroed.
+    fn punch_hole(&self, offset: usize, len: usize) -> VfsResult<()> {
+        let mut data = self.data.lock();
+        let end = cmp::min(offset + len, data.size);
+        mapping::invalidate(self.inode_id(), offset..end);
+        let mut pos = offset;
+        while pos < end {
+            let index = pos / PAGE_SIZE;
+            let start = pos % PAGE_SIZE;
+            let n = cmp::min(PAGE_SIZE - start, end - pos);
+            match &data.pages[index] {
+                Some(page) if n == PAGE_SIZE && !page.is_mapped() => {
+                    self.release(page);
+                    data.pages[index] = None;
+                }
+                Some(_) => self.page_mut(&mut data, index).fill(start, n),
+                None => {}
+            }
+            pos += n;
+        }
+        Ok(())
+    }
+
+    /// Allocate the pages of the range, the file grows to its end unless
+    /// keep_size. The list of pages ends with the file, a keep_size beyond
+    /// the end fails with NotSupported.
+    fn allocate(&self, offset: usize, len: usize, keep_size: bool) -> VfsResult<()> {
+        let mut data = self.data.lock();
+        let end = offset + len;
+        if end > data.size {
+            if keep_size {
+                return Err(VfsError::NotSupported);
+            }
+            self.resize(&mut data, end);
+        }
+        for index in offset / PAGE_SIZE..end.div_ceil(PAGE_SIZE) {
+            self.page_at(&mut data, index);
+        }
+        Ok(())
+    }
+}
+
+impl TmpFile {
+    /// The file of the node in this TmpFs, NotSupported for the nodes of
+    /// other filesystems.
+    fn file_of(&self, node: &Arc<dyn INodeInterface>) -> VfsResult<Arc<TmpFile>> {
+        let file = node.downcast_ref::<TmpFile>();
+        file.filter(|x| Arc::ptr_eq(&x.shared, &self.shared))
+            .and_then(|x| x.this.upgrade())
+            .ok_or(VfsError::NotSupported)
+    }
+
+    /// The pages of len bytes at offset of data, held by the clone. A
+    /// mapped page is copied.
+    fn take_pages(&self, data: &TmpData, offset: usize, len: usize) -> Vec<Option<PageRef>> {
+        let first = offset / PAGE_SIZE;
+        data.pages[first..first + len.div_ceil(PAGE_SIZE)]
+            .iter()
+            .map(|slot| match slot {
+                Some(page) if page.is_mapped() => Some(self.copy_page(page)),
+                Some(page) => {
+                    page.owners.fetch_add(1, Ordering::Relaxed);
+                    Some(page.clone())
+                }
+                None => None,
+            })
+            .collect()
+    }
+
+    /// Put the pages of a clone of len bytes at offset, in place of those
+    /// of the range, the file grows to its end.
+    fn put_pages(
+        &self,
+        data: &mut TmpData,
+        offset: usize,
+        len: usize,
+        pages: Vec<Option<PageRef>>,
+    ) {
+        if offset + len > data.size {
+            self.resize(data, offset + len);
+        }
+        for (index, page) in (offset / PAGE_SIZE..).zip(pages) {
+            if let Some(old) = core::mem::replace(&mut data.pages[index], page) {
+                self.release(&old);
+            }
+        }
+    }
+
+    /// Share len bytes at src_offset of src at offset, see
+    /// reflink::clone_range, replace drops the data of the file first.
+    /// The two files are locked in the order of their inode numbers.
+    fn share(
+        &self,
+        src: &Arc<dyn INodeInterface>,
+        src_offset: usize,
+        len: usize,
+        offset: usize,
+        replace: bool,
+    ) -> VfsResult<()> {
+        let src = self.file_of(src)?;
+        if src.ino == self.ino {
+            let mut data = self.data.lock();
+            let len = clone_len(&data, src_offset, len, offset, data.size)?;
+            // the ranges of a clone in the file mustn't overlap.
+            if replace || (src_offset < offset + len && offset < src_offset + len) {
+                return Err(VfsError::InvalidInput);
+            }
+            let pages = self.take_pages(&data, src_offset, len);
+            self.put_pages(&mut data, offset, len, pages);
+            return Ok(());
+        }
+        let (mut data, src_data) = match self.ino < src.ino {
+            true => {
+                let data = self.data.lock();
+                (data, src.data.lock())
+            }
+            false => {
+                let src_data = src.data.lock();
+                (self.data.lock(), src_data)
+            }
+        };
+        let size = match replace {
+            true => 0,
+            false => data.size,
+        };
+        let len = clone_len(&src_data, src_offset, len, offset, size)?;
+        let pages = self.take_pages(&src_data, src_offset, len);
+        if replace {
+            self.resize(&mut data, 0);
+        }
+        self.put_pages(&mut data, offset, len, pages);
+        Ok(())
+    }
+}
+
+/// The length of a clone of len bytes at src_offset of the file of src, 0
+/// up to its end, to offset of a file of size bytes. See
+/// reflink::clone_range.
+fn clone_len(
+    src: &TmpData,
+    src_offset: usize,
+    len: usize,
+    offset: usize,
+    size: usize,
+) -> VfsResult<usize> {
+    let len = match len {
+        0 => src
+            .size
+            .checked_sub(src_offset)
+            .ok_or(VfsError::InvalidInput)?,
+        len => len,
+    };
+    let to_end = src_offset + len == src.size && offset + len >= size;
+    if src_offset + len > src.size || (len % PAGE_SIZE != 0 && !to_end) {
+        return Err(VfsError::InvalidInput);
+    }
+    Ok(len)
+}
+
+/// The clones share the pages, the writes copy them.
+impl CloneINode for TmpFile {
+    fn clone_source(&self) -> VfsResult<Arc<dyn INodeInterface>> {
+        let file = self.this.upgrade().ok_or(VfsError::FileNotFound)?;
+        Ok(file)
+    }
+
+    fn clone_from(&self, src: &Arc<dyn INodeInterface>) -> VfsResult<()> {
+        self.share(src, 0, 0, 0, true)
+    }
+
+    fn clone_range(
+        &self,
+        src: &Arc<dyn INodeInterface>,
+        src_offset: usize,
+        len: usize,
+        offset: usize,
+    ) -> VfsResult<()> {
+        self.share(src, src_offset, len, offset, false)
+    }
+}
+
+impl INodeInterface for TmpFile {
+    fn readat(&self, offset: usize, buffer: &mut [u8]) -> VfsResult<usize> {
+        check_range(offset, buffer.len(), u64::MAX)?;
+        let data = self.data.lock();
+        if offset >= data.size {
+            return Ok(0);
+        }
+        let len = cmp::min(buffer.len(), data.size - offset);
+        let mut pos = 0;
+        while pos < len {
+            let index = (offset + pos) / PAGE_SIZE;
+            let start = (offset + pos) % PAGE_SIZE;
+            let n = cmp::min(PAGE_SIZE - start, len - pos);
+            match &data.pages[index] {
+                Some(page) => page.read(start, &mut buffer[pos..pos + n]),
+                None => buffer[pos..pos + n].fill(0),
+            }
+            pos += n;
+        }
+        Ok(len)
+    }
+
+    fn writeat(&self, offset: usize, buffer: &[u8]) -> VfsResult<usize> {
+        check_range(offset, buffer.len(), u64::MAX)?;
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let mut data = self.data.lock();
+        let end = offset + buffer.len();
+        if end > data.size {
+            self.resize(&mut data, end);
+        }
+        let mut pos = 0;
+        while pos < buffer.len() {
+            let index = (offset + pos) / PAGE_SIZE;
+            let start = (offset + pos) % PAGE_SIZE;
+            let n = cmp::min(PAGE_SIZE - start, buffer.len() - pos);
+            self.page_mut(&mut data, index)
+                .write(start, &buffer[pos..pos + n]);
+            pos += n;
+        }
+        Ok(buffer.len())
+    }
+
+    /// Grow or shrink the list of pages, the pages beyond size are
+    /// dropped, the mappings holding them keep their memory.
+    fn truncate(&self, size: usize) -> VfsResult<()> {
+        check_range(size, 0, u64::MAX)?;
+        let mut data = self.data.lock();
+        mapping::invalidate(self.inode_id(), size..data.size);
+        self.resize(&mut data, size);
+        Ok(())
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(Metadata {
+            filename: &self.filename,
+            inode: self.ino as _,
+            file_type: FileType::File,
+            size: self.data.lock().size,
+            childrens: 0,
+        })
+    }
+
+    fn stat(&self, stat: &mut Stat) -> VfsResult<()> {
+        let data = self.data.lock();
+        let pages = data.pages.iter().flatten().count();
+        stat.dev = self.shared.dev as _;
+        stat.ino = self.ino as _;
+        stat.mode = StatMode::FILE | StatMode::from_bits_truncate(0o666);
+        stat.nlink = 1;
+        stat.uid = 0;
+        stat.gid = 0;
+        stat.size = data.size as _;
+        stat.blksize = PAGE_SIZE as _;
+        stat.blocks = (pages * PAGE_SIZE / 512) as _;
+        Ok(())
+    }
+
+    fn statfs(&self, statfs: &mut StatFS) -> VfsResult<()> {
+        self::statfs(&self.shared, statfs)
+    }
+}
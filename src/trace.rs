@@ -0,0 +1,158 @@
+// Tracing of the filesystem operations, for the debugging and the
+// behavioral tests. The open file handles, the path resolution and the
+// ext4 shim emit a TraceEvent per operation to the hook installed by
+// set_hook. Without a hook an operation costs one relaxed load, the
+// target and the time aren't computed.
+
+use core::fmt::{self, Display};
+
+use alloc::sync::Arc;
+use vfscore::{VfsError, VfsResult};
+
+use crate::hook::HookCell;
+
+/// The traced operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceOp {
+    Open,
+    Lookup,
+    Read,
+    Write,
+    Truncate,
+    Flush,
+    Mkdir,
+    Touch,
+    Unlink,
+    Rmdir,
+    Link,
+    Symlink,
+    Rename,
+    Mknod,
+}
+
+/// What the operation works on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target<'a> {
+    /// The layer doesn't know it, like the open file handles.
+    Unknown,
+    Path(&'a str),
+    Inode(u64),
+    /// The entry name in the directory dir.
+    Entry {
+        dir: u64,
+        name: &'a str,
+    },
+}
+
+/// An operation which finished.
+#[derive(Debug)]
+pub struct TraceEvent<'a> {
+    pub op: TraceOp,
+    /// The layer which emitted it: "handle", "dentry" or "ext4".
+    pub source: &'static str,
+    pub target: Target<'a>,
+    pub offset: usize,
+    pub len: usize,
+    /// The bytes read or written, 0 for the other operations.
+    pub result: Result<usize, &'a VfsError>,
+    /// 0 without a clock.
+    pub duration_ns: u64,
+}
+
+impl Display for TraceEvent<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:?}", self.source, self.op)?;
+        match self.target {
+            Target::Unknown => {}
+            Target::Path(path) => write!(f, " {}", path)?,
+            Target::Inode(ino) => write!(f, " ino {}", ino)?,
+            Target::Entry { dir, name } => write!(f, " ino {} {}", dir, name)?,
+        }
+        if self.len != 0 || self.offset != 0 {
+            write!(f, " {}+{}", self.offset, self.len)?;
+        }
+        match self.result {
+            Ok(count) => write!(f, " -> {}", count)?,
+            Err(err) => write!(f, " -> {:?}", err)?,
+        }
+        write!(f, " ({}ns)", self.duration_ns)
+    }
+}
+
+pub type Hook = fn(&TraceEvent);
+
+static HOOK: HookCell<Hook> = HookCell::new();
+static CLOCK: HookCell<fn() -> u64> = HookCell::new();
+
+pub fn set_hook(hook: Hook) {
+    HOOK.set(hook);
+}
+
+pub fn clear_hook() {
+    HOOK.clear();
+}
+
+/// The installed hook, to restore it after replacing it.
+pub fn hook() -> Option<Hook> {
+    HOOK.get()
+}
+
+/// Set the monotonic clock in nanoseconds timing the operations.
+pub fn set_clock(clock: fn() -> u64) {
+    CLOCK.set(clock);
+}
+
+fn now() -> u64 {
+    CLOCK.get().map_or(0, |clock| clock())
+}
+
+/// A hook writing the events to the debug log.
+pub fn log_hook(event: &TraceEvent) {
+    log::debug!("{}", event);
+}
+
+/// The result of a traced operation.
+pub trait Outcome {
+    /// The bytes read or written.
+    fn count(&self) -> usize {
+        0
+    }
+}
+
+impl Outcome for usize {
+    fn count(&self) -> usize {
+        *self
+    }
+}
+
+impl Outcome for () {}
+
+impl<T: ?Sized> Outcome for Arc<T> {}
+
+/// Run f and emit the event of the operation to the hook.
+#[inline]
+pub(crate) fn traced<'a, R: Outcome>(
+    op: TraceOp,
+    source: &'static str,
+    target: impl FnOnce() -> Target<'a>,
+    offset: usize,
+    len: usize,
+    f: impl FnOnce() -> VfsResult<R>,
+) -> VfsResult<R> {
+    let Some(hook) = hook() else {
+        return f();
+    };
+    let target = target();
+    let start = now();
+    let r = f();
+    hook(&TraceEvent {
+        op,
+        source,
+        target,
+        offset,
+        len,
+        result: r.as_ref().map(Outcome::count),
+        duration_ns: now().saturating_sub(start),
+    });
+    r
+}
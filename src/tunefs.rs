@@ -0,0 +1,252 @@
+// The tunables of the mounts at runtime, like /sys/fs/ext4/<dev>/ and
+// /proc/sys/vm of Linux. A mount registers a directory of its tunables
+// under the name of its subsystem and its own name, and unregisters it
+// when it's dropped. Every tunable is a pseudo file of pseudo.rs: a read
+// returns the value now with a newline, a write is parsed whole and
+// passed to the setter, which validates the value before applying it,
+// so a bad one fails with InvalidInput, EINVAL, and changes nothing.
+// The directories are the tree of a TuneFs, the "tunefs" type of
+// fstype.rs, mounted like the other filesystems, at /sys/fs for example:
+// its root holds a directory of each subsystem, like "ext4", which holds
+// those of the mounts.
+// TODO: a TuneFs lists the registered mounts only, the tunables of the
+// caches shared by the mounts aren't there yet.
+
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use vfscore::{
+    DirEntry, FileSystem, FileType, INodeInterface, Metadata, OpenFlags, Stat, StatMode, VfsError,
+    VfsResult,
+};
+
+use crate::fstype::FsType;
+use crate::ops::add_dot_entries;
+use crate::pseudo::CallbackInode;
+use crate::sys::Mutex;
+
+type GetFn = Box<dyn Fn() -> String + Send + Sync>;
+type SetFn = Box<dyn Fn(&str) -> VfsResult<()> + Send + Sync>;
+
+/// A value of a mount which can be changed while it's mounted.
+pub struct Tunable {
+    pub name: &'static str,
+    get: GetFn,
+    set: SetFn,
+}
+
+impl Tunable {
+    /// A tunable read by get and written by set, set gets the written
+    /// value without the surrounding white space.
+    pub fn new(
+        name: &'static str,
+        get: impl Fn() -> String + Send + Sync + 'static,
+        set: impl Fn(&str) -> VfsResult<()> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name,
+            get: Box::new(get),
+            set: Box::new(set),
+        }
+    }
+
+    fn into_node(self) -> Arc<CallbackInode> {
+        let Self { get, set, .. } = self;
+        CallbackInode::new(
+            move || {
+                let mut value = get();
+                value.push('\n');
+                value.into_bytes()
+            },
+            Some(move |buf: &[u8]| {
+                let value = core::str::from_utf8(buf).map_err(|_| VfsError::InvalidInput)?;
+                set(value.trim())
+            }),
+        )
+    }
+}
+
+/// The tunable nodes of the registered mounts, by their subsystem and
+/// their name.
+static DIRS: Mutex<BTreeMap<String, BTreeMap<String, Vec<(&'static str, Arc<CallbackInode>)>>>> =
+    Mutex::new(BTreeMap::new());
+
+/// Register the tunables of the mount name of the subsystem, replacing
+/// those it had.
+pub fn register(subsystem: &str, name: &str, tunables: Vec<Tunable>) {
+    let nodes = tunables
+        .into_iter()
+        .map(|x| (x.name, x.into_node()))
+        .collect();
+    DIRS.lock()
+        .entry(subsystem.to_string())
+        .or_default()
+        .insert(name.to_string(), nodes);
+}
+
+/// Unregister the tunables of the mount name of the subsystem, the
+/// subsystem without a mount is removed too.
+pub fn unregister(subsystem: &str, name: &str) {
+    let mut dirs = DIRS.lock();
+    if let Some(mounts) = dirs.get_mut(subsystem) {
+        mounts.remove(name);
+        if mounts.is_empty() {
+            dirs.remove(subsystem);
+        }
+    }
+}
+
+/// Parse the value of a numeric tunable.
+pub fn parse_usize(value: &str) -> VfsResult<usize> {
+    value.parse().map_err(|_| VfsError::InvalidInput)
+}
+
+/// The tunefs type of fstype.rs, every mount shows the same tree.
+pub(crate) const FSTYPE: FsType = FsType {
+    name: "tunefs",
+    detect: None,
+    identify: None,
+    mount: |_, _| Ok(TuneFs::new() as Arc<dyn FileSystem>),
+    priority: 0,
+};
+
+/// The filesystem of the registered tunables, see the module.
+pub struct TuneFs;
+
+impl TuneFs {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self)
+    }
+
+    /// The root directory, without the static self of root_dir.
+    pub fn root(&self) -> Arc<dyn INodeInterface> {
+        TuneDir::new("", DirKind::Root)
+    }
+}
+
+impl FileSystem for TuneFs {
+    fn name(&self) -> &str {
+        "tunefs"
+    }
+
+    fn root_dir(&'static self) -> Arc<dyn INodeInterface> {
+        self.root()
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+enum DirKind {
+    Root,
+    Subsystem(String),
+    Mount(String, String),
+}
+
+/// A directory of a TuneFs, its entries are the registered ones at each
+/// lookup. It fails with FileNotFound once its mounts are unregistered.
+struct TuneDir {
+    filename: String,
+    kind: DirKind,
+}
+
+enum TuneEntry {
+    Dir(DirKind),
+    File(Arc<CallbackInode>),
+}
+
+impl TuneDir {
+    fn new(filename: &str, kind: DirKind) -> Arc<dyn INodeInterface> {
+        Arc::new(Self {
+            filename: filename.to_string(),
+            kind,
+        })
+    }
+
+    fn entries(&self) -> VfsResult<Vec<(String, TuneEntry)>> {
+        let dirs = DIRS.lock();
+        Ok(match &self.kind {
+            DirKind::Root => dirs
+                .keys()
+                .map(|x| (x.clone(), TuneEntry::Dir(DirKind::Subsystem(x.clone()))))
+                .collect(),
+            DirKind::Subsystem(subsystem) => dirs
+                .get(subsystem)
+                .ok_or(VfsError::FileNotFound)?
+                .keys()
+                .map(|x| {
+                    let kind = DirKind::Mount(subsystem.clone(), x.clone());
+                    (x.clone(), TuneEntry::Dir(kind))
+                })
+                .collect(),
+            DirKind::Mount(subsystem, name) => dirs
+                .get(subsystem)
+                .and_then(|x| x.get(name))
+                .ok_or(VfsError::FileNotFound)?
+                .iter()
+                .map(|(x, node)| (x.to_string(), TuneEntry::File(node.clone())))
+                .collect(),
+        })
+    }
+}
+
+impl INodeInterface for TuneDir {
+    fn open(&self, name: &str, _flags: OpenFlags) -> VfsResult<Arc<dyn INodeInterface>> {
+        self.lookup(name)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<Arc<dyn INodeInterface>> {
+        let (name, entry) = self
+            .entries()?
+            .into_iter()
+            .find(|(x, _)| x == name)
+            .ok_or(VfsError::FileNotFound)?;
+        Ok(match entry {
+            TuneEntry::Dir(kind) => TuneDir::new(&name, kind),
+            TuneEntry::File(node) => node,
+        })
+    }
+
+    fn read_dir(&self) -> VfsResult<Vec<DirEntry>> {
+        let mut entries: Vec<_> = self
+            .entries()?
+            .into_iter()
+            .map(|(filename, entry)| DirEntry {
+                filename,
+                len: 0,
+                file_type: match entry {
+                    TuneEntry::Dir(_) => FileType::Directory,
+                    TuneEntry::File(_) => FileType::File,
+                },
+            })
+            .collect();
+        add_dot_entries(&mut entries);
+        Ok(entries)
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(Metadata {
+            filename: &self.filename,
+            inode: usize::MAX,
+            file_type: FileType::Directory,
+            size: 0,
+            childrens: self.entries()?.len(),
+        })
+    }
+
+    fn stat(&self, stat: &mut Stat) -> VfsResult<()> {
+        self.entries()?;
+        stat.mode = StatMode::DIR | StatMode::from_bits_truncate(0o555);
+        stat.nlink = 1;
+        stat.size = 0;
+        stat.blksize = 4096;
+        stat.blocks = 0;
+        Ok(())
+    }
+}
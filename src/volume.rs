@@ -0,0 +1,104 @@
+// The identity of the mounted volumes: the uuid and the label, for the
+// mounts by UUID= or LABEL= and the logs. FileSystem of vfscore doesn't
+// report them, so the filesystems which know theirs register a Volume
+// with their FileSystem, like the StatsSource of the stats. The lookups
+// work on any FileSystem, the unregistered ones have neither.
+
+use alloc::{
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use vfscore::FileSystem;
+
+use crate::sys::Mutex;
+
+/// The identity of a mounted filesystem, None if it has no such field.
+pub trait Volume: Send + Sync {
+    fn uuid(&self) -> Option<[u8; 16]> {
+        None
+    }
+
+    fn label(&self) -> Option<String> {
+        None
+    }
+}
+
+/// The registered volumes with their filesystem, the dropped ones are
+/// removed lazily.
+static VOLUMES: Mutex<Vec<(Weak<dyn FileSystem>, Weak<dyn Volume>)>> = Mutex::new(Vec::new());
+
+/// Register the volume of the filesystem.
+pub fn register(fs: Weak<dyn FileSystem>, volume: Weak<dyn Volume>) {
+    VOLUMES.lock().push((fs, volume));
+}
+
+fn volumes() -> Vec<(Arc<dyn FileSystem>, Arc<dyn Volume>)> {
+    let mut volumes = VOLUMES.lock();
+    volumes.retain(|(fs, volume)| fs.strong_count() > 0 && volume.strong_count() > 0);
+    volumes
+        .iter()
+        .filter_map(|(fs, volume)| Some((fs.upgrade()?, volume.upgrade()?)))
+        .collect()
+}
+
+fn volume_of(fs: &Arc<dyn FileSystem>) -> Option<Arc<dyn Volume>> {
+    volumes()
+        .into_iter()
+        .find(|(x, _)| core::ptr::addr_eq(Arc::as_ptr(x), Arc::as_ptr(fs)))
+        .map(|(_, volume)| volume)
+}
+
+pub fn uuid(fs: &Arc<dyn FileSystem>) -> Option<[u8; 16]> {
+    volume_of(fs)?.uuid()
+}
+
+pub fn label(fs: &Arc<dyn FileSystem>) -> Option<String> {
+    volume_of(fs)?.label()
+}
+
+/// Find the mounted filesystem with the uuid.
+pub fn find_by_uuid(uuid: &[u8; 16]) -> Option<Arc<dyn FileSystem>> {
+    volumes()
+        .into_iter()
+        .find(|(_, volume)| volume.uuid().as_ref() == Some(uuid))
+        .map(|(fs, _)| fs)
+}
+
+/// Find the mounted filesystem with the label.
+pub fn find_by_label(label: &str) -> Option<Arc<dyn FileSystem>> {
+    volumes()
+        .into_iter()
+        .find(|(_, volume)| volume.label().as_deref() == Some(label))
+        .map(|(fs, _)| fs)
+}
+
+/// Format the uuid like blkid: 8-4-4-4-12 lowercase hex digits.
+pub fn format_uuid(uuid: &[u8; 16]) -> String {
+    let mut s = String::with_capacity(36);
+    for (i, byte) in uuid.iter().enumerate() {
+        if matches!(i, 4 | 6 | 8 | 10) {
+            s.push('-');
+        }
+        s.push_str(&format!("{:02x}", byte));
+    }
+    s
+}
+
+/// Parse the uuid formatted by format_uuid, the case of the digits and
+/// the dashes don't matter.
+pub fn parse_uuid(s: &str) -> Option<[u8; 16]> {
+    let digits: Vec<u8> = s
+        .chars()
+        .filter(|x| *x != '-')
+        .map(|x| x.to_digit(16).map(|x| x as u8))
+        .collect::<Option<_>>()?;
+    if digits.len() != 32 {
+        return None;
+    }
+    let mut uuid = [0; 16];
+    for (i, pair) in digits.chunks(2).enumerate() {
+        uuid[i] = pair[0] << 4 | pair[1];
+    }
+    Some(uuid)
+}
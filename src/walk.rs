@@ -0,0 +1,309 @@
+// A recursive iterator over the tree below a directory, for the tools
+// walking whole trees like find and du. The entries come parents before
+// children, each directory in the order of its read_dir. The walk keeps
+// a stack of the listings of the open directories, one per level, so it
+// doesn't recurse and its memory grows with the depth, not the size of
+// the tree. A directory reached twice, by a loop of a corrupted
+// filesystem or a followed link, is yielded as an error and not walked
+// again, so the walk ends on any tree whose directories have an identity.
+
+use alloc::{
+    collections::BTreeSet,
+    string::String,
+    sync::Arc,
+    vec::{self, Vec},
+};
+use vfscore::{DirEntry, FileType, INodeInterface, StatFS, VfsError};
+
+use crate::dentry::{mounted_at, MAX_SYMLINK_DEPTH};
+use crate::error::{Errno, FsError, FsResult};
+
+/// An entry below the directory of the walk.
+#[derive(Clone)]
+pub struct WalkEntry {
+    /// The path relative to the directory of the walk.
+    pub path: String,
+    /// The node of the entry, the target of a followed link.
+    pub inode: Arc<dyn INodeInterface>,
+    /// The type of inode, a followed link has the type of its target.
+    pub file_type: FileType,
+    /// 1 for the entries of the directory of the walk.
+    pub depth: usize,
+}
+
+/// A failure of the walk, the walk goes on with the next entry.
+#[derive(Debug)]
+pub struct WalkError {
+    /// The entry which failed, the directory for a failed read_dir.
+    pub path: String,
+    pub depth: usize,
+    pub error: FsError,
+}
+
+/// The error of a loop, the directory was walked already.
+const LOOP: FsError = FsError::new(VfsError::InvalidInput, Errno::ELOOP);
+
+/// The builder of a walk, WalkDir::new(dir).max_depth(2).into_iter().
+pub struct WalkDir {
+    dir: Arc<dyn INodeInterface>,
+    max_depth: usize,
+    follow_symlinks: bool,
+    same_fs: bool,
+    root: Option<Arc<dyn INodeInterface>>,
+}
+
+impl WalkDir {
+    pub fn new(dir: Arc<dyn INodeInterface>) -> Self {
+        Self {
+            dir,
+            max_depth: usize::MAX,
+            follow_symlinks: false,
+            same_fs: false,
+            root: None,
+        }
+    }
+
+    /// Yield the entries up to the depth, the directories at the depth
+    /// aren't read.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Walk into the directories the symbol links point to. A directory
+    /// reached twice is a loop, it's yielded as an ELOOP error instead of
+    /// walked again, with or without the links. The directories are
+    /// identified by the fsid of statfs and the inode of metadata, the
+    /// ones with inode 0 can't be identified and aren't checked.
+    pub fn follow_symlinks(mut self, follow: bool) -> Self {
+        self.follow_symlinks = follow;
+        self
+    }
+
+    /// Stay on the filesystem of the directory of the walk: the mount
+    /// points and the directories with another fsid are skipped. Without
+    /// it the walk goes into the filesystems mounted on its directories,
+    /// the mount points are matched like dentry::is_mount_point.
+    pub fn same_fs(mut self, same: bool) -> Self {
+        self.same_fs = same;
+        self
+    }
+
+    /// The root of the filesystem resolving the absolute link targets,
+    /// without it they fail with NotSupported.
+    pub fn root(mut self, root: Arc<dyn INodeInterface>) -> Self {
+        self.root = Some(root);
+        self
+    }
+}
+
+impl IntoIterator for WalkDir {
+    type Item = Result<WalkEntry, WalkError>;
+    type IntoIter = Walk;
+
+    fn into_iter(self) -> Walk {
+        let mut walk = Walk {
+            stack: Vec::new(),
+            pending: None,
+            visited: BTreeSet::new(),
+            max_depth: self.max_depth,
+            follow_symlinks: self.follow_symlinks,
+            same_fs: self.same_fs,
+            fsid: fsid(self.dir.as_ref()),
+            root: self.root,
+        };
+        if let Some(id) = identity(self.dir.as_ref()) {
+            walk.visited.insert(id);
+        }
+        if walk.max_depth == 0 {
+            return walk;
+        }
+        match self.dir.read_dir() {
+            Ok(entries) => walk.stack.push(Level {
+                dir: self.dir,
+                path: String::new(),
+                entries: entries.into_iter(),
+            }),
+            Err(error) => {
+                walk.pending = Some(WalkError {
+                    path: String::new(),
+                    depth: 0,
+                    error,
+                })
+            }
+        }
+        walk
+    }
+}
+
+/// An open directory of the walk.
+struct Level {
+    dir: Arc<dyn INodeInterface>,
+    path: String,
+    entries: vec::IntoIter<DirEntry>,
+}
+
+pub struct Walk {
+    stack: Vec<Level>,
+    /// The error of a directory yielded before it.
+    pending: Option<WalkError>,
+    visited: BTreeSet<(u64, usize)>,
+    max_depth: usize,
+    follow_symlinks: bool,
+    same_fs: bool,
+    /// The fsid of the directory of the walk.
+    fsid: Option<u64>,
+    root: Option<Arc<dyn INodeInterface>>,
+}
+
+impl Iterator for Walk {
+    type Item = Result<WalkEntry, WalkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(err) = self.pending.take() {
+            return Some(Err(err));
+        }
+        loop {
+            let depth = self.stack.len();
+            let level = self.stack.last_mut()?;
+            let Some(entry) = level.entries.next() else {
+                self.stack.pop();
+                continue;
+            };
+            if entry.filename == "." || entry.filename == ".." {
+                continue;
+            }
+            let path = match level.path.is_empty() {
+                true => entry.filename.clone(),
+                false => format!("{}/{}", level.path, entry.filename),
+            };
+            let dir = level.dir.clone();
+            let fail = |error| {
+                Some(Err(WalkError {
+                    path: path.clone(),
+                    depth,
+                    error,
+                }))
+            };
+            let mut inode = match mounted_at(&dir, &entry.filename) {
+                Some(_) if self.same_fs => continue,
+                Some(root) => root,
+                None => match dir.lookup(&entry.filename) {
+                    Ok(inode) => inode,
+                    Err(err) => return fail(err.into()),
+                },
+            };
+            let mut file_type = entry.file_type;
+            if self.follow_symlinks && matches!(file_type, FileType::Link) {
+                let chain = self.stack.iter().map(|x| x.dir.clone()).collect();
+                let target = inode
+                    .resolve_link()
+                    .map_err(FsError::from)
+                    .and_then(|x| resolve(self.root.as_ref(), chain, &x, 0));
+                match target.and_then(|x| Ok((x.metadata()?.file_type, x))) {
+                    Ok((x, target)) => (file_type, inode) = (x, target),
+                    Err(err) => return fail(err),
+                }
+                if matches!(file_type, FileType::Directory)
+                    && identity(inode.as_ref()).is_some_and(|x| !self.visited.insert(x))
+                {
+                    return fail(LOOP);
+                }
+            } else if matches!(file_type, FileType::Directory)
+                && identity(inode.as_ref()).is_some_and(|x| !self.visited.insert(x))
+            {
+                return fail(LOOP);
+            }
+            if self.same_fs
+                && matches!(file_type, FileType::Directory)
+                && self.fsid.is_some()
+                && fsid(inode.as_ref()) != self.fsid
+            {
+                continue;
+            }
+            if matches!(file_type, FileType::Directory) && depth < self.max_depth {
+                match inode.read_dir() {
+                    Ok(entries) => self.stack.push(Level {
+                        dir: inode.clone(),
+                        path: path.clone(),
+                        entries: entries.into_iter(),
+                    }),
+                    Err(error) => {
+                        self.pending = Some(WalkError {
+                            path: path.clone(),
+                            depth,
+                            error: error.into(),
+                        })
+                    }
+                }
+            }
+            return Some(Ok(WalkEntry {
+                path,
+                inode,
+                file_type,
+                depth,
+            }));
+        }
+    }
+}
+
+/// Resolve the link target from the last directory of chain, the
+/// directories of the walk down to the one of the link. ".." goes up the
+/// chain, so a followed directory goes back to the directory of the link
+/// like cd -L, and it can't go above the directory of the walk unless the
+/// target is absolute.
+fn resolve(
+    root: Option<&Arc<dyn INodeInterface>>,
+    mut chain: Vec<Arc<dyn INodeInterface>>,
+    target: &str,
+    hops: usize,
+) -> FsResult<Arc<dyn INodeInterface>> {
+    if hops > MAX_SYMLINK_DEPTH {
+        return Err(FsError::new(VfsError::InvalidInput, Errno::ELOOP));
+    }
+    let absolute = target.starts_with('/');
+    if absolute {
+        chain = vec![root.cloned().ok_or(VfsError::NotSupported)?];
+    }
+    let names: Vec<&str> = target
+        .split('/')
+        .filter(|x| !x.is_empty() && *x != ".")
+        .collect();
+    for (i, name) in names.iter().enumerate() {
+        if *name == ".." {
+            match chain.len() {
+                1 if absolute => {}
+                1 => return Err(VfsError::NotSupported.into()),
+                _ => {
+                    chain.pop();
+                }
+            }
+            continue;
+        }
+        let mut node = chain.last().unwrap().lookup(name)?;
+        if matches!(node.metadata()?.file_type, FileType::Link) {
+            node = resolve(root, chain.clone(), &node.resolve_link()?, hops + 1)?;
+        }
+        if i == names.len() - 1 {
+            return Ok(node);
+        }
+        chain.push(node);
+    }
+    Ok(chain.pop().unwrap())
+}
+
+/// The (fsid, inode) of the node, None if it reports inode 0.
+pub(crate) fn identity(node: &dyn INodeInterface) -> Option<(u64, usize)> {
+    let inode = node.metadata().ok()?.inode;
+    if inode == 0 {
+        return None;
+    }
+    Some((fsid(node).unwrap_or(0), inode))
+}
+
+/// The fsid of statfs, None if the node has no statfs.
+fn fsid(node: &dyn INodeInterface) -> Option<u64> {
+    let mut statfs = StatFS::default();
+    node.statfs(&mut statfs).ok()?;
+    Some(statfs.fsid)
+}
@@ -0,0 +1,105 @@
+// The benches of src/bench.rs, ignored by the test runs since they're
+// slow, run them with
+//   cargo test --no-default-features --features std,bench -- --ignored bench_
+// and RUSTFLAGS='--cfg root_fs="ext4_rs"' for the ext4 configs. Each
+// prints the CSV then the summary of its configs, and fails if a cached
+// run makes more device requests than its baseline.
+
+use fs::bench::{self, Config, Measurement, Scenario};
+
+fn run(configs: &[Config]) {
+    let results = bench::compare(configs, &bench::standard()).expect("the bench failed");
+    println!("{}", bench::csv(&results));
+    println!("{}", bench::summary(&results));
+    let failures = bench::check_baselines(&results);
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+/// The output and the baselines of made up results, without running.
+#[test]
+fn bench_output() {
+    let scenario = Scenario::SeqRead {
+        file_size: 1 << 20,
+        io_size: 4096,
+        warm: false,
+    };
+    let measurement = |config: &str, reads: usize, cached: bool| Measurement {
+        config: config.to_string(),
+        scenario,
+        cached,
+        ops: 256,
+        wall_nanos: 1_000_000,
+        device_nanos: 1_000_000,
+        reads,
+        writes: 0,
+        flushes: 0,
+        bytes_read: reads * 4096,
+        bytes_written: 0,
+    };
+    let results = [
+        measurement("a", 300, true),
+        measurement("b", 3000, true),
+        measurement("c", 3000, false),
+    ];
+    let csv = bench::csv(&results);
+    assert_eq!(csv.lines().next(), Some(bench::CSV_HEADER));
+    assert_eq!(
+        csv.lines().nth(1),
+        Some("a,seq_read/4096/cold,256,1000000,1000000,128000.0,300,0,0,1228800,0")
+    );
+    let summary = bench::summary(&results);
+    assert!(summary.starts_with("seq_read/4096/cold:\n"), "{}", summary);
+    assert_eq!(summary.lines().count(), 4, "{}", summary);
+    // the uncached run has no baseline.
+    let failures = bench::check_baselines(&results);
+    assert!(
+        failures.len() == 1 && failures[0].starts_with("seq_read/4096/cold on b: 3000"),
+        "{:?}",
+        failures
+    );
+}
+
+#[test]
+#[ignore]
+fn bench_tmpfs() {
+    run(&[Config::tmpfs()]);
+}
+
+/// The page cache on and off, on a device of 100us requests.
+#[cfg(root_fs = "ext4_rs")]
+#[test]
+#[ignore]
+fn bench_ext4_page_cache() {
+    run(&[
+        Config::ext4("ext4", |x| x).delay(100_000),
+        Config::ext4("ext4/no_page_cache", |x| x)
+            .delay(100_000)
+            .page_cache_bytes(0),
+    ]);
+}
+
+/// The group cache of the bitmaps, the default and a tenth of a megabyte.
+#[cfg(root_fs = "ext4_rs")]
+#[test]
+#[ignore]
+fn bench_ext4_block_cache() {
+    run(&[
+        Config::ext4("ext4", |x| x).delay(100_000),
+        Config::ext4("ext4/block_cache=100K", |x| x.block_cache_bytes(100 << 10)).delay(100_000),
+    ]);
+}
+
+/// The readahead of the reads missing the page cache.
+#[cfg(root_fs = "ext4_rs")]
+#[test]
+#[ignore]
+fn bench_ext4_readahead() {
+    let configs: Vec<Config> = [0, 8, 32]
+        .into_iter()
+        .map(|blocks| {
+            let name = format!("ext4/readahead={}", blocks);
+            Config::ext4(&name, move |x| x.readahead_blocks(blocks)).delay(100_000)
+        })
+        .collect();
+    run(&configs);
+}
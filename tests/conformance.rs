@@ -0,0 +1,270 @@
+// The conformance suite of src/testsuite.rs and the standalone cases of
+// src/testsuite/, on the host:
+//   cargo test --no-default-features --features std,testsuite
+// and with RUSTFLAGS='--cfg root_fs="ext4_rs"' for the ext4 volumes in
+// memory. The cases share the registries and hooks of the crate, so they
+// take SERIAL and run one at a time. The cases taking a device id need a
+// device registered by the kernel, they're not run here.
+
+use std::sync::{Mutex, MutexGuard};
+
+use fs::testing::CountingAlloc;
+use fs::testsuite::{self, Caps, Failure};
+use fs::tmpfs::TmpFs;
+
+/// The cases bounding the memory of an operation count the allocations
+/// of the binary.
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+static SERIAL: Mutex<()> = Mutex::new(());
+
+/// A failed case doesn't poison the others.
+fn serial() -> MutexGuard<'static, ()> {
+    SERIAL
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn assert_passed(failures: &[Failure]) {
+    let report: Vec<String> = failures
+        .iter()
+        .map(|failure| format!("{}: {}", failure.case, failure.reason))
+        .collect();
+    assert!(failures.is_empty(), "{}", report.join("\n"));
+}
+
+#[test]
+fn conformance_tmpfs() {
+    let _serial = serial();
+    assert_passed(&testsuite::run(TmpFs::new(), Caps::ALL));
+}
+
+#[cfg(root_fs = "ext4_rs")]
+#[test]
+fn conformance_ext4() {
+    let _serial = serial();
+    assert_passed(&testsuite::run_ext4_ram(8 << 20, Caps::ALL));
+}
+
+#[cfg(root_fs = "ext4_rs")]
+#[test]
+fn conformance_ext4_64bit() {
+    let _serial = serial();
+    assert_passed(&testsuite::run_ext4_ram_64bit(8 << 20, Caps::ALL));
+}
+
+/// A test for each case of testsuite, under the cfg of the case.
+macro_rules! cases {
+    ($($(#[$attr:meta])* $case:ident,)*) => {
+        $(
+            $(#[$attr])*
+            #[test]
+            fn $case() {
+                let _serial = serial();
+                if let Err(reason) = testsuite::$case() {
+                    panic!("{}: {}", stringify!($case), reason);
+                }
+            }
+        )*
+    };
+}
+
+cases! {
+    #[cfg(root_fs = "ext4_rs")]
+    two_ext4_mounts,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_times,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_times_2045,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_remount,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_backup_superblock,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_error_history,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_csum_round_trip,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_direct_io,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_read_only_replay,
+    #[cfg(all(feature = "ext4_debug", root_fs = "ext4_rs"))]
+    ext4_replay_committed,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_fast_mount,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_mount_timeout,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_subtree,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_atime,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_inode_flags,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_rename,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_rename_flags,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_quota,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_reserved_blocks,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_secure_delete,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_sealed_inodes,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_inline_write,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_device_numbers,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_mknod_round_trip,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_bigalloc_refused,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_doctored_superblock,
+    #[cfg(all(root_fs = "ext4_rs", feature = "std"))]
+    ext4_empty_device,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_feature_matrix,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_ro_compat_shims,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_many_entries,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_huge_directory,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_htree_lookup_blocks,
+    #[cfg(all(root_fs = "ext4_rs", feature = "std"))]
+    ext4_lookup_allocations,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_read_dir_plus,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_deep_tree,
+    #[cfg(all(feature = "ext4_debug", root_fs = "ext4_rs"))]
+    ext4_extent_tree_random,
+    #[cfg(all(feature = "ext4_debug", root_fs = "ext4_rs"))]
+    ext4_allocation_locality,
+    #[cfg(all(feature = "ext4_debug", root_fs = "ext4_rs"))]
+    ext4_interleaved_appends,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_pathconf,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_write_requests,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_sync_writes,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_fsync_inode_blocks,
+    io_streams,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_tar_round_trip,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_bulk_create,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_reproducible_image,
+    #[cfg(all(root_fs = "ext4_rs", feature = "std"))]
+    ext4_bounded_transfers,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_cancelled_write,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_buffered_write_full,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_fill_enospc,
+    #[cfg(all(feature = "std", root_fs = "ext4_rs"))]
+    ext4_freeze,
+    #[cfg(all(feature = "std", root_fs = "ext4_rs"))]
+    ext4_concurrent_alloc,
+    #[cfg(feature = "std")]
+    snapshot_torn_reads,
+    #[cfg(all(feature = "std", root_fs = "ext4_rs"))]
+    ext4_stat_snapshots,
+    #[cfg(all(feature = "std", root_fs = "ext4_rs"))]
+    ext4_create_race,
+    #[cfg(all(feature = "std", root_fs = "ext4_rs"))]
+    ext4_concurrent_dirs,
+    #[cfg(feature = "std")]
+    tmpfs_dir_snapshot_race,
+    #[cfg(all(feature = "std", root_fs = "ext4_rs"))]
+    ext4_dir_snapshot_race,
+    tmpfs_dir_versions,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_dir_versions,
+    #[cfg(all(feature = "std", root_fs = "ext4_rs"))]
+    ext4_write_guard,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_handles,
+    pipe_semantics,
+    #[cfg(feature = "std")]
+    fifo_open_pairing,
+    #[cfg(feature = "std")]
+    pipe_stress,
+    tmpfs_capabilities,
+    crippled_fallbacks,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_capabilities,
+    #[cfg(all(feature = "std", root_fs = "ext4_rs"))]
+    devnode_mkfs,
+    tmpfs_statfs_ids,
+    mounts_busy_report,
+    pathconf_limits,
+    fstype_registry,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_create_rollback,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_retry_relocate,
+    #[cfg(root_fs = "ext4_rs")]
+    tunefs_ext4,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_open_file_cap,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_lazy_itable_init,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_dirent_tails,
+    #[cfg(all(feature = "std", root_fs = "ext4_rs"))]
+    boot_mount_tree,
+    #[cfg(all(feature = "std", root_fs = "ext4_rs"))]
+    mount_by_spec,
+    #[cfg(root_fs = "ext4_rs")]
+    iosched_priorities,
+    pseudo_files,
+    tmpfs_shared_pages,
+    tmpfs_reflink,
+    tmpfs_mapping_hooks,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_mapping_hooks,
+    tmpfs_dir_loops,
+    tmpfs_rename_flags,
+    #[cfg(feature = "std")]
+    tmpfs_rename_noreplace_race,
+    mock_disk,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_power_cut,
+    #[cfg(feature = "std")]
+    model_tmpfs,
+    #[cfg(all(feature = "std", root_fs = "ext4_rs"))]
+    model_ext4,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_crash_journaled,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_crash_no_journal,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_crash_sync_policies,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_crash_reordering,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_sync_policies,
+    disk_layout_fixtures,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_background_work,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_revalidate,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_latin1_names,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_dirent_types,
+    tmpfs_create_with,
+    #[cfg(feature = "std")]
+    tmpfs_create_with_readers,
+    #[cfg(root_fs = "ext4_rs")]
+    ext4_create_with,
+}